@@ -0,0 +1,41 @@
+use std::{env, process::Command};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("proto/broker.proto")?;
+    emit_build_info();
+    Ok(())
+}
+
+/// Build identity for `broker_build_info` and `/admin/version`
+///
+/// Every value falls back to "unknown" rather than failing the build: crate
+/// registry and vendored builds have no git checkout.
+fn emit_build_info() {
+    let commit = env::var("BROKER_GIT_COMMIT")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=BROKER_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BROKER_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=BROKER_FEATURES={}", features.join(","));
+    println!("cargo:rerun-if-env-changed=BROKER_GIT_COMMIT");
+    if let Some(git_dir) = command_output("git", &["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/refs", git_dir);
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+    (output.status.success() && !stdout.is_empty()).then(|| stdout.to_string())
+}
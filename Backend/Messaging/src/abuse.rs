@@ -0,0 +1,750 @@
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+use arc_swap::ArcSwap;
+use async_nats::{jetstream::kv, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::DashSet;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::AbuseScoreConfig,
+    config_watch::ConfigWatcher,
+    ingestion_pause::{IngestionPauses, PauseSelector},
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+    rate_limit::UserRateLimiter,
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Egress header asking gateways to hold a sender's messages for review
+pub const REVIEW_REQUIRED_HEADER: &str = "Broker-Review-Required";
+
+/// Actor recorded for automated transitions
+const ACTOR: &str = "abuse_score";
+
+const RECORD_CODEC: VersionedCodec<AbuseRecord> = VersionedCodec::new(schema::ABUSE_SCORE, 1, &[]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbuseSignal {
+    RateLimited,
+    /// Ingress rejected the user's message as invalid, disallowed or denied by policy
+    Rejected,
+    /// A recipient's block list suppressed the user's message
+    BlockSuppressed,
+    /// Flagged as spam by a `flag_spam` control command
+    SpamFlagged,
+}
+
+impl AbuseSignal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AbuseSignal::RateLimited => "rate_limited",
+            AbuseSignal::Rejected => "rejected",
+            AbuseSignal::BlockSuppressed => "block_suppressed",
+            AbuseSignal::SpamFlagged => "spam_flagged",
+        }
+    }
+}
+
+/// Automated responses, each including the ones below it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbuseResponse {
+    #[default]
+    None,
+    /// Rate limit cut to `abuse.throttle_fraction` of normal
+    Throttle,
+    /// Egress messages carry `Broker-Review-Required`
+    Review,
+    /// Ingestion paused for the sender
+    Pause,
+}
+
+impl AbuseResponse {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AbuseResponse::None => "none",
+            AbuseResponse::Throttle => "throttle",
+            AbuseResponse::Review => "review",
+            AbuseResponse::Pause => "pause",
+        }
+    }
+}
+
+/// One user's score as of `updated_at`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbuseRecord {
+    pub user_id: String,
+    pub score: f64,
+    /// Timestamp in milliseconds
+    pub updated_at: i64,
+    /// Response currently applied
+    pub response: AbuseResponse,
+    /// Admin override; applied instead of whatever the score calls for
+    pub pinned: Option<AbuseResponse>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl AbuseRecord {
+    fn new(user_id: &str, now: i64) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            score: 0.0,
+            updated_at: now,
+            response: AbuseResponse::None,
+            pinned: None,
+            dirty: true,
+        }
+    }
+
+    fn decay_to(&mut self, now: i64, half_life: Duration) {
+        self.score = decayed(self.score, now - self.updated_at, half_life);
+        self.updated_at = now;
+    }
+}
+
+/// `score` after `elapsed_ms` of exponential decay
+pub fn decayed(score: f64, elapsed_ms: i64, half_life: Duration) -> f64 {
+    if elapsed_ms <= 0 || half_life.is_zero() {
+        return score;
+    }
+    score * 0.5f64.powf(elapsed_ms as f64 / half_life.as_millis() as f64)
+}
+
+/// Response for `score` coming from `current`
+///
+/// A tier is entered once the score reaches its band's `enter` and kept
+/// until it drops below the band's `exit`, so a score hovering around one
+/// threshold doesn't flap.
+pub fn next_response(current: AbuseResponse, score: f64, config: &AbuseScoreConfig) -> AbuseResponse {
+    let tiers = [
+        (AbuseResponse::Pause, &config.pause),
+        (AbuseResponse::Review, &config.review),
+        (AbuseResponse::Throttle, &config.throttle),
+    ];
+    for (tier, band) in tiers {
+        if score >= band.enter || (current >= tier && score >= band.exit) {
+            return tier;
+        }
+    }
+    AbuseResponse::None
+}
+
+struct Transition {
+    from: AbuseResponse,
+    to: AbuseResponse,
+    score: f64,
+}
+
+/// Decaying per-user abuse score driving automated restrictions
+///
+/// Rate-limit hits, rejected messages, block-list suppressions and spam
+/// flags add their configured weight to the sender's score, which halves
+/// every `abuse.half_life`. As the score crosses the throttle, review and
+/// pause bands the user's rate limit is cut, their egress messages are
+/// marked for review, and their ingestion is paused; each response is
+/// reverted once the decayed score falls below the band's exit. Every
+/// transition is audited.
+///
+/// Admins can pin a response, `none` included, which holds regardless of
+/// the score until cleared. Scores live in bounded per-shard LRUs; a user
+/// evicted under memory pressure loses their automated responses with
+/// their score, but pinned users are never evicted; a shard holding
+/// nothing but pins grows instead. With a store attached, records are
+/// written to KV every `abuse.sweep_interval` on a best-effort basis and
+/// reapplied, pins included, on startup.
+pub struct AbuseScores {
+    shards: Vec<Mutex<LruCache<String, AbuseRecord>>>,
+    /// Users whose egress messages need `REVIEW_REQUIRED_HEADER`
+    reviewed: DashSet<String>,
+    config: ArcSwap<AbuseScoreConfig>,
+    limiter: Arc<UserRateLimiter>,
+    pauses: Arc<IngestionPauses>,
+    kv: Option<kv::Store>,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl AbuseScores {
+    pub fn new(
+        config: AbuseScoreConfig,
+        limiter: Arc<UserRateLimiter>,
+        pauses: Arc<IngestionPauses>,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let per_shard = NonZeroUsize::new(config.records_per_shard.max(1)).unwrap();
+        Self {
+            shards: (0..config.shards.max(1))
+                .map(|_| Mutex::new(LruCache::new(per_shard)))
+                .collect(),
+            reviewed: DashSet::new(),
+            config: ArcSwap::from_pointee(config),
+            limiter,
+            pauses,
+            kv: None,
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    /// Persist records to `kv` and restore them from it on `load`
+    pub fn with_store(mut self, kv: kv::Store) -> Self {
+        self.kv = Some(kv);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Add `signal`'s weight to the user's score
+    pub fn observe(&self, user_id: &str, signal: AbuseSignal) {
+        let config = self.config.load();
+        if !config.enabled {
+            return;
+        }
+        self.metrics.record_abuse_signal(signal.as_str());
+        let weight = config.weights.of(signal);
+        self.update(user_id, &config, ACTOR, |record| record.score += weight);
+    }
+
+    /// Current record with the score decayed to now
+    pub fn status(&self, user_id: &str) -> Option<AbuseRecord> {
+        let half_life = self.config.load().half_life;
+        let mut record = self.shard(user_id).lock().peek(user_id).cloned()?;
+        record.decay_to(self.clock.now_millis(), half_life);
+        Some(record)
+    }
+
+    pub fn review_required(&self, sender: &str) -> bool {
+        !self.reviewed.is_empty() && self.reviewed.contains(sender)
+    }
+
+    /// Mark an egress publish of `sender`'s message if they're under review
+    pub fn apply_headers(&self, sender: &str, headers: &mut HeaderMap) {
+        if self.review_required(sender) {
+            headers.insert(REVIEW_REQUIRED_HEADER, ACTOR);
+        }
+    }
+
+    /// Hold `response` for the user whatever their score
+    pub fn pin(&self, user_id: &str, response: AbuseResponse, actor: &str) {
+        let config = self.config.load();
+        info!("Abuse response for {} pinned to {} by {}", user_id, response.as_str(), actor);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "abuse.override_set",
+            serde_json::json!({ "user_id": user_id, "response": response }),
+        ));
+        self.update(user_id, &config, actor, |record| record.pinned = Some(response));
+    }
+
+    /// Return the user to automated responses, optionally from a zero score
+    pub fn unpin(&self, user_id: &str, reset_score: bool, actor: &str) {
+        let config = self.config.load();
+        info!("Abuse override for {} cleared by {}", user_id, actor);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "abuse.override_cleared",
+            serde_json::json!({ "user_id": user_id, "reset_score": reset_score }),
+        ));
+        self.update(user_id, &config, actor, |record| {
+            record.pinned = None;
+            if reset_score {
+                record.score = 0.0;
+            }
+        });
+    }
+
+    fn shard(&self, user_id: &str) -> &Mutex<LruCache<String, AbuseRecord>> {
+        &self.shards[shard_for(user_id, self.shards.len())]
+    }
+
+    /// Make room for one more record, evicting the least recently used one
+    /// that isn't pinned
+    fn make_room(shard: &mut LruCache<String, AbuseRecord>) -> Option<AbuseRecord> {
+        if shard.len() < shard.cap().get() {
+            return None;
+        }
+        let victim = shard
+            .iter()
+            .rev()
+            .find(|(_, record)| record.pinned.is_none())
+            .map(|(user_id, _)| user_id.clone());
+        match victim {
+            Some(user_id) => shard.pop(&user_id),
+            None => {
+                // Pins are set by hand, so a shard full of them stays small
+                shard.resize(shard.cap().saturating_add(1));
+                None
+            }
+        }
+    }
+
+    fn update(&self, user_id: &str, config: &AbuseScoreConfig, actor: &str, change: impl FnOnce(&mut AbuseRecord)) {
+        let now = self.clock.now_millis();
+        let (transition, evicted) = {
+            let mut shard = self.shard(user_id).lock();
+            let evicted = if shard.contains(user_id) {
+                None
+            } else {
+                let evicted = Self::make_room(&mut shard);
+                shard.put(user_id.to_string(), AbuseRecord::new(user_id, now));
+                evicted
+            };
+            let record = shard.get_mut(user_id).expect("record was just ensured");
+            record.decay_to(now, config.half_life);
+            change(record);
+            record.dirty = true;
+            (Self::transition(record, config), evicted)
+        };
+
+        if let Some(evicted) = evicted {
+            self.metrics.record_abuse_evicted();
+            if evicted.response != AbuseResponse::None {
+                self.apply(&evicted.user_id, evicted.response, AbuseResponse::None, evicted.score, "abuse_score.evicted");
+            }
+        }
+        if let Some(transition) = transition {
+            self.apply(user_id, transition.from, transition.to, transition.score, actor);
+        }
+    }
+
+    fn transition(record: &mut AbuseRecord, config: &AbuseScoreConfig) -> Option<Transition> {
+        let target = record
+            .pinned
+            .unwrap_or_else(|| next_response(record.response, record.score, config));
+        if target == record.response {
+            return None;
+        }
+        let from = std::mem::replace(&mut record.response, target);
+        Some(Transition {
+            from,
+            to: target,
+            score: record.score,
+        })
+    }
+
+    /// Put the user's restrictions in line with moving from `from` to `to`
+    fn apply(&self, user_id: &str, from: AbuseResponse, to: AbuseResponse, score: f64, actor: &str) {
+        let config = self.config.load();
+        let throttled = to >= AbuseResponse::Throttle;
+        if throttled != (from >= AbuseResponse::Throttle) {
+            self.limiter
+                .set_override(user_id, throttled.then_some(config.throttle_fraction));
+        }
+        if to >= AbuseResponse::Review {
+            self.reviewed.insert(user_id.to_string());
+        } else {
+            self.reviewed.remove(user_id);
+        }
+        let selector = PauseSelector::Sender(user_id.to_string());
+        match (from == AbuseResponse::Pause, to == AbuseResponse::Pause) {
+            (false, true) => {
+                self.pauses.pause(selector, Some(config.pause_ttl), actor);
+            }
+            (true, false) => {
+                self.pauses.resume(&selector, actor);
+            }
+            _ => {}
+        }
+
+        warn!(
+            "Abuse response for {} changed {} -> {} at score {:.1} by {}",
+            user_id,
+            from.as_str(),
+            to.as_str(),
+            score,
+            actor
+        );
+        self.metrics.record_abuse_transition(to.as_str());
+        self.audit.record(AuditEntry::new(
+            actor,
+            "abuse.response_changed",
+            serde_json::json!({ "user_id": user_id, "from": from, "to": to, "score": score }),
+        ));
+    }
+
+    /// Decay every score, revert responses that fell out of their band, and
+    /// forget unrestricted users whose score is negligible
+    fn sweep(&self) -> (Vec<AbuseRecord>, Vec<String>) {
+        let config = self.config.load();
+        let now = self.clock.now_millis();
+        let mut transitions = Vec::new();
+        let mut dirty = Vec::new();
+        let mut forgotten = Vec::new();
+
+        for shard in &self.shards {
+            let mut shard = shard.lock();
+            let mut drop_keys = Vec::new();
+            for (user_id, record) in shard.iter_mut() {
+                record.decay_to(now, config.half_life);
+                if let Some(transition) = Self::transition(record, &config) {
+                    record.dirty = true;
+                    transitions.push((user_id.clone(), transition));
+                }
+                if record.response == AbuseResponse::None && record.pinned.is_none() && record.score < config.forget_below {
+                    drop_keys.push(user_id.clone());
+                } else if std::mem::take(&mut record.dirty) {
+                    dirty.push(record.clone());
+                }
+            }
+            for user_id in drop_keys {
+                shard.pop(&user_id);
+                forgotten.push(user_id);
+            }
+        }
+
+        for (user_id, transition) in transitions {
+            self.apply(&user_id, transition.from, transition.to, transition.score, ACTOR);
+        }
+        self.metrics.update_abuse_restricted(self.restricted());
+        (dirty, forgotten)
+    }
+
+    fn restricted(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().iter().filter(|(_, r)| r.response != AbuseResponse::None).count())
+            .sum()
+    }
+
+    async fn persist(&self, dirty: Vec<AbuseRecord>, forgotten: Vec<String>) {
+        let Some(kv) = &self.kv else {
+            return;
+        };
+        for record in dirty {
+            let value = match RECORD_CODEC.encode(&record) {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Failed to encode abuse score for {}: {}", record.user_id, e);
+                    continue;
+                }
+            };
+            if let Err(e) = kv.put(record_key(&record.user_id), value.into()).await {
+                debug!("Failed to persist abuse score for {}: {}", record.user_id, e);
+            }
+        }
+        for user_id in forgotten {
+            if let Err(e) = kv.delete(record_key(&user_id)).await {
+                debug!("Failed to purge abuse score for {}: {}", user_id, e);
+            }
+        }
+    }
+
+    /// Restore persisted scores and reapply their responses
+    pub async fn load(&self) -> Result<usize, AbuseScoreError> {
+        let Some(kv) = &self.kv else {
+            return Ok(0);
+        };
+        let config = self.config.load();
+        let mut keys = kv.keys().await.map_err(|e| AbuseScoreError(e.to_string()))?;
+        let mut restored = 0;
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(|e| AbuseScoreError(e.to_string()))?;
+            let entry = kv.entry(&key).await.map_err(|e| AbuseScoreError(e.to_string()))?;
+            let Some(entry) = entry.filter(|entry| entry.operation == kv::Operation::Put) else {
+                continue;
+            };
+            let mut record = match RECORD_CODEC.decode(&entry.value) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Skipping unreadable abuse score {}: {}", key, e);
+                    continue;
+                }
+            };
+            let response = std::mem::take(&mut record.response);
+            let user_id = record.user_id.clone();
+            // Restoring from `none` re-derives the response from the decayed score
+            let now = self.clock.now_millis();
+            self.update(&user_id, &config, "abuse_score.restore", |fresh| {
+                *fresh = record;
+                fresh.decay_to(now, config.half_life);
+            });
+            debug!("Restored abuse score for {} (was {})", user_id, response.as_str());
+            restored += 1;
+        }
+        info!("Restored {} abuse scores", restored);
+        Ok(restored)
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let scores = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            scores.config.store(Arc::new(config.abuse.clone()));
+        });
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let scores = Arc::clone(self);
+        spawn_traced("abuse_score_sweep", TaskContext::new("abuse"), async move {
+            if let Err(e) = scores.load().await {
+                warn!("Failed to restore abuse scores: {}", e);
+            }
+            loop {
+                scores.clock.sleep(scores.config.load().sweep_interval).await;
+                let (dirty, forgotten) = scores.sweep();
+                scores.persist(dirty, forgotten).await;
+            }
+        })
+    }
+}
+
+fn record_key(user_id: &str) -> String {
+    format!("abuse.{}", URL_SAFE_NO_PAD.encode(user_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("abuse score store error: {0}")]
+pub struct AbuseScoreError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream;
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    struct Fixture {
+        scores: AbuseScores,
+        limiter: Arc<UserRateLimiter>,
+        pauses: Arc<IngestionPauses>,
+        clock: Arc<SimClock>,
+    }
+
+    impl Fixture {
+        /// Rate limit the user currently gets, out of 100
+        fn limit(&self, user_id: &str) -> u32 {
+            self.limiter.check(user_id).unwrap().limit
+        }
+
+        fn paused(&self, user_id: &str) -> bool {
+            self.pauses
+                .list()
+                .iter()
+                .any(|pause| pause.selector == PauseSelector::Sender(user_id.into()))
+        }
+
+        fn response(&self, user_id: &str) -> AbuseResponse {
+            self.scores.status(user_id).map_or(AbuseResponse::None, |record| record.response)
+        }
+
+        fn flag(&self, user_id: &str, times: usize) {
+            for _ in 0..times {
+                self.scores.observe(user_id, AbuseSignal::SpamFlagged);
+            }
+        }
+    }
+
+    /// Pausing never touches NATS, so the client is never connected
+    async fn fixture(configure: impl FnOnce(&mut AbuseScoreConfig)) -> Fixture {
+        let config = BrokerConfig::load().unwrap();
+        let mut abuse = config.abuse.clone();
+        abuse.enabled = true;
+        configure(&mut abuse);
+
+        let clock = Arc::new(SimClock::new());
+        let metrics = BrokerMetrics::new().unwrap();
+        let limiter = Arc::new(UserRateLimiter::new(&config.limits, metrics.clone()).with_clock(clock.clone()));
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("localhost:4222")
+            .await
+            .unwrap();
+        let pauses = Arc::new(
+            IngestionPauses::new(
+                config.ingestion_pause.clone(),
+                jetstream::new(client),
+                "ingress".into(),
+                AuditLog::tracing_only(),
+                metrics.clone(),
+            )
+            .with_clock(clock.clone()),
+        );
+        let scores = AbuseScores::new(abuse, Arc::clone(&limiter), Arc::clone(&pauses), AuditLog::tracing_only(), metrics)
+            .with_clock(clock.clone());
+        Fixture {
+            scores,
+            limiter,
+            pauses,
+            clock,
+        }
+    }
+
+    #[test]
+    fn scores_halve_every_half_life() {
+        assert_eq!(decayed(80.0, HOUR.as_millis() as i64, HOUR), 40.0);
+        assert_eq!(decayed(80.0, 2 * HOUR.as_millis() as i64, HOUR), 20.0);
+        assert!((decayed(80.0, HOUR.as_millis() as i64 / 2, HOUR) - 80.0 / 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(decayed(80.0, 0, HOUR), 80.0);
+        assert_eq!(decayed(80.0, -1_000, HOUR), 80.0, "a clock step back never inflates a score");
+        assert_eq!(decayed(80.0, 1_000, Duration::ZERO), 80.0);
+    }
+
+    #[tokio::test]
+    async fn signals_add_their_weight_to_the_decayed_score() {
+        let fixture = fixture(|_| {}).await;
+        assert!(fixture.scores.status("mallory").is_none());
+
+        fixture.scores.observe("mallory", AbuseSignal::BlockSuppressed);
+        fixture.scores.observe("mallory", AbuseSignal::Rejected);
+        fixture.scores.observe("mallory", AbuseSignal::RateLimited);
+        assert_eq!(fixture.scores.status("mallory").unwrap().score, 8.0);
+
+        fixture.clock.advance(HOUR);
+        assert!((fixture.scores.status("mallory").unwrap().score - 4.0).abs() < 1e-9);
+        fixture.scores.observe("mallory", AbuseSignal::Rejected);
+        assert!((fixture.scores.status("mallory").unwrap().score - 6.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn disabled_scoring_ignores_signals() {
+        let fixture = fixture(|config| config.enabled = false).await;
+        fixture.flag("mallory", 10);
+        assert!(fixture.scores.status("mallory").is_none());
+        assert_eq!(fixture.limit("mallory"), 100);
+    }
+
+    #[test]
+    fn tiers_are_entered_at_enter_and_kept_down_to_exit() {
+        let config = BrokerConfig::load().unwrap().abuse;
+        let cases = [
+            (AbuseResponse::None, 19.9, AbuseResponse::None),
+            (AbuseResponse::None, 20.0, AbuseResponse::Throttle),
+            (AbuseResponse::None, 100.0, AbuseResponse::Pause),
+            (AbuseResponse::Throttle, 10.0, AbuseResponse::Throttle),
+            (AbuseResponse::Throttle, 9.9, AbuseResponse::None),
+            (AbuseResponse::Throttle, 49.9, AbuseResponse::Throttle),
+            (AbuseResponse::Review, 25.0, AbuseResponse::Review),
+            (AbuseResponse::Review, 24.9, AbuseResponse::Throttle),
+            (AbuseResponse::Pause, 50.0, AbuseResponse::Pause),
+            (AbuseResponse::Pause, 49.9, AbuseResponse::Review),
+            (AbuseResponse::Pause, 5.0, AbuseResponse::None),
+        ];
+        for (current, score, expected) in cases {
+            assert_eq!(next_response(current, score, &config), expected, "{:?} at {}", current, score);
+        }
+    }
+
+    #[tokio::test]
+    async fn restrictions_are_applied_on_the_way_up_and_reverted_at_each_exit() {
+        let fixture = fixture(|_| {}).await;
+        let mut headers = HeaderMap::new();
+
+        fixture.flag("mallory", 5);
+        assert_eq!(fixture.response("mallory"), AbuseResponse::Pause);
+        assert_eq!(fixture.limit("mallory"), 25);
+        assert!(fixture.paused("mallory"));
+        fixture.scores.apply_headers("mallory", &mut headers);
+        assert_eq!(headers.get(REVIEW_REQUIRED_HEADER).map(|v| v.as_str()), Some(ACTOR));
+
+        // 100 -> 50: still inside the pause band
+        fixture.clock.advance(HOUR);
+        fixture.scores.sweep();
+        assert_eq!(fixture.response("mallory"), AbuseResponse::Pause);
+
+        // ~44.5: out of pause, still under review
+        fixture.clock.advance(Duration::from_secs(600));
+        fixture.scores.sweep();
+        assert_eq!(fixture.response("mallory"), AbuseResponse::Review);
+        assert!(!fixture.paused("mallory"));
+        assert!(fixture.scores.review_required("mallory"));
+
+        // ~22: throttled only
+        fixture.clock.advance(HOUR);
+        fixture.scores.sweep();
+        assert_eq!(fixture.response("mallory"), AbuseResponse::Throttle);
+        assert!(!fixture.scores.review_required("mallory"));
+        assert_eq!(fixture.limit("mallory"), 25);
+
+        // ~5.6: back to normal
+        fixture.clock.advance(2 * HOUR);
+        fixture.scores.sweep();
+        assert_eq!(fixture.response("mallory"), AbuseResponse::None);
+        assert_eq!(fixture.limit("mallory"), 100);
+        assert_eq!(fixture.limit("bystander"), 100);
+    }
+
+    #[tokio::test]
+    async fn negligible_unrestricted_scores_are_forgotten() {
+        let fixture = fixture(|_| {}).await;
+        fixture.scores.observe("mallory", AbuseSignal::Rejected);
+        fixture.clock.advance(3 * HOUR);
+        let (_, forgotten) = fixture.scores.sweep();
+        assert_eq!(forgotten, ["mallory"]);
+        assert!(fixture.scores.status("mallory").is_none());
+    }
+
+    #[tokio::test]
+    async fn pins_hold_whatever_the_score_until_cleared() {
+        let fixture = fixture(|_| {}).await;
+
+        fixture.scores.pin("mallory", AbuseResponse::Pause, "ops");
+        assert!(fixture.paused("mallory"));
+        fixture.clock.advance(10 * HOUR);
+        fixture.scores.sweep();
+        assert_eq!(fixture.response("mallory"), AbuseResponse::Pause);
+        fixture.scores.unpin("mallory", false, "ops");
+        assert_eq!(fixture.response("mallory"), AbuseResponse::None);
+        assert!(!fixture.paused("mallory"));
+
+        // Pinned to none, a flood of flags restricts nothing
+        fixture.flag("trusted", 5);
+        assert!(fixture.paused("trusted"));
+        fixture.scores.pin("trusted", AbuseResponse::None, "ops");
+        assert!(!fixture.paused("trusted"));
+        assert_eq!(fixture.limit("trusted"), 100);
+        fixture.flag("trusted", 5);
+        fixture.scores.sweep();
+        assert_eq!(fixture.response("trusted"), AbuseResponse::None);
+
+        // Clearing the pin re-derives from the score unless it is reset
+        fixture.scores.unpin("trusted", false, "ops");
+        assert_eq!(fixture.response("trusted"), AbuseResponse::Pause);
+        fixture.scores.pin("trusted", AbuseResponse::None, "ops");
+        fixture.scores.unpin("trusted", true, "ops");
+        assert_eq!(fixture.scores.status("trusted").unwrap().score, 0.0);
+        assert_eq!(fixture.response("trusted"), AbuseResponse::None);
+    }
+
+    #[tokio::test]
+    async fn eviction_reverts_automated_responses_but_never_touches_pins() {
+        let fixture = fixture(|config| {
+            config.shards = 1;
+            config.records_per_shard = 2;
+        })
+        .await;
+
+        fixture.scores.pin("pinned", AbuseResponse::Pause, "ops");
+        fixture.flag("flagged", 1);
+        assert_eq!(fixture.limit("flagged"), 25);
+
+        // `pinned` is least recently used, so `flagged` goes instead
+        fixture.scores.observe("newcomer", AbuseSignal::Rejected);
+        assert!(fixture.scores.status("flagged").is_none());
+        assert_eq!(fixture.limit("flagged"), 100);
+        assert_eq!(fixture.response("pinned"), AbuseResponse::Pause);
+        assert!(fixture.paused("pinned"));
+
+        // With nothing but pins left the shard grows
+        fixture.scores.pin("newcomer", AbuseResponse::Throttle, "ops");
+        fixture.scores.pin("another", AbuseResponse::Review, "ops");
+        for user_id in ["pinned", "newcomer", "another"] {
+            assert!(fixture.scores.status(user_id).unwrap().pinned.is_some(), "{} was evicted", user_id);
+        }
+    }
+}
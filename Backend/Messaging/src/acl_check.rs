@@ -0,0 +1,410 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use async_nats::{ConnectOptions, Event, HeaderMap};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{config::BrokerConfig, subject_match::subscription_subject};
+
+/// Header on publish probes; consumers must drop messages carrying it
+pub const ACL_PROBE_HEADER: &str = "Broker-Acl-Probe";
+
+/// Token substituted for wildcards when probing a pattern
+const PROBE_TOKEN: &str = "_acl_probe";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclOperation {
+    Publish,
+    Subscribe,
+}
+
+/// One subject or pattern the broker needs, with its purpose
+#[derive(Debug, Clone, Serialize)]
+pub struct SubjectUse {
+    pub subject: String,
+    pub purpose: &'static str,
+    pub operation: AclOperation,
+}
+
+/// Every subject the effective config makes the broker publish or subscribe to
+pub fn subject_inventory(config: &BrokerConfig) -> Vec<SubjectUse> {
+    use AclOperation::{Publish, Subscribe};
+
+    let nats = &config.nats;
+    let mut subjects = vec![
+        (nats.ingress_topic.clone(), "ingress", Subscribe),
+        (nats.ingress_topic.clone(), "ingress (outbox, transactions)", Publish),
+        (format!("{}.>", nats.egress_user_prefix), "user egress", Publish),
+        (format!("{}.>", nats.egress_group_prefix), "group egress", Publish),
+        (nats.control_topic.clone(), "control", Subscribe),
+        (nats.control_topic.clone(), "control (handover broadcast)", Publish),
+        (nats.dead_letter_topic.clone(), "dead letters", Publish),
+        (nats.delivery_fallback_topic.clone(), "push fallback hand-off", Publish),
+        (format!("{}.>", config.ingestion_pause.holding_subject), "parked ingress", Publish),
+        (format!("{}.>", config.session_migration.gateway_control_prefix), "gateway session commands", Publish),
+        (format!("{}.>", config.session_migration.gateway_session_prefix), "migration mirrored deliveries", Publish),
+        (subscription_subject(&config.receipts.subject), "gateway receipts", Subscribe),
+        (config.receipts.capabilities_subject.clone(), "receipt capability requests", Subscribe),
+        (config.integrity.report_subject.clone(), "gateway corruption reports", Subscribe),
+        (config.compression.dictionary_subject.clone(), "compression dictionary announcements", Publish),
+        (config.compression.request_subject.clone(), "compression dictionary requests", Subscribe),
+        (config.compression.holdings_subject.clone(), "gateway dictionary holdings", Subscribe),
+        (format!("{}.config.{}", nats.control_topic, config.broker_id), "config dump requests", Subscribe),
+        (format!("{}.config.>", nats.control_topic), "peer config dump requests", Publish),
+        (format!("{}.>", config.quota_feedback.subject), "sender quota feedback", Publish),
+        (config.conversation_lifecycle.subject.clone(), "conversation lifecycle events", Publish),
+        ("$JS.API.>".to_string(), "JetStream API", Publish),
+        ("_INBOX.>".to_string(), "request replies", Subscribe),
+    ];
+
+    for (bucket, purpose) in [
+        (&nats.checkpoint_bucket, "checkpoint KV"),
+        (&nats.presence_bucket, "presence KV"),
+        (&nats.read_horizon_bucket, "read horizon KV"),
+        (&nats.key_distribution_bucket, "key distribution KV"),
+        (&nats.delivery_status_bucket, "delivery status KV"),
+        (&nats.sequence_bucket, "sequence KV"),
+        (&config.cluster.bucket, "cluster membership KV"),
+        (&config.session_migration.bucket, "session migration KV"),
+        (&config.control.lease_bucket, "control command leases"),
+        (&config.route_warming.bucket, "route activity KV"),
+        (&config.archive.bucket, "archived conversations KV"),
+        (&config.abuse.bucket, "abuse score KV"),
+        (&config.conversation_home.bucket, "conversation home KV"),
+        (&config.unread.bucket, "unread skip KV"),
+    ] {
+        subjects.push((format!("$KV.{}.>", bucket), purpose, Publish));
+        subjects.push((format!("$KV.{}.>", bucket), purpose, Subscribe));
+    }
+
+    if config.conversation_home.enabled {
+        for subject in config.conversation_home.regions.values() {
+            subjects.push((subject.clone(), "home region ingress", Publish));
+        }
+    }
+
+    if let Some(migration) = &nats.migration {
+        subjects.push((migration.old_ingress_topic.clone(), "legacy ingress drain", Subscribe));
+        for mapping in &migration.subject_mappings {
+            subjects.push((format!("{}.>", mapping.to), "migrated subjects", Publish));
+        }
+    }
+
+    subjects
+        .into_iter()
+        .map(|(subject, purpose, operation)| SubjectUse {
+            subject,
+            purpose,
+            operation,
+        })
+        .collect()
+}
+
+/// Output for `--print-subjects`
+pub fn print_subjects(config: &BrokerConfig) -> String {
+    subject_inventory(config)
+        .iter()
+        .map(|s| format!("{:9}  {:40}  {}\n", format!("{:?}", s.operation), s.subject, s.purpose))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AclResult {
+    #[serde(flatten)]
+    pub subject: SubjectUse,
+    pub allowed: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AclReport {
+    pub results: Vec<AclResult>,
+}
+
+impl AclReport {
+    pub fn denied(&self) -> impl Iterator<Item = &AclResult> {
+        self.results.iter().filter(|r| !r.allowed)
+    }
+
+    pub fn render_table(&self) -> String {
+        self.results
+            .iter()
+            .map(|r| {
+                format!(
+                    "{:7}  {:9}  {:40}  {}\n",
+                    if r.allowed { "allowed" } else { "DENIED" },
+                    format!("{:?}", r.subject.operation),
+                    r.subject.subject,
+                    r.subject.purpose
+                )
+            })
+            .collect()
+    }
+}
+
+/// Probe the connected account's permissions for every inventory subject
+///
+/// Uses a dedicated connection so the server's asynchronous permission
+/// violation errors can be attributed to probes. Subscribe probes
+/// subscribe and unsubscribe; publish probes send an empty message with
+/// `ACL_PROBE_HEADER` set, with wildcards replaced by a probe token.
+pub async fn run_acl_check(config: &BrokerConfig, grace: Duration) -> anyhow::Result<AclReport> {
+    let violations: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let client = probe_options(config, Arc::clone(&violations))
+        .connect(config.nats.servers.join(",").as_str())
+        .await?;
+
+    let inventory = subject_inventory(config);
+    for subject in &inventory {
+        match subject.operation {
+            AclOperation::Subscribe => {
+                let mut subscriber = client.subscribe(subject.subject.clone()).await?;
+                subscriber.unsubscribe().await?;
+            }
+            AclOperation::Publish => {
+                let mut headers = HeaderMap::new();
+                headers.insert(ACL_PROBE_HEADER, "1");
+                client
+                    .publish_with_headers(probe_subject(&subject.subject), headers, Default::default())
+                    .await?;
+            }
+        }
+    }
+    client.flush().await?;
+
+    // Permission errors arrive asynchronously after the probes
+    tokio::time::sleep(grace).await;
+
+    let violations = violations.lock().clone();
+    let results = inventory
+        .into_iter()
+        .map(|subject| AclResult {
+            allowed: !is_denied(&subject, &violations),
+            subject,
+        })
+        .collect();
+
+    Ok(AclReport { results })
+}
+
+/// Run the self-check and fail startup on denials when configured to
+pub async fn enforce_acl_check(config: &BrokerConfig) -> anyhow::Result<()> {
+    if !config.nats.acl_check_enabled {
+        return Ok(());
+    }
+
+    let report = run_acl_check(config, Duration::from_millis(500)).await?;
+    let denied: HashSet<&str> = report.denied().map(|r| r.subject.subject.as_str()).collect();
+    if denied.is_empty() {
+        info!("NATS ACL self-check passed for {} subjects", report.results.len());
+        return Ok(());
+    }
+
+    warn!("NATS ACL self-check found denied subjects:\n{}", report.render_table());
+    if config.nats.acl_check_fail_on_deny {
+        anyhow::bail!("NATS account denies {} required subjects", denied.len());
+    }
+    Ok(())
+}
+
+/// Concrete subject published for a publish probe
+fn probe_subject(subject: &str) -> String {
+    subject.replace(['>', '*'], PROBE_TOKEN)
+}
+
+/// Whether one of the lowercased permission violations names this probe
+fn is_denied(subject: &SubjectUse, violations: &[String]) -> bool {
+    let (kind, checked) = match subject.operation {
+        AclOperation::Publish => ("publish", probe_subject(&subject.subject)),
+        AclOperation::Subscribe => ("subscription", subject.subject.clone()),
+    };
+    let quoted = format!("\"{}\"", checked.to_lowercase());
+    violations.iter().any(|v| v.contains(kind) && v.contains(&quoted))
+}
+
+fn probe_options(config: &BrokerConfig, violations: Arc<Mutex<Vec<String>>>) -> ConnectOptions {
+    let nats = &config.nats;
+    let mut options = ConnectOptions::new()
+        .name(format!("{}-acl-probe", config.broker_id))
+        .event_callback(move |event| {
+            let violations = Arc::clone(&violations);
+            async move {
+                if let Event::ServerError(error) = event {
+                    let message = error.to_string().to_lowercase();
+                    if message.contains("permissions violation") {
+                        violations.lock().push(message);
+                    }
+                }
+            }
+        });
+
+    if let (Some(user), Some(password)) = (&nats.username, &nats.password) {
+        options = options.user_and_password(user.clone(), password.clone());
+    }
+    if let Some(token) = &nats.token {
+        options = options.token(token.clone());
+    }
+    if let (Some(cert), Some(key)) = (&nats.tls_cert, &nats.tls_key) {
+        options = options.add_client_certificate(cert.into(), key.into());
+    }
+    if let Some(ca) = &nats.tls_ca {
+        options = options.add_root_certificates(ca.into()).require_tls(true);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        process::{Child, Command},
+    };
+
+    use super::*;
+
+    fn find<'a>(inventory: &'a [SubjectUse], subject: &str, operation: AclOperation) -> Option<&'a SubjectUse> {
+        inventory.iter().find(|s| s.subject == subject && s.operation == operation)
+    }
+
+    #[test]
+    fn inventory_follows_the_effective_config() {
+        let mut config = BrokerConfig::load().unwrap();
+        let inventory = subject_inventory(&config);
+        assert!(find(&inventory, "broker.control", AclOperation::Subscribe).is_some());
+        assert!(find(&inventory, "gateway.user.>", AclOperation::Publish).is_some());
+        let presence_kv = format!("$KV.{}.>", config.nats.presence_bucket);
+        assert!(find(&inventory, &presence_kv, AclOperation::Publish).is_some());
+        assert!(find(&inventory, &presence_kv, AclOperation::Subscribe).is_some());
+
+        config.nats.egress_user_prefix = "msg.v2.user".into();
+        let inventory = subject_inventory(&config);
+        assert!(find(&inventory, "gateway.user.>", AclOperation::Publish).is_none());
+        assert!(find(&inventory, "msg.v2.user.>", AclOperation::Publish).is_some());
+
+        let printed = print_subjects(&config);
+        assert_eq!(printed.lines().count(), inventory.len());
+        assert!(printed.contains("msg.v2.user.>"));
+    }
+
+    #[test]
+    fn violations_are_attributed_to_their_probe() {
+        let publish = SubjectUse {
+            subject: "gateway.user.>".into(),
+            purpose: "user egress",
+            operation: AclOperation::Publish,
+        };
+        let subscribe = SubjectUse {
+            subject: "broker.control".into(),
+            purpose: "control",
+            operation: AclOperation::Subscribe,
+        };
+        let publish_control = SubjectUse {
+            operation: AclOperation::Publish,
+            ..subscribe.clone()
+        };
+
+        let violations = vec![
+            "permissions violation for publish to \"gateway.user._acl_probe\"".to_string(),
+            "permissions violation for subscription to \"broker.control\"".to_string(),
+        ];
+        assert!(is_denied(&publish, &violations));
+        assert!(is_denied(&subscribe, &violations));
+        assert!(!is_denied(&publish_control, &violations));
+        assert!(!is_denied(&publish, &[]));
+    }
+
+    /// `nats-server` on a free port with an unrestricted and a restricted user
+    struct NatsServer {
+        process: Child,
+        url: String,
+    }
+
+    impl NatsServer {
+        fn start() -> Self {
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let config = std::env::temp_dir().join(format!("acl-check-{}.conf", port));
+            std::fs::write(
+                &config,
+                format!(
+                    r#"
+                    port: {port}
+                    authorization {{
+                        users = [
+                            {{ user: broker, password: secret }}
+                            {{
+                                user: restricted, password: secret,
+                                permissions: {{
+                                    publish: {{ deny: ["gateway.user.>"] }}
+                                    subscribe: {{ deny: ["broker.control"] }}
+                                }}
+                            }}
+                        ]
+                    }}
+                    "#
+                ),
+            )
+            .unwrap();
+            let process = Command::new("nats-server").arg("-c").arg(&config).spawn().unwrap();
+            Self {
+                process,
+                url: format!("nats://127.0.0.1:{}", port),
+            }
+        }
+
+        async fn config(&self, user: &str) -> BrokerConfig {
+            // Wait for the server to accept connections
+            for _ in 0..50 {
+                if tokio::net::TcpStream::connect(self.url.trim_start_matches("nats://")).await.is_ok() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            let mut config = BrokerConfig::load().unwrap();
+            config.nats.servers = vec![self.url.clone()];
+            config.nats.username = Some(user.into());
+            config.nats.password = Some("secret".into());
+            config
+        }
+    }
+
+    impl Drop for NatsServer {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn an_unrestricted_account_passes() {
+        let server = NatsServer::start();
+        let config = server.config("broker").await;
+
+        let report = run_acl_check(&config, Duration::from_millis(500)).await.unwrap();
+        assert_eq!(report.results.len(), subject_inventory(&config).len());
+        assert_eq!(report.denied().count(), 0, "{}", report.render_table());
+        enforce_acl_check(&config).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn a_restricted_account_reports_and_fails_on_denied_subjects() {
+        let server = NatsServer::start();
+        let mut config = server.config("restricted").await;
+
+        let report = run_acl_check(&config, Duration::from_millis(500)).await.unwrap();
+        let mut denied: Vec<(&str, AclOperation)> = report
+            .denied()
+            .map(|r| (r.subject.subject.as_str(), r.subject.operation))
+            .collect();
+        denied.sort_by_key(|(subject, _)| *subject);
+        assert_eq!(
+            denied,
+            vec![("broker.control", AclOperation::Subscribe), ("gateway.user.>", AclOperation::Publish)]
+        );
+        assert!(report.render_table().contains("DENIED   Publish    gateway.user.>"));
+
+        assert!(enforce_acl_check(&config).await.is_err());
+        config.nats.acl_check_fail_on_deny = false;
+        enforce_acl_check(&config).await.unwrap();
+    }
+}
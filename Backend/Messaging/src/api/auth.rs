@@ -0,0 +1,695 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+use parking_lot::Mutex;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tower::{Layer, Service};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    config::{ApiAuthConfig, ApiKeyConfig},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// What an API key may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Send,
+    Subscribe,
+    /// Operator endpoints; satisfies every other scope too
+    Admin,
+    /// Relays acting for many users, e.g. federation bridges
+    Bridge,
+}
+
+/// Scopes each REST route or gRPC method accepts, any one sufficing
+///
+/// Entries ending in `/` match by prefix. Paths not listed need no key:
+/// health and readiness probes and tenant metrics (authorized by their own
+/// tenant token). The broker-to-broker `BrokerPeer` service takes no API
+/// keys either; it runs on its own listener, which only admits peers with
+/// a client certificate from the cluster CA, see `forward::peer_server_tls`.
+pub const ROUTE_SCOPES: &[(&str, &[Scope])] = &[
+    ("/broker.v1.Broker/Subscribe", &[Scope::Subscribe]),
+    ("/broker.v1.Broker/Keepalive", &[Scope::Subscribe]),
+    ("/broker.v1.Broker/SendTransaction", &[Scope::Send, Scope::Bridge]),
+    ("/broker.v1.Broker/FetchHistory", &[Scope::Subscribe, Scope::Bridge]),
+    ("/broker.v1.Broker/GetReadHorizons", &[Scope::Subscribe]),
+    ("/broker.v1.Broker/GetMessageStatus", &[Scope::Send, Scope::Bridge]),
+    ("/broker.v1.Broker/GetUnreadCounts", &[Scope::Subscribe]),
+    ("/read-horizons/", &[Scope::Subscribe]),
+    ("/key-distributions/", &[Scope::Send]),
+    ("/debug/", &[Scope::Admin]),
+    ("/ingestion-pauses", &[Scope::Admin]),
+    ("/maintenance", &[Scope::Admin]),
+    ("/admin/", &[Scope::Admin]),
+];
+
+/// Routes a tenant's key may call
+///
+/// Their handlers confine the caller to its own tenant's data. Every other
+/// route is keyed by user, conversation or message alone, with nothing to
+/// tell one tenant's records from another's, so tenant keys are refused
+/// there whatever their scopes.
+pub const TENANT_ROUTES: &[&str] = &["/broker.v1.Broker/SendTransaction"];
+
+pub fn required_scopes(path: &str) -> Option<&'static [Scope]> {
+    ROUTE_SCOPES
+        .iter()
+        .find(|(route, _)| {
+            if route.ends_with('/') {
+                path.starts_with(route)
+            } else {
+                path == *route
+            }
+        })
+        .map(|(_, scopes)| *scopes)
+}
+
+/// Authenticated caller, attached to request extensions for handlers and audit
+#[derive(Debug, Clone)]
+pub struct ApiIdentity {
+    pub key_id: String,
+    pub scopes: Vec<Scope>,
+    /// Set for keys issued to one user
+    pub user_id: Option<String>,
+    /// Set for keys of a provisioned tenant, which may only act for that tenant
+    pub tenant_id: Option<String>,
+}
+
+impl ApiIdentity {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AuthFailure {
+    #[error("missing API key")]
+    Missing,
+    #[error("unknown API key")]
+    Unknown,
+    #[error("API key {0} expired")]
+    Expired(String),
+    #[error("API key {0} lacks the required scope")]
+    InsufficientScope(String),
+}
+
+impl AuthFailure {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthFailure::Missing => "missing",
+            AuthFailure::Unknown => "unknown",
+            AuthFailure::Expired(_) => "expired",
+            AuthFailure::InsufficientScope(_) => "insufficient_scope",
+        }
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        match self {
+            AuthFailure::Expired(key_id) | AuthFailure::InsufficientScope(key_id) => Some(key_id),
+            AuthFailure::Missing | AuthFailure::Unknown => None,
+        }
+    }
+}
+
+/// Active API keys by the hex SHA-256 of their secret
+struct KeySet {
+    by_hash: HashMap<String, ApiKeyConfig>,
+}
+
+impl KeySet {
+    fn new(keys: Vec<ApiKeyConfig>) -> Self {
+        Self {
+            by_hash: keys
+                .into_iter()
+                .map(|key| (key.sha256.to_ascii_lowercase(), key))
+                .collect(),
+        }
+    }
+}
+
+/// Bearer API key authentication shared by the REST and gRPC surfaces
+///
+/// Keys come from `api.auth.keys` plus the JSON list in
+/// `api.auth.keys_file`, which is re-read every `api.auth.reload_interval`
+/// so a mounted secret can be rotated in place. Only SHA-256 hashes of the
+/// secrets are configured. Rotation is zero-downtime: list the new key next
+/// to the old one, move clients over, and let the old key's `not_after`
+/// pass. Failures are counted by reason; expired and insufficient-scope
+/// failures, and every admin-scoped request, are audited with the key ID,
+/// never the secret.
+///
+/// Provisioned tenants' keys are held apart from the configured ones, set
+/// and removed by the tenant registry, and survive keys file reloads. Keys
+/// bound to a tenant only pass on `TENANT_ROUTES`.
+pub struct ApiAuth {
+    config: ApiAuthConfig,
+    keys: ArcSwap<KeySet>,
+    /// Keys from config and the keys file as last loaded
+    configured: Mutex<Vec<ApiKeyConfig>>,
+    tenant_keys: Mutex<BTreeMap<String, ApiKeyConfig>>,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl ApiAuth {
+    pub fn new(config: ApiAuthConfig, audit: AuditLog, metrics: BrokerMetrics) -> Result<Self, AuthConfigError> {
+        let keys = load_keys(&config)?;
+        Ok(Self {
+            keys: ArcSwap::from_pointee(KeySet::new(keys.clone())),
+            configured: Mutex::new(keys),
+            tenant_keys: Mutex::new(BTreeMap::new()),
+            config,
+            audit,
+            metrics,
+        })
+    }
+
+    /// Check the `Authorization` header value against the scopes `path` requires
+    pub fn check(&self, path: &str, authorization: Option<&str>) -> Result<Option<ApiIdentity>, AuthFailure> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let Some(scopes) = required_scopes(path) else {
+            return Ok(None);
+        };
+
+        let result = self.authenticate(authorization, scopes).and_then(|identity| {
+            if identity.tenant_id.is_some() && !TENANT_ROUTES.contains(&path) {
+                return Err(AuthFailure::InsufficientScope(identity.key_id));
+            }
+            Ok(identity)
+        });
+        match &result {
+            Ok(identity) if scopes.contains(&Scope::Admin) => {
+                self.audit.record(AuditEntry::new(
+                    identity.key_id.clone(),
+                    "api.admin_request",
+                    json!({ "path": path }),
+                ));
+            }
+            Ok(_) => {}
+            Err(failure) => {
+                self.metrics.record_api_auth_failure(failure.reason());
+                if let Some(key_id) = failure.key_id() {
+                    self.audit.record(AuditEntry::new(
+                        key_id,
+                        "api.auth_rejected",
+                        json!({ "path": path, "reason": failure.reason() }),
+                    ));
+                }
+            }
+        }
+        result.map(Some)
+    }
+
+    fn authenticate(&self, authorization: Option<&str>, scopes: &[Scope]) -> Result<ApiIdentity, AuthFailure> {
+        let secret = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .filter(|secret| !secret.is_empty())
+            .ok_or(AuthFailure::Missing)?;
+        let hash = hex::encode(digest(&SHA256, secret.as_bytes()));
+
+        let keys = self.keys.load();
+        let key = keys.by_hash.get(&hash).ok_or(AuthFailure::Unknown)?;
+        if key.not_after.is_some_and(|not_after| Utc::now() > not_after) {
+            return Err(AuthFailure::Expired(key.id.clone()));
+        }
+        let identity = ApiIdentity {
+            key_id: key.id.clone(),
+            scopes: key.scopes.clone(),
+            user_id: key.user_id.clone(),
+            tenant_id: key.tenant_id.clone(),
+        };
+        if !scopes.iter().any(|scope| identity.allows(*scope)) {
+            return Err(AuthFailure::InsufficientScope(key.id.clone()));
+        }
+        Ok(identity)
+    }
+
+    /// Re-read the keys file; on error the current keys stay active
+    pub fn reload(&self) -> Result<usize, AuthConfigError> {
+        let keys = load_keys(&self.config)?;
+        let count = keys.len();
+        *self.configured.lock() = keys;
+        self.rebuild();
+        Ok(count)
+    }
+
+    /// Set or, with `None`, remove the key of a provisioned tenant
+    pub fn set_tenant_key(&self, tenant_id: &str, key: Option<ApiKeyConfig>) {
+        {
+            let mut tenant_keys = self.tenant_keys.lock();
+            match key {
+                Some(key) => tenant_keys.insert(tenant_id.to_string(), key),
+                None => tenant_keys.remove(tenant_id),
+            };
+        }
+        self.rebuild();
+    }
+
+    /// Both locks are held through the store so concurrent rebuilds can't go stale
+    fn rebuild(&self) {
+        let configured = self.configured.lock();
+        let tenant_keys = self.tenant_keys.lock();
+        let keys = configured.iter().chain(tenant_keys.values()).cloned().collect();
+        self.keys.store(Arc::new(KeySet::new(keys)));
+    }
+
+    pub fn spawn_reload(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled || self.config.keys_file.is_none() {
+            return None;
+        }
+        let auth = Arc::clone(self);
+        Some(spawn_traced("api_key_reload", TaskContext::new("api_auth"), async move {
+            let mut ticker = tokio::time::interval(auth.config.reload_interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match auth.reload() {
+                    Ok(count) => info!("Reloaded {} API keys", count),
+                    Err(e) => warn!("Keeping current API keys: {}", e),
+                }
+            }
+        }))
+    }
+}
+
+fn load_keys(config: &ApiAuthConfig) -> Result<Vec<ApiKeyConfig>, AuthConfigError> {
+    let mut keys = config.keys.clone();
+    if let Some(path) = &config.keys_file {
+        let raw = std::fs::read(path).map_err(|e| AuthConfigError(format!("{}: {}", path, e)))?;
+        let from_file: Vec<ApiKeyConfig> =
+            serde_json::from_slice(&raw).map_err(|e| AuthConfigError(format!("{}: {}", path, e)))?;
+        keys.extend(from_file);
+    }
+    Ok(keys)
+}
+
+/// `axum::middleware::from_fn_with_state` adapter for the REST router
+pub async fn rest_auth(State(auth): State<Arc<ApiAuth>>, mut request: Request, next: Next) -> Result<Response, StatusCode> {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match auth.check(request.uri().path(), authorization) {
+        Ok(identity) => {
+            if let Some(identity) = identity {
+                request.extensions_mut().insert(identity);
+            }
+            Ok(next.run(request).await)
+        }
+        Err(AuthFailure::InsufficientScope(_)) => Err(StatusCode::FORBIDDEN),
+        Err(_) => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Tower layer for the tonic server; tonic runs on the hyper 0.14 `http` types
+#[derive(Clone)]
+pub struct GrpcAuthLayer {
+    auth: Arc<ApiAuth>,
+}
+
+impl GrpcAuthLayer {
+    pub fn new(auth: Arc<ApiAuth>) -> Self {
+        Self { auth }
+    }
+}
+
+impl<S> Layer<S> for GrpcAuthLayer {
+    type Service = GrpcAuth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcAuth {
+            inner,
+            auth: Arc::clone(&self.auth),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcAuth<S> {
+    inner: S,
+    auth: Arc<ApiAuth>,
+}
+
+impl<S, B> Service<hyper::Request<B>> for GrpcAuth<S>
+where
+    S: Service<hyper::Request<B>, Response = hyper::Response<tonic::body::BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: hyper::Request<B>) -> Self::Future {
+        let authorization = request
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match self.auth.check(request.uri().path(), authorization) {
+            Ok(identity) => {
+                if let Some(identity) = identity {
+                    request.extensions_mut().insert(identity);
+                }
+                // The clone isn't ready; call the instance poll_ready was called on
+                let clone = self.inner.clone();
+                let mut inner = std::mem::replace(&mut self.inner, clone);
+                Box::pin(async move { inner.call(request).await })
+            }
+            Err(failure) => {
+                let status = match failure {
+                    AuthFailure::InsufficientScope(_) => tonic::Status::permission_denied(failure.to_string()),
+                    _ => tonic::Status::unauthenticated(failure.to_string()),
+                };
+                Box::pin(async move { Ok(status.to_http()) })
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid API key configuration: {0}")]
+pub struct AuthConfigError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, path::PathBuf};
+
+    use axum::{body::Body, middleware, routing::get, Router};
+    use chrono::Duration;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    /// Concrete paths of the operator endpoints on the REST router
+    const ADMIN_PATHS: &[&str] = &[
+        "/debug/state",
+        "/debug/traces/m1",
+        "/ingestion-pauses",
+        "/maintenance",
+        "/admin/version",
+        "/admin/config/fingerprint",
+        "/admin/config/diff",
+        "/admin/audit/verify",
+        "/admin/volume/top",
+        "/admin/tenants",
+        "/admin/tenants/acme/restore",
+        "/admin/users/alice/recent",
+        "/admin/users/alice/offline:export",
+        "/admin/users/alice/offline:purge",
+        "/admin/debug/pprof/heap",
+        "/admin/retries/r1",
+        "/admin/scheduled/s1",
+    ];
+
+    fn key(id: &str, secret: &str, scopes: &[Scope]) -> ApiKeyConfig {
+        ApiKeyConfig {
+            id: id.into(),
+            sha256: hex::encode(digest(&SHA256, secret.as_bytes())),
+            scopes: scopes.to_vec(),
+            user_id: None,
+            tenant_id: None,
+            not_after: None,
+        }
+    }
+
+    fn config(keys: Vec<ApiKeyConfig>) -> ApiAuthConfig {
+        let mut config = BrokerConfig::load().unwrap().api.auth;
+        config.enabled = true;
+        config.keys = keys;
+        config.keys_file = None;
+        config
+    }
+
+    fn auth(keys: Vec<ApiKeyConfig>) -> ApiAuth {
+        ApiAuth::new(config(keys), AuditLog::tracing_only(), BrokerMetrics::new().unwrap()).unwrap()
+    }
+
+    fn keys() -> Vec<ApiKeyConfig> {
+        vec![
+            key("sender", "send-secret", &[Scope::Send]),
+            key("reader", "subscribe-secret", &[Scope::Subscribe]),
+            key("operator", "admin-secret", &[Scope::Admin]),
+        ]
+    }
+
+    fn bearer(secret: &str) -> String {
+        format!("Bearer {}", secret)
+    }
+
+    fn with_key(auth: &ApiAuth, path: &str, secret: &str) -> Result<Option<ApiIdentity>, AuthFailure> {
+        auth.check(path, Some(bearer(secret).as_str()))
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("api-auth-{}-{}", name, Uuid::new_v4().simple()))
+    }
+
+    #[test]
+    fn every_failure_mode_is_told_apart() {
+        let mut expired = key("retired", "old-secret", &[Scope::Send]);
+        expired.not_after = Some(Utc::now() - Duration::seconds(1));
+        let mut keys = keys();
+        keys.push(expired);
+        let auth = auth(keys);
+        let path = "/read-horizons/alice";
+
+        assert_eq!(auth.check(path, None).unwrap_err(), AuthFailure::Missing);
+        assert_eq!(auth.check(path, Some("subscribe-secret")).unwrap_err(), AuthFailure::Missing);
+        assert_eq!(auth.check(path, Some("Bearer ")).unwrap_err(), AuthFailure::Missing);
+        assert_eq!(with_key(&auth, path, "guess").unwrap_err(), AuthFailure::Unknown);
+        assert_eq!(with_key(&auth, path, "old-secret").unwrap_err(), AuthFailure::Expired("retired".into()));
+        assert_eq!(
+            with_key(&auth, path, "send-secret").unwrap_err(),
+            AuthFailure::InsufficientScope("sender".into())
+        );
+
+        let identity = with_key(&auth, path, "subscribe-secret").unwrap().unwrap();
+        assert_eq!(identity.key_id, "reader");
+        assert_eq!(identity.scopes, [Scope::Subscribe]);
+    }
+
+    #[test]
+    fn admin_satisfies_every_scope_and_unlisted_paths_need_no_key() {
+        let auth = auth(keys());
+        for path in ["/read-horizons/alice", "/key-distributions/m1", "/broker.v1.Broker/SendTransaction"] {
+            assert!(with_key(&auth, path, "admin-secret").unwrap().is_some(), "{}", path);
+        }
+        for path in ["/health", "/ready", "/metrics/tenant/acme", "/broker.v1.Internal/Forward"] {
+            assert!(required_scopes(path).is_none(), "{}", path);
+            assert!(auth.check(path, None).unwrap().is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn disabled_auth_lets_everything_through() {
+        let mut config = config(keys());
+        config.enabled = false;
+        let auth = ApiAuth::new(config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap()).unwrap();
+        assert!(auth.check("/admin/version", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn rotation_accepts_both_keys_until_the_old_one_expires() {
+        let mut old = key("v1", "old-secret", &[Scope::Send]);
+        old.not_after = Some(Utc::now() + Duration::hours(1));
+        let new = key("v2", "new-secret", &[Scope::Send]);
+        let path = "/key-distributions/m1";
+
+        let overlapping = auth(vec![old.clone(), new.clone()]);
+        assert_eq!(with_key(&overlapping, path, "old-secret").unwrap().unwrap().key_id, "v1");
+        assert_eq!(with_key(&overlapping, path, "new-secret").unwrap().unwrap().key_id, "v2");
+
+        old.not_after = Some(Utc::now() - Duration::seconds(1));
+        let rotated = auth(vec![old, new]);
+        assert_eq!(with_key(&rotated, path, "old-secret").unwrap_err(), AuthFailure::Expired("v1".into()));
+        assert!(with_key(&rotated, path, "new-secret").is_ok());
+    }
+
+    #[test]
+    fn keys_file_reloads_rotate_in_place() {
+        let path = temp_path("keys");
+        std::fs::write(&path, serde_json::to_vec(&[key("file-v1", "file-secret-1", &[Scope::Send])]).unwrap()).unwrap();
+        let mut config = config(Vec::new());
+        config.keys_file = Some(path.to_string_lossy().into_owned());
+        let auth = ApiAuth::new(config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap()).unwrap();
+        let route = "/key-distributions/m1";
+        assert!(with_key(&auth, route, "file-secret-1").is_ok());
+
+        let rotated = [
+            key("file-v1", "file-secret-1", &[Scope::Send]),
+            key("file-v2", "file-secret-2", &[Scope::Send]),
+        ];
+        std::fs::write(&path, serde_json::to_vec(&rotated).unwrap()).unwrap();
+        assert_eq!(auth.reload().unwrap(), 2);
+        assert!(with_key(&auth, route, "file-secret-1").is_ok());
+        assert!(with_key(&auth, route, "file-secret-2").is_ok());
+
+        // A broken file keeps the keys that were active
+        std::fs::write(&path, b"[{").unwrap();
+        assert!(auth.reload().is_err());
+        assert!(with_key(&auth, route, "file-secret-2").is_ok());
+
+        std::fs::write(&path, serde_json::to_vec(&[key("file-v2", "file-secret-2", &[Scope::Send])]).unwrap()).unwrap();
+        auth.reload().unwrap();
+        assert_eq!(with_key(&auth, route, "file-secret-1").unwrap_err(), AuthFailure::Unknown);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tenant_keys_only_pass_on_tenant_routes_and_survive_reloads() {
+        let auth = auth(keys());
+        let mut tenant = key("acme-key", "acme-secret", &[Scope::Send, Scope::Subscribe]);
+        tenant.tenant_id = Some("acme".into());
+        auth.set_tenant_key("acme", Some(tenant));
+
+        let identity = with_key(&auth, "/broker.v1.Broker/SendTransaction", "acme-secret").unwrap().unwrap();
+        assert_eq!(identity.tenant_id.as_deref(), Some("acme"));
+        assert_eq!(
+            with_key(&auth, "/read-horizons/alice", "acme-secret").unwrap_err(),
+            AuthFailure::InsufficientScope("acme-key".into())
+        );
+
+        auth.reload().unwrap();
+        assert!(with_key(&auth, "/broker.v1.Broker/SendTransaction", "acme-secret").is_ok());
+        auth.set_tenant_key("acme", None);
+        assert_eq!(
+            with_key(&auth, "/broker.v1.Broker/SendTransaction", "acme-secret").unwrap_err(),
+            AuthFailure::Unknown
+        );
+    }
+
+    #[test]
+    fn failures_are_counted_by_reason() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let auth = auth(keys());
+            let path = "/admin/version";
+            let _ = auth.check(path, None);
+            let _ = with_key(&auth, path, "guess");
+            let _ = with_key(&auth, path, "guess-again");
+            let _ = with_key(&auth, path, "send-secret");
+            let _ = with_key(&auth, path, "admin-secret");
+        });
+
+        let rendered = handle.render();
+        for line in [
+            r#"broker_api_auth_failures_total{reason="missing"} 1"#,
+            r#"broker_api_auth_failures_total{reason="unknown"} 2"#,
+            r#"broker_api_auth_failures_total{reason="insufficient_scope"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+        assert!(!rendered.contains(r#"reason="expired""#));
+    }
+
+    #[test]
+    fn audit_entries_name_the_key_never_the_secret() {
+        let path = temp_path("audit");
+        let audit_config = BrokerConfig::load().unwrap().audit;
+        let metrics = BrokerMetrics::new().unwrap();
+        let audit = AuditLog::open(Some(&path.to_string_lossy()), &audit_config, metrics.clone()).unwrap();
+        let auth = ApiAuth::new(config(keys()), audit, metrics).unwrap();
+
+        with_key(&auth, "/admin/version", "admin-secret").unwrap();
+        with_key(&auth, "/admin/version", "send-secret").unwrap_err();
+        with_key(&auth, "/admin/version", "guess").unwrap_err();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("api.admin_request") && written.contains("operator"), "{}", written);
+        assert!(written.contains("api.auth_rejected") && written.contains("sender"), "{}", written);
+        for secret in ["admin-secret", "send-secret", "guess"] {
+            assert!(!written.contains(secret), "{} leaked into the audit log", secret);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn operator_endpoints_require_admin() {
+        for path in ADMIN_PATHS {
+            assert_eq!(required_scopes(path), Some(&[Scope::Admin][..]), "{}", path);
+        }
+    }
+
+    async fn status(router: &Router, path: &str, secret: Option<&str>) -> StatusCode {
+        let mut request = axum::http::Request::builder().uri(path);
+        if let Some(secret) = secret {
+            request = request.header(header::AUTHORIZATION, bearer(secret));
+        }
+        router.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn rest_middleware_gates_the_admin_endpoints() {
+        let auth = Arc::new(auth(keys()));
+        // A fallback rather than one route per path: `offline:export` and
+        // `offline:purge` are the same route to the router
+        let router = Router::new()
+            .fallback(get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(auth, rest_auth));
+
+        for path in ADMIN_PATHS {
+            assert_eq!(status(&router, path, None).await, StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(status(&router, path, Some("guess")).await, StatusCode::UNAUTHORIZED, "{}", path);
+            assert_eq!(status(&router, path, Some("send-secret")).await, StatusCode::FORBIDDEN, "{}", path);
+            assert_eq!(status(&router, path, Some("subscribe-secret")).await, StatusCode::FORBIDDEN, "{}", path);
+            assert_eq!(status(&router, path, Some("admin-secret")).await, StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_layer_maps_failures_to_grpc_statuses() {
+        let service = GrpcAuthLayer::new(Arc::new(auth(keys()))).layer(tower::service_fn(
+            |request: hyper::Request<()>| async move {
+                assert!(request.extensions().get::<ApiIdentity>().is_some());
+                Ok::<_, Infallible>(hyper::Response::new(tonic::body::empty_body()))
+            },
+        ));
+        let grpc_status = |secret: Option<&str>| {
+            let service = service.clone();
+            let mut request = hyper::Request::builder().uri("/broker.v1.Broker/Subscribe");
+            if let Some(secret) = secret {
+                request = request.header(hyper::header::AUTHORIZATION, bearer(secret));
+            }
+            async move {
+                let response = service.oneshot(request.body(()).unwrap()).await.unwrap();
+                response
+                    .headers()
+                    .get("grpc-status")
+                    .map(|status| status.to_str().unwrap().to_string())
+            }
+        };
+
+        assert_eq!(grpc_status(None).await.as_deref(), Some("16"));
+        assert_eq!(grpc_status(Some("send-secret")).await.as_deref(), Some("7"));
+        assert_eq!(grpc_status(Some("subscribe-secret")).await, None);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{sync::Arc, time::Duration};
+use tonic::{metadata::MetadataValue, Request, Response, Status};
+use tracing::debug;
+
+use super::{
+    auth::ApiIdentity,
+    proto::{
+        broker_server::Broker, FetchHistoryRequest, FetchHistoryResponse, Freshness,
+        GetMessageStatusRequest, GetMessageStatusResponse, GetReadHorizonsRequest,
+        GetReadHorizonsResponse, GetUnreadCountsRequest, GetUnreadCountsResponse, HistoryMessage,
+        KeepaliveRequest, KeepaliveResponse, MessageError, ReadHorizon, RecipientDeliveryStatus,
+        SendTransactionRequest, SendTransactionResponse, SubscribeRequest, UnreadCount,
+    },
+    subscriptions::{FrameStream, SubscriptionRegistry},
+};
+use crate::{
+    coalesce::Coalescer,
+    deadline::Deadline,
+    delivery::{DeliveryTracker, MessageStatus},
+    envelope_guard::EnvelopeGuard,
+    history::{HistoryError, HistoryReader},
+    maintenance::MaintenanceMode,
+    metrics::BrokerMetrics,
+    read_horizon::ReadHorizonStore,
+    read_replica::ReadOperation,
+    transaction::{self, TransactionCoordinator, TransactionError},
+    unread::UnreadCounter,
+};
+
+/// Header carrying the stream ID on Subscribe responses
+pub const STREAM_ID_HEADER: &str = "x-stream-id";
+
+pub struct BrokerService {
+    subscriptions: Arc<SubscriptionRegistry>,
+    transactions: Arc<TransactionCoordinator>,
+    history: Arc<HistoryReader>,
+    read_horizons: Arc<ReadHorizonStore>,
+    delivery: Arc<DeliveryTracker>,
+    maintenance: Arc<MaintenanceMode>,
+    guard: Arc<EnvelopeGuard>,
+    unread: Arc<UnreadCounter>,
+    /// Brought off client deadlines so handlers stop before tonic drops them
+    deadline_margin: Duration,
+    /// `GetMessageStatus` by message ID
+    status_coalescer: Arc<Coalescer<GetMessageStatusResponse, Status>>,
+    metrics: BrokerMetrics,
+}
+
+impl BrokerService {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        subscriptions: Arc<SubscriptionRegistry>,
+        transactions: Arc<TransactionCoordinator>,
+        history: Arc<HistoryReader>,
+        read_horizons: Arc<ReadHorizonStore>,
+        delivery: Arc<DeliveryTracker>,
+        maintenance: Arc<MaintenanceMode>,
+        guard: Arc<EnvelopeGuard>,
+        unread: Arc<UnreadCounter>,
+        deadline_margin: Duration,
+        status_coalescer: Arc<Coalescer<GetMessageStatusResponse, Status>>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            subscriptions,
+            transactions,
+            history,
+            read_horizons,
+            delivery,
+            maintenance,
+            guard,
+            unread,
+            deadline_margin,
+            status_coalescer,
+            metrics,
+        }
+    }
+
+    fn deadline<T>(&self, request: &Request<T>) -> Deadline {
+        Deadline::from_metadata(request.metadata(), self.deadline_margin)
+    }
+
+    async fn message_status(&self, message_id: &str) -> Result<GetMessageStatusResponse, Status> {
+        let status = self
+            .delivery
+            .message_status(message_id)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+
+        let response = match status {
+            MessageStatus::Detailed(detail) => {
+                let mut response = GetMessageStatusResponse {
+                    detail_available: true,
+                    ..Default::default()
+                };
+                for (recipient, status) in detail {
+                    *response
+                        .state_counts
+                        .entry(status.state.as_str().to_string())
+                        .or_default() += 1;
+                    response.handed_off += status.handed_off as u64;
+                    response.recipients.push(RecipientDeliveryStatus {
+                        recipient,
+                        state: status.state.as_str().to_string(),
+                        handed_off: status.handed_off,
+                        updated_at: status.updated_at,
+                    });
+                }
+                response
+            }
+            MessageStatus::Summary(summary) => GetMessageStatusResponse {
+                state_counts: summary
+                    .states
+                    .into_iter()
+                    .map(|(state, count)| (state.as_str().to_string(), count as u64))
+                    .collect(),
+                handed_off: summary.handed_off as u64,
+                ..Default::default()
+            },
+            MessageStatus::Expired => GetMessageStatusResponse {
+                expired: true,
+                ..Default::default()
+            },
+        };
+        Ok(response)
+    }
+}
+
+#[tonic::async_trait]
+impl Broker for BrokerService {
+    type SubscribeStream = FrameStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let SubscribeRequest { gateway_id, user_id } = request.into_inner();
+        if user_id.is_empty() || gateway_id.is_empty() {
+            return Err(Status::invalid_argument("user_id and gateway_id are required"));
+        }
+
+        let (stream_id, stream) = self.subscriptions.open(user_id.clone(), gateway_id);
+        debug!("Opened subscribe stream {} for {}", stream_id, user_id);
+
+        let mut response = Response::new(stream);
+        response
+            .metadata_mut()
+            .insert(STREAM_ID_HEADER, MetadataValue::from(stream_id));
+        Ok(response)
+    }
+
+    async fn keepalive(
+        &self,
+        request: Request<KeepaliveRequest>,
+    ) -> Result<Response<KeepaliveResponse>, Status> {
+        let KeepaliveRequest { stream_id, ack_nonce } = request.into_inner();
+        let alive = self.subscriptions.keepalive(stream_id, ack_nonce);
+        Ok(Response::new(KeepaliveResponse { alive }))
+    }
+
+    async fn send_transaction(
+        &self,
+        request: Request<SendTransactionRequest>,
+    ) -> Result<Response<SendTransactionResponse>, Status> {
+        self.maintenance.check("grpc").map_err(|e| e.to_status())?;
+        let deadline = self.deadline(&request);
+        let tenant_id = request
+            .extensions()
+            .get::<ApiIdentity>()
+            .and_then(|identity| identity.tenant_id.clone());
+
+        let mut messages = Vec::new();
+        let mut errors = Vec::new();
+        for (index, raw) in request.into_inner().messages.iter().enumerate() {
+            match self.guard.parse(raw) {
+                Ok(message) => messages.push(message),
+                Err(e) => errors.push(MessageError {
+                    index: index as u32,
+                    code: "MALFORMED_MESSAGE".into(),
+                    message: e.to_string(),
+                }),
+            }
+        }
+        if !errors.is_empty() {
+            return Ok(Response::new(SendTransactionResponse {
+                errors,
+                ..Default::default()
+            }));
+        }
+
+        // A tenant's key sends as that tenant only
+        if let Some(tenant_id) = &tenant_id {
+            for (index, message) in messages.iter_mut().enumerate() {
+                match &message.tenant_id {
+                    None => message.tenant_id = Some(tenant_id.clone()),
+                    Some(own) if own == tenant_id => {}
+                    Some(other) => errors.push(MessageError {
+                        index: index as u32,
+                        code: "TENANT_MISMATCH".into(),
+                        message: format!("message belongs to tenant {}", other),
+                    }),
+                }
+            }
+            if !errors.is_empty() {
+                return Ok(Response::new(SendTransactionResponse {
+                    errors,
+                    ..Default::default()
+                }));
+            }
+        }
+
+        match self.transactions.submit(messages, &deadline).await {
+            Ok(receipt) => {
+                let mut response = Response::new(SendTransactionResponse {
+                    accepted: true,
+                    transaction_id: receipt.transaction_id,
+                    message_ids: receipt.message_ids,
+                    sequences: receipt.sequences,
+                    errors: Vec::new(),
+                });
+                // With several senders, report the one closest to its limit
+                if let Some((_, quota)) = receipt.quotas.iter().min_by_key(|(_, quota)| quota.remaining) {
+                    quota.apply_metadata(response.metadata_mut());
+                }
+                Ok(response)
+            }
+            Err(TransactionError::Rejected(errors)) => Ok(Response::new(SendTransactionResponse {
+                errors: message_errors(errors),
+                ..Default::default()
+            })),
+            Err(TransactionError::RateLimited { errors, quota }) => {
+                let mut response = Response::new(SendTransactionResponse {
+                    errors: message_errors(errors),
+                    ..Default::default()
+                });
+                quota.apply_metadata(response.metadata_mut());
+                Ok(response)
+            }
+            Err(e @ (TransactionError::Empty | TransactionError::TooLarge { .. })) => {
+                Err(Status::invalid_argument(e.to_string()))
+            }
+            Err(TransactionError::Deadline(e)) => {
+                self.metrics.record_deadline_abandoned("send_transaction", e.stage);
+                Err(e.to_status())
+            }
+            Err(e) => Err(Status::unavailable(e.to_string())),
+        }
+    }
+
+    async fn fetch_history(
+        &self,
+        request: Request<FetchHistoryRequest>,
+    ) -> Result<Response<FetchHistoryResponse>, Status> {
+        let deadline = self.deadline(&request);
+        let request = request.into_inner();
+        if request.conversation_id.is_empty() {
+            return Err(Status::invalid_argument("conversation_id is required"));
+        }
+
+        let page = self
+            .history
+            .fetch(
+                ReadOperation::FetchHistory,
+                &request.conversation_id,
+                request.after_sequence,
+                request.limit as usize,
+                &deadline,
+            )
+            .await
+            .map_err(|e| match e {
+                HistoryError::Deadline(e) => {
+                    self.metrics.record_deadline_abandoned("fetch_history", e.stage);
+                    e.to_status()
+                }
+                e => Status::unavailable(e.to_string()),
+            })?;
+
+        Ok(Response::new(FetchHistoryResponse {
+            messages: page
+                .entries
+                .into_iter()
+                .map(|entry| HistoryMessage {
+                    stream_sequence: entry.stream_sequence,
+                    envelope: entry.payload,
+                })
+                .collect(),
+            next_sequence: page.next_sequence.unwrap_or(0),
+            freshness: Some(Freshness {
+                stream: page.freshness.stream,
+                from_mirror: page.freshness.from_mirror,
+                mirror_lag: page.freshness.mirror_lag,
+                from_cache: page.cached_age.is_some(),
+                cache_age_ms: page.cached_age.map_or(0, |age| age.as_millis() as u64),
+            }),
+        }))
+    }
+
+    async fn get_read_horizons(
+        &self,
+        request: Request<GetReadHorizonsRequest>,
+    ) -> Result<Response<GetReadHorizonsResponse>, Status> {
+        let requester =
+            own_user(&request).ok_or_else(|| Status::permission_denied("API key is not bound to a user"))?;
+        let request = request.into_inner();
+        if request.user_id.is_empty() {
+            return Err(Status::invalid_argument("user_id is required"));
+        }
+        if request.user_id != requester {
+            return Err(Status::permission_denied("read state of another user"));
+        }
+
+        let horizons = self
+            .read_horizons
+            .get(&request.user_id, &request.conversation_ids)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+
+        Ok(Response::new(GetReadHorizonsResponse {
+            horizons: horizons
+                .into_iter()
+                .map(|(conversation_id, sequence)| ReadHorizon {
+                    conversation_id,
+                    sequence,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_unread_counts(
+        &self,
+        request: Request<GetUnreadCountsRequest>,
+    ) -> Result<Response<GetUnreadCountsResponse>, Status> {
+        let requester =
+            own_user(&request).ok_or_else(|| Status::permission_denied("API key is not bound to a user"))?;
+        let request = request.into_inner();
+        if request.user_id.is_empty() {
+            return Err(Status::invalid_argument("user_id is required"));
+        }
+        if request.user_id != requester {
+            return Err(Status::permission_denied("read state of another user"));
+        }
+
+        let counts = self
+            .unread
+            .counts(&request.user_id, &request.conversation_ids)
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+
+        Ok(Response::new(GetUnreadCountsResponse {
+            counts: counts
+                .into_iter()
+                .map(|(conversation_id, unread)| UnreadCount {
+                    conversation_id,
+                    count: unread.count,
+                    approximate: unread.approximate,
+                    clamped: unread.clamped,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn get_message_status(
+        &self,
+        request: Request<GetMessageStatusRequest>,
+    ) -> Result<Response<GetMessageStatusResponse>, Status> {
+        let request = request.into_inner();
+        if request.message_id.is_empty() {
+            return Err(Status::invalid_argument("message_id is required"));
+        }
+
+        let response = self
+            .status_coalescer
+            .run(request.message_id.clone(), || self.message_status(&request.message_id))
+            .await?;
+        Ok(Response::new(response))
+    }
+}
+
+fn message_errors(errors: Vec<transaction::MessageError>) -> Vec<MessageError> {
+    errors
+        .into_iter()
+        .map(|e| MessageError {
+            index: e.index as u32,
+            code: e.code.into(),
+            message: e.message,
+        })
+        .collect()
+}
+
+/// The user the caller's API key was issued to, if it is bound to one
+fn own_user<T>(request: &Request<T>) -> Option<String> {
+    request
+        .extensions()
+        .get::<ApiIdentity>()
+        .and_then(|identity| identity.user_id.clone())
+}
@@ -0,0 +1,8 @@
+pub mod auth;
+pub mod grpc;
+pub mod rest;
+pub mod subscriptions;
+
+pub mod proto {
+    tonic::include_proto!("broker.v1");
+}
@@ -0,0 +1,938 @@
+use std::{collections::HashMap, sync::Arc};
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    handler::Handler,
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    auth::{rest_auth, ApiAuth, ApiIdentity},
+    subscriptions::{StreamDebugInfo, SubscriptionRegistry},
+};
+use crate::{
+    audit::{AuditEntry, AuditError, AuditLog, AuditVerifyReport},
+    build_info::{BuildInfo, VersionInfo},
+    coalesce::Coalescer,
+    config_drift::{ConfigDiff, ConfigDrift, DriftError},
+    config_override::{AppliedOverride, RuntimeOverrides},
+    gateway_interest::{GatewayInterest, InterestReconciler},
+    ingestion_pause::{ActivePause, IngestionPauses},
+    key_distribution::{KeyDistributionError, KeyDistributionRecord, KeyDistributor},
+    lock_metrics::{self, LockContention},
+    maintenance::{MaintenanceMode, MaintenanceWindow},
+    nats_probe::NatsProbe,
+    offline_quarantine::{OfflineQuarantine, PurgeMode, QuarantineError, RestoreSummary},
+    offline_transfer::{ImportSummary, OfflineTransfer, TransferError, TransferFormat},
+    pending_queue::{
+        CancelOutcome, ForceOutcome, PendingEntry, PendingPage, PendingQueue, RetryEntry, RetryQueue,
+        ScheduledEntry, ScheduledQueue,
+    },
+    profiling::{ProfileError, Profiler},
+    read_horizon::ReadHorizonStore,
+    recipient_trace::RecipientTracer,
+    tenant::{Provisioned, TenantError, TenantRecord, TenantRegistry, TenantSpec},
+    tenant_metrics::TenantMetrics,
+    trace::MessageTrace,
+    user_events::{RecentUserEvents, UserEvent},
+    volume_accounting::{ConversationVolume, VolumeAccounting},
+    warmup::CacheWarmup,
+};
+
+/// Shared state for the REST router
+#[derive(Clone)]
+pub struct RestState {
+    pub broker_id: String,
+    pub subscriptions: Arc<SubscriptionRegistry>,
+    pub read_horizons: Arc<ReadHorizonStore>,
+    pub key_distributions: Arc<KeyDistributor>,
+    pub ingestion_pauses: Arc<IngestionPauses>,
+    /// `None` when no metered tenants are configured
+    pub tenant_metrics: Option<Arc<TenantMetrics>>,
+    pub warmup: Arc<CacheWarmup>,
+    pub auth: Arc<ApiAuth>,
+    pub maintenance: Arc<MaintenanceMode>,
+    pub config_drift: Arc<ConfigDrift>,
+    pub config_overrides: Arc<RuntimeOverrides>,
+    pub offline_transfer: Arc<OfflineTransfer>,
+    pub offline_quarantine: Arc<OfflineQuarantine>,
+    pub recipient_tracer: Arc<RecipientTracer>,
+    pub profiler: Arc<Profiler>,
+    pub retries: Arc<RetryQueue>,
+    pub scheduled: Arc<ScheduledQueue>,
+    /// `/debug/state` as serialized JSON; read-only, so safe to share
+    pub debug_state_coalescer: Arc<Coalescer<Bytes, String>>,
+    pub nats_probe: Arc<NatsProbe>,
+    pub build_info: Arc<BuildInfo>,
+    pub interest: Arc<InterestReconciler>,
+    pub audit: AuditLog,
+    pub volume: Arc<VolumeAccounting>,
+    pub tenants: Arc<TenantRegistry>,
+    pub user_events: Arc<RecentUserEvents>,
+    /// Locks listed in `/debug/state`, see `lock_metrics.report_top`
+    pub lock_report_top: usize,
+}
+
+pub fn router(state: RestState) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/debug/state", get(debug_state))
+        .route("/debug/traces/:message_id", get(message_trace))
+        .route("/metrics/tenant/:tenant_id", get(tenant_metrics))
+        .route(
+            "/read-horizons/:user_id",
+            get(read_horizons).with_state(Arc::clone(&state.read_horizons)),
+        )
+        .route("/key-distributions/:message_id", get(key_distribution_status))
+        .route("/ingestion-pauses", get(ingestion_pauses))
+        .route("/maintenance", get(maintenance))
+        .route("/admin/version", get(version).with_state(Arc::clone(&state.build_info)))
+        .route("/admin/config/fingerprint", get(config_fingerprint))
+        .route("/admin/audit/verify", get(audit_verify))
+        .route("/admin/volume/top", get(volume_top))
+        .route("/admin/tenants", get(list_tenants).post(provision_tenant))
+        .route("/admin/tenants/:tenant_id", delete(delete_tenant))
+        .route("/admin/tenants/:tenant_id/restore", post(restore_tenant))
+        .route(
+            "/admin/users/:user_id/recent",
+            get(user_recent_events).with_state((Arc::clone(&state.user_events), state.audit.clone())),
+        )
+        .route("/admin/config/diff", get(config_diff))
+        // `POST /admin/users/{id}/offline:export` and the other offline actions
+        .route("/admin/users/:user_id/:action", post(user_action))
+        .route("/admin/debug/pprof/profile", get(cpu_profile))
+        .route("/admin/debug/pprof/heap", get(heap_profile))
+        .route("/admin/retries", get(list_retries))
+        // `POST /admin/retries/{id}:force`; the action suffix is part of the segment
+        .route("/admin/retries/:id", delete(cancel_retry).post(retry_action))
+        .route("/admin/scheduled", get(list_scheduled))
+        .route("/admin/scheduled/:id", delete(cancel_scheduled))
+        .layer(middleware::from_fn_with_state(Arc::clone(&state.maintenance), reject_mutations))
+        // Scopes per route are declared in `auth::ROUTE_SCOPES`
+        .layer(middleware::from_fn_with_state(Arc::clone(&state.auth), rest_auth))
+        .with_state(state)
+}
+
+async fn health() -> &'static str {
+    "ok"
+}
+
+/// 503 while startup warm-up holds readiness, see `warmup.gate_readiness`,
+/// during maintenance unless `maintenance.stay_ready` is set, or while the
+/// NATS path is degraded if `nats_probe.gate_readiness` is set
+async fn ready(State(state): State<RestState>) -> (StatusCode, String) {
+    if !state.maintenance.ready() {
+        (StatusCode::SERVICE_UNAVAILABLE, "maintenance".to_string())
+    } else if state.nats_probe.blocks_readiness() {
+        (StatusCode::SERVICE_UNAVAILABLE, "nats degraded".to_string())
+    } else if state.warmup.ready() {
+        (StatusCode::OK, "ok".to_string())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("warming {:.0}%", state.warmup.progress() * 100.0),
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct DebugState {
+    broker_id: String,
+    subscribe_streams: Vec<StreamDebugInfo>,
+    /// Most contended locks; empty unless built with `lock-metrics`
+    lock_contention: Vec<LockContention>,
+    /// Registered gateways and how their control subjects answered the last probe
+    gateway_interest: Vec<GatewayInterest>,
+}
+
+/// Polled by dashboards on every replica; identical concurrent requests share one rendering
+async fn debug_state(State(state): State<RestState>) -> Response {
+    let rendered = state
+        .debug_state_coalescer
+        .run("debug_state".to_string(), || async {
+            let debug_state = DebugState {
+                broker_id: state.broker_id.clone(),
+                subscribe_streams: state.subscriptions.debug_state(),
+                lock_contention: lock_metrics::top_contended(state.lock_report_top),
+                gateway_interest: state.interest.report(),
+            };
+            serde_json::to_vec(&debug_state)
+                .map(Bytes::from)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    match rendered {
+        Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// Retained trace of a message routed under a routing watch
+async fn message_trace(
+    State(state): State<RestState>,
+    Path(message_id): Path<String>,
+) -> Result<Json<MessageTrace>, StatusCode> {
+    state
+        .recipient_tracer
+        .retained(&message_id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Prometheus text for one metered tenant, authorized by its bearer token
+async fn tenant_metrics(
+    State(state): State<RestState>,
+    Path(tenant_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let metrics = state.tenant_metrics.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !metrics.authorize(&tenant_id, token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let body = metrics.render(&tenant_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
+#[derive(Deserialize)]
+struct ReadHorizonsQuery {
+    /// Comma-separated conversation IDs
+    conversations: String,
+}
+
+/// A user's read horizons, for that user only
+///
+/// The user is the one the caller's API key was issued to; keys not bound
+/// to a user, or bound to someone else, are refused.
+async fn read_horizons(
+    State(read_horizons): State<Arc<ReadHorizonStore>>,
+    Path(user_id): Path<String>,
+    Query(query): Query<ReadHorizonsQuery>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<HashMap<String, u64>>, StatusCode> {
+    let requester = identity
+        .and_then(|Extension(identity)| identity.user_id)
+        .ok_or(StatusCode::FORBIDDEN)?;
+    if requester != user_id {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let conversation_ids: Vec<String> = query
+        .conversations
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    read_horizons
+        .get(&user_id, &conversation_ids)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Per-member delivery accounting for a key distribution, for its sender
+///
+/// The sender is the user the caller's API key was issued to; keys not
+/// bound to a user are refused.
+async fn key_distribution_status(
+    State(state): State<RestState>,
+    Path(message_id): Path<String>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<KeyDistributionRecord>, StatusCode> {
+    let requester = identity
+        .and_then(|Extension(identity)| identity.user_id)
+        .ok_or(StatusCode::FORBIDDEN)?;
+    state
+        .key_distributions
+        .status(&message_id, &requester)
+        .await
+        .map(Json)
+        .map_err(|e| match e {
+            KeyDistributionError::NotFound(_) => StatusCode::NOT_FOUND,
+            KeyDistributionError::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        })
+}
+
+async fn ingestion_pauses(State(state): State<RestState>) -> Json<Vec<ActivePause>> {
+    Json(state.ingestion_pauses.list())
+}
+
+async fn maintenance(State(state): State<RestState>) -> Json<Option<MaintenanceWindow>> {
+    Json(state.maintenance.current())
+}
+
+#[derive(Serialize)]
+struct ConfigFingerprint {
+    broker_id: String,
+    fingerprint: String,
+    /// Runtime overrides folded into the fingerprinted config
+    overrides: Vec<AppliedOverride>,
+}
+
+/// Same values as the `broker_build_info` labels
+async fn version(State(build_info): State<Arc<BuildInfo>>) -> Json<VersionInfo> {
+    Json(build_info.version())
+}
+
+#[derive(Deserialize)]
+struct AuditVerifyQuery {
+    #[serde(default)]
+    from: u64,
+    /// Defaults to the chain head
+    to: Option<u64>,
+}
+
+/// Re-walk the audit chain over `[from, to]`; a broken link is reported in
+/// the body, not as an error status
+async fn audit_verify(
+    State(state): State<RestState>,
+    Query(query): Query<AuditVerifyQuery>,
+) -> Result<Json<AuditVerifyReport>, StatusCode> {
+    let audit = state.audit.clone();
+    tokio::task::spawn_blocking(move || audit.verify(query.from, query.to))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .map_err(|e| match e {
+            AuditError::NotConfigured => StatusCode::NOT_FOUND,
+            AuditError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        })
+}
+
+#[derive(Deserialize)]
+struct VolumeTopQuery {
+    #[serde(default = "default_volume_limit")]
+    limit: usize,
+}
+
+fn default_volume_limit() -> usize {
+    20
+}
+
+/// Conversations by delivered bytes in the current, unflushed interval
+async fn volume_top(State(state): State<RestState>, Query(query): Query<VolumeTopQuery>) -> Json<Vec<ConversationVolume>> {
+    Json(state.volume.top(query.limit.min(1000)))
+}
+
+async fn list_tenants(State(state): State<RestState>) -> Result<Json<Vec<TenantRecord>>, StatusCode> {
+    state.tenants.list().await.map(Json).map_err(tenant_status)
+}
+
+/// Provision a tenant; 201 with its API key when created, 200 without one when it already existed
+async fn provision_tenant(
+    State(state): State<RestState>,
+    identity: Option<Extension<ApiIdentity>>,
+    Json(spec): Json<TenantSpec>,
+) -> Result<(StatusCode, Json<Provisioned>), StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    let provisioned = state.tenants.provision(spec, &actor).await.map_err(tenant_status)?;
+    let status = match provisioned.api_key {
+        Some(_) => StatusCode::CREATED,
+        None => StatusCode::OK,
+    };
+    Ok((status, Json(provisioned)))
+}
+
+#[derive(Deserialize)]
+struct TenantDeleteQuery {
+    /// `hard` skips the restore grace period
+    #[serde(default)]
+    mode: PurgeMode,
+}
+
+async fn delete_tenant(
+    State(state): State<RestState>,
+    Path(tenant_id): Path<String>,
+    Query(query): Query<TenantDeleteQuery>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<StatusCode, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    state
+        .tenants
+        .delete(&tenant_id, query.mode, &actor)
+        .await
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(tenant_status)
+}
+
+async fn restore_tenant(
+    State(state): State<RestState>,
+    Path(tenant_id): Path<String>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<TenantRecord>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    state
+        .tenants
+        .restore(&tenant_id, &actor)
+        .await
+        .map(Json)
+        .map_err(tenant_status)
+}
+
+fn tenant_status(e: TenantError) -> StatusCode {
+    match e {
+        TenantError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        TenantError::Conflict(_) | TenantError::Deleted(_) => StatusCode::CONFLICT,
+        TenantError::NotFound(_) => StatusCode::NOT_FOUND,
+        TenantError::GraceExpired(_) => StatusCode::GONE,
+        TenantError::Store(_) | TenantError::Stream(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// The user's recent broker events, newest first; empty for users idle past `user_events.active_window`
+async fn user_recent_events(
+    State((user_events, audit)): State<(Arc<RecentUserEvents>, AuditLog)>,
+    Path(user_id): Path<String>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Json<Vec<UserEvent>> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    let events = user_events.recent(&user_id);
+    audit.record(AuditEntry::new(
+        actor,
+        "user_events.viewed",
+        serde_json::json!({ "user_id": user_id, "events": events.len() }),
+    ));
+    Json(events)
+}
+
+async fn config_fingerprint(State(state): State<RestState>) -> Json<ConfigFingerprint> {
+    Json(ConfigFingerprint {
+        broker_id: state.broker_id.clone(),
+        fingerprint: state.config_drift.local_fingerprint(),
+        overrides: state.config_overrides.applied(),
+    })
+}
+
+#[derive(Deserialize)]
+struct ConfigDiffQuery {
+    peer: String,
+}
+
+/// Field-by-field diff of this broker's sanitized config against `peer`'s
+async fn config_diff(
+    State(state): State<RestState>,
+    Query(query): Query<ConfigDiffQuery>,
+) -> Result<Json<ConfigDiff>, StatusCode> {
+    state
+        .config_drift
+        .diff_with(&query.peer)
+        .await
+        .map(Json)
+        .map_err(|e| match e {
+            DriftError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            DriftError::Request(_) => StatusCode::BAD_GATEWAY,
+        })
+}
+
+#[derive(Deserialize)]
+struct OfflineTransferQuery {
+    #[serde(default)]
+    format: TransferFormat,
+    /// Cursor of the last frame received, to resume a broken export
+    cursor: Option<String>,
+}
+
+/// Dispatch on the action suffix, which is part of the last segment
+async fn user_action(
+    State(state): State<RestState>,
+    Path((_, action)): Path<(String, String)>,
+    request: Request,
+) -> Response {
+    match action.as_str() {
+        "offline:export" => offline_export.call(request, state).await,
+        "offline:import" => offline_import.call(request, state).await,
+        "offline:confirm" => offline_confirm.call(request, state).await,
+        "offline:purge" => offline_purge.call(request, state).await,
+        "offline:restore" => offline_restore.call(request, state).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Stream the user's offline queue up to the current last entry
+async fn offline_export(
+    State(state): State<RestState>,
+    Path((user_id, _)): Path<(String, String)>,
+    Query(query): Query<OfflineTransferQuery>,
+) -> Result<Response, StatusCode> {
+    let cursor = state
+        .offline_transfer
+        .begin_export(&user_id, query.cursor.as_deref())
+        .await
+        .map_err(transfer_status)?;
+    let frames = state.offline_transfer.export(cursor, query.format);
+    Ok(([(header::CONTENT_TYPE, query.format.content_type())], Body::from_stream(frames)).into_response())
+}
+
+async fn offline_import(
+    State(state): State<RestState>,
+    Path((user_id, _)): Path<(String, String)>,
+    Query(query): Query<OfflineTransferQuery>,
+    body: Body,
+) -> Result<Json<ImportSummary>, StatusCode> {
+    state
+        .offline_transfer
+        .import(&user_id, query.format, body.into_data_stream())
+        .await
+        .map(Json)
+        .map_err(transfer_status)
+}
+
+#[derive(Deserialize)]
+struct OfflineConfirmRequest {
+    /// Cursor from the export's `end` frame
+    cursor: String,
+}
+
+#[derive(Serialize)]
+struct OfflineConfirmed {
+    deleted: usize,
+}
+
+/// Delete source entries once the destination has imported a complete export
+async fn offline_confirm(
+    State(state): State<RestState>,
+    Path((user_id, _)): Path<(String, String)>,
+    identity: Option<Extension<ApiIdentity>>,
+    Json(request): Json<OfflineConfirmRequest>,
+) -> Result<Json<OfflineConfirmed>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    state
+        .offline_transfer
+        .confirm(&user_id, &request.cursor, &actor)
+        .await
+        .map(|deleted| Json(OfflineConfirmed { deleted }))
+        .map_err(transfer_status)
+}
+
+#[derive(Deserialize)]
+struct OfflinePurgeRequest {
+    /// `hard` skips quarantine; only for erasure requests
+    #[serde(default)]
+    mode: PurgeMode,
+}
+
+#[derive(Serialize)]
+struct OfflinePurged {
+    purged: usize,
+}
+
+async fn offline_purge(
+    State(state): State<RestState>,
+    Path((user_id, _)): Path<(String, String)>,
+    identity: Option<Extension<ApiIdentity>>,
+    Json(request): Json<OfflinePurgeRequest>,
+) -> Result<Json<OfflinePurged>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    state
+        .offline_quarantine
+        .purge(&user_id, request.mode, &actor)
+        .await
+        .map(|purged| Json(OfflinePurged { purged }))
+        .map_err(quarantine_status)
+}
+
+/// Re-queue entries from a soft purge still within retention
+async fn offline_restore(
+    State(state): State<RestState>,
+    Path((user_id, _)): Path<(String, String)>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<RestoreSummary>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    state
+        .offline_quarantine
+        .restore(&user_id, &actor)
+        .await
+        .map(Json)
+        .map_err(quarantine_status)
+}
+
+fn quarantine_status(e: QuarantineError) -> StatusCode {
+    match e {
+        QuarantineError::Store(_) | QuarantineError::Stream(_) => StatusCode::SERVICE_UNAVAILABLE,
+        QuarantineError::Decode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfileQuery {
+    #[serde(default = "default_profile_seconds")]
+    seconds: u64,
+}
+
+fn default_profile_seconds() -> u64 {
+    15
+}
+
+/// CPU profile for `go tool pprof`; needs the `profiling` feature
+async fn cpu_profile(
+    State(state): State<RestState>,
+    Query(query): Query<ProfileQuery>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Response, (StatusCode, String)> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    let profile = state.profiler.cpu(query.seconds, &actor).await.map_err(profile_status)?;
+    Ok(profile_response("cpu.pb.gz", profile))
+}
+
+/// jemalloc allocation profile; needs the `heap-profiling` feature
+async fn heap_profile(
+    State(state): State<RestState>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Response, (StatusCode, String)> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    let profile = state.profiler.heap(&actor).await.map_err(profile_status)?;
+    Ok(profile_response("heap.pb.gz", profile))
+}
+
+fn profile_response(filename: &str, profile: Vec<u8>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        profile,
+    )
+        .into_response()
+}
+
+fn profile_status(e: ProfileError) -> (StatusCode, String) {
+    let status = match e {
+        ProfileError::Disabled => StatusCode::NOT_FOUND,
+        ProfileError::Unsupported(_) | ProfileError::HeapInactive => StatusCode::NOT_IMPLEMENTED,
+        ProfileError::Busy => StatusCode::CONFLICT,
+        ProfileError::InvalidDuration => StatusCode::BAD_REQUEST,
+        ProfileError::Cancelled | ProfileError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+#[derive(Deserialize)]
+struct PendingQuery {
+    filter: Option<String>,
+    /// `next_cursor` of the previous page
+    after: Option<String>,
+    #[serde(default = "default_pending_limit")]
+    limit: usize,
+}
+
+fn default_pending_limit() -> usize {
+    100
+}
+
+const MAX_PENDING_LIMIT: usize = 1000;
+
+fn list_pending<E: PendingEntry>(queue: &PendingQueue<E>, query: PendingQuery) -> Json<PendingPage<E>> {
+    Json(queue.list(
+        query.filter.as_deref(),
+        query.after.as_deref(),
+        query.limit.clamp(1, MAX_PENDING_LIMIT),
+    ))
+}
+
+fn cancel_pending<E: PendingEntry>(queue: &PendingQueue<E>, id: &str, actor: &str) -> Result<Json<CancelOutcome>, StatusCode> {
+    match queue.cancel(id, actor) {
+        CancelOutcome::NotFound => Err(StatusCode::NOT_FOUND),
+        outcome => Ok(Json(outcome)),
+    }
+}
+
+async fn list_retries(State(state): State<RestState>, Query(query): Query<PendingQuery>) -> Json<PendingPage<RetryEntry>> {
+    list_pending(&state.retries, query)
+}
+
+async fn cancel_retry(
+    State(state): State<RestState>,
+    Path(id): Path<String>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<CancelOutcome>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    cancel_pending(&state.retries, &id, &actor)
+}
+
+/// Only `{id}:force`: attempt the retry now
+async fn retry_action(
+    State(state): State<RestState>,
+    Path(id): Path<String>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<ForceOutcome>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    let id = id.strip_suffix(":force").ok_or(StatusCode::NOT_FOUND)?;
+    match state.retries.force(id, &actor) {
+        ForceOutcome::NotFound => Err(StatusCode::NOT_FOUND),
+        ForceOutcome::InFlight => Err(StatusCode::CONFLICT),
+        ForceOutcome::Forced => Ok(Json(ForceOutcome::Forced)),
+    }
+}
+
+async fn list_scheduled(
+    State(state): State<RestState>,
+    Query(query): Query<PendingQuery>,
+) -> Json<PendingPage<ScheduledEntry>> {
+    list_pending(&state.scheduled, query)
+}
+
+async fn cancel_scheduled(
+    State(state): State<RestState>,
+    Path(id): Path<String>,
+    identity: Option<Extension<ApiIdentity>>,
+) -> Result<Json<CancelOutcome>, StatusCode> {
+    let actor = identity.map_or_else(|| "rest".to_string(), |Extension(identity)| identity.key_id);
+    cancel_pending(&state.scheduled, &id, &actor)
+}
+
+fn transfer_status(e: TransferError) -> StatusCode {
+    match e {
+        TransferError::Store(_) => StatusCode::SERVICE_UNAVAILABLE,
+        TransferError::Incomplete { .. } => StatusCode::CONFLICT,
+        TransferError::FrameTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        TransferError::Encode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        TransferError::InvalidCursor(_) | TransferError::CursorMismatch(_) | TransferError::Frame(_) => {
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+/// 503 with the `MAINTENANCE` payload for every non-read request during maintenance
+///
+/// `:export` actions are reads despite being POSTs, so offline queues can
+/// still be moved off a broker in maintenance.
+async fn reject_mutations(
+    State(maintenance): State<Arc<MaintenanceMode>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method().is_safe() || request.uri().path().ends_with(":export") {
+        return next.run(request).await;
+    }
+    match maintenance.check("rest") {
+        Ok(()) => next.run(request).await,
+        Err(rejection) => (StatusCode::SERVICE_UNAVAILABLE, Json(rejection)).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tower::ServiceExt;
+
+    use ring::digest::{digest, SHA256};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        api::auth::Scope,
+        build_info::tests::build_info_series,
+        clock::SimClock,
+        config::{ApiKeyConfig, BrokerConfig, MaintenanceConfig},
+        metrics::BrokerMetrics,
+        nats_probe::tests::EmbeddedServer,
+        user_events::UserEventKind,
+    };
+
+    fn maintenance_router(maintenance: &Arc<MaintenanceMode>) -> Router {
+        Router::new()
+            .route("/things", get(|| async { "read" }).post(|| async { "written" }))
+            .route("/things/:id", delete(|| async { "deleted" }).post(|| async { "exported" }))
+            .layer(middleware::from_fn_with_state(Arc::clone(maintenance), reject_mutations))
+    }
+
+    async fn call(router: &Router, method: Method, uri: &str) -> (StatusCode, Bytes) {
+        let request = axum::http::Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        (status, axum::body::to_bytes(response.into_body(), 1 << 16).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn the_version_endpoint_agrees_with_build_info() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let build_info = metrics::with_local_recorder(&recorder, || {
+            Arc::new(BuildInfo::new("3f2a9c".into(), BrokerMetrics::new().unwrap()))
+        });
+        let router = Router::new().route("/admin/version", get(version).with_state(build_info));
+
+        let (status, body) = call(&router, Method::GET, "/admin/version").await;
+        assert_eq!(status, StatusCode::OK);
+        let served: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let rendered = recorder.handle().render();
+        let series = build_info_series(&rendered);
+        assert_eq!(series.len(), 1, "{}", rendered);
+        for (label, value) in &series[0].0 {
+            assert_eq!(served[label].as_str(), Some(value.as_str()), "{} differs", label);
+        }
+        let start = format!("broker_start_time_seconds {}", served["start_time"]);
+        assert!(rendered.contains(&start), "missing {} in\n{}", start, rendered);
+    }
+
+    #[tokio::test]
+    async fn mutations_get_the_maintenance_payload_while_reads_keep_working() {
+        let config = MaintenanceConfig {
+            enabled: false,
+            stay_ready: false,
+            message: None,
+        };
+        let maintenance = Arc::new(MaintenanceMode::new(
+            &config,
+            AuditLog::tracing_only(),
+            BrokerMetrics::new().unwrap(),
+        ));
+        let router = maintenance_router(&maintenance);
+
+        assert_eq!(call(&router, Method::POST, "/things").await.0, StatusCode::OK);
+
+        maintenance.enter(Some("moving streams".to_string()), Some(1_700_000_000_000), "ops");
+        let (status, body) = call(&router, Method::GET, "/things").await;
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"read"[..]));
+        assert_eq!(call(&router, Method::HEAD, "/things").await.0, StatusCode::OK);
+        let (status, body) = call(&router, Method::POST, "/things/1:export").await;
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"exported"[..]));
+
+        for (method, uri) in [
+            (Method::POST, "/things"),
+            (Method::DELETE, "/things/1"),
+            (Method::POST, "/things/1:import"),
+        ] {
+            let (status, body) = call(&router, method, uri).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                payload,
+                serde_json::json!({
+                    "code": "MAINTENANCE",
+                    "message": "moving streams",
+                    "expected_end": 1_700_000_000_000i64
+                })
+            );
+        }
+
+        maintenance.leave("ops");
+        let (status, body) = call(&router, Method::DELETE, "/things/1").await;
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"deleted"[..]));
+    }
+
+    #[tokio::test]
+    async fn recent_events_are_served_newest_first_to_admins_only_and_audited() {
+        let config = BrokerConfig::load().unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let path = std::env::temp_dir().join(format!("user-events-audit-{}", Uuid::new_v4().simple()));
+        let audit = AuditLog::open(Some(&path.to_string_lossy()), &config.audit, metrics.clone()).unwrap();
+        let key = |id: &str, secret: &str, scope| ApiKeyConfig {
+            id: id.into(),
+            sha256: hex::encode(digest(&SHA256, secret.as_bytes())),
+            scopes: vec![scope],
+            user_id: None,
+            tenant_id: None,
+            not_after: None,
+        };
+        let mut auth_config = config.api.auth.clone();
+        auth_config.enabled = true;
+        auth_config.keys = vec![key("support", "admin-secret", Scope::Admin), key("sender", "send-secret", Scope::Send)];
+        auth_config.keys_file = None;
+        let auth = Arc::new(ApiAuth::new(auth_config, audit.clone(), metrics.clone()).unwrap());
+
+        let clock = Arc::new(SimClock::new());
+        let user_events =
+            Arc::new(RecentUserEvents::new(config.user_events.clone(), metrics).with_clock(clock.clone()));
+        for (kind, message_id) in [
+            (UserEventKind::Queued, "m1"),
+            (UserEventKind::Delivered, "m1"),
+            (UserEventKind::Receipt, "m1"),
+        ] {
+            user_events.record("alice", kind, Some("dm:alice:bob"), Some(message_id));
+            clock.advance(std::time::Duration::from_millis(10));
+        }
+        let router = Router::new()
+            .route(
+                "/admin/users/:user_id/recent",
+                get(user_recent_events).with_state((user_events, audit)),
+            )
+            .layer(middleware::from_fn_with_state(auth, rest_auth));
+        let get_recent = |secret: Option<&'static str>| {
+            let mut request = axum::http::Request::builder().uri("/admin/users/alice/recent");
+            if let Some(secret) = secret {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", secret));
+            }
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(get_recent(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get_recent(Some("send-secret")).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let response = get_recent(Some("admin-secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 1 << 16).await.unwrap();
+        let served: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let kinds: Vec<&str> = served.iter().map(|event| event["kind"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["receipt", "delivered", "queued"]);
+        let times: Vec<i64> = served.iter().map(|event| event["at"].as_i64().unwrap()).collect();
+        assert!(times.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", times);
+        assert!(served.iter().all(|event| event.as_object().unwrap().len() == 4), "metadata only: {:?}", served);
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.matches("user_events.viewed").count(), 1, "{}", written);
+        assert!(written.contains("support"), "{}", written);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn read_horizons_are_served_to_their_own_user_only() {
+        let config = BrokerConfig::load().unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let key = |id: &str, secret: &str, user_id: Option<&str>| ApiKeyConfig {
+            id: id.into(),
+            sha256: hex::encode(digest(&SHA256, secret.as_bytes())),
+            scopes: vec![Scope::Subscribe],
+            user_id: user_id.map(String::from),
+            tenant_id: None,
+            not_after: None,
+        };
+        let mut auth_config = config.api.auth.clone();
+        auth_config.enabled = true;
+        auth_config.keys = vec![
+            key("alice", "alice-secret", Some("alice")),
+            key("bob", "bob-secret", Some("bob")),
+            key("service", "service-secret", None),
+        ];
+        auth_config.keys_file = None;
+        let auth = Arc::new(ApiAuth::new(auth_config, AuditLog::tracing_only(), metrics.clone()).unwrap());
+
+        let server = EmbeddedServer::start(Arc::new(SimClock::new())).await;
+        let client = async_nats::connect(&server.url).await.unwrap();
+        let store = Arc::new(ReadHorizonStore::new(
+            async_nats::jetstream::new(client).get_key_value("readhorizons").await.unwrap(),
+            Arc::new(SubscriptionRegistry::new(&config.api, metrics.clone())),
+            std::time::Duration::from_millis(250),
+            metrics,
+        ));
+        let router = Router::new()
+            .route("/read-horizons/:user_id", get(read_horizons).with_state(store))
+            .layer(middleware::from_fn_with_state(auth, rest_auth));
+        let get_horizons = |secret: &'static str| {
+            let request = axum::http::Request::builder()
+                .uri("/read-horizons/alice?conversations=")
+                .header(header::AUTHORIZATION, format!("Bearer {}", secret));
+            router.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(get_horizons("alice-secret").await.unwrap().status(), StatusCode::OK);
+        // Another user's key, and a key bound to nobody
+        assert_eq!(get_horizons("bob-secret").await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(get_horizons("service-secret").await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+}
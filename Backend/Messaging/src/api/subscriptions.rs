@@ -0,0 +1,740 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use arc_swap::ArcSwapOption;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{mpsc, Notify};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{metadata::MetadataMap, Code, Status};
+use tracing::debug;
+
+use super::proto::{DeliveryFrame, StreamKeepalive};
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::ApiConfig,
+    metrics::BrokerMetrics,
+    route_cache::RouteCache,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Header on the idle-close status telling the gateway to re-subscribe lazily
+pub const RESUBSCRIBE_HEADER: &str = "x-resubscribe";
+
+pub type FrameStream = ReceiverStream<Result<DeliveryFrame, Status>>;
+
+/// One open Subscribe stream
+pub struct StreamHandle {
+    pub id: u64,
+    pub user_id: String,
+    pub gateway_id: String,
+    buffer: Mutex<VecDeque<DeliveryFrame>>,
+    capacity: AtomicUsize,
+    notify: Notify,
+    /// Wakes a send blocked on a client that stopped reading
+    closed: Notify,
+    /// Deliveries and client-originated keepalives; acks of broker keepalives don't count
+    last_activity: AtomicI64,
+    shrunk: AtomicBool,
+    close_status: Mutex<Option<Status>>,
+    /// Broker keepalive waiting to go out ahead of the buffer; never counts against capacity
+    keepalive: Mutex<Option<DeliveryFrame>>,
+    /// Nonce of the last keepalive sent, and of the last one the client acked
+    keepalive_sent: AtomicU64,
+    keepalive_acked: AtomicU64,
+    /// Timestamps in milliseconds
+    keepalive_sent_at: AtomicI64,
+    keepalive_acked_at: AtomicI64,
+    /// When the stream was marked wedged, 0 if it isn't
+    wedged_at: AtomicI64,
+}
+
+impl StreamHandle {
+    fn touch(&self, now: i64) {
+        self.last_activity.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self, now: i64) -> Duration {
+        let idle = now - self.last_activity.load(Ordering::Relaxed);
+        Duration::from_millis(idle.max(0) as u64)
+    }
+
+    fn close(&self, status: Status) {
+        *self.close_status.lock() = Some(status);
+        self.notify.notify_one();
+        self.closed.notify_one();
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        let buffer = self.buffer.lock();
+        buffer.capacity() * std::mem::size_of::<DeliveryFrame>()
+            + buffer
+                .iter()
+                .map(|f| f.envelope.len() + f.message_id.len() + f.from.len())
+                .sum::<usize>()
+    }
+}
+
+/// Per-stream state exposed on the debug state endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamDebugInfo {
+    pub id: u64,
+    pub user_id: String,
+    pub gateway_id: String,
+    pub buffered: usize,
+    pub capacity: usize,
+    pub approx_bytes: usize,
+    pub idle_ms: u64,
+    pub shrunk: bool,
+    pub wedged: bool,
+    /// Since the client last acked a broker keepalive; `None` before the first ack
+    pub keepalive_acked_ms: Option<u64>,
+}
+
+/// Registry of open Subscribe streams with idle resource reclamation
+///
+/// Streams with no delivered messages and no client-originated keepalive
+/// for `idle_timeout` have their buffers shrunk; past `hard_timeout` they are closed with an
+/// `UNAVAILABLE` status carrying the re-subscribe header.
+///
+/// Every `subscribe_keepalive_interval` each stream also gets a keepalive
+/// frame carrying a nonce and its buffered count, sent ahead of the buffer
+/// and outside its capacity. The gateway acks the nonce with `Keepalive`
+/// once it has processed the frame, which proves the application is
+/// draining, not just the HTTP/2 connection alive. A nonce unacked after
+/// `subscribe_keepalive_timeout_ms` marks the stream wedged: deliveries skip
+/// it, so a user whose only streams are wedged counts as undelivered and
+/// falls back to the offline queue, and the user's cached route is dropped
+/// as suspect so the next fanout re-reads presence. An ack clears the
+/// mark; a stream still wedged after `subscribe_wedged_grace` is closed.
+/// Acks only prove the stream is drained, so they don't keep an otherwise
+/// unused stream from going idle.
+pub struct SubscriptionRegistry {
+    streams: DashMap<u64, Arc<StreamHandle>>,
+    by_user: DashMap<String, Vec<u64>>,
+    next_id: AtomicU64,
+    buffer_size: usize,
+    min_buffer_size: usize,
+    idle_timeout: Duration,
+    hard_timeout: Duration,
+    keepalive_interval: Duration,
+    keepalive_timeout: Duration,
+    wedged_grace: Duration,
+    /// Told about wedged streams once attached
+    routes: ArcSwapOption<RouteCache>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl SubscriptionRegistry {
+    pub fn new(config: &ApiConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            streams: DashMap::new(),
+            by_user: DashMap::new(),
+            next_id: AtomicU64::new(1),
+            buffer_size: config.subscribe_buffer_size,
+            min_buffer_size: config.subscribe_min_buffer_size,
+            idle_timeout: config.subscribe_idle_timeout,
+            hard_timeout: config.subscribe_hard_timeout,
+            keepalive_interval: config.subscribe_keepalive_interval,
+            keepalive_timeout: Duration::from_millis(config.subscribe_keepalive_timeout_ms),
+            wedged_grace: config.subscribe_wedged_grace,
+            routes: ArcSwapOption::empty(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn attach_routes(&self, routes: Arc<RouteCache>) {
+        self.routes.store(Some(routes));
+    }
+
+    /// Open a stream and spawn its forwarding task
+    pub fn open(self: &Arc<Self>, user_id: String, gateway_id: String) -> (u64, FrameStream) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = self.clock.now_millis();
+        let handle = Arc::new(StreamHandle {
+            id,
+            user_id: user_id.clone(),
+            gateway_id,
+            buffer: Mutex::new(VecDeque::with_capacity(self.buffer_size)),
+            capacity: AtomicUsize::new(self.buffer_size),
+            notify: Notify::new(),
+            closed: Notify::new(),
+            last_activity: AtomicI64::new(now),
+            shrunk: AtomicBool::new(false),
+            close_status: Mutex::new(None),
+            keepalive: Mutex::new(None),
+            keepalive_sent: AtomicU64::new(0),
+            keepalive_acked: AtomicU64::new(0),
+            keepalive_sent_at: AtomicI64::new(now),
+            keepalive_acked_at: AtomicI64::new(0),
+            wedged_at: AtomicI64::new(0),
+        });
+
+        self.streams.insert(id, Arc::clone(&handle));
+        self.by_user.entry(user_id).or_default().push(id);
+        self.metrics.update_subscribe_streams(self.streams.len() as i64);
+
+        let (tx, rx) = mpsc::channel(self.min_buffer_size.max(1));
+        let registry = Arc::clone(self);
+        spawn_traced("subscribe_forward", TaskContext::new("subscriptions"), async move {
+            registry.forward(handle, tx).await;
+        });
+
+        (id, ReceiverStream::new(rx))
+    }
+
+    /// Queue a frame on every stream of the user, returning how many accepted it
+    pub fn deliver(&self, user_id: &str, frame: &DeliveryFrame) -> usize {
+        let Some(ids) = self.by_user.get(user_id).map(|ids| ids.clone()) else {
+            return 0;
+        };
+
+        let mut delivered = 0;
+        for id in ids {
+            let Some(handle) = self.streams.get(&id).map(|h| Arc::clone(&h)) else {
+                continue;
+            };
+            if handle.wedged_at.load(Ordering::Relaxed) != 0 {
+                self.metrics.record_subscribe_wedged_skip();
+                continue;
+            }
+
+            // A message revives an idle-shrunk stream before the hard timeout
+            if handle.shrunk.swap(false, Ordering::Relaxed) {
+                handle.capacity.store(self.buffer_size, Ordering::Relaxed);
+                handle.buffer.lock().reserve(self.buffer_size);
+                self.metrics.record_subscribe_revived();
+            }
+
+            {
+                let mut buffer = handle.buffer.lock();
+                if buffer.len() >= handle.capacity.load(Ordering::Relaxed) {
+                    self.metrics.record_backpressure_event();
+                    continue;
+                }
+                buffer.push_back(frame.clone());
+            }
+
+            handle.touch(self.clock.now_millis());
+            handle.notify.notify_one();
+            delivered += 1;
+        }
+
+        delivered
+    }
+
+    /// Client keepalive: a ping when `ack_nonce` is zero, otherwise an ack of
+    /// broker keepalives up to it; returns false for unknown streams
+    pub fn keepalive(&self, stream_id: u64, ack_nonce: u64) -> bool {
+        let Some(handle) = self.streams.get(&stream_id).map(|h| Arc::clone(&h)) else {
+            return false;
+        };
+        if ack_nonce == 0 {
+            handle.touch(self.clock.now_millis());
+        } else {
+            handle.keepalive_acked.fetch_max(ack_nonce, Ordering::Relaxed);
+            handle
+                .keepalive_acked_at
+                .store(self.clock.now_millis(), Ordering::Relaxed);
+            if ack_nonce >= handle.keepalive_sent.load(Ordering::Relaxed) && handle.wedged_at.swap(0, Ordering::Relaxed) != 0 {
+                debug!("Subscribe stream {} for {} recovered", handle.id, handle.user_id);
+                self.metrics.record_subscribe_wedged("recovered");
+            }
+        }
+        true
+    }
+
+    /// Send due keepalive frames, mark streams with overdue acks wedged and
+    /// close those wedged past the grace period
+    pub fn sweep_keepalives(&self) {
+        let now = self.clock.now_millis();
+        let handles: Vec<Arc<StreamHandle>> =
+            self.streams.iter().map(|h| Arc::clone(h.value())).collect();
+
+        for handle in handles {
+            let wedged_at = handle.wedged_at.load(Ordering::Relaxed);
+            if wedged_at != 0 {
+                if now - wedged_at >= self.wedged_grace.as_millis() as i64 {
+                    handle.close(Status::unavailable("subscribe stream wedged; keepalives unanswered"));
+                    self.remove(&handle);
+                    self.metrics.record_subscribe_wedged("closed");
+                }
+                continue;
+            }
+
+            let sent = handle.keepalive_sent.load(Ordering::Relaxed);
+            let since_sent = now - handle.keepalive_sent_at.load(Ordering::Relaxed);
+            if sent > handle.keepalive_acked.load(Ordering::Relaxed) {
+                if since_sent >= self.keepalive_timeout.as_millis() as i64 {
+                    debug!("Subscribe stream {} for {} wedged", handle.id, handle.user_id);
+                    handle.wedged_at.store(now, Ordering::Relaxed);
+                    self.metrics.record_subscribe_wedged("wedged");
+                    if let Some(routes) = &*self.routes.load() {
+                        routes.invalidate(&handle.user_id);
+                    }
+                }
+                continue;
+            }
+            if since_sent >= self.keepalive_interval.as_millis() as i64 {
+                self.send_keepalive(&handle, now);
+            }
+        }
+    }
+
+    pub fn spawn_keepalive_sweeper(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let registry = Arc::clone(self);
+        // Fine enough to notice an overdue ack close to its timeout
+        let tick = (self.keepalive_timeout / 4).clamp(Duration::from_millis(100), self.keepalive_interval);
+        spawn_traced("subscribe_keepalive_sweeper", TaskContext::new("subscriptions"), async move {
+            loop {
+                registry.clock.sleep(tick).await;
+                registry.sweep_keepalives();
+            }
+        })
+    }
+
+    fn send_keepalive(&self, handle: &StreamHandle, now: i64) {
+        let nonce = handle.keepalive_sent.load(Ordering::Relaxed) + 1;
+        let frame = DeliveryFrame {
+            message_id: format!("keepalive:{}:{}", handle.id, nonce),
+            timestamp: now,
+            keepalive: Some(StreamKeepalive {
+                nonce,
+                buffered: handle.buffer.lock().len() as u32,
+                sent_at: now,
+            }),
+            ..Default::default()
+        };
+        *handle.keepalive.lock() = Some(frame);
+        handle.keepalive_sent_at.store(now, Ordering::Relaxed);
+        handle.keepalive_sent.store(nonce, Ordering::Relaxed);
+        handle.notify.notify_one();
+    }
+
+    /// Shrink idle buffers and close streams past the hard timeout
+    pub fn sweep_idle(&self) {
+        let now = self.clock.now_millis();
+        let handles: Vec<Arc<StreamHandle>> =
+            self.streams.iter().map(|h| Arc::clone(h.value())).collect();
+
+        for handle in handles {
+            let idle = handle.idle_for(now);
+
+            if idle >= self.hard_timeout {
+                let mut metadata = MetadataMap::new();
+                metadata.insert(RESUBSCRIBE_HEADER, "on-demand".parse().unwrap());
+                handle.close(Status::with_metadata(
+                    Code::Unavailable,
+                    "subscribe stream idle; re-subscribe on demand",
+                    metadata,
+                ));
+                self.remove(&handle);
+                self.metrics.record_subscribe_idle_closed();
+                continue;
+            }
+
+            if idle >= self.idle_timeout && !handle.shrunk.load(Ordering::Relaxed) {
+                let mut buffer = handle.buffer.lock();
+                if !buffer.is_empty() {
+                    continue;
+                }
+                handle.capacity.store(self.min_buffer_size, Ordering::Relaxed);
+                buffer.shrink_to(self.min_buffer_size);
+                handle.shrunk.store(true, Ordering::Relaxed);
+                self.metrics.record_subscribe_idle_shrunk();
+            }
+        }
+    }
+
+    pub fn spawn_idle_sweeper(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let registry = Arc::clone(self);
+        spawn_traced("subscribe_idle_sweeper", TaskContext::new("subscriptions"), async move {
+            loop {
+                registry.clock.sleep(interval).await;
+                registry.sweep_idle();
+            }
+        })
+    }
+
+    pub fn debug_state(&self) -> Vec<StreamDebugInfo> {
+        let now = self.clock.now_millis();
+        self.streams
+            .iter()
+            .map(|entry| {
+                let handle = entry.value();
+                // Both take the buffer lock; a struct-literal temporary would hold it
+                let buffered = handle.buffer.lock().len();
+                StreamDebugInfo {
+                    id: handle.id,
+                    user_id: handle.user_id.clone(),
+                    gateway_id: handle.gateway_id.clone(),
+                    buffered,
+                    capacity: handle.capacity.load(Ordering::Relaxed),
+                    approx_bytes: handle.buffered_bytes(),
+                    idle_ms: handle.idle_for(now).as_millis() as u64,
+                    shrunk: handle.shrunk.load(Ordering::Relaxed),
+                    wedged: handle.wedged_at.load(Ordering::Relaxed) != 0,
+                    keepalive_acked_ms: match handle.keepalive_acked_at.load(Ordering::Relaxed) {
+                        0 => None,
+                        acked_at => Some((now - acked_at).max(0) as u64),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    async fn forward(&self, handle: Arc<StreamHandle>, tx: mpsc::Sender<Result<DeliveryFrame, Status>>) {
+        'stream: loop {
+            tokio::select! {
+                _ = handle.notify.notified() => {}
+                _ = tx.closed() => break 'stream,
+            }
+
+            let close_status = handle.close_status.lock().take();
+            if let Some(status) = close_status {
+                let _ = tx.send(Err(status)).await;
+                break 'stream;
+            }
+
+            loop {
+                // A keepalive set while a send was blocked still goes out next
+                let keepalive = handle.keepalive.lock().take();
+                let Some(frame) = keepalive.or_else(|| handle.buffer.lock().pop_front()) else {
+                    break;
+                };
+                if !Self::send(&handle, &tx, frame).await {
+                    break 'stream;
+                }
+            }
+        }
+
+        debug!("Subscribe stream {} for {} ended", handle.id, handle.user_id);
+        self.remove(&handle);
+    }
+
+    /// False once the client is gone, or when the stream is closed while the
+    /// client isn't reading; a wedged client would otherwise hold the send forever
+    async fn send(handle: &StreamHandle, tx: &mpsc::Sender<Result<DeliveryFrame, Status>>, frame: DeliveryFrame) -> bool {
+        tokio::select! {
+            sent = tx.send(Ok(frame)) => sent.is_ok(),
+            _ = handle.closed.notified() => {
+                if let Some(status) = handle.close_status.lock().take() {
+                    let _ = tx.try_send(Err(status));
+                }
+                false
+            }
+        }
+    }
+
+    fn remove(&self, handle: &StreamHandle) {
+        if self.streams.remove(&handle.id).is_none() {
+            return;
+        }
+        self.by_user.remove_if_mut(&handle.user_id, |_, ids| {
+            ids.retain(|id| *id != handle.id);
+            ids.is_empty()
+        });
+        self.metrics.update_subscribe_streams(self.streams.len() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        route_cache::{RouteLookupError, UserLookup, UserResolver, UserRoute},
+    };
+
+    const IDLE: Duration = Duration::from_secs(60);
+    const HARD: Duration = Duration::from_secs(300);
+    const KEEPALIVE: Duration = Duration::from_secs(15);
+    const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+    const GRACE: Duration = Duration::from_secs(30);
+    const MS: Duration = Duration::from_millis(1);
+
+    fn registry() -> (Arc<SubscriptionRegistry>, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().api;
+        config.subscribe_buffer_size = 64;
+        config.subscribe_min_buffer_size = 4;
+        config.subscribe_idle_timeout = IDLE;
+        config.subscribe_hard_timeout = HARD;
+        config.subscribe_keepalive_interval = KEEPALIVE;
+        config.subscribe_keepalive_timeout_ms = ACK_TIMEOUT.as_millis() as u64;
+        config.subscribe_wedged_grace = GRACE;
+        let clock = Arc::new(SimClock::new());
+        let registry = SubscriptionRegistry::new(&config, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (Arc::new(registry), clock)
+    }
+
+    fn frame(message_id: &str) -> DeliveryFrame {
+        DeliveryFrame {
+            message_id: message_id.into(),
+            from: "bob".into(),
+            envelope: b"{}".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn state(registry: &SubscriptionRegistry, id: u64) -> StreamDebugInfo {
+        registry.debug_state().into_iter().find(|s| s.id == id).unwrap()
+    }
+
+    #[tokio::test]
+    async fn idle_streams_shrink_their_buffers() {
+        let (registry, clock) = registry();
+        let (id, _stream) = registry.open("alice".into(), "gw-1".into());
+        assert_eq!(state(&registry, id).capacity, 64);
+
+        clock.advance(IDLE - Duration::from_secs(1));
+        registry.sweep_idle();
+        assert!(!state(&registry, id).shrunk);
+
+        clock.advance(Duration::from_secs(1));
+        registry.sweep_idle();
+        let shrunk = state(&registry, id);
+        assert!(shrunk.shrunk);
+        assert_eq!(shrunk.capacity, 4);
+    }
+
+    #[tokio::test]
+    async fn hard_timeout_closes_with_the_resubscribe_status() {
+        let (registry, clock) = registry();
+        let (_, mut stream) = registry.open("alice".into(), "gw-1".into());
+
+        clock.advance(HARD);
+        registry.sweep_idle();
+        assert!(registry.debug_state().is_empty());
+
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.metadata().get(RESUBSCRIBE_HEADER).unwrap(), "on-demand");
+    }
+
+    #[tokio::test]
+    async fn a_message_just_before_the_hard_timeout_revives_the_stream() {
+        let (registry, clock) = registry();
+        let (id, mut stream) = registry.open("alice".into(), "gw-1".into());
+        clock.advance(IDLE);
+        registry.sweep_idle();
+        assert!(state(&registry, id).shrunk);
+
+        clock.advance(HARD - IDLE - Duration::from_secs(1));
+        assert_eq!(registry.deliver("alice", &frame("m1")), 1);
+        let revived = state(&registry, id);
+        assert!(!revived.shrunk);
+        assert_eq!(revived.capacity, 64);
+
+        clock.advance(Duration::from_secs(1));
+        registry.sweep_idle();
+        assert_eq!(stream.next().await.unwrap().unwrap().message_id, "m1");
+        assert_eq!(registry.debug_state().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_pings_reset_the_idle_timers() {
+        let (registry, clock) = registry();
+        let (id, _stream) = registry.open("alice".into(), "gw-1".into());
+
+        clock.advance(HARD - Duration::from_secs(1));
+        assert!(registry.keepalive(id, 0));
+        clock.advance(Duration::from_secs(1));
+        registry.sweep_idle();
+
+        let pinged = state(&registry, id);
+        assert!(!pinged.shrunk);
+        assert_eq!(pinged.idle_ms, 1_000);
+    }
+
+    #[tokio::test]
+    async fn debug_state_shows_memory_reclaimed_by_shrinking() {
+        let (registry, clock) = registry();
+        let (id, _stream) = registry.open("alice".into(), "gw-1".into());
+        let full = state(&registry, id).approx_bytes;
+
+        clock.advance(IDLE);
+        registry.sweep_idle();
+        assert!(state(&registry, id).approx_bytes < full);
+    }
+
+    /// Counts the presence reads behind a route cache
+    #[derive(Default)]
+    struct CountingResolver {
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl UserResolver for CountingResolver {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(UserLookup::Found(UserRoute { presence: None }))
+        }
+    }
+
+    /// Read frames as a gateway would until the next broker keepalive
+    async fn next_keepalive(stream: &mut FrameStream) -> StreamKeepalive {
+        loop {
+            if let Some(keepalive) = stream.next().await.unwrap().unwrap().keepalive {
+                return keepalive;
+            }
+        }
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_client_that_stops_acking_is_wedged_skipped_and_closed_after_the_grace() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let (registry, clock) = registry();
+        let resolver = Arc::new(CountingResolver::default());
+        let routes = Arc::new(RouteCache::new(
+            resolver.clone(),
+            &BrokerConfig::load().unwrap().routing,
+            BrokerMetrics::new().unwrap(),
+        ));
+        registry.attach_routes(routes.clone());
+        let (id, mut stream) = registry.open("alice".into(), "gw-1".into());
+
+        // A healthy client acks each keepalive it processes
+        clock.advance(KEEPALIVE);
+        registry.sweep_keepalives();
+        let keepalive = next_keepalive(&mut stream).await;
+        assert_eq!((keepalive.nonce, keepalive.buffered), (1, 0));
+        assert!(registry.keepalive(id, keepalive.nonce));
+        routes.lookup("alice").await.unwrap();
+
+        // Then it stops answering, though the stream stays open
+        clock.advance(KEEPALIVE);
+        registry.sweep_keepalives();
+        assert_eq!(next_keepalive(&mut stream).await.nonce, 2);
+        clock.advance(ACK_TIMEOUT - MS);
+        registry.sweep_keepalives();
+        assert!(!state(&registry, id).wedged);
+        assert_eq!(registry.deliver("alice", &frame("m1")), 1);
+        settle().await;
+
+        clock.advance(MS);
+        registry.sweep_keepalives();
+        assert!(state(&registry, id).wedged);
+        // Nothing accepts the message, so the caller queues it offline
+        assert_eq!(registry.deliver("alice", &frame("m2")), 0);
+        // The route is suspect: the next fanout reads presence again
+        routes.lookup("alice").await.unwrap();
+        assert_eq!(resolver.reads.load(Ordering::Relaxed), 2);
+
+        // No further keepalives go to a wedged stream
+        clock.advance(GRACE - MS);
+        registry.sweep_keepalives();
+        assert_eq!(registry.debug_state().len(), 1);
+        clock.advance(MS);
+        registry.sweep_keepalives();
+        assert!(registry.debug_state().is_empty());
+        assert_eq!(registry.deliver("alice", &frame("m3")), 0);
+
+        assert_eq!(stream.next().await.unwrap().unwrap().message_id, "m1");
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(stream.next().await.is_none());
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_subscribe_wedged_total{event="wedged"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_subscribe_wedged_total{event="closed"} 1"#), "{}", rendered);
+        assert!(rendered.contains("broker_subscribe_wedged_skips_total 1"), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn a_late_ack_recovers_a_wedged_stream_but_keeps_it_idle() {
+        let (registry, clock) = registry();
+        let (id, mut stream) = registry.open("alice".into(), "gw-1".into());
+        // A second stream of the same user keeps delivering while the first is wedged
+        let (other_id, mut other) = registry.open("alice".into(), "gw-2".into());
+
+        clock.advance(KEEPALIVE);
+        registry.sweep_keepalives();
+        let first = next_keepalive(&mut stream).await;
+        assert!(registry.keepalive(id, first.nonce));
+        assert!(registry.keepalive(other_id, next_keepalive(&mut other).await.nonce));
+
+        clock.advance(KEEPALIVE);
+        registry.sweep_keepalives();
+        let second = next_keepalive(&mut stream).await;
+        assert!(registry.keepalive(other_id, next_keepalive(&mut other).await.nonce));
+        clock.advance(ACK_TIMEOUT);
+        registry.sweep_keepalives();
+        assert!(state(&registry, id).wedged);
+        assert_eq!(registry.deliver("alice", &frame("m1")), 1);
+
+        // A repeated ack of an older nonce doesn't clear the mark
+        clock.advance(GRACE / 2);
+        assert!(registry.keepalive(id, first.nonce));
+        assert!(state(&registry, id).wedged);
+        assert!(registry.keepalive(id, second.nonce));
+        let recovered = state(&registry, id);
+        assert!(!recovered.wedged);
+        assert_eq!(recovered.keepalive_acked_ms, Some(0));
+        // Acks prove draining, not use: the stream has been idle since it opened
+        assert_eq!(recovered.idle_ms, (KEEPALIVE * 2 + ACK_TIMEOUT + GRACE / 2).as_millis() as u64);
+        assert_eq!(registry.deliver("alice", &frame("m2")), 2);
+
+        // Past the grace the recovered stream stays open
+        clock.advance(GRACE);
+        registry.sweep_keepalives();
+        assert_eq!(registry.debug_state().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keepalives_go_ahead_of_a_full_buffer_without_using_its_capacity() {
+        let (registry, clock) = registry();
+        let (id, mut stream) = registry.open("alice".into(), "gw-1".into());
+
+        // The client stops reading: its channel fills, then the buffer does
+        let mut queued = 0;
+        loop {
+            settle().await;
+            if registry.deliver("alice", &frame(&format!("m{}", queued))) == 0 {
+                break;
+            }
+            queued += 1;
+        }
+        let buffered = state(&registry, id).buffered;
+        assert_eq!(buffered, 64);
+
+        clock.advance(KEEPALIVE);
+        registry.sweep_keepalives();
+        settle().await;
+        assert_eq!(state(&registry, id).buffered, buffered, "the keepalive isn't buffered");
+        assert_eq!(registry.deliver("alice", &frame("late")), 0, "nor does it free or use capacity");
+
+        // Frames already handed to the channel, and the one blocked on it, come first
+        let in_flight = queued - buffered;
+        for sequence in 0..in_flight {
+            assert_eq!(stream.next().await.unwrap().unwrap().message_id, format!("m{}", sequence));
+        }
+        let keepalive = stream.next().await.unwrap().unwrap().keepalive.unwrap();
+        assert_eq!(keepalive.buffered as usize, buffered);
+        assert_eq!(stream.next().await.unwrap().unwrap().message_id, format!("m{}", in_flight));
+    }
+}
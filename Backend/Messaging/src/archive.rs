@@ -0,0 +1,291 @@
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::ArchiveConfig,
+    message::types::{MessageEnvelope, MessageType},
+    metrics::BrokerMetrics,
+};
+
+struct CachedState {
+    archived: bool,
+    fetched_at: Instant,
+}
+
+struct ArchiveCache {
+    entries: LruCache<String, CachedState>,
+    /// Bumped by every archive or unarchive, under the same lock
+    generation: u64,
+}
+
+/// Archived conversations, rejected at ingress with `CONVERSATION_ARCHIVED`
+///
+/// Archive state lives in KV under `archived.{base64url(conversation_id)}`
+/// and is cached in a bounded LRU, active conversations included, for
+/// `archive.cache_ttl`. Archive and unarchive control events update the
+/// cache on every broker as they are applied, so an unarchive takes effect
+/// on the next message. A KV lookup that raced one of those events is not
+/// cached. Message types in `archive.allowed_types` (admin notices,
+/// receipts) still flow into archived conversations.
+pub struct ArchivedConversations {
+    kv: kv::Store,
+    cache: Mutex<ArchiveCache>,
+    allowed: HashSet<MessageType>,
+    ttl: Duration,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl ArchivedConversations {
+    pub fn new(kv: kv::Store, config: &ArchiveConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        Self {
+            kv,
+            cache: Mutex::new(ArchiveCache {
+                entries: LruCache::new(NonZeroUsize::new(config.cache_size.max(1)).unwrap()),
+                generation: 0,
+            }),
+            allowed: config.allowed_types.iter().copied().collect(),
+            ttl: config.cache_ttl,
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether the envelope targets an archived conversation and isn't allow-listed
+    pub async fn rejects(&self, envelope: &MessageEnvelope) -> Result<bool, ArchiveError> {
+        if self.allowed.contains(&envelope.message_type) {
+            return Ok(false);
+        }
+        let archived = self.is_archived(&envelope.conversation_id()).await?;
+        if archived {
+            self.metrics.record_archived_rejection();
+        }
+        Ok(archived)
+    }
+
+    pub async fn is_archived(&self, conversation_id: &str) -> Result<bool, ArchiveError> {
+        let generation = {
+            let mut cache = self.cache.lock();
+            if let Some(cached) = cache.entries.get(conversation_id) {
+                if self.clock.now_instant().saturating_duration_since(cached.fetched_at) < self.ttl {
+                    self.metrics.record_archive_cache_lookup("hit");
+                    return Ok(cached.archived);
+                }
+            }
+            cache.generation
+        };
+        self.metrics.record_archive_cache_lookup("miss");
+
+        let archived = self
+            .kv
+            .get(archive_key(conversation_id))
+            .await
+            .map_err(|e| ArchiveError(e.to_string()))?
+            .is_some();
+
+        let mut cache = self.cache.lock();
+        // An archive event during the lookup knows better than KV read before it
+        if cache.generation == generation {
+            cache.entries.put(
+                conversation_id.to_string(),
+                CachedState {
+                    archived,
+                    fetched_at: self.clock.now_instant(),
+                },
+            );
+            self.metrics.update_archive_cache_size(cache.entries.len());
+        }
+        Ok(archived)
+    }
+
+    /// Apply an archive or unarchive event; every broker runs this
+    pub async fn set_archived(&self, conversation_id: &str, archived: bool, actor: &str) -> Result<(), ArchiveError> {
+        self.store_local(conversation_id, archived);
+
+        // Every broker writes the same value, so the puts are idempotent
+        let key = archive_key(conversation_id);
+        if archived {
+            self.kv
+                .put(key, actor.to_string().into())
+                .await
+                .map_err(|e| ArchiveError(e.to_string()))?;
+        } else {
+            self.kv.delete(key).await.map_err(|e| ArchiveError(e.to_string()))?;
+        }
+
+        self.audit.record(AuditEntry::new(
+            actor,
+            if archived { "conversation.archived" } else { "conversation.unarchived" },
+            json!({ "conversation_id": conversation_id }),
+        ));
+        Ok(())
+    }
+
+    fn store_local(&self, conversation_id: &str, archived: bool) {
+        let mut cache = self.cache.lock();
+        cache.generation += 1;
+        cache.entries.put(
+            conversation_id.to_string(),
+            CachedState {
+                archived,
+                fetched_at: self.clock.now_instant(),
+            },
+        );
+        let size = cache.entries.len();
+        drop(cache);
+        self.metrics.update_archive_cache_size(size);
+    }
+}
+
+fn archive_key(conversation_id: &str) -> String {
+    format!("archived.{}", URL_SAFE_NO_PAD.encode(conversation_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("archive store error: {0}")]
+pub struct ArchiveError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_nats::jetstream;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{config::BrokerConfig, message::types::EncryptedPayload};
+
+    const CONVERSATION: &str = "dm:alice:bob";
+
+    #[test]
+    fn keys_are_single_tokens_whatever_the_conversation_id() {
+        for conversation_id in [CONVERSATION, "group.with.dots", "tenant/room *"] {
+            let key = archive_key(conversation_id);
+            let token = key.strip_prefix("archived.").unwrap();
+            assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "{}", key);
+        }
+        assert_ne!(archive_key("a.b"), archive_key("a_b"));
+    }
+
+    fn message(message_type: MessageType) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        MessageEnvelope::new(message_type, "alice".into(), vec!["bob".into()], payload)
+    }
+
+    async fn bucket() -> kv::Store {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        jetstream::new(async_nats::connect(url).await.unwrap())
+            .create_key_value(kv::Config {
+                bucket: format!("archive-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+    }
+
+    /// One broker's view of the shared bucket
+    fn broker(kv: &kv::Store) -> Arc<ArchivedConversations> {
+        let mut config = BrokerConfig::load().unwrap().archive;
+        config.cache_ttl = Duration::from_secs(3600);
+        Arc::new(ArchivedConversations::new(
+            kv.clone(),
+            &config,
+            AuditLog::tracing_only(),
+            BrokerMetrics::new().unwrap(),
+        ))
+    }
+
+    /// The archive tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+    /// `cargo test -- --ignored archive`
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn archived_conversations_reject_all_but_the_allowed_kinds() {
+        let archived = broker(&bucket().await);
+        assert!(!archived.rejects(&message(MessageType::TextMessage)).await.unwrap());
+
+        archived.set_archived(CONVERSATION, true, "owner").await.unwrap();
+        for rejected in [MessageType::TextMessage, MessageType::Typing] {
+            assert!(archived.rejects(&message(rejected)).await.unwrap(), "{:?}", rejected);
+        }
+        for allowed in [MessageType::SystemMessage, MessageType::Delivered, MessageType::Read] {
+            assert!(!archived.rejects(&message(allowed)).await.unwrap(), "{:?}", allowed);
+        }
+
+        // Other conversations are untouched
+        let mut other = message(MessageType::TextMessage);
+        other.to = vec!["carol".into()];
+        assert!(!archived.rejects(&other).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn unarchive_takes_effect_on_every_broker_at_once() {
+        let kv = bucket().await;
+        let (a, b) = (broker(&kv), broker(&kv));
+        a.set_archived(CONVERSATION, true, "owner").await.unwrap();
+        b.set_archived(CONVERSATION, true, "owner").await.unwrap();
+        assert!(a.is_archived(CONVERSATION).await.unwrap());
+        assert!(b.is_archived(CONVERSATION).await.unwrap());
+
+        // Both have the archived state cached for an hour; the event overrides it
+        a.set_archived(CONVERSATION, false, "owner").await.unwrap();
+        b.set_archived(CONVERSATION, false, "owner").await.unwrap();
+        assert!(!a.rejects(&message(MessageType::TextMessage)).await.unwrap());
+        assert!(!b.rejects(&message(MessageType::TextMessage)).await.unwrap());
+
+        // A broker that never saw either event reads KV
+        assert!(!broker(&kv).is_archived(CONVERSATION).await.unwrap());
+        a.set_archived(CONVERSATION, true, "owner").await.unwrap();
+        assert!(broker(&kv).is_archived(CONVERSATION).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn an_event_racing_a_cold_lookup_wins() {
+        let kv = bucket().await;
+        for round in 0..50 {
+            let conversation_id = format!("dm:alice:user-{}", round);
+            let archive = round % 2 == 0;
+            let archived = broker(&kv);
+            if !archive {
+                archived.set_archived(&conversation_id, true, "owner").await.unwrap();
+                archived.cache.lock().entries.clear();
+            }
+
+            // A message's lookup is in flight when the event lands
+            let lookup = tokio::spawn({
+                let archived = archived.clone();
+                let conversation_id = conversation_id.clone();
+                async move { archived.is_archived(&conversation_id).await.unwrap() }
+            });
+            archived.set_archived(&conversation_id, archive, "owner").await.unwrap();
+            lookup.await.unwrap();
+
+            // Whatever the in-flight lookup saw, the next message sees the event
+            assert_eq!(archived.is_archived(&conversation_id).await.unwrap(), archive, "round {}", round);
+        }
+    }
+}
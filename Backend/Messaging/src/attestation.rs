@@ -0,0 +1,696 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use async_nats::jetstream::kv;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use dashmap::DashMap;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::AttestationConfig,
+    message::types::{MessageEnvelope, SenderAttestation},
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+};
+
+const STORED_KEY_CODEC: VersionedCodec<StoredGatewayKey> = VersionedCodec::new(schema::GATEWAY_KEY, 1, &[]);
+
+/// Keys registered for one gateway
+struct GatewayKeys {
+    current: VerifyingKey,
+    /// Previous key, still accepted until the rotation grace period ends
+    previous: Option<(VerifyingKey, Instant)>,
+}
+
+/// A gateway's keys as persisted in `attestation.key_bucket`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredGatewayKey {
+    gateway_id: String,
+    public_key: String,
+    #[serde(default)]
+    previous_key: Option<String>,
+    /// Timestamp in milliseconds until which `previous_key` is accepted
+    #[serde(default)]
+    previous_valid_until: Option<i64>,
+}
+
+/// Verifies gateway-signed sender identities on ingress
+///
+/// Gateway keys come from `attestation.gateway_keys` and from
+/// `RegisterGatewayKey` commands, which must be signed by one of
+/// `attestation.registration_keys`. With a store attached, registered
+/// keys are written to `attestation.key_bucket` and `load` restores them
+/// at startup, taking precedence over configured keys as the newer
+/// registration. An attestation only verifies for messages consumed from
+/// the gateway it names, so one gateway's key can't vouch for traffic
+/// arriving through another.
+pub struct SenderAttestor {
+    keys: DashMap<String, GatewayKeys>,
+    registration_keys: Vec<VerifyingKey>,
+    /// IDs of authorized key commands, with the time their timestamp leaves the replay window
+    seen_commands: Mutex<HashMap<String, i64>>,
+    store: Option<kv::Store>,
+    trusted_sources: HashSet<String>,
+    enabled: bool,
+    replay_window_ms: i64,
+    rotation_grace: Duration,
+    verified: AtomicU64,
+    unverified: AtomicU64,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl SenderAttestor {
+    pub fn new(config: &AttestationConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        let keys = DashMap::new();
+        for (gateway_id, public_key) in &config.gateway_keys {
+            match decode_key(public_key) {
+                Ok(current) => {
+                    keys.insert(gateway_id.clone(), GatewayKeys { current, previous: None });
+                }
+                Err(e) => warn!("Ignoring configured key of gateway {}: {}", gateway_id, e),
+            }
+        }
+        let registration_keys = config
+            .registration_keys
+            .iter()
+            .filter_map(|public_key| match decode_key(public_key) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("Ignoring configured registration key: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            keys,
+            registration_keys,
+            seen_commands: Mutex::new(HashMap::new()),
+            store: None,
+            trusted_sources: config.trusted_sources.iter().cloned().collect(),
+            enabled: config.enabled,
+            replay_window_ms: config.replay_window.as_millis() as i64,
+            rotation_grace: config.key_rotation_grace,
+            verified: AtomicU64::new(0),
+            unverified: AtomicU64::new(0),
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Persist registered keys to `kv`
+    pub fn with_store(mut self, kv: kv::Store) -> Self {
+        self.store = Some(kv);
+        self
+    }
+
+    /// Restore keys registered before a restart; returns how many gateways were loaded
+    pub async fn load(&self) -> Result<usize, AttestationError> {
+        let Some(store) = &self.store else {
+            return Ok(0);
+        };
+        let mut names = store.keys().await.map_err(|e| AttestationError::Store(e.to_string()))?;
+        let now_ms = self.clock.now_millis();
+        let mut loaded = 0;
+        while let Some(name) = names.next().await {
+            let name = name.map_err(|e| AttestationError::Store(e.to_string()))?;
+            let Some(value) = store.get(&name).await.map_err(|e| AttestationError::Store(e.to_string()))? else {
+                continue;
+            };
+            let stored = match STORED_KEY_CODEC.decode(&value) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    warn!("Skipping unreadable gateway key {}: {}", name, e);
+                    continue;
+                }
+            };
+            let Ok(current) = decode_key(&stored.public_key) else {
+                warn!("Skipping malformed stored key of gateway {}", stored.gateway_id);
+                continue;
+            };
+            // Remaining grace carries over as wall-clock time
+            let previous = match (stored.previous_key.as_deref().map(decode_key), stored.previous_valid_until) {
+                (Some(Ok(previous)), Some(until)) if until > now_ms => {
+                    Some((previous, self.clock.now_instant() + Duration::from_millis((until - now_ms) as u64)))
+                }
+                _ => None,
+            };
+            self.keys.insert(stored.gateway_id, GatewayKeys { current, previous });
+            loaded += 1;
+        }
+        info!("Loaded {} registered gateway keys", loaded);
+        Ok(loaded)
+    }
+
+    /// Check an operator's signature over a key registration or removal
+    ///
+    /// `public_key` is empty for a removal. The signature covers the
+    /// command ID and timestamp too, commands outside the replay window are
+    /// refused, and a command ID is only honoured once while it is inside
+    /// the window, so a captured registration can't be replayed to roll a
+    /// gateway back to an old key.
+    pub fn authorize_registration(
+        &self,
+        command_id: &str,
+        gateway_id: &str,
+        public_key: &str,
+        timestamp: i64,
+        signature: &str,
+    ) -> Result<(), AttestationError> {
+        if !self.within_window(timestamp) {
+            return Err(AttestationError::UnauthorizedRegistration);
+        }
+        let signature = STANDARD
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(AttestationError::UnauthorizedRegistration)?;
+        let payload = registration_payload(command_id, gateway_id, public_key, timestamp);
+        if !self
+            .registration_keys
+            .iter()
+            .any(|key| key.verify(&payload, &signature).is_ok())
+        {
+            return Err(AttestationError::UnauthorizedRegistration);
+        }
+
+        // Only signed commands are remembered, so forgeries can't fill the cache
+        let now_ms = self.clock.now_millis();
+        let mut seen = self.seen_commands.lock();
+        seen.retain(|_, until| *until >= now_ms);
+        if seen.contains_key(command_id) {
+            return Err(AttestationError::ReplayedRegistration);
+        }
+        seen.insert(command_id.to_string(), timestamp.saturating_add(self.replay_window_ms));
+        Ok(())
+    }
+
+    /// Whether `timestamp` is within the replay window of now, either side;
+    /// the difference can't overflow whatever the sender put there
+    fn within_window(&self, timestamp: i64) -> bool {
+        self.clock.now_millis().abs_diff(timestamp) <= self.replay_window_ms as u64
+    }
+
+    /// Register or rotate a gateway's public key (base64 encoded, 32 bytes)
+    ///
+    /// Callers authorize the registration first; see `authorize_registration`.
+    pub async fn register_key(&self, gateway_id: &str, public_key: &str) -> Result<(), AttestationError> {
+        let key = decode_key(public_key)?;
+        let grace_ms = self.rotation_grace.as_millis() as i64;
+
+        let (rotated, stored) = match self.keys.get_mut(gateway_id) {
+            Some(mut keys) => {
+                if keys.current == key {
+                    return Ok(());
+                }
+                let previous = std::mem::replace(&mut keys.current, key);
+                keys.previous = Some((previous, self.clock.now_instant() + self.rotation_grace));
+                let stored = StoredGatewayKey {
+                    gateway_id: gateway_id.to_string(),
+                    public_key: public_key.to_string(),
+                    previous_key: Some(STANDARD.encode(previous.as_bytes())),
+                    previous_valid_until: Some(self.clock.now_millis() + grace_ms),
+                };
+                (true, stored)
+            }
+            None => {
+                self.keys.insert(
+                    gateway_id.to_string(),
+                    GatewayKeys {
+                        current: key,
+                        previous: None,
+                    },
+                );
+                let stored = StoredGatewayKey {
+                    gateway_id: gateway_id.to_string(),
+                    public_key: public_key.to_string(),
+                    previous_key: None,
+                    previous_valid_until: None,
+                };
+                (false, stored)
+            }
+        };
+        self.persist(&stored).await?;
+
+        info!(
+            "Gateway {} signing key {}",
+            gateway_id,
+            if rotated { "rotated" } else { "registered" }
+        );
+        self.audit.record(AuditEntry::new(
+            gateway_id,
+            if rotated { "attestation.key_rotated" } else { "attestation.key_registered" },
+            serde_json::json!({ "public_key": public_key }),
+        ));
+
+        Ok(())
+    }
+
+    pub async fn remove_key(&self, gateway_id: &str) -> Result<(), AttestationError> {
+        if let Some(store) = &self.store {
+            store
+                .purge(stored_key_name(gateway_id))
+                .await
+                .map_err(|e| AttestationError::Store(e.to_string()))?;
+        }
+        if self.keys.remove(gateway_id).is_some() {
+            self.audit.record(AuditEntry::new(
+                gateway_id,
+                "attestation.key_removed",
+                serde_json::Value::Null,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn persist(&self, stored: &StoredGatewayKey) -> Result<(), AttestationError> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let value = STORED_KEY_CODEC
+            .encode(stored)
+            .map_err(|e| AttestationError::Store(e.to_string()))?;
+        store
+            .put(stored_key_name(&stored.gateway_id), value.into())
+            .await
+            .map_err(|e| AttestationError::Store(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Verify the envelope's sender attestation
+    ///
+    /// `source` is the gateway the message was consumed from; trusted sources
+    /// skip verification during the migration period.
+    pub fn verify(&self, source: &str, envelope: &MessageEnvelope) -> Result<(), AttestationError> {
+        if !self.enabled || self.trusted_sources.contains(source) {
+            self.record_unverified();
+            return Ok(());
+        }
+
+        let result = match &envelope.attestation {
+            Some(attestation) => self.check(source, &envelope.from, attestation),
+            None => Err(AttestationError::Missing),
+        };
+
+        match &result {
+            Ok(()) => {
+                self.verified.fetch_add(1, Ordering::Relaxed);
+                self.update_unverified_share();
+            }
+            Err(e) => {
+                let gateway_id = envelope
+                    .attestation
+                    .as_ref()
+                    .map(|a| a.gateway_id.as_str())
+                    .unwrap_or(source);
+                self.reject(gateway_id, &envelope.from, e);
+            }
+        }
+
+        result
+    }
+
+    fn check(&self, source: &str, sender_id: &str, attestation: &SenderAttestation) -> Result<(), AttestationError> {
+        if attestation.gateway_id != source {
+            return Err(AttestationError::GatewayMismatch);
+        }
+        if !self.within_window(attestation.timestamp) {
+            return Err(AttestationError::Expired);
+        }
+
+        let signature = STANDARD
+            .decode(&attestation.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(AttestationError::MalformedSignature)?;
+
+        let payload = signing_payload(sender_id, &attestation.connection_id, attestation.timestamp);
+
+        let keys = self
+            .keys
+            .get(&attestation.gateway_id)
+            .ok_or(AttestationError::UnknownGateway)?;
+
+        if keys.current.verify(&payload, &signature).is_ok() {
+            return Ok(());
+        }
+
+        match keys.previous {
+            Some((previous, valid_until)) if self.clock.now_instant() < valid_until => previous
+                .verify(&payload, &signature)
+                .map_err(|_| AttestationError::BadSignature),
+            _ => Err(AttestationError::BadSignature),
+        }
+    }
+
+    fn reject(&self, gateway_id: &str, sender_id: &str, error: &AttestationError) {
+        self.metrics.record_attestation_rejected(error.reason());
+        warn!(
+            "Rejected ingress from gateway {} for sender {}: {}",
+            gateway_id, sender_id, error
+        );
+        self.audit.record(AuditEntry::new(
+            gateway_id,
+            "attestation.rejected",
+            serde_json::json!({ "sender_id": sender_id, "reason": error.reason() }),
+        ));
+    }
+
+    fn record_unverified(&self) {
+        self.unverified.fetch_add(1, Ordering::Relaxed);
+        self.metrics.record_attestation_unverified();
+        self.update_unverified_share();
+    }
+
+    fn update_unverified_share(&self) {
+        let verified = self.verified.load(Ordering::Relaxed);
+        let unverified = self.unverified.load(Ordering::Relaxed);
+        let total = verified + unverified;
+        if total > 0 {
+            self.metrics
+                .update_attestation_unverified_share(unverified as f64 / total as f64);
+        }
+    }
+}
+
+/// Bytes signed by the gateway: `sender_id \0 connection_id \0 timestamp_be`
+pub fn signing_payload(sender_id: &str, connection_id: &str, timestamp: i64) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(sender_id.len() + connection_id.len() + 10);
+    payload.extend_from_slice(sender_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(connection_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(&timestamp.to_be_bytes());
+    payload
+}
+
+/// Bytes an operator signs to register or remove a gateway key:
+/// `command_id \0 gateway_id \0 public_key \0 timestamp_be`
+pub fn registration_payload(command_id: &str, gateway_id: &str, public_key: &str, timestamp: i64) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(command_id.len() + gateway_id.len() + public_key.len() + 11);
+    payload.extend_from_slice(command_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(gateway_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(public_key.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(&timestamp.to_be_bytes());
+    payload
+}
+
+fn stored_key_name(gateway_id: &str) -> String {
+    format!("gateway.{}", URL_SAFE_NO_PAD.encode(gateway_id))
+}
+
+fn decode_key(public_key: &str) -> Result<VerifyingKey, AttestationError> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(AttestationError::MalformedKey)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| AttestationError::MalformedKey)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AttestationError {
+    #[error("missing sender attestation")]
+    Missing,
+    #[error("unknown gateway key")]
+    UnknownGateway,
+    #[error("attestation names a different gateway than the message came from")]
+    GatewayMismatch,
+    #[error("attestation timestamp outside replay window")]
+    Expired,
+    #[error("malformed attestation signature")]
+    MalformedSignature,
+    #[error("signature does not match gateway key")]
+    BadSignature,
+    #[error("malformed gateway public key")]
+    MalformedKey,
+    #[error("gateway key change is not signed by a registration key")]
+    UnauthorizedRegistration,
+    #[error("gateway key command was already applied")]
+    ReplayedRegistration,
+    #[error("gateway key store error: {0}")]
+    Store(String),
+}
+
+impl AttestationError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AttestationError::Missing => "missing",
+            AttestationError::UnknownGateway => "unknown_gateway",
+            AttestationError::GatewayMismatch => "gateway_mismatch",
+            AttestationError::Expired => "expired",
+            AttestationError::MalformedSignature => "malformed_signature",
+            AttestationError::BadSignature => "bad_signature",
+            AttestationError::MalformedKey => "malformed_key",
+            AttestationError::UnauthorizedRegistration => "unauthorized_registration",
+            AttestationError::ReplayedRegistration => "replayed_registration",
+            AttestationError::Store(_) => "store",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    const WINDOW: Duration = Duration::from_secs(30);
+    const GRACE: Duration = Duration::from_secs(60);
+
+    fn config(gateway_keys: HashMap<String, String>) -> AttestationConfig {
+        AttestationConfig {
+            enabled: true,
+            replay_window: WINDOW,
+            key_rotation_grace: GRACE,
+            trusted_sources: vec!["legacy-gw".into()],
+            gateway_keys,
+            key_bucket: "broker-gateway-keys".into(),
+            registration_keys: vec![public(&operator())],
+        }
+    }
+
+    fn attestor(keys: &[(&str, &SigningKey)]) -> (SenderAttestor, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let keys = keys.iter().map(|(gateway, key)| (gateway.to_string(), public(key))).collect();
+        let attestor = SenderAttestor::new(&config(keys), AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        (attestor, clock)
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn operator() -> SigningKey {
+        key(99)
+    }
+
+    fn public(key: &SigningKey) -> String {
+        STANDARD.encode(key.verifying_key().as_bytes())
+    }
+
+    fn envelope(gateway_id: &str, key: &SigningKey, timestamp: i64) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(MessageType::TextMessage, "alice".into(), vec!["bob".into()], payload);
+        let signature = key.sign(&signing_payload("alice", "conn-1", timestamp));
+        envelope.attestation = Some(SenderAttestation {
+            gateway_id: gateway_id.into(),
+            connection_id: "conn-1".into(),
+            timestamp,
+            signature: STANDARD.encode(signature.to_bytes()),
+        });
+        envelope
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let gateway = key(1);
+        let (attestor, clock) = attestor(&[("gw-1", &gateway)]);
+        let envelope = envelope("gw-1", &gateway, clock.now_millis());
+        assert!(attestor.verify("gw-1", &envelope).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_signature_by_the_wrong_key() {
+        let (attestor, clock) = attestor(&[("gw-1", &key(1))]);
+        let envelope = envelope("gw-1", &key(2), clock.now_millis());
+        assert!(matches!(attestor.verify("gw-1", &envelope), Err(AttestationError::BadSignature)));
+    }
+
+    #[test]
+    fn rejects_a_tampered_sender() {
+        let gateway = key(1);
+        let (attestor, clock) = attestor(&[("gw-1", &gateway)]);
+        let mut envelope = envelope("gw-1", &gateway, clock.now_millis());
+        envelope.from = "mallory".into();
+        assert!(matches!(attestor.verify("gw-1", &envelope), Err(AttestationError::BadSignature)));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_replay_window() {
+        let gateway = key(1);
+        let (attestor, clock) = attestor(&[("gw-1", &gateway)]);
+        let signed_at = clock.now_millis();
+        let stale = envelope("gw-1", &gateway, signed_at);
+
+        clock.advance(WINDOW + Duration::from_millis(1));
+        assert!(matches!(attestor.verify("gw-1", &stale), Err(AttestationError::Expired)));
+
+        let ahead = envelope("gw-1", &gateway, clock.now_millis() + WINDOW.as_millis() as i64 + 1);
+        assert!(matches!(attestor.verify("gw-1", &ahead), Err(AttestationError::Expired)));
+
+        // Hostile timestamps are rejected rather than overflowing the age
+        for timestamp in [i64::MIN, i64::MAX, -1] {
+            let hostile = envelope("gw-1", &gateway, timestamp);
+            assert!(matches!(attestor.verify("gw-1", &hostile), Err(AttestationError::Expired)));
+        }
+    }
+
+    #[test]
+    fn rejects_attestations_naming_another_gateway() {
+        let gateway = key(1);
+        let (attestor, clock) = attestor(&[("gw-1", &gateway), ("gw-2", &key(2))]);
+        let envelope = envelope("gw-1", &gateway, clock.now_millis());
+        assert!(matches!(attestor.verify("gw-2", &envelope), Err(AttestationError::GatewayMismatch)));
+    }
+
+    #[test]
+    fn rejects_missing_attestations_and_unknown_gateways() {
+        let (attestor, clock) = attestor(&[]);
+        let mut envelope = envelope("gw-1", &key(1), clock.now_millis());
+        assert!(matches!(attestor.verify("gw-1", &envelope), Err(AttestationError::UnknownGateway)));
+
+        envelope.attestation = None;
+        assert!(matches!(attestor.verify("gw-1", &envelope), Err(AttestationError::Missing)));
+    }
+
+    #[test]
+    fn trusted_sources_skip_verification() {
+        let (attestor, clock) = attestor(&[]);
+        let mut envelope = envelope("legacy-gw", &key(1), clock.now_millis());
+        envelope.attestation = None;
+        assert!(attestor.verify("legacy-gw", &envelope).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rotation_mid_traffic_accepts_both_keys_until_the_grace_ends() {
+        let old = key(1);
+        let new = key(2);
+        let (attestor, clock) = attestor(&[("gw-1", &old)]);
+
+        attestor.register_key("gw-1", &public(&new)).await.unwrap();
+        // In-flight messages signed before the rotation still verify
+        assert!(attestor.verify("gw-1", &envelope("gw-1", &old, clock.now_millis())).is_ok());
+        assert!(attestor.verify("gw-1", &envelope("gw-1", &new, clock.now_millis())).is_ok());
+
+        clock.advance(GRACE);
+        assert!(matches!(
+            attestor.verify("gw-1", &envelope("gw-1", &old, clock.now_millis())),
+            Err(AttestationError::BadSignature)
+        ));
+        assert!(attestor.verify("gw-1", &envelope("gw-1", &new, clock.now_millis())).is_ok());
+    }
+
+    #[test]
+    fn registration_needs_an_operator_signature() {
+        let (attestor, clock) = attestor(&[]);
+        let now = clock.now_millis();
+        let new_key = public(&key(3));
+        let payload = registration_payload("cmd-1", "gw-3", &new_key, now);
+        let signed = STANDARD.encode(operator().sign(&payload).to_bytes());
+        let forged = STANDARD.encode(key(3).sign(&payload).to_bytes());
+
+        assert!(attestor.authorize_registration("cmd-1", "gw-3", &new_key, now, &signed).is_ok());
+        assert!(attestor.authorize_registration("cmd-1", "gw-3", &new_key, now, &forged).is_err());
+        // The signature is bound to the gateway it registers
+        assert!(attestor.authorize_registration("cmd-1", "gw-4", &new_key, now, &signed).is_err());
+
+        clock.advance(WINDOW + Duration::from_millis(1));
+        assert!(attestor.authorize_registration("cmd-1", "gw-3", &new_key, now, &signed).is_err());
+
+        for timestamp in [i64::MIN, i64::MAX] {
+            let payload = registration_payload("cmd-2", "gw-3", &new_key, timestamp);
+            let signed = STANDARD.encode(operator().sign(&payload).to_bytes());
+            assert!(matches!(
+                attestor.authorize_registration("cmd-2", "gw-3", &new_key, timestamp, &signed),
+                Err(AttestationError::UnauthorizedRegistration)
+            ));
+        }
+    }
+
+    #[test]
+    fn a_registration_is_honoured_once() {
+        let (attestor, clock) = attestor(&[]);
+        let sign = |command_id: &str, public_key: &str, timestamp: i64| {
+            let payload = registration_payload(command_id, "gw-3", public_key, timestamp);
+            STANDARD.encode(operator().sign(&payload).to_bytes())
+        };
+        let (old_key, new_key) = (public(&key(3)), public(&key(4)));
+        let now = clock.now_millis();
+        let first = sign("cmd-1", &old_key, now);
+        assert!(attestor.authorize_registration("cmd-1", "gw-3", &old_key, now, &first).is_ok());
+        let second = sign("cmd-2", &new_key, now + 1);
+        assert!(attestor.authorize_registration("cmd-2", "gw-3", &new_key, now + 1, &second).is_ok());
+
+        // Replaying the first inside the window would roll the gateway back
+        clock.advance(WINDOW / 2);
+        assert!(matches!(
+            attestor.authorize_registration("cmd-1", "gw-3", &old_key, now, &first),
+            Err(AttestationError::ReplayedRegistration)
+        ));
+        // A forgery reusing a fresh ID doesn't burn it
+        let forged = STANDARD.encode(key(3).sign(&registration_payload("cmd-3", "gw-3", &old_key, now)).to_bytes());
+        assert!(attestor.authorize_registration("cmd-3", "gw-3", &old_key, now, &forged).is_err());
+        let third = sign("cmd-3", &old_key, clock.now_millis());
+        assert!(attestor
+            .authorize_registration("cmd-3", "gw-3", &old_key, clock.now_millis(), &third)
+            .is_ok());
+
+        // Past the window the command is refused as stale, and its ID is forgotten
+        clock.advance(WINDOW);
+        assert!(matches!(
+            attestor.authorize_registration("cmd-1", "gw-3", &old_key, now, &first),
+            Err(AttestationError::UnauthorizedRegistration)
+        ));
+        let later = sign("cmd-4", &new_key, clock.now_millis());
+        assert!(attestor
+            .authorize_registration("cmd-4", "gw-3", &new_key, clock.now_millis(), &later)
+            .is_ok());
+        assert_eq!(attestor.seen_commands.lock().len(), 2);
+    }
+}
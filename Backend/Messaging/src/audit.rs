@@ -0,0 +1,720 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use chrono::Utc;
+use parking_lot::Mutex;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::AuditConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// `prev_hash` of the first record of a chain
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A single operator-visible audit record
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+
+    /// Who caused the action (operator, gateway id, subsystem name)
+    pub actor: String,
+
+    /// What happened, e.g. `degradation.level_changed`
+    pub action: String,
+
+    /// Free-form structured details
+    pub details: serde_json::Value,
+}
+
+impl AuditEntry {
+    pub fn new(actor: impl Into<String>, action: impl Into<String>, details: serde_json::Value) -> Self {
+        Self {
+            timestamp: Utc::now().timestamp_millis(),
+            actor: actor.into(),
+            action: action.into(),
+            details,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    Entry,
+    /// Chain head checkpoint, also published for external notarization
+    Anchor,
+    /// First record of a file after rotation, naming the file it continues
+    Continuation,
+}
+
+/// One line of the audit file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainedRecord {
+    pub seq: u64,
+    pub kind: RecordKind,
+    pub prev_hash: String,
+    pub entry: AuditEntry,
+    /// SHA-256 over the canonical form of every other field
+    pub hash: String,
+}
+
+impl ChainedRecord {
+    fn seal(seq: u64, kind: RecordKind, prev_hash: String, entry: AuditEntry) -> Self {
+        let mut record = Self {
+            seq,
+            kind,
+            prev_hash,
+            entry,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        record
+    }
+
+    fn compute_hash(&self) -> String {
+        let mut value = canonical_value(&serde_json::to_value(self).expect("audit records serialize"));
+        if let Value::Object(fields) = &mut value {
+            fields.remove("hash");
+        }
+        hex::encode(digest(&SHA256, &canonical_bytes(&value)))
+    }
+
+    fn line(&self) -> Vec<u8> {
+        let value = serde_json::to_value(self).expect("audit records serialize");
+        let mut line = canonical_bytes(&canonical_value(&value));
+        line.push(b'\n');
+        line
+    }
+}
+
+/// Canonical form of a JSON value: object keys sorted, no floats
+///
+/// `serde_json::Map` is ordered by key in this build, so sorting is done by
+/// the map itself. Floats have no single textual form across serializers and
+/// are written as their shortest round-trip string instead.
+fn canonical_value(value: &Value) -> Value {
+    match value {
+        Value::Number(number) if number.is_f64() => Value::String(number.to_string()),
+        Value::Array(items) => Value::Array(items.iter().map(canonical_value).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), canonical_value(value)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn canonical_bytes(value: &Value) -> Vec<u8> {
+    serde_json::to_vec(value).expect("json values serialize")
+}
+
+/// Where a chain walk found its first inconsistency
+#[derive(Debug, Clone, Serialize)]
+pub struct BrokenLink {
+    pub file: String,
+    /// 1-based line within `file`
+    pub line: usize,
+    /// Sequence number of the offending record, when it parsed
+    pub seq: Option<u64>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditVerifyReport {
+    pub from: u64,
+    pub to: Option<u64>,
+    /// Records in range whose links were checked
+    pub checked: u64,
+    /// Sequence number and hash of the last intact record in range
+    pub head_seq: Option<u64>,
+    pub head_hash: Option<String>,
+    pub first_broken: Option<BrokenLink>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("no audit log file is configured")]
+    NotConfigured,
+    #[error("audit log I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+struct ChainWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+    next_seq: u64,
+    head_hash: String,
+    max_file_bytes: u64,
+}
+
+impl ChainWriter {
+    fn open(path: PathBuf, max_file_bytes: u64) -> std::io::Result<Self> {
+        let (next_seq, head_hash) = match last_record(&path)? {
+            Some(record) => (record.seq + 1, record.hash),
+            None => match rotated_files(&path)?.last() {
+                Some(rotated) => last_record(rotated)?
+                    .map(|record| (record.seq + 1, record.hash))
+                    .unwrap_or((0, GENESIS_HASH.to_string())),
+                None => (0, GENESIS_HASH.to_string()),
+            },
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            written,
+            next_seq,
+            head_hash,
+            max_file_bytes,
+        })
+    }
+
+    fn append(&mut self, kind: RecordKind, entry: AuditEntry) -> std::io::Result<ChainedRecord> {
+        let record = ChainedRecord::seal(self.next_seq, kind, self.head_hash.clone(), entry);
+        let line = record.line();
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+        self.written += line.len() as u64;
+        self.next_seq += 1;
+        self.head_hash = record.hash.clone();
+
+        if self.max_file_bytes > 0 && self.written >= self.max_file_bytes && kind != RecordKind::Continuation {
+            self.rotate(record.seq)?;
+        }
+        Ok(record)
+    }
+
+    /// Move the current file aside and start the next one with a continuation
+    ///
+    /// Rotated files are named after their last sequence number, zero padded
+    /// so lexical order is chain order.
+    fn rotate(&mut self, last_seq: u64) -> std::io::Result<()> {
+        let rotated = PathBuf::from(format!("{}.{:020}", self.path.display(), last_seq));
+        fs::rename(&self.path, &rotated)?;
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.written = 0;
+        info!("Audit log rotated to {}", rotated.display());
+
+        let previous = rotated.file_name().map(|name| name.to_string_lossy().into_owned());
+        self.append(
+            RecordKind::Continuation,
+            AuditEntry::new("audit", "audit.continued", json!({ "previous_file": previous, "previous_seq": last_seq })),
+        )?;
+        Ok(())
+    }
+}
+
+/// Append-only, hash-chained audit log
+///
+/// Every entry is emitted on the `audit` tracing target and, when a path is
+/// configured, appended to a JSON-lines file. Lines are canonical JSON
+/// (sorted keys, floats as strings) so the same record always hashes the
+/// same, and each record carries the hash of the one before it: editing,
+/// dropping or reordering any line breaks every link after it.
+///
+/// Past `audit.max_file_bytes` the file is renamed to
+/// `{path}.{last seq}` and the new file opens with a continuation record
+/// chained to the old file's last hash. Every `audit.anchor_interval` an
+/// anchor record is appended and the chain head is published on
+/// `audit.anchor_subject`, so an external notary holds hashes the broker
+/// can't rewrite. `verify` re-walks the files and reports the first
+/// broken link.
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Option<Arc<Mutex<ChainWriter>>>,
+    clock: SharedClock,
+    metrics: Option<BrokerMetrics>,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<&str>, config: &AuditConfig, metrics: BrokerMetrics) -> anyhow::Result<Self> {
+        let sink = match path {
+            Some(path) => {
+                let writer = ChainWriter::open(PathBuf::from(path), config.max_file_bytes)?;
+                info!("Audit log writing to {} from seq {}", path, writer.next_seq);
+                Some(Arc::new(Mutex::new(writer)))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            sink,
+            clock: SystemClock::shared(),
+            metrics: Some(metrics),
+        })
+    }
+
+    /// Audit log that only emits to tracing
+    pub fn tracing_only() -> Self {
+        Self {
+            sink: None,
+            clock: SystemClock::shared(),
+            metrics: None,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        info!(
+            target: "audit",
+            actor = %entry.actor,
+            action = %entry.action,
+            details = %entry.details,
+            "audit"
+        );
+
+        let Some(sink) = &self.sink else {
+            return;
+        };
+
+        let action = entry.action.clone();
+        if let Err(e) = sink.lock().append(RecordKind::Entry, entry) {
+            warn!("Failed to write audit entry {}: {}", action, e);
+        }
+    }
+
+    /// Append an anchor record and publish the resulting chain head
+    pub async fn anchor(&self, client: &async_nats::Client, subject: &str) -> Result<(), AuditError> {
+        let sink = self.sink.as_ref().ok_or(AuditError::NotConfigured)?;
+        let record = {
+            let mut writer = sink.lock();
+            let covered = writer.next_seq.checked_sub(1);
+            let head = writer.head_hash.clone();
+            let entry = AuditEntry {
+                timestamp: self.clock.now_millis(),
+                ..AuditEntry::new("audit", "audit.anchor", json!({ "covered_seq": covered, "covered_hash": head }))
+            };
+            writer.append(RecordKind::Anchor, entry)?
+        };
+
+        let payload = canonical_bytes(&json!({
+            "seq": record.seq,
+            "hash": record.hash,
+            "timestamp": record.entry.timestamp,
+        }));
+        let published = client.publish(subject.to_string(), payload.into()).await;
+        let outcome = if published.is_ok() { "published" } else { "failed" };
+        if let Err(e) = published {
+            warn!("Failed to publish audit anchor {} to {}: {}", record.seq, subject, e);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record_audit_anchor(outcome);
+        }
+        Ok(())
+    }
+
+    pub fn spawn_anchor_task(&self, client: async_nats::Client, config: &AuditConfig) -> Option<tokio::task::JoinHandle<()>> {
+        if self.sink.is_none() || config.anchor_subject.is_empty() {
+            return None;
+        }
+        let log = self.clone();
+        let subject = config.anchor_subject.clone();
+        let interval = config.anchor_interval;
+        Some(spawn_traced("audit_anchor", TaskContext::new("audit"), async move {
+            // Anchor only after a full interval
+            loop {
+                log.clock.sleep(interval).await;
+                if let Err(e) = log.anchor(&client, &subject).await {
+                    warn!("Audit anchor failed: {}", e);
+                }
+            }
+        }))
+    }
+
+    /// Re-walk the chain and check every record with `from <= seq <= to`
+    ///
+    /// Records before `from` are still read, since the first record in range
+    /// links to them, but a break there isn't reported. Blocking file I/O;
+    /// run it off the async runtime.
+    pub fn verify(&self, from: u64, to: Option<u64>) -> Result<AuditVerifyReport, AuditError> {
+        let sink = self.sink.as_ref().ok_or(AuditError::NotConfigured)?;
+        let path = {
+            let mut writer = sink.lock();
+            writer.writer.flush()?;
+            writer.path.clone()
+        };
+
+        let mut files = rotated_files(&path)?;
+        files.push(path);
+        let report = verify_files(&files, from, to)?;
+        if report.first_broken.is_some() {
+            if let Some(metrics) = &self.metrics {
+                metrics.record_audit_verify_failure();
+            }
+        }
+        Ok(report)
+    }
+}
+
+fn verify_files(files: &[PathBuf], from: u64, to: Option<u64>) -> std::io::Result<AuditVerifyReport> {
+    let mut report = AuditVerifyReport {
+        from,
+        to,
+        checked: 0,
+        head_seq: None,
+        head_hash: None,
+        first_broken: None,
+    };
+    let in_range = |seq: u64| seq >= from && to.is_none_or(|to| seq <= to);
+    // (seq, hash) of the previous record, whatever file it was in
+    let mut previous: Option<(u64, String)> = None;
+
+    for file in files {
+        let name = file.display().to_string();
+        let reader = match File::open(file) {
+            Ok(f) => BufReader::new(f),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let broken = |seq: Option<u64>, reason: String| BrokenLink {
+                file: name.clone(),
+                line: index + 1,
+                seq,
+                reason,
+            };
+
+            let record: ChainedRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    // Can't tell its seq; report it unless the walk is already past `to`
+                    if previous.as_ref().is_none_or(|(seq, _)| to.is_none_or(|to| *seq < to)) {
+                        report.first_broken = Some(broken(None, format!("unparseable record: {}", e)));
+                        return Ok(report);
+                    }
+                    break;
+                }
+            };
+            if to.is_some_and(|to| record.seq > to) {
+                return Ok(report);
+            }
+
+            let problem = if record.hash != record.compute_hash() {
+                Some("hash does not match record contents".to_string())
+            } else if record.line() != format!("{}\n", line).into_bytes() {
+                Some("record is not in canonical form".to_string())
+            } else {
+                match &previous {
+                    Some((seq, _)) if record.seq != seq + 1 => {
+                        Some(format!("sequence jumps from {} to {}", seq, record.seq))
+                    }
+                    Some((_, hash)) if &record.prev_hash != hash => {
+                        Some("prev_hash does not match the previous record".to_string())
+                    }
+                    None if record.seq == 0 && record.prev_hash != GENESIS_HASH => {
+                        Some("first record does not link to the genesis hash".to_string())
+                    }
+                    _ => None,
+                }
+            };
+
+            if in_range(record.seq) {
+                if let Some(reason) = problem {
+                    report.first_broken = Some(broken(Some(record.seq), reason));
+                    return Ok(report);
+                }
+                report.checked += 1;
+                report.head_seq = Some(record.seq);
+                report.head_hash = Some(record.hash.clone());
+            }
+            previous = Some((record.seq, record.hash));
+        }
+    }
+    Ok(report)
+}
+
+/// Rotated files of `path`, oldest first
+fn rotated_files(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let Some(base) = path.file_name().map(|name| format!("{}.", name.to_string_lossy())) else {
+        return Ok(Vec::new());
+    };
+    let mut files: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.strip_prefix(&base)
+                    .is_some_and(|suffix| !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .map(|entry| entry.path())
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    files.sort();
+    Ok(files)
+}
+
+/// The last complete record of `path`, if it has one
+fn last_record(path: &Path) -> std::io::Result<Option<ChainedRecord>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            last = Some(line);
+        }
+    }
+    Ok(last.and_then(|line| match serde_json::from_str(&line) {
+        Ok(record) => Some(record),
+        Err(e) => {
+            // Chaining on from genesis keeps writing; verify reports the break
+            warn!("Last audit record in {} is unreadable, restarting the chain: {}", path.display(), e);
+            None
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        nats_probe::tests::EmbeddedServer,
+    };
+
+    const MAX_FILE_BYTES: u64 = 1024;
+
+    fn audit_path() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audit-{}", Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("audit.log")
+    }
+
+    fn config() -> AuditConfig {
+        let mut config = BrokerConfig::load().unwrap().audit;
+        config.max_file_bytes = MAX_FILE_BYTES;
+        config.anchor_subject = "broker.audit.anchor".into();
+        config.anchor_interval = Duration::from_secs(300);
+        config
+    }
+
+    fn open(path: &Path) -> AuditLog {
+        open_with(path, &config())
+    }
+
+    fn open_with(path: &Path, config: &AuditConfig) -> AuditLog {
+        AuditLog::open(Some(&path.to_string_lossy()), config, BrokerMetrics::new().unwrap()).unwrap()
+    }
+
+    fn entry(index: usize) -> AuditEntry {
+        AuditEntry::new("operator", "path_override.set", json!({ "index": index, "ttl_secs": 600 }))
+    }
+
+    fn records(file: &Path) -> Vec<ChainedRecord> {
+        fs::read_to_string(file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    /// Every file of the log, oldest first
+    fn files(path: &Path) -> Vec<PathBuf> {
+        let mut files = rotated_files(path).unwrap();
+        files.push(path.to_path_buf());
+        files
+    }
+
+    #[test]
+    fn records_are_canonical_json() {
+        let path = audit_path();
+        let log = open(&path);
+        log.record(AuditEntry::new("operator", "limits.changed", json!({ "zeta": 1, "alpha": { "rate": 0.5, "b": [2.25, 3] } })));
+
+        let line = fs::read_to_string(&path).unwrap();
+        assert!(line.starts_with(r#"{"entry":{"action":"limits.changed","actor":"operator","details":{"alpha":{"b":["2.25",3],"rate":"0.5"},"zeta":1}"#), "{}", line);
+        let record = &records(&path)[0];
+        assert_eq!((record.seq, record.kind, record.prev_hash.as_str()), (0, RecordKind::Entry, GENESIS_HASH));
+        assert_eq!(record.hash, record.compute_hash());
+    }
+
+    #[test]
+    fn the_chain_verifies_across_rotated_files_and_restarts() {
+        let path = audit_path();
+        let log = open(&path);
+        for index in 0..20 {
+            log.record(entry(index));
+        }
+
+        let files = files(&path);
+        assert!(files.len() >= 4, "{} files", files.len());
+        for pair in files.windows(2) {
+            let last = records(&pair[0]).pop().unwrap();
+            let first = &records(&pair[1])[0];
+            assert_eq!(first.kind, RecordKind::Continuation);
+            assert_eq!(first.seq, last.seq + 1);
+            assert_eq!(first.prev_hash, last.hash);
+            assert_eq!(first.entry.details["previous_file"], pair[0].file_name().unwrap().to_string_lossy().as_ref());
+            assert!(fs::metadata(&pair[0]).unwrap().len() >= MAX_FILE_BYTES);
+        }
+
+        let total: usize = files.iter().map(|file| records(file).len()).sum();
+        let report = log.verify(0, None).unwrap();
+        assert!(report.first_broken.is_none(), "{:?}", report.first_broken);
+        assert_eq!(report.checked, total as u64);
+        assert_eq!(report.head_seq, Some(total as u64 - 1));
+
+        // A restarted broker carries on from the chain head
+        drop(log);
+        let log = open(&path);
+        log.record(entry(20));
+        let report = log.verify(0, None).unwrap();
+        assert!(report.first_broken.is_none(), "{:?}", report.first_broken);
+        assert_eq!(report.head_seq, Some(total as u64));
+
+        // A range checks just its records, linked to the ones before
+        let report = log.verify(5, Some(9)).unwrap();
+        assert!(report.first_broken.is_none());
+        assert_eq!((report.checked, report.head_seq), (5, Some(9)));
+    }
+
+    #[test]
+    fn a_single_flipped_byte_breaks_the_chain_at_its_record() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let path = audit_path();
+            let log = open(&path);
+            for index in 0..20 {
+                log.record(entry(index));
+            }
+
+            // One byte of one record in the middle of a rotated file
+            let file = files(&path)[1].clone();
+            let mut bytes = fs::read(&file).unwrap();
+            let second_line = bytes.iter().position(|b| *b == b'\n').unwrap() + 1;
+            let tampered_seq = records(&file)[1].seq;
+            let actor = second_line + find(&bytes[second_line..], br#""actor":"operator""#) + br#""actor":""#.len();
+            bytes[actor] ^= 0x01;
+            fs::write(&file, &bytes).unwrap();
+
+            let report = log.verify(0, None).unwrap();
+            let broken = report.first_broken.expect("the flipped byte is found");
+            assert_eq!(broken.file, file.display().to_string());
+            assert_eq!((broken.line, broken.seq), (2, Some(tampered_seq)));
+            assert_eq!(broken.reason, "hash does not match record contents");
+            assert_eq!(report.checked, tampered_seq);
+
+            // Everything before the tampered record still verifies
+            let before = log.verify(0, Some(tampered_seq - 1)).unwrap();
+            assert!(before.first_broken.is_none());
+            assert_eq!(before.head_seq, Some(tampered_seq - 1));
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains("broker_audit_verify_failures_total 1"), "{}", rendered);
+        });
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack.windows(needle.len()).position(|window| window == needle).unwrap()
+    }
+
+    /// Wait for the embedded server to have received `count` anchors
+    async fn anchors(server: &EmbeddedServer, count: usize) -> Vec<Value> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let published = server.published.lock().clone();
+                if published.len() >= count {
+                    return published
+                        .into_iter()
+                        .map(|(subject, payload)| {
+                            assert_eq!(subject, "broker.audit.anchor");
+                            serde_json::from_slice(&payload).unwrap()
+                        })
+                        .collect();
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("anchor never published")
+    }
+
+    async fn sleeping(clock: &SimClock) {
+        while clock.pending() == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn anchors_publish_the_chain_head_once_per_interval() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let server = EmbeddedServer::start(clock.clone()).await;
+        let client = async_nats::connect(&server.url).await.unwrap();
+        let path = audit_path();
+        let mut config = config();
+        config.max_file_bytes = 0;
+        let log = open_with(&path, &config).with_clock(clock.clone());
+        for index in 0..3 {
+            log.record(entry(index));
+        }
+        let _task = log.spawn_anchor_task(client, &config).unwrap();
+
+        // Nothing until a full interval has passed
+        sleeping(&clock).await;
+        clock.advance(config.anchor_interval - Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(server.published.lock().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let published = anchors(&server, 1).await;
+        let anchor = records(&path).pop().unwrap();
+        assert_eq!(anchor.kind, RecordKind::Anchor);
+        assert_eq!(anchor.entry.details["covered_seq"], 2);
+        assert_eq!(published[0], json!({ "seq": 3, "hash": anchor.hash, "timestamp": clock.now_millis() }));
+
+        // The next one covers what was written since
+        log.record(entry(3));
+        sleeping(&clock).await;
+        clock.advance(config.anchor_interval);
+        let published = anchors(&server, 2).await;
+        let anchor = records(&path).pop().unwrap();
+        assert_eq!(anchor.entry.details["covered_seq"], 4);
+        assert_eq!(published[1]["seq"], 5);
+        assert_eq!(published[1]["hash"], anchor.hash.as_str());
+        assert_eq!(published.len(), 2);
+
+        assert!(log.verify(0, None).unwrap().first_broken.is_none());
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_audit_anchors_total{outcome="published"} 2"#), "{}", rendered);
+    }
+}
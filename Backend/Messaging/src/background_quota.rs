@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::BackgroundQuotaConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Background publishers that share the non-live part of publish capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerClass {
+    Retry,
+    OfflineReplay,
+    GapRepair,
+    ScheduledDispatch,
+    /// Predictive route and membership refreshes
+    RouteWarming,
+    /// High priority messages released after a rate-limit deferral
+    RateLimitDeferred,
+}
+
+impl WorkerClass {
+    pub const ALL: [WorkerClass; 6] = [
+        WorkerClass::Retry,
+        WorkerClass::OfflineReplay,
+        WorkerClass::GapRepair,
+        WorkerClass::ScheduledDispatch,
+        WorkerClass::RouteWarming,
+        WorkerClass::RateLimitDeferred,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkerClass::Retry => "retry",
+            WorkerClass::OfflineReplay => "offline_replay",
+            WorkerClass::GapRepair => "gap_repair",
+            WorkerClass::ScheduledDispatch => "scheduled_dispatch",
+            WorkerClass::RouteWarming => "route_warming",
+            WorkerClass::RateLimitDeferred => "rate_limit_deferred",
+        }
+    }
+}
+
+/// Token buckets for classes sharing one rate by weight
+///
+/// `split` divides a rate between the classes taking part; `refill` credits
+/// each class its rate for the time since the last refill, holding at most
+/// one second of it. Classes left out of a refill keep their tokens but earn
+/// nothing. Ingress admission splits its capacity the same way.
+pub(crate) struct FairShares<K> {
+    tokens: HashMap<K, f64>,
+    last_refill: Instant,
+}
+
+impl<K: Copy + Eq + Hash> FairShares<K> {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            tokens: HashMap::new(),
+            last_refill: now,
+        }
+    }
+
+    /// Start counting refills from `now`, as after a clock change
+    pub(crate) fn restart(&mut self, now: Instant) {
+        self.last_refill = now;
+    }
+
+    /// `rate` divided between `participants` in proportion to `weight`
+    pub(crate) fn split(participants: &[K], rate: f64, weight: impl Fn(&K) -> f64) -> Vec<(K, f64)> {
+        let total_weight: f64 = participants.iter().map(&weight).sum::<f64>().max(f64::EPSILON);
+        participants.iter().map(|class| (*class, rate * weight(class) / total_weight)).collect()
+    }
+
+    pub(crate) fn refill(&mut self, rates: &[(K, f64)], now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        for (class, rate) in rates {
+            let tokens = self.tokens.entry(*class).or_default();
+            // One second of burst at the class rate
+            *tokens = (*tokens + elapsed * rate).min(rate.max(1.0));
+        }
+    }
+
+    /// Take `count` tokens from `class` if it has them
+    pub(crate) fn try_take(&mut self, class: K, count: u32) -> bool {
+        match self.tokens.get_mut(&class) {
+            Some(tokens) if *tokens >= count as f64 => {
+                *tokens -= count as f64;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClassState {
+    backlog: u64,
+    /// Publishes taken since the last adjustment
+    consumed: u64,
+    /// Publishes per second over the last adjustment window
+    rate: f64,
+}
+
+struct QuotaState {
+    /// Share of total capacity currently granted to background work
+    fraction: f64,
+    /// Smoothed live-path latency in milliseconds
+    live_latency_ms: Option<f64>,
+    classes: HashMap<WorkerClass, ClassState>,
+    tokens: FairShares<WorkerClass>,
+    last_adjust: Instant,
+}
+
+/// Publish-rate allowance for background workers, kept apart from live traffic
+///
+/// Background work gets `background_quota.initial_fraction` of
+/// `limits.messages_per_second`, split between worker classes by weight.
+/// Only classes with a backlog take part in the split, so an idle class
+/// doesn't strand capacity. Every adjustment window the fraction shrinks
+/// multiplicatively while smoothed live latency is over target and grows
+/// additively while it is under half the target.
+pub struct BackgroundQuota {
+    state: Mutex<QuotaState>,
+    config: BackgroundQuotaConfig,
+    total_capacity: f64,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl BackgroundQuota {
+    pub fn new(config: BackgroundQuotaConfig, total_capacity: u32, metrics: BrokerMetrics) -> Self {
+        let clock = SystemClock::shared();
+        let now = clock.now_instant();
+        Self {
+            state: Mutex::new(QuotaState {
+                fraction: config.initial_fraction,
+                live_latency_ms: None,
+                classes: WorkerClass::ALL.iter().map(|class| (*class, ClassState::default())).collect(),
+                tokens: FairShares::new(now),
+                last_adjust: now,
+            }),
+            config,
+            total_capacity: total_capacity as f64,
+            clock,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        let now = clock.now_instant();
+        let state = self.state.get_mut();
+        state.tokens.restart(now);
+        state.last_adjust = now;
+        self.clock = clock;
+        self
+    }
+
+    /// Take `count` publishes for `class` if its share allows it now
+    pub fn try_acquire(&self, class: WorkerClass, count: u32) -> bool {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+
+        if !state.tokens.try_take(class, count) {
+            return false;
+        }
+        let class_state = state.classes.get_mut(&class).expect("every class is registered");
+        class_state.consumed += count as u64;
+        true
+    }
+
+    /// Wait until `count` publishes for `class` are allowed
+    pub async fn acquire(&self, class: WorkerClass, count: u32) {
+        while !self.try_acquire(class, count) {
+            self.clock.sleep(Duration::from_millis(self.config.acquire_poll_ms)).await;
+        }
+    }
+
+    /// Current queue depth of a worker class, used for the split and drain ETA
+    pub fn report_backlog(&self, class: WorkerClass, depth: u64) {
+        if let Some(class_state) = self.state.lock().classes.get_mut(&class) {
+            class_state.backlog = depth;
+        }
+    }
+
+    /// Live-path publish latency sample
+    pub fn observe_live_latency(&self, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1000.0;
+        let mut state = self.state.lock();
+        state.live_latency_ms = Some(match state.live_latency_ms {
+            Some(smoothed) => smoothed + self.config.latency_smoothing * (sample - smoothed),
+            None => sample,
+        });
+    }
+
+    /// Background publishes per second currently granted
+    pub fn allowance(&self) -> f64 {
+        self.total_capacity * self.state.lock().fraction
+    }
+
+    pub fn spawn_adjuster(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let quota = Arc::clone(self);
+        spawn_traced("background_quota", TaskContext::new("background_quota"), async move {
+            loop {
+                quota.clock.sleep(quota.config.adjust_interval).await;
+                quota.adjust();
+            }
+        })
+    }
+
+    /// Resize the allowance from live latency and publish per-class gauges
+    pub fn adjust(&self) {
+        let mut state = self.state.lock();
+        let now = self.clock.now_instant();
+        let elapsed = now.duration_since(state.last_adjust).as_secs_f64().max(0.001);
+        state.last_adjust = now;
+
+        let target = self.config.live_latency_target_ms as f64;
+        if let Some(latency) = state.live_latency_ms {
+            let previous = state.fraction;
+            if latency > target {
+                state.fraction *= self.config.decrease_factor;
+            } else if latency < target / 2.0 {
+                state.fraction += self.config.increase_step;
+            }
+            state.fraction = state
+                .fraction
+                .clamp(self.config.min_fraction, self.config.max_fraction);
+            if state.fraction != previous {
+                debug!(
+                    "Background allowance {:.3} -> {:.3} (live latency {:.1}ms)",
+                    previous, state.fraction, latency
+                );
+            }
+        }
+        self.metrics.update_background_allowance(state.fraction);
+
+        for class in WorkerClass::ALL {
+            let class_state = state.classes.get_mut(&class).expect("every class is registered");
+            class_state.rate = class_state.consumed as f64 / elapsed;
+            class_state.consumed = 0;
+
+            let eta = if class_state.backlog == 0 {
+                0.0
+            } else if class_state.rate > 0.0 {
+                class_state.backlog as f64 / class_state.rate
+            } else {
+                f64::INFINITY
+            };
+            self.metrics
+                .update_background_class(class.as_str(), class_state.rate, eta);
+        }
+    }
+
+    fn refill(&self, state: &mut QuotaState) {
+        let allowance = self.total_capacity * state.fraction;
+        let weight = |class: &WorkerClass| self.config.weights.get(class).copied().unwrap_or(1.0);
+        let active: Vec<WorkerClass> = WorkerClass::ALL
+            .into_iter()
+            .filter(|class| state.classes[class].backlog > 0)
+            .collect();
+        // With no reported backlog every class gets its weighted share
+        let participants = if active.is_empty() { WorkerClass::ALL.to_vec() } else { active };
+        let rates = FairShares::split(&participants, allowance, weight);
+        state.tokens.refill(&rates, self.clock.now_instant());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const CAPACITY: u32 = 1_000;
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn quota(weights: HashMap<WorkerClass, f64>) -> (BackgroundQuota, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().background_quota;
+        config.initial_fraction = 0.2;
+        config.min_fraction = 0.02;
+        config.max_fraction = 0.5;
+        config.live_latency_target_ms = 50;
+        config.decrease_factor = 0.5;
+        config.increase_step = 0.02;
+        config.weights = weights;
+        let clock = Arc::new(SimClock::new());
+        let quota = BackgroundQuota::new(config, CAPACITY, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (quota, clock)
+    }
+
+    /// Take everything the class's share allows right now, up to `backlog`
+    fn drain(quota: &BackgroundQuota, class: WorkerClass, backlog: &mut u64) -> u64 {
+        let mut taken = 0;
+        while *backlog > 0 && quota.try_acquire(class, 1) {
+            *backlog -= 1;
+            taken += 1;
+        }
+        quota.report_backlog(class, *backlog);
+        taken
+    }
+
+    /// Live latency of a downstream queue running at `utilization`
+    fn live_latency(utilization: f64) -> Duration {
+        if utilization >= 0.99 {
+            return Duration::from_millis(500);
+        }
+        Duration::from_secs_f64(0.005 / (1.0 - utilization))
+    }
+
+    fn drain_eta(quota: &BackgroundQuota, class: WorkerClass) -> Duration {
+        let state = quota.state.lock();
+        let class_state = &state.classes[&class];
+        Duration::from_secs_f64(class_state.backlog as f64 / class_state.rate)
+    }
+
+    fn assert_allowance(quota: &BackgroundQuota, expected: f64) {
+        assert!((quota.allowance() - expected).abs() < 1e-6, "allowance {}", quota.allowance());
+    }
+
+    #[test]
+    fn a_retry_backlog_drains_without_hurting_live_latency() {
+        const LIVE_RATE: f64 = 400.0;
+        let (quota, clock) = quota(HashMap::new());
+        let mut backlog = 20_000;
+        quota.report_backlog(WorkerClass::Retry, backlog);
+
+        let mut elapsed = Duration::ZERO;
+        let mut eta = None;
+        let mut window = VecDeque::new();
+        let mut latencies = Vec::new();
+        while backlog > 0 {
+            assert!(elapsed < Duration::from_secs(300), "backlog never drained");
+            clock.advance(TICK);
+            elapsed += TICK;
+
+            // Downstream load over the last 100ms: live traffic plus background publishes
+            window.push_back(drain(&quota, WorkerClass::Retry, &mut backlog));
+            if window.len() > 10 {
+                window.pop_front();
+            }
+            let background_rate = window.iter().sum::<u64>() as f64 / (TICK * window.len() as u32).as_secs_f64();
+            let latency = live_latency((LIVE_RATE + background_rate) / CAPACITY as f64);
+            latencies.push(latency);
+            quota.observe_live_latency(latency);
+
+            if elapsed.as_millis().is_multiple_of(1_000) {
+                quota.adjust();
+                eta.get_or_insert_with(|| drain_eta(&quota, WorkerClass::Retry));
+            }
+        }
+
+        latencies.sort();
+        let p99 = latencies[latencies.len() * 99 / 100];
+        assert!(p99 <= Duration::from_millis(50), "live p99 {:?}", p99);
+        // The first estimate assumes the starting rate; the allowance only grew from there
+        let eta = eta.unwrap();
+        assert!(elapsed <= eta + Duration::from_secs(1), "drained in {:?}, estimated {:?}", elapsed, eta);
+        assert!(quota.allowance() > 200.0);
+    }
+
+    #[test]
+    fn live_latency_moves_the_allowance_within_bounds() {
+        let (quota, _) = quota(HashMap::new());
+        assert_allowance(&quota, 200.0);
+
+        quota.observe_live_latency(Duration::from_millis(200));
+        quota.adjust();
+        assert_allowance(&quota, 100.0);
+        for _ in 0..10 {
+            quota.adjust();
+        }
+        assert_allowance(&quota, 20.0);
+
+        for _ in 0..100 {
+            quota.observe_live_latency(Duration::from_millis(1));
+        }
+        quota.adjust();
+        assert_allowance(&quota, 40.0);
+        for _ in 0..100 {
+            quota.adjust();
+        }
+        assert_allowance(&quota, 500.0);
+
+        // Between half the target and the target the allowance holds
+        for _ in 0..100 {
+            quota.observe_live_latency(Duration::from_millis(40));
+        }
+        quota.adjust();
+        assert_allowance(&quota, 500.0);
+    }
+
+    #[test]
+    fn backlogged_classes_split_the_allowance_by_weight() {
+        let (quota, clock) = quota(HashMap::from([(WorkerClass::Retry, 3.0), (WorkerClass::OfflineReplay, 1.0)]));
+        let (mut retries, mut replays) = (u64::MAX, u64::MAX);
+        let (mut retried, mut replayed) = (0, 0);
+        for _ in 0..1_000 {
+            clock.advance(TICK);
+            retried += drain(&quota, WorkerClass::Retry, &mut retries);
+            replayed += drain(&quota, WorkerClass::OfflineReplay, &mut replays);
+        }
+
+        // 200 per second for 10 seconds, three to one
+        assert!((1_490..=1_510).contains(&retried), "{}", retried);
+        assert!((490..=510).contains(&replayed), "{}", replayed);
+    }
+
+    #[test]
+    fn idle_classes_leave_their_share_to_backlogged_ones() {
+        let (quota, clock) = quota(HashMap::new());
+        let mut replays = u64::MAX;
+        let mut replayed = 0;
+        for _ in 0..1_000 {
+            clock.advance(TICK);
+            replayed += drain(&quota, WorkerClass::OfflineReplay, &mut replays);
+        }
+        assert!((1_990..=2_010).contains(&replayed), "{}", replayed);
+        assert!(!quota.try_acquire(WorkerClass::Retry, 1));
+    }
+}
@@ -0,0 +1,397 @@
+use std::{future::Future, time::Duration};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    config::RetryConfig,
+};
+
+/// How random spread is applied to the exponential delay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    /// Exact exponential delays
+    None,
+    /// Uniform in `[0, delay]`
+    Full,
+    /// Uniform in `[delay / 2, delay]`
+    Equal,
+    /// Uniform in `[initial, previous * multiplier]`, capped at `max`
+    Decorrelated,
+}
+
+/// Exponential backoff schedule
+///
+/// Built with `BackoffPolicy::new(initial)` and the builder methods below;
+/// `iter()` yields one delay per retry. Every delay is at most `max`. With
+/// `seeded` the jitter RNG is deterministic, so a schedule can be asserted
+/// exactly.
+#[derive(Debug, Clone)]
+pub struct BackoffPolicy {
+    initial: Duration,
+    multiplier: f64,
+    max: Duration,
+    jitter: Jitter,
+    max_retries: Option<u32>,
+    seed: Option<u64>,
+}
+
+impl BackoffPolicy {
+    pub fn new(initial: Duration) -> Self {
+        Self {
+            initial,
+            multiplier: 2.0,
+            max: initial.max(Duration::from_secs(30)),
+            jitter: Jitter::Full,
+            max_retries: None,
+            seed: None,
+        }
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max.max(self.initial);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Stop after this many retries; unlimited by default
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = Some(retries);
+        self
+    }
+
+    /// Deterministic jitter for tests
+    pub fn seeded(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn iter(&self) -> Backoff {
+        let rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Backoff {
+            policy: self.clone(),
+            retries: 0,
+            base: self.initial,
+            previous: self.initial,
+            rng,
+        }
+    }
+}
+
+/// Delays of one retry sequence; start over with `BackoffPolicy::iter`
+pub struct Backoff {
+    policy: BackoffPolicy,
+    retries: u32,
+    /// Un-jittered delay for the next retry
+    base: Duration,
+    /// Last delay handed out, for decorrelated jitter
+    previous: Duration,
+    rng: StdRng,
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.policy.max_retries.is_some_and(|max| self.retries >= max) {
+            return None;
+        }
+        self.retries += 1;
+
+        let max = self.policy.max;
+        let base = self.base.min(max);
+        self.base = base.mul_f64(self.policy.multiplier).min(max);
+
+        let delay = match self.policy.jitter {
+            Jitter::None => base,
+            Jitter::Full => self.between(Duration::ZERO, base),
+            Jitter::Equal => self.between(base / 2, base),
+            Jitter::Decorrelated => {
+                let upper = self.previous.mul_f64(self.policy.multiplier).min(max);
+                self.between(self.policy.initial.min(upper), upper)
+            }
+        };
+        self.previous = delay.max(self.policy.initial);
+        Some(delay)
+    }
+}
+
+impl Backoff {
+    fn between(&mut self, low: Duration, high: Duration) -> Duration {
+        if high <= low {
+            return high;
+        }
+        Duration::from_nanos(self.rng.gen_range(low.as_nanos() as u64..=high.as_nanos() as u64))
+    }
+}
+
+/// Broker-wide cap on retries relative to successful operations
+///
+/// Each success deposits `retry.budget_ratio` tokens and each retry spends
+/// one, up to `retry.budget_max_tokens`. When the budget is empty, callers
+/// give up instead of retrying, so a failing dependency isn't hammered by
+/// every retry loop at once.
+pub struct RetryBudget {
+    tokens: Mutex<f64>,
+    ratio: f64,
+    max_tokens: f64,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryConfig) -> Self {
+        let max_tokens = config.budget_max_tokens as f64;
+        Self {
+            tokens: Mutex::new(max_tokens),
+            ratio: config.budget_ratio,
+            max_tokens,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut tokens = self.tokens.lock();
+        *tokens = (*tokens + self.ratio).min(self.max_tokens);
+    }
+
+    /// Spend a token for one retry; false if the budget is exhausted
+    pub fn try_withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Run `op` until it succeeds, the policy runs out of retries or the budget is empty
+///
+/// `op` receives the attempt number, starting at 1. Every attempt increments
+/// `attempts`. The last error is returned when giving up.
+pub async fn retry_with<T, E, F, Fut>(
+    policy: &BackoffPolicy,
+    budget: Option<&RetryBudget>,
+    attempts: &metrics::Counter,
+    op: F,
+) -> Result<T, E>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_with_clock(&SystemClock, policy, budget, attempts, op).await
+}
+
+/// `retry_with`, sleeping between attempts on `clock`
+pub async fn retry_with_clock<T, E, F, Fut>(
+    clock: &dyn Clock,
+    policy: &BackoffPolicy,
+    budget: Option<&RetryBudget>,
+    attempts: &metrics::Counter,
+    mut op: F,
+) -> Result<T, E>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delays = policy.iter();
+    let mut attempt = 1;
+
+    loop {
+        attempts.increment(1);
+        let error = match op(attempt).await {
+            Ok(value) => {
+                if let Some(budget) = budget {
+                    budget.record_success();
+                }
+                return Ok(value);
+            }
+            Err(e) => e,
+        };
+
+        let Some(delay) = delays.next() else {
+            return Err(error);
+        };
+        if budget.is_some_and(|budget| !budget.try_withdraw()) {
+            return Err(error);
+        }
+        clock.sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const JITTERS: [Jitter; 4] = [Jitter::None, Jitter::Full, Jitter::Equal, Jitter::Decorrelated];
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// Random policies for the property checks, reproducible from `seed`
+    fn policies(seed: u64, count: usize) -> Vec<BackoffPolicy> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|i| {
+                let initial = ms(rng.gen_range(1..=500));
+                BackoffPolicy::new(initial)
+                    .multiplier(rng.gen_range(0.5..4.0))
+                    .max(ms(rng.gen_range(1..=20_000)))
+                    .jitter(JITTERS[i % JITTERS.len()])
+                    .seeded(rng.gen())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delays_respect_their_bounds() {
+        for policy in policies(7, 400) {
+            let mut base = policy.initial.min(policy.max);
+            for delay in policy.iter().take(40) {
+                assert!(delay <= policy.max, "{:?}: {:?}", policy, delay);
+                match policy.jitter {
+                    Jitter::None => assert_eq!(delay, base),
+                    Jitter::Full => assert!(delay <= base),
+                    Jitter::Equal => assert!(delay >= base / 2 && delay <= base),
+                    Jitter::Decorrelated => assert!(delay >= policy.initial.min(policy.max)),
+                }
+                base = base.mul_f64(policy.multiplier).min(policy.max);
+            }
+        }
+    }
+
+    #[test]
+    fn the_cap_is_reached_and_held() {
+        for policy in policies(11, 200) {
+            let policy = policy.jitter(Jitter::None);
+            let delays: Vec<_> = policy.iter().take(200).collect();
+            assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", policy);
+            if policy.multiplier > 1.1 {
+                assert_eq!(*delays.last().unwrap(), policy.max, "{:?}", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn exponential_schedule_without_jitter() {
+        let policy = BackoffPolicy::new(ms(100)).max(ms(1000)).jitter(Jitter::None).max_retries(6);
+        let delays: Vec<_> = policy.iter().collect();
+        assert_eq!(delays, [ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]);
+    }
+
+    #[test]
+    fn a_fixed_seed_gives_the_same_schedule() {
+        for jitter in [Jitter::Full, Jitter::Equal, Jitter::Decorrelated] {
+            let policy = BackoffPolicy::new(ms(50)).max(ms(5000)).jitter(jitter).seeded(42);
+            let first: Vec<_> = policy.iter().take(20).collect();
+            assert_eq!(first, policy.iter().take(20).collect::<Vec<_>>());
+            assert_eq!(first, policy.clone().iter().take(20).collect::<Vec<_>>());
+
+            let other: Vec<_> = policy.seeded(43).iter().take(20).collect();
+            assert_ne!(first, other, "{:?}", jitter);
+        }
+    }
+
+    #[test]
+    fn builder_clamps_nonsense() {
+        let policy = BackoffPolicy::new(ms(500)).multiplier(0.1).max(ms(10)).jitter(Jitter::None);
+        assert_eq!(policy.multiplier, 1.0);
+        assert_eq!(policy.max, ms(500));
+        assert!(policy.iter().take(5).all(|delay| delay == ms(500)));
+        assert_eq!(BackoffPolicy::new(ms(1)).max_retries(0).iter().next(), None);
+    }
+
+    fn budget(max_tokens: u32, ratio: f64) -> RetryBudget {
+        let mut config = BrokerConfig::load().unwrap().retry;
+        config.budget_max_tokens = max_tokens;
+        config.budget_ratio = ratio;
+        RetryBudget::new(&config)
+    }
+
+    #[test]
+    fn the_budget_refills_from_successes() {
+        let budget = budget(2, 0.5);
+        assert!(budget.try_withdraw());
+        assert!(budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+
+        budget.record_success();
+        assert!(!budget.try_withdraw());
+        budget.record_success();
+        assert!(budget.try_withdraw());
+
+        for _ in 0..100 {
+            budget.record_success();
+        }
+        assert!(budget.try_withdraw() && budget.try_withdraw());
+        assert!(!budget.try_withdraw());
+    }
+
+    #[tokio::test]
+    async fn retries_sleep_the_seeded_schedule() {
+        let clock = Arc::new(SimClock::new().auto_advance(true));
+        let policy = BackoffPolicy::new(ms(100)).max(ms(2000)).jitter(Jitter::Full).seeded(9);
+        let expected: Duration = policy.iter().take(3).sum();
+        let started = clock.now_instant();
+
+        let calls = AtomicU32::new(0);
+        let result: Result<u32, &str> =
+            retry_with_clock(clock.as_ref(), &policy, None, &metrics::Counter::noop(), |attempt| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async move { if attempt < 4 { Err("down") } else { Ok(attempt) } }
+            })
+            .await;
+
+        assert_eq!(result, Ok(4));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(clock.now_instant() - started, expected);
+    }
+
+    #[tokio::test]
+    async fn gives_up_with_the_last_error() {
+        let clock = Arc::new(SimClock::new().auto_advance(true));
+        let policy = BackoffPolicy::new(ms(10)).max_retries(2);
+        let result: Result<(), u32> =
+            retry_with_clock(clock.as_ref(), &policy, None, &metrics::Counter::noop(), |attempt| async move {
+                Err(attempt)
+            })
+            .await;
+        assert_eq!(result, Err(3));
+    }
+
+    #[tokio::test]
+    async fn an_empty_budget_stops_retrying() {
+        let clock = Arc::new(SimClock::new().auto_advance(true));
+        let budget = budget(1, 0.1);
+        let policy = BackoffPolicy::new(ms(10)).max_retries(10);
+        let calls = AtomicU32::new(0);
+        let result: Result<(), ()> =
+            retry_with_clock(clock.as_ref(), &policy, Some(&budget), &metrics::Counter::noop(), |_| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Err(()) }
+            })
+            .await;
+
+        assert!(result.is_err());
+        // The first attempt plus the single retry the budget paid for
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,245 @@
+use std::time::Duration;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::{config::AdaptiveBatchingConfig, metrics::BrokerMetrics};
+
+/// Egress destination classes with independent batch sizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DestinationClass {
+    LocalGateway,
+    RemoteRegion,
+    PushBridge,
+}
+
+impl DestinationClass {
+    pub const ALL: [DestinationClass; 3] = [
+        DestinationClass::LocalGateway,
+        DestinationClass::RemoteRegion,
+        DestinationClass::PushBridge,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DestinationClass::LocalGateway => "local_gateway",
+            DestinationClass::RemoteRegion => "remote_region",
+            DestinationClass::PushBridge => "push_bridge",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            DestinationClass::LocalGateway => 0,
+            DestinationClass::RemoteRegion => 1,
+            DestinationClass::PushBridge => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClassState {
+    batch_size: usize,
+    /// Publishes observed in the current window
+    publishes: u64,
+    failures: u64,
+    total_latency: Duration,
+}
+
+/// AIMD controller for the fanout batch size of each destination class
+///
+/// Per-publish latency and failures are accumulated over a window of
+/// `window_publishes`. At the end of a window the batch size grows by
+/// `additive_step` if the window was healthy and is multiplied by
+/// `decrease_factor` otherwise, always within `[min_batch_size, max_batch_size]`.
+pub struct AdaptiveBatchController {
+    config: AdaptiveBatchingConfig,
+    static_batch_size: usize,
+    classes: [Mutex<ClassState>; 3],
+    metrics: BrokerMetrics,
+}
+
+impl AdaptiveBatchController {
+    pub fn new(config: AdaptiveBatchingConfig, static_batch_size: usize, metrics: BrokerMetrics) -> Self {
+        let initial = ClassState {
+            batch_size: static_batch_size.clamp(config.min_batch_size, config.max_batch_size.max(config.min_batch_size)),
+            publishes: 0,
+            failures: 0,
+            total_latency: Duration::ZERO,
+        };
+
+        let controller = Self {
+            config,
+            static_batch_size,
+            classes: [Mutex::new(initial), Mutex::new(initial), Mutex::new(initial)],
+            metrics,
+        };
+        for class in DestinationClass::ALL {
+            controller
+                .metrics
+                .update_fanout_batch_size(class.as_str(), controller.batch_size(class));
+        }
+        controller
+    }
+
+    /// Effective batch size for the destination class
+    pub fn batch_size(&self, class: DestinationClass) -> usize {
+        if !self.config.enabled {
+            return self.static_batch_size;
+        }
+        self.classes[class.index()].lock().batch_size
+    }
+
+    /// Record the outcome of publishing one batch
+    pub fn record(&self, class: DestinationClass, batch_len: usize, elapsed: Duration, failures: usize) {
+        if !self.config.enabled || batch_len == 0 {
+            return;
+        }
+
+        let mut state = self.classes[class.index()].lock();
+        state.publishes += batch_len as u64;
+        state.failures += failures as u64;
+        state.total_latency += elapsed;
+
+        if state.publishes < self.config.window_publishes {
+            return;
+        }
+
+        let mean_latency = state.total_latency / state.publishes as u32;
+        let failure_rate = state.failures as f64 / state.publishes as f64;
+        let previous = state.batch_size;
+
+        let healthy = mean_latency <= Duration::from_millis(self.config.target_publish_latency_ms)
+            && failure_rate <= self.config.max_failure_rate;
+        state.batch_size = if healthy {
+            previous + self.config.additive_step
+        } else {
+            (previous as f64 * self.config.decrease_factor) as usize
+        }
+        .clamp(self.config.min_batch_size, self.config.max_batch_size);
+
+        state.publishes = 0;
+        state.failures = 0;
+        state.total_latency = Duration::ZERO;
+
+        if state.batch_size != previous {
+            debug!(
+                "Fanout batch size for {}: {} -> {} (mean publish latency {:?}, failure rate {:.3})",
+                class.as_str(),
+                previous,
+                state.batch_size,
+                mean_latency,
+                failure_rate
+            );
+            self.metrics.update_fanout_batch_size(class.as_str(), state.batch_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(1);
+    const SLOW: Duration = Duration::from_millis(20);
+
+    fn controller(enabled: bool) -> AdaptiveBatchController {
+        let config = AdaptiveBatchingConfig {
+            enabled,
+            min_batch_size: 10,
+            max_batch_size: 500,
+            window_publishes: 200,
+            target_publish_latency_ms: 5,
+            max_failure_rate: 0.01,
+            additive_step: 10,
+            decrease_factor: 0.5,
+        };
+        AdaptiveBatchController::new(config, 100, BrokerMetrics::new().unwrap())
+    }
+
+    /// Publish batches at `per_publish` latency until `done` holds for the
+    /// batch size, returning how many adjustments that took
+    fn drive(
+        controller: &AdaptiveBatchController,
+        class: DestinationClass,
+        per_publish: Duration,
+        max_adjustments: usize,
+        done: impl Fn(usize) -> bool,
+    ) -> usize {
+        let mut adjustments = 0;
+        let mut size = controller.batch_size(class);
+        while !done(size) {
+            controller.record(class, size, per_publish * size as u32, 0);
+            let next = controller.batch_size(class);
+            if next != size {
+                adjustments += 1;
+                assert!(adjustments <= max_adjustments, "no convergence after {} adjustments", adjustments);
+            }
+            size = next;
+        }
+        adjustments
+    }
+
+    #[test]
+    fn latency_step_shrinks_the_batch_and_recovers() {
+        let controller = controller(true);
+        let class = DestinationClass::RemoteRegion;
+
+        drive(&controller, class, FAST, 10, |size| size >= 150);
+
+        // 150 -> 75 -> 37 -> 18 -> 10
+        let shrink = drive(&controller, class, SLOW, 4, |size| size == 10);
+        assert_eq!(shrink, 4);
+
+        // Stays at the floor while the destination is slow
+        for _ in 0..20 {
+            controller.record(class, 10, SLOW * 10, 0);
+        }
+        assert_eq!(controller.batch_size(class), 10);
+
+        let recover = drive(&controller, class, FAST, 14, |size| size >= 150);
+        assert_eq!(recover, 14);
+    }
+
+    #[test]
+    fn failures_shrink_the_batch_even_when_fast() {
+        let controller = controller(true);
+        let class = DestinationClass::PushBridge;
+        for _ in 0..2 {
+            controller.record(class, 100, FAST * 100, 5);
+        }
+        assert_eq!(controller.batch_size(class), 50);
+    }
+
+    #[test]
+    fn classes_adapt_independently() {
+        let controller = controller(true);
+        drive(&controller, DestinationClass::RemoteRegion, SLOW, 4, |size| size == 10);
+        drive(&controller, DestinationClass::LocalGateway, FAST, 10, |size| size >= 200);
+
+        assert_eq!(controller.batch_size(DestinationClass::RemoteRegion), 10);
+        assert!(controller.batch_size(DestinationClass::LocalGateway) >= 200);
+        assert_eq!(controller.batch_size(DestinationClass::PushBridge), 100);
+    }
+
+    #[test]
+    fn growth_is_capped_at_the_maximum() {
+        let controller = controller(true);
+        let class = DestinationClass::LocalGateway;
+        drive(&controller, class, FAST, 40, |size| size == 500);
+        for _ in 0..10 {
+            controller.record(class, 500, FAST * 500, 0);
+        }
+        assert_eq!(controller.batch_size(class), 500);
+    }
+
+    #[test]
+    fn disabled_pins_the_static_batch_size() {
+        let controller = controller(false);
+        for class in DestinationClass::ALL {
+            for _ in 0..20 {
+                controller.record(class, 100, SLOW * 100, 10);
+            }
+            assert_eq!(controller.batch_size(class), 100);
+        }
+    }
+}
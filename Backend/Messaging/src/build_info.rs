@@ -0,0 +1,174 @@
+use std::sync::Arc;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::BrokerConfig,
+    config_drift::{fingerprint, sanitized},
+    config_watch::ConfigWatcher,
+    metrics::BrokerMetrics,
+};
+
+/// Build and config identity, as on `/admin/version` and `broker_build_info`
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    /// Short commit hash, or "unknown" for builds outside a git checkout
+    pub git_commit: &'static str,
+    pub rustc: &'static str,
+    /// Enabled cargo features, comma separated
+    pub features: &'static str,
+    pub config_fingerprint: String,
+    /// Seconds since the epoch
+    pub start_time: i64,
+}
+
+/// Publishes `broker_build_info` and `broker_start_time_seconds`
+///
+/// The build labels are fixed at compile time by the build script. The
+/// config fingerprint label follows reloads: the series for the old
+/// fingerprint drops to 0 and the new one is set to 1, so "which config is
+/// each broker on" stays a single `== 1` query.
+pub struct BuildInfo {
+    start_time: i64,
+    config_fingerprint: Mutex<String>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl BuildInfo {
+    pub fn new(config_fingerprint: String, metrics: BrokerMetrics) -> Self {
+        let clock = SystemClock::shared();
+        let info = Self {
+            start_time: clock.now_utc().timestamp(),
+            config_fingerprint: Mutex::new(config_fingerprint),
+            clock,
+            metrics,
+        };
+        info.metrics.update_start_time(info.start_time);
+        info.publish();
+        info
+    }
+
+    /// Also restamps the start time from the new clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.start_time = clock.now_utc().timestamp();
+        self.clock = clock;
+        self.metrics.update_start_time(self.start_time);
+        self.publish();
+        self
+    }
+
+    pub fn version(&self) -> VersionInfo {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("BROKER_GIT_COMMIT"),
+            rustc: env!("BROKER_RUSTC_VERSION"),
+            features: env!("BROKER_FEATURES"),
+            config_fingerprint: self.config_fingerprint.lock().clone(),
+            start_time: self.start_time,
+        }
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let info = Arc::clone(self);
+        watcher.on_reload(move |config| info.reloaded(config));
+    }
+
+    fn reloaded(&self, config: &BrokerConfig) {
+        let current = fingerprint(&sanitized(config));
+        let previous = std::mem::replace(&mut *self.config_fingerprint.lock(), current);
+        let mut retired = self.version();
+        if retired.config_fingerprint != previous {
+            retired.config_fingerprint = previous;
+            self.metrics.update_build_info(&retired, false);
+            self.publish();
+        }
+    }
+
+    fn publish(&self) {
+        self.metrics.update_build_info(&self.version(), true);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::HashMap, time::Duration};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::clock::{Clock, SimClock};
+
+    /// Label sets of every `broker_build_info` series, with their values
+    pub(crate) fn build_info_series(rendered: &str) -> Vec<(HashMap<String, String>, f64)> {
+        rendered
+            .lines()
+            .filter_map(|line| line.strip_prefix("broker_build_info{"))
+            .map(|line| {
+                let (labels, value) = line.rsplit_once("} ").unwrap();
+                let labels = labels
+                    .trim_end_matches('"')
+                    .split("\",")
+                    .map(|pair| {
+                        let (key, value) = pair.split_once("=\"").unwrap();
+                        (key.to_string(), value.to_string())
+                    })
+                    .collect();
+                (labels, value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn build_info_is_exported_once_with_non_empty_labels() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let clock = Arc::new(SimClock::new());
+        metrics::with_local_recorder(&recorder, || {
+            BuildInfo::new("3f2a9c".into(), BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        });
+
+        let rendered = recorder.handle().render();
+        let series = build_info_series(&rendered);
+        let current: Vec<_> = series.iter().filter(|(_, value)| *value == 1.0).collect();
+        assert_eq!(current.len(), 1, "{}", rendered);
+        let labels = &current[0].0;
+        for label in ["version", "git_commit", "rustc", "features", "config_fingerprint"] {
+            assert!(labels.get(label).is_some_and(|value| !value.is_empty()), "empty {} in\n{}", label, rendered);
+        }
+        assert_eq!(labels["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(labels["config_fingerprint"], "3f2a9c");
+        assert!(labels["rustc"].starts_with("rustc "), "{}", labels["rustc"]);
+        let features: Vec<&str> = labels["features"].split(',').collect();
+        assert_eq!(features.contains(&"tls"), cfg!(feature = "tls"));
+        assert_eq!(features.contains(&"jemalloc"), cfg!(feature = "jemalloc"));
+
+        let start = format!("broker_start_time_seconds {}", clock.now_utc().timestamp());
+        assert!(rendered.contains(&start), "missing {} in\n{}", start, rendered);
+    }
+
+    #[test]
+    fn a_reload_moves_the_current_series_to_the_new_fingerprint() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let config = BrokerConfig::load().unwrap();
+        let mut reloaded = config.clone();
+        reloaded.nats_probe.interval += Duration::from_secs(1);
+        let expected = fingerprint(&sanitized(&reloaded));
+
+        let info = metrics::with_local_recorder(&recorder, || {
+            let info = BuildInfo::new(fingerprint(&sanitized(&config)), BrokerMetrics::new().unwrap());
+            info.reloaded(&reloaded);
+            // Reloading an unchanged config retires nothing
+            info.reloaded(&reloaded);
+            info
+        });
+        assert_eq!(info.version().config_fingerprint, expected);
+
+        let series = build_info_series(&recorder.handle().render());
+        assert_eq!(series.len(), 2);
+        for (labels, value) in series {
+            let current = labels["config_fingerprint"] == expected;
+            assert_eq!(value, if current { 1.0 } else { 0.0 }, "{:?}", labels);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use tracing::info;
+
+use crate::metrics::BrokerMetrics;
+
+/// What one shrink freed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reclaimed {
+    pub entries: usize,
+    /// Heap and inline bytes of what was dropped, as counted in `footprint`
+    pub bytes: usize,
+}
+
+impl std::ops::AddAssign for Reclaimed {
+    fn add_assign(&mut self, other: Self) {
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+/// A cache that can give memory back under pressure
+///
+/// `shrink` must be safe at any moment: it may only make the cache forget,
+/// never make it answer wrongly, and what it reports must match the drop
+/// in `footprint` so budget accounting stays truthful.
+pub trait ShrinkableCache: Send + Sync {
+    fn cache_name(&self) -> &'static str;
+
+    /// Bytes the cache currently accounts for
+    fn footprint(&self) -> usize;
+
+    fn shrink(&self) -> Reclaimed;
+
+    /// Pressure is over; grow back to the configured size as entries arrive
+    fn restore(&self);
+}
+
+/// Caches a memory budget can shrink together
+pub struct CacheShrinker {
+    caches: Vec<Arc<dyn ShrinkableCache>>,
+    metrics: BrokerMetrics,
+}
+
+impl CacheShrinker {
+    pub fn new(metrics: BrokerMetrics) -> Self {
+        Self {
+            caches: Vec::new(),
+            metrics,
+        }
+    }
+
+    pub fn register(&mut self, cache: Arc<dyn ShrinkableCache>) {
+        self.caches.push(cache);
+    }
+
+    pub fn footprint(&self) -> usize {
+        self.caches.iter().map(|cache| cache.footprint()).sum()
+    }
+
+    /// Shrink every cache once
+    pub fn shrink(&self) -> Reclaimed {
+        let mut total = Reclaimed::default();
+        for cache in &self.caches {
+            let reclaimed = cache.shrink();
+            self.metrics.record_cache_shrink(cache.cache_name(), reclaimed.entries, reclaimed.bytes);
+            total += reclaimed;
+        }
+        info!("Shrank caches under memory pressure: {} entries, {} bytes", total.entries, total.bytes);
+        total
+    }
+
+    pub fn restore(&self) {
+        for cache in &self.caches {
+            cache.restore();
+        }
+    }
+}
+
+/// Accounted size of one `LruCache<String, V>` entry: key heap, key and
+/// value inline, and the list node's two links
+pub fn lru_entry_bytes<V>(key: &str) -> usize {
+    key.len() + std::mem::size_of::<(String, V)>() + 2 * std::mem::size_of::<usize>()
+}
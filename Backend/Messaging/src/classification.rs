@@ -0,0 +1,554 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use lru::LruCache;
+use parking_lot::Mutex;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    abuse::AbuseScores,
+    clock::{SharedClock, SystemClock},
+    config::ClassificationConfig,
+    config_watch::ConfigWatcher,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Metadata on a delivered message the classifier flagged as spam in `flag` mode
+pub const CLASSIFIER_FLAG_METADATA: &str = "classifier_flag";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Clean,
+    Spam,
+}
+
+/// External spam and content classification service
+#[async_trait]
+pub trait Classifier: Send + Sync {
+    async fn classify(&self, envelope: &MessageEnvelope) -> Result<Verdict, ClassifierError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("classifier error: {0}")]
+pub struct ClassifierError(pub String);
+
+/// What a spam verdict does, per tenant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnforcementMode {
+    /// Reject the message
+    Enforce,
+    /// Deliver with `CLASSIFIER_FLAG_METADATA` set
+    Flag,
+    /// Record the verdict and what enforcement would have done; deliver unchanged
+    Shadow,
+    /// Never classify
+    Off,
+}
+
+impl EnforcementMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EnforcementMode::Enforce => "enforce",
+            EnforcementMode::Flag => "flag",
+            EnforcementMode::Shadow => "shadow",
+            EnforcementMode::Off => "off",
+        }
+    }
+}
+
+/// Why a message was sent to the classifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suspicion {
+    NewSender,
+    HighFanout,
+    AbuseScore,
+}
+
+impl Suspicion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Suspicion::NewSender => "new_sender",
+            Suspicion::HighFanout => "high_fanout",
+            Suspicion::AbuseScore => "abuse_score",
+        }
+    }
+}
+
+/// Outcome of the stage for one message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Classification {
+    /// Not suspicious, classification off, clean, shadowed or failed open
+    Deliver,
+    /// Spam in `flag` mode; delivered with the flag set
+    Flagged,
+    /// Spam in `enforce` mode
+    Blocked,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("message classified as spam")]
+pub struct ClassifiedSpam;
+
+struct CachedVerdict {
+    verdict: Verdict,
+    at: Instant,
+}
+
+/// Inline classification of suspicious messages
+///
+/// Only messages passing a cheap pre-filter reach the classifier: senders
+/// first seen within `classification.new_sender_window` (tracked in a
+/// bounded LRU, so everyone is new again after a restart), messages to at
+/// least `fanout_threshold` recipients, and senders whose abuse score is at
+/// least `abuse_score_threshold`. Verdicts are cached by SHA-256 of the
+/// payload for `cache_ttl`, so a blast of one payload costs one call. Each
+/// call is bounded by `timeout_ms`; a timeout or error delivers the message
+/// and counts in `broker_classifier_failures_total`.
+///
+/// A spam verdict is acted on according to the tenant's mode. Shadow mode
+/// counts what enforcement would have done in
+/// `broker_classification_actions_total{mode="shadow",action="would_block"}`,
+/// for tuning before a tenant is switched to enforce.
+pub struct ClassificationStage {
+    classifier: Arc<dyn Classifier>,
+    config: ArcSwap<ClassificationConfig>,
+    abuse: Arc<AbuseScores>,
+    first_seen: Mutex<LruCache<String, Instant>>,
+    verdicts: Mutex<LruCache<[u8; 32], CachedVerdict>>,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl ClassificationStage {
+    pub fn new(
+        classifier: Arc<dyn Classifier>,
+        config: ClassificationConfig,
+        abuse: Arc<AbuseScores>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            classifier,
+            first_seen: Mutex::new(LruCache::new(NonZeroUsize::new(config.tracked_senders.max(1)).unwrap())),
+            verdicts: Mutex::new(LruCache::new(NonZeroUsize::new(config.cache_size.max(1)).unwrap())),
+            config: ArcSwap::from_pointee(config),
+            abuse,
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn mode_for(&self, tenant_id: Option<&str>) -> EnforcementMode {
+        let config = self.config.load();
+        tenant_id
+            .and_then(|tenant| config.tenant_modes.get(tenant))
+            .copied()
+            .unwrap_or(config.default_mode)
+    }
+
+    /// Classify the message if suspicious and apply the tenant's mode
+    pub async fn classify(&self, envelope: &mut MessageEnvelope) -> Classification {
+        // Only the broker sets the flag
+        envelope.metadata.remove(CLASSIFIER_FLAG_METADATA);
+
+        let config = self.config.load_full();
+        let mode = self.mode_for(envelope.tenant_id.as_deref());
+        if !config.enabled || mode == EnforcementMode::Off {
+            return Classification::Deliver;
+        }
+        let Some(suspicion) = self.suspicion(envelope, &config) else {
+            return Classification::Deliver;
+        };
+        self.metrics.record_classification_candidate(suspicion.as_str());
+
+        let Some(verdict) = self.verdict(envelope, &config).await else {
+            return Classification::Deliver;
+        };
+        if verdict == Verdict::Clean {
+            self.metrics.record_classification_action(mode.as_str(), "deliver");
+            return Classification::Deliver;
+        }
+
+        match mode {
+            EnforcementMode::Enforce => {
+                self.metrics.record_classification_action(mode.as_str(), "block");
+                Classification::Blocked
+            }
+            EnforcementMode::Flag => {
+                self.metrics.record_classification_action(mode.as_str(), "flag");
+                envelope
+                    .metadata
+                    .insert(CLASSIFIER_FLAG_METADATA.to_string(), "spam".to_string());
+                Classification::Flagged
+            }
+            EnforcementMode::Shadow | EnforcementMode::Off => {
+                debug!(
+                    "Shadow classification: would block {} from {} ({})",
+                    envelope.message_id,
+                    envelope.from,
+                    suspicion.as_str()
+                );
+                self.metrics.record_classification_action(mode.as_str(), "would_block");
+                Classification::Deliver
+            }
+        }
+    }
+
+    fn suspicion(&self, envelope: &MessageEnvelope, config: &ClassificationConfig) -> Option<Suspicion> {
+        let now = self.clock.now_instant();
+        let first_seen = *self.first_seen.lock().get_or_insert(envelope.from.clone(), || now);
+        if now.duration_since(first_seen) < config.new_sender_window {
+            return Some(Suspicion::NewSender);
+        }
+        if envelope.to.len() >= config.fanout_threshold {
+            return Some(Suspicion::HighFanout);
+        }
+        if self
+            .abuse
+            .status(&envelope.from)
+            .is_some_and(|record| record.score >= config.abuse_score_threshold)
+        {
+            return Some(Suspicion::AbuseScore);
+        }
+        None
+    }
+
+    /// Cached or fresh verdict; `None` when the classifier failed and the message goes through
+    async fn verdict(&self, envelope: &MessageEnvelope, config: &ClassificationConfig) -> Option<Verdict> {
+        let key = payload_hash(envelope);
+        if let Some(cached) = self.verdicts.lock().get(&key) {
+            if self.clock.now_instant().saturating_duration_since(cached.at) < config.cache_ttl {
+                self.metrics.record_classification_cache("hit");
+                return Some(cached.verdict);
+            }
+        }
+        self.metrics.record_classification_cache("miss");
+
+        let started = self.clock.now_instant();
+        let result = tokio::select! {
+            biased;
+            result = self.classifier.classify(envelope) => Some(result),
+            _ = self.clock.sleep(Duration::from_millis(config.timeout_ms)) => None,
+        };
+        self.metrics
+            .record_classifier_latency(self.clock.now_instant().saturating_duration_since(started).as_secs_f64());
+
+        let verdict = match result {
+            Some(Ok(verdict)) => verdict,
+            Some(Err(e)) => {
+                debug!("Classifier failed for {}, delivering: {}", envelope.message_id, e);
+                self.metrics.record_classifier_failure("error");
+                return None;
+            }
+            None => {
+                self.metrics.record_classifier_failure("timeout");
+                return None;
+            }
+        };
+        self.verdicts.lock().put(
+            key,
+            CachedVerdict {
+                verdict,
+                at: self.clock.now_instant(),
+            },
+        );
+        Some(verdict)
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let stage = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            stage.config.store(Arc::new(config.classification.clone()));
+        });
+    }
+}
+
+fn payload_hash(envelope: &MessageEnvelope) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(digest(&SHA256, envelope.payload.ciphertext.as_bytes()).as_ref());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_nats::jetstream;
+    use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusRecorder};
+
+    use super::*;
+    use crate::{
+        abuse::AbuseSignal,
+        audit::AuditLog,
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        ingestion_pause::IngestionPauses,
+        message::types::{EncryptedPayload, MessageType},
+        rate_limit::UserRateLimiter,
+    };
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    enum Reply {
+        Verdict(Verdict),
+        Error,
+        Hang,
+    }
+
+    /// Classifier answering every call the same way, counting the calls
+    struct MockClassifier {
+        reply: Reply,
+        calls: AtomicUsize,
+    }
+
+    impl MockClassifier {
+        fn new(reply: Reply) -> Arc<Self> {
+            Arc::new(Self {
+                reply,
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl Classifier for MockClassifier {
+        async fn classify(&self, _envelope: &MessageEnvelope) -> Result<Verdict, ClassifierError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match self.reply {
+                Reply::Verdict(verdict) => Ok(verdict),
+                Reply::Error => Err(ClassifierError("unavailable".into())),
+                Reply::Hang => std::future::pending().await,
+            }
+        }
+    }
+
+    struct Fixture {
+        stage: ClassificationStage,
+        classifier: Arc<MockClassifier>,
+        abuse: Arc<AbuseScores>,
+        clock: Arc<SimClock>,
+    }
+
+    /// Pausing never touches NATS, so the client is never connected
+    async fn fixture(reply: Reply, configure: impl FnOnce(&mut ClassificationConfig)) -> Fixture {
+        let config = BrokerConfig::load().unwrap();
+        let mut classification = config.classification.clone();
+        classification.enabled = true;
+        classification.tenant_modes = [
+            ("acme".to_string(), EnforcementMode::Enforce),
+            ("globex".to_string(), EnforcementMode::Flag),
+            ("initech".to_string(), EnforcementMode::Off),
+        ]
+        .into();
+        configure(&mut classification);
+        let mut abuse = config.abuse.clone();
+        abuse.enabled = true;
+
+        // Sleeps that would block jump straight to their deadline, so a hung
+        // classifier times out without waiting
+        let clock = Arc::new(SimClock::new().auto_advance(true));
+        let metrics = BrokerMetrics::new().unwrap();
+        let limiter = Arc::new(UserRateLimiter::new(&config.limits, metrics.clone()).with_clock(clock.clone()));
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("localhost:4222")
+            .await
+            .unwrap();
+        let pauses = Arc::new(
+            IngestionPauses::new(
+                config.ingestion_pause.clone(),
+                jetstream::new(client),
+                "ingress".into(),
+                AuditLog::tracing_only(),
+                metrics.clone(),
+            )
+            .with_clock(clock.clone()),
+        );
+        let abuse = Arc::new(
+            AbuseScores::new(abuse, limiter, pauses, AuditLog::tracing_only(), metrics.clone()).with_clock(clock.clone()),
+        );
+        let classifier = MockClassifier::new(reply);
+        let stage = ClassificationStage::new(classifier.clone(), classification, Arc::clone(&abuse), metrics)
+            .with_clock(clock.clone());
+        Fixture {
+            stage,
+            classifier,
+            abuse,
+            clock,
+        }
+    }
+
+    fn envelope(from: &str, recipients: usize, tenant: Option<&str>, ciphertext: &str) -> MessageEnvelope {
+        let mut envelope = MessageEnvelope::new(
+            MessageType::TextMessage,
+            from.into(),
+            (0..recipients).map(|i| format!("user-{}", i)).collect(),
+            EncryptedPayload {
+                ciphertext: ciphertext.into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        );
+        envelope.tenant_id = tenant.map(str::to_string);
+        envelope
+    }
+
+    fn assert_rendered(recorder: &PrometheusRecorder, lines: &[&str]) {
+        let rendered = recorder.handle().render();
+        for line in lines {
+            assert!(rendered.contains(line), "missing {}\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    async fn spam_is_blocked_flagged_or_shadowed_by_tenant_mode() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let fixture = fixture(Reply::Verdict(Verdict::Spam), |_| {}).await;
+
+        let mut blocked = envelope("mallory", 1, Some("acme"), "YQ==");
+        assert_eq!(fixture.stage.classify(&mut blocked).await, Classification::Blocked);
+
+        let mut flagged = envelope("mallory", 1, Some("globex"), "Yg==");
+        assert_eq!(fixture.stage.classify(&mut flagged).await, Classification::Flagged);
+        assert_eq!(flagged.metadata.get(CLASSIFIER_FLAG_METADATA).map(String::as_str), Some("spam"));
+
+        let mut shadowed = envelope("mallory", 1, None, "Yw==");
+        assert_eq!(fixture.stage.classify(&mut shadowed).await, Classification::Deliver);
+        assert!(!shadowed.metadata.contains_key(CLASSIFIER_FLAG_METADATA));
+
+        let mut off = envelope("mallory", 1, Some("initech"), "ZA==");
+        assert_eq!(fixture.stage.classify(&mut off).await, Classification::Deliver);
+        assert_eq!(fixture.classifier.calls(), 3, "off never calls the classifier");
+
+        assert_rendered(
+            &recorder,
+            &[
+                r#"broker_classification_actions_total{mode="enforce",action="block"} 1"#,
+                r#"broker_classification_actions_total{mode="flag",action="flag"} 1"#,
+                r#"broker_classification_actions_total{mode="shadow",action="would_block"} 1"#,
+                r#"broker_classification_candidates_total{suspicion="new_sender"} 3"#,
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn clean_verdicts_deliver_and_senders_cannot_set_the_flag() {
+        let fixture = fixture(Reply::Verdict(Verdict::Clean), |_| {}).await;
+        let mut forged = envelope("mallory", 1, Some("globex"), "YQ==");
+        forged
+            .metadata
+            .insert(CLASSIFIER_FLAG_METADATA.to_string(), "clean".to_string());
+
+        assert_eq!(fixture.stage.classify(&mut forged).await, Classification::Deliver);
+        assert!(!forged.metadata.contains_key(CLASSIFIER_FLAG_METADATA));
+        assert_eq!(fixture.classifier.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn classifier_errors_and_timeouts_fail_open() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+
+        let failing = fixture(Reply::Error, |_| {}).await;
+        let mut message = envelope("mallory", 1, Some("acme"), "YQ==");
+        assert_eq!(failing.stage.classify(&mut message).await, Classification::Deliver);
+
+        let hung = fixture(Reply::Hang, |config| config.timeout_ms = 50).await;
+        let started = hung.clock.now_instant();
+        let mut message = envelope("mallory", 1, Some("acme"), "YQ==");
+        assert_eq!(hung.stage.classify(&mut message).await, Classification::Deliver);
+        assert_eq!(hung.clock.now_instant() - started, Duration::from_millis(50));
+        assert_eq!(hung.classifier.calls(), 1);
+
+        // Failures aren't cached; the next message asks again
+        let mut message = envelope("mallory", 1, Some("acme"), "YQ==");
+        hung.stage.classify(&mut message).await;
+        assert_eq!(hung.classifier.calls(), 2);
+
+        assert_rendered(
+            &recorder,
+            &[
+                r#"broker_classifier_failures_total{kind="error"} 1"#,
+                r#"broker_classifier_failures_total{kind="timeout"} 2"#,
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_verdicts_skip_the_call_until_they_expire() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let fixture = fixture(Reply::Verdict(Verdict::Spam), |config| config.cache_ttl = Duration::from_secs(600)).await;
+
+        for sender in ["mallory", "trudy", "eve"] {
+            let mut message = envelope(sender, 1, Some("acme"), "c3BhbQ==");
+            assert_eq!(fixture.stage.classify(&mut message).await, Classification::Blocked);
+        }
+        assert_eq!(fixture.classifier.calls(), 1, "one payload costs one call");
+
+        let mut other = envelope("mallory", 1, Some("acme"), "b3RoZXI=");
+        fixture.stage.classify(&mut other).await;
+        assert_eq!(fixture.classifier.calls(), 2);
+
+        fixture.clock.advance(Duration::from_secs(600));
+        let mut message = envelope("mallory", 1, Some("acme"), "c3BhbQ==");
+        assert_eq!(fixture.stage.classify(&mut message).await, Classification::Blocked);
+        assert_eq!(fixture.classifier.calls(), 3);
+
+        assert_rendered(
+            &recorder,
+            &[
+                r#"broker_classification_cache_total{result="hit"} 2"#,
+                r#"broker_classification_cache_total{result="miss"} 3"#,
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn established_senders_are_classified_only_for_fanout_or_abuse() {
+        let fixture = fixture(Reply::Verdict(Verdict::Spam), |config| config.fanout_threshold = 10).await;
+        let mut first = envelope("mallory", 1, Some("acme"), "YQ==");
+        assert_eq!(fixture.stage.classify(&mut first).await, Classification::Blocked);
+
+        fixture.clock.advance(DAY);
+        let mut quiet = envelope("mallory", 9, Some("acme"), "Yg==");
+        assert_eq!(fixture.stage.classify(&mut quiet).await, Classification::Deliver);
+        assert_eq!(fixture.classifier.calls(), 1, "an established sender is past the pre-filter");
+
+        let mut blast = envelope("mallory", 10, Some("acme"), "Yw==");
+        assert_eq!(fixture.stage.classify(&mut blast).await, Classification::Blocked);
+
+        fixture.abuse.observe("mallory", AbuseSignal::SpamFlagged);
+        let mut suspect = envelope("mallory", 1, Some("acme"), "ZA==");
+        assert_eq!(fixture.stage.classify(&mut suspect).await, Classification::Blocked);
+        assert_eq!(fixture.classifier.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn disabled_classification_delivers_everything() {
+        let fixture = fixture(Reply::Verdict(Verdict::Spam), |config| config.enabled = false).await;
+        let mut message = envelope("mallory", 100, Some("acme"), "YQ==");
+        assert_eq!(fixture.stage.classify(&mut message).await, Classification::Deliver);
+        assert_eq!(fixture.classifier.calls(), 0);
+    }
+}
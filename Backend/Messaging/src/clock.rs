@@ -0,0 +1,200 @@
+//! Time source for time-dependent broker logic
+//!
+//! Anything that measures elapsed time, stamps wall-clock times or waits
+//! for a deadline takes a `SharedClock` instead of calling
+//! `Instant::now()`, `SystemTime::now()`, `Utc::now()` or
+//! `tokio::time::sleep` directly. Components default to `SystemClock` and
+//! accept another through a `with_clock` builder method; structures that
+//! hold timers (token buckets, deferred queues) take `now: Instant` as a
+//! parameter from their owner rather than reading a clock themselves, so
+//! one reading is used for a whole operation. `SimClock` stands in for
+//! real time: it only moves when advanced, optionally jumping straight to
+//! the next pending sleep, so nothing waits on real time.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+pub type Sleep<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub trait Clock: Send + Sync + 'static {
+    fn now_instant(&self) -> Instant;
+
+    fn now_system(&self) -> SystemTime;
+
+    /// Resolves once `now_instant()` has reached `deadline`
+    fn sleep_until(&self, deadline: Instant) -> Sleep<'_>;
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        self.sleep_until(self.now_instant() + duration)
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.now_system().into()
+    }
+
+    fn now_millis(&self) -> i64 {
+        self.now_utc().timestamp_millis()
+    }
+}
+
+/// Real time, via tokio's timer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_system(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep<'_> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+struct SimState {
+    elapsed: Duration,
+    /// Deadlines of sleeps in progress, with how many wait on each
+    pending: BTreeMap<Instant, usize>,
+    auto_advance: bool,
+}
+
+/// Clock that moves only when told to
+///
+/// Starts at the real time of its creation. `advance` moves both readings
+/// forward and wakes every sleep whose deadline has passed. With
+/// `auto_advance` on, a sleep that would block instead moves the clock to
+/// the earliest pending deadline, so code under it runs through its
+/// timers back to back.
+pub struct SimClock {
+    start: Instant,
+    system_start: SystemTime,
+    state: Mutex<SimState>,
+    ticks: watch::Sender<Duration>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            state: Mutex::new(SimState {
+                elapsed: Duration::ZERO,
+                pending: BTreeMap::new(),
+                auto_advance: false,
+            }),
+            ticks: watch::channel(Duration::ZERO).0,
+        }
+    }
+
+    pub fn auto_advance(self, enabled: bool) -> Self {
+        self.state.lock().auto_advance = enabled;
+        self
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock();
+        state.elapsed += by;
+        self.ticks.send_replace(state.elapsed);
+    }
+
+    /// Move to the earliest pending sleep deadline; false if nothing is sleeping
+    pub fn advance_to_next(&self) -> bool {
+        let mut state = self.state.lock();
+        let Some(&next) = state.pending.keys().next() else {
+            return false;
+        };
+        self.move_to(&mut state, next);
+        true
+    }
+
+    /// Sleeps that haven't resolved yet
+    pub fn pending(&self) -> usize {
+        self.state.lock().pending.values().sum()
+    }
+
+    fn move_to(&self, state: &mut SimState, deadline: Instant) {
+        let target = deadline.saturating_duration_since(self.start);
+        if target > state.elapsed {
+            state.elapsed = target;
+            self.ticks.send_replace(target);
+        }
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now_instant(&self) -> Instant {
+        self.start + self.state.lock().elapsed
+    }
+
+    fn now_system(&self) -> SystemTime {
+        self.system_start + self.state.lock().elapsed
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep<'_> {
+        let target = deadline.saturating_duration_since(self.start);
+        let mut ticks = self.ticks.subscribe();
+        Box::pin(async move {
+            {
+                let mut state = self.state.lock();
+                if state.elapsed >= target {
+                    return;
+                }
+                *state.pending.entry(deadline).or_default() += 1;
+                if state.auto_advance {
+                    let next = *state.pending.keys().next().unwrap_or(&deadline);
+                    self.move_to(&mut state, next);
+                }
+            }
+            let _pending = PendingSleep { clock: self, deadline };
+            while *ticks.borrow_and_update() < target {
+                if ticks.changed().await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+}
+
+/// Unregisters a sleep however its future ends
+struct PendingSleep<'a> {
+    clock: &'a SimClock,
+    deadline: Instant,
+}
+
+impl Drop for PendingSleep<'_> {
+    fn drop(&mut self) {
+        let mut state = self.clock.state.lock();
+        if let Some(waiting) = state.pending.get_mut(&self.deadline) {
+            *waiting -= 1;
+            if *waiting == 0 {
+                state.pending.remove(&self.deadline);
+            }
+        }
+    }
+}
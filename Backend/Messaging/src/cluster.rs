@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use async_nats::jetstream::kv;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+
+use crate::{
+    config::ClusterConfig,
+    config_override::AppliedOverride,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Broker entry heartbeated into the cluster bucket under `cluster.{broker_id}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub broker_id: String,
+    /// Internal gRPC address for direct forwarding, if advertised
+    pub grpc_addr: Option<String>,
+    /// Timestamp in milliseconds
+    pub last_seen: i64,
+    /// Warm standby: live, but owns no partitions until activated
+    #[serde(default)]
+    pub standby: bool,
+    /// Hash of the broker's sanitized effective config, see `config_drift`
+    #[serde(default)]
+    pub config_fingerprint: Option<String>,
+    /// Runtime config overrides in effect, see `config_override`
+    #[serde(default)]
+    pub config_overrides: Vec<AppliedOverride>,
+    /// NATS probing found the broker's connection degraded, see `nats_probe`
+    #[serde(default)]
+    pub nats_degraded: bool,
+}
+
+/// Live brokers and partition ownership
+///
+/// Ownership of a key (conversation ID) is decided by rendezvous hashing
+/// over the live members, so every broker agrees without coordination.
+/// Members that miss three heartbeats drop out of the view; standby members
+/// stay in it but never own keys. Members advertising a degraded NATS path
+/// own keys only when no healthy member is left.
+pub struct ClusterView {
+    kv: kv::Store,
+    local: PeerInfo,
+    standby: AtomicBool,
+    nats_degraded: AtomicBool,
+    config_fingerprint: ArcSwapOption<String>,
+    config_overrides: ArcSwap<Vec<AppliedOverride>>,
+    members: ArcSwap<HashMap<String, PeerInfo>>,
+    heartbeat_interval: Duration,
+}
+
+impl ClusterView {
+    pub fn new(kv: kv::Store, broker_id: String, config: &ClusterConfig) -> Self {
+        Self {
+            kv,
+            local: PeerInfo {
+                broker_id,
+                grpc_addr: config.advertise_grpc_addr.clone(),
+                last_seen: 0,
+                standby: false,
+                config_fingerprint: None,
+                config_overrides: Vec::new(),
+                nats_degraded: false,
+            },
+            standby: AtomicBool::new(false),
+            nats_degraded: AtomicBool::new(false),
+            config_fingerprint: ArcSwapOption::empty(),
+            config_overrides: ArcSwap::from_pointee(Vec::new()),
+            members: ArcSwap::from_pointee(HashMap::new()),
+            heartbeat_interval: config.heartbeat_interval,
+        }
+    }
+
+    pub fn local_id(&self) -> &str {
+        &self.local.broker_id
+    }
+
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::Release);
+    }
+
+    /// Advertised from the next heartbeat on
+    pub fn set_nats_degraded(&self, degraded: bool) {
+        self.nats_degraded.store(degraded, Ordering::Release);
+    }
+
+    /// Advertised from the next heartbeat on
+    pub fn set_config_fingerprint(&self, fingerprint: String) {
+        self.config_fingerprint.store(Some(Arc::new(fingerprint)));
+    }
+
+    /// Advertised from the next heartbeat on
+    pub fn set_config_overrides(&self, overrides: Vec<AppliedOverride>) {
+        self.config_overrides.store(Arc::new(overrides));
+    }
+
+    /// Live members, including this broker once its heartbeat is seen
+    pub fn members(&self) -> Vec<PeerInfo> {
+        let stale_before = Utc::now().timestamp_millis() - 3 * self.heartbeat_interval.as_millis() as i64;
+        self.members
+            .load()
+            .values()
+            .filter(|peer| peer.last_seen >= stale_before)
+            .cloned()
+            .collect()
+    }
+
+    /// Owning peer of `key`, or `None` when this broker owns it or the view is empty
+    pub fn remote_owner(&self, key: &str) -> Option<PeerInfo> {
+        let candidates: Vec<PeerInfo> = self.members().into_iter().filter(|peer| !peer.standby).collect();
+        let any_healthy = candidates.iter().any(|peer| !peer.nats_degraded);
+        let owner = candidates
+            .into_iter()
+            .filter(|peer| !any_healthy || !peer.nats_degraded)
+            .max_by_key(|peer| rendezvous_weight(&peer.broker_id, key))?;
+        (owner.broker_id != self.local.broker_id).then_some(owner)
+    }
+
+    pub fn spawn(self: &Arc<Self>) {
+        let view = Arc::clone(self);
+        spawn_traced("cluster_heartbeat", TaskContext::new("cluster"), async move {
+            let mut ticker = tokio::time::interval(view.heartbeat_interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = view.heartbeat().await {
+                    warn!("Cluster heartbeat failed: {}", e);
+                }
+            }
+        });
+
+        let view = Arc::clone(self);
+        spawn_traced("cluster_watch", TaskContext::new("cluster"), async move {
+            loop {
+                if let Err(e) = view.watch().await {
+                    warn!("Cluster watch ended: {}", e);
+                }
+                tokio::time::sleep(view.heartbeat_interval).await;
+            }
+        });
+    }
+
+    pub async fn heartbeat(&self) -> Result<(), async_nats::Error> {
+        let mut info = self.local.clone();
+        info.last_seen = Utc::now().timestamp_millis();
+        info.standby = self.standby.load(Ordering::Acquire);
+        info.nats_degraded = self.nats_degraded.load(Ordering::Acquire);
+        info.config_fingerprint = self.config_fingerprint.load_full().map(|f| f.to_string());
+        info.config_overrides = self.config_overrides.load().to_vec();
+        let value = serde_json::to_vec(&info)?;
+        self.kv.put(member_key(&info.broker_id), value.into()).await?;
+        Ok(())
+    }
+
+    async fn watch(&self) -> Result<(), async_nats::Error> {
+        let mut entries = self.kv.watch_with_history("cluster.>").await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let broker_id = entry.key.trim_start_matches("cluster.").to_string();
+
+            let mut members = HashMap::clone(&self.members.load());
+            match entry.operation {
+                kv::Operation::Put => match serde_json::from_slice::<PeerInfo>(&entry.value) {
+                    Ok(info) => {
+                        members.insert(broker_id, info);
+                    }
+                    Err(e) => debug!("Ignoring malformed cluster entry {}: {}", entry.key, e),
+                },
+                kv::Operation::Delete | kv::Operation::Purge => {
+                    members.remove(&broker_id);
+                }
+            }
+            self.members.store(Arc::new(members));
+        }
+        Ok(())
+    }
+}
+
+fn member_key(broker_id: &str) -> String {
+    format!("cluster.{}", broker_id)
+}
+
+/// FNV-1a over `broker_id` and `key`
+fn rendezvous_weight(broker_id: &str, key: &str) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    broker_id
+        .as_bytes()
+        .iter()
+        .chain(b"/")
+        .chain(key.as_bytes())
+        .fold(OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(PRIME))
+}
@@ -0,0 +1,327 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::sync::OnceCell;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::CoalesceConfig,
+    metrics::BrokerMetrics,
+};
+
+/// One computation of a key, shared by everyone who asked while it ran
+struct Flight<V, E> {
+    /// Result and when it was ready
+    result: OnceCell<(Result<V, E>, Instant)>,
+}
+
+impl<V, E> Flight<V, E> {
+    fn expired(&self, window: Duration, now: Instant) -> bool {
+        self.result.get().is_some_and(|(_, at)| now.saturating_duration_since(*at) >= window)
+    }
+}
+
+/// Singleflight for read-only status queries
+///
+/// Concurrent calls with the same key share one computation and its
+/// result, success or error alike; for `coalesce.window_ms` after it
+/// finishes the result is served again without recomputing. Results
+/// heavier than `coalesce.max_entry_bytes` go to the callers that were
+/// waiting but aren't kept for the window, and at most `coalesce.max_keys`
+/// keys are tracked; past that, calls run uncoalesced. Keys must capture
+/// everything the response depends on, and only side-effect-free
+/// endpoints may go through here: a mutation coalesced away wouldn't run.
+pub struct Coalescer<V, E> {
+    endpoint: &'static str,
+    flights: DashMap<String, Arc<Flight<V, E>>>,
+    config: CoalesceConfig,
+    /// Retained size of a result, compared against `max_entry_bytes`
+    weigh: fn(&V) -> usize,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl<V: Clone, E: Clone> Coalescer<V, E> {
+    pub fn new(endpoint: &'static str, config: CoalesceConfig, weigh: fn(&V) -> usize, metrics: BrokerMetrics) -> Self {
+        Self {
+            endpoint,
+            flights: DashMap::new(),
+            config,
+            weigh,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn run<F, Fut>(&self, key: String, compute: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if !self.config.enabled {
+            return compute().await;
+        }
+        let Some(flight) = self.flight(&key) else {
+            self.metrics.record_coalesce(self.endpoint, "bypassed");
+            return compute().await;
+        };
+        if let Some((result, _)) = flight.result.get() {
+            self.metrics.record_coalesce(self.endpoint, "cached");
+            return result.clone();
+        }
+
+        let mut computed = false;
+        let (result, _) = flight
+            .result
+            .get_or_init(|| async {
+                computed = true;
+                (compute().await, self.clock.now_instant())
+            })
+            .await;
+        if !computed {
+            self.metrics.record_coalesce(self.endpoint, "coalesced");
+            return result.clone();
+        }
+
+        self.metrics.record_coalesce(self.endpoint, "computed");
+        let oversized = result.as_ref().is_ok_and(|value| (self.weigh)(value) > self.config.max_entry_bytes);
+        if oversized {
+            self.flights.remove_if(&key, |_, current| Arc::ptr_eq(current, &flight));
+        }
+        result.clone()
+    }
+
+    /// The key's current flight, a new one if it has none or it's expired,
+    /// or `None` when the key limit is reached
+    fn flight(&self, key: &str) -> Option<Arc<Flight<V, E>>> {
+        let window = self.window();
+        let now = self.clock.now_instant();
+        if let Some(flight) = self.flights.get(key) {
+            if !flight.expired(window, now) {
+                return Some(Arc::clone(&flight));
+            }
+        }
+        if self.flights.len() >= self.config.max_keys {
+            self.flights.retain(|_, flight| !flight.expired(window, now));
+            if self.flights.len() >= self.config.max_keys {
+                return None;
+            }
+        }
+
+        let fresh = Arc::new(Flight { result: OnceCell::new() });
+        match self.flights.entry(key.to_string()) {
+            Entry::Occupied(mut entry) if entry.get().expired(window, now) => {
+                entry.insert(Arc::clone(&fresh));
+                Some(fresh)
+            }
+            Entry::Occupied(entry) => Some(Arc::clone(entry.get())),
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::clone(&fresh));
+                Some(fresh)
+            }
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_millis(self.config.window_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::{sync::Semaphore, task::JoinSet};
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const WINDOW: Duration = Duration::from_millis(250);
+
+    fn config() -> CoalesceConfig {
+        let mut config = BrokerConfig::load().unwrap().coalesce;
+        config.enabled = true;
+        config.window_ms = WINDOW.as_millis() as u64;
+        config.max_keys = 100;
+        config.max_entry_bytes = 1024;
+        config
+    }
+
+    fn coalescer(config: CoalesceConfig) -> (Arc<Coalescer<Vec<u8>, String>>, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let coalescer =
+            Coalescer::new("status", config, |value: &Vec<u8>| value.len(), BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (Arc::new(coalescer), clock)
+    }
+
+    /// Counts its runs and holds each open until released
+    struct Computation {
+        runs: AtomicUsize,
+        gate: Semaphore,
+    }
+
+    impl Default for Computation {
+        fn default() -> Self {
+            Self {
+                runs: AtomicUsize::new(0),
+                gate: Semaphore::new(0),
+            }
+        }
+    }
+
+    impl Computation {
+        async fn compute(&self, result: Result<Vec<u8>, String>) -> Result<Vec<u8>, String> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            self.gate.acquire().await.unwrap().forget();
+            result
+        }
+
+        fn runs(&self) -> usize {
+            self.runs.load(Ordering::SeqCst)
+        }
+    }
+
+    /// `callers` concurrent identical calls, released once all have started
+    async fn concurrent(
+        coalescer: &Arc<Coalescer<Vec<u8>, String>>,
+        computation: &Arc<Computation>,
+        callers: usize,
+        result: Result<Vec<u8>, String>,
+    ) -> Vec<Result<Vec<u8>, String>> {
+        let mut calls = JoinSet::new();
+        for _ in 0..callers {
+            let (coalescer, computation, result) = (coalescer.clone(), computation.clone(), result.clone());
+            calls.spawn(async move { coalescer.run("msg-1".into(), || computation.compute(result)).await });
+        }
+        while computation.runs() == 0 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        computation.gate.add_permits(callers);
+        let mut results = Vec::new();
+        while let Some(result) = calls.join_next().await {
+            results.push(result.unwrap());
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn a_hundred_identical_requests_compute_once() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let (coalescer, _) = coalescer(config());
+        let computation = Arc::new(Computation::default());
+
+        let results = concurrent(&coalescer, &computation, 100, Ok(b"delivered".to_vec())).await;
+        assert_eq!(computation.runs(), 1);
+        assert_eq!(results.len(), 100);
+        assert!(results.iter().all(|result| result.as_deref() == Ok(&b"delivered"[..])));
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_coalesced_requests_total{endpoint="status",outcome="computed"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_coalesced_requests_total{endpoint="status",outcome="coalesced"} 99"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn results_are_reused_for_the_window_then_recomputed() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let (coalescer, clock) = coalescer(config());
+        let runs = AtomicUsize::new(0);
+        let compute = || async {
+            Ok::<_, String>(vec![runs.fetch_add(1, Ordering::SeqCst) as u8])
+        };
+
+        assert_eq!(coalescer.run("msg-1".into(), compute).await, Ok(vec![0]));
+        clock.advance(WINDOW - Duration::from_millis(1));
+        assert_eq!(coalescer.run("msg-1".into(), compute).await, Ok(vec![0]));
+        assert_eq!(coalescer.run("msg-2".into(), compute).await, Ok(vec![1]), "keys don't share");
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(coalescer.run("msg-1".into(), compute).await, Ok(vec![2]));
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_coalesced_requests_total{endpoint="status",outcome="computed"} 3"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_coalesced_requests_total{endpoint="status",outcome="cached"} 1"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn errors_are_shared_but_not_kept_past_the_window() {
+        let (coalescer, clock) = coalescer(config());
+        let computation = Arc::new(Computation::default());
+
+        let results = concurrent(&coalescer, &computation, 20, Err("stream unavailable".into())).await;
+        assert_eq!(computation.runs(), 1);
+        assert!(results.iter().all(|result| result.as_ref().unwrap_err() == "stream unavailable"));
+
+        clock.advance(WINDOW);
+        let recovered = coalescer.run("msg-1".into(), || async { Ok(b"delivered".to_vec()) }).await;
+        assert_eq!(recovered.unwrap(), b"delivered");
+    }
+
+    #[tokio::test]
+    async fn oversized_results_and_keys_past_the_limit_are_not_kept() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let mut config = config();
+        config.max_keys = 2;
+        let (coalescer, clock) = coalescer(config);
+        let runs = AtomicUsize::new(0);
+        let compute = |bytes: usize| {
+            let runs = &runs;
+            move || async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, String>(vec![0; bytes])
+            }
+        };
+
+        coalescer.run("big".into(), compute(2048)).await.unwrap();
+        coalescer.run("big".into(), compute(2048)).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2, "over max_entry_bytes, so never reused");
+
+        coalescer.run("a".into(), compute(1)).await.unwrap();
+        coalescer.run("b".into(), compute(1)).await.unwrap();
+        coalescer.run("c".into(), compute(1)).await.unwrap();
+        coalescer.run("c".into(), compute(1)).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 6, "a third key runs uncoalesced");
+        assert_eq!(coalescer.flights.len(), 2);
+
+        // Expired keys make room
+        clock.advance(WINDOW);
+        coalescer.run("c".into(), compute(1)).await.unwrap();
+        coalescer.run("c".into(), compute(1)).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 7);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_coalesced_requests_total{endpoint="status",outcome="bypassed"} 2"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn disabled_coalescing_computes_every_call() {
+        let mut config = config();
+        config.enabled = false;
+        let (coalescer, _) = coalescer(config);
+        let runs = AtomicUsize::new(0);
+        for _ in 0..3 {
+            coalescer
+                .run("msg-1".into(), || async {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, String>(Vec::new())
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(coalescer.flights.is_empty());
+    }
+}
@@ -0,0 +1,855 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::Instant,
+};
+use arc_swap::ArcSwap;
+use async_nats::HeaderMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use dashmap::DashMap;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+use zstd::dict::EncoderDictionary;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::CompressionConfig,
+    config_watch::ConfigWatcher,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+    path_override::{PathContext, PathFeature, PathOverrides},
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Set to `zstd` on egress publishes whose payload is dictionary-compressed
+pub const COMPRESSION_HEADER: &str = "Broker-Compression";
+/// ID of the dictionary an egress payload was compressed with
+pub const DICTIONARY_HEADER: &str = "Broker-Zstd-Dictionary";
+
+/// Resolution of the per-message sampling hash, matching `sampling.rs`
+const SAMPLE_BUCKETS: usize = 1_000_000;
+
+/// Every n-th sample is held out of training to measure the dictionary
+const HOLDOUT_EVERY: usize = 10;
+
+/// Published to `compression.dictionary_subject` when a bucket's dictionary
+/// rotates, and sent in reply to a `DictionaryRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DictionaryAnnouncement {
+    pub dictionary_id: String,
+    pub bucket: String,
+    /// Base64 encoded zstd dictionary
+    pub dictionary: String,
+    /// Dictionary this one replaces, still referenced until `retires_at`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<String>,
+    /// Timestamp in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retires_at: Option<i64>,
+    pub issued_by: String,
+}
+
+/// Sent by a gateway on `compression.request_subject` for a dictionary it lacks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DictionaryRequest {
+    pub gateway_id: String,
+    pub dictionary_id: String,
+}
+
+/// Published by a gateway on `compression.holdings_subject` at startup and
+/// whenever it loads or drops a dictionary; replaces what it held before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatewayDictionaries {
+    pub gateway_id: String,
+    pub held: Vec<String>,
+}
+
+/// Tenant and content type bucket dictionaries are trained for
+pub fn bucket_for(envelope: &MessageEnvelope) -> String {
+    format!(
+        "{}/{}",
+        envelope.tenant_id.as_deref().unwrap_or("-"),
+        envelope.payload.content_type.as_deref().unwrap_or("-")
+    )
+}
+
+/// Content-addressed, so brokers training the same bytes agree on the ID
+fn dictionary_id(raw: &[u8]) -> String {
+    hex::encode(&digest(&SHA256, raw).as_ref()[..8])
+}
+
+/// Serialized envelope with identifiers, ciphertext and metadata values blanked
+///
+/// Only the shape of the JSON is worth learning, and dictionaries leave the
+/// broker, so nothing user-specific may end up in one.
+fn redacted_sample(envelope: &MessageEnvelope) -> Option<Vec<u8>> {
+    let mut envelope = envelope.clone();
+    envelope.message_id.clear();
+    envelope.from.clear();
+    envelope.to.clear();
+    envelope.payload.ciphertext.clear();
+    envelope.member_snapshot = None;
+    for value in envelope.metadata.values_mut() {
+        value.clear();
+    }
+    if let Some(attestation) = envelope.attestation.as_mut() {
+        attestation.connection_id.clear();
+        attestation.signature.clear();
+    }
+    serde_json::to_vec(&envelope).ok()
+}
+
+struct Dictionary {
+    id: String,
+    bucket: String,
+    raw: Bytes,
+    encoder: EncoderDictionary<'static>,
+}
+
+impl Dictionary {
+    fn new(bucket: String, raw: Vec<u8>, level: i32) -> Self {
+        Self {
+            id: dictionary_id(&raw),
+            bucket,
+            encoder: EncoderDictionary::copy(&raw, level),
+            raw: raw.into(),
+        }
+    }
+
+    fn compress(&self, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        zstd::bulk::Compressor::with_prepared_dictionary(&self.encoder)?.compress(payload)
+    }
+
+    fn announcement(&self, replaces: Option<&Dictionary>, retires_at: Option<i64>, broker_id: &str) -> DictionaryAnnouncement {
+        DictionaryAnnouncement {
+            dictionary_id: self.id.clone(),
+            bucket: self.bucket.clone(),
+            dictionary: STANDARD.encode(&self.raw),
+            replaces: replaces.map(|previous| previous.id.clone()),
+            retires_at,
+            issued_by: broker_id.to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    samples: VecDeque<Vec<u8>>,
+    current: Option<Arc<Dictionary>>,
+    /// Replaced dictionary and when it stops being used
+    previous: Option<(Arc<Dictionary>, Instant)>,
+}
+
+impl Bucket {
+    /// Dictionaries usable now, newest first
+    fn usable(&self, now: Instant) -> impl Iterator<Item = &Arc<Dictionary>> {
+        self.current.iter().chain(
+            self.previous
+                .iter()
+                .filter(move |(_, until)| *until > now)
+                .map(|(dictionary, _)| dictionary),
+        )
+    }
+}
+
+/// A bucket due for training: its key, drained samples and current dictionary
+type TrainingInput = (String, Vec<Vec<u8>>, Option<Arc<Dictionary>>);
+
+/// Held-out sizes of a freshly trained dictionary
+struct Training {
+    raw: Vec<u8>,
+    /// Held-out samples compressed without a dictionary
+    plain_bytes: usize,
+    trained_bytes: usize,
+    /// The same samples with the bucket's current dictionary, if any
+    current_bytes: Option<usize>,
+}
+
+fn train_dictionary(
+    samples: Vec<Vec<u8>>,
+    current: Option<Arc<Dictionary>>,
+    max_bytes: usize,
+    level: i32,
+) -> std::io::Result<Training> {
+    let (holdout, training): (Vec<_>, Vec<_>) = samples
+        .into_iter()
+        .enumerate()
+        .partition(|(index, _)| index % HOLDOUT_EVERY == 0);
+    let training: Vec<Vec<u8>> = training.into_iter().map(|(_, sample)| sample).collect();
+    let raw = zstd::dict::from_samples(&training, max_bytes)?;
+    let trained = Dictionary::new(String::new(), raw, level);
+
+    let mut plain_bytes = 0;
+    let mut trained_bytes = 0;
+    let mut current_bytes = current.as_ref().map(|_| 0);
+    for (_, sample) in &holdout {
+        plain_bytes += zstd::bulk::compress(sample, level)?.len();
+        trained_bytes += trained.compress(sample)?.len();
+        if let (Some(total), Some(current)) = (current_bytes.as_mut(), current.as_ref()) {
+            *total += current.compress(sample)?.len();
+        }
+    }
+    Ok(Training {
+        raw: Vec::from(trained.raw),
+        plain_bytes,
+        trained_bytes,
+        current_bytes,
+    })
+}
+
+/// Payload to publish and the headers describing it
+#[derive(Debug, Clone)]
+pub struct EgressPayload {
+    pub payload: Bytes,
+    pub dictionary_id: Option<String>,
+}
+
+impl EgressPayload {
+    fn uncompressed(payload: &Bytes) -> Self {
+        Self {
+            payload: payload.clone(),
+            dictionary_id: None,
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(dictionary_id) = &self.dictionary_id {
+            headers.insert(COMPRESSION_HEADER, "zstd");
+            headers.insert(DICTIONARY_HEADER, dictionary_id.as_str());
+        }
+    }
+}
+
+/// Dictionary chosen for one egress payload, from `DictionaryCompression::plan`
+#[derive(Clone)]
+pub struct CompressionPlan(Option<Arc<Dictionary>>);
+
+impl CompressionPlan {
+    /// `None` when the payload goes out uncompressed
+    pub fn dictionary_id(&self) -> Option<&str> {
+        self.0.as_ref().map(|dictionary| dictionary.id.as_str())
+    }
+}
+
+/// Zstd dictionary compression of small egress payloads
+///
+/// Egress payloads are a few hundred bytes of similarly shaped JSON, which
+/// plain zstd barely shrinks. A `compression.sample_rate` share of them is
+/// sampled, redacted down to its JSON shape, into per tenant and content
+/// type buckets. Every `train_interval` each bucket with `min_samples`
+/// samples gets a dictionary of at most `max_dictionary_bytes`, trained on a
+/// blocking thread and measured on a held-out tenth of the samples. It
+/// replaces the bucket's current dictionary only when it beats both plain
+/// zstd and the current dictionary by `min_improvement`, so a stable traffic
+/// shape doesn't churn dictionaries.
+///
+/// New dictionaries are announced to gateways on `dictionary_subject`, and a
+/// gateway that missed one asks for it by ID on `request_subject`. Gateways
+/// report what they hold on `holdings_subject`, and a payload is compressed
+/// only with a dictionary its gateway holds; otherwise it goes out
+/// uncompressed. A replaced dictionary stays in use for gateways that hold
+/// it, and stays fetchable, for `grace_period` after rotation.
+pub struct DictionaryCompression {
+    broker_id: String,
+    client: async_nats::Client,
+    config: ArcSwap<CompressionConfig>,
+    buckets: DashMap<String, Bucket>,
+    /// Gateway ID -> dictionary IDs it reported holding
+    gateways: DashMap<String, HashSet<String>>,
+    overrides: Arc<PathOverrides>,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl DictionaryCompression {
+    pub fn new(
+        broker_id: String,
+        client: async_nats::Client,
+        config: CompressionConfig,
+        overrides: Arc<PathOverrides>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            broker_id,
+            client,
+            config: ArcSwap::from_pointee(config),
+            buckets: DashMap::new(),
+            gateways: DashMap::new(),
+            overrides,
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keep the envelope's redacted shape as a training sample if selected
+    pub fn sample(&self, envelope: &MessageEnvelope) {
+        let config = self.config.load();
+        let threshold = (config.sample_rate.clamp(0.0, 1.0) * SAMPLE_BUCKETS as f64) as usize;
+        if !config.enabled || shard_for(&envelope.message_id, SAMPLE_BUCKETS) >= threshold {
+            return;
+        }
+        let bucket = bucket_for(envelope);
+        if !self.buckets.contains_key(&bucket) && self.buckets.len() >= config.max_buckets {
+            self.metrics.record_compression_sample("bucket_limit");
+            return;
+        }
+        let Some(sample) = redacted_sample(envelope) else {
+            return;
+        };
+        let mut entry = self.buckets.entry(bucket).or_default();
+        entry.samples.push_back(sample);
+        while entry.samples.len() > config.samples_per_bucket {
+            entry.samples.pop_front();
+        }
+        self.metrics.record_compression_sample("sampled");
+    }
+
+    /// Compress `payload` for `gateway_id` with the bucket's dictionary, if the gateway holds it
+    ///
+    /// A path override forcing compression skips the enabled and minimum
+    /// size checks, but still needs a dictionary the gateway holds.
+    pub fn compress(&self, gateway_id: &str, bucket: &str, payload: &Bytes, path: &PathContext<'_>) -> EgressPayload {
+        let plan = self.plan(gateway_id, bucket, payload, path);
+        self.apply(&plan, payload)
+    }
+
+    /// Pick the dictionary `compress` would use, without compressing
+    ///
+    /// Lets callers that reuse prepared payloads key them by the dictionary
+    /// before paying for compression, then `apply` the same choice.
+    pub fn plan(&self, gateway_id: &str, bucket: &str, payload: &Bytes, path: &PathContext<'_>) -> CompressionPlan {
+        let config = self.config.load();
+        let too_small = payload.len() < config.min_payload_bytes;
+        if !self.overrides.decide(path, PathFeature::Compression, config.enabled && !too_small) {
+            if config.enabled && too_small {
+                self.metrics.record_compression_egress("too_small", 0);
+            }
+            return CompressionPlan(None);
+        }
+
+        let Some(bucket) = self.buckets.get(bucket) else {
+            self.metrics.record_compression_egress("no_dictionary", 0);
+            return CompressionPlan(None);
+        };
+        let held = self.gateways.get(gateway_id);
+        let now = self.clock.now_instant();
+        let usable = bucket
+            .usable(now)
+            .find(|dictionary| held.as_ref().is_some_and(|held| held.contains(&dictionary.id)))
+            .cloned();
+        if usable.is_none() {
+            let outcome = if bucket.current.is_some() { "gateway_missing" } else { "no_dictionary" };
+            self.metrics.record_compression_egress(outcome, 0);
+        }
+        CompressionPlan(usable)
+    }
+
+    /// Compress `payload` as planned
+    pub fn apply(&self, plan: &CompressionPlan, payload: &Bytes) -> EgressPayload {
+        let Some(dictionary) = &plan.0 else {
+            return EgressPayload::uncompressed(payload);
+        };
+        match dictionary.compress(payload) {
+            Ok(compressed) if compressed.len() < payload.len() => {
+                self.metrics
+                    .record_compression_egress("compressed", payload.len() - compressed.len());
+                EgressPayload {
+                    payload: compressed.into(),
+                    dictionary_id: Some(dictionary.id.clone()),
+                }
+            }
+            Ok(_) => {
+                self.metrics.record_compression_egress("no_gain", 0);
+                EgressPayload::uncompressed(payload)
+            }
+            Err(e) => {
+                debug!("Dictionary compression failed for {}: {}", dictionary.bucket, e);
+                self.metrics.record_compression_egress("failed", 0);
+                EgressPayload::uncompressed(payload)
+            }
+        }
+    }
+
+    /// Train every bucket with enough samples, returning how many dictionaries rotated
+    pub async fn train(&self) -> usize {
+        let config = self.config.load_full();
+        let now = self.clock.now_instant();
+        let ready: Vec<TrainingInput> = self
+            .buckets
+            .iter_mut()
+            .filter_map(|mut bucket| {
+                if bucket.previous.as_ref().is_some_and(|(_, until)| *until <= now) {
+                    bucket.previous = None;
+                }
+                if bucket.samples.len() < config.min_samples.max(HOLDOUT_EVERY) {
+                    return None;
+                }
+                // Each dictionary learns from traffic since the last attempt
+                let samples = std::mem::take(&mut bucket.samples).into();
+                Some((bucket.key().clone(), samples, bucket.current.clone()))
+            })
+            .collect();
+
+        let mut rotated = 0;
+        for (bucket, samples, current) in ready {
+            let (max_bytes, level) = (config.max_dictionary_bytes, config.level);
+            let training =
+                match tokio::task::spawn_blocking(move || train_dictionary(samples, current, max_bytes, level)).await {
+                    Ok(Ok(training)) => training,
+                    Ok(Err(e)) => {
+                        warn!("Dictionary training failed for {}: {}", bucket, e);
+                        self.metrics.record_compression_training("failed");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Dictionary training task failed for {}: {}", bucket, e);
+                        self.metrics.record_compression_training("failed");
+                        continue;
+                    }
+                };
+
+            let gain = 1.0 - training.trained_bytes as f64 / training.plain_bytes.max(1) as f64;
+            self.metrics.record_compression_dictionary_gain(gain);
+            let beats = |baseline: usize| {
+                (training.trained_bytes as f64) <= baseline as f64 * (1.0 - config.min_improvement)
+            };
+            if !beats(training.plain_bytes) || training.current_bytes.is_some_and(|current| !beats(current)) {
+                debug!("Kept the dictionary for {}: trained one saves {:.1}%", bucket, gain * 100.0);
+                self.metrics.record_compression_training("kept");
+                continue;
+            }
+
+            let dictionary = Arc::new(Dictionary::new(bucket.clone(), training.raw, config.level));
+            self.rotate(dictionary, &config, gain).await;
+            rotated += 1;
+        }
+        self.update_gauge();
+        rotated
+    }
+
+    async fn rotate(&self, dictionary: Arc<Dictionary>, config: &CompressionConfig, gain: f64) {
+        let retires_at = self.clock.now_millis() + config.grace_period.as_millis() as i64;
+        let announcement = {
+            let mut bucket = self.buckets.entry(dictionary.bucket.clone()).or_default();
+            let replaced = bucket.current.replace(Arc::clone(&dictionary));
+            let announcement = dictionary.announcement(
+                replaced.as_deref(),
+                replaced.is_some().then_some(retires_at),
+                &self.broker_id,
+            );
+            bucket.previous = replaced.map(|previous| (previous, self.clock.now_instant() + config.grace_period));
+            announcement
+        };
+
+        info!(
+            "Rotated the {} dictionary to {} ({} bytes, {:.1}% smaller than plain zstd)",
+            dictionary.bucket,
+            dictionary.id,
+            dictionary.raw.len(),
+            gain * 100.0
+        );
+        self.metrics.record_compression_training("rotated");
+        match serde_json::to_vec(&announcement) {
+            Ok(payload) => {
+                if let Err(e) = self.client.publish(config.dictionary_subject.clone(), payload.into()).await {
+                    // Gateways that missed it fetch it by ID; until then they get uncompressed payloads
+                    warn!("Failed to announce dictionary {}: {}", dictionary.id, e);
+                }
+            }
+            Err(e) => warn!("Failed to encode dictionary {}: {}", dictionary.id, e),
+        }
+    }
+
+    /// Announcement for a dictionary still in use, for a gateway that lacks it
+    fn lookup(&self, dictionary_id: &str) -> Option<DictionaryAnnouncement> {
+        let now = self.clock.now_instant();
+        self.buckets.iter().find_map(|bucket| {
+            bucket
+                .usable(now)
+                .find(|dictionary| dictionary.id == dictionary_id)
+                .map(|dictionary| dictionary.announcement(None, None, &self.broker_id))
+        })
+    }
+
+    pub fn handle_holdings(&self, payload: &[u8]) -> Result<(), CompressionError> {
+        let holdings: GatewayDictionaries =
+            serde_json::from_slice(payload).map_err(|e| CompressionError(e.to_string()))?;
+        self.gateways
+            .insert(holdings.gateway_id, holdings.held.into_iter().collect());
+        Ok(())
+    }
+
+    async fn handle_request(&self, message: async_nats::Message) -> Result<(), CompressionError> {
+        let request: DictionaryRequest =
+            serde_json::from_slice(&message.payload).map_err(|e| CompressionError(e.to_string()))?;
+        // Only the broker holding the dictionary answers; the gateway times out otherwise
+        let (Some(reply), Some(announcement)) = (message.reply, self.lookup(&request.dictionary_id)) else {
+            self.metrics.record_compression_request("unknown");
+            return Ok(());
+        };
+        let payload = serde_json::to_vec(&announcement).map_err(|e| CompressionError(e.to_string()))?;
+        self.client
+            .publish(reply, payload.into())
+            .await
+            .map_err(|e| CompressionError(e.to_string()))?;
+        debug!("Sent dictionary {} to gateway {}", request.dictionary_id, request.gateway_id);
+        self.metrics.record_compression_request("served");
+        Ok(())
+    }
+
+    fn update_gauge(&self) {
+        let now = self.clock.now_instant();
+        let active: usize = self.buckets.iter().map(|bucket| bucket.usable(now).count()).sum();
+        self.metrics.update_compression_dictionaries(active);
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let compression = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            compression.config.store(Arc::new(config.compression.clone()));
+        });
+    }
+
+    pub fn spawn_trainer(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let compression = Arc::clone(self);
+        spawn_traced("dictionary_trainer", TaskContext::new("compression"), async move {
+            loop {
+                let interval = compression.config.load().train_interval;
+                compression.clock.sleep(interval).await;
+                if compression.config.load().enabled {
+                    compression.train().await;
+                }
+            }
+        })
+    }
+
+    pub fn spawn_listener(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let compression = Arc::clone(self);
+        spawn_traced("dictionary_gateways", TaskContext::new("compression"), async move {
+            let config = compression.config.load_full();
+            let subscribed = tokio::try_join!(
+                compression.client.subscribe(config.holdings_subject.clone()),
+                compression.client.subscribe(config.request_subject.clone()),
+            );
+            let (mut holdings, mut requests) = match subscribed {
+                Ok(subscriptions) => subscriptions,
+                Err(e) => {
+                    warn!("Failed to subscribe to gateway dictionary subjects: {}", e);
+                    return;
+                }
+            };
+            loop {
+                tokio::select! {
+                    Some(message) = holdings.next() => {
+                        if let Err(e) = compression.handle_holdings(&message.payload) {
+                            debug!("Dropped malformed dictionary holdings: {}", e);
+                        }
+                    }
+                    Some(message) = requests.next() => {
+                        if let Err(e) = compression.handle_request(message).await {
+                            debug!("Dropped dictionary request: {}", e);
+                        }
+                    }
+                    else => break,
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("malformed gateway dictionary message: {0}")]
+pub struct CompressionError(pub String);
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+        path_override::{OverrideEffect, PathOverrideRule},
+    };
+
+    pub(crate) const BUCKET: &str = "acme/application/json";
+
+    /// Envelope shaped like production chat traffic: random identifiers and
+    /// ciphertext around a repeating set of fields and metadata keys
+    pub(crate) fn envelope(index: usize, group: bool) -> MessageEnvelope {
+        let noise = |salt: &str| hex::encode(&digest(&SHA256, format!("{}-{}", salt, index).as_bytes()).as_ref()[..12]);
+        let payload = EncryptedPayload {
+            ciphertext: STANDARD.encode(noise("ciphertext").repeat(3)),
+            iv: Some(STANDARD.encode(noise("iv"))),
+            tag: Some(STANDARD.encode(noise("tag"))),
+            key_id: Some(format!("key-{}", index % 7)),
+            content_type: Some("application/json".into()),
+        };
+        let (kind, to) = if group {
+            (MessageType::GroupMessage, (0..4).map(|member| format!("user-{}", noise("member") + &member.to_string())).collect())
+        } else {
+            (MessageType::TextMessage, vec![format!("user-{}", noise("to"))])
+        };
+        let mut envelope = MessageEnvelope::new(kind, format!("user-{}", noise("from")), to, payload);
+        envelope.tenant_id = Some("acme".into());
+        envelope.timestamp = 1_700_000_000_000 + index as i64 * 1_000;
+        let metadata: &[(&str, String)] = if group {
+            &[
+                ("group_id", noise("group")),
+                ("group_epoch", (index % 40).to_string()),
+                ("mentions", "[]".into()),
+            ]
+        } else {
+            &[
+                ("client_platform", ["ios", "android", "web"][index % 3].into()),
+                ("client_version", format!("5.{}.{}", index % 4, index % 11)),
+                ("thread_id", noise("thread")),
+            ]
+        };
+        for (key, value) in metadata {
+            envelope.metadata.insert(key.to_string(), value.clone());
+        }
+        envelope
+    }
+
+    pub(crate) fn wire(envelope: &MessageEnvelope) -> Bytes {
+        serde_json::to_vec(envelope).unwrap().into()
+    }
+
+    pub(crate) async fn compression(configure: impl FnOnce(&mut CompressionConfig), clock: &Arc<SimClock>) -> DictionaryCompression {
+        let config = BrokerConfig::load().unwrap();
+        let mut compression = config.compression.clone();
+        compression.sample_rate = 1.0;
+        compression.min_samples = 500;
+        configure(&mut compression);
+        let metrics = BrokerMetrics::new().unwrap();
+        let overrides = Arc::new(PathOverrides::new(
+            config.path_override.clone(),
+            crate::audit::AuditLog::tracing_only(),
+            metrics.clone(),
+        ));
+        // Announcements are published into the void; nothing here waits for a server
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("localhost:4222")
+            .await
+            .unwrap();
+        DictionaryCompression::new("broker-1".into(), client, compression, overrides, metrics).with_clock(clock.clone())
+    }
+
+    pub(crate) fn path(envelope: &MessageEnvelope) -> PathContext<'_> {
+        PathContext::of(envelope, "dm:a:b", Some("gw-1"))
+    }
+
+    /// What a gateway does with an egress payload: decompress with the announced dictionary
+    fn decompress(announcement: &DictionaryAnnouncement, payload: &[u8]) -> Vec<u8> {
+        let raw = STANDARD.decode(&announcement.dictionary).unwrap();
+        zstd::bulk::Decompressor::with_dictionary(&raw)
+            .unwrap()
+            .decompress(payload, 1 << 16)
+            .unwrap()
+    }
+
+    pub(crate) fn hold(compression: &DictionaryCompression, gateway_id: &str, held: &[&str]) {
+        let holdings = GatewayDictionaries {
+            gateway_id: gateway_id.into(),
+            held: held.iter().map(|id| id.to_string()).collect(),
+        };
+        compression.handle_holdings(&serde_json::to_vec(&holdings).unwrap()).unwrap();
+    }
+
+    pub(crate) async fn trained(compression: &DictionaryCompression, group: bool, offset: usize) -> String {
+        for index in offset..offset + 1_000 {
+            compression.sample(&envelope(index, group));
+        }
+        assert_eq!(compression.train().await, 1);
+        compression.buckets.get(BUCKET).unwrap().current.as_ref().unwrap().id.clone()
+    }
+
+    #[tokio::test]
+    async fn trained_dictionaries_round_trip_through_a_gateway() {
+        let compression = compression(|_| {}, &Arc::new(SimClock::new())).await;
+        let dictionary_id = trained(&compression, false, 0).await;
+        hold(&compression, "gw-1", &[&dictionary_id]);
+
+        let announcement = compression.lookup(&dictionary_id).unwrap();
+        assert_eq!((announcement.bucket.as_str(), announcement.replaces.as_ref()), (BUCKET, None));
+        for index in 5_000..5_050 {
+            let message = envelope(index, false);
+            let payload = wire(&message);
+            let egress = compression.compress("gw-1", BUCKET, &payload, &path(&message));
+            assert_eq!(egress.dictionary_id.as_deref(), Some(dictionary_id.as_str()));
+            assert!(egress.payload.len() < payload.len());
+            assert_eq!(decompress(&announcement, &egress.payload), payload);
+
+            let mut headers = HeaderMap::new();
+            egress.apply(&mut headers);
+            assert_eq!(headers.get(COMPRESSION_HEADER).map(|value| value.as_str()), Some("zstd"));
+            assert_eq!(headers.get(DICTIONARY_HEADER).map(|value| value.as_str()), Some(dictionary_id.as_str()));
+        }
+    }
+
+    #[tokio::test]
+    async fn gateways_without_the_dictionary_get_uncompressed_payloads() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let compression = compression(|_| {}, &Arc::new(SimClock::new())).await;
+        let message = envelope(0, false);
+        let payload = wire(&message);
+
+        let untrained = compression.compress("gw-1", BUCKET, &payload, &path(&message));
+        assert_eq!((untrained.dictionary_id, untrained.payload), (None, payload.clone()));
+
+        let dictionary_id = trained(&compression, false, 0).await;
+        hold(&compression, "gw-1", &["some-other-dictionary"]);
+        let missing = compression.compress("gw-1", BUCKET, &payload, &path(&message));
+        assert_eq!((missing.dictionary_id.as_deref(), &missing.payload), (None, &payload));
+        let mut headers = HeaderMap::new();
+        missing.apply(&mut headers);
+        assert!(headers.get(COMPRESSION_HEADER).is_none());
+
+        // The gateway fetches it by ID and reports holding it
+        assert!(compression.lookup("some-other-dictionary").is_none());
+        assert!(compression.lookup(&dictionary_id).is_some());
+        hold(&compression, "gw-1", &[&dictionary_id]);
+        assert!(compression.compress("gw-1", BUCKET, &payload, &path(&message)).dictionary_id.is_some());
+
+        let tiny = Bytes::from_static(b"{}");
+        assert!(compression.compress("gw-1", BUCKET, &tiny, &path(&message)).dictionary_id.is_none());
+
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_compression_egress_total{outcome="no_dictionary"} 1"#,
+            r#"broker_compression_egress_total{outcome="gateway_missing"} 1"#,
+            r#"broker_compression_egress_total{outcome="compressed"} 1"#,
+            r#"broker_compression_egress_total{outcome="too_small"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {}\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    async fn path_overrides_switch_compression_per_gateway() {
+        // Every real payload is below the minimum, so nothing compresses by default
+        let compression = compression(|config| config.min_payload_bytes = 1 << 20, &Arc::new(SimClock::new())).await;
+        let dictionary_id = trained(&compression, false, 0).await;
+        hold(&compression, "gw-1", &[&dictionary_id]);
+        hold(&compression, "gw-2", &[&dictionary_id]);
+        let message = envelope(7_000, false);
+        let payload = wire(&message);
+        let to = |gateway_id| compression.compress(gateway_id, BUCKET, &payload, &PathContext::of(&message, "dm:a:b", Some(gateway_id)));
+        assert!(to("gw-1").dictionary_id.is_none());
+
+        let rule = |id: &str, effect, gateways: &[&str]| PathOverrideRule {
+            id: id.into(),
+            feature: PathFeature::Compression,
+            effect,
+            tenants: vec!["acme".into()],
+            conversations: Vec::new(),
+            gateways: gateways.iter().map(|gateway| gateway.to_string()).collect(),
+            kinds: Vec::new(),
+        };
+        compression.overrides.set(rule("force", OverrideEffect::Force, &["gw-1"]), None, "ops");
+        let forced = to("gw-1");
+        assert_eq!(forced.dictionary_id.as_deref(), Some(dictionary_id.as_str()));
+        assert!(forced.payload.len() < payload.len());
+        assert!(to("gw-2").dictionary_id.is_none());
+
+        // Forcing still needs a dictionary the gateway holds
+        hold(&compression, "gw-1", &[]);
+        assert!(to("gw-1").dictionary_id.is_none());
+        hold(&compression, "gw-1", &[&dictionary_id]);
+
+        compression.overrides.set(rule("forbid", OverrideEffect::Forbid, &[]), None, "ops");
+        assert!(to("gw-1").dictionary_id.is_none());
+        compression.overrides.clear("forbid", "ops");
+        assert!(to("gw-1").dictionary_id.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_dictionary_beats_plain_zstd_on_small_payloads() {
+        let compression = compression(|_| {}, &Arc::new(SimClock::new())).await;
+        let dictionary_id = trained(&compression, false, 0).await;
+        hold(&compression, "gw-1", &[&dictionary_id]);
+
+        let (mut raw, mut plain, mut dictionary) = (0, 0, 0);
+        for index in 10_000..10_200 {
+            let message = envelope(index, false);
+            let payload = wire(&message);
+            raw += payload.len();
+            plain += zstd::bulk::compress(&payload, 3).unwrap().len();
+            dictionary += compression.compress("gw-1", BUCKET, &payload, &path(&message)).payload.len();
+        }
+        let ratio = |compressed: usize| compressed as f64 / raw as f64;
+        assert!(
+            ratio(dictionary) < ratio(plain) * 0.8,
+            "dictionary {:.2} vs plain {:.2} of {} bytes",
+            ratio(dictionary),
+            ratio(plain),
+            raw
+        );
+    }
+
+    #[tokio::test]
+    async fn a_replaced_dictionary_stays_usable_for_the_grace_period() {
+        let clock = Arc::new(SimClock::new());
+        let compression = compression(
+            |config| {
+                config.grace_period = Duration::from_secs(900);
+                config.min_improvement = 0.1;
+            },
+            &clock,
+        )
+        .await;
+        let first = trained(&compression, false, 0).await;
+
+        // Same traffic again: not enough better to rotate
+        for index in 1_000..2_000 {
+            compression.sample(&envelope(index, false));
+        }
+        assert_eq!(compression.train().await, 0);
+
+        // The bucket's traffic changes shape
+        let second = trained(&compression, true, 2_000).await;
+        assert_ne!(first, second);
+        assert_eq!(compression.buckets.get(BUCKET).unwrap().previous.as_ref().unwrap().0.id, first);
+
+        hold(&compression, "gw-old", &[&first]);
+        hold(&compression, "gw-1", &[&first, &second]);
+        let message = envelope(9_000, true);
+        let payload = wire(&message);
+        assert_eq!(compression.compress("gw-1", BUCKET, &payload, &path(&message)).dictionary_id, Some(second.clone()));
+        let old = compression.compress("gw-old", BUCKET, &payload, &path(&message));
+        assert_eq!(old.dictionary_id.as_deref(), Some(first.as_str()));
+        assert_eq!(decompress(&compression.lookup(&first).unwrap(), &old.payload), payload);
+
+        clock.advance(Duration::from_secs(900));
+        assert!(compression.lookup(&first).is_none());
+        assert!(compression.compress("gw-old", BUCKET, &payload, &path(&message)).dictionary_id.is_none());
+        assert_eq!(compression.compress("gw-1", BUCKET, &payload, &path(&message)).dictionary_id, Some(second));
+    }
+
+    #[test]
+    fn samples_keep_the_shape_but_nothing_user_specific() {
+        let message = envelope(42, false);
+        let sample = String::from_utf8(redacted_sample(&message).unwrap()).unwrap();
+        for secret in [&message.from, &message.to[0], &message.message_id, &message.payload.ciphertext] {
+            assert!(!sample.contains(secret.as_str()), "{} leaked into {}", secret, sample);
+        }
+        for value in message.metadata.values() {
+            assert!(!sample.contains(value.as_str()), "{} leaked into {}", value, sample);
+        }
+        assert!(sample.contains("client_platform") && sample.contains("\"tenant_id\":\"acme\""), "{}", sample);
+    }
+}
@@ -1,17 +1,120 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, time::Duration};
+use crate::{
+    abuse::AbuseSignal,
+    api::auth::Scope,
+    background_quota::WorkerClass,
+    classification::EnforcementMode,
+    degradation::DegradationToggles,
+    egress_cipher::UnpinnedPolicy,
+    egress_redaction::{RedactionAction, RedactionRule},
+    ingestion_pause::PauseAction,
+    ingress_admission::SourceClass,
+    kind_budget::TrafficKind,
+    retry_classifier::{PublishErrorKind, RetryAction},
+    shed_exemption::ExemptionSelector,
+    message::types::{MessageType, Priority},
+    policy::PolicyConfig,
+    preview::PreviewMode,
+    webhook_replay::TimestampUnit,
+    sampling::PayloadRedaction,
+    slo::SliIndicator,
+};
 use serde::{Deserialize, Serialize};
-use config::{Config, ConfigError, Environment, File};
+use config::{Config, ConfigError, Environment, File, ValueKind};
+use tracing::warn;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BrokerConfig {
     pub broker_id: String,
     pub environment: String,
     
+    /// Reject out-of-range numeric values instead of clamping them
+    pub strict_config: bool,
+    /// Fields clamped into range at load time, for the startup metrics
+    #[serde(skip)]
+    pub clamped_fields: Vec<&'static str>,
+    
+    /// How often config sources are re-read for hot-reloadable sections
+    #[serde(with = "duration_secs")]
+    pub config_reload_interval: Duration,
+    
     pub nats: NatsConfig,
     pub api: ApiConfig,
     pub routing: RoutingConfig,
     pub metrics: MetricsConfig,
     pub limits: RateLimits,
+    pub degradation: DegradationConfig,
+    pub attestation: AttestationConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    pub priority_inheritance: PriorityInheritanceConfig,
+    pub sampling: SamplingConfig,
+    pub cluster: ClusterConfig,
+    pub ingestion_pause: IngestionPauseConfig,
+    pub retry: RetryConfig,
+    pub background_quota: BackgroundQuotaConfig,
+    #[serde(default)]
+    pub content_types: ContentTypePolicyConfig,
+    pub session_migration: SessionMigrationConfig,
+    pub conversation_limit: ConversationLimitConfig,
+    pub size_accounting: SizeAccountingConfig,
+    pub control: ControlConfig,
+    pub standby: StandbyConfig,
+    pub receipts: ReceiptConfig,
+    pub route_warming: RouteWarmingConfig,
+    pub dr_replication: DrReplicationConfig,
+    pub warmup: WarmupConfig,
+    pub threads: ThreadConfig,
+    pub integrity: IntegrityConfig,
+    pub invitations: InvitationConfig,
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub slo: SloConfig,
+    pub maintenance: MaintenanceConfig,
+    pub config_drift: ConfigDriftConfig,
+    pub quota_feedback: QuotaFeedbackConfig,
+    pub conversation_lifecycle: ConversationLifecycleConfig,
+    pub envelope_guard: EnvelopeGuardConfig,
+    pub lock_metrics: LockMetricsConfig,
+    pub kind_budgets: KindBudgetConfig,
+    pub offline_transfer: OfflineTransferConfig,
+    pub tls_monitor: TlsMonitorConfig,
+    pub abuse: AbuseScoreConfig,
+    pub recipient_trace: RecipientTraceConfig,
+    pub config_overrides: ConfigOverrideConfig,
+    pub classification: ClassificationConfig,
+    pub offline_quarantine: OfflineQuarantineConfig,
+    pub compression: CompressionConfig,
+    pub profiling: ProfilingConfig,
+    pub persist_dedup: PersistDedupConfig,
+    pub egress_cipher: EgressCipherConfig,
+    pub conversation_home: ConversationHomeConfig,
+    pub unread: UnreadConfig,
+    pub coalesce: CoalesceConfig,
+    pub kv_compaction: KvCompactionConfig,
+    pub path_override: PathOverrideConfig,
+    pub nats_probe: NatsProbeConfig,
+    pub route_repair: RouteRepairConfig,
+    pub ingress_admission: IngressAdmissionConfig,
+    pub interest_reconcile: InterestReconcileConfig,
+    pub audit: AuditConfig,
+    pub preview: PreviewConfig,
+    pub volume_accounting: VolumeAccountingConfig,
+    pub webhook_replay: WebhookReplayConfig,
+    pub conversation_bootstrap: ConversationBootstrapConfig,
+    pub shed_exemptions: ShedExemptionConfig,
+    pub history_cache: HistoryCacheConfig,
+    pub nak: NakConfig,
+    pub tenants: TenantConfig,
+    pub user_events: UserEventsConfig,
+    pub soft_handoff: SoftHandoffConfig,
+    pub payload_intern: PayloadInternConfig,
+    #[serde(default)]
+    pub sanitize: SanitizeConfig,
+    #[serde(default)]
+    pub egress_redaction: EgressRedactionConfig,
+    #[cfg(feature = "outbox")]
+    pub outbox: Option<OutboxConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,16 +132,75 @@ pub struct NatsConfig {
     pub egress_user_prefix: String,
     pub egress_group_prefix: String,
     pub control_topic: String,
+    pub dead_letter_topic: String,
+    /// Hand-off events for recipients that missed their delivery deadline
+    pub delivery_fallback_topic: String,
     
     // JetStream for persistence
     pub stream_name: String,
     pub consumer_name: String,
+    /// How long a consumer handover waits for in-flight messages to be acked
+    #[serde(with = "duration_secs")]
+    pub handover_drain_timeout: Duration,
+    
+    /// Mirror stream serving read-only operations (history, gap repair, DLQ stats)
+    pub read_stream_name: Option<String>,
+    /// Reads fall back to the primary when the mirror trails by more sequences
+    pub mirror_max_lag: u64,
+    #[serde(with = "duration_secs")]
+    pub mirror_lag_check_interval: Duration,
+    
+    /// Per-conversation history subjects are `{prefix}.{conversation_id}`
+    pub history_subject_prefix: String,
+    pub history_max_page_size: usize,
+    
+    /// KV bucket for crash-recovery checkpoints
+    pub checkpoint_bucket: String,
+    /// KV bucket holding per-user presence records
+    pub presence_bucket: String,
+    /// KV bucket holding per-user, per-conversation read horizons
+    pub read_horizon_bucket: String,
+    /// KV bucket holding key distribution snapshots and delivery accounting
+    pub key_distribution_bucket: String,
+    /// KV bucket holding compacted per-message delivery summaries; its max age
+    /// bounds how long status stays queryable
+    pub delivery_status_bucket: String,
+    /// KV bucket holding per-conversation sequence high-water marks
+    pub sequence_bucket: String,
+    
+    /// Probe subject permissions at startup
+    pub acl_check_enabled: bool,
+    /// Refuse to start when a required subject is denied
+    pub acl_check_fail_on_deny: bool,
+    
+    /// Old subject/stream names kept for a rename transition window
+    #[serde(default)]
+    pub migration: Option<StreamMigrationConfig>,
     
+    #[serde(with = "duration_secs")]
     pub connect_timeout: Duration,
+    #[serde(with = "duration_secs")]
     pub reconnect_delay: Duration,
     pub max_reconnects: Option<usize>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamMigrationConfig {
+    pub old_ingress_topic: String,
+    pub old_stream_name: String,
+    /// Durable consumer draining the old ingress subject
+    pub old_consumer_name: String,
+    /// Prefix rewrites for other old subjects (offline queues, DLQ)
+    #[serde(default)]
+    pub subject_mappings: Vec<SubjectMapping>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubjectMapping {
+    pub from: String,
+    pub to: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiConfig {
     pub grpc_addr: SocketAddr,
@@ -48,6 +210,60 @@ pub struct ApiConfig {
     
     pub max_concurrent_streams: u32,
     pub max_frame_size: usize,
+    
+    /// Frames buffered per Subscribe stream
+    pub subscribe_buffer_size: usize,
+    /// Buffer size an idle stream is shrunk to
+    pub subscribe_min_buffer_size: usize,
+    /// No deliveries or keepalives for this long shrinks the buffer
+    #[serde(with = "duration_secs")]
+    pub subscribe_idle_timeout: Duration,
+    /// No deliveries or keepalives for this long closes the stream
+    #[serde(with = "duration_secs")]
+    pub subscribe_hard_timeout: Duration,
+    /// How often the broker sends a keepalive frame on each Subscribe stream
+    #[serde(with = "duration_secs")]
+    pub subscribe_keepalive_interval: Duration,
+    /// A keepalive frame not acked within this marks the stream wedged
+    pub subscribe_keepalive_timeout_ms: u64,
+    /// A wedged stream that hasn't acked by then is closed
+    #[serde(with = "duration_secs")]
+    pub subscribe_wedged_grace: Duration,
+    /// gRPC handlers treat a client's deadline as this much earlier, leaving time to release what they hold
+    pub deadline_margin_ms: u64,
+    
+    pub auth: ApiAuthConfig,
+}
+
+/// API key authentication for the REST and gRPC surfaces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiAuthConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub keys: Vec<ApiKeyConfig>,
+    /// JSON list of `ApiKeyConfig`, e.g. a mounted secret; merged with `keys`
+    #[serde(default)]
+    pub keys_file: Option<String>,
+    #[serde(with = "duration_secs")]
+    pub reload_interval: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// Logged and audited in place of the secret
+    pub id: String,
+    /// Hex SHA-256 of the secret
+    pub sha256: String,
+    pub scopes: Vec<Scope>,
+    /// User the key was issued to; user-owned records, e.g. key distribution status, are only shown to them
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Tenant the key was issued to; it may then only call `TENANT_ROUTES`, for that tenant
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    /// Rejected after this instant; set on the old key during rotation
+    #[serde(default)]
+    pub not_after: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,11 +272,86 @@ pub struct RoutingConfig {
     pub fanout_batch_size: usize,
     pub fanout_parallelism: usize,
     
+    #[serde(with = "duration_secs")]
     pub presence_ttl: Duration,
+    #[serde(with = "duration_secs")]
     pub typing_ttl: Duration,
     
+    /// Read receipts for the same conversation within this window share one KV write
+    pub read_horizon_coalesce_ms: u64,
+    
+    /// How long per-recipient delivery statuses stay in memory before compaction
+    #[serde(with = "duration_secs")]
+    pub delivery_status_retention: Duration,
+    /// In-memory per-recipient statuses above which the oldest are compacted early
+    pub delivery_status_max_hot_recipients: usize,
+    
+    /// Offline retention for key distributions to snapshotted members
+    #[serde(with = "duration_secs")]
+    pub key_distribution_offline_retention: Duration,
+    
+    /// Recent deliveries remembered per recipient for delivery ID reuse on retry
+    pub delivery_id_recent_per_recipient: usize,
+    /// Recipients tracked per shard for delivery ID reuse
+    pub delivery_id_recipients_per_shard: usize,
+    
+    /// Sequences claimed from KV at a time; larger blocks mean less KV
+    /// traffic but bigger gaps after a failover
+    pub sequence_block_size: u64,
+    /// Fraction of a block left when the next one is claimed in the background
+    pub sequence_prefetch_fraction: f64,
+    
+    /// Users written per chunk when expanding a bulk presence refresh
+    pub presence_bulk_chunk_size: usize,
+    /// Concurrent KV writes while expanding a bulk presence refresh
+    pub presence_bulk_concurrency: usize,
+    
     pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub route_cache_ttl: Duration,
+    /// Budget for cached "no such user" lookups, separate from `cache_size`
+    pub negative_cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub negative_cache_ttl: Duration,
+    /// IDs per dedup bloom generation; two generations are kept
     pub bloom_filter_size: usize,
+    /// Exact message IDs behind the dedup bloom filters
+    pub dedup_cache_size: usize,
+    
+    /// Subjects with activity inside this window count as active topics
+    #[serde(with = "duration_secs")]
+    pub subject_active_window: Duration,
+    /// Per-conversation state is collected after this much inactivity
+    #[serde(with = "duration_secs")]
+    pub conversation_idle_timeout: Duration,
+    #[serde(with = "duration_secs")]
+    pub conversation_gc_interval: Duration,
+    
+    /// How long resolved group memberships are cached
+    #[serde(with = "duration_secs")]
+    pub membership_ttl: Duration,
+    /// Relative size change that flags a resolved membership as suspicious
+    pub membership_delta_threshold: f64,
+    /// Reject messages to groups over `limits.max_group_size` instead of truncating
+    pub reject_oversized_groups: bool,
+    
+    pub adaptive_batching: AdaptiveBatchingConfig,
+}
+
+/// AIMD fanout batch sizing; disabled pins every class to `fanout_batch_size`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdaptiveBatchingConfig {
+    pub enabled: bool,
+    pub min_batch_size: usize,
+    pub max_batch_size: usize,
+    
+    /// Publishes observed before each adjustment
+    pub window_publishes: u64,
+    pub target_publish_latency_ms: u64,
+    pub max_failure_rate: f64,
+    
+    pub additive_step: usize,
+    pub decrease_factor: f64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -69,6 +360,18 @@ pub struct MetricsConfig {
     pub log_level: String,
     pub enable_tracing: bool,
     pub otel_endpoint: Option<String>,
+    pub audit_log_path: Option<String>,
+    
+    /// Tenants with scoped metrics views, keyed by tenant ID with the bearer token as value
+    #[serde(default)]
+    pub metered_tenants: HashMap<String, String>,
+    
+    /// Prefix for every broker metric name, for embedding several brokers in one process
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Labels added to every broker series, e.g. `broker_id`
+    #[serde(default)]
+    pub constant_labels: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -80,21 +383,1262 @@ pub struct RateLimits {
     pub max_group_size: usize,
     
     pub user_message_limit: u32,
+    #[serde(with = "duration_secs")]
     pub user_message_window: Duration,
     pub connection_limit_per_user: u32,
+    
+    /// A window counts as quiet when the user sends at most this fraction of `user_message_limit`
+    pub burst_credit_quiet_fraction: f64,
+    /// Consecutive quiet windows before burst credit starts accruing
+    pub burst_credit_quiet_windows: u32,
+    /// Credit earned per further quiet window
+    pub burst_credit_per_window: u32,
+    /// Credit cap; 0 disables earn-back
+    pub burst_credit_max: u32,
+    /// High priority messages held per rate-limited user instead of rejected; 0 rejects them
+    pub deferred_queue_size: usize,
+    
+    /// Maximum messages in one SendTransaction batch
+    pub max_transaction_messages: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// KV bucket holding broker heartbeats
+    pub bucket: String,
+    #[serde(with = "duration_secs")]
+    pub heartbeat_interval: Duration,
+    /// Internal gRPC address peers use for `ForwardMessage`
+    pub advertise_grpc_addr: Option<String>,
+    
+    /// Forward messages for peer-owned conversations over gRPC instead of NATS
+    pub direct_forwarding: bool,
+    pub forward_timeout_ms: u64,
+}
+
+/// Ingress-to-egress message size tracking
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizeAccountingConfig {
+    /// Egress frame over ingress payload size above which a warning is logged
+    pub warn_factor: f64,
+    /// Fraction of over-factor messages that are actually logged
+    pub warn_sample_rate: f64,
+}
+
+/// User-visible metadata fields cleaned at ingress; payloads are never touched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizeConfig {
+    #[serde(default)]
+    pub fields: Vec<SanitizedField>,
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> Self {
+        Self {
+            fields: vec![
+                SanitizedField { key: "display_name".to_string(), max_codepoints: 64 },
+                SanitizedField { key: "conversation_title".to_string(), max_codepoints: 128 },
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SanitizedField {
+    /// Key in the envelope's `metadata` map
+    pub key: String,
+    /// Longer values are cut, ending in `…`
+    pub max_codepoints: usize,
+}
+
+/// Reuse of compressed and checksummed egress payloads across identical publishes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayloadInternConfig {
+    pub enabled: bool,
+    /// Budget for interned artifacts, split evenly across shards
+    pub max_bytes: usize,
+    pub shards: usize,
+    /// Larger encoded payloads are never interned
+    pub max_payload_bytes: usize,
+    #[serde(with = "duration_secs")]
+    pub ttl: Duration,
+    /// Tenants whose payloads are never kept beyond their own fanout
+    #[serde(default)]
+    pub regulated_tenants: HashSet<String>,
+}
+
+/// Timers handed to a successor broker across a drain-restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoftHandoffConfig {
+    pub enabled: bool,
+    /// KV bucket for handoff records; create it with `max_age` of `record_ttl`
+    pub bucket: String,
+    /// Brokers only claim records of their own slot, e.g. a StatefulSet
+    /// ordinal; empty shares one pool across the fleet
+    #[serde(default)]
+    pub slot: String,
+    /// Older records are discarded rather than restored
+    #[serde(with = "duration_secs")]
+    pub record_ttl: Duration,
+    /// Deadlines carried per record, soonest first
+    pub max_deadlines: usize,
+}
+
+/// Per-user rings of recent broker events for `/admin/users/:user_id/recent`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEventsConfig {
+    pub enabled: bool,
+    /// Events kept per user
+    pub ring_size: usize,
+    pub shards: usize,
+    /// Rings per shard; the least recently active user is evicted past it
+    pub users_per_shard: usize,
+    /// Users idle longer than this are dropped
+    #[serde(with = "duration_secs")]
+    pub active_window: Duration,
+    #[serde(with = "duration_secs")]
+    pub sweep_interval: Duration,
+}
+
+/// Tenants provisioned through `/admin/tenants`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantConfig {
+    /// KV bucket holding tenant records
+    pub bucket: String,
+    /// Each tenant gets stream `{stream_prefix}_{tenant_id}`
+    pub stream_prefix: String,
+    /// capturing `{subject_prefix}.{tenant_id}.>`
+    pub subject_prefix: String,
+    /// Categories a tenant spec may name, by name
+    #[serde(default = "default_retention_categories")]
+    pub retention_categories: HashMap<String, RetentionCategory>,
+    /// How long a soft-deleted tenant can be restored
+    #[serde(with = "duration_secs")]
+    pub delete_grace: Duration,
+    /// How often records are re-read from KV and expired deletions purged
+    #[serde(with = "duration_secs")]
+    pub sync_interval: Duration,
+}
+
+/// Limits of a tenant's stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionCategory {
+    #[serde(with = "duration_secs")]
+    pub max_age: Duration,
+    /// Unset leaves the stream unbounded by size
+    #[serde(default)]
+    pub max_bytes: Option<i64>,
+}
+
+fn default_retention_categories() -> HashMap<String, RetentionCategory> {
+    HashMap::from([
+        (
+            "standard".to_string(),
+            RetentionCategory { max_age: Duration::from_secs(30 * 86400), max_bytes: None },
+        ),
+        (
+            "short".to_string(),
+            RetentionCategory { max_age: Duration::from_secs(7 * 86400), max_bytes: None },
+        ),
+    ])
+}
+
+/// Delays on ingress NAKs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NakConfig {
+    /// Cap on hinted delays
+    pub max_delay_ms: u64,
+    /// Unhinted delay on the first attempt, doubled per further attempt
+    pub base_delay_ms: u64,
+    pub max_default_delay_ms: u64,
+    /// NAKed messages whose earliest redelivery is remembered
+    pub max_tracked: usize,
+}
+
+/// Cached tail pages of `FetchHistory`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryCacheConfig {
+    pub enabled: bool,
+    /// Conversations with a cached page, least recently read evicted first
+    pub max_conversations: usize,
+    /// Pages with more payload bytes than this are never cached
+    pub max_page_bytes: usize,
+}
+
+/// Traffic never dropped or deferred by shedding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShedExemptionConfig {
+    #[serde(default)]
+    pub selectors: Vec<ExemptionSelector>,
+    /// Metadata key carrying the conversation label for `tenant_label` selectors
+    pub label_key: String,
+    /// Static and runtime selectors together
+    pub max_entries: usize,
+}
+
+/// First-message fast path and `ConversationWillStart` hints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationBootstrapConfig {
+    pub enabled: bool,
+    /// Hinted state with no traffic is dropped after this long
+    #[serde(with = "duration_secs")]
+    pub hint_ttl: Duration,
+    pub max_speculative: usize,
+    /// Deferred state is created anyway if the first delivery isn't reported by then
+    #[serde(with = "duration_secs")]
+    pub deferred_timeout: Duration,
+    #[serde(with = "duration_secs")]
+    pub sweep_interval: Duration,
+}
+
+/// Replay protection for signed webhook bridge requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookReplayConfig {
+    pub enabled: bool,
+    /// How old a provider timestamp may be, on top of `clock_skew_ms`
+    #[serde(with = "duration_secs")]
+    pub freshness_window: Duration,
+    /// Tolerated difference between the provider's clock and ours, either way
+    pub clock_skew_ms: u64,
+    pub max_nonces_per_provider: usize,
+    /// Keyed by provider name, as used in the bridge routes
+    #[serde(default)]
+    pub providers: HashMap<String, WebhookProviderConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookProviderConfig {
+    pub timestamp_header: String,
+    #[serde(default)]
+    pub timestamp_unit: TimestampUnit,
+    /// Per-request nonce; providers without one are deduplicated by signature
+    #[serde(default)]
+    pub nonce_header: Option<String>,
+    pub signature_header: String,
+}
+
+/// Per-conversation delivered volume appended as JetStream deltas
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeAccountingConfig {
+    pub enabled: bool,
+    pub subject: String,
+    /// Stream holding `subject`, owned downstream; its duplicate window is widened
+    pub stream: String,
+    #[serde(with = "duration_secs")]
+    pub flush_interval: Duration,
+    pub shards: usize,
+    /// Conversations tracked per interval before folding into `_overflow`
+    pub max_conversations: usize,
+    /// Failed deltas kept for retry
+    pub max_unconfirmed: usize,
+}
+
+/// Message previews for push hand-off and the last-message cache
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewConfig {
+    pub default: PreviewPolicy,
+    /// Replaces `default` entirely for the named tenant
+    #[serde(default)]
+    pub tenants: HashMap<String, PreviewPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewPolicy {
+    #[serde(default)]
+    pub mode: PreviewMode,
+    /// Grapheme clusters of text kept, the ellipsis included
+    #[serde(default = "PreviewPolicy::default_max_graphemes")]
+    pub max_graphemes: usize,
+    /// Shown for every message in `placeholder` mode
+    #[serde(default = "PreviewPolicy::default_placeholder")]
+    pub placeholder: String,
+    /// Labels for non-text content keyed by content type pattern
+    /// (`image/png`, `image/*`, `*/*`); unmatched kinds use built-in labels
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+impl PreviewPolicy {
+    fn default_max_graphemes() -> usize {
+        100
+    }
+
+    fn default_placeholder() -> String {
+        "New message".to_string()
+    }
+}
+
+/// Hash-chained audit file written at `metrics.audit_log_path`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// Rotate once the current file reaches this size; 0 never rotates
+    pub max_file_bytes: u64,
+    /// Subject the chain head is published on; empty disables anchoring
+    pub anchor_subject: String,
+    #[serde(with = "duration_secs")]
+    pub anchor_interval: Duration,
+}
+
+/// Periodic check of registered gateways against their subscription interest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterestReconcileConfig {
+    pub enabled: bool,
+    #[serde(with = "duration_secs")]
+    pub interval: Duration,
+    pub probe_timeout_ms: u64,
+    /// Consecutive mismatched cycles before a gateway's users are evicted
+    pub confirm_cycles: u32,
+}
+
+/// Weighted-fair admission between ingress sources
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngressAdmissionConfig {
+    pub enabled: bool,
+    /// Ingress capacity shared by all source classes
+    pub messages_per_second: u32,
+    /// Relative shares of busy classes; missing classes weigh 1
+    #[serde(default)]
+    pub shares: HashMap<SourceClass, f64>,
+    /// Fraction of capacity NATS ingress keeps whatever else is busy
+    pub nats_guaranteed_share: f64,
+    /// How often a deferred caller re-checks its class's share
+    pub acquire_poll_ms: u64,
+}
+
+/// Presence re-check and redelivery when a cached route gets no responders
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteRepairConfig {
+    /// Bound on the presence read inside the fanout
+    pub presence_timeout_ms: u64,
+    /// Repairs of the same user within this share one presence read
+    pub dedup_window_ms: u64,
+    pub max_per_user: u32,
+    #[serde(with = "duration_secs")]
+    pub window: Duration,
+    /// Users whose repair windows are remembered
+    pub tracked_users: usize,
+}
+
+/// Idle-time health probing of the NATS connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatsProbeConfig {
+    pub enabled: bool,
+    /// At most one probe per interval
+    #[serde(with = "duration_secs")]
+    pub interval: Duration,
+    pub timeout_ms: u64,
+    /// Probes slower than this count against the path
+    pub slow_rtt_ms: u64,
+    /// Acked traffic at or above this rate suppresses probing
+    pub idle_messages_per_second: f64,
+    /// Consecutive slow or lost probes before the path is degraded
+    pub degraded_after: u32,
+    /// Consecutive good intervals before it's healthy again
+    pub recover_after: u32,
+    /// Fail `/ready` while the path is degraded
+    pub gate_readiness: bool,
+}
+
+/// Operator overrides of delivery path decisions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathOverrideConfig {
+    /// TTL for overrides issued without one
+    #[serde(with = "duration_secs")]
+    pub default_ttl: Duration,
+    #[serde(with = "duration_secs")]
+    pub max_ttl: Duration,
+}
+
+/// Scheduled purge of stale keys from the broker's KV buckets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvCompactionConfig {
+    pub enabled: bool,
+    /// Report what would be purged without purging
+    pub dry_run: bool,
+    #[serde(with = "duration_secs")]
+    pub interval: Duration,
+    /// Keys updated more recently than this are never purged
+    #[serde(with = "duration_secs")]
+    pub min_age: Duration,
+    pub purges_per_second: f64,
+    #[serde(default)]
+    pub buckets: Vec<KvRetentionConfig>,
+}
+    
+/// Retention of one KV bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvRetentionConfig {
+    pub bucket: String,
+    /// Keys not updated for this long are purged; unset keeps them
+    #[serde(default, with = "duration_secs::option")]
+    pub max_age: Option<Duration>,
+    /// Prefixes followed by a user ID (`presence.`); a deleted user's keys are purged
+    #[serde(default)]
+    pub user_key_prefixes: Vec<String>,
+}
+    
+/// Shared computation of identical concurrent status queries, see `Coalescer`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoalesceConfig {
+    pub enabled: bool,
+    /// A finished result answers identical queries for this long
+    pub window_ms: u64,
+    /// Keys tracked per endpoint; further keys run uncoalesced
+    pub max_keys: usize,
+    /// Results larger than this aren't kept for the window
+    pub max_entry_bytes: usize,
+}
+    
+/// Broker-side unread counts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnreadConfig {
+    /// KV bucket holding sequences never delivered to a user
+    pub bucket: String,
+    /// Counts above this are reported as this, flagged clamped
+    pub max_count: u64,
+    /// Undelivered sequences kept exactly per user and conversation
+    pub exact_skip_limit: usize,
+    pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub cache_ttl: Duration,
+}
+    
+/// Egress fields removed or hashed per recipient gateway trust tier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressRedactionConfig {
+    pub enabled: bool,
+    /// Tier of gateways that registered none and aren't listed in `gateway_tiers`
+    pub default_tier: String,
+    /// Tier by gateway ID, for gateways that don't register one
+    #[serde(default)]
+    pub gateway_tiers: HashMap<String, String>,
+    /// Rules by tier; a tier with no entry gets everything
+    #[serde(default)]
+    pub tiers: HashMap<String, Vec<RedactionRule>>,
+    /// Distinct renderings one fanout may encode before falling back to `overflow_tier`
+    pub max_renderings_per_fanout: usize,
+    /// Should be the most restrictive tier
+    pub overflow_tier: String,
+}
+
+impl Default for EgressRedactionConfig {
+    fn default() -> Self {
+        let rule = |path: &str, action| RedactionRule { path: path.to_string(), action };
+        Self {
+            enabled: true,
+            default_tier: "first_party".to_string(),
+            gateway_tiers: HashMap::new(),
+            tiers: HashMap::from([(
+                "third_party".to_string(),
+                vec![
+                    rule("/metadata/internal_user_id", RedactionAction::Remove),
+                    rule("/metadata/moderation_flags", RedactionAction::Remove),
+                    rule("/metadata/trace_baggage", RedactionAction::Remove),
+                    rule("/member_snapshot", RedactionAction::Remove),
+                    rule("/attestation/connection_id", RedactionAction::Hash),
+                ],
+            )]),
+            max_renderings_per_fanout: 4,
+            overflow_tier: "third_party".to_string(),
+        }
+    }
+}
+    
+/// Per-conversation home regions for multi-region ingress
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationHomeConfig {
+    pub enabled: bool,
+    /// Region this broker runs in
+    pub region: String,
+    /// KV bucket holding conversation homes and down regions
+    pub bucket: String,
+    pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub cache_ttl: Duration,
+    /// Publishing ingress to another region gives up after this long
+    #[serde(with = "duration_secs")]
+    pub forward_timeout: Duration,
+    /// Ingress subject of every other region, by region name
+    #[serde(default)]
+    pub regions: HashMap<String, String>,
+    /// Home region for new conversations of a tenant, by tenant ID
+    #[serde(default)]
+    pub tenant_regions: HashMap<String, String>,
+}
+    
+/// Encryption of egress payloads under each gateway's pinned key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressCipherConfig {
+    pub enabled: bool,
+    /// Egress to gateways without a pinned key; unset refuses in production and sends plaintext elsewhere
+    #[serde(default)]
+    pub unpinned_policy: Option<UnpinnedPolicy>,
+    /// A gateway's session key is replaced after this long...
+    #[serde(with = "duration_secs")]
+    pub session_max_age: Duration,
+    /// ...or after sealing this many messages, whichever comes first
+    pub session_max_messages: u64,
+}
+    
+/// Server-side dedup of the broker's JetStream appends
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersistDedupConfig {
+    /// Minimum duplicate window on every stream the broker appends to
+    #[serde(with = "duration_secs")]
+    pub duplicate_window: Duration,
+    /// Appended streams created outside the broker config, e.g. the offline store's
+    #[serde(default)]
+    pub extra_streams: Vec<String>,
+}
+    
+/// On-demand pprof profiles at `/admin/debug/pprof/*`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfilingConfig {
+    pub enabled: bool,
+    /// Cap on a CPU profile's duration
+    pub max_seconds: u64,
+    /// CPU stack samples per second
+    pub frequency: u32,
+}
+    
+/// Zstd dictionary compression of egress payloads; reloadable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Zstd level for dictionary compression
+    pub level: i32,
+    /// Share of egress payloads sampled for training
+    pub sample_rate: f64,
+    /// Most recent samples kept per tenant and content type bucket
+    pub samples_per_bucket: usize,
+    /// Samples a bucket needs before a dictionary is trained for it
+    pub min_samples: usize,
+    /// Buckets sampled; new ones past this are ignored
+    pub max_buckets: usize,
+    pub max_dictionary_bytes: usize,
+    /// Payloads below this are sent uncompressed
+    pub min_payload_bytes: usize,
+    #[serde(with = "duration_secs")]
+    pub train_interval: Duration,
+    /// Share of held-out bytes a new dictionary must save over plain zstd and the current dictionary
+    pub min_improvement: f64,
+    /// How long a replaced dictionary stays in use and fetchable
+    #[serde(with = "duration_secs")]
+    pub grace_period: Duration,
+    /// New dictionaries are announced here
+    pub dictionary_subject: String,
+    /// Gateways request missing dictionaries by ID here
+    pub request_subject: String,
+    /// Gateways report the dictionaries they hold here
+    pub holdings_subject: String,
+}
+    
+/// Grace period for purged offline queue entries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineQuarantineConfig {
+    /// Stream capturing `subject`
+    pub stream: String,
+    /// Prefix of the per-user quarantine subjects
+    pub subject: String,
+    /// How long purged entries can be restored
+    #[serde(with = "duration_secs")]
+    pub retention: Duration,
+    #[serde(with = "duration_secs")]
+    pub reap_interval: Duration,
+    /// Entries read from the store per round trip while purging
+    pub batch_size: usize,
+}
+
+/// Inline spam classification of suspicious messages; reloadable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClassificationConfig {
+    pub enabled: bool,
+    /// Per-call budget; slower calls deliver the message
+    pub timeout_ms: u64,
+    pub default_mode: EnforcementMode,
+    #[serde(default)]
+    pub tenant_modes: HashMap<String, EnforcementMode>,
+    
+    /// Senders first seen this recently are classified
+    #[serde(with = "duration_secs")]
+    pub new_sender_window: Duration,
+    /// Senders remembered for the new-sender check
+    pub tracked_senders: usize,
+    /// Messages to at least this many recipients are classified
+    pub fanout_threshold: usize,
+    /// Senders with at least this abuse score are classified
+    pub abuse_score_threshold: f64,
+    
+    /// Verdicts cached by payload hash
+    pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub cache_ttl: Duration,
+}
+
+/// Operator-issued runtime overrides, see `config_override`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigOverrideConfig {
+    /// Longest TTL an override may ask for
+    #[serde(with = "duration_secs")]
+    pub max_ttl: Duration,
+    #[serde(with = "duration_secs")]
+    pub expiry_check_interval: Duration,
+}
+
+/// Per-recipient fanout decisions in message traces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientTraceConfig {
+    pub enabled: bool,
+    /// Fraction of messages traced without a watch, e.g. 0.0001
+    pub sample_rate: f64,
+    /// Recipients recorded individually per message; the rest are summarized
+    pub max_recipients: usize,
+    /// Default lifetime of a routing watch
+    #[serde(with = "duration_secs")]
+    pub watch_ttl: Duration,
+    /// Watched-message traces kept for `/debug/traces`
+    pub retained_traces: usize,
+}
+
+/// Decaying per-user abuse score and its automated responses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbuseScoreConfig {
+    pub enabled: bool,
+    /// Time for a score to halve
+    #[serde(with = "duration_secs")]
+    pub half_life: Duration,
+    pub weights: AbuseWeights,
+    pub throttle: AbuseBand,
+    pub review: AbuseBand,
+    pub pause: AbuseBand,
+    /// Fraction of the normal rate limit while throttled
+    pub throttle_fraction: f64,
+    /// Ingestion pause TTL; a still-high score after it lapses doesn't renew it
+    #[serde(with = "duration_secs")]
+    pub pause_ttl: Duration,
+    pub shards: usize,
+    pub records_per_shard: usize,
+    /// Unrestricted users below this score are forgotten by the sweep
+    pub forget_below: f64,
+    #[serde(with = "duration_secs")]
+    pub sweep_interval: Duration,
+    /// KV bucket holding persisted scores
+    pub bucket: String,
+}
+
+/// Score added per signal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbuseWeights {
+    pub rate_limited: f64,
+    pub rejected: f64,
+    pub block_suppressed: f64,
+    pub spam_flagged: f64,
+}
+
+impl AbuseWeights {
+    pub fn of(&self, signal: AbuseSignal) -> f64 {
+        match signal {
+            AbuseSignal::RateLimited => self.rate_limited,
+            AbuseSignal::Rejected => self.rejected,
+            AbuseSignal::BlockSuppressed => self.block_suppressed,
+            AbuseSignal::SpamFlagged => self.spam_flagged,
+        }
+    }
+}
+
+/// Score at which a response starts, and below which it ends
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbuseBand {
+    pub enter: f64,
+    pub exit: f64,
+}
+
+/// Expiry, key pairing and chain checks for configured certificates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsMonitorConfig {
+    #[serde(with = "duration_secs")]
+    pub check_interval: Duration,
+    /// Days before expiry at which to warn, once per threshold
+    pub warn_days: Vec<u32>,
+    /// Certificates not named elsewhere in the config (admin and webhook client certs)
+    #[serde(default)]
+    pub extra: Vec<MonitoredCert>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MonitoredCert {
+    /// `purpose` label on the TLS gauges
+    pub purpose: String,
+    /// PEM file; intermediates may follow the leaf
+    pub cert: String,
+    /// Private key PEM, checked against the leaf
+    #[serde(default)]
+    pub key: Option<String>,
+    /// CA bundle the chain must verify against
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+/// Offline queue export and import for users moving between clusters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineTransferConfig {
+    /// Entries read from the store per round trip while exporting
+    pub batch_size: usize,
+    /// Largest single frame accepted on import
+    pub max_frame_bytes: usize,
+    /// Secret the export cursors are signed with, e.g. a mounted secret;
+    /// without one each process signs with a random key, so cursors don't
+    /// survive a restart or move between brokers
+    #[serde(default)]
+    pub cursor_secret_file: Option<String>,
+}
+
+/// Share-of-throughput budgets for low-value traffic kinds under load
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KindBudgetConfig {
+    pub enabled: bool,
+    /// Arrival rate over `limits.messages_per_second` at which budgets apply
+    pub utilization_threshold: f64,
+    #[serde(with = "duration_secs")]
+    pub window: Duration,
+    /// Budgets aren't enforced until a window has admitted this many messages
+    pub min_window_messages: u64,
+    /// Percent of admitted traffic per kind; only typing, receipt and presence can be budgeted
+    #[serde(default)]
+    pub share_percent: HashMap<TrafficKind, f64>,
+}
+
+/// Lock contention reporting; only takes effect with the `lock-metrics` feature
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockMetricsConfig {
+    /// Waits longer than this count toward `broker_lock_slow_waits_total`
+    pub slow_wait_us: u64,
+    /// Locks listed under `lock_contention` in `/debug/state`
+    pub report_top: usize,
+}
+
+/// Pre-parse limits on envelopes from clients, gateways and the outbox
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvelopeGuardConfig {
+    /// Raw envelope cap; `api.max_frame_size` applies if smaller
+    pub max_envelope_bytes: usize,
+    /// JSON nesting depth; at most 64
+    pub max_depth: usize,
+    /// Elements in any one array or object (recipients, metadata)
+    pub max_elements: usize,
+    /// Bytes in any one string, ciphertext included
+    pub max_string_bytes: usize,
+    pub max_headers: usize,
+    /// Header names plus values
+    pub max_header_bytes: usize,
+}
+
+/// Conversation observed/idle/evicted events for downstream services
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationLifecycleConfig {
+    pub enabled: bool,
+    pub subject: String,
+    /// Quiet time before `ConversationIdle`; checked every `routing.conversation_gc_interval`
+    #[serde(with = "duration_secs")]
+    pub idle_after: Duration,
+    #[serde(with = "duration_secs")]
+    pub flush_interval: Duration,
+    /// Events per published batch
+    pub max_batch: usize,
+    /// Queued events beyond this are dropped
+    pub max_pending: usize,
+}
+
+/// Low-quota warnings published to NATS-sourced senders
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaFeedbackConfig {
+    pub enabled: bool,
+    /// Prefix; feedback goes to `{subject}.{user_id}`
+    pub subject: String,
+    /// Remaining share of the limit below which feedback is sent
+    pub threshold: f64,
+}
+
+/// Cross-broker config fingerprint comparison
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigDriftConfig {
+    /// How often peers' advertised fingerprints are compared with ours
+    #[serde(with = "duration_secs")]
+    pub check_interval: Duration,
+    /// Wait for a peer's config dump before giving up
+    #[serde(with = "duration_secs")]
+    pub request_timeout: Duration,
+}
+
+/// Read-only maintenance mode; reloadable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// Start in, or on reload enter, maintenance
+    pub enabled: bool,
+    /// Keep reporting ready during maintenance, e.g. to keep serving reads behind a load balancer
+    pub stay_ready: bool,
+    /// Shown to rejected callers when entered through config
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+/// Service-level objectives computed in-process; reloadable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SloConfig {
+    /// Error budget period; changing it takes a restart
+    #[serde(with = "duration_secs")]
+    pub period: Duration,
+    #[serde(default)]
+    pub objectives: Vec<SloDefinition>,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(30 * 24 * 3600),
+            objectives: vec![
+                SloDefinition {
+                    name: "delivery_latency".to_string(),
+                    indicator: SliIndicator::DeliveryLatency { threshold_ms: 1000 },
+                    objective: 0.999,
+                },
+                SloDefinition {
+                    name: "ingress_availability".to_string(),
+                    indicator: SliIndicator::IngressAcceptance,
+                    objective: 0.9995,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SloDefinition {
+    /// `slo` label on the exported gauges
+    pub name: String,
+    pub indicator: SliIndicator,
+    /// Target share of good events, e.g. 0.999
+    pub objective: f64,
+}
+
+/// Rejection of sends to archived conversations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// KV bucket holding archived conversation IDs
+    pub bucket: String,
+    pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub cache_ttl: Duration,
+    /// Message types still accepted into archived conversations
+    pub allowed_types: Vec<MessageType>,
+}
+    
+/// Invitation-gated group delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvitationConfig {
+    /// Invitation lifetime when the resolver gives no expiry
+    #[serde(with = "duration_secs")]
+    pub default_ttl: Duration,
+    /// Invitations whose single notification is remembered
+    pub tracked_invitations: usize,
+}
+    
+/// Egress payload checksums and gateway corruption reports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityConfig {
+    pub enabled: bool,
+    /// Gateways publish `CorruptionReport`s here
+    pub report_subject: String,
+    /// Delivery ID to checksum records kept per shard for classification
+    pub records_per_shard: usize,
+}
+    
+/// Thread-scoped fanout inside conversations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadConfig {
+    /// Cached (conversation, thread) participant sets
+    pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub participant_ttl: Duration,
+    /// Minimum time between activity markers for one member and thread
+    #[serde(with = "duration_secs")]
+    pub marker_window: Duration,
+    /// (member, thread) pairs whose marker window is tracked
+    pub marker_state_size: usize,
+}
+    
+/// Membership cache fill before a broker starts serving
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WarmupConfig {
+    pub enabled: bool,
+    /// Warmed first, ahead of the groups the route warmer persisted
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub top_k: usize,
+    pub groups_per_second: f64,
+    /// Serving starts after this long even if warm-up isn't done
+    #[serde(with = "duration_secs")]
+    pub max_duration: Duration,
+    /// Hold `/ready` until `readiness_threshold` of the groups were tried
+    pub gate_readiness: bool,
+    pub readiness_threshold: f64,
+}
+    
+/// Presence and routing state shipped to the disaster-recovery region
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DrReplicationConfig {
+    pub enabled: bool,
+    /// Subject on the DR cluster carrying replication frames
+    pub subject: String,
+    /// DR stream capturing `subject`, consumed by the standby broker
+    pub stream: String,
+    pub consumer: String,
+    pub batch_max_records: usize,
+    pub flush_interval_ms: u64,
+    /// Changes held while the DR link is down before falling back to a resync
+    pub buffer_max_records: usize,
+}
+
+/// Predictive refresh of hot routes ahead of daily activity spikes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteWarmingConfig {
+    pub enabled: bool,
+    /// KV bucket holding per-shard hourly activity
+    pub bucket: String,
+    /// How long before a predicted spike warming starts
+    #[serde(with = "duration_secs")]
+    pub lead_time: Duration,
+    /// Predicted hour over hourly baseline at which a shard counts as hot
+    pub threshold_factor: f64,
+    /// Minimum predicted routing events for a hot hour
+    pub min_hourly_events: u64,
+    /// Users and groups whose routing frequency is tracked
+    pub tracked_keys: usize,
+    pub top_users: usize,
+    pub top_groups: usize,
+    /// Most routed groups persisted for startup warm-up
+    pub persisted_groups: usize,
+    #[serde(with = "duration_secs")]
+    pub tick_interval: Duration,
+    #[serde(with = "duration_secs")]
+    pub persist_interval: Duration,
+}
+
+/// Gateway receipt intake, single and bulk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptConfig {
+    pub subject: String,
+    pub queue_group: String,
+    /// Gateways request `ReceiptCapabilities` here before switching to bulk frames
+    pub capabilities_subject: String,
+    pub max_entries_per_frame: usize,
+    /// Batching window advertised to gateways
+    pub gateway_flush_window_ms: u64,
+    /// Window of the per-gateway clock offset minimum, see `e2e_latency::ClockSkew`
+    #[serde(with = "duration_secs")]
+    pub skew_window: Duration,
+}
+
+/// Warm standby: start fully initialized but inactive until activated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StandbyConfig {
+    pub enabled: bool,
+    /// Activate once no active peer has been seen this long; zero disables
+    #[serde(with = "duration_secs")]
+    pub auto_activate_after: Duration,
+    pub peer_poll_ms: u64,
+    /// Target time from trigger to full operation
+    pub activation_budget_ms: u64,
+}
+
+/// Coordination of fleet-wide control commands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlConfig {
+    /// KV bucket holding single-executor command leases
+    pub lease_bucket: String,
+    /// How long a silent executor keeps its lease before another broker takes over
+    #[serde(with = "duration_secs")]
+    pub lease_ttl: Duration,
+}
+
+/// Per-conversation flood protection, applied after per-user limits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationLimitConfig {
+    pub enabled: bool,
+    pub messages_per_window: u32,
+    #[serde(with = "duration_secs")]
+    pub window: Duration,
+    
+    /// How often a conversation over its cap gets a digest frame
+    #[serde(with = "duration_secs")]
+    pub digest_interval: Duration,
+    /// Envelopes carried per digest; the rest are only counted
+    pub digest_max_messages: usize,
+    
+    /// Cap per tenant; takes precedence over group-size buckets
+    #[serde(default)]
+    pub tenants: HashMap<String, u32>,
+    #[serde(default)]
+    pub group_size_buckets: Vec<GroupSizeLimit>,
+}
+
+/// Cap for conversations with at least `min_members` recipients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupSizeLimit {
+    pub min_members: usize,
+    pub messages_per_window: u32,
+}
+
+/// Broker-driven moves of user sessions between gateways
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionMigrationConfig {
+    /// KV bucket holding in-flight migration state
+    pub bucket: String,
+    /// Gateways answer session commands on `{prefix}.{gateway_id}`
+    pub gateway_control_prefix: String,
+    /// Mirrored deliveries go to `{prefix}.{gateway_id}.{user_id}`
+    pub gateway_session_prefix: String,
+    pub request_timeout_ms: u64,
+    /// Retries per gateway command before the step counts as failed
+    pub command_retries: u32,
+    
+    /// How long the client has to appear on the target gateway
+    #[serde(with = "duration_secs")]
+    pub reconnect_timeout: Duration,
+    pub presence_poll_ms: u64,
+}
+
+/// Payload content types allowed at ingress
+///
+/// An empty list allows every type. Patterns are exact (`text/plain`),
+/// wildcard (`image/*`) or catch-all (`*/*`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContentTypePolicyConfig {
+    #[serde(default)]
+    pub default: Vec<ContentTypeRule>,
+    /// Replaces `default` entirely for the named tenant
+    #[serde(default)]
+    pub tenants: HashMap<String, Vec<ContentTypeRule>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentTypeRule {
+    pub content_type: String,
+    /// Serialized envelope cap for this type; only effective below `limits.max_message_size`
+    pub max_size: Option<usize>,
+}
+
+/// Publish capacity reserved for background workers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackgroundQuotaConfig {
+    /// Starting share of `limits.messages_per_second` for background work
+    pub initial_fraction: f64,
+    pub min_fraction: f64,
+    pub max_fraction: f64,
+    
+    /// Live-path latency above which the background share shrinks
+    pub live_latency_target_ms: u64,
+    /// EWMA factor for live latency samples
+    pub latency_smoothing: f64,
+    pub decrease_factor: f64,
+    pub increase_step: f64,
+    #[serde(with = "duration_secs")]
+    pub adjust_interval: Duration,
+    
+    /// Relative shares within the background allowance; missing classes weigh 1
+    #[serde(default)]
+    pub weights: HashMap<WorkerClass, f64>,
+    /// How often a waiting worker re-checks its share
+    pub acquire_poll_ms: u64,
+}
+
+/// Publish failure classification
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Consecutive failures on one subject before retries give way to offline queueing
+    pub escalate_after: u32,
+    /// Failures further apart than this don't count as consecutive
+    #[serde(with = "duration_secs")]
+    pub history_window: Duration,
+    
+    /// Retry tokens earned per successful operation
+    pub budget_ratio: f64,
+    /// Retry tokens that can be saved up for a burst of failures
+    pub budget_max_tokens: u32,
+    
+    /// Emergency replacements for the built-in error kind to action mapping
+    #[serde(default)]
+    pub overrides: HashMap<PublishErrorKind, RetryAction>,
+}
+
+/// Targeted ingestion pauses set by control commands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestionPauseConfig {
+    /// NAK matching messages for redelivery, or park them in the holding stream
+    pub action: PauseAction,
+    #[serde(with = "duration_secs")]
+    pub nak_delay: Duration,
+    /// TTL for pauses issued without one
+    #[serde(with = "duration_secs")]
+    pub default_ttl: Duration,
+    #[serde(with = "duration_secs")]
+    pub max_ttl: Duration,
+    
+    /// Parked messages go to `{holding_subject}.{kind}.{selector}`
+    pub holding_subject: String,
+    /// Stream capturing `holding_subject`
+    pub holding_stream: String,
+}
+
+/// Debug-stream sampling of processed envelopes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    pub enabled: bool,
+    /// Fraction of messages sampled, e.g. 0.001 for 0.1%
+    pub base_rate: f64,
+    pub subject: String,
+    
+    /// Tenants whose messages are always sampled
+    #[serde(default)]
+    pub always_tenants: Vec<String>,
+    pub always_dead_lettered: bool,
+    /// Messages processed slower than this are always sampled
+    pub latency_threshold_ms: Option<u64>,
+    
+    pub redaction: PayloadRedaction,
+    pub truncate_bytes: usize,
+    /// Sample records buffered before new samples are dropped
+    pub queue_size: usize,
+}
+
+#[cfg(feature = "outbox")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxConfig {
+    pub connection_string: String,
+    /// Table or view holding outbox rows
+    pub table: String,
+    #[serde(with = "duration_secs")]
+    pub poll_interval: Duration,
+    pub batch_size: i64,
+    /// Upper bound for the backoff after database errors
+    #[serde(with = "duration_secs")]
+    pub max_backoff: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriorityInheritanceConfig {
+    pub enabled: bool,
+    
+    /// Recently fanned-out messages remembered for inheritance
+    pub cache_size: usize,
+    #[serde(with = "duration_secs")]
+    pub cache_ttl: Duration,
+    
+    /// Highest priority a reply may inherit unless the tenant overrides it
+    pub default_cap: Priority,
+    #[serde(default)]
+    pub tenant_caps: HashMap<String, Priority>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationConfig {
+    /// Verify gateway-signed sender identities on ingress
+    pub enabled: bool,
+    
+    /// Maximum age (either direction) of an attestation timestamp
+    #[serde(with = "duration_secs")]
+    pub replay_window: Duration,
+    
+    /// How long the previous gateway key stays valid after rotation
+    #[serde(with = "duration_secs")]
+    pub key_rotation_grace: Duration,
+    
+    /// Sources that skip verification during the migration period
+    #[serde(default)]
+    pub trusted_sources: Vec<String>,
+    
+    /// Gateway keys (base64 ed25519) registered at startup, by gateway ID
+    #[serde(default)]
+    pub gateway_keys: HashMap<String, String>,
+    
+    /// KV bucket keeping keys registered by control command across restarts
+    pub key_bucket: String,
+    
+    /// Operator keys (base64 ed25519) one of which must sign `RegisterGatewayKey`
+    /// and `RemoveGatewayKey`; with none, both commands are refused
+    #[serde(default)]
+    pub registration_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DegradationConfig {
+    /// How long a manually or automatically selected level stays active
+    /// before reverting to `normal` unless renewed
+    #[serde(with = "duration_secs")]
+    pub level_ttl: Duration,
+    
+    /// Let the load-shedding subsystem raise the level when it escalates
+    pub auto_trigger_on_shed: bool,
+    
+    /// Behavior toggles for each named level
+    #[serde(default)]
+    pub levels: DegradationLevels,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DegradationLevels {
+    pub normal: DegradationToggles,
+    pub conserve: DegradationToggles,
+    pub emergency: DegradationToggles,
+}
+
+impl Default for DegradationLevels {
+    fn default() -> Self {
+        Self {
+            normal: DegradationToggles::default(),
+            conserve: DegradationToggles {
+                disable_typing_fanout: true,
+                drop_bulk_priority: true,
+                kind_shares: HashMap::from([(TrafficKind::Receipt, 10.0), (TrafficKind::Presence, 5.0)]),
+                nak_backoff_ms: HashMap::from([(TrafficKind::Receipt, 5_000), (TrafficKind::Presence, 10_000)]),
+                ..DegradationToggles::default()
+            },
+            emergency: DegradationToggles {
+                disable_receipts: true,
+                disable_typing_fanout: true,
+                disable_presence_fanout: true,
+                drop_bulk_priority: true,
+                force_broadcast_group_size: Some(1000),
+                kind_shares: HashMap::new(),
+                nak_backoff_ms: HashMap::from([
+                    (TrafficKind::Message, 2_000),
+                    (TrafficKind::KeyDistribution, 2_000),
+                ]),
+            },
+        }
+    }
 }
 
 impl BrokerConfig {
     pub fn load() -> Result<Self, ConfigError> {
+        Self::load_with_overrides(&[])
+    }
+    
+    /// Load with runtime overrides layered above every source
+    ///
+    /// Overrides go through the same clamping and validation as the sources.
+    pub fn load_with_overrides(overrides: &[(String, serde_json::Value)]) -> Result<Self, ConfigError> {
         let env = std::env::var("ENVIRONMENT").unwrap_or_else(|_| "development".into());
         
-        let config = Config::builder()
+        let mut builder = Config::builder()
             .add_source(File::with_name("config/default").required(false))
             .add_source(File::with_name(&format!("config/{}", env)).required(false))
             .add_source(File::with_name("config/local").required(false))
             .add_source(Environment::with_prefix("BROKER").separator("__"))
             .set_default("broker_id", generate_broker_id())?
             .set_default("environment", env)?
+            .set_default("strict_config", false)?
+            .set_default("config_reload_interval", 30)? // seconds
             
             // NATS defaults
             .set_default("nats.servers", vec!["nats://localhost:4222"])?
@@ -102,8 +1646,23 @@ impl BrokerConfig {
             .set_default("nats.egress_user_prefix", "gateway.user")?
             .set_default("nats.egress_group_prefix", "gateway.group")?
             .set_default("nats.control_topic", "broker.control")?
+            .set_default("nats.dead_letter_topic", "broker.dlq")?
+            .set_default("nats.delivery_fallback_topic", "broker.fallback.push")?
             .set_default("nats.stream_name", "messages")?
             .set_default("nats.consumer_name", "broker-consumer")?
+            .set_default("nats.handover_drain_timeout", 60)? // seconds
+            .set_default("nats.checkpoint_bucket", "broker-checkpoints")?
+            .set_default("nats.presence_bucket", "broker-presence")?
+            .set_default("nats.read_horizon_bucket", "broker-read-horizons")?
+            .set_default("nats.key_distribution_bucket", "broker-key-distribution")?
+            .set_default("nats.delivery_status_bucket", "broker-delivery-status")?
+            .set_default("nats.sequence_bucket", "broker-sequences")?
+            .set_default("nats.mirror_max_lag", 1000)?
+            .set_default("nats.mirror_lag_check_interval", 5)? // seconds
+            .set_default("nats.history_subject_prefix", "history")?
+            .set_default("nats.history_max_page_size", 200)?
+            .set_default("nats.acl_check_enabled", true)?
+            .set_default("nats.acl_check_fail_on_deny", true)?
             .set_default("nats.connect_timeout", 5)? // seconds
             .set_default("nats.reconnect_delay", 2)? // seconds
             
@@ -112,6 +1671,16 @@ impl BrokerConfig {
             .set_default("api.rest_addr", "0.0.0.0:8080")?
             .set_default("api.max_concurrent_streams", 10000)?
             .set_default("api.max_frame_size", 1048576)? // 1MB
+            .set_default("api.subscribe_buffer_size", 256)?
+            .set_default("api.subscribe_min_buffer_size", 4)?
+            .set_default("api.subscribe_idle_timeout", 600)? // 10 minutes
+            .set_default("api.subscribe_hard_timeout", 7200)? // 2 hours
+            .set_default("api.subscribe_keepalive_interval", 15)? // seconds
+            .set_default("api.subscribe_keepalive_timeout_ms", 10000)?
+            .set_default("api.subscribe_wedged_grace", 30)? // seconds
+            .set_default("api.deadline_margin_ms", 20)?
+            .set_default("api.auth.enabled", false)?
+            .set_default("api.auth.reload_interval", 60)? // seconds
             
             // Routing defaults
             .set_default("routing.shard_count", 64)?
@@ -119,8 +1688,36 @@ impl BrokerConfig {
             .set_default("routing.fanout_parallelism", 16)?
             .set_default("routing.presence_ttl", 300)? // 5 minutes
             .set_default("routing.typing_ttl", 10)? // 10 seconds
+            .set_default("routing.read_horizon_coalesce_ms", 500)?
+            .set_default("routing.delivery_status_retention", 600)? // 10 minutes
+            .set_default("routing.delivery_status_max_hot_recipients", 2000000)?
+            .set_default("routing.key_distribution_offline_retention", 2592000)? // 30 days
+            .set_default("routing.delivery_id_recent_per_recipient", 32)?
+            .set_default("routing.delivery_id_recipients_per_shard", 10000)?
+            .set_default("routing.sequence_block_size", 1000)?
+            .set_default("routing.sequence_prefetch_fraction", 0.2)?
+            .set_default("routing.presence_bulk_chunk_size", 500)?
+            .set_default("routing.presence_bulk_concurrency", 32)?
             .set_default("routing.cache_size", 10000)?
+            .set_default("routing.route_cache_ttl", 30)? // seconds
+            .set_default("routing.negative_cache_size", 10000)?
+            .set_default("routing.negative_cache_ttl", 10)? // seconds
             .set_default("routing.bloom_filter_size", 100000)?
+            .set_default("routing.dedup_cache_size", 100000)?
+            .set_default("routing.subject_active_window", 300)? // 5 minutes
+            .set_default("routing.conversation_idle_timeout", 3600)? // 1 hour
+            .set_default("routing.conversation_gc_interval", 60)? // 1 minute
+            .set_default("routing.membership_ttl", 60)? // 1 minute
+            .set_default("routing.membership_delta_threshold", 0.5)?
+            .set_default("routing.reject_oversized_groups", true)?
+            .set_default("routing.adaptive_batching.enabled", true)?
+            .set_default("routing.adaptive_batching.min_batch_size", 10)?
+            .set_default("routing.adaptive_batching.max_batch_size", 1000)?
+            .set_default("routing.adaptive_batching.window_publishes", 500)?
+            .set_default("routing.adaptive_batching.target_publish_latency_ms", 5)?
+            .set_default("routing.adaptive_batching.max_failure_rate", 0.01)?
+            .set_default("routing.adaptive_batching.additive_step", 10)?
+            .set_default("routing.adaptive_batching.decrease_factor", 0.5)?
             
             // Metrics defaults
             .set_default("metrics.prometheus_addr", "0.0.0.0:9090")?
@@ -136,10 +1733,525 @@ impl BrokerConfig {
             .set_default("limits.user_message_limit", 100)?
             .set_default("limits.user_message_window", 60)? // 1 minute
             .set_default("limits.connection_limit_per_user", 10)?
+            .set_default("limits.burst_credit_quiet_fraction", 0.5)?
+            .set_default("limits.burst_credit_quiet_windows", 3)?
+            .set_default("limits.burst_credit_per_window", 10)?
+            .set_default("limits.burst_credit_max", 50)?
+            .set_default("limits.deferred_queue_size", 10)?
+            .set_default("limits.max_transaction_messages", 10)?
+            
+            // Degradation defaults
+            .set_default("degradation.level_ttl", 1800)? // 30 minutes
+            .set_default("degradation.auto_trigger_on_shed", true)?
+            
+            // Priority inheritance defaults
+            .set_default("priority_inheritance.enabled", true)?
+            .set_default("priority_inheritance.cache_size", 50000)?
+            .set_default("priority_inheritance.cache_ttl", 900)? // 15 minutes
+            .set_default("priority_inheritance.default_cap", "high")?
+            
+            // Cluster defaults
+            .set_default("cluster.bucket", "broker-cluster")?
+            .set_default("cluster.heartbeat_interval", 5)? // seconds
+            .set_default("cluster.direct_forwarding", false)?
+            .set_default("cluster.forward_timeout_ms", 200)?
+            
+            // Size accounting defaults
+            .set_default("size_accounting.warn_factor", 2.0)?
+            .set_default("size_accounting.warn_sample_rate", 0.01)?
+            
+            // Control command coordination defaults
+            .set_default("control.lease_bucket", "broker-control-leases")?
+            .set_default("control.lease_ttl", 30)? // seconds
+            
+            // Payload interning defaults
+            .set_default("payload_intern.enabled", true)?
+            .set_default("payload_intern.max_bytes", 16777216)? // 16 MiB
+            .set_default("payload_intern.shards", 16)?
+            .set_default("payload_intern.max_payload_bytes", 65536)?
+            .set_default("payload_intern.ttl", 10)? // seconds
+            
+            // Soft state handoff defaults
+            .set_default("soft_handoff.enabled", true)?
+            .set_default("soft_handoff.bucket", "broker-handoff")?
+            .set_default("soft_handoff.record_ttl", 30)? // seconds
+            .set_default("soft_handoff.max_deadlines", 100000)?
+            
+            // User event ring defaults
+            .set_default("user_events.enabled", true)?
+            .set_default("user_events.ring_size", 20)?
+            .set_default("user_events.shards", 16)?
+            .set_default("user_events.users_per_shard", 4096)?
+            .set_default("user_events.active_window", 900)? // 15 minutes
+            .set_default("user_events.sweep_interval", 30)? // seconds
+            
+            // Tenant provisioning defaults
+            .set_default("tenants.bucket", "broker-tenants")?
+            .set_default("tenants.stream_prefix", "tenant")?
+            .set_default("tenants.subject_prefix", "tenant")?
+            .set_default("tenants.delete_grace", 604800)? // 7 days
+            .set_default("tenants.sync_interval", 60)? // seconds
+            
+            // NAK delay defaults
+            .set_default("nak.max_delay_ms", 300000)?
+            .set_default("nak.base_delay_ms", 500)?
+            .set_default("nak.max_default_delay_ms", 30000)?
+            .set_default("nak.max_tracked", 100000)?
+            
+            // History cache defaults
+            .set_default("history_cache.enabled", true)?
+            .set_default("history_cache.max_conversations", 10000)?
+            .set_default("history_cache.max_page_bytes", 262144)?
+            
+            // Shed exemption defaults
+            .set_default("shed_exemptions.label_key", "conversation_label")?
+            .set_default("shed_exemptions.max_entries", 256)?
+            
+            // Conversation bootstrap defaults
+            .set_default("conversation_bootstrap.enabled", true)?
+            .set_default("conversation_bootstrap.hint_ttl", 120)? // seconds
+            .set_default("conversation_bootstrap.max_speculative", 10000)?
+            .set_default("conversation_bootstrap.deferred_timeout", 30)? // seconds
+            .set_default("conversation_bootstrap.sweep_interval", 10)? // seconds
             
-            .build()?;
+            // Webhook replay protection defaults
+            .set_default("webhook_replay.enabled", true)?
+            .set_default("webhook_replay.freshness_window", 300)? // 5 minutes
+            .set_default("webhook_replay.clock_skew_ms", 30000)?
+            .set_default("webhook_replay.max_nonces_per_provider", 1000000)?
+            
+            // Volume accounting defaults
+            .set_default("volume_accounting.enabled", true)?
+            .set_default("volume_accounting.subject", "broker.volume.deltas")?
+            .set_default("volume_accounting.stream", "BROKER_VOLUME")?
+            .set_default("volume_accounting.flush_interval", 60)? // seconds
+            .set_default("volume_accounting.shards", 16)?
+            .set_default("volume_accounting.max_conversations", 200000)?
+            .set_default("volume_accounting.max_unconfirmed", 500000)?
+            
+            // Preview defaults
+            .set_default("preview.default.mode", "text")?
+            .set_default("preview.default.max_graphemes", 100)?
+            .set_default("preview.default.placeholder", "New message")?
+            
+            // Audit chain defaults
+            .set_default("audit.max_file_bytes", 104_857_600)?
+            .set_default("audit.anchor_subject", "broker.audit.anchor")?
+            .set_default("audit.anchor_interval", 300)? // seconds
+            
+            // Interest reconciliation defaults
+            .set_default("interest_reconcile.enabled", true)?
+            .set_default("interest_reconcile.interval", 30)? // seconds
+            .set_default("interest_reconcile.probe_timeout_ms", 2000)?
+            .set_default("interest_reconcile.confirm_cycles", 2)?
+            
+            // Ingress admission defaults
+            .set_default("ingress_admission.enabled", true)?
+            .set_default("ingress_admission.messages_per_second", 20000)?
+            .set_default("ingress_admission.shares.nats", 4.0)?
+            .set_default("ingress_admission.shares.rest_bulk", 1.0)?
+            .set_default("ingress_admission.shares.bridge", 1.0)?
+            .set_default("ingress_admission.shares.ws", 2.0)?
+            .set_default("ingress_admission.nats_guaranteed_share", 0.5)?
+            .set_default("ingress_admission.acquire_poll_ms", 10)?
+            
+            // Route repair defaults
+            .set_default("route_repair.presence_timeout_ms", 100)?
+            .set_default("route_repair.dedup_window_ms", 1000)?
+            .set_default("route_repair.max_per_user", 3)?
+            .set_default("route_repair.window", 60)? // seconds
+            .set_default("route_repair.tracked_users", 100000)?
+            
+            // NATS probe defaults
+            .set_default("nats_probe.enabled", true)?
+            .set_default("nats_probe.interval", 5)? // seconds
+            .set_default("nats_probe.timeout_ms", 2000)?
+            .set_default("nats_probe.slow_rtt_ms", 250)?
+            .set_default("nats_probe.idle_messages_per_second", 1.0)?
+            .set_default("nats_probe.degraded_after", 3)?
+            .set_default("nats_probe.recover_after", 3)?
+            .set_default("nats_probe.gate_readiness", false)?
+            
+            // Path override defaults
+            .set_default("path_override.default_ttl", 3600)? // 1 hour
+            .set_default("path_override.max_ttl", 86400)? // 24 hours
+            
+            // KV compaction defaults
+            .set_default("kv_compaction.enabled", false)?
+            .set_default("kv_compaction.dry_run", true)?
+            .set_default("kv_compaction.interval", 3600)? // 1 hour
+            .set_default("kv_compaction.min_age", 86400)? // 1 day
+            .set_default("kv_compaction.purges_per_second", 100.0)?
+            
+            // Status query coalescing defaults
+            .set_default("coalesce.enabled", true)?
+            .set_default("coalesce.window_ms", 250)?
+            .set_default("coalesce.max_keys", 10000)?
+            .set_default("coalesce.max_entry_bytes", 1048576)? // 1 MiB
+            
+            // Unread count defaults
+            .set_default("unread.bucket", "broker-unread-skips")?
+            .set_default("unread.max_count", 999)?
+            .set_default("unread.exact_skip_limit", 64)?
+            .set_default("unread.cache_size", 100000)?
+            .set_default("unread.cache_ttl", 30)? // seconds
+            
+            // Conversation home defaults
+            .set_default("conversation_home.enabled", false)?
+            .set_default("conversation_home.region", "default")?
+            .set_default("conversation_home.bucket", "broker-conversation-homes")?
+            .set_default("conversation_home.cache_size", 100000)?
+            .set_default("conversation_home.cache_ttl", 60)? // seconds
+            .set_default("conversation_home.forward_timeout", 2)? // seconds
+            
+            // Egress cipher defaults
+            .set_default("egress_cipher.enabled", true)?
+            .set_default("egress_cipher.session_max_age", 3600)? // 1 hour
+            .set_default("egress_cipher.session_max_messages", 1_000_000)?
+            
+            // Persist dedup defaults
+            .set_default("persist_dedup.duplicate_window", 600)? // 10 minutes
+            
+            // Profiling defaults
+            .set_default("profiling.enabled", true)?
+            .set_default("profiling.max_seconds", 60)?
+            .set_default("profiling.frequency", 99)?
+            
+            // Compression defaults
+            .set_default("compression.enabled", true)?
+            .set_default("compression.level", 3)?
+            .set_default("compression.sample_rate", 0.01)?
+            .set_default("compression.samples_per_bucket", 2000)?
+            .set_default("compression.min_samples", 500)?
+            .set_default("compression.max_buckets", 64)?
+            .set_default("compression.max_dictionary_bytes", 16384)?
+            .set_default("compression.min_payload_bytes", 64)?
+            .set_default("compression.train_interval", 3600)? // 1 hour
+            .set_default("compression.min_improvement", 0.05)?
+            .set_default("compression.grace_period", 900)? // 15 minutes
+            .set_default("compression.dictionary_subject", "broker.compression.dictionaries")?
+            .set_default("compression.request_subject", "broker.compression.requests")?
+            .set_default("compression.holdings_subject", "broker.compression.holdings")?
+            
+            // Offline quarantine defaults
+            .set_default("offline_quarantine.stream", "BROKER_OFFLINE_QUARANTINE")?
+            .set_default("offline_quarantine.subject", "broker.offline.quarantine")?
+            .set_default("offline_quarantine.retention", 259200)? // 72 hours
+            .set_default("offline_quarantine.reap_interval", 300)? // 5 minutes
+            .set_default("offline_quarantine.batch_size", 500)?
+            
+            // Classification defaults
+            .set_default("classification.enabled", false)?
+            .set_default("classification.timeout_ms", 50)?
+            .set_default("classification.default_mode", "shadow")?
+            .set_default("classification.new_sender_window", 86400)? // 24 hours
+            .set_default("classification.tracked_senders", 100000)?
+            .set_default("classification.fanout_threshold", 50)?
+            .set_default("classification.abuse_score_threshold", 20.0)?
+            .set_default("classification.cache_size", 50000)?
+            .set_default("classification.cache_ttl", 600)? // 10 minutes
+            
+            // Config override defaults
+            .set_default("config_overrides.max_ttl", 604800)? // 7 days
+            .set_default("config_overrides.expiry_check_interval", 10)? // seconds
+            
+            // Recipient trace defaults
+            .set_default("recipient_trace.enabled", true)?
+            .set_default("recipient_trace.sample_rate", 0.0001)?
+            .set_default("recipient_trace.max_recipients", 100)?
+            .set_default("recipient_trace.watch_ttl", 3600)? // 1 hour
+            .set_default("recipient_trace.retained_traces", 1000)?
+            
+            // Abuse score defaults
+            .set_default("abuse.enabled", false)?
+            .set_default("abuse.half_life", 3600)? // 1 hour
+            .set_default("abuse.weights.rate_limited", 1.0)?
+            .set_default("abuse.weights.rejected", 2.0)?
+            .set_default("abuse.weights.block_suppressed", 5.0)?
+            .set_default("abuse.weights.spam_flagged", 20.0)?
+            .set_default("abuse.throttle.enter", 20.0)?
+            .set_default("abuse.throttle.exit", 10.0)?
+            .set_default("abuse.review.enter", 50.0)?
+            .set_default("abuse.review.exit", 25.0)?
+            .set_default("abuse.pause.enter", 100.0)?
+            .set_default("abuse.pause.exit", 50.0)?
+            .set_default("abuse.throttle_fraction", 0.25)?
+            .set_default("abuse.pause_ttl", 1800)? // 30 minutes
+            .set_default("abuse.shards", 16)?
+            .set_default("abuse.records_per_shard", 10000)?
+            .set_default("abuse.forget_below", 0.5)?
+            .set_default("abuse.sweep_interval", 60)? // seconds
+            .set_default("abuse.bucket", "broker_abuse_scores")?
+            
+            // TLS monitor defaults
+            .set_default("tls_monitor.check_interval", 3600)? // 1 hour
+            .set_default("tls_monitor.warn_days", vec![30, 14, 7])?
+            
+            // Offline transfer defaults
+            .set_default("offline_transfer.batch_size", 500)?
+            .set_default("offline_transfer.max_frame_bytes", 1048576)? // 1MB
+            
+            // Kind budget defaults
+            .set_default("kind_budgets.enabled", false)?
+            .set_default("kind_budgets.utilization_threshold", 0.8)?
+            .set_default("kind_budgets.window", 10)? // seconds
+            .set_default("kind_budgets.min_window_messages", 100)?
+            .set_default("kind_budgets.share_percent.typing", 10.0)?
+            .set_default("kind_budgets.share_percent.receipt", 25.0)?
+            .set_default("kind_budgets.share_percent.presence", 10.0)?
+            
+            // Lock metrics defaults
+            .set_default("lock_metrics.slow_wait_us", 1000)? // 1ms
+            .set_default("lock_metrics.report_top", 10)?
+            
+            // Envelope guard defaults
+            .set_default("envelope_guard.max_envelope_bytes", 262144)? // 256KB
+            .set_default("envelope_guard.max_depth", 16)?
+            .set_default("envelope_guard.max_elements", 10000)?
+            .set_default("envelope_guard.max_string_bytes", 131072)? // 128KB
+            .set_default("envelope_guard.max_headers", 64)?
+            .set_default("envelope_guard.max_header_bytes", 16384)? // 16KB
+            
+            // Conversation lifecycle defaults
+            .set_default("conversation_lifecycle.enabled", false)?
+            .set_default("conversation_lifecycle.subject", "broker.lifecycle.conversations")?
+            .set_default("conversation_lifecycle.idle_after", 900)? // 15 minutes
+            .set_default("conversation_lifecycle.flush_interval", 5)? // seconds
+            .set_default("conversation_lifecycle.max_batch", 500)?
+            .set_default("conversation_lifecycle.max_pending", 100000)?
+            
+            // Quota feedback defaults
+            .set_default("quota_feedback.enabled", false)?
+            .set_default("quota_feedback.subject", "broker.quota")?
+            .set_default("quota_feedback.threshold", 0.2)?
+            
+            // Config drift defaults
+            .set_default("config_drift.check_interval", 60)? // seconds
+            .set_default("config_drift.request_timeout", 5)? // seconds
+            
+            // Maintenance defaults
+            .set_default("maintenance.enabled", false)?
+            .set_default("maintenance.stay_ready", true)?
+            
+            // Archive defaults
+            .set_default("archive.bucket", "broker-archived-conversations")?
+            .set_default("archive.cache_size", 100000)?
+            .set_default("archive.cache_ttl", 300)? // seconds
+            .set_default("archive.allowed_types", vec!["system_message", "delivered", "read"])?
+            
+            // Invitation defaults
+            .set_default("invitations.default_ttl", 604800)? // 7 days
+            .set_default("invitations.tracked_invitations", 500000)?
+            
+            // Integrity defaults
+            .set_default("integrity.enabled", true)?
+            .set_default("integrity.report_subject", "broker.integrity.corruption")?
+            .set_default("integrity.records_per_shard", 20000)?
+            
+            // Thread defaults
+            .set_default("threads.cache_size", 50000)?
+            .set_default("threads.participant_ttl", 300)? // seconds
+            .set_default("threads.marker_window", 60)? // seconds
+            .set_default("threads.marker_state_size", 200000)?
+            
+            // Startup warm-up defaults
+            .set_default("warmup.enabled", true)?
+            .set_default("warmup.top_k", 1000)?
+            .set_default("warmup.groups_per_second", 50.0)?
+            .set_default("warmup.max_duration", 60)? // seconds
+            .set_default("warmup.gate_readiness", false)?
+            .set_default("warmup.readiness_threshold", 0.8)?
+            
+            // DR replication defaults
+            .set_default("dr_replication.enabled", false)?
+            .set_default("dr_replication.subject", "dr.state")?
+            .set_default("dr_replication.stream", "DR_STATE")?
+            .set_default("dr_replication.consumer", "dr-state-applier")?
+            .set_default("dr_replication.batch_max_records", 500)?
+            .set_default("dr_replication.flush_interval_ms", 200)?
+            .set_default("dr_replication.buffer_max_records", 100000)?
+            
+            // Route warming defaults
+            .set_default("route_warming.enabled", true)?
+            .set_default("route_warming.bucket", "broker-route-activity")?
+            .set_default("route_warming.lead_time", 600)? // seconds
+            .set_default("route_warming.threshold_factor", 2.0)?
+            .set_default("route_warming.min_hourly_events", 1000)?
+            .set_default("route_warming.tracked_keys", 50000)?
+            .set_default("route_warming.top_users", 5000)?
+            .set_default("route_warming.top_groups", 1000)?
+            .set_default("route_warming.persisted_groups", 1000)?
+            .set_default("route_warming.tick_interval", 60)? // seconds
+            .set_default("route_warming.persist_interval", 300)? // seconds
+            
+            // Receipt intake defaults
+            .set_default("receipts.subject", "broker.receipts")?
+            .set_default("receipts.queue_group", "broker-receipts")?
+            .set_default("receipts.capabilities_subject", "broker.receipts.capabilities")?
+            .set_default("receipts.max_entries_per_frame", 1000)?
+            .set_default("receipts.gateway_flush_window_ms", 50)?
+            .set_default("receipts.skew_window", 300)? // 5 minutes
+            
+            // Warm standby defaults
+            .set_default("standby.enabled", false)?
+            .set_default("standby.auto_activate_after", 15)? // seconds
+            .set_default("standby.peer_poll_ms", 250)?
+            .set_default("standby.activation_budget_ms", 500)?
+            
+            // Conversation limit defaults
+            .set_default("conversation_limit.enabled", true)?
+            .set_default("conversation_limit.messages_per_window", 300)?
+            .set_default("conversation_limit.window", 60)? // seconds
+            .set_default("conversation_limit.digest_interval", 5)? // seconds
+            .set_default("conversation_limit.digest_max_messages", 50)?
+            
+            // Session migration defaults
+            .set_default("session_migration.bucket", "broker-session-migrations")?
+            .set_default("session_migration.gateway_control_prefix", "gateway.control")?
+            .set_default("session_migration.gateway_session_prefix", "gateway.session")?
+            .set_default("session_migration.request_timeout_ms", 2000)?
+            .set_default("session_migration.command_retries", 3)?
+            .set_default("session_migration.reconnect_timeout", 30)? // seconds
+            .set_default("session_migration.presence_poll_ms", 500)?
+            
+            // Background quota defaults
+            .set_default("background_quota.initial_fraction", 0.2)?
+            .set_default("background_quota.min_fraction", 0.02)?
+            .set_default("background_quota.max_fraction", 0.5)?
+            .set_default("background_quota.live_latency_target_ms", 50)?
+            .set_default("background_quota.latency_smoothing", 0.2)?
+            .set_default("background_quota.decrease_factor", 0.5)?
+            .set_default("background_quota.increase_step", 0.02)?
+            .set_default("background_quota.adjust_interval", 1)? // seconds
+            .set_default("background_quota.acquire_poll_ms", 10)?
+            
+            // Retry classification defaults
+            .set_default("retry.escalate_after", 5)?
+            .set_default("retry.history_window", 60)? // seconds
+            .set_default("retry.budget_ratio", 0.1)?
+            .set_default("retry.budget_max_tokens", 100)?
+            
+            // Ingestion pause defaults
+            .set_default("ingestion_pause.action", "nak")?
+            .set_default("ingestion_pause.nak_delay", 60)? // seconds
+            .set_default("ingestion_pause.default_ttl", 900)? // 15 minutes
+            .set_default("ingestion_pause.max_ttl", 86400)? // 24 hours
+            .set_default("ingestion_pause.holding_subject", "broker.ingress.held")?
+            .set_default("ingestion_pause.holding_stream", "BROKER_INGRESS_HELD")?
+            
+            // Sampling defaults
+            .set_default("sampling.enabled", false)?
+            .set_default("sampling.base_rate", 0.001)?
+            .set_default("sampling.subject", "broker.debug.samples")?
+            .set_default("sampling.always_dead_lettered", true)?
+            .set_default("sampling.redaction", "hash")?
+            .set_default("sampling.truncate_bytes", 64)?
+            .set_default("sampling.queue_size", 10000)?
+            
+            // Attestation defaults
+            .set_default("attestation.enabled", false)?
+            .set_default("attestation.replay_window", 30)? // seconds
+            .set_default("attestation.key_rotation_grace", 300)? // 5 minutes
+            .set_default("attestation.key_bucket", "broker-gateway-keys")?;
         
-        config.try_deserialize()
+        for (path, value) in overrides {
+            builder = builder.set_override(path.as_str(), override_value(value))?;
+        }
+        
+        let mut config: Self = builder.build()?.try_deserialize()?;
+        config.clamp_ranges()?;
+        config.validate_slos()?;
+        config.validate_kind_budgets()?;
+        config.validate_abuse_bands()?;
+        Ok(config)
+    }
+    
+    /// An objective of 1 leaves no error budget to burn
+    fn validate_slos(&self) -> Result<(), ConfigError> {
+        for slo in &self.slo.objectives {
+            if !(slo.objective > 0.0 && slo.objective < 1.0) {
+                return Err(ConfigError::Message(format!(
+                    "slo {} objective {} must be between 0 and 1, exclusive",
+                    slo.name, slo.objective
+                )));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Shares are percentages, and only deferrable or droppable kinds have one
+    fn validate_kind_budgets(&self) -> Result<(), ConfigError> {
+        let levels = &self.degradation.levels;
+        let shares = self
+            .kind_budgets
+            .share_percent
+            .iter()
+            .chain(&levels.normal.kind_shares)
+            .chain(&levels.conserve.kind_shares)
+            .chain(&levels.emergency.kind_shares);
+        for (kind, share) in shares {
+            if kind.over_budget_action().is_none() {
+                return Err(ConfigError::Message(format!("{} traffic can't be given a budget", kind.as_str())));
+            }
+            if !(0.0..=100.0).contains(share) {
+                return Err(ConfigError::Message(format!(
+                    "{} budget {}% must be between 0 and 100",
+                    kind.as_str(),
+                    share
+                )));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Each band exits below where it enters, and tiers enter in escalating order
+    fn validate_abuse_bands(&self) -> Result<(), ConfigError> {
+        let abuse = &self.abuse;
+        for (name, band) in [("throttle", &abuse.throttle), ("review", &abuse.review), ("pause", &abuse.pause)] {
+            if band.exit > band.enter {
+                return Err(ConfigError::Message(format!(
+                    "abuse.{} exit {} is above its enter {}",
+                    name, band.exit, band.enter
+                )));
+            }
+        }
+        if !(abuse.throttle.enter <= abuse.review.enter && abuse.review.enter <= abuse.pause.enter) {
+            return Err(ConfigError::Message(
+                "abuse bands must enter in order: throttle, review, pause".into(),
+            ));
+        }
+        Ok(())
+    }
+    
+    /// Clamp numeric fields into `CONFIG_RANGES`, or fail under `strict_config`
+    pub fn clamp_ranges(&mut self) -> Result<(), ConfigError> {
+        self.clamped_fields.clear();
+        
+        for range in CONFIG_RANGES {
+            let strict = self.strict_config;
+            let mut field = (range.access)(self);
+            let value = field.get();
+            if (range.min..=range.max).contains(&value) {
+                continue;
+            }
+            
+            if strict {
+                return Err(ConfigError::Message(format!(
+                    "{} = {} is outside the sane range [{}, {}]",
+                    range.field, value, range.min, range.max
+                )));
+            }
+            
+            let clamped = value.clamp(range.min, range.max);
+            field.set(clamped);
+            warn!(
+                "CONFIG CLAMPED: {} = {} is outside [{}, {}], using {}",
+                range.field, value, range.min, range.max, clamped
+            );
+            self.clamped_fields.push(range.field);
+        }
+        
+        Ok(())
     }
     
     pub fn is_production(&self) -> bool {
@@ -151,6 +2263,230 @@ impl BrokerConfig {
     }
 }
 
+/// Mutable view of one numeric config field
+pub enum NumericField<'a> {
+    Usize(&'a mut usize),
+    U32(&'a mut u32),
+    U64(&'a mut u64),
+    F64(&'a mut f64),
+}
+
+impl NumericField<'_> {
+    fn get(&self) -> f64 {
+        match self {
+            NumericField::Usize(v) => **v as f64,
+            NumericField::U32(v) => **v as f64,
+            NumericField::U64(v) => **v as f64,
+            NumericField::F64(v) => **v,
+        }
+    }
+    
+    fn set(&mut self, value: f64) {
+        match self {
+            NumericField::Usize(v) => **v = value as usize,
+            NumericField::U32(v) => **v = value as u32,
+            NumericField::U64(v) => **v = value as u64,
+            NumericField::F64(v) => **v = value,
+        }
+    }
+}
+
+/// Sane range for a numeric config field
+pub struct ConfigRange {
+    pub field: &'static str,
+    pub min: f64,
+    pub max: f64,
+    access: fn(&mut BrokerConfig) -> NumericField<'_>,
+}
+
+/// Single source for range validation, clamping and `--print-config-ranges`
+pub const CONFIG_RANGES: &[ConfigRange] = &[
+    ConfigRange { field: "nats.mirror_max_lag", min: 0.0, max: 10_000_000.0, access: |c| NumericField::U64(&mut c.nats.mirror_max_lag) },
+    ConfigRange { field: "nats.history_max_page_size", min: 1.0, max: 1_000.0, access: |c| NumericField::Usize(&mut c.nats.history_max_page_size) },
+    ConfigRange { field: "api.max_concurrent_streams", min: 1.0, max: 100_000.0, access: |c| NumericField::U32(&mut c.api.max_concurrent_streams) },
+    ConfigRange { field: "api.max_frame_size", min: 1_024.0, max: 16_777_216.0, access: |c| NumericField::Usize(&mut c.api.max_frame_size) },
+    ConfigRange { field: "api.subscribe_buffer_size", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.api.subscribe_buffer_size) },
+    ConfigRange { field: "api.subscribe_min_buffer_size", min: 1.0, max: 10_000.0, access: |c| NumericField::Usize(&mut c.api.subscribe_min_buffer_size) },
+    ConfigRange { field: "routing.shard_count", min: 1.0, max: 4_096.0, access: |c| NumericField::Usize(&mut c.routing.shard_count) },
+    ConfigRange { field: "routing.fanout_batch_size", min: 1.0, max: 10_000.0, access: |c| NumericField::Usize(&mut c.routing.fanout_batch_size) },
+    ConfigRange { field: "routing.fanout_parallelism", min: 1.0, max: 1_024.0, access: |c| NumericField::Usize(&mut c.routing.fanout_parallelism) },
+    ConfigRange { field: "routing.presence_bulk_chunk_size", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.routing.presence_bulk_chunk_size) },
+    ConfigRange { field: "routing.presence_bulk_concurrency", min: 1.0, max: 1_024.0, access: |c| NumericField::Usize(&mut c.routing.presence_bulk_concurrency) },
+    ConfigRange { field: "size_accounting.warn_sample_rate", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.size_accounting.warn_sample_rate) },
+    ConfigRange { field: "threads.cache_size", min: 1.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.threads.cache_size) },
+    ConfigRange { field: "threads.marker_state_size", min: 1.0, max: 100_000_000.0, access: |c| NumericField::Usize(&mut c.threads.marker_state_size) },
+    ConfigRange { field: "warmup.groups_per_second", min: 0.1, max: 10_000.0, access: |c| NumericField::F64(&mut c.warmup.groups_per_second) },
+    ConfigRange { field: "warmup.readiness_threshold", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.warmup.readiness_threshold) },
+    ConfigRange { field: "payload_intern.shards", min: 1.0, max: 1024.0, access: |c| NumericField::Usize(&mut c.payload_intern.shards) },
+    ConfigRange { field: "soft_handoff.max_deadlines", min: 1.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.soft_handoff.max_deadlines) },
+    ConfigRange { field: "user_events.ring_size", min: 1.0, max: 1000.0, access: |c| NumericField::Usize(&mut c.user_events.ring_size) },
+    ConfigRange { field: "user_events.shards", min: 1.0, max: 1024.0, access: |c| NumericField::Usize(&mut c.user_events.shards) },
+    ConfigRange { field: "user_events.users_per_shard", min: 1.0, max: 1_000_000.0, access: |c| NumericField::Usize(&mut c.user_events.users_per_shard) },
+    ConfigRange { field: "nak.max_tracked", min: 1.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.nak.max_tracked) },
+    ConfigRange { field: "history_cache.max_conversations", min: 1.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.history_cache.max_conversations) },
+    ConfigRange { field: "volume_accounting.shards", min: 1.0, max: 1024.0, access: |c| NumericField::Usize(&mut c.volume_accounting.shards) },
+    ConfigRange { field: "preview.default.max_graphemes", min: 1.0, max: 1000.0, access: |c| NumericField::Usize(&mut c.preview.default.max_graphemes) },
+    ConfigRange { field: "interest_reconcile.confirm_cycles", min: 2.0, max: 100.0, access: |c| NumericField::U32(&mut c.interest_reconcile.confirm_cycles) },
+    ConfigRange { field: "ingress_admission.nats_guaranteed_share", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.ingress_admission.nats_guaranteed_share) },
+    ConfigRange { field: "route_repair.presence_timeout_ms", min: 1.0, max: 5_000.0, access: |c| NumericField::U64(&mut c.route_repair.presence_timeout_ms) },
+    ConfigRange { field: "nats_probe.timeout_ms", min: 10.0, max: 60_000.0, access: |c| NumericField::U64(&mut c.nats_probe.timeout_ms) },
+    ConfigRange { field: "nats_probe.degraded_after", min: 1.0, max: 100.0, access: |c| NumericField::U32(&mut c.nats_probe.degraded_after) },
+    ConfigRange { field: "nats_probe.recover_after", min: 1.0, max: 100.0, access: |c| NumericField::U32(&mut c.nats_probe.recover_after) },
+    ConfigRange { field: "kv_compaction.purges_per_second", min: 0.1, max: 10_000.0, access: |c| NumericField::F64(&mut c.kv_compaction.purges_per_second) },
+    ConfigRange { field: "coalesce.window_ms", min: 0.0, max: 10_000.0, access: |c| NumericField::U64(&mut c.coalesce.window_ms) },
+    ConfigRange { field: "coalesce.max_keys", min: 1.0, max: 1_000_000.0, access: |c| NumericField::Usize(&mut c.coalesce.max_keys) },
+    ConfigRange { field: "unread.max_count", min: 1.0, max: 1_000_000.0, access: |c| NumericField::U64(&mut c.unread.max_count) },
+    ConfigRange { field: "unread.exact_skip_limit", min: 1.0, max: 10_000.0, access: |c| NumericField::Usize(&mut c.unread.exact_skip_limit) },
+    ConfigRange { field: "conversation_home.cache_size", min: 1.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.conversation_home.cache_size) },
+    ConfigRange { field: "egress_cipher.session_max_messages", min: 1_000.0, max: 1e9, access: |c| NumericField::U64(&mut c.egress_cipher.session_max_messages) },
+    ConfigRange { field: "api.deadline_margin_ms", min: 0.0, max: 1_000.0, access: |c| NumericField::U64(&mut c.api.deadline_margin_ms) },
+    ConfigRange { field: "profiling.max_seconds", min: 1.0, max: 300.0, access: |c| NumericField::U64(&mut c.profiling.max_seconds) },
+    ConfigRange { field: "profiling.frequency", min: 1.0, max: 1_000.0, access: |c| NumericField::U32(&mut c.profiling.frequency) },
+    ConfigRange { field: "compression.sample_rate", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.compression.sample_rate) },
+    ConfigRange { field: "compression.min_samples", min: 10.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.compression.min_samples) },
+    ConfigRange { field: "compression.max_dictionary_bytes", min: 1_024.0, max: 1_048_576.0, access: |c| NumericField::Usize(&mut c.compression.max_dictionary_bytes) },
+    ConfigRange { field: "compression.min_improvement", min: 0.0, max: 0.9, access: |c| NumericField::F64(&mut c.compression.min_improvement) },
+    ConfigRange { field: "offline_quarantine.batch_size", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.offline_quarantine.batch_size) },
+    ConfigRange { field: "classification.timeout_ms", min: 1.0, max: 1_000.0, access: |c| NumericField::U64(&mut c.classification.timeout_ms) },
+    ConfigRange { field: "recipient_trace.sample_rate", min: 0.0, max: 0.01, access: |c| NumericField::F64(&mut c.recipient_trace.sample_rate) },
+    ConfigRange { field: "recipient_trace.max_recipients", min: 1.0, max: 10000.0, access: |c| NumericField::Usize(&mut c.recipient_trace.max_recipients) },
+    ConfigRange { field: "abuse.throttle_fraction", min: 0.01, max: 1.0, access: |c| NumericField::F64(&mut c.abuse.throttle_fraction) },
+    ConfigRange { field: "offline_transfer.batch_size", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.offline_transfer.batch_size) },
+    ConfigRange { field: "kind_budgets.utilization_threshold", min: 0.0, max: 2.0, access: |c| NumericField::F64(&mut c.kind_budgets.utilization_threshold) },
+    ConfigRange { field: "lock_metrics.slow_wait_us", min: 1.0, max: 10_000_000.0, access: |c| NumericField::U64(&mut c.lock_metrics.slow_wait_us) },
+    ConfigRange { field: "envelope_guard.max_depth", min: 2.0, max: 64.0, access: |c| NumericField::Usize(&mut c.envelope_guard.max_depth) },
+    ConfigRange { field: "envelope_guard.max_elements", min: 16.0, max: 1_000_000.0, access: |c| NumericField::Usize(&mut c.envelope_guard.max_elements) },
+    ConfigRange { field: "conversation_lifecycle.max_batch", min: 1.0, max: 10_000.0, access: |c| NumericField::Usize(&mut c.conversation_lifecycle.max_batch) },
+    ConfigRange { field: "quota_feedback.threshold", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.quota_feedback.threshold) },
+    ConfigRange { field: "route_warming.threshold_factor", min: 1.0, max: 100.0, access: |c| NumericField::F64(&mut c.route_warming.threshold_factor) },
+    ConfigRange { field: "receipts.max_entries_per_frame", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.receipts.max_entries_per_frame) },
+    ConfigRange { field: "routing.sequence_block_size", min: 1.0, max: 1_000_000.0, access: |c| NumericField::U64(&mut c.routing.sequence_block_size) },
+    ConfigRange { field: "routing.sequence_prefetch_fraction", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.routing.sequence_prefetch_fraction) },
+    ConfigRange { field: "routing.cache_size", min: 100.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.routing.cache_size) },
+    ConfigRange { field: "routing.bloom_filter_size", min: 1_000.0, max: 100_000_000.0, access: |c| NumericField::Usize(&mut c.routing.bloom_filter_size) },
+    ConfigRange { field: "routing.dedup_cache_size", min: 1_024.0, max: 100_000_000.0, access: |c| NumericField::Usize(&mut c.routing.dedup_cache_size) },
+    ConfigRange { field: "routing.membership_delta_threshold", min: 0.01, max: 10.0, access: |c| NumericField::F64(&mut c.routing.membership_delta_threshold) },
+    ConfigRange { field: "routing.read_horizon_coalesce_ms", min: 0.0, max: 60_000.0, access: |c| NumericField::U64(&mut c.routing.read_horizon_coalesce_ms) },
+    ConfigRange { field: "routing.adaptive_batching.min_batch_size", min: 1.0, max: 10_000.0, access: |c| NumericField::Usize(&mut c.routing.adaptive_batching.min_batch_size) },
+    ConfigRange { field: "routing.adaptive_batching.max_batch_size", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.routing.adaptive_batching.max_batch_size) },
+    ConfigRange { field: "routing.adaptive_batching.decrease_factor", min: 0.1, max: 0.95, access: |c| NumericField::F64(&mut c.routing.adaptive_batching.decrease_factor) },
+    ConfigRange { field: "limits.messages_per_second", min: 1.0, max: 1_000_000.0, access: |c| NumericField::U32(&mut c.limits.messages_per_second) },
+    ConfigRange { field: "limits.burst_size", min: 1.0, max: 1_000_000.0, access: |c| NumericField::U32(&mut c.limits.burst_size) },
+    ConfigRange { field: "limits.max_message_size", min: 1_024.0, max: 67_108_864.0, access: |c| NumericField::Usize(&mut c.limits.max_message_size) },
+    ConfigRange { field: "limits.max_recipients_per_message", min: 1.0, max: 100_000.0, access: |c| NumericField::Usize(&mut c.limits.max_recipients_per_message) },
+    ConfigRange { field: "limits.max_group_size", min: 2.0, max: 1_000_000.0, access: |c| NumericField::Usize(&mut c.limits.max_group_size) },
+    ConfigRange { field: "limits.max_transaction_messages", min: 1.0, max: 1_000.0, access: |c| NumericField::Usize(&mut c.limits.max_transaction_messages) },
+    ConfigRange { field: "limits.deferred_queue_size", min: 0.0, max: 1_000.0, access: |c| NumericField::Usize(&mut c.limits.deferred_queue_size) },
+    ConfigRange { field: "limits.burst_credit_quiet_fraction", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.limits.burst_credit_quiet_fraction) },
+    ConfigRange { field: "background_quota.initial_fraction", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.background_quota.initial_fraction) },
+    ConfigRange { field: "background_quota.max_fraction", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.background_quota.max_fraction) },
+    ConfigRange { field: "sampling.base_rate", min: 0.0, max: 1.0, access: |c| NumericField::F64(&mut c.sampling.base_rate) },
+    ConfigRange { field: "priority_inheritance.cache_size", min: 0.0, max: 10_000_000.0, access: |c| NumericField::Usize(&mut c.priority_inheritance.cache_size) },
+];
+
+/// Output for `--print-config-ranges`
+pub fn print_config_ranges() -> String {
+    let width = CONFIG_RANGES.iter().map(|r| r.field.len()).max().unwrap_or(0);
+    CONFIG_RANGES
+        .iter()
+        .map(|r| format!("{:width$}  [{}, {}]\n", r.field, r.min, r.max, width = width))
+        .collect()
+}
+
+fn override_value(value: &serde_json::Value) -> config::Value {
+    use serde_json::Value as Json;
+    let kind = match value {
+        Json::Null => ValueKind::Nil,
+        Json::Bool(b) => ValueKind::Boolean(*b),
+        Json::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => ValueKind::I64(i),
+            (None, Some(u)) => ValueKind::U64(u),
+            (None, None) => ValueKind::Float(n.as_f64().unwrap_or_default()),
+        },
+        Json::String(s) => ValueKind::String(s.clone()),
+        Json::Array(items) => ValueKind::Array(items.iter().map(override_value).collect()),
+        Json::Object(fields) => ValueKind::Table(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), override_value(value)))
+                .collect(),
+        ),
+    };
+    config::Value::new(None, kind)
+}
+
+/// Durations as (possibly fractional) seconds in every config source
+///
+/// Defaults, files and environment variables all give durations as plain
+/// seconds; environment values arrive as strings.
+mod duration_secs {
+    use std::{fmt, time::Duration};
+
+    use serde::{de, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration.subsec_nanos() {
+            0 => serializer.serialize_u64(duration.as_secs()),
+            _ => serializer.serialize_f64(duration.as_secs_f64()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(SecondsVisitor)
+    }
+
+    struct SecondsVisitor;
+
+    impl de::Visitor<'_> for SecondsVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a non-negative number of seconds")
+        }
+
+        fn visit_u64<E: de::Error>(self, secs: u64) -> Result<Duration, E> {
+            Ok(Duration::from_secs(secs))
+        }
+
+        fn visit_i64<E: de::Error>(self, secs: i64) -> Result<Duration, E> {
+            u64::try_from(secs)
+                .map(Duration::from_secs)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(secs), &self))
+        }
+
+        fn visit_f64<E: de::Error>(self, secs: f64) -> Result<Duration, E> {
+            Duration::try_from_secs_f64(secs).map_err(|_| E::invalid_value(de::Unexpected::Float(secs), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Duration, E> {
+            match value.trim().parse::<f64>() {
+                Ok(secs) => self.visit_f64(secs),
+                Err(_) => Err(E::invalid_value(de::Unexpected::Str(value), &self)),
+            }
+        }
+    }
+
+    pub mod option {
+        use std::time::Duration;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(duration) => super::serialize(duration, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+            #[derive(Deserialize)]
+            struct Seconds(#[serde(with = "super")] Duration);
+
+            Ok(Option::<Seconds>::deserialize(deserializer)?.map(|Seconds(duration)| duration))
+        }
+    }
+}
+
 fn generate_broker_id() -> String {
     use std::time::{SystemTime, UNIX_EPOCH};
     
@@ -166,3 +2502,73 @@ fn generate_broker_id() -> String {
     
     format!("{}-{}-{}", hostname, pid, timestamp)
   }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(values: &[(&str, serde_json::Value)]) -> Vec<(String, serde_json::Value)> {
+        values.iter().map(|(path, value)| (path.to_string(), value.clone())).collect()
+    }
+
+    #[test]
+    fn defaults_are_within_every_range() {
+        let config = BrokerConfig::load().unwrap();
+        assert!(config.clamped_fields.is_empty(), "clamped: {:?}", config.clamped_fields);
+    }
+
+    #[test]
+    fn absurd_values_are_clamped_outside_strict_mode() {
+        let config = BrokerConfig::load_with_overrides(&overrides(&[
+            ("routing.fanout_parallelism", serde_json::json!(100_000)),
+            ("routing.cache_size", serde_json::json!(2)),
+        ]))
+        .unwrap();
+
+        assert_eq!(config.routing.fanout_parallelism, 1_024);
+        assert_eq!(config.routing.cache_size, 100);
+        assert_eq!(config.clamped_fields, vec!["routing.fanout_parallelism", "routing.cache_size"]);
+    }
+
+    #[test]
+    fn strict_mode_rejects_out_of_range_values() {
+        let err = BrokerConfig::load_with_overrides(&overrides(&[
+            ("strict_config", serde_json::json!(true)),
+            ("routing.cache_size", serde_json::json!(2)),
+        ]))
+        .unwrap_err();
+        assert!(err.to_string().contains("routing.cache_size = 2"), "{}", err);
+
+        let mut config = BrokerConfig::load().unwrap();
+        config.strict_config = true;
+        config.routing.fanout_parallelism = 100_000;
+        assert!(config.clamp_ranges().is_err());
+        assert_eq!(config.routing.fanout_parallelism, 100_000);
+    }
+
+    #[test]
+    fn boundary_values_pass_untouched() {
+        let mut config = BrokerConfig::load().unwrap();
+        config.strict_config = true;
+
+        let bounds: [fn(&ConfigRange) -> f64; 2] = [|r| r.min, |r| r.max];
+        for bound in bounds {
+            for range in CONFIG_RANGES {
+                (range.access)(&mut config).set(bound(range));
+            }
+            config.clamp_ranges().unwrap();
+            assert!(config.clamped_fields.is_empty());
+            for range in CONFIG_RANGES {
+                assert_eq!((range.access)(&mut config).get(), bound(range), "{}", range.field);
+            }
+        }
+    }
+
+    #[test]
+    fn printed_ranges_come_from_the_table() {
+        let printed = print_config_ranges();
+        assert_eq!(printed.lines().count(), CONFIG_RANGES.len());
+        let line = printed.lines().find(|l| l.starts_with("routing.fanout_parallelism ")).unwrap();
+        assert!(line.ends_with("[1, 1024]"));
+    }
+}
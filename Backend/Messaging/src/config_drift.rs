@@ -0,0 +1,448 @@
+use std::{collections::BTreeSet, sync::Arc};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use serde_json::Value;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    cluster::ClusterView,
+    config::{BrokerConfig, ConfigDriftConfig},
+    config_watch::ConfigWatcher,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Secrets, replaced by `REDACTED` in dumps and fingerprints
+const SECRET_FIELDS: &[&str] = &[
+    "/nats/password",
+    "/nats/token",
+    "/metrics/metered_tenants",
+    "/outbox/connection_string",
+];
+
+/// Fields that legitimately differ per broker
+const LOCAL_FIELDS: &[&str] = &["/broker_id", "/cluster/advertise_grpc_addr"];
+
+const REDACTED: &str = "[redacted]";
+
+/// Effective config as JSON with secrets redacted and per-broker fields removed
+pub fn sanitized(config: &BrokerConfig) -> Value {
+    let mut value = serde_json::to_value(config).unwrap_or(Value::Null);
+    for pointer in SECRET_FIELDS {
+        if let Some(field) = value.pointer_mut(pointer) {
+            if !field.is_null() {
+                *field = Value::String(REDACTED.to_string());
+            }
+        }
+    }
+    for pointer in LOCAL_FIELDS {
+        let (parent, key) = pointer.rsplit_once('/').unwrap_or(("", pointer));
+        if let Some(Value::Object(object)) = value.pointer_mut(parent) {
+            object.remove(key);
+        }
+    }
+    value
+}
+
+/// Hex SHA-256 of the sanitized config; object keys serialize sorted, so it's stable
+pub fn fingerprint(sanitized: &Value) -> String {
+    let canonical = serde_json::to_vec(sanitized).unwrap_or_default();
+    hex::encode(digest(&SHA256, &canonical))
+}
+
+/// One leaf that differs between two sanitized configs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldDiff {
+    /// Dotted path, e.g. `limits.max_message_size`
+    pub field: String,
+    /// `None` when the field is absent on that side
+    pub local: Option<Value>,
+    pub peer: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigDiff {
+    pub local_broker: String,
+    pub peer_broker: String,
+    pub local_fingerprint: String,
+    pub peer_fingerprint: String,
+    pub fields: Vec<FieldDiff>,
+}
+
+/// Field-by-field differences; objects recurse, arrays compare whole
+pub fn diff(local: &Value, peer: &Value) -> Vec<FieldDiff> {
+    let mut fields = Vec::new();
+    diff_into("", Some(local), Some(peer), &mut fields);
+    fields
+}
+
+fn diff_into(path: &str, local: Option<&Value>, peer: Option<&Value>, out: &mut Vec<FieldDiff>) {
+    match (local, peer) {
+        (Some(Value::Object(local)), Some(Value::Object(peer))) => {
+            let keys: BTreeSet<&String> = local.keys().chain(peer.keys()).collect();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_into(&child, local.get(key), peer.get(key), out);
+            }
+        }
+        (local, peer) if local != peer => out.push(FieldDiff {
+            field: path.to_string(),
+            local: local.cloned(),
+            peer: peer.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// Config drift detection across the cluster
+///
+/// Each broker advertises the fingerprint of its sanitized effective config
+/// in its cluster heartbeat, recomputed on every reload. The monitor
+/// compares live peers' fingerprints with the local one every
+/// `config_drift.check_interval`, exports the number of diverging peers as
+/// `broker_cluster_config_divergence`, and warns when that set changes.
+/// Peers answer dump requests on `{control_topic}.config.{broker_id}`, so
+/// an operator can get a field-level diff from any broker. Secrets are
+/// redacted before hashing or dumping, and fields that differ per broker
+/// by design (`broker_id`, advertised address) are left out.
+pub struct ConfigDrift {
+    cluster: Arc<ClusterView>,
+    client: async_nats::Client,
+    dump_prefix: String,
+    config: ConfigDriftConfig,
+    local: ArcSwap<Value>,
+    /// Peers seen diverging on the last check, for change-only warnings
+    diverging: Mutex<BTreeSet<String>>,
+    metrics: BrokerMetrics,
+}
+
+impl ConfigDrift {
+    pub fn new(
+        broker_config: &BrokerConfig,
+        cluster: Arc<ClusterView>,
+        client: async_nats::Client,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let local = sanitized(broker_config);
+        cluster.set_config_fingerprint(fingerprint(&local));
+        Self {
+            cluster,
+            client,
+            dump_prefix: format!("{}.config", broker_config.nats.control_topic),
+            config: broker_config.config_drift.clone(),
+            local: ArcSwap::from_pointee(local),
+            diverging: Mutex::new(BTreeSet::new()),
+            metrics,
+        }
+    }
+
+    pub fn local_dump(&self) -> Arc<Value> {
+        self.local.load_full()
+    }
+
+    pub fn local_fingerprint(&self) -> String {
+        fingerprint(&self.local.load())
+    }
+
+    /// Keep the dump and advertised fingerprint current across reloads
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let drift = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            let local = sanitized(config);
+            drift.cluster.set_config_fingerprint(fingerprint(&local));
+            drift.local.store(Arc::new(local));
+        });
+    }
+
+    /// Peers whose advertised fingerprint differs from ours; older brokers advertise none
+    pub fn check(&self) -> usize {
+        let local = self.local_fingerprint();
+        let diverging: BTreeSet<String> = self
+            .cluster
+            .members()
+            .into_iter()
+            .filter(|peer| peer.broker_id != self.cluster.local_id())
+            .filter(|peer| peer.config_fingerprint.as_ref().is_some_and(|f| *f != local))
+            .map(|peer| peer.broker_id)
+            .collect();
+        self.metrics.update_cluster_config_divergence(diverging.len());
+
+        let mut previous = self.diverging.lock();
+        if *previous != diverging {
+            if diverging.is_empty() {
+                info!("Cluster config converged");
+            } else {
+                warn!(
+                    "Config fingerprint {} differs from peers {:?}; compare with /admin/config/diff",
+                    local, diverging
+                );
+            }
+            *previous = diverging.clone();
+        }
+        diverging.len()
+    }
+
+    /// Fetch a peer's sanitized config dump over request-reply
+    pub async fn fetch_peer(&self, peer_id: &str) -> Result<Value, DriftError> {
+        let request = self
+            .client
+            .request(format!("{}.{}", self.dump_prefix, peer_id), Vec::new().into());
+        let reply = tokio::time::timeout(self.config.request_timeout, request)
+            .await
+            .map_err(|_| DriftError::Timeout(peer_id.to_string()))?
+            .map_err(|e| DriftError::Request(e.to_string()))?;
+        serde_json::from_slice(&reply.payload).map_err(|e| DriftError::Request(e.to_string()))
+    }
+
+    pub async fn diff_with(&self, peer_id: &str) -> Result<ConfigDiff, DriftError> {
+        let peer = self.fetch_peer(peer_id).await?;
+        let local = self.local.load_full();
+        Ok(ConfigDiff {
+            local_broker: self.cluster.local_id().to_string(),
+            peer_broker: peer_id.to_string(),
+            local_fingerprint: fingerprint(&local),
+            peer_fingerprint: fingerprint(&peer),
+            fields: diff(&local, &peer),
+        })
+    }
+
+    /// Answer dump requests for this broker and run the divergence check
+    pub fn spawn(self: &Arc<Self>) -> Vec<tokio::task::JoinHandle<()>> {
+        let drift = Arc::clone(self);
+        let responder = spawn_traced("config_dump_responder", TaskContext::new("config_drift"), async move {
+            let subject = format!("{}.{}", drift.dump_prefix, drift.cluster.local_id());
+            let mut requests = match drift.client.subscribe(subject).await {
+                Ok(requests) => requests,
+                Err(e) => {
+                    warn!("Failed to subscribe to config dump requests: {}", e);
+                    return;
+                }
+            };
+            while let Some(request) = requests.next().await {
+                let Some(reply) = request.reply else {
+                    continue;
+                };
+                let payload = serde_json::to_vec(&**drift.local.load()).unwrap_or_default();
+                if let Err(e) = drift.client.publish(reply, payload.into()).await {
+                    warn!("Failed to answer config dump request: {}", e);
+                }
+            }
+        });
+
+        let drift = Arc::clone(self);
+        let monitor = spawn_traced("config_drift_check", TaskContext::new("config_drift"), async move {
+            let mut ticker = tokio::time::interval(drift.config.check_interval);
+            loop {
+                ticker.tick().await;
+                drift.check();
+            }
+        });
+
+        vec![responder, monitor]
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DriftError {
+    #[error("peer {0} did not answer the config dump request")]
+    Timeout(String),
+    #[error("config dump request failed: {0}")]
+    Request(String),
+}
+
+/// The two-broker tests run against NATS at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored config_drift`
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_nats::jetstream::{self, kv};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn config(broker_id: &str) -> BrokerConfig {
+        let mut config = BrokerConfig::load().unwrap();
+        config.broker_id = broker_id.to_string();
+        config
+    }
+
+    /// A broker whose limits were left behind by a partial rollout
+    fn stale(broker_id: &str) -> BrokerConfig {
+        let mut config = config(broker_id);
+        config.limits.max_message_size += 1;
+        config.limits.messages_per_second += 1;
+        config
+    }
+
+    fn fields(diff: &[FieldDiff]) -> Vec<&str> {
+        diff.iter().map(|field| field.field.as_str()).collect()
+    }
+
+    #[test]
+    fn secrets_are_redacted_and_local_fields_dropped() {
+        let mut config = config("broker-1");
+        config.nats.password = Some("hunter2".to_string());
+        config.nats.token = None;
+        config.cluster.advertise_grpc_addr = Some("10.0.0.1:50051".to_string());
+
+        let dump = sanitized(&config);
+        assert_eq!(dump.pointer("/nats/password"), Some(&Value::String(REDACTED.to_string())));
+        // Unset secrets stay null so the dump shows they are unset
+        assert_eq!(dump.pointer("/nats/token"), Some(&Value::Null));
+        assert!(dump.pointer("/broker_id").is_none());
+        assert!(dump.pointer("/cluster/advertise_grpc_addr").is_none());
+        assert!(dump.pointer("/cluster/heartbeat_interval").is_some());
+        assert!(!dump.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn fingerprints_ignore_secrets_and_broker_identity() {
+        let mut first = config("broker-1");
+        first.nats.password = Some("one".to_string());
+        let mut second = config("broker-2");
+        second.nats.password = Some("different".to_string());
+        second.cluster.advertise_grpc_addr = Some("10.0.0.2:50051".to_string());
+        let mut unset = config("broker-3");
+        unset.nats.password = None;
+
+        let fingerprint_of = |config: &BrokerConfig| fingerprint(&sanitized(config));
+        assert_eq!(fingerprint_of(&first), fingerprint_of(&first));
+        assert_eq!(fingerprint_of(&first).len(), 64);
+        assert_eq!(fingerprint_of(&first), fingerprint_of(&second));
+        assert_ne!(fingerprint_of(&first), fingerprint_of(&stale("broker-1")));
+        // Whether a secret is set at all is still part of the config
+        assert_ne!(fingerprint_of(&second), fingerprint_of(&unset));
+    }
+
+    #[test]
+    fn diff_names_exactly_the_differing_fields() {
+        let local = sanitized(&config("broker-1"));
+        let peer = sanitized(&stale("broker-2"));
+        assert!(diff(&local, &local).is_empty());
+
+        let fields_diff = diff(&local, &peer);
+        assert_eq!(fields(&fields_diff), vec!["limits.max_message_size", "limits.messages_per_second"]);
+        let size = &fields_diff[0];
+        assert_eq!(
+            size.peer.as_ref().unwrap().as_u64().unwrap(),
+            size.local.as_ref().unwrap().as_u64().unwrap() + 1
+        );
+    }
+
+    #[test]
+    fn diff_reports_missing_fields_and_compares_arrays_whole() {
+        let local = serde_json::json!({ "a": { "b": 1, "c": [1, 2] }, "only_local": true });
+        let peer = serde_json::json!({ "a": { "b": 1, "c": [2, 1] }, "only_peer": "x" });
+        assert_eq!(
+            diff(&local, &peer),
+            vec![
+                FieldDiff {
+                    field: "a.c".to_string(),
+                    local: Some(serde_json::json!([1, 2])),
+                    peer: Some(serde_json::json!([2, 1])),
+                },
+                FieldDiff {
+                    field: "only_local".to_string(),
+                    local: Some(Value::Bool(true)),
+                    peer: None,
+                },
+                FieldDiff {
+                    field: "only_peer".to_string(),
+                    local: None,
+                    peer: Some(Value::String("x".to_string())),
+                },
+            ]
+        );
+    }
+
+    async fn broker(
+        client: &async_nats::Client,
+        bucket: &kv::Store,
+        config: &BrokerConfig,
+    ) -> (Arc<ConfigDrift>, Arc<ClusterView>) {
+        let view = Arc::new(ClusterView::new(bucket.clone(), config.broker_id.clone(), &config.cluster));
+        let drift = Arc::new(ConfigDrift::new(
+            config,
+            Arc::clone(&view),
+            client.clone(),
+            BrokerMetrics::new().unwrap(),
+        ));
+        view.spawn();
+        drift.spawn();
+        view.heartbeat().await.unwrap();
+        (drift, view)
+    }
+
+    async fn wait_for_members(view: &ClusterView, count: usize) {
+        for _ in 0..100 {
+            if view.members().len() == count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("cluster view never saw {} members", count);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn two_brokers_on_different_limits_diverge_and_diff() {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let client = async_nats::connect(url).await.unwrap();
+        let bucket = jetstream::new(client.clone())
+            .create_key_value(kv::Config {
+                bucket: format!("drift-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let id = Uuid::new_v4().simple().to_string();
+        let mut current = config(&format!("current-{}", id));
+        current.nats.control_topic = format!("drift-test-{}", id);
+        let mut behind = stale(&format!("stale-{}", id));
+        behind.nats.control_topic = current.nats.control_topic.clone();
+
+        let (first, first_view) = broker(&client, &bucket, &current).await;
+        let (second, second_view) = broker(&client, &bucket, &behind).await;
+        wait_for_members(&first_view, 2).await;
+        wait_for_members(&second_view, 2).await;
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        assert_eq!(metrics::with_local_recorder(&recorder, || first.check()), 1);
+        assert!(handle.render().contains("broker_cluster_config_divergence 1"), "{}", handle.render());
+        assert_eq!(second.check(), 1);
+
+        let report = first.diff_with(second_view.local_id()).await.unwrap();
+        assert_eq!(report.local_broker, current.broker_id);
+        assert_eq!(report.peer_broker, behind.broker_id);
+        assert_eq!(report.local_fingerprint, first.local_fingerprint());
+        assert_eq!(report.peer_fingerprint, second.local_fingerprint());
+        assert_eq!(fields(&report.fields), vec!["limits.max_message_size", "limits.messages_per_second"]);
+
+        // The stale broker catches up: its next heartbeat clears the divergence
+        second_view.set_config_fingerprint(first.local_fingerprint());
+        second_view.heartbeat().await.unwrap();
+        for _ in 0..100 {
+            if metrics::with_local_recorder(&recorder, || first.check()) == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(handle.render().contains("broker_cluster_config_divergence 0"), "{}", handle.render());
+
+        assert!(matches!(
+            first.fetch_peer("no-such-broker").await,
+            Err(DriftError::Timeout(_) | DriftError::Request(_))
+        ));
+    }
+}
@@ -0,0 +1,503 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    cluster::ClusterView,
+    config::{BrokerConfig, ConfigOverrideConfig},
+    config_watch::ConfigWatcher,
+    metrics::BrokerMetrics,
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Resolution of the rollout bucket, in hundredths of a percent
+const ROLLOUT_BUCKETS: usize = 10_000;
+
+/// Config paths an override may target: sections with a reload listener
+///
+/// A dotted path is allowed when it equals an entry or lies beneath one
+/// ending in `.`. Add a section here only once it registers with
+/// `ConfigWatcher`, or its users read it from the reloaded config each
+/// time; anything else would be advertised as applied while the broker
+/// keeps running the old value.
+pub const HOT_RELOADABLE: &[&str] = &[
+    "content_types.",
+    "limits.max_message_size",
+    "routing.fanout_parallelism",
+    "slo.",
+    "maintenance.stay_ready",
+    "kind_budgets.",
+    "tls_monitor.",
+    "abuse.",
+    "recipient_trace.",
+    "classification.",
+    "compression.",
+    "preview.",
+    "conversation_limit.",
+];
+
+pub fn is_hot_reloadable(path: &str) -> bool {
+    HOT_RELOADABLE.iter().any(|allowed| match allowed.strip_suffix('.') {
+        Some(section) => path.strip_prefix(section).is_some_and(|rest| rest.starts_with('.')),
+        None => path == *allowed,
+    })
+}
+
+/// Operator request to change one config value on a share of the fleet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigOverride {
+    /// Dotted config path, e.g. `kind_budgets.utilization_threshold`
+    pub path: String,
+    pub value: Value,
+    /// Share of brokers that apply it, 0 to 100
+    pub rollout_percentage: f64,
+    /// Reverted after this long; capped at `config_overrides.max_ttl`
+    pub ttl_seconds: u64,
+}
+
+/// Override in effect on this broker, as advertised in heartbeats
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppliedOverride {
+    pub path: String,
+    pub value: Value,
+    /// Timestamp in milliseconds
+    pub expires_at: i64,
+    pub issued_by: String,
+}
+
+/// What a broker did with an offered override
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrideOutcome {
+    Applied,
+    /// Outside the rollout percentage; any earlier override of the path was reverted
+    NotSelected,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OverrideError {
+    #[error("{0} is not hot-reloadable and can't be overridden")]
+    NotAllowed(String),
+    #[error("rollout percentage {0} is outside 0 to 100")]
+    BadPercentage(f64),
+    #[error("override of {path} fails validation: {reason}")]
+    Invalid { path: String, reason: String },
+}
+
+/// Whether `broker_id` falls inside `percentage` for `path`
+///
+/// Each broker's bucket for a path is fixed, so raising the percentage only
+/// adds brokers and lowering it only removes them. Including the path
+/// spreads different overrides over different subsets.
+pub fn selected(broker_id: &str, path: &str, percentage: f64) -> bool {
+    let bucket = shard_for(&format!("{}/{}", path, broker_id), ROLLOUT_BUCKETS);
+    (bucket as f64) < percentage * (ROLLOUT_BUCKETS as f64 / 100.0)
+}
+
+/// Managed runtime config overrides with gradual rollout and expiry
+///
+/// A `ConfigOverride` control message reaches every broker; each one
+/// decides from a hash of its broker ID whether it falls inside the rollout
+/// percentage. A selected broker validates the config with the override
+/// layered above every source, clamping as `BrokerConfig::load` does, and
+/// then has the `ConfigWatcher` reload immediately so the new value flows
+/// through the usual reload listeners. Only `HOT_RELOADABLE` paths are
+/// accepted. Applied overrides are advertised in the cluster heartbeat,
+/// once a cluster view is attached, and on `/admin/config/fingerprint`,
+/// and revert on their own at TTL.
+///
+/// Other subsystems can also register managed layers under an owner key
+/// with `set_managed`, such as a provisioned tenant's settings. Managed
+/// layers apply on every broker, never expire, and sit below the operator
+/// overrides; their owner is responsible for converging them across the
+/// fleet.
+pub struct RuntimeOverrides {
+    broker_id: String,
+    config: ArcSwap<ConfigOverrideConfig>,
+    active: Mutex<BTreeMap<String, AppliedOverride>>,
+    managed: Mutex<BTreeMap<String, Vec<(String, Value)>>>,
+    changed: Arc<Notify>,
+    cluster: Option<Arc<ClusterView>>,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl RuntimeOverrides {
+    pub fn new(broker_id: String, config: ConfigOverrideConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        Self {
+            broker_id,
+            config: ArcSwap::from_pointee(config),
+            active: Mutex::new(BTreeMap::new()),
+            managed: Mutex::new(BTreeMap::new()),
+            changed: Arc::new(Notify::new()),
+            cluster: None,
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    /// Advertise the applied set in `cluster`'s heartbeats
+    pub fn with_cluster(mut self, cluster: Arc<ClusterView>) -> Self {
+        cluster.set_config_overrides(self.applied());
+        self.cluster = Some(cluster);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Apply or decline an override, replacing any earlier one for the path
+    pub fn offer(&self, request: ConfigOverride, actor: &str) -> Result<OverrideOutcome, OverrideError> {
+        if !is_hot_reloadable(&request.path) {
+            self.metrics.record_config_override("rejected");
+            return Err(OverrideError::NotAllowed(request.path));
+        }
+        if !(0.0..=100.0).contains(&request.rollout_percentage) {
+            self.metrics.record_config_override("rejected");
+            return Err(OverrideError::BadPercentage(request.rollout_percentage));
+        }
+
+        if !selected(&self.broker_id, &request.path, request.rollout_percentage) {
+            if self.active.lock().remove(&request.path).is_some() {
+                info!("Config override of {} reverted: broker left the rollout", request.path);
+                self.record_revert(&request.path, actor, "rollout_narrowed");
+                self.publish();
+            }
+            self.metrics.record_config_override("not_selected");
+            return Ok(OverrideOutcome::NotSelected);
+        }
+
+        let ttl = Duration::from_secs(request.ttl_seconds).min(self.config.load().max_ttl);
+        let applied = AppliedOverride {
+            path: request.path.clone(),
+            value: request.value,
+            expires_at: self.clock.now_millis() + ttl.as_millis() as i64,
+            issued_by: actor.to_string(),
+        };
+
+        let managed = self.managed.lock().clone();
+        let mut active = self.active.lock();
+        let mut candidate = active.clone();
+        candidate.insert(applied.path.clone(), applied.clone());
+        if let Err(e) = BrokerConfig::load_with_overrides(&layers(&managed, &candidate)) {
+            self.metrics.record_config_override("rejected");
+            return Err(OverrideError::Invalid {
+                path: applied.path,
+                reason: e.to_string(),
+            });
+        }
+        *active = candidate;
+        drop(active);
+
+        info!(
+            "Config override {} = {} applied for {}s by {}",
+            applied.path, applied.value, ttl.as_secs(), actor
+        );
+        self.audit.record(AuditEntry::new(
+            actor,
+            "config.override_applied",
+            serde_json::json!({
+                "path": applied.path,
+                "value": applied.value,
+                "rollout_percentage": request.rollout_percentage,
+                "expires_at": applied.expires_at,
+            }),
+        ));
+        self.metrics.record_config_override("applied");
+        self.publish();
+        Ok(OverrideOutcome::Applied)
+    }
+
+    /// Replace the managed layers registered under `owner`
+    ///
+    /// Validated like an override, against everything else in effect.
+    pub fn set_managed(&self, owner: &str, entries: Vec<(String, Value)>) -> Result<(), OverrideError> {
+        if let Some((path, _)) = entries.iter().find(|(path, _)| !is_hot_reloadable(path)) {
+            return Err(OverrideError::NotAllowed(path.clone()));
+        }
+        let mut managed = self.managed.lock();
+        if managed.get(owner) == Some(&entries) {
+            return Ok(());
+        }
+        let mut candidate = managed.clone();
+        candidate.insert(owner.to_string(), entries);
+        let active = self.active.lock().clone();
+        if let Err(e) = BrokerConfig::load_with_overrides(&layers(&candidate, &active)) {
+            return Err(OverrideError::Invalid {
+                path: owner.to_string(),
+                reason: e.to_string(),
+            });
+        }
+        *managed = candidate;
+        drop(managed);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Drop the managed layers registered under `owner`
+    pub fn clear_managed(&self, owner: &str) -> bool {
+        let removed = self.managed.lock().remove(owner).is_some();
+        if removed {
+            self.changed.notify_one();
+        }
+        removed
+    }
+
+    /// Revert an override before its TTL
+    pub fn clear(&self, path: &str, actor: &str) -> bool {
+        let removed = self.active.lock().remove(path).is_some();
+        if removed {
+            info!("Config override of {} cleared by {}", path, actor);
+            self.record_revert(path, actor, "cleared");
+            self.publish();
+        }
+        removed
+    }
+
+    /// Revert overrides whose TTL has passed
+    pub fn expire(&self) -> usize {
+        let now = self.clock.now_millis();
+        let expired: Vec<String> = {
+            let mut active = self.active.lock();
+            let expired = active
+                .values()
+                .filter(|applied| applied.expires_at <= now)
+                .map(|applied| applied.path.clone())
+                .collect::<Vec<_>>();
+            for path in &expired {
+                active.remove(path);
+            }
+            expired
+        };
+        for path in &expired {
+            info!("Config override of {} expired", path);
+            self.record_revert(path, "config_override", "expired");
+        }
+        if !expired.is_empty() {
+            self.publish();
+        }
+        expired.len()
+    }
+
+    fn record_revert(&self, path: &str, actor: &str, reason: &str) {
+        self.audit.record(AuditEntry::new(
+            actor,
+            "config.override_reverted",
+            serde_json::json!({ "path": path, "reason": reason }),
+        ));
+        self.metrics.record_config_override("reverted");
+    }
+
+    /// Advertise the set and wake the config watcher
+    fn publish(&self) {
+        let applied = self.applied();
+        self.metrics.update_config_overrides_active(applied.len());
+        if let Some(cluster) = &self.cluster {
+            cluster.set_config_overrides(applied);
+        }
+        self.changed.notify_one();
+    }
+
+    pub fn applied(&self) -> Vec<AppliedOverride> {
+        self.active.lock().values().cloned().collect()
+    }
+
+    /// Path and value pairs to layer above the config sources
+    pub fn layers(&self) -> Vec<(String, Value)> {
+        let managed = self.managed.lock().clone();
+        layers(&managed, &self.active.lock())
+    }
+
+    /// Signalled whenever the applied set changes
+    pub fn changed(&self) -> Arc<Notify> {
+        Arc::clone(&self.changed)
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let overrides = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            overrides.config.store(Arc::new(config.config_overrides.clone()));
+        });
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let overrides = Arc::clone(self);
+        spawn_traced("config_override_expiry", TaskContext::new("config"), async move {
+            loop {
+                overrides.clock.sleep(overrides.config.load().expiry_check_interval).await;
+                overrides.expire();
+            }
+        })
+    }
+}
+
+/// Managed layers first, so operator overrides win
+fn layers(
+    managed: &BTreeMap<String, Vec<(String, Value)>>,
+    active: &BTreeMap<String, AppliedOverride>,
+) -> Vec<(String, Value)> {
+    managed
+        .values()
+        .flatten()
+        .cloned()
+        .chain(active.values().map(|applied| (applied.path.clone(), applied.value.clone())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use async_nats::jetstream::{self, kv};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        cluster::PeerInfo,
+    };
+
+    const PATH: &str = "routing.fanout_parallelism";
+
+    fn overrides(broker_id: &str) -> (RuntimeOverrides, Arc<SimClock>) {
+        let config = BrokerConfig::load().unwrap().config_overrides;
+        let clock = Arc::new(SimClock::new());
+        let overrides = RuntimeOverrides::new(broker_id.into(), config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        (overrides, clock)
+    }
+
+    fn request(path: &str, value: Value, rollout_percentage: f64, ttl_seconds: u64) -> ConfigOverride {
+        ConfigOverride {
+            path: path.into(),
+            value,
+            rollout_percentage,
+            ttl_seconds,
+        }
+    }
+
+    fn brokers() -> Vec<String> {
+        (0..500).map(|i| format!("broker-{}", i)).collect()
+    }
+
+    fn selected_at(path: &str, percentage: f64) -> HashSet<String> {
+        brokers()
+            .into_iter()
+            .filter(|broker| selected(broker, path, percentage))
+            .collect()
+    }
+
+    #[test]
+    fn rollout_buckets_are_fixed_per_broker_and_path() {
+        assert_eq!(selected_at(PATH, 30.0), selected_at(PATH, 30.0));
+        assert!(selected_at(PATH, 0.0).is_empty());
+        assert_eq!(selected_at(PATH, 100.0).len(), 500);
+
+        let mut previous = HashSet::new();
+        for percentage in [5.0, 25.0, 50.0, 75.0, 99.0] {
+            let current = selected_at(PATH, percentage);
+            assert!(current.is_superset(&previous), "raising to {}% dropped a broker", percentage);
+            let share = current.len() as f64 / 5.0;
+            assert!((share - percentage).abs() < 10.0, "{}% selected {}%", percentage, share);
+            previous = current;
+        }
+        assert_ne!(selected_at(PATH, 50.0), selected_at("slo.latency_target_ms", 50.0));
+    }
+
+    #[test]
+    fn only_hot_reloadable_paths_are_accepted() {
+        for path in [PATH, "slo.window", "kind_budgets.typing.share", "limits.max_message_size"] {
+            assert!(is_hot_reloadable(path), "{}", path);
+        }
+        for path in ["slo", "slow.window", "routing.cache_size", "nats.servers", "limits.max_message_size_extra"] {
+            assert!(!is_hot_reloadable(path), "{}", path);
+        }
+
+        let (overrides, _) = overrides("broker-1");
+        let refused = overrides.offer(request("nats.servers", json!(["nats://elsewhere:4222"]), 100.0, 60), "ops");
+        assert!(matches!(refused, Err(OverrideError::NotAllowed(path)) if path == "nats.servers"));
+        let refused = overrides.offer(request(PATH, json!(32), 120.0, 60), "ops");
+        assert!(matches!(refused, Err(OverrideError::BadPercentage(_))));
+        let refused = overrides.offer(request(PATH, json!("lots"), 100.0, 60), "ops");
+        assert!(matches!(refused, Err(OverrideError::Invalid { .. })));
+        assert!(overrides.applied().is_empty());
+
+        assert_eq!(overrides.offer(request(PATH, json!(32), 100.0, 60), "ops").unwrap(), OverrideOutcome::Applied);
+        assert_eq!(BrokerConfig::load_with_overrides(&overrides.layers()).unwrap().routing.fanout_parallelism, 32);
+    }
+
+    #[test]
+    fn overrides_revert_at_their_ttl() {
+        let (overrides, clock) = overrides("broker-1");
+        overrides.offer(request(PATH, json!(32), 100.0, 60), "ops").unwrap();
+        let applied = overrides.applied();
+        assert_eq!(applied[0].expires_at, clock.now_millis() + 60_000);
+        assert_eq!(applied[0].issued_by, "ops");
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(overrides.expire(), 0);
+        assert_eq!(overrides.layers(), [(PATH.to_string(), json!(32))]);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(overrides.expire(), 1);
+        assert!(overrides.applied().is_empty());
+        assert!(overrides.layers().is_empty());
+
+        // Asking for longer than `max_ttl` gets `max_ttl`
+        let max_ttl = BrokerConfig::load().unwrap().config_overrides.max_ttl;
+        overrides.offer(request(PATH, json!(32), 100.0, u64::MAX / 1000), "ops").unwrap();
+        assert_eq!(overrides.applied()[0].expires_at, clock.now_millis() + max_ttl.as_millis() as i64);
+    }
+
+    #[test]
+    fn brokers_leaving_a_narrowed_rollout_revert() {
+        let broker = brokers()
+            .into_iter()
+            .find(|broker| selected(broker, PATH, 50.0) && !selected(broker, PATH, 10.0))
+            .unwrap();
+        let (overrides, _) = overrides(&broker);
+        assert_eq!(overrides.offer(request(PATH, json!(32), 50.0, 60), "ops").unwrap(), OverrideOutcome::Applied);
+        assert_eq!(overrides.offer(request(PATH, json!(32), 10.0, 60), "ops").unwrap(), OverrideOutcome::NotSelected);
+        assert!(overrides.applied().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn heartbeats_advertise_the_applied_set() {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let members = jetstream::new(async_nats::connect(url).await.unwrap())
+            .create_key_value(kv::Config {
+                bucket: format!("override-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let config = BrokerConfig::load().unwrap();
+        let cluster = Arc::new(ClusterView::new(members.clone(), "broker-1".into(), &config.cluster));
+        let (overrides, _) = overrides("broker-1");
+        let overrides = overrides.with_cluster(Arc::clone(&cluster));
+
+        let advertised = || async {
+            cluster.heartbeat().await.unwrap();
+            let entry = members.get("cluster.broker-1").await.unwrap().unwrap();
+            serde_json::from_slice::<PeerInfo>(&entry).unwrap().config_overrides
+        };
+        assert!(advertised().await.is_empty());
+
+        overrides.offer(request(PATH, json!(32), 100.0, 60), "ops").unwrap();
+        assert_eq!(advertised().await, overrides.applied());
+        assert_eq!(advertised().await[0].value, json!(32));
+
+        overrides.clear(PATH, "ops");
+        assert!(advertised().await.is_empty());
+    }
+}
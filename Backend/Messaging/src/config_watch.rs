@@ -0,0 +1,87 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::{
+    config::BrokerConfig,
+    config_override::RuntimeOverrides,
+    task::{spawn_traced, TaskContext},
+};
+
+type ReloadListener = Box<dyn Fn(&BrokerConfig) + Send + Sync>;
+
+/// Re-reads the config sources periodically and notifies listeners
+///
+/// Only sections that are safe to swap at runtime should register; anything
+/// else still requires a restart. Runtime overrides are layered above the
+/// sources on every reload, and a change to them reloads right away.
+pub struct ConfigWatcher {
+    interval: Duration,
+    listeners: Vec<ReloadListener>,
+    overrides: Option<Arc<RuntimeOverrides>>,
+}
+
+impl ConfigWatcher {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            listeners: Vec::new(),
+            overrides: None,
+        }
+    }
+
+    pub fn with_overrides(mut self, overrides: Arc<RuntimeOverrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+
+    pub fn on_reload(&mut self, listener: impl Fn(&BrokerConfig) + Send + Sync + 'static) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    pub fn spawn(self, initial: BrokerConfig) -> tokio::task::JoinHandle<()> {
+        spawn_traced("config_watch", TaskContext::new("config"), async move {
+            let mut last = comparable(&initial);
+            let mut ticker = tokio::time::interval(self.interval);
+            ticker.tick().await;
+            let changed = self
+                .overrides
+                .as_ref()
+                .map_or_else(|| Arc::new(Notify::new()), |overrides| overrides.changed());
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = changed.notified() => {}
+                }
+
+                let layers = self.overrides.as_ref().map(|o| o.layers()).unwrap_or_default();
+                let config = match BrokerConfig::load_with_overrides(&layers) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        warn!("Config reload failed, keeping previous config: {}", e);
+                        continue;
+                    }
+                };
+
+                let current = comparable(&config);
+                if current == last {
+                    continue;
+                }
+
+                debug!("Config changed, notifying {} listeners", self.listeners.len());
+                for listener in &self.listeners {
+                    listener(&config);
+                }
+                last = current;
+            }
+        })
+    }
+}
+
+/// Config as JSON without fields that differ on every load
+fn comparable(config: &BrokerConfig) -> Option<serde_json::Value> {
+    let mut value = serde_json::to_value(config).ok()?;
+    value.as_object_mut()?.remove("broker_id");
+    Some(value)
+}
@@ -0,0 +1,566 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use arc_swap::ArcSwap;
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, DeliverPolicy},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    control::{ControlCommand, ControlMessage},
+    metrics::BrokerMetrics,
+};
+
+/// Handover phases broadcast to every broker on the control topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandoverPhase {
+    /// Stop pulling from the old consumer
+    Begin,
+    /// Switch to the new consumer and resume
+    Commit,
+    /// Resume the old consumer
+    Abort,
+}
+
+/// Steps reported while a handover runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandoverStep {
+    Idle,
+    Begun,
+    Draining,
+    CreatedNew { start_sequence: u64 },
+    DeletedOld,
+    Committed,
+    Aborted,
+}
+
+impl HandoverStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HandoverStep::Idle => "idle",
+            HandoverStep::Begun => "begun",
+            HandoverStep::Draining => "draining",
+            HandoverStep::CreatedNew { .. } => "created_new",
+            HandoverStep::DeletedOld => "deleted_old",
+            HandoverStep::Committed => "committed",
+            HandoverStep::Aborted => "aborted",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ActiveConsumer {
+    pub name: String,
+    /// The ingest loop must not pull while paused
+    pub paused: bool,
+}
+
+/// Consumer the ingest loop pulls from, switched by handover phases
+pub struct ConsumerSwitch {
+    active: ArcSwap<ActiveConsumer>,
+}
+
+impl ConsumerSwitch {
+    pub fn new(consumer_name: String) -> Self {
+        Self {
+            active: ArcSwap::from_pointee(ActiveConsumer {
+                name: consumer_name,
+                paused: false,
+            }),
+        }
+    }
+
+    pub fn current(&self) -> Arc<ActiveConsumer> {
+        self.active.load_full()
+    }
+
+    pub fn apply(&self, phase: HandoverPhase, old_consumer: &str, new_consumer: &str) {
+        let next = match phase {
+            HandoverPhase::Begin => ActiveConsumer {
+                name: old_consumer.to_string(),
+                paused: true,
+            },
+            HandoverPhase::Commit => ActiveConsumer {
+                name: new_consumer.to_string(),
+                paused: false,
+            },
+            HandoverPhase::Abort => ActiveConsumer {
+                name: old_consumer.to_string(),
+                paused: false,
+            },
+        };
+        info!("Consumer handover {:?}: now {} (paused: {})", phase, next.name, next.paused);
+        self.active.store(Arc::new(next));
+    }
+}
+
+/// Drives a cooperative consumer rename
+///
+/// 1. broadcast `begin` so every broker stops pulling the old consumer
+/// 2. wait until the old consumer has no unacknowledged deliveries and no
+///    waiting pull requests
+/// 3. create the new consumer at the old consumer's delivered sequence + 1;
+///    pull consumers deliver nothing until pulled, so it stays idle
+/// 4. delete the old consumer (no abort past this point)
+/// 5. broadcast `commit` so brokers switch to the new consumer
+///
+/// Every step is audited; `abort` before step 4 resumes the old consumer.
+pub struct ConsumerHandover {
+    jetstream: jetstream::Context,
+    client: async_nats::Client,
+    stream_name: String,
+    control_topic: String,
+    broker_id: String,
+    drain_timeout: Duration,
+    switch: Arc<ConsumerSwitch>,
+    running: AtomicBool,
+    abort_requested: AtomicBool,
+    progress: watch::Sender<HandoverStep>,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl ConsumerHandover {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        jetstream: jetstream::Context,
+        client: async_nats::Client,
+        stream_name: String,
+        control_topic: String,
+        broker_id: String,
+        drain_timeout: Duration,
+        switch: Arc<ConsumerSwitch>,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let (progress, _) = watch::channel(HandoverStep::Idle);
+        Self {
+            jetstream,
+            client,
+            stream_name,
+            control_topic,
+            broker_id,
+            drain_timeout,
+            switch,
+            running: AtomicBool::new(false),
+            abort_requested: AtomicBool::new(false),
+            progress,
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn broker_id(&self) -> &str {
+        &self.broker_id
+    }
+
+    pub fn switch(&self) -> &Arc<ConsumerSwitch> {
+        &self.switch
+    }
+
+    /// Follow handover progress
+    pub fn progress(&self) -> watch::Receiver<HandoverStep> {
+        self.progress.subscribe()
+    }
+
+    /// Request an abort; honored at the next step boundary before deletion
+    pub fn abort(&self) {
+        self.abort_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// Run the full handover from the active consumer to `new_consumer`
+    pub async fn run(&self, new_consumer: &str, issued_by: &str) -> Result<(), HandoverError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(HandoverError::AlreadyRunning);
+        }
+        self.abort_requested.store(false, Ordering::SeqCst);
+
+        let old_consumer = self.switch.current().name.clone();
+        // Checked before anything can roll back, which deletes the new name
+        if old_consumer == new_consumer {
+            self.running.store(false, Ordering::SeqCst);
+            return Err(HandoverError::Invalid("new consumer name matches the active one".into()));
+        }
+        let result = self.drive(&old_consumer, new_consumer, issued_by).await;
+
+        if let Err(e) = &result {
+            if !matches!(e, HandoverError::PastPointOfNoReturn(_)) {
+                self.rollback(&old_consumer, new_consumer, issued_by, &e.to_string()).await;
+            }
+        }
+        self.running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    async fn drive(&self, old_consumer: &str, new_consumer: &str, issued_by: &str) -> Result<(), HandoverError> {
+        let stream = self.jetstream.get_stream(&self.stream_name).await.map_err(nats_error)?;
+
+        self.broadcast(HandoverPhase::Begin, old_consumer, new_consumer, issued_by).await?;
+        self.switch.apply(HandoverPhase::Begin, old_consumer, new_consumer);
+        self.step(HandoverStep::Begun, old_consumer, new_consumer, issued_by);
+
+        // Wait for in-flight deliveries to be acknowledged and for pull
+        // requests still waiting on the old consumer to expire
+        self.step(HandoverStep::Draining, old_consumer, new_consumer, issued_by);
+        let deadline = tokio::time::Instant::now() + self.drain_timeout;
+        let old_info = loop {
+            self.check_abort()?;
+            let info = stream.consumer_info(old_consumer).await.map_err(nats_error)?;
+            if info.num_ack_pending == 0
+                && info.num_waiting == 0
+                && info.ack_floor.stream_sequence >= info.delivered.stream_sequence
+            {
+                break info;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(HandoverError::DrainTimeout(info.num_ack_pending));
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        };
+
+        self.check_abort()?;
+        let start_sequence = old_info.delivered.stream_sequence + 1;
+        let old_config = old_info.config;
+        stream
+            .create_consumer(pull::Config {
+                durable_name: Some(new_consumer.to_string()),
+                deliver_policy: DeliverPolicy::ByStartSequence { start_sequence },
+                filter_subject: old_config.filter_subject,
+                ack_policy: old_config.ack_policy,
+                ack_wait: old_config.ack_wait,
+                max_deliver: old_config.max_deliver,
+                max_ack_pending: old_config.max_ack_pending,
+                ..Default::default()
+            })
+            .await
+            .map_err(nats_error)?;
+        self.step(HandoverStep::CreatedNew { start_sequence }, old_consumer, new_consumer, issued_by);
+
+        self.check_abort()?;
+        stream.delete_consumer(old_consumer).await.map_err(nats_error)?;
+        self.step(HandoverStep::DeletedOld, old_consumer, new_consumer, issued_by);
+
+        self.broadcast(HandoverPhase::Commit, old_consumer, new_consumer, issued_by)
+            .await
+            .map_err(|e| HandoverError::PastPointOfNoReturn(e.to_string()))?;
+        self.switch.apply(HandoverPhase::Commit, old_consumer, new_consumer);
+        self.step(HandoverStep::Committed, old_consumer, new_consumer, issued_by);
+        Ok(())
+    }
+
+    async fn rollback(&self, old_consumer: &str, new_consumer: &str, issued_by: &str, reason: &str) {
+        warn!("Aborting consumer handover to {}: {}", new_consumer, reason);
+
+        if let Ok(stream) = self.jetstream.get_stream(&self.stream_name).await {
+            // The new consumer may not exist yet
+            let _ = stream.delete_consumer(new_consumer).await;
+        }
+        if let Err(e) = self.broadcast(HandoverPhase::Abort, old_consumer, new_consumer, issued_by).await {
+            warn!("Failed to broadcast handover abort: {}", e);
+        }
+        self.switch.apply(HandoverPhase::Abort, old_consumer, new_consumer);
+        self.step(HandoverStep::Aborted, old_consumer, new_consumer, issued_by);
+    }
+
+    fn check_abort(&self) -> Result<(), HandoverError> {
+        if self.abort_requested.load(Ordering::SeqCst) {
+            return Err(HandoverError::Aborted);
+        }
+        Ok(())
+    }
+
+    fn step(&self, step: HandoverStep, old_consumer: &str, new_consumer: &str, issued_by: &str) {
+        self.progress.send_replace(step);
+        self.metrics.record_consumer_handover_step(step.as_str());
+        self.audit.record(AuditEntry::new(
+            issued_by,
+            format!("consumer_handover.{}", step.as_str()),
+            serde_json::json!({
+                "old_consumer": old_consumer,
+                "new_consumer": new_consumer,
+                "step": step,
+            }),
+        ));
+    }
+
+    async fn broadcast(
+        &self,
+        phase: HandoverPhase,
+        old_consumer: &str,
+        new_consumer: &str,
+        issued_by: &str,
+    ) -> Result<(), HandoverError> {
+        let message = ControlMessage {
+            command_id: uuid::Uuid::new_v4().to_string(),
+            issued_by: issued_by.to_string(),
+            timestamp: Utc::now().timestamp_millis(),
+            scope: None,
+            command: ControlCommand::ConsumerHandover {
+                phase,
+                old_consumer: old_consumer.to_string(),
+                new_consumer: new_consumer.to_string(),
+            },
+        };
+        let payload = serde_json::to_vec(&message).map_err(|e| HandoverError::Nats(e.to_string()))?;
+        self.client
+            .publish(self.control_topic.clone(), payload.into())
+            .await
+            .map_err(nats_error)?;
+        self.client.flush().await.map_err(nats_error)?;
+        Ok(())
+    }
+}
+
+fn nats_error(e: impl std::fmt::Display) -> HandoverError {
+    HandoverError::Nats(e.to_string())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandoverError {
+    #[error("a consumer handover is already running")]
+    AlreadyRunning,
+    #[error("invalid handover: {0}")]
+    Invalid(String),
+    #[error("handover aborted")]
+    Aborted,
+    #[error("old consumer still has {0} unacknowledged deliveries")]
+    DrainTimeout(usize),
+    #[error("NATS error during handover: {0}")]
+    Nats(String),
+    #[error("old consumer deleted but commit failed: {0}")]
+    PastPointOfNoReturn(String),
+}
+
+/// The handover tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored consumer_handover`
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use parking_lot::Mutex;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn phases_switch_the_active_consumer() {
+        let switch = ConsumerSwitch::new("old".into());
+
+        switch.apply(HandoverPhase::Begin, "old", "new");
+        let current = switch.current();
+        assert_eq!((current.name.as_str(), current.paused), ("old", true));
+
+        switch.apply(HandoverPhase::Abort, "old", "new");
+        let current = switch.current();
+        assert_eq!((current.name.as_str(), current.paused), ("old", false));
+
+        switch.apply(HandoverPhase::Begin, "old", "new");
+        switch.apply(HandoverPhase::Commit, "old", "new");
+        let current = switch.current();
+        assert_eq!((current.name.as_str(), current.paused), ("new", false));
+    }
+
+    struct Fixture {
+        jetstream: jetstream::Context,
+        handover: Arc<ConsumerHandover>,
+        stream: String,
+        subject: String,
+        /// Times each payload was processed
+        processed: Arc<Mutex<HashMap<String, usize>>>,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let client = async_nats::connect(url).await.unwrap();
+            let jetstream = jetstream::new(client.clone());
+            let stream = format!("handover_test_{}", Uuid::new_v4().simple());
+            let subject = format!("{}.ingress", stream);
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: stream.clone(),
+                    subjects: vec![subject.clone()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .create_consumer(pull::Config {
+                    durable_name: Some("old".into()),
+                    ack_policy: jetstream::consumer::AckPolicy::Explicit,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let handover = ConsumerHandover::new(
+                jetstream.clone(),
+                client,
+                stream.clone(),
+                format!("{}.control", stream),
+                "broker-1".into(),
+                Duration::from_secs(5),
+                Arc::new(ConsumerSwitch::new("old".into())),
+                AuditLog::tracing_only(),
+                BrokerMetrics::new().unwrap(),
+            );
+
+            Self {
+                jetstream,
+                handover: Arc::new(handover),
+                stream,
+                subject,
+                processed: Arc::new(Mutex::new(HashMap::new())),
+            }
+        }
+
+        /// The ingest loop: pull from whichever consumer the switch names
+        fn spawn_worker(&self) -> tokio::task::JoinHandle<()> {
+            let jetstream = self.jetstream.clone();
+            let switch = Arc::clone(self.handover.switch());
+            let stream_name = self.stream.clone();
+            let processed = Arc::clone(&self.processed);
+            tokio::spawn(async move {
+                let stream = jetstream.get_stream(&stream_name).await.unwrap();
+                loop {
+                    let active = switch.current();
+                    if active.paused {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        continue;
+                    }
+                    let Ok(consumer) = stream.get_consumer::<pull::Config>(&active.name).await else {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        continue;
+                    };
+                    let Ok(mut batch) = consumer
+                        .batch()
+                        .max_messages(10)
+                        .expires(Duration::from_millis(100))
+                        .messages()
+                        .await
+                    else {
+                        continue;
+                    };
+                    // A fetched batch is in flight and finishes even if the switch pauses
+                    while let Some(Ok(message)) = batch.next().await {
+                        let payload = String::from_utf8(message.payload.to_vec()).unwrap();
+                        *processed.lock().entry(payload).or_default() += 1;
+                        message.ack().await.unwrap();
+                    }
+                }
+            })
+        }
+
+        async fn publish(&self, range: std::ops::Range<usize>) {
+            for i in range {
+                self.jetstream
+                    .publish(self.subject.clone(), format!("m{}", i).into())
+                    .await
+                    .unwrap()
+                    .await
+                    .unwrap();
+            }
+        }
+
+        async fn wait_processed(&self, count: usize) {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while self.processed.lock().len() < count {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            })
+            .await
+            .expect("not every message was processed");
+            // Give any duplicate a chance to show up
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+
+        async fn consumer_names(&self) -> Vec<String> {
+            let stream = self.jetstream.get_stream(&self.stream).await.unwrap();
+            let mut names: Vec<String> = stream.consumer_names().map(Result::unwrap).collect().await;
+            names.sort();
+            names
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn every_message_is_processed_exactly_once_across_the_handover() {
+        let fixture = Fixture::new().await;
+        let worker = fixture.spawn_worker();
+
+        let before = fixture.publish(0..200);
+        let handover = fixture.handover.run("new", "oncall");
+        let (_, result) = tokio::join!(before, handover);
+        result.unwrap();
+        fixture.publish(200..300).await;
+
+        fixture.wait_processed(300).await;
+        worker.abort();
+
+        {
+            let processed = fixture.processed.lock();
+            assert_eq!(processed.len(), 300);
+            let duplicates: Vec<_> = processed.iter().filter(|(_, count)| **count > 1).collect();
+            assert!(duplicates.is_empty(), "processed twice: {:?}", duplicates);
+        }
+
+        assert_eq!(*fixture.handover.progress().borrow(), HandoverStep::Committed);
+        assert_eq!(fixture.handover.switch().current().name, "new");
+        assert_eq!(fixture.consumer_names().await, vec!["new".to_string()]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn abort_while_draining_resumes_the_old_consumer() {
+        let fixture = Fixture::new().await;
+        fixture.publish(0..5).await;
+
+        // An unacknowledged delivery holds the drain open
+        let stream = fixture.jetstream.get_stream(&fixture.stream).await.unwrap();
+        let old: jetstream::consumer::Consumer<pull::Config> = stream.get_consumer("old").await.unwrap();
+        let mut fetched = old.fetch().max_messages(1).messages().await.unwrap();
+        let held = fetched.next().await.unwrap().unwrap();
+
+        let handover = Arc::clone(&fixture.handover);
+        let running = tokio::spawn(async move { handover.run("new", "oncall").await });
+        let mut progress = fixture.handover.progress();
+        progress.wait_for(|step| *step == HandoverStep::Draining).await.unwrap();
+        assert!(fixture.handover.switch().current().paused);
+
+        fixture.handover.abort();
+        assert!(matches!(running.await.unwrap(), Err(HandoverError::Aborted)));
+        assert_eq!(*fixture.handover.progress().borrow(), HandoverStep::Aborted);
+        let current = fixture.handover.switch().current();
+        assert_eq!((current.name.as_str(), current.paused), ("old", false));
+        assert_eq!(fixture.consumer_names().await, vec!["old".to_string()]);
+
+        // The old consumer carries on where it left off
+        held.ack().await.unwrap();
+        let worker = fixture.spawn_worker();
+        fixture.wait_processed(4).await;
+        worker.abort();
+        assert!(!fixture.processed.lock().contains_key("m0"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn handing_over_to_the_active_name_is_rejected() {
+        let fixture = Fixture::new().await;
+        let result = fixture.handover.run("old", "oncall").await;
+        assert!(matches!(result, Err(HandoverError::Invalid(_))));
+        assert_eq!(fixture.handover.switch().current().name, "old");
+        assert_eq!(fixture.consumer_names().await, vec!["old".to_string()]);
+    }
+}
@@ -0,0 +1,384 @@
+use std::{collections::HashMap, sync::Arc};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    config::{ContentTypePolicyConfig, ContentTypeRule},
+    config_watch::ConfigWatcher,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Content type assumed for payloads that don't declare one
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Lowercased `type/subtype` with parameters and whitespace removed
+///
+/// `TEXT/Plain; charset=utf-8` becomes `text/plain`.
+pub fn normalize(content_type: &str) -> String {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence
+        .split('/')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("/")
+        .to_ascii_lowercase()
+}
+
+/// Allowed types for one tenant (or the defaults), keyed by specificity
+#[derive(Debug, Default)]
+struct CompiledRules {
+    /// No rules configured: every type is allowed
+    any: bool,
+    exact: HashMap<String, Option<usize>>,
+    /// `image/*` keyed by `image`
+    wildcards: HashMap<String, Option<usize>>,
+    /// `*/*` or `*`
+    catch_all: Option<Option<usize>>,
+}
+
+impl CompiledRules {
+    fn compile(rules: &[ContentTypeRule]) -> Self {
+        let mut compiled = CompiledRules {
+            any: rules.is_empty(),
+            ..Default::default()
+        };
+        for rule in rules {
+            let pattern = normalize(&rule.content_type);
+            match pattern.as_str() {
+                "*" | "*/*" => compiled.catch_all = Some(rule.max_size),
+                _ => match pattern.strip_suffix("/*") {
+                    Some(major) => {
+                        compiled.wildcards.insert(major.to_string(), rule.max_size);
+                    }
+                    None => {
+                        compiled.exact.insert(pattern, rule.max_size);
+                    }
+                },
+            }
+        }
+        compiled
+    }
+
+    /// Size cap of the most specific matching rule; `None` if the type isn't allowed
+    fn lookup(&self, content_type: &str) -> Option<Option<usize>> {
+        if self.any {
+            return Some(None);
+        }
+        if let Some(max_size) = self.exact.get(content_type) {
+            return Some(*max_size);
+        }
+        let major = content_type.split('/').next().unwrap_or_default();
+        if let Some(max_size) = self.wildcards.get(major) {
+            return Some(*max_size);
+        }
+        self.catch_all
+    }
+}
+
+struct CompiledPolicy {
+    default: CompiledRules,
+    tenants: HashMap<String, CompiledRules>,
+    max_message_size: usize,
+}
+
+/// Per-tenant allow list of payload content types with per-type size caps
+///
+/// A tenant with its own rule list uses only that list; everyone else uses
+/// `content_types.default`. The most specific rule wins (exact type, then
+/// `type/*`, then `*/*`). A rule's `max_size` can only lower
+/// `limits.max_message_size`, never raise it.
+pub struct ContentTypePolicy {
+    policy: ArcSwap<CompiledPolicy>,
+    metrics: BrokerMetrics,
+}
+
+impl ContentTypePolicy {
+    pub fn new(config: &ContentTypePolicyConfig, max_message_size: usize, metrics: BrokerMetrics) -> Self {
+        Self {
+            policy: ArcSwap::from_pointee(compile(config, max_message_size)),
+            metrics,
+        }
+    }
+
+    pub fn reload(&self, config: &ContentTypePolicyConfig, max_message_size: usize) {
+        self.policy.store(Arc::new(compile(config, max_message_size)));
+        info!(
+            "Content type policy reloaded: {} default rules, {} tenant overrides",
+            config.default.len(),
+            config.tenants.len()
+        );
+    }
+
+    /// Swap the rules whenever the config watcher sees a change
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let policy = Arc::clone(self);
+        watcher.on_reload(move |config| policy.reload(&config.content_types, config.limits.max_message_size));
+    }
+
+    pub fn check(&self, envelope: &MessageEnvelope) -> Result<(), ContentTypeRejection> {
+        let policy = self.policy.load();
+        let content_type = envelope
+            .payload
+            .content_type
+            .as_deref()
+            .map(normalize)
+            .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+
+        let rules = envelope
+            .tenant_id
+            .as_deref()
+            .and_then(|tenant| policy.tenants.get(tenant))
+            .unwrap_or(&policy.default);
+
+        let Some(max_size) = rules.lookup(&content_type) else {
+            self.metrics
+                .record_content_type_rejection(&content_type, ContentTypeRejection::UNSUPPORTED);
+            return Err(ContentTypeRejection::Unsupported { content_type });
+        };
+
+        // The global limit was already enforced by validation
+        let Some(max_size) = max_size.filter(|max| *max < policy.max_message_size) else {
+            return Ok(());
+        };
+        let size = serde_json::to_vec(envelope).map(|v| v.len()).unwrap_or(usize::MAX);
+        if size > max_size {
+            self.metrics
+                .record_content_type_rejection(&content_type, ContentTypeRejection::SIZE_EXCEEDED);
+            return Err(ContentTypeRejection::SizeExceeded {
+                content_type,
+                size,
+                max_size,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn compile(config: &ContentTypePolicyConfig, max_message_size: usize) -> CompiledPolicy {
+    CompiledPolicy {
+        default: CompiledRules::compile(&config.default),
+        tenants: config
+            .tenants
+            .iter()
+            .map(|(tenant, rules)| (tenant.clone(), CompiledRules::compile(rules)))
+            .collect(),
+        max_message_size,
+    }
+}
+
+/// Sender-visible content type rejection
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+pub enum ContentTypeRejection {
+    #[error("content type {content_type} is not allowed")]
+    Unsupported { content_type: String },
+    #[error("{size} bytes exceeds the {max_size} byte limit for {content_type}")]
+    SizeExceeded {
+        content_type: String,
+        size: usize,
+        max_size: usize,
+    },
+}
+
+impl ContentTypeRejection {
+    pub const UNSUPPORTED: &'static str = "UNSUPPORTED_CONTENT_TYPE";
+    pub const SIZE_EXCEEDED: &'static str = "SIZE_EXCEEDED_FOR_TYPE";
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContentTypeRejection::Unsupported { .. } => Self::UNSUPPORTED,
+            ContentTypeRejection::SizeExceeded { .. } => Self::SIZE_EXCEEDED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::types::{EncryptedPayload, MessageType};
+
+    const KB: usize = 1024;
+    const MAX_MESSAGE_SIZE: usize = 256 * KB;
+
+    fn rule(content_type: &str, max_size: Option<usize>) -> ContentTypeRule {
+        ContentTypeRule {
+            content_type: content_type.into(),
+            max_size,
+        }
+    }
+
+    /// Defaults allow text and images; tenant A only text and JSON up to 32KB,
+    /// tenant B also protobuf up to 64KB
+    fn config() -> ContentTypePolicyConfig {
+        ContentTypePolicyConfig {
+            default: vec![rule("text/plain", None), rule("image/*", Some(128 * KB)), rule("image/gif", Some(16 * KB))],
+            tenants: HashMap::from([
+                (
+                    "tenant-a".to_string(),
+                    vec![rule("text/plain", Some(32 * KB)), rule("application/json", Some(32 * KB))],
+                ),
+                (
+                    "tenant-b".to_string(),
+                    vec![
+                        rule("TEXT/PLAIN", Some(64 * KB)),
+                        rule("application/json", Some(64 * KB)),
+                        rule("application/x-protobuf", Some(64 * KB)),
+                    ],
+                ),
+            ]),
+        }
+    }
+
+    fn policy() -> ContentTypePolicy {
+        ContentTypePolicy::new(&config(), MAX_MESSAGE_SIZE, BrokerMetrics::new().unwrap())
+    }
+
+    fn envelope(tenant: Option<&str>, content_type: Option<&str>, size: usize) -> MessageEnvelope {
+        let mut envelope = MessageEnvelope::new(
+            MessageType::TextMessage,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: "a".repeat(size),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: content_type.map(str::to_string),
+            },
+        );
+        envelope.tenant_id = tenant.map(str::to_string);
+        envelope
+    }
+
+    fn code(policy: &ContentTypePolicy, envelope: &MessageEnvelope) -> Option<&'static str> {
+        policy.check(envelope).err().map(|rejection| rejection.code())
+    }
+
+    #[test]
+    fn normalization_drops_parameters_case_and_whitespace() {
+        assert_eq!(normalize("TEXT/Plain; charset=utf-8"), "text/plain");
+        assert_eq!(normalize("text/plain;"), "text/plain");
+        assert_eq!(normalize(" Application / JSON ;charset=\"UTF-8\"; q=1"), "application/json");
+        assert_eq!(normalize("image/*"), "image/*");
+        assert_eq!(normalize("text"), "text");
+        assert_eq!(normalize(""), "");
+    }
+
+    #[test]
+    fn declared_types_match_after_normalization() {
+        let policy = policy();
+        assert!(policy.check(&envelope(None, Some("TEXT/Plain; charset=utf-8"), 10)).is_ok());
+        assert!(policy.check(&envelope(Some("tenant-b"), Some("text/plain"), 10)).is_ok());
+    }
+
+    #[test]
+    fn wildcards_match_their_major_type_only() {
+        let policy = policy();
+        assert!(policy.check(&envelope(None, Some("image/png"), 10)).is_ok());
+        assert!(policy.check(&envelope(None, Some("IMAGE/webp"), 10)).is_ok());
+        assert_eq!(code(&policy, &envelope(None, Some("imagex/png"), 10)), Some(ContentTypeRejection::UNSUPPORTED));
+        assert_eq!(code(&policy, &envelope(None, Some("video/mp4"), 10)), Some(ContentTypeRejection::UNSUPPORTED));
+    }
+
+    #[test]
+    fn the_most_specific_rule_sets_the_cap() {
+        let policy = policy();
+        // image/gif has its own 16KB cap below the 128KB image/* one
+        assert!(policy.check(&envelope(None, Some("image/png"), 20 * KB)).is_ok());
+        assert_eq!(
+            code(&policy, &envelope(None, Some("image/gif"), 20 * KB)),
+            Some(ContentTypeRejection::SIZE_EXCEEDED)
+        );
+    }
+
+    #[test]
+    fn catch_all_admits_everything_else() {
+        let mut config = config();
+        config.default.push(rule("*/*", Some(8 * KB)));
+        let policy = ContentTypePolicy::new(&config, MAX_MESSAGE_SIZE, BrokerMetrics::new().unwrap());
+
+        assert!(policy.check(&envelope(None, Some("video/mp4"), 10)).is_ok());
+        assert_eq!(
+            code(&policy, &envelope(None, Some("video/mp4"), 10 * KB)),
+            Some(ContentTypeRejection::SIZE_EXCEEDED)
+        );
+        // More specific rules still win over the catch-all
+        assert!(policy.check(&envelope(None, Some("image/png"), 10 * KB)).is_ok());
+    }
+
+    #[test]
+    fn tenant_rules_replace_the_defaults() {
+        let policy = policy();
+        assert!(policy.check(&envelope(Some("tenant-a"), Some("application/json"), 10)).is_ok());
+        assert_eq!(
+            code(&policy, &envelope(Some("tenant-a"), Some("image/png"), 10)),
+            Some(ContentTypeRejection::UNSUPPORTED)
+        );
+        assert_eq!(
+            code(&policy, &envelope(Some("tenant-a"), Some("application/x-protobuf"), 10)),
+            Some(ContentTypeRejection::UNSUPPORTED)
+        );
+        assert!(policy.check(&envelope(Some("tenant-b"), Some("application/x-protobuf"), 10)).is_ok());
+
+        // Tenants without their own list get the defaults
+        assert!(policy.check(&envelope(Some("tenant-c"), Some("image/png"), 10)).is_ok());
+        assert_eq!(
+            code(&policy, &envelope(Some("tenant-c"), Some("application/json"), 10)),
+            Some(ContentTypeRejection::UNSUPPORTED)
+        );
+    }
+
+    #[test]
+    fn tenant_caps_apply_per_tenant() {
+        let policy = policy();
+        let rejection = policy.check(&envelope(Some("tenant-a"), Some("text/plain"), 40 * KB)).unwrap_err();
+        let ContentTypeRejection::SizeExceeded { content_type, max_size, .. } = &rejection else {
+            panic!("expected a size rejection, got {:?}", rejection);
+        };
+        assert_eq!(content_type, "text/plain");
+        assert_eq!(*max_size, 32 * KB);
+        assert_eq!(rejection.code(), "SIZE_EXCEEDED_FOR_TYPE");
+
+        assert!(policy.check(&envelope(Some("tenant-b"), Some("text/plain"), 40 * KB)).is_ok());
+    }
+
+    #[test]
+    fn caps_only_ever_lower_the_global_limit() {
+        let mut config = config();
+        config.default = vec![rule("text/plain", Some(1024 * KB))];
+        let policy = ContentTypePolicy::new(&config, 16 * KB, BrokerMetrics::new().unwrap());
+
+        // Above the global limit, which validation already enforces, the rule's cap is ignored
+        assert!(policy.check(&envelope(None, Some("text/plain"), 8 * KB)).is_ok());
+        assert!(policy.check(&envelope(None, Some("text/plain"), 512 * KB)).is_ok());
+    }
+
+    #[test]
+    fn undeclared_payloads_are_octet_streams() {
+        let policy = policy();
+        let rejection = policy.check(&envelope(None, None, 10)).unwrap_err();
+        assert_eq!(rejection.code(), "UNSUPPORTED_CONTENT_TYPE");
+        assert!(rejection.to_string().contains(DEFAULT_CONTENT_TYPE));
+    }
+
+    #[test]
+    fn no_rules_allows_every_type() {
+        let config = ContentTypePolicyConfig::default();
+        let policy = ContentTypePolicy::new(&config, MAX_MESSAGE_SIZE, BrokerMetrics::new().unwrap());
+        assert!(policy.check(&envelope(None, Some("video/mp4"), 10)).is_ok());
+        assert!(policy.check(&envelope(Some("tenant-a"), None, 10)).is_ok());
+    }
+
+    #[test]
+    fn reload_swaps_the_rules() {
+        let policy = policy();
+        assert!(policy.check(&envelope(Some("tenant-a"), Some("application/x-protobuf"), 10)).is_err());
+
+        let mut config = config();
+        config.tenants.get_mut("tenant-a").unwrap().push(rule("application/x-protobuf", None));
+        policy.reload(&config, MAX_MESSAGE_SIZE);
+        assert!(policy.check(&envelope(Some("tenant-a"), Some("application/x-protobuf"), 10)).is_ok());
+    }
+}
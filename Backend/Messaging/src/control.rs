@@ -0,0 +1,725 @@
+use std::{sync::Arc, time::Duration};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    abuse::{AbuseResponse, AbuseScores, AbuseSignal},
+    archive::ArchivedConversations,
+    attestation::SenderAttestor,
+    config_override::{ConfigOverride, RuntimeOverrides},
+    consumer_handover::{ConsumerHandover, HandoverPhase},
+    conversation_bootstrap::ConversationBootstrap,
+    conversation_home::ConversationHomes,
+    control_lease::{CommandLeases, LeaseOutcome},
+    degradation::{DegradationLevel, DegradationSwitchboard},
+    egress_cipher::EgressCipher,
+    egress_redaction::EgressRedactor,
+    ingestion_pause::{IngestionPauses, PauseSelector},
+    invitation::InvitationGate,
+    kv_compaction::KvCompactor,
+    maintenance::MaintenanceMode,
+    membership::MemberState,
+    migration::StreamMigration,
+    path_override::{PathOverrideRule, PathOverrides},
+    recipient_trace::{RecipientTracer, RoutingWatch},
+    route_cache::RouteCache,
+    session_migration::SessionMigrator,
+    shed_exemption::{ExemptionSelector, ShedExemptions},
+    standby::{ActivationTrigger, StandbyController},
+    task::{spawn_traced, TaskContext},
+    tenant::TenantRegistry,
+    thread::ThreadParticipantCache,
+};
+
+/// Control-plane message received on `nats.control_topic`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlMessage {
+    /// Unique command ID
+    pub command_id: String,
+
+    /// Operator or service that issued the command
+    pub issued_by: String,
+
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+
+    /// Which brokers execute the command; defaults per command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<CommandScope>,
+
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+/// Which brokers act on a control command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CommandScope {
+    /// Every broker applies it to its own state
+    EveryBroker,
+    /// Exactly one broker executes it, chosen by lease
+    SingleExecutor,
+    /// Only the cluster owner of `key` (a conversation or user ID) executes it
+    OwnerOf { key: String },
+}
+
+/// Control commands understood by the broker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Switch the degradation level (setting the active level renews its TTL)
+    SetDegradationLevel {
+        level: DegradationLevel,
+        reason: String,
+        ttl_seconds: Option<u64>,
+    },
+
+    /// Register or rotate a gateway's sender attestation key
+    RegisterGatewayKey {
+        gateway_id: String,
+        /// Base64 encoded ed25519 public key
+        public_key: String,
+        /// Egress redaction tier; unset uses `egress_redaction.gateway_tiers` or the default
+        #[serde(default)]
+        trust_tier: Option<String>,
+        /// Base64 signature by an `attestation.registration_keys` key over `attestation::registration_payload`
+        #[serde(default)]
+        signature: String,
+    },
+
+    /// Forget a decommissioned gateway's attestation key
+    RemoveGatewayKey {
+        gateway_id: String,
+        /// As for `RegisterGatewayKey`, with an empty public key
+        #[serde(default)]
+        signature: String,
+    },
+
+    /// Copy remaining old-stream messages into the renamed streams
+    MigrateStreams,
+
+    /// Hand the ingest consumer over to a new name; only `driver` runs it
+    HandoverConsumer {
+        new_consumer: String,
+        /// Broker ID that drives the handover
+        driver: String,
+    },
+
+    /// Abort a running consumer handover before the old consumer is deleted
+    AbortConsumerHandover,
+
+    /// Handover phase broadcast by the driving broker
+    ConsumerHandover {
+        phase: HandoverPhase,
+        old_consumer: String,
+        new_consumer: String,
+    },
+
+    /// Hold back ingress matching `selector` until resumed or `ttl_seconds` pass
+    PauseIngestion {
+        selector: PauseSelector,
+        ttl_seconds: Option<u64>,
+    },
+
+    ResumeIngestion {
+        selector: PauseSelector,
+    },
+
+    /// Re-publish a resumed selector's parked messages to ingress in order
+    ReinjectParked {
+        selector: PauseSelector,
+    },
+
+    /// Move a user's sessions to another gateway without losing messages
+    MigrateUser {
+        user_id: String,
+        from_gateway: String,
+        to_gateway: String,
+    },
+
+    /// Bring a warm standby broker into full operation; all standbys if `broker_id` is unset
+    Activate {
+        broker_id: Option<String>,
+    },
+
+    /// User lifecycle: an account was created
+    UserCreated {
+        user_id: String,
+    },
+
+    /// User lifecycle: an account was deleted
+    UserDeleted {
+        user_id: String,
+    },
+
+    /// Thread participation changed; every thread of the conversation if `thread_id` is unset
+    InvalidateThread {
+        conversation_id: String,
+        thread_id: Option<String>,
+    },
+
+    /// A user was invited to, joined, left or was banned from a group
+    MemberStateChanged {
+        group_id: String,
+        user_id: String,
+        state: MemberState,
+    },
+
+    /// The owner archived or closed a conversation; further sends are rejected
+    ArchiveConversation {
+        conversation_id: String,
+    },
+
+    UnarchiveConversation {
+        conversation_id: String,
+    },
+
+    /// Serve reads only: pause ingress and queued work, reject mutations with `MAINTENANCE`
+    EnterMaintenance {
+        message: Option<String>,
+        /// Expected end, timestamp in milliseconds
+        expected_end: Option<i64>,
+    },
+
+    LeaveMaintenance,
+
+    /// Trust & safety flagged a user's traffic as spam; feeds their abuse score
+    FlagSpam {
+        user_id: String,
+    },
+
+    /// Hold an abuse response for a user regardless of their score; `none` exempts them
+    OverrideAbuseResponse {
+        user_id: String,
+        response: AbuseResponse,
+    },
+
+    /// Return a user to automated abuse responses
+    ClearAbuseOverride {
+        user_id: String,
+        #[serde(default)]
+        reset_score: bool,
+    },
+
+    /// Record per-recipient routing decisions for matching messages until `ttl_seconds` pass
+    WatchRouting {
+        watch: RoutingWatch,
+        ttl_seconds: Option<u64>,
+    },
+
+    UnwatchRouting {
+        watch: RoutingWatch,
+    },
+
+    /// Override a hot-reloadable config value on a percentage of brokers until its TTL
+    ConfigOverride(ConfigOverride),
+
+    /// Revert a runtime override before its TTL
+    ClearConfigOverride {
+        path: String,
+    },
+
+    /// Pin a gateway's egress encryption key; a different key is refused while one is pinned
+    PinGatewayEgressKey {
+        gateway_id: String,
+        /// Base64 encoded X25519 public key
+        public_key: String,
+    },
+
+    /// Release a gateway's pinned egress key, e.g. before it rotates to a new one
+    UnpinGatewayEgressKey {
+        gateway_id: String,
+    },
+
+    /// Fail over a region: conversations homed there move to the region that next sees them
+    MarkRegionDown {
+        region: String,
+    },
+
+    /// Stop failing over a region; conversations already moved stay where they are
+    MarkRegionUp {
+        region: String,
+    },
+
+    /// Move one conversation's home region
+    RehomeConversation {
+        conversation_id: String,
+        region: String,
+    },
+
+    /// Force or forbid a delivery path feature for matching traffic until `ttl_seconds` pass
+    SetPathOverride {
+        rule: PathOverrideRule,
+        ttl_seconds: Option<u64>,
+    },
+
+    ClearPathOverride {
+        id: String,
+    },
+
+    /// Exempt matching traffic from load shedding and degradation drops
+    AddShedExemption {
+        selector: ExemptionSelector,
+        reason: String,
+    },
+
+    RemoveShedExemption {
+        selector: ExemptionSelector,
+    },
+
+    /// Application hint that a conversation is about to see its first message
+    ConversationWillStart {
+        conversation_id: String,
+        #[serde(default)]
+        tenant_id: Option<String>,
+    },
+
+    /// A tenant was provisioned, deleted or restored; re-read its record
+    TenantChanged {
+        tenant_id: String,
+    },
+}
+
+impl ControlCommand {
+    /// Scope used when the message doesn't declare one
+    pub fn default_scope(&self) -> CommandScope {
+        match self {
+            ControlCommand::MigrateStreams
+            | ControlCommand::ReinjectParked { .. }
+            | ControlCommand::MigrateUser { .. } => CommandScope::SingleExecutor,
+            ControlCommand::ConversationWillStart { conversation_id, .. } => CommandScope::OwnerOf {
+                key: conversation_id.clone(),
+            },
+            _ => CommandScope::EveryBroker,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::SetDegradationLevel { .. } => "set_degradation_level",
+            ControlCommand::RegisterGatewayKey { .. } => "register_gateway_key",
+            ControlCommand::RemoveGatewayKey { .. } => "remove_gateway_key",
+            ControlCommand::MigrateStreams => "migrate_streams",
+            ControlCommand::HandoverConsumer { .. } => "handover_consumer",
+            ControlCommand::AbortConsumerHandover => "abort_consumer_handover",
+            ControlCommand::ConsumerHandover { .. } => "consumer_handover",
+            ControlCommand::PauseIngestion { .. } => "pause_ingestion",
+            ControlCommand::ResumeIngestion { .. } => "resume_ingestion",
+            ControlCommand::ReinjectParked { .. } => "reinject_parked",
+            ControlCommand::MigrateUser { .. } => "migrate_user",
+            ControlCommand::Activate { .. } => "activate",
+            ControlCommand::UserCreated { .. } => "user_created",
+            ControlCommand::UserDeleted { .. } => "user_deleted",
+            ControlCommand::InvalidateThread { .. } => "invalidate_thread",
+            ControlCommand::MemberStateChanged { .. } => "member_state_changed",
+            ControlCommand::ArchiveConversation { .. } => "archive_conversation",
+            ControlCommand::UnarchiveConversation { .. } => "unarchive_conversation",
+            ControlCommand::EnterMaintenance { .. } => "enter_maintenance",
+            ControlCommand::LeaveMaintenance => "leave_maintenance",
+            ControlCommand::FlagSpam { .. } => "flag_spam",
+            ControlCommand::OverrideAbuseResponse { .. } => "override_abuse_response",
+            ControlCommand::ClearAbuseOverride { .. } => "clear_abuse_override",
+            ControlCommand::WatchRouting { .. } => "watch_routing",
+            ControlCommand::UnwatchRouting { .. } => "unwatch_routing",
+            ControlCommand::ConfigOverride(_) => "config_override",
+            ControlCommand::ClearConfigOverride { .. } => "clear_config_override",
+            ControlCommand::PinGatewayEgressKey { .. } => "pin_gateway_egress_key",
+            ControlCommand::UnpinGatewayEgressKey { .. } => "unpin_gateway_egress_key",
+            ControlCommand::MarkRegionDown { .. } => "mark_region_down",
+            ControlCommand::MarkRegionUp { .. } => "mark_region_up",
+            ControlCommand::RehomeConversation { .. } => "rehome_conversation",
+            ControlCommand::SetPathOverride { .. } => "set_path_override",
+            ControlCommand::ClearPathOverride { .. } => "clear_path_override",
+            ControlCommand::ConversationWillStart { .. } => "conversation_will_start",
+            ControlCommand::AddShedExemption { .. } => "add_shed_exemption",
+            ControlCommand::RemoveShedExemption { .. } => "remove_shed_exemption",
+            ControlCommand::TenantChanged { .. } => "tenant_changed",
+        }
+    }
+}
+
+/// Applies control commands to the broker subsystems
+pub struct ControlHandler {
+    switchboard: Arc<DegradationSwitchboard>,
+    attestor: Arc<SenderAttestor>,
+    migration: Option<Arc<StreamMigration>>,
+    handover: Arc<ConsumerHandover>,
+    routes: Arc<RouteCache>,
+    pauses: Arc<IngestionPauses>,
+    sessions: Arc<SessionMigrator>,
+    leases: Arc<CommandLeases>,
+    standby: Arc<StandbyController>,
+    threads: Arc<ThreadParticipantCache>,
+    invitations: Arc<InvitationGate>,
+    archived: Arc<ArchivedConversations>,
+    maintenance: Arc<MaintenanceMode>,
+    abuse: Arc<AbuseScores>,
+    recipient_tracer: Arc<RecipientTracer>,
+    config_overrides: Arc<RuntimeOverrides>,
+    egress_cipher: Arc<EgressCipher>,
+    homes: Arc<ConversationHomes>,
+    redactor: Arc<EgressRedactor>,
+    compactor: Arc<KvCompactor>,
+    path_overrides: Arc<PathOverrides>,
+    bootstrap: Arc<ConversationBootstrap>,
+    exemptions: Arc<ShedExemptions>,
+    tenants: Arc<TenantRegistry>,
+}
+
+impl ControlHandler {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        switchboard: Arc<DegradationSwitchboard>,
+        attestor: Arc<SenderAttestor>,
+        migration: Option<Arc<StreamMigration>>,
+        handover: Arc<ConsumerHandover>,
+        routes: Arc<RouteCache>,
+        pauses: Arc<IngestionPauses>,
+        sessions: Arc<SessionMigrator>,
+        leases: Arc<CommandLeases>,
+        standby: Arc<StandbyController>,
+        threads: Arc<ThreadParticipantCache>,
+        invitations: Arc<InvitationGate>,
+        archived: Arc<ArchivedConversations>,
+        maintenance: Arc<MaintenanceMode>,
+        abuse: Arc<AbuseScores>,
+        recipient_tracer: Arc<RecipientTracer>,
+        config_overrides: Arc<RuntimeOverrides>,
+        egress_cipher: Arc<EgressCipher>,
+        homes: Arc<ConversationHomes>,
+        redactor: Arc<EgressRedactor>,
+        compactor: Arc<KvCompactor>,
+        path_overrides: Arc<PathOverrides>,
+        bootstrap: Arc<ConversationBootstrap>,
+        exemptions: Arc<ShedExemptions>,
+        tenants: Arc<TenantRegistry>,
+    ) -> Self {
+        Self {
+            switchboard,
+            attestor,
+            migration,
+            handover,
+            routes,
+            pauses,
+            sessions,
+            leases,
+            standby,
+            threads,
+            invitations,
+            archived,
+            maintenance,
+            abuse,
+            recipient_tracer,
+            config_overrides,
+            egress_cipher,
+            homes,
+            redactor,
+            compactor,
+            path_overrides,
+            bootstrap,
+            exemptions,
+            tenants,
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<ControlMessage, ControlError> {
+        serde_json::from_slice(payload).map_err(|e| ControlError::Malformed(e.to_string()))
+    }
+
+    pub async fn handle_raw(&self, payload: &[u8]) -> Result<(), ControlError> {
+        let message = Self::parse(payload).map_err(|e| {
+            warn!("Rejected control message: {}", e);
+            e
+        })?;
+        self.handle(message).await
+    }
+
+    pub async fn handle(&self, message: ControlMessage) -> Result<(), ControlError> {
+        debug!(
+            "Control command {} ({}) from {}",
+            message.command.name(),
+            message.command_id,
+            message.issued_by
+        );
+
+        let name = message.command.name();
+        let scope = message
+            .scope
+            .clone()
+            .unwrap_or_else(|| message.command.default_scope());
+
+        match scope {
+            CommandScope::EveryBroker => self.execute(message).await,
+            // Standbys keep their state fresh but leave execution to active brokers
+            _ if self.standby.is_standby() => {
+                self.leases.record_observed(&message.command_id, name, None);
+                Ok(())
+            }
+            CommandScope::OwnerOf { key } if !self.leases.owns(&key) => {
+                self.leases.record_observed(&message.command_id, name, None);
+                Ok(())
+            }
+            CommandScope::OwnerOf { .. } => {
+                self.execute(message).await?;
+                self.leases.record_executed(name);
+                Ok(())
+            }
+            CommandScope::SingleExecutor => {
+                let command_id = message.command_id.clone();
+                let lease = match self.leases.acquire(&command_id).await {
+                    Ok(LeaseOutcome::Acquired(lease)) => lease,
+                    Ok(LeaseOutcome::HeldBy(executor) | LeaseOutcome::Completed(executor)) => {
+                        self.leases.record_observed(&command_id, name, Some(&executor));
+                        return Ok(());
+                    }
+                    Err(e) => return Err(ControlError::Rejected(e.to_string())),
+                };
+
+                match self.execute(message).await {
+                    Ok(()) => {
+                        self.leases.record_executed(name);
+                        self.leases
+                            .complete(lease)
+                            .await
+                            .map_err(|e| ControlError::Rejected(e.to_string()))
+                    }
+                    Err(e) => {
+                        if let Err(release) = self.leases.release(lease).await {
+                            warn!("Failed to release lease for {}: {}", command_id, release);
+                        }
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    async fn execute(&self, message: ControlMessage) -> Result<(), ControlError> {
+        match message.command {
+            ControlCommand::SetDegradationLevel { level, reason, ttl_seconds } => {
+                self.switchboard.set_level(
+                    level,
+                    &message.issued_by,
+                    &reason,
+                    ttl_seconds.map(Duration::from_secs),
+                );
+            }
+            ControlCommand::RegisterGatewayKey {
+                gateway_id,
+                public_key,
+                trust_tier,
+                signature,
+            } => {
+                self.attestor
+                    .authorize_registration(&message.command_id, &gateway_id, &public_key, message.timestamp, &signature)
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+                self.attestor
+                    .register_key(&gateway_id, &public_key)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+                self.redactor.set_tier(&gateway_id, trust_tier.as_deref());
+            }
+            ControlCommand::RemoveGatewayKey { gateway_id, signature } => {
+                self.attestor
+                    .authorize_registration(&message.command_id, &gateway_id, "", message.timestamp, &signature)
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+                self.attestor
+                    .remove_key(&gateway_id)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+                self.redactor.set_tier(&gateway_id, None);
+            }
+            ControlCommand::MigrateStreams => {
+                let migration = self
+                    .migration
+                    .as_ref()
+                    .ok_or_else(|| ControlError::Rejected("stream migration is not configured".to_string()))?;
+                migration
+                    .migrate_streams()
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+                migration
+                    .refresh_residual()
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::HandoverConsumer { new_consumer, driver } => {
+                if driver != self.handover.broker_id() {
+                    return Ok(());
+                }
+                // Runs in the background so abort and phase messages keep flowing
+                let handover = Arc::clone(&self.handover);
+                let issued_by = message.issued_by;
+                spawn_traced("consumer_handover", TaskContext::new("control"), async move {
+                    if let Err(e) = handover.run(&new_consumer, &issued_by).await {
+                        warn!("Consumer handover to {} failed: {}", new_consumer, e);
+                    }
+                });
+            }
+            ControlCommand::AbortConsumerHandover => {
+                self.handover.abort();
+            }
+            ControlCommand::ConsumerHandover { phase, old_consumer, new_consumer } => {
+                self.handover.switch().apply(phase, &old_consumer, &new_consumer);
+            }
+            ControlCommand::PauseIngestion { selector, ttl_seconds } => {
+                self.pauses
+                    .pause(selector, ttl_seconds.map(Duration::from_secs), &message.issued_by);
+            }
+            ControlCommand::ResumeIngestion { selector } => {
+                self.pauses.resume(&selector, &message.issued_by);
+            }
+            ControlCommand::ReinjectParked { selector } => {
+                // Can take a while for a large backlog; run off the control loop
+                let pauses = Arc::clone(&self.pauses);
+                let maintenance = Arc::clone(&self.maintenance);
+                let issued_by = message.issued_by;
+                spawn_traced("reinject_parked", TaskContext::new("control"), async move {
+                    // Parked messages stay in the holding stream until maintenance ends
+                    maintenance.wait_inactive().await;
+                    match pauses.reinject(&selector, &issued_by).await {
+                        Ok(count) => info!("Re-injected {} parked messages for {:?}", count, selector),
+                        Err(e) => warn!("Re-injecting parked messages for {:?} failed: {}", selector, e),
+                    }
+                });
+            }
+            ControlCommand::MigrateUser {
+                user_id,
+                from_gateway,
+                to_gateway,
+            } => {
+                // Persists the first step, then drives the rest in the background
+                self.sessions
+                    .start(&user_id, &from_gateway, &to_gateway, &message.issued_by)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::Activate { broker_id } => {
+                if broker_id.is_some_and(|id| id != self.standby.broker_id()) {
+                    return Ok(());
+                }
+                self.standby
+                    .activate(ActivationTrigger::Command, &message.issued_by)
+                    .await;
+            }
+            ControlCommand::UserCreated { user_id } => {
+                self.routes.invalidate(&user_id);
+            }
+            ControlCommand::UserDeleted { user_id } => {
+                self.routes.invalidate(&user_id);
+                self.compactor.user_deleted(&user_id);
+            }
+            ControlCommand::InvalidateThread { conversation_id, thread_id } => {
+                self.threads.invalidate(&conversation_id, thread_id.as_deref());
+            }
+            ControlCommand::MemberStateChanged { group_id, user_id, state } => {
+                self.invitations.on_state_change(&group_id, &user_id, state);
+            }
+            ControlCommand::ArchiveConversation { conversation_id } => {
+                self.archived
+                    .set_archived(&conversation_id, true, &message.issued_by)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::UnarchiveConversation { conversation_id } => {
+                self.archived
+                    .set_archived(&conversation_id, false, &message.issued_by)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::EnterMaintenance { message: text, expected_end } => {
+                self.maintenance.enter(text, expected_end, &message.issued_by);
+            }
+            ControlCommand::LeaveMaintenance => {
+                self.maintenance.leave(&message.issued_by);
+            }
+            ControlCommand::FlagSpam { user_id } => {
+                self.abuse.observe(&user_id, AbuseSignal::SpamFlagged);
+            }
+            ControlCommand::OverrideAbuseResponse { user_id, response } => {
+                self.abuse.pin(&user_id, response, &message.issued_by);
+            }
+            ControlCommand::ClearAbuseOverride { user_id, reset_score } => {
+                self.abuse.unpin(&user_id, reset_score, &message.issued_by);
+            }
+            ControlCommand::WatchRouting { watch, ttl_seconds } => {
+                self.recipient_tracer.add_watch(watch, ttl_seconds.map(Duration::from_secs));
+            }
+            ControlCommand::UnwatchRouting { watch } => {
+                self.recipient_tracer.remove_watch(&watch);
+            }
+            ControlCommand::ConfigOverride(request) => {
+                self.config_overrides
+                    .offer(request, &message.issued_by)
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::ClearConfigOverride { path } => {
+                self.config_overrides.clear(&path, &message.issued_by);
+            }
+            ControlCommand::PinGatewayEgressKey { gateway_id, public_key } => {
+                self.egress_cipher
+                    .pin(&gateway_id, &public_key, &message.issued_by)
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::UnpinGatewayEgressKey { gateway_id } => {
+                self.egress_cipher.unpin(&gateway_id, &message.issued_by);
+            }
+            ControlCommand::MarkRegionDown { region } => {
+                self.homes
+                    .set_region_down(&region, true, &message.issued_by)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::MarkRegionUp { region } => {
+                self.homes
+                    .set_region_down(&region, false, &message.issued_by)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::RehomeConversation { conversation_id, region } => {
+                self.homes
+                    .rehome(&conversation_id, &region, &message.issued_by)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::SetPathOverride { rule, ttl_seconds } => {
+                self.path_overrides
+                    .set(rule, ttl_seconds.map(Duration::from_secs), &message.issued_by);
+            }
+            ControlCommand::ClearPathOverride { id } => {
+                if !self.path_overrides.clear(&id, &message.issued_by) {
+                    debug!("No path override {} to clear", id);
+                }
+            }
+            ControlCommand::ConversationWillStart { conversation_id, tenant_id } => {
+                self.bootstrap.hint(&conversation_id, tenant_id.as_deref());
+            }
+            ControlCommand::AddShedExemption { selector, reason } => {
+                self.exemptions
+                    .add(selector, &reason, &message.issued_by)
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+            ControlCommand::RemoveShedExemption { selector } => {
+                if !self.exemptions.remove(&selector, &message.issued_by) {
+                    debug!("No runtime shed exemption {:?} to remove", selector);
+                }
+            }
+            ControlCommand::TenantChanged { tenant_id } => {
+                self.tenants
+                    .refresh(&tenant_id)
+                    .await
+                    .map_err(|e| ControlError::Rejected(e.to_string()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ControlError {
+    #[error("malformed control message: {0}")]
+    Malformed(String),
+    #[error("control command rejected: {0}")]
+    Rejected(String),
+}
@@ -0,0 +1,437 @@
+use std::{sync::Arc, time::Duration};
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    cluster::ClusterView,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Execution lease for one control command, stored under `lease.{base64url(command_id)}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandLease {
+    pub command_id: String,
+    pub holder: String,
+    /// Timestamp in milliseconds after which another broker may take over
+    pub expires_at: i64,
+    /// Set once the holder finished executing
+    pub completed: bool,
+}
+
+/// Lease held by this broker; pass back to `complete` or `release`
+#[derive(Debug)]
+pub struct HeldLease {
+    command_id: String,
+    revision: u64,
+    renewal: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Debug)]
+pub enum LeaseOutcome {
+    Acquired(HeldLease),
+    /// Another broker holds an unexpired lease
+    HeldBy(String),
+    /// Another broker already executed the command
+    Completed(String),
+}
+
+/// Decides which broker executes a control command
+///
+/// Single-executor commands go through a KV lease: the first broker to
+/// create it executes, renewing it every third of `control.lease_ttl` while
+/// the command runs. If the holder crashes, the lease expires and the next
+/// broker to see the command takes it over and re-executes, so
+/// single-executor commands must be idempotent. Owner-scoped commands run
+/// on the cluster owner of their key.
+pub struct CommandLeases {
+    kv: kv::Store,
+    broker_id: String,
+    ttl: Duration,
+    cluster: Arc<ClusterView>,
+    audit: AuditLog,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl CommandLeases {
+    pub fn new(
+        kv: kv::Store,
+        cluster: Arc<ClusterView>,
+        ttl: Duration,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            kv,
+            broker_id: cluster.local_id().to_string(),
+            ttl,
+            cluster,
+            audit,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// True if this broker owns `key` in the current cluster view
+    pub fn owns(&self, key: &str) -> bool {
+        self.cluster.remote_owner(key).is_none()
+    }
+
+    pub fn record_executed(&self, command: &'static str) {
+        self.metrics.record_control_execution(command, "executed");
+    }
+
+    /// Audit a command this broker saw but left to `executor`
+    pub fn record_observed(&self, command_id: &str, command: &'static str, executor: Option<&str>) {
+        self.metrics.record_control_execution(command, "observed");
+        self.audit.record(AuditEntry::new(
+            self.broker_id.as_str(),
+            "control.observed_not_executed",
+            serde_json::json!({
+                "command_id": command_id,
+                "command": command,
+                "executor": executor,
+            }),
+        ));
+    }
+
+    pub async fn acquire(self: &Arc<Self>, command_id: &str) -> Result<LeaseOutcome, LeaseError> {
+        let key = lease_key(command_id);
+        let lease = self.fresh_lease(command_id);
+        let value = serde_json::to_vec(&lease).map_err(|e| LeaseError(e.to_string()))?;
+
+        let revision = match self.kv.create(&key, value.clone().into()).await {
+            Ok(revision) => revision,
+            Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => {
+                let Some((current, revision)) = self.load(&key).await? else {
+                    return Err(LeaseError(format!("lease {} vanished", key)));
+                };
+                if current.completed {
+                    return Ok(LeaseOutcome::Completed(current.holder));
+                }
+                if current.expires_at > self.clock.now_millis() {
+                    return Ok(LeaseOutcome::HeldBy(current.holder));
+                }
+                // Previous holder died mid-command; only one broker wins the CAS
+                match self.kv.update(&key, value.into(), revision).await {
+                    Ok(revision) => {
+                        warn!("Took over expired lease for {} from {}", command_id, current.holder);
+                        revision
+                    }
+                    Err(_) => {
+                        debug!("Lost lease takeover race for {}", command_id);
+                        return Ok(LeaseOutcome::HeldBy(current.holder));
+                    }
+                }
+            }
+            Err(e) => return Err(LeaseError(e.to_string())),
+        };
+
+        Ok(LeaseOutcome::Acquired(HeldLease {
+            command_id: command_id.to_string(),
+            revision,
+            renewal: self.spawn_renewal(command_id.to_string(), revision),
+        }))
+    }
+
+    /// Mark the command executed so later deliveries don't run it again
+    pub async fn complete(&self, lease: HeldLease) -> Result<(), LeaseError> {
+        lease.renewal.abort();
+        let mut record = self.fresh_lease(&lease.command_id);
+        record.completed = true;
+        let value = serde_json::to_vec(&record).map_err(|e| LeaseError(e.to_string()))?;
+        // The renewal task may have advanced the revision; completion wins regardless
+        self.kv
+            .put(lease_key(&lease.command_id), value.into())
+            .await
+            .map_err(|e| LeaseError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Give the lease up after a failed execution so another broker can retry
+    pub async fn release(&self, lease: HeldLease) -> Result<(), LeaseError> {
+        lease.renewal.abort();
+        let mut record = self.fresh_lease(&lease.command_id);
+        record.expires_at = 0;
+        let value = serde_json::to_vec(&record).map_err(|e| LeaseError(e.to_string()))?;
+        self.kv
+            .put(lease_key(&lease.command_id), value.into())
+            .await
+            .map_err(|e| LeaseError(e.to_string()))?;
+        debug!("Released lease for {} at revision {}", lease.command_id, lease.revision);
+        Ok(())
+    }
+
+    fn spawn_renewal(self: &Arc<Self>, command_id: String, mut revision: u64) -> tokio::task::JoinHandle<()> {
+        let leases = Arc::clone(self);
+        spawn_traced("control_lease_renewal", TaskContext::new("control"), async move {
+            let period = (leases.ttl / 3).max(Duration::from_millis(100));
+            loop {
+                leases.clock.sleep(period).await;
+                let Ok(value) = serde_json::to_vec(&leases.fresh_lease(&command_id)) else {
+                    return;
+                };
+                match leases.kv.update(lease_key(&command_id), value.into(), revision).await {
+                    Ok(next) => revision = next,
+                    Err(e) => {
+                        warn!("Lost lease for {} while executing: {}", command_id, e);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    fn fresh_lease(&self, command_id: &str) -> CommandLease {
+        CommandLease {
+            command_id: command_id.to_string(),
+            holder: self.broker_id.clone(),
+            expires_at: self.clock.now_millis() + self.ttl.as_millis() as i64,
+            completed: false,
+        }
+    }
+
+    async fn load(&self, key: &str) -> Result<Option<(CommandLease, u64)>, LeaseError> {
+        let entry = self.kv.entry(key).await.map_err(|e| LeaseError(e.to_string()))?;
+        match entry {
+            Some(entry) if entry.operation == kv::Operation::Put => {
+                let lease = serde_json::from_slice(&entry.value).map_err(|e| LeaseError(e.to_string()))?;
+                Ok(Some((lease, entry.revision)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn lease_key(command_id: &str) -> String {
+    format!("lease.{}", URL_SAFE_NO_PAD.encode(command_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("control lease error: {0}")]
+pub struct LeaseError(pub String);
+
+/// The lease tests run three in-process brokers against JetStream at `NATS_URL`
+/// (default `localhost:4222`): `cargo test -- --ignored control_lease`
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_nats::jetstream;
+    use tokio::task::JoinSet;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        control::{CommandScope, ControlMessage},
+    };
+
+    const BROKERS: [&str; 3] = ["broker-a", "broker-b", "broker-c"];
+
+    #[test]
+    fn scopes_default_per_command() {
+        let parse = |json: &str| serde_json::from_str::<ControlMessage>(json).unwrap();
+        let migrate = parse(r#"{"command_id":"c1","issued_by":"ops","timestamp":0,"command":"migrate_streams"}"#);
+        assert_eq!(migrate.scope, None);
+        assert_eq!(migrate.command.default_scope(), CommandScope::SingleExecutor);
+
+        let scoped = parse(
+            r#"{"command_id":"c2","issued_by":"ops","timestamp":0,"command":"migrate_streams",
+                "scope":{"kind":"owner_of","key":"group_team"}}"#,
+        );
+        assert_eq!(
+            scoped.scope,
+            Some(CommandScope::OwnerOf {
+                key: "group_team".into()
+            })
+        );
+    }
+
+    #[test]
+    fn command_ids_stay_a_single_key_token() {
+        let key = lease_key("purge.user.alice");
+        assert!(key.starts_with("lease."));
+        assert_eq!(key.matches('.').count(), 1);
+    }
+
+    /// Three brokers sharing one lease bucket, one cluster view bucket and one clock
+    async fn brokers(ttl: Duration) -> (Vec<Arc<CommandLeases>>, Arc<SimClock>) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let context = jetstream::new(async_nats::connect(url).await.unwrap());
+        let id = Uuid::new_v4().simple();
+        let leases = context
+            .create_key_value(kv::Config {
+                bucket: format!("control-lease-test-{}", id),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let members = context
+            .create_key_value(kv::Config {
+                bucket: format!("control-cluster-test-{}", id),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let mut config = BrokerConfig::load().unwrap().cluster;
+        config.heartbeat_interval = Duration::from_millis(200);
+        let clock = Arc::new(SimClock::new());
+        let mut brokers = Vec::new();
+        for broker_id in BROKERS {
+            let cluster = Arc::new(ClusterView::new(members.clone(), broker_id.into(), &config));
+            cluster.spawn();
+            brokers.push(Arc::new(
+                CommandLeases::new(leases.clone(), cluster, ttl, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+                    .with_clock(clock.clone()),
+            ));
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while brokers.iter().any(|broker| broker.cluster.members().len() < BROKERS.len()) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("brokers never saw each other");
+        (brokers, clock)
+    }
+
+    /// What `ControlHandler` does with a single-executor command
+    async fn deliver(broker: &Arc<CommandLeases>, command_id: &str, executions: &AtomicU32) -> bool {
+        match broker.acquire(command_id).await.unwrap() {
+            LeaseOutcome::Acquired(lease) => {
+                executions.fetch_add(1, Ordering::SeqCst);
+                broker.complete(lease).await.unwrap();
+                true
+            }
+            LeaseOutcome::HeldBy(_) | LeaseOutcome::Completed(_) => false,
+        }
+    }
+
+    /// Deliver the command to every broker at once; how many executed it
+    async fn deliver_to_all(brokers: &[Arc<CommandLeases>], command_id: &str, executions: &Arc<AtomicU32>) -> usize {
+        let mut deliveries = JoinSet::new();
+        for broker in brokers {
+            let (broker, command_id, executions) = (broker.clone(), command_id.to_string(), executions.clone());
+            deliveries.spawn(async move { deliver(&broker, &command_id, &executions).await });
+        }
+        let mut executed = 0;
+        while let Some(result) = deliveries.join_next().await {
+            executed += result.unwrap() as usize;
+        }
+        executed
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn single_executor_commands_run_exactly_once() {
+        let (brokers, _) = brokers(Duration::from_secs(30)).await;
+        let executions = Arc::new(AtomicU32::new(0));
+
+        for round in 0..20 {
+            let command_id = format!("purge-{}", round);
+            assert_eq!(deliver_to_all(&brokers, &command_id, &executions).await, 1, "{}", command_id);
+        }
+        assert_eq!(executions.load(Ordering::SeqCst), 20);
+
+        // Redelivery after completion runs nothing again
+        for broker in &brokers {
+            assert!(matches!(
+                broker.acquire("purge-0").await.unwrap(),
+                LeaseOutcome::Completed(_)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_crashed_executor_is_taken_over_after_the_ttl() {
+        let ttl = Duration::from_secs(30);
+        let (brokers, clock) = brokers(ttl).await;
+
+        let LeaseOutcome::Acquired(lease) = brokers[0].acquire("migrate").await.unwrap() else {
+            panic!("first broker should win an uncontended lease");
+        };
+        // Crash: the renewal stops and the command never completes
+        lease.renewal.abort();
+        for broker in &brokers[1..] {
+            assert!(matches!(
+                broker.acquire("migrate").await.unwrap(),
+                LeaseOutcome::HeldBy(holder) if holder == "broker-a"
+            ));
+        }
+
+        clock.advance(ttl + Duration::from_millis(1));
+        let executions = Arc::new(AtomicU32::new(0));
+        assert_eq!(deliver_to_all(&brokers[1..], "migrate", &executions).await, 1);
+        assert!(matches!(
+            brokers[0].acquire("migrate").await.unwrap(),
+            LeaseOutcome::Completed(holder) if holder != "broker-a"
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_running_executor_keeps_its_lease() {
+        let ttl = Duration::from_secs(30);
+        let (brokers, clock) = brokers(ttl).await;
+        let LeaseOutcome::Acquired(lease) = brokers[0].acquire("slow").await.unwrap() else {
+            panic!("first broker should win an uncontended lease");
+        };
+
+        // Well past the TTL, but the renewal keeps it alive
+        let key = lease_key("slow");
+        for _ in 0..9 {
+            let before = brokers[0].load(&key).await.unwrap().unwrap().1;
+            clock.advance(ttl / 3);
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while brokers[0].load(&key).await.unwrap().unwrap().1 == before {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await
+            .expect("the lease was never renewed");
+        }
+        assert!(matches!(brokers[1].acquire("slow").await.unwrap(), LeaseOutcome::HeldBy(_)));
+
+        // A failed execution hands the command to the next broker right away
+        brokers[0].release(lease).await.unwrap();
+        assert!(matches!(brokers[2].acquire("slow").await.unwrap(), LeaseOutcome::Acquired(_)));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn owner_scoped_commands_run_on_exactly_one_owner() {
+        let (brokers, _) = brokers(Duration::from_secs(30)).await;
+        let mut owned_by = std::collections::HashMap::new();
+
+        for i in 0..300 {
+            let key = format!("group_{}", i);
+            let owners: Vec<_> = brokers.iter().filter(|broker| broker.owns(&key)).collect();
+            assert_eq!(owners.len(), 1, "{}", key);
+            *owned_by.entry(owners[0].broker_id.clone()).or_insert(0) += 1;
+
+            // Everyone else routes it to the same owner
+            for broker in &brokers {
+                if let Some(owner) = broker.cluster.remote_owner(&key) {
+                    assert_eq!(owner.broker_id, owners[0].broker_id);
+                }
+            }
+        }
+        // Rendezvous hashing spreads keys over all three brokers
+        assert_eq!(owned_by.len(), BROKERS.len());
+    }
+}
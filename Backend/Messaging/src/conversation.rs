@@ -0,0 +1,613 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use dashmap::{mapref::entry::Entry, DashMap};
+use parking_lot::RwLock;
+use tracing::debug;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    conversation_lifecycle::{ConversationLifecycle, LifecycleKind},
+    delivery::DeliveryTracker,
+    metrics::BrokerMetrics,
+    pending_queue::{RetryQueue, ScheduledQueue},
+    subjects::SubjectRegistry,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Per-conversation state that can be garbage collected once idle
+/// (ordering sequence counters, preview cache entries, receipt aggregation)
+pub trait ConversationStateStore: Send + Sync {
+    /// Store name used as the `store` label on GC metrics
+    fn name(&self) -> &'static str;
+
+    /// Remove all state for the conversation, returning whether any existed
+    fn evict(&self, conversation_id: &str) -> bool;
+
+    /// Number of conversations with state in this store
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Veto on GC for conversations that still have outstanding work
+/// (pending offline replay, unsettled retries)
+pub trait ConversationRetention: Send + Sync {
+    /// Those of the `idle` conversations whose state must be kept
+    fn retained(&self, idle: &HashSet<String>) -> HashSet<String>;
+}
+
+/// What the registry knows about one tracked conversation
+struct Activity {
+    /// Timestamps in milliseconds
+    last: i64,
+    observed_at: i64,
+    tenant_id: Option<String>,
+    participants: u32,
+    /// `ConversationIdle` went out for the current quiet spell
+    idle_emitted: bool,
+}
+
+/// Tracks last activity per conversation and evicts idle state
+///
+/// With a lifecycle publisher attached, a conversation's first message
+/// since it was last evicted emits `ConversationObserved`, a quiet spell of
+/// `conversation_lifecycle.idle_after` emits `ConversationIdle` (once, until
+/// traffic resumes), and GC emits `ConversationStateEvicted`, after which
+/// the next message is observed afresh.
+pub struct ConversationRegistry {
+    activity: DashMap<String, Activity>,
+    stores: RwLock<Vec<Arc<dyn ConversationStateStore>>>,
+    retention: RwLock<Vec<Arc<dyn ConversationRetention>>>,
+    idle_timeout: Duration,
+    lifecycle: Option<Arc<ConversationLifecycle>>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ConversationRegistry {
+    pub fn new(idle_timeout: Duration, lifecycle: Option<Arc<ConversationLifecycle>>, metrics: BrokerMetrics) -> Self {
+        Self {
+            activity: DashMap::new(),
+            stores: RwLock::new(Vec::new()),
+            retention: RwLock::new(Vec::new()),
+            idle_timeout,
+            lifecycle,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn register_store(&self, store: Arc<dyn ConversationStateStore>) {
+        self.stores.write().push(store);
+    }
+
+    pub fn register_retention(&self, retention: Arc<dyn ConversationRetention>) {
+        self.retention.write().push(retention);
+    }
+
+    /// Keep state for conversations with undelivered or offline-queued
+    /// recipients, queued retries or scheduled sends, and have the tracker
+    /// record activity for every message it tracks
+    pub fn attach_delivery(
+        self: &Arc<Self>,
+        tracker: &Arc<DeliveryTracker>,
+        retries: Arc<RetryQueue>,
+        scheduled: Arc<ScheduledQueue>,
+    ) {
+        tracker.attach_conversations(self);
+        self.register_retention(tracker.clone());
+        self.register_retention(retries);
+        self.register_retention(scheduled);
+    }
+
+    /// Record that a message with `participants` (sender and recipients) was routed
+    ///
+    /// Called before the message touches any per-conversation store, so GC
+    /// never evicts state a message is about to use.
+    pub fn record_activity(&self, conversation_id: &str, tenant_id: Option<&str>, participants: u32) {
+        let now = self.clock.now_millis();
+        if let Some(mut activity) = self.activity.get_mut(conversation_id) {
+            activity.last = now;
+            activity.participants = participants;
+            activity.idle_emitted = false;
+            return;
+        }
+
+        // Only the caller whose insert won emits, so concurrent first messages observe once
+        let mut inserted = false;
+        self.activity.entry(conversation_id.to_string()).or_insert_with(|| {
+            inserted = true;
+            Activity {
+                last: now,
+                observed_at: now,
+                tenant_id: tenant_id.map(str::to_string),
+                participants,
+                idle_emitted: false,
+            }
+        });
+        if inserted {
+            if let Some(lifecycle) = &self.lifecycle {
+                lifecycle.emit(conversation_id, tenant_id, participants, LifecycleKind::ConversationObserved);
+            }
+        }
+    }
+
+    /// Number of conversations currently tracked
+    pub fn len(&self) -> usize {
+        self.activity.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.activity.is_empty()
+    }
+
+    /// Evict state for every idle conversation not vetoed by a retention check
+    ///
+    /// Returns the number of conversations collected.
+    pub fn collect_idle(&self) -> usize {
+        self.emit_idle();
+        let cutoff = self.clock.now_millis() - self.idle_timeout.as_millis() as i64;
+
+        let idle: HashSet<String> = self
+            .activity
+            .iter()
+            .filter(|entry| entry.value().last < cutoff)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        if idle.is_empty() {
+            return 0;
+        }
+
+        let retained: HashSet<String> = self
+            .retention
+            .read()
+            .iter()
+            .flat_map(|retention| retention.retained(&idle))
+            .collect();
+        let stores = self.stores.read();
+        let mut collected = 0;
+
+        for conversation_id in idle.difference(&retained) {
+            // Evict under the entry lock, re-checking first: a message routed
+            // meanwhile either kept the conversation active or waits in
+            // `record_activity` until its old state is gone
+            let Entry::Occupied(entry) = self.activity.entry(conversation_id.clone()) else {
+                continue;
+            };
+            if entry.get().last >= cutoff {
+                continue;
+            }
+            let mut evicted_from = Vec::new();
+            for store in stores.iter() {
+                if store.evict(conversation_id) {
+                    self.metrics.record_conversation_state_gc(store.name());
+                    evicted_from.push(store.name().to_string());
+                }
+            }
+            let activity = entry.remove();
+
+            if let Some(lifecycle) = &self.lifecycle {
+                lifecycle.emit(
+                    conversation_id,
+                    activity.tenant_id.as_deref(),
+                    activity.participants,
+                    LifecycleKind::ConversationStateEvicted {
+                        observed_at: activity.observed_at,
+                        stores: evicted_from,
+                    },
+                );
+            }
+            collected += 1;
+        }
+
+        debug!("Collected state for {} idle conversations", collected);
+        collected
+    }
+
+    /// Emit `ConversationIdle` for conversations newly quiet for `idle_after`
+    fn emit_idle(&self) {
+        let Some(lifecycle) = &self.lifecycle else {
+            return;
+        };
+        let cutoff = self.clock.now_millis() - lifecycle.config().idle_after.as_millis() as i64;
+        for mut entry in self.activity.iter_mut() {
+            if entry.idle_emitted || entry.last >= cutoff {
+                continue;
+            }
+            entry.idle_emitted = true;
+            lifecycle.emit(
+                entry.key(),
+                entry.tenant_id.as_deref(),
+                entry.participants,
+                LifecycleKind::ConversationIdle {
+                    last_activity_at: entry.last,
+                },
+            );
+        }
+    }
+
+    /// Periodically collect idle conversations and refresh subject activity
+    pub fn spawn_gc_task(
+        self: &Arc<Self>,
+        subjects: Arc<SubjectRegistry>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let registry = Arc::clone(self);
+        spawn_traced("conversation_gc", TaskContext::new("conversation"), async move {
+            loop {
+                registry.clock.sleep(interval).await;
+                registry.collect_idle();
+                subjects.prune(registry.idle_timeout);
+                subjects.refresh_gauge();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        history_cache::HistoryCache,
+        message::types::{EncryptedPayload, MessageEnvelope, MessageType},
+        nats_probe::tests::EmbeddedServer,
+        pending_queue::{AttemptEnd, PendingQueue, RetryEntry, ScheduledEntry},
+        preview::PreviewExtractor,
+    };
+
+    const IDLE: Duration = Duration::from_secs(300);
+
+    /// Stand-in for the sequence counters, preview cache and receipt state
+    #[derive(Default)]
+    struct MapStore {
+        conversations: Mutex<HashSet<String>>,
+    }
+
+    impl ConversationStateStore for MapStore {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn evict(&self, conversation_id: &str) -> bool {
+            self.conversations.lock().remove(conversation_id)
+        }
+
+        fn len(&self) -> usize {
+            self.conversations.lock().len()
+        }
+    }
+
+    struct Pending(HashSet<String>);
+
+    impl ConversationRetention for Pending {
+        fn retained(&self, idle: &HashSet<String>) -> HashSet<String> {
+            idle.intersection(&self.0).cloned().collect()
+        }
+    }
+
+    fn registry() -> (ConversationRegistry, Arc<MapStore>, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let registry = ConversationRegistry::new(IDLE, None, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        let store = Arc::new(MapStore::default());
+        registry.register_store(store.clone());
+        (registry, store, clock)
+    }
+
+    fn route(registry: &ConversationRegistry, store: &MapStore, conversation_id: &str) {
+        registry.record_activity(conversation_id, None, 2);
+        store.conversations.lock().insert(conversation_id.to_string());
+    }
+
+    #[test]
+    fn state_shrinks_back_after_the_idle_period() {
+        let (registry, store, clock) = registry();
+        for i in 0..5_000 {
+            route(&registry, &store, &format!("conv-{}", i));
+        }
+        assert_eq!(registry.len(), 5_000);
+        assert_eq!(store.len(), 5_000);
+
+        assert_eq!(registry.collect_idle(), 0);
+
+        clock.advance(IDLE + Duration::from_secs(1));
+        assert_eq!(registry.collect_idle(), 5_000);
+        assert!(registry.is_empty());
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn active_conversations_keep_their_state() {
+        let (registry, store, clock) = registry();
+        route(&registry, &store, "quiet");
+        route(&registry, &store, "busy");
+
+        clock.advance(IDLE / 2);
+        route(&registry, &store, "busy");
+        clock.advance(IDLE / 2 + Duration::from_secs(1));
+
+        assert_eq!(registry.collect_idle(), 1);
+        assert_eq!(registry.len(), 1);
+        assert!(store.conversations.lock().contains("busy"));
+    }
+
+    #[test]
+    fn retention_vetoes_collection() {
+        let (registry, store, clock) = registry();
+        route(&registry, &store, "replaying");
+        route(&registry, &store, "done");
+        registry.register_retention(Arc::new(Pending(HashSet::from(["replaying".to_string()]))));
+
+        clock.advance(IDLE + Duration::from_secs(1));
+        assert_eq!(registry.collect_idle(), 1);
+        assert!(store.conversations.lock().contains("replaying"));
+        assert!(!store.conversations.lock().contains("done"));
+    }
+
+    /// Routes another message for the conversation from a second thread while it is being evicted
+    #[derive(Default)]
+    struct Racing {
+        store: MapStore,
+        on_evict: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+        raced: Mutex<Option<std::thread::JoinHandle<()>>>,
+    }
+
+    impl ConversationStateStore for Racing {
+        fn name(&self) -> &'static str {
+            "racing"
+        }
+
+        fn evict(&self, conversation_id: &str) -> bool {
+            if let Some(route) = self.on_evict.lock().take() {
+                *self.raced.lock() = Some(std::thread::spawn(route));
+                // Long enough for the message to reach `record_activity`
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            self.store.evict(conversation_id)
+        }
+
+        fn len(&self) -> usize {
+            self.store.len()
+        }
+    }
+
+    #[test]
+    fn a_message_routed_during_eviction_keeps_its_fresh_state() {
+        let clock = Arc::new(SimClock::new());
+        let registry =
+            Arc::new(ConversationRegistry::new(IDLE, None, BrokerMetrics::new().unwrap()).with_clock(clock.clone()));
+        let racing = Arc::new(Racing::default());
+        registry.register_store(racing.clone());
+        route(&registry, &racing.store, "group_team");
+        clock.advance(IDLE + Duration::from_secs(1));
+
+        *racing.on_evict.lock() = Some(Box::new({
+            let (registry, racing) = (Arc::clone(&registry), Arc::clone(&racing));
+            move || route(&registry, &racing.store, "group_team")
+        }));
+        assert_eq!(registry.collect_idle(), 1);
+        racing.raced.lock().take().unwrap().join().unwrap();
+
+        assert_eq!(registry.len(), 1);
+        assert!(racing.store.conversations.lock().contains("group_team"));
+        assert_eq!(registry.collect_idle(), 0);
+    }
+
+    fn message(from: &str, to: &str) -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::TextMessage,
+            from.into(),
+            vec![to.into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn outstanding_delivery_work_holds_conversations_back() {
+        let (registry, store, clock) = registry();
+        let registry = Arc::new(registry);
+        let server = EmbeddedServer::start(clock.clone()).await;
+        let jetstream = async_nats::jetstream::new(async_nats::connect(&server.url).await.unwrap());
+        let broker = BrokerConfig::load().unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let tracker = Arc::new(
+            DeliveryTracker::new(
+                jetstream.clone(),
+                jetstream.get_key_value("status").await.unwrap(),
+                "push.fallback".into(),
+                Arc::new(PreviewExtractor::new(broker.preview)),
+                Arc::new(HistoryCache::new(&broker.history_cache, metrics.clone())),
+                &broker.routing,
+                metrics.clone(),
+            )
+            .with_clock(clock.clone()),
+        );
+        let retries = Arc::new(PendingQueue::new(AuditLog::tracing_only(), metrics.clone()));
+        let scheduled = Arc::new(PendingQueue::new(AuditLog::tracing_only(), metrics));
+        registry.attach_delivery(&tracker, retries.clone(), scheduled.clone());
+
+        // Tracking a message is activity
+        let online = message("alice", "bob");
+        let offline = message("alice", "carol");
+        for envelope in [&online, &offline] {
+            tracker.track(envelope, &envelope.to);
+            store.conversations.lock().insert(envelope.conversation_id());
+        }
+        tracker.mark_queued(&offline.message_id, "carol");
+        retries.insert(RetryEntry {
+            id: "r-1".into(),
+            message_id: "m-retry".into(),
+            conversation_id: "group_retrying".into(),
+            recipient: "dave".into(),
+            attempts: 1,
+            next_attempt_at: clock.now_millis(),
+            last_error: Some("no responders".into()),
+        });
+        scheduled.insert(ScheduledEntry {
+            id: "s-1".into(),
+            message_id: "m-later".into(),
+            conversation_id: "group_scheduled".into(),
+            from: "erin".into(),
+            deliver_at: clock.now_millis() + 86_400_000,
+            campaign: None,
+        });
+        for conversation_id in ["group_retrying", "group_scheduled", "group_done"] {
+            route(&registry, &store, conversation_id);
+        }
+        assert_eq!(registry.len(), 5);
+
+        clock.advance(IDLE + Duration::from_secs(1));
+        assert_eq!(registry.collect_idle(), 1);
+        assert!(!store.conversations.lock().contains("group_done"));
+
+        // Once each piece of work settles its conversation is let go
+        tracker.ack(&online.message_id, "bob");
+        assert_eq!(registry.collect_idle(), 1);
+        assert!(!store.conversations.lock().contains(&online.conversation_id()));
+
+        // Still waiting in the offline queue for replay
+        assert!(store.conversations.lock().contains(&offline.conversation_id()));
+        tracker.ack(&offline.message_id, "carol");
+        retries.begin_attempt("r-1").unwrap();
+        assert_eq!(registry.collect_idle(), 1);
+        assert!(store.conversations.lock().contains("group_retrying"));
+
+        retries.end_attempt("r-1", AttemptEnd::Done);
+        scheduled.cancel("s-1", "ops");
+        assert_eq!(registry.collect_idle(), 2);
+        assert!(registry.is_empty());
+        assert_eq!(store.len(), 0);
+    }
+
+    fn lifecycle_registry() -> (ConversationRegistry, Arc<MapStore>, Arc<ConversationLifecycle>, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().conversation_lifecycle;
+        config.enabled = true;
+        config.idle_after = IDLE / 5;
+        let lifecycle = Arc::new(ConversationLifecycle::new("broker-1".into(), config, BrokerMetrics::new().unwrap()));
+        let clock = Arc::new(SimClock::new());
+        let registry = ConversationRegistry::new(IDLE, Some(Arc::clone(&lifecycle)), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        let store = Arc::new(MapStore::default());
+        registry.register_store(store.clone());
+        (registry, store, lifecycle, clock)
+    }
+
+    fn kinds(lifecycle: &ConversationLifecycle) -> Vec<LifecycleKind> {
+        lifecycle.drain().into_iter().map(|event| event.kind).collect()
+    }
+
+    #[test]
+    fn conversations_go_observed_idle_evicted() {
+        let (registry, store, lifecycle, clock) = lifecycle_registry();
+        let started = clock.now_millis();
+        registry.record_activity("group_team", Some("acme"), 3);
+        // Further messages don't observe again
+        route(&registry, &store, "group_team");
+
+        let events = lifecycle.drain();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, LifecycleKind::ConversationObserved);
+        assert_eq!(events[0].conversation_id, "group_team");
+        assert_eq!(events[0].tenant_id.as_deref(), Some("acme"));
+        assert_eq!(events[0].approximate_participants, 3);
+        assert_eq!(events[0].broker_id, "broker-1");
+
+        clock.advance(IDLE / 5 + Duration::from_secs(1));
+        assert_eq!(registry.collect_idle(), 0);
+        registry.collect_idle();
+        assert_eq!(
+            kinds(&lifecycle),
+            vec![LifecycleKind::ConversationIdle {
+                last_activity_at: started
+            }]
+        );
+
+        // Traffic ends the quiet spell; the next one is reported again
+        route(&registry, &store, "group_team");
+        let resumed = clock.now_millis();
+        clock.advance(IDLE / 5 + Duration::from_secs(1));
+        registry.collect_idle();
+        assert_eq!(
+            kinds(&lifecycle),
+            vec![LifecycleKind::ConversationIdle {
+                last_activity_at: resumed
+            }]
+        );
+
+        clock.advance(IDLE);
+        assert_eq!(registry.collect_idle(), 1);
+        let events = lifecycle.drain();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].kind,
+            LifecycleKind::ConversationStateEvicted {
+                observed_at: started,
+                stores: vec!["test".to_string()],
+            }
+        );
+        assert_eq!(events[0].tenant_id.as_deref(), Some("acme"));
+        assert_eq!(events[0].approximate_participants, 2);
+    }
+
+    #[test]
+    fn evicted_conversations_are_observed_afresh() {
+        let (registry, store, lifecycle, clock) = lifecycle_registry();
+        route(&registry, &store, "group_team");
+        clock.advance(IDLE + Duration::from_secs(1));
+        registry.collect_idle();
+        assert_eq!(lifecycle.drain().len(), 3);
+
+        route(&registry, &store, "group_team");
+        route(&registry, &store, "group_team");
+        assert_eq!(kinds(&lifecycle), vec![LifecycleKind::ConversationObserved]);
+
+        // The new cycle's eviction carries the new observation time
+        let observed = clock.now_millis();
+        clock.advance(IDLE + Duration::from_secs(1));
+        registry.collect_idle();
+        let kinds = kinds(&lifecycle);
+        assert_eq!(kinds.len(), 2);
+        assert!(matches!(kinds[0], LifecycleKind::ConversationIdle { .. }));
+        assert!(matches!(
+            &kinds[1],
+            LifecycleKind::ConversationStateEvicted { observed_at, .. } if *observed_at == observed
+        ));
+    }
+
+    #[test]
+    fn short_lived_conversations_stay_within_the_three_events() {
+        let (registry, store, lifecycle, clock) = lifecycle_registry();
+        for i in 0..100 {
+            for _ in 0..10 {
+                route(&registry, &store, &format!("conv-{}", i));
+            }
+        }
+        clock.advance(IDLE + Duration::from_secs(1));
+        registry.collect_idle();
+
+        let events = lifecycle.drain();
+        assert_eq!(events.len(), 300);
+        for kind in ["observed", "idle", "evicted"] {
+            assert_eq!(events.iter().filter(|event| event.kind.as_str() == kind).count(), 100);
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Instant,
+};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::ConversationBootstrapConfig,
+    conversation::ConversationStateStore,
+    conversation_home::ConversationHomes,
+    metrics::BrokerMetrics,
+    sequence::SequenceAllocator,
+    task::{spawn_traced, TaskContext},
+};
+
+/// State creation postponed until the conversation's first delivery
+pub type DeferredInit = Box<dyn FnOnce() + Send>;
+
+/// How a conversation's first message found its state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warmth {
+    /// Pre-created from a `ConversationWillStart` hint
+    Hinted,
+    /// Created on the first message's own path
+    Cold,
+}
+
+impl Warmth {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Warmth::Hinted => "hinted",
+            Warmth::Cold => "cold",
+        }
+    }
+}
+
+/// A conversation whose first message hasn't been delivered yet
+struct Bootstrapping {
+    warmth: Warmth,
+    started: Instant,
+    deferred: Vec<DeferredInit>,
+}
+
+/// Fast path for the first message of a brand-new conversation
+///
+/// The first message used to claim a sequence block (a KV read and a
+/// write) and then resolve the conversation's home (another read and
+/// write), one after the other, before anything else. `prepare` issues the
+/// block claim and the home assignment together, so the first message waits
+/// for the slower of the two instead of their sum. State the first delivery
+/// doesn't need, such as the preview cache entry and receipt aggregation,
+/// is handed to `defer` and created once `delivered` reports the first
+/// delivery done, or after `conversation_bootstrap.deferred_timeout` if it
+/// never is.
+///
+/// A `ConversationWillStart` control hint runs the same preparation ahead
+/// of any traffic. Hinted conversations that see no message within
+/// `conversation_bootstrap.hint_ttl` have their in-memory state dropped;
+/// the claimed block stays in KV and shows up as an ordinary sequence gap
+/// if the conversation starts later. At most
+/// `conversation_bootstrap.max_speculative` hints are outstanding.
+///
+/// `broker_first_message_latency_seconds` measures ingress to first
+/// delivery for each conversation's first message, labelled by warmth.
+pub struct ConversationBootstrap {
+    sequences: Arc<SequenceAllocator>,
+    homes: Arc<ConversationHomes>,
+    /// Hinted conversations with no message yet, by hint time
+    speculative: Mutex<HashMap<String, Instant>>,
+    bootstrapping: Mutex<HashMap<String, Bootstrapping>>,
+    config: ConversationBootstrapConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ConversationBootstrap {
+    pub fn new(
+        sequences: Arc<SequenceAllocator>,
+        homes: Arc<ConversationHomes>,
+        config: ConversationBootstrapConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            sequences,
+            homes,
+            speculative: Mutex::new(HashMap::new()),
+            bootstrapping: Mutex::new(HashMap::new()),
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Ready a conversation's state before its first message is sequenced
+    ///
+    /// Conversations that already have state here return immediately.
+    pub async fn prepare(&self, conversation_id: &str, tenant_id: Option<&str>) {
+        if !self.config.enabled {
+            return;
+        }
+        if self.speculative.lock().remove(conversation_id).is_some() {
+            self.begin(conversation_id, Warmth::Hinted);
+            self.metrics.record_conversation_hint("materialized");
+            return;
+        }
+        if self.sequences.has_state(conversation_id) {
+            return;
+        }
+        self.begin(conversation_id, Warmth::Cold);
+        self.warm(conversation_id, tenant_id).await;
+    }
+
+    /// `ConversationWillStart`: pre-create state for a conversation about to start
+    pub fn hint(self: &Arc<Self>, conversation_id: &str, tenant_id: Option<&str>) {
+        if !self.config.enabled || self.sequences.has_state(conversation_id) {
+            return;
+        }
+        {
+            let mut speculative = self.speculative.lock();
+            if speculative.len() >= self.config.max_speculative {
+                self.metrics.record_conversation_hint("rejected");
+                return;
+            }
+            if speculative.insert(conversation_id.to_string(), self.clock.now_instant()).is_some() {
+                return;
+            }
+        }
+        self.metrics.record_conversation_hint("accepted");
+
+        let bootstrap = Arc::clone(self);
+        let conversation_id = conversation_id.to_string();
+        let tenant_id = tenant_id.map(str::to_string);
+        spawn_traced("conversation_hint", TaskContext::new("conversation_bootstrap"), async move {
+            bootstrap.warm(&conversation_id, tenant_id.as_deref()).await;
+        });
+    }
+
+    /// Create `init` after the conversation's first delivery, or now if that already happened
+    pub fn defer(&self, conversation_id: &str, init: DeferredInit) {
+        let mut bootstrapping = self.bootstrapping.lock();
+        match bootstrapping.get_mut(conversation_id) {
+            Some(pending) => pending.deferred.push(init),
+            None => {
+                drop(bootstrapping);
+                init();
+            }
+        }
+    }
+
+    /// A message of the conversation was delivered; `ingress_timestamp` in milliseconds
+    pub fn delivered(&self, conversation_id: &str, ingress_timestamp: i64) {
+        let Some(pending) = self.bootstrapping.lock().remove(conversation_id) else {
+            return;
+        };
+        let latency = (self.clock.now_millis() - ingress_timestamp).max(0) as f64 / 1000.0;
+        self.metrics.record_first_message_latency(pending.warmth.as_str(), latency);
+        for init in pending.deferred {
+            init();
+        }
+    }
+
+    pub fn spawn_sweeper(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let bootstrap = Arc::clone(self);
+        Some(spawn_traced("conversation_bootstrap", TaskContext::new("conversation_bootstrap"), async move {
+            loop {
+                bootstrap.clock.sleep(bootstrap.config.sweep_interval).await;
+                bootstrap.sweep();
+            }
+        }))
+    }
+
+    /// Drop expired hints and run deferred work whose first delivery never came
+    pub fn sweep(&self) {
+        let now = self.clock.now_instant();
+        let expired: Vec<String> = {
+            let mut speculative = self.speculative.lock();
+            let expired = speculative
+                .iter()
+                .filter(|(_, hinted_at)| now.saturating_duration_since(**hinted_at) >= self.config.hint_ttl)
+                .map(|(conversation_id, _)| conversation_id.clone())
+                .collect::<Vec<_>>();
+            for conversation_id in &expired {
+                speculative.remove(conversation_id);
+            }
+            expired
+        };
+        for conversation_id in &expired {
+            self.sequences.evict(conversation_id);
+            self.metrics.record_conversation_hint("expired");
+        }
+        if !expired.is_empty() {
+            debug!("Dropped {} conversation hints that saw no traffic", expired.len());
+        }
+
+        let overdue: Vec<Bootstrapping> = {
+            let mut bootstrapping = self.bootstrapping.lock();
+            let overdue_ids: Vec<String> = bootstrapping
+                .iter()
+                .filter(|(_, pending)| now.saturating_duration_since(pending.started) >= self.config.deferred_timeout)
+                .map(|(conversation_id, _)| conversation_id.clone())
+                .collect();
+            overdue_ids
+                .iter()
+                .filter_map(|conversation_id| bootstrapping.remove(conversation_id))
+                .collect()
+        };
+        for pending in overdue {
+            for init in pending.deferred {
+                init();
+            }
+        }
+    }
+
+    fn begin(&self, conversation_id: &str, warmth: Warmth) {
+        self.bootstrapping.lock().entry(conversation_id.to_string()).or_insert_with(|| Bootstrapping {
+            warmth,
+            started: self.clock.now_instant(),
+            deferred: Vec::new(),
+        });
+    }
+
+    /// Claim the first sequence block and resolve the home concurrently
+    async fn warm(&self, conversation_id: &str, tenant_id: Option<&str>) {
+        let home = async {
+            if self.homes.enabled() {
+                self.homes.home_of(conversation_id, tenant_id).await.map(|_| ())
+            } else {
+                Ok(())
+            }
+        };
+        let (sequence, home) = tokio::join!(self.sequences.warm(conversation_id), home);
+        // Failures only lose the head start; the message path claims and resolves again
+        if let Err(e) = sequence {
+            warn!("Warming sequences for {} failed: {}", conversation_id, e);
+        }
+        if let Err(e) = home {
+            warn!("Resolving the home of {} failed: {}", conversation_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use async_nats::jetstream::{self, kv};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        nats_probe::tests::EmbeddedServer,
+        sequence::sequence_key,
+    };
+
+    const HINT_TTL: Duration = Duration::from_secs(120);
+    const DEFERRED_TIMEOUT: Duration = Duration::from_secs(30);
+    const BLOCK: u64 = 100;
+
+    fn bootstrap(jetstream: &jetstream::Context, kv: &kv::Store, clock: &Arc<SimClock>) -> Arc<ConversationBootstrap> {
+        let config = BrokerConfig::load().unwrap();
+        let mut routing = config.routing;
+        routing.sequence_block_size = BLOCK;
+        let sequences = Arc::new(SequenceAllocator::new(kv.clone(), &routing, BrokerMetrics::new().unwrap()));
+        let mut home_config = config.conversation_home;
+        home_config.enabled = false;
+        let homes = Arc::new(ConversationHomes::new(
+            home_config,
+            kv.clone(),
+            jetstream.clone(),
+            AuditLog::tracing_only(),
+            BrokerMetrics::new().unwrap(),
+        ));
+        let mut bootstrap_config = config.conversation_bootstrap;
+        bootstrap_config.enabled = true;
+        bootstrap_config.hint_ttl = HINT_TTL;
+        bootstrap_config.deferred_timeout = DEFERRED_TIMEOUT;
+        bootstrap_config.max_speculative = 2;
+        Arc::new(
+            ConversationBootstrap::new(sequences, homes, bootstrap_config, BrokerMetrics::new().unwrap())
+                .with_clock(clock.clone()),
+        )
+    }
+
+    /// Behind the embedded server every KV read fails, so warming only
+    /// creates in-memory state and the message path claims on its own
+    async fn offline(clock: &Arc<SimClock>) -> (Arc<ConversationBootstrap>, EmbeddedServer) {
+        let server = EmbeddedServer::start(clock.clone()).await;
+        let jetstream = jetstream::new(async_nats::connect(&server.url).await.unwrap());
+        let kv = jetstream.get_key_value("sequences").await.unwrap();
+        (bootstrap(&jetstream, &kv, clock), server)
+    }
+
+    fn counter(count: &Arc<AtomicUsize>) -> DeferredInit {
+        let count = count.clone();
+        Box::new(move || {
+            count.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[tokio::test]
+    async fn only_the_first_delivery_is_measured_and_it_releases_deferred_state() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let (bootstrap, _server) = offline(&clock).await;
+        let created = Arc::new(AtomicUsize::new(0));
+
+        let ingress = clock.now_millis();
+        bootstrap.prepare("dm:a:b", None).await;
+        bootstrap.defer("dm:a:b", counter(&created));
+        bootstrap.defer("dm:a:b", counter(&created));
+        assert_eq!(created.load(Ordering::SeqCst), 0, "held until the first delivery");
+
+        clock.advance(Duration::from_millis(40));
+        bootstrap.delivered("dm:a:b", ingress);
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+
+        // Steady state: nothing to prepare, nothing measured, nothing held back
+        clock.advance(Duration::from_millis(500));
+        bootstrap.prepare("dm:a:b", None).await;
+        bootstrap.delivered("dm:a:b", ingress);
+        bootstrap.defer("dm:a:b", counter(&created));
+        assert_eq!(created.load(Ordering::SeqCst), 3);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_first_message_latency_seconds_count{warmth="cold"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_first_message_latency_seconds_sum{warmth="cold"} 0.04"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn deferred_state_is_created_after_the_timeout_when_no_delivery_is_reported() {
+        let clock = Arc::new(SimClock::new());
+        let (bootstrap, _server) = offline(&clock).await;
+        let created = Arc::new(AtomicUsize::new(0));
+        bootstrap.prepare("dm:a:b", None).await;
+        bootstrap.defer("dm:a:b", counter(&created));
+
+        clock.advance(DEFERRED_TIMEOUT - Duration::from_millis(1));
+        bootstrap.sweep();
+        assert_eq!(created.load(Ordering::SeqCst), 0);
+        clock.advance(Duration::from_millis(1));
+        bootstrap.sweep();
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+        assert!(bootstrap.bootstrapping.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_hint_that_never_sees_traffic_is_dropped_after_its_ttl() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let (bootstrap, _server) = offline(&clock).await;
+
+        bootstrap.hint("dm:a:b", None);
+        bootstrap.hint("dm:a:b", None);
+        bootstrap.hint("group-1", Some("tenant-a"));
+        bootstrap.hint("group-2", None);
+        assert_eq!(bootstrap.speculative.lock().len(), 2, "a repeat is ignored and max_speculative holds");
+        while !bootstrap.sequences.has_state("dm:a:b") || !bootstrap.sequences.has_state("group-1") {
+            tokio::task::yield_now().await;
+        }
+
+        clock.advance(HINT_TTL - Duration::from_millis(1));
+        bootstrap.sweep();
+        assert_eq!(bootstrap.speculative.lock().len(), 2);
+        clock.advance(Duration::from_millis(1));
+        bootstrap.sweep();
+        assert!(bootstrap.speculative.lock().is_empty());
+        assert!(!bootstrap.sequences.has_state("dm:a:b"), "hinted state is dropped with the hint");
+        assert!(bootstrap.bootstrapping.lock().is_empty());
+
+        // Traffic arriving later starts cold, as if there had been no hint
+        let ingress = clock.now_millis();
+        bootstrap.prepare("dm:a:b", None).await;
+        assert_eq!(bootstrap.bootstrapping.lock()["dm:a:b"].warmth, Warmth::Cold);
+        bootstrap.delivered("dm:a:b", ingress);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_conversation_hints_total{outcome="accepted"} 2"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_conversation_hints_total{outcome="rejected"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_conversation_hints_total{outcome="expired"} 2"#), "{}", rendered);
+        assert!(!rendered.contains(r#"outcome="materialized""#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_first_message_latency_seconds_count{warmth="cold"} 1"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn a_hinted_conversations_first_message_is_measured_as_hinted() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let (bootstrap, _server) = offline(&clock).await;
+
+        bootstrap.hint("dm:a:b", None);
+        let ingress = clock.now_millis();
+        bootstrap.prepare("dm:a:b", None).await;
+        clock.advance(Duration::from_millis(5));
+        bootstrap.delivered("dm:a:b", ingress);
+        // A materialized hint isn't swept as unused
+        clock.advance(HINT_TTL);
+        bootstrap.sweep();
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_conversation_hints_total{outcome="materialized"} 1"#), "{}", rendered);
+        assert!(!rendered.contains(r#"outcome="expired""#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_first_message_latency_seconds_count{warmth="hinted"} 1"#), "{}", rendered);
+    }
+
+    /// A fresh sequence bucket on a JetStream-enabled server at `NATS_URL`:
+    /// `cargo test -- --ignored conversation_bootstrap`
+    async fn bucket() -> (jetstream::Context, kv::Store) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+        let kv = jetstream
+            .create_key_value(kv::Config {
+                bucket: format!("bootstrap-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        (jetstream, kv)
+    }
+
+    /// The conversation's KV high-water mark, if it has one
+    async fn high_water(kv: &kv::Store, conversation_id: &str) -> Option<String> {
+        let value = kv.get(sequence_key(conversation_id)).await.unwrap()?;
+        Some(String::from_utf8(value.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_warmed_first_message_claims_nothing_on_its_own_path() {
+        let (jetstream, kv) = bucket().await;
+        let clock = Arc::new(SimClock::new());
+        let bootstrap = bootstrap(&jetstream, &kv, &clock);
+
+        // Without the fast path the first allocation claims its block itself
+        assert_eq!(bootstrap.sequences.next("dm:unprepared").await.unwrap(), 1);
+        assert_eq!(high_water(&kv, "dm:unprepared").await.as_deref(), Some("100"));
+
+        // Hinted: the block is claimed ahead of the message...
+        bootstrap.hint("dm:hinted", None);
+        while high_water(&kv, "dm:hinted").await.is_none() {
+            tokio::task::yield_now().await;
+        }
+        let revision = kv.entry(sequence_key("dm:hinted")).await.unwrap().unwrap().revision;
+        // ...so the first message allocates from memory, like every later one
+        bootstrap.prepare("dm:hinted", None).await;
+        let first = bootstrap.sequences.next("dm:hinted").await.unwrap();
+        let second = bootstrap.sequences.next("dm:hinted").await.unwrap();
+        assert_eq!((first, second), (1, 2));
+        let unchanged = kv.entry(sequence_key("dm:hinted")).await.unwrap().unwrap().revision;
+        assert_eq!(unchanged, revision);
+
+        // Cold: the claim moves into `prepare`, off the allocation
+        bootstrap.prepare("dm:cold", None).await;
+        assert_eq!(high_water(&kv, "dm:cold").await.as_deref(), Some("100"));
+        assert_eq!(bootstrap.sequences.next("dm:cold").await.unwrap(), 1);
+        assert_eq!(high_water(&kv, "dm:cold").await.as_deref(), Some("100"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn an_unused_hint_leaves_only_a_sequence_gap() {
+        let (jetstream, kv) = bucket().await;
+        let clock = Arc::new(SimClock::new());
+        let bootstrap = bootstrap(&jetstream, &kv, &clock);
+
+        bootstrap.hint("dm:a:b", None);
+        while high_water(&kv, "dm:a:b").await.is_none() {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(HINT_TTL);
+        bootstrap.sweep();
+        assert!(!bootstrap.sequences.has_state("dm:a:b"));
+
+        // The conversation starts after all: sequences continue past the unused block
+        bootstrap.prepare("dm:a:b", None).await;
+        assert_eq!(bootstrap.sequences.next("dm:a:b").await.unwrap(), BLOCK + 1);
+        assert_eq!(high_water(&kv, "dm:a:b").await.as_deref(), Some("200"));
+    }
+}
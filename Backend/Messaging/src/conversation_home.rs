@@ -0,0 +1,682 @@
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    time::Instant,
+};
+use async_nats::{
+    jetstream::{self, kv},
+    HeaderMap,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use lru::LruCache;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::ConversationHomeConfig,
+    forward::IngestMetadata,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Set on ingress forwarded to a conversation's home region; names the region it came from
+pub const HOME_FORWARDED_HEADER: &str = "Broker-Home-Forwarded-From";
+
+/// Home of one conversation, stored under `home.{base64url(conversation_id)}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HomeRecord {
+    pub region: String,
+    /// Incremented by every re-home
+    pub epoch: u64,
+    /// Milliseconds
+    pub assigned_at: i64,
+}
+
+/// Where a conversation's ingress is processed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HomeRoute {
+    Local,
+    /// Published to the home region's ingress subject
+    Forwarded { region: String },
+}
+
+struct CachedHome {
+    record: HomeRecord,
+    fetched_at: Instant,
+}
+
+struct HomeCache {
+    entries: LruCache<String, CachedHome>,
+    /// Bumped by every re-home applied here, under the same lock
+    generation: u64,
+}
+
+/// Sticky per-conversation home regions for multi-region deployments
+///
+/// Brokers in every region accept ingress, but only brokers in a
+/// conversation's home region allocate its sequences and process it; the
+/// others publish its ingress to the home region's ingress subject
+/// (`conversation_home.regions`) unchanged. A conversation's home is fixed
+/// the first time any region sees it: the tenant's region from
+/// `conversation_home.tenant_regions` if set, otherwise the region that saw
+/// it, written with a KV create so concurrent first messages agree. Homes
+/// are cached in a bounded LRU for `conversation_home.cache_ttl`; re-home
+/// commands update the cache on every broker as they are applied.
+///
+/// Failover: `MarkRegionDown` records the region as down in KV, and the
+/// next message for a conversation homed there re-homes it to the region
+/// that received the message, with a revision check so brokers racing to
+/// re-home agree on one winner. The new home's sequence allocator doesn't
+/// see numbers the old home handed out but never replicated, so ordering
+/// may be discontinuous across the switch for messages in flight at the
+/// time; clients order those by timestamp. `MarkRegionUp` stops further
+/// re-homes but doesn't move conversations back.
+pub struct ConversationHomes {
+    config: ConversationHomeConfig,
+    kv: kv::Store,
+    jetstream: jetstream::Context,
+    cache: Mutex<HomeCache>,
+    down: RwLock<HashSet<String>>,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl ConversationHomes {
+    pub fn new(
+        config: ConversationHomeConfig,
+        kv: kv::Store,
+        jetstream: jetstream::Context,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            cache: Mutex::new(HomeCache {
+                entries: LruCache::new(NonZeroUsize::new(config.cache_size.max(1)).unwrap()),
+                generation: 0,
+            }),
+            config,
+            kv,
+            jetstream,
+            down: RwLock::new(HashSet::new()),
+            audit,
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn local_region(&self) -> &str {
+        &self.config.region
+    }
+
+    /// Load the regions marked down before this broker started
+    pub async fn load_down_regions(&self) -> Result<(), HomeError> {
+        let mut keys = self.kv.keys().await.map_err(|e| HomeError(e.to_string()))?;
+        let mut down = HashSet::new();
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(|e| HomeError(e.to_string()))?;
+            if let Some(region) = key.strip_prefix("region_down.") {
+                down.insert(region.to_string());
+            }
+        }
+        if !down.is_empty() {
+            warn!("Regions marked down: {:?}", down);
+        }
+        *self.down.write() = down;
+        Ok(())
+    }
+
+    /// Process locally or forward to the conversation's home region
+    ///
+    /// Messages that already crossed regions, or were forwarded between
+    /// peers, are processed here whatever the home says, so a re-home in
+    /// flight can't bounce a message back and forth.
+    pub async fn route(&self, envelope: &MessageEnvelope, metadata: &IngestMetadata) -> Result<HomeRoute, HomeError> {
+        if !self.config.enabled {
+            return Ok(HomeRoute::Local);
+        }
+        if metadata.forwarded || metadata.home_forwarded_from.is_some() {
+            self.metrics.record_home_routing(&self.config.region, "local_forwarded");
+            return Ok(HomeRoute::Local);
+        }
+
+        let home = self.home(envelope).await?;
+        if home.region == self.config.region {
+            self.metrics.record_home_routing(&home.region, "local");
+            return Ok(HomeRoute::Local);
+        }
+
+        let subject = self
+            .config
+            .regions
+            .get(&home.region)
+            .ok_or_else(|| HomeError(format!("no ingress subject for region {}", home.region)))?;
+        self.forward(subject, envelope, metadata).await?;
+        self.metrics.record_home_routing(&home.region, "forwarded");
+        Ok(HomeRoute::Forwarded { region: home.region })
+    }
+
+    /// The conversation's home, assigning or re-homing it as needed
+    pub async fn home(&self, envelope: &MessageEnvelope) -> Result<HomeRecord, HomeError> {
+        self.home_of(&envelope.conversation_id(), envelope.tenant_id.as_deref()).await
+    }
+
+    /// `home` by conversation ID, for callers without an envelope
+    pub async fn home_of(&self, conversation_id: &str, tenant_id: Option<&str>) -> Result<HomeRecord, HomeError> {
+        let conversation_id = conversation_id.to_string();
+        let now = self.clock.now_instant();
+        let generation = {
+            let mut cache = self.cache.lock();
+            if let Some(cached) = cache.entries.get(&conversation_id) {
+                let age = now.saturating_duration_since(cached.fetched_at);
+                if age < self.config.cache_ttl && !self.is_down(&cached.record.region) {
+                    self.metrics.record_home_cache_lookup("hit");
+                    return Ok(cached.record.clone());
+                }
+            }
+            cache.generation
+        };
+        self.metrics.record_home_cache_lookup("miss");
+
+        let key = home_key(&conversation_id);
+        let entry = self
+            .kv
+            .entry(&key)
+            .await
+            .map_err(|e| HomeError(e.to_string()))?
+            .filter(|entry| entry.operation == kv::Operation::Put);
+        let record = match entry {
+            Some(entry) => {
+                let record: HomeRecord =
+                    serde_json::from_slice(&entry.value).map_err(|e| HomeError(e.to_string()))?;
+                if self.is_down(&record.region) {
+                    self.rehome_down(&conversation_id, record, entry.revision).await?
+                } else {
+                    record
+                }
+            }
+            None => self.assign(&conversation_id, tenant_id).await?,
+        };
+
+        let mut cache = self.cache.lock();
+        // A re-home applied during the lookup knows better than KV read before it
+        if cache.generation == generation {
+            cache.entries.put(
+                conversation_id,
+                CachedHome {
+                    record: record.clone(),
+                    fetched_at: self.clock.now_instant(),
+                },
+            );
+        }
+        Ok(record)
+    }
+
+    /// First sighting: claim the home, or take whichever region claimed it first
+    async fn assign(&self, conversation_id: &str, tenant_id: Option<&str>) -> Result<HomeRecord, HomeError> {
+        let region = tenant_id
+            .and_then(|tenant| self.config.tenant_regions.get(tenant))
+            .filter(|region| !self.is_down(region))
+            .unwrap_or(&self.config.region)
+            .clone();
+        let record = HomeRecord {
+            region,
+            epoch: 0,
+            assigned_at: self.clock.now_millis(),
+        };
+        let value = serde_json::to_vec(&record).map_err(|e| HomeError(e.to_string()))?;
+
+        let key = home_key(conversation_id);
+        match self.kv.create(&key, value.into()).await {
+            Ok(_) => {
+                self.metrics.record_home_assignment(&record.region, "assigned");
+                Ok(record)
+            }
+            Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => {
+                let entry = self
+                    .kv
+                    .get(&key)
+                    .await
+                    .map_err(|e| HomeError(e.to_string()))?
+                    .ok_or_else(|| HomeError(format!("home of {} vanished", conversation_id)))?;
+                serde_json::from_slice(&entry).map_err(|e| HomeError(e.to_string()))
+            }
+            Err(e) => Err(HomeError(e.to_string())),
+        }
+    }
+
+    /// Move a conversation off a down region to this one; losers of the race adopt the winner's home
+    async fn rehome_down(&self, conversation_id: &str, current: HomeRecord, revision: u64) -> Result<HomeRecord, HomeError> {
+        let record = HomeRecord {
+            region: self.config.region.clone(),
+            epoch: current.epoch + 1,
+            assigned_at: self.clock.now_millis(),
+        };
+        let value = serde_json::to_vec(&record).map_err(|e| HomeError(e.to_string()))?;
+        let key = home_key(conversation_id);
+        if self.kv.update(&key, value.into(), revision).await.is_err() {
+            let entry = self
+                .kv
+                .get(&key)
+                .await
+                .map_err(|e| HomeError(e.to_string()))?
+                .ok_or_else(|| HomeError(format!("home of {} vanished", conversation_id)))?;
+            return serde_json::from_slice(&entry).map_err(|e| HomeError(e.to_string()));
+        }
+
+        info!(
+            "Re-homed conversation {} from down region {} to {}",
+            conversation_id, current.region, record.region
+        );
+        self.metrics.record_home_assignment(&record.region, "failover");
+        Ok(record)
+    }
+
+    /// Explicitly move a conversation's home; every broker applies this
+    pub async fn rehome(&self, conversation_id: &str, region: &str, actor: &str) -> Result<(), HomeError> {
+        if region != self.config.region && !self.config.regions.contains_key(region) {
+            return Err(HomeError(format!("unknown region {}", region)));
+        }
+        let key = home_key(conversation_id);
+        let epoch = match self.kv.get(&key).await.map_err(|e| HomeError(e.to_string()))? {
+            Some(value) => serde_json::from_slice::<HomeRecord>(&value)
+                .map(|record| record.epoch)
+                .unwrap_or(0),
+            None => 0,
+        };
+        let record = HomeRecord {
+            region: region.to_string(),
+            epoch: epoch + 1,
+            assigned_at: self.clock.now_millis(),
+        };
+        self.store_local(conversation_id, &record);
+
+        // Every broker writes the same home; the epoch may differ by a re-read, which is harmless
+        let value = serde_json::to_vec(&record).map_err(|e| HomeError(e.to_string()))?;
+        self.kv
+            .put(key, value.into())
+            .await
+            .map_err(|e| HomeError(e.to_string()))?;
+
+        self.audit.record(AuditEntry::new(
+            actor,
+            "conversation.rehomed",
+            json!({ "conversation_id": conversation_id, "region": region }),
+        ));
+        Ok(())
+    }
+
+    /// Mark a region down or back up; every broker applies this
+    pub async fn set_region_down(&self, region: &str, down: bool, actor: &str) -> Result<(), HomeError> {
+        let changed = if down {
+            self.down.write().insert(region.to_string())
+        } else {
+            self.down.write().remove(region)
+        };
+        if changed {
+            warn!("Region {} marked {} by {}", region, if down { "down" } else { "up" }, actor);
+        }
+
+        let key = format!("region_down.{}", region);
+        if down {
+            self.kv
+                .put(key, actor.to_string().into())
+                .await
+                .map_err(|e| HomeError(e.to_string()))?;
+        } else {
+            self.kv.delete(key).await.map_err(|e| HomeError(e.to_string()))?;
+        }
+
+        self.audit.record(AuditEntry::new(
+            actor,
+            if down { "region.marked_down" } else { "region.marked_up" },
+            json!({ "region": region }),
+        ));
+        Ok(())
+    }
+
+    fn is_down(&self, region: &str) -> bool {
+        self.down.read().contains(region)
+    }
+
+    fn store_local(&self, conversation_id: &str, record: &HomeRecord) {
+        let mut cache = self.cache.lock();
+        cache.generation += 1;
+        cache.entries.put(
+            conversation_id.to_string(),
+            CachedHome {
+                record: record.clone(),
+                fetched_at: self.clock.now_instant(),
+            },
+        );
+    }
+
+    async fn forward(&self, subject: &str, envelope: &MessageEnvelope, metadata: &IngestMetadata) -> Result<(), HomeError> {
+        let mut headers = HeaderMap::new();
+        metadata.to_headers(&mut headers);
+        headers.insert(HOME_FORWARDED_HEADER, self.config.region.as_str());
+        headers.insert("Nats-Msg-Id", envelope.message_id.as_str());
+
+        let payload = serde_json::to_vec(envelope).map_err(|e| HomeError(e.to_string()))?;
+        let publish = async {
+            self.jetstream
+                .publish_with_headers(subject.to_string(), headers, payload.into())
+                .await
+                .map_err(|e| HomeError(e.to_string()))?
+                .await
+                .map_err(|e| HomeError(e.to_string()))
+        };
+        tokio::select! {
+            biased;
+            published = publish => published.map(|_| ()),
+            _ = self.clock.sleep(self.config.forward_timeout) => {
+                Err(HomeError(format!("forward to {} timed out", subject)))
+            }
+        }
+    }
+}
+
+fn home_key(conversation_id: &str) -> String {
+    format!("home.{}", URL_SAFE_NO_PAD.encode(conversation_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("conversation home error: {0}")]
+pub struct HomeError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    const US: &str = "us";
+    const EU: &str = "eu";
+    const CACHE_TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn home_keys_are_single_tokens_whatever_the_conversation_id() {
+        for conversation_id in ["dm:alice:bob", "group.with.dots", "tenant/room *"] {
+            let key = home_key(conversation_id);
+            let token = key.strip_prefix("home.").unwrap();
+            assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'), "{}", key);
+        }
+        assert_ne!(home_key("a.b"), home_key("a_b"));
+    }
+
+    fn message(from: &str, to: &str, tenant_id: Option<&str>) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(MessageType::TextMessage, from.into(), vec![to.into()], payload);
+        envelope.tenant_id = tenant_id.map(String::from);
+        envelope
+    }
+
+    fn received(region: &str) -> IngestMetadata {
+        IngestMetadata {
+            origin_broker: format!("broker-{}", region),
+            gateway_id: format!("gw-{}", region),
+            service_account: None,
+            received_at: 1_700_000_000_000,
+            forwarded: false,
+            home_forwarded_from: None,
+        }
+    }
+
+    /// Two regions sharing one NATS, told apart by their ingress subjects
+    struct World {
+        jetstream: jetstream::Context,
+        kv: kv::Store,
+        id: String,
+        clock: Arc<SimClock>,
+    }
+
+    impl World {
+        /// A fresh bucket and ingress stream on JetStream at `NATS_URL` (default `localhost:4222`):
+        /// `cargo test -- --ignored conversation_home`
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+            let id = Uuid::new_v4().simple().to_string();
+            let kv = jetstream
+                .create_key_value(kv::Config {
+                    bucket: format!("home-test-{}", id),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: format!("HOME_TEST_{}", id),
+                    subjects: vec![format!("test.{}.>", id)],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            Self {
+                jetstream,
+                kv,
+                id,
+                clock: Arc::new(SimClock::new()),
+            }
+        }
+
+        fn ingress(&self, region: &str) -> String {
+            format!("test.{}.{}.ingress", self.id, region)
+        }
+
+        fn broker(&self, region: &str, tenant_regions: &[(&str, &str)]) -> ConversationHomes {
+            let mut config = BrokerConfig::load().unwrap().conversation_home;
+            config.enabled = true;
+            config.region = region.to_string();
+            config.cache_ttl = CACHE_TTL;
+            config.regions = [US, EU]
+                .into_iter()
+                .filter(|other| *other != region)
+                .map(|other| (other.to_string(), self.ingress(other)))
+                .collect();
+            config.tenant_regions = tenant_regions
+                .iter()
+                .map(|(tenant, region)| (tenant.to_string(), region.to_string()))
+                .collect();
+            ConversationHomes::new(
+                config,
+                self.kv.clone(),
+                self.jetstream.clone(),
+                AuditLog::tracing_only(),
+                BrokerMetrics::new().unwrap(),
+            )
+            .with_clock(self.clock.clone())
+        }
+
+        /// Message ID and forwarding region of everything published to a region's ingress
+        async fn forwarded_to(&self, region: &str) -> Vec<(String, String)> {
+            let mut stream = self.jetstream.get_stream(format!("HOME_TEST_{}", self.id)).await.unwrap();
+            let last = stream.info().await.unwrap().state.last_sequence;
+            let mut forwarded = Vec::new();
+            for sequence in 1..=last {
+                let Ok(raw) = stream.get_raw_message(sequence).await else { continue };
+                let message = async_nats::Message::try_from(raw).unwrap();
+                if message.subject.as_str() != self.ingress(region) {
+                    continue;
+                }
+                let headers = message.headers.unwrap();
+                let envelope: MessageEnvelope = serde_json::from_slice(&message.payload).unwrap();
+                assert_eq!(headers.get("Nats-Msg-Id").unwrap().as_str(), envelope.message_id);
+                let from = headers.get(HOME_FORWARDED_HEADER).unwrap().to_string();
+                forwarded.push((envelope.message_id, from));
+            }
+            forwarded
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn conversations_stay_where_first_seen_and_the_other_region_forwards_there() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new().await;
+        let (us, eu) = (world.broker(US, &[]), world.broker(EU, &[]));
+
+        let first = message("alice", "bob", None);
+        assert_eq!(us.route(&first, &received(US)).await.unwrap(), HomeRoute::Local);
+        let home = us.home(&first).await.unwrap();
+        assert_eq!((home.region.as_str(), home.epoch), (US, 0));
+
+        let reply = message("bob", "alice", None);
+        assert_eq!(
+            eu.route(&reply, &received(EU)).await.unwrap(),
+            HomeRoute::Forwarded { region: US.into() }
+        );
+        assert_eq!(world.forwarded_to(US).await, [(reply.message_id.clone(), EU.to_string())]);
+
+        // The home region processes what was forwarded to it, whatever its cache says
+        let mut forwarded = received(EU);
+        forwarded.home_forwarded_from = Some(EU.into());
+        assert_eq!(us.route(&reply, &forwarded).await.unwrap(), HomeRoute::Local);
+
+        // A conversation eu sees first lives in eu
+        let other = message("carol", "dave", None);
+        assert_eq!(eu.route(&other, &received(EU)).await.unwrap(), HomeRoute::Local);
+        assert_eq!(
+            us.route(&other, &received(US)).await.unwrap(),
+            HomeRoute::Forwarded { region: EU.into() }
+        );
+        assert_eq!(world.forwarded_to(EU).await, [(other.message_id.clone(), US.to_string())]);
+
+        let rendered = recorder.handle().render();
+        for (region, outcome) in [(US, "local"), (US, "forwarded"), (US, "local_forwarded"), (EU, "local"), (EU, "forwarded")] {
+            let line = format!(r#"broker_home_routing_total{{region="{}",outcome="{}"}} 1"#, region, outcome);
+            assert!(rendered.contains(&line), "{}\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn tenant_policy_picks_the_home_of_new_conversations() {
+        let world = World::new().await;
+        let us = world.broker(US, &[("acme", EU)]);
+
+        let acme = message("alice", "bob", Some("acme"));
+        assert_eq!(
+            us.route(&acme, &received(US)).await.unwrap(),
+            HomeRoute::Forwarded { region: EU.into() }
+        );
+        assert_eq!(us.home(&acme).await.unwrap().region, EU);
+        assert_eq!(us.route(&message("carol", "dave", None), &received(US)).await.unwrap(), HomeRoute::Local);
+
+        // The policy doesn't send new conversations to a region that's down
+        us.set_region_down(EU, true, "ops").await.unwrap();
+        let during_outage = message("erin", "frank", Some("acme"));
+        assert_eq!(us.route(&during_outage, &received(US)).await.unwrap(), HomeRoute::Local);
+        assert_eq!(us.home(&during_outage).await.unwrap().region, US);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn a_down_region_hands_its_conversations_to_the_next_region_to_see_them() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new().await;
+        let (us, eu) = (world.broker(US, &[]), world.broker(EU, &[]));
+        let conversation = message("alice", "bob", None);
+        us.route(&conversation, &received(US)).await.unwrap();
+        assert_eq!(
+            eu.route(&conversation, &received(EU)).await.unwrap(),
+            HomeRoute::Forwarded { region: US.into() }
+        );
+
+        // Every broker applies MarkRegionDown
+        for broker in [&us, &eu] {
+            broker.set_region_down(US, true, "ops").await.unwrap();
+        }
+        assert_eq!(eu.route(&conversation, &received(EU)).await.unwrap(), HomeRoute::Local);
+        let home = eu.home(&conversation).await.unwrap();
+        assert_eq!((home.region.as_str(), home.epoch), (EU, 1));
+        // Cached homes in a down region aren't trusted
+        assert_eq!(
+            us.route(&conversation, &received(US)).await.unwrap(),
+            HomeRoute::Forwarded { region: EU.into() }
+        );
+
+        // A broker starting during the outage learns about it from KV
+        let late = world.broker(US, &[]);
+        late.load_down_regions().await.unwrap();
+        assert!(late.is_down(US));
+
+        // Coming back up doesn't move anything back
+        for broker in [&us, &eu] {
+            broker.set_region_down(US, false, "ops").await.unwrap();
+        }
+        let fresh = world.broker(US, &[]);
+        fresh.load_down_regions().await.unwrap();
+        assert!(!fresh.is_down(US));
+        assert_eq!(fresh.home(&conversation).await.unwrap().region, EU);
+
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains(r#"broker_home_assignments_total{region="eu",reason="failover"} 1"#),
+            "{}",
+            rendered
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn rehome_commands_update_the_cache_and_stale_entries_expire_on_the_clock() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new().await;
+        let (us, eu) = (world.broker(US, &[]), world.broker(EU, &[]));
+        let conversation = message("alice", "bob", None);
+        let conversation_id = conversation.conversation_id();
+        us.route(&conversation, &received(US)).await.unwrap();
+        eu.route(&conversation, &received(EU)).await.unwrap();
+
+        assert!(us.rehome(&conversation_id, "ap", "ops").await.is_err());
+
+        // us has applied the re-home, eu hasn't received it yet
+        us.rehome(&conversation_id, EU, "ops").await.unwrap();
+        assert_eq!(
+            us.route(&conversation, &received(US)).await.unwrap(),
+            HomeRoute::Forwarded { region: EU.into() }
+        );
+        world.clock.advance(CACHE_TTL - Duration::from_millis(1));
+        assert_eq!(eu.home_of(&conversation_id, None).await.unwrap().region, US);
+        world.clock.advance(Duration::from_millis(1));
+        let home = eu.home_of(&conversation_id, None).await.unwrap();
+        assert_eq!((home.region.as_str(), home.epoch), (EU, 1));
+        assert_eq!(eu.route(&conversation, &received(EU)).await.unwrap(), HomeRoute::Local);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_home_cache_lookups_total{result="hit"}"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_home_cache_lookups_total{result="miss"}"#), "{}", rendered);
+    }
+}
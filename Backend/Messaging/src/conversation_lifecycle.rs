@@ -0,0 +1,268 @@
+use std::sync::Arc;
+use chrono::Utc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    config::ConversationLifecycleConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Lifecycle event published on `conversation_lifecycle.subject`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LifecycleEvent {
+    pub event_id: String,
+    /// Broker that emitted the event
+    pub broker_id: String,
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+    pub conversation_id: String,
+    pub tenant_id: Option<String>,
+    /// Sender plus recipients of the most recent message
+    pub approximate_participants: u32,
+
+    #[serde(flatten)]
+    pub kind: LifecycleKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LifecycleKind {
+    /// First message routed since the broker started or last evicted the conversation
+    ConversationObserved,
+    /// No traffic for `conversation_lifecycle.idle_after`
+    ConversationIdle {
+        /// Timestamp in milliseconds
+        last_activity_at: i64,
+    },
+    /// Idle GC removed the conversation's state from these stores
+    ConversationStateEvicted {
+        /// Timestamp in milliseconds
+        observed_at: i64,
+        stores: Vec<String>,
+    },
+}
+
+impl LifecycleKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LifecycleKind::ConversationObserved => "observed",
+            LifecycleKind::ConversationIdle { .. } => "idle",
+            LifecycleKind::ConversationStateEvicted { .. } => "evicted",
+        }
+    }
+}
+
+/// Batched publisher of conversation lifecycle events
+///
+/// `ConversationRegistry` decides when events happen: observed once per
+/// tracking cycle, idle once per quiet spell, evicted when GC removes the
+/// state. Events are queued and published every
+/// `conversation_lifecycle.flush_interval` as JSON arrays of at most
+/// `max_batch` events, so short-lived conversations cost one message per
+/// flush rather than one per event. The queue holds at most
+/// `max_pending` events; beyond that, new events are dropped and counted.
+pub struct ConversationLifecycle {
+    broker_id: String,
+    config: ConversationLifecycleConfig,
+    pending: Mutex<Vec<LifecycleEvent>>,
+    metrics: BrokerMetrics,
+}
+
+impl ConversationLifecycle {
+    pub fn new(broker_id: String, config: ConversationLifecycleConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            broker_id,
+            config,
+            pending: Mutex::new(Vec::new()),
+            metrics,
+        }
+    }
+
+    pub fn config(&self) -> &ConversationLifecycleConfig {
+        &self.config
+    }
+
+    pub fn emit(&self, conversation_id: &str, tenant_id: Option<&str>, participants: u32, kind: LifecycleKind) {
+        if !self.config.enabled {
+            return;
+        }
+        let label = kind.as_str();
+        let event = LifecycleEvent {
+            event_id: Uuid::new_v4().to_string(),
+            broker_id: self.broker_id.clone(),
+            timestamp: Utc::now().timestamp_millis(),
+            conversation_id: conversation_id.to_string(),
+            tenant_id: tenant_id.map(str::to_string),
+            approximate_participants: participants,
+            kind,
+        };
+
+        let mut pending = self.pending.lock();
+        if pending.len() >= self.config.max_pending {
+            drop(pending);
+            self.metrics.record_lifecycle_event(label, "dropped");
+            return;
+        }
+        pending.push(event);
+        drop(pending);
+        self.metrics.record_lifecycle_event(label, "queued");
+    }
+
+    /// Take everything queued, in emission order
+    pub fn drain(&self) -> Vec<LifecycleEvent> {
+        std::mem::take(&mut *self.pending.lock())
+    }
+
+    async fn flush(&self, client: &async_nats::Client) {
+        let events = self.drain();
+        for batch in events.chunks(self.config.max_batch.max(1)) {
+            let payload = match serde_json::to_vec(batch) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to encode lifecycle events: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = client.publish(self.config.subject.clone(), payload.into()).await {
+                warn!("Failed to publish {} lifecycle events: {}", batch.len(), e);
+                for event in batch {
+                    self.metrics.record_lifecycle_event(event.kind.as_str(), "publish_failed");
+                }
+            }
+        }
+    }
+
+    pub fn spawn(self: &Arc<Self>, client: async_nats::Client) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let lifecycle = Arc::clone(self);
+        Some(spawn_traced("conversation_lifecycle", TaskContext::new("conversation"), async move {
+            let mut ticker = tokio::time::interval(lifecycle.config.flush_interval);
+            loop {
+                ticker.tick().await;
+                lifecycle.flush(&client).await;
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    fn config(enabled: bool) -> ConversationLifecycleConfig {
+        let mut config = BrokerConfig::load().unwrap().conversation_lifecycle;
+        config.enabled = enabled;
+        config.max_batch = 2;
+        config.max_pending = 3;
+        config
+    }
+
+    fn lifecycle(enabled: bool) -> ConversationLifecycle {
+        ConversationLifecycle::new("broker-1".into(), config(enabled), BrokerMetrics::new().unwrap())
+    }
+
+    #[test]
+    fn events_serialize_flat_with_an_event_tag() {
+        let lifecycle = lifecycle(true);
+        lifecycle.emit(
+            "group_team",
+            Some("acme"),
+            4,
+            LifecycleKind::ConversationStateEvicted {
+                observed_at: 10,
+                stores: vec!["sequences".into()],
+            },
+        );
+        let event = lifecycle.drain().remove(0);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "conversation_state_evicted");
+        assert_eq!(json["conversation_id"], "group_team");
+        assert_eq!(json["tenant_id"], "acme");
+        assert_eq!(json["approximate_participants"], 4);
+        assert_eq!(json["observed_at"], 10);
+        assert_eq!(json["stores"], serde_json::json!(["sequences"]));
+
+        let parsed: LifecycleEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.kind, event.kind);
+        assert_eq!(parsed.event_id, event.event_id);
+    }
+
+    #[test]
+    fn disabled_lifecycle_queues_nothing() {
+        let lifecycle = lifecycle(false);
+        lifecycle.emit("group_team", None, 2, LifecycleKind::ConversationObserved);
+        assert!(lifecycle.drain().is_empty());
+    }
+
+    #[test]
+    fn the_queue_is_bounded_and_drains_in_order() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let drained = metrics::with_local_recorder(&recorder, || {
+            let lifecycle = lifecycle(true);
+            for i in 0..5 {
+                lifecycle.emit(&format!("conv-{}", i), None, 2, LifecycleKind::ConversationObserved);
+            }
+            lifecycle.drain()
+        });
+
+        let ids: Vec<&str> = drained.iter().map(|event| event.conversation_id.as_str()).collect();
+        assert_eq!(ids, vec!["conv-0", "conv-1", "conv-2"]);
+        let rendered = handle.render();
+        for line in [
+            r#"broker_conversation_lifecycle_events_total{event="observed",outcome="queued"} 3"#,
+            r#"broker_conversation_lifecycle_events_total{event="observed",outcome="dropped"} 2"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    /// Runs against NATS at `NATS_URL` (default `localhost:4222`):
+    /// `cargo test -- --ignored conversation_lifecycle`
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn flushes_publish_batches_of_at_most_max_batch() {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let client = async_nats::connect(url).await.unwrap();
+        let mut config = config(true);
+        config.subject = format!("lifecycle-test-{}", Uuid::new_v4().simple());
+        config.max_pending = 100;
+        let mut batches = client.subscribe(config.subject.clone()).await.unwrap();
+        client.flush().await.unwrap();
+
+        let lifecycle = ConversationLifecycle::new("broker-1".into(), config, BrokerMetrics::new().unwrap());
+        for i in 0..5 {
+            lifecycle.emit(&format!("conv-{}", i), None, 2, LifecycleKind::ConversationObserved);
+        }
+        lifecycle.flush(&client).await;
+        client.flush().await.unwrap();
+
+        let mut seen = Vec::new();
+        for expected in [2, 2, 1] {
+            let message = tokio::time::timeout(Duration::from_secs(2), batches.next())
+                .await
+                .unwrap()
+                .unwrap();
+            let batch: Vec<LifecycleEvent> = serde_json::from_slice(&message.payload).unwrap();
+            assert_eq!(batch.len(), expected);
+            seen.extend(batch.into_iter().map(|event| event.conversation_id));
+        }
+        assert_eq!(seen, (0..5).map(|i| format!("conv-{}", i)).collect::<Vec<_>>());
+
+        // Nothing left to publish
+        lifecycle.flush(&client).await;
+        assert!(tokio::time::timeout(Duration::from_millis(200), batches.next()).await.is_err());
+    }
+}
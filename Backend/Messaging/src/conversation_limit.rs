@@ -0,0 +1,436 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use tracing::debug;
+
+use crate::{
+    api::{
+        proto::{DeliveryFrame, MessageDigest},
+        subscriptions::SubscriptionRegistry,
+    },
+    clock::{SharedClock, SystemClock},
+    config::ConversationLimitConfig,
+    config_watch::ConfigWatcher,
+    message::types::{MessageEnvelope, Priority},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// What fanout should do with a message that passed the per-user limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConversationAdmission {
+    Deliver,
+    /// Held for the conversation's next digest frame; skip regular fanout
+    Digested,
+}
+
+#[derive(Default)]
+struct PendingDigest {
+    members: Vec<String>,
+    envelopes: Vec<Vec<u8>>,
+    message_count: u32,
+    first_sequence: Option<u64>,
+    last_sequence: Option<u64>,
+}
+
+struct ConversationWindow {
+    started: Instant,
+    count: u32,
+    pending: PendingDigest,
+    /// Totals for the compression ratio of the current window
+    digested: u64,
+    frames: u64,
+}
+
+impl ConversationWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            count: 0,
+            pending: PendingDigest::default(),
+            digested: 0,
+            frames: 0,
+        }
+    }
+
+    fn in_digest_mode(&self) -> bool {
+        self.digested > 0
+    }
+}
+
+/// Per-conversation message cap that downgrades floods to digests
+///
+/// Applied after the per-user limits. Once a conversation exceeds its cap
+/// for the current window, further messages are accepted but held and sent
+/// to members as one digest frame every `digest_interval`. High-priority
+/// messages always go through. The cap comes from the tenant override, then
+/// the largest group-size bucket the conversation reaches, then the default.
+/// Caps are reloadable; the digest interval takes a restart.
+pub struct ConversationRateLimiter {
+    config: ArcSwap<ConversationLimitConfig>,
+    windows: DashMap<String, ConversationWindow>,
+    subscriptions: Arc<SubscriptionRegistry>,
+    next_digest: AtomicU64,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ConversationRateLimiter {
+    pub fn new(
+        config: ConversationLimitConfig,
+        subscriptions: Arc<SubscriptionRegistry>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            config: ArcSwap::from_pointee(config),
+            windows: DashMap::new(),
+            subscriptions,
+            next_digest: AtomicU64::new(0),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let limiter = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            limiter.config.store(Arc::new(config.conversation_limit.clone()));
+        });
+    }
+
+    /// Count a message against its conversation; `members` are its recipients
+    pub fn admit(&self, envelope: &MessageEnvelope, members: &[String]) -> ConversationAdmission {
+        let config = self.config.load();
+        if !config.enabled || envelope.priority == Priority::High {
+            return ConversationAdmission::Deliver;
+        }
+
+        let limit = limit_for(&config, envelope.tenant_id.as_deref(), members.len());
+        let now = self.clock.now_instant();
+        let mut window = self
+            .windows
+            .entry(envelope.conversation_id())
+            .or_insert_with(|| ConversationWindow::new(now));
+
+        if now.duration_since(window.started) >= config.window {
+            // Held messages still go out with the next digest
+            self.close_window(&mut window);
+            window.started = now;
+            window.count = 0;
+        }
+
+        window.count += 1;
+        if window.count <= limit {
+            return ConversationAdmission::Deliver;
+        }
+
+        let pending = &mut window.pending;
+        if pending.envelopes.len() < config.digest_max_messages {
+            match serde_json::to_vec(envelope) {
+                Ok(bytes) => pending.envelopes.push(bytes),
+                Err(e) => debug!("Failed to encode {} for digest: {}", envelope.message_id, e),
+            }
+        }
+        pending.message_count += 1;
+        if let Some(sequence) = envelope.sequence {
+            pending.first_sequence.get_or_insert(sequence);
+            pending.last_sequence = Some(sequence);
+        }
+        pending.members = members.to_vec();
+        window.digested += 1;
+
+        self.metrics.record_conversation_digested();
+        ConversationAdmission::Digested
+    }
+
+    pub fn spawn_digest_flusher(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let limiter = Arc::clone(self);
+        spawn_traced("conversation_digest", TaskContext::new("conversation_limit"), async move {
+            let mut ticker = tokio::time::interval(limiter.config.load().digest_interval);
+            loop {
+                ticker.tick().await;
+                limiter.flush();
+            }
+        })
+    }
+
+    /// Send pending digests and forget conversations idle past their window
+    pub fn flush(&self) {
+        let now = self.clock.now_instant();
+        let mut in_digest_mode = 0;
+        let window_length = self.config.load().window;
+
+        self.windows.retain(|conversation_id, window| {
+            if window.pending.message_count > 0 {
+                let pending = std::mem::take(&mut window.pending);
+                self.send_digest(conversation_id, pending);
+                window.frames += 1;
+            }
+
+            if now.duration_since(window.started) < window_length {
+                if window.in_digest_mode() {
+                    in_digest_mode += 1;
+                }
+                return true;
+            }
+            self.close_window(window);
+            false
+        });
+
+        self.metrics.update_conversations_in_digest(in_digest_mode);
+    }
+
+    fn send_digest(&self, conversation_id: &str, pending: PendingDigest) {
+        let id = self.next_digest.fetch_add(1, Ordering::Relaxed);
+        let frame = DeliveryFrame {
+            message_id: format!("digest:{}:{}", conversation_id, id),
+            from: String::new(),
+            envelope: Vec::new(),
+            timestamp: self.clock.now_millis(),
+            read_horizon: None,
+            digest: Some(MessageDigest {
+                conversation_id: conversation_id.to_string(),
+                message_count: pending.message_count,
+                envelopes: pending.envelopes,
+                first_sequence: pending.first_sequence.unwrap_or(0),
+                last_sequence: pending.last_sequence.unwrap_or(0),
+            }),
+            keepalive: None,
+        };
+        for member in &pending.members {
+            self.subscriptions.deliver(member, &frame);
+        }
+        debug!(
+            "Digest of {} messages sent to {} members of {}",
+            pending.message_count,
+            pending.members.len(),
+            conversation_id
+        );
+    }
+
+    /// Record the window's compression ratio once its digests are out
+    fn close_window(&self, window: &mut ConversationWindow) {
+        if window.digested > 0 && window.frames > 0 {
+            self.metrics
+                .record_conversation_digest_ratio(window.digested as f64 / window.frames as f64);
+        }
+        window.digested = 0;
+        window.frames = 0;
+    }
+}
+
+fn limit_for(config: &ConversationLimitConfig, tenant_id: Option<&str>, group_size: usize) -> u32 {
+    if let Some(limit) = tenant_id.and_then(|tenant| config.tenants.get(tenant)) {
+        return *limit;
+    }
+    config
+        .group_size_buckets
+        .iter()
+        .filter(|bucket| group_size >= bucket.min_members)
+        .max_by_key(|bucket| bucket.min_members)
+        .map_or(config.messages_per_window, |bucket| bucket.messages_per_window)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::{BrokerConfig, GroupSizeLimit},
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    const GROUP: &str = "group_flash";
+    const LIMIT: u32 = 10;
+    const WINDOW: Duration = Duration::from_secs(60);
+    const DIGEST_INTERVAL: Duration = Duration::from_secs(5);
+
+    fn config() -> ConversationLimitConfig {
+        let mut config = BrokerConfig::load().unwrap().conversation_limit;
+        config.enabled = true;
+        config.messages_per_window = LIMIT;
+        config.window = WINDOW;
+        config.digest_interval = DIGEST_INTERVAL;
+        config.digest_max_messages = 20;
+        config.tenants = HashMap::new();
+        config.group_size_buckets = Vec::new();
+        config
+    }
+
+    fn limiter() -> (Arc<ConversationRateLimiter>, Arc<SubscriptionRegistry>, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let api = BrokerConfig::load().unwrap().api;
+        let subscriptions = Arc::new(SubscriptionRegistry::new(&api, BrokerMetrics::new().unwrap()).with_clock(clock.clone()));
+        let limiter = ConversationRateLimiter::new(config(), subscriptions.clone(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        (Arc::new(limiter), subscriptions, clock)
+    }
+
+    fn message(message_type: MessageType, from: &str, to: &str, sequence: u64) -> MessageEnvelope {
+        let mut envelope = MessageEnvelope::new(
+            message_type,
+            from.into(),
+            vec![to.into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        );
+        envelope.sequence = Some(sequence);
+        envelope
+    }
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn subscribe(subscriptions: &Arc<SubscriptionRegistry>, user_id: &str) -> mpsc::Receiver<Result<DeliveryFrame, tonic::Status>> {
+        subscriptions.open(user_id.into(), "gw-1".into()).1.into_inner()
+    }
+
+    async fn digests(stream: &mut mpsc::Receiver<Result<DeliveryFrame, tonic::Status>>) -> Vec<MessageDigest> {
+        // Each stream's forwarder task shares this test's single thread
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        std::iter::from_fn(|| stream.try_recv().ok())
+            .filter_map(|frame| frame.unwrap().digest)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_flood_is_digested_at_the_configured_cadence() {
+        let (limiter, subscriptions, clock) = limiter();
+        let mut bob = subscribe(&subscriptions, "bob");
+        let mut dave = subscribe(&subscriptions, "dave");
+        let group = members(&["bob", "carol"]);
+        let dm = members(&["dave"]);
+
+        let mut sequence = 0;
+        let mut digested = 0;
+        let mut frames = Vec::new();
+        for second in 1..=20u32 {
+            for _ in 0..100 {
+                sequence += 1;
+                let flood = message(MessageType::GroupMessage, "alice", GROUP, sequence);
+                if limiter.admit(&flood, &group) == ConversationAdmission::Digested {
+                    digested += 1;
+                }
+            }
+            // One DM per digest interval stays well under the window's limit
+            if second % DIGEST_INTERVAL.as_secs() as u32 == 1 {
+                let quiet = message(MessageType::TextMessage, "erin", "dave", u64::from(second));
+                assert_eq!(limiter.admit(&quiet, &dm), ConversationAdmission::Deliver);
+            }
+
+            clock.advance(Duration::from_secs(1));
+            if second % DIGEST_INTERVAL.as_secs() as u32 == 0 {
+                limiter.flush();
+                frames.push(digests(&mut bob).await);
+            } else {
+                assert!(digests(&mut bob).await.is_empty());
+            }
+        }
+
+        assert_eq!(digested, 2000 - LIMIT);
+        // One digest per interval, each covering that interval's held messages
+        assert_eq!(frames.len(), 4);
+        for (interval, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.len(), 1);
+            let digest = &frame[0];
+            assert_eq!(digest.conversation_id, GROUP);
+            let expected = if interval == 0 { 500 - LIMIT } else { 500 };
+            assert_eq!(digest.message_count, expected);
+            assert_eq!(digest.envelopes.len(), 20);
+            assert_eq!(digest.last_sequence - digest.first_sequence + 1, u64::from(expected));
+        }
+        assert!(digests(&mut dave).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn high_priority_messages_skip_the_digest() {
+        let (limiter, _, _) = limiter();
+        let group = members(&["bob", "carol"]);
+        for sequence in 0..u64::from(LIMIT) {
+            let envelope = message(MessageType::GroupMessage, "alice", GROUP, sequence);
+            assert_eq!(limiter.admit(&envelope, &group), ConversationAdmission::Deliver);
+        }
+
+        let mut urgent = message(MessageType::GroupMessage, "alice", GROUP, 100);
+        urgent.priority = Priority::High;
+        assert_eq!(limiter.admit(&urgent, &group), ConversationAdmission::Deliver);
+        let normal = message(MessageType::GroupMessage, "alice", GROUP, 101);
+        assert_eq!(limiter.admit(&normal, &group), ConversationAdmission::Digested);
+    }
+
+    #[tokio::test]
+    async fn a_new_window_delivers_again() {
+        let (limiter, subscriptions, clock) = limiter();
+        let mut bob = subscribe(&subscriptions, "bob");
+        let group = members(&["bob"]);
+        for sequence in 0..=u64::from(LIMIT) {
+            limiter.admit(&message(MessageType::GroupMessage, "alice", GROUP, sequence), &group);
+        }
+
+        clock.advance(WINDOW);
+        let next = message(MessageType::GroupMessage, "alice", GROUP, 50);
+        assert_eq!(limiter.admit(&next, &group), ConversationAdmission::Deliver);
+        // The message held in the old window still goes out
+        limiter.flush();
+        assert_eq!(digests(&mut bob).await[0].message_count, 1);
+    }
+
+    #[test]
+    fn tenant_caps_win_over_group_size_buckets() {
+        let mut config = config();
+        config.group_size_buckets = vec![
+            GroupSizeLimit {
+                min_members: 50,
+                messages_per_window: 100,
+            },
+            GroupSizeLimit {
+                min_members: 500,
+                messages_per_window: 30,
+            },
+        ];
+        config.tenants.insert("acme".into(), 5);
+
+        assert_eq!(limit_for(&config, None, 10), LIMIT);
+        assert_eq!(limit_for(&config, None, 50), 100);
+        assert_eq!(limit_for(&config, None, 1000), 30);
+        assert_eq!(limit_for(&config, Some("acme"), 1000), 5);
+        assert_eq!(limit_for(&config, Some("other"), 1000), 30);
+    }
+
+    #[tokio::test]
+    async fn disabled_limits_deliver_everything() {
+        let (limiter, _, _) = limiter();
+        let mut config = config();
+        config.enabled = false;
+        limiter.config.store(Arc::new(config));
+
+        let group = members(&["bob"]);
+        for sequence in 0..100 {
+            let envelope = message(MessageType::GroupMessage, "alice", GROUP, sequence);
+            assert_eq!(limiter.admit(&envelope, &group), ConversationAdmission::Deliver);
+        }
+    }
+}
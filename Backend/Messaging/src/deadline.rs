@@ -0,0 +1,260 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+use tonic::{metadata::MetadataMap, Status};
+
+use crate::clock::{SharedClock, SystemClock};
+
+/// Metadata key a gRPC client's deadline arrives in
+pub const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// When the client of an RPC stops waiting for its answer
+///
+/// Handlers derive one from the request's `grpc-timeout` and pass it into
+/// the work they do, which checks it at natural yield points (between
+/// stages, chunks and stream reads) and wraps downstream calls in `run` so
+/// an in-flight JetStream read or resolver call is dropped the moment the
+/// client is gone. The margin brings the deadline forward so the broker
+/// stops on its own terms, releasing what it reserved, before tonic
+/// abandons the handler future wholesale.
+#[derive(Clone)]
+pub struct Deadline {
+    at: Option<Instant>,
+    clock: SharedClock,
+}
+
+impl Deadline {
+    /// No deadline: background work and clients that didn't set one
+    pub fn none() -> Self {
+        Self {
+            at: None,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn after(timeout: Duration) -> Self {
+        Self::after_on(SystemClock::shared(), timeout)
+    }
+
+    /// `timeout` from now on `clock`
+    pub fn after_on(clock: SharedClock, timeout: Duration) -> Self {
+        Self {
+            at: Some(clock.now_instant() + timeout),
+            clock,
+        }
+    }
+
+    /// Deadline from `grpc-timeout`, brought forward by `margin`
+    pub fn from_metadata(metadata: &MetadataMap, margin: Duration) -> Self {
+        metadata
+            .get(GRPC_TIMEOUT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_grpc_timeout)
+            .map(|timeout| Self::after(timeout.saturating_sub(margin)))
+            .unwrap_or_else(Self::none)
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.map(|at| at.saturating_duration_since(self.clock.now_instant()))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining().is_some_and(|remaining| remaining.is_zero())
+    }
+
+    /// Fails once the deadline has passed; `stage` names where the work stopped
+    pub fn check(&self, stage: &'static str) -> Result<(), DeadlineExceeded> {
+        if self.expired() {
+            return Err(DeadlineExceeded { stage });
+        }
+        Ok(())
+    }
+
+    /// Run `future` until it finishes or the deadline passes, dropping it in the latter case
+    pub async fn run<F: Future>(&self, stage: &'static str, future: F) -> Result<F::Output, DeadlineExceeded> {
+        let Some(at) = self.at else {
+            return Ok(future.await);
+        };
+        tokio::select! {
+            biased;
+            output = future => Ok(output),
+            _ = self.clock.sleep_until(at) => Err(DeadlineExceeded { stage }),
+        }
+    }
+
+    /// `wanted`, shortened to what's left of the deadline
+    pub fn cap(&self, wanted: Duration) -> Duration {
+        self.remaining().map_or(wanted, |remaining| wanted.min(remaining))
+    }
+}
+
+/// `grpc-timeout` value: up to 8 digits followed by a unit (H, M, S, m, u, n)
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("client deadline exceeded during {stage}")]
+pub struct DeadlineExceeded {
+    pub stage: &'static str,
+}
+
+impl DeadlineExceeded {
+    pub fn to_status(&self) -> Status {
+        Status::deadline_exceeded(self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::clock::SimClock;
+
+    /// A downstream dependency that never answers, counting calls started and abandoned
+    #[derive(Default)]
+    struct SlowDependency {
+        started: AtomicUsize,
+        dropped: AtomicUsize,
+    }
+
+    struct InFlight<'a>(&'a SlowDependency);
+
+    impl Drop for InFlight<'_> {
+        fn drop(&mut self) {
+            self.0.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl SlowDependency {
+        async fn call(&self) {
+            self.started.fetch_add(1, Ordering::SeqCst);
+            let _in_flight = InFlight(self);
+            std::future::pending::<()>().await
+        }
+    }
+
+    /// How a handler walks its stages: check at each yield point, wrap each call in `run`
+    async fn handler(deadline: &Deadline, dependency: &SlowDependency, calls: usize) -> Result<(), DeadlineExceeded> {
+        for _ in 0..calls {
+            deadline.check("stage")?;
+            deadline.run("call", dependency.call()).await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn grpc_timeouts_parse_every_unit() {
+        for (value, expected) in [
+            ("2H", Duration::from_secs(7200)),
+            ("3M", Duration::from_secs(180)),
+            ("10S", Duration::from_secs(10)),
+            ("250m", Duration::from_millis(250)),
+            ("99999999u", Duration::from_micros(99_999_999)),
+            ("5n", Duration::from_nanos(5)),
+        ] {
+            assert_eq!(parse_grpc_timeout(value), Some(expected), "{}", value);
+        }
+        for value in ["", "S", "10", "10s", "-1S", "123456789S", "1.5S"] {
+            assert_eq!(parse_grpc_timeout(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn metadata_deadlines_come_forward_by_the_margin() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(GRPC_TIMEOUT_HEADER, "2S".parse().unwrap());
+        let remaining = Deadline::from_metadata(&metadata, Duration::from_millis(500)).remaining().unwrap();
+        assert!(remaining <= Duration::from_millis(1500) && remaining > Duration::from_millis(1000), "{:?}", remaining);
+
+        // A margin longer than the timeout leaves nothing, not an underflow
+        assert!(Deadline::from_metadata(&metadata, Duration::from_secs(5)).expired());
+
+        // Missing and malformed timeouts mean no deadline
+        assert!(Deadline::from_metadata(&MetadataMap::new(), Duration::ZERO).remaining().is_none());
+        metadata.insert(GRPC_TIMEOUT_HEADER, "soon".parse().unwrap());
+        assert!(Deadline::from_metadata(&metadata, Duration::ZERO).remaining().is_none());
+    }
+
+    #[test]
+    fn deadlines_expire_on_their_clock() {
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(2));
+        assert_eq!(deadline.remaining(), Some(Duration::from_secs(2)));
+        assert_eq!(deadline.cap(Duration::from_millis(500)), Duration::from_millis(500));
+
+        clock.advance(Duration::from_millis(1800));
+        assert_eq!(deadline.cap(Duration::from_millis(500)), Duration::from_millis(200));
+        assert!(deadline.check("read").is_ok());
+
+        clock.advance(Duration::from_millis(200));
+        assert!(deadline.expired());
+        assert_eq!(deadline.cap(Duration::from_millis(500)), Duration::ZERO);
+        let exceeded = deadline.check("read").unwrap_err();
+        assert_eq!(exceeded.stage, "read");
+        assert_eq!(exceeded.to_status().code(), tonic::Code::DeadlineExceeded);
+
+        let none = Deadline::none();
+        assert!(!none.expired() && none.check("read").is_ok());
+        assert_eq!(none.cap(Duration::from_secs(9)), Duration::from_secs(9));
+    }
+
+    #[tokio::test]
+    async fn work_finishing_in_time_is_untouched() {
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(1));
+        assert_eq!(deadline.run("call", async { 7 }).await.unwrap(), 7);
+        assert_eq!(Deadline::none().run("call", async { 8 }).await.unwrap(), 8);
+        assert_eq!(clock.pending(), 0, "no timer is left behind");
+    }
+
+    #[tokio::test]
+    async fn a_slow_call_is_dropped_when_the_deadline_passes_and_nothing_follows() {
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(1));
+        let dependency = SlowDependency::default();
+
+        let (result, ()) = tokio::join!(handler(&deadline, &dependency, 3), async {
+            while clock.pending() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_millis(999));
+            tokio::task::yield_now().await;
+            assert_eq!(dependency.dropped.load(Ordering::SeqCst), 0, "still within the deadline");
+            clock.advance(Duration::from_millis(1));
+        });
+
+        assert_eq!(result.unwrap_err().stage, "call");
+        assert_eq!(dependency.started.load(Ordering::SeqCst), 1);
+        assert_eq!(dependency.dropped.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn an_expired_deadline_issues_no_calls() {
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        let dependency = SlowDependency::default();
+
+        assert_eq!(handler(&deadline, &dependency, 3).await.unwrap_err().stage, "stage");
+        assert_eq!(dependency.started.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+};
+use lru::LruCache;
+
+use crate::{
+    cache_shrink::{lru_entry_bytes, Reclaimed, ShrinkableCache},
+    config::RoutingConfig,
+    lock_metrics::{Mutex, NamedLock},
+};
+
+/// Bloom bits per expected entry; about 1% false positives with `HASHES`
+const BITS_PER_ENTRY: usize = 10;
+const HASHES: u64 = 7;
+/// Shrinking never takes the exact tier below this many entries
+const MIN_EXACT_ENTRIES: usize = 1024;
+
+struct Bloom {
+    bits: Vec<u64>,
+    inserted: usize,
+}
+
+impl Bloom {
+    fn new(capacity: usize) -> Self {
+        Self {
+            bits: vec![0; (capacity.max(1) * BITS_PER_ENTRY).div_ceil(64)],
+            inserted: 0,
+        }
+    }
+
+    fn insert(&mut self, id: &str) {
+        for bit in positions(id, self.bits.len()) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.inserted += 1;
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        positions(id, self.bits.len()).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+}
+
+/// Bits of `id` in a filter of `words` words; double hashing, bit `i` is `h1 + i * h2`
+fn positions(id: &str, words: usize) -> impl Iterator<Item = usize> {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+    let len = words as u64 * 64;
+    (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+}
+
+struct DedupState {
+    current: Bloom,
+    previous: Option<Bloom>,
+    /// Generation of `current`; `previous` is the one before
+    generation: u64,
+    /// Exact IDs with the generation they were inserted in, hottest last
+    exact: LruCache<String, u64>,
+    exact_bytes: usize,
+}
+
+impl DedupState {
+    fn oldest_live(&self) -> u64 {
+        match self.previous {
+            Some(_) => self.generation.saturating_sub(1),
+            None => self.generation,
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<usize> {
+        let (id, _) = self.exact.pop_lru()?;
+        let bytes = lru_entry_bytes::<u64>(&id);
+        self.exact_bytes -= bytes;
+        Some(bytes)
+    }
+}
+
+/// Recently seen message IDs, so redelivered ingress is dropped once
+///
+/// Two bloom generations of `routing.bloom_filter_size` IDs each answer
+/// "definitely new" without touching the exact tier, an LRU of
+/// `routing.dedup_cache_size` IDs tagged with their generation. An ID is a
+/// duplicate only if a bloom generation may hold it and the exact tier has
+/// it from a live generation, so a bloom false positive or a forgotten
+/// entry lets a duplicate through but a unique ID is never dropped.
+/// Once `current` fills it becomes `previous` and the old `previous` goes.
+///
+/// Shrinking drops `previous` early, which only raises the lower bound of
+/// the dedup window, along with the exact entries it covered, then halves
+/// the exact tier keeping its most recently seen half. `restore` lets the
+/// exact tier grow back; the bloom regains its second generation at the
+/// next rotation.
+pub struct DedupSet {
+    state: Mutex<DedupState>,
+    generation_capacity: usize,
+    exact_capacity: usize,
+}
+
+impl DedupSet {
+    pub fn new(routing: &RoutingConfig) -> Self {
+        let exact_capacity = routing.dedup_cache_size.max(MIN_EXACT_ENTRIES);
+        Self {
+            state: Mutex::named(
+                "dedup",
+                DedupState {
+                    current: Bloom::new(routing.bloom_filter_size),
+                    previous: None,
+                    generation: 0,
+                    exact: LruCache::new(NonZeroUsize::new(exact_capacity).unwrap()),
+                    exact_bytes: 0,
+                },
+            ),
+            generation_capacity: routing.bloom_filter_size.max(1),
+            exact_capacity,
+        }
+    }
+
+    /// Record `id`; false if it was already seen
+    pub fn insert(&self, id: &str) -> bool {
+        let mut state = self.state.lock();
+        let maybe_seen = state.current.contains(id) || state.previous.as_ref().is_some_and(|bloom| bloom.contains(id));
+        if maybe_seen {
+            let oldest_live = state.oldest_live();
+            if state.exact.get(id).is_some_and(|&generation| generation >= oldest_live) {
+                return false;
+            }
+        }
+
+        state.current.insert(id);
+        let generation = state.generation;
+        let key = id.to_string();
+        let added = lru_entry_bytes::<u64>(&key);
+        if let Some((old, _)) = state.exact.push(key, generation) {
+            state.exact_bytes -= lru_entry_bytes::<u64>(&old);
+        }
+        state.exact_bytes += added;
+
+        if state.current.inserted >= self.generation_capacity {
+            let next = Bloom::new(self.generation_capacity);
+            state.previous = Some(std::mem::replace(&mut state.current, next));
+            state.generation += 1;
+        }
+        true
+    }
+}
+
+impl ShrinkableCache for DedupSet {
+    fn cache_name(&self) -> &'static str {
+        "dedup"
+    }
+
+    fn footprint(&self) -> usize {
+        let state = self.state.lock();
+        state.current.bytes() + state.previous.as_ref().map_or(0, Bloom::bytes) + state.exact_bytes
+    }
+
+    fn shrink(&self) -> Reclaimed {
+        let mut state = self.state.lock();
+        let mut reclaimed = Reclaimed::default();
+
+        if let Some(previous) = state.previous.take() {
+            reclaimed.bytes += previous.bytes();
+            // Unreachable now that their generation is gone
+            let oldest_live = state.oldest_live();
+            let stale: Vec<String> = state
+                .exact
+                .iter()
+                .filter(|(_, &generation)| generation < oldest_live)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in stale {
+                if state.exact.pop(&id).is_some() {
+                    let bytes = lru_entry_bytes::<u64>(&id);
+                    state.exact_bytes -= bytes;
+                    reclaimed.entries += 1;
+                    reclaimed.bytes += bytes;
+                }
+            }
+        }
+
+        let target = (state.exact.cap().get() / 2).max(MIN_EXACT_ENTRIES);
+        while state.exact.len() > target {
+            let Some(bytes) = state.pop_lru() else {
+                break;
+            };
+            reclaimed.entries += 1;
+            reclaimed.bytes += bytes;
+        }
+        state.exact.resize(NonZeroUsize::new(target).unwrap());
+        reclaimed
+    }
+
+    fn restore(&self) {
+        self.state
+            .lock()
+            .exact
+            .resize(NonZeroUsize::new(self.exact_capacity).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    fn dedup(bloom_filter_size: usize, dedup_cache_size: usize) -> DedupSet {
+        let mut routing = BrokerConfig::load().unwrap().routing;
+        routing.bloom_filter_size = bloom_filter_size;
+        routing.dedup_cache_size = dedup_cache_size;
+        DedupSet::new(&routing)
+    }
+
+    fn ids(prefix: &str, range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| format!("{}-{}", prefix, i)).collect()
+    }
+
+    /// Shrink, checking the reported bytes against the footprint
+    fn shrink(set: &DedupSet) -> Reclaimed {
+        let before = set.footprint();
+        let entries = set.state.lock().exact.len();
+        let reclaimed = set.shrink();
+        assert_eq!(before - set.footprint(), reclaimed.bytes);
+        assert_eq!(entries - set.state.lock().exact.len(), reclaimed.entries);
+        reclaimed
+    }
+
+    #[test]
+    fn duplicates_are_caught_across_a_rotation() {
+        let set = dedup(500, 2048);
+        let sent = ids("msg", 0..700);
+        for id in &sent {
+            assert!(set.insert(id));
+        }
+        assert_eq!(set.state.lock().generation, 1);
+        for id in &sent {
+            assert!(!set.insert(id), "{} redelivered", id);
+        }
+    }
+
+    #[test]
+    fn shrinking_drops_the_older_generation_and_its_exact_entries() {
+        let set = dedup(500, 2048);
+        let older = ids("older", 0..500);
+        let newer = ids("newer", 0..100);
+        for id in older.iter().chain(&newer) {
+            set.insert(id);
+        }
+
+        let bloom = set.state.lock().previous.as_ref().unwrap().bytes();
+        let reclaimed = shrink(&set);
+        assert_eq!(reclaimed.entries, 500, "only the dropped generation's entries; 100 fit under half");
+        assert_eq!(reclaimed.bytes, bloom + older.iter().map(|id| lru_entry_bytes::<u64>(id)).sum::<usize>());
+
+        for id in &newer {
+            assert!(!set.insert(id), "{} is still in the window", id);
+        }
+        // The window now starts later: these are let through again, never wrongly dropped
+        for id in &older {
+            assert!(set.insert(id));
+        }
+    }
+
+    #[test]
+    fn shrinking_keeps_the_hottest_half_of_the_exact_tier() {
+        let set = dedup(100_000, 4096);
+        let sent = ids("msg", 0..4096);
+        for id in &sent {
+            set.insert(id);
+        }
+        // Redeliveries of the oldest hundred make them the most recently seen
+        for id in &sent[..100] {
+            assert!(!set.insert(id));
+        }
+
+        assert_eq!(shrink(&set).entries, 2048);
+        for id in sent[..100].iter().chain(&sent[2148..]) {
+            assert!(!set.insert(id), "{} kept", id);
+        }
+        assert!(set.insert(&sent[100]), "the coldest are forgotten");
+
+        // Halving stops at the floor
+        assert_eq!(shrink(&set).entries, 1024);
+        assert_eq!(shrink(&set).entries, 0);
+        assert_eq!(set.state.lock().exact.cap().get(), MIN_EXACT_ENTRIES);
+
+        set.restore();
+        assert_eq!(set.state.lock().exact.cap().get(), 4096);
+    }
+
+    #[test]
+    fn no_unique_id_is_dropped_whenever_shrinks_come() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let set = dedup(rng.gen_range(200..2_000), rng.gen_range(1024..4096));
+            let mut sent: Vec<String> = Vec::new();
+            // Messages sent before this may have been forgotten by a shrink
+            let mut shrunk_at = 0;
+
+            for step in 0..20_000 {
+                match rng.gen_range(0..1_000) {
+                    0..=9 => {
+                        shrink(&set);
+                        shrunk_at = sent.len();
+                    }
+                    10..=14 => set.restore(),
+                    15..=299 if !sent.is_empty() => {
+                        let index = sent.len() - 1 - rng.gen_range(0..sent.len().min(256));
+                        let caught = !set.insert(&sent[index]);
+                        // Under 100 messages back is well inside both the
+                        // smallest generation and the exact tier's floor
+                        if index >= shrunk_at && sent.len() - index <= 100 {
+                            assert!(caught, "seed {}: recent {} let through", seed, sent[index]);
+                        }
+                    }
+                    _ => {
+                        let id = format!("msg-{}-{}", seed, step);
+                        assert!(set.insert(&id), "seed {}: unique {} dropped", seed, id);
+                        sent.push(id);
+                    }
+                }
+            }
+        }
+    }
+}
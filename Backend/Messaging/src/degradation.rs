@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use arc_swap::{ArcSwap, Guard};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::{DegradationConfig, DegradationLevels},
+    kind_budget::TrafficKind,
+    message::types::Priority,
+    metrics::BrokerMetrics,
+    path_override::{PathContext, PathFeature, PathOverrides},
+    task::{spawn_traced, TaskContext},
+};
+
+/// Named degradation levels, ordered from least to most degraded
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DegradationLevel {
+    Normal,
+    Conserve,
+    Emergency,
+}
+
+impl DegradationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DegradationLevel::Normal => "normal",
+            DegradationLevel::Conserve => "conserve",
+            DegradationLevel::Emergency => "emergency",
+        }
+    }
+
+    /// Numeric value exported on the `broker_degradation_level` gauge
+    pub fn as_gauge(&self) -> i64 {
+        *self as i64
+    }
+}
+
+/// Behaviors that can be switched off while degraded
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DegradationToggles {
+    /// Drop delivery/read receipts at ingress
+    pub disable_receipts: bool,
+    /// Drop typing indicators at ingress
+    pub disable_typing_fanout: bool,
+    /// Drop presence updates at ingress
+    pub disable_presence_fanout: bool,
+    /// Drop messages with `Priority::Bulk`
+    pub drop_bulk_priority: bool,
+    /// Groups at or above this size are fanned out via broadcast subjects
+    pub force_broadcast_group_size: Option<usize>,
+    /// Budget percentages replacing `kind_budgets.share_percent` per kind at this level
+    pub kind_shares: HashMap<TrafficKind, f64>,
+    /// NAK delay in milliseconds for messages of each kind deferred at this level
+    pub nak_backoff_ms: HashMap<TrafficKind, u64>,
+}
+
+/// Currently active level and its toggles
+#[derive(Debug)]
+pub struct ActiveLevel {
+    pub level: DegradationLevel,
+    pub toggles: DegradationToggles,
+    pub expires_at: Option<Instant>,
+    pub actor: String,
+}
+
+impl ActiveLevel {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+}
+
+/// Runtime switchboard consulted by every degradable behavior
+///
+/// Reads are a single `ArcSwap` load. Non-normal levels revert to `normal`
+/// after the configured TTL unless renewed by setting the level again.
+pub struct DegradationSwitchboard {
+    active: ArcSwap<ActiveLevel>,
+    levels: DegradationLevels,
+    level_ttl: Duration,
+    auto_trigger_on_shed: bool,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl DegradationSwitchboard {
+    pub fn new(config: &DegradationConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        let active = ActiveLevel {
+            level: DegradationLevel::Normal,
+            toggles: config.levels.normal.clone(),
+            expires_at: None,
+            actor: "startup".into(),
+        };
+        metrics.update_degradation_level(DegradationLevel::Normal.as_gauge());
+
+        Self {
+            active: ArcSwap::from_pointee(active),
+            levels: config.levels.clone(),
+            level_ttl: config.level_ttl,
+            auto_trigger_on_shed: config.auto_trigger_on_shed,
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Current level, reverting first if its TTL has passed
+    pub fn current(&self) -> Guard<Arc<ActiveLevel>> {
+        let active = self.active.load();
+        if active.is_expired(self.clock.now_instant()) {
+            drop(active);
+            self.expire();
+            return self.active.load();
+        }
+        active
+    }
+
+    pub fn level(&self) -> DegradationLevel {
+        self.current().level
+    }
+
+    pub fn receipts_enabled(&self) -> bool {
+        !self.current().toggles.disable_receipts
+    }
+
+    pub fn typing_fanout_enabled(&self) -> bool {
+        !self.current().toggles.disable_typing_fanout
+    }
+
+    pub fn presence_fanout_enabled(&self) -> bool {
+        !self.current().toggles.disable_presence_fanout
+    }
+
+    pub fn admits_priority(&self, priority: Priority) -> bool {
+        priority != Priority::Bulk || !self.current().toggles.drop_bulk_priority
+    }
+
+    /// Whether a group of this size must be fanned out via broadcast, after
+    /// any path override for the message
+    pub fn force_broadcast(&self, group_size: usize, path: &PathContext<'_>, overrides: &PathOverrides) -> bool {
+        let forced = self
+            .current()
+            .toggles
+            .force_broadcast_group_size
+            .is_some_and(|threshold| group_size >= threshold);
+        overrides.decide(path, PathFeature::Broadcast, forced)
+    }
+
+    /// Switch to `level`, or renew it if already active
+    pub fn set_level(&self, level: DegradationLevel, actor: &str, reason: &str, ttl: Option<Duration>) {
+        let expires_at = match level {
+            DegradationLevel::Normal => None,
+            _ => Some(self.clock.now_instant() + ttl.unwrap_or(self.level_ttl)),
+        };
+
+        let previous = self.active.swap(Arc::new(ActiveLevel {
+            level,
+            toggles: self.toggles_for(level).clone(),
+            expires_at,
+            actor: actor.to_string(),
+        }));
+
+        self.metrics.update_degradation_level(level.as_gauge());
+        if previous.level != level {
+            self.metrics.record_degradation_level_change(level.as_str());
+            warn!(
+                "Degradation level changed {} -> {} by {}: {}",
+                previous.level.as_str(),
+                level.as_str(),
+                actor,
+                reason
+            );
+        } else {
+            info!("Degradation level {} renewed by {}", level.as_str(), actor);
+        }
+
+        self.audit.record(AuditEntry::new(
+            actor,
+            "degradation.level_set",
+            serde_json::json!({
+                "from": previous.level.as_str(),
+                "to": level.as_str(),
+                "reason": reason,
+                "ttl_seconds": expires_at.map(|_| ttl.unwrap_or(self.level_ttl).as_secs()),
+            }),
+        ));
+    }
+
+    /// Raise the level on load-shedding escalation; never lowers it
+    pub fn escalate_from_shedder(&self, level: DegradationLevel, reason: &str) {
+        if !self.auto_trigger_on_shed || level <= self.level() {
+            return;
+        }
+        self.set_level(level, "load_shedder", reason, None);
+    }
+
+    /// Revert to `normal` if the active level has expired
+    pub fn expire(&self) {
+        let now = self.clock.now_instant();
+        let active = self.active.load();
+        if !active.is_expired(now) {
+            return;
+        }
+
+        let normal = Arc::new(ActiveLevel {
+            level: DegradationLevel::Normal,
+            toggles: self.levels.normal.clone(),
+            expires_at: None,
+            actor: "ttl".into(),
+        });
+
+        // Only the caller that wins the swap reports the revert
+        let previous = self.active.compare_and_swap(&*active, normal);
+        if !Arc::ptr_eq(&*previous, &*active) {
+            return;
+        }
+
+        self.metrics.update_degradation_level(DegradationLevel::Normal.as_gauge());
+        self.metrics.record_degradation_level_change(DegradationLevel::Normal.as_str());
+        info!("Degradation level {} expired, reverted to normal", previous.level.as_str());
+
+        self.audit.record(AuditEntry::new(
+            "ttl",
+            "degradation.level_expired",
+            serde_json::json!({ "from": previous.level.as_str(), "to": "normal" }),
+        ));
+    }
+
+    /// Periodically revert expired levels even when no traffic reads them
+    pub fn spawn_expiry_task(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let switchboard = Arc::clone(self);
+        spawn_traced("degradation_expiry", TaskContext::new("degradation"), async move {
+            loop {
+                switchboard.clock.sleep(Duration::from_secs(1)).await;
+                switchboard.expire();
+            }
+        })
+    }
+
+    fn toggles_for(&self, level: DegradationLevel) -> &DegradationToggles {
+        match level {
+            DegradationLevel::Normal => &self.levels.normal,
+            DegradationLevel::Conserve => &self.levels.conserve,
+            DegradationLevel::Emergency => &self.levels.emergency,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimClock;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn switchboard() -> (DegradationSwitchboard, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let config = DegradationConfig {
+            level_ttl: TTL,
+            auto_trigger_on_shed: true,
+            levels: DegradationLevels::default(),
+        };
+        let switchboard = DegradationSwitchboard::new(&config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        (switchboard, clock)
+    }
+
+    #[test]
+    fn normal_degrades_nothing() {
+        let (switchboard, _) = switchboard();
+        assert_eq!(switchboard.level(), DegradationLevel::Normal);
+        assert!(switchboard.receipts_enabled());
+        assert!(switchboard.typing_fanout_enabled());
+        assert!(switchboard.presence_fanout_enabled());
+        assert!(switchboard.admits_priority(Priority::Bulk));
+    }
+
+    #[test]
+    fn conserve_toggles_take_effect_immediately() {
+        let (switchboard, _) = switchboard();
+        switchboard.set_level(DegradationLevel::Conserve, "oncall", "incident", None);
+
+        assert_eq!(switchboard.level(), DegradationLevel::Conserve);
+        assert!(switchboard.receipts_enabled());
+        assert!(!switchboard.typing_fanout_enabled());
+        assert!(switchboard.presence_fanout_enabled());
+        assert!(!switchboard.admits_priority(Priority::Bulk));
+        assert!(switchboard.admits_priority(Priority::High));
+    }
+
+    #[test]
+    fn emergency_toggles_take_effect_immediately() {
+        let (switchboard, _) = switchboard();
+        switchboard.set_level(DegradationLevel::Emergency, "oncall", "incident", None);
+
+        assert!(!switchboard.receipts_enabled());
+        assert!(!switchboard.typing_fanout_enabled());
+        assert!(!switchboard.presence_fanout_enabled());
+        assert!(!switchboard.admits_priority(Priority::Bulk));
+    }
+
+    #[test]
+    fn level_reverts_to_normal_after_ttl() {
+        let (switchboard, clock) = switchboard();
+        switchboard.set_level(DegradationLevel::Emergency, "oncall", "incident", None);
+
+        clock.advance(TTL - Duration::from_secs(1));
+        assert_eq!(switchboard.level(), DegradationLevel::Emergency);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(switchboard.level(), DegradationLevel::Normal);
+        assert!(switchboard.receipts_enabled());
+        assert_eq!(switchboard.current().actor, "ttl");
+    }
+
+    #[test]
+    fn setting_the_level_again_renews_it() {
+        let (switchboard, clock) = switchboard();
+        switchboard.set_level(DegradationLevel::Conserve, "oncall", "incident", None);
+        clock.advance(TTL / 2);
+        switchboard.set_level(DegradationLevel::Conserve, "oncall", "still degraded", None);
+
+        clock.advance(TTL / 2 + Duration::from_secs(1));
+        assert_eq!(switchboard.level(), DegradationLevel::Conserve);
+    }
+
+    #[test]
+    fn explicit_ttl_overrides_the_default() {
+        let (switchboard, clock) = switchboard();
+        switchboard.set_level(DegradationLevel::Conserve, "oncall", "incident", Some(Duration::from_secs(5)));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(switchboard.level(), DegradationLevel::Normal);
+    }
+
+    #[test]
+    fn normal_never_expires() {
+        let (switchboard, clock) = switchboard();
+        switchboard.set_level(DegradationLevel::Normal, "oncall", "resolved", None);
+        assert!(switchboard.current().expires_at.is_none());
+
+        clock.advance(TTL * 10);
+        assert_eq!(switchboard.current().actor, "oncall");
+    }
+}
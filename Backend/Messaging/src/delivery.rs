@@ -0,0 +1,1049 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+use async_nats::{
+    jetstream::{self, kv},
+    HeaderMap,
+};
+use arc_swap::ArcSwapOption;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::RoutingConfig,
+    conversation::{ConversationRegistry, ConversationRetention},
+    history_cache::HistoryCache,
+    idempotent_append::{self, AppendKey, AppendPurpose},
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+    preview::PreviewExtractor,
+    task::{spawn_traced, TaskContext},
+    user_events::{RecentUserEvents, UserEventKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Pending,
+    /// Recipient offline; held in the offline queue
+    Queued,
+    Delivered,
+    Failed,
+    /// Missed the delivery deadline and went to the fallback channel
+    HandedOff,
+}
+
+impl DeliveryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryState::Pending => "pending",
+            DeliveryState::Queued => "queued",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Failed => "failed",
+            DeliveryState::HandedOff => "handed_off",
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        matches!(self, DeliveryState::Delivered | DeliveryState::Failed | DeliveryState::HandedOff)
+    }
+}
+
+/// Per-recipient delivery status
+///
+/// `handed_off` stays set when a late ack moves the state to `Delivered`,
+/// so the push service can reconcile instead of pushing twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecipientStatus {
+    pub state: DeliveryState,
+    pub handed_off: bool,
+    /// Timestamp in milliseconds
+    pub updated_at: i64,
+}
+
+/// Event published to `nats.delivery_fallback_topic`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryHandOff {
+    pub message_id: String,
+    pub recipient: String,
+    pub from: String,
+    pub conversation_id: String,
+    /// Extracted under the tenant's preview policy; never the payload itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+    pub sequence: Option<u64>,
+    pub reason: String,
+    pub deadline_ms: u64,
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+}
+
+/// A recipient whose deadline hasn't fired, as carried to a successor broker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDeadline {
+    pub message_id: String,
+    pub recipient: String,
+    pub from: String,
+    pub conversation_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+    pub sequence: Option<u64>,
+    pub deadline_ms: u64,
+    /// `Pending` or `Queued`
+    pub state: DeliveryState,
+    /// When the deadline fires, in milliseconds since the epoch
+    pub fires_at: i64,
+}
+
+const SUMMARY_CODEC: VersionedCodec<MessageStatusSummary> = VersionedCodec::new(schema::DELIVERY_SUMMARY, 1, &[]);
+
+/// Per-message counts kept once per-recipient detail is compacted away,
+/// stored under `status.{base64url(message_id)}` in the status bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageStatusSummary {
+    pub message_id: String,
+    pub recipients: usize,
+    pub states: BTreeMap<DeliveryState, usize>,
+    pub handed_off: usize,
+    /// Timestamp in milliseconds
+    pub compacted_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageStatus {
+    /// Still in memory with per-recipient detail
+    Detailed(HashMap<String, RecipientStatus>),
+    /// Compacted to the persisted tier; counts only
+    Summary(MessageStatusSummary),
+    /// Unknown, or past the persisted tier's retention
+    Expired,
+}
+
+/// Message details shared by all of its recipients
+struct MessageRef {
+    from: String,
+    conversation_id: String,
+    sequence: Option<u64>,
+    deadline_ms: Option<u64>,
+    /// Only extracted for messages with a deadline, the ones that can be handed off
+    preview: Option<String>,
+    recipients: Vec<String>,
+}
+
+struct TrackedRecipient {
+    status: RecipientStatus,
+    message: Arc<MessageRef>,
+}
+
+type RecipientKey = (String, String);
+
+/// Messages compacted per batch before yielding to status updates
+const COMPACTION_BATCH: usize = 1000;
+
+/// Delay before retrying a compaction whose summary write failed
+const COMPACTION_RETRY: Duration = Duration::from_secs(5);
+
+struct TimerEntry {
+    at: Instant,
+    seq: u64,
+    key: RecipientKey,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// Tracks per-recipient delivery and hands off recipients that miss their deadline
+///
+/// Deadlines live in a single min-heap driven by one task, so millions of
+/// pending recipients cost one heap entry each rather than a timer task.
+/// Acks don't remove heap entries; stale entries are skipped when they fire.
+///
+/// Per-recipient statuses stay in memory for `routing.delivery_status_retention`
+/// (or until the message's deadline, if later) and are then compacted into a
+/// per-message summary in the status KV bucket. Compaction runs in small
+/// batches and starts early, oldest first, when more than
+/// `routing.delivery_status_max_hot_recipients` statuses are held.
+pub struct DeliveryTracker {
+    recipients: DashMap<RecipientKey, TrackedRecipient>,
+    messages: DashMap<String, Arc<MessageRef>>,
+    timers: Mutex<BinaryHeap<Reverse<TimerEntry>>>,
+    timer_seq: AtomicU64,
+    wake: Notify,
+    compactions: Mutex<BinaryHeap<Reverse<(Instant, String)>>>,
+    jetstream: jetstream::Context,
+    status_kv: kv::Store,
+    fallback_subject: String,
+    previews: Arc<PreviewExtractor>,
+    /// Dropped per conversation as each new message is tracked
+    history_cache: Arc<HistoryCache>,
+    /// Told about deliveries and offline queueing once attached
+    recent_events: ArcSwapOption<RecentUserEvents>,
+    /// Told about every tracked message once attached; weak, as the registry holds the tracker
+    conversations: OnceLock<Weak<ConversationRegistry>>,
+    hot_window: Duration,
+    max_hot_recipients: usize,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl DeliveryTracker {
+    pub fn new(
+        jetstream: jetstream::Context,
+        status_kv: kv::Store,
+        fallback_subject: String,
+        previews: Arc<PreviewExtractor>,
+        history_cache: Arc<HistoryCache>,
+        routing: &RoutingConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            recipients: DashMap::new(),
+            messages: DashMap::new(),
+            timers: Mutex::new(BinaryHeap::new()),
+            timer_seq: AtomicU64::new(0),
+            wake: Notify::new(),
+            compactions: Mutex::new(BinaryHeap::new()),
+            jetstream,
+            status_kv,
+            fallback_subject,
+            previews,
+            history_cache,
+            recent_events: ArcSwapOption::empty(),
+            conversations: OnceLock::new(),
+            hot_window: routing.delivery_status_retention,
+            max_hot_recipients: routing.delivery_status_max_hot_recipients,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn attach_recent_events(&self, events: Arc<RecentUserEvents>) {
+        self.recent_events.store(Some(events));
+    }
+
+    pub(crate) fn attach_conversations(&self, registry: &Arc<ConversationRegistry>) {
+        let _ = self.conversations.set(Arc::downgrade(registry));
+    }
+
+    fn record_event(&self, recipient: &str, kind: UserEventKind, message: &MessageRef, message_id: &str) {
+        if let Some(events) = &*self.recent_events.load() {
+            events.record(recipient, kind, Some(&message.conversation_id), Some(message_id));
+        }
+    }
+
+    /// Start tracking each recipient, arming deadlines when `delivery_deadline_ms` is set
+    pub fn track(&self, envelope: &MessageEnvelope, recipients: &[String]) {
+        let now = self.clock.now_instant();
+        let deadline_ms = envelope.delivery_deadline_ms;
+        if let Some(conversations) = self.conversations.get().and_then(Weak::upgrade) {
+            conversations.record_activity(
+                &envelope.conversation_id(),
+                envelope.tenant_id.as_deref(),
+                recipients.len() as u32 + 1,
+            );
+        }
+        self.history_cache.invalidate(&envelope.conversation_id());
+        let message = Arc::new(MessageRef {
+            from: envelope.from.clone(),
+            conversation_id: envelope.conversation_id(),
+            sequence: envelope.sequence,
+            deadline_ms,
+            preview: deadline_ms.and_then(|_| self.previews.extract(envelope)),
+            recipients: recipients.to_vec(),
+        });
+        self.messages.insert(envelope.message_id.clone(), Arc::clone(&message));
+
+        let deadline = deadline_ms.map(|ms| now + Duration::from_millis(ms));
+        for recipient in recipients {
+            let key = (envelope.message_id.clone(), recipient.clone());
+            self.recipients.insert(
+                key.clone(),
+                TrackedRecipient {
+                    status: RecipientStatus {
+                        state: DeliveryState::Pending,
+                        handed_off: false,
+                        updated_at: self.clock.now_millis(),
+                    },
+                    message: Arc::clone(&message),
+                },
+            );
+            if let Some(at) = deadline {
+                self.schedule(at, key);
+            }
+        }
+
+        // Keep detail until a pending deadline has had its chance to fire
+        let compact_at = deadline.map_or(now + self.hot_window, |at| at.max(now + self.hot_window));
+        self.compactions
+            .lock()
+            .push(Reverse((compact_at, envelope.message_id.clone())));
+        self.metrics.update_delivery_hot_statuses(self.recipients.len());
+    }
+
+    /// Deadlines still armed for recipients that are pending or queued, soonest first
+    pub fn pending_deadlines(&self) -> Vec<PendingDeadline> {
+        let now = self.clock.now_instant();
+        let now_ms = self.clock.now_millis();
+        let mut armed: Vec<(Instant, RecipientKey)> = self
+            .timers
+            .lock()
+            .iter()
+            .map(|entry| (entry.0.at, entry.0.key.clone()))
+            .collect();
+        armed.sort_by_key(|a| a.0);
+
+        let mut seen = HashSet::new();
+        let mut pending = Vec::new();
+        for (at, key) in armed {
+            // Re-armed keys have one heap entry per arming; the earliest wins
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let Some(tracked) = self.recipients.get(&key) else {
+                continue;
+            };
+            if !matches!(tracked.status.state, DeliveryState::Pending | DeliveryState::Queued) {
+                continue;
+            }
+            let message = &tracked.message;
+            let (message_id, recipient) = key;
+            pending.push(PendingDeadline {
+                message_id,
+                recipient,
+                from: message.from.clone(),
+                conversation_id: message.conversation_id.clone(),
+                preview: message.preview.clone(),
+                sequence: message.sequence,
+                deadline_ms: message.deadline_ms.unwrap_or_default(),
+                state: tracked.status.state,
+                fires_at: now_ms + at.saturating_duration_since(now).as_millis() as i64,
+            });
+        }
+        pending
+    }
+
+    /// Track recipients handed over by another broker and re-arm their
+    /// deadlines for the time left, firing overdue ones right away
+    ///
+    /// Messages this broker already tracks are skipped. Returns how many
+    /// recipients were restored.
+    pub fn restore_deadlines(&self, deadlines: Vec<PendingDeadline>) -> usize {
+        let now = self.clock.now_instant();
+        let now_ms = self.clock.now_millis();
+        let mut by_message: HashMap<String, Vec<PendingDeadline>> = HashMap::new();
+        for deadline in deadlines {
+            by_message.entry(deadline.message_id.clone()).or_default().push(deadline);
+        }
+
+        let mut restored = 0;
+        for (message_id, deadlines) in by_message {
+            if self.messages.contains_key(&message_id) {
+                continue;
+            }
+            let first = &deadlines[0];
+            let message = Arc::new(MessageRef {
+                from: first.from.clone(),
+                conversation_id: first.conversation_id.clone(),
+                sequence: first.sequence,
+                deadline_ms: Some(first.deadline_ms),
+                preview: first.preview.clone(),
+                recipients: deadlines.iter().map(|deadline| deadline.recipient.clone()).collect(),
+            });
+            self.messages.insert(message_id.clone(), Arc::clone(&message));
+
+            let mut latest = now;
+            for deadline in deadlines {
+                let at = now + Duration::from_millis((deadline.fires_at - now_ms).max(0) as u64);
+                latest = latest.max(at);
+                let key = (message_id.clone(), deadline.recipient);
+                self.recipients.insert(
+                    key.clone(),
+                    TrackedRecipient {
+                        status: RecipientStatus {
+                            state: deadline.state,
+                            handed_off: false,
+                            updated_at: now_ms,
+                        },
+                        message: Arc::clone(&message),
+                    },
+                );
+                self.schedule(at, key);
+                restored += 1;
+            }
+            self.compactions
+                .lock()
+                .push(Reverse((latest.max(now + self.hot_window), message_id)));
+        }
+        self.metrics.update_delivery_hot_statuses(self.recipients.len());
+        restored
+    }
+
+    /// Gateway delivery ack for one recipient
+    pub fn ack(&self, message_id: &str, recipient: &str) {
+        let key = (message_id.to_string(), recipient.to_string());
+        if let Some(mut tracked) = self.recipients.get_mut(&key) {
+            self.apply_ack(&mut tracked, &key);
+        }
+    }
+
+    /// Acks for many `(message_id, recipient)` pairs, taking each map shard's
+    /// lock once rather than once per pair; returns how many were tracked
+    pub fn ack_batch<'a>(&self, acks: impl IntoIterator<Item = (&'a str, &'a str)>) -> usize {
+        let mut by_shard: HashMap<usize, Vec<RecipientKey>> = HashMap::new();
+        for (message_id, recipient) in acks {
+            let key = (message_id.to_string(), recipient.to_string());
+            by_shard
+                .entry(self.recipients.determine_map(&key))
+                .or_default()
+                .push(key);
+        }
+
+        let mut applied = 0;
+        for (shard, keys) in by_shard {
+            let mut shard = self.recipients.shards()[shard].write();
+            for key in keys {
+                if let Some(tracked) = shard.get_mut(&key) {
+                    self.apply_ack(tracked.get_mut(), &key);
+                    applied += 1;
+                }
+            }
+        }
+        applied
+    }
+
+    fn apply_ack(&self, tracked: &mut TrackedRecipient, (message_id, recipient): &RecipientKey) {
+        if tracked.status.state == DeliveryState::Delivered {
+            return;
+        }
+
+        if tracked.status.handed_off {
+            debug!("Late ack for {} to {} after hand-off", message_id, recipient);
+            self.metrics.record_delivery_late_ack();
+        }
+        tracked.status.state = DeliveryState::Delivered;
+        tracked.status.updated_at = self.clock.now_millis();
+        self.record_event(recipient, UserEventKind::Delivered, &tracked.message, message_id);
+    }
+
+    /// Recipient was offline and the message went to their offline queue
+    pub fn mark_queued(&self, message_id: &str, recipient: &str) {
+        self.set_state(message_id, recipient, DeliveryState::Queued);
+    }
+
+    pub fn mark_failed(&self, message_id: &str, recipient: &str) {
+        self.set_state(message_id, recipient, DeliveryState::Failed);
+    }
+
+    pub fn status(&self, message_id: &str, recipient: &str) -> Option<RecipientStatus> {
+        self.recipients
+            .get(&(message_id.to_string(), recipient.to_string()))
+            .map(|tracked| tracked.status)
+    }
+
+    /// Status of a whole message: in-memory detail first, then the persisted summary
+    pub async fn message_status(&self, message_id: &str) -> Result<MessageStatus, DeliveryStatusError> {
+        if let Some(message) = self.messages.get(message_id).map(|m| Arc::clone(&m)) {
+            let detail = message
+                .recipients
+                .iter()
+                .filter_map(|recipient| {
+                    self.status(message_id, recipient)
+                        .map(|status| (recipient.clone(), status))
+                })
+                .collect();
+            return Ok(MessageStatus::Detailed(detail));
+        }
+
+        let summary = self
+            .status_kv
+            .get(status_key(message_id))
+            .await
+            .map_err(|e| DeliveryStatusError(e.to_string()))?;
+        match summary {
+            Some(bytes) => SUMMARY_CODEC
+                .decode(&bytes)
+                .map(MessageStatus::Summary)
+                .map_err(|e| DeliveryStatusError(e.to_string())),
+            None => Ok(MessageStatus::Expired),
+        }
+    }
+
+    pub fn spawn_timer_task(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let tracker = Arc::clone(self);
+        spawn_traced("delivery_deadlines", TaskContext::new("delivery"), async move {
+            tracker.run_timers().await;
+        })
+    }
+
+    pub fn spawn_compactor(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let tracker = Arc::clone(self);
+        spawn_traced("delivery_status_compactor", TaskContext::new("delivery"), async move {
+            loop {
+                tracker.clock.sleep(Duration::from_secs(1)).await;
+                tracker.compact().await;
+            }
+        })
+    }
+
+    /// Compact due messages, and the oldest ones while over the memory cap
+    pub async fn compact(&self) {
+        loop {
+            let over_cap = self.recipients.len() > self.max_hot_recipients;
+            let batch = self.pop_compactions(over_cap);
+            if batch.is_empty() {
+                break;
+            }
+
+            for message_id in batch {
+                if let Err(e) = self.compact_message(&message_id).await {
+                    warn!("Failed to compact delivery status for {}: {}", message_id, e);
+                    self.compactions
+                        .lock()
+                        .push(Reverse((self.clock.now_instant() + COMPACTION_RETRY, message_id)));
+                    return;
+                }
+            }
+            self.metrics.update_delivery_hot_statuses(self.recipients.len());
+            // Let status updates through between batches
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn pop_compactions(&self, over_cap: bool) -> Vec<String> {
+        let now = self.clock.now_instant();
+        let mut compactions = self.compactions.lock();
+        let mut batch = Vec::new();
+        while batch.len() < COMPACTION_BATCH
+            && compactions
+                .peek()
+                .is_some_and(|entry| over_cap || entry.0 .0 <= now)
+        {
+            batch.push(compactions.pop().unwrap().0 .1);
+        }
+        batch
+    }
+
+    async fn compact_message(&self, message_id: &str) -> Result<(), DeliveryStatusError> {
+        let Some(message) = self.messages.get(message_id).map(|m| Arc::clone(&m)) else {
+            return Ok(());
+        };
+
+        let mut summary = MessageStatusSummary {
+            message_id: message_id.to_string(),
+            recipients: message.recipients.len(),
+            states: BTreeMap::new(),
+            handed_off: 0,
+            compacted_at: self.clock.now_millis(),
+        };
+        for recipient in &message.recipients {
+            if let Some(status) = self.status(message_id, recipient) {
+                *summary.states.entry(status.state).or_default() += 1;
+                summary.handed_off += status.handed_off as usize;
+            }
+        }
+
+        let value = SUMMARY_CODEC
+            .encode(&summary)
+            .map_err(|e| DeliveryStatusError(e.to_string()))?;
+        self.status_kv
+            .put(status_key(message_id), value.into())
+            .await
+            .map_err(|e| DeliveryStatusError(e.to_string()))?;
+
+        // Detail goes only after the summary is durable
+        for recipient in &message.recipients {
+            self.recipients.remove(&(message_id.to_string(), recipient.clone()));
+        }
+        self.messages.remove(message_id);
+        self.metrics.record_delivery_status_compacted();
+        Ok(())
+    }
+
+    fn set_state(&self, message_id: &str, recipient: &str, state: DeliveryState) {
+        let key = (message_id.to_string(), recipient.to_string());
+        let Some(mut tracked) = self.recipients.get_mut(&key) else {
+            return;
+        };
+        if tracked.status.state.is_terminal() {
+            return;
+        }
+        tracked.status.state = state;
+        tracked.status.updated_at = self.clock.now_millis();
+        if state == DeliveryState::Queued {
+            self.record_event(recipient, UserEventKind::Queued, &tracked.message, message_id);
+        }
+    }
+
+    fn schedule(&self, at: Instant, key: RecipientKey) {
+        let seq = self.timer_seq.fetch_add(1, atomic::Ordering::Relaxed);
+        let earliest = {
+            let mut timers = self.timers.lock();
+            timers.push(Reverse(TimerEntry { at, seq, key }));
+            self.metrics.update_delivery_timers(timers.len());
+            timers.peek().map(|entry| entry.0.seq) == Some(seq)
+        };
+        if earliest {
+            self.wake.notify_one();
+        }
+    }
+
+    async fn run_timers(&self) {
+        loop {
+            let next = self.timers.lock().peek().map(|entry| entry.0.at);
+            match next {
+                None => self.wake.notified().await,
+                Some(at) if at > self.clock.now_instant() => {
+                    tokio::select! {
+                        _ = self.clock.sleep_until(at) => {}
+                        _ = self.wake.notified() => {}
+                    }
+                }
+                Some(_) => {
+                    let due = self.pop_due();
+                    for entry in due {
+                        self.fire_deadline(entry.key).await;
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop_due(&self) -> Vec<TimerEntry> {
+        let now = self.clock.now_instant();
+        let mut timers = self.timers.lock();
+        let mut due = Vec::new();
+        while timers.peek().is_some_and(|entry| entry.0.at <= now) {
+            due.push(timers.pop().unwrap().0);
+        }
+        self.metrics.update_delivery_timers(timers.len());
+        due
+    }
+
+    async fn fire_deadline(&self, key: RecipientKey) {
+        let message = {
+            let Some(mut tracked) = self.recipients.get_mut(&key) else {
+                return;
+            };
+            if !matches!(tracked.status.state, DeliveryState::Pending | DeliveryState::Queued) {
+                return;
+            }
+            tracked.status = RecipientStatus {
+                state: DeliveryState::HandedOff,
+                handed_off: true,
+                updated_at: self.clock.now_millis(),
+            };
+            Arc::clone(&tracked.message)
+        };
+
+        let (message_id, recipient) = key.clone();
+        let event = DeliveryHandOff {
+            message_id,
+            recipient,
+            from: message.from.clone(),
+            conversation_id: message.conversation_id.clone(),
+            preview: message.preview.clone(),
+            sequence: message.sequence,
+            reason: "deadline_exceeded".to_string(),
+            deadline_ms: message.deadline_ms.unwrap_or_default(),
+            timestamp: self.clock.now_millis(),
+        };
+
+        if let Err(e) = self.publish_handoff(&event).await {
+            warn!("Failed to publish hand-off for {} to {}: {}", event.message_id, event.recipient, e);
+        } else {
+            self.metrics.record_delivery_handoff();
+        }
+    }
+
+    async fn publish_handoff(&self, event: &DeliveryHandOff) -> Result<(), async_nats::Error> {
+        // One push per recipient even if the hand-off is republished
+        let key = AppendKey {
+            purpose: AppendPurpose::Handoff,
+            message_id: &event.message_id,
+            recipient: &event.recipient,
+        };
+        idempotent_append::append(
+            &self.jetstream,
+            key,
+            self.fallback_subject.clone(),
+            HeaderMap::new(),
+            serde_json::to_vec(event)?.into(),
+            &self.metrics,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+fn status_key(message_id: &str) -> String {
+    format!("status.{}", URL_SAFE_NO_PAD.encode(message_id))
+}
+
+/// Recipients not yet delivered, handed off or failed, including those held
+/// in the offline queue for replay, keep their conversation's state
+impl ConversationRetention for DeliveryTracker {
+    fn retained(&self, idle: &HashSet<String>) -> HashSet<String> {
+        self.recipients
+            .iter()
+            .filter(|tracked| !tracked.status.state.is_terminal())
+            .filter(|tracked| idle.contains(&tracked.message.conversation_id))
+            .map(|tracked| tracked.message.conversation_id.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("delivery status store error: {0}")]
+pub struct DeliveryStatusError(pub String);
+
+/// The tracker tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored delivery`
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::{BrokerConfig, RoutingConfig},
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    const DEADLINE: Duration = Duration::from_secs(30);
+    const HOT_WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn frozen_v1_summaries_still_load() {
+        let fixture: &[u8] = include_bytes!("../fixtures/persist/delivery_summary.v1.bin");
+        for bytes in [fixture, &fixture[5..]] {
+            let summary = SUMMARY_CODEC.decode(bytes).unwrap();
+            assert_eq!(summary.message_id, "m1");
+            assert_eq!(summary.recipients, 3);
+            assert_eq!(summary.states.get(&DeliveryState::Delivered), Some(&2));
+            assert_eq!(summary.states.get(&DeliveryState::HandedOff), Some(&1));
+            assert_eq!(summary.handed_off, 1);
+            assert_eq!(summary.compacted_at, 1_700_000_000_000);
+        }
+    }
+
+    struct Fixture {
+        tracker: DeliveryTracker,
+        clock: Arc<SimClock>,
+        jetstream: jetstream::Context,
+        fallback_stream: String,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            Self::with_routing(|_| {}).await
+        }
+
+        async fn with_routing(tune: impl FnOnce(&mut RoutingConfig)) -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+            let name = format!("delivery_test_{}", Uuid::new_v4().simple());
+            let status_kv = jetstream
+                .create_key_value(kv::Config {
+                    bucket: name.clone(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let fallback_subject = format!("{}.handoff", name);
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: name.clone(),
+                    subjects: vec![fallback_subject.clone()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let mut config = BrokerConfig::load().unwrap();
+            config.routing.delivery_status_retention = HOT_WINDOW;
+            tune(&mut config.routing);
+            let metrics = BrokerMetrics::new().unwrap();
+            let clock = Arc::new(SimClock::new());
+            let tracker = DeliveryTracker::new(
+                jetstream.clone(),
+                status_kv,
+                fallback_subject,
+                Arc::new(PreviewExtractor::new(config.preview)),
+                Arc::new(HistoryCache::new(&config.history_cache, metrics.clone())),
+                &config.routing,
+                metrics,
+            )
+            .with_clock(clock.clone());
+
+            Self {
+                tracker,
+                clock,
+                jetstream,
+                fallback_stream: name,
+            }
+        }
+
+        fn send(&self, recipients: &[&str]) -> String {
+            self.send_with_deadline(recipients, Some(DEADLINE))
+        }
+
+        fn send_with_deadline(&self, recipients: &[&str], deadline: Option<Duration>) -> String {
+            let mut envelope = MessageEnvelope::new(
+                MessageType::TextMessage,
+                "alice".into(),
+                recipients.iter().map(|r| r.to_string()).collect(),
+                EncryptedPayload {
+                    ciphertext: "aGk=".into(),
+                    iv: None,
+                    tag: None,
+                    key_id: None,
+                    content_type: None,
+                },
+            );
+            envelope.delivery_deadline_ms = deadline.map(|deadline| deadline.as_millis() as u64);
+            let recipients: Vec<String> = recipients.iter().map(|r| r.to_string()).collect();
+            self.tracker.track(&envelope, &recipients);
+            envelope.message_id
+        }
+
+        /// One pass of the timer task's loop body
+        async fn fire_due(&self) {
+            for entry in self.tracker.pop_due() {
+                self.tracker.fire_deadline(entry.key).await;
+            }
+        }
+
+        async fn handoffs(&self) -> Vec<DeliveryHandOff> {
+            let mut stream = self.jetstream.get_stream(&self.fallback_stream).await.unwrap();
+            let state = stream.info().await.unwrap().state;
+            let mut handoffs = Vec::new();
+            for sequence in state.first_sequence..=state.last_sequence {
+                if let Ok(raw) = stream.get_raw_message(sequence).await {
+                    let message = async_nats::Message::try_from(raw).unwrap();
+                    handoffs.push(serde_json::from_slice(&message.payload).unwrap());
+                }
+            }
+            handoffs
+        }
+
+        fn state(&self, message_id: &str, recipient: &str) -> RecipientStatus {
+            self.tracker.status(message_id, recipient).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn ack_just_before_the_deadline_prevents_the_handoff() {
+        let fixture = Fixture::new().await;
+        let message_id = fixture.send(&["bob"]);
+
+        fixture.clock.advance(DEADLINE - Duration::from_millis(1));
+        fixture.fire_due().await;
+        fixture.tracker.ack(&message_id, "bob");
+
+        fixture.clock.advance(Duration::from_millis(1));
+        fixture.fire_due().await;
+
+        let status = fixture.state(&message_id, "bob");
+        assert_eq!(status.state, DeliveryState::Delivered);
+        assert!(!status.handed_off);
+        assert!(fixture.handoffs().await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn ack_just_after_the_deadline_keeps_the_handoff_flag() {
+        let fixture = Fixture::new().await;
+        let message_id = fixture.send(&["bob"]);
+
+        fixture.clock.advance(DEADLINE);
+        fixture.fire_due().await;
+        let status = fixture.state(&message_id, "bob");
+        assert_eq!(status.state, DeliveryState::HandedOff);
+        assert!(status.handed_off);
+
+        // The late ack records delivery, and the push service can see it was handed off
+        fixture.tracker.ack(&message_id, "bob");
+        let status = fixture.state(&message_id, "bob");
+        assert_eq!(status.state, DeliveryState::Delivered);
+        assert!(status.handed_off);
+
+        let handoffs = fixture.handoffs().await;
+        assert_eq!(handoffs.len(), 1);
+        assert_eq!(handoffs[0].message_id, message_id);
+        assert_eq!(handoffs[0].recipient, "bob");
+        assert_eq!(handoffs[0].reason, "deadline_exceeded");
+        assert_eq!(handoffs[0].deadline_ms, DEADLINE.as_millis() as u64);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn each_recipient_has_its_own_deadline() {
+        let fixture = Fixture::new().await;
+        let message_id = fixture.send(&["bob", "carol", "dave"]);
+        fixture.tracker.ack(&message_id, "bob");
+        fixture.tracker.mark_queued(&message_id, "carol");
+        fixture.tracker.mark_failed(&message_id, "dave");
+
+        fixture.clock.advance(DEADLINE);
+        fixture.fire_due().await;
+
+        assert_eq!(fixture.state(&message_id, "bob").state, DeliveryState::Delivered);
+        // Queued offline isn't delivered to a device, so it still goes to push
+        assert_eq!(fixture.state(&message_id, "carol").state, DeliveryState::HandedOff);
+        assert_eq!(fixture.state(&message_id, "dave").state, DeliveryState::Failed);
+        let handoffs = fixture.handoffs().await;
+        assert_eq!(handoffs.len(), 1);
+        assert_eq!(handoffs[0].recipient, "carol");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn pending_deadlines_share_one_heap() {
+        let fixture = Fixture::new().await;
+        let recipients: Vec<String> = (0..100_000).map(|i| format!("user-{}", i)).collect();
+        let recipients: Vec<&str> = recipients.iter().map(String::as_str).collect();
+        fixture.send(&recipients);
+
+        assert_eq!(fixture.tracker.timers.lock().len(), 100_000);
+        fixture.clock.advance(DEADLINE - Duration::from_millis(1));
+        assert!(fixture.tracker.pop_due().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn status_queries_cross_the_tier_boundary() {
+        let fixture = Fixture::new().await;
+        let message_id = fixture.send_with_deadline(&["bob", "carol", "dave"], None);
+        fixture.tracker.ack(&message_id, "bob");
+        fixture.tracker.mark_queued(&message_id, "carol");
+
+        let MessageStatus::Detailed(detail) = fixture.tracker.message_status(&message_id).await.unwrap() else {
+            panic!("hot status should have per-recipient detail");
+        };
+        assert_eq!(detail["bob"].state, DeliveryState::Delivered);
+        assert_eq!(detail["carol"].state, DeliveryState::Queued);
+        assert_eq!(detail["dave"].state, DeliveryState::Pending);
+
+        fixture.clock.advance(HOT_WINDOW);
+        fixture.tracker.compact().await;
+        let MessageStatus::Summary(summary) = fixture.tracker.message_status(&message_id).await.unwrap() else {
+            panic!("compacted status should be a summary");
+        };
+        assert_eq!(summary.recipients, 3);
+        assert_eq!(summary.states[&DeliveryState::Delivered], 1);
+        assert_eq!(summary.states[&DeliveryState::Queued], 1);
+        assert_eq!(summary.states[&DeliveryState::Pending], 1);
+        assert!(fixture.tracker.status(&message_id, "bob").is_none());
+
+        assert!(matches!(
+            fixture.tracker.message_status("never-tracked").await.unwrap(),
+            MessageStatus::Expired
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn detail_outlives_the_hot_window_until_the_deadline() {
+        let fixture = Fixture::new().await;
+        let message_id = fixture.send(&["bob"]);
+
+        fixture.clock.advance(HOT_WINDOW);
+        fixture.tracker.compact().await;
+        assert!(matches!(
+            fixture.tracker.message_status(&message_id).await.unwrap(),
+            MessageStatus::Detailed(_)
+        ));
+
+        fixture.clock.advance(DEADLINE - HOT_WINDOW);
+        fixture.fire_due().await;
+        fixture.tracker.compact().await;
+        let MessageStatus::Summary(summary) = fixture.tracker.message_status(&message_id).await.unwrap() else {
+            panic!("status should be compacted after the deadline");
+        };
+        assert_eq!(summary.handed_off, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_million_messages_stay_under_the_hot_cap() {
+        const CAP: usize = 20_000;
+        const COMPACT_EVERY: usize = 10_000;
+        let fixture = Fixture::with_routing(|routing| routing.delivery_status_max_hot_recipients = CAP).await;
+
+        // Nothing is due by age, so only the cap drives compaction
+        let mut first = None;
+        let mut peak = 0;
+        for i in 0..1_000_000 {
+            let message_id = fixture.send_with_deadline(&["bob"], None);
+            first.get_or_insert(message_id);
+            peak = peak.max(fixture.tracker.recipients.len());
+            if (i + 1) % COMPACT_EVERY == 0 {
+                fixture.tracker.compact().await;
+                assert!(fixture.tracker.recipients.len() <= CAP);
+                assert!(fixture.tracker.messages.len() <= CAP);
+            }
+        }
+        assert!(peak <= CAP + COMPACT_EVERY);
+
+        // The oldest went first and are still answerable from the persisted tier
+        assert!(matches!(
+            fixture.tracker.message_status(&first.unwrap()).await.unwrap(),
+            MessageStatus::Summary(_)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn batched_acks_match_single_acks() {
+        let fixture = Fixture::new().await;
+        let first = fixture.send(&["bob", "carol"]);
+        let second = fixture.send(&["bob"]);
+
+        let applied = fixture
+            .tracker
+            .ack_batch([(first.as_str(), "bob"), (first.as_str(), "carol"), ("unknown", "bob")]);
+        assert_eq!(applied, 2);
+        fixture.tracker.ack(&second, "bob");
+        for (message_id, recipient) in [(&first, "bob"), (&first, "carol"), (&second, "bob")] {
+            let status = fixture.tracker.status(message_id, recipient).unwrap();
+            assert_eq!(status.state, DeliveryState::Delivered);
+        }
+        // A repeated ack changes nothing
+        assert_eq!(fixture.tracker.ack_batch([(first.as_str(), "bob")]), 1);
+    }
+}
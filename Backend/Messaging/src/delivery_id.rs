@@ -0,0 +1,179 @@
+use std::{collections::VecDeque, num::NonZeroUsize};
+use async_nats::HeaderMap;
+use lru::LruCache;
+
+use crate::{
+    config::RoutingConfig,
+    integrity::PAYLOAD_CHECKSUM_HEADER,
+    lock_metrics::{Mutex, NamedLock},
+    metrics::BrokerMetrics,
+    shard::shard_for,
+};
+
+/// Stable per-(recipient, message) delivery ID header on egress publishes
+pub const DELIVERY_ID_HEADER: &str = "Broker-Delivery-Id";
+/// Publish attempt for the delivery ID, starting at 1
+pub const DELIVERY_ATTEMPT_HEADER: &str = "Broker-Delivery-Attempt";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryStamp {
+    pub delivery_id: String,
+    pub attempt: u32,
+    /// Payload CRC32C, set by `EgressIntegrity::stamp`
+    pub checksum: Option<u32>,
+}
+
+impl DeliveryStamp {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(DELIVERY_ID_HEADER, self.delivery_id.as_str());
+        headers.insert(DELIVERY_ATTEMPT_HEADER, self.attempt.to_string().as_str());
+        if let Some(checksum) = self.checksum {
+            headers.insert(PAYLOAD_CHECKSUM_HEADER, format!("{:08x}", checksum).as_str());
+        }
+    }
+}
+
+struct RecentDelivery {
+    message_id: String,
+    stamp: DeliveryStamp,
+}
+
+/// Assigns delivery IDs so broker retries reuse the ID of the first attempt
+///
+/// Each shard keeps an LRU of recipients, and each recipient a bounded
+/// queue of recent deliveries. A message seen again for the same recipient
+/// is a retry and keeps its ID with a bumped attempt; anything else gets a
+/// fresh ID. Gateways dedupe on the ID alone.
+pub struct DeliveryIdAssigner {
+    shards: Vec<Mutex<LruCache<String, VecDeque<RecentDelivery>>>>,
+    recent_per_recipient: usize,
+    metrics: BrokerMetrics,
+}
+
+impl DeliveryIdAssigner {
+    pub fn new(config: &RoutingConfig, metrics: BrokerMetrics) -> Self {
+        let recipients_per_shard = NonZeroUsize::new(config.delivery_id_recipients_per_shard.max(1)).unwrap();
+        Self {
+            shards: (0..config.shard_count.max(1))
+                .map(|_| Mutex::named("delivery_id.shard", LruCache::new(recipients_per_shard)))
+                .collect(),
+            recent_per_recipient: config.delivery_id_recent_per_recipient.max(1),
+            metrics,
+        }
+    }
+
+    /// Stamp for publishing `message_id` to `recipient`
+    pub fn stamp(&self, recipient: &str, message_id: &str) -> DeliveryStamp {
+        let shard = shard_for(recipient, self.shards.len());
+        let mut recipients = self.shards[shard].lock();
+
+        if let Some(recent) = recipients.get_mut(recipient) {
+            if let Some(previous) = recent.iter_mut().find(|d| d.message_id == message_id) {
+                previous.stamp.attempt += 1;
+                self.metrics.record_delivery_id("reused");
+                return previous.stamp.clone();
+            }
+        }
+
+        let stamp = DeliveryStamp {
+            delivery_id: uuid::Uuid::new_v4().to_string(),
+            attempt: 1,
+            checksum: None,
+        };
+        let recent = recipients.get_or_insert_mut(recipient.to_string(), VecDeque::new);
+        if recent.len() >= self.recent_per_recipient {
+            recent.pop_front();
+        }
+        recent.push_back(RecentDelivery {
+            message_id: message_id.to_string(),
+            stamp: stamp.clone(),
+        });
+        self.metrics.record_delivery_id("new");
+        stamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    fn assigner(recent_per_recipient: usize, recipients_per_shard: usize) -> DeliveryIdAssigner {
+        let mut routing = BrokerConfig::load().unwrap().routing;
+        routing.shard_count = 4;
+        routing.delivery_id_recent_per_recipient = recent_per_recipient;
+        routing.delivery_id_recipients_per_shard = recipients_per_shard;
+        DeliveryIdAssigner::new(&routing, BrokerMetrics::new().unwrap())
+    }
+
+    fn published(stamp: &DeliveryStamp) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        stamp.apply(&mut headers);
+        headers
+    }
+
+    #[test]
+    fn retry_after_an_unacked_publish_reuses_the_delivery_id() {
+        let assigner = assigner(32, 100);
+
+        // The first publish reached the gateway but its ack timed out
+        let first = published(&assigner.stamp("bob", "m1"));
+        let retry = published(&assigner.stamp("bob", "m1"));
+
+        assert_eq!(
+            first.get(DELIVERY_ID_HEADER).unwrap().as_str(),
+            retry.get(DELIVERY_ID_HEADER).unwrap().as_str()
+        );
+        assert_eq!(first.get(DELIVERY_ATTEMPT_HEADER).unwrap().as_str(), "1");
+        assert_eq!(retry.get(DELIVERY_ATTEMPT_HEADER).unwrap().as_str(), "2");
+        assert!(retry.get(PAYLOAD_CHECKSUM_HEADER).is_none());
+    }
+
+    #[test]
+    fn new_messages_and_other_recipients_get_fresh_ids() {
+        let assigner = assigner(32, 100);
+        let first = assigner.stamp("bob", "m1");
+
+        assert_ne!(assigner.stamp("bob", "m2").delivery_id, first.delivery_id);
+        let carol = assigner.stamp("carol", "m1");
+        assert_ne!(carol.delivery_id, first.delivery_id);
+        assert_eq!(carol.attempt, 1);
+    }
+
+    #[test]
+    fn recent_ids_are_bounded_per_recipient() {
+        let assigner = assigner(3, 100);
+        let first = assigner.stamp("bob", "m0");
+        for i in 1..=3 {
+            assigner.stamp("bob", &format!("m{}", i));
+        }
+
+        // m0 fell out of bob's recent set, so a retry now looks new
+        let late_retry = assigner.stamp("bob", "m0");
+        assert_ne!(late_retry.delivery_id, first.delivery_id);
+        assert_eq!(late_retry.attempt, 1);
+        assert_eq!(assigner.stamp("bob", "m3").attempt, 2);
+    }
+
+    #[test]
+    fn recipients_are_bounded_per_shard() {
+        let assigner = assigner(32, 2);
+        let first = assigner.stamp("user-0", "m1");
+        for i in 1..1_000 {
+            assigner.stamp(&format!("user-{}", i), "m1");
+        }
+
+        assert!(assigner.shards.iter().all(|shard| shard.lock().len() <= 2));
+        assert_ne!(assigner.stamp("user-0", "m1").delivery_id, first.delivery_id);
+    }
+
+    #[test]
+    fn checksums_are_stamped_as_hex() {
+        let stamp = DeliveryStamp {
+            delivery_id: "d1".into(),
+            attempt: 1,
+            checksum: Some(0xbeef),
+        };
+        assert_eq!(published(&stamp).get(PAYLOAD_CHECKSUM_HEADER).unwrap().as_str(), "0000beef");
+    }
+}
@@ -0,0 +1,824 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use async_nats::jetstream::{self, consumer::pull, kv};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backoff::{BackoffPolicy, Jitter},
+    clock::{SharedClock, SystemClock},
+    config::DrReplicationConfig,
+    ingestion_pause::{IngestionPauses, PauseSelector},
+    membership::MembershipCache,
+    metrics::BrokerMetrics,
+    presence::{presence_user, PresenceRecord, PresenceStore},
+    read_horizon::{parse_horizon_key, parse_sequence, ReadHorizonStore},
+    standby::StandbyController,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Actor recorded on pauses applied from the primary region
+const DR_ACTOR: &str = "dr-replication";
+
+/// One replicated state change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeRecord {
+    /// `record: None` means the presence entry was deleted
+    Presence { user_id: String, record: Option<PresenceRecord> },
+    ReadHorizon { user_id: String, conversation_id: String, sequence: u64 },
+    GroupInvalidated { group_id: String },
+    PauseSet { selector: PauseSelector, expires_at: i64 },
+    PauseLifted { selector: PauseSelector },
+}
+
+impl ChangeRecord {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ChangeRecord::Presence { .. } => "presence",
+            ChangeRecord::ReadHorizon { .. } => "read_horizon",
+            ChangeRecord::GroupInvalidated { .. } => "group_invalidated",
+            ChangeRecord::PauseSet { .. } => "pause_set",
+            ChangeRecord::PauseLifted { .. } => "pause_lifted",
+        }
+    }
+}
+
+/// Unit published to the DR subject
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplicationFrame {
+    /// Changes were lost; the replica drops derived caches and a full
+    /// snapshot of every replicated key follows
+    Resync { origin: String, epoch: u64 },
+    Batch {
+        origin: String,
+        epoch: u64,
+        /// Timestamp in milliseconds of the oldest change in the batch
+        oldest_change: i64,
+        records: Vec<ChangeRecord>,
+    },
+}
+
+/// Tails local state and ships it to the disaster-recovery region
+///
+/// Presence and read horizons are followed through KV watches on their
+/// buckets, so nothing is added to the hot path; group invalidations come
+/// from `MembershipCache::subscribe_invalidations`, and ingestion pauses
+/// are diffed against the last shipped set every flush. Changes are batched
+/// into `ReplicationFrame::Batch` publishes on `dr_replication.subject`,
+/// acknowledged by the DR stream.
+///
+/// While the DR link is down changes accumulate up to
+/// `dr_replication.buffer_max_records`. Past that (or if the invalidation
+/// feed lags) the buffer is dropped and, once the link is back, a `Resync`
+/// marker is sent and the watches restart with history, which replays the
+/// current value of every key as the snapshot.
+pub struct StateReplicator {
+    config: DrReplicationConfig,
+    origin: String,
+    dr: jetstream::Context,
+    presence: kv::Store,
+    horizons: kv::Store,
+    memberships: Arc<MembershipCache>,
+    pauses: Arc<IngestionPauses>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl StateReplicator {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: DrReplicationConfig,
+        origin: String,
+        dr: jetstream::Context,
+        presence: kv::Store,
+        horizons: kv::Store,
+        memberships: Arc<MembershipCache>,
+        pauses: Arc<IngestionPauses>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            config,
+            origin,
+            dr,
+            presence,
+            horizons,
+            memberships,
+            pauses,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let replicator = Arc::clone(self);
+        spawn_traced("dr_replicator", TaskContext::new("dr_replication"), async move {
+            let policy = BackoffPolicy::new(Duration::from_millis(500))
+                .max(Duration::from_secs(30))
+                .jitter(Jitter::Equal);
+            let mut epoch = 0;
+            loop {
+                // Every epoch after the first starts with a snapshot behind a resync marker
+                if epoch > 0 {
+                    let mut backoff = policy.iter();
+                    while let Err(e) = replicator.send_resync(epoch).await {
+                        debug!("DR link still down: {}", e);
+                        replicator
+                            .clock
+                            .sleep(backoff.next().unwrap_or(Duration::from_secs(30)))
+                            .await;
+                    }
+                    replicator.metrics.record_dr_resync();
+                    info!("DR replication resynchronizing (epoch {})", epoch);
+                }
+                match replicator.run_epoch(epoch).await {
+                    Ok(()) => warn!("DR replication watches ended; resynchronizing"),
+                    Err(e) => warn!("DR replication epoch {} ended: {}", epoch, e),
+                }
+                epoch += 1;
+            }
+        })
+    }
+
+    /// Replicate until changes are lost or a watch fails
+    async fn run_epoch(&self, epoch: u64) -> Result<(), DrReplicationError> {
+        let presence = self
+            .presence
+            .watch_with_history("presence.>")
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+        let horizons = self
+            .horizons
+            .watch_with_history("readhorizon.>")
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+        let mut changes = presence
+            .map(|entry| entry.ok().and_then(|entry| presence_change(&entry)))
+            .merge(horizons.map(|entry| entry.ok().and_then(|entry| horizon_change(&entry))));
+        let mut invalidations = self.memberships.subscribe_invalidations();
+
+        let mut buffer: VecDeque<(i64, ChangeRecord)> = VecDeque::new();
+        let mut shipped_pauses: HashSet<PauseSelector> = HashSet::new();
+        let flush_every = Duration::from_millis(self.config.flush_interval_ms);
+        let mut next_flush = self.clock.now_instant() + flush_every;
+
+        loop {
+            tokio::select! {
+                change = changes.next() => match change {
+                    Some(Some(record)) => buffer.push_back((self.clock.now_millis(), record)),
+                    Some(None) => {}
+                    None => return Ok(()),
+                },
+                group = invalidations.recv() => match group {
+                    Ok(group_id) => buffer.push_back((
+                        self.clock.now_millis(),
+                        ChangeRecord::GroupInvalidated { group_id },
+                    )),
+                    Err(RecvError::Lagged(missed)) => {
+                        return Err(DrReplicationError::Overflow(format!("{} group invalidations", missed)));
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = self.clock.sleep_until(next_flush) => {
+                    self.diff_pauses(&mut shipped_pauses, &mut buffer);
+                    self.flush(epoch, &mut buffer).await;
+                    next_flush = self.clock.now_instant() + flush_every;
+                }
+            }
+
+            if buffer.len() > self.config.buffer_max_records {
+                self.metrics.update_dr_replication_buffer(0);
+                return Err(DrReplicationError::Overflow(format!("{} buffered changes", buffer.len())));
+            }
+        }
+    }
+
+    fn diff_pauses(&self, shipped: &mut HashSet<PauseSelector>, buffer: &mut VecDeque<(i64, ChangeRecord)>) {
+        let now = self.clock.now_millis();
+        let active = self.pauses.list();
+        let current: HashSet<PauseSelector> = active.iter().map(|pause| pause.selector.clone()).collect();
+        for pause in active {
+            if !shipped.contains(&pause.selector) {
+                buffer.push_back((
+                    now,
+                    ChangeRecord::PauseSet {
+                        selector: pause.selector,
+                        expires_at: pause.expires_at,
+                    },
+                ));
+            }
+        }
+        for selector in shipped.difference(&current) {
+            buffer.push_back((now, ChangeRecord::PauseLifted { selector: selector.clone() }));
+        }
+        *shipped = current;
+    }
+
+    /// Publish buffered changes in batches; stops at the first failure and keeps the rest
+    async fn flush(&self, epoch: u64, buffer: &mut VecDeque<(i64, ChangeRecord)>) {
+        while !buffer.is_empty() {
+            let count = buffer.len().min(self.config.batch_max_records.max(1));
+            let oldest_change = buffer[0].0;
+            let frame = ReplicationFrame::Batch {
+                origin: self.origin.clone(),
+                epoch,
+                oldest_change,
+                records: buffer.iter().take(count).map(|(_, record)| record.clone()).collect(),
+            };
+            if let Err(e) = self.publish(&frame).await {
+                debug!("DR publish failed, {} changes buffered: {}", buffer.len(), e);
+                break;
+            }
+            for (_, record) in buffer.drain(..count) {
+                self.metrics.record_dr_replicated(record.kind(), "sent");
+            }
+        }
+
+        self.metrics.update_dr_replication_buffer(buffer.len());
+        let lag = buffer
+            .front()
+            .map_or(0, |(at, _)| self.clock.now_millis() - at);
+        self.metrics.update_dr_replication_lag("primary", lag as f64 / 1000.0);
+    }
+
+    async fn send_resync(&self, epoch: u64) -> Result<(), DrReplicationError> {
+        self.publish(&ReplicationFrame::Resync {
+            origin: self.origin.clone(),
+            epoch,
+        })
+        .await
+    }
+
+    async fn publish(&self, frame: &ReplicationFrame) -> Result<(), DrReplicationError> {
+        let payload = serde_json::to_vec(frame).map_err(|e| DrReplicationError::Encode(e.to_string()))?;
+        self.dr
+            .publish(self.config.subject.clone(), payload.into())
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+        Ok(())
+    }
+}
+
+fn presence_change(entry: &kv::Entry) -> Option<ChangeRecord> {
+    let user_id = presence_user(&entry.key)?.to_string();
+    let record = match entry.operation {
+        kv::Operation::Put => Some(serde_json::from_slice(&entry.value).ok()?),
+        kv::Operation::Delete | kv::Operation::Purge => None,
+    };
+    Some(ChangeRecord::Presence { user_id, record })
+}
+
+fn horizon_change(entry: &kv::Entry) -> Option<ChangeRecord> {
+    if entry.operation != kv::Operation::Put {
+        return None;
+    }
+    let (user_id, conversation_id) = parse_horizon_key(&entry.key)?;
+    Some(ChangeRecord::ReadHorizon {
+        user_id,
+        conversation_id,
+        sequence: parse_sequence(&entry.value)?,
+    })
+}
+
+/// Applies replicated state on a standby broker in the DR region
+///
+/// Consumes the DR stream through a durable consumer, so frames published
+/// while the broker was down are applied on start. Stops once the broker
+/// is activated: from then on it is the source of truth.
+pub struct StateApplier {
+    config: DrReplicationConfig,
+    dr: jetstream::Context,
+    presence: Arc<PresenceStore>,
+    horizons: Arc<ReadHorizonStore>,
+    memberships: Arc<MembershipCache>,
+    pauses: Arc<IngestionPauses>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl StateApplier {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: DrReplicationConfig,
+        dr: jetstream::Context,
+        presence: Arc<PresenceStore>,
+        horizons: Arc<ReadHorizonStore>,
+        memberships: Arc<MembershipCache>,
+        pauses: Arc<IngestionPauses>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            config,
+            dr,
+            presence,
+            horizons,
+            memberships,
+            pauses,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn spawn(self: &Arc<Self>, standby: Arc<StandbyController>) -> tokio::task::JoinHandle<()> {
+        let applier = Arc::clone(self);
+        spawn_traced("dr_applier", TaskContext::new("dr_replication"), async move {
+            let policy = BackoffPolicy::new(Duration::from_secs(1))
+                .max(Duration::from_secs(30))
+                .jitter(Jitter::Equal);
+            let mut backoff = policy.iter();
+            loop {
+                tokio::select! {
+                    _ = standby.wait_active() => {
+                        info!("Broker active; DR state applier stopped");
+                        return;
+                    }
+                    result = applier.consume() => {
+                        if let Err(e) = result {
+                            warn!("DR state consumer failed: {}", e);
+                        }
+                    }
+                }
+                applier.clock.sleep(backoff.next().unwrap_or(Duration::from_secs(30))).await;
+            }
+        })
+    }
+
+    async fn consume(&self) -> Result<(), DrReplicationError> {
+        let stream = self
+            .dr
+            .get_stream(&self.config.stream)
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+        let messages = stream
+            .get_or_create_consumer(
+                &self.config.consumer,
+                pull::Config {
+                    durable_name: Some(self.config.consumer.clone()),
+                    filter_subject: self.config.subject.clone(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?
+            .messages()
+            .await
+            .map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+        tokio::pin!(messages);
+
+        while let Some(message) = messages.next().await {
+            let message = message.map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+            match serde_json::from_slice::<ReplicationFrame>(&message.payload) {
+                Ok(frame) => self.apply(frame).await?,
+                Err(e) => warn!("Skipping malformed DR frame: {}", e),
+            }
+            message.ack().await.map_err(|e| DrReplicationError::Nats(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    async fn apply(&self, frame: ReplicationFrame) -> Result<(), DrReplicationError> {
+        match frame {
+            ReplicationFrame::Resync { origin, epoch } => {
+                // Cached groups may have missed invalidations; the snapshot refills the rest
+                info!("DR resync from {} (epoch {})", origin, epoch);
+                self.memberships.clear();
+                self.metrics.record_dr_resync();
+            }
+            ReplicationFrame::Batch {
+                oldest_change, records, ..
+            } => {
+                for record in records {
+                    let kind = record.kind();
+                    self.apply_record(record).await?;
+                    self.metrics.record_dr_replicated(kind, "applied");
+                }
+                let lag = self.clock.now_millis() - oldest_change;
+                self.metrics.update_dr_replication_lag("replica", lag.max(0) as f64 / 1000.0);
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply_record(&self, record: ChangeRecord) -> Result<(), DrReplicationError> {
+        match record {
+            ChangeRecord::Presence { user_id, record } => self
+                .presence
+                .apply_replicated(&user_id, record.as_ref())
+                .await
+                .map_err(|e| DrReplicationError::Apply(e.to_string()))?,
+            ChangeRecord::ReadHorizon {
+                user_id,
+                conversation_id,
+                sequence,
+            } => self.horizons.record(&user_id, &conversation_id, sequence),
+            ChangeRecord::GroupInvalidated { group_id } => self.memberships.invalidate(&group_id),
+            ChangeRecord::PauseSet { selector, expires_at } => {
+                let remaining = expires_at - self.clock.now_millis();
+                if remaining > 0 {
+                    self.pauses
+                        .pause(selector, Some(Duration::from_millis(remaining as u64)), DR_ACTOR);
+                }
+            }
+            ChangeRecord::PauseLifted { selector } => {
+                self.pauses.resume(&selector, DR_ACTOR);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DrReplicationError {
+    #[error("DR replication NATS error: {0}")]
+    Nats(String),
+    #[error("changes lost: {0}")]
+    Overflow(String),
+    #[error("failed to encode replication frame: {0}")]
+    Encode(String),
+    #[error("failed to apply replicated change: {0}")]
+    Apply(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream::stream;
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use tokio::time::Instant;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        api::subscriptions::SubscriptionRegistry,
+        audit::AuditLog,
+        cluster::ClusterView,
+        config::BrokerConfig,
+        membership::{MembershipResolver, ResolverError},
+        message::types::PresenceStatus,
+        route_cache::{RouteCache, RouteLookupError, UserLookup, UserResolver},
+    };
+
+    const CONVERGE_WITHIN: Duration = Duration::from_secs(30);
+
+    struct NoUsers;
+
+    #[async_trait]
+    impl UserResolver for NoUsers {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            Ok(UserLookup::NotFound)
+        }
+    }
+
+    struct NoGroups;
+
+    #[async_trait]
+    impl MembershipResolver for NoGroups {
+        async fn resolve(&self, _group_id: &str) -> Result<Vec<String>, ResolverError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn frames_are_tagged_for_the_wire() {
+        let frame = ReplicationFrame::Batch {
+            origin: "us-east".into(),
+            epoch: 3,
+            oldest_change: 1_700_000_000_000,
+            records: vec![
+                ChangeRecord::ReadHorizon {
+                    user_id: "alice".into(),
+                    conversation_id: "dm:alice:bob".into(),
+                    sequence: 42,
+                },
+                ChangeRecord::PauseLifted {
+                    selector: PauseSelector::Tenant("acme".into()),
+                },
+            ],
+        };
+        let json: serde_json::Value = serde_json::to_value(&frame).unwrap();
+        assert_eq!(json["type"], "batch");
+        assert_eq!(json["records"][0]["kind"], "read_horizon");
+        assert_eq!(json["records"][1]["kind"], "pause_lifted");
+
+        let ReplicationFrame::Batch { records, .. } = serde_json::from_value(json).unwrap() else {
+            panic!("batch decoded as another frame");
+        };
+        let kinds: Vec<_> = records.iter().map(ChangeRecord::kind).collect();
+        assert_eq!(kinds, ["read_horizon", "pause_lifted"]);
+
+        let resync = serde_json::to_value(ReplicationFrame::Resync {
+            origin: "us-east".into(),
+            epoch: 4,
+        })
+        .unwrap();
+        assert_eq!(resync["type"], "resync");
+    }
+
+    #[test]
+    fn deleted_presence_replicates_as_none() {
+        let change = ChangeRecord::Presence {
+            user_id: "alice".into(),
+            record: None,
+        };
+        let json = serde_json::to_string(&change).unwrap();
+        let ChangeRecord::Presence { user_id, record } = serde_json::from_str(&json).unwrap() else {
+            panic!("presence decoded as another change");
+        };
+        assert_eq!(user_id, "alice");
+        assert!(record.is_none());
+    }
+
+    /// Primary state, the DR stream and a standby's replicated copy, all on one server
+    struct Regions {
+        context: jetstream::Context,
+        config: DrReplicationConfig,
+        primary_presence: kv::Store,
+        primary_horizons: Arc<ReadHorizonStore>,
+        primary_pauses: Arc<IngestionPauses>,
+        dr_presence: Arc<PresenceStore>,
+        dr_horizons: Arc<ReadHorizonStore>,
+        dr_pauses: Arc<IngestionPauses>,
+        replicator: tokio::task::JoinHandle<()>,
+        applier: tokio::task::JoinHandle<()>,
+    }
+
+    impl Regions {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let context = jetstream::new(async_nats::connect(url).await.unwrap());
+            let id = Uuid::new_v4().simple().to_string();
+            let broker = BrokerConfig::load().unwrap();
+            let metrics = BrokerMetrics::new().unwrap();
+
+            let mut config = broker.dr_replication.clone();
+            config.enabled = true;
+            config.subject = format!("test.{}.dr", id);
+            config.stream = format!("dr-test-{}", id);
+            config.consumer = "dr-applier".into();
+            config.batch_max_records = 20;
+            config.flush_interval_ms = 50;
+            config.buffer_max_records = 50;
+            context
+                .create_stream(stream::Config {
+                    name: config.stream.clone(),
+                    subjects: vec![config.subject.clone()],
+                    discard: stream::DiscardPolicy::New,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let bucket = |name: &str| kv::Config {
+                bucket: format!("dr-{}-test-{}", name, id),
+                ..Default::default()
+            };
+            let primary_presence = context.create_key_value(bucket("presence")).await.unwrap();
+            let subscriptions = Arc::new(SubscriptionRegistry::new(&broker.api, metrics.clone()));
+            let horizons = |kv| {
+                Arc::new(ReadHorizonStore::new(
+                    kv,
+                    subscriptions.clone(),
+                    Duration::from_millis(50),
+                    metrics.clone(),
+                ))
+            };
+            let primary_horizons_kv = context.create_key_value(bucket("horizons")).await.unwrap();
+            let primary_horizons = horizons(primary_horizons_kv.clone());
+            let dr_horizons = horizons(context.create_key_value(bucket("dr-horizons")).await.unwrap());
+            let pauses = || {
+                Arc::new(IngestionPauses::new(
+                    broker.ingestion_pause.clone(),
+                    context.clone(),
+                    format!("test.{}.ingress", id),
+                    AuditLog::tracing_only(),
+                    metrics.clone(),
+                ))
+            };
+            let (primary_pauses, dr_pauses) = (pauses(), pauses());
+            let memberships = || {
+                Arc::new(MembershipCache::new(
+                    Arc::new(NoGroups),
+                    &broker.routing,
+                    &broker.limits,
+                    metrics.clone(),
+                ))
+            };
+
+            let routes = Arc::new(RouteCache::new(Arc::new(NoUsers), &broker.routing, metrics.clone()));
+            let dr_presence = Arc::new(PresenceStore::new(
+                context.create_key_value(bucket("dr-presence")).await.unwrap(),
+                &broker.routing,
+                routes,
+                metrics.clone(),
+            ));
+
+            let mut standby_config = broker.standby.clone();
+            standby_config.enabled = true;
+            standby_config.auto_activate_after = Duration::ZERO;
+            let cluster = Arc::new(ClusterView::new(
+                context.create_key_value(bucket("cluster")).await.unwrap(),
+                "dr-broker".into(),
+                &broker.cluster,
+            ));
+            let standby = Arc::new(StandbyController::new(
+                standby_config,
+                cluster,
+                AuditLog::tracing_only(),
+                metrics.clone(),
+            ));
+
+            let replicator = Arc::new(StateReplicator::new(
+                config.clone(),
+                "primary".into(),
+                context.clone(),
+                primary_presence.clone(),
+                primary_horizons_kv,
+                memberships(),
+                primary_pauses.clone(),
+                metrics.clone(),
+            ))
+            .spawn();
+            let applier = Arc::new(StateApplier::new(
+                config.clone(),
+                context.clone(),
+                dr_presence.clone(),
+                dr_horizons.clone(),
+                memberships(),
+                dr_pauses.clone(),
+                metrics,
+            ))
+            .spawn(standby);
+
+            Self {
+                context,
+                config,
+                primary_presence,
+                primary_horizons,
+                primary_pauses,
+                dr_presence,
+                dr_horizons,
+                dr_pauses,
+                replicator,
+                applier,
+            }
+        }
+
+        async fn online(&self, user_id: &str, gateway_id: &str) {
+            let record = PresenceRecord {
+                gateway_id: gateway_id.into(),
+                status: PresenceStatus::Online,
+                last_seen: Utc::now().timestamp_millis(),
+            };
+            self.primary_presence
+                .put(format!("presence.{}", user_id), serde_json::to_vec(&record).unwrap().into())
+                .await
+                .unwrap();
+        }
+
+        async fn read(&self, user_id: &str, sequence: u64) {
+            self.primary_horizons.record(user_id, "dm:alice:bob", sequence);
+            self.primary_horizons.flush().await;
+        }
+
+        /// Cut the DR link: the stream is full and rejects every publish
+        async fn partition(&self) {
+            self.limit_stream(|held| held as i64).await;
+        }
+
+        async fn heal(&self) {
+            self.limit_stream(|_| -1).await;
+        }
+
+        async fn limit_stream(&self, max_messages: impl Fn(u64) -> i64) {
+            let mut stream = self.context.get_stream(&self.config.stream).await.unwrap();
+            let info = stream.info().await.unwrap();
+            let mut config = info.config.clone();
+            config.max_messages = max_messages(info.state.messages);
+            self.context.update_stream(&config).await.unwrap();
+        }
+
+        /// Whether the DR copy holds `gateway` for every online user and `sequence` for every reader
+        async fn converged(&self, online: &[(String, &str)], deleted: &[String], horizons: &[(String, u64)]) -> bool {
+            for (user_id, gateway_id) in online {
+                match self.dr_presence.lookup(user_id).await.unwrap() {
+                    Some(record) if record.gateway_id == *gateway_id && record.status == PresenceStatus::Online => {}
+                    _ => return false,
+                }
+            }
+            for user_id in deleted {
+                if self.dr_presence.lookup(user_id).await.unwrap().is_some() {
+                    return false;
+                }
+            }
+            for (user_id, sequence) in horizons {
+                let stored = self.dr_horizons.get(user_id, &["dm:alice:bob".into()]).await.unwrap();
+                if stored.get("dm:alice:bob") != Some(sequence) {
+                    return false;
+                }
+            }
+            true
+        }
+
+        async fn wait_converged(&self, online: &[(String, &str)], deleted: &[String], horizons: &[(String, u64)]) {
+            let deadline = Instant::now() + CONVERGE_WITHIN;
+            while !self.converged(online, deleted, horizons).await {
+                assert!(Instant::now() < deadline, "DR copy did not converge");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    impl Drop for Regions {
+        fn drop(&mut self) {
+            self.replicator.abort();
+            self.applier.abort();
+        }
+    }
+
+    /// The replication tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+    /// `cargo test -- --ignored dr_replication`
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn dr_copy_converges_after_partition_and_snapshot_resync() {
+        let regions = Regions::new().await;
+        let mut frames = async_nats::connect(std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into()))
+            .await
+            .unwrap()
+            .subscribe(regions.config.subject.clone())
+            .await
+            .unwrap();
+
+        // Before the partition changes flow batch by batch
+        let early: Vec<String> = (0..10).map(|i| format!("early-{}", i)).collect();
+        for user_id in &early {
+            regions.online(user_id, "gateway-a").await;
+        }
+        regions.read("alice", 5).await;
+        let online: Vec<(String, &str)> = early.iter().map(|u| (u.clone(), "gateway-a")).collect();
+        regions.wait_converged(&online, &[], &[("alice".into(), 5)]).await;
+
+        // More changes than the buffer holds while no publish can land
+        regions.partition().await;
+        let late: Vec<String> = (0..200).map(|i| format!("late-{}", i)).collect();
+        for user_id in &late {
+            regions.online(user_id, "gateway-b").await;
+        }
+        for user_id in &early[..5] {
+            regions.online(user_id, "gateway-b").await;
+        }
+        for user_id in &early[5..] {
+            regions.primary_presence.delete(format!("presence.{}", user_id)).await.unwrap();
+        }
+        regions.read("alice", 9).await;
+        regions.read("bob", 3).await;
+        regions
+            .primary_pauses
+            .pause(PauseSelector::Tenant("acme".into()), Some(Duration::from_secs(600)), "ops");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(
+            !regions.converged(&[("late-199".into(), "gateway-b")], &[], &[]).await,
+            "changes crossed a partitioned link"
+        );
+        regions.heal().await;
+
+        let mut online: Vec<(String, &str)> = late.iter().map(|u| (u.clone(), "gateway-b")).collect();
+        online.extend(early[..5].iter().map(|u| (u.clone(), "gateway-b")));
+        regions
+            .wait_converged(&online, &early[5..], &[("alice".into(), 9), ("bob".into(), 3)])
+            .await;
+        let deadline = Instant::now() + CONVERGE_WITHIN;
+        while regions.dr_pauses.list().is_empty() {
+            assert!(Instant::now() < deadline, "pause did not replicate");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(regions.dr_pauses.list()[0].selector, PauseSelector::Tenant("acme".into()));
+
+        // The overflow fell back to a marked snapshot rather than a partial replay
+        let mut resynced = false;
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(200), frames.next()).await {
+            if let Ok(ReplicationFrame::Resync { epoch, .. }) = serde_json::from_slice(&message.payload) {
+                assert!(epoch > 0);
+                resynced = true;
+            }
+        }
+        assert!(resynced, "no resync marker was published");
+    }
+}
@@ -0,0 +1,367 @@
+use std::time::Duration;
+use chrono::Utc;
+use dashmap::DashMap;
+
+use crate::{
+    api::proto::DeliveryPath,
+    clock::{SharedClock, SystemClock},
+    config::ReceiptConfig,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Metadata stamped at ingress and echoed back by gateways on delivery receipts:
+/// broker receive time in milliseconds
+pub const INGESTED_AT_METADATA: &str = "broker_ingested_at";
+/// Metadata on single-format receipts: how the message reached the device
+pub const DELIVERY_PATH_METADATA: &str = "delivery_path";
+/// Metadata on single-format receipts: priority of the delivered message
+pub const PRIORITY_METADATA: &str = "priority";
+/// Metadata on single-format receipts: gateway that delivered the message
+pub const GATEWAY_METADATA: &str = "gateway_id";
+
+pub fn path_label(path: DeliveryPath) -> &'static str {
+    match path {
+        DeliveryPath::Live => "live",
+        DeliveryPath::OfflineReplay => "offline_replay",
+        DeliveryPath::Retry => "retry",
+        DeliveryPath::Unspecified => "unknown",
+    }
+}
+
+fn parse_path(value: &str) -> DeliveryPath {
+    match value {
+        "live" => DeliveryPath::Live,
+        "offline_replay" => DeliveryPath::OfflineReplay,
+        "retry" => DeliveryPath::Retry,
+        _ => DeliveryPath::Unspecified,
+    }
+}
+
+pub fn priority_label(value: &str) -> &'static str {
+    match value {
+        "bulk" => "bulk",
+        "high" => "high",
+        _ => "normal",
+    }
+}
+
+/// Stamp the broker receive time; any client-supplied value is replaced
+pub fn stamp_ingest(envelope: &mut MessageEnvelope) {
+    envelope
+        .metadata
+        .insert(INGESTED_AT_METADATA.to_string(), Utc::now().timestamp_millis().to_string());
+}
+
+/// One delivery receipt's timing, as reported by the gateway
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryTiming<'a> {
+    pub gateway_id: &'a str,
+    /// Gateway clock, milliseconds
+    pub delivered_at: i64,
+    /// Broker clock, milliseconds; 0 when the gateway didn't echo it
+    pub ingested_at: i64,
+    pub path: DeliveryPath,
+    pub priority: &'static str,
+}
+
+impl<'a> DeliveryTiming<'a> {
+    /// Timing carried on a single-format receipt's metadata
+    pub fn from_receipt(receipt: &'a MessageEnvelope) -> Option<Self> {
+        let metadata = &receipt.metadata;
+        Some(Self {
+            gateway_id: metadata.get(GATEWAY_METADATA)?,
+            delivered_at: receipt.timestamp,
+            ingested_at: metadata
+                .get(INGESTED_AT_METADATA)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0),
+            path: metadata
+                .get(DELIVERY_PATH_METADATA)
+                .map_or(DeliveryPath::Unspecified, |value| parse_path(value)),
+            priority: metadata.get(PRIORITY_METADATA).map_or("normal", |value| priority_label(value)),
+        })
+    }
+}
+
+/// Minimum observed offset over the current and previous window
+struct SkewWindow {
+    started: i64,
+    current: i64,
+    previous: Option<i64>,
+}
+
+/// Per-gateway clock offset from receipt frame publish times
+///
+/// For each frame, `received_at - sent_at` is transit time plus how far the
+/// gateway clock runs behind ours. The minimum over a window approximates
+/// the pure offset, since some frame in it will have crossed with little
+/// queueing; the estimate is the minimum of the current and previous
+/// `receipts.skew_window`, so it follows drift without jumping at a window
+/// boundary. It overstates the offset by the fastest transit seen, which is
+/// well under the histogram's smallest bucket.
+pub struct ClockSkew {
+    windows: DashMap<String, SkewWindow>,
+    window_ms: i64,
+}
+
+impl ClockSkew {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            windows: DashMap::new(),
+            window_ms: window.as_millis().max(1) as i64,
+        }
+    }
+
+    /// A frame stamped `sent_at` by the gateway arrived at `received_at`
+    pub fn observe(&self, gateway_id: &str, sent_at: i64, received_at: i64) {
+        let offset = received_at - sent_at;
+        let mut window = self.windows.entry(gateway_id.to_string()).or_insert(SkewWindow {
+            started: received_at,
+            current: offset,
+            previous: None,
+        });
+        if received_at - window.started >= self.window_ms {
+            window.previous = Some(window.current);
+            window.current = offset;
+            window.started = received_at;
+        } else {
+            window.current = window.current.min(offset);
+        }
+    }
+
+    /// Milliseconds to add to a gateway timestamp to put it on our clock
+    pub fn offset_ms(&self, gateway_id: &str) -> Option<i64> {
+        let window = self.windows.get(gateway_id)?;
+        Some(window.previous.map_or(window.current, |previous| previous.min(window.current)))
+    }
+}
+
+/// Sender-to-device latency from gateway delivery receipts
+///
+/// Latency is the gateway's delivery timestamp moved onto the broker clock
+/// with the gateway's `ClockSkew` offset, minus the ingest timestamp the
+/// gateway echoed from `INGESTED_AT_METADATA`. It lands in
+/// `broker_e2e_delivery_latency_seconds{priority,path}` and feeds
+/// `EndToEndLatency` SLOs. Receipts without an echoed timestamp (older
+/// gateways), from gateways with no offset estimate yet, or that come out
+/// negative are counted in `broker_e2e_latency_skipped_total{reason}` and
+/// left out of the histogram.
+pub struct E2eLatency {
+    skew: ClockSkew,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl E2eLatency {
+    pub fn new(config: &ReceiptConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            skew: ClockSkew::new(config.skew_window),
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn skew(&self) -> &ClockSkew {
+        &self.skew
+    }
+
+    /// A receipt frame published at `sent_at` on the gateway's clock arrived now
+    pub fn observe_frame(&self, gateway_id: &str, sent_at: i64) {
+        if sent_at <= 0 {
+            return;
+        }
+        self.skew.observe(gateway_id, sent_at, self.clock.now_millis());
+        if let Some(offset) = self.skew.offset_ms(gateway_id) {
+            self.metrics.update_gateway_clock_offset(gateway_id, offset as f64 / 1000.0);
+        }
+    }
+
+    /// Corrected latency in seconds, or why the receipt was skipped
+    pub fn latency(&self, timing: &DeliveryTiming<'_>) -> Result<f64, &'static str> {
+        if timing.ingested_at <= 0 {
+            return Err("no_ingest_timestamp");
+        }
+        let offset = self.skew.offset_ms(timing.gateway_id).ok_or("no_skew_estimate")?;
+        let latency_ms = timing.delivered_at + offset - timing.ingested_at;
+        if latency_ms < 0 {
+            return Err("negative");
+        }
+        Ok(latency_ms as f64 / 1000.0)
+    }
+
+    pub fn record(&self, timing: &DeliveryTiming<'_>) {
+        match self.latency(timing) {
+            Ok(seconds) => self
+                .metrics
+                .record_e2e_delivery_latency(timing.priority, path_label(timing.path), seconds),
+            Err(reason) => self.metrics.record_e2e_latency_skipped(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusRecorder};
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+        metrics::HISTOGRAM_BUCKETS,
+    };
+
+    const WINDOW: Duration = Duration::from_secs(300);
+
+    fn recorder() -> PrometheusRecorder {
+        let mut builder = PrometheusBuilder::new();
+        for (name, buckets) in HISTOGRAM_BUCKETS {
+            builder = builder.set_buckets_for_metric(Matcher::Suffix((*name).to_string()), buckets).unwrap();
+        }
+        builder.build_recorder()
+    }
+
+    fn latency(clock: &Arc<SimClock>) -> E2eLatency {
+        let config = BrokerConfig::load().unwrap().receipts;
+        E2eLatency::new(&config, BrokerMetrics::new().unwrap()).with_clock(clock.clone())
+    }
+
+    fn timing(delivered_at: i64, ingested_at: i64, path: DeliveryPath, priority: &'static str) -> DeliveryTiming<'static> {
+        DeliveryTiming {
+            gateway_id: "gw-1",
+            delivered_at,
+            ingested_at,
+            path,
+            priority,
+        }
+    }
+
+    #[test]
+    fn the_offset_is_the_smallest_transit_over_two_windows() {
+        let skew = ClockSkew::new(WINDOW);
+        assert_eq!(skew.offset_ms("gw-1"), None);
+
+        // Gateway runs 2s behind; transits of 40ms, 5ms and 90ms
+        skew.observe("gw-1", 1_000, 3_040);
+        skew.observe("gw-1", 2_000, 4_005);
+        skew.observe("gw-1", 3_000, 5_090);
+        assert_eq!(skew.offset_ms("gw-1"), Some(2_005));
+
+        // A new window keeps the previous minimum until it is replaced itself
+        let next = 3_040 + WINDOW.as_millis() as i64;
+        skew.observe("gw-1", next - 2_100, next);
+        assert_eq!(skew.offset_ms("gw-1"), Some(2_005));
+        let after = next + WINDOW.as_millis() as i64;
+        skew.observe("gw-1", after - 2_100, after);
+        assert_eq!(skew.offset_ms("gw-1"), Some(2_100), "the drifted offset takes over");
+        assert_eq!(skew.offset_ms("gw-2"), None);
+    }
+
+    #[test]
+    fn gateway_timestamps_are_moved_onto_the_broker_clock() {
+        let clock = Arc::new(SimClock::new());
+        let e2e = latency(&clock);
+        let now = clock.now_millis();
+        // The gateway clock runs 1.5s ahead; this frame took 10ms
+        e2e.observe_frame("gw-1", now + 1_500 - 10);
+        assert_eq!(e2e.skew().offset_ms("gw-1"), Some(-1_490));
+
+        let ingested = now - 700;
+        let delivered = now + 1_500 - 300;
+        let seconds = e2e.latency(&timing(delivered, ingested, DeliveryPath::Live, "normal")).unwrap();
+        assert!((seconds - 0.41).abs() < 1e-9, "{}", seconds);
+    }
+
+    #[test]
+    fn corrected_latencies_land_in_their_buckets_by_priority_and_path() {
+        let recorder = recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let clock = Arc::new(SimClock::new());
+            let e2e = latency(&clock);
+            // Gateway 3s behind, fastest transit 20ms
+            e2e.observe_frame("gw-1", clock.now_millis() - 3_020);
+            let ingested = clock.now_millis();
+            let on_gateway = |broker_ms: i64| broker_ms - 3_020;
+
+            e2e.record(&timing(on_gateway(ingested + 200), ingested, DeliveryPath::Live, "high"));
+            e2e.record(&timing(on_gateway(ingested + 40), ingested, DeliveryPath::Live, "high"));
+            e2e.record(&timing(on_gateway(ingested + 45_000), ingested, DeliveryPath::OfflineReplay, "normal"));
+            e2e.record(&timing(on_gateway(ingested + 3_000), ingested, DeliveryPath::Retry, "bulk"));
+        });
+
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="high",path="live",le="0.05"} 1"#,
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="high",path="live",le="0.1"} 1"#,
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="high",path="live",le="0.25"} 2"#,
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="normal",path="offline_replay",le="30"} 0"#,
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="normal",path="offline_replay",le="60"} 1"#,
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="bulk",path="retry",le="2.5"} 0"#,
+            r#"broker_e2e_delivery_latency_seconds_bucket{priority="bulk",path="retry",le="5"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {}\n{}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn receipts_without_a_usable_timing_are_skipped() {
+        let recorder = recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let clock = Arc::new(SimClock::new());
+            let e2e = latency(&clock);
+            let now = clock.now_millis();
+
+            e2e.record(&timing(now, now - 100, DeliveryPath::Live, "normal"));
+            e2e.observe_frame("gw-1", now);
+            e2e.record(&timing(now, 0, DeliveryPath::Live, "normal"));
+            e2e.record(&timing(now - 5_000, now, DeliveryPath::Live, "normal"));
+        });
+
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_e2e_latency_skipped_total{reason="no_skew_estimate"} 1"#,
+            r#"broker_e2e_latency_skipped_total{reason="no_ingest_timestamp"} 1"#,
+            r#"broker_e2e_latency_skipped_total{reason="negative"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {}\n{}", line, rendered);
+        }
+        assert!(!rendered.contains("broker_e2e_delivery_latency_seconds"), "{}", rendered);
+    }
+
+    #[test]
+    fn receipt_metadata_carries_the_echoed_timing() {
+        let mut receipt = MessageEnvelope::new(
+            MessageType::Delivered,
+            "gw-1".into(),
+            vec!["broker".into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        );
+        assert!(DeliveryTiming::from_receipt(&receipt).is_none(), "no gateway, no timing");
+
+        receipt.metadata.insert(GATEWAY_METADATA.into(), "gw-1".into());
+        let old_gateway = DeliveryTiming::from_receipt(&receipt).unwrap();
+        assert_eq!(old_gateway.ingested_at, 0);
+        assert_eq!((old_gateway.path, old_gateway.priority), (DeliveryPath::Unspecified, "normal"));
+
+        stamp_ingest(&mut receipt);
+        receipt.metadata.insert(DELIVERY_PATH_METADATA.into(), "offline_replay".into());
+        receipt.metadata.insert(PRIORITY_METADATA.into(), "urgent".into());
+        let timing = DeliveryTiming::from_receipt(&receipt).unwrap();
+        assert!(timing.ingested_at > 0);
+        assert_eq!(timing.delivered_at, receipt.timestamp);
+        assert_eq!((timing.path, timing.priority), (DeliveryPath::OfflineReplay, "normal"));
+    }
+}
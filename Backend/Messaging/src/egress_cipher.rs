@@ -0,0 +1,551 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use async_nats::HeaderMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305,
+};
+use dashmap::DashMap;
+use ring::hkdf;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::EgressCipherConfig,
+    metrics::BrokerMetrics,
+};
+
+/// Names the scheme on sealed egress publishes
+pub const EGRESS_CIPHER_HEADER: &str = "Broker-Egress-Cipher";
+/// Base64 X25519 public key of the session that sealed the payload
+pub const EGRESS_SESSION_HEADER: &str = "Broker-Egress-Session";
+
+pub const EGRESS_CIPHER: &str = "x25519-xchacha20poly1305";
+
+/// HKDF info prefix, followed by the gateway ID
+const KDF_INFO: &[u8] = b"broker-egress-v1";
+
+/// What egress to a gateway without a pinned key does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnpinnedPolicy {
+    /// Publish unsealed
+    Plaintext,
+    /// Don't publish
+    Refuse,
+}
+
+/// Session key for `gateway_id` from the X25519 shared secret
+///
+/// HKDF-SHA256 salted with both public keys, so gateways derive the same key
+/// from the session header and their own secret.
+pub fn session_key(
+    shared: &[u8; 32],
+    session_public: &PublicKey,
+    gateway_public: &PublicKey,
+    gateway_id: &str,
+) -> [u8; 32] {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(session_public.as_bytes());
+    salt.extend_from_slice(gateway_public.as_bytes());
+    let info = [KDF_INFO, gateway_id.as_bytes()];
+
+    let mut key = [0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &salt)
+        .extract(shared)
+        .expand(&info, hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// One key agreement's worth of egress to a gateway
+struct Session {
+    cipher: XChaCha20Poly1305,
+    /// Base64 session public key, sent in `EGRESS_SESSION_HEADER`
+    public: String,
+    started: Instant,
+    sealed: AtomicU64,
+}
+
+impl Session {
+    fn open(gateway_id: &str, gateway_public: &PublicKey, now: Instant) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(gateway_public);
+        let key = session_key(shared.as_bytes(), &public, gateway_public, gateway_id);
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            public: STANDARD.encode(public.as_bytes()),
+            started: now,
+            sealed: AtomicU64::new(0),
+        }
+    }
+
+    fn expired(&self, config: &EgressCipherConfig, now: Instant) -> Option<&'static str> {
+        if now.saturating_duration_since(self.started) >= config.session_max_age {
+            Some("age")
+        } else if self.sealed.load(Ordering::Relaxed) >= config.session_max_messages {
+            Some("messages")
+        } else {
+            None
+        }
+    }
+}
+
+struct PinnedGateway {
+    public_key: PublicKey,
+    session: Option<Arc<Session>>,
+}
+
+/// Payload to publish and the headers describing it
+#[derive(Debug, Clone)]
+pub struct SealedEgress {
+    pub payload: Bytes,
+    /// Base64 session public key; `None` when published unsealed
+    pub session: Option<String>,
+}
+
+impl SealedEgress {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if let Some(session) = &self.session {
+            headers.insert(EGRESS_CIPHER_HEADER, EGRESS_CIPHER);
+            headers.insert(EGRESS_SESSION_HEADER, session.as_str());
+        }
+    }
+}
+
+/// Broker-to-gateway payload encryption under pinned gateway keys
+///
+/// A gateway pins an X25519 public key with `PinGatewayEgressKey` when it
+/// connects; once pinned, a different key is refused until an operator
+/// unpins the gateway, so a compromised NATS can't swap in its own. Egress
+/// to the gateway is sealed with XChaCha20-Poly1305 under a session key
+/// agreed between an ephemeral broker key and the pinned key (see
+/// `session_key`), with the gateway ID and session public key as associated data,
+/// so a modified or replayed-elsewhere payload fails to open. Sessions
+/// rotate after `egress_cipher.session_max_age` or `session_max_messages`,
+/// which keeps the per-message cost to one symmetric encryption; gateways
+/// cache keys by session public key.
+///
+/// Egress to a gateway with no pinned key follows the unpinned policy,
+/// which refuses in production and publishes plaintext elsewhere unless
+/// set explicitly.
+pub struct EgressCipher {
+    config: EgressCipherConfig,
+    unpinned: UnpinnedPolicy,
+    gateways: DashMap<String, PinnedGateway>,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl EgressCipher {
+    pub fn new(config: EgressCipherConfig, production: bool, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        let unpinned = config.unpinned_policy.unwrap_or(if production {
+            UnpinnedPolicy::Refuse
+        } else {
+            UnpinnedPolicy::Plaintext
+        });
+        Self {
+            config,
+            unpinned,
+            gateways: DashMap::new(),
+            audit,
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Pin a gateway's egress key (base64 encoded X25519, 32 bytes)
+    ///
+    /// Pinning the key already pinned is a no-op; any other key is refused.
+    pub fn pin(&self, gateway_id: &str, public_key: &str, issued_by: &str) -> Result<(), EgressCipherError> {
+        let key = decode_key(public_key)?;
+        if let Some(pinned) = self.gateways.get(gateway_id) {
+            if pinned.public_key == key {
+                return Ok(());
+            }
+            self.metrics.record_egress_key_pin("mismatch");
+            self.audit.record(AuditEntry::new(
+                issued_by,
+                "egress_cipher.pin_refused",
+                serde_json::json!({ "gateway_id": gateway_id, "public_key": public_key }),
+            ));
+            return Err(EgressCipherError::PinMismatch(gateway_id.to_string()));
+        }
+
+        self.gateways.insert(
+            gateway_id.to_string(),
+            PinnedGateway {
+                public_key: key,
+                session: None,
+            },
+        );
+        info!("Gateway {} egress key pinned", gateway_id);
+        self.audit.record(AuditEntry::new(
+            issued_by,
+            "egress_cipher.pinned",
+            serde_json::json!({ "gateway_id": gateway_id, "public_key": public_key }),
+        ));
+        self.metrics.record_egress_key_pin("pinned");
+        self.metrics.update_egress_pinned_gateways(self.gateways.len());
+        Ok(())
+    }
+
+    /// Forget a gateway's pinned key so it can pin a new one
+    pub fn unpin(&self, gateway_id: &str, issued_by: &str) -> bool {
+        let removed = self.gateways.remove(gateway_id).is_some();
+        if removed {
+            info!("Gateway {} egress key unpinned by {}", gateway_id, issued_by);
+            self.audit.record(AuditEntry::new(
+                issued_by,
+                "egress_cipher.unpinned",
+                serde_json::json!({ "gateway_id": gateway_id }),
+            ));
+            self.metrics.update_egress_pinned_gateways(self.gateways.len());
+        }
+        removed
+    }
+
+    /// Seal `payload` for `gateway_id`, or apply the unpinned policy
+    pub fn seal(&self, gateway_id: &str, payload: &Bytes) -> Result<SealedEgress, EgressCipherError> {
+        if !self.config.enabled {
+            return Ok(SealedEgress {
+                payload: payload.clone(),
+                session: None,
+            });
+        }
+        let Some(session) = self.session(gateway_id) else {
+            return match self.unpinned {
+                UnpinnedPolicy::Plaintext => {
+                    self.metrics.record_egress_seal("plaintext");
+                    Ok(SealedEgress {
+                        payload: payload.clone(),
+                        session: None,
+                    })
+                }
+                UnpinnedPolicy::Refuse => {
+                    self.metrics.record_egress_seal("refused");
+                    Err(EgressCipherError::Unpinned(gateway_id.to_string()))
+                }
+            };
+        };
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(gateway_id, &session.public);
+        let ciphertext = session
+            .cipher
+            .encrypt(&nonce, Payload { msg: payload, aad: &aad })
+            .map_err(|_| EgressCipherError::Seal)?;
+        session.sealed.fetch_add(1, Ordering::Relaxed);
+        self.metrics.record_egress_seal("sealed");
+
+        // Nonce first, then ciphertext and tag
+        let mut sealed = BytesMut::with_capacity(nonce.len() + ciphertext.len());
+        sealed.put_slice(&nonce);
+        sealed.put_slice(&ciphertext);
+        Ok(SealedEgress {
+            payload: sealed.freeze(),
+            session: Some(session.public.clone()),
+        })
+    }
+
+    /// Current session for a pinned gateway, opening a new one when due
+    fn session(&self, gateway_id: &str) -> Option<Arc<Session>> {
+        let now = self.clock.now_instant();
+        {
+            let pinned = self.gateways.get(gateway_id)?;
+            if let Some(session) = &pinned.session {
+                if session.expired(&self.config, now).is_none() {
+                    return Some(Arc::clone(session));
+                }
+            }
+        }
+
+        let mut pinned = self.gateways.get_mut(gateway_id)?;
+        // Another caller may have rotated while we waited for the lock
+        let reason = match &pinned.session {
+            Some(session) => match session.expired(&self.config, now) {
+                Some(reason) => reason,
+                None => return Some(Arc::clone(session)),
+            },
+            None => "new",
+        };
+        let session = Arc::new(Session::open(gateway_id, &pinned.public_key, now));
+        pinned.session = Some(Arc::clone(&session));
+        self.metrics.record_egress_session(reason);
+        Some(session)
+    }
+}
+
+/// Binds a sealed payload to its gateway and session
+fn associated_data(gateway_id: &str, session_public: &str) -> Vec<u8> {
+    format!("{}\0{}", gateway_id, session_public).into_bytes()
+}
+
+fn decode_key(public_key: &str) -> Result<PublicKey, EgressCipherError> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(EgressCipherError::InvalidKey)?;
+    Ok(PublicKey::from(bytes))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EgressCipherError {
+    #[error("egress key must be a base64 encoded 32-byte X25519 public key")]
+    InvalidKey,
+    #[error("gateway {0} already pinned a different egress key")]
+    PinMismatch(String),
+    #[error("gateway {0} has no pinned egress key")]
+    Unpinned(String),
+    #[error("failed to seal egress payload")]
+    Seal,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const GATEWAY: &str = "gw-1";
+
+    /// The gateway's side: a long-lived secret and the session keys it derived
+    struct Gateway {
+        secret: [u8; 32],
+        sessions: DashMap<String, XChaCha20Poly1305>,
+    }
+
+    impl Gateway {
+        fn new(seed: u8) -> Self {
+            Self {
+                secret: [seed; 32],
+                sessions: DashMap::new(),
+            }
+        }
+
+        fn public(&self) -> PublicKey {
+            PublicKey::from(x25519(self.secret, X25519_BASEPOINT_BYTES))
+        }
+
+        fn public_base64(&self) -> String {
+            STANDARD.encode(self.public().as_bytes())
+        }
+
+        /// Open a sealed payload the way a gateway does, from the session header alone
+        fn open(&self, gateway_id: &str, sealed: &SealedEgress) -> Option<Vec<u8>> {
+            let session = sealed.session.as_ref()?;
+            let cipher = self
+                .sessions
+                .entry(session.clone())
+                .or_insert_with(|| {
+                    let session_public = decode_key(session).unwrap();
+                    let shared = x25519(self.secret, session_public.to_bytes());
+                    let key = session_key(&shared, &session_public, &self.public(), gateway_id);
+                    XChaCha20Poly1305::new(&key.into())
+                })
+                .clone();
+            let (nonce, ciphertext) = sealed.payload.split_at(24);
+            let aad = associated_data(gateway_id, session);
+            cipher.decrypt(nonce.into(), Payload { msg: ciphertext, aad: &aad }).ok()
+        }
+    }
+
+    fn config() -> EgressCipherConfig {
+        let mut config = BrokerConfig::load().unwrap().egress_cipher;
+        config.enabled = true;
+        config.unpinned_policy = None;
+        config.session_max_age = Duration::from_secs(3600);
+        config.session_max_messages = 3;
+        config
+    }
+
+    fn cipher(config: EgressCipherConfig, production: bool, clock: &Arc<SimClock>) -> EgressCipher {
+        EgressCipher::new(config, production, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone())
+    }
+
+    #[test]
+    fn pinned_gateways_open_what_the_broker_seals() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let cipher = cipher(config(), true, &Arc::new(SimClock::new()));
+            let gateway = Gateway::new(7);
+            cipher.pin(GATEWAY, &gateway.public_base64(), "gw-1").unwrap();
+
+            let payload = Bytes::from_static(b"{\"message_id\":\"m1\"}");
+            let sealed = cipher.seal(GATEWAY, &payload).unwrap();
+            assert_ne!(sealed.payload, payload);
+            assert_eq!(gateway.open(GATEWAY, &sealed).as_deref(), Some(&payload[..]));
+
+            let mut headers = HeaderMap::new();
+            sealed.apply(&mut headers);
+            assert_eq!(headers.get(EGRESS_CIPHER_HEADER).unwrap().as_str(), EGRESS_CIPHER);
+            assert_eq!(headers.get(EGRESS_SESSION_HEADER).unwrap().as_str(), sealed.session.as_deref().unwrap());
+
+            // Another gateway's key can't open it
+            assert_eq!(Gateway::new(8).open(GATEWAY, &sealed), None);
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_egress_seals_total{outcome="sealed"} 1"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_egress_sessions_total{reason="new"} 1"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn a_pinned_key_can_only_be_replaced_after_an_unpin() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let cipher = cipher(config(), true, &Arc::new(SimClock::new()));
+            let (original, impostor) = (Gateway::new(7), Gateway::new(9));
+            cipher.pin(GATEWAY, &original.public_base64(), "gw-1").unwrap();
+            // Reconnecting with the same key is fine
+            cipher.pin(GATEWAY, &original.public_base64(), "gw-1").unwrap();
+
+            assert!(matches!(
+                cipher.pin(GATEWAY, &impostor.public_base64(), "mallory"),
+                Err(EgressCipherError::PinMismatch(id)) if id == GATEWAY
+            ));
+            let sealed = cipher.seal(GATEWAY, &Bytes::from_static(b"hi")).unwrap();
+            assert!(original.open(GATEWAY, &sealed).is_some());
+            assert!(impostor.open(GATEWAY, &sealed).is_none());
+
+            for bad in ["not base64!", "c2hvcnQ=", ""] {
+                assert!(matches!(cipher.pin("gw-2", bad, "gw-2"), Err(EgressCipherError::InvalidKey)), "{}", bad);
+            }
+
+            assert!(cipher.unpin(GATEWAY, "ops"));
+            assert!(!cipher.unpin(GATEWAY, "ops"));
+            cipher.pin(GATEWAY, &impostor.public_base64(), "ops").unwrap();
+            let sealed = cipher.seal(GATEWAY, &Bytes::from_static(b"hi")).unwrap();
+            assert!(impostor.open(GATEWAY, &sealed).is_some());
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_egress_key_pins_total{outcome="pinned"} 2"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_egress_key_pins_total{outcome="mismatch"} 1"#), "{}", rendered);
+            assert!(rendered.contains("broker_egress_pinned_gateways 1"), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn sessions_rotate_by_message_count_and_age() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let clock = Arc::new(SimClock::new());
+            let cipher = cipher(config(), true, &clock);
+            let gateway = Gateway::new(7);
+            cipher.pin(GATEWAY, &gateway.public_base64(), "gw-1").unwrap();
+            let payload = Bytes::from_static(b"hi");
+            let seal = || {
+                let sealed = cipher.seal(GATEWAY, &payload).unwrap();
+                assert!(gateway.open(GATEWAY, &sealed).is_some());
+                sealed.session.unwrap()
+            };
+
+            // Three messages share one key agreement, the fourth starts another
+            let first: Vec<_> = (0..3).map(|_| seal()).collect();
+            assert!(first.iter().all(|session| *session == first[0]));
+            let second = seal();
+            assert_ne!(second, first[0]);
+
+            clock.advance(Duration::from_secs(3599));
+            assert_eq!(seal(), second);
+            clock.advance(Duration::from_secs(1));
+            let third = seal();
+            assert_ne!(third, second);
+            assert_eq!(gateway.sessions.len(), 3);
+
+            let rendered = recorder.handle().render();
+            for (reason, count) in [("new", 1), ("messages", 1), ("age", 1)] {
+                let line = format!(r#"broker_egress_sessions_total{{reason="{}"}} {}"#, reason, count);
+                assert!(rendered.contains(&line), "{}", rendered);
+            }
+        });
+    }
+
+    #[test]
+    fn unpinned_gateways_are_refused_in_production_and_plaintext_elsewhere() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let clock = Arc::new(SimClock::new());
+            let payload = Bytes::from_static(b"hi");
+
+            let production = cipher(config(), true, &clock);
+            assert!(matches!(
+                production.seal(GATEWAY, &payload),
+                Err(EgressCipherError::Unpinned(id)) if id == GATEWAY
+            ));
+
+            let development = cipher(config(), false, &clock).seal(GATEWAY, &payload).unwrap();
+            assert_eq!((development.payload, development.session), (payload.clone(), None));
+
+            // An explicit policy wins over the environment
+            let mut explicit = config();
+            explicit.unpinned_policy = Some(UnpinnedPolicy::Plaintext);
+            assert!(cipher(explicit.clone(), true, &clock).seal(GATEWAY, &payload).is_ok());
+            explicit.unpinned_policy = Some(UnpinnedPolicy::Refuse);
+            assert!(cipher(explicit, false, &clock).seal(GATEWAY, &payload).is_err());
+
+            // Disabled sealing passes everything through, pinned or not
+            let mut disabled = config();
+            disabled.enabled = false;
+            let cipher = cipher(disabled, true, &clock);
+            cipher.pin(GATEWAY, &Gateway::new(7).public_base64(), "gw-1").unwrap();
+            let sealed = cipher.seal(GATEWAY, &payload).unwrap();
+            assert_eq!((sealed.payload, sealed.session), (payload, None));
+            let mut headers = HeaderMap::new();
+            SealedEgress { payload: Bytes::new(), session: None }.apply(&mut headers);
+            assert!(headers.is_empty());
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_egress_seals_total{outcome="refused"} 2"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_egress_seals_total{outcome="plaintext"} 2"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn modified_envelopes_fail_to_open() {
+        let cipher = cipher(config(), true, &Arc::new(SimClock::new()));
+        let gateway = Gateway::new(7);
+        cipher.pin(GATEWAY, &gateway.public_base64(), "gw-1").unwrap();
+        cipher.pin("gw-2", &gateway.public_base64(), "gw-2").unwrap();
+        let sealed = cipher.seal(GATEWAY, &Bytes::from_static(b"{\"message_id\":\"m1\"}")).unwrap();
+        assert!(gateway.open(GATEWAY, &sealed).is_some());
+
+        // Every byte is covered: nonce, ciphertext and tag
+        for index in [0, 23, 24, sealed.payload.len() - 1] {
+            let mut payload = sealed.payload.to_vec();
+            payload[index] ^= 0x01;
+            let tampered = SealedEgress { payload: payload.into(), session: sealed.session.clone() };
+            assert_eq!(gateway.open(GATEWAY, &tampered), None, "byte {}", index);
+        }
+
+        // So are the gateway it was sealed for and the session header
+        assert_eq!(gateway.open("gw-2", &sealed), None);
+        let other_session = cipher.seal("gw-2", &Bytes::from_static(b"x")).unwrap().session;
+        let swapped = SealedEgress { payload: sealed.payload.clone(), session: other_session };
+        assert_eq!(gateway.open(GATEWAY, &swapped), None);
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use bytes::Bytes;
+use dashmap::DashMap;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    config::EgressRedactionConfig,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Prefix of a value replaced by its hash, so receivers can tell it apart
+pub const HASHED_PREFIX: &str = "redacted:";
+
+/// Envelope fields a redacted rendering must keep for it to still parse
+const REQUIRED_PATHS: &[&str] = &[
+    "/message_type",
+    "/from",
+    "/to",
+    "/payload",
+    "/payload/ciphertext",
+    "/message_id",
+    "/timestamp",
+    "/metadata",
+    "/attestation/gateway_id",
+    "/attestation/connection_id",
+    "/attestation/timestamp",
+    "/attestation/signature",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionAction {
+    Remove,
+    /// Replace with a truncated SHA-256, keeping values joinable without revealing them
+    Hash,
+}
+
+/// One field to redact, as a JSON pointer into the envelope (`/metadata/trace_baggage`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedactionRule {
+    pub path: String,
+    pub action: RedactionAction,
+}
+
+/// Field-level redaction of egress envelopes by recipient gateway trust tier
+///
+/// Each gateway has a trust tier, registered alongside its attestation key
+/// or taken from `egress_redaction.gateway_tiers`, else the default tier.
+/// A tier's rules remove or hash envelope fields by JSON pointer. Removing
+/// a field the envelope can't parse without (`REQUIRED_PATHS`) hashes it
+/// instead, and hashing only touches strings (and arrays of strings), so
+/// every rendering stays a valid envelope. Tiers without rules get the
+/// original bytes untouched.
+pub struct EgressRedactor {
+    config: EgressRedactionConfig,
+    /// Tiers registered at runtime; override the configured ones
+    registered: DashMap<String, String>,
+    metrics: BrokerMetrics,
+}
+
+impl EgressRedactor {
+    pub fn new(config: EgressRedactionConfig, metrics: BrokerMetrics) -> Self {
+        for (tier, rules) in &config.tiers {
+            for rule in rules {
+                if !rule.path.starts_with('/') {
+                    warn!("Redaction rule {} of tier {} is not a JSON pointer; ignored", rule.path, tier);
+                }
+            }
+        }
+        Self {
+            config,
+            registered: DashMap::new(),
+            metrics,
+        }
+    }
+
+    /// Record the tier a gateway registered with; `None` reverts to config
+    pub fn set_tier(&self, gateway_id: &str, tier: Option<&str>) {
+        match tier {
+            Some(tier) => {
+                if !self.config.tiers.contains_key(tier) && tier != self.config.default_tier {
+                    warn!("Gateway {} registered unknown trust tier {}; it gets no redaction rules", gateway_id, tier);
+                }
+                self.registered.insert(gateway_id.to_string(), tier.to_string());
+            }
+            None => {
+                self.registered.remove(gateway_id);
+            }
+        }
+    }
+
+    pub fn tier(&self, gateway_id: &str) -> String {
+        if let Some(tier) = self.registered.get(gateway_id) {
+            return tier.clone();
+        }
+        self.config
+            .gateway_tiers
+            .get(gateway_id)
+            .unwrap_or(&self.config.default_tier)
+            .clone()
+    }
+
+    /// Renderings for one fanout of `envelope`, whose unredacted encoding is `encoded`
+    pub fn fanout<'a>(&'a self, envelope: &'a MessageEnvelope, encoded: Bytes) -> FanoutRenderings<'a> {
+        FanoutRenderings {
+            redactor: self,
+            envelope,
+            encoded,
+            renderings: HashMap::new(),
+        }
+    }
+
+    fn rules(&self, tier: &str) -> &[RedactionRule] {
+        self.config.tiers.get(tier).map_or(&[], Vec::as_slice)
+    }
+
+    fn render(&self, envelope: &MessageEnvelope, tier: &str) -> Result<Bytes, RedactionError> {
+        let mut value = serde_json::to_value(envelope).map_err(|e| RedactionError(e.to_string()))?;
+        for rule in self.rules(tier) {
+            apply(&mut value, rule);
+        }
+        self.metrics.record_redaction_render(tier);
+        serde_json::to_vec(&value)
+            .map(Bytes::from)
+            .map_err(|e| RedactionError(e.to_string()))
+    }
+}
+
+/// Per-fanout cache: one rendering per tier, however many recipients share it
+///
+/// At most `egress_redaction.max_renderings_per_fanout` tiers are rendered;
+/// gateways of further tiers get the `overflow_tier` rendering, which should
+/// be the most restrictive. The count is recorded when the fanout ends.
+pub struct FanoutRenderings<'a> {
+    redactor: &'a EgressRedactor,
+    envelope: &'a MessageEnvelope,
+    encoded: Bytes,
+    renderings: HashMap<String, Bytes>,
+}
+
+impl FanoutRenderings<'_> {
+    /// Bytes to publish to `gateway_id`
+    pub fn for_gateway(&mut self, gateway_id: &str) -> Result<Bytes, RedactionError> {
+        let config = &self.redactor.config;
+        if !config.enabled {
+            return Ok(self.encoded.clone());
+        }
+        let mut tier = self.redactor.tier(gateway_id);
+        if self.redactor.rules(&tier).is_empty() {
+            return Ok(self.encoded.clone());
+        }
+        if let Some(rendering) = self.renderings.get(&tier) {
+            return Ok(rendering.clone());
+        }
+
+        if self.renderings.len() >= config.max_renderings_per_fanout {
+            self.redactor.metrics.record_redaction_overflow();
+            tier = config.overflow_tier.clone();
+            if let Some(rendering) = self.renderings.get(&tier) {
+                return Ok(rendering.clone());
+            }
+        }
+        let rendering = self.redactor.render(self.envelope, &tier)?;
+        self.renderings.insert(tier, rendering.clone());
+        Ok(rendering)
+    }
+
+    /// Tiers rendered so far
+    pub fn renderings(&self) -> usize {
+        self.renderings.len()
+    }
+}
+
+impl Drop for FanoutRenderings<'_> {
+    fn drop(&mut self) {
+        if !self.renderings.is_empty() {
+            self.redactor.metrics.record_redaction_renderings(self.renderings.len());
+        }
+    }
+}
+
+fn apply(value: &mut Value, rule: &RedactionRule) {
+    let action = match rule.action {
+        RedactionAction::Remove if REQUIRED_PATHS.contains(&rule.path.as_str()) => RedactionAction::Hash,
+        action => action,
+    };
+    match action {
+        RedactionAction::Remove => remove(value, &rule.path),
+        RedactionAction::Hash => {
+            if let Some(target) = value.pointer_mut(&rule.path) {
+                hash_in_place(target);
+            }
+        }
+    }
+}
+
+fn remove(value: &mut Value, path: &str) {
+    let Some((parent, key)) = path.rsplit_once('/') else {
+        return;
+    };
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match value.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.remove(&key);
+        }
+        Some(Value::Array(items)) => {
+            if let Ok(index) = key.parse::<usize>() {
+                if index < items.len() {
+                    items.remove(index);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Strings become hashes; other scalars are left alone so the type still parses
+fn hash_in_place(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = hashed(s),
+        Value::Array(items) => items.iter_mut().for_each(hash_in_place),
+        Value::Object(map) => map.values_mut().for_each(hash_in_place),
+        _ => {}
+    }
+}
+
+fn hashed(value: &str) -> String {
+    format!("{}{}", HASHED_PREFIX, hex::encode(&digest(&SHA256, value.as_bytes()).as_ref()[..12]))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("egress redaction failed: {0}")]
+pub struct RedactionError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::message::types::{EncryptedPayload, MessageType, SenderAttestation};
+
+    fn rule(path: &str, action: RedactionAction) -> RedactionRule {
+        RedactionRule {
+            path: path.into(),
+            action,
+        }
+    }
+
+    fn config() -> EgressRedactionConfig {
+        EgressRedactionConfig {
+            enabled: true,
+            default_tier: "first_party".into(),
+            gateway_tiers: [("partner-gw-1", "partner"), ("partner-gw-2", "partner"), ("bot-gw", "integration")]
+                .into_iter()
+                .map(|(gateway, tier)| (gateway.to_string(), tier.to_string()))
+                .collect(),
+            tiers: HashMap::from([
+                (
+                    "partner".to_string(),
+                    vec![
+                        rule("/metadata/trace_baggage", RedactionAction::Remove),
+                        rule("/metadata/moderation_flags", RedactionAction::Remove),
+                        rule("/from", RedactionAction::Hash),
+                    ],
+                ),
+                (
+                    "integration".to_string(),
+                    vec![
+                        rule("/metadata", RedactionAction::Remove),
+                        rule("/attestation", RedactionAction::Remove),
+                        rule("/to", RedactionAction::Remove),
+                    ],
+                ),
+            ]),
+            max_renderings_per_fanout: 4,
+            overflow_tier: "integration".into(),
+        }
+    }
+
+    fn envelope() -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope =
+            MessageEnvelope::new(MessageType::TextMessage, "internal-user-42".into(), vec!["bob".into()], payload);
+        envelope.metadata.insert("trace_baggage".into(), "tenant=acme".into());
+        envelope.metadata.insert("moderation_flags".into(), "spam_suspect".into());
+        envelope.metadata.insert("locale".into(), "en".into());
+        envelope.attestation = Some(SenderAttestation {
+            gateway_id: "gw-1".into(),
+            connection_id: "conn-1".into(),
+            timestamp: 1_700_000_000_000,
+            signature: "c2ln".into(),
+        });
+        envelope
+    }
+
+    fn parsed(bytes: &Bytes) -> MessageEnvelope {
+        serde_json::from_slice(bytes).expect("every rendering is still a valid envelope")
+    }
+
+    #[test]
+    fn tiers_receive_different_bytes_from_one_message() {
+        let redactor = EgressRedactor::new(config(), BrokerMetrics::new().unwrap());
+        let envelope = envelope();
+        let encoded = Bytes::from(serde_json::to_vec(&envelope).unwrap());
+        let mut fanout = redactor.fanout(&envelope, encoded.clone());
+
+        let first_party = fanout.for_gateway("gw-1").unwrap();
+        let partner = fanout.for_gateway("partner-gw-1").unwrap();
+        let integration = fanout.for_gateway("bot-gw").unwrap();
+        assert_eq!(first_party, encoded, "first-party gateways get the original bytes");
+        assert_ne!(partner, encoded);
+        assert_ne!(integration, partner);
+
+        let partner = parsed(&partner);
+        assert_eq!(partner.metadata, HashMap::from([("locale".to_string(), "en".to_string())]));
+        assert!(partner.from.starts_with(HASHED_PREFIX), "{}", partner.from);
+        assert_ne!(partner.from, envelope.from);
+        assert_eq!(partner.message_id, envelope.message_id);
+        assert_eq!(partner.payload.ciphertext, envelope.payload.ciphertext);
+        assert!(partner.attestation.is_some());
+
+        // Hashes are stable, so a partner can still tell senders apart
+        assert_eq!(partner.from, hashed(&envelope.from));
+    }
+
+    #[test]
+    fn removing_a_required_field_hashes_it_instead() {
+        let redactor = EgressRedactor::new(config(), BrokerMetrics::new().unwrap());
+        let envelope = envelope();
+        let mut fanout = redactor.fanout(&envelope, Bytes::new());
+        let integration = fanout.for_gateway("bot-gw").unwrap();
+
+        let value: Value = serde_json::from_slice(&integration).unwrap();
+        // Optional: really gone. Required: still there, hashed where it holds strings
+        assert!(value.get("attestation").is_none());
+        assert_eq!(value["metadata"]["locale"].as_str().unwrap(), hashed("en"));
+        assert_eq!(value["to"][0].as_str().unwrap(), hashed("bob"));
+        let integration = parsed(&integration);
+        assert!(integration.attestation.is_none());
+        assert_eq!(integration.timestamp, envelope.timestamp);
+    }
+
+    #[test]
+    fn one_rendering_per_tier_however_many_recipients() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let redactor = EgressRedactor::new(config(), BrokerMetrics::new().unwrap());
+            let envelope = envelope();
+            let encoded = Bytes::from(serde_json::to_vec(&envelope).unwrap());
+            let mut fanout = redactor.fanout(&envelope, encoded);
+            for recipient in 0..300 {
+                let gateway = match recipient % 4 {
+                    0 => "partner-gw-1",
+                    1 => "partner-gw-2",
+                    2 => "bot-gw",
+                    _ => "gw-1",
+                };
+                fanout.for_gateway(gateway).unwrap();
+            }
+            assert_eq!(fanout.renderings(), 2);
+            drop(fanout);
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_redaction_renders_total{tier="partner"} 1"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_redaction_renders_total{tier="integration"} 1"#), "{}", rendered);
+            assert!(!rendered.contains(r#"tier="first_party""#), "{}", rendered);
+            assert!(rendered.contains("broker_redaction_renderings_per_fanout_sum 2"), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn tiers_past_the_bound_get_the_overflow_rendering() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let mut config = config();
+            config.max_renderings_per_fanout = 1;
+            let redactor = EgressRedactor::new(config, BrokerMetrics::new().unwrap());
+            let envelope = envelope();
+            let mut fanout = redactor.fanout(&envelope, Bytes::new());
+
+            let partner = fanout.for_gateway("partner-gw-1").unwrap();
+            let overflowed = fanout.for_gateway("bot-gw").unwrap();
+            // The overflow tier is rendered once it's needed, and then reused
+            assert_ne!(overflowed, partner);
+            assert_eq!(fanout.for_gateway("bot-gw").unwrap(), overflowed);
+            assert_eq!(fanout.for_gateway("partner-gw-2").unwrap(), partner);
+            assert_eq!(fanout.renderings(), 2);
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains("broker_redaction_overflows_total 1"), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn registered_tiers_override_config_until_cleared() {
+        let redactor = EgressRedactor::new(config(), BrokerMetrics::new().unwrap());
+        assert_eq!(redactor.tier("gw-1"), "first_party");
+        assert_eq!(redactor.tier("partner-gw-1"), "partner");
+
+        redactor.set_tier("gw-1", Some("integration"));
+        redactor.set_tier("partner-gw-1", Some("first_party"));
+        assert_eq!(redactor.tier("gw-1"), "integration");
+        assert_eq!(redactor.tier("partner-gw-1"), "first_party");
+
+        redactor.set_tier("partner-gw-1", None);
+        assert_eq!(redactor.tier("partner-gw-1"), "partner");
+    }
+
+    #[test]
+    fn disabled_redaction_sends_everyone_the_original() {
+        let mut config = config();
+        config.enabled = false;
+        let redactor = EgressRedactor::new(config, BrokerMetrics::new().unwrap());
+        let envelope = envelope();
+        let encoded = Bytes::from(serde_json::to_vec(&envelope).unwrap());
+        let mut fanout = redactor.fanout(&envelope, encoded.clone());
+        for gateway in ["gw-1", "partner-gw-1", "bot-gw"] {
+            assert_eq!(fanout.for_gateway(gateway).unwrap(), encoded);
+        }
+        assert_eq!(fanout.renderings(), 0);
+    }
+
+    #[test]
+    fn pointers_unescape_and_reach_into_arrays() {
+        let mut value = serde_json::json!({ "a/b": 1, "m~n": 2, "list": ["x", "y", "z"], "keep": true });
+        for path in ["/a~1b", "/m~0n", "/list/1", "/list/9", "/missing/deeper", "no-slash"] {
+            remove(&mut value, path);
+        }
+        assert_eq!(value, serde_json::json!({ "list": ["x", "z"], "keep": true }));
+
+        let mut numbers = serde_json::json!({ "n": 5, "s": "secret" });
+        apply(&mut numbers, &rule("/n", RedactionAction::Hash));
+        apply(&mut numbers, &rule("/s", RedactionAction::Hash));
+        assert_eq!(numbers["n"], 5, "non-strings keep their type");
+        assert_eq!(numbers["s"].as_str().unwrap(), hashed("secret"));
+    }
+}
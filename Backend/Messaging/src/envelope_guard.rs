@@ -0,0 +1,423 @@
+use async_nats::HeaderMap;
+
+use crate::{config::EnvelopeGuardConfig, message::types::MessageEnvelope, metrics::BrokerMetrics};
+
+/// Which pre-parse limit an input broke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardKind {
+    Size,
+    Depth,
+    ArrayLength,
+    StringLength,
+    HeaderCount,
+    HeaderSize,
+    Malformed,
+}
+
+impl GuardKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GuardKind::Size => "size",
+            GuardKind::Depth => "depth",
+            GuardKind::ArrayLength => "array_length",
+            GuardKind::StringLength => "string_length",
+            GuardKind::HeaderCount => "header_count",
+            GuardKind::HeaderSize => "header_size",
+            GuardKind::Malformed => "malformed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("envelope rejected by {} guard: {detail}", .kind.as_str())]
+pub struct GuardViolation {
+    pub kind: GuardKind,
+    pub detail: String,
+}
+
+/// Pre-parse limits on untrusted ingress envelopes
+///
+/// Before serde sees a byte, the raw size is checked against
+/// `envelope_guard.max_envelope_bytes` (never above `api.max_frame_size`)
+/// and one linear scan over the JSON bounds nesting depth, elements per
+/// array or object, and string length. The scan keeps one counter per open
+/// container on a fixed stack of at most 64 entries, so rejecting costs
+/// O(bytes) time and no memory proportional to the input; deserializing an
+/// envelope that passed allocates at most what the limits allow. NATS
+/// header blocks are bounded by count and total size the same way.
+/// Violations are counted by guard in `broker_envelope_guard_rejections_total`.
+pub struct EnvelopeGuard {
+    config: EnvelopeGuardConfig,
+    max_bytes: usize,
+    metrics: BrokerMetrics,
+}
+
+impl EnvelopeGuard {
+    pub fn new(config: EnvelopeGuardConfig, max_frame_size: usize, metrics: BrokerMetrics) -> Self {
+        Self {
+            max_bytes: config.max_envelope_bytes.min(max_frame_size),
+            config,
+            metrics,
+        }
+    }
+
+    /// Check limits, then deserialize
+    pub fn parse(&self, raw: &[u8]) -> Result<MessageEnvelope, GuardViolation> {
+        self.check(raw)?;
+        serde_json::from_slice(raw).map_err(|e| self.reject(GuardKind::Malformed, e.to_string()))
+    }
+
+    /// Size and structure limits without deserializing
+    pub fn check(&self, raw: &[u8]) -> Result<(), GuardViolation> {
+        if raw.len() > self.max_bytes {
+            return Err(self.reject(
+                GuardKind::Size,
+                format!("{} bytes exceeds {}", raw.len(), self.max_bytes),
+            ));
+        }
+        scan(raw, &self.config).map_err(|(kind, detail)| self.reject(kind, detail))
+    }
+
+    pub fn check_headers(&self, headers: Option<&HeaderMap>) -> Result<(), GuardViolation> {
+        let Some(headers) = headers else {
+            return Ok(());
+        };
+        let mut count = 0;
+        let mut bytes = 0;
+        for (name, values) in headers.iter() {
+            let name: &str = name.as_ref();
+            for value in values {
+                count += 1;
+                bytes += name.len() + value.as_str().len();
+                if count > self.config.max_headers {
+                    return Err(self.reject(
+                        GuardKind::HeaderCount,
+                        format!("more than {} headers", self.config.max_headers),
+                    ));
+                }
+                if bytes > self.config.max_header_bytes {
+                    return Err(self.reject(
+                        GuardKind::HeaderSize,
+                        format!("headers exceed {} bytes", self.config.max_header_bytes),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reject(&self, kind: GuardKind, detail: String) -> GuardViolation {
+        self.metrics.record_envelope_guard_rejection(kind.as_str());
+        GuardViolation { kind, detail }
+    }
+}
+
+/// One pass over `raw` enforcing depth, container length and string length
+///
+/// Not a validator: anything structurally odd that slips past is left for
+/// serde to reject. Only brackets outside strings count, and escapes are
+/// skipped so `"\""` doesn't end a string early.
+fn scan(raw: &[u8], limits: &EnvelopeGuardConfig) -> Result<(), (GuardKind, String)> {
+    /// Separators seen per open container; `max_depth` is capped so this fits
+    const STACK: usize = 64;
+    let max_depth = limits.max_depth.min(STACK);
+    let mut separators = [0usize; STACK];
+    let mut depth = 0usize;
+
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut string_len = 0usize;
+
+    for &byte in raw {
+        if in_string {
+            string_len += 1;
+            if string_len > limits.max_string_bytes {
+                return Err((
+                    GuardKind::StringLength,
+                    format!("string longer than {} bytes", limits.max_string_bytes),
+                ));
+            }
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => {
+                in_string = true;
+                string_len = 0;
+            }
+            b'{' | b'[' => {
+                if depth == max_depth {
+                    return Err((GuardKind::Depth, format!("nesting deeper than {}", max_depth)));
+                }
+                separators[depth] = 0;
+                depth += 1;
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            // n separators mean n + 1 elements
+            b',' if depth > 0 => {
+                separators[depth - 1] += 1;
+                if separators[depth - 1] >= limits.max_elements {
+                    return Err((
+                        GuardKind::ArrayLength,
+                        format!("more than {} elements in one array or object", limits.max_elements),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Counts this thread's allocations while `COUNTING` is set, for tests of
+/// paths that mustn't allocate. Only without jemalloc, which is the global
+/// allocator otherwise: `cargo test --no-default-features --features tls`
+#[cfg(all(test, not(feature = "jemalloc")))]
+pub(crate) mod allocations {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    struct Counting;
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = COUNTING.try_with(|counting| {
+                if counting.get() {
+                    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+                }
+            });
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Bytes allocated on this thread while running `f`
+    pub fn measure(f: impl FnOnce()) -> usize {
+        ALLOCATED.with(|allocated| allocated.set(0));
+        COUNTING.with(|counting| counting.set(true));
+        f();
+        COUNTING.with(|counting| counting.set(false));
+        ALLOCATED.with(Cell::get)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    /// Defaults: 256KB, depth 16, 10,000 elements, 128KB strings, 64 headers in 16KB
+    fn guard() -> EnvelopeGuard {
+        let config = BrokerConfig::load().unwrap();
+        EnvelopeGuard::new(config.envelope_guard, usize::MAX, BrokerMetrics::new().unwrap())
+    }
+
+    fn envelope() -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::TextMessage,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    /// A well-formed envelope with `field` replaced by `value`
+    fn envelope_with(field: &str, value: Value) -> Vec<u8> {
+        let mut json = serde_json::to_value(envelope()).unwrap();
+        json[field] = value;
+        serde_json::to_vec(&json).unwrap()
+    }
+
+    fn recipients(count: usize) -> Vec<u8> {
+        envelope_with("to", Value::Array((0..count).map(|i| Value::String(format!("u{}", i))).collect()))
+    }
+
+    fn deep(depth: usize) -> Vec<u8> {
+        let mut raw = vec![b'['; depth];
+        raw.extend(std::iter::repeat_n(b']', depth));
+        raw
+    }
+
+    fn long_ciphertext(bytes: usize) -> Vec<u8> {
+        envelope_with(
+            "payload",
+            serde_json::json!({ "ciphertext": "A".repeat(bytes), "iv": null, "tag": null }),
+        )
+    }
+
+    fn kind(result: Result<(), GuardViolation>) -> Option<GuardKind> {
+        result.err().map(|violation| violation.kind)
+    }
+
+    /// Inputs that pass the size cap and are built to hurt the parser
+    fn adversarial() -> Vec<(GuardKind, Vec<u8>)> {
+        vec![
+            (GuardKind::Depth, deep(100_000)),
+            (GuardKind::Depth, b"{\"a\":".repeat(20_000)),
+            (GuardKind::ArrayLength, recipients(20_000)),
+            (GuardKind::ArrayLength, b"[".iter().chain(b"0,".repeat(120_000).iter()).copied().collect()),
+            (GuardKind::StringLength, long_ciphertext(200_000)),
+        ]
+    }
+
+    #[test]
+    fn well_formed_envelopes_parse() {
+        let raw = serde_json::to_vec(&envelope()).unwrap();
+        let parsed = guard().parse(&raw).unwrap();
+        assert_eq!(parsed.to, vec!["bob"]);
+
+        // Limits are inclusive, and brackets or quotes inside strings don't count
+        guard().check(&recipients(10_000)).unwrap();
+        guard().check(&deep(16)).unwrap();
+        guard().check(&long_ciphertext(131_000)).unwrap();
+        guard().check(br#"{"a":"[[[[{{{{\"]]]]\\","b":[1]}"#).unwrap();
+    }
+
+    #[test]
+    fn oversized_envelopes_are_rejected_before_scanning() {
+        let huge = recipients(1_000_000);
+        assert!(huge.len() > 8 * 1024 * 1024);
+        assert_eq!(kind(guard().check(&huge)), Some(GuardKind::Size));
+
+        // The frame size applies when it is the smaller cap
+        let config = BrokerConfig::load().unwrap().envelope_guard;
+        let guard = EnvelopeGuard::new(config, 1_000, BrokerMetrics::new().unwrap());
+        assert_eq!(kind(guard.check(&long_ciphertext(2_000))), Some(GuardKind::Size));
+        guard.check(&serde_json::to_vec(&envelope()).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn adversarial_structures_are_rejected_by_their_guard() {
+        let guard = guard();
+        for (expected, raw) in adversarial() {
+            assert!(raw.len() <= 256 * 1024, "{:?} input is {} bytes", expected, raw.len());
+            assert_eq!(kind(guard.check(&raw)), Some(expected));
+            assert_eq!(guard.parse(&raw).unwrap_err().kind, expected);
+        }
+    }
+
+    #[test]
+    fn depth_is_capped_at_the_fixed_stack() {
+        let mut config = BrokerConfig::load().unwrap().envelope_guard;
+        config.max_depth = 10_000;
+        let guard = EnvelopeGuard::new(config, usize::MAX, BrokerMetrics::new().unwrap());
+        guard.check(&deep(64)).unwrap();
+        assert_eq!(kind(guard.check(&deep(65))), Some(GuardKind::Depth));
+    }
+
+    #[test]
+    fn malformed_json_that_passes_the_scan_is_left_to_serde() {
+        assert_eq!(guard().parse(b"{\"from\":").unwrap_err().kind, GuardKind::Malformed);
+        assert_eq!(guard().parse(b"]]]]").unwrap_err().kind, GuardKind::Malformed);
+    }
+
+    #[test]
+    fn header_bombs_are_bounded() {
+        let guard = guard();
+        guard.check_headers(None).unwrap();
+
+        let mut headers = HeaderMap::new();
+        for i in 0..64 {
+            headers.insert(format!("x-h{}", i).as_str(), "v");
+        }
+        guard.check_headers(Some(&headers)).unwrap();
+        headers.insert("x-one-too-many", "v");
+        assert_eq!(kind(guard.check_headers(Some(&headers))), Some(GuardKind::HeaderCount));
+
+        // Repeated values of one header count one by one
+        let mut headers = HeaderMap::new();
+        for _ in 0..65 {
+            headers.append("x-repeat", "v");
+        }
+        assert_eq!(kind(guard.check_headers(Some(&headers))), Some(GuardKind::HeaderCount));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-big", "v".repeat(20_000).as_str());
+        assert_eq!(kind(guard.check_headers(Some(&headers))), Some(GuardKind::HeaderSize));
+    }
+
+    #[test]
+    fn rejections_are_counted_by_guard() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let guard = guard();
+            for (_, raw) in adversarial() {
+                let _ = guard.check(&raw);
+            }
+            let _ = guard.check(&recipients(1_000_000));
+        });
+
+        let rendered = handle.render();
+        for line in [
+            r#"broker_envelope_guard_rejections_total{guard="depth"} 2"#,
+            r#"broker_envelope_guard_rejections_total{guard="array_length"} 2"#,
+            r#"broker_envelope_guard_rejections_total{guard="string_length"} 1"#,
+            r#"broker_envelope_guard_rejections_total{guard="size"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, ignore = "timing is only meaningful in release builds")]
+    fn rejecting_costs_a_linear_scan_at_most() {
+        let guard = guard();
+        for (expected, raw) in adversarial() {
+            let started = Instant::now();
+            for _ in 0..100 {
+                assert!(guard.check(&raw).is_err());
+            }
+            // 100 scans of up to 256KB; well over 1GB/s in practice
+            let elapsed = started.elapsed();
+            assert!(elapsed < Duration::from_millis(250), "{:?} rejections took {:?}", expected, elapsed);
+        }
+    }
+
+    #[cfg(not(feature = "jemalloc"))]
+    #[test]
+    fn rejecting_allocates_nothing_proportional_to_the_input() {
+        let guard = guard();
+        let mut inputs = adversarial();
+        inputs.push((GuardKind::Size, recipients(1_000_000)));
+        for (expected, raw) in inputs {
+            // Only the violation's detail string and the metric key are allocated
+            let allocated = allocations::measure(|| assert_eq!(kind(guard.check(&raw)), Some(expected)));
+            assert!(allocated < 1024, "{:?} rejection of {} bytes allocated {}", expected, raw.len(), allocated);
+        }
+    }
+}
@@ -0,0 +1,897 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use async_nats::{jetstream, HeaderMap};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use tonic::{
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig},
+    Request, Response, Status,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    api::proto::{
+        broker_peer_client::BrokerPeerClient, broker_peer_server::BrokerPeer, ForwardMessageRequest,
+        ForwardMessageResponse, IngestMetadata as ProtoIngestMetadata,
+    },
+    cluster::{ClusterView, PeerInfo},
+    config::{ApiConfig, ClusterConfig, NatsConfig},
+    conversation_home::{ConversationHomes, HomeError, HomeRoute},
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+    nats_probe::NatsProbe,
+    path_override::{PathContext, PathFeature, PathOverrides},
+};
+
+pub const ORIGIN_BROKER_HEADER: &str = "Broker-Origin";
+pub const ORIGIN_GATEWAY_HEADER: &str = "Broker-Origin-Gateway";
+pub const ORIGIN_SERVICE_ACCOUNT_HEADER: &str = "Broker-Origin-Service-Account";
+pub const RECEIVED_AT_HEADER: &str = "Broker-Received-At";
+
+/// Where and when a message entered the cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngestMetadata {
+    pub origin_broker: String,
+    pub gateway_id: String,
+    pub service_account: Option<String>,
+    /// Timestamp in milliseconds
+    pub received_at: i64,
+    /// Arrived over `ForwardMessage`; never forwarded again
+    pub forwarded: bool,
+    /// Region that forwarded it here as the conversation's home; never forwarded again
+    pub home_forwarded_from: Option<String>,
+}
+
+impl IngestMetadata {
+    pub fn to_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(ORIGIN_BROKER_HEADER, self.origin_broker.as_str());
+        headers.insert(ORIGIN_GATEWAY_HEADER, self.gateway_id.as_str());
+        if let Some(account) = &self.service_account {
+            headers.insert(ORIGIN_SERVICE_ACCOUNT_HEADER, account.as_str());
+        }
+        headers.insert(RECEIVED_AT_HEADER, self.received_at.to_string().as_str());
+    }
+
+    fn to_proto(&self) -> ProtoIngestMetadata {
+        ProtoIngestMetadata {
+            origin_broker: self.origin_broker.clone(),
+            gateway_id: self.gateway_id.clone(),
+            service_account: self.service_account.clone().unwrap_or_default(),
+            received_at: self.received_at,
+        }
+    }
+
+    fn from_proto(metadata: ProtoIngestMetadata) -> Self {
+        Self {
+            origin_broker: metadata.origin_broker,
+            gateway_id: metadata.gateway_id,
+            service_account: Some(metadata.service_account).filter(|a| !a.is_empty()),
+            received_at: metadata.received_at,
+            forwarded: true,
+            home_forwarded_from: None,
+        }
+    }
+}
+
+/// Local processing entry point for messages this broker owns
+#[async_trait]
+pub trait LocalIngest: Send + Sync {
+    async fn ingest(&self, envelope: MessageEnvelope, metadata: IngestMetadata) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteOutcome {
+    /// This broker owns the conversation; process locally
+    Local,
+    /// Delivered to the owner over `ForwardMessage`
+    Forwarded { broker_id: String },
+    /// Published to the NATS ingress subject for the owner to consume
+    Published,
+    /// Published to the ingress subject of the conversation's home region
+    HomeRegion { region: String },
+}
+
+/// Sends validated ingress messages straight to the owning broker
+///
+/// Falls back to the NATS ingress path when forwarding is disabled, the
+/// owner advertises no gRPC address, or the RPC fails. Messages that
+/// already arrived by forwarding are always processed locally. With
+/// conversation homes enabled, conversations homed in another region go
+/// there before any of this. A path override can force or forbid direct
+/// forwarding for matching traffic. Peers only accept calls made with a
+/// client certificate, so without the gRPC listener certificate to present
+/// every message takes the NATS path.
+pub struct PeerForwarder {
+    cluster: Arc<ClusterView>,
+    homes: Arc<ConversationHomes>,
+    overrides: Arc<PathOverrides>,
+    probe: Arc<NatsProbe>,
+    clients: DashMap<String, BrokerPeerClient<Channel>>,
+    tls: Option<ClientTlsConfig>,
+    enabled: bool,
+    timeout: Duration,
+    jetstream: jetstream::Context,
+    ingress_subject: String,
+    metrics: BrokerMetrics,
+}
+
+impl PeerForwarder {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        cluster: Arc<ClusterView>,
+        homes: Arc<ConversationHomes>,
+        overrides: Arc<PathOverrides>,
+        probe: Arc<NatsProbe>,
+        config: &ClusterConfig,
+        api: &ApiConfig,
+        nats: &NatsConfig,
+        jetstream: jetstream::Context,
+        metrics: BrokerMetrics,
+    ) -> anyhow::Result<Self> {
+        // Brokers authenticate each other with the gRPC listener certificate
+        let tls = match (&api.grpc_tls_cert, &api.grpc_tls_key) {
+            (Some(cert), Some(key)) => {
+                let mut tls = ClientTlsConfig::new().identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+                if let Some(ca) = &nats.tls_ca {
+                    tls = tls.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+                }
+                Some(tls)
+            }
+            _ => None,
+        };
+        let enabled = config.direct_forwarding && tls.is_some();
+        if config.direct_forwarding && !enabled {
+            warn!("Direct forwarding needs api.grpc_tls_cert and api.grpc_tls_key to authenticate to peers; using NATS");
+        }
+
+        Ok(Self {
+            cluster,
+            homes,
+            overrides,
+            probe,
+            clients: DashMap::new(),
+            tls,
+            enabled,
+            timeout: Duration::from_millis(config.forward_timeout_ms),
+            jetstream,
+            ingress_subject: nats.ingress_topic.clone(),
+            metrics,
+        })
+    }
+
+    pub async fn route(&self, envelope: &MessageEnvelope, metadata: &IngestMetadata) -> Result<RouteOutcome, ForwardError> {
+        if let HomeRoute::Forwarded { region } = self.homes.route(envelope, metadata).await? {
+            return Ok(RouteOutcome::HomeRegion { region });
+        }
+        if metadata.forwarded {
+            return Ok(RouteOutcome::Local);
+        }
+        let conversation_id = envelope.conversation_id();
+        let Some(owner) = self.cluster.remote_owner(&conversation_id) else {
+            return Ok(RouteOutcome::Local);
+        };
+
+        let path = PathContext::of(envelope, &conversation_id, Some(&metadata.gateway_id));
+        if self.overrides.decide(&path, PathFeature::DirectForwarding, self.enabled) {
+            match self.forward(&owner, envelope, metadata).await {
+                Ok(()) => return Ok(RouteOutcome::Forwarded { broker_id: owner.broker_id }),
+                Err(reason) => {
+                    debug!("Forward to {} failed ({}), using NATS", owner.broker_id, reason);
+                    self.metrics.record_peer_forward(reason);
+                }
+            }
+        }
+
+        self.publish(envelope, metadata).await?;
+        Ok(RouteOutcome::Published)
+    }
+
+    async fn forward(&self, owner: &PeerInfo, envelope: &MessageEnvelope, metadata: &IngestMetadata) -> Result<(), &'static str> {
+        let addr = owner.grpc_addr.as_deref().ok_or("fallback_no_address")?;
+        // A path override can force forwarding that has no identity to present
+        let tls = self.tls.as_ref().ok_or("fallback_no_tls")?;
+        let mut client = self.client(addr, tls).map_err(|_| "fallback_bad_address")?;
+        let request = ForwardMessageRequest {
+            envelope: serde_json::to_vec(envelope).map_err(|_| "fallback_encode")?,
+            metadata: Some(metadata.to_proto()),
+        };
+
+        let started = Instant::now();
+        let response = tokio::time::timeout(self.timeout, client.forward_message(request))
+            .await
+            .map_err(|_| "fallback_timeout")?
+            .map_err(|status| {
+                warn!("ForwardMessage to {} failed: {}", owner.broker_id, status);
+                self.clients.remove(addr);
+                "fallback_rpc_error"
+            })?
+            .into_inner();
+
+        if !response.accepted {
+            debug!("Peer {} refused forwarded message: {}", owner.broker_id, response.error);
+            return Err("fallback_refused");
+        }
+        self.metrics.record_peer_forward("forwarded");
+        self.metrics.record_peer_forward_latency(started.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    async fn publish(&self, envelope: &MessageEnvelope, metadata: &IngestMetadata) -> Result<(), ForwardError> {
+        let mut headers = HeaderMap::new();
+        metadata.to_headers(&mut headers);
+        headers.insert("Nats-Msg-Id", envelope.message_id.as_str());
+
+        let payload = serde_json::to_vec(envelope).map_err(|e| ForwardError(e.to_string()))?;
+        self.jetstream
+            .publish_with_headers(self.ingress_subject.clone(), headers, payload.into())
+            .await
+            .map_err(|e| ForwardError(e.to_string()))?
+            .await
+            .map_err(|e| ForwardError(e.to_string()))?;
+        self.probe.observe_traffic();
+        self.metrics.record_peer_forward("published");
+        Ok(())
+    }
+
+    fn client(&self, addr: &str, tls: &ClientTlsConfig) -> Result<BrokerPeerClient<Channel>, tonic::transport::Error> {
+        if let Some(client) = self.clients.get(addr) {
+            return Ok(client.clone());
+        }
+
+        let endpoint = Endpoint::from_shared(format!("https://{}", addr))?
+            .connect_timeout(self.timeout)
+            .tls_config(tls.clone())?;
+        let client = BrokerPeerClient::new(endpoint.connect_lazy());
+        self.clients.insert(addr.to_string(), client.clone());
+        Ok(client)
+    }
+}
+
+/// TLS for the listener serving `BrokerPeer`, requiring client certificates
+///
+/// Peers present their gRPC listener certificate as their client identity
+/// (see `PeerForwarder::new`), so callers are verified against the same CA
+/// the forwarder verifies peers with, `nats.tls_ca`. `None` without a
+/// listener certificate and key.
+pub fn peer_server_tls(api: &ApiConfig, nats: &NatsConfig) -> anyhow::Result<Option<ServerTlsConfig>> {
+    let (Some(cert), Some(key)) = (&api.grpc_tls_cert, &api.grpc_tls_key) else {
+        return Ok(None);
+    };
+    let ca = nats
+        .tls_ca
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("nats.tls_ca is required to verify peer brokers' certificates"))?;
+    let tls = ServerTlsConfig::new()
+        .identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?))
+        .client_ca_root(Certificate::from_pem(std::fs::read(ca)?));
+    Ok(Some(tls))
+}
+
+/// Server side of `ForwardMessage`
+///
+/// Serve it with `peer_server_tls`, whose handshake rejects certificates
+/// the cluster CA didn't issue. Calls without a client certificate, such as
+/// any made over a plaintext listener, are refused, so a caller that can
+/// merely reach the port can't inject messages as a peer.
+pub struct PeerService {
+    local: Arc<dyn LocalIngest>,
+}
+
+impl PeerService {
+    pub fn new(local: Arc<dyn LocalIngest>) -> Self {
+        Self { local }
+    }
+}
+
+#[tonic::async_trait]
+impl BrokerPeer for PeerService {
+    async fn forward_message(
+        &self,
+        request: Request<ForwardMessageRequest>,
+    ) -> Result<Response<ForwardMessageResponse>, Status> {
+        if request.peer_certs().is_none_or(|certs| certs.is_empty()) {
+            return Err(Status::unauthenticated("ForwardMessage requires a peer certificate"));
+        }
+        let request = request.into_inner();
+        let envelope: MessageEnvelope = serde_json::from_slice(&request.envelope)
+            .map_err(|e| Status::invalid_argument(format!("malformed envelope: {}", e)))?;
+        let metadata = request
+            .metadata
+            .map(IngestMetadata::from_proto)
+            .ok_or_else(|| Status::invalid_argument("metadata is required"))?;
+
+        let response = match self.local.ingest(envelope, metadata).await {
+            Ok(()) => ForwardMessageResponse {
+                accepted: true,
+                error: String::new(),
+            },
+            Err(error) => ForwardMessageResponse { accepted: false, error },
+        };
+        Ok(Response::new(response))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("ingress publish failed: {0}")]
+pub struct ForwardError(pub String);
+
+impl From<HomeError> for ForwardError {
+    fn from(e: HomeError) -> Self {
+        ForwardError(e.to_string())
+    }
+}
+
+/// The two-broker tests start their own JetStream-enabled `nats-server`:
+/// `cargo test -- --ignored forward`
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, TcpListener},
+        path::PathBuf,
+        process::{Child, Command},
+    };
+
+    use async_nats::jetstream::{kv, stream};
+    use parking_lot::Mutex;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use tokio::sync::oneshot;
+    use tonic::transport::Server;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        api::proto::broker_peer_server::BrokerPeerServer,
+        audit::AuditLog,
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+        path_override::{OverrideEffect, PathOverrideRule},
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        ingested: Mutex<Vec<(MessageEnvelope, IngestMetadata)>>,
+        refuse: Option<String>,
+    }
+
+    #[async_trait]
+    impl LocalIngest for Recorder {
+        async fn ingest(&self, envelope: MessageEnvelope, metadata: IngestMetadata) -> Result<(), String> {
+            if let Some(error) = &self.refuse {
+                return Err(error.clone());
+            }
+            self.ingested.lock().push((envelope, metadata));
+            Ok(())
+        }
+    }
+
+    fn envelope(to: &str) -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::TextMessage,
+            "alice".into(),
+            vec![to.into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    fn metadata(origin: &str) -> IngestMetadata {
+        IngestMetadata {
+            origin_broker: origin.into(),
+            gateway_id: "gw-1".into(),
+            service_account: None,
+            received_at: 1_700_000_000_000,
+            forwarded: false,
+            home_forwarded_from: None,
+        }
+    }
+
+    #[test]
+    fn metadata_survives_the_wire_and_arrives_as_forwarded() {
+        let mut sent = metadata("broker-a");
+        sent.service_account = Some("billing".into());
+
+        let received = IngestMetadata::from_proto(sent.to_proto());
+        assert_eq!(received.origin_broker, "broker-a");
+        assert_eq!(received.gateway_id, "gw-1");
+        assert_eq!(received.service_account.as_deref(), Some("billing"));
+        assert_eq!(received.received_at, sent.received_at);
+        assert!(received.forwarded);
+
+        let unattributed = IngestMetadata::from_proto(metadata("broker-a").to_proto());
+        assert_eq!(unattributed.service_account, None);
+    }
+
+    #[test]
+    fn headers_carry_the_original_ingest_metadata() {
+        let mut headers = HeaderMap::new();
+        metadata("broker-a").to_headers(&mut headers);
+        assert_eq!(headers.get(ORIGIN_BROKER_HEADER).map(|v| v.as_str()), Some("broker-a"));
+        assert_eq!(headers.get(ORIGIN_GATEWAY_HEADER).map(|v| v.as_str()), Some("gw-1"));
+        assert_eq!(headers.get(RECEIVED_AT_HEADER).map(|v| v.as_str()), Some("1700000000000"));
+        assert!(headers.get(ORIGIN_SERVICE_ACCOUNT_HEADER).is_none());
+    }
+
+    /// A cluster CA that issued `broker`, and an `outsider` issued by another CA
+    struct PeerPki {
+        dir: PathBuf,
+    }
+
+    impl PeerPki {
+        fn generate() -> Self {
+            let dir = std::env::temp_dir().join(format!("forward-pki-{}", Uuid::new_v4().simple()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let ca = || {
+                let mut params = CertificateParams::new(Vec::new());
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                rcgen::Certificate::from_params(params).unwrap()
+            };
+            let issue = |name: &str, issuer: &rcgen::Certificate| {
+                let names = vec!["127.0.0.1".to_string(), "localhost".to_string()];
+                let leaf = rcgen::Certificate::from_params(CertificateParams::new(names)).unwrap();
+                std::fs::write(dir.join(format!("{}.pem", name)), leaf.serialize_pem_with_signer(issuer).unwrap()).unwrap();
+                std::fs::write(dir.join(format!("{}.key", name)), leaf.serialize_private_key_pem()).unwrap();
+            };
+
+            let cluster_ca = ca();
+            std::fs::write(dir.join("ca.pem"), cluster_ca.serialize_pem().unwrap()).unwrap();
+            issue("broker", &cluster_ca);
+            issue("outsider", &ca());
+            Self { dir }
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_string_lossy().into_owned()
+        }
+
+        /// Present `identity` and trust the cluster CA
+        fn configure(&self, config: &mut BrokerConfig, identity: &str) {
+            config.api.grpc_tls_cert = Some(self.path(&format!("{}.pem", identity)));
+            config.api.grpc_tls_key = Some(self.path(&format!("{}.key", identity)));
+            config.nats.tls_ca = Some(self.path("ca.pem"));
+        }
+
+        fn client(&self, addr: SocketAddr, identity: Option<&str>) -> BrokerPeerClient<Channel> {
+            let ca = std::fs::read(self.path("ca.pem")).unwrap();
+            let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+            if let Some(identity) = identity {
+                let cert = std::fs::read(self.path(&format!("{}.pem", identity))).unwrap();
+                let key = std::fs::read(self.path(&format!("{}.key", identity))).unwrap();
+                tls = tls.identity(Identity::from_pem(cert, key));
+            }
+            let endpoint = Endpoint::from_shared(format!("https://{}", addr)).unwrap().tls_config(tls).unwrap();
+            BrokerPeerClient::new(endpoint.connect_lazy())
+        }
+    }
+
+    impl Drop for PeerPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// The peer listener as a broker serves it, on a free port
+    struct PeerListener {
+        addr: SocketAddr,
+        shutdown: oneshot::Sender<()>,
+        server: tokio::task::JoinHandle<()>,
+    }
+
+    impl PeerListener {
+        async fn start(config: &BrokerConfig, recorder: Arc<Recorder>) -> Self {
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let tls = peer_server_tls(&config.api, &config.nats).unwrap().unwrap();
+            let (shutdown, signal) = oneshot::channel::<()>();
+            let mut builder = Server::builder().tls_config(tls).unwrap();
+            let router = builder.add_service(BrokerPeerServer::new(PeerService::new(recorder)));
+            let server = tokio::spawn(async move {
+                router
+                    .serve_with_shutdown(addr, async {
+                        signal.await.ok();
+                    })
+                    .await
+                    .unwrap();
+            });
+            while tokio::net::TcpStream::connect(addr).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Self { addr, shutdown, server }
+        }
+
+        async fn stop(self) {
+            self.shutdown.send(()).unwrap();
+            self.server.await.unwrap();
+        }
+    }
+
+    fn forward_request(envelope: &MessageEnvelope, metadata: Option<&IngestMetadata>) -> ForwardMessageRequest {
+        ForwardMessageRequest {
+            envelope: serde_json::to_vec(envelope).unwrap(),
+            metadata: metadata.map(IngestMetadata::to_proto),
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_without_a_peer_certificate_are_refused() {
+        let recorder = Arc::new(Recorder::default());
+        let service = PeerService::new(recorder.clone());
+
+        // As over a plaintext listener: nothing verified the caller
+        let request = Request::new(forward_request(&envelope("bob"), Some(&metadata("broker-a"))));
+        let status = service.forward_message(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert!(recorder.ingested.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peer_service_ingests_locally_as_forwarded() {
+        let pki = PeerPki::generate();
+        let mut config = BrokerConfig::load().unwrap();
+        pki.configure(&mut config, "broker");
+        let recorder = Arc::new(Recorder::default());
+        let listener = PeerListener::start(&config, recorder.clone()).await;
+        let sent = envelope("bob");
+
+        let mut client = pki.client(listener.addr, Some("broker"));
+        let response = client
+            .forward_message(forward_request(&sent, Some(&metadata("broker-a"))))
+            .await
+            .unwrap();
+        assert!(response.get_ref().accepted);
+
+        {
+            let ingested = recorder.ingested.lock();
+            assert_eq!(ingested.len(), 1);
+            assert_eq!(ingested[0].0.message_id, sent.message_id);
+            assert_eq!(ingested[0].1.origin_broker, "broker-a");
+            assert!(ingested[0].1.forwarded);
+        }
+        listener.stop().await;
+    }
+
+    #[tokio::test]
+    async fn peer_service_reports_refusals_and_rejects_bad_requests() {
+        let pki = PeerPki::generate();
+        let mut config = BrokerConfig::load().unwrap();
+        pki.configure(&mut config, "broker");
+        let recorder = Arc::new(Recorder {
+            refuse: Some("rate limited".into()),
+            ..Default::default()
+        });
+        let listener = PeerListener::start(&config, recorder).await;
+        let mut client = pki.client(listener.addr, Some("broker"));
+        let sent = envelope("bob");
+
+        let response = client
+            .forward_message(forward_request(&sent, Some(&metadata("broker-a"))))
+            .await
+            .unwrap();
+        assert!(!response.get_ref().accepted);
+        assert_eq!(response.get_ref().error, "rate limited");
+
+        let status = client.forward_message(forward_request(&sent, None)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let malformed = ForwardMessageRequest {
+            envelope: b"not json".to_vec(),
+            metadata: Some(metadata("broker-a").to_proto()),
+        };
+        let status = client.forward_message(malformed).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        listener.stop().await;
+    }
+
+    #[tokio::test]
+    async fn callers_outside_the_cluster_ca_never_reach_the_service() {
+        let pki = PeerPki::generate();
+        let mut config = BrokerConfig::load().unwrap();
+        pki.configure(&mut config, "broker");
+        let recorder = Arc::new(Recorder::default());
+        let listener = PeerListener::start(&config, recorder.clone()).await;
+        let request = || forward_request(&envelope("bob"), Some(&metadata("broker-a")));
+
+        // No client certificate at all, then one some other CA issued
+        assert!(pki.client(listener.addr, None).forward_message(request()).await.is_err());
+        assert!(pki.client(listener.addr, Some("outsider")).forward_message(request()).await.is_err());
+        assert!(recorder.ingested.lock().is_empty());
+        listener.stop().await;
+    }
+
+    #[test]
+    fn the_peer_listener_needs_the_cluster_ca_to_verify_callers() {
+        let pki = PeerPki::generate();
+        let mut config = BrokerConfig::load().unwrap();
+        config.api.grpc_tls_cert = None;
+        assert!(peer_server_tls(&config.api, &config.nats).unwrap().is_none());
+
+        pki.configure(&mut config, "broker");
+        config.nats.tls_ca = None;
+        assert!(peer_server_tls(&config.api, &config.nats).is_err());
+    }
+
+    /// `nats-server` with JetStream on a free port
+    struct NatsServer {
+        process: Child,
+        url: String,
+        store: PathBuf,
+    }
+
+    impl NatsServer {
+        async fn start() -> Self {
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let store = std::env::temp_dir().join(format!("forward-js-{}", port));
+            let process = Command::new("nats-server")
+                .args(["-js", "-a", "127.0.0.1", "-p", &port.to_string(), "-sd"])
+                .arg(&store)
+                .spawn()
+                .unwrap();
+            let url = format!("127.0.0.1:{}", port);
+            // Wait for the server to accept connections
+            for _ in 0..50 {
+                if tokio::net::TcpStream::connect(&url).await.is_ok() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Self { process, url, store }
+        }
+    }
+
+    impl Drop for NatsServer {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = std::fs::remove_dir_all(&self.store);
+        }
+    }
+
+    struct Broker {
+        cluster: Arc<ClusterView>,
+        forwarder: PeerForwarder,
+        recorder: Arc<Recorder>,
+        listener: Option<PeerListener>,
+    }
+
+    impl Broker {
+        /// Stop the `ForwardMessage` listener, leaving the advertised address in the cluster view
+        async fn stop_listener(&mut self) {
+            self.listener.take().unwrap().stop().await;
+        }
+    }
+
+    struct Cluster {
+        _server: NatsServer,
+        pki: PeerPki,
+        jetstream: jetstream::Context,
+        stream: String,
+        ingress_subject: String,
+    }
+
+    impl Cluster {
+        async fn new() -> (Self, kv::Store, async_nats::Client) {
+            let server = NatsServer::start().await;
+            let client = async_nats::connect(&server.url).await.unwrap();
+            let jetstream = jetstream::new(client.clone());
+            let kv = jetstream
+                .create_key_value(kv::Config {
+                    bucket: "forward-test".into(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let stream = "forward-test".to_string();
+            let ingress_subject = "ingress-test".to_string();
+            jetstream
+                .create_stream(stream::Config {
+                    name: stream.clone(),
+                    subjects: vec![ingress_subject.clone()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let cluster = Self {
+                _server: server,
+                pki: PeerPki::generate(),
+                jetstream,
+                stream,
+                ingress_subject,
+            };
+            (cluster, kv, client)
+        }
+
+        /// Messages that took the NATS ingress hop
+        async fn published(&self) -> u64 {
+            self.jetstream.get_stream(&self.stream).await.unwrap().info().await.unwrap().state.messages
+        }
+
+        async fn broker(&self, kv: &kv::Store, client: &async_nats::Client, broker_id: &str, listen: bool) -> Broker {
+            let mut config = BrokerConfig::load().unwrap();
+            config.nats.ingress_topic = self.ingress_subject.clone();
+            config.cluster.heartbeat_interval = Duration::from_millis(100);
+            config.cluster.direct_forwarding = true;
+            config.cluster.forward_timeout_ms = 500;
+            self.pki.configure(&mut config, "broker");
+
+            let recorder = Arc::new(Recorder::default());
+            let mut listener = None;
+            if listen {
+                let started = PeerListener::start(&config, recorder.clone()).await;
+                config.cluster.advertise_grpc_addr = Some(started.addr.to_string());
+                listener = Some(started);
+            }
+
+            let metrics = BrokerMetrics::new().unwrap();
+            let audit = AuditLog::tracing_only();
+            let cluster = Arc::new(ClusterView::new(kv.clone(), broker_id.into(), &config.cluster));
+            cluster.spawn();
+            let homes = Arc::new(ConversationHomes::new(
+                config.conversation_home.clone(),
+                kv.clone(),
+                self.jetstream.clone(),
+                audit.clone(),
+                metrics.clone(),
+            ));
+            let overrides = Arc::new(PathOverrides::new(config.path_override.clone(), audit, metrics.clone()));
+            let probe = Arc::new(NatsProbe::new(client.clone(), cluster.clone(), config.nats_probe.clone(), metrics.clone()));
+            let forwarder = PeerForwarder::new(
+                cluster.clone(),
+                homes,
+                overrides,
+                probe,
+                &config.cluster,
+                &config.api,
+                &config.nats,
+                self.jetstream.clone(),
+                metrics,
+            )
+            .unwrap();
+
+            Broker {
+                cluster,
+                forwarder,
+                recorder,
+                listener,
+            }
+        }
+    }
+
+    async fn converge(brokers: &[&Broker]) {
+        while brokers.iter().any(|broker| broker.cluster.members().len() < brokers.len()) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// A message ingested at `from` for a conversation owned by `owner`
+    fn owned_by(from: &Broker, owner: &str) -> MessageEnvelope {
+        (0..)
+            .map(|i| envelope(&format!("user-{}", i)))
+            .find(|envelope| {
+                from.cluster
+                    .remote_owner(&envelope.conversation_id())
+                    .is_some_and(|peer| peer.broker_id == owner)
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn forwarding_skips_the_nats_hop_and_falls_back_when_the_listener_stops() {
+        let (cluster, kv, client) = Cluster::new().await;
+        let a = cluster.broker(&kv, &client, "broker-a", true).await;
+        let mut b = cluster.broker(&kv, &client, "broker-b", true).await;
+        tokio::time::timeout(Duration::from_secs(5), converge(&[&a, &b])).await.unwrap();
+
+        // Direct: one RPC to the owner, nothing through the ingress stream
+        for _ in 0..10 {
+            let outcome = a.forwarder.route(&owned_by(&a, "broker-b"), &metadata("broker-a")).await.unwrap();
+            assert_eq!(outcome, RouteOutcome::Forwarded { broker_id: "broker-b".into() });
+        }
+        assert!(b.recorder.ingested.lock().iter().all(|(_, m)| m.forwarded && m.origin_broker == "broker-a"));
+        assert_eq!(b.recorder.ingested.lock().len(), 10);
+        assert_eq!(cluster.published().await, 0);
+
+        // Listener gone but still advertised: the RPC fails and NATS takes over
+        b.stop_listener().await;
+        let outcome = a.forwarder.route(&owned_by(&a, "broker-b"), &metadata("broker-a")).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::Published);
+        assert_eq!(b.recorder.ingested.lock().len(), 10);
+        assert_eq!(cluster.published().await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn peers_without_an_address_get_the_nats_path() {
+        let (cluster, kv, client) = Cluster::new().await;
+        let a = cluster.broker(&kv, &client, "broker-a", true).await;
+        let b = cluster.broker(&kv, &client, "broker-b", false).await;
+        tokio::time::timeout(Duration::from_secs(5), converge(&[&a, &b])).await.unwrap();
+
+        let outcome = a.forwarder.route(&owned_by(&a, "broker-b"), &metadata("broker-a")).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::Published);
+        assert_eq!(cluster.published().await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn brokers_without_a_certificate_never_forward() {
+        let (cluster, kv, client) = Cluster::new().await;
+        let a = cluster.broker(&kv, &client, "broker-a", false).await;
+        let b = cluster.broker(&kv, &client, "broker-b", true).await;
+        tokio::time::timeout(Duration::from_secs(5), converge(&[&a, &b])).await.unwrap();
+
+        // Direct forwarding asked for, but no certificate to present to the peer
+        let mut config = BrokerConfig::load().unwrap();
+        config.nats.ingress_topic = cluster.ingress_subject.clone();
+        config.cluster.direct_forwarding = true;
+        config.api.grpc_tls_cert = None;
+        let uncertified = PeerForwarder::new(
+            a.cluster.clone(),
+            a.forwarder.homes.clone(),
+            a.forwarder.overrides.clone(),
+            a.forwarder.probe.clone(),
+            &config.cluster,
+            &config.api,
+            &config.nats,
+            cluster.jetstream.clone(),
+            BrokerMetrics::new().unwrap(),
+        )
+        .unwrap();
+
+        let outcome = uncertified.route(&owned_by(&a, "broker-b"), &metadata("broker-a")).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::Published);
+        assert_eq!(cluster.published().await, 1);
+        assert!(b.recorder.ingested.lock().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn an_override_forbidding_direct_forwarding_sends_matching_traffic_through_nats() {
+        let (cluster, kv, client) = Cluster::new().await;
+        let a = cluster.broker(&kv, &client, "broker-a", true).await;
+        let b = cluster.broker(&kv, &client, "broker-b", true).await;
+        tokio::time::timeout(Duration::from_secs(5), converge(&[&a, &b])).await.unwrap();
+        let message = owned_by(&a, "broker-b");
+
+        let rule = PathOverrideRule {
+            id: "nats-only".into(),
+            feature: PathFeature::DirectForwarding,
+            effect: OverrideEffect::Forbid,
+            tenants: Vec::new(),
+            conversations: vec![message.conversation_id()],
+            gateways: vec!["gw-1".into()],
+            kinds: Vec::new(),
+        };
+        a.forwarder.overrides.set(rule, None, "ops");
+        let outcome = a.forwarder.route(&message, &metadata("broker-a")).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::Published);
+        assert_eq!(cluster.published().await, 1);
+        assert!(b.recorder.ingested.lock().is_empty());
+
+        assert!(a.forwarder.overrides.clear("nats-only", "ops"));
+        let outcome = a.forwarder.route(&message, &metadata("broker-a")).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::Forwarded { broker_id: "broker-b".into() });
+        assert_eq!(b.recorder.ingested.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats-server on PATH"]
+    async fn forwarded_messages_are_never_forwarded_again() {
+        let (cluster, kv, client) = Cluster::new().await;
+        let a = cluster.broker(&kv, &client, "broker-a", true).await;
+        let b = cluster.broker(&kv, &client, "broker-b", true).await;
+        tokio::time::timeout(Duration::from_secs(5), converge(&[&a, &b])).await.unwrap();
+
+        // Views that disagree on ownership must not bounce a message between brokers
+        let mut forwarded = metadata("broker-b");
+        forwarded.forwarded = true;
+        let outcome = a.forwarder.route(&owned_by(&a, "broker-b"), &forwarded).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::Local);
+        assert!(b.recorder.ingested.lock().is_empty());
+        assert_eq!(cluster.published().await, 0);
+    }
+}
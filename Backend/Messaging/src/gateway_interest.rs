@@ -0,0 +1,393 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use async_nats::RequestErrorKind;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::{InterestReconcileConfig, SessionMigrationConfig},
+    metrics::BrokerMetrics,
+    presence::PresenceStore,
+    session_migration::GatewaySessionCommand,
+    task::{spawn_traced, TaskContext},
+};
+
+/// How a registered gateway's control subject answered a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterestState {
+    Answered,
+    /// Nobody subscribes any more: the gateway is gone but its users aren't
+    NoInterest,
+    /// Somebody subscribes but never answers: a leaked subscription
+    Unresponsive,
+}
+
+impl InterestState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InterestState::Answered => "answered",
+            InterestState::NoInterest => "no_interest",
+            InterestState::Unresponsive => "unresponsive",
+        }
+    }
+
+    fn mismatched(&self) -> bool {
+        *self != InterestState::Answered
+    }
+}
+
+/// Per-gateway reconciliation detail for the debug endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct GatewayInterest {
+    pub gateway_id: String,
+    pub state: InterestState,
+    /// Consecutive cycles the gateway has been mismatched
+    pub mismatched_cycles: u32,
+    /// Users moved off the gateway when it was confirmed dead
+    pub evicted_users: Option<usize>,
+    /// Timestamp in milliseconds
+    pub checked_at: i64,
+}
+
+/// Reconciles the presence registry against real subscription interest
+///
+/// Every `interest_reconcile.interval`, each gateway the registry places
+/// users on gets a `Ping` request on its session control subject. No
+/// responders means nobody is subscribed any more; a timeout means a
+/// subscription is still there but nothing behind it answers, the
+/// signature of a gateway that crashed without cleanup while its queue
+/// group membership lingers. Either one is a mismatch, exported as
+/// `broker_gateway_interest_mismatch` and detailed in `/debug/state`.
+///
+/// A gateway mismatched for `interest_reconcile.confirm_cycles` consecutive
+/// cycles (two by default, so one lost probe never evicts anyone) is
+/// confirmed dead: its users are marked offline through the presence
+/// eviction path, so deliveries re-resolve their routes and queue offline
+/// or reach the user's new gateway instead of feeding the dead consumer.
+pub struct InterestReconciler {
+    client: async_nats::Client,
+    presence: Arc<PresenceStore>,
+    control_prefix: String,
+    config: InterestReconcileConfig,
+    gateways: Mutex<HashMap<String, GatewayInterest>>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl InterestReconciler {
+    pub fn new(
+        client: async_nats::Client,
+        presence: Arc<PresenceStore>,
+        sessions: &SessionMigrationConfig,
+        config: InterestReconcileConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            client,
+            presence,
+            control_prefix: sessions.gateway_control_prefix.clone(),
+            config,
+            gateways: Mutex::new(HashMap::new()),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let reconciler = Arc::clone(self);
+        Some(spawn_traced("interest_reconcile", TaskContext::new("interest_reconcile"), async move {
+            loop {
+                reconciler.reconcile().await;
+                reconciler.clock.sleep(reconciler.config.interval).await;
+            }
+        }))
+    }
+
+    /// One reconciliation cycle over every registered gateway
+    pub async fn reconcile(&self) {
+        let registered = self.presence.registered_gateways();
+        let mut results = Vec::with_capacity(registered.len());
+        for gateway_id in registered {
+            let state = self.probe(&gateway_id).await;
+            results.push((gateway_id, state));
+        }
+
+        let mut confirmed = Vec::new();
+        {
+            let mut gateways = self.gateways.lock();
+            gateways.retain(|gateway_id, _| results.iter().any(|(id, _)| id == gateway_id));
+            let checked_at = self.clock.now_millis();
+            for (gateway_id, state) in &results {
+                let entry = gateways.entry(gateway_id.clone()).or_insert_with(|| GatewayInterest {
+                    gateway_id: gateway_id.clone(),
+                    state: *state,
+                    mismatched_cycles: 0,
+                    evicted_users: None,
+                    checked_at,
+                });
+                entry.state = *state;
+                entry.checked_at = checked_at;
+                entry.mismatched_cycles = if state.mismatched() { entry.mismatched_cycles + 1 } else { 0 };
+                if entry.mismatched_cycles >= self.config.confirm_cycles {
+                    confirmed.push(gateway_id.clone());
+                }
+            }
+            let mismatched = gateways.values().filter(|gateway| gateway.state.mismatched()).count();
+            self.metrics.update_gateway_interest_mismatch(mismatched);
+        }
+
+        for gateway_id in confirmed {
+            self.evict(&gateway_id).await;
+        }
+    }
+
+    pub fn report(&self) -> Vec<GatewayInterest> {
+        let mut report: Vec<GatewayInterest> = self.gateways.lock().values().cloned().collect();
+        report.sort_by(|a, b| a.gateway_id.cmp(&b.gateway_id));
+        report
+    }
+
+    async fn probe(&self, gateway_id: &str) -> InterestState {
+        let subject = format!("{}.{}", self.control_prefix, gateway_id);
+        let payload: Bytes = serde_json::to_vec(&GatewaySessionCommand::Ping)
+            .expect("ping serializes")
+            .into();
+        let timeout = Duration::from_millis(self.config.probe_timeout_ms);
+        let state = tokio::select! {
+            answer = self.client.request(subject, payload) => match answer {
+                Ok(_) => InterestState::Answered,
+                Err(e) if e.kind() == RequestErrorKind::NoResponders => InterestState::NoInterest,
+                Err(_) => InterestState::Unresponsive,
+            },
+            _ = self.clock.sleep(timeout) => InterestState::Unresponsive,
+        };
+        self.metrics.record_gateway_interest_probe(state.as_str());
+        state
+    }
+
+    async fn evict(&self, gateway_id: &str) {
+        warn!(
+            "Gateway {} mismatched its subscription interest for {} cycles, evicting its users",
+            gateway_id, self.config.confirm_cycles
+        );
+        match self.presence.evict_gateway(gateway_id).await {
+            Ok(evicted) => {
+                info!("Evicted {} users from gateway {}", evicted, gateway_id);
+                self.metrics.record_gateway_interest_eviction(evicted);
+                if let Some(entry) = self.gateways.lock().get_mut(gateway_id) {
+                    entry.evicted_users = Some(evicted);
+                }
+            }
+            Err(e) => warn!("Eviction of gateway {} failed: {}", gateway_id, e),
+        }
+    }
+}
+
+/// Run against a JetStream-enabled server at `NATS_URL`:
+/// `cargo test -- --ignored gateway_interest`
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream::{self, kv};
+    use async_trait::async_trait;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        message::types::{PresenceStatus, PresenceUpdate},
+        route_cache::{RouteCache, RouteLookupError, UserLookup, UserResolver},
+    };
+
+    struct NoUsers;
+
+    #[async_trait]
+    impl UserResolver for NoUsers {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            Ok(UserLookup::NotFound)
+        }
+    }
+
+    struct World {
+        client: async_nats::Client,
+        presence: Arc<PresenceStore>,
+        reconciler: InterestReconciler,
+        control_prefix: String,
+        clock: Arc<SimClock>,
+        /// Subscriptions that must outlive the test body
+        _subscriptions: Vec<tokio::task::JoinHandle<()>>,
+    }
+
+    impl World {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let client = async_nats::connect(url).await.unwrap();
+            let kv = jetstream::new(client.clone())
+                .create_key_value(kv::Config {
+                    bucket: format!("interest-test-{}", Uuid::new_v4().simple()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let config = BrokerConfig::load().unwrap();
+            let mut sessions = config.session_migration.clone();
+            sessions.gateway_control_prefix = format!("test.control.{}", Uuid::new_v4().simple());
+            let mut interest = config.interest_reconcile.clone();
+            interest.probe_timeout_ms = 500;
+            interest.confirm_cycles = 2;
+
+            let clock = Arc::new(SimClock::new());
+            let metrics = BrokerMetrics::new().unwrap();
+            let routes = Arc::new(RouteCache::new(Arc::new(NoUsers), &config.routing, metrics.clone()));
+            let presence =
+                Arc::new(PresenceStore::new(kv, &config.routing, routes, metrics.clone()).with_clock(clock.clone()));
+            let reconciler = InterestReconciler::new(client.clone(), presence.clone(), &sessions, interest, metrics)
+                .with_clock(clock.clone());
+            Self {
+                client,
+                presence,
+                reconciler,
+                control_prefix: sessions.gateway_control_prefix,
+                clock,
+                _subscriptions: Vec::new(),
+            }
+        }
+
+        async fn online(&self, gateway_id: &str, user_id: &str) {
+            let update = PresenceUpdate {
+                user_id: user_id.into(),
+                status: PresenceStatus::Online,
+                device_id: "d".into(),
+                last_seen: self.clock.now_millis(),
+                platform: None,
+            };
+            self.presence.heartbeat(gateway_id, &update).await.unwrap();
+        }
+
+        /// A gateway's queue-group subscription; a live one answers pings,
+        /// an orphaned one takes them and never replies
+        async fn subscribe(&mut self, gateway_id: &str, answers: bool) {
+            let subject = format!("{}.{}", self.control_prefix, gateway_id);
+            let mut subscription = self.client.queue_subscribe(subject, "gateways".into()).await.unwrap();
+            self.client.flush().await.unwrap();
+            let client = self.client.clone();
+            self._subscriptions.push(tokio::spawn(async move {
+                while let Some(message) = subscription.next().await {
+                    if let (true, Some(reply)) = (answers, message.reply) {
+                        client.publish(reply, Bytes::new()).await.unwrap();
+                    }
+                }
+            }));
+        }
+
+        /// One reconciliation cycle; probe timeouts elapse on the sim clock
+        /// once live gateways have had real time to answer
+        async fn cycle(&self) {
+            let reconcile = self.reconciler.reconcile();
+            tokio::pin!(reconcile);
+            loop {
+                tokio::select! {
+                    _ = &mut reconcile => return,
+                    _ = tokio::time::sleep(Duration::from_millis(200)) => {
+                        self.clock.advance_to_next();
+                    }
+                }
+            }
+        }
+
+        fn state(&self, gateway_id: &str) -> Option<GatewayInterest> {
+            self.reconciler.report().into_iter().find(|gateway| gateway.gateway_id == gateway_id)
+        }
+
+        async fn status(&self, user_id: &str) -> Option<(String, PresenceStatus)> {
+            let record = self.presence.lookup(user_id).await.unwrap()?;
+            Some((record.gateway_id, record.status))
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn an_orphaned_subscription_is_detected_and_evicted_after_two_cycles() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let mut world = World::new().await;
+        world.subscribe("gw-live", true).await;
+        world.subscribe("gw-crashed", false).await;
+        world.online("gw-live", "alice").await;
+        world.online("gw-crashed", "bob").await;
+        world.online("gw-crashed", "carol").await;
+        // Carol reconnected through the live gateway before the crash was noticed
+        world.online("gw-live", "carol").await;
+
+        world.cycle().await;
+        let crashed = world.state("gw-crashed").unwrap();
+        assert_eq!(crashed.state, InterestState::Unresponsive);
+        assert_eq!(crashed.mismatched_cycles, 1);
+        assert_eq!(crashed.evicted_users, None, "one mismatched cycle evicts nobody");
+        assert_eq!(world.state("gw-live").unwrap().state, InterestState::Answered);
+        assert_eq!(world.status("bob").await, Some(("gw-crashed".into(), PresenceStatus::Online)));
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("broker_gateway_interest_mismatch 1"), "{}", rendered);
+
+        world.cycle().await;
+        let crashed = world.state("gw-crashed").unwrap();
+        assert_eq!(crashed.mismatched_cycles, 2);
+        assert_eq!(crashed.evicted_users, Some(1));
+        assert_eq!(crashed.checked_at, world.clock.now_millis());
+
+        // Bob's deliveries now queue offline; Carol keeps her new gateway
+        assert_eq!(world.status("bob").await, Some(("gw-crashed".into(), PresenceStatus::Offline)));
+        assert_eq!(world.status("carol").await, Some(("gw-live".into(), PresenceStatus::Online)));
+        assert_eq!(world.status("alice").await, Some(("gw-live".into(), PresenceStatus::Online)));
+        assert!(!world.presence.registered_gateways().contains(&"gw-crashed".to_string()));
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("broker_gateway_interest_evicted_users_total 1"), "{}", rendered);
+        assert!(
+            rendered.contains(r#"broker_gateway_interest_probes_total{outcome="unresponsive"} 2"#),
+            "{}",
+            rendered
+        );
+
+        // The evicted gateway drops out of the report and the gauge
+        world.cycle().await;
+        assert!(world.state("gw-crashed").is_none());
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("broker_gateway_interest_mismatch 0"), "{}", rendered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_gateway_without_interest_is_evicted_and_a_recovered_one_is_not() {
+        let mut world = World::new().await;
+        world.online("gw-gone", "dave").await;
+        world.online("gw-flaky", "erin").await;
+
+        world.cycle().await;
+        assert_eq!(world.state("gw-gone").unwrap().state, InterestState::NoInterest);
+        assert_eq!(world.state("gw-flaky").unwrap().state, InterestState::NoInterest);
+
+        // The flaky gateway resubscribes before the second cycle, resetting its count
+        world.subscribe("gw-flaky", true).await;
+        world.cycle().await;
+        assert_eq!(world.state("gw-gone").unwrap().evicted_users, Some(1));
+        let flaky = world.state("gw-flaky").unwrap();
+        assert_eq!((flaky.state, flaky.mismatched_cycles, flaky.evicted_users), (InterestState::Answered, 0, None));
+
+        assert_eq!(world.status("dave").await, Some(("gw-gone".into(), PresenceStatus::Offline)));
+        assert_eq!(world.status("erin").await, Some(("gw-flaky".into(), PresenceStatus::Online)));
+    }
+}
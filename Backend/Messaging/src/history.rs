@@ -0,0 +1,315 @@
+use std::{sync::Arc, time::Duration};
+use async_nats::jetstream::{
+    consumer::{pull, AckPolicy, DeliverPolicy},
+    stream::LastRawMessageErrorKind,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio_stream::StreamExt;
+
+use crate::{
+    deadline::{Deadline, DeadlineExceeded},
+    history_cache::HistoryCache,
+    read_replica::{ReadFreshness, ReadOperation, ReadStreamSelector},
+};
+
+/// One stored message of a conversation
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub stream_sequence: u64,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Pass as `after_sequence` to fetch the next page
+    pub next_sequence: Option<u64>,
+    pub freshness: ReadFreshness,
+    /// How old the page was when served from `HistoryCache`; `None` if read from the stream
+    pub cached_age: Option<Duration>,
+}
+
+/// Reads per-conversation history subjects (`{prefix}.{conversation_id}`)
+///
+/// Reads go through the `ReadStreamSelector` so history fetches and gap
+/// repair don't compete with the ingress consumer on the primary stream.
+/// `FetchHistory` reads are served from the `HistoryCache` when one is set
+/// and it covers the request.
+pub struct HistoryReader {
+    selector: Arc<ReadStreamSelector>,
+    subject_prefix: String,
+    max_page_size: usize,
+    cache: Option<Arc<HistoryCache>>,
+}
+
+impl HistoryReader {
+    pub fn new(selector: Arc<ReadStreamSelector>, subject_prefix: String, max_page_size: usize) -> Self {
+        Self {
+            selector,
+            subject_prefix,
+            max_page_size,
+            cache: None,
+        }
+    }
+
+    pub fn with_cache(mut self, cache: Arc<HistoryCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn subject(&self, conversation_id: &str) -> String {
+        format!("{}.{}", self.subject_prefix, conversation_id)
+    }
+
+    /// Fetch up to `limit` messages stored after `after_sequence`
+    ///
+    /// Stops reading once `deadline` passes; the pull consumer is ephemeral
+    /// and expires on its own, so there's nothing to clean up.
+    pub async fn fetch(
+        &self,
+        operation: ReadOperation,
+        conversation_id: &str,
+        after_sequence: u64,
+        limit: usize,
+        deadline: &Deadline,
+    ) -> Result<HistoryPage, HistoryError> {
+        let limit = limit.clamp(1, self.max_page_size);
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|_| matches!(operation, ReadOperation::FetchHistory));
+        let ticket = match cache {
+            Some(cache) => match cache.get(conversation_id, after_sequence, limit) {
+                Some(page) => return Ok(page),
+                None => Some(cache.ticket(conversation_id)),
+            },
+            None => None,
+        };
+
+        let freshness = deadline.run("select_stream", self.selector.select(operation)).await?;
+
+        let stream = deadline
+            .run("get_stream", self.selector.jetstream().get_stream(&freshness.stream))
+            .await?
+            .map_err(|e| HistoryError::Read(e.to_string()))?;
+
+        let deliver_policy = match after_sequence {
+            0 => DeliverPolicy::All,
+            sequence => DeliverPolicy::ByStartSequence {
+                start_sequence: sequence + 1,
+            },
+        };
+
+        let consumer = deadline
+            .run(
+                "create_consumer",
+                stream.create_consumer(pull::Config {
+                    filter_subject: self.subject(conversation_id),
+                    deliver_policy,
+                    ack_policy: AckPolicy::None,
+                    inactive_threshold: Duration::from_secs(30),
+                    ..Default::default()
+                }),
+            )
+            .await?
+            .map_err(|e| HistoryError::Read(e.to_string()))?;
+
+        let mut messages = deadline
+            .run(
+                "fetch",
+                consumer
+                    .fetch()
+                    .max_messages(limit)
+                    .expires(deadline.cap(Duration::from_millis(500)))
+                    .messages(),
+            )
+            .await?
+            .map_err(|e| HistoryError::Read(e.to_string()))?;
+
+        let mut entries = Vec::with_capacity(limit);
+        while let Some(message) = deadline.run("read", messages.next()).await? {
+            let message = message.map_err(|e| HistoryError::Read(e.to_string()))?;
+            let info = message.info().map_err(|e| HistoryError::Read(e.to_string()))?;
+            entries.push(HistoryEntry {
+                stream_sequence: info.stream_sequence,
+                payload: message.payload.to_vec(),
+            });
+        }
+
+        let next_sequence = match entries.len() {
+            n if n == limit => entries.last().map(|e| e.stream_sequence),
+            _ => None,
+        };
+
+        let page = HistoryPage {
+            entries,
+            next_sequence,
+            freshness,
+            cached_age: None,
+        };
+        if let Some((cache, ticket)) = cache.zip(ticket) {
+            cache.fill(ticket, after_sequence, &page);
+        }
+        Ok(page)
+    }
+
+    /// Conversation sequence of the newest stored message, if any
+    pub async fn latest_sequence(&self, conversation_id: &str) -> Result<Option<u64>, HistoryError> {
+        #[derive(serde::Deserialize)]
+        struct Sequenced {
+            sequence: Option<u64>,
+        }
+
+        let freshness = self.selector.select(ReadOperation::FetchHistory).await;
+        let stream = self
+            .selector
+            .jetstream()
+            .get_stream(&freshness.stream)
+            .await
+            .map_err(|e| HistoryError::Read(e.to_string()))?;
+        let message = match stream.get_last_raw_message_by_subject(&self.subject(conversation_id)).await {
+            Ok(message) => message,
+            Err(e) if e.kind() == LastRawMessageErrorKind::NoMessageFound => return Ok(None),
+            Err(e) => return Err(HistoryError::Read(e.to_string())),
+        };
+        let payload = STANDARD
+            .decode(&message.payload)
+            .map_err(|e| HistoryError::Read(e.to_string()))?;
+        let envelope: Sequenced = serde_json::from_slice(&payload).map_err(|e| HistoryError::Read(e.to_string()))?;
+        Ok(envelope.sequence)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HistoryError {
+    #[error("history read failed: {0}")]
+    Read(String),
+    #[error(transparent)]
+    Deadline(#[from] DeadlineExceeded),
+}
+
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig, metrics::BrokerMetrics};
+
+    /// A reader whose JetStream calls never get an answer
+    async fn stalled_reader() -> HistoryReader {
+        // Never connected, so every request waits on a reply that doesn't come
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("localhost:4222")
+            .await
+            .unwrap();
+        let mut nats = BrokerConfig::load().unwrap().nats;
+        nats.read_stream_name = None;
+        let selector = ReadStreamSelector::new(jetstream::new(client), &nats, BrokerMetrics::new().unwrap());
+        HistoryReader::new(Arc::new(selector), "history".into(), 100)
+    }
+
+    #[tokio::test]
+    async fn a_stalled_read_stops_at_the_deadline() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let reader = stalled_reader().await;
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(2));
+
+        let (result, ()) = tokio::join!(
+            reader.fetch(ReadOperation::FetchHistory, "dm:alice:bob", 0, 50, &deadline),
+            async {
+                while clock.pending() == 0 {
+                    tokio::task::yield_now().await;
+                }
+                clock.advance(Duration::from_secs(2));
+            }
+        );
+
+        // Abandoned waiting for the stream, before any consumer was created
+        let Err(HistoryError::Deadline(exceeded)) = result else {
+            panic!("fetch outlived its deadline: {:?}", result.map(|page| page.entries.len()));
+        };
+        assert_eq!(exceeded.stage, "get_stream");
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains(r#"broker_stream_reads_total{operation="fetch_history",stream="primary"} 1"#),
+            "{}",
+            rendered
+        );
+    }
+
+    #[tokio::test]
+    async fn an_expired_deadline_gives_up_on_the_first_jetstream_call() {
+        let reader = stalled_reader().await;
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(2));
+        clock.advance(Duration::from_secs(3));
+
+        let result = reader.fetch(ReadOperation::FetchHistory, "dm:alice:bob", 0, 50, &deadline).await;
+        assert!(matches!(result, Err(HistoryError::Deadline(DeadlineExceeded { stage: "get_stream" }))));
+    }
+
+    /// Run against a JetStream-enabled server at `NATS_URL`: `cargo test -- --ignored history`
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn cached_pages_are_identical_to_stream_pages() {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+        let id = Uuid::new_v4().simple().to_string();
+        let prefix = format!("history-test-{}", id);
+        let mut nats = BrokerConfig::load().unwrap().nats;
+        nats.stream_name = format!("HISTORY_TEST_{}", id);
+        nats.read_stream_name = None;
+        jetstream
+            .create_stream(jetstream::stream::Config {
+                name: nats.stream_name.clone(),
+                subjects: vec![format!("{}.>", prefix)],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // Interleaved, so each conversation's stream sequences are sparse
+        for index in 0..12 {
+            let conversation_id = if index % 3 == 0 { "group-1" } else { "dm:alice:bob" };
+            let subject = format!("{}.{}", prefix, conversation_id);
+            jetstream.publish(subject, format!("payload-{}", index).into()).await.unwrap().await.unwrap();
+        }
+
+        let reader = |cache: Option<Arc<HistoryCache>>| {
+            let selector = ReadStreamSelector::new(jetstream.clone(), &nats, BrokerMetrics::new().unwrap());
+            let reader = HistoryReader::new(Arc::new(selector), prefix.clone(), 100);
+            match cache {
+                Some(cache) => reader.with_cache(cache),
+                None => reader,
+            }
+        };
+        let mut config = BrokerConfig::load().unwrap().history_cache;
+        config.enabled = true;
+        let cache = Arc::new(HistoryCache::new(&config, BrokerMetrics::new().unwrap()));
+        let (uncached, cached) = (reader(None), reader(Some(cache)));
+        async fn fetch(reader: &HistoryReader, after_sequence: u64, limit: usize) -> Result<HistoryPage, HistoryError> {
+            reader.fetch(ReadOperation::FetchHistory, "dm:alice:bob", after_sequence, limit, &Deadline::none()).await
+        }
+
+        let primed = fetch(&cached, 0, 100).await.unwrap();
+        assert_eq!(primed.entries.len(), 8);
+        assert!(primed.cached_age.is_none());
+        for after_sequence in 0..=13 {
+            for limit in 1..=9 {
+                let streamed = fetch(&uncached, after_sequence, limit).await.unwrap();
+                let served = fetch(&cached, after_sequence, limit).await.unwrap();
+                assert!(served.cached_age.is_some(), "after {} limit {} missed the cache", after_sequence, limit);
+                let entries =
+                    |page: &HistoryPage| page.entries.iter().map(|e| (e.stream_sequence, e.payload.clone())).collect::<Vec<_>>();
+                assert_eq!(entries(&served), entries(&streamed), "after {} limit {}", after_sequence, limit);
+                assert_eq!(served.next_sequence, streamed.next_sequence, "after {} limit {}", after_sequence, limit);
+                assert_eq!(served.freshness.stream, streamed.freshness.stream);
+            }
+        }
+    }
+}
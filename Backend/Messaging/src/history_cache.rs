@@ -0,0 +1,365 @@
+use std::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::HistoryCacheConfig,
+    history::{HistoryEntry, HistoryPage},
+    metrics::BrokerMetrics,
+    read_replica::ReadFreshness,
+};
+
+/// The tail of one conversation's history, as last read from the primary
+struct CachedTail {
+    /// `after_sequence` of the read; the entries are everything stored after it
+    after_sequence: u64,
+    entries: Vec<HistoryEntry>,
+    freshness: ReadFreshness,
+    cached_at: Instant,
+}
+
+struct Slot {
+    /// Changes on every invalidation; a fill only lands if it still matches
+    generation: u64,
+    tail: Option<CachedTail>,
+}
+
+/// Proof of which generation a cache fill was read under
+pub struct FillTicket {
+    conversation_id: String,
+    generation: u64,
+}
+
+/// Most recent history page per hot conversation
+///
+/// A page is cached only when it reached the end of the conversation's
+/// history on the primary stream, so it holds every stored message after
+/// its `after_sequence`. Any later request for the same conversation
+/// starting at or past that point is answered by slicing the cached
+/// entries exactly as the stream would have: the same messages, the same
+/// `next_sequence`. Mirror reads are never cached, since a lagging mirror
+/// page could outlive its lag.
+///
+/// A new message for the conversation, or a redaction tombstoning any of
+/// its messages, calls `invalidate` before it's acknowledged: the page is
+/// dropped and the slot's generation moves on. Reads take a `FillTicket`
+/// before touching the stream and their result only fills the cache if the
+/// generation is unchanged, so a read that raced a redaction can't put the
+/// redacted message back. Generations come from one counter, so a slot
+/// evicted and recreated never reuses one. At most
+/// `history_cache.max_conversations` slots are kept, least recently used
+/// first out.
+pub struct HistoryCache {
+    slots: Mutex<LruCache<String, Slot>>,
+    next_generation: AtomicU64,
+    enabled: bool,
+    max_page_bytes: usize,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl HistoryCache {
+    pub fn new(config: &HistoryCacheConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            slots: Mutex::new(LruCache::new(NonZeroUsize::new(config.max_conversations.max(1)).unwrap())),
+            next_generation: AtomicU64::new(1),
+            enabled: config.enabled,
+            max_page_bytes: config.max_page_bytes,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The page the stream would return for this request, if the cached tail covers it
+    ///
+    /// `limit` must already be clamped the way `HistoryReader::fetch` clamps it.
+    pub fn get(&self, conversation_id: &str, after_sequence: u64, limit: usize) -> Option<HistoryPage> {
+        if !self.enabled {
+            return None;
+        }
+        let now = self.clock.now_instant();
+        let mut slots = self.slots.lock();
+        let hit = slots
+            .get(conversation_id)
+            .and_then(|slot| slot.tail.as_ref())
+            .filter(|tail| after_sequence >= tail.after_sequence)
+            .map(|tail| {
+                let entries: Vec<HistoryEntry> = tail
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.stream_sequence > after_sequence)
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                let next_sequence = match entries.len() {
+                    n if n == limit => entries.last().map(|e| e.stream_sequence),
+                    _ => None,
+                };
+                HistoryPage {
+                    entries,
+                    next_sequence,
+                    freshness: tail.freshness.clone(),
+                    cached_age: Some(now.saturating_duration_since(tail.cached_at)),
+                }
+            });
+        drop(slots);
+        self.metrics.record_history_cache(if hit.is_some() { "hit" } else { "miss" });
+        hit
+    }
+
+    /// Take a ticket before reading the stream for `conversation_id`
+    pub fn ticket(&self, conversation_id: &str) -> FillTicket {
+        if !self.enabled {
+            return FillTicket {
+                conversation_id: conversation_id.to_string(),
+                generation: 0,
+            };
+        }
+        let mut slots = self.slots.lock();
+        let generation = match slots.get(conversation_id) {
+            Some(slot) => slot.generation,
+            None => {
+                let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+                slots.put(conversation_id.to_string(), Slot { generation, tail: None });
+                generation
+            }
+        };
+        FillTicket {
+            conversation_id: conversation_id.to_string(),
+            generation,
+        }
+    }
+
+    /// Cache a page read under `ticket`, if it's a primary tail page and nothing invalidated it since
+    pub fn fill(&self, ticket: FillTicket, after_sequence: u64, page: &HistoryPage) {
+        if !self.enabled || page.next_sequence.is_some() || page.freshness.from_mirror {
+            return;
+        }
+        let bytes: usize = page.entries.iter().map(|entry| entry.payload.len()).sum();
+        if bytes > self.max_page_bytes {
+            return;
+        }
+        let mut slots = self.slots.lock();
+        match slots.peek_mut(&ticket.conversation_id) {
+            Some(slot) if slot.generation == ticket.generation => {
+                slot.tail = Some(CachedTail {
+                    after_sequence,
+                    entries: page.entries.clone(),
+                    freshness: page.freshness.clone(),
+                    cached_at: self.clock.now_instant(),
+                });
+            }
+            // Invalidated or evicted while the read was in flight
+            _ => self.metrics.record_history_cache("fill_discarded"),
+        }
+    }
+
+    /// Drop the conversation's page
+    ///
+    /// Redaction processing must call this before it acknowledges the
+    /// tombstone. Once it returns, neither a cached page nor a read already
+    /// in flight can serve the redacted message.
+    pub fn invalidate(&self, conversation_id: &str) {
+        if !self.enabled {
+            return;
+        }
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let mut slots = self.slots.lock();
+        // Only slots that exist can hold a page or an outstanding ticket
+        if let Some(slot) = slots.peek_mut(conversation_id) {
+            slot.generation = generation;
+            if slot.tail.take().is_some() {
+                drop(slots);
+                self.metrics.record_history_cache("invalidated");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const CONVERSATION: &str = "dm:alice:bob";
+
+    fn cache(max_conversations: usize) -> (HistoryCache, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().history_cache;
+        config.enabled = true;
+        config.max_conversations = max_conversations;
+        config.max_page_bytes = 1024;
+        let clock = Arc::new(SimClock::new());
+        (HistoryCache::new(&config, BrokerMetrics::new().unwrap()).with_clock(clock.clone()), clock)
+    }
+
+    fn entry(stream_sequence: u64) -> HistoryEntry {
+        HistoryEntry {
+            stream_sequence,
+            payload: format!("message-{}", stream_sequence).into_bytes(),
+        }
+    }
+
+    /// What the primary stream returns: everything stored after `after_sequence`, up to `limit`
+    fn read(stored: &[u64], after_sequence: u64, limit: usize) -> HistoryPage {
+        let entries: Vec<HistoryEntry> =
+            stored.iter().filter(|sequence| **sequence > after_sequence).take(limit).map(|sequence| entry(*sequence)).collect();
+        let next_sequence = match entries.len() {
+            n if n == limit => entries.last().map(|e| e.stream_sequence),
+            _ => None,
+        };
+        HistoryPage {
+            entries,
+            next_sequence,
+            freshness: ReadFreshness {
+                stream: "MESSAGES".into(),
+                from_mirror: false,
+                mirror_lag: 0,
+            },
+            cached_age: None,
+        }
+    }
+
+    /// A miss, a stream read and the fill it leads to
+    fn read_through(cache: &HistoryCache, conversation_id: &str, stored: &[u64], after_sequence: u64, limit: usize) {
+        assert!(cache.get(conversation_id, after_sequence, limit).is_none());
+        let ticket = cache.ticket(conversation_id);
+        cache.fill(ticket, after_sequence, &read(stored, after_sequence, limit));
+    }
+
+    fn sequences(page: &HistoryPage) -> Vec<u64> {
+        page.entries.iter().map(|entry| entry.stream_sequence).collect()
+    }
+
+    #[test]
+    fn a_read_racing_a_redaction_never_caches_the_redacted_message() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let (cache, _) = cache(100);
+            read_through(&cache, CONVERSATION, &[3, 5, 8], 0, 50);
+            assert_eq!(sequences(&cache.get(CONVERSATION, 0, 50).unwrap()), [3, 5, 8]);
+
+            assert_eq!(sequences(&cache.get(CONVERSATION, 3, 50).unwrap()), [5, 8]);
+
+            // A read starts, then 5 is tombstoned while it's in flight
+            let in_flight = cache.ticket(CONVERSATION);
+            cache.invalidate(CONVERSATION);
+            assert!(cache.get(CONVERSATION, 0, 50).is_none(), "nothing cached survives the redaction");
+            // The read saw the stream before the tombstone and still holds 5
+            cache.fill(in_flight, 0, &read(&[3, 5, 8], 0, 50));
+            assert!(cache.get(CONVERSATION, 0, 50).is_none());
+
+            // A read started after the redaction fills the cache without it
+            read_through(&cache, CONVERSATION, &[3, 8], 0, 50);
+            assert_eq!(sequences(&cache.get(CONVERSATION, 0, 50).unwrap()), [3, 8]);
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_history_cache_total{outcome="invalidated"} 1"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_history_cache_total{outcome="fill_discarded"} 1"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_history_cache_total{outcome="hit"} 3"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_history_cache_total{outcome="miss"} 4"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn an_evicted_slot_never_takes_a_ticket_from_before_it() {
+        let (cache, _) = cache(2);
+        let stale = cache.ticket(CONVERSATION);
+        read_through(&cache, "group-1", &[1], 0, 50);
+        read_through(&cache, "group-2", &[2], 0, 50);
+
+        // Evicted and recreated: the new slot's generation is one never handed out before
+        let current = cache.ticket(CONVERSATION);
+        cache.invalidate(CONVERSATION);
+        cache.fill(stale, 0, &read(&[3, 5], 0, 50));
+        cache.fill(current, 0, &read(&[3, 5], 0, 50));
+        assert!(cache.get(CONVERSATION, 0, 50).is_none());
+    }
+
+    #[test]
+    fn only_the_most_recently_read_conversations_stay_cached() {
+        let (cache, _) = cache(3);
+        let conversations: Vec<String> = (0..10).map(|index| format!("group-{}", index)).collect();
+        for (index, conversation_id) in conversations.iter().enumerate() {
+            read_through(&cache, conversation_id, &[index as u64 + 1], 0, 50);
+        }
+        let cached: Vec<&String> =
+            conversations.iter().filter(|conversation_id| cache.get(conversation_id, 0, 50).is_some()).collect();
+        assert_eq!(cached, ["group-7", "group-8", "group-9"]);
+
+        // Reading group-7 keeps it over group-8, the least recently read
+        cache.get("group-7", 0, 50).unwrap();
+        cache.get("group-9", 0, 50).unwrap();
+        read_through(&cache, "group-0", &[1], 0, 50);
+        assert!(cache.get("group-8", 0, 50).is_none());
+        for conversation_id in ["group-0", "group-7", "group-9"] {
+            assert!(cache.get(conversation_id, 0, 50).is_some(), "{}", conversation_id);
+        }
+        assert_eq!(cache.slots.lock().len(), 3);
+    }
+
+    #[test]
+    fn cached_pages_match_the_stream_page_for_page() {
+        let (cache, _) = cache(100);
+        // Stream sequences are shared with other conversations, so this one's are sparse
+        let stored = [4, 9, 10, 17, 23, 24, 31];
+        read_through(&cache, CONVERSATION, &stored, 9, 50);
+
+        for after_sequence in 9..=35 {
+            for limit in 1..=8 {
+                let cached = cache.get(CONVERSATION, after_sequence, limit).unwrap();
+                let streamed = read(&stored, after_sequence, limit);
+                assert_eq!(sequences(&cached), sequences(&streamed), "after {} limit {}", after_sequence, limit);
+                let payloads = |page: &HistoryPage| page.entries.iter().map(|entry| entry.payload.clone()).collect::<Vec<_>>();
+                assert_eq!(payloads(&cached), payloads(&streamed));
+                assert_eq!(cached.next_sequence, streamed.next_sequence, "after {} limit {}", after_sequence, limit);
+                assert_eq!(cached.freshness.stream, streamed.freshness.stream);
+            }
+        }
+        // Before the cached tail starts, only the stream knows
+        assert!(cache.get(CONVERSATION, 4, 50).is_none());
+    }
+
+    #[test]
+    fn only_whole_primary_tails_are_cached() {
+        let (cache, clock) = cache(100);
+        // A full page may have more behind it
+        read_through(&cache, CONVERSATION, &[1, 2, 3], 0, 3);
+        read_through(&cache, CONVERSATION, &[1, 2, 3], 0, 4);
+        assert!(cache.get(CONVERSATION, 0, 4).is_some());
+
+        let mut mirrored = read(&[1, 2, 3], 0, 50);
+        mirrored.freshness.from_mirror = true;
+        cache.invalidate(CONVERSATION);
+        cache.fill(cache.ticket(CONVERSATION), 0, &mirrored);
+        assert!(cache.get(CONVERSATION, 0, 50).is_none(), "a lagging mirror page could outlive its lag");
+
+        let oversized = HistoryPage {
+            entries: vec![HistoryEntry {
+                stream_sequence: 1,
+                payload: vec![0; 2048],
+            }],
+            ..read(&[], 0, 50)
+        };
+        cache.fill(cache.ticket("group-1"), 0, &oversized);
+        assert!(cache.get("group-1", 0, 50).is_none());
+
+        // Served pages say how old they are
+        read_through(&cache, CONVERSATION, &[1, 2, 3], 0, 50);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(cache.get(CONVERSATION, 0, 50).unwrap().cached_age, Some(Duration::from_millis(1500)));
+    }
+}
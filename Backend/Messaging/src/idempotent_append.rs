@@ -0,0 +1,319 @@
+use std::time::Duration;
+use async_nats::{jetstream, HeaderMap};
+use bytes::Bytes;
+use ring::digest::{digest, SHA256};
+use tracing::{info, warn};
+
+use crate::{
+    config::BrokerConfig,
+    metrics::BrokerMetrics,
+};
+
+/// Why the broker appends to a JetStream stream; the first part of the append's `Nats-Msg-Id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendPurpose {
+    OfflineQueue,
+    DeadLetter,
+    Scheduled,
+    Quarantine,
+    StatusSpill,
+    Parked,
+    Handoff,
+    Volume,
+}
+
+impl AppendPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendPurpose::OfflineQueue => "offline",
+            AppendPurpose::DeadLetter => "dlq",
+            AppendPurpose::Scheduled => "scheduled",
+            AppendPurpose::Quarantine => "quarantine",
+            AppendPurpose::StatusSpill => "status",
+            AppendPurpose::Parked => "parked",
+            AppendPurpose::Handoff => "handoff",
+            AppendPurpose::Volume => "volume",
+        }
+    }
+}
+
+/// What one append is: `message_id` for `recipient`, for `purpose`
+///
+/// Appends with no single recipient use `""`.
+#[derive(Debug, Clone, Copy)]
+pub struct AppendKey<'a> {
+    pub purpose: AppendPurpose,
+    pub message_id: &'a str,
+    pub recipient: &'a str,
+}
+
+impl AppendKey<'_> {
+    /// `Nats-Msg-Id` for the append
+    ///
+    /// The same key always gives the same ID, so a crash-retried append is a
+    /// duplicate within the stream's window. The pair is hashed to keep the
+    /// header short and free of whatever characters IDs contain.
+    pub fn msg_id(&self) -> String {
+        let key = format!("{}\0{}", self.message_id, self.recipient);
+        format!(
+            "{}-{}",
+            self.purpose.as_str(),
+            hex::encode(&digest(&SHA256, key.as_bytes()).as_ref()[..16])
+        )
+    }
+}
+
+/// Outcome of an idempotent append
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Appended {
+    New { sequence: u64 },
+    /// The stream already held an append with this ID; `sequence` is the original's
+    Duplicate { sequence: u64 },
+}
+
+/// Append with the key's `Nats-Msg-Id`, counting server-side duplicates instead of treating them as new
+pub async fn append(
+    jetstream: &jetstream::Context,
+    key: AppendKey<'_>,
+    subject: String,
+    mut headers: HeaderMap,
+    payload: Bytes,
+    metrics: &BrokerMetrics,
+) -> Result<Appended, AppendError> {
+    headers.insert("Nats-Msg-Id", key.msg_id().as_str());
+    let ack = jetstream
+        .publish_with_headers(subject, headers, payload)
+        .await
+        .map_err(|e| AppendError::Publish(e.to_string()))?
+        .await
+        .map_err(|e| AppendError::Publish(e.to_string()))?;
+    if ack.duplicate {
+        metrics.record_persist_duplicate_suppressed(&ack.stream);
+        return Ok(Appended::Duplicate { sequence: ack.sequence });
+    }
+    Ok(Appended::New { sequence: ack.sequence })
+}
+
+/// Streams the broker appends to with `AppendKey` IDs
+fn appended_streams(config: &BrokerConfig) -> Vec<String> {
+    let mut streams = vec![
+        config.nats.stream_name.clone(),
+        config.offline_quarantine.stream.clone(),
+        config.ingestion_pause.holding_stream.clone(),
+        config.volume_accounting.stream.clone(),
+    ];
+    streams.extend(config.persist_dedup.extra_streams.iter().cloned());
+    streams.sort();
+    streams.dedup();
+    streams
+}
+
+/// Widen each appended stream's duplicate window to `persist_dedup.duplicate_window`
+///
+/// A window shorter than the longest crash-retry gap lets a retried append
+/// through as new. Streams already at or above the configured window are
+/// left alone, and a missing stream is skipped with a warning since the
+/// component that owns it creates it. Returns how many streams changed.
+pub async fn ensure_duplicate_windows(
+    jetstream: &jetstream::Context,
+    config: &BrokerConfig,
+) -> Result<usize, AppendError> {
+    let window: Duration = config.persist_dedup.duplicate_window;
+    let mut updated = 0;
+    for name in appended_streams(config) {
+        let mut stream = match jetstream.get_stream(&name).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Skipping duplicate window for stream {}: {}", name, e);
+                continue;
+            }
+        };
+        let info = stream
+            .info()
+            .await
+            .map_err(|e| AppendError::Provision(name.clone(), e.to_string()))?;
+        if info.config.duplicate_window >= window {
+            continue;
+        }
+        let mut stream_config = info.config.clone();
+        stream_config.duplicate_window = window;
+        jetstream
+            .update_stream(&stream_config)
+            .await
+            .map_err(|e| AppendError::Provision(name.clone(), e.to_string()))?;
+        info!(
+            "Raised duplicate window of stream {} from {:?} to {:?}",
+            name, info.config.duplicate_window, window
+        );
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppendError {
+    #[error("append failed: {0}")]
+    Publish(String),
+    #[error("failed to set duplicate window on stream {0}: {1}")]
+    Provision(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+
+    const PURPOSES: [AppendPurpose; 8] = [
+        AppendPurpose::OfflineQueue,
+        AppendPurpose::DeadLetter,
+        AppendPurpose::Scheduled,
+        AppendPurpose::Quarantine,
+        AppendPurpose::StatusSpill,
+        AppendPurpose::Parked,
+        AppendPurpose::Handoff,
+        AppendPurpose::Volume,
+    ];
+
+    fn key<'a>(purpose: AppendPurpose, message_id: &'a str, recipient: &'a str) -> AppendKey<'a> {
+        AppendKey { purpose, message_id, recipient }
+    }
+
+    #[test]
+    fn ids_are_deterministic_and_distinct_per_purpose_message_and_recipient() {
+        let mut ids = HashSet::new();
+        for purpose in PURPOSES {
+            for (message_id, recipient) in [("m1", "bob"), ("m1", "carol"), ("m2", "bob"), ("m1", "")] {
+                let id = key(purpose, message_id, recipient).msg_id();
+                assert_eq!(id, key(purpose, message_id, recipient).msg_id());
+                assert!(id.starts_with(&format!("{}-", purpose.as_str())), "{}", id);
+                assert!(ids.insert(id));
+            }
+        }
+        // The separator keeps shifted boundaries apart
+        assert_ne!(
+            key(AppendPurpose::OfflineQueue, "m1b", "ob").msg_id(),
+            key(AppendPurpose::OfflineQueue, "m1", "bob").msg_id()
+        );
+    }
+
+    #[test]
+    fn ids_are_short_and_header_safe_whatever_the_ids_contain() {
+        let long = "x".repeat(4096);
+        for (message_id, recipient) in [("m 1\r\n", "bob: carol"), (long.as_str(), "ünïcode"), ("", "")] {
+            let id = key(AppendPurpose::DeadLetter, message_id, recipient).msg_id();
+            assert_eq!(id.len(), "dlq-".len() + 32, "{}", id);
+            assert!(id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'), "{}", id);
+        }
+    }
+
+    #[test]
+    fn every_appended_stream_is_provisioned_once() {
+        let mut config = BrokerConfig::load().unwrap();
+        config.persist_dedup.extra_streams = vec![config.nats.stream_name.clone(), "OFFLINE_STORE".into()];
+        let streams = appended_streams(&config);
+        for expected in [
+            &config.nats.stream_name,
+            &config.offline_quarantine.stream,
+            &config.ingestion_pause.holding_stream,
+            &config.volume_accounting.stream,
+            "OFFLINE_STORE",
+        ] {
+            assert_eq!(streams.iter().filter(|s| s.as_str() == expected).count(), 1, "{:?}", streams);
+        }
+    }
+
+    /// A fresh stream on JetStream at `NATS_URL` (default `localhost:4222`):
+    /// `cargo test -- --ignored idempotent_append`
+    async fn stream(duplicate_window: Duration) -> (jetstream::Context, String, String) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+        let id = Uuid::new_v4().simple().to_string();
+        let (name, subject) = (format!("APPEND_TEST_{}", id), format!("test.append.{}", id));
+        jetstream
+            .create_stream(jetstream::stream::Config {
+                name: name.clone(),
+                subjects: vec![format!("{}.>", subject)],
+                duplicate_window,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        (jetstream, name, subject)
+    }
+
+    /// What a fanout persists for one message: an offline entry per recipient and a dead letter
+    async fn persist(jetstream: &jetstream::Context, subject: &str, metrics: &BrokerMetrics) -> Vec<Appended> {
+        let mut appended = Vec::new();
+        for recipient in ["bob", "carol", "dave"] {
+            let key = key(AppendPurpose::OfflineQueue, "m1", recipient);
+            let subject = format!("{}.offline.{}", subject, recipient);
+            let payload = Bytes::from(format!("m1 for {}", recipient));
+            appended.push(append(jetstream, key, subject, HeaderMap::new(), payload, metrics).await.unwrap());
+        }
+        let key = key(AppendPurpose::DeadLetter, "m1", "");
+        let subject = format!("{}.dlq", subject);
+        appended.push(append(jetstream, key, subject, HeaderMap::new(), "m1".into(), metrics).await.unwrap());
+        appended
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn a_replayed_fanout_persists_one_copy_per_recipient() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let metrics = BrokerMetrics::new().unwrap();
+        let (jetstream, name, subject) = stream(Duration::from_secs(600)).await;
+
+        // The broker crashes after persisting and recovery runs the same fanout again
+        let first = persist(&jetstream, &subject, &metrics).await;
+        let replayed = persist(&jetstream, &subject, &metrics).await;
+
+        for (first, replayed) in first.iter().zip(&replayed) {
+            let Appended::New { sequence } = *first else { panic!("{:?}", first) };
+            assert_eq!(*replayed, Appended::Duplicate { sequence });
+        }
+        let mut stream = jetstream.get_stream(&name).await.unwrap();
+        assert_eq!(stream.info().await.unwrap().state.messages, 4);
+        for recipient in ["bob", "carol", "dave"] {
+            let raw = stream
+                .get_last_raw_message_by_subject(&format!("{}.offline.{}", subject, recipient))
+                .await
+                .unwrap();
+            let stored = async_nats::Message::try_from(raw).unwrap();
+            let msg_id = stored.headers.as_ref().and_then(|h| h.get("Nats-Msg-Id")).map(|v| v.to_string());
+            assert_eq!(msg_id, Some(key(AppendPurpose::OfflineQueue, "m1", recipient).msg_id()));
+        }
+
+        let rendered = recorder.handle().render();
+        let expected = format!(r#"broker_persist_duplicates_suppressed_total{{stream="{}"}} 4"#, name);
+        assert!(rendered.contains(&expected), "{}", rendered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn duplicate_windows_are_raised_but_never_lowered() {
+        let (jetstream, short, _) = stream(Duration::from_secs(10)).await;
+        let (_, long, _) = stream(Duration::from_secs(3600)).await;
+        let mut config = BrokerConfig::load().unwrap();
+        config.nats.stream_name = short.clone();
+        config.persist_dedup.extra_streams = vec![long.clone()];
+        for missing in [
+            &mut config.offline_quarantine.stream,
+            &mut config.ingestion_pause.holding_stream,
+            &mut config.volume_accounting.stream,
+        ] {
+            *missing = format!("APPEND_TEST_MISSING_{}", Uuid::new_v4().simple());
+        }
+
+        assert_eq!(ensure_duplicate_windows(&jetstream, &config).await.unwrap(), 1);
+        assert_eq!(ensure_duplicate_windows(&jetstream, &config).await.unwrap(), 0);
+        for (name, window) in [(short, config.persist_dedup.duplicate_window), (long, Duration::from_secs(3600))] {
+            let mut stream = jetstream.get_stream(&name).await.unwrap();
+            assert_eq!(stream.info().await.unwrap().config.duplicate_window, window, "{}", name);
+        }
+    }
+}
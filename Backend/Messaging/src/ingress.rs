@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use crate::{
+    abuse::{AbuseScores, AbuseSignal},
+    archive::{ArchiveError, ArchivedConversations},
+    attestation::{AttestationError, SenderAttestor},
+    classification::{Classification, ClassificationStage, ClassifiedSpam},
+    config::RateLimits,
+    content_policy::{ContentTypePolicy, ContentTypeRejection},
+    degradation::DegradationSwitchboard,
+    e2e_latency,
+    ingestion_pause::{IngestionPauses, PauseSelector},
+    ingress_admission::IngressAdmission,
+    kind_budget::{BudgetAction, KindBudgets, OverBudget, TrafficKind},
+    maintenance::{InMaintenance, MaintenanceMode},
+    message::types::{MessageEnvelope, MessageType, Priority, ValidationError},
+    metrics::BrokerMetrics,
+    policy::{IngressSource, PolicyDenied, PolicyEngine},
+    sanitize::MetadataSanitizer,
+    shed_exemption::ShedExemptions,
+    tenant_metrics::TenantMetrics,
+};
+
+/// Admission checks run on every ingress envelope before routing
+pub struct IngressGate {
+    limits: RateLimits,
+    attestor: Arc<SenderAttestor>,
+    policy: Arc<PolicyEngine>,
+    content_types: Arc<ContentTypePolicy>,
+    sanitizer: MetadataSanitizer,
+    switchboard: Arc<DegradationSwitchboard>,
+    pauses: Arc<IngestionPauses>,
+    archived: Arc<ArchivedConversations>,
+    maintenance: Arc<MaintenanceMode>,
+    kind_budgets: Arc<KindBudgets>,
+    abuse: Arc<AbuseScores>,
+    classification: Arc<ClassificationStage>,
+    admission: Arc<IngressAdmission>,
+    exemptions: Arc<ShedExemptions>,
+    tenant_metrics: Option<Arc<TenantMetrics>>,
+    metrics: BrokerMetrics,
+}
+
+impl IngressGate {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        limits: RateLimits,
+        attestor: Arc<SenderAttestor>,
+        policy: Arc<PolicyEngine>,
+        content_types: Arc<ContentTypePolicy>,
+        sanitizer: MetadataSanitizer,
+        switchboard: Arc<DegradationSwitchboard>,
+        pauses: Arc<IngestionPauses>,
+        archived: Arc<ArchivedConversations>,
+        maintenance: Arc<MaintenanceMode>,
+        kind_budgets: Arc<KindBudgets>,
+        abuse: Arc<AbuseScores>,
+        classification: Arc<ClassificationStage>,
+        admission: Arc<IngressAdmission>,
+        exemptions: Arc<ShedExemptions>,
+        tenant_metrics: Option<Arc<TenantMetrics>>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            limits,
+            attestor,
+            policy,
+            content_types,
+            sanitizer,
+            switchboard,
+            pauses,
+            archived,
+            maintenance,
+            kind_budgets,
+            abuse,
+            classification,
+            admission,
+            exemptions,
+            tenant_metrics,
+            metrics,
+        }
+    }
+
+    /// Verify, sanitize, validate, authorize and apply the active degradation toggles
+    pub async fn admit(&self, source: &IngressSource, envelope: &mut MessageEnvelope) -> Result<(), IngressRejection> {
+        // Consumers stop pulling during maintenance; this catches what was in flight. Caller NAKs.
+        self.maintenance.check("ingress")?;
+
+        // Waits while the source's class is over its share; NATS consumers stall, HTTP bodies stop being read
+        self.admission.admit(source.class, 1).await;
+
+        // Sender identity is checked before any other processing, so forged
+        // traffic neither counts as received nor spends a kind's budget
+        self.attestor.verify(&source.gateway_id, envelope)?;
+
+        self.metrics.record_message_received();
+        let kind = self.kind_budgets.observe(envelope);
+
+        // Raw values are overwritten here, before anything logs or stores them
+        self.sanitizer.sanitize(envelope);
+        e2e_latency::stamp_ingest(envelope);
+
+        // Caller NAKs or parks according to `IngestionPauses::action`
+        if let Some(selector) = self.pauses.matches(source, envelope) {
+            self.metrics.record_ingestion_paused(selector.kind());
+            return Err(IngressRejection::Paused(selector));
+        }
+
+        if let Err(e) = envelope.validate(&self.limits) {
+            self.metrics.record_message_invalid();
+            self.abuse.observe(&envelope.from, AbuseSignal::Rejected);
+            return Err(IngressRejection::Invalid(e));
+        }
+
+        // Only past attestation, so nobody can run up someone else's abuse score
+        let checked = self
+            .content_types
+            .check(envelope)
+            .map_err(IngressRejection::from)
+            .and_then(|()| self.policy.evaluate(source, envelope).map_err(IngressRejection::from));
+        if let Err(rejection) = checked {
+            self.abuse.observe(&envelope.from, AbuseSignal::Rejected);
+            return Err(rejection);
+        }
+
+        if self.archived.rejects(envelope).await? {
+            return Err(IngressRejection::Archived(envelope.conversation_id()));
+        }
+
+        shed(&self.switchboard, &self.kind_budgets, &self.exemptions, &self.metrics, envelope, kind)?;
+
+        // Last, so only admitted messages pay for a classifier call
+        if self.classification.classify(envelope).await == Classification::Blocked {
+            self.metrics.record_message_dropped("classified_spam");
+            self.abuse.observe(&envelope.from, AbuseSignal::Rejected);
+            return Err(ClassifiedSpam.into());
+        }
+
+        if let Some(tenant_metrics) = &self.tenant_metrics {
+            tenant_metrics.record_received(envelope.tenant_id.as_deref(), envelope.payload.ciphertext.len());
+        }
+
+        Ok(())
+    }
+}
+
+/// Degradation drops and kind budgets, minus what `exemptions` lets through
+///
+/// Exemptions are looked up only for messages about to be shed. Caller NAKs
+/// deferred kinds with `KindBudgets::window` as the delay.
+pub(crate) fn shed(
+    switchboard: &DegradationSwitchboard,
+    kind_budgets: &KindBudgets,
+    exemptions: &ShedExemptions,
+    metrics: &BrokerMetrics,
+    envelope: &MessageEnvelope,
+    kind: TrafficKind,
+) -> Result<(), IngressRejection> {
+    if let Some(behavior) = degraded_behavior(switchboard, envelope) {
+        if !exemptions.exempts(envelope, behavior) {
+            metrics.record_message_dropped(behavior);
+            return Err(IngressRejection::Degraded(behavior));
+        }
+    }
+
+    if let Err(over) = kind_budgets.admit(kind) {
+        if !exemptions.exempts(envelope, "kind_budget") {
+            if over.action == BudgetAction::Drop {
+                metrics.record_message_dropped("kind_budget");
+            }
+            return Err(over.into());
+        }
+    }
+    Ok(())
+}
+
+fn degraded_behavior(switchboard: &DegradationSwitchboard, envelope: &MessageEnvelope) -> Option<&'static str> {
+    let active = switchboard.current();
+    let toggles = &active.toggles;
+
+    match envelope.message_type {
+        MessageType::Delivered | MessageType::Read if toggles.disable_receipts => {
+            return Some("degraded_receipts");
+        }
+        MessageType::Typing if toggles.disable_typing_fanout => {
+            return Some("degraded_typing");
+        }
+        MessageType::Presence if toggles.disable_presence_fanout => {
+            return Some("degraded_presence");
+        }
+        _ => {}
+    }
+
+    if toggles.drop_bulk_priority && envelope.priority == Priority::Bulk {
+        return Some("degraded_bulk");
+    }
+
+    None
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IngressRejection {
+    #[error("sender attestation failed: {0}")]
+    Unattested(#[from] AttestationError),
+    #[error("invalid message: {0}")]
+    Invalid(#[from] ValidationError),
+    #[error(transparent)]
+    ContentType(#[from] ContentTypeRejection),
+    #[error(transparent)]
+    Denied(#[from] PolicyDenied),
+    #[error("dropped while degraded: {0}")]
+    Degraded(&'static str),
+    #[error("ingestion paused for {}:{}", .0.kind(), .0.value())]
+    Paused(PauseSelector),
+    #[error("conversation {0} is archived")]
+    Archived(String),
+    #[error(transparent)]
+    ArchiveLookup(#[from] ArchiveError),
+    #[error(transparent)]
+    Maintenance(#[from] InMaintenance),
+    #[error(transparent)]
+    OverBudget(#[from] OverBudget),
+    #[error(transparent)]
+    Spam(#[from] ClassifiedSpam),
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    background_quota::FairShares,
+    clock::{SharedClock, SystemClock},
+    config::IngressAdmissionConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Where ingress traffic entered the broker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceClass {
+    /// Gateways publishing on the ingress subject, and peer forwards
+    #[default]
+    Nats,
+    /// REST bulk send
+    RestBulk,
+    /// Webhook bridge and the outbox adapter
+    Bridge,
+    /// WebSocket clients
+    Ws,
+}
+
+impl SourceClass {
+    pub const ALL: [SourceClass; 4] = [SourceClass::Nats, SourceClass::RestBulk, SourceClass::Bridge, SourceClass::Ws];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceClass::Nats => "nats",
+            SourceClass::RestBulk => "rest_bulk",
+            SourceClass::Bridge => "bridge",
+            SourceClass::Ws => "ws",
+        }
+    }
+}
+
+/// How recently a class must have admitted to count as busy without waiters
+const DEMAND_WINDOW: Duration = Duration::from_secs(1);
+
+/// How often the report task publishes queue depths
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+struct AdmissionState {
+    tokens: FairShares<SourceClass>,
+    /// When each class last admitted
+    last_admit: HashMap<SourceClass, Instant>,
+}
+
+/// Weighted-fair admission shared by every ingress source
+///
+/// `ingress_admission.messages_per_second` is split between source classes
+/// by `ingress_admission.shares`, with the background quota's `FairShares`:
+/// only busy classes take part, so an idle class's share goes to the others
+/// and a lone class can use the whole capacity. A class is busy while
+/// callers wait on it or it admitted within the last second; NATS traffic
+/// is usually admitted without waiting, so counting waiters alone would
+/// hand its share to HTTP classes the moment they queue. NATS always
+/// refills at least `ingress_admission.nats_guaranteed_share` of capacity,
+/// whatever the other classes are doing, so an HTTP batch can slow NATS
+/// ingress to its guarantee but never below it.
+///
+/// Tokens are topped up whenever a caller checks them, holding at most one
+/// second of the class's rate; deferred callers re-check every
+/// `ingress_admission.acquire_poll_ms`, as background workers do.
+///
+/// Every message waits for its class's admission in the ingress gate.
+pub struct IngressAdmission {
+    state: Mutex<AdmissionState>,
+    /// Callers currently waiting for admission, per class
+    waiting: HashMap<SourceClass, AtomicU64>,
+    config: IngressAdmissionConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl IngressAdmission {
+    pub fn new(config: IngressAdmissionConfig, metrics: BrokerMetrics) -> Self {
+        Self::with_clock(config, SystemClock::shared(), metrics)
+    }
+
+    pub fn with_clock(config: IngressAdmissionConfig, clock: SharedClock, metrics: BrokerMetrics) -> Self {
+        Self {
+            state: Mutex::new(AdmissionState {
+                tokens: FairShares::new(clock.now_instant()),
+                last_admit: HashMap::new(),
+            }),
+            waiting: SourceClass::ALL.iter().map(|class| (*class, AtomicU64::new(0))).collect(),
+            config,
+            clock,
+            metrics,
+        }
+    }
+
+    /// Take `count` messages for `class` if its share allows it now
+    ///
+    /// `count` is at most one second of the class's rate, or it's never admitted.
+    pub fn try_admit(&self, class: SourceClass, count: u32) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        let now = self.clock.now_instant();
+        {
+            let mut state = self.state.lock();
+            self.refill(&mut state, now);
+            if !state.tokens.try_take(class, count) {
+                return false;
+            }
+            state.last_admit.insert(class, now);
+        }
+        self.metrics.record_ingress_admission(class.as_str(), "admitted", count);
+        true
+    }
+
+    /// Wait until `count` messages for `class` are allowed
+    pub async fn admit(&self, class: SourceClass, count: u32) {
+        if self.try_admit(class, count) {
+            return;
+        }
+        self.metrics.record_ingress_admission(class.as_str(), "deferred", count);
+        let _waiting = Waiting::enter(&self.waiting[&class]);
+        while !self.try_admit(class, count) {
+            self.clock.sleep(self.poll_interval()).await;
+        }
+    }
+
+    /// Publish per-class queue depths
+    pub fn report(&self) {
+        for class in SourceClass::ALL {
+            self.metrics
+                .update_ingress_admission_waiting(class.as_str(), self.waiting[&class].load(Ordering::Relaxed));
+        }
+    }
+
+    /// Report queue depths every second
+    pub fn spawn(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let admission = Arc::clone(self);
+        Some(spawn_traced("ingress_admission", TaskContext::new("ingress_admission"), async move {
+            loop {
+                admission.report();
+                admission.clock.sleep(REPORT_INTERVAL).await;
+            }
+        }))
+    }
+
+    fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.config.acquire_poll_ms.max(1))
+    }
+
+    /// Whether `class` has callers waiting or admitted within `DEMAND_WINDOW`
+    fn busy(&self, state: &AdmissionState, class: SourceClass, now: Instant) -> bool {
+        self.waiting[&class].load(Ordering::Relaxed) > 0
+            || state
+                .last_admit
+                .get(&class)
+                .is_some_and(|at| now.saturating_duration_since(*at) <= DEMAND_WINDOW)
+    }
+
+    /// Per-class token rates given which classes are busy
+    fn rates(&self, busy: &[SourceClass]) -> Vec<(SourceClass, f64)> {
+        let capacity = self.config.messages_per_second as f64;
+        let share = |class: &SourceClass| self.config.shares.get(class).copied().unwrap_or(1.0);
+        // With nobody busy every class gets its configured share
+        let participants = if busy.is_empty() { SourceClass::ALL.to_vec() } else { busy.to_vec() };
+        let guaranteed = capacity * self.config.nats_guaranteed_share.clamp(0.0, 1.0);
+
+        let mut rates = FairShares::split(&participants, capacity, share);
+        match rates.iter().find(|(class, _)| *class == SourceClass::Nats) {
+            Some((_, nats)) if *nats >= guaranteed => {}
+            // Topping NATS up to its guarantee comes out of the others' shares
+            Some(_) => {
+                let others: Vec<SourceClass> =
+                    participants.into_iter().filter(|class| *class != SourceClass::Nats).collect();
+                rates = FairShares::split(&others, (capacity - guaranteed).max(0.0), share);
+                rates.push((SourceClass::Nats, guaranteed));
+            }
+            // NATS refills its guarantee even while idle, so it never waits for it
+            None => rates.push((SourceClass::Nats, guaranteed)),
+        }
+        rates
+    }
+
+    fn refill(&self, state: &mut AdmissionState, now: Instant) {
+        let busy: Vec<SourceClass> =
+            SourceClass::ALL.into_iter().filter(|class| self.busy(state, *class, now)).collect();
+        let rates = self.rates(&busy);
+        state.tokens.refill(&rates, now);
+    }
+}
+
+/// Counts a caller in its class's queue until dropped
+struct Waiting<'a> {
+    waiting: &'a AtomicU64,
+}
+
+impl<'a> Waiting<'a> {
+    fn enter(waiting: &'a AtomicU64) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self { waiting }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+    };
+
+    const CAPACITY: u32 = 1_000;
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn admission(shares: [(SourceClass, f64); 4]) -> (Arc<IngressAdmission>, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().ingress_admission;
+        config.enabled = true;
+        config.messages_per_second = CAPACITY;
+        config.shares = HashMap::from(shares);
+        config.nats_guaranteed_share = 0.5;
+        config.acquire_poll_ms = TICK.as_millis() as u64;
+        let clock = Arc::new(SimClock::new());
+        let admission = IngressAdmission::with_clock(config, clock.clone(), BrokerMetrics::new().unwrap());
+        (Arc::new(admission), clock)
+    }
+
+    /// Offer `offered(class)` messages per class every tick for `duration`;
+    /// `u32::MAX` saturates. Returns what each class got through.
+    fn run(
+        admission: &IngressAdmission,
+        clock: &SimClock,
+        duration: Duration,
+        offered: impl Fn(SourceClass) -> u32,
+    ) -> HashMap<SourceClass, u64> {
+        let mut admitted = HashMap::new();
+        for _ in 0..duration.as_millis() / TICK.as_millis() {
+            clock.advance(TICK);
+            for class in SourceClass::ALL {
+                let mut offer = offered(class);
+                while offer > 0 && admission.try_admit(class, 1) {
+                    *admitted.entry(class).or_default() += 1;
+                    offer -= 1;
+                }
+            }
+        }
+        admitted
+    }
+
+    fn assert_near(admitted: &HashMap<SourceClass, u64>, class: SourceClass, expected: u64) {
+        let got = admitted.get(&class).copied().unwrap_or(0);
+        assert!(got.abs_diff(expected) <= expected / 50, "{} admitted {}, expected {}", class.as_str(), got, expected);
+    }
+
+    #[test]
+    fn a_saturating_rest_batch_and_steady_nats_split_capacity_by_share() {
+        let (admission, clock) = admission([
+            (SourceClass::Nats, 3.0),
+            (SourceClass::RestBulk, 1.0),
+            (SourceClass::Bridge, 1.0),
+            (SourceClass::Ws, 1.0),
+        ]);
+        // NATS offers the whole capacity, the REST batch as much as it can
+        let admitted = run(&admission, &clock, Duration::from_secs(10), |class| match class {
+            SourceClass::Nats => 10,
+            SourceClass::RestBulk => u32::MAX,
+            _ => 0,
+        });
+
+        // Three to one between the busy classes; idle ones strand nothing
+        assert_near(&admitted, SourceClass::Nats, 7_500);
+        assert_near(&admitted, SourceClass::RestBulk, 2_500);
+        assert!(!admitted.contains_key(&SourceClass::Bridge));
+    }
+
+    #[test]
+    fn nats_keeps_its_guarantee_whatever_the_shares() {
+        let (admission, clock) = admission([
+            (SourceClass::Nats, 1.0),
+            (SourceClass::RestBulk, 9.0),
+            (SourceClass::Bridge, 1.0),
+            (SourceClass::Ws, 1.0),
+        ]);
+        let admitted = run(&admission, &clock, Duration::from_secs(10), |class| match class {
+            SourceClass::Nats => 10,
+            SourceClass::RestBulk => u32::MAX,
+            _ => 0,
+        });
+        // Its share alone would be a tenth
+        assert_near(&admitted, SourceClass::Nats, 5_000);
+        assert_near(&admitted, SourceClass::RestBulk, 5_000);
+
+        // Once NATS goes quiet the batch gets the whole capacity...
+        let rest_only = |class| if class == SourceClass::RestBulk { u32::MAX } else { 0 };
+        run(&admission, &clock, DEMAND_WINDOW, rest_only);
+        let admitted = run(&admission, &clock, Duration::from_secs(2), rest_only);
+        assert_near(&admitted, SourceClass::RestBulk, 2_000);
+        // ...while NATS still banks its guarantee, so a burst doesn't wait
+        assert!(admission.try_admit(SourceClass::Nats, CAPACITY / 2));
+    }
+
+    #[tokio::test]
+    async fn deferred_callers_wait_on_the_clock() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let (admission, _) = admission([
+            (SourceClass::Nats, 4.0),
+            (SourceClass::RestBulk, 1.0),
+            (SourceClass::Bridge, 1.0),
+            (SourceClass::Ws, 2.0),
+        ]);
+        let clock = Arc::new(SimClock::new().auto_advance(true));
+        let admission = Arc::new(IngressAdmission::with_clock(
+            admission.config.clone(),
+            clock.clone(),
+            BrokerMetrics::new().unwrap(),
+        ));
+
+        let started = clock.now_instant();
+        admission.admit(SourceClass::Bridge, 100).await;
+
+        // A lone bridge caller gets the whole capacity: 100 messages in about 100ms
+        let waited = clock.now_instant() - started;
+        assert!((Duration::from_millis(100)..=Duration::from_millis(110)).contains(&waited), "{:?}", waited);
+        assert_eq!(admission.waiting[&SourceClass::Bridge].load(Ordering::Relaxed), 0);
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_ingress_admission_total{class="bridge",outcome="deferred"} 100"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_ingress_admission_total{class="bridge",outcome="admitted"} 100"#), "{}", rendered);
+    }
+}
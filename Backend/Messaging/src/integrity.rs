@@ -0,0 +1,324 @@
+use std::{num::NonZeroUsize, sync::Arc};
+use bytes::Bytes;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+
+use crate::{
+    config::IntegrityConfig,
+    delivery_id::DeliveryStamp,
+    metrics::BrokerMetrics,
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+};
+
+/// CRC32C of the egress payload bytes, lowercase hex
+pub const PAYLOAD_CHECKSUM_HEADER: &str = "Broker-Payload-Crc32c";
+
+/// Published by a gateway whose received payload failed its checksum or didn't decode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorruptionReport {
+    pub gateway_id: String,
+    pub delivery_id: String,
+    /// Checksum from the `Broker-Payload-Crc32c` header as received, if any
+    pub declared_checksum: Option<u32>,
+    /// CRC32C the gateway computed over the bytes it received
+    pub received_checksum: u32,
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+}
+
+/// Where a reported corruption happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionClass {
+    /// The gateway got exactly the bytes the broker checksummed: the broker published garbage
+    BrokerSide,
+    /// The bytes or header changed between broker publish and gateway
+    TransitSide,
+    /// No publish record left for the delivery
+    Unknown,
+}
+
+impl CorruptionClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CorruptionClass::BrokerSide => "broker",
+            CorruptionClass::TransitSide => "transit",
+            CorruptionClass::Unknown => "unknown",
+        }
+    }
+}
+
+/// CRC32C over the payload; computed once per unique payload, not per recipient
+pub fn payload_checksum(payload: &[u8]) -> u32 {
+    crc32c::crc32c(payload)
+}
+
+/// Egress checksums and the record needed to classify gateway corruption reports
+///
+/// Fanout calls `seal` once per serialized payload and shares the result
+/// across every recipient's stamp, so the CPU cost is a single CRC32C pass
+/// (hardware accelerated where available) per unique payload. The checksum
+/// published for each delivery ID is kept in a sharded LRU of
+/// `integrity.records_per_shard` entries per shard; reports for deliveries
+/// that have since been evicted are classified `unknown`.
+pub struct EgressIntegrity {
+    records: Vec<Mutex<LruCache<String, u32>>>,
+    config: IntegrityConfig,
+    metrics: BrokerMetrics,
+}
+
+impl EgressIntegrity {
+    pub fn new(config: IntegrityConfig, shard_count: usize, metrics: BrokerMetrics) -> Self {
+        let per_shard = NonZeroUsize::new(config.records_per_shard.max(1)).unwrap();
+        Self {
+            records: (0..shard_count.max(1))
+                .map(|_| Mutex::new(LruCache::new(per_shard)))
+                .collect(),
+            config,
+            metrics,
+        }
+    }
+
+    pub fn seal(&self, payload: &Bytes) -> Option<u32> {
+        self.config.enabled.then(|| payload_checksum(payload))
+    }
+
+    /// Attach `checksum` to a recipient's stamp and remember it for its delivery ID
+    pub fn stamp(&self, stamp: &mut DeliveryStamp, checksum: Option<u32>) {
+        let Some(checksum) = checksum else {
+            return;
+        };
+        stamp.checksum = Some(checksum);
+        let shard = shard_for(&stamp.delivery_id, self.records.len());
+        self.records[shard].lock().put(stamp.delivery_id.clone(), checksum);
+    }
+
+    pub fn classify(&self, report: &CorruptionReport) -> CorruptionClass {
+        let shard = shard_for(&report.delivery_id, self.records.len());
+        let Some(published) = self.records[shard].lock().get(&report.delivery_id).copied() else {
+            return CorruptionClass::Unknown;
+        };
+        let header_intact = report.declared_checksum.is_none_or(|declared| declared == published);
+        if header_intact && report.received_checksum == published {
+            CorruptionClass::BrokerSide
+        } else {
+            CorruptionClass::TransitSide
+        }
+    }
+
+    pub fn handle_report(&self, payload: &[u8]) -> Result<CorruptionClass, IntegrityError> {
+        let report: CorruptionReport =
+            serde_json::from_slice(payload).map_err(|e| IntegrityError(e.to_string()))?;
+        let class = self.classify(&report);
+        self.metrics.record_egress_corruption(class.as_str());
+        warn!(
+            "Gateway {} reported corrupted delivery {}: {}-side",
+            report.gateway_id,
+            report.delivery_id,
+            class.as_str()
+        );
+        Ok(class)
+    }
+
+    pub fn spawn(self: &Arc<Self>, client: async_nats::Client) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let integrity = Arc::clone(self);
+        Some(spawn_traced("corruption_reports", TaskContext::new("integrity"), async move {
+            let mut reports = match client.subscribe(integrity.config.report_subject.clone()).await {
+                Ok(reports) => reports,
+                Err(e) => {
+                    warn!("Failed to subscribe to corruption reports: {}", e);
+                    return;
+                }
+            };
+            while let Some(report) = reports.next().await {
+                if let Err(e) = integrity.handle_report(&report.payload) {
+                    debug!("Dropped malformed corruption report: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("malformed corruption report: {0}")]
+pub struct IntegrityError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use async_nats::HeaderMap;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    fn integrity() -> EgressIntegrity {
+        let mut config = BrokerConfig::load().unwrap().integrity;
+        config.enabled = true;
+        config.records_per_shard = 4;
+        EgressIntegrity::new(config, 2, BrokerMetrics::new().unwrap())
+    }
+
+    fn stamp(delivery_id: &str) -> DeliveryStamp {
+        DeliveryStamp {
+            delivery_id: delivery_id.into(),
+            attempt: 1,
+            checksum: None,
+        }
+    }
+
+    /// What a gateway does with a received publish: a report if the bytes don't check out
+    fn gateway_receive(delivery_id: &str, headers: &HeaderMap, payload: &[u8]) -> Option<CorruptionReport> {
+        let declared = headers
+            .get(PAYLOAD_CHECKSUM_HEADER)
+            .and_then(|value| u32::from_str_radix(value.as_str(), 16).ok());
+        let received = payload_checksum(payload);
+        let decodes = serde_json::from_slice::<serde_json::Value>(payload).is_ok();
+        if declared == Some(received) && decodes {
+            return None;
+        }
+        Some(CorruptionReport {
+            gateway_id: "gateway-1".into(),
+            delivery_id: delivery_id.into(),
+            declared_checksum: declared,
+            received_checksum: received,
+            timestamp: 0,
+        })
+    }
+
+    /// Seal and stamp `payload` for one delivery; the headers as published
+    fn publish(integrity: &EgressIntegrity, delivery_id: &str, payload: &Bytes) -> HeaderMap {
+        let mut stamp = stamp(delivery_id);
+        integrity.stamp(&mut stamp, integrity.seal(payload));
+        let mut headers = HeaderMap::new();
+        stamp.apply(&mut headers);
+        headers
+    }
+
+    fn flip(payload: &[u8], index: usize) -> Bytes {
+        let mut corrupted = payload.to_vec();
+        corrupted[index] ^= 0x20;
+        corrupted.into()
+    }
+
+    const PAYLOAD: &[u8] = br#"{"message_id":"m1","payload":{"ciphertext":"aGk="}}"#;
+
+    #[test]
+    fn intact_deliveries_raise_no_report() {
+        let integrity = integrity();
+        let payload = Bytes::from_static(PAYLOAD);
+        let headers = publish(&integrity, "d1", &payload);
+        assert_eq!(
+            headers.get(PAYLOAD_CHECKSUM_HEADER).unwrap().as_str(),
+            format!("{:08x}", payload_checksum(PAYLOAD))
+        );
+        assert!(gateway_receive("d1", &headers, &payload).is_none());
+    }
+
+    #[test]
+    fn garbage_the_broker_sealed_is_broker_side() {
+        let integrity = integrity();
+        // Corrupted before the checksum pass, as a serialization bug would
+        let garbage = flip(PAYLOAD, 0);
+        let headers = publish(&integrity, "d1", &garbage);
+
+        let report = gateway_receive("d1", &headers, &garbage).expect("gateway accepted undecodable bytes");
+        assert_eq!(integrity.classify(&report), CorruptionClass::BrokerSide);
+    }
+
+    #[test]
+    fn bytes_changed_after_publish_are_transit_side() {
+        let integrity = integrity();
+        let payload = Bytes::from_static(PAYLOAD);
+        let headers = publish(&integrity, "d1", &payload);
+
+        let report = gateway_receive("d1", &headers, &flip(PAYLOAD, 20)).expect("corruption went unnoticed");
+        assert_eq!(report.declared_checksum, Some(payload_checksum(PAYLOAD)));
+        assert_eq!(integrity.classify(&report), CorruptionClass::TransitSide);
+    }
+
+    #[test]
+    fn a_mangled_checksum_header_is_transit_side() {
+        let integrity = integrity();
+        let payload = Bytes::from_static(PAYLOAD);
+        publish(&integrity, "d1", &payload);
+        let mut headers = HeaderMap::new();
+        headers.insert(PAYLOAD_CHECKSUM_HEADER, format!("{:08x}", payload_checksum(PAYLOAD) ^ 1).as_str());
+
+        let report = gateway_receive("d1", &headers, &payload).expect("header mismatch went unnoticed");
+        assert_eq!(integrity.classify(&report), CorruptionClass::TransitSide);
+    }
+
+    #[test]
+    fn evicted_or_foreign_deliveries_are_unknown() {
+        let integrity = integrity();
+        let payload = Bytes::from_static(PAYLOAD);
+        for i in 0..100 {
+            publish(&integrity, &format!("d{}", i), &payload);
+        }
+        let report = |delivery_id: &str| CorruptionReport {
+            gateway_id: "gateway-1".into(),
+            delivery_id: delivery_id.into(),
+            declared_checksum: None,
+            received_checksum: 0,
+            timestamp: 0,
+        };
+        assert_eq!(integrity.classify(&report("d0")), CorruptionClass::Unknown);
+        assert_eq!(integrity.classify(&report("never-published")), CorruptionClass::Unknown);
+        assert_eq!(integrity.classify(&report("d99")), CorruptionClass::TransitSide);
+    }
+
+    #[test]
+    fn one_checksum_is_shared_across_recipients() {
+        let integrity = integrity();
+        let payload = Bytes::from_static(PAYLOAD);
+        let checksum = integrity.seal(&payload);
+        let mut stamps: Vec<_> = ["d1", "d2", "d3"].into_iter().map(stamp).collect();
+        for stamp in &mut stamps {
+            integrity.stamp(stamp, checksum);
+        }
+        assert!(stamps.iter().all(|stamp| stamp.checksum == checksum));
+
+        let report = gateway_receive("d3", &HeaderMap::new(), &flip(PAYLOAD, 5)).unwrap();
+        assert_eq!(integrity.classify(&report), CorruptionClass::TransitSide);
+    }
+
+    #[test]
+    fn disabled_integrity_adds_no_header() {
+        let mut config = BrokerConfig::load().unwrap().integrity;
+        config.enabled = false;
+        let integrity = EgressIntegrity::new(config, 2, BrokerMetrics::new().unwrap());
+        let headers = publish(&integrity, "d1", &Bytes::from_static(PAYLOAD));
+        assert!(headers.get(PAYLOAD_CHECKSUM_HEADER).is_none());
+    }
+
+    #[test]
+    fn reports_are_counted_per_class() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let integrity = integrity();
+            let garbage = flip(PAYLOAD, 0);
+            let headers = publish(&integrity, "broken", &garbage);
+            let broker_side = gateway_receive("broken", &headers, &garbage).unwrap();
+            let payload = Bytes::from_static(PAYLOAD);
+            let headers = publish(&integrity, "fine", &payload);
+            let transit_side = gateway_receive("fine", &headers, &flip(PAYLOAD, 20)).unwrap();
+
+            for report in [&broker_side, &transit_side, &transit_side] {
+                let class = integrity.handle_report(&serde_json::to_vec(report).unwrap()).unwrap();
+                assert_ne!(class, CorruptionClass::Unknown);
+            }
+            assert!(integrity.handle_report(b"not json").is_err());
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains(r#"broker_egress_corruption_total{class="broker"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_egress_corruption_total{class="transit"} 2"#), "{}", rendered);
+    }
+}
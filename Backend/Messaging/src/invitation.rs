@@ -0,0 +1,506 @@
+use std::{num::NonZeroUsize, sync::Arc};
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::InvitationConfig,
+    degradation::DegradationSwitchboard,
+    membership::{GroupView, MemberState, MembershipCache, MembershipError},
+    message::types::{EncryptedPayload, MessageEnvelope, MessageType, Priority},
+    metrics::BrokerMetrics,
+    path_override::{PathContext, PathOverrides},
+    recipient_trace::{Downgrade, ExclusionReason, RecipientDecisions},
+    shed_exemption::ShedExemptions,
+};
+
+/// Metadata on an invitation event: when the invitation lapses, in milliseconds
+pub const INVITATION_EXPIRES_METADATA: &str = "invitation_expires_at";
+
+/// Recipients of one group message by member state
+#[derive(Debug, Clone, Default)]
+pub struct GroupFanout {
+    /// Users in `member` state; get the message itself
+    pub members: Vec<String>,
+    /// First message since an invited user's invitation; one event each
+    pub invitations: Vec<(String, MessageEnvelope)>,
+    /// Send `members` the message once over the group's broadcast subject
+    /// instead of one copy each
+    pub broadcast: bool,
+}
+
+/// Gates group traffic on member state
+///
+/// Only `member` users receive group messages. An `invited` user gets
+/// exactly one `GroupInvitation` event, on the first message after the
+/// invitation, and nothing else until a join; `banned` users get nothing.
+/// Notified invitations are tracked in an LRU of
+/// `invitations.tracked_invitations` entries that expire with the
+/// invitation, so an invitation renewed after it lapsed notifies again.
+///
+/// `on_state_change` applies a resolver or control event: it invalidates
+/// the group in the membership cache, so a join takes effect on the next
+/// message, and forgets the user's notification.
+///
+/// Groups at the active degradation level's `force_broadcast_group_size`
+/// go out via broadcast, subject to path overrides, unless shed exemptions
+/// cover the conversation.
+pub struct InvitationGate {
+    memberships: Arc<MembershipCache>,
+    switchboard: Arc<DegradationSwitchboard>,
+    overrides: Arc<PathOverrides>,
+    exemptions: Arc<ShedExemptions>,
+    /// (group, user) -> invitation expiry in milliseconds
+    notified: Mutex<LruCache<(String, String), i64>>,
+    config: InvitationConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl InvitationGate {
+    pub fn new(
+        memberships: Arc<MembershipCache>,
+        switchboard: Arc<DegradationSwitchboard>,
+        overrides: Arc<PathOverrides>,
+        exemptions: Arc<ShedExemptions>,
+        config: InvitationConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let tracked = NonZeroUsize::new(config.tracked_invitations.max(1)).unwrap();
+        Self {
+            memberships,
+            switchboard,
+            overrides,
+            exemptions,
+            notified: Mutex::new(LruCache::new(tracked)),
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Split a group message's recipients by member state
+    pub async fn fanout(
+        &self,
+        envelope: &MessageEnvelope,
+        group_id: &str,
+        decisions: &mut RecipientDecisions,
+    ) -> Result<GroupFanout, MembershipError> {
+        let view = self.memberships.view(group_id).await?;
+        Ok(self.plan(envelope, group_id, &view, decisions))
+    }
+
+    pub fn plan(
+        &self,
+        envelope: &MessageEnvelope,
+        group_id: &str,
+        view: &GroupView,
+        decisions: &mut RecipientDecisions,
+    ) -> GroupFanout {
+        let path = PathContext::of(envelope, group_id, None);
+        let broadcast = self.switchboard.force_broadcast(view.members.len(), &path, &self.overrides)
+            && !self.exemptions.exempts(envelope, "degraded_broadcast");
+        let mut fanout = GroupFanout {
+            members: view.members.as_ref().clone(),
+            invitations: Vec::new(),
+            broadcast,
+        };
+        let now = self.clock.now_millis();
+        let default_expiry = now + self.config.default_ttl.as_millis() as i64;
+
+        let mut notified = self.notified.lock();
+        for other in view.others.iter() {
+            match other.state {
+                MemberState::Member => {}
+                MemberState::Banned => {
+                    self.metrics.record_group_delivery_suppressed("banned");
+                    decisions.exclude(&other.user_id, ExclusionReason::BannedMember);
+                }
+                MemberState::Invited => {
+                    let expires_at = other.expires_at.unwrap_or(default_expiry);
+                    if expires_at <= now {
+                        self.metrics.record_group_delivery_suppressed("invitation_expired");
+                        decisions.exclude(&other.user_id, ExclusionReason::InvitationExpired);
+                        continue;
+                    }
+                    let key = (group_id.to_string(), other.user_id.clone());
+                    if notified.get(&key).is_some_and(|&until| until > now) {
+                        self.metrics.record_group_delivery_suppressed("invited");
+                        decisions.exclude(&other.user_id, ExclusionReason::InvitationPending);
+                        continue;
+                    }
+                    notified.put(key, expires_at);
+                    fanout
+                        .invitations
+                        .push((other.user_id.clone(), invitation_event(envelope, group_id, &other.user_id, expires_at)));
+                    self.metrics.record_group_invitation_sent();
+                    decisions.downgrade(&other.user_id, Downgrade::Invitation);
+                }
+            }
+        }
+        fanout
+    }
+
+    /// A user's state in a group changed, e.g. a join event
+    pub fn on_state_change(&self, group_id: &str, user_id: &str, state: MemberState) {
+        self.memberships.invalidate(group_id);
+        self.notified.lock().pop(&(group_id.to_string(), user_id.to_string()));
+        self.metrics.record_member_state_change(state.as_str());
+    }
+}
+
+fn invitation_event(message: &MessageEnvelope, group_id: &str, user_id: &str, expires_at: i64) -> MessageEnvelope {
+    let payload = EncryptedPayload {
+        ciphertext: String::new(),
+        iv: None,
+        tag: None,
+        key_id: None,
+        content_type: None,
+    };
+    let mut event = MessageEnvelope::new(
+        MessageType::GroupInvitation,
+        group_id.to_string(),
+        vec![user_id.to_string()],
+        payload,
+    );
+    event.tenant_id = message.tenant_id.clone();
+    event.priority = Priority::High;
+    event.metadata.insert("group_id".to_string(), group_id.to_string());
+    event
+        .metadata
+        .insert(INVITATION_EXPIRES_METADATA.to_string(), expires_at.to_string());
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::sync::{Notify, Semaphore};
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        degradation::DegradationLevel,
+        membership::{GroupMember, MembershipResolver, ResolverError},
+        path_override::{OverrideEffect, PathFeature, PathOverrideRule},
+        recipient_trace::{RecipientDecision, RecipientTracer, RoutingWatch},
+        shed_exemption::ExemptionSelector,
+    };
+
+    const GROUP: &str = "group-1";
+
+    /// Serves whatever states the test last set; while `hold` is set, each
+    /// resolve signals `entered` and waits for a `release` permit
+    struct ScriptedStates {
+        states: Mutex<Vec<GroupMember>>,
+        resolves: AtomicUsize,
+        hold: AtomicBool,
+        entered: Notify,
+        release: Semaphore,
+    }
+
+    impl ScriptedStates {
+        fn new() -> Self {
+            Self {
+                states: Mutex::new(Vec::new()),
+                resolves: AtomicUsize::new(0),
+                hold: AtomicBool::new(false),
+                entered: Notify::new(),
+                release: Semaphore::new(0),
+            }
+        }
+
+        fn set(&self, user_id: &str, state: MemberState, expires_at: Option<i64>) {
+            let mut states = self.states.lock();
+            states.retain(|member| member.user_id != user_id);
+            states.push(GroupMember {
+                user_id: user_id.into(),
+                state,
+                expires_at,
+            });
+        }
+    }
+
+    #[async_trait]
+    impl MembershipResolver for ScriptedStates {
+        async fn resolve(&self, _group_id: &str) -> Result<Vec<String>, ResolverError> {
+            unreachable!("the gate resolves states")
+        }
+
+        async fn resolve_states(&self, _group_id: &str) -> Result<Vec<GroupMember>, ResolverError> {
+            self.resolves.fetch_add(1, Ordering::Relaxed);
+            let states = self.states.lock().clone();
+            if self.hold.load(Ordering::Acquire) {
+                self.entered.notify_one();
+                self.release.acquire().await.unwrap().forget();
+            }
+            Ok(states)
+        }
+    }
+
+    fn gate(tracked_invitations: usize) -> (InvitationGate, Arc<ScriptedStates>, Arc<SimClock>) {
+        let broker = BrokerConfig::load().unwrap();
+        let mut routing = broker.routing;
+        routing.membership_ttl = Duration::from_secs(3600);
+        let mut config = broker.invitations;
+        config.tracked_invitations = tracked_invitations;
+
+        let resolver = Arc::new(ScriptedStates::new());
+        resolver.set("alice", MemberState::Member, None);
+        resolver.set("bob", MemberState::Member, None);
+        resolver.set("carol", MemberState::Invited, None);
+        resolver.set("mallory", MemberState::Banned, None);
+        let metrics = BrokerMetrics::new().unwrap();
+        let memberships = Arc::new(MembershipCache::new(resolver.clone(), &routing, &broker.limits, metrics.clone()));
+        let mut degradation = broker.degradation;
+        degradation.levels.emergency.force_broadcast_group_size = Some(2);
+        let switchboard = Arc::new(DegradationSwitchboard::new(&degradation, AuditLog::tracing_only(), metrics.clone()));
+        let overrides = Arc::new(PathOverrides::new(broker.path_override, AuditLog::tracing_only(), metrics.clone()));
+        let exemptions = Arc::new(ShedExemptions::new(&broker.shed_exemptions, AuditLog::tracing_only(), metrics.clone()));
+        let clock = Arc::new(SimClock::new());
+        let gate =
+            InvitationGate::new(memberships, switchboard, overrides, exemptions, config, metrics).with_clock(clock.clone());
+        (gate, resolver, clock)
+    }
+
+    fn message() -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        MessageEnvelope::new(MessageType::GroupMessage, "alice".into(), vec![GROUP.into()], payload)
+    }
+
+    async fn fanout(gate: &InvitationGate) -> GroupFanout {
+        gate.fanout(&message(), GROUP, &mut RecipientDecisions::untraced())
+            .await
+            .unwrap()
+    }
+
+    /// Decisions for `envelope` as a watched message records them
+    fn traced(envelope: &MessageEnvelope) -> RecipientDecisions {
+        let tracer = RecipientTracer::new(BrokerConfig::load().unwrap().recipient_trace, BrokerMetrics::new().unwrap());
+        tracer.add_watch(RoutingWatch::Message(envelope.message_id.clone()), None);
+        tracer.begin(envelope)
+    }
+
+    fn invited(fanout: &GroupFanout) -> Vec<&str> {
+        fanout.invitations.iter().map(|(user_id, _)| user_id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn invited_users_get_one_event_and_banned_users_nothing() {
+        let (gate, _, clock) = gate(100);
+
+        let first = fanout(&gate).await;
+        assert_eq!(first.members, ["alice", "bob"]);
+        assert_eq!(invited(&first), ["carol"]);
+        let (_, event) = &first.invitations[0];
+        assert_eq!(event.message_type, MessageType::GroupInvitation);
+        assert_eq!(event.to, ["carol"]);
+        assert!(event.payload.ciphertext.is_empty());
+        assert_eq!(event.metadata["group_id"], GROUP);
+        assert!(event.metadata[INVITATION_EXPIRES_METADATA].parse::<i64>().unwrap() > clock.now_millis());
+
+        for _ in 0..5 {
+            let repeat = fanout(&gate).await;
+            assert_eq!(repeat.members, ["alice", "bob"]);
+            assert!(repeat.invitations.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn lapsed_invitations_get_nothing() {
+        let (gate, resolver, clock) = gate(100);
+        resolver.set("carol", MemberState::Invited, Some(clock.now_millis() - 1));
+
+        let fanout = fanout(&gate).await;
+        assert!(fanout.invitations.is_empty());
+        assert_eq!(fanout.members, ["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn a_renewed_invitation_notifies_again_once_the_old_one_lapsed() {
+        let (gate, resolver, clock) = gate(100);
+        resolver.set("carol", MemberState::Invited, Some(clock.now_millis() + 100));
+        assert_eq!(invited(&fanout(&gate).await), ["carol"]);
+
+        // Still notified while the old invitation runs
+        resolver.set("carol", MemberState::Invited, Some(clock.now_millis() + 60_000));
+        gate.memberships.invalidate(GROUP);
+        assert!(fanout(&gate).await.invitations.is_empty());
+
+        clock.advance(Duration::from_millis(100));
+        gate.memberships.invalidate(GROUP);
+        assert_eq!(invited(&fanout(&gate).await), ["carol"]);
+        assert!(fanout(&gate).await.invitations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn notification_tracking_is_bounded() {
+        let (gate, resolver, _) = gate(2);
+        resolver.set("dave", MemberState::Invited, None);
+        resolver.set("erin", MemberState::Invited, None);
+
+        assert_eq!(invited(&fanout(&gate).await), ["carol", "dave", "erin"]);
+        assert_eq!(gate.notified.lock().len(), 2);
+
+        // Past the bound memory stays fixed at the cost of repeat events
+        assert_eq!(invited(&fanout(&gate).await), ["carol", "dave", "erin"]);
+        assert_eq!(gate.notified.lock().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_join_takes_effect_on_the_next_message() {
+        let (gate, resolver, _) = gate(100);
+        assert_eq!(invited(&fanout(&gate).await), ["carol"]);
+
+        resolver.set("carol", MemberState::Member, None);
+        gate.on_state_change(GROUP, "carol", MemberState::Member);
+
+        let after = fanout(&gate).await;
+        assert_eq!(after.members, ["alice", "bob", "carol"]);
+        assert!(after.invitations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_join_racing_an_in_flight_fanout_is_not_lost() {
+        let (gate, resolver, _) = gate(100);
+        let gate = Arc::new(gate);
+        resolver.hold.store(true, Ordering::Release);
+
+        // The fanout resolved carol as invited, then the join lands before it finishes
+        let in_flight = tokio::spawn({
+            let gate = gate.clone();
+            async move { fanout(&gate).await }
+        });
+        resolver.entered.notified().await;
+        resolver.set("carol", MemberState::Member, None);
+        gate.on_state_change(GROUP, "carol", MemberState::Member);
+        resolver.hold.store(false, Ordering::Release);
+        resolver.release.add_permits(1);
+
+        // The in-flight message still went out on the state it resolved
+        let raced = in_flight.await.unwrap();
+        assert_eq!(raced.members, ["alice", "bob"]);
+        assert_eq!(invited(&raced), ["carol"]);
+
+        // That stale view wasn't cached: the very next message sees the join
+        let next = fanout(&gate).await;
+        assert_eq!(next.members, ["alice", "bob", "carol"]);
+        assert!(next.invitations.is_empty());
+        assert_eq!(resolver.resolves.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn large_groups_broadcast_only_while_the_level_forces_it() {
+        let (gate, resolver, _) = gate(100);
+        assert!(!fanout(&gate).await.broadcast);
+
+        gate.switchboard.set_level(DegradationLevel::Emergency, "ops", "incident", None);
+        let forced = fanout(&gate).await;
+        assert!(forced.broadcast);
+        assert_eq!(forced.members, ["alice", "bob"]);
+
+        // Below the threshold each member still gets a copy
+        resolver.set("bob", MemberState::Banned, None);
+        gate.memberships.invalidate(GROUP);
+        assert!(!fanout(&gate).await.broadcast);
+    }
+
+    #[tokio::test]
+    async fn overrides_and_exemptions_keep_a_group_off_forced_broadcast() {
+        let (gate, _, _) = gate(100);
+        gate.switchboard.set_level(DegradationLevel::Emergency, "ops", "incident", None);
+
+        let rule = PathOverrideRule {
+            id: "no-broadcast".into(),
+            feature: PathFeature::Broadcast,
+            effect: OverrideEffect::Forbid,
+            tenants: Vec::new(),
+            conversations: vec![GROUP.into()],
+            gateways: Vec::new(),
+            kinds: Vec::new(),
+        };
+        gate.overrides.set(rule, None, "ops");
+        assert!(!fanout(&gate).await.broadcast);
+        assert!(gate.overrides.clear("no-broadcast", "ops"));
+        assert!(fanout(&gate).await.broadcast);
+
+        let selector = ExemptionSelector::Conversation {
+            conversation_id: GROUP.into(),
+        };
+        gate.exemptions.add(selector, "vip", "ops").unwrap();
+        assert!(!fanout(&gate).await.broadcast);
+    }
+
+    #[tokio::test]
+    async fn suppressions_are_counted_per_state() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let (gate, resolver, clock) = metrics::with_local_recorder(&recorder, || gate(100));
+        resolver.set("dave", MemberState::Invited, Some(clock.now_millis() - 1));
+
+        let view = gate.memberships.view(GROUP).await.unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            for _ in 0..3 {
+                gate.plan(&message(), GROUP, &view, &mut RecipientDecisions::untraced());
+            }
+            gate.on_state_change(GROUP, "carol", MemberState::Member);
+        });
+
+        let rendered = handle.render();
+        for line in [
+            r#"broker_group_delivery_suppressed_total{state="banned"} 3"#,
+            r#"broker_group_delivery_suppressed_total{state="invited"} 2"#,
+            r#"broker_group_delivery_suppressed_total{state="invitation_expired"} 3"#,
+            "broker_group_invitations_sent_total 1",
+            r#"broker_member_state_changes_total{state="member"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    async fn each_member_state_is_traced_with_its_reason() {
+        let (gate, resolver, clock) = gate(100);
+        resolver.set("dave", MemberState::Invited, Some(clock.now_millis() - 1));
+        let view = gate.memberships.view(GROUP).await.unwrap();
+
+        let first = message();
+        let mut decisions = traced(&first);
+        gate.plan(&first, GROUP, &view, &mut decisions);
+        let expected = [
+            ("mallory", RecipientDecision::Excluded { reason: ExclusionReason::BannedMember }),
+            ("carol", RecipientDecision::Downgraded { how: Downgrade::Invitation }),
+            ("dave", RecipientDecision::Excluded { reason: ExclusionReason::InvitationExpired }),
+        ];
+        for (user_id, decision) in expected {
+            assert_eq!(decisions.decision_for(user_id), Some(decision), "{}", user_id);
+        }
+
+        let second = message();
+        let mut decisions = traced(&second);
+        gate.plan(&second, GROUP, &view, &mut decisions);
+        assert_eq!(
+            decisions.decision_for("carol"),
+            Some(RecipientDecision::Excluded { reason: ExclusionReason::InvitationPending })
+        );
+    }
+}
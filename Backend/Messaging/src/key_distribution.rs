@@ -0,0 +1,487 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    membership::{MembershipCache, MembershipError},
+    message::types::{MessageEnvelope, MessageType, Priority},
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+};
+
+/// Envelope metadata telling the offline store how long to hold the entry
+pub const OFFLINE_RETENTION_METADATA: &str = "offline_retention_secs";
+
+/// CAS attempts per accounting update
+const MAX_CAS_ATTEMPTS: usize = 5;
+
+const RECORD_CODEC: VersionedCodec<KeyDistributionRecord> =
+    VersionedCodec::new(schema::KEY_DISTRIBUTION_RECORD, 1, &[]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyDeliveryState {
+    Pending,
+    /// Recipient offline; held in the offline store
+    Queued,
+    Delivered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRecipientStatus {
+    pub state: KeyDeliveryState,
+    /// Timestamp in milliseconds
+    pub updated_at: i64,
+}
+
+/// Accounting record stored under `keydist.{base64url(message_id)}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyDistributionRecord {
+    pub message_id: String,
+    pub sender: String,
+    pub conversation_id: String,
+    /// Every snapshotted member and where their copy is
+    pub recipients: BTreeMap<String, KeyRecipientStatus>,
+    /// Timestamp in milliseconds
+    pub created_at: i64,
+}
+
+impl KeyDistributionRecord {
+    pub fn delivered(&self) -> impl Iterator<Item = &str> {
+        self.recipients
+            .iter()
+            .filter(|(_, status)| status.state == KeyDeliveryState::Delivered)
+            .map(|(recipient, _)| recipient.as_str())
+    }
+}
+
+/// Distributes sender key rotations to the members present at ingress
+///
+/// Membership is snapshotted once, at ingress, into the envelope and into a
+/// KV accounting record, so fanout and any redelivery after a crash use the
+/// original member set no matter how the membership cache changes. Members
+/// missing from the snapshot can never be marked delivered, and members
+/// already delivered are skipped on redelivery.
+pub struct KeyDistributor {
+    kv: kv::Store,
+    membership: Arc<MembershipCache>,
+    offline_retention: Duration,
+    metrics: BrokerMetrics,
+}
+
+impl KeyDistributor {
+    pub fn new(
+        kv: kv::Store,
+        membership: Arc<MembershipCache>,
+        offline_retention: Duration,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            kv,
+            membership,
+            offline_retention,
+            metrics,
+        }
+    }
+
+    /// Capture the member set at ingress; redelivered envelopes keep their snapshot
+    pub async fn snapshot(&self, envelope: &mut MessageEnvelope) -> Result<(), KeyDistributionError> {
+        if envelope.message_type != MessageType::KeyDistribution {
+            return Ok(());
+        }
+
+        if envelope.member_snapshot.is_none() {
+            let mut members: Vec<String> = match envelope.group_id() {
+                Some(group_id) => self.membership.members(group_id).await?.as_ref().clone(),
+                None => envelope.to.clone(),
+            };
+            members.retain(|member| *member != envelope.from);
+            members.sort();
+            members.dedup();
+
+            envelope.member_snapshot = Some(members);
+            envelope.priority = Priority::High;
+            envelope.metadata.insert(
+                OFFLINE_RETENTION_METADATA.to_string(),
+                self.offline_retention.as_secs().to_string(),
+            );
+        }
+
+        self.persist(envelope).await
+    }
+
+    /// Snapshotted members that have not received the message yet
+    pub async fn pending_recipients(&self, envelope: &MessageEnvelope) -> Result<Vec<String>, KeyDistributionError> {
+        let snapshot = envelope
+            .member_snapshot
+            .as_ref()
+            .ok_or_else(|| KeyDistributionError::MissingSnapshot(envelope.message_id.clone()))?;
+        let record = self.load(&envelope.message_id).await?;
+
+        Ok(snapshot
+            .iter()
+            .filter(|member| {
+                record
+                    .as_ref()
+                    .and_then(|(record, _)| record.recipients.get(*member))
+                    .is_none_or(|status| status.state != KeyDeliveryState::Delivered)
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Note that a snapshotted member's copy went to the offline store
+    pub async fn mark_queued(&self, message_id: &str, recipient: &str) -> Result<bool, KeyDistributionError> {
+        self.transition(message_id, recipient, KeyDeliveryState::Queued).await
+    }
+
+    /// Note that a snapshotted member received the message; false if already recorded
+    pub async fn mark_delivered(&self, message_id: &str, recipient: &str) -> Result<bool, KeyDistributionError> {
+        self.transition(message_id, recipient, KeyDeliveryState::Delivered).await
+    }
+
+    /// Delivery accounting, visible only to the sender
+    pub async fn status(&self, message_id: &str, requester: &str) -> Result<KeyDistributionRecord, KeyDistributionError> {
+        let (record, _) = self
+            .load(message_id)
+            .await?
+            .ok_or_else(|| KeyDistributionError::NotFound(message_id.to_string()))?;
+        if record.sender != requester {
+            return Err(KeyDistributionError::Forbidden);
+        }
+        Ok(record)
+    }
+
+    async fn persist(&self, envelope: &MessageEnvelope) -> Result<(), KeyDistributionError> {
+        let now = Utc::now().timestamp_millis();
+        let record = KeyDistributionRecord {
+            message_id: envelope.message_id.clone(),
+            sender: envelope.from.clone(),
+            conversation_id: envelope.conversation_id(),
+            recipients: envelope
+                .member_snapshot
+                .iter()
+                .flatten()
+                .map(|member| {
+                    let status = KeyRecipientStatus {
+                        state: KeyDeliveryState::Pending,
+                        updated_at: now,
+                    };
+                    (member.clone(), status)
+                })
+                .collect(),
+            created_at: now,
+        };
+        let value = RECORD_CODEC
+            .encode(&record)
+            .map_err(|e| KeyDistributionError::Store(e.to_string()))?;
+
+        match self.kv.create(record_key(&envelope.message_id), value.into()).await {
+            Ok(_) => {
+                self.metrics.record_key_distribution("snapshotted");
+                Ok(())
+            }
+            // Redelivery: the accounting from the first attempt stands
+            Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => {
+                self.metrics.record_key_distribution("redelivered");
+                Ok(())
+            }
+            Err(e) => Err(KeyDistributionError::Store(e.to_string())),
+        }
+    }
+
+    async fn transition(
+        &self,
+        message_id: &str,
+        recipient: &str,
+        state: KeyDeliveryState,
+    ) -> Result<bool, KeyDistributionError> {
+        let key = record_key(message_id);
+
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let (mut record, revision) = self
+                .load(message_id)
+                .await?
+                .ok_or_else(|| KeyDistributionError::NotFound(message_id.to_string()))?;
+
+            let Some(status) = record.recipients.get_mut(recipient) else {
+                self.metrics.record_key_distribution("excluded");
+                return Err(KeyDistributionError::NotInSnapshot(recipient.to_string()));
+            };
+            if status.state == KeyDeliveryState::Delivered || status.state == state {
+                self.metrics.record_key_distribution("duplicate");
+                return Ok(false);
+            }
+            *status = KeyRecipientStatus {
+                state,
+                updated_at: Utc::now().timestamp_millis(),
+            };
+
+            let value = RECORD_CODEC
+                .encode(&record)
+                .map_err(|e| KeyDistributionError::Store(e.to_string()))?;
+            if self.kv.update(&key, value.into(), revision).await.is_ok() {
+                self.metrics.record_key_distribution(match state {
+                    KeyDeliveryState::Delivered => "delivered",
+                    KeyDeliveryState::Queued => "queued",
+                    KeyDeliveryState::Pending => "pending",
+                });
+                return Ok(true);
+            }
+            debug!("Key distribution CAS conflict on {}", key);
+        }
+
+        Err(KeyDistributionError::Store(format!(
+            "gave up on {} after {} CAS attempts",
+            key, MAX_CAS_ATTEMPTS
+        )))
+    }
+
+    async fn load(&self, message_id: &str) -> Result<Option<(KeyDistributionRecord, u64)>, KeyDistributionError> {
+        let entry = self
+            .kv
+            .entry(record_key(message_id))
+            .await
+            .map_err(|e| KeyDistributionError::Store(e.to_string()))?;
+
+        match entry {
+            Some(entry) if entry.operation == kv::Operation::Put => {
+                let record = RECORD_CODEC
+                    .decode(&entry.value)
+                    .map_err(|e| KeyDistributionError::Store(e.to_string()))?;
+                Ok(Some((record, entry.revision)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn record_key(message_id: &str) -> String {
+    format!("keydist.{}", URL_SAFE_NO_PAD.encode(message_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyDistributionError {
+    #[error("membership snapshot failed: {0}")]
+    Membership(#[from] MembershipError),
+    #[error("key distribution {0} has no member snapshot")]
+    MissingSnapshot(String),
+    #[error("key distribution {0} not found")]
+    NotFound(String),
+    #[error("{0} was not a member when the key was distributed")]
+    NotInSnapshot(String),
+    #[error("only the sender may query delivery status")]
+    Forbidden,
+    #[error("key distribution store error: {0}")]
+    Store(String),
+}
+
+/// The accounting tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored key_distribution`
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream;
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::BrokerConfig,
+        membership::{MembershipResolver, ResolverError},
+        message::types::EncryptedPayload,
+    };
+
+    const GROUP: &str = "group_team";
+
+    #[test]
+    fn frozen_v1_records_still_load() {
+        let fixture: &[u8] = include_bytes!("../fixtures/persist/key_distribution_record.v1.bin");
+        for bytes in [fixture, &fixture[5..]] {
+            let record = RECORD_CODEC.decode(bytes).unwrap();
+            assert_eq!(record.message_id, "m1");
+            assert_eq!(record.sender, "alice");
+            assert_eq!(record.conversation_id, GROUP);
+            assert_eq!(
+                record.recipients["bob"],
+                KeyRecipientStatus {
+                    state: KeyDeliveryState::Delivered,
+                    updated_at: 1_700_000_000_100
+                }
+            );
+            assert_eq!(record.recipients["carol"].state, KeyDeliveryState::Queued);
+            assert_eq!(record.created_at, 1_700_000_000_000);
+        }
+    }
+
+    /// Resolver returning whatever the test last set
+    struct ScriptedResolver {
+        members: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedResolver {
+        fn set(&self, members: &[&str]) {
+            *self.members.lock() = members.iter().map(|m| m.to_string()).collect();
+        }
+    }
+
+    #[async_trait]
+    impl MembershipResolver for ScriptedResolver {
+        async fn resolve(&self, _group_id: &str) -> Result<Vec<String>, ResolverError> {
+            Ok(self.members.lock().clone())
+        }
+    }
+
+    fn rotation(to: &[&str]) -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::KeyDistribution,
+            "alice".into(),
+            to.iter().map(|to| to.to_string()).collect(),
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    #[test]
+    fn group_rotations_resolve_to_their_group() {
+        assert_eq!(rotation(&[GROUP]).group_id(), Some(GROUP));
+        assert_eq!(rotation(&["bob"]).group_id(), None);
+    }
+
+    #[test]
+    fn the_snapshot_travels_with_the_message() {
+        let mut envelope = rotation(&[GROUP]);
+        envelope.member_snapshot = Some(vec!["bob".into(), "carol".into()]);
+
+        let stored: MessageEnvelope = serde_json::from_slice(&serde_json::to_vec(&envelope).unwrap()).unwrap();
+        assert_eq!(stored.member_snapshot, envelope.member_snapshot);
+    }
+
+    async fn distributor() -> (KeyDistributor, Arc<MembershipCache>, Arc<ScriptedResolver>) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let kv = jetstream::new(async_nats::connect(url).await.unwrap())
+            .create_key_value(kv::Config {
+                bucket: format!("keydist-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let config = BrokerConfig::load().unwrap();
+        let resolver = Arc::new(ScriptedResolver {
+            members: Mutex::new(Vec::new()),
+        });
+        resolver.set(&["alice", "bob", "carol"]);
+        let metrics = BrokerMetrics::new().unwrap();
+        let membership = Arc::new(MembershipCache::new(resolver.clone(), &config.routing, &config.limits, metrics.clone()));
+        let distributor = KeyDistributor::new(kv, membership.clone(), Duration::from_secs(30 * 86_400), metrics);
+        (distributor, membership, resolver)
+    }
+
+    /// A member joins after ingress and the membership cache refreshes mid-fanout
+    async fn join_mid_fanout(membership: &MembershipCache, resolver: &ScriptedResolver, members: &[&str]) {
+        resolver.set(members);
+        membership.invalidate(GROUP);
+        assert_eq!(*membership.members(GROUP).await.unwrap(), members);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn members_joining_after_ingress_are_excluded() {
+        let (distributor, membership, resolver) = distributor().await;
+        let mut envelope = rotation(&[GROUP]);
+        distributor.snapshot(&mut envelope).await.unwrap();
+
+        assert_eq!(envelope.member_snapshot, Some(vec!["bob".into(), "carol".into()]));
+        assert_eq!(envelope.priority, Priority::High);
+        assert_eq!(envelope.metadata[OFFLINE_RETENTION_METADATA], "2592000");
+
+        join_mid_fanout(&membership, &resolver, &["alice", "bob", "carol", "dave"]).await;
+        assert_eq!(distributor.pending_recipients(&envelope).await.unwrap(), vec!["bob", "carol"]);
+        assert!(matches!(
+            distributor.mark_delivered(&envelope.message_id, "dave").await,
+            Err(KeyDistributionError::NotInSnapshot(_))
+        ));
+
+        assert!(distributor.mark_delivered(&envelope.message_id, "bob").await.unwrap());
+        assert!(distributor.mark_queued(&envelope.message_id, "carol").await.unwrap());
+        assert_eq!(distributor.pending_recipients(&envelope).await.unwrap(), vec!["carol"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn only_the_sender_sees_exactly_who_received_it() {
+        let (distributor, _, _) = distributor().await;
+        let mut envelope = rotation(&[GROUP]);
+        distributor.snapshot(&mut envelope).await.unwrap();
+        distributor.mark_queued(&envelope.message_id, "carol").await.unwrap();
+        distributor.mark_delivered(&envelope.message_id, "bob").await.unwrap();
+
+        let record = distributor.status(&envelope.message_id, "alice").await.unwrap();
+        assert_eq!(record.delivered().collect::<Vec<_>>(), vec!["bob"]);
+        assert_eq!(record.recipients["carol"].state, KeyDeliveryState::Queued);
+        assert_eq!(record.recipients.len(), 2);
+
+        assert!(matches!(
+            distributor.status(&envelope.message_id, "bob").await,
+            Err(KeyDistributionError::Forbidden)
+        ));
+        assert!(matches!(
+            distributor.status("missing", "alice").await,
+            Err(KeyDistributionError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn each_member_is_delivered_exactly_once() {
+        let (distributor, _, _) = distributor().await;
+        let mut envelope = rotation(&[GROUP]);
+        distributor.snapshot(&mut envelope).await.unwrap();
+
+        assert!(distributor.mark_delivered(&envelope.message_id, "bob").await.unwrap());
+        assert!(!distributor.mark_delivered(&envelope.message_id, "bob").await.unwrap());
+        // A late offline hand-off doesn't undo the delivery
+        assert!(!distributor.mark_queued(&envelope.message_id, "bob").await.unwrap());
+
+        let record = distributor.status(&envelope.message_id, "alice").await.unwrap();
+        assert_eq!(record.recipients["bob"].state, KeyDeliveryState::Delivered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn redelivery_after_a_crash_uses_the_original_snapshot() {
+        let (distributor, membership, resolver) = distributor().await;
+        let mut envelope = rotation(&[GROUP]);
+        distributor.snapshot(&mut envelope).await.unwrap();
+        distributor.mark_delivered(&envelope.message_id, "bob").await.unwrap();
+
+        // Crash mid-fanout; membership moves on before the stream redelivers
+        let mut redelivered: MessageEnvelope = serde_json::from_slice(&serde_json::to_vec(&envelope).unwrap()).unwrap();
+        join_mid_fanout(&membership, &resolver, &["alice", "bob", "dave"]).await;
+
+        distributor.snapshot(&mut redelivered).await.unwrap();
+        assert_eq!(redelivered.member_snapshot, envelope.member_snapshot);
+        assert_eq!(distributor.pending_recipients(&redelivered).await.unwrap(), vec!["carol"]);
+        let record = distributor.status(&envelope.message_id, "alice").await.unwrap();
+        assert_eq!(record.delivered().collect::<Vec<_>>(), vec!["bob"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn direct_rotations_snapshot_their_recipients() {
+        let (distributor, _, _) = distributor().await;
+        let mut envelope = rotation(&["carol", "bob", "alice", "bob"]);
+        distributor.snapshot(&mut envelope).await.unwrap();
+
+        assert_eq!(envelope.member_snapshot, Some(vec!["bob".into(), "carol".into()]));
+    }
+}
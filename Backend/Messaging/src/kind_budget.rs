@@ -0,0 +1,534 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::KindBudgetConfig,
+    config_watch::ConfigWatcher,
+    degradation::{DegradationLevel, DegradationSwitchboard},
+    message::types::{MessageEnvelope, MessageType},
+    metrics::BrokerMetrics,
+};
+
+/// Coarse traffic classes for accounting and budgets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficKind {
+    /// Text, group, media and system messages, thread activity, invitations
+    Message,
+    Typing,
+    /// Delivered and read receipts
+    Receipt,
+    Presence,
+    KeyDistribution,
+    /// Acks and errors
+    Control,
+}
+
+impl TrafficKind {
+    pub const ALL: [TrafficKind; 6] = [
+        TrafficKind::Message,
+        TrafficKind::Typing,
+        TrafficKind::Receipt,
+        TrafficKind::Presence,
+        TrafficKind::KeyDistribution,
+        TrafficKind::Control,
+    ];
+
+    pub fn of(message_type: MessageType) -> Self {
+        match message_type {
+            MessageType::TextMessage
+            | MessageType::GroupMessage
+            | MessageType::MediaMessage
+            | MessageType::SystemMessage
+            | MessageType::ThreadActivity
+            | MessageType::GroupInvitation => TrafficKind::Message,
+            MessageType::Typing => TrafficKind::Typing,
+            MessageType::Delivered | MessageType::Read => TrafficKind::Receipt,
+            MessageType::Presence => TrafficKind::Presence,
+            MessageType::KeyDistribution => TrafficKind::KeyDistribution,
+            MessageType::Ack | MessageType::Error => TrafficKind::Control,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficKind::Message => "message",
+            TrafficKind::Typing => "typing",
+            TrafficKind::Receipt => "receipt",
+            TrafficKind::Presence => "presence",
+            TrafficKind::KeyDistribution => "key_distribution",
+            TrafficKind::Control => "control",
+        }
+    }
+
+    /// What happens to this kind over budget; `None` for kinds that are never budgeted
+    pub fn over_budget_action(&self) -> Option<BudgetAction> {
+        match self {
+            TrafficKind::Typing | TrafficKind::Presence => Some(BudgetAction::Drop),
+            TrafficKind::Receipt => Some(BudgetAction::Defer),
+            TrafficKind::Message | TrafficKind::KeyDistribution | TrafficKind::Control => None,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetAction {
+    /// NAK for redelivery; the kind still matters later
+    Defer,
+    /// Acknowledge and discard; the kind is stale by the time there's room
+    Drop,
+}
+
+impl BudgetAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetAction::Defer => "defer",
+            BudgetAction::Drop => "drop",
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{} traffic over its {share}% budget ({})", .kind.as_str(), .action.as_str())]
+pub struct OverBudget {
+    pub kind: TrafficKind,
+    pub action: BudgetAction,
+    /// Percent of admitted traffic the kind is allowed
+    pub share: f64,
+}
+
+struct Window {
+    started: Instant,
+    /// Admitted per kind, indexed by `TrafficKind::index`
+    admitted: [u64; TrafficKind::ALL.len()],
+    admitted_total: u64,
+    /// Everything that reached the check, admitted or not
+    arrived: u64,
+    /// Arrivals per second over the last complete window
+    last_rate: f64,
+}
+
+/// Per-kind accounting and share-of-throughput budgets at ingress
+///
+/// Every envelope is counted by kind in `broker_messages_by_kind_total` and
+/// `broker_message_bytes_by_kind_total`. Budgets apply while the arrival
+/// rate of the last `kind_budgets.window` is at least
+/// `utilization_threshold` of `limits.messages_per_second`, and at every
+/// degraded level regardless of load. Then a budgeted kind that already
+/// makes up its `share_percent` of what the current window admitted is
+/// turned away: receipts are deferred for redelivery, typing and presence
+/// dropped. Messages, key distribution and control traffic are never
+/// budgeted, so low-value kinds give way to them rather than the reverse.
+/// The active degradation level's `kind_shares` override the configured
+/// shares kind by kind.
+///
+/// A window that closes with arrivals at or over full capacity means the
+/// budgets alone aren't holding; that escalates the switchboard to
+/// `conserve` when `degradation.auto_trigger_on_shed` allows it.
+pub struct KindBudgets {
+    config: ArcSwap<KindBudgetConfig>,
+    /// Ingress messages per second the broker is sized for
+    capacity: f64,
+    switchboard: Arc<DegradationSwitchboard>,
+    window: Mutex<Window>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl KindBudgets {
+    pub fn new(
+        config: KindBudgetConfig,
+        capacity: u32,
+        switchboard: Arc<DegradationSwitchboard>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let clock = SystemClock::shared();
+        Self {
+            config: ArcSwap::from_pointee(config),
+            capacity: capacity.max(1) as f64,
+            switchboard,
+            window: Mutex::new(Window {
+                started: clock.now_instant(),
+                admitted: [0; TrafficKind::ALL.len()],
+                admitted_total: 0,
+                arrived: 0,
+                last_rate: 0.0,
+            }),
+            clock,
+            metrics,
+        }
+    }
+
+    /// Also restarts the current window on the new clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.window.get_mut().started = clock.now_instant();
+        self.clock = clock;
+        self
+    }
+
+    /// Count an ingress envelope by kind
+    pub fn observe(&self, envelope: &MessageEnvelope) -> TrafficKind {
+        let kind = TrafficKind::of(envelope.message_type);
+        self.metrics.record_message_kind(kind.as_str(), envelope.payload.ciphertext.len());
+        kind
+    }
+
+    /// Admit `kind` or report it over budget
+    pub fn admit(&self, kind: TrafficKind) -> Result<(), OverBudget> {
+        let config = self.config.load();
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let mut window = self.window.lock();
+        let now = self.clock.now_instant();
+        let elapsed = now.saturating_duration_since(window.started);
+        if elapsed >= config.window {
+            window.last_rate = window.arrived as f64 / elapsed.as_secs_f64();
+            window.started = now;
+            window.admitted = [0; TrafficKind::ALL.len()];
+            window.admitted_total = 0;
+            window.arrived = 0;
+            let utilization = window.last_rate / self.capacity;
+            self.metrics.update_ingress_utilization(utilization);
+            if utilization >= 1.0 {
+                self.switchboard.escalate_from_shedder(
+                    DegradationLevel::Conserve,
+                    &format!("ingress at {:.0}% of capacity", utilization * 100.0),
+                );
+            }
+        }
+        window.arrived += 1;
+
+        if let Some(over) = self.over_budget(&config, &window, kind) {
+            drop(window);
+            self.metrics.record_kind_budget_rejection(kind.as_str(), over.action.as_str());
+            return Err(over);
+        }
+        window.admitted[kind.index()] += 1;
+        window.admitted_total += 1;
+        Ok(())
+    }
+
+    fn over_budget(&self, config: &KindBudgetConfig, window: &Window, kind: TrafficKind) -> Option<OverBudget> {
+        let action = kind.over_budget_action()?;
+        let active = self.switchboard.current();
+        let share = active
+            .toggles
+            .kind_shares
+            .get(&kind)
+            .or_else(|| config.share_percent.get(&kind))
+            .copied()?;
+
+        let loaded = active.level != DegradationLevel::Normal
+            || window.last_rate >= config.utilization_threshold * self.capacity;
+        // Too few samples early in a window to judge a share
+        if !loaded || window.admitted_total < config.min_window_messages {
+            return None;
+        }
+
+        let admitted = window.admitted[kind.index()] as f64;
+        if admitted < share / 100.0 * window.admitted_total as f64 {
+            return None;
+        }
+        Some(OverBudget { kind, action, share })
+    }
+
+    /// Window length, for callers that defer and want to retry after it
+    pub fn window(&self) -> Duration {
+        self.config.load().window
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let budgets = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            budgets.config.store(Arc::new(config.kind_budgets.clone()));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::SimClock,
+        config::BrokerConfig,
+        message::types::EncryptedPayload,
+    };
+
+    const CAPACITY: u32 = 1_000;
+    const WINDOW: Duration = Duration::from_millis(300);
+
+    fn config() -> KindBudgetConfig {
+        let mut config = BrokerConfig::load().unwrap().kind_budgets;
+        config.enabled = true;
+        config.utilization_threshold = 0.8;
+        config.window = WINDOW;
+        config.min_window_messages = 10;
+        config.share_percent = HashMap::from([
+            (TrafficKind::Typing, 10.0),
+            (TrafficKind::Receipt, 25.0),
+            (TrafficKind::Presence, 10.0),
+        ]);
+        config
+    }
+
+    fn budgets_with(config: KindBudgetConfig, conserve_shares: &[(TrafficKind, f64)]) -> (KindBudgets, Arc<SimClock>) {
+        let mut degradation = BrokerConfig::load().unwrap().degradation;
+        // Saturating windows would otherwise escalate every test to conserve
+        degradation.auto_trigger_on_shed = false;
+        degradation.levels.conserve.kind_shares = conserve_shares.iter().copied().collect();
+        let switchboard = Arc::new(DegradationSwitchboard::new(
+            &degradation,
+            AuditLog::tracing_only(),
+            BrokerMetrics::new().unwrap(),
+        ));
+        let clock = Arc::new(SimClock::new());
+        let budgets = KindBudgets::new(config, CAPACITY, switchboard, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (budgets, clock)
+    }
+
+    fn budgets() -> (KindBudgets, Arc<SimClock>) {
+        budgets_with(config(), &[])
+    }
+
+    /// Close a window whose arrival rate is well over the threshold
+    fn saturate(budgets: &KindBudgets, clock: &SimClock) {
+        for _ in 0..CAPACITY {
+            budgets.admit(TrafficKind::Message).unwrap();
+        }
+        clock.advance(WINDOW);
+    }
+
+    #[derive(Default, Debug)]
+    struct Outcome {
+        typing_admitted: u64,
+        typing_dropped: u64,
+        messages_admitted: u64,
+        messages_rejected: u64,
+    }
+
+    impl Outcome {
+        fn typing_share(&self) -> f64 {
+            self.typing_admitted as f64 / (self.typing_admitted + self.messages_admitted) as f64
+        }
+    }
+
+    /// Four typing indicators for every message; `share` is the typing
+    /// share in force
+    fn typing_flood(budgets: &KindBudgets, arrivals: usize, share: f64) -> Outcome {
+        let mut outcome = Outcome::default();
+        for i in 0..arrivals {
+            if i % 5 == 4 {
+                match budgets.admit(TrafficKind::Message) {
+                    Ok(()) => outcome.messages_admitted += 1,
+                    Err(_) => outcome.messages_rejected += 1,
+                }
+                continue;
+            }
+            match budgets.admit(TrafficKind::Typing) {
+                Ok(()) => outcome.typing_admitted += 1,
+                Err(over) => {
+                    assert_eq!(over.action, BudgetAction::Drop);
+                    assert_eq!(over.share, share);
+                    outcome.typing_dropped += 1;
+                }
+            }
+        }
+        outcome
+    }
+
+    #[test]
+    fn typing_flood_is_capped_at_its_share_under_load() {
+        let (budgets, clock) = budgets();
+        saturate(&budgets, &clock);
+
+        let outcome = typing_flood(&budgets, 10_000, 10.0);
+        // Messages are never budgeted, so none wait behind the flood
+        assert_eq!(outcome.messages_admitted, 2_000, "{:?}", outcome);
+        assert_eq!(outcome.messages_rejected, 0);
+        assert!(outcome.typing_share() <= 0.101, "{:?}", outcome);
+        assert!(outcome.typing_share() >= 0.09, "{:?}", outcome);
+        assert_eq!(outcome.typing_admitted + outcome.typing_dropped, 8_000);
+    }
+
+    #[test]
+    fn budgets_wait_for_the_utilization_threshold() {
+        let (budgets, clock) = budgets();
+        // No history yet: the first window is never loaded
+        assert_eq!(typing_flood(&budgets, 1_000, 10.0).typing_dropped, 0);
+
+        // A quiet window: well under 80% of capacity
+        clock.advance(WINDOW);
+        for _ in 0..10 {
+            budgets.admit(TrafficKind::Message).unwrap();
+        }
+        clock.advance(WINDOW);
+        assert_eq!(typing_flood(&budgets, 1_000, 10.0).typing_dropped, 0);
+
+        // A window just under the threshold, then one at it
+        let threshold = (0.8 * CAPACITY as f64 * WINDOW.as_secs_f64()) as u32;
+        for (arrivals, budgeted) in [(threshold - 1, false), (threshold, true)] {
+            clock.advance(WINDOW);
+            for _ in 0..arrivals {
+                budgets.admit(TrafficKind::Message).unwrap();
+            }
+            clock.advance(WINDOW);
+            let outcome = typing_flood(&budgets, 1_000, 10.0);
+            assert_eq!(outcome.typing_dropped > 0, budgeted, "{} arrivals: {:?}", arrivals, outcome);
+        }
+    }
+
+    #[test]
+    fn receipts_defer_and_essential_kinds_are_never_budgeted() {
+        let (budgets, clock) = budgets();
+        saturate(&budgets, &clock);
+        for _ in 0..10 {
+            budgets.admit(TrafficKind::Message).unwrap();
+        }
+
+        // With 10 admitted, the first 4 receipts fit under 25%
+        for _ in 0..4 {
+            budgets.admit(TrafficKind::Receipt).unwrap();
+        }
+        let over = budgets.admit(TrafficKind::Receipt).unwrap_err();
+        assert_eq!((over.kind, over.action, over.share), (TrafficKind::Receipt, BudgetAction::Defer, 25.0));
+        budgets.admit(TrafficKind::Presence).unwrap();
+        let over = (0..10).find_map(|_| budgets.admit(TrafficKind::Presence).err()).unwrap();
+        assert_eq!(over.action, BudgetAction::Drop);
+
+        for kind in [TrafficKind::Message, TrafficKind::KeyDistribution, TrafficKind::Control] {
+            assert_eq!(kind.over_budget_action(), None);
+            for _ in 0..1_000 {
+                budgets.admit(kind).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn degraded_levels_budget_without_load_and_override_shares() {
+        let (budgets, _) = budgets_with(config(), &[(TrafficKind::Typing, 50.0)]);
+        budgets
+            .switchboard
+            .set_level(DegradationLevel::Conserve, "ops", "incident", None);
+
+        let outcome = typing_flood(&budgets, 10_000, 50.0);
+        assert!(outcome.typing_dropped > 0);
+        assert!((outcome.typing_share() - 0.5).abs() < 0.01, "{:?}", outcome);
+        // Receipts keep the configured share at this level
+        let over = (0..10_000).find_map(|_| budgets.admit(TrafficKind::Receipt).err()).unwrap();
+        assert_eq!(over.share, 25.0);
+    }
+
+    #[test]
+    fn a_window_over_capacity_escalates_to_conserve() {
+        let mut degradation = BrokerConfig::load().unwrap().degradation;
+        degradation.auto_trigger_on_shed = true;
+        let switchboard = Arc::new(DegradationSwitchboard::new(
+            &degradation,
+            AuditLog::tracing_only(),
+            BrokerMetrics::new().unwrap(),
+        ));
+        let clock = Arc::new(SimClock::new());
+        let budgets = KindBudgets::new(config(), CAPACITY, switchboard.clone(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+
+        // Just under capacity: budgets apply but nothing escalates
+        let under = (CAPACITY as f64 * WINDOW.as_secs_f64()) as u32 - 1;
+        for _ in 0..under {
+            budgets.admit(TrafficKind::Message).unwrap();
+        }
+        clock.advance(WINDOW);
+        budgets.admit(TrafficKind::Message).unwrap();
+        assert_eq!(switchboard.level(), DegradationLevel::Normal);
+
+        // Over capacity by count, but spread over a long window
+        for _ in 0..CAPACITY {
+            budgets.admit(TrafficKind::Message).unwrap();
+        }
+        clock.advance(WINDOW * 4);
+        budgets.admit(TrafficKind::Message).unwrap();
+        assert_eq!(switchboard.level(), DegradationLevel::Normal);
+
+        saturate(&budgets, &clock);
+        budgets.admit(TrafficKind::Message).unwrap();
+        assert_eq!(switchboard.level(), DegradationLevel::Conserve);
+        assert_eq!(switchboard.current().actor, "load_shedder");
+
+        // Never lowers a level an operator raised further
+        switchboard.set_level(DegradationLevel::Emergency, "ops", "incident", None);
+        saturate(&budgets, &clock);
+        budgets.admit(TrafficKind::Message).unwrap();
+        assert_eq!(switchboard.current().actor, "ops");
+    }
+
+    #[test]
+    fn disabled_budgets_admit_everything() {
+        let mut config = config();
+        config.enabled = false;
+        let (budgets, clock) = budgets_with(config, &[]);
+        saturate(&budgets, &clock);
+        assert_eq!(typing_flood(&budgets, 1_000, 10.0).typing_dropped, 0);
+    }
+
+    fn envelope(message_type: MessageType, bytes: usize) -> MessageEnvelope {
+        MessageEnvelope::new(
+            message_type,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: "A".repeat(bytes),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    #[test]
+    fn traffic_is_counted_by_kind_and_bytes() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let (budgets, _) = budgets();
+            for (message_type, bytes) in [
+                (MessageType::TextMessage, 100),
+                (MessageType::GroupMessage, 50),
+                (MessageType::Typing, 4),
+                (MessageType::Delivered, 8),
+                (MessageType::Read, 8),
+                (MessageType::KeyDistribution, 300),
+            ] {
+                budgets.observe(&envelope(message_type, bytes));
+            }
+        });
+
+        let rendered = handle.render();
+        for line in [
+            r#"broker_messages_by_kind_total{kind="message"} 2"#,
+            r#"broker_message_bytes_by_kind_total{kind="message"} 150"#,
+            r#"broker_messages_by_kind_total{kind="typing"} 1"#,
+            r#"broker_messages_by_kind_total{kind="receipt"} 2"#,
+            r#"broker_message_bytes_by_kind_total{kind="receipt"} 16"#,
+            r#"broker_message_bytes_by_kind_total{kind="key_distribution"} 300"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+}
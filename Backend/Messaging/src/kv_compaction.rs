@@ -0,0 +1,459 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use async_nats::jetstream::{self, kv};
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::json;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::{KvCompactionConfig, KvRetentionConfig},
+    control_lease::{CommandLeases, LeaseOutcome},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Deleted users remembered until every bucket has had a cycle to purge them
+const DELETED_USER_MEMORY: usize = 100_000;
+
+/// What one compaction of one bucket found, and purged unless `dry_run`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompactionReport {
+    pub bucket: String,
+    pub dry_run: bool,
+    pub scanned: u64,
+    /// Past the bucket's `max_age`
+    pub expired: u64,
+    /// Belonging to a deleted user
+    pub tombstoned: u64,
+    /// Would have qualified but were touched within `kv_compaction.min_age`
+    pub kept_recent: u64,
+    pub purged: u64,
+}
+
+/// Scan-and-purge of the KV buckets the broker owns
+///
+/// Every `kv_compaction.interval`, each configured bucket is compacted by
+/// exactly one broker: the one that wins that cycle's single-executor
+/// lease. A key is purged when it hasn't been updated for the bucket's
+/// `max_age`, or when it sits under one of the bucket's user key prefixes
+/// (`presence.{user_id}`, `presence.{user_id}.…`) for a user deleted since
+/// the last cycles. Keys updated within `kv_compaction.min_age` are never
+/// purged, whatever the policy says. Purges are paced at
+/// `kv_compaction.purges_per_second`. In dry-run mode the cycle only
+/// counts what it would purge; the report is logged and audited either
+/// way.
+pub struct KvCompactor {
+    jetstream: jetstream::Context,
+    config: KvCompactionConfig,
+    leases: Arc<CommandLeases>,
+    deleted_users: Mutex<LruCache<String, Instant>>,
+    audit: AuditLog,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl KvCompactor {
+    pub fn new(
+        jetstream: jetstream::Context,
+        config: KvCompactionConfig,
+        leases: Arc<CommandLeases>,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            jetstream,
+            config,
+            leases,
+            deleted_users: Mutex::new(LruCache::new(NonZeroUsize::new(DELETED_USER_MEMORY).unwrap())),
+            audit,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A `UserDeleted` lifecycle event; the user's keys go in the next cycles
+    pub fn user_deleted(&self, user_id: &str) {
+        self.deleted_users.lock().put(user_id.to_string(), self.clock.now_instant());
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled || self.config.buckets.is_empty() {
+            return None;
+        }
+        let compactor = Arc::clone(self);
+        Some(spawn_traced("kv_compaction", TaskContext::new("kv_compaction"), async move {
+            loop {
+                compactor.run_cycle().await;
+                compactor.clock.sleep(compactor.config.interval).await;
+            }
+        }))
+    }
+
+    async fn run_cycle(&self) {
+        self.forget_old_deletions();
+        let interval_ms = self.config.interval.as_millis().max(1) as i64;
+        let cycle = self.clock.now_millis() / interval_ms;
+
+        for retention in &self.config.buckets {
+            let command_id = format!("kv_compaction.{}.{}", retention.bucket, cycle);
+            let lease = match self.leases.acquire(&command_id).await {
+                Ok(LeaseOutcome::Acquired(lease)) => lease,
+                Ok(LeaseOutcome::HeldBy(executor) | LeaseOutcome::Completed(executor)) => {
+                    debug!("Compaction of {} this cycle is {}'s", retention.bucket, executor);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Couldn't take the compaction lease for {}: {}", retention.bucket, e);
+                    continue;
+                }
+            };
+
+            match self.compact(retention).await {
+                Ok(report) => {
+                    self.report(&report);
+                    if let Err(e) = self.leases.complete(lease).await {
+                        warn!("Failed to complete the compaction lease for {}: {}", retention.bucket, e);
+                    }
+                }
+                Err(e) => {
+                    warn!("Compaction of {} failed: {}", retention.bucket, e);
+                    self.metrics.record_kv_compaction_cycle(&retention.bucket, "failed");
+                    if let Err(e) = self.leases.release(lease).await {
+                        warn!("Failed to release the compaction lease for {}: {}", retention.bucket, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Scan one bucket and purge what its policy and the safety rails allow
+    pub async fn compact(&self, retention: &KvRetentionConfig) -> Result<CompactionReport, CompactionError> {
+        let store = self
+            .jetstream
+            .get_key_value(&retention.bucket)
+            .await
+            .map_err(|e| CompactionError(e.to_string()))?;
+        let mut report = CompactionReport {
+            bucket: retention.bucket.clone(),
+            dry_run: self.config.dry_run,
+            ..Default::default()
+        };
+
+        let now = self.clock.now_utc().timestamp();
+        let min_age = self.config.min_age.as_secs() as i64;
+        let max_age = retention.max_age.map(|age| age.as_secs() as i64);
+        let pace = Duration::from_secs_f64(1.0 / self.config.purges_per_second.max(0.1));
+
+        let mut keys = store.keys().await.map_err(|e| CompactionError(e.to_string()))?;
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(|e| CompactionError(e.to_string()))?;
+            report.scanned += 1;
+            let Some(entry) = store
+                .entry(&key)
+                .await
+                .map_err(|e| CompactionError(e.to_string()))?
+                .filter(|entry| entry.operation == kv::Operation::Put)
+            else {
+                continue;
+            };
+
+            let age = now - entry.created.unix_timestamp();
+            let tombstoned = self.belongs_to_deleted_user(&key, &retention.user_key_prefixes);
+            let expired = max_age.is_some_and(|max_age| age >= max_age);
+            if !tombstoned && !expired {
+                continue;
+            }
+            if age < min_age {
+                report.kept_recent += 1;
+                continue;
+            }
+            if tombstoned {
+                report.tombstoned += 1;
+            } else {
+                report.expired += 1;
+            }
+            if self.config.dry_run {
+                continue;
+            }
+
+            // Only purge the revision we judged; a write since then keeps the key
+            match store.purge_expect_revision(&key, Some(entry.revision)).await {
+                Ok(()) => report.purged += 1,
+                Err(e) => debug!("Skipped purging {} in {}: {}", key, retention.bucket, e),
+            }
+            self.clock.sleep(pace).await;
+        }
+        Ok(report)
+    }
+
+    fn belongs_to_deleted_user(&self, key: &str, prefixes: &[String]) -> bool {
+        let deleted = self.deleted_users.lock();
+        prefixes.iter().any(|prefix| {
+            key.strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.split('.').next())
+                .is_some_and(|user_id| deleted.contains(user_id))
+        })
+    }
+
+    /// Deletions older than two cycles have been seen by every bucket's compaction
+    fn forget_old_deletions(&self) {
+        let horizon = self.config.interval * 2;
+        let now = self.clock.now_instant();
+        let mut deleted = self.deleted_users.lock();
+        while deleted.peek_lru().is_some_and(|(_, &at)| now.saturating_duration_since(at) > horizon) {
+            deleted.pop_lru();
+        }
+    }
+
+    fn report(&self, report: &CompactionReport) {
+        info!(
+            "Compacted {}{}: scanned {}, expired {}, tombstoned {}, kept recent {}, purged {}",
+            report.bucket,
+            if report.dry_run { " (dry run)" } else { "" },
+            report.scanned,
+            report.expired,
+            report.tombstoned,
+            report.kept_recent,
+            report.purged
+        );
+        self.metrics.record_kv_compaction(report);
+        self.metrics
+            .record_kv_compaction_cycle(&report.bucket, if report.dry_run { "dry_run" } else { "completed" });
+        self.audit.record(AuditEntry::new(
+            "kv_compaction",
+            "kv.compacted",
+            json!(report),
+        ));
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("kv compaction error: {0}")]
+pub struct CompactionError(pub String);
+
+/// Against a JetStream server at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored kv_compaction`
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{clock::SimClock, cluster::ClusterView, config::BrokerConfig};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    struct World {
+        jetstream: jetstream::Context,
+        config: KvCompactionConfig,
+        leases: kv::Store,
+        clock: Arc<SimClock>,
+        audit_path: std::path::PathBuf,
+    }
+
+    impl World {
+        /// `sessions` keeps keys for an hour and `pins` for a day; both
+        /// tombstone deleted users' keys
+        async fn new(dry_run: bool) -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+            let id = Uuid::new_v4().simple();
+            let bucket = |name: &str| format!("compaction-test-{}-{}", name, id);
+
+            let mut config = BrokerConfig::load().unwrap().kv_compaction;
+            config.enabled = true;
+            config.dry_run = dry_run;
+            config.min_age = Duration::from_secs(300);
+            config.purges_per_second = 1000.0;
+            config.buckets = vec![
+                KvRetentionConfig {
+                    bucket: bucket("sessions"),
+                    max_age: Some(HOUR),
+                    user_key_prefixes: vec!["presence.".into()],
+                },
+                KvRetentionConfig {
+                    bucket: bucket("pins"),
+                    max_age: Some(HOUR * 24),
+                    user_key_prefixes: vec!["pin.".into()],
+                },
+            ];
+            for retention in &config.buckets {
+                jetstream
+                    .create_key_value(kv::Config {
+                        bucket: retention.bucket.clone(),
+                        history: 5,
+                        ..Default::default()
+                    })
+                    .await
+                    .unwrap();
+            }
+            let leases = jetstream
+                .create_key_value(kv::Config {
+                    bucket: bucket("leases"),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let world = Self {
+                jetstream,
+                config,
+                leases,
+                // Purge pacing runs through the clock instead of waiting
+                clock: Arc::new(SimClock::new().auto_advance(true)),
+                audit_path: std::env::temp_dir().join(format!("compaction-audit-{}", id)),
+            };
+            world.seed().await;
+            world
+        }
+
+        async fn seed(&self) {
+            let (sessions, pins) = (self.store(0).await, self.store(1).await);
+            for key in ["presence.alice", "presence.bob", "presence.bob.phone", "meta.build"] {
+                sessions.put(key, "x".into()).await.unwrap();
+            }
+            for key in ["pin.alice.c1", "pin.bob.c1"] {
+                pins.put(key, "x".into()).await.unwrap();
+            }
+        }
+
+        async fn store(&self, index: usize) -> kv::Store {
+            self.jetstream.get_key_value(&self.config.buckets[index].bucket).await.unwrap()
+        }
+
+        fn compactor(&self, broker_id: &str) -> KvCompactor {
+            let cluster_config = BrokerConfig::load().unwrap().cluster;
+            let cluster = Arc::new(ClusterView::new(self.leases.clone(), broker_id.into(), &cluster_config));
+            let metrics = BrokerMetrics::new().unwrap();
+            let leases = Arc::new(CommandLeases::new(
+                self.leases.clone(),
+                cluster,
+                Duration::from_secs(30),
+                AuditLog::tracing_only(),
+                metrics.clone(),
+            ));
+            let audit_config = BrokerConfig::load().unwrap().audit;
+            let audit = AuditLog::open(Some(&self.audit_path.to_string_lossy()), &audit_config, metrics.clone()).unwrap();
+            KvCompactor::new(self.jetstream.clone(), self.config.clone(), leases, audit, metrics).with_clock(self.clock.clone())
+        }
+
+        async fn remaining(&self, index: usize) -> Vec<String> {
+            let store = self.store(index).await;
+            let mut remaining = Vec::new();
+            for key in ["presence.alice", "presence.bob", "presence.bob.phone", "meta.build", "pin.alice.c1", "pin.bob.c1"] {
+                if store.get(key).await.unwrap().is_some() {
+                    remaining.push(key.to_string());
+                }
+            }
+            remaining
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn only_keys_past_their_policy_are_purged() {
+        let world = World::new(false).await;
+        let compactor = world.compactor("broker-a");
+        compactor.user_deleted("bob");
+
+        // Bob's keys qualify at once, but were written within min_age
+        let report = compactor.compact(&world.config.buckets[0]).await.unwrap();
+        assert_eq!((report.scanned, report.kept_recent, report.purged), (4, 2, 0), "{:?}", report);
+
+        world.clock.advance(HOUR * 2);
+        let sessions = compactor.compact(&world.config.buckets[0]).await.unwrap();
+        assert_eq!((sessions.expired, sessions.tombstoned, sessions.purged), (2, 2, 4), "{:?}", sessions);
+        assert!(world.remaining(0).await.is_empty());
+
+        // Two hours is inside the pins' day, so only bob's pin goes
+        let pins = compactor.compact(&world.config.buckets[1]).await.unwrap();
+        assert_eq!((pins.scanned, pins.expired, pins.tombstoned, pins.purged), (2, 0, 1, 1), "{:?}", pins);
+        assert_eq!(world.remaining(1).await, vec!["pin.alice.c1"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_dry_run_reports_and_audits_without_purging() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new(true).await;
+        let compactor = Arc::new(world.compactor("broker-a"));
+        compactor.user_deleted("bob");
+        world.clock.advance(HOUR * 2);
+
+        compactor.run_cycle().await;
+        assert_eq!(world.remaining(0).await.len(), 4);
+        assert_eq!(world.remaining(1).await.len(), 2);
+
+        let written = std::fs::read_to_string(&world.audit_path).unwrap();
+        assert_eq!(written.matches("kv.compacted").count(), 2, "{}", written);
+        assert!(written.contains(r#""dry_run":true"#), "{}", written);
+        let rendered = recorder.handle().render();
+        let sessions = &world.config.buckets[0].bucket;
+        for line in [
+            format!(r#"broker_kv_compaction_candidates_total{{bucket="{}",reason="expired"}} 2"#, sessions),
+            format!(r#"broker_kv_compaction_candidates_total{{bucket="{}",reason="tombstoned"}} 2"#, sessions),
+            format!(r#"broker_kv_compaction_purged_total{{bucket="{}"}} 0"#, sessions),
+            format!(r#"broker_kv_compaction_cycles_total{{bucket="{}",outcome="dry_run"}} 1"#, sessions),
+        ] {
+            assert!(rendered.contains(&line), "missing {} in\n{}", line, rendered);
+        }
+        let _ = std::fs::remove_file(&world.audit_path);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn one_broker_compacts_each_bucket_per_cycle() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new(false).await;
+        let (a, b) = (Arc::new(world.compactor("broker-a")), Arc::new(world.compactor("broker-b")));
+        world.clock.advance(HOUR * 2);
+
+        tokio::join!(a.run_cycle(), b.run_cycle());
+        assert_eq!(world.remaining(0).await, Vec::<String>::new());
+        assert_eq!(world.remaining(1).await.len(), 2);
+
+        let written = std::fs::read_to_string(&world.audit_path).unwrap();
+        assert_eq!(written.matches("kv.compacted").count(), 2, "one report per bucket: {}", written);
+        let rendered = recorder.handle().render();
+        for retention in &world.config.buckets {
+            let line = format!(r#"broker_kv_compaction_cycles_total{{bucket="{}",outcome="completed"}} 1"#, retention.bucket);
+            assert!(rendered.contains(&line), "missing {} in\n{}", line, rendered);
+        }
+        let _ = std::fs::remove_file(&world.audit_path);
+    }
+
+    /// Deletions are forgotten two intervals after they were seen
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn deleted_users_are_remembered_for_two_intervals() {
+        let world = World::new(false).await;
+        let compactor = world.compactor("broker-a");
+        compactor.user_deleted("bob");
+        let prefixes = vec!["presence.".to_string()];
+        assert!(compactor.belongs_to_deleted_user("presence.bob.phone", &prefixes));
+        assert!(!compactor.belongs_to_deleted_user("presence.bobby", &prefixes));
+        assert!(!compactor.belongs_to_deleted_user("meta.bob", &prefixes));
+
+        world.clock.advance(world.config.interval * 2);
+        compactor.forget_old_deletions();
+        assert!(compactor.belongs_to_deleted_user("presence.bob", &prefixes));
+        world.clock.advance(Duration::from_millis(1));
+        compactor.forget_old_deletions();
+        assert!(!compactor.belongs_to_deleted_user("presence.bob", &prefixes));
+    }
+}
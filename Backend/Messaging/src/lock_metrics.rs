@@ -0,0 +1,465 @@
+use serde::Serialize;
+
+use crate::{config::LockMetricsConfig, metrics::BrokerMetrics};
+
+#[cfg(feature = "lock-metrics")]
+pub use instrumented::{InstrumentedGuard, InstrumentedMutex, InstrumentedRwLock};
+
+/// Lock used by the hot routing, presence and dedup structures
+///
+/// A plain `parking_lot` lock unless the `lock-metrics` feature is enabled,
+/// in which case every acquisition records wait and hold time under the
+/// lock's name. Construct through `NamedLock::named` so both variants build
+/// from the same call site.
+#[cfg(not(feature = "lock-metrics"))]
+pub type Mutex<T> = parking_lot::Mutex<T>;
+#[cfg(feature = "lock-metrics")]
+pub type Mutex<T> = InstrumentedMutex<T>;
+
+#[cfg(not(feature = "lock-metrics"))]
+pub type RwLock<T> = parking_lot::RwLock<T>;
+#[cfg(feature = "lock-metrics")]
+pub type RwLock<T> = InstrumentedRwLock<T>;
+
+/// Constructor taking the name contention is reported under
+///
+/// Same-named locks (e.g. the shards of one structure) share one series.
+pub trait NamedLock<T> {
+    fn named(name: &'static str, value: T) -> Self;
+}
+
+impl<T> NamedLock<T> for parking_lot::Mutex<T> {
+    fn named(_name: &'static str, value: T) -> Self {
+        parking_lot::Mutex::new(value)
+    }
+}
+
+impl<T> NamedLock<T> for parking_lot::RwLock<T> {
+    fn named(_name: &'static str, value: T) -> Self {
+        parking_lot::RwLock::new(value)
+    }
+}
+
+/// Accumulated contention for one lock name, as shown in `/debug/state`
+#[derive(Debug, Clone, Serialize)]
+pub struct LockContention {
+    pub lock: &'static str,
+    pub acquisitions: u64,
+    /// Acquisitions that found the lock held
+    pub contended: u64,
+    pub total_wait_seconds: f64,
+    pub max_wait_seconds: f64,
+    /// Waits longer than `lock_metrics.slow_wait_us`
+    pub slow_waits: u64,
+}
+
+/// Export instrumented locks through `metrics`; a no-op without `lock-metrics`
+pub fn install(config: &LockMetricsConfig, metrics: BrokerMetrics) {
+    #[cfg(feature = "lock-metrics")]
+    instrumented::install(config, metrics);
+    #[cfg(not(feature = "lock-metrics"))]
+    let _ = (config, metrics);
+}
+
+/// Locks with the most total wait time since startup, worst first
+pub fn top_contended(limit: usize) -> Vec<LockContention> {
+    #[cfg(feature = "lock-metrics")]
+    return instrumented::top_contended(limit);
+    #[cfg(not(feature = "lock-metrics"))]
+    {
+        let _ = limit;
+        Vec::new()
+    }
+}
+
+#[cfg(feature = "lock-metrics")]
+mod instrumented {
+    use std::{
+        ops::{Deref, DerefMut},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, OnceLock,
+        },
+        time::{Duration, Instant},
+    };
+    use dashmap::DashMap;
+
+    use super::{LockContention, NamedLock};
+    use crate::{config::LockMetricsConfig, metrics::BrokerMetrics};
+
+    static REGISTRY: OnceLock<DashMap<&'static str, Arc<LockStats>>> = OnceLock::new();
+    static METRICS: OnceLock<BrokerMetrics> = OnceLock::new();
+    static SLOW_WAIT_NANOS: AtomicU64 = AtomicU64::new(1_000_000);
+
+    pub(super) fn install(config: &LockMetricsConfig, metrics: BrokerMetrics) {
+        SLOW_WAIT_NANOS.store(config.slow_wait_us.saturating_mul(1000), Ordering::Relaxed);
+        let _ = METRICS.set(metrics);
+    }
+
+    pub(super) fn top_contended(limit: usize) -> Vec<LockContention> {
+        let Some(registry) = REGISTRY.get() else {
+            return Vec::new();
+        };
+        let mut locks: Vec<LockContention> = registry.iter().map(|entry| entry.value().snapshot()).collect();
+        locks.sort_by(|a, b| b.total_wait_seconds.total_cmp(&a.total_wait_seconds));
+        locks.truncate(limit);
+        locks
+    }
+
+    /// Metric handles for one lock name, resolved once `install` has run
+    struct Instruments {
+        wait: metrics::Histogram,
+        hold: metrics::Histogram,
+        slow_waits: metrics::Counter,
+    }
+
+    struct LockStats {
+        name: &'static str,
+        acquisitions: AtomicU64,
+        contended: AtomicU64,
+        wait_nanos: AtomicU64,
+        max_wait_nanos: AtomicU64,
+        slow_waits: AtomicU64,
+        instruments: OnceLock<Instruments>,
+    }
+
+    impl LockStats {
+        fn register(name: &'static str) -> Arc<Self> {
+            REGISTRY
+                .get_or_init(DashMap::new)
+                .entry(name)
+                .or_insert_with(|| {
+                    Arc::new(Self {
+                        name,
+                        acquisitions: AtomicU64::new(0),
+                        contended: AtomicU64::new(0),
+                        wait_nanos: AtomicU64::new(0),
+                        max_wait_nanos: AtomicU64::new(0),
+                        slow_waits: AtomicU64::new(0),
+                        instruments: OnceLock::new(),
+                    })
+                })
+                .clone()
+        }
+
+        fn instruments(&self) -> Option<&Instruments> {
+            if let Some(instruments) = self.instruments.get() {
+                return Some(instruments);
+            }
+            let metrics = METRICS.get()?;
+            let (wait, hold, slow_waits) = metrics.lock_instruments(self.name);
+            Some(self.instruments.get_or_init(|| Instruments { wait, hold, slow_waits }))
+        }
+
+        fn record_wait(&self, waited: Option<Duration>) {
+            self.acquisitions.fetch_add(1, Ordering::Relaxed);
+            let nanos = waited.map_or(0, |w| w.as_nanos() as u64);
+            let slow = nanos > SLOW_WAIT_NANOS.load(Ordering::Relaxed);
+            if waited.is_some() {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+                self.max_wait_nanos.fetch_max(nanos, Ordering::Relaxed);
+                if slow {
+                    self.slow_waits.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if let Some(instruments) = self.instruments() {
+                instruments.wait.record(nanos as f64 / 1e9);
+                if slow {
+                    instruments.slow_waits.increment(1);
+                }
+            }
+        }
+
+        fn record_hold(&self, held: Duration) {
+            if let Some(instruments) = self.instruments() {
+                instruments.hold.record(held.as_secs_f64());
+            }
+        }
+
+        fn snapshot(&self) -> LockContention {
+            LockContention {
+                lock: self.name,
+                acquisitions: self.acquisitions.load(Ordering::Relaxed),
+                contended: self.contended.load(Ordering::Relaxed),
+                total_wait_seconds: self.wait_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+                max_wait_seconds: self.max_wait_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+                slow_waits: self.slow_waits.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Acquire with a non-blocking attempt first, so uncontended acquisitions
+    /// don't pay for a clock read before locking
+    fn acquire<G>(stats: &LockStats, try_acquire: impl FnOnce() -> Option<G>, acquire: impl FnOnce() -> G) -> G {
+        match try_acquire() {
+            Some(guard) => {
+                stats.record_wait(None);
+                guard
+            }
+            None => {
+                let started = Instant::now();
+                let guard = acquire();
+                stats.record_wait(Some(started.elapsed()));
+                guard
+            }
+        }
+    }
+
+    /// Guard that records how long the lock was held when dropped
+    pub struct InstrumentedGuard<'a, G> {
+        guard: G,
+        stats: &'a LockStats,
+        acquired: Instant,
+    }
+
+    impl<'a, G> InstrumentedGuard<'a, G> {
+        fn new(guard: G, stats: &'a LockStats) -> Self {
+            Self {
+                guard,
+                stats,
+                acquired: Instant::now(),
+            }
+        }
+    }
+
+    impl<G: Deref> Deref for InstrumentedGuard<'_, G> {
+        type Target = G::Target;
+
+        fn deref(&self) -> &Self::Target {
+            &self.guard
+        }
+    }
+
+    impl<G: DerefMut> DerefMut for InstrumentedGuard<'_, G> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.guard
+        }
+    }
+
+    impl<G> Drop for InstrumentedGuard<'_, G> {
+        fn drop(&mut self) {
+            self.stats.record_hold(self.acquired.elapsed());
+        }
+    }
+
+    /// `parking_lot::Mutex` recording wait and hold time under a name
+    pub struct InstrumentedMutex<T> {
+        inner: parking_lot::Mutex<T>,
+        stats: Arc<LockStats>,
+    }
+
+    impl<T> InstrumentedMutex<T> {
+        pub fn lock(&self) -> InstrumentedGuard<'_, parking_lot::MutexGuard<'_, T>> {
+            let guard = acquire(&self.stats, || self.inner.try_lock(), || self.inner.lock());
+            InstrumentedGuard::new(guard, &self.stats)
+        }
+
+        pub fn try_lock(&self) -> Option<InstrumentedGuard<'_, parking_lot::MutexGuard<'_, T>>> {
+            let guard = self.inner.try_lock()?;
+            self.stats.record_wait(None);
+            Some(InstrumentedGuard::new(guard, &self.stats))
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.inner.get_mut()
+        }
+
+        pub fn into_inner(self) -> T {
+            self.inner.into_inner()
+        }
+    }
+
+    impl<T> NamedLock<T> for InstrumentedMutex<T> {
+        fn named(name: &'static str, value: T) -> Self {
+            Self {
+                inner: parking_lot::Mutex::new(value),
+                stats: LockStats::register(name),
+            }
+        }
+    }
+
+    /// `parking_lot::RwLock` recording wait and hold time under a name
+    ///
+    /// Reads and writes share the lock's series.
+    pub struct InstrumentedRwLock<T> {
+        inner: parking_lot::RwLock<T>,
+        stats: Arc<LockStats>,
+    }
+
+    impl<T> InstrumentedRwLock<T> {
+        pub fn read(&self) -> InstrumentedGuard<'_, parking_lot::RwLockReadGuard<'_, T>> {
+            let guard = acquire(&self.stats, || self.inner.try_read(), || self.inner.read());
+            InstrumentedGuard::new(guard, &self.stats)
+        }
+
+        pub fn write(&self) -> InstrumentedGuard<'_, parking_lot::RwLockWriteGuard<'_, T>> {
+            let guard = acquire(&self.stats, || self.inner.try_write(), || self.inner.write());
+            InstrumentedGuard::new(guard, &self.stats)
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.inner.get_mut()
+        }
+
+        pub fn into_inner(self) -> T {
+            self.inner.into_inner()
+        }
+    }
+
+    impl<T> NamedLock<T> for InstrumentedRwLock<T> {
+        fn named(name: &'static str, value: T) -> Self {
+            Self {
+                inner: parking_lot::RwLock::new(value),
+                stats: LockStats::register(name),
+            }
+        }
+    }
+}
+
+/// The contention tests need the instrumented locks:
+/// `cargo test --features lock-metrics lock_metrics`
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "lock-metrics")]
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn the_aliases_keep_the_plain_lock_api() {
+        let mutex: Mutex<Vec<u32>> = NamedLock::named("test.api_mutex", Vec::new());
+        mutex.lock().push(1);
+        assert_eq!(mutex.try_lock().unwrap().len(), 1);
+        assert_eq!(mutex.into_inner(), vec![1]);
+
+        let mut rwlock: RwLock<u32> = NamedLock::named("test.api_rwlock", 1);
+        *rwlock.write() += 1;
+        assert_eq!(*rwlock.read(), 2);
+        *rwlock.get_mut() += 1;
+        assert_eq!(rwlock.into_inner(), 3);
+    }
+
+    #[cfg(not(feature = "lock-metrics"))]
+    #[test]
+    fn without_the_feature_nothing_is_reported() {
+        let mutex: Mutex<u32> = NamedLock::named("test.plain", 0);
+        *mutex.lock() += 1;
+        assert!(top_contended(usize::MAX).is_empty());
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    fn contention(lock: &str) -> LockContention {
+        top_contended(usize::MAX)
+            .into_iter()
+            .find(|contention| contention.lock == lock)
+            .unwrap()
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    #[test]
+    fn a_hot_lock_shows_up_in_the_metrics() {
+        use metrics_exporter_prometheus::PrometheusBuilder;
+
+        use crate::config::BrokerConfig;
+
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 25;
+
+        let mut config = BrokerConfig::load().unwrap().lock_metrics;
+        config.slow_wait_us = 1000;
+        install(&config, BrokerMetrics::new().unwrap());
+
+        let hot: Arc<Mutex<u64>> = Arc::new(NamedLock::named("test.hot", 0));
+        let cold: Mutex<u64> = NamedLock::named("test.cold", 0);
+
+        // Resolve the hot lock's instruments against a local recorder; the
+        // handles then record from every thread
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || *hot.lock() += 1);
+
+        for _ in 0..THREADS * ROUNDS {
+            *cold.lock() += 1;
+        }
+
+        let start = Arc::new(Barrier::new(THREADS));
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let hot = Arc::clone(&hot);
+                let start = Arc::clone(&start);
+                thread::spawn(move || {
+                    start.wait();
+                    for _ in 0..ROUNDS {
+                        let mut value = hot.lock();
+                        // Hold it long enough that every other thread queues up
+                        thread::sleep(Duration::from_millis(2));
+                        *value += 1;
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(*hot.lock(), (THREADS * ROUNDS + 1) as u64);
+
+        let hot_stats = contention("test.hot");
+        assert_eq!(hot_stats.acquisitions, (THREADS * ROUNDS + 2) as u64);
+        assert!(hot_stats.contended >= ROUNDS as u64, "{:?}", hot_stats);
+        assert!(hot_stats.slow_waits > 0, "{:?}", hot_stats);
+        assert!(hot_stats.max_wait_seconds >= 0.002, "{:?}", hot_stats);
+        assert!(hot_stats.total_wait_seconds >= hot_stats.max_wait_seconds);
+
+        let cold_stats = contention("test.cold");
+        assert_eq!(cold_stats.acquisitions, (THREADS * ROUNDS) as u64);
+        assert_eq!(cold_stats.contended, 0);
+        assert_eq!(cold_stats.total_wait_seconds, 0.0);
+
+        let ranked: Vec<&str> = top_contended(usize::MAX).iter().map(|contention| contention.lock).collect();
+        let position = |lock| ranked.iter().position(|name| *name == lock).unwrap();
+        assert!(position("test.hot") < position("test.cold"), "{:?}", ranked);
+        assert_eq!(top_contended(1).len(), 1);
+
+        let rendered = handle.render();
+        for line in [
+            format!(r#"broker_lock_wait_seconds_count{{lock="test.hot"}} {}"#, THREADS * ROUNDS + 2),
+            format!(r#"broker_lock_hold_seconds_count{{lock="test.hot"}} {}"#, THREADS * ROUNDS + 2),
+            format!(r#"broker_lock_slow_waits_total{{lock="test.hot"}} {}"#, hot_stats.slow_waits),
+        ] {
+            assert!(rendered.contains(&line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    #[test]
+    fn concurrent_readers_do_not_contend() {
+        const READERS: usize = 6;
+
+        let lock: Arc<RwLock<u64>> = Arc::new(NamedLock::named("test.readers", 7));
+        let holding = Arc::new(Barrier::new(READERS));
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                let holding = Arc::clone(&holding);
+                thread::spawn(move || {
+                    let value = lock.read();
+                    // Every reader holds the lock at once
+                    holding.wait();
+                    *value
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 7);
+        }
+
+        let stats = contention("test.readers");
+        assert_eq!(stats.acquisitions, READERS as u64);
+        assert_eq!(stats.contended, 0);
+    }
+}
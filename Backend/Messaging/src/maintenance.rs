@@ -0,0 +1,401 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    config::MaintenanceConfig,
+    config_watch::ConfigWatcher,
+    metrics::BrokerMetrics,
+};
+
+/// Error code returned to mutating callers during maintenance
+pub const MAINTENANCE_CODE: &str = "MAINTENANCE";
+
+/// Metadata keys on gRPC `UNAVAILABLE` responses during maintenance
+pub const MAINTENANCE_MESSAGE_HEADER: &str = "x-maintenance-message";
+pub const MAINTENANCE_UNTIL_HEADER: &str = "x-maintenance-until";
+
+/// Actor recorded when the config flag toggles maintenance
+const CONFIG_ACTOR: &str = "config";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MaintenanceWindow {
+    /// Operator-supplied reason shown to rejected callers
+    pub message: Option<String>,
+    /// Expected end, timestamp in milliseconds
+    pub expected_end: Option<i64>,
+    pub entered_by: String,
+    /// Timestamp in milliseconds
+    pub entered_at: i64,
+}
+
+/// Rejection of a mutation while the broker is in maintenance
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+#[error("broker is in maintenance{}", .message.as_deref().map(|m| format!(": {}", m)).unwrap_or_default())]
+pub struct InMaintenance {
+    pub code: &'static str,
+    pub message: Option<String>,
+    pub expected_end: Option<i64>,
+}
+
+impl InMaintenance {
+    pub fn to_status(&self) -> tonic::Status {
+        let mut status = tonic::Status::unavailable(format!("{}: {}", self.code, self));
+        if let Some(message) = self.message.as_deref().and_then(|m| m.parse().ok()) {
+            status.metadata_mut().insert(MAINTENANCE_MESSAGE_HEADER, message);
+        }
+        if let Some(until) = self.expected_end {
+            status
+                .metadata_mut()
+                .insert(MAINTENANCE_UNTIL_HEADER, until.to_string().parse().expect("digits are valid metadata"));
+        }
+        status
+    }
+}
+
+/// Read-only maintenance mode
+///
+/// While active, ingress consumers and queued work (outbox polling, parked
+/// message re-injection) wait in `wait_inactive` before taking their next
+/// batch, so JetStream and the outbox keep everything unprocessed and
+/// nothing is lost; the ingress gate also turns away anything already in
+/// flight, for the caller to NAK. Mutating RPCs and REST endpoints get
+/// `MAINTENANCE` with the operator's message and expected end time, while
+/// history, status, presence and read-horizon queries keep working.
+///
+/// Entered through `maintenance.enabled` or the `enter_maintenance`
+/// control command; entering while already in maintenance only updates the
+/// message and end time, and leaving when not in maintenance is a no-op, so
+/// only real transitions are audited. Readiness stays up during maintenance
+/// if `maintenance.stay_ready` is set.
+pub struct MaintenanceMode {
+    state: watch::Sender<Option<MaintenanceWindow>>,
+    stay_ready: AtomicBool,
+    /// Last seen value of `maintenance.enabled`, so reloads act only on changes
+    config_flag: AtomicBool,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl MaintenanceMode {
+    pub fn new(config: &MaintenanceConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        let mode = Self {
+            state: watch::Sender::new(None),
+            stay_ready: AtomicBool::new(config.stay_ready),
+            config_flag: AtomicBool::new(config.enabled),
+            audit,
+            metrics,
+        };
+        mode.metrics.update_maintenance_mode(false);
+        if config.enabled {
+            mode.enter(config.message.clone(), None, CONFIG_ACTOR);
+        }
+        mode
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.borrow().is_some()
+    }
+
+    pub fn current(&self) -> Option<MaintenanceWindow> {
+        self.state.borrow().clone()
+    }
+
+    /// Reject a mutation if in maintenance; `surface` labels the rejection metric
+    pub fn check(&self, surface: &'static str) -> Result<(), InMaintenance> {
+        let state = self.state.borrow();
+        let Some(window) = state.as_ref() else {
+            return Ok(());
+        };
+        self.metrics.record_maintenance_rejection(surface);
+        Err(InMaintenance {
+            code: MAINTENANCE_CODE,
+            message: window.message.clone(),
+            expected_end: window.expected_end,
+        })
+    }
+
+    /// Resolves once the broker is out of maintenance; immediately if it isn't in it
+    pub async fn wait_inactive(&self) {
+        let mut state = self.state.subscribe();
+        // The sender lives as long as `self`, so this can't fail while we're borrowed
+        let _ = state.wait_for(Option::is_none).await;
+    }
+
+    /// Whether `/ready` should report ready as far as maintenance is concerned
+    pub fn ready(&self) -> bool {
+        !self.is_active() || self.stay_ready.load(Ordering::Relaxed)
+    }
+
+    /// Enter maintenance; true if the broker wasn't in maintenance before
+    pub fn enter(&self, message: Option<String>, expected_end: Option<i64>, actor: &str) -> bool {
+        let mut entered = false;
+        self.state.send_modify(|state| match state {
+            Some(window) => {
+                window.message = message.clone();
+                window.expected_end = expected_end;
+            }
+            None => {
+                entered = true;
+                *state = Some(MaintenanceWindow {
+                    message: message.clone(),
+                    expected_end,
+                    entered_by: actor.to_string(),
+                    entered_at: Utc::now().timestamp_millis(),
+                });
+            }
+        });
+        if !entered {
+            return false;
+        }
+
+        warn!("Entered maintenance mode by {}: {}", actor, message.as_deref().unwrap_or("-"));
+        self.metrics.update_maintenance_mode(true);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "maintenance.entered",
+            serde_json::json!({ "message": message, "expected_end": expected_end }),
+        ));
+        true
+    }
+
+    /// Leave maintenance; true if the broker was in maintenance
+    pub fn leave(&self, actor: &str) -> bool {
+        let Some(window) = self.state.send_replace(None) else {
+            return false;
+        };
+
+        let duration_ms = Utc::now().timestamp_millis() - window.entered_at;
+        info!("Left maintenance mode by {} after {}ms", actor, duration_ms);
+        self.metrics.update_maintenance_mode(false);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "maintenance.left",
+            serde_json::json!({ "entered_by": window.entered_by, "duration_ms": duration_ms }),
+        ));
+        true
+    }
+
+    /// Follow `maintenance.enabled` and `maintenance.stay_ready` across reloads
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let mode = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            let config = &config.maintenance;
+            mode.stay_ready.store(config.stay_ready, Ordering::Relaxed);
+            // A command-entered window survives reloads that leave the flag alone
+            if mode.config_flag.swap(config.enabled, Ordering::Relaxed) == config.enabled {
+                return;
+            }
+            if config.enabled {
+                mode.enter(config.message.clone(), None, CONFIG_ACTOR);
+            } else {
+                mode.leave(CONFIG_ACTOR);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    const END: i64 = 1_700_000_000_000;
+
+    fn config(enabled: bool, stay_ready: bool) -> MaintenanceConfig {
+        MaintenanceConfig {
+            enabled,
+            stay_ready,
+            message: enabled.then(|| "config window".to_string()),
+        }
+    }
+
+    fn mode(config: &MaintenanceConfig) -> MaintenanceMode {
+        MaintenanceMode::new(config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+    }
+
+    #[test]
+    fn mutations_get_the_operator_message_and_end_time() {
+        let mode = mode(&config(false, false));
+        mode.check("grpc").unwrap();
+        assert!(mode.current().is_none());
+
+        assert!(mode.enter(Some("moving streams".to_string()), Some(END), "ops"));
+        let rejection = mode.check("grpc").unwrap_err();
+        assert_eq!(rejection.code, MAINTENANCE_CODE);
+        assert_eq!(rejection.to_string(), "broker is in maintenance: moving streams");
+        assert_eq!(
+            serde_json::to_value(&rejection).unwrap(),
+            serde_json::json!({ "code": "MAINTENANCE", "message": "moving streams", "expected_end": END })
+        );
+
+        let status = rejection.to_status();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(status.message().starts_with("MAINTENANCE: "), "{}", status.message());
+        assert_eq!(status.metadata().get(MAINTENANCE_MESSAGE_HEADER).unwrap(), "moving streams");
+        assert_eq!(status.metadata().get(MAINTENANCE_UNTIL_HEADER).unwrap(), END.to_string().as_str());
+
+        let window = mode.current().unwrap();
+        assert_eq!(window.entered_by, "ops");
+        assert_eq!(window.expected_end, Some(END));
+
+        assert!(mode.leave("ops"));
+        mode.check("grpc").unwrap();
+    }
+
+    #[test]
+    fn a_bare_window_has_no_metadata() {
+        let mode = mode(&config(false, false));
+        mode.enter(None, None, "ops");
+        let rejection = mode.check("rest").unwrap_err();
+        assert_eq!(rejection.to_string(), "broker is in maintenance");
+        let status = rejection.to_status();
+        assert!(status.metadata().get(MAINTENANCE_MESSAGE_HEADER).is_none());
+        assert!(status.metadata().get(MAINTENANCE_UNTIL_HEADER).is_none());
+    }
+
+    #[test]
+    fn entering_and_leaving_are_idempotent_and_audited_once() {
+        let path = std::env::temp_dir().join(format!("maintenance-audit-{}", Uuid::new_v4().simple()));
+        let audit_config = BrokerConfig::load().unwrap().audit;
+        let metrics = BrokerMetrics::new().unwrap();
+        let audit = AuditLog::open(Some(&path.to_string_lossy()), &audit_config, metrics.clone()).unwrap();
+        let mode = MaintenanceMode::new(&config(false, false), audit, metrics);
+
+        assert!(!mode.leave("ops"));
+        assert!(mode.enter(Some("first".to_string()), None, "alice"));
+        // A second enter only updates the message and end time
+        assert!(!mode.enter(Some("second".to_string()), Some(END), "bob"));
+        let window = mode.current().unwrap();
+        assert_eq!(window.message.as_deref(), Some("second"));
+        assert_eq!(window.expected_end, Some(END));
+        assert_eq!(window.entered_by, "alice");
+
+        assert!(mode.leave("bob"));
+        assert!(!mode.leave("bob"));
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.matches("maintenance.entered").count(), 1, "{}", written);
+        assert_eq!(written.matches("maintenance.left").count(), 1, "{}", written);
+        assert!(written.contains("alice") && written.contains("bob"), "{}", written);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn readiness_follows_stay_ready() {
+        let mode = mode(&config(true, false));
+        assert!(mode.is_active());
+        assert_eq!(mode.current().unwrap().entered_by, CONFIG_ACTOR);
+        assert_eq!(mode.current().unwrap().message.as_deref(), Some("config window"));
+        assert!(!mode.ready());
+        mode.leave("ops");
+        assert!(mode.ready());
+
+        let mode = self::mode(&config(true, true));
+        assert!(mode.is_active());
+        assert!(mode.ready());
+    }
+
+    #[test]
+    fn gauge_and_rejections_are_exported() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let mode = mode(&config(false, false));
+            mode.enter(None, None, "ops");
+            assert!(handle.render().contains("broker_maintenance_mode 1"), "{}", handle.render());
+            let _ = mode.check("grpc");
+            let _ = mode.check("rest");
+            let _ = mode.check("rest");
+            mode.leave("ops");
+            let _ = mode.check("rest");
+        });
+
+        let rendered = handle.render();
+        for line in [
+            "broker_maintenance_mode 0",
+            r#"broker_maintenance_rejections_total{surface="grpc"} 1"#,
+            r#"broker_maintenance_rejections_total{surface="rest"} 2"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    /// A consumer loop the way ingress and the outbox poller run theirs:
+    /// wait out maintenance, take the next message, and NAK it for
+    /// redelivery if the gate turns it away
+    fn consumer(
+        mode: Arc<MaintenanceMode>,
+        mut queue: mpsc::UnboundedReceiver<u32>,
+    ) -> (tokio::task::JoinHandle<()>, mpsc::UnboundedReceiver<u32>) {
+        let (processed, results) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(async move {
+            let mut nacked = None;
+            loop {
+                mode.wait_inactive().await;
+                let message = match nacked.take() {
+                    Some(message) => message,
+                    None => match queue.recv().await {
+                        Some(message) => message,
+                        None => return,
+                    },
+                };
+                if mode.check("ingress").is_err() {
+                    nacked = Some(message);
+                    continue;
+                }
+                processed.send(message).unwrap();
+            }
+        });
+        (handle, results)
+    }
+
+    #[tokio::test]
+    async fn consumers_process_nothing_during_maintenance_and_resume_without_loss() {
+        let mode = Arc::new(mode(&config(false, false)));
+        let (queue, backlog) = mpsc::unbounded_channel();
+        let (handle, mut processed) = consumer(Arc::clone(&mode), backlog);
+
+        queue.send(0).unwrap();
+        assert_eq!(processed.recv().await, Some(0));
+
+        // The consumer is already waiting on the queue, so the first message
+        // after entering is the in-flight one the gate turns away
+        mode.enter(Some("migration".to_string()), None, "ops");
+        for message in 1..=100 {
+            queue.send(message).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(processed.try_recv().is_err(), "ingress processed a message during maintenance");
+
+        mode.leave("ops");
+        for expected in 1..=100 {
+            let message = tokio::time::timeout(Duration::from_secs(1), processed.recv()).await.unwrap();
+            assert_eq!(message, Some(expected));
+        }
+
+        drop(queue);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_inactive_returns_at_once_outside_maintenance() {
+        let mode = mode(&config(false, false));
+        tokio::time::timeout(Duration::from_millis(100), mode.wait_inactive()).await.unwrap();
+
+        mode.enter(None, None, "ops");
+        assert!(tokio::time::timeout(Duration::from_millis(50), mode.wait_inactive()).await.is_err());
+    }
+}
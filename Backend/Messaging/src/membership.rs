@@ -0,0 +1,409 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::{RateLimits, RoutingConfig},
+    message::types::is_valid_user_id,
+    metrics::BrokerMetrics,
+};
+
+/// Invalidations buffered per subscriber before it starts lagging
+const INVALIDATION_CHANNEL_CAPACITY: usize = 1024;
+
+/// A user's standing in a group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Member,
+    /// Invited but not joined; gets one invitation event, no traffic
+    Invited,
+    Banned,
+}
+
+impl MemberState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemberState::Member => "member",
+            MemberState::Invited => "invited",
+            MemberState::Banned => "banned",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMember {
+    pub user_id: String,
+    pub state: MemberState,
+    /// When an invitation lapses, in milliseconds; `None` uses `invitations.default_ttl`
+    pub expires_at: Option<i64>,
+}
+
+/// Source of truth for group membership
+#[async_trait]
+pub trait MembershipResolver: Send + Sync {
+    /// Users in `member` state
+    async fn resolve(&self, group_id: &str) -> Result<Vec<String>, ResolverError>;
+
+    /// Every user with a standing in the group
+    ///
+    /// Resolvers that don't track invitations or bans only know members.
+    async fn resolve_states(&self, group_id: &str) -> Result<Vec<GroupMember>, ResolverError> {
+        Ok(self
+            .resolve(group_id)
+            .await?
+            .into_iter()
+            .map(|user_id| GroupMember {
+                user_id,
+                state: MemberState::Member,
+                expires_at: None,
+            })
+            .collect())
+    }
+}
+
+/// Cached view of a group
+#[derive(Debug, Clone)]
+pub struct GroupView {
+    /// Users in `member` state, the only ones receiving traffic
+    pub members: Arc<Vec<String>>,
+    /// Invited and banned users
+    pub others: Arc<Vec<GroupMember>>,
+}
+
+struct CachedMembership {
+    members: Arc<Vec<String>>,
+    others: Arc<Vec<GroupMember>>,
+    fetched_at: Instant,
+    /// Size of a suspicious resolution awaiting a confirming re-resolve
+    suspect_size: Option<usize>,
+}
+
+/// Cached group membership with sanity checks on every resolution
+///
+/// Resolved member lists are checked for malformed IDs, capped at
+/// `limits.max_group_size`, and compared against the cached size; a change
+/// beyond `routing.membership_delta_threshold` is flagged and the cached
+/// copy keeps being served until a second resolution confirms the new size.
+///
+/// Invited and banned users are cached next to the members but never
+/// returned by `members`. A resolution that raced an `invalidate` is
+/// returned to its caller but not cached, so a join event takes effect on
+/// the next message instead of after the TTL.
+pub struct MembershipCache {
+    resolver: Arc<dyn MembershipResolver>,
+    cache: DashMap<String, CachedMembership>,
+    ttl: Duration,
+    max_group_size: usize,
+    delta_threshold: f64,
+    reject_oversized: bool,
+    invalidations: broadcast::Sender<String>,
+    /// Bumped by every invalidation
+    generation: AtomicU64,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl MembershipCache {
+    pub fn new(
+        resolver: Arc<dyn MembershipResolver>,
+        routing: &RoutingConfig,
+        limits: &RateLimits,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            resolver,
+            cache: DashMap::new(),
+            ttl: routing.membership_ttl,
+            max_group_size: limits.max_group_size,
+            delta_threshold: routing.membership_delta_threshold,
+            reject_oversized: routing.reject_oversized_groups,
+            invalidations: broadcast::channel(INVALIDATION_CHANNEL_CAPACITY).0,
+            generation: AtomicU64::new(0),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn members(&self, group_id: &str) -> Result<Arc<Vec<String>>, MembershipError> {
+        Ok(self.view(group_id).await?.members)
+    }
+
+    /// Members plus invited and banned users
+    pub async fn view(&self, group_id: &str) -> Result<GroupView, MembershipError> {
+        if let Some(cached) = self.cache.get(group_id) {
+            if self.clock.now_instant().saturating_duration_since(cached.fetched_at) < self.ttl && cached.suspect_size.is_none() {
+                self.metrics.record_routing_cache_hit();
+                return Ok(GroupView {
+                    members: Arc::clone(&cached.members),
+                    others: Arc::clone(&cached.others),
+                });
+            }
+        }
+        self.metrics.record_routing_cache_miss();
+        self.resolve(group_id).await
+    }
+
+    /// Re-resolve a group ahead of demand, e.g. from the route warmer
+    pub async fn refresh(&self, group_id: &str) -> Result<(), MembershipError> {
+        self.resolve(group_id).await?;
+        Ok(())
+    }
+
+    async fn resolve(&self, group_id: &str) -> Result<GroupView, MembershipError> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let (members, others): (Vec<GroupMember>, Vec<GroupMember>) = self
+            .resolver
+            .resolve_states(group_id)
+            .await?
+            .into_iter()
+            .partition(|member| member.state == MemberState::Member);
+        let members = self.sanitize(group_id, members.into_iter().map(|m| m.user_id).collect())?;
+        let others = Arc::new(others);
+
+        // An invalidation during the resolve may mean the result is already stale
+        if self.generation.load(Ordering::Acquire) != generation {
+            self.metrics.record_membership_anomaly("invalidated_during_resolve", 1);
+            return Ok(GroupView {
+                members: Arc::new(members),
+                others,
+            });
+        }
+        Ok(self.store(group_id, members, others))
+    }
+
+    /// Drop a cached membership so the next lookup re-resolves
+    pub fn invalidate(&self, group_id: &str) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.cache.remove(group_id);
+        // No subscribers is the common case and not an error
+        let _ = self.invalidations.send(group_id.to_string());
+    }
+
+    /// Drop every cached membership
+    pub fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.cache.clear();
+    }
+
+    /// Group IDs passed to `invalidate` from now on; never blocks the invalidating caller
+    pub fn subscribe_invalidations(&self) -> broadcast::Receiver<String> {
+        self.invalidations.subscribe()
+    }
+
+    fn sanitize(&self, group_id: &str, mut members: Vec<String>) -> Result<Vec<String>, MembershipError> {
+        let before = members.len();
+        members.retain(|member| is_valid_user_id(member));
+        let malformed = before - members.len();
+        if malformed > 0 {
+            warn!("Resolver returned {} malformed members for {}", malformed, group_id);
+            self.metrics
+                .record_membership_anomaly("malformed_member", malformed as u64);
+        }
+
+        if members.len() > self.max_group_size {
+            self.metrics.record_membership_anomaly("capped", 1);
+            warn!(
+                "Resolver returned {} members for {}, over max_group_size {}",
+                members.len(),
+                group_id,
+                self.max_group_size
+            );
+            if self.reject_oversized {
+                return Err(MembershipError::GroupTooLarge {
+                    size: members.len(),
+                    max: self.max_group_size,
+                });
+            }
+            members.truncate(self.max_group_size);
+        }
+
+        Ok(members)
+    }
+
+    fn store(&self, group_id: &str, members: Vec<String>, others: Arc<Vec<GroupMember>>) -> GroupView {
+        let size = members.len();
+        let members = Arc::new(members);
+
+        let mut entry = match self.cache.get_mut(group_id) {
+            Some(entry) => entry,
+            None => {
+                self.cache.insert(
+                    group_id.to_string(),
+                    CachedMembership {
+                        members: Arc::clone(&members),
+                        others: Arc::clone(&others),
+                        fetched_at: self.clock.now_instant(),
+                        suspect_size: None,
+                    },
+                );
+                return GroupView { members, others };
+            }
+        };
+
+        let cached_size = entry.members.len();
+        let confirmed = entry
+            .suspect_size
+            .is_some_and(|suspect| !self.is_suspicious(suspect, size));
+
+        if !confirmed && self.is_suspicious(cached_size, size) {
+            self.metrics.record_membership_anomaly("delta_flagged", 1);
+            warn!(
+                "Membership of {} changed from {} to {} members; serving cached copy until confirmed",
+                group_id, cached_size, size
+            );
+            entry.suspect_size = Some(size);
+            // Invitations and bans aren't part of the size check
+            entry.others = Arc::clone(&others);
+            return GroupView {
+                members: Arc::clone(&entry.members),
+                others,
+            };
+        }
+
+        entry.members = Arc::clone(&members);
+        entry.others = Arc::clone(&others);
+        entry.fetched_at = self.clock.now_instant();
+        entry.suspect_size = None;
+        GroupView { members, others }
+    }
+
+    fn is_suspicious(&self, previous: usize, current: usize) -> bool {
+        if previous == 0 {
+            return false;
+        }
+        let delta = (current as f64 - previous as f64).abs() / previous as f64;
+        delta > self.delta_threshold
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("membership resolver error: {0}")]
+pub struct ResolverError(pub String);
+
+#[derive(Debug, thiserror::Error)]
+pub enum MembershipError {
+    #[error("group has {size} members, over the limit of {max}")]
+    GroupTooLarge { size: usize, max: usize },
+    #[error(transparent)]
+    Resolver(#[from] ResolverError),
+}
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    /// Resolver returning whatever the test last set
+    #[derive(Default)]
+    struct ScriptedResolver {
+        members: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedResolver {
+        fn set(&self, count: usize) {
+            *self.members.lock() = (0..count).map(|i| format!("user-{}", i)).collect();
+        }
+    }
+
+    #[async_trait]
+    impl MembershipResolver for ScriptedResolver {
+        async fn resolve(&self, _group_id: &str) -> Result<Vec<String>, ResolverError> {
+            Ok(self.members.lock().clone())
+        }
+    }
+
+    fn cache(reject_oversized: bool) -> (MembershipCache, Arc<ScriptedResolver>) {
+        let config = BrokerConfig::load().unwrap();
+        let mut routing = config.routing;
+        routing.membership_ttl = Duration::from_secs(3600);
+        routing.membership_delta_threshold = 0.5;
+        routing.reject_oversized_groups = reject_oversized;
+        let mut limits = config.limits;
+        limits.max_group_size = 100;
+
+        let resolver = Arc::new(ScriptedResolver::default());
+        let cache = MembershipCache::new(resolver.clone(), &routing, &limits, BrokerMetrics::new().unwrap());
+        (cache, resolver)
+    }
+
+    #[tokio::test]
+    async fn oversized_groups_are_rejected() {
+        let (cache, resolver) = cache(true);
+        resolver.set(250);
+        assert!(matches!(
+            cache.members("group_a").await,
+            Err(MembershipError::GroupTooLarge { size: 250, max: 100 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_groups_are_truncated_when_configured() {
+        let (cache, resolver) = cache(false);
+        resolver.set(250);
+        assert_eq!(cache.members("group_a").await.unwrap().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn malformed_members_are_dropped_before_route_lookup() {
+        let (cache, resolver) = cache(true);
+        *resolver.members.lock() = vec!["alice".into(), "".into(), "has space".into(), "x".repeat(65), "bob".into()];
+        assert_eq!(*cache.members("group_a").await.unwrap(), vec!["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn suspicious_deltas_serve_the_cached_copy_until_confirmed() {
+        let (cache, resolver) = cache(true);
+        resolver.set(10);
+        assert_eq!(cache.members("group_a").await.unwrap().len(), 10);
+
+        resolver.set(40);
+        // Flagged: the cached ten keep being served
+        assert_eq!(cache.resolve("group_a").await.unwrap().members.len(), 10);
+
+        // The flag forces a re-resolve, which confirms the new size
+        assert_eq!(cache.members("group_a").await.unwrap().len(), 40);
+        assert_eq!(cache.members("group_a").await.unwrap().len(), 40);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_deltas_keep_the_cached_copy() {
+        let (cache, resolver) = cache(true);
+        resolver.set(10);
+        cache.members("group_a").await.unwrap();
+
+        resolver.set(40);
+        cache.refresh("group_a").await.unwrap();
+        // The glitch is gone by the confirming resolve
+        resolver.set(11);
+        assert_eq!(cache.members("group_a").await.unwrap().len(), 11);
+    }
+
+    #[tokio::test]
+    async fn small_deltas_are_accepted_directly() {
+        let (cache, resolver) = cache(true);
+        resolver.set(10);
+        cache.members("group_a").await.unwrap();
+
+        resolver.set(14);
+        cache.refresh("group_a").await.unwrap();
+        assert_eq!(cache.members("group_a").await.unwrap().len(), 14);
+    }
+}
@@ -1,10 +1,10 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use std::collections::HashMap;
 
 /// Core message types that the broker handles
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageType {
     /// Encrypted text message
@@ -13,6 +13,8 @@ pub enum MessageType {
     GroupMessage,
     /// Media message (metadata only)
     MediaMessage,
+    /// Platform-generated notice sent by a service account
+    SystemMessage,
     /// Presence update
     Presence,
     /// Typing indicator
@@ -25,6 +27,26 @@ pub enum MessageType {
     Ack,
     /// Error response
     Error,
+    /// Sender key rotation for a conversation, delivered only to the
+    /// members present when it was ingested
+    KeyDistribution,
+    /// Payload-free notice of new replies in a thread the recipient doesn't follow
+    ThreadActivity,
+    /// Sent once to an invited user in place of the group's traffic
+    GroupInvitation,
+}
+
+/// Delivery priority used for scheduling and load shedding
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Best-effort traffic (campaigns, digests) - first to be shed
+    Bulk,
+    /// Regular user traffic
+    #[default]
+    Normal,
+    /// System notices and alerts
+    High,
 }
 
 /// Base message envelope - THE BROKER ONLY SEES THIS
@@ -37,6 +59,10 @@ pub struct MessageEnvelope {
     /// Sender user ID
     pub from: String,
     
+    /// Tenant the sender belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    
     /// Recipient(s) - either single user ID or group ID
     /// For group messages, this is the group ID
     pub to: Vec<String>,
@@ -51,8 +77,56 @@ pub struct MessageEnvelope {
     /// Timestamp in milliseconds
     pub timestamp: i64,
     
+    /// Delivery priority
+    #[serde(default)]
+    pub priority: Priority,
+    
+    /// Message this one replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    
+    /// Per-conversation ordering sequence, assigned by the broker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    
+    /// Thread within the conversation this message replies in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    
+    /// Ordering sequence within the thread, assigned alongside `sequence`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_sequence: Option<u64>,
+    
+    /// Milliseconds a recipient may stay undelivered before push hand-off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_deadline_ms: Option<u64>,
+    
+    /// Conversation members captured at ingress for `KeyDistribution`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub member_snapshot: Option<Vec<String>>,
+    
     /// Optional metadata for routing
     pub metadata: HashMap<String, String>,
+    
+    /// Gateway-signed proof of the sender identity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<SenderAttestation>,
+}
+
+/// Gateway signature over (sender_id, connection_id, timestamp)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SenderAttestation {
+    /// Gateway that authenticated the sender
+    pub gateway_id: String,
+    
+    /// Client connection the message arrived on
+    pub connection_id: String,
+    
+    /// Signing timestamp in milliseconds
+    pub timestamp: i64,
+    
+    /// Base64 encoded ed25519 signature
+    pub signature: String,
 }
 
 /// Encrypted payload - treated as opaque bytes by broker
@@ -71,6 +145,10 @@ pub struct EncryptedPayload {
     
     /// Optional key ID for future key rotation
     pub key_id: Option<String>,
+    
+    /// MIME type of the plaintext, checked against the content type policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
 }
 
 /// Media metadata (encrypted in payload for E2EE, or plain for non-E2EE)
@@ -127,7 +205,40 @@ pub struct PresenceUpdate {
     pub platform: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+/// Presence refresh for every user on a gateway, sent after a reconnect
+/// instead of one heartbeat per user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceBulkRefresh {
+    pub gateway_id: String,
+    pub users: BulkUserSet,
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+}
+
+/// Users covered by a bulk refresh
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum BulkUserSet {
+    /// Explicit list of online users
+    Users(Vec<String>),
+    /// Every user the broker already has registered for the gateway in
+    /// these shards (inclusive ranges)
+    ShardRanges(Vec<(u32, u32)>),
+}
+
+/// Incremental presence changes for a gateway after a bulk refresh
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceDelta {
+    pub gateway_id: String,
+    #[serde(default)]
+    pub online: Vec<String>,
+    #[serde(default)]
+    pub offline: Vec<String>,
+    /// Timestamp in milliseconds
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum PresenceStatus {
     Online,
@@ -197,11 +308,20 @@ impl MessageEnvelope {
         Self {
             message_type,
             from,
+            tenant_id: None,
             to,
             payload,
             message_id: Uuid::new_v4().to_string(),
             timestamp: Utc::now().timestamp_millis(),
+            priority: Priority::Normal,
+            in_reply_to: None,
+            sequence: None,
+            thread_id: None,
+            thread_sequence: None,
+            delivery_deadline_ms: None,
+            member_snapshot: None,
             metadata: HashMap::new(),
+            attestation: None,
         }
     }
     
@@ -242,17 +362,40 @@ impl MessageEnvelope {
         self.message_type == MessageType::GroupMessage
     }
     
-    /// Get group ID if this is a group message
+    /// Check if this is a delivery or read receipt
+    pub fn is_receipt(&self) -> bool {
+        matches!(self.message_type, MessageType::Delivered | MessageType::Read)
+    }
+    
+    /// Get group ID if this is a group message or a group key distribution
     pub fn group_id(&self) -> Option<&str> {
-        if self.is_group_message() && !self.to.is_empty() {
+        let group_key_distribution = self.message_type == MessageType::KeyDistribution
+            && self.to.first().is_some_and(|to| is_valid_group_id(to));
+        if (self.is_group_message() || group_key_distribution) && !self.to.is_empty() {
             Some(&self.to[0])
         } else {
             None
         }
     }
+    
+    /// Conversation this message belongs to
+    ///
+    /// Group messages use the group ID; direct messages use a stable
+    /// `dm:{a}:{b}` key with the two user IDs sorted.
+    pub fn conversation_id(&self) -> String {
+        if let Some(group_id) = self.group_id() {
+            return group_id.to_string();
+        }
+        
+        match self.to.first() {
+            Some(to) if to.as_str() < self.from.as_str() => format!("dm:{}:{}", to, self.from),
+            Some(to) => format!("dm:{}:{}", self.from, to),
+            None => format!("dm:{}", self.from),
+        }
+    }
 }
 
-fn is_valid_user_id(id: &str) -> bool {
+pub(crate) fn is_valid_user_id(id: &str) -> bool {
     // Simple validation - in production use proper regex
     !id.is_empty() && id.len() <= 64 && !id.contains(' ')
 }
@@ -1,8 +1,69 @@
 use std::sync::Arc;
+use arc_swap::ArcSwapOption;
 use metrics::{describe_counter, describe_gauge, describe_histogram};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 use tracing::{info, error};
-use tokio::sync::RwLock;
+
+use crate::{
+    build_info::VersionInfo,
+    config::MetricsConfig,
+    kv_compaction::CompactionReport,
+    slo::SloTracker,
+    task::{spawn_traced, TaskContext},
+};
+
+/// `metrics::counter!`/`gauge!`/`histogram!` under a `MetricScope`'s prefix and constant labels
+macro_rules! scoped {
+    ($scope:expr, $kind:ident, $name:literal $(, $key:literal => $value:expr)* $(,)?) => {{
+        let scope: &MetricScope = &$scope;
+        #[allow(unused_mut)]
+        let mut labels = scope.labels.clone();
+        $(labels.push(metrics::Label::new($key, $value));)*
+        metrics::$kind!(scope.name($name), labels)
+    }};
+}
+
+/// Name prefix and constant labels applied to every series one `BrokerMetrics` emits
+///
+/// Lets several broker instances share a process-wide recorder with
+/// disjoint series. The default scope has neither, so metric names stay
+/// exactly as dashboards expect.
+#[derive(Debug, Clone, Default)]
+pub struct MetricScope {
+    prefix: Option<String>,
+    labels: Vec<metrics::Label>,
+}
+
+impl MetricScope {
+    /// Metric names become `{prefix}_{name}`
+    pub fn new(prefix: Option<&str>) -> Self {
+        Self {
+            prefix: prefix.filter(|p| !p.is_empty()).map(str::to_string),
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn from_config(config: &MetricsConfig) -> Self {
+        let mut scope = Self::new(config.namespace.as_deref());
+        for (key, value) in &config.constant_labels {
+            scope = scope.label(key.clone(), value.clone());
+        }
+        scope
+    }
+
+    /// Label added to every series, e.g. `broker_id`
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push(metrics::Label::new(key.into(), value.into()));
+        self
+    }
+
+    pub fn name(&self, name: &'static str) -> metrics::KeyName {
+        match &self.prefix {
+            Some(prefix) => format!("{}_{}", prefix, name).into(),
+            None => name.into(),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct BrokerMetrics {
@@ -10,6 +71,8 @@ pub struct BrokerMetrics {
 }
 
 struct BrokerMetricsInner {
+    scope: MetricScope,
+    
     // Incoming messages
     messages_received_total: metrics::Counter,
     messages_invalid_total: metrics::Counter,
@@ -18,7 +81,6 @@ struct BrokerMetricsInner {
     // Outgoing messages
     messages_sent_total: metrics::Counter,
     messages_failed_total: metrics::Counter,
-    messages_queued_total: metrics::Counter,
     
     // Fanout metrics
     fanout_operations_total: metrics::Counter,
@@ -28,18 +90,14 @@ struct BrokerMetricsInner {
     // Routing metrics
     routing_cache_hits: metrics::Counter,
     routing_cache_misses: metrics::Counter,
-    routing_shard_operations: metrics::CounterVec,
     
     // NATS metrics
     nats_published_total: metrics::Counter,
-    nats_consumed_total: metrics::Counter,
     nats_errors_total: metrics::Counter,
     
     // System metrics
     active_connections: metrics::Gauge,
     active_topics: metrics::Gauge,
-    memory_usage_bytes: metrics::Gauge,
-    cpu_usage_percent: metrics::Gauge,
     
     // Latency histograms
     ingress_latency_seconds: metrics::Histogram,
@@ -49,258 +107,2192 @@ struct BrokerMetricsInner {
     // Rate limiting
     rate_limit_hits_total: metrics::Counter,
     backpressure_events_total: metrics::Counter,
+    
+    // Degradation
+    degradation_level: metrics::Gauge,
+    
+    // SLO tracking, fed by the message counters above once attached
+    slo: ArcSwapOption<SloTracker>,
 }
 
 impl BrokerMetrics {
+    /// Unprefixed metrics with the historical names
     pub fn new() -> anyhow::Result<Self> {
+        Self::scoped(MetricScope::default())
+    }
+    
+    /// Metrics under `scope`; several scopes can coexist on one recorder, and
+    /// describing a name again only replaces its identical description
+    pub fn scoped(scope: MetricScope) -> anyhow::Result<Self> {
         // Describe metrics for Prometheus
         describe_counter!(
-            "broker_messages_received_total",
+            scope.name("broker_messages_received_total"),
             "Total number of messages received"
         );
         describe_counter!(
-            "broker_messages_invalid_total",
+            scope.name("broker_messages_invalid_total"),
             "Total number of invalid messages rejected"
         );
         describe_counter!(
-            "broker_messages_dropped_total",
+            scope.name("broker_messages_dropped_total"),
             "Total number of messages dropped due to backpressure"
         );
         
         describe_counter!(
-            "broker_messages_sent_total",
+            scope.name("broker_messages_sent_total"),
             "Total number of messages sent to recipients"
         );
         describe_counter!(
-            "broker_messages_failed_total",
+            scope.name("broker_messages_failed_total"),
             "Total number of messages that failed to send"
         );
-        describe_counter!(
-            "broker_messages_queued_total",
-            "Total number of messages queued for offline users"
-        );
         
         describe_counter!(
-            "broker_fanout_operations_total",
+            scope.name("broker_fanout_operations_total"),
             "Total number of fanout operations"
         );
         describe_histogram!(
-            "broker_fanout_latency_seconds",
-            "Fanout operation latency in seconds",
-            unit: metrics::Unit::Seconds,
-            buckets: [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+            scope.name("broker_fanout_latency_seconds"),
+            metrics::Unit::Seconds,
+            "Fanout operation latency in seconds"
         );
         describe_histogram!(
-            "broker_fanout_recipients_per_message",
-            "Number of recipients per fanout operation",
-            buckets: [1.0, 10.0, 100.0, 1000.0, 10000.0, 100000.0]
+            scope.name("broker_fanout_recipients_per_message"),
+            "Number of recipients per fanout operation"
         );
         
         describe_counter!(
-            "broker_routing_cache_hits",
+            scope.name("broker_routing_cache_hits"),
             "Routing cache hits"
         );
         describe_counter!(
-            "broker_routing_cache_misses",
+            scope.name("broker_routing_cache_misses"),
             "Routing cache misses"
         );
         
         describe_counter!(
-            "broker_nats_published_total",
+            scope.name("broker_nats_published_total"),
             "Total messages published to NATS"
         );
         describe_counter!(
-            "broker_nats_consumed_total",
-            "Total messages consumed from NATS"
-        );
-        describe_counter!(
-            "broker_nats_errors_total",
+            scope.name("broker_nats_errors_total"),
             "Total NATS communication errors"
         );
         
         describe_gauge!(
-            "broker_active_connections",
+            scope.name("broker_active_connections"),
             "Number of active connections to gateways"
         );
         describe_gauge!(
-            "broker_active_topics",
+            scope.name("broker_active_topics"),
             "Number of active routing topics"
         );
+        
+        describe_histogram!(
+            scope.name("broker_ingress_latency_seconds"),
+            metrics::Unit::Seconds,
+            "Ingress processing latency"
+        );
+        describe_histogram!(
+            scope.name("broker_egress_latency_seconds"),
+            metrics::Unit::Seconds,
+            "Egress processing latency"
+        );
+        
+        describe_counter!(
+            scope.name("broker_rate_limit_hits_total"),
+            "Total rate limit hits"
+        );
+        describe_counter!(
+            scope.name("broker_backpressure_events_total"),
+            "Total backpressure events"
+        );
+        
+        describe_counter!(
+            scope.name("broker_attestation_rejected_total"),
+            "Ingress messages rejected by sender attestation, by reason"
+        );
+        describe_counter!(
+            scope.name("broker_attestation_unverified_total"),
+            "Ingress messages accepted without attestation verification"
+        );
         describe_gauge!(
-            "broker_memory_usage_bytes",
-            "Memory usage in bytes"
+            scope.name("broker_attestation_unverified_share"),
+            "Share of ingress traffic accepted without attestation verification"
         );
+        
+        describe_counter!(
+            scope.name("broker_conversation_state_gc_total"),
+            "Per-conversation state entries removed by idle garbage collection"
+        );
+        
         describe_gauge!(
-            "broker_cpu_usage_percent",
-            "CPU usage percentage"
+            scope.name("broker_subscribe_streams"),
+            "Open gRPC Subscribe streams"
+        );
+        describe_counter!(
+            scope.name("broker_subscribe_idle_shrunk_total"),
+            "Subscribe stream buffers shrunk after the idle timeout"
+        );
+        describe_counter!(
+            scope.name("broker_subscribe_idle_closed_total"),
+            "Subscribe streams closed after the hard idle timeout"
+        );
+        describe_counter!(
+            scope.name("broker_subscribe_wedged_total"),
+            "Subscribe streams by wedge event (wedged, recovered, closed) from unanswered keepalive frames"
+        );
+        describe_counter!(
+            scope.name("broker_subscribe_wedged_skips_total"),
+            "Deliveries not queued on a stream because it is wedged"
+        );
+        describe_counter!(
+            scope.name("broker_subscribe_revived_total"),
+            "Idle-shrunk Subscribe streams revived by a new message"
         );
         
+        describe_gauge!(
+            scope.name("broker_conversations_in_digest"),
+            "Conversations over their message cap and receiving digests"
+        );
+        describe_counter!(
+            scope.name("broker_conversation_digested_total"),
+            "Messages held for a conversation digest instead of regular fanout"
+        );
         describe_histogram!(
-            "broker_ingress_latency_seconds",
-            "Ingress processing latency",
-            unit: metrics::Unit::Seconds,
-            buckets: [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5]
+            scope.name("broker_conversation_digest_ratio"),
+            "Messages per digest frame over a conversation's limit window"
+        );
+        
+        describe_counter!(
+            scope.name("broker_session_migrations_total"),
+            "Gateway session migrations by outcome"
+        );
+        describe_counter!(
+            scope.name("broker_session_migration_steps_total"),
+            "Session migration steps run, by step and result"
         );
         describe_histogram!(
-            "broker_egress_latency_seconds",
-            "Egress processing latency",
-            unit: metrics::Unit::Seconds,
-            buckets: [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5]
+            scope.name("broker_session_migration_step_duration_seconds"),
+            "Time spent in each session migration step"
+        );
+        describe_counter!(
+            scope.name("broker_session_migrations_resumed_total"),
+            "Session migrations resumed after a broker restart, by step"
+        );
+        describe_counter!(
+            scope.name("broker_session_migration_mirrored_total"),
+            "Deliveries mirrored to the target gateway during a session migration"
         );
         
         describe_counter!(
-            "broker_rate_limit_hits_total",
-            "Total rate limit hits"
+            scope.name("broker_content_type_rejections_total"),
+            "Ingress messages rejected by the content type policy, by type and code"
         );
+        
+        describe_gauge!(
+            scope.name("broker_background_allowance_fraction"),
+            "Share of publish capacity granted to background workers"
+        );
+        describe_gauge!(
+            scope.name("broker_background_publish_rate"),
+            "Background publishes per second by worker class"
+        );
+        describe_gauge!(
+            scope.name("broker_background_drain_eta_seconds"),
+            "Estimated time to drain a worker class backlog at its current rate"
+        );
+        
         describe_counter!(
-            "broker_backpressure_events_total",
-            "Total backpressure events"
+            scope.name("broker_publish_errors_classified_total"),
+            "Publish failures by error kind and chosen retry action"
         );
         
-        let inner = BrokerMetricsInner {
-            messages_received_total: metrics::counter!("broker_messages_received_total"),
-            messages_invalid_total: metrics::counter!("broker_messages_invalid_total"),
-            messages_dropped_total: metrics::counter!("broker_messages_dropped_total"),
-            
-            messages_sent_total: metrics::counter!("broker_messages_sent_total"),
-            messages_failed_total: metrics::counter!("broker_messages_failed_total"),
-            messages_queued_total: metrics::counter!("broker_messages_queued_total"),
-            
-            fanout_operations_total: metrics::counter!("broker_fanout_operations_total"),
-            fanout_latency_seconds: metrics::histogram!("broker_fanout_latency_seconds"),
-            fanout_recipients_per_message: metrics::histogram!("broker_fanout_recipients_per_message"),
-            
-            routing_cache_hits: metrics::counter!("broker_routing_cache_hits"),
-            routing_cache_misses: metrics::counter!("broker_routing_cache_misses"),
-            routing_shard_operations: metrics::counter_vec!("broker_routing_shard_operations", &["shard_id"]),
-            
-            nats_published_total: metrics::counter!("broker_nats_published_total"),
-            nats_consumed_total: metrics::counter!("broker_nats_consumed_total"),
-            nats_errors_total: metrics::counter!("broker_nats_errors_total"),
-            
-            active_connections: metrics::gauge!("broker_active_connections"),
-            active_topics: metrics::gauge!("broker_active_topics"),
-            memory_usage_bytes: metrics::gauge!("broker_memory_usage_bytes"),
-            cpu_usage_percent: metrics::gauge!("broker_cpu_usage_percent"),
-            
-            ingress_latency_seconds: metrics::histogram!("broker_ingress_latency_seconds"),
-            egress_latency_seconds: metrics::histogram!("broker_egress_latency_seconds"),
-            processing_latency_seconds: metrics::histogram!("broker_processing_latency_seconds"),
-            
-            rate_limit_hits_total: metrics::counter!("broker_rate_limit_hits_total"),
-            backpressure_events_total: metrics::counter!("broker_backpressure_events_total"),
-        };
+        describe_gauge!(
+            scope.name("broker_ingestion_pauses"),
+            "Active ingestion pauses by selector kind"
+        );
+        describe_counter!(
+            scope.name("broker_ingestion_paused_total"),
+            "Ingress messages held back by an ingestion pause"
+        );
+        describe_counter!(
+            scope.name("broker_ingestion_parked_total"),
+            "Paused messages moved to the holding stream"
+        );
+        describe_counter!(
+            scope.name("broker_ingestion_reinjected_total"),
+            "Parked messages re-published to ingress after resume"
+        );
         
-        Ok(Self {
-            inner: Arc::new(inner),
-        })
-    }
-    
-    pub fn record_message_received(&self) {
-        self.inner.messages_received_total.increment(1);
-    }
-    
-    pub fn record_message_invalid(&self) {
-        self.inner.messages_invalid_total.increment(1);
-    }
-    
-    pub fn record_message_dropped(&self, reason: &str) {
-        self.inner.messages_dropped_total.increment(1);
-        metrics::counter!("broker_messages_dropped_reason", "reason" => reason.to_string()).increment(1);
-    }
-    
-    pub fn record_message_sent(&self, recipient_count: u64) {
-        self.inner.messages_sent_total.increment(recipient_count);
-    }
-    
-    pub fn record_message_failed(&self, reason: &str) {
-        self.inner.messages_failed_total.increment(1);
-        metrics::counter!("broker_messages_failed_reason", "reason" => reason.to_string()).increment(1);
-    }
-    
-    pub fn record_fanout_operation(&self, recipient_count: u64, latency: f64) {
-        self.inner.fanout_operations_total.increment(1);
-        self.inner.fanout_latency_seconds.record(latency);
-        self.inner.fanout_recipients_per_message.record(recipient_count as f64);
-    }
-    
-    pub fn record_routing_cache_hit(&self) {
-        self.inner.routing_cache_hits.increment(1);
-    }
-    
-    pub fn record_routing_cache_miss(&self) {
-        self.inner.routing_cache_misses.increment(1);
-    }
-    
-    pub fn record_nats_published(&self, count: u64) {
-        self.inner.nats_published_total.increment(count);
-    }
-    
-    pub fn record_nats_error(&self, error: &str) {
-        self.inner.nats_errors_total.increment(1);
-        metrics::counter!("broker_nats_error_types", "error" => error.to_string()).increment(1);
-    }
-    
-    pub fn update_active_connections(&self, count: i64) {
-        self.inner.active_connections.set(count as f64);
-    }
-    
-    pub fn update_active_topics(&self, count: i64) {
-        self.inner.active_topics.set(count as f64);
-    }
-    
-    pub fn record_rate_limit_hit(&self, user_id: &str) {
-        self.inner.rate_limit_hits_total.increment(1);
-        metrics::counter!("broker_rate_limit_hits_user", "user_id" => user_id.to_string()).increment(1);
-    }
-    
-    pub fn record_backpressure_event(&self) {
-        self.inner.backpressure_events_total.increment(1);
-    }
-    
-    pub fn record_ingress_latency(&self, latency: f64) {
-        self.inner.ingress_latency_seconds.record(latency);
-    }
-    
-    pub fn record_egress_latency(&self, latency: f64) {
-        self.inner.egress_latency_seconds.record(latency);
-    }
-    
-    pub fn start_processing_timer(&self) -> ProcessingTimer {
-        ProcessingTimer::new()
-    }
-}
-
-pub struct ProcessingTimer {
-    start: std::time::Instant,
-}
-
-impl ProcessingTimer {
-    fn new() -> Self {
-        Self {
-            start: std::time::Instant::now(),
-        }
-    }
-    
-    pub fn record(self) {
-        let elapsed = self.start.elapsed();
-        metrics::histogram!("broker_processing_latency_seconds")
-            .record(elapsed.as_secs_f64());
-    }
-}
-
-pub fn start_metrics_server(addr: std::net::SocketAddr) -> anyhow::Result<()> {
-    let builder = PrometheusBuilder::new();
-    
-    tokio::spawn(async move {
-        match builder.with_http_listener(addr).install() {
-            Ok(_) => info!("Prometheus metrics server started on {}", addr),
-            Err(e) => error!("Failed to start metrics server: {}", e),
-        }
+        describe_counter!(
+            scope.name("broker_route_cache_lookups_total"),
+            "Route lookups by cache tier (positive_hit, warmed_hit, negative_hit, miss)"
+        );
         
-        // Keep the task alive
-        std::future::pending::<()>().await;
-    });
-    
-    Ok(())
-                      }
+        describe_counter!(
+            scope.name("broker_key_distributions_total"),
+            "Key distribution snapshot and accounting events by outcome"
+        );
+        
+        describe_counter!(
+            scope.name("broker_burst_credit_consumed_total"),
+            "Rate limit burst credit spent ahead of the regular bucket"
+        );
+        describe_counter!(
+            scope.name("broker_burst_credit_forfeited_total"),
+            "Burst credit discarded when its owner was rate limited"
+        );
+        describe_histogram!(
+            scope.name("broker_burst_credit_level"),
+            "Burst credit balance after each earning window"
+        );
+        
+        describe_counter!(
+            scope.name("broker_peer_forwards_total"),
+            "Ingress routing to owning brokers by outcome (forwarded, published, fallback_*)"
+        );
+        describe_histogram!(
+            scope.name("broker_peer_forward_seconds"),
+            metrics::Unit::Seconds,
+            "ForwardMessage RPC latency"
+        );
+        
+        describe_counter!(
+            scope.name("broker_envelope_samples_total"),
+            "Envelope samples by outcome (queued, dropped, published, publish_failed)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_subject_unmatched_total"),
+            "Messages dropped because their subject matched none of the consumer's patterns"
+        );
+        
+        describe_counter!(
+            scope.name("broker_cache_shrink_entries_total"),
+            "Cache entries dropped by memory pressure shrinks"
+        );
+        describe_counter!(
+            scope.name("broker_cache_shrink_bytes_total"),
+            "Bytes reclaimed by memory pressure shrinks, by cache"
+        );
+        
+        describe_counter!(
+            scope.name("broker_coalesced_requests_total"),
+            "Status queries by whether they computed, joined a computation in flight, were served from the freshness window or bypassed coalescing"
+        );
+        
+        describe_counter!(
+            scope.name("broker_kv_compaction_scanned_total"),
+            "KV keys scanned by compaction, by bucket"
+        );
+        describe_counter!(
+            scope.name("broker_kv_compaction_purged_total"),
+            "KV keys purged by compaction, by bucket"
+        );
+        describe_counter!(
+            scope.name("broker_kv_compaction_candidates_total"),
+            "KV keys qualifying for purge, by bucket and reason; kept_recent were spared by kv_compaction.min_age"
+        );
+        describe_gauge!(
+            scope.name("broker_kv_bucket_entries"),
+            "Keys left in the bucket after its last compaction"
+        );
+        describe_counter!(
+            scope.name("broker_kv_compaction_cycles_total"),
+            "Bucket compactions run by this broker, by outcome"
+        );
+        describe_counter!(
+            scope.name("broker_payload_intern_total"),
+            "Egress payload intern lookups: hit, miss, skipped_size or skipped_regulated"
+        );
+        describe_counter!(
+            scope.name("broker_payload_intern_saved_bytes_total"),
+            "Encoded payload bytes whose compression and checksum were reused from the intern cache"
+        );
+        describe_gauge!(
+            scope.name("broker_payload_intern_bytes"),
+            "Bytes of egress artifacts held by the payload intern cache"
+        );
+        describe_counter!(
+            scope.name("broker_soft_handoff_records_total"),
+            "Soft state handoff records published at drain or found at startup, by outcome"
+        );
+        describe_counter!(
+            scope.name("broker_soft_handoff_deadlines_total"),
+            "Delivery deadlines carried in soft state handoff records, by outcome"
+        );
+        describe_gauge!(
+            scope.name("broker_user_event_rings"),
+            "Users with a recent-event ring, as of the last sweep"
+        );
+        describe_counter!(
+            scope.name("broker_user_event_ring_evictions_total"),
+            "Recent-event rings taken from a still-active user because their shard was full"
+        );
+        describe_counter!(
+            scope.name("broker_tenant_operations_total"),
+            "Tenant provisioning, deletion and restore requests, by operation and outcome"
+        );
+        describe_gauge!(
+            scope.name("broker_tenants"),
+            "Provisioned tenants applied on this broker, soft-deleted ones included"
+        );
+        describe_histogram!(
+            scope.name("broker_nak_delay_seconds"),
+            metrics::Unit::Seconds,
+            "Delay carried by ingress NAKs, by reason"
+        );
+        describe_counter!(
+            scope.name("broker_nak_premature_redeliveries_total"),
+            "NAKed messages redelivered before their delay and NAKed again unprocessed"
+        );
+        describe_counter!(
+            scope.name("broker_history_cache_total"),
+            "History page cache lookups and invalidations, by outcome (hit, miss, invalidated, fill_discarded)"
+        );
+        describe_counter!(
+            scope.name("broker_shed_exempted_total"),
+            "Messages let through by a shed exemption, by the behavior that would have shed them"
+        );
+        describe_gauge!(
+            scope.name("broker_shed_exemptions"),
+            "Configured and runtime shed exemption selectors"
+        );
+        describe_histogram!(
+            scope.name("broker_first_message_latency_seconds"),
+            metrics::Unit::Seconds,
+            "Ingress to first delivery for a conversation's first message, by hinted or cold state"
+        );
+        describe_counter!(
+            scope.name("broker_conversation_hints_total"),
+            "ConversationWillStart hints by outcome (accepted, rejected, materialized, expired)"
+        );
+        describe_counter!(
+            scope.name("broker_webhook_replay_checks_total"),
+            "Webhook requests by replay check outcome (accepted, stale, future, replayed, malformed, cache_full)"
+        );
+        describe_gauge!(
+            scope.name("broker_webhook_nonces_cached"),
+            "Webhook nonces held per provider until their timestamps leave the window"
+        );
+        describe_counter!(
+            scope.name("broker_volume_deltas_total"),
+            "Per-conversation volume deltas by append outcome (appended, duplicate, failed, dropped)"
+        );
+        describe_counter!(
+            scope.name("broker_volume_delta_bytes_total"),
+            "Delivered bytes carried by volume deltas, by append outcome"
+        );
+        describe_counter!(
+            scope.name("broker_volume_overflow_bytes_total"),
+            "Delivered bytes folded into the overflow conversation past the per-interval cap"
+        );
+        describe_gauge!(
+            scope.name("broker_volume_unconfirmed_deltas"),
+            "Volume deltas awaiting a confirmed append"
+        );
+        describe_counter!(
+            scope.name("broker_audit_anchors_total"),
+            "Audit chain anchors appended, by whether publishing the head succeeded"
+        );
+        describe_counter!(
+            scope.name("broker_audit_verify_failures_total"),
+            "Audit chain verifications that found a broken link"
+        );
+        describe_gauge!(
+            scope.name("broker_gateway_interest_mismatch"),
+            "Registered gateways whose control subject has no interest or doesn't answer"
+        );
+        describe_counter!(
+            scope.name("broker_gateway_interest_probes_total"),
+            "Gateway interest probes by outcome (answered, no_interest, unresponsive)"
+        );
+        describe_counter!(
+            scope.name("broker_gateway_interest_evicted_users_total"),
+            "Users marked offline because their gateway was confirmed dead by interest reconciliation"
+        );
+        describe_counter!(
+            scope.name("broker_ingress_admission_total"),
+            "Ingress messages by source class and admission outcome (admitted, deferred)"
+        );
+        describe_gauge!(
+            scope.name("broker_ingress_admission_waiting"),
+            "Callers waiting for ingress admission, by source class"
+        );
+        describe_gauge!(
+            scope.name("broker_build_info"),
+            "1 for the running build and config fingerprint, labelled by version, commit, rustc and features"
+        );
+        describe_gauge!(
+            scope.name("broker_start_time_seconds"),
+            "When this broker process started, seconds since the epoch"
+        );
+        describe_counter!(
+            scope.name("broker_route_repairs_total"),
+            "Stale route repairs after no-responders publishes, by outcome (recovered, went_offline, rate_limited, ...)"
+        );
+        describe_histogram!(
+            scope.name("broker_nats_probe_rtt_seconds"),
+            "Round trip of idle-time PING probes on the NATS connection"
+        );
+        describe_counter!(
+            scope.name("broker_nats_probes_total"),
+            "NATS probe intervals by outcome (ok, slow, lost, suppressed)"
+        );
+        describe_gauge!(
+            scope.name("broker_nats_path_healthy"),
+            "1 unless consecutive slow or lost probes marked the NATS path degraded"
+        );
+        describe_counter!(
+            scope.name("broker_path_override_applied_total"),
+            "Delivery path decisions changed by an operator override, by feature and rule"
+        );
+        describe_gauge!(
+            scope.name("broker_path_overrides_active"),
+            "Path overrides currently set on this broker"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_unread_compute_seconds"),
+            metrics::Unit::Seconds,
+            "Time to compute one GetUnreadCounts request"
+        );
+        
+        describe_counter!(
+            scope.name("broker_unread_results_total"),
+            "Unread counts returned by kind (exact, approximate, clamped)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_unread_cache_lookups_total"),
+            "Unread count cache lookups by result (hit, miss)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_unread_skips_recorded_total"),
+            "Sequences recorded as never delivered to a user"
+        );
+        
+        describe_counter!(
+            scope.name("broker_redaction_renders_total"),
+            "Redacted egress renderings encoded, by trust tier"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_redaction_renderings_per_fanout"),
+            "Distinct redacted renderings encoded for one fanout"
+        );
+        
+        describe_counter!(
+            scope.name("broker_redaction_overflows_total"),
+            "Gateways given the overflow tier because a fanout hit its rendering limit"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_pending_queue_entries"),
+            "Entries in an operator-visible pending queue (retry, scheduled)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_pending_queue_operator_actions_total"),
+            "Operator interventions on pending queues by queue and action (cancelled, forced)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_home_routing_total"),
+            "Ingress by conversation home region and outcome (local, local_forwarded, forwarded)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_home_cache_lookups_total"),
+            "Conversation home cache lookups by result (hit, miss)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_home_assignments_total"),
+            "Conversation homes claimed by this broker, by region and reason (assigned, failover)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_deadline_abandoned_total"),
+            "Operations abandoned because the client's deadline passed, by RPC and the stage reached"
+        );
+        
+        describe_counter!(
+            scope.name("broker_egress_seals_total"),
+            "Egress payloads by seal outcome (sealed, plaintext, refused)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_egress_sessions_total"),
+            "Egress cipher sessions opened, by reason (new, age, messages)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_egress_key_pins_total"),
+            "Gateway egress key pin attempts by outcome (pinned, mismatch)"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_egress_pinned_gateways"),
+            "Gateways with a pinned egress key"
+        );
+        
+        describe_counter!(
+            scope.name("broker_persist_duplicates_suppressed_total"),
+            "JetStream appends acknowledged as duplicates of an earlier append, by stream"
+        );
+        
+        describe_counter!(
+            scope.name("broker_profiles_total"),
+            "On-demand profiles by kind (cpu, heap) and outcome (collected, busy, failed)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_rate_limit_deferrals_total"),
+            "Rate-limited High priority messages by outcome (deferred, released, overflow)"
+        );
+        describe_histogram!(
+            scope.name("broker_rate_limit_deferred_release_seconds"),
+            metrics::Unit::Seconds,
+            "Time deferred High priority messages waited for quota"
+        );
+        describe_gauge!(
+            scope.name("broker_rate_limit_deferred_messages"),
+            "High priority messages waiting in per-user deferred queues"
+        );
+        
+        describe_counter!(
+            scope.name("broker_compression_samples_total"),
+            "Egress payload samples for dictionary training by outcome (sampled, bucket_limit)"
+        );
+        describe_counter!(
+            scope.name("broker_compression_trainings_total"),
+            "Dictionary training runs by outcome (rotated, kept, failed)"
+        );
+        describe_histogram!(
+            scope.name("broker_compression_dictionary_gain"),
+            "Share of held-out bytes a trained dictionary saves over plain zstd"
+        );
+        describe_counter!(
+            scope.name("broker_compression_egress_total"),
+            "Egress payloads by compression outcome (compressed, no_gain, too_small, no_dictionary, gateway_missing, failed)"
+        );
+        describe_counter!(
+            scope.name("broker_compression_saved_bytes_total"),
+            "Egress bytes saved by dictionary compression"
+        );
+        describe_counter!(
+            scope.name("broker_compression_dictionary_requests_total"),
+            "Gateway requests for a missing dictionary by outcome (served, unknown)"
+        );
+        describe_gauge!(
+            scope.name("broker_compression_dictionaries"),
+            "Dictionaries in use, including replaced ones within their grace period"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_purged_entries_total"),
+            "Offline queue entries purged, by mode (soft keeps them in quarantine)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_restores_total"),
+            "Offline queue restores from quarantine"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_restored_entries_total"),
+            "Offline queue entries restored from quarantine"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_quarantine_reaped_total"),
+            "Quarantined offline entries deleted for good past retention"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_offline_quarantine_messages"),
+            "Entries in the offline quarantine stream, as of the last reap"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_offline_quarantine_bytes"),
+            "Size of the offline quarantine stream, as of the last reap"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_e2e_delivery_latency_seconds"),
+            metrics::Unit::Seconds,
+            "Ingest to device delivery latency from gateway receipts, corrected for gateway clock offset"
+        );
+        
+        describe_counter!(
+            scope.name("broker_e2e_latency_skipped_total"),
+            "Delivery receipts left out of the end-to-end histogram, by reason"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_gateway_clock_offset_seconds"),
+            "Estimated offset to add to a gateway's timestamps to reach broker time, including minimum transit"
+        );
+        
+        describe_counter!(
+            scope.name("broker_classification_candidates_total"),
+            "Messages sent to classification by pre-filter match (new_sender, high_fanout, abuse_score)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_classification_cache_total"),
+            "Classification verdict cache lookups by result"
+        );
+        
+        describe_counter!(
+            scope.name("broker_classification_actions_total"),
+            "Classified messages by tenant mode and action; shadow mode counts would_block"
+        );
+        
+        describe_counter!(
+            scope.name("broker_classifier_failures_total"),
+            "Classifier calls that timed out or failed; the message was delivered"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_classifier_call_duration_seconds"),
+            "Classifier call latency, including timed-out calls"
+        );
+        
+        describe_counter!(
+            scope.name("broker_config_overrides_total"),
+            "Runtime config overrides by outcome (applied, not_selected, rejected, reverted)"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_config_overrides_active"),
+            "Runtime config overrides in effect on this broker"
+        );
+        
+        describe_counter!(
+            scope.name("broker_recipient_exclusions_total"),
+            "Fanout candidates excluded, by reason"
+        );
+        
+        describe_counter!(
+            scope.name("broker_recipient_traces_total"),
+            "Messages with per-recipient decisions traced, by trigger (watched, sampled)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_abuse_signals_total"),
+            "Abuse signals scored by kind (rate_limited, rejected, block_suppressed, spam_flagged)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_abuse_transitions_total"),
+            "Automated or overridden abuse response changes by new response"
+        );
+        
+        describe_counter!(
+            scope.name("broker_abuse_records_evicted_total"),
+            "Abuse score records evicted from the bounded per-shard LRUs"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_abuse_restricted_users"),
+            "Users with an abuse response other than none, as of the last sweep"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_tls_cert_expiry_timestamp_seconds"),
+            "Earliest not_after in each configured certificate chain, by purpose"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_tls_cert_invalid"),
+            "1 when a configured certificate file can't be read or parsed, by purpose"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_tls_cert_check_failed"),
+            "1 when a certificate's key doesn't match or its chain doesn't verify, by purpose and check (key_pair, chain)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_exports_total"),
+            "Offline queue export streams by outcome (complete, interrupted, failed)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_export_entries_total"),
+            "Offline queue entries streamed to export clients"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_import_entries_total"),
+            "Imported offline queue entries by outcome (appended, duplicate, rejected)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_offline_export_deleted_total"),
+            "Source offline queue entries deleted after a confirmed export"
+        );
+        
+        describe_counter!(
+            scope.name("broker_messages_by_kind_total"),
+            "Ingress messages by traffic kind (message, typing, receipt, presence, key_distribution, control)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_message_bytes_by_kind_total"),
+            "Ingress payload bytes by traffic kind"
+        );
+        
+        describe_counter!(
+            scope.name("broker_kind_budget_rejections_total"),
+            "Ingress messages turned away for exceeding their kind's budget, by kind and action (defer, drop)"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_ingress_utilization_ratio"),
+            "Ingress arrival rate over limits.messages_per_second, per kind_budgets.window"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_lock_wait_seconds"),
+            "Time spent waiting to acquire instrumented locks, by lock (lock-metrics builds only)"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_lock_hold_seconds"),
+            "Time instrumented locks were held, by lock (lock-metrics builds only)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_lock_slow_waits_total"),
+            "Lock acquisitions that waited longer than lock_metrics.slow_wait_us, by lock"
+        );
+        
+        describe_counter!(
+            scope.name("broker_envelope_guard_rejections_total"),
+            "Envelopes rejected before parsing by guard (size, depth, array_length, string_length, header_count, header_size, malformed)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_conversation_lifecycle_events_total"),
+            "Conversation lifecycle events by event and outcome (queued, dropped, publish_failed)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_quota_feedback_sent_total"),
+            "Low-quota warnings published to NATS-sourced senders"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_cluster_config_divergence"),
+            "Live peers advertising a config fingerprint different from this broker's"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_maintenance_mode"),
+            "Whether the broker is in read-only maintenance mode (1 = maintenance)"
+        );
+        describe_counter!(
+            scope.name("broker_maintenance_rejections_total"),
+            "Mutations and ingress refused during maintenance by surface (ingress, grpc, rest)"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_slo_burn_rate"),
+            "Error budget burn rate per SLO over the 5m, 1h and 6h windows (1 = on budget)"
+        );
+        describe_gauge!(
+            scope.name("broker_slo_error_budget_remaining_ratio"),
+            "Share of the SLO period's error budget left; negative once overspent"
+        );
+        
+        describe_counter!(
+            scope.name("broker_archived_rejections_total"),
+            "Messages rejected because their conversation is archived"
+        );
+        describe_counter!(
+            scope.name("broker_archive_cache_lookups_total"),
+            "Archived conversation cache lookups by result (hit, miss)"
+        );
+        describe_gauge!(
+            scope.name("broker_archive_cache_entries"),
+            "Conversations with a cached archive state"
+        );
+        
+        describe_counter!(
+            scope.name("broker_api_auth_failures_total"),
+            "Rejected API requests by reason (missing, unknown, expired, insufficient_scope)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_group_delivery_suppressed_total"),
+            "Group message copies withheld by member state (invited, invitation_expired, banned)"
+        );
+        describe_counter!(
+            scope.name("broker_group_invitations_sent_total"),
+            "Invitation events sent in place of group traffic"
+        );
+        describe_counter!(
+            scope.name("broker_member_state_changes_total"),
+            "Group member state changes applied, by new state"
+        );
+        
+        describe_counter!(
+            scope.name("broker_egress_corruption_total"),
+            "Gateway-reported corrupted deliveries by where they were corrupted (broker, transit, unknown)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_thread_cache_lookups_total"),
+            "Thread participant cache lookups by result (hit, miss)"
+        );
+        describe_counter!(
+            scope.name("broker_thread_fanout_total"),
+            "Thread reply fanout by kind (full, marker, coalesced)"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_warmup_progress_ratio"),
+            "Fraction of startup warm-up groups resolved; 1 once warm-up has ended"
+        );
+        describe_counter!(
+            scope.name("broker_warmup_failures_total"),
+            "Startup warm-up groups the membership resolver failed on"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_dr_replication_lag_seconds"),
+            metrics::Unit::Seconds,
+            "Age of the oldest unshipped change (primary) or of the last applied batch (replica)"
+        );
+        describe_gauge!(
+            scope.name("broker_dr_replication_buffered"),
+            "Changes buffered while waiting for the DR link"
+        );
+        describe_counter!(
+            scope.name("broker_dr_resyncs_total"),
+            "Snapshot resyncs after replicated changes were lost"
+        );
+        describe_counter!(
+            scope.name("broker_dr_replicated_total"),
+            "Replicated state changes, by kind and direction (sent, applied)"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_route_activity_predicted"),
+            "Routing events predicted for the last full hour"
+        );
+        describe_gauge!(
+            scope.name("broker_route_activity_actual"),
+            "Routing events seen in the last full hour"
+        );
+        describe_counter!(
+            scope.name("broker_routes_warmed_total"),
+            "Predictive route refreshes, by kind (user, group) and outcome"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_receipts_per_frame"),
+            "Receipts carried per receipt publish, by format (single, bulk-v1)"
+        );
+        describe_counter!(
+            scope.name("broker_receipt_decode_errors_total"),
+            "Receipt publishes that could not be decoded, by format"
+        );
+        
+        describe_counter!(
+            scope.name("broker_metadata_sanitized_total"),
+            "Metadata values changed at ingress, by field and action"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_standby"),
+            "1 while the broker is a warm standby, 0 once active"
+        );
+        describe_histogram!(
+            scope.name("broker_standby_activation_seconds"),
+            "Time from activation trigger to full operation, by trigger"
+        );
+        describe_counter!(
+            scope.name("broker_standby_activations_total"),
+            "Standby activations, by trigger and whether they met the budget"
+        );
+        
+        describe_counter!(
+            scope.name("broker_control_commands_total"),
+            "Control commands seen, by command and outcome (executed, observed)"
+        );
+        
+        describe_histogram!(
+            scope.name("broker_message_size_inflation_ratio"),
+            "Egress frame size over ingress payload size per message"
+        );
+        describe_histogram!(
+            scope.name("broker_message_size_inflation_bytes"),
+            "Bytes added per message, by cause (envelope_overhead, encoding, headers)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_retry_attempts_total"),
+            "Attempts made through the shared backoff helper, by retry site"
+        );
+        
+        describe_counter!(
+            scope.name("broker_sequence_blocks_claimed_total"),
+            "Sequence blocks claimed from the KV high-water mark"
+        );
+        describe_counter!(
+            scope.name("broker_sequence_gap_total"),
+            "Sequence numbers skipped because another broker claimed past our block"
+        );
+        
+        describe_counter!(
+            scope.name("broker_delivery_ids_total"),
+            "Egress delivery IDs by outcome (new, reused on retry)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_consumer_handover_steps_total"),
+            "Consumer handover steps reached"
+        );
+        
+        describe_counter!(
+            scope.name("broker_config_clamped_total"),
+            "Config values clamped into their sane range at load time"
+        );
+        
+        describe_counter!(
+            scope.name("broker_delivery_handoffs_total"),
+            "Recipients handed off to the fallback channel after missing their deadline"
+        );
+        describe_counter!(
+            scope.name("broker_delivery_late_acks_total"),
+            "Gateway delivery acks that arrived after a hand-off"
+        );
+        describe_gauge!(
+            scope.name("broker_delivery_timers"),
+            "Armed delivery deadline timers"
+        );
+        describe_gauge!(
+            scope.name("broker_delivery_hot_statuses"),
+            "Per-recipient delivery statuses held in memory"
+        );
+        describe_counter!(
+            scope.name("broker_delivery_status_compactions_total"),
+            "Messages whose delivery statuses were compacted to the persisted tier"
+        );
+        
+        describe_counter!(
+            scope.name("broker_task_panics_total"),
+            "Background task panics by subsystem"
+        );
+        describe_counter!(
+            scope.name("broker_task_restarts_total"),
+            "Supervised background task restarts by subsystem"
+        );
+        
+        describe_counter!(
+            scope.name("broker_read_horizon_updates_total"),
+            "Read horizon updates by outcome (queued, written, stale)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_migration_forwarded_total"),
+            "Old-stream messages copied into the new subject hierarchy"
+        );
+        describe_gauge!(
+            scope.name("broker_migration_residual_messages"),
+            "Messages still pending on old subjects during a stream migration"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_fanout_batch_size"),
+            "Effective fanout batch size by destination class"
+        );
+        
+        describe_counter!(
+            scope.name("broker_outbox_rows_total"),
+            "Outbox rows processed by outcome"
+        );
+        describe_gauge!(
+            scope.name("broker_outbox_lag_seconds"),
+            metrics::Unit::Seconds,
+            "Age of the oldest unprocessed outbox row"
+        );
+        
+        describe_counter!(
+            scope.name("broker_presence_kv_writes_total"),
+            "Presence KV writes by path (individual, bulk, delta, replicated)"
+        );
+        describe_histogram!(
+            scope.name("broker_presence_bulk_refresh_size"),
+            "Users covered by a bulk presence refresh"
+        );
+        describe_histogram!(
+            scope.name("broker_presence_bulk_refresh_seconds"),
+            metrics::Unit::Seconds,
+            "Bulk presence refresh processing duration"
+        );
+        describe_counter!(
+            scope.name("broker_presence_bulk_superseded_total"),
+            "Bulk presence refreshes cancelled by a newer refresh from the same gateway"
+        );
+        
+        describe_counter!(
+            scope.name("broker_stream_reads_total"),
+            "Read-only stream operations by operation and serving stream"
+        );
+        describe_gauge!(
+            scope.name("broker_mirror_lag_sequences"),
+            "Sequences the read mirror trails the primary stream by"
+        );
+        
+        describe_counter!(
+            scope.name("broker_priority_inheritance_total"),
+            "Reply priority inheritance decisions (promoted, unchanged, cache_miss)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_policy_decisions_total"),
+            "Ingress policy decisions by rule and effect"
+        );
+        
+        describe_counter!(
+            scope.name("broker_membership_anomalies_total"),
+            "Membership resolution anomalies (capped, delta_flagged, malformed_member, invalidated_during_resolve)"
+        );
+        
+        describe_counter!(
+            scope.name("broker_transactions_total"),
+            "SendTransaction batches by outcome"
+        );
+        
+        describe_gauge!(
+            scope.name("broker_degradation_level"),
+            "Active degradation level (0 = normal, 1 = conserve, 2 = emergency)"
+        );
+        describe_counter!(
+            scope.name("broker_degradation_level_changes_total"),
+            "Total degradation level changes by target level"
+        );
+        
+        let inner = BrokerMetricsInner {
+            scope: scope.clone(),
+            
+            messages_received_total: scoped!(scope, counter, "broker_messages_received_total"),
+            messages_invalid_total: scoped!(scope, counter, "broker_messages_invalid_total"),
+            messages_dropped_total: scoped!(scope, counter, "broker_messages_dropped_total"),
+            
+            messages_sent_total: scoped!(scope, counter, "broker_messages_sent_total"),
+            messages_failed_total: scoped!(scope, counter, "broker_messages_failed_total"),
+            
+            fanout_operations_total: scoped!(scope, counter, "broker_fanout_operations_total"),
+            fanout_latency_seconds: scoped!(scope, histogram, "broker_fanout_latency_seconds"),
+            fanout_recipients_per_message: scoped!(scope, histogram, "broker_fanout_recipients_per_message"),
+            
+            routing_cache_hits: scoped!(scope, counter, "broker_routing_cache_hits"),
+            routing_cache_misses: scoped!(scope, counter, "broker_routing_cache_misses"),
+            
+            nats_published_total: scoped!(scope, counter, "broker_nats_published_total"),
+            nats_errors_total: scoped!(scope, counter, "broker_nats_errors_total"),
+            
+            active_connections: scoped!(scope, gauge, "broker_active_connections"),
+            active_topics: scoped!(scope, gauge, "broker_active_topics"),
+            
+            ingress_latency_seconds: scoped!(scope, histogram, "broker_ingress_latency_seconds"),
+            egress_latency_seconds: scoped!(scope, histogram, "broker_egress_latency_seconds"),
+            processing_latency_seconds: scoped!(scope, histogram, "broker_processing_latency_seconds"),
+            
+            rate_limit_hits_total: scoped!(scope, counter, "broker_rate_limit_hits_total"),
+            backpressure_events_total: scoped!(scope, counter, "broker_backpressure_events_total"),
+            
+            degradation_level: scoped!(scope, gauge, "broker_degradation_level"),
+            
+            slo: ArcSwapOption::empty(),
+        };
+        
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+    
+    pub fn record_message_received(&self) {
+        self.inner.messages_received_total.increment(1);
+        if let Some(slo) = &*self.inner.slo.load() {
+            slo.observe_ingress(false);
+        }
+    }
+    
+    pub fn record_message_invalid(&self) {
+        self.inner.messages_invalid_total.increment(1);
+    }
+    
+    pub fn record_message_dropped(&self, reason: &str) {
+        self.inner.messages_dropped_total.increment(1);
+        if let Some(slo) = &*self.inner.slo.load() {
+            slo.observe_ingress(true);
+        }
+        scoped!(self.inner.scope, counter, "broker_messages_dropped_reason", "reason" => reason.to_string()).increment(1);
+    }
+    
+    pub fn record_message_sent(&self, recipient_count: u64) {
+        self.inner.messages_sent_total.increment(recipient_count);
+    }
+    
+    pub fn record_message_failed(&self, reason: &str) {
+        self.inner.messages_failed_total.increment(1);
+        if let Some(slo) = &*self.inner.slo.load() {
+            slo.observe_delivery_failed();
+        }
+        scoped!(self.inner.scope, counter, "broker_messages_failed_reason", "reason" => reason.to_string()).increment(1);
+    }
+    
+    pub fn record_fanout_operation(&self, recipient_count: u64, latency: f64) {
+        self.inner.fanout_operations_total.increment(1);
+        self.inner.fanout_latency_seconds.record(latency);
+        self.inner.fanout_recipients_per_message.record(recipient_count as f64);
+    }
+    
+    pub fn record_routing_cache_hit(&self) {
+        self.inner.routing_cache_hits.increment(1);
+    }
+    
+    pub fn record_routing_cache_miss(&self) {
+        self.inner.routing_cache_misses.increment(1);
+    }
+    
+    pub fn record_nats_published(&self, count: u64) {
+        self.inner.nats_published_total.increment(count);
+    }
+    
+    pub fn record_nats_error(&self, error: &str) {
+        self.inner.nats_errors_total.increment(1);
+        scoped!(self.inner.scope, counter, "broker_nats_error_types", "error" => error.to_string()).increment(1);
+    }
+    
+    pub fn update_active_connections(&self, count: i64) {
+        self.inner.active_connections.set(count as f64);
+    }
+    
+    pub fn update_active_topics(&self, count: i64) {
+        self.inner.active_topics.set(count as f64);
+    }
+    
+    pub fn record_rate_limit_hit(&self, user_id: &str) {
+        self.inner.rate_limit_hits_total.increment(1);
+        scoped!(self.inner.scope, counter, "broker_rate_limit_hits_user", "user_id" => user_id.to_string()).increment(1);
+    }
+    
+    pub fn record_backpressure_event(&self) {
+        self.inner.backpressure_events_total.increment(1);
+    }
+    
+    pub fn record_ingress_latency(&self, latency: f64) {
+        self.inner.ingress_latency_seconds.record(latency);
+    }
+    
+    pub fn record_egress_latency(&self, latency: f64) {
+        self.inner.egress_latency_seconds.record(latency);
+        if let Some(slo) = &*self.inner.slo.load() {
+            slo.observe_delivery(latency);
+        }
+    }
+    
+    pub fn record_attestation_rejected(&self, reason: &str) {
+        scoped!(self.inner.scope, counter, "broker_attestation_rejected_total", "reason" => reason.to_string()).increment(1);
+    }
+    
+    pub fn record_attestation_unverified(&self) {
+        scoped!(self.inner.scope, counter, "broker_attestation_unverified_total").increment(1);
+    }
+    
+    pub fn update_attestation_unverified_share(&self, share: f64) {
+        scoped!(self.inner.scope, gauge, "broker_attestation_unverified_share").set(share);
+    }
+    
+    pub fn record_conversation_state_gc(&self, store: &str) {
+        scoped!(self.inner.scope, counter, "broker_conversation_state_gc_total", "store" => store.to_string()).increment(1);
+    }
+    
+    pub fn update_subscribe_streams(&self, count: i64) {
+        scoped!(self.inner.scope, gauge, "broker_subscribe_streams").set(count as f64);
+    }
+    
+    pub fn record_subscribe_idle_shrunk(&self) {
+        scoped!(self.inner.scope, counter, "broker_subscribe_idle_shrunk_total").increment(1);
+    }
+    
+    pub fn record_subscribe_idle_closed(&self) {
+        scoped!(self.inner.scope, counter, "broker_subscribe_idle_closed_total").increment(1);
+    }
+    
+    pub fn record_subscribe_wedged(&self, event: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_subscribe_wedged_total", "event" => event).increment(1);
+    }
+    
+    pub fn record_subscribe_wedged_skip(&self) {
+        scoped!(self.inner.scope, counter, "broker_subscribe_wedged_skips_total").increment(1);
+    }
+    
+    pub fn record_subscribe_revived(&self) {
+        scoped!(self.inner.scope, counter, "broker_subscribe_revived_total").increment(1);
+    }
+    
+    pub fn update_conversations_in_digest(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_conversations_in_digest").set(count as f64);
+    }
+    
+    pub fn record_conversation_digested(&self) {
+        scoped!(self.inner.scope, counter, "broker_conversation_digested_total").increment(1);
+    }
+    
+    pub fn record_conversation_digest_ratio(&self, ratio: f64) {
+        scoped!(self.inner.scope, histogram, "broker_conversation_digest_ratio").record(ratio);
+    }
+    
+    pub fn record_session_migration(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_session_migrations_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_session_migration_step(&self, step: &str, result: &str, duration_seconds: f64) {
+        scoped!(self.inner.scope, counter, 
+            "broker_session_migration_steps_total",
+            "step" => step.to_string(),
+            "result" => result.to_string()
+        )
+        .increment(1);
+        scoped!(self.inner.scope, histogram, "broker_session_migration_step_duration_seconds", "step" => step.to_string())
+            .record(duration_seconds);
+    }
+    
+    pub fn record_session_migration_resumed(&self, step: &str) {
+        scoped!(self.inner.scope, counter, "broker_session_migrations_resumed_total", "step" => step.to_string()).increment(1);
+    }
+    
+    pub fn record_session_migration_mirrored(&self) {
+        scoped!(self.inner.scope, counter, "broker_session_migration_mirrored_total").increment(1);
+    }
+    
+    pub fn record_content_type_rejection(&self, content_type: &str, code: &str) {
+        scoped!(self.inner.scope, counter, 
+            "broker_content_type_rejections_total",
+            "content_type" => content_type.to_string(),
+            "code" => code.to_string()
+        )
+        .increment(1);
+    }
+    
+    pub fn update_background_allowance(&self, fraction: f64) {
+        scoped!(self.inner.scope, gauge, "broker_background_allowance_fraction").set(fraction);
+    }
+    
+    pub fn update_background_class(&self, class: &str, rate: f64, drain_eta_seconds: f64) {
+        scoped!(self.inner.scope, gauge, "broker_background_publish_rate", "class" => class.to_string()).set(rate);
+        scoped!(self.inner.scope, gauge, "broker_background_drain_eta_seconds", "class" => class.to_string()).set(drain_eta_seconds);
+    }
+    
+    pub fn record_publish_error_classified(&self, kind: &str, action: &str) {
+        scoped!(self.inner.scope, counter, 
+            "broker_publish_errors_classified_total",
+            "error_kind" => kind.to_string(),
+            "action" => action.to_string()
+        )
+        .increment(1);
+    }
+    
+    pub fn update_ingestion_pauses(&self, kind: &str, active: usize) {
+        scoped!(self.inner.scope, gauge, "broker_ingestion_pauses", "kind" => kind.to_string()).set(active as f64);
+    }
+    
+    pub fn record_ingestion_paused(&self, kind: &str) {
+        scoped!(self.inner.scope, counter, "broker_ingestion_paused_total", "kind" => kind.to_string()).increment(1);
+    }
+    
+    pub fn record_ingestion_parked(&self, kind: &str) {
+        scoped!(self.inner.scope, counter, "broker_ingestion_parked_total", "kind" => kind.to_string()).increment(1);
+    }
+    
+    pub fn record_ingestion_reinjected(&self, kind: &str) {
+        scoped!(self.inner.scope, counter, "broker_ingestion_reinjected_total", "kind" => kind.to_string()).increment(1);
+    }
+    
+    pub fn record_route_cache_lookup(&self, tier: &str) {
+        scoped!(self.inner.scope, counter, "broker_route_cache_lookups_total", "tier" => tier.to_string()).increment(1);
+    }
+    
+    pub fn record_key_distribution(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_key_distributions_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_burst_credit_consumed(&self, credit: f64) {
+        scoped!(self.inner.scope, counter, "broker_burst_credit_consumed_total").increment(credit as u64);
+    }
+    
+    pub fn record_burst_credit_forfeited(&self, credit: f64) {
+        scoped!(self.inner.scope, counter, "broker_burst_credit_forfeited_total").increment(credit as u64);
+    }
+    
+    pub fn record_burst_credit_level(&self, credit: f64) {
+        scoped!(self.inner.scope, histogram, "broker_burst_credit_level").record(credit);
+    }
+    
+    pub fn record_peer_forward(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_peer_forwards_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_peer_forward_latency(&self, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_peer_forward_seconds").record(seconds);
+    }
+    
+    pub fn record_envelope_sample(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_envelope_samples_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_subject_unmatched(&self, consumer: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_subject_unmatched_total", "consumer" => consumer).increment(1);
+    }
+    
+    pub fn record_cache_shrink(&self, cache: &'static str, entries: usize, bytes: usize) {
+        scoped!(self.inner.scope, counter, "broker_cache_shrink_entries_total", "cache" => cache).increment(entries as u64);
+        scoped!(self.inner.scope, counter, "broker_cache_shrink_bytes_total", "cache" => cache).increment(bytes as u64);
+    }
+    
+    pub fn record_coalesce(&self, endpoint: &'static str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_coalesced_requests_total", "endpoint" => endpoint, "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_kv_compaction(&self, report: &CompactionReport) {
+        let bucket = report.bucket.clone();
+        scoped!(self.inner.scope, counter, "broker_kv_compaction_scanned_total", "bucket" => bucket.clone()).increment(report.scanned);
+        scoped!(self.inner.scope, counter, "broker_kv_compaction_purged_total", "bucket" => bucket.clone()).increment(report.purged);
+        scoped!(self.inner.scope, counter, "broker_kv_compaction_candidates_total", "bucket" => bucket.clone(), "reason" => "expired").increment(report.expired);
+        scoped!(self.inner.scope, counter, "broker_kv_compaction_candidates_total", "bucket" => bucket.clone(), "reason" => "tombstoned").increment(report.tombstoned);
+        scoped!(self.inner.scope, counter, "broker_kv_compaction_candidates_total", "bucket" => bucket.clone(), "reason" => "kept_recent").increment(report.kept_recent);
+        scoped!(self.inner.scope, gauge, "broker_kv_bucket_entries", "bucket" => bucket).set(report.scanned.saturating_sub(report.purged) as f64);
+    }
+    
+    pub fn record_kv_compaction_cycle(&self, bucket: &str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_kv_compaction_cycles_total", "bucket" => bucket.to_string(), "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_payload_intern(&self, outcome: &'static str, saved_bytes: usize) {
+        scoped!(self.inner.scope, counter, "broker_payload_intern_total", "outcome" => outcome).increment(1);
+        if saved_bytes > 0 {
+            scoped!(self.inner.scope, counter, "broker_payload_intern_saved_bytes_total").increment(saved_bytes as u64);
+        }
+    }
+    
+    pub fn update_payload_intern_bytes(&self, bytes: usize) {
+        scoped!(self.inner.scope, gauge, "broker_payload_intern_bytes").set(bytes as f64);
+    }
+    
+    pub fn record_soft_handoff(&self, outcome: &'static str, deadlines: usize) {
+        scoped!(self.inner.scope, counter, "broker_soft_handoff_records_total", "outcome" => outcome).increment(1);
+        scoped!(self.inner.scope, counter, "broker_soft_handoff_deadlines_total", "outcome" => outcome).increment(deadlines as u64);
+    }
+    
+    pub fn update_user_event_rings(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_user_event_rings").set(count as f64);
+    }
+    
+    pub fn record_user_event_ring_evicted(&self) {
+        scoped!(self.inner.scope, counter, "broker_user_event_ring_evictions_total").increment(1);
+    }
+    
+    pub fn record_tenant_operation(&self, operation: &'static str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_tenant_operations_total", "operation" => operation, "outcome" => outcome).increment(1);
+    }
+    
+    pub fn update_tenants(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_tenants").set(count as f64);
+    }
+    
+    pub fn record_nak_delay(&self, reason: &'static str, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_nak_delay_seconds", "reason" => reason).record(seconds);
+    }
+    
+    pub fn record_nak_premature_redelivery(&self) {
+        scoped!(self.inner.scope, counter, "broker_nak_premature_redeliveries_total").increment(1);
+    }
+    
+    pub fn record_history_cache(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_history_cache_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_shed_exempted(&self, behavior: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_shed_exempted_total", "behavior" => behavior).increment(1);
+    }
+    
+    pub fn update_shed_exemptions(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_shed_exemptions").set(count as f64);
+    }
+    
+    pub fn record_first_message_latency(&self, warmth: &'static str, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_first_message_latency_seconds", "warmth" => warmth).record(seconds);
+    }
+    
+    pub fn record_conversation_hint(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_conversation_hints_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_webhook_replay_check(&self, provider: &str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_webhook_replay_checks_total", "provider" => provider.to_string(), "outcome" => outcome).increment(1);
+    }
+    
+    pub fn update_webhook_nonces(&self, provider: &str, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_webhook_nonces_cached", "provider" => provider.to_string()).set(count as f64);
+    }
+    
+    pub fn record_volume_delta(&self, outcome: &'static str, bytes: u64) {
+        scoped!(self.inner.scope, counter, "broker_volume_deltas_total", "outcome" => outcome).increment(1);
+        scoped!(self.inner.scope, counter, "broker_volume_delta_bytes_total", "outcome" => outcome).increment(bytes);
+    }
+    
+    pub fn record_volume_overflow(&self, bytes: usize) {
+        scoped!(self.inner.scope, counter, "broker_volume_overflow_bytes_total").increment(bytes as u64);
+    }
+    
+    pub fn update_volume_unconfirmed(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_volume_unconfirmed_deltas").set(count as f64);
+    }
+    
+    pub fn record_audit_anchor(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_audit_anchors_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_audit_verify_failure(&self) {
+        scoped!(self.inner.scope, counter, "broker_audit_verify_failures_total").increment(1);
+    }
+    
+    pub fn update_gateway_interest_mismatch(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_gateway_interest_mismatch").set(count as f64);
+    }
+    
+    pub fn record_gateway_interest_probe(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_gateway_interest_probes_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_gateway_interest_eviction(&self, users: usize) {
+        scoped!(self.inner.scope, counter, "broker_gateway_interest_evicted_users_total").increment(users as u64);
+    }
+    
+    pub fn record_ingress_admission(&self, class: &'static str, outcome: &'static str, count: u32) {
+        scoped!(self.inner.scope, counter, "broker_ingress_admission_total", "class" => class, "outcome" => outcome).increment(count as u64);
+    }
+    
+    pub fn update_ingress_admission_waiting(&self, class: &'static str, waiting: u64) {
+        scoped!(self.inner.scope, gauge, "broker_ingress_admission_waiting", "class" => class).set(waiting as f64);
+    }
+    
+    pub fn update_build_info(&self, info: &VersionInfo, current: bool) {
+        scoped!(
+            self.inner.scope,
+            gauge,
+            "broker_build_info",
+            "version" => info.version,
+            "git_commit" => info.git_commit,
+            "rustc" => info.rustc,
+            "features" => info.features,
+            "config_fingerprint" => info.config_fingerprint.clone(),
+        )
+        .set(if current { 1.0 } else { 0.0 });
+    }
+    
+    pub fn update_start_time(&self, seconds: i64) {
+        scoped!(self.inner.scope, gauge, "broker_start_time_seconds").set(seconds as f64);
+    }
+    
+    pub fn record_route_repair(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_route_repairs_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_nats_probe_rtt(&self, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_nats_probe_rtt_seconds").record(seconds);
+    }
+    
+    pub fn record_nats_probe(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_nats_probes_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn update_nats_path_healthy(&self, healthy: bool) {
+        scoped!(self.inner.scope, gauge, "broker_nats_path_healthy").set(if healthy { 1.0 } else { 0.0 });
+    }
+    
+    pub fn record_path_override_applied(&self, feature: &'static str, rule_id: &str) {
+        scoped!(self.inner.scope, counter, "broker_path_override_applied_total", "feature" => feature, "rule" => rule_id.to_string()).increment(1);
+    }
+    
+    pub fn update_path_overrides(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_path_overrides_active").set(count as f64);
+    }
+    
+    pub fn record_unread_latency(&self, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_unread_compute_seconds").record(seconds);
+    }
+    
+    pub fn record_unread_result(&self, kind: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_unread_results_total", "kind" => kind).increment(1);
+    }
+    
+    pub fn record_unread_cache_lookup(&self, result: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_unread_cache_lookups_total", "result" => result).increment(1);
+    }
+    
+    pub fn record_unread_skip(&self) {
+        scoped!(self.inner.scope, counter, "broker_unread_skips_recorded_total").increment(1);
+    }
+    
+    pub fn record_redaction_render(&self, tier: &str) {
+        scoped!(self.inner.scope, counter, "broker_redaction_renders_total", "tier" => tier.to_string()).increment(1);
+    }
+    
+    pub fn record_redaction_renderings(&self, renderings: usize) {
+        scoped!(self.inner.scope, histogram, "broker_redaction_renderings_per_fanout").record(renderings as f64);
+    }
+    
+    pub fn record_redaction_overflow(&self) {
+        scoped!(self.inner.scope, counter, "broker_redaction_overflows_total").increment(1);
+    }
+    
+    pub fn update_pending_queue_size(&self, queue: &'static str, entries: usize) {
+        scoped!(self.inner.scope, gauge, "broker_pending_queue_entries", "queue" => queue).set(entries as f64);
+    }
+    
+    pub fn record_pending_queue_action(&self, queue: &'static str, action: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_pending_queue_operator_actions_total", "queue" => queue, "action" => action).increment(1);
+    }
+    
+    pub fn record_home_routing(&self, region: &str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_home_routing_total", "region" => region.to_string(), "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_home_cache_lookup(&self, result: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_home_cache_lookups_total", "result" => result).increment(1);
+    }
+    
+    pub fn record_home_assignment(&self, region: &str, reason: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_home_assignments_total", "region" => region.to_string(), "reason" => reason).increment(1);
+    }
+    
+    pub fn record_deadline_abandoned(&self, rpc: &'static str, stage: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_deadline_abandoned_total", "rpc" => rpc, "stage" => stage).increment(1);
+    }
+    
+    pub fn record_egress_seal(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_egress_seals_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_egress_session(&self, reason: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_egress_sessions_total", "reason" => reason).increment(1);
+    }
+    
+    pub fn record_egress_key_pin(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_egress_key_pins_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn update_egress_pinned_gateways(&self, pinned: usize) {
+        scoped!(self.inner.scope, gauge, "broker_egress_pinned_gateways").set(pinned as f64);
+    }
+    
+    pub fn record_persist_duplicate_suppressed(&self, stream: &str) {
+        scoped!(self.inner.scope, counter, "broker_persist_duplicates_suppressed_total", "stream" => stream.to_string()).increment(1);
+    }
+    
+    pub fn record_profile(&self, kind: &'static str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_profiles_total", "kind" => kind, "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_rate_limit_deferral(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_rate_limit_deferrals_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_deferred_release_latency(&self, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_rate_limit_deferred_release_seconds").record(seconds);
+    }
+    
+    pub fn update_rate_limit_deferred(&self, waiting: usize) {
+        scoped!(self.inner.scope, gauge, "broker_rate_limit_deferred_messages").set(waiting as f64);
+    }
+    
+    pub fn record_compression_sample(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_compression_samples_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_compression_training(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_compression_trainings_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_compression_dictionary_gain(&self, gain: f64) {
+        scoped!(self.inner.scope, histogram, "broker_compression_dictionary_gain").record(gain);
+    }
+    
+    pub fn record_compression_egress(&self, outcome: &'static str, saved_bytes: usize) {
+        scoped!(self.inner.scope, counter, "broker_compression_egress_total", "outcome" => outcome).increment(1);
+        if saved_bytes > 0 {
+            scoped!(self.inner.scope, counter, "broker_compression_saved_bytes_total").increment(saved_bytes as u64);
+        }
+    }
+    
+    pub fn record_compression_request(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_compression_dictionary_requests_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn update_compression_dictionaries(&self, active: usize) {
+        scoped!(self.inner.scope, gauge, "broker_compression_dictionaries").set(active as f64);
+    }
+    
+    pub fn record_offline_purge(&self, mode: &'static str, entries: usize) {
+        scoped!(self.inner.scope, counter, "broker_offline_purged_entries_total", "mode" => mode).increment(entries as u64);
+    }
+    
+    pub fn record_offline_restore(&self, entries: u64) {
+        scoped!(self.inner.scope, counter, "broker_offline_restores_total").increment(1);
+        scoped!(self.inner.scope, counter, "broker_offline_restored_entries_total").increment(entries);
+    }
+    
+    pub fn record_offline_quarantine_reaped(&self, entries: u64) {
+        scoped!(self.inner.scope, counter, "broker_offline_quarantine_reaped_total").increment(entries);
+    }
+    
+    pub fn update_offline_quarantine_size(&self, messages: u64, bytes: u64) {
+        scoped!(self.inner.scope, gauge, "broker_offline_quarantine_messages").set(messages as f64);
+        scoped!(self.inner.scope, gauge, "broker_offline_quarantine_bytes").set(bytes as f64);
+    }
+    
+    pub fn record_e2e_delivery_latency(&self, priority: &'static str, path: &'static str, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_e2e_delivery_latency_seconds", "priority" => priority, "path" => path)
+            .record(seconds);
+        if let Some(slo) = &*self.inner.slo.load() {
+            slo.observe_e2e_delivery(seconds);
+        }
+    }
+    
+    pub fn record_e2e_latency_skipped(&self, reason: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_e2e_latency_skipped_total", "reason" => reason).increment(1);
+    }
+    
+    pub fn update_gateway_clock_offset(&self, gateway_id: &str, seconds: f64) {
+        scoped!(self.inner.scope, gauge, "broker_gateway_clock_offset_seconds", "gateway" => gateway_id.to_string()).set(seconds);
+    }
+    
+    pub fn record_classification_candidate(&self, suspicion: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_classification_candidates_total", "suspicion" => suspicion).increment(1);
+    }
+    
+    pub fn record_classification_cache(&self, result: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_classification_cache_total", "result" => result).increment(1);
+    }
+    
+    pub fn record_classification_action(&self, mode: &'static str, action: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_classification_actions_total", "mode" => mode, "action" => action).increment(1);
+    }
+    
+    pub fn record_classifier_failure(&self, kind: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_classifier_failures_total", "kind" => kind).increment(1);
+    }
+    
+    pub fn record_classifier_latency(&self, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_classifier_call_duration_seconds").record(seconds);
+    }
+    
+    pub fn record_config_override(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_config_overrides_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn update_config_overrides_active(&self, count: usize) {
+        scoped!(self.inner.scope, gauge, "broker_config_overrides_active").set(count as f64);
+    }
+    
+    pub fn record_recipient_exclusions(&self, reason: &'static str, count: u64) {
+        scoped!(self.inner.scope, counter, "broker_recipient_exclusions_total", "reason" => reason).increment(count);
+    }
+    
+    pub fn record_recipient_trace(&self, trigger: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_recipient_traces_total", "trigger" => trigger).increment(1);
+    }
+    
+    pub fn record_abuse_signal(&self, signal: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_abuse_signals_total", "signal" => signal).increment(1);
+    }
+    
+    pub fn record_abuse_transition(&self, response: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_abuse_transitions_total", "to" => response).increment(1);
+    }
+    
+    pub fn record_abuse_evicted(&self) {
+        scoped!(self.inner.scope, counter, "broker_abuse_records_evicted_total").increment(1);
+    }
+    
+    pub fn update_abuse_restricted(&self, users: usize) {
+        scoped!(self.inner.scope, gauge, "broker_abuse_restricted_users").set(users as f64);
+    }
+    
+    pub fn update_tls_cert_expiry(&self, purpose: &str, not_after: i64) {
+        scoped!(self.inner.scope, gauge, "broker_tls_cert_expiry_timestamp_seconds", "purpose" => purpose.to_string()).set(not_after as f64);
+    }
+    
+    pub fn update_tls_cert_invalid(&self, purpose: &str, invalid: bool) {
+        scoped!(self.inner.scope, gauge, "broker_tls_cert_invalid", "purpose" => purpose.to_string()).set(if invalid { 1.0 } else { 0.0 });
+    }
+    
+    pub fn update_tls_cert_check_failed(&self, purpose: &str, check: &'static str, failed: bool) {
+        scoped!(self.inner.scope, gauge, "broker_tls_cert_check_failed", "purpose" => purpose.to_string(), "check" => check).set(if failed { 1.0 } else { 0.0 });
+    }
+    
+    pub fn record_offline_export(&self, entries: u64, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_offline_exports_total", "outcome" => outcome).increment(1);
+        scoped!(self.inner.scope, counter, "broker_offline_export_entries_total").increment(entries);
+    }
+    
+    pub fn record_offline_import(&self, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_offline_import_entries_total", "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_offline_export_deleted(&self, entries: usize) {
+        scoped!(self.inner.scope, counter, "broker_offline_export_deleted_total").increment(entries as u64);
+    }
+    
+    pub fn record_message_kind(&self, kind: &'static str, bytes: usize) {
+        scoped!(self.inner.scope, counter, "broker_messages_by_kind_total", "kind" => kind).increment(1);
+        scoped!(self.inner.scope, counter, "broker_message_bytes_by_kind_total", "kind" => kind).increment(bytes as u64);
+    }
+    
+    pub fn record_kind_budget_rejection(&self, kind: &'static str, action: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_kind_budget_rejections_total", "kind" => kind, "action" => action).increment(1);
+    }
+    
+    pub fn update_ingress_utilization(&self, ratio: f64) {
+        scoped!(self.inner.scope, gauge, "broker_ingress_utilization_ratio").set(ratio);
+    }
+    
+    /// Wait histogram, hold histogram and slow-wait counter for one named lock
+    pub fn lock_instruments(&self, lock: &'static str) -> (metrics::Histogram, metrics::Histogram, metrics::Counter) {
+        (
+            scoped!(self.inner.scope, histogram, "broker_lock_wait_seconds", "lock" => lock),
+            scoped!(self.inner.scope, histogram, "broker_lock_hold_seconds", "lock" => lock),
+            scoped!(self.inner.scope, counter, "broker_lock_slow_waits_total", "lock" => lock),
+        )
+    }
+    
+    pub fn record_envelope_guard_rejection(&self, guard: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_envelope_guard_rejections_total", "guard" => guard).increment(1);
+    }
+    
+    pub fn record_lifecycle_event(&self, event: &'static str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_conversation_lifecycle_events_total", "event" => event, "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_quota_feedback_sent(&self) {
+        scoped!(self.inner.scope, counter, "broker_quota_feedback_sent_total").increment(1);
+    }
+    
+    pub fn update_cluster_config_divergence(&self, peers: usize) {
+        scoped!(self.inner.scope, gauge, "broker_cluster_config_divergence").set(peers as f64);
+    }
+    
+    pub fn update_maintenance_mode(&self, active: bool) {
+        scoped!(self.inner.scope, gauge, "broker_maintenance_mode").set(if active { 1.0 } else { 0.0 });
+    }
+    
+    pub fn record_maintenance_rejection(&self, surface: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_maintenance_rejections_total", "surface" => surface).increment(1);
+    }
+    
+    /// Feed SLO indicators from this handle's message counters; clones share it
+    pub fn attach_slo(&self, tracker: Arc<SloTracker>) {
+        self.inner.slo.store(Some(tracker));
+    }
+    
+    pub fn update_slo_burn_rate(&self, slo: &str, window: &'static str, rate: f64) {
+        scoped!(self.inner.scope, gauge, "broker_slo_burn_rate", "slo" => slo.to_string(), "window" => window).set(rate);
+    }
+    
+    pub fn update_slo_budget_remaining(&self, slo: &str, ratio: f64) {
+        scoped!(self.inner.scope, gauge, "broker_slo_error_budget_remaining_ratio", "slo" => slo.to_string()).set(ratio);
+    }
+    
+    pub fn record_archived_rejection(&self) {
+        scoped!(self.inner.scope, counter, "broker_archived_rejections_total").increment(1);
+    }
+    
+    pub fn record_archive_cache_lookup(&self, result: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_archive_cache_lookups_total", "result" => result).increment(1);
+    }
+    
+    pub fn update_archive_cache_size(&self, entries: usize) {
+        scoped!(self.inner.scope, gauge, "broker_archive_cache_entries").set(entries as f64);
+    }
+    
+    pub fn record_api_auth_failure(&self, reason: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_api_auth_failures_total", "reason" => reason).increment(1);
+    }
+    
+    pub fn record_group_delivery_suppressed(&self, state: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_group_delivery_suppressed_total", "state" => state).increment(1);
+    }
+    
+    pub fn record_group_invitation_sent(&self) {
+        scoped!(self.inner.scope, counter, "broker_group_invitations_sent_total").increment(1);
+    }
+    
+    pub fn record_member_state_change(&self, state: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_member_state_changes_total", "state" => state).increment(1);
+    }
+    
+    pub fn record_egress_corruption(&self, class: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_egress_corruption_total", "class" => class).increment(1);
+    }
+    
+    pub fn record_thread_cache_lookup(&self, result: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_thread_cache_lookups_total", "result" => result).increment(1);
+    }
+    
+    pub fn record_thread_fanout(&self, kind: &'static str, recipients: usize) {
+        scoped!(self.inner.scope, counter, "broker_thread_fanout_total", "kind" => kind).increment(recipients as u64);
+    }
+    
+    pub fn update_warmup_progress(&self, ratio: f64) {
+        scoped!(self.inner.scope, gauge, "broker_warmup_progress_ratio").set(ratio);
+    }
+    
+    pub fn record_warmup_failures(&self, count: u64) {
+        scoped!(self.inner.scope, counter, "broker_warmup_failures_total").increment(count);
+    }
+    
+    pub fn update_dr_replication_lag(&self, side: &'static str, seconds: f64) {
+        scoped!(self.inner.scope, gauge, "broker_dr_replication_lag_seconds", "side" => side).set(seconds);
+    }
+    
+    pub fn update_dr_replication_buffer(&self, records: usize) {
+        scoped!(self.inner.scope, gauge, "broker_dr_replication_buffered").set(records as f64);
+    }
+    
+    pub fn record_dr_resync(&self) {
+        scoped!(self.inner.scope, counter, "broker_dr_resyncs_total").increment(1);
+    }
+    
+    pub fn record_dr_replicated(&self, kind: &'static str, direction: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_dr_replicated_total", "kind" => kind, "direction" => direction)
+            .increment(1);
+    }
+    
+    pub fn update_route_activity(&self, predicted: f64, actual: f64) {
+        scoped!(self.inner.scope, gauge, "broker_route_activity_predicted").set(predicted);
+        scoped!(self.inner.scope, gauge, "broker_route_activity_actual").set(actual);
+    }
+    
+    pub fn record_route_warmed(&self, kind: &'static str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_routes_warmed_total", "kind" => kind, "outcome" => outcome).increment(1);
+    }
+    
+    pub fn record_receipts_per_frame(&self, format: &'static str, count: usize) {
+        scoped!(self.inner.scope, histogram, "broker_receipts_per_frame", "format" => format).record(count as f64);
+    }
+    
+    pub fn record_receipt_decode_error(&self, format: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_receipt_decode_errors_total", "format" => format).increment(1);
+    }
+    
+    pub fn record_metadata_sanitized(&self, field: &str, action: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_metadata_sanitized_total", "field" => field.to_string(), "action" => action)
+            .increment(1);
+    }
+    
+    pub fn update_broker_standby(&self, standby: bool) {
+        scoped!(self.inner.scope, gauge, "broker_standby").set(if standby { 1.0 } else { 0.0 });
+    }
+    
+    pub fn record_standby_activation(&self, trigger: &'static str, seconds: f64, within_budget: bool) {
+        scoped!(self.inner.scope, histogram, "broker_standby_activation_seconds", "trigger" => trigger).record(seconds);
+        scoped!(self.inner.scope, counter, 
+            "broker_standby_activations_total",
+            "trigger" => trigger,
+            "within_budget" => if within_budget { "true" } else { "false" }
+        )
+        .increment(1);
+    }
+    
+    pub fn record_control_execution(&self, command: &'static str, outcome: &'static str) {
+        scoped!(self.inner.scope, counter, "broker_control_commands_total", "command" => command, "outcome" => outcome)
+            .increment(1);
+    }
+    
+    pub fn record_size_inflation(&self, ratio: f64) {
+        scoped!(self.inner.scope, histogram, "broker_message_size_inflation_ratio").record(ratio);
+    }
+    
+    pub fn record_size_inflation_bytes(&self, cause: &'static str, bytes: usize) {
+        scoped!(self.inner.scope, histogram, "broker_message_size_inflation_bytes", "cause" => cause).record(bytes as f64);
+    }
+    
+    /// Attempt counter handed to `backoff::retry_with` for one retry site
+    pub fn retry_attempts(&self, site: &'static str) -> metrics::Counter {
+        scoped!(self.inner.scope, counter, "broker_retry_attempts_total", "site" => site)
+    }
+    
+    pub fn record_sequence_block_claimed(&self) {
+        scoped!(self.inner.scope, counter, "broker_sequence_blocks_claimed_total").increment(1);
+    }
+    
+    pub fn record_sequence_gap(&self, skipped: u64) {
+        scoped!(self.inner.scope, counter, "broker_sequence_gap_total").increment(skipped);
+    }
+    
+    pub fn record_delivery_id(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_delivery_ids_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_consumer_handover_step(&self, step: &str) {
+        scoped!(self.inner.scope, counter, "broker_consumer_handover_steps_total", "step" => step.to_string()).increment(1);
+    }
+    
+    pub fn record_config_clamped(&self, field: &str) {
+        scoped!(self.inner.scope, counter, "broker_config_clamped_total", "field" => field.to_string()).increment(1);
+    }
+    
+    pub fn record_delivery_handoff(&self) {
+        scoped!(self.inner.scope, counter, "broker_delivery_handoffs_total").increment(1);
+    }
+    
+    pub fn record_delivery_late_ack(&self) {
+        scoped!(self.inner.scope, counter, "broker_delivery_late_acks_total").increment(1);
+    }
+    
+    pub fn update_delivery_timers(&self, armed: usize) {
+        scoped!(self.inner.scope, gauge, "broker_delivery_timers").set(armed as f64);
+    }
+    
+    pub fn update_delivery_hot_statuses(&self, statuses: usize) {
+        scoped!(self.inner.scope, gauge, "broker_delivery_hot_statuses").set(statuses as f64);
+    }
+    
+    pub fn record_delivery_status_compacted(&self) {
+        scoped!(self.inner.scope, counter, "broker_delivery_status_compactions_total").increment(1);
+    }
+    
+    pub fn record_task_panic(&self, subsystem: &str) {
+        scoped!(self.inner.scope, counter, "broker_task_panics_total", "subsystem" => subsystem.to_string()).increment(1);
+    }
+    
+    pub fn record_task_restart(&self, subsystem: &str) {
+        scoped!(self.inner.scope, counter, "broker_task_restarts_total", "subsystem" => subsystem.to_string()).increment(1);
+    }
+    
+    pub fn record_read_horizon_update(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_read_horizon_updates_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_migration_forwarded(&self, path: &str) {
+        scoped!(self.inner.scope, counter, "broker_migration_forwarded_total", "path" => path.to_string()).increment(1);
+    }
+    
+    pub fn update_migration_residual(&self, residual: u64) {
+        scoped!(self.inner.scope, gauge, "broker_migration_residual_messages").set(residual as f64);
+    }
+    
+    pub fn update_fanout_batch_size(&self, class: &str, size: usize) {
+        scoped!(self.inner.scope, gauge, "broker_fanout_batch_size", "class" => class.to_string()).set(size as f64);
+    }
+    
+    pub fn record_outbox_row(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_outbox_rows_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn update_outbox_lag(&self, seconds: f64) {
+        scoped!(self.inner.scope, gauge, "broker_outbox_lag_seconds").set(seconds);
+    }
+    
+    pub fn record_presence_kv_writes(&self, path: &str, count: u64) {
+        scoped!(self.inner.scope, counter, "broker_presence_kv_writes_total", "path" => path.to_string()).increment(count);
+    }
+    
+    pub fn record_presence_bulk_refresh_size(&self, users: usize) {
+        scoped!(self.inner.scope, histogram, "broker_presence_bulk_refresh_size").record(users as f64);
+    }
+    
+    pub fn record_presence_bulk_refresh_duration(&self, seconds: f64) {
+        scoped!(self.inner.scope, histogram, "broker_presence_bulk_refresh_seconds").record(seconds);
+    }
+    
+    pub fn record_presence_bulk_superseded(&self) {
+        scoped!(self.inner.scope, counter, "broker_presence_bulk_superseded_total").increment(1);
+    }
+    
+    pub fn record_stream_read(&self, operation: &str, stream: &str) {
+        scoped!(self.inner.scope, counter, "broker_stream_reads_total", "operation" => operation.to_string(), "stream" => stream.to_string()).increment(1);
+    }
+    
+    pub fn update_mirror_lag(&self, lag: u64) {
+        scoped!(self.inner.scope, gauge, "broker_mirror_lag_sequences").set(lag as f64);
+    }
+    
+    pub fn record_priority_inheritance(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_priority_inheritance_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn record_policy_decision(&self, rule_id: &str, allowed: bool) {
+        let effect = if allowed { "allow" } else { "deny" };
+        scoped!(self.inner.scope, counter, "broker_policy_decisions_total", "rule" => rule_id.to_string(), "effect" => effect).increment(1);
+    }
+    
+    pub fn record_membership_anomaly(&self, kind: &str, count: u64) {
+        scoped!(self.inner.scope, counter, "broker_membership_anomalies_total", "kind" => kind.to_string()).increment(count);
+    }
+    
+    pub fn record_transaction(&self, outcome: &str) {
+        scoped!(self.inner.scope, counter, "broker_transactions_total", "outcome" => outcome.to_string()).increment(1);
+    }
+    
+    pub fn update_degradation_level(&self, level: i64) {
+        self.inner.degradation_level.set(level as f64);
+    }
+    
+    pub fn record_degradation_level_change(&self, level: &str) {
+        scoped!(self.inner.scope, counter, "broker_degradation_level_changes_total", "level" => level.to_string()).increment(1);
+    }
+    
+    pub fn start_processing_timer(&self) -> ProcessingTimer {
+        ProcessingTimer::new(self.inner.processing_latency_seconds.clone())
+    }
+}
+
+pub struct ProcessingTimer {
+    start: std::time::Instant,
+    histogram: metrics::Histogram,
+}
+
+impl ProcessingTimer {
+    fn new(histogram: metrics::Histogram) -> Self {
+        Self {
+            start: std::time::Instant::now(),
+            histogram,
+        }
+    }
+    
+    pub fn record(self) {
+        self.histogram.record(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// Bucket bounds for histograms whose default buckets do not fit their range,
+/// matched by metric-name suffix so a configured prefix still applies
+pub(crate) const HISTOGRAM_BUCKETS: &[(&str, &[f64])] = &[
+    ("broker_fanout_latency_seconds", &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]),
+    ("broker_fanout_recipients_per_message", &[1.0, 10.0, 100.0, 1000.0, 10000.0, 100000.0]),
+    ("broker_ingress_latency_seconds", &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5]),
+    ("broker_egress_latency_seconds", &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5]),
+    ("broker_first_message_latency_seconds", &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+    ("broker_rate_limit_deferred_release_seconds", &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]),
+    ("broker_compression_dictionary_gain", &[0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]),
+    ("broker_e2e_delivery_latency_seconds", &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 3600.0]),
+];
+
+pub fn start_metrics_server(addr: std::net::SocketAddr) -> anyhow::Result<()> {
+    let mut builder = PrometheusBuilder::new();
+    for (name, buckets) in HISTOGRAM_BUCKETS {
+        builder = builder.set_buckets_for_metric(Matcher::Suffix((*name).to_string()), buckets)?;
+    }
+    
+    spawn_traced("prometheus_exporter", TaskContext::new("metrics"), async move {
+        match builder.with_http_listener(addr).install() {
+            Ok(_) => info!("Prometheus metrics server started on {}", addr),
+            Err(e) => error!("Failed to start metrics server: {}", e),
+        }
+        
+        // Keep the task alive
+        std::future::pending::<()>().await;
+    });
+    
+    Ok(())
+                      }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    /// Render what `record` emits on a recorder of its own
+    fn render(record: impl FnOnce()) -> String {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, record);
+        handle.render()
+    }
+
+    /// Sample lines, without `# HELP` / `# TYPE`
+    fn series(rendered: &str) -> Vec<&str> {
+        rendered.lines().filter(|line| !line.is_empty() && !line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn two_prefixed_instances_write_disjoint_series() {
+        let rendered = render(|| {
+            let east = BrokerMetrics::scoped(MetricScope::new(Some("east")).label("broker_id", "a")).unwrap();
+            let west = BrokerMetrics::scoped(MetricScope::new(Some("west")).label("broker_id", "b")).unwrap();
+            east.record_message_received();
+            east.record_message_received();
+            west.record_message_received();
+            east.record_sequence_block_claimed();
+            west.record_degradation_level_change("minimal");
+        });
+
+        assert!(rendered.contains("east_broker_messages_received_total{broker_id=\"a\"} 2"), "{}", rendered);
+        assert!(rendered.contains("west_broker_messages_received_total{broker_id=\"b\"} 1"), "{}", rendered);
+        assert!(rendered.contains("east_broker_sequence_blocks_claimed_total{broker_id=\"a\"} 1"));
+        assert!(rendered.contains("west_broker_degradation_level_changes_total{broker_id=\"b\",level=\"minimal\"} 1"));
+
+        let series = series(&rendered);
+        assert!(!series.is_empty());
+        for line in series {
+            let east = line.starts_with("east_") && line.contains("broker_id=\"a\"");
+            let west = line.starts_with("west_") && line.contains("broker_id=\"b\"");
+            assert!(east != west, "series belongs to neither or both instances: {}", line);
+        }
+    }
+
+    #[test]
+    fn the_default_scope_keeps_historical_names() {
+        let rendered = render(|| {
+            let metrics = BrokerMetrics::new().unwrap();
+            metrics.record_message_received();
+            metrics.record_rate_limit_hit("alice");
+        });
+
+        let series = series(&rendered);
+        assert!(series.contains(&"broker_messages_received_total 1"), "{}", rendered);
+        assert!(series.contains(&"broker_rate_limit_hits_user{user_id=\"alice\"} 1"), "{}", rendered);
+    }
+
+    #[test]
+    fn describing_again_is_idempotent() {
+        let rendered = render(|| {
+            let scope = MetricScope::new(Some("edge"));
+            let first = BrokerMetrics::scoped(scope.clone()).unwrap();
+            let second = BrokerMetrics::scoped(scope).unwrap();
+            first.record_message_received();
+            second.record_message_received();
+        });
+
+        // Same scope, same series: the counts add up under one description
+        assert!(rendered.contains("edge_broker_messages_received_total 2"), "{}", rendered);
+        assert_eq!(rendered.matches("# HELP edge_broker_messages_received_total ").count(), 1);
+    }
+
+    #[test]
+    fn scopes_come_from_config() {
+        let mut config = BrokerConfig::load().unwrap().metrics;
+        config.namespace = Some("embedded".into());
+        config.constant_labels.insert("broker_id".into(), "mini".into());
+        let scope = MetricScope::from_config(&config);
+        assert_eq!(scope.name("broker_up").as_str(), "embedded_broker_up");
+        assert_eq!(scope.labels, vec![metrics::Label::new("broker_id", "mini")]);
+
+        config.namespace = Some(String::new());
+        assert_eq!(MetricScope::from_config(&config).name("broker_up").as_str(), "broker_up");
+    }
+}
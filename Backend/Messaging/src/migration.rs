@@ -0,0 +1,409 @@
+use std::{sync::Arc, time::Duration};
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, Consumer},
+    },
+    HeaderMap,
+};
+use serde::Serialize;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backoff::{BackoffPolicy, Jitter},
+    config::{NatsConfig, StreamMigrationConfig},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+pub const MIGRATED_STREAM_HEADER: &str = "Broker-Migrated-Stream";
+pub const MIGRATED_SEQUENCE_HEADER: &str = "Broker-Migrated-Sequence";
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrationReport {
+    pub copied: u64,
+    /// Messages already deleted from the old stream (e.g. acked work-queue entries)
+    pub missing: u64,
+}
+
+/// Transition-window support for renamed subjects and streams
+///
+/// While configured, a drain consumer on the old stream forwards everything
+/// still arriving on old subjects into the new hierarchy, and the broker only
+/// publishes to new subjects. Every copy carries `Nats-Msg-Id`
+/// `migrated-{old_stream}-{sequence}`, so the drain and `migrate-streams`
+/// never produce a message twice within the new stream's duplicate window.
+pub struct StreamMigration {
+    jetstream: jetstream::Context,
+    config: StreamMigrationConfig,
+    new_ingress_topic: String,
+    metrics: BrokerMetrics,
+}
+
+impl StreamMigration {
+    /// `None` unless migration mode is configured
+    pub fn from_config(jetstream: jetstream::Context, nats: &NatsConfig, metrics: BrokerMetrics) -> Option<Arc<Self>> {
+        let config = nats.migration.clone()?;
+        Some(Arc::new(Self {
+            jetstream,
+            config,
+            new_ingress_topic: nats.ingress_topic.clone(),
+            metrics,
+        }))
+    }
+
+    /// Map an old subject onto the new hierarchy, `None` if it has no mapping
+    pub fn map_subject(&self, subject: &str) -> Option<String> {
+        map_subject(&self.config, &self.new_ingress_topic, subject)
+    }
+
+    /// Forward messages arriving on old subjects until the task is dropped
+    pub fn spawn_drain(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let migration = Arc::clone(self);
+        spawn_traced("migration_drain", TaskContext::new("migration"), async move {
+            let policy = BackoffPolicy::new(Duration::from_secs(5))
+                .max(Duration::from_secs(60))
+                .jitter(Jitter::Equal);
+            let mut backoff = policy.iter();
+            loop {
+                match migration.drain().await {
+                    Ok(()) => backoff = policy.iter(),
+                    Err(e) => warn!("Legacy stream drain failed: {}", e),
+                }
+                tokio::time::sleep(backoff.next().unwrap_or(Duration::from_secs(60))).await;
+            }
+        })
+    }
+
+    /// Keep the residual gauge current while migration mode is configured
+    pub fn spawn_residual_monitor(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let migration = Arc::clone(self);
+        spawn_traced("migration_residual", TaskContext::new("migration"), async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match migration.refresh_residual().await {
+                    Ok(0) => debug!("Old subjects fully drained"),
+                    Ok(residual) => debug!("{} messages pending on old subjects", residual),
+                    Err(e) => warn!("Failed to measure migration residual: {}", e),
+                }
+            }
+        })
+    }
+
+    async fn drain(&self) -> Result<(), async_nats::Error> {
+        let stream = self.jetstream.get_stream(&self.config.old_stream_name).await?;
+        let consumer: pull::Stream = stream
+            .get_or_create_consumer(
+                &self.config.old_consumer_name,
+                pull::Config {
+                    durable_name: Some(self.config.old_consumer_name.clone()),
+                    filter_subject: self.config.old_ingress_topic.clone(),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .messages()
+            .await?;
+        tokio::pin!(consumer);
+
+        info!("Draining {} from {}", self.config.old_ingress_topic, self.config.old_stream_name);
+        while let Some(message) = consumer.next().await {
+            let message = message?;
+            let sequence = message.info()?.stream_sequence;
+
+            self.forward(&message.subject, sequence, message.headers.clone(), message.payload.clone())
+                .await?;
+            message.ack().await?;
+            self.metrics.record_migration_forwarded("drain");
+        }
+        Ok(())
+    }
+
+    /// Copy every remaining old-stream message into the new hierarchy
+    pub async fn migrate_streams(&self) -> Result<MigrationReport, async_nats::Error> {
+        let mut stream = self.jetstream.get_stream(&self.config.old_stream_name).await?;
+        let state = stream.info().await?.state;
+        let mut report = MigrationReport::default();
+
+        for sequence in state.first_sequence..=state.last_sequence {
+            let raw = match stream.get_raw_message(sequence).await {
+                Ok(raw) => raw,
+                Err(e) => {
+                    debug!("Old stream sequence {} unavailable: {}", sequence, e);
+                    report.missing += 1;
+                    continue;
+                }
+            };
+            // Raw messages carry base64 payloads and unparsed headers
+            let message = async_nats::Message::try_from(raw)?;
+
+            if !self
+                .forward(&message.subject, sequence, message.headers, message.payload)
+                .await?
+            {
+                continue;
+            }
+            report.copied += 1;
+            self.metrics.record_migration_forwarded("migrate_streams");
+        }
+
+        info!(
+            "Migrated {} messages from {} ({} missing)",
+            report.copied, self.config.old_stream_name, report.missing
+        );
+        Ok(report)
+    }
+
+    /// Messages still pending on the old subjects; safe to drop the config at zero
+    pub async fn refresh_residual(&self) -> Result<u64, async_nats::Error> {
+        let mut consumer: Consumer<pull::Config> = self
+            .jetstream
+            .get_stream(&self.config.old_stream_name)
+            .await?
+            .get_consumer(&self.config.old_consumer_name)
+            .await?;
+        let info = consumer.info().await?;
+        let residual = info.num_pending + info.num_ack_pending as u64;
+        self.metrics.update_migration_residual(residual);
+        Ok(residual)
+    }
+
+    async fn forward(
+        &self,
+        subject: &str,
+        sequence: u64,
+        headers: Option<HeaderMap>,
+        payload: bytes::Bytes,
+    ) -> Result<bool, async_nats::Error> {
+        let Some(target) = self.map_subject(subject) else {
+            debug!("No mapping for old subject {}, skipping", subject);
+            return Ok(false);
+        };
+
+        let mut headers = headers.unwrap_or_default();
+        headers.insert(
+            "Nats-Msg-Id",
+            format!("migrated-{}-{}", self.config.old_stream_name, sequence).as_str(),
+        );
+        headers.insert(MIGRATED_STREAM_HEADER, self.config.old_stream_name.as_str());
+        headers.insert(MIGRATED_SEQUENCE_HEADER, sequence.to_string().as_str());
+
+        self.jetstream
+            .publish_with_headers(target, headers, payload)
+            .await?
+            .await?;
+        Ok(true)
+    }
+}
+
+fn map_subject(config: &StreamMigrationConfig, new_ingress_topic: &str, subject: &str) -> Option<String> {
+    if subject == config.old_ingress_topic {
+        return Some(new_ingress_topic.to_string());
+    }
+    config.subject_mappings.iter().find_map(|mapping| {
+        subject
+            .strip_prefix(&mapping.from)
+            .filter(|rest| rest.is_empty() || rest.starts_with('.'))
+            .map(|rest| format!("{}{}", mapping.to, rest))
+    })
+}
+
+/// The migration tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored migration`
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use async_nats::Message;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::{BrokerConfig, SubjectMapping};
+
+    fn config(prefix: &str) -> StreamMigrationConfig {
+        StreamMigrationConfig {
+            old_ingress_topic: format!("{}.broker.ingress", prefix),
+            old_stream_name: format!("{}_old", prefix),
+            old_consumer_name: "legacy-drain".into(),
+            subject_mappings: vec![
+                SubjectMapping {
+                    from: format!("{}.offline", prefix),
+                    to: format!("{}.v2.offline", prefix),
+                },
+                SubjectMapping {
+                    from: format!("{}.dlq", prefix),
+                    to: format!("{}.v2.dlq", prefix),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn old_subjects_map_onto_the_new_hierarchy() {
+        let config = config("t");
+        let new = "t.msg.ingress.v2";
+        assert_eq!(map_subject(&config, new, "t.broker.ingress").as_deref(), Some(new));
+        assert_eq!(map_subject(&config, new, "t.offline.alice").as_deref(), Some("t.v2.offline.alice"));
+        assert_eq!(map_subject(&config, new, "t.dlq").as_deref(), Some("t.v2.dlq"));
+        // Prefixes only match whole tokens
+        assert_eq!(map_subject(&config, new, "t.offlineish.alice"), None);
+        assert_eq!(map_subject(&config, new, "t.broker.ingress.extra"), None);
+        assert_eq!(map_subject(&config, new, "t.presence.alice"), None);
+    }
+
+    struct Fixture {
+        jetstream: jetstream::Context,
+        migration: Arc<StreamMigration>,
+        prefix: String,
+        new_stream: String,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+            let prefix = format!("mig{}", Uuid::new_v4().simple());
+            let migration = config(&prefix);
+
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: migration.old_stream_name.clone(),
+                    subjects: vec![
+                        migration.old_ingress_topic.clone(),
+                        format!("{}.offline.>", prefix),
+                        format!("{}.dlq", prefix),
+                    ],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let new_stream = format!("{}_new", prefix);
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: new_stream.clone(),
+                    subjects: vec![format!("{}.msg.ingress.v2", prefix), format!("{}.v2.>", prefix)],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let mut nats = BrokerConfig::load().unwrap().nats;
+            nats.ingress_topic = format!("{}.msg.ingress.v2", prefix);
+            nats.migration = Some(migration);
+            let migration = StreamMigration::from_config(jetstream.clone(), &nats, BrokerMetrics::new().unwrap()).unwrap();
+
+            Self {
+                jetstream,
+                migration,
+                prefix,
+                new_stream,
+            }
+        }
+
+        async fn publish_old(&self, subject: &str, count: usize) {
+            for i in 0..count {
+                self.jetstream
+                    .publish(format!("{}.{}", self.prefix, subject), format!("{}-{}", subject, i).into())
+                    .await
+                    .unwrap()
+                    .await
+                    .unwrap();
+            }
+        }
+
+        /// Old sequence of every message in the new stream
+        async fn migrated_sequences(&self) -> Vec<u64> {
+            let mut stream = self.jetstream.get_stream(&self.new_stream).await.unwrap();
+            let state = stream.info().await.unwrap().state;
+            let mut sequences = Vec::new();
+            for sequence in state.first_sequence..=state.last_sequence {
+                let message = Message::try_from(stream.get_raw_message(sequence).await.unwrap()).unwrap();
+                let header = message.headers.unwrap();
+                sequences.push(header.get(MIGRATED_SEQUENCE_HEADER).unwrap().as_str().parse().unwrap());
+            }
+            sequences
+        }
+
+        async fn wait_drained(&self) {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    match self.migration.refresh_residual().await {
+                        Ok(0) => return,
+                        _ => tokio::time::sleep(Duration::from_millis(50)).await,
+                    }
+                }
+            })
+            .await
+            .expect("old subjects never drained");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn messages_on_old_subjects_mid_migration_arrive_exactly_once() {
+        let fixture = Fixture::new().await;
+        fixture.publish_old("broker.ingress", 5).await;
+        fixture.publish_old("offline.alice", 2).await;
+        fixture.publish_old("dlq", 1).await;
+
+        let drain = fixture.migration.spawn_drain();
+        // Published after the switchover by a broker still on the old config
+        fixture.publish_old("broker.ingress", 3).await;
+
+        let report = fixture.migration.migrate_streams().await.unwrap();
+        assert_eq!(report.missing, 0);
+        fixture.wait_drained().await;
+        drain.abort();
+
+        // The drain and migrate-streams both forwarded ingress; dedup kept one copy each
+        let sequences = fixture.migrated_sequences().await;
+        assert_eq!(sequences.len(), 11);
+        assert_eq!(sequences.iter().collect::<HashSet<_>>().len(), 11);
+
+        // Running the command again after the window has nothing new to add
+        fixture.migration.migrate_streams().await.unwrap();
+        assert_eq!(fixture.migrated_sequences().await.len(), 11);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn residual_reaches_zero_once_the_old_subject_is_drained() {
+        let fixture = Fixture::new().await;
+        fixture.publish_old("broker.ingress", 20).await;
+
+        let drain = fixture.migration.spawn_drain();
+        fixture.wait_drained().await;
+        assert_eq!(fixture.migration.refresh_residual().await.unwrap(), 0);
+
+        fixture.publish_old("broker.ingress", 5).await;
+        fixture.wait_drained().await;
+        drain.abort();
+
+        let sequences = fixture.migrated_sequences().await;
+        assert_eq!(sequences, (1..=25).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn copies_carry_the_old_stream_and_sequence() {
+        let fixture = Fixture::new().await;
+        fixture.publish_old("offline.bob", 1).await;
+        fixture.migration.migrate_streams().await.unwrap();
+
+        let stream = fixture.jetstream.get_stream(&fixture.new_stream).await.unwrap();
+        let message = Message::try_from(stream.get_raw_message(1).await.unwrap()).unwrap();
+        let headers = message.headers.unwrap();
+        assert_eq!(message.subject.as_str(), format!("{}.v2.offline.bob", fixture.prefix));
+        assert_eq!(
+            headers.get(MIGRATED_STREAM_HEADER).unwrap().as_str(),
+            format!("{}_old", fixture.prefix)
+        );
+        assert_eq!(headers.get(MIGRATED_SEQUENCE_HEADER).unwrap().as_str(), "1");
+        assert_eq!(
+            headers.get("Nats-Msg-Id").unwrap().as_str(),
+            format!("migrated-{}_old-1", fixture.prefix)
+        );
+    }
+}
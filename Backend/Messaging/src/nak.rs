@@ -0,0 +1,401 @@
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+use async_nats::jetstream::{self, AckKind};
+use lru::LruCache;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::NakConfig,
+    degradation::DegradationSwitchboard,
+    ingestion_pause::IngestionPauses,
+    ingress::IngressRejection,
+    kind_budget::{BudgetAction, KindBudgets},
+    metrics::BrokerMetrics,
+    rate_limit::RateLimited,
+};
+
+/// Why an ingress message is being handed back for redelivery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NakReason {
+    RateLimited,
+    Shed,
+    Paused,
+    Maintenance,
+}
+
+impl NakReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NakReason::RateLimited => "rate_limited",
+            NakReason::Shed => "shed",
+            NakReason::Paused => "paused",
+            NakReason::Maintenance => "maintenance",
+        }
+    }
+}
+
+/// How long a NAKed message should stay away, if the rejecting path knows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NakHint {
+    pub reason: NakReason,
+    pub delay: Option<Duration>,
+}
+
+impl NakHint {
+    /// Until the sender's bucket admits one more message
+    pub fn rate_limited(limited: &RateLimited) -> Self {
+        Self {
+            reason: NakReason::RateLimited,
+            delay: Some(limited.quota.retry_after),
+        }
+    }
+
+    /// The hint for an ingress rejection the consumer NAKs; `None` for ones it acks or drops
+    ///
+    /// Budget deferrals use the current degradation level's
+    /// `nak_backoff_ms` entry for the kind, or the budget window when the
+    /// level has none.
+    pub fn for_rejection(
+        rejection: &IngressRejection,
+        switchboard: &DegradationSwitchboard,
+        kind_budgets: &KindBudgets,
+        pauses: &IngestionPauses,
+    ) -> Option<Self> {
+        let (reason, delay) = match rejection {
+            IngressRejection::OverBudget(over) if over.action == BudgetAction::Defer => {
+                let backoff = switchboard.current().toggles.nak_backoff_ms.get(&over.kind).copied();
+                let delay = backoff.map_or_else(|| kind_budgets.window(), Duration::from_millis);
+                (NakReason::Shed, Some(delay))
+            }
+            IngressRejection::Paused(_) => (NakReason::Paused, Some(pauses.nak_delay())),
+            IngressRejection::Maintenance(_) => (NakReason::Maintenance, None),
+            _ => return None,
+        };
+        Some(Self { reason, delay })
+    }
+}
+
+/// Issues delayed NAKs and holds back redeliveries that arrive too early
+///
+/// Each NAK carries an explicit delay: the hint, capped at
+/// `nak.max_delay_ms`, or with no hint `nak.base_delay_ms` doubled per
+/// delivery attempt up to `nak.max_default_delay_ms`. Without a delay
+/// JetStream redelivers at once, feeding the overload that caused the NAK.
+///
+/// Servers that ignore the delay redeliver early anyway. The time each
+/// message may return is remembered by stream sequence, for up to
+/// `nak.max_tracked` messages, and the consumer calls `hold_premature`
+/// before anything else on each delivery: an early one is NAKed again for
+/// the rest of its delay without being parsed or validated. The record
+/// lives on the broker that issued the NAK, so a redelivery landing on
+/// another broker of the same consumer is processed normally.
+pub struct NakScheduler {
+    not_before: Mutex<LruCache<u64, Instant>>,
+    config: NakConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl NakScheduler {
+    pub fn new(config: NakConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            not_before: Mutex::new(LruCache::new(NonZeroUsize::new(config.max_tracked.max(1)).unwrap())),
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Delay for a message on its `delivered`th attempt
+    pub fn delay(&self, hint: Option<Duration>, delivered: i64) -> Duration {
+        match hint {
+            Some(delay) => delay.min(Duration::from_millis(self.config.max_delay_ms)),
+            None => {
+                let doublings = (delivered.max(1) - 1).min(32) as u32;
+                Duration::from_millis(
+                    self.config
+                        .base_delay_ms
+                        .saturating_mul(1u64 << doublings)
+                        .min(self.config.max_default_delay_ms),
+                )
+            }
+        }
+    }
+
+    /// NAK `message` to come back after its hinted delay
+    pub async fn nak(&self, message: &jetstream::Message, hint: NakHint) -> Result<(), async_nats::Error> {
+        let info = message.info()?;
+        let delay = self.delay(hint.delay, info.delivered);
+        let stream_sequence = info.stream_sequence;
+        self.not_before.lock().put(stream_sequence, self.clock.now_instant() + delay);
+        self.metrics.record_nak_delay(hint.reason.as_str(), delay.as_secs_f64());
+        message.ack_with(AckKind::Nak(Some(delay))).await
+    }
+
+    /// NAK `message` again if it came back before its delay; true if it did
+    pub async fn hold_premature(&self, message: &jetstream::Message) -> bool {
+        let Ok(info) = message.info() else {
+            return false;
+        };
+        let stream_sequence = info.stream_sequence;
+        let now = self.clock.now_instant();
+        let remaining = {
+            let mut not_before = self.not_before.lock();
+            let Some(at) = not_before.peek(&stream_sequence).copied() else {
+                return false;
+            };
+            match at.checked_duration_since(now) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => {
+                    not_before.pop(&stream_sequence);
+                    return false;
+                }
+            }
+        };
+        self.metrics.record_nak_premature_redelivery();
+        if let Err(e) = message.ack_with(AckKind::Nak(Some(remaining))).await {
+            debug!("Re-NAK of early redelivery {} failed: {}", stream_sequence, e);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::SimClock,
+        config::{BrokerConfig, DegradationConfig, DegradationLevels},
+        degradation::DegradationLevel,
+        ingestion_pause::PauseSelector,
+        kind_budget::{OverBudget, TrafficKind},
+        maintenance::InMaintenance,
+        nats_probe::tests::EmbeddedServer,
+    };
+
+    fn scheduler(clock: &Arc<SimClock>) -> NakScheduler {
+        let mut config = BrokerConfig::load().unwrap().nak;
+        config.max_delay_ms = 60_000;
+        config.base_delay_ms = 500;
+        config.max_default_delay_ms = 4_000;
+        config.max_tracked = 2;
+        NakScheduler::new(config, BrokerMetrics::new().unwrap()).with_clock(clock.clone())
+    }
+
+    #[test]
+    fn hints_are_capped_and_unhinted_delays_double_per_attempt() {
+        let scheduler = scheduler(&Arc::new(SimClock::new()));
+        assert_eq!(scheduler.delay(Some(Duration::from_secs(3)), 7), Duration::from_secs(3));
+        assert_eq!(scheduler.delay(Some(Duration::from_secs(3600)), 1), Duration::from_secs(60));
+        assert_eq!(scheduler.delay(Some(Duration::ZERO), 1), Duration::ZERO);
+
+        let defaults: Vec<u128> = (0..=6).map(|delivered| scheduler.delay(None, delivered).as_millis()).collect();
+        assert_eq!(defaults, [500, 500, 1000, 2000, 4000, 4000, 4000]);
+        assert_eq!(scheduler.delay(None, i64::MAX), Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn shed_paused_and_maintenance_rejections_carry_their_hints() {
+        let clock = Arc::new(SimClock::new());
+        let config = BrokerConfig::load().unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let degradation = DegradationConfig {
+            level_ttl: Duration::from_secs(600),
+            auto_trigger_on_shed: false,
+            levels: DegradationLevels::default(),
+        };
+        let switchboard = Arc::new(
+            DegradationSwitchboard::new(&degradation, AuditLog::tracing_only(), metrics.clone()).with_clock(clock.clone()),
+        );
+        let kind_budgets = KindBudgets::new(config.kind_budgets.clone(), 1000, switchboard.clone(), metrics.clone());
+        // Hints never touch NATS, so the client is never connected
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("localhost:4222")
+            .await
+            .unwrap();
+        let pauses = IngestionPauses::new(
+            config.ingestion_pause.clone(),
+            jetstream::new(client),
+            "ingress".into(),
+            AuditLog::tracing_only(),
+            metrics,
+        );
+        let hint = |rejection: IngressRejection| NakHint::for_rejection(&rejection, &switchboard, &kind_budgets, &pauses);
+        let over = |kind, action| IngressRejection::OverBudget(OverBudget { kind, action, share: 10.0 });
+
+        // The level's backoff table first, then the budget window
+        switchboard.set_level(DegradationLevel::Conserve, "oncall", "overload", None);
+        assert_eq!(
+            hint(over(TrafficKind::Receipt, BudgetAction::Defer)),
+            Some(NakHint {
+                reason: NakReason::Shed,
+                delay: Some(Duration::from_secs(5)),
+            })
+        );
+        switchboard.set_level(DegradationLevel::Emergency, "oncall", "overload", None);
+        assert_eq!(hint(over(TrafficKind::Receipt, BudgetAction::Defer)).unwrap().delay, Some(kind_budgets.window()));
+        assert_eq!(hint(over(TrafficKind::Typing, BudgetAction::Drop)), None, "dropped, not NAKed");
+
+        assert_eq!(
+            hint(IngressRejection::Paused(PauseSelector::Tenant("acme".into()))),
+            Some(NakHint {
+                reason: NakReason::Paused,
+                delay: Some(pauses.nak_delay()),
+            })
+        );
+        let maintenance = InMaintenance {
+            code: "maintenance",
+            message: None,
+            expected_end: None,
+        };
+        assert_eq!(
+            hint(IngressRejection::Maintenance(maintenance)),
+            Some(NakHint {
+                reason: NakReason::Maintenance,
+                delay: None,
+            })
+        );
+        assert_eq!(hint(IngressRejection::Degraded("degraded_typing")), None);
+
+        let limited = RateLimited {
+            user_id: "alice".into(),
+            quota: crate::rate_limit::QuotaStatus {
+                limit: 10,
+                remaining: 0,
+                reset_seconds: 10,
+                retry_after: Duration::from_millis(1500),
+            },
+        };
+        assert_eq!(NakHint::rate_limited(&limited).delay, Some(Duration::from_millis(1500)));
+    }
+
+    /// Deliveries of stream sequence `stream_sequence`, as JetStream sends them
+    struct Deliveries {
+        context: jetstream::Context,
+        server: EmbeddedServer,
+        stream_sequence: u64,
+    }
+
+    impl Deliveries {
+        async fn new(clock: &Arc<SimClock>, stream_sequence: u64) -> Self {
+            let server = EmbeddedServer::start(clock.clone()).await;
+            let context = jetstream::new(async_nats::connect(&server.url).await.unwrap());
+            Self {
+                context,
+                server,
+                stream_sequence,
+            }
+        }
+
+        /// The `delivered`th delivery; the payload isn't an envelope, so anything parsing it would fail
+        fn delivery(&self, delivered: i64) -> jetstream::Message {
+            let reply = format!("$JS.ACK.MESSAGES.ingress.{}.{}.{}.1700000000000000000.0", delivered, self.stream_sequence, delivered);
+            jetstream::Message {
+                message: async_nats::Message {
+                    subject: "broker.ingress".into(),
+                    reply: Some(reply.into()),
+                    payload: "not an envelope".into(),
+                    headers: None,
+                    status: None,
+                    description: None,
+                    length: 0,
+                },
+                context: self.context.clone(),
+            }
+        }
+
+        /// NAK delays sent back so far, in order
+        async fn naks(&self, count: usize) -> Vec<Duration> {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let published = self.server.published.lock().clone();
+                    if published.len() >= count {
+                        return published
+                            .into_iter()
+                            .map(|(subject, payload)| {
+                                assert!(subject.starts_with("$JS.ACK.MESSAGES.ingress."), "{}", subject);
+                                let body = std::str::from_utf8(&payload).unwrap();
+                                let delay: serde_json::Value = serde_json::from_str(body.strip_prefix("-NAK ").unwrap()).unwrap();
+                                Duration::from_nanos(delay["delay"].as_u64().unwrap())
+                            })
+                            .collect();
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn early_redeliveries_are_renaked_for_the_rest_of_their_hint() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let scheduler = scheduler(&clock);
+        let deliveries = Deliveries::new(&clock, 42).await;
+
+        let first = deliveries.delivery(1);
+        assert!(!scheduler.hold_premature(&first).await, "never NAKed, so processed");
+        let hint = NakHint {
+            reason: NakReason::RateLimited,
+            delay: Some(Duration::from_millis(1500)),
+        };
+        scheduler.nak(&first, hint).await.unwrap();
+
+        // An older server sends it straight back, then again partway through the delay
+        assert!(scheduler.hold_premature(&deliveries.delivery(2)).await);
+        clock.advance(Duration::from_millis(1000));
+        assert!(scheduler.hold_premature(&deliveries.delivery(3)).await);
+        assert_eq!(
+            deliveries.naks(3).await,
+            [Duration::from_millis(1500), Duration::from_millis(1500), Duration::from_millis(500)]
+        );
+
+        // At the hinted time it goes through, and isn't held again
+        clock.advance(Duration::from_millis(500));
+        assert!(!scheduler.hold_premature(&deliveries.delivery(4)).await);
+        assert!(!scheduler.hold_premature(&deliveries.delivery(5)).await);
+        assert_eq!(deliveries.naks(3).await.len(), 3);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("broker_nak_premature_redeliveries_total 2"), "{}", rendered);
+        assert!(rendered.contains(r#"broker_nak_delay_seconds_count{reason="rate_limited"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_nak_delay_seconds_sum{reason="rate_limited"} 1.5"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn unhinted_naks_back_off_with_the_delivery_count() {
+        let clock = Arc::new(SimClock::new());
+        let scheduler = scheduler(&clock);
+        let deliveries = Deliveries::new(&clock, 7).await;
+        let unhinted = NakHint {
+            reason: NakReason::Maintenance,
+            delay: None,
+        };
+        for delivered in 1..=4 {
+            let message = deliveries.delivery(delivered);
+            assert!(!scheduler.hold_premature(&message).await, "attempt {}", delivered);
+            scheduler.nak(&message, unhinted).await.unwrap();
+            clock.advance(scheduler.delay(None, delivered));
+        }
+        let expected: Vec<Duration> = [500, 1000, 2000, 4000].into_iter().map(Duration::from_millis).collect();
+        assert_eq!(deliveries.naks(4).await, expected);
+    }
+}
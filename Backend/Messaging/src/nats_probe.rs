@@ -0,0 +1,533 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use async_nats::RequestErrorKind;
+use bytes::Bytes;
+use tracing::{info, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    cluster::ClusterView,
+    config::NatsProbeConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Outcome of one probe interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    Ok(Duration),
+    /// Answered, but slower than `nats_probe.slow_rtt_ms`
+    Slow(Duration),
+    /// No answer within `nats_probe.timeout_ms`, or the connection refused the ping
+    Lost,
+    /// Real traffic gave a fresh enough signal this interval
+    Suppressed,
+}
+
+impl ProbeOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeOutcome::Ok(_) => "ok",
+            ProbeOutcome::Slow(_) => "slow",
+            ProbeOutcome::Lost => "lost",
+            ProbeOutcome::Suppressed => "suppressed",
+        }
+    }
+}
+
+/// Active health probing of the NATS connection while traffic is idle
+///
+/// Every `nats_probe.interval` the prober looks at how much acked NATS
+/// traffic the broker saw since the last interval (`observe_traffic`). At
+/// or above `nats_probe.idle_messages_per_second` that traffic already
+/// proves the path and the probe is skipped; below it, one request/reply
+/// round trip is timed on the active connection and recorded in
+/// `broker_nats_probe_rtt_seconds`. Probes run one at a time on the ticker,
+/// so the probe rate never exceeds one per interval.
+///
+/// `nats_probe.degraded_after` consecutive slow or lost probes mark the path
+/// degraded; `nats_probe.recover_after` consecutive good ones, or healthy
+/// traffic, clear it. While degraded the broker advertises it in its cluster
+/// heartbeat, so ownership moves to peers with a healthy path, and `/ready`
+/// fails when `nats_probe.gate_readiness` is set.
+pub struct NatsProbe {
+    client: async_nats::Client,
+    cluster: Arc<ClusterView>,
+    config: NatsProbeConfig,
+    /// Acked traffic since the last interval
+    traffic: AtomicU64,
+    bad_streak: AtomicU32,
+    good_streak: AtomicU32,
+    degraded: AtomicBool,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl NatsProbe {
+    pub fn new(
+        client: async_nats::Client,
+        cluster: Arc<ClusterView>,
+        config: NatsProbeConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            client,
+            cluster,
+            config,
+            traffic: AtomicU64::new(0),
+            bad_streak: AtomicU32::new(0),
+            good_streak: AtomicU32::new(0),
+            degraded: AtomicBool::new(false),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A publish or request on the connection completed; its ack is a fresh
+    /// latency signal
+    pub fn observe_traffic(&self) {
+        self.traffic.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn healthy(&self) -> bool {
+        !self.degraded.load(Ordering::Acquire)
+    }
+
+    /// Whether `/ready` should fail on account of the NATS path
+    pub fn blocks_readiness(&self) -> bool {
+        self.config.gate_readiness && !self.healthy()
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let probe = Arc::clone(self);
+        Some(spawn_traced("nats_probe", TaskContext::new("nats_probe"), async move {
+            loop {
+                probe.clock.sleep(probe.config.interval).await;
+                probe.run_interval().await;
+            }
+        }))
+    }
+
+    /// Probe once unless this interval's traffic already answered for the path
+    pub async fn run_interval(&self) -> ProbeOutcome {
+        let observed = self.traffic.swap(0, Ordering::Relaxed);
+        let idle_below = self.config.idle_messages_per_second * self.config.interval.as_secs_f64();
+        let outcome = if observed as f64 >= idle_below.max(1.0) {
+            ProbeOutcome::Suppressed
+        } else {
+            self.probe().await
+        };
+        self.record(outcome);
+        outcome
+    }
+
+    async fn probe(&self) -> ProbeOutcome {
+        let started = self.clock.now_instant();
+        // Nothing subscribes to a fresh inbox, so the server answers the
+        // request itself with a no-responders status: one full round trip.
+        // A flush only drains the socket and never waits for the server.
+        let answered = tokio::select! {
+            answered = self.client.request(self.client.new_inbox(), Bytes::new()) => Some(answered),
+            _ = self.clock.sleep(Duration::from_millis(self.config.timeout_ms)) => None,
+        };
+        match answered {
+            Some(Err(e)) if e.kind() != RequestErrorKind::NoResponders => ProbeOutcome::Lost,
+            Some(_) => {
+                let rtt = self.clock.now_instant().saturating_duration_since(started);
+                self.metrics.record_nats_probe_rtt(rtt.as_secs_f64());
+                if rtt > Duration::from_millis(self.config.slow_rtt_ms) {
+                    ProbeOutcome::Slow(rtt)
+                } else {
+                    ProbeOutcome::Ok(rtt)
+                }
+            }
+            None => ProbeOutcome::Lost,
+        }
+    }
+
+    fn record(&self, outcome: ProbeOutcome) {
+        self.metrics.record_nats_probe(outcome.as_str());
+        match outcome {
+            ProbeOutcome::Slow(_) | ProbeOutcome::Lost => {
+                self.good_streak.store(0, Ordering::Relaxed);
+                let streak = self.bad_streak.fetch_add(1, Ordering::Relaxed) + 1;
+                if streak >= self.config.degraded_after {
+                    self.set_degraded(true, outcome);
+                }
+            }
+            // Traffic acked fast enough to keep the broker busy counts as a good probe
+            ProbeOutcome::Ok(_) | ProbeOutcome::Suppressed => {
+                self.bad_streak.store(0, Ordering::Relaxed);
+                let streak = self.good_streak.fetch_add(1, Ordering::Relaxed) + 1;
+                if streak >= self.config.recover_after {
+                    self.set_degraded(false, outcome);
+                }
+            }
+        }
+    }
+
+    fn set_degraded(&self, degraded: bool, outcome: ProbeOutcome) {
+        if self.degraded.swap(degraded, Ordering::AcqRel) == degraded {
+            return;
+        }
+        if degraded {
+            warn!(
+                "NATS path degraded after {} bad probes (last: {:?})",
+                self.config.degraded_after, outcome
+            );
+        } else {
+            info!("NATS path recovered");
+        }
+        self.cluster.set_nats_degraded(degraded);
+        self.metrics.update_nats_path_healthy(!degraded);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use async_nats::jetstream;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use parking_lot::Mutex;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        cluster::PeerInfo,
+        config::BrokerConfig,
+    };
+
+    /// How the embedded server answers a probe
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Answer {
+        Now,
+        /// After this much time on the test's clock
+        After(Duration),
+        Never,
+    }
+
+    /// Just enough of a NATS server for one client: the handshake, pings,
+    /// subscriptions, the stream lookup and acked puts behind a KV handle,
+    /// and a no-responders status for every other request, delayed on the
+    /// test's clock as told. Publishes without a reply are only recorded.
+    pub(crate) struct EmbeddedServer {
+        pub(crate) url: String,
+        answer: Arc<Mutex<Answer>>,
+        probes: Arc<AtomicUsize>,
+        pub(crate) puts: Puts,
+        pub(crate) published: Puts,
+    }
+
+    /// Payloads by subject
+    type Puts = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    impl EmbeddedServer {
+        pub(crate) async fn start(clock: Arc<SimClock>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Self {
+                url: format!("nats://{}", listener.local_addr().unwrap()),
+                answer: Arc::new(Mutex::new(Answer::Now)),
+                probes: Arc::new(AtomicUsize::new(0)),
+                puts: Arc::new(Mutex::new(Vec::new())),
+                published: Arc::new(Mutex::new(Vec::new())),
+            };
+            let (answer, probes, puts, published) =
+                (server.answer.clone(), server.probes.clone(), server.puts.clone(), server.published.clone());
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    let connection = Connection {
+                        clock: clock.clone(),
+                        answer: answer.clone(),
+                        probes: probes.clone(),
+                        puts: puts.clone(),
+                        published: published.clone(),
+                    };
+                    tokio::spawn(connection.serve(socket));
+                }
+            });
+            server
+        }
+
+        fn answer(&self, answer: Answer) {
+            *self.answer.lock() = answer;
+        }
+    }
+
+    struct Connection {
+        clock: Arc<SimClock>,
+        answer: Arc<Mutex<Answer>>,
+        probes: Arc<AtomicUsize>,
+        puts: Puts,
+        published: Puts,
+    }
+
+    impl Connection {
+        async fn serve(self, socket: tokio::net::TcpStream) {
+            let (read, mut write) = socket.into_split();
+            let (out, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+            tokio::spawn(async move {
+                while let Some(bytes) = outgoing.recv().await {
+                    if write.write_all(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            let info = r#"{"server_id":"embedded","server_name":"embedded","version":"2.10.0","go":"go1.21","host":"127.0.0.1","port":4222,"headers":true,"max_payload":1048576,"proto":1}"#;
+            let _ = out.send(format!("INFO {}\r\n", info).into_bytes());
+
+            let mut reader = BufReader::new(read);
+            let mut subscriptions: Vec<(String, String)> = Vec::new();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                match fields.first().copied() {
+                    Some("PING") => {
+                        let _ = out.send(b"PONG\r\n".to_vec());
+                    }
+                    Some("SUB") => subscriptions.push((fields[1].to_string(), fields[fields.len() - 1].to_string())),
+                    Some(op @ ("PUB" | "HPUB")) => {
+                        let size: usize = fields[fields.len() - 1].parse().unwrap();
+                        let mut body = vec![0; size + 2];
+                        reader.read_exact(&mut body).await.unwrap();
+                        body.truncate(size);
+                        let reply = match (op, fields.len()) {
+                            ("PUB", 4) | ("HPUB", 5) => fields[2],
+                            _ => {
+                                self.published.lock().push((fields[1].to_string(), body));
+                                continue;
+                            }
+                        };
+                        let Some((_, sid)) = subscriptions.iter().find(|(pattern, _)| subject_matches(pattern, reply)) else {
+                            continue;
+                        };
+                        self.handle(fields[1], reply, sid, body, &out);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        fn handle(&self, subject: &str, reply: &str, sid: &str, body: Vec<u8>, out: &mpsc::UnboundedSender<Vec<u8>>) {
+            let message = |payload: String| format!("MSG {} {} {}\r\n{}\r\n", reply, sid, payload.len(), payload).into_bytes();
+            if let Some(stream_name) = subject.strip_prefix("$JS.API.STREAM.INFO.") {
+                let info = serde_json::json!({
+                    "config": {
+                        "name": stream_name, "subjects": [format!("$KV.{}.>", &stream_name[3..])],
+                        "max_bytes": -1, "max_msgs": -1, "max_msgs_per_subject": 1, "max_consumers": -1,
+                        "max_age": 0, "discard": "new", "retention": "limits", "storage": "file", "num_replicas": 1,
+                    },
+                    "created": "2026-01-01T00:00:00Z",
+                    "state": {
+                        "messages": 0, "bytes": 0, "consumer_count": 0,
+                        "first_seq": 0, "first_ts": "2026-01-01T00:00:00Z",
+                        "last_seq": 0, "last_ts": "2026-01-01T00:00:00Z",
+                    },
+                });
+                let _ = out.send(message(info.to_string()));
+            } else if subject.starts_with("$KV.") {
+                self.puts.lock().push((subject.to_string(), body));
+                let _ = out.send(message(r#"{"stream":"KV_members","seq":1}"#.into()));
+            } else {
+                self.probes.fetch_add(1, Ordering::Relaxed);
+                let no_responders = format!("HMSG {} {} 16 16\r\nNATS/1.0 503\r\n\r\n\r\n", reply, sid).into_bytes();
+                match *self.answer.lock() {
+                    Answer::Now => {
+                        let _ = out.send(no_responders);
+                    }
+                    Answer::After(delay) => {
+                        let (clock, out) = (self.clock.clone(), out.clone());
+                        tokio::spawn(async move {
+                            clock.sleep(delay).await;
+                            let _ = out.send(no_responders);
+                        });
+                    }
+                    Answer::Never => {}
+                }
+            }
+        }
+    }
+
+    /// Exact subjects, or a trailing `*` for one more token
+    fn subject_matches(pattern: &str, subject: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => subject.strip_prefix(prefix).is_some_and(|token| !token.is_empty() && !token.contains('.')),
+            None => pattern == subject,
+        }
+    }
+
+    struct Fixture {
+        probe: Arc<NatsProbe>,
+        cluster: Arc<ClusterView>,
+        server: EmbeddedServer,
+        clock: Arc<SimClock>,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let clock = Arc::new(SimClock::new());
+            let server = EmbeddedServer::start(clock.clone()).await;
+            let client = async_nats::connect(&server.url).await.unwrap();
+            let members = jetstream::new(client.clone()).get_key_value("members").await.unwrap();
+            let config = BrokerConfig::load().unwrap();
+            let cluster = Arc::new(ClusterView::new(members, "broker-1".into(), &config.cluster));
+            let mut probe_config = config.nats_probe;
+            probe_config.interval = Duration::from_secs(5);
+            probe_config.timeout_ms = 2_000;
+            probe_config.slow_rtt_ms = 250;
+            probe_config.idle_messages_per_second = 1.0;
+            probe_config.degraded_after = 3;
+            probe_config.recover_after = 2;
+            probe_config.gate_readiness = true;
+            let probe = NatsProbe::new(client, cluster.clone(), probe_config, BrokerMetrics::new().unwrap())
+                .with_clock(clock.clone());
+            Self {
+                probe: Arc::new(probe),
+                cluster,
+                server,
+                clock,
+            }
+        }
+
+        /// One interval with the server answering as told; the clock only
+        /// moves to the first deadline among the probe's timeout and the answer
+        async fn interval(&self, answer: Answer) -> ProbeOutcome {
+            self.server.answer(answer);
+            let probe = self.probe.clone();
+            let running = tokio::spawn(async move { probe.run_interval().await });
+            let sleeping = match answer {
+                Answer::Now => 0,
+                Answer::After(_) => 2,
+                Answer::Never => 1,
+            };
+            if sleeping > 0 {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while self.clock.pending() < sleeping {
+                        tokio::task::yield_now().await;
+                    }
+                })
+                .await
+                .expect("the probe never reached the server");
+                self.clock.advance_to_next();
+            }
+            running.await.unwrap()
+        }
+
+        /// What the next heartbeat advertises
+        async fn advertised_degraded(&self) -> bool {
+            self.cluster.heartbeat().await.unwrap();
+            let (_, value) = self.server.puts.lock().pop().unwrap();
+            serde_json::from_slice::<PeerInfo>(&value).unwrap().nats_degraded
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_time_a_round_trip_through_the_server() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let fixture = Fixture::new().await;
+
+        let delay = Duration::from_millis(120);
+        assert_eq!(fixture.interval(Answer::After(delay)).await, ProbeOutcome::Ok(delay));
+        assert_eq!(fixture.server.probes.load(Ordering::Relaxed), 1);
+        let slow = Duration::from_millis(900);
+        assert_eq!(fixture.interval(Answer::After(slow)).await, ProbeOutcome::Slow(slow));
+        assert_eq!(fixture.interval(Answer::Never).await, ProbeOutcome::Lost);
+        assert_eq!(fixture.server.probes.load(Ordering::Relaxed), 3);
+
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_nats_probes_total{outcome="ok"} 1"#,
+            r#"broker_nats_probes_total{outcome="slow"} 1"#,
+            r#"broker_nats_probes_total{outcome="lost"} 1"#,
+            "broker_nats_probe_rtt_seconds_count 2",
+            "broker_nats_probe_rtt_seconds_sum 1.02",
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_delayed_server_is_detected_within_degraded_after_intervals() {
+        let fixture = Fixture::new().await;
+        assert!(matches!(fixture.interval(Answer::Now).await, ProbeOutcome::Ok(_)));
+        assert!(!fixture.advertised_degraded().await);
+
+        let slow = Answer::After(Duration::from_millis(800));
+        for interval in 1..=3 {
+            assert!(matches!(fixture.interval(slow).await, ProbeOutcome::Slow(_)));
+            assert_eq!(fixture.probe.healthy(), interval < 3, "after {} slow intervals", interval);
+        }
+        assert!(fixture.probe.blocks_readiness());
+        assert!(fixture.advertised_degraded().await);
+
+        // One good probe between bad ones restarts both streaks
+        assert!(matches!(fixture.interval(Answer::Now).await, ProbeOutcome::Ok(_)));
+        assert!(!fixture.probe.healthy());
+        assert!(matches!(fixture.interval(Answer::Now).await, ProbeOutcome::Ok(_)));
+        assert!(fixture.probe.healthy());
+        assert!(!fixture.probe.blocks_readiness());
+        assert!(!fixture.advertised_degraded().await);
+    }
+
+    #[tokio::test]
+    async fn lost_probes_degrade_the_path_like_slow_ones() {
+        let fixture = Fixture::new().await;
+        for outcome in [Answer::Never, Answer::After(Duration::from_millis(300)), Answer::Never] {
+            assert!(fixture.probe.healthy());
+            fixture.interval(outcome).await;
+        }
+        assert!(!fixture.probe.healthy());
+        assert!(fixture.advertised_degraded().await);
+    }
+
+    #[tokio::test]
+    async fn real_traffic_suppresses_probing() {
+        let fixture = Fixture::new().await;
+        let slow = Answer::After(Duration::from_millis(800));
+        for _ in 0..3 {
+            fixture.interval(slow).await;
+        }
+        assert!(!fixture.probe.healthy());
+
+        // Five seconds at one acked message a second answers for the path
+        for _ in 0..4 {
+            for _ in 0..500 {
+                fixture.probe.observe_traffic();
+            }
+            assert_eq!(fixture.interval(Answer::Now).await, ProbeOutcome::Suppressed);
+        }
+        assert_eq!(fixture.server.probes.load(Ordering::Relaxed), 3);
+        assert!(fixture.probe.healthy());
+
+        // Below the idle rate, the interval is probed
+        for _ in 0..4 {
+            fixture.probe.observe_traffic();
+        }
+        assert!(matches!(fixture.interval(Answer::Now).await, ProbeOutcome::Ok(_)));
+        assert_eq!(fixture.server.probes.load(Ordering::Relaxed), 4);
+        // Traffic only counts for the interval it arrived in
+        assert!(matches!(fixture.interval(Answer::Now).await, ProbeOutcome::Ok(_)));
+    }
+}
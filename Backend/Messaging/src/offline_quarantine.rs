@@ -0,0 +1,567 @@
+use std::sync::Arc;
+use async_nats::{jetstream, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::OfflineQuarantineConfig,
+    idempotent_append::{self, AppendKey, AppendPurpose},
+    metrics::BrokerMetrics,
+    offline_transfer::{OfflineEntry, OfflineQueueStore, OfflineStoreError},
+    task::{spawn_traced, TaskContext},
+};
+
+/// How a purge treats the removed entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeMode {
+    /// Keep them in quarantine for `offline_quarantine.retention`
+    #[default]
+    Soft,
+    /// Delete immediately with no way back, e.g. for a GDPR erasure
+    Hard,
+}
+
+impl PurgeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PurgeMode::Soft => "soft",
+            PurgeMode::Hard => "hard",
+        }
+    }
+}
+
+/// A purged entry as stored in the quarantine stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QuarantinedEntry {
+    /// Timestamp in milliseconds
+    quarantined_at: i64,
+    purged_by: String,
+    entry: OfflineEntry,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RestoreSummary {
+    pub restored: u64,
+    /// Already back in the queue, e.g. restored twice
+    pub duplicates: u64,
+    /// Past retention but not yet reaped
+    pub expired: u64,
+}
+
+/// Soft-delete layer for destructive offline queue operations
+///
+/// A soft purge copies the user's queued entries, oldest first, into the
+/// `offline_quarantine.stream` under a per-user subject before deleting
+/// them from the queue. `restore` re-appends them in their original order
+/// (after anything enqueued since the purge) while they are within
+/// `offline_quarantine.retention`, and a reaper deletes quarantined entries
+/// past retention for good. A hard purge skips quarantine entirely and is
+/// reserved for erasure requests that must leave nothing behind.
+pub struct OfflineQuarantine {
+    store: Arc<dyn OfflineQueueStore>,
+    jetstream: jetstream::Context,
+    config: OfflineQuarantineConfig,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl OfflineQuarantine {
+    pub fn new(
+        store: Arc<dyn OfflineQueueStore>,
+        jetstream: jetstream::Context,
+        config: OfflineQuarantineConfig,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            store,
+            jetstream,
+            config,
+            audit,
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Remove everything queued for `user_id` now, returning how many entries went
+    pub async fn purge(&self, user_id: &str, mode: PurgeMode, actor: &str) -> Result<usize, QuarantineError> {
+        let Some(last) = self.store.last_sequence(user_id).await? else {
+            return Ok(0);
+        };
+
+        if mode == PurgeMode::Soft {
+            let quarantined_at = self.clock.now_millis();
+            let mut after = 0;
+            loop {
+                let batch = self
+                    .store
+                    .read_range(user_id, after, last, self.config.batch_size.max(1))
+                    .await?;
+                let Some(tail) = batch.last() else {
+                    break;
+                };
+                after = tail.sequence;
+                // Each publish is acked before the next so stream order is queue order
+                for entry in batch {
+                    self.quarantine(user_id, quarantined_at, actor, entry).await?;
+                }
+            }
+        }
+
+        let deleted = self.store.delete_through(user_id, last).await?;
+        info!("Purged {} offline entries for {} ({}) by {}", deleted, user_id, mode.as_str(), actor);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "offline.purged",
+            serde_json::json!({ "user_id": user_id, "mode": mode, "entries": deleted, "through": last }),
+        ));
+        self.metrics.record_offline_purge(mode.as_str(), deleted);
+        Ok(deleted)
+    }
+
+    async fn quarantine(
+        &self,
+        user_id: &str,
+        quarantined_at: i64,
+        actor: &str,
+        entry: OfflineEntry,
+    ) -> Result<(), QuarantineError> {
+        // A retried purge republishes; the ID lets JetStream drop the copy. The
+        // queue sequence is part of it so purging again after a restore, which
+        // re-queued the entry under a new sequence, isn't dropped as well.
+        let message_id = format!("{}@{}", entry.envelope.message_id, entry.sequence);
+        let payload = serde_json::to_vec(&QuarantinedEntry {
+            quarantined_at,
+            purged_by: actor.to_string(),
+            entry,
+        })
+        .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        let key = AppendKey {
+            purpose: AppendPurpose::Quarantine,
+            message_id: &message_id,
+            recipient: user_id,
+        };
+        idempotent_append::append(
+            &self.jetstream,
+            key,
+            self.subject(user_id),
+            HeaderMap::new(),
+            payload.into(),
+            &self.metrics,
+        )
+        .await
+        .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Re-append the user's quarantined entries, oldest first, and drop them from quarantine
+    pub async fn restore(&self, user_id: &str, actor: &str) -> Result<RestoreSummary, QuarantineError> {
+        let stream = self
+            .jetstream
+            .get_stream(&self.config.stream)
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        let consumer = stream
+            .create_consumer(jetstream::consumer::pull::OrderedConfig {
+                filter_subject: self.subject(user_id),
+                deliver_policy: jetstream::consumer::DeliverPolicy::All,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        let pending = consumer.cached_info().num_pending;
+        let mut messages = consumer
+            .messages()
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+
+        let cutoff = self.cutoff();
+        let mut summary = RestoreSummary::default();
+        for _ in 0..pending {
+            let Some(message) = messages.next().await else {
+                break;
+            };
+            let message = message.map_err(|e| QuarantineError::Stream(e.to_string()))?;
+            let sequence = message
+                .info()
+                .map_err(|e| QuarantineError::Stream(e.to_string()))?
+                .stream_sequence;
+            let quarantined: QuarantinedEntry = serde_json::from_slice(&message.payload)
+                .map_err(|e| QuarantineError::Decode(e.to_string()))?;
+
+            if quarantined.quarantined_at <= cutoff {
+                summary.expired += 1;
+            } else if self.store.append(user_id, quarantined.entry).await? {
+                summary.restored += 1;
+            } else {
+                summary.duplicates += 1;
+            }
+            stream
+                .delete_message(sequence)
+                .await
+                .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        }
+
+        info!(
+            "Restored {} offline entries for {} by {} ({} duplicates, {} expired)",
+            summary.restored, user_id, actor, summary.duplicates, summary.expired
+        );
+        self.audit.record(AuditEntry::new(
+            actor,
+            "offline.restored",
+            serde_json::json!({ "user_id": user_id, "summary": summary }),
+        ));
+        self.metrics.record_offline_restore(summary.restored);
+        Ok(summary)
+    }
+
+    /// Permanently delete quarantined entries past retention, returning how many went
+    ///
+    /// Entries are quarantined in time order, so the sweep stops at the first
+    /// one still within retention.
+    pub async fn reap(&self) -> Result<u64, QuarantineError> {
+        let mut stream = self
+            .jetstream
+            .get_stream(&self.config.stream)
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        let consumer = stream
+            .create_consumer(jetstream::consumer::pull::OrderedConfig {
+                filter_subject: format!("{}.>", self.config.subject),
+                deliver_policy: jetstream::consumer::DeliverPolicy::All,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+        let pending = consumer.cached_info().num_pending;
+        let mut messages = consumer
+            .messages()
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+
+        let cutoff = self.cutoff();
+        let mut reaped = 0;
+        for _ in 0..pending {
+            let Some(message) = messages.next().await else {
+                break;
+            };
+            let message = message.map_err(|e| QuarantineError::Stream(e.to_string()))?;
+            let quarantined_at = serde_json::from_slice::<QuarantinedEntry>(&message.payload)
+                .map_or(i64::MIN, |quarantined| quarantined.quarantined_at);
+            if quarantined_at > cutoff {
+                break;
+            }
+            let sequence = message
+                .info()
+                .map_err(|e| QuarantineError::Stream(e.to_string()))?
+                .stream_sequence;
+            stream
+                .delete_message(sequence)
+                .await
+                .map_err(|e| QuarantineError::Stream(e.to_string()))?;
+            reaped += 1;
+        }
+
+        let state = stream
+            .info()
+            .await
+            .map_err(|e| QuarantineError::Stream(e.to_string()))?
+            .state;
+        self.metrics.update_offline_quarantine_size(state.messages, state.bytes);
+        if reaped > 0 {
+            self.metrics.record_offline_quarantine_reaped(reaped);
+            info!("Reaped {} quarantined offline entries past retention", reaped);
+        }
+        Ok(reaped)
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let quarantine = Arc::clone(self);
+        spawn_traced("offline_quarantine_reaper", TaskContext::new("offline_quarantine"), async move {
+            loop {
+                if let Err(e) = quarantine.reap().await {
+                    warn!("Offline quarantine reap failed: {}", e);
+                }
+                quarantine.clock.sleep(quarantine.config.reap_interval).await;
+            }
+        })
+    }
+
+    fn cutoff(&self) -> i64 {
+        self.clock.now_millis() - self.config.retention.as_millis() as i64
+    }
+
+    fn subject(&self, user_id: &str) -> String {
+        format!("{}.{}", self.config.subject, URL_SAFE_NO_PAD.encode(user_id))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuarantineError {
+    #[error(transparent)]
+    Store(#[from] OfflineStoreError),
+    #[error("quarantine stream error: {0}")]
+    Stream(String),
+    #[error("malformed quarantined entry: {0}")]
+    Decode(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+    use async_trait::async_trait;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use parking_lot::Mutex;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageEnvelope, MessageType, Priority},
+    };
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    /// Offline queues in memory, deduplicating appends by delivery ID
+    #[derive(Default)]
+    struct MemoryQueues {
+        queues: Mutex<HashMap<String, Vec<OfflineEntry>>>,
+    }
+
+    impl MemoryQueues {
+        fn enqueue(&self, user_id: &str, delivery_id: &str) {
+            let mut queues = self.queues.lock();
+            let queue = queues.entry(user_id.to_string()).or_default();
+            let sequence = queue.last().map_or(1, |last| last.sequence + 1);
+            let mut envelope = MessageEnvelope::new(
+                MessageType::TextMessage,
+                "carol".into(),
+                vec![user_id.into()],
+                EncryptedPayload {
+                    ciphertext: "aGk=".into(),
+                    iv: None,
+                    tag: None,
+                    key_id: None,
+                    content_type: None,
+                },
+            );
+            envelope.message_id = format!("m-{}", delivery_id);
+            queue.push(OfflineEntry {
+                sequence,
+                delivery_id: delivery_id.to_string(),
+                priority: Priority::Normal,
+                enqueued_at: 1_700_000_000_000 + sequence as i64,
+                envelope,
+            });
+        }
+
+        fn delivery_ids(&self, user_id: &str) -> Vec<String> {
+            self.queues.lock().get(user_id).map_or_else(Vec::new, |queue| {
+                queue.iter().map(|entry| entry.delivery_id.clone()).collect()
+            })
+        }
+    }
+
+    #[async_trait]
+    impl OfflineQueueStore for MemoryQueues {
+        async fn last_sequence(&self, user_id: &str) -> Result<Option<u64>, OfflineStoreError> {
+            Ok(self.queues.lock().get(user_id).and_then(|queue| queue.last()).map(|entry| entry.sequence))
+        }
+
+        async fn read_range(
+            &self,
+            user_id: &str,
+            after: u64,
+            up_to: u64,
+            limit: usize,
+        ) -> Result<Vec<OfflineEntry>, OfflineStoreError> {
+            Ok(self.queues.lock().get(user_id).map_or_else(Vec::new, |queue| {
+                queue
+                    .iter()
+                    .filter(|entry| entry.sequence > after && entry.sequence <= up_to)
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            }))
+        }
+
+        async fn append(&self, user_id: &str, mut entry: OfflineEntry) -> Result<bool, OfflineStoreError> {
+            let mut queues = self.queues.lock();
+            let queue = queues.entry(user_id.to_string()).or_default();
+            if queue.iter().any(|queued| queued.delivery_id == entry.delivery_id) {
+                return Ok(false);
+            }
+            entry.sequence = queue.last().map_or(1, |last| last.sequence + 1);
+            queue.push(entry);
+            Ok(true)
+        }
+
+        async fn delete_through(&self, user_id: &str, up_to: u64) -> Result<usize, OfflineStoreError> {
+            let mut queues = self.queues.lock();
+            let Some(queue) = queues.get_mut(user_id) else {
+                return Ok(0);
+            };
+            let before = queue.len();
+            queue.retain(|entry| entry.sequence > up_to);
+            Ok(before - queue.len())
+        }
+    }
+
+    fn quarantine(
+        store: &Arc<MemoryQueues>,
+        jetstream: jetstream::Context,
+        config: OfflineQuarantineConfig,
+        clock: &Arc<SimClock>,
+    ) -> OfflineQuarantine {
+        OfflineQuarantine::new(
+            store.clone(),
+            jetstream,
+            config,
+            AuditLog::tracing_only(),
+            BrokerMetrics::new().unwrap(),
+        )
+        .with_clock(clock.clone())
+    }
+
+    fn fill(store: &MemoryQueues, user_id: &str, delivery_ids: &[&str]) {
+        for delivery_id in delivery_ids {
+            store.enqueue(user_id, delivery_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_hard_purge_never_touches_the_quarantine_stream() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        // Never connected: any publish would fail the purge
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("localhost:4222")
+            .await
+            .unwrap();
+        let store = Arc::new(MemoryQueues::default());
+        fill(&store, "alice", &["d1", "d2", "d3"]);
+        let config = BrokerConfig::load().unwrap().offline_quarantine;
+        let quarantine = quarantine(&store, jetstream::new(client), config, &Arc::new(SimClock::new()));
+
+        assert_eq!(quarantine.purge("alice", PurgeMode::Hard, "dpo").await.unwrap(), 3);
+        assert!(store.delivery_ids("alice").is_empty());
+        assert_eq!(quarantine.purge("alice", PurgeMode::Soft, "dpo").await.unwrap(), 0);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_offline_purged_entries_total{mode="hard"} 3"#), "{}", rendered);
+    }
+
+    /// A fresh quarantine stream on JetStream at `NATS_URL` (default `localhost:4222`)
+    async fn stream() -> (jetstream::Context, OfflineQuarantineConfig) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+        let id = Uuid::new_v4().simple().to_string();
+        let mut config = BrokerConfig::load().unwrap().offline_quarantine;
+        config.stream = format!("QUARANTINE_TEST_{}", id);
+        config.subject = format!("test.quarantine.{}", id);
+        config.batch_size = 2;
+        jetstream
+            .create_stream(jetstream::stream::Config {
+                name: config.stream.clone(),
+                subjects: vec![format!("{}.>", config.subject)],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        (jetstream, config)
+    }
+
+    async fn quarantined(jetstream: &jetstream::Context, config: &OfflineQuarantineConfig) -> u64 {
+        let mut stream = jetstream.get_stream(&config.stream).await.unwrap();
+        stream.info().await.unwrap().state.messages
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn restore_requeues_in_purge_order_after_newer_entries() {
+        let (jetstream, config) = stream().await;
+        let store = Arc::new(MemoryQueues::default());
+        let quarantine = quarantine(&store, jetstream.clone(), config.clone(), &Arc::new(SimClock::new()));
+        fill(&store, "alice", &["d1", "d2", "d3", "d4", "d5"]);
+        fill(&store, "bob", &["b1"]);
+
+        assert_eq!(quarantine.purge("alice", PurgeMode::Soft, "ops").await.unwrap(), 5);
+        assert!(store.delivery_ids("alice").is_empty());
+        assert_eq!(quarantined(&jetstream, &config).await, 5);
+        fill(&store, "alice", &["n1"]);
+
+        let summary = quarantine.restore("alice", "ops").await.unwrap();
+        assert_eq!((summary.restored, summary.duplicates, summary.expired), (5, 0, 0));
+        assert_eq!(store.delivery_ids("alice"), ["n1", "d1", "d2", "d3", "d4", "d5"]);
+        assert_eq!(store.delivery_ids("bob"), ["b1"], "other users are untouched");
+        assert_eq!(quarantined(&jetstream, &config).await, 0);
+        assert_eq!(quarantine.restore("alice", "ops").await.unwrap().restored, 0);
+
+        // The restored entries have new sequences, so a second purge quarantines them again
+        assert_eq!(quarantine.purge("alice", PurgeMode::Soft, "ops").await.unwrap(), 6);
+        assert_eq!(quarantined(&jetstream, &config).await, 6);
+        fill(&store, "alice", &["d3"]);
+        let summary = quarantine.restore("alice", "ops").await.unwrap();
+        assert_eq!((summary.restored, summary.duplicates), (5, 1));
+        assert_eq!(store.delivery_ids("alice"), ["d3", "n1", "d1", "d2", "d4", "d5"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn the_reaper_deletes_entries_once_past_retention() {
+        let (jetstream, config) = stream().await;
+        let clock = Arc::new(SimClock::new());
+        let store = Arc::new(MemoryQueues::default());
+        let quarantine = quarantine(&store, jetstream.clone(), config.clone(), &clock);
+        fill(&store, "alice", &["a1", "a2"]);
+        fill(&store, "bob", &["b1", "b2", "b3"]);
+        fill(&store, "carol", &["c1"]);
+
+        quarantine.purge("alice", PurgeMode::Soft, "ops").await.unwrap();
+        clock.advance(HOUR);
+        quarantine.purge("bob", PurgeMode::Soft, "ops").await.unwrap();
+        quarantine.purge("carol", PurgeMode::Soft, "ops").await.unwrap();
+
+        clock.advance(config.retention - HOUR - Duration::from_millis(1));
+        assert_eq!(quarantine.reap().await.unwrap(), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(quarantine.reap().await.unwrap(), 2);
+        assert_eq!(quarantined(&jetstream, &config).await, 4);
+        assert_eq!(quarantine.restore("alice", "ops").await.unwrap().restored, 0);
+
+        // Within retention for bob; carol's expire before the reaper gets to them
+        assert_eq!(quarantine.restore("bob", "ops").await.unwrap().restored, 3);
+        clock.advance(HOUR);
+        let summary = quarantine.restore("carol", "ops").await.unwrap();
+        assert_eq!((summary.restored, summary.expired), (0, 1));
+        assert!(store.delivery_ids("carol").is_empty());
+        assert_eq!(quarantined(&jetstream, &config).await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn a_hard_purge_leaves_nothing_to_restore() {
+        let (jetstream, config) = stream().await;
+        let store = Arc::new(MemoryQueues::default());
+        let quarantine = quarantine(&store, jetstream.clone(), config.clone(), &Arc::new(SimClock::new()));
+        fill(&store, "alice", &["d1", "d2"]);
+
+        assert_eq!(quarantine.purge("alice", PurgeMode::Hard, "dpo").await.unwrap(), 2);
+        assert_eq!(quarantined(&jetstream, &config).await, 0);
+        assert_eq!(quarantine.restore("alice", "ops").await.unwrap().restored, 0);
+        assert!(store.delivery_ids("alice").is_empty());
+    }
+}
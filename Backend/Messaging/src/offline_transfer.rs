@@ -0,0 +1,730 @@
+use std::{fmt::Display, sync::Arc};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::{Buf, Bytes, BytesMut};
+use ring::{hmac, rand::SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    config::{OfflineTransferConfig, RateLimits},
+    message::types::{MessageEnvelope, Priority},
+    metrics::BrokerMetrics,
+    persist::{schema, PersistError, VersionedCodec},
+    task::{spawn_traced, TaskContext},
+};
+
+const FRAME_CODEC: VersionedCodec<ExportFrame> = VersionedCodec::new(schema::OFFLINE_EXPORT_FRAME, 1, &[]);
+
+/// Frames buffered between the store reader and a slow HTTP client
+const EXPORT_BUFFER: usize = 64;
+
+/// One delivery waiting in a user's offline queue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineEntry {
+    /// Position in the user's queue; increases with every enqueue
+    pub sequence: u64,
+    pub delivery_id: String,
+    pub priority: Priority,
+    /// Timestamp in milliseconds
+    pub enqueued_at: i64,
+    pub envelope: MessageEnvelope,
+}
+
+/// Offline queue storage as seen by export and import
+#[async_trait]
+pub trait OfflineQueueStore: Send + Sync {
+    /// Highest sequence in the user's queue, `None` when empty
+    async fn last_sequence(&self, user_id: &str) -> Result<Option<u64>, OfflineStoreError>;
+
+    /// Entries with `after < sequence <= up_to`, ascending, at most `limit`
+    async fn read_range(
+        &self,
+        user_id: &str,
+        after: u64,
+        up_to: u64,
+        limit: usize,
+    ) -> Result<Vec<OfflineEntry>, OfflineStoreError>;
+
+    /// Append under a new local sequence, keeping priority and enqueue time;
+    /// false if an entry with the same delivery ID is already queued
+    ///
+    /// JetStream-backed stores append through `idempotent_append::append`
+    /// with `AppendPurpose::OfflineQueue`, the message ID and the user, so a
+    /// crash-retried enqueue is dropped server-side and reported as `false`.
+    async fn append(&self, user_id: &str, entry: OfflineEntry) -> Result<bool, OfflineStoreError>;
+
+    /// Remove entries with `sequence <= up_to`, returning how many went
+    async fn delete_through(&self, user_id: &str, up_to: u64) -> Result<usize, OfflineStoreError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("offline queue store error: {0}")]
+pub struct OfflineStoreError(pub String);
+
+/// Wire format of an export or import body
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferFormat {
+    /// One JSON frame per line
+    #[default]
+    Ndjson,
+    /// Each frame is a big-endian u32 length followed by a versioned blob
+    Binary,
+}
+
+impl TransferFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TransferFormat::Ndjson => "application/x-ndjson",
+            TransferFormat::Binary => "application/octet-stream",
+        }
+    }
+
+    fn encode(&self, frame: &ExportFrame) -> Result<Bytes, TransferError> {
+        let mut out = match self {
+            TransferFormat::Ndjson => serde_json::to_vec(frame).map_err(|e| TransferError::Encode(e.to_string()))?,
+            TransferFormat::Binary => {
+                let body = FRAME_CODEC.encode(frame)?;
+                let mut out = Vec::with_capacity(4 + body.len());
+                out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                out.extend_from_slice(&body);
+                return Ok(out.into());
+            }
+        };
+        out.push(b'\n');
+        Ok(out.into())
+    }
+
+    fn decode(&self, raw: &[u8]) -> Result<ExportFrame, TransferError> {
+        match self {
+            TransferFormat::Ndjson => serde_json::from_slice(raw).map_err(|e| TransferError::Frame(e.to_string())),
+            TransferFormat::Binary => FRAME_CODEC.decode(raw).map_err(|e| TransferError::Frame(e.to_string())),
+        }
+    }
+}
+
+/// Resume point of an export, handed out opaque with every frame
+///
+/// `boundary` is the last sequence when the export began; entries enqueued
+/// after it are left for the next export. On the wire a cursor is its JSON,
+/// base64 encoded, then `.` and an HMAC over that under the broker's cursor
+/// secret, so `confirm` only ever deletes up to a boundary it handed out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportCursor {
+    pub user_id: String,
+    pub boundary: u64,
+    /// Last sequence sent
+    pub position: u64,
+}
+
+impl ExportCursor {
+    /// What a cursor token says, without checking its signature; only the
+    /// broker that issued it can
+    pub fn decode(token: &str) -> Result<Self, TransferError> {
+        let (payload, _) = token.split_once('.').unwrap_or((token, ""));
+        URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .ok_or_else(|| TransferError::InvalidCursor(token.to_string()))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.position >= self.boundary
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+pub enum ExportFrame {
+    Entry { cursor: String, entry: Box<OfflineEntry> },
+    /// Last frame of a transfer that reached the boundary
+    End { cursor: String, exported: u64 },
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub appended: u64,
+    /// Already queued under the same delivery ID
+    pub duplicates: u64,
+    /// Failed envelope validation
+    pub rejected: u64,
+    /// Cursor of the last frame read, to resume a broken import from
+    pub last_cursor: Option<String>,
+    /// An `end` frame was read, so the export was complete
+    pub complete: bool,
+}
+
+/// Export and import of offline queues for users moving between clusters
+///
+/// An export snapshots the queue's last sequence as its boundary and
+/// streams entries up to it in sequence order, each frame carrying a cursor
+/// to resume from if the transfer breaks; enqueues carry on meanwhile and
+/// land after the boundary, for a follow-up export. The destination imports
+/// frames in order, validating every envelope and skipping delivery IDs it
+/// already holds, so replaying an import or resuming it from an older
+/// cursor is harmless. The source keeps every exported entry until
+/// `confirm` is called with a completed cursor, which deletes up to the
+/// boundary and is audited.
+pub struct OfflineTransfer {
+    store: Arc<dyn OfflineQueueStore>,
+    config: OfflineTransferConfig,
+    limits: RateLimits,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+    /// Signs the cursors handed out with export frames
+    cursor_key: hmac::Key,
+}
+
+impl OfflineTransfer {
+    pub fn new(
+        store: Arc<dyn OfflineQueueStore>,
+        config: OfflineTransferConfig,
+        limits: RateLimits,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> anyhow::Result<Self> {
+        let cursor_key = match &config.cursor_secret_file {
+            Some(path) => {
+                let secret = std::fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+                let secret = secret.trim_ascii();
+                anyhow::ensure!(!secret.is_empty(), "{}: cursor secret is empty", path);
+                hmac::Key::new(hmac::HMAC_SHA256, secret)
+            }
+            None => {
+                warn!("No offline_transfer.cursor_secret_file set; export cursors are only valid on this process");
+                hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                    .map_err(|_| anyhow::anyhow!("failed to generate an export cursor key"))?
+            }
+        };
+        Ok(Self {
+            store,
+            config,
+            limits,
+            audit,
+            metrics,
+            cursor_key,
+        })
+    }
+
+    /// Cursor for a new export of `user_id`, or the one given to resume
+    pub async fn begin_export(&self, user_id: &str, resume: Option<&str>) -> Result<ExportCursor, TransferError> {
+        if let Some(token) = resume {
+            return self.verified_cursor(user_id, token);
+        }
+        let boundary = self.store.last_sequence(user_id).await?.unwrap_or(0);
+        Ok(ExportCursor {
+            user_id: user_id.to_string(),
+            boundary,
+            position: 0,
+        })
+    }
+
+    /// Stream frames after `cursor` up to its boundary; a store error ends the stream with it
+    pub fn export(
+        self: &Arc<Self>,
+        cursor: ExportCursor,
+        format: TransferFormat,
+    ) -> ReceiverStream<Result<Bytes, TransferError>> {
+        let (frames, stream) = mpsc::channel(EXPORT_BUFFER);
+        let transfer = Arc::clone(self);
+        spawn_traced("offline_export", TaskContext::new("offline_transfer"), async move {
+            transfer.run_export(cursor, format, frames).await;
+        });
+        ReceiverStream::new(stream)
+    }
+
+    async fn run_export(
+        &self,
+        mut cursor: ExportCursor,
+        format: TransferFormat,
+        frames: mpsc::Sender<Result<Bytes, TransferError>>,
+    ) {
+        let mut exported = 0u64;
+        while !cursor.is_complete() {
+            let batch = match self
+                .store
+                .read_range(&cursor.user_id, cursor.position, cursor.boundary, self.config.batch_size)
+                .await
+            {
+                Ok(batch) => batch,
+                Err(e) => {
+                    warn!("Offline export for {} failed at {}: {}", cursor.user_id, cursor.position, e);
+                    self.metrics.record_offline_export(exported, "failed");
+                    let _ = frames.send(Err(e.into())).await;
+                    return;
+                }
+            };
+            // Whatever is left below the boundary was confirmed or expired meanwhile
+            if batch.is_empty() {
+                cursor.position = cursor.boundary;
+                break;
+            }
+
+            for entry in batch {
+                cursor.position = entry.sequence;
+                let frame = format.encode(&ExportFrame::Entry {
+                    cursor: self.sign(&cursor),
+                    entry: Box::new(entry),
+                });
+                if frames.send(frame).await.is_err() {
+                    self.metrics.record_offline_export(exported, "interrupted");
+                    return;
+                }
+                exported += 1;
+            }
+        }
+
+        let end = format.encode(&ExportFrame::End {
+            cursor: self.sign(&cursor),
+            exported,
+        });
+        if frames.send(end).await.is_ok() {
+            info!("Exported {} offline entries for {} up to {}", exported, cursor.user_id, cursor.boundary);
+            self.metrics.record_offline_export(exported, "complete");
+        } else {
+            self.metrics.record_offline_export(exported, "interrupted");
+        }
+    }
+
+    /// Append exported frames to `user_id`'s queue in the order received
+    pub async fn import<S, E>(&self, user_id: &str, format: TransferFormat, mut body: S) -> Result<ImportSummary, TransferError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: Display,
+    {
+        let mut summary = ImportSummary::default();
+        let mut buffer = BytesMut::new();
+        loop {
+            while let Some(raw) = self.next_frame(format, &mut buffer)? {
+                self.import_frame(user_id, format.decode(&raw)?, &mut summary).await?;
+            }
+            match body.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk.map_err(|e| TransferError::Frame(e.to_string()))?),
+                None => break,
+            }
+        }
+        if !buffer.iter().all(u8::is_ascii_whitespace) {
+            return Err(TransferError::Frame("body ends mid-frame".into()));
+        }
+
+        info!(
+            "Imported offline entries for {}: {} appended, {} duplicate, {} rejected",
+            user_id, summary.appended, summary.duplicates, summary.rejected
+        );
+        Ok(summary)
+    }
+
+    fn next_frame(&self, format: TransferFormat, buffer: &mut BytesMut) -> Result<Option<Bytes>, TransferError> {
+        let max = self.config.max_frame_bytes;
+        match format {
+            TransferFormat::Ndjson => {
+                let Some(end) = buffer.iter().position(|b| *b == b'\n') else {
+                    if buffer.len() > max {
+                        return Err(TransferError::FrameTooLarge(max));
+                    }
+                    return Ok(None);
+                };
+                let line = buffer.split_to(end + 1).freeze();
+                let line = line.slice(..end);
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    return self.next_frame(format, buffer);
+                }
+                Ok(Some(line))
+            }
+            TransferFormat::Binary => {
+                if buffer.len() < 4 {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+                if len > max {
+                    return Err(TransferError::FrameTooLarge(max));
+                }
+                if buffer.len() < 4 + len {
+                    return Ok(None);
+                }
+                buffer.advance(4);
+                Ok(Some(buffer.split_to(len).freeze()))
+            }
+        }
+    }
+
+    async fn import_frame(&self, user_id: &str, frame: ExportFrame, summary: &mut ImportSummary) -> Result<(), TransferError> {
+        let (token, entry) = match frame {
+            ExportFrame::Entry { cursor, entry } => (cursor, Some(*entry)),
+            ExportFrame::End { cursor, .. } => {
+                summary.complete = true;
+                (cursor, None)
+            }
+        };
+        // Signed by the source cluster, so only its user can be checked here
+        let cursor = ExportCursor::decode(&token)?;
+        if cursor.user_id != user_id {
+            return Err(TransferError::CursorMismatch(cursor.user_id));
+        }
+        summary.last_cursor = Some(token);
+        let Some(entry) = entry else {
+            return Ok(());
+        };
+
+        if let Err(e) = entry.envelope.validate(&self.limits) {
+            warn!("Rejected imported offline entry {} for {}: {}", entry.delivery_id, user_id, e);
+            summary.rejected += 1;
+            self.metrics.record_offline_import("rejected");
+            return Ok(());
+        }
+        if self.store.append(user_id, entry).await? {
+            summary.appended += 1;
+            self.metrics.record_offline_import("appended");
+        } else {
+            summary.duplicates += 1;
+            self.metrics.record_offline_import("duplicate");
+        }
+        Ok(())
+    }
+
+    /// Delete the source entries of a completed export
+    pub async fn confirm(&self, user_id: &str, token: &str, actor: &str) -> Result<usize, TransferError> {
+        let cursor = self.verified_cursor(user_id, token)?;
+        if !cursor.is_complete() {
+            return Err(TransferError::Incomplete {
+                position: cursor.position,
+                boundary: cursor.boundary,
+            });
+        }
+
+        let deleted = self.store.delete_through(user_id, cursor.boundary).await?;
+        info!("Deleted {} exported offline entries for {} by {}", deleted, user_id, actor);
+        self.metrics.record_offline_export_deleted(deleted);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "offline.export_confirmed",
+            serde_json::json!({ "user_id": user_id, "boundary": cursor.boundary, "deleted": deleted }),
+        ));
+        Ok(deleted)
+    }
+
+    fn sign(&self, cursor: &ExportCursor) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).unwrap_or_default());
+        let tag = hmac::sign(&self.cursor_key, payload.as_bytes());
+        format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    /// A cursor this broker issued for `user_id`; anything else is refused
+    fn verified_cursor(&self, user_id: &str, token: &str) -> Result<ExportCursor, TransferError> {
+        let invalid = || TransferError::InvalidCursor(token.to_string());
+        let (payload, tag) = token.split_once('.').ok_or_else(invalid)?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).map_err(|_| invalid())?;
+        hmac::verify(&self.cursor_key, payload.as_bytes(), &tag).map_err(|_| invalid())?;
+        let cursor = ExportCursor::decode(token)?;
+        if cursor.user_id != user_id {
+            return Err(TransferError::CursorMismatch(cursor.user_id));
+        }
+        Ok(cursor)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransferError {
+    #[error(transparent)]
+    Store(#[from] OfflineStoreError),
+    #[error("invalid export cursor {0}")]
+    InvalidCursor(String),
+    #[error("export cursor belongs to user {0}")]
+    CursorMismatch(String),
+    #[error("export incomplete: at {position} of {boundary}")]
+    Incomplete { position: u64, boundary: u64 },
+    #[error("malformed frame: {0}")]
+    Frame(String),
+    #[error("frame larger than {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("failed to encode frame: {0}")]
+    Encode(String),
+}
+
+impl From<PersistError> for TransferError {
+    fn from(e: PersistError) -> Self {
+        TransferError::Encode(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, convert::Infallible};
+    use parking_lot::Mutex;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    /// Offline queues in memory, deduplicating appends by delivery ID
+    #[derive(Default)]
+    struct MemoryQueues {
+        queues: Mutex<HashMap<String, Vec<OfflineEntry>>>,
+    }
+
+    impl MemoryQueues {
+        fn enqueue(&self, user_id: &str, delivery_id: &str) {
+            let mut queues = self.queues.lock();
+            let queue = queues.entry(user_id.to_string()).or_default();
+            let sequence = queue.last().map_or(1, |last| last.sequence + 1);
+            queue.push(OfflineEntry {
+                sequence,
+                delivery_id: delivery_id.to_string(),
+                priority: Priority::Normal,
+                enqueued_at: 1_700_000_000_000 + sequence as i64,
+                envelope: envelope(user_id),
+            });
+        }
+
+        fn delivery_ids(&self, user_id: &str) -> Vec<String> {
+            self.queues.lock().get(user_id).map_or_else(Vec::new, |queue| {
+                queue.iter().map(|entry| entry.delivery_id.clone()).collect()
+            })
+        }
+    }
+
+    #[async_trait]
+    impl OfflineQueueStore for MemoryQueues {
+        async fn last_sequence(&self, user_id: &str) -> Result<Option<u64>, OfflineStoreError> {
+            Ok(self.queues.lock().get(user_id).and_then(|queue| queue.last()).map(|entry| entry.sequence))
+        }
+
+        async fn read_range(
+            &self,
+            user_id: &str,
+            after: u64,
+            up_to: u64,
+            limit: usize,
+        ) -> Result<Vec<OfflineEntry>, OfflineStoreError> {
+            Ok(self.queues.lock().get(user_id).map_or_else(Vec::new, |queue| {
+                queue
+                    .iter()
+                    .filter(|entry| entry.sequence > after && entry.sequence <= up_to)
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            }))
+        }
+
+        async fn append(&self, user_id: &str, mut entry: OfflineEntry) -> Result<bool, OfflineStoreError> {
+            let mut queues = self.queues.lock();
+            let queue = queues.entry(user_id.to_string()).or_default();
+            if queue.iter().any(|queued| queued.delivery_id == entry.delivery_id) {
+                return Ok(false);
+            }
+            entry.sequence = queue.last().map_or(1, |last| last.sequence + 1);
+            queue.push(entry);
+            Ok(true)
+        }
+
+        async fn delete_through(&self, user_id: &str, up_to: u64) -> Result<usize, OfflineStoreError> {
+            let mut queues = self.queues.lock();
+            let Some(queue) = queues.get_mut(user_id) else {
+                return Ok(0);
+            };
+            let before = queue.len();
+            queue.retain(|entry| entry.sequence > up_to);
+            Ok(before - queue.len())
+        }
+    }
+
+    fn envelope(to: &str) -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::TextMessage,
+            "carol".into(),
+            vec![to.into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    fn transfer_with(store: Arc<MemoryQueues>, secret_file: Option<String>) -> Arc<OfflineTransfer> {
+        let config = BrokerConfig::load().unwrap();
+        let mut transfer_config = config.offline_transfer;
+        transfer_config.batch_size = 2;
+        transfer_config.cursor_secret_file = secret_file;
+        Arc::new(
+            OfflineTransfer::new(
+                store,
+                transfer_config,
+                config.limits,
+                AuditLog::tracing_only(),
+                BrokerMetrics::new().unwrap(),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn transfer(store: Arc<MemoryQueues>) -> Arc<OfflineTransfer> {
+        transfer_with(store, None)
+    }
+
+    fn queue_of(user_id: &str, entries: usize) -> Arc<MemoryQueues> {
+        let store = Arc::new(MemoryQueues::default());
+        for i in 1..=entries {
+            store.enqueue(user_id, &format!("d{}", i));
+        }
+        store
+    }
+
+    /// Raw frames of an export, up to `limit` of them
+    async fn export_frames(transfer: &Arc<OfflineTransfer>, cursor: ExportCursor, limit: usize) -> Vec<Bytes> {
+        transfer
+            .export(cursor, TransferFormat::Ndjson)
+            .take(limit)
+            .map(Result::unwrap)
+            .collect()
+            .await
+    }
+
+    fn frame_cursor(raw: &Bytes) -> String {
+        match TransferFormat::Ndjson.decode(&raw[..raw.len() - 1]).unwrap() {
+            ExportFrame::Entry { cursor, .. } | ExportFrame::End { cursor, .. } => cursor,
+        }
+    }
+
+    async fn import(transfer: &OfflineTransfer, user_id: &str, frames: Vec<Bytes>) -> ImportSummary {
+        let body = tokio_stream::iter(frames.into_iter().map(Ok::<_, Infallible>));
+        transfer.import(user_id, TransferFormat::Ndjson, body).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_broken_export_resumes_from_the_last_cursor_received() {
+        let store = queue_of("alice", 5);
+        let transfer = transfer(Arc::clone(&store));
+
+        let cursor = transfer.begin_export("alice", None).await.unwrap();
+        let first = export_frames(&transfer, cursor, 3).await;
+        let resume = frame_cursor(first.last().unwrap());
+
+        let cursor = transfer.begin_export("alice", Some(&resume)).await.unwrap();
+        assert_eq!((cursor.position, cursor.boundary), (3, 5));
+        let rest = export_frames(&transfer, cursor, usize::MAX).await;
+        assert_eq!(rest.len(), 3, "entries 4 and 5, then the end frame");
+
+        let destination = Arc::new(MemoryQueues::default());
+        let summary = import(&transfer_with(Arc::clone(&destination), None), "alice", [first, rest].concat()).await;
+        assert!(summary.complete);
+        assert_eq!(summary.appended, 5);
+        assert_eq!(destination.delivery_ids("alice"), ["d1", "d2", "d3", "d4", "d5"]);
+    }
+
+    #[tokio::test]
+    async fn enqueues_during_an_export_stay_behind_for_the_next_one() {
+        let store = queue_of("alice", 3);
+        let transfer = transfer(Arc::clone(&store));
+
+        let cursor = transfer.begin_export("alice", None).await.unwrap();
+        store.enqueue("alice", "late");
+        let frames = export_frames(&transfer, cursor, usize::MAX).await;
+        assert_eq!(frames.len(), 4);
+
+        let deleted = transfer.confirm("alice", &frame_cursor(frames.last().unwrap()), "ops").await.unwrap();
+        assert_eq!(deleted, 3);
+        assert_eq!(store.delivery_ids("alice"), ["late"]);
+    }
+
+    #[tokio::test]
+    async fn reimporting_or_resuming_from_an_older_cursor_skips_what_is_queued() {
+        let source = queue_of("alice", 4);
+        let transfer = transfer(source);
+        let cursor = transfer.begin_export("alice", None).await.unwrap();
+        let frames = export_frames(&transfer, cursor, usize::MAX).await;
+
+        let destination = Arc::new(MemoryQueues::default());
+        let importer = transfer_with(Arc::clone(&destination), None);
+        let first = import(&importer, "alice", frames[..2].to_vec()).await;
+        assert_eq!((first.appended, first.duplicates, first.complete), (2, 0, false));
+
+        let replay = import(&importer, "alice", frames.clone()).await;
+        assert_eq!((replay.appended, replay.duplicates, replay.complete), (2, 2, true));
+        let again = import(&importer, "alice", frames).await;
+        assert_eq!((again.appended, again.duplicates), (0, 4));
+        assert_eq!(destination.delivery_ids("alice"), ["d1", "d2", "d3", "d4"]);
+    }
+
+    #[tokio::test]
+    async fn imports_refuse_frames_exported_for_someone_else() {
+        let transfer = transfer(queue_of("alice", 1));
+        let cursor = transfer.begin_export("alice", None).await.unwrap();
+        let frames = export_frames(&transfer, cursor, usize::MAX).await;
+
+        let destination = transfer_with(Arc::new(MemoryQueues::default()), None);
+        let body = tokio_stream::iter(frames.into_iter().map(Ok::<_, Infallible>));
+        let refused = destination.import("bob", TransferFormat::Ndjson, body).await;
+        assert!(matches!(refused, Err(TransferError::CursorMismatch(user)) if user == "alice"));
+    }
+
+    #[tokio::test]
+    async fn confirm_is_refused_until_the_export_reached_its_boundary() {
+        let store = queue_of("alice", 4);
+        let transfer = transfer(Arc::clone(&store));
+        let cursor = transfer.begin_export("alice", None).await.unwrap();
+        let frames = export_frames(&transfer, cursor, usize::MAX).await;
+
+        let partial = transfer.confirm("alice", &frame_cursor(&frames[1]), "ops").await;
+        assert!(matches!(partial, Err(TransferError::Incomplete { position: 2, boundary: 4 })));
+        assert_eq!(store.delivery_ids("alice").len(), 4);
+
+        let end = frame_cursor(frames.last().unwrap());
+        assert!(matches!(transfer.confirm("bob", &end, "ops").await, Err(TransferError::CursorMismatch(_))));
+        assert_eq!(transfer.confirm("alice", &end, "ops").await.unwrap(), 4);
+        assert!(store.delivery_ids("alice").is_empty());
+    }
+
+    #[tokio::test]
+    async fn forged_cursors_are_rejected_before_anything_is_deleted() {
+        let store = queue_of("alice", 4);
+        let transfer = transfer(Arc::clone(&store));
+        let cursor = transfer.begin_export("alice", None).await.unwrap();
+        let frames = export_frames(&transfer, cursor, 1).await;
+        let issued = frame_cursor(&frames[0]);
+        let (_, tag) = issued.split_once('.').unwrap();
+
+        // Past the boundary so it reads as complete, with and without the issued tag
+        let forged = ExportCursor {
+            user_id: "alice".into(),
+            boundary: 1,
+            position: 4,
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        let signed_elsewhere = transfer_with(Arc::new(MemoryQueues::default()), None).sign(&forged);
+        for token in [payload.clone(), format!("{}.{}", payload, tag), signed_elsewhere] {
+            assert!(
+                matches!(transfer.confirm("alice", &token, "ops").await, Err(TransferError::InvalidCursor(_))),
+                "{} was accepted",
+                token
+            );
+            assert!(matches!(transfer.begin_export("alice", Some(&token)).await, Err(TransferError::InvalidCursor(_))));
+        }
+        assert_eq!(store.delivery_ids("alice").len(), 4);
+    }
+
+    #[tokio::test]
+    async fn cursors_signed_with_a_shared_secret_outlive_the_process() {
+        let secret = std::env::temp_dir().join(format!("offline-cursor-{}", Uuid::new_v4().simple()));
+        std::fs::write(&secret, "shared-cursor-secret\n").unwrap();
+        let secret = Some(secret.to_string_lossy().into_owned());
+
+        let store = queue_of("alice", 2);
+        let before = transfer_with(Arc::clone(&store), secret.clone());
+        let cursor = before.begin_export("alice", None).await.unwrap();
+        let end = frame_cursor(export_frames(&before, cursor, usize::MAX).await.last().unwrap());
+
+        let after = transfer_with(Arc::clone(&store), secret);
+        assert_eq!(after.confirm("alice", &end, "ops").await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,485 @@
+//! Postgres outbox ingestion adapter (`outbox` feature)
+//!
+//! Expected table layout:
+//!
+//! ```sql
+//! CREATE TABLE message_outbox (
+//!     id           BIGSERIAL PRIMARY KEY,
+//!     payload      TEXT        NOT NULL, -- JSON MessageEnvelope
+//!     created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+//!     processed_at TIMESTAMPTZ,
+//!     error        TEXT
+//! );
+//! ```
+#![cfg(feature = "outbox")]
+
+use std::{future::Future, sync::Arc};
+use async_nats::{jetstream, HeaderMap};
+use tokio_postgres::{Client, NoTls};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backoff::{BackoffPolicy, Jitter},
+    config::OutboxConfig,
+    envelope_guard::EnvelopeGuard,
+    ingress::{IngressGate, IngressRejection},
+    ingress_admission::SourceClass,
+    maintenance::MaintenanceMode,
+    metrics::BrokerMetrics,
+    policy::IngressSource,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Source name used for outbox traffic in policy and attestation config
+pub const OUTBOX_SOURCE: &str = "outbox";
+
+/// Polls unprocessed outbox rows into the ingress pipeline
+///
+/// Rows are marked processed in the same database transaction that read
+/// them, and only after JetStream acknowledged the publish. A crash between
+/// the two re-reads the rows; the row ID is the message ID and the
+/// `Nats-Msg-Id`, so the duplicate publish is dropped by dedup.
+pub struct OutboxPoller {
+    config: OutboxConfig,
+    ingress: Arc<IngressGate>,
+    maintenance: Arc<MaintenanceMode>,
+    guard: Arc<EnvelopeGuard>,
+    jetstream: jetstream::Context,
+    ingress_subject: String,
+    metrics: BrokerMetrics,
+}
+
+impl OutboxPoller {
+    pub fn new(
+        config: OutboxConfig,
+        ingress: Arc<IngressGate>,
+        maintenance: Arc<MaintenanceMode>,
+        guard: Arc<EnvelopeGuard>,
+        jetstream: jetstream::Context,
+        ingress_subject: String,
+        metrics: BrokerMetrics,
+    ) -> anyhow::Result<Self> {
+        if !is_valid_identifier(&config.table) {
+            anyhow::bail!("invalid outbox table name: {}", config.table);
+        }
+
+        Ok(Self {
+            config,
+            ingress,
+            maintenance,
+            guard,
+            jetstream,
+            ingress_subject,
+            metrics,
+        })
+    }
+
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        spawn_traced("outbox_poller", TaskContext::new("outbox"), async move { self.run().await })
+    }
+
+    async fn run(self) {
+        let policy = BackoffPolicy::new(self.config.poll_interval)
+            .max(self.config.max_backoff)
+            .jitter(Jitter::Equal);
+        let mut backoff = policy.iter();
+
+        loop {
+            match self.connect().await {
+                Ok(mut client) => {
+                    info!("Outbox poller connected, reading {}", self.config.table);
+                    backoff = policy.iter();
+
+                    loop {
+                        // Rows stay unprocessed in the table until maintenance ends
+                        self.maintenance.wait_inactive().await;
+                        match self.poll_once(&mut client).await {
+                            Ok(0) => tokio::time::sleep(self.config.poll_interval).await,
+                            Ok(count) => debug!("Ingested {} outbox rows", count),
+                            Err(e) => {
+                                warn!("Outbox poll failed: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Outbox database connection failed: {}", e),
+            }
+
+            // Back off politely before reconnecting
+            let delay = backoff.next().unwrap_or(self.config.max_backoff);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn connect(&self) -> Result<Client, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::connect(&self.config.connection_string, NoTls).await?;
+        spawn_traced("outbox_connection", TaskContext::new("outbox"), async move {
+            if let Err(e) = connection.await {
+                warn!("Outbox database connection closed: {}", e);
+            }
+        });
+        Ok(client)
+    }
+
+    /// Ingest one batch, returning the number of rows processed
+    async fn poll_once(&self, client: &mut Client) -> Result<usize, tokio_postgres::Error> {
+        self.update_lag(client).await?;
+        poll_batch(client, &self.config.table, self.config.batch_size, |id, payload| async move {
+            let result = self.ingest(id, &payload).await;
+            if let Err(OutboxRowError::Publish(reason)) = &result {
+                warn!("Outbox row {} publish failed: {}", id, reason);
+                self.metrics.record_outbox_row("publish_failed");
+            }
+            result
+        })
+        .await
+    }
+
+    async fn ingest(&self, id: i64, payload: &str) -> Result<(), OutboxRowError> {
+        let mut envelope = self.guard.parse(payload.as_bytes()).map_err(|e| {
+            self.metrics.record_outbox_row("malformed");
+            OutboxRowError::Rejected(format!("malformed payload: {}", e))
+        })?;
+
+        // The row ID is the idempotency key
+        envelope.message_id = message_id(id);
+
+        let source = IngressSource {
+            gateway_id: OUTBOX_SOURCE.to_string(),
+            service_account: Some(self.config.table.clone()),
+            class: SourceClass::Bridge,
+        };
+        match self.ingress.admit(&source, &mut envelope).await {
+            Ok(()) => {}
+            // Paused rows stay in the table until ingestion resumes
+            Err(
+                e @ (IngressRejection::Paused(_)
+                | IngressRejection::Maintenance(_)
+                | IngressRejection::ArchiveLookup(_)),
+            ) => {
+                return Err(OutboxRowError::Publish(e.to_string()))
+            }
+            Err(e) => {
+                self.metrics.record_outbox_row("rejected");
+                return Err(OutboxRowError::Rejected(e.to_string()));
+            }
+        }
+
+        let body = serde_json::to_vec(&envelope).map_err(|e| OutboxRowError::Rejected(e.to_string()))?;
+        let mut headers = HeaderMap::new();
+        headers.insert("Nats-Msg-Id", envelope.message_id.as_str());
+
+        self.jetstream
+            .publish_with_headers(self.ingress_subject.clone(), headers, body.into())
+            .await
+            .map_err(|e| OutboxRowError::Publish(e.to_string()))?
+            .await
+            .map_err(|e| OutboxRowError::Publish(e.to_string()))?;
+
+        self.metrics.record_nats_published(1);
+        self.metrics.record_outbox_row("accepted");
+        Ok(())
+    }
+
+    async fn update_lag(&self, client: &Client) -> Result<(), tokio_postgres::Error> {
+        let lag = oldest_unprocessed_age(client, &self.config.table).await?;
+        self.metrics.update_outbox_lag(lag);
+        Ok(())
+    }
+}
+
+/// Read one batch of unprocessed rows and mark those `ingest` settled
+///
+/// Marks are written in the transaction that locked the rows, so rows whose
+/// publish was acknowledged but whose transaction never committed are read
+/// again by the next poll.
+async fn poll_batch<F, Fut>(
+    client: &mut Client,
+    table: &str,
+    batch_size: i64,
+    mut ingest: F,
+) -> Result<usize, tokio_postgres::Error>
+where
+    F: FnMut(i64, String) -> Fut,
+    Fut: Future<Output = Result<(), OutboxRowError>>,
+{
+    let transaction = client.transaction().await?;
+    let rows = transaction
+        .query(
+            &format!(
+                "SELECT id, payload FROM {} WHERE processed_at IS NULL ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+                table
+            ),
+            &[&batch_size],
+        )
+        .await?;
+
+    if rows.is_empty() {
+        transaction.commit().await?;
+        return Ok(0);
+    }
+
+    let mut accepted: Vec<i64> = Vec::with_capacity(rows.len());
+    let mut rejected: Vec<(i64, String)> = Vec::new();
+
+    for row in &rows {
+        let id: i64 = row.get(0);
+        let payload: String = row.get(1);
+
+        match ingest(id, payload).await {
+            Ok(()) => accepted.push(id),
+            Err(OutboxRowError::Rejected(reason)) => rejected.push((id, reason)),
+            // Leave this and later rows unprocessed; they are retried next poll
+            Err(OutboxRowError::Publish(_)) => break,
+        }
+    }
+
+    transaction
+        .execute(
+            &format!("UPDATE {} SET processed_at = now() WHERE id = ANY($1)", table),
+            &[&accepted],
+        )
+        .await?;
+    for (id, reason) in &rejected {
+        transaction
+            .execute(
+                &format!("UPDATE {} SET processed_at = now(), error = $2 WHERE id = $1", table),
+                &[id, reason],
+            )
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(accepted.len() + rejected.len())
+}
+
+/// Message ID, and so `Nats-Msg-Id`, for an outbox row
+fn message_id(row_id: i64) -> String {
+    format!("outbox-{}", row_id)
+}
+
+/// Age in seconds of the oldest unprocessed row, zero when caught up
+async fn oldest_unprocessed_age(client: &Client, table: &str) -> Result<f64, tokio_postgres::Error> {
+    let row = client
+        .query_one(
+            &format!(
+                "SELECT COALESCE(EXTRACT(EPOCH FROM now() - min(created_at)), 0)::float8 FROM {} WHERE processed_at IS NULL",
+                table
+            ),
+            &[],
+        )
+        .await?;
+    Ok(row.get(0))
+}
+
+fn is_valid_identifier(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+}
+
+enum OutboxRowError {
+    /// Row can never be ingested; mark it processed with the reason
+    Rejected(String),
+    /// Transient failure; leave the row for the next poll
+    Publish(String),
+}
+
+/// The transactional tests run against Postgres at `OUTBOX_TEST_DATABASE_URL`
+/// and JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test --features outbox -- --ignored outbox`
+#[cfg(test)]
+mod tests {
+    use std::{pin::Pin, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    type Publish = Pin<Box<dyn Future<Output = Result<(), OutboxRowError>> + Send>>;
+
+    struct Fixture {
+        client: Client,
+        table: String,
+        jetstream: jetstream::Context,
+        subject: String,
+    }
+
+    impl Fixture {
+        async fn new(rows: usize) -> Self {
+            let url = std::env::var("OUTBOX_TEST_DATABASE_URL").expect("OUTBOX_TEST_DATABASE_URL");
+            let (client, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+            tokio::spawn(connection);
+
+            let name = format!("outbox_test_{}", Uuid::new_v4().simple());
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE {name} (
+                        id           BIGSERIAL PRIMARY KEY,
+                        payload      TEXT        NOT NULL,
+                        created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        processed_at TIMESTAMPTZ,
+                        error        TEXT
+                    )"
+                ))
+                .await
+                .unwrap();
+            for i in 0..rows {
+                client
+                    .execute(&format!("INSERT INTO {} (payload) VALUES ($1)", name), &[&format!("row-{}", i)])
+                    .await
+                    .unwrap();
+            }
+
+            let nats = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(nats).await.unwrap());
+            let subject = format!("{}.ingress", name);
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: name.clone(),
+                    subjects: vec![subject.clone()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            Self {
+                client,
+                table: name,
+                jetstream,
+                subject,
+            }
+        }
+
+        /// Publish rows the way the poller does, keyed by row ID
+        fn publisher(&self) -> impl FnMut(i64, String) -> Publish {
+            let jetstream = self.jetstream.clone();
+            let subject = self.subject.clone();
+            move |id, payload| {
+                let jetstream = jetstream.clone();
+                let subject = subject.clone();
+                Box::pin(async move {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("Nats-Msg-Id", message_id(id).as_str());
+                    jetstream
+                        .publish_with_headers(subject, headers, payload.into_bytes().into())
+                        .await
+                        .map_err(|e| OutboxRowError::Publish(e.to_string()))?
+                        .await
+                        .map_err(|e| OutboxRowError::Publish(e.to_string()))?;
+                    Ok(())
+                })
+            }
+        }
+
+        async fn unprocessed(&self) -> i64 {
+            let row = self
+                .client
+                .query_one(&format!("SELECT count(*) FROM {} WHERE processed_at IS NULL", self.table), &[])
+                .await
+                .unwrap();
+            row.get(0)
+        }
+
+        async fn published(&self) -> u64 {
+            let mut stream = self.jetstream.get_stream(&self.table).await.unwrap();
+            stream.info().await.unwrap().state.messages
+        }
+    }
+
+    #[test]
+    fn table_names_must_be_plain_identifiers() {
+        assert!(is_valid_identifier("message_outbox"));
+        assert!(is_valid_identifier("billing.message_outbox"));
+        assert!(!is_valid_identifier(""));
+        assert!(!is_valid_identifier("outbox;DROP TABLE users"));
+        assert!(!is_valid_identifier("billing..outbox"));
+        assert!(!is_valid_identifier("outbox "));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres and a JetStream server"]
+    async fn crash_between_accept_and_mark_republishes_without_duplicates() {
+        let mut fixture = Fixture::new(3).await;
+        let table = fixture.table.clone();
+
+        // Every row is published and acknowledged, then the poller dies before commit
+        let mut publish = fixture.publisher();
+        let crashing = poll_batch(&mut fixture.client, &table, 10, |id, payload| {
+            let published = publish(id, payload);
+            async move {
+                published.await?;
+                if id == 3 {
+                    std::future::pending::<()>().await;
+                }
+                Ok(())
+            }
+        });
+        assert!(tokio::time::timeout(Duration::from_secs(1), crashing).await.is_err());
+        assert_eq!(fixture.unprocessed().await, 3);
+        assert_eq!(fixture.published().await, 3);
+
+        // The restarted poller re-reads and re-publishes; dedup drops the copies
+        let publish = fixture.publisher();
+        let processed = poll_batch(&mut fixture.client, &table, 10, publish).await.unwrap();
+        assert_eq!(processed, 3);
+        assert_eq!(fixture.unprocessed().await, 0);
+        assert_eq!(fixture.published().await, 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres and a JetStream server"]
+    async fn publish_failure_leaves_the_row_and_later_rows_for_the_next_poll() {
+        let mut fixture = Fixture::new(4).await;
+        let table = fixture.table.clone();
+
+        let mut publish = fixture.publisher();
+        let processed = poll_batch(&mut fixture.client, &table, 10, |id, payload| {
+            let published = publish(id, payload);
+            async move {
+                match id {
+                    1 => Err(OutboxRowError::Rejected("malformed payload".into())),
+                    3 => Err(OutboxRowError::Publish("no responders".into())),
+                    _ => published.await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(processed, 2);
+        assert_eq!(fixture.unprocessed().await, 2);
+        let row = fixture
+            .client
+            .query_one(&format!("SELECT error FROM {} WHERE id = 1", table), &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("malformed payload"));
+
+        let publish = fixture.publisher();
+        let processed = poll_batch(&mut fixture.client, &table, 10, publish).await.unwrap();
+        assert_eq!(processed, 2);
+        assert_eq!(fixture.unprocessed().await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres and a JetStream server"]
+    async fn lag_is_the_age_of_the_oldest_unprocessed_row() {
+        let mut fixture = Fixture::new(2).await;
+        let table = fixture.table.clone();
+        fixture
+            .client
+            .execute(&format!("UPDATE {} SET created_at = now() - interval '30 seconds' WHERE id = 1", table), &[])
+            .await
+            .unwrap();
+
+        let lag = oldest_unprocessed_age(&fixture.client, &table).await.unwrap();
+        assert!((30.0..60.0).contains(&lag), "lag {}", lag);
+
+        let publish = fixture.publisher();
+        poll_batch(&mut fixture.client, &table, 10, publish).await.unwrap();
+        assert_eq!(oldest_unprocessed_age(&fixture.client, &table).await.unwrap(), 0.0);
+    }
+}
@@ -0,0 +1,566 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::PathOverrideConfig,
+    message::types::{MessageEnvelope, MessageType},
+    metrics::BrokerMetrics,
+    policy::StrMatcher,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Pipeline feature an override can force on or off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathFeature {
+    /// Broadcast fanout of large groups
+    Broadcast,
+    /// Dictionary compression of egress payloads
+    Compression,
+    /// Coalescing of thread-activity markers within `threads.marker_window`
+    Coalescing,
+    /// `ForwardMessage` to the owning broker instead of the NATS ingress path
+    DirectForwarding,
+}
+
+impl PathFeature {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PathFeature::Broadcast => "broadcast",
+            PathFeature::Compression => "compression",
+            PathFeature::Coalescing => "coalescing",
+            PathFeature::DirectForwarding => "direct_forwarding",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideEffect {
+    /// Use the feature wherever it's possible at all
+    Force,
+    /// Never use the feature
+    Forbid,
+}
+
+/// One override as issued on the control plane
+///
+/// Empty match lists match anything. String patterns are exact, or a prefix
+/// when they end in `*`, as in policy rules.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathOverrideRule {
+    pub id: String,
+    pub feature: PathFeature,
+    pub effect: OverrideEffect,
+    #[serde(default)]
+    pub tenants: Vec<String>,
+    #[serde(default)]
+    pub conversations: Vec<String>,
+    /// Recipient gateway for egress decisions, source gateway for ingress ones
+    #[serde(default)]
+    pub gateways: Vec<String>,
+    #[serde(default)]
+    pub kinds: Vec<MessageType>,
+}
+
+/// What a decision point knows about the traffic it's deciding for
+pub struct PathContext<'a> {
+    pub tenant: Option<&'a str>,
+    pub conversation_id: &'a str,
+    pub gateway_id: Option<&'a str>,
+    pub kind: MessageType,
+}
+
+impl<'a> PathContext<'a> {
+    /// `conversation_id` is passed in so callers compute it once per message
+    pub fn of(envelope: &'a MessageEnvelope, conversation_id: &'a str, gateway_id: Option<&'a str>) -> Self {
+        Self {
+            tenant: envelope.tenant_id.as_deref(),
+            conversation_id,
+            gateway_id,
+            kind: envelope.message_type,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveOverride {
+    pub rule: PathOverrideRule,
+    pub issued_by: String,
+    /// Timestamp in milliseconds
+    pub expires_at: i64,
+    #[serde(skip)]
+    deadline: Instant,
+}
+
+struct CompiledOverride {
+    id: Arc<str>,
+    effect: OverrideEffect,
+    kinds: u32,
+    tenants: StrMatcher,
+    conversations: StrMatcher,
+    gateways: StrMatcher,
+    deadline: Instant,
+}
+
+impl CompiledOverride {
+    fn compile(active: &ActiveOverride) -> Self {
+        let rule = &active.rule;
+        Self {
+            id: Arc::from(rule.id.as_str()),
+            effect: rule.effect,
+            kinds: match rule.kinds.is_empty() {
+                true => u32::MAX,
+                false => rule.kinds.iter().fold(0, |mask, kind| mask | 1 << *kind as u32),
+            },
+            tenants: StrMatcher::compile(&rule.tenants),
+            conversations: StrMatcher::compile(&rule.conversations),
+            gateways: StrMatcher::compile(&rule.gateways),
+            deadline: active.deadline,
+        }
+    }
+
+    fn matches(&self, path: &PathContext<'_>, now: Instant) -> bool {
+        self.deadline > now
+            && self.kinds & (1 << path.kind as u32) != 0
+            && self.conversations.matches(path.conversation_id)
+            && self.tenants.matches_opt(path.tenant)
+            && self.gateways.matches_opt(path.gateway_id)
+    }
+}
+
+/// Per-traffic overrides of delivery path decisions, for tests and incidents
+///
+/// Rules compile into the policy engine's matchers behind an `ArcSwap`, by
+/// feature, so a decision point with no rules for its feature pays one
+/// load and a map lookup. Decision points pass a `PathContext` and their
+/// own decision to `decide`, which returns it unchanged unless a live rule
+/// matches. When rules conflict, `Forbid` beats `Force` whatever their
+/// order or specificity: an override meant to route around a bug must not
+/// be undone by a broader one. Forcing a feature can't make it possible
+/// where it isn't (no shared dictionary, no peer address); it only skips
+/// the broker's own choice not to use it. Rules lapse at their TTL even
+/// before the expiry task removes them, and every change is audited.
+///
+/// There is no envelope version selection to override yet; a feature for
+/// it belongs here once egress can choose between versions.
+pub struct PathOverrides {
+    compiled: ArcSwap<HashMap<PathFeature, Vec<CompiledOverride>>>,
+    /// Source of truth; `compiled` is rebuilt from it on every change
+    active: Mutex<HashMap<String, ActiveOverride>>,
+    config: PathOverrideConfig,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl PathOverrides {
+    pub fn new(config: PathOverrideConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        Self {
+            compiled: ArcSwap::from_pointee(HashMap::new()),
+            active: Mutex::new(HashMap::new()),
+            config,
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// `default` unless a live rule for `feature` matches `path`
+    pub fn decide(&self, path: &PathContext<'_>, feature: PathFeature, default: bool) -> bool {
+        let compiled = self.compiled.load();
+        let Some(rules) = compiled.get(&feature) else {
+            return default;
+        };
+        let now = self.clock.now_instant();
+        let mut matched = rules.iter().filter(|rule| rule.matches(path, now));
+        let Some(first) = matched.next() else {
+            return default;
+        };
+        let winner = match first.effect {
+            OverrideEffect::Forbid => first,
+            OverrideEffect::Force => matched.find(|rule| rule.effect == OverrideEffect::Forbid).unwrap_or(first),
+        };
+        let decision = winner.effect == OverrideEffect::Force;
+        if decision != default {
+            self.metrics.record_path_override_applied(feature.as_str(), &winner.id);
+        }
+        decision
+    }
+
+    /// Add or replace the rule with this ID
+    pub fn set(&self, rule: PathOverrideRule, ttl: Option<Duration>, issued_by: &str) -> ActiveOverride {
+        let ttl = ttl.unwrap_or(self.config.default_ttl).min(self.config.max_ttl);
+        let active = ActiveOverride {
+            rule,
+            issued_by: issued_by.to_string(),
+            expires_at: self.clock.now_millis() + ttl.as_millis() as i64,
+            deadline: self.clock.now_instant() + ttl,
+        };
+
+        {
+            let mut overrides = self.active.lock();
+            overrides.insert(active.rule.id.clone(), active.clone());
+            self.publish(&overrides);
+        }
+
+        warn!(
+            "Path override {} ({:?} {}) set by {} for {:?}",
+            active.rule.id,
+            active.rule.effect,
+            active.rule.feature.as_str(),
+            issued_by,
+            ttl
+        );
+        self.audit.record(AuditEntry::new(
+            issued_by,
+            "path_override.set",
+            serde_json::json!({
+                "rule": active.rule,
+                "ttl_seconds": ttl.as_secs(),
+            }),
+        ));
+        active
+    }
+
+    /// Remove a rule; false if none was active
+    pub fn clear(&self, id: &str, issued_by: &str) -> bool {
+        let removed = {
+            let mut overrides = self.active.lock();
+            let removed = overrides.remove(id).is_some();
+            if removed {
+                self.publish(&overrides);
+            }
+            removed
+        };
+
+        if removed {
+            info!("Path override {} cleared by {}", id, issued_by);
+            self.audit.record(AuditEntry::new(
+                issued_by,
+                "path_override.cleared",
+                serde_json::json!({ "id": id }),
+            ));
+        }
+        removed
+    }
+
+    pub fn list(&self) -> Vec<ActiveOverride> {
+        let now = self.clock.now_instant();
+        self.active
+            .lock()
+            .values()
+            .filter(|active| active.deadline > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Drop rules past their TTL
+    pub fn expire(&self) {
+        let now = self.clock.now_instant();
+        let expired: Vec<String> = {
+            let mut overrides = self.active.lock();
+            let expired: Vec<String> = overrides
+                .values()
+                .filter(|active| active.deadline <= now)
+                .map(|active| active.rule.id.clone())
+                .collect();
+            if expired.is_empty() {
+                return;
+            }
+            for id in &expired {
+                overrides.remove(id);
+            }
+            self.publish(&overrides);
+            expired
+        };
+
+        for id in expired {
+            info!("Path override {} expired", id);
+            self.audit.record(AuditEntry::new(
+                "ttl",
+                "path_override.cleared",
+                serde_json::json!({ "id": id }),
+            ));
+        }
+    }
+
+    pub fn spawn_expiry_task(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let overrides = Arc::clone(self);
+        spawn_traced("path_override_expiry", TaskContext::new("path_override"), async move {
+            loop {
+                overrides.clock.sleep(Duration::from_secs(1)).await;
+                overrides.expire();
+            }
+        })
+    }
+
+    fn publish(&self, overrides: &HashMap<String, ActiveOverride>) {
+        let mut compiled: HashMap<PathFeature, Vec<CompiledOverride>> = HashMap::new();
+        for active in overrides.values() {
+            compiled
+                .entry(active.rule.feature)
+                .or_default()
+                .push(CompiledOverride::compile(active));
+        }
+        self.metrics.update_path_overrides(overrides.len());
+        self.compiled.store(Arc::new(compiled));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::{BrokerConfig, DegradationConfig, DegradationLevels},
+        degradation::{DegradationLevel, DegradationSwitchboard},
+        message::types::EncryptedPayload,
+    };
+
+    const FEATURES: [PathFeature; 4] = [
+        PathFeature::Broadcast,
+        PathFeature::Compression,
+        PathFeature::Coalescing,
+        PathFeature::DirectForwarding,
+    ];
+
+    fn overrides() -> (PathOverrides, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let config = BrokerConfig::load().unwrap().path_override;
+        let overrides = PathOverrides::new(config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        (overrides, clock)
+    }
+
+    /// Matches all traffic until narrowed
+    fn rule(id: &str, feature: PathFeature, effect: OverrideEffect) -> PathOverrideRule {
+        PathOverrideRule {
+            id: id.into(),
+            feature,
+            effect,
+            tenants: Vec::new(),
+            conversations: Vec::new(),
+            gateways: Vec::new(),
+            kinds: Vec::new(),
+        }
+    }
+
+    fn path<'a>(tenant: Option<&'a str>, conversation_id: &'a str, gateway_id: Option<&'a str>, kind: MessageType) -> PathContext<'a> {
+        PathContext {
+            tenant,
+            conversation_id,
+            gateway_id,
+            kind,
+        }
+    }
+
+    fn chat<'a>() -> PathContext<'a> {
+        path(Some("acme"), "group-1", Some("gw-1"), MessageType::GroupMessage)
+    }
+
+    #[test]
+    fn each_feature_is_decided_by_its_own_rules() {
+        let (overrides, _) = overrides();
+        for feature in FEATURES {
+            overrides.set(rule(feature.as_str(), feature, OverrideEffect::Force), None, "ops");
+            assert!(overrides.decide(&chat(), feature, false), "{:?}", feature);
+            for other in FEATURES.into_iter().filter(|other| *other != feature) {
+                assert!(!overrides.decide(&chat(), other, false), "{:?} moved {:?}", feature, other);
+            }
+
+            // Same ID replaces the rule
+            overrides.set(rule(feature.as_str(), feature, OverrideEffect::Forbid), None, "ops");
+            assert!(!overrides.decide(&chat(), feature, true), "{:?}", feature);
+            assert!(overrides.clear(feature.as_str(), "ops"));
+            assert!(overrides.decide(&chat(), feature, true));
+            assert!(!overrides.decide(&chat(), feature, false));
+        }
+        assert!(!overrides.clear("broadcast", "ops"));
+    }
+
+    #[test]
+    fn rules_match_on_every_dimension() {
+        let (overrides, _) = overrides();
+        let mut narrow = rule("narrow", PathFeature::Compression, OverrideEffect::Forbid);
+        narrow.tenants = vec!["ac*".into()];
+        narrow.conversations = vec!["group-1".into()];
+        narrow.gateways = vec!["gw-1".into()];
+        narrow.kinds = vec![MessageType::GroupMessage, MessageType::TextMessage];
+        overrides.set(narrow, None, "ops");
+
+        let compressed = |path: &PathContext<'_>| overrides.decide(path, PathFeature::Compression, true);
+        assert!(!compressed(&chat()));
+        assert!(!compressed(&path(Some("acme-eu"), "group-1", Some("gw-1"), MessageType::TextMessage)));
+        assert!(compressed(&path(Some("globex"), "group-1", Some("gw-1"), MessageType::GroupMessage)));
+        assert!(compressed(&path(None, "group-1", Some("gw-1"), MessageType::GroupMessage)));
+        assert!(compressed(&path(Some("acme"), "group-2", Some("gw-1"), MessageType::GroupMessage)));
+        assert!(compressed(&path(Some("acme"), "group-1", Some("gw-2"), MessageType::GroupMessage)));
+        assert!(compressed(&path(Some("acme"), "group-1", None, MessageType::GroupMessage)));
+        assert!(compressed(&path(Some("acme"), "group-1", Some("gw-1"), MessageType::Delivered)));
+
+        // Empty lists match traffic with nothing to match against too
+        overrides.set(rule("broad", PathFeature::Compression, OverrideEffect::Forbid), None, "ops");
+        assert!(!compressed(&path(None, "dm:a:b", None, MessageType::Delivered)));
+    }
+
+    #[test]
+    fn forbid_beats_force_whatever_the_order_or_specificity() {
+        for forbid_first in [true, false] {
+            let (overrides, _) = overrides();
+            let mut specific_force = rule("specific-force", PathFeature::Broadcast, OverrideEffect::Force);
+            specific_force.conversations = vec!["group-1".into()];
+            specific_force.gateways = vec!["gw-1".into()];
+            let broad_forbid = rule("broad-forbid", PathFeature::Broadcast, OverrideEffect::Forbid);
+            if forbid_first {
+                overrides.set(broad_forbid, None, "ops");
+                overrides.set(specific_force, None, "ops");
+            } else {
+                overrides.set(specific_force, None, "ops");
+                overrides.set(broad_forbid, None, "ops");
+            }
+            assert!(!overrides.decide(&chat(), PathFeature::Broadcast, false));
+            assert!(!overrides.decide(&chat(), PathFeature::Broadcast, true));
+
+            // With the forbid gone, the force applies
+            overrides.clear("broad-forbid", "ops");
+            assert!(overrides.decide(&chat(), PathFeature::Broadcast, false));
+        }
+
+        // Many forces don't outvote one forbid
+        let (overrides, _) = overrides();
+        for index in 0..8 {
+            overrides.set(rule(&format!("force-{}", index), PathFeature::Coalescing, OverrideEffect::Force), None, "ops");
+        }
+        let mut forbid = rule("forbid", PathFeature::Coalescing, OverrideEffect::Forbid);
+        forbid.conversations = vec!["group-*".into()];
+        overrides.set(forbid, None, "ops");
+        assert!(!overrides.decide(&chat(), PathFeature::Coalescing, true));
+        assert!(overrides.decide(&path(None, "dm:a:b", None, MessageType::TextMessage), PathFeature::Coalescing, false));
+    }
+
+    #[test]
+    fn overrides_lapse_at_their_ttl_before_the_expiry_task_runs() {
+        let audit_path = std::env::temp_dir().join(format!("path-override-audit-{}", uuid::Uuid::new_v4().simple()));
+        let config = BrokerConfig::load().unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let audit = AuditLog::open(Some(&audit_path.to_string_lossy()), &config.audit, metrics.clone()).unwrap();
+        let clock = Arc::new(SimClock::new());
+        let overrides = PathOverrides::new(config.path_override.clone(), audit, metrics).with_clock(clock.clone());
+
+        let short = overrides.set(
+            rule("short", PathFeature::DirectForwarding, OverrideEffect::Forbid),
+            Some(Duration::from_secs(60)),
+            "ops",
+        );
+        assert_eq!(short.expires_at, clock.now_millis() + 60_000);
+        // Asking for more than the maximum gets the maximum
+        let long = overrides.set(
+            rule("long", PathFeature::Broadcast, OverrideEffect::Force),
+            Some(Duration::from_secs(86_400 * 7)),
+            "ops",
+        );
+        assert_eq!(long.expires_at, clock.now_millis() + config.path_override.max_ttl.as_millis() as i64);
+        let default = overrides.set(rule("default", PathFeature::Compression, OverrideEffect::Forbid), None, "ops");
+        assert_eq!(default.expires_at, clock.now_millis() + config.path_override.default_ttl.as_millis() as i64);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!overrides.decide(&chat(), PathFeature::DirectForwarding, true));
+        clock.advance(Duration::from_secs(1));
+        assert!(overrides.decide(&chat(), PathFeature::DirectForwarding, true));
+        let mut listed: Vec<String> = overrides.list().into_iter().map(|active| active.rule.id).collect();
+        listed.sort();
+        assert_eq!(listed, ["default", "long"]);
+
+        overrides.expire();
+        clock.advance(config.path_override.default_ttl);
+        assert!(overrides.decide(&chat(), PathFeature::Compression, true));
+        assert!(overrides.decide(&chat(), PathFeature::Broadcast, false));
+        overrides.expire();
+        clock.advance(config.path_override.max_ttl);
+        overrides.expire();
+        assert!(overrides.list().is_empty());
+        assert!(overrides.active.lock().is_empty());
+
+        let written = std::fs::read_to_string(&audit_path).unwrap();
+        assert_eq!(written.matches("path_override.set").count(), 3, "{}", written);
+        assert_eq!(written.matches("path_override.cleared").count(), 3, "{}", written);
+        assert!(written.contains(r#""ttl_seconds":86400"#), "{}", written);
+        let _ = std::fs::remove_file(&audit_path);
+    }
+
+    #[test]
+    fn applications_are_counted_only_when_they_change_the_decision() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let (overrides, _) = overrides();
+            overrides.set(rule("force", PathFeature::Broadcast, OverrideEffect::Force), None, "ops");
+            overrides.set(rule("forbid", PathFeature::Compression, OverrideEffect::Forbid), None, "ops");
+            for _ in 0..3 {
+                overrides.decide(&chat(), PathFeature::Broadcast, false);
+                overrides.decide(&chat(), PathFeature::Broadcast, true);
+                overrides.decide(&chat(), PathFeature::Compression, false);
+            }
+            overrides.decide(&chat(), PathFeature::Compression, true);
+            overrides.clear("force", "ops");
+        });
+
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_path_override_applied_total{feature="broadcast",rule="force"} 3"#,
+            r#"broker_path_override_applied_total{feature="compression",rule="forbid"} 1"#,
+            "broker_path_overrides_active 1",
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn broadcast_overrides_change_the_switchboard_decision() {
+        let (overrides, _) = overrides();
+        let config = DegradationConfig {
+            level_ttl: Duration::from_secs(60),
+            auto_trigger_on_shed: false,
+            levels: DegradationLevels::default(),
+        };
+        let switchboard = DegradationSwitchboard::new(&config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap());
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(MessageType::GroupMessage, "alice".into(), vec!["bob".into()], payload);
+        envelope.tenant_id = Some("acme".into());
+        let context = PathContext::of(&envelope, "group-1", None);
+
+        let mut force = rule("force", PathFeature::Broadcast, OverrideEffect::Force);
+        force.tenants = vec!["acme".into()];
+        overrides.set(force, None, "ops");
+        assert!(switchboard.force_broadcast(10, &context, &overrides));
+        overrides.clear("force", "ops");
+        assert!(!switchboard.force_broadcast(10, &context, &overrides));
+
+        switchboard.set_level(DegradationLevel::Emergency, "ops", "incident", None);
+        assert!(switchboard.force_broadcast(5_000, &context, &overrides));
+        overrides.set(rule("forbid", PathFeature::Broadcast, OverrideEffect::Forbid), None, "ops");
+        assert!(!switchboard.force_broadcast(5_000, &context, &overrides));
+    }
+}
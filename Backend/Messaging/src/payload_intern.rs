@@ -0,0 +1,553 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use bytes::Bytes;
+use lru::LruCache;
+use ring::digest::{digest, SHA256};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    compression::{CompressionPlan, EgressPayload},
+    config::PayloadInternConfig,
+    lock_metrics::{Mutex, NamedLock},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Rough per-entry overhead of key, LRU node and dictionary ID, counted against the byte budget
+const ENTRY_OVERHEAD: usize = 128;
+
+/// Egress artifacts for one encoded payload: compressed bytes and checksum
+///
+/// `Bytes` are immutable and reference counted, so a hit hands out the
+/// same buffer every other user of the entry publishes.
+#[derive(Debug, Clone)]
+pub struct PreparedEgress {
+    pub payload: EgressPayload,
+    /// From `EgressIntegrity::seal`
+    pub checksum: Option<u32>,
+}
+
+/// Content address of an encoded payload under one encoding variant
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InternKey {
+    digest: [u8; 32],
+    /// Egress envelope encoding version the bytes were produced under
+    envelope_version: u16,
+    /// Dictionary the payload is compressed with; `None` for uncompressed
+    dictionary_id: Option<String>,
+}
+
+struct Interned {
+    prepared: PreparedEgress,
+    cost: usize,
+    cached_at: Instant,
+}
+
+struct Shard {
+    entries: LruCache<InternKey, Interned>,
+    bytes: usize,
+}
+
+/// Shares compression and checksum work between identical egress payloads
+///
+/// Bots and integrations publish byte-identical payloads to many
+/// recipients within seconds, and fanout would otherwise compress and
+/// checksum the same bytes for each. Fanout builds a key from the encoded
+/// bytes' SHA-256, the envelope encoding version and the planned
+/// dictionary, and `prepare` returns the artifacts cached under it or
+/// builds and caches them. Keys of different envelope versions or
+/// dictionaries never match, so a hit is byte-identical to what building
+/// would have produced.
+///
+/// Entries live for `payload_intern.ttl`: a lookup past it rebuilds, and
+/// the sweeper drops expired entries nobody looks up again, so an idle
+/// cache empties within two TTLs. Each of the `shards` shards keeps its
+/// share of `max_bytes`, evicting expired entries first and then least
+/// recently used ones past it.
+/// Payloads over `max_payload_bytes` and those of `regulated_tenants` are
+/// never interned.
+pub struct PayloadInterner {
+    shards: Vec<Mutex<Shard>>,
+    shard_budget: usize,
+    /// Held across all shards
+    bytes: AtomicUsize,
+    config: PayloadInternConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl PayloadInterner {
+    pub fn new(config: PayloadInternConfig, metrics: BrokerMetrics) -> Self {
+        let shard_count = config.shards.max(1);
+        Self {
+            shards: (0..shard_count)
+                .map(|_| {
+                    Mutex::named(
+                        "payload_intern.shard",
+                        Shard {
+                            entries: LruCache::unbounded(),
+                            bytes: 0,
+                        },
+                    )
+                })
+                .collect(),
+            shard_budget: config.max_bytes / shard_count,
+            bytes: AtomicUsize::new(0),
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Key for `encoded`, or `None` when it must not be interned
+    pub fn key(
+        &self,
+        tenant_id: Option<&str>,
+        envelope_version: u16,
+        plan: &CompressionPlan,
+        encoded: &Bytes,
+    ) -> Option<InternKey> {
+        if !self.config.enabled {
+            return None;
+        }
+        if encoded.len() > self.config.max_payload_bytes {
+            self.metrics.record_payload_intern("skipped_size", 0);
+            return None;
+        }
+        if tenant_id.is_some_and(|tenant_id| self.config.regulated_tenants.contains(tenant_id)) {
+            self.metrics.record_payload_intern("skipped_regulated", 0);
+            return None;
+        }
+        let mut sha = [0u8; 32];
+        sha.copy_from_slice(digest(&SHA256, encoded).as_ref());
+        Some(InternKey {
+            digest: sha,
+            envelope_version,
+            dictionary_id: plan.dictionary_id().map(str::to_string),
+        })
+    }
+
+    /// Artifacts interned under `key`, or those from `build`, interned for next time
+    ///
+    /// Without a key, `build` runs every time.
+    pub fn prepare(
+        &self,
+        key: Option<InternKey>,
+        encoded: &Bytes,
+        build: impl FnOnce() -> PreparedEgress,
+    ) -> PreparedEgress {
+        let Some(key) = key else {
+            return build();
+        };
+        let shard = &self.shards[key.digest[0] as usize % self.shards.len()];
+
+        {
+            let now = self.clock.now_instant();
+            let mut shard = shard.lock();
+            if let Some(interned) = shard.entries.get(&key) {
+                if now.saturating_duration_since(interned.cached_at) < self.config.ttl {
+                    self.metrics.record_payload_intern("hit", encoded.len());
+                    return interned.prepared.clone();
+                }
+            }
+            // Still present means expired
+            if let Some(expired) = shard.entries.pop(&key) {
+                shard.bytes -= expired.cost;
+                self.release(expired.cost);
+            }
+        }
+
+        // Built outside the lock; a concurrent miss on the same key builds the same bytes
+        let prepared = build();
+        self.metrics.record_payload_intern("miss", 0);
+        let cost = prepared.payload.payload.len() + ENTRY_OVERHEAD;
+        if cost > self.shard_budget {
+            return prepared;
+        }
+
+        let mut shard = shard.lock();
+        let interned = Interned {
+            prepared: prepared.clone(),
+            cost,
+            cached_at: self.clock.now_instant(),
+        };
+        if let Some(replaced) = shard.entries.put(key, interned) {
+            shard.bytes -= replaced.cost;
+            self.release(replaced.cost);
+        }
+        shard.bytes += cost;
+        let total = self.bytes.fetch_add(cost, Ordering::Relaxed) + cost;
+        self.metrics.update_payload_intern_bytes(total);
+        if shard.bytes > self.shard_budget {
+            self.evict_expired(&mut shard);
+        }
+        while shard.bytes > self.shard_budget {
+            let Some((_, evicted)) = shard.entries.pop_lru() else {
+                break;
+            };
+            shard.bytes -= evicted.cost;
+            self.release(evicted.cost);
+        }
+        prepared
+    }
+
+    /// Drop entries past their TTL in every shard; returns how many went
+    pub fn sweep(&self) -> usize {
+        let mut swept = 0;
+        for shard in &self.shards {
+            swept += self.evict_expired(&mut shard.lock());
+        }
+        swept
+    }
+
+    pub fn spawn_sweeper(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let interner = Arc::clone(self);
+        let interval = self.config.ttl.max(Duration::from_secs(1));
+        Some(spawn_traced("payload_intern_sweeper", TaskContext::new("payload_intern"), async move {
+            loop {
+                interner.clock.sleep(interval).await;
+                interner.sweep();
+            }
+        }))
+    }
+
+    fn evict_expired(&self, shard: &mut Shard) -> usize {
+        let now = self.clock.now_instant();
+        // Hits don't refresh `cached_at`, so expiry order isn't LRU order
+        let expired: Vec<InternKey> = shard
+            .entries
+            .iter()
+            .filter(|(_, interned)| now.saturating_duration_since(interned.cached_at) >= self.config.ttl)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            if let Some(interned) = shard.entries.pop(key) {
+                shard.bytes -= interned.cost;
+                self.release(interned.cost);
+            }
+        }
+        expired.len()
+    }
+
+    fn release(&self, cost: usize) {
+        let total = self.bytes.fetch_sub(cost, Ordering::Relaxed) - cost;
+        self.metrics.update_payload_intern_bytes(total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, collections::HashSet};
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        compression::{
+            tests::{compression, envelope, hold, path, trained, wire, BUCKET},
+            DictionaryCompression,
+        },
+        config::BrokerConfig,
+        integrity::EgressIntegrity,
+    };
+
+    fn config(max_bytes: usize, shards: usize) -> PayloadInternConfig {
+        PayloadInternConfig {
+            enabled: true,
+            max_bytes,
+            shards,
+            max_payload_bytes: 4096,
+            ttl: Duration::from_secs(10),
+            regulated_tenants: HashSet::from(["bank".to_string()]),
+        }
+    }
+
+    fn interner(config: PayloadInternConfig) -> (PayloadInterner, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let interner = PayloadInterner::new(config, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (interner, clock)
+    }
+
+    /// What fanout does with an encoded payload, with or without an interner
+    struct Egress {
+        compression: DictionaryCompression,
+        integrity: EgressIntegrity,
+    }
+
+    impl Egress {
+        async fn new(clock: &Arc<SimClock>) -> Self {
+            let config = BrokerConfig::load().unwrap();
+            let mut integrity = config.integrity;
+            integrity.enabled = true;
+            Self {
+                compression: compression(|_| {}, clock).await,
+                integrity: EgressIntegrity::new(integrity, 4, BrokerMetrics::new().unwrap()),
+            }
+        }
+
+        fn build(&self, plan: &CompressionPlan, encoded: &Bytes) -> PreparedEgress {
+            let payload = self.compression.apply(plan, encoded);
+            let checksum = self.integrity.seal(&payload.payload);
+            PreparedEgress { payload, checksum }
+        }
+    }
+
+    /// Artifacts that differ only in the bytes they carry
+    fn prepared(bytes: &'static [u8]) -> PreparedEgress {
+        PreparedEgress {
+            payload: EgressPayload {
+                payload: Bytes::from_static(bytes),
+                dictionary_id: None,
+            },
+            checksum: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn interned_egress_is_byte_identical_to_building_it_every_time() {
+        let clock = Arc::new(SimClock::new());
+        let egress = Egress::new(&clock).await;
+        let dictionary_id = trained(&egress.compression, false, 0).await;
+        hold(&egress.compression, "gw-1", &[&dictionary_id]);
+        let (interner, _) = interner(config(1 << 20, 4));
+
+        // A status broadcast: the same few payloads to many conversations, through gateways with and without the dictionary
+        let broadcast: Vec<_> = (5_000..5_005)
+            .map(|index| {
+                let message = envelope(index, false);
+                let encoded = wire(&message);
+                (message, encoded)
+            })
+            .collect();
+        let mut compressed = 0;
+        for round in 0..20 {
+            for (message, encoded) in &broadcast {
+                for gateway_id in ["gw-1", "gw-2"] {
+                    let plan = egress.compression.plan(gateway_id, BUCKET, encoded, &path(message));
+                    let key = interner.key(Some("acme"), 2, &plan, encoded);
+                    assert!(key.is_some());
+                    let interned = interner.prepare(key, encoded, || egress.build(&plan, encoded));
+                    let direct = egress.build(&plan, encoded);
+
+                    assert_eq!(interned.payload.payload, direct.payload.payload, "round {} {}", round, gateway_id);
+                    assert_eq!(interned.payload.dictionary_id, direct.payload.dictionary_id);
+                    assert_eq!(interned.checksum, direct.checksum);
+                    if interned.payload.dictionary_id.is_some() {
+                        compressed += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(compressed, 100, "gw-1 got compressed payloads throughout");
+
+        // Hits share one buffer rather than copying it
+        let (message, encoded) = &broadcast[0];
+        let plan = egress.compression.plan("gw-1", BUCKET, encoded, &path(message));
+        let hit = |_| {
+            let key = interner.key(Some("acme"), 2, &plan, encoded);
+            interner.prepare(key, encoded, || panic!("should have been a hit"))
+        };
+        let (first, second) = (hit(0), hit(1));
+        assert_eq!(first.payload.payload.as_ptr(), second.payload.payload.as_ptr());
+        assert_eq!(interner.shards.iter().map(|shard| shard.lock().entries.len()).sum::<usize>(), 10);
+    }
+
+    #[tokio::test]
+    async fn envelope_versions_and_dictionaries_never_share_entries() {
+        let clock = Arc::new(SimClock::new());
+        let egress = Egress::new(&clock).await;
+        let dictionary_id = trained(&egress.compression, false, 0).await;
+        hold(&egress.compression, "gw-1", &[&dictionary_id]);
+        let (interner, _) = interner(config(1 << 20, 4));
+
+        let message = envelope(6_000, false);
+        let encoded = wire(&message);
+        let with_dictionary = egress.compression.plan("gw-1", BUCKET, &encoded, &path(&message));
+        let without = egress.compression.plan("gw-2", BUCKET, &encoded, &path(&message));
+        let builds = Cell::new(0);
+        let prepare = |version, plan: &CompressionPlan| {
+            let key = interner.key(Some("acme"), version, plan, &encoded);
+            interner.prepare(key, &encoded, || {
+                builds.set(builds.get() + 1);
+                egress.build(plan, &encoded)
+            })
+        };
+
+        let v1 = prepare(1, &with_dictionary);
+        let v2 = prepare(2, &with_dictionary);
+        let plain = prepare(2, &without);
+        assert_eq!(builds.get(), 3, "each variant built its own artifacts");
+        assert_eq!(v2.payload.dictionary_id.as_deref(), Some(dictionary_id.as_str()));
+        assert_eq!(plain.payload.dictionary_id, None);
+        assert_eq!(plain.payload.payload, encoded);
+        assert_ne!(v2.payload.payload, plain.payload.payload);
+
+        // And each variant hits only its own entry
+        assert_eq!(prepare(1, &with_dictionary).payload.payload.as_ptr(), v1.payload.payload.as_ptr());
+        assert_eq!(prepare(2, &with_dictionary).payload.payload.as_ptr(), v2.payload.payload.as_ptr());
+        assert_eq!(prepare(2, &without).payload.payload.as_ptr(), plain.payload.payload.as_ptr());
+        assert_eq!(builds.get(), 3);
+        assert_ne!(
+            interner.key(None, 1, &with_dictionary, &encoded),
+            interner.key(None, 2, &with_dictionary, &encoded)
+        );
+    }
+
+    #[tokio::test]
+    async fn large_regulated_and_disabled_payloads_are_never_interned() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let plan = Egress::new(&clock).await.compression.plan("gw-1", BUCKET, &Bytes::new(), &path(&envelope(0, false)));
+        let (interner, _) = interner(config(1 << 20, 4));
+
+        let large = Bytes::from(vec![b'x'; 4097]);
+        assert!(interner.key(Some("acme"), 2, &plan, &large).is_none());
+        assert!(interner.key(Some("acme"), 2, &plan, &large.slice(..4096)).is_some());
+        let small = Bytes::from_static(b"{\"status\":\"away\"}");
+        assert!(interner.key(Some("bank"), 2, &plan, &small).is_none());
+        assert!(interner.key(None, 2, &plan, &small).is_some());
+
+        // Without a key the artifacts are built every time
+        let builds = Cell::new(0);
+        for _ in 0..3 {
+            interner.prepare(None, &small, || {
+                builds.set(builds.get() + 1);
+                prepared(b"built")
+            });
+        }
+        assert_eq!(builds.get(), 3);
+        assert_eq!(interner.bytes.load(Ordering::Relaxed), 0);
+
+        let disabled = PayloadInterner::new(
+            PayloadInternConfig {
+                enabled: false,
+                ..config(1 << 20, 4)
+            },
+            BrokerMetrics::new().unwrap(),
+        );
+        assert!(disabled.key(Some("acme"), 2, &plan, &small).is_none());
+        assert!(Arc::new(disabled).spawn_sweeper().is_none());
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_payload_intern_total{outcome="skipped_size"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_payload_intern_total{outcome="skipped_regulated"} 1"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn hits_count_the_encoding_work_they_save() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let plan = Egress::new(&clock).await.compression.plan("gw-1", BUCKET, &Bytes::new(), &path(&envelope(0, false)));
+        let (interner, _) = interner(config(1 << 20, 4));
+
+        let encoded = Bytes::from(vec![b'x'; 300]);
+        for _ in 0..10 {
+            let key = interner.key(None, 2, &plan, &encoded);
+            interner.prepare(key, &encoded, || prepared(b"artifacts"));
+        }
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_payload_intern_total{outcome="miss"} 1"#,
+            r#"broker_payload_intern_total{outcome="hit"} 9"#,
+            "broker_payload_intern_saved_bytes_total 2700",
+            &format!("broker_payload_intern_bytes {}", 9 + ENTRY_OVERHEAD),
+        ] {
+            assert!(rendered.contains(line), "missing {}\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_the_ttl_and_are_swept() {
+        let clock = Arc::new(SimClock::new());
+        let plan = Egress::new(&clock).await.compression.plan("gw-1", BUCKET, &Bytes::new(), &path(&envelope(0, false)));
+        let (interner, clock) = interner(config(1 << 20, 1));
+        let (first, second) = (Bytes::from_static(b"first"), Bytes::from_static(b"second"));
+        let builds = Cell::new(0);
+        let prepare = |encoded: &Bytes| {
+            let key = interner.key(None, 2, &plan, encoded);
+            interner.prepare(key, encoded, || {
+                builds.set(builds.get() + 1);
+                prepared(b"artifacts")
+            })
+        };
+
+        prepare(&first);
+        clock.advance(Duration::from_secs(5));
+        prepare(&second);
+        // Hits don't extend an entry's life
+        clock.advance(Duration::from_secs(5) - Duration::from_millis(1));
+        prepare(&first);
+        assert_eq!(builds.get(), 2);
+        clock.advance(Duration::from_millis(1));
+        prepare(&first);
+        assert_eq!(builds.get(), 3, "rebuilt at the TTL");
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(interner.sweep(), 1, "only second had expired");
+        assert_eq!(interner.bytes.load(Ordering::Relaxed), 9 + ENTRY_OVERHEAD);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(interner.sweep(), 1);
+        assert_eq!(interner.bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn the_byte_budget_evicts_expired_then_least_recently_used_entries() {
+        let clock = Arc::new(SimClock::new());
+        let plan = Egress::new(&clock).await.compression.plan("gw-1", BUCKET, &Bytes::new(), &path(&envelope(0, false)));
+        // Room for three entries of 72 bytes of artifacts
+        let (interner, clock) = interner(config(3 * (72 + ENTRY_OVERHEAD), 1));
+        let builds = Cell::new(0);
+        let prepare = |index: u8| {
+            let encoded = Bytes::from(vec![index; 16]);
+            let key = interner.key(None, 2, &plan, &encoded);
+            interner.prepare(key, &encoded, || {
+                builds.set(builds.get() + 1);
+                prepared(&[0; 72])
+            });
+        };
+
+        for index in 0..3 {
+            prepare(index);
+        }
+        prepare(0);
+        prepare(3);
+        assert_eq!(builds.get(), 4);
+        assert_eq!(interner.bytes.load(Ordering::Relaxed), 3 * (72 + ENTRY_OVERHEAD));
+        // 1 was least recently used; 0 was refreshed by its hit
+        prepare(0);
+        prepare(2);
+        assert_eq!(builds.get(), 4);
+        prepare(1);
+        assert_eq!(builds.get(), 5);
+
+        // Expired entries go before live ones, however recently used
+        clock.advance(Duration::from_secs(10));
+        prepare(4);
+        prepare(5);
+        assert_eq!(builds.get(), 7);
+        assert_eq!(interner.bytes.load(Ordering::Relaxed), 2 * (72 + ENTRY_OVERHEAD));
+
+        // Artifacts bigger than the whole budget are never kept
+        let huge = Bytes::from_static(b"huge");
+        let key = interner.key(None, 2, &plan, &huge);
+        interner.prepare(key.clone(), &huge, || prepared(&[0; 1024]));
+        assert!(interner.shards[0].lock().entries.peek(&key.unwrap()).is_none());
+        assert_eq!(interner.bytes.load(Ordering::Relaxed), 2 * (72 + ENTRY_OVERHEAD));
+    }
+}
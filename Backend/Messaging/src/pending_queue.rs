@@ -0,0 +1,582 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    num::NonZeroUsize,
+    ops::Bound,
+};
+use lru::LruCache;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    conversation::ConversationRetention,
+    metrics::BrokerMetrics,
+};
+
+/// Cancelled IDs remembered so a repeated cancel still answers `Cancelled`
+const CANCELLED_MEMORY: usize = 10_000;
+
+/// What an operator-visible queue holds
+pub trait PendingEntry: Clone + Serialize + Send + Sync + 'static {
+    /// `queue` label on metrics and prefix of audit actions
+    const QUEUE: &'static str;
+
+    /// Stable ID, also the listing order
+    fn id(&self) -> &str;
+
+    /// Conversation whose state the entry still needs
+    fn conversation_id(&self) -> &str;
+
+    /// Substring match for `?filter=` on listings
+    fn matches(&self, filter: &str) -> bool;
+
+    /// Make the entry due now
+    fn force(&mut self, now_ms: i64);
+}
+
+/// A message waiting for another publish attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryEntry {
+    pub id: String,
+    pub message_id: String,
+    pub conversation_id: String,
+    pub recipient: String,
+    pub attempts: u32,
+    /// Milliseconds
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+}
+
+impl PendingEntry for RetryEntry {
+    const QUEUE: &'static str = "retry";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        self.message_id.contains(filter)
+            || self.conversation_id.contains(filter)
+            || self.recipient.contains(filter)
+            || self.last_error.as_deref().is_some_and(|e| e.contains(filter))
+    }
+
+    fn force(&mut self, now_ms: i64) {
+        self.next_attempt_at = now_ms;
+    }
+}
+
+/// A message held until its delivery time
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledEntry {
+    pub id: String,
+    pub message_id: String,
+    pub conversation_id: String,
+    pub from: String,
+    /// Milliseconds
+    pub deliver_at: i64,
+    /// Campaign or other grouping the scheduler was given, if any
+    pub campaign: Option<String>,
+}
+
+impl PendingEntry for ScheduledEntry {
+    const QUEUE: &'static str = "scheduled";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn conversation_id(&self) -> &str {
+        &self.conversation_id
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        self.message_id.contains(filter)
+            || self.conversation_id.contains(filter)
+            || self.from.contains(filter)
+            || self.campaign.as_deref().is_some_and(|c| c.contains(filter))
+    }
+
+    fn force(&mut self, now_ms: i64) {
+        self.deliver_at = now_ms;
+    }
+}
+
+pub type RetryQueue = PendingQueue<RetryEntry>;
+pub type ScheduledQueue = PendingQueue<ScheduledEntry>;
+
+struct Slot<E> {
+    entry: E,
+    /// A worker is attempting it right now
+    in_flight: bool,
+    /// Cancelled while in flight; the worker drops it when the attempt ends
+    cancelled: bool,
+}
+
+/// One page of a listing; pass `next_cursor` as `after` for the next
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingPage<E> {
+    pub entries: Vec<E>,
+    pub next_cursor: Option<String>,
+}
+
+/// Result of an operator cancel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelOutcome {
+    Cancelled,
+    /// An attempt is running; it won't be retried or rescheduled afterwards
+    CancelledInFlight,
+    /// Cancelled earlier; nothing changed
+    AlreadyCancelled,
+    NotFound,
+}
+
+/// Result of an operator force
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceOutcome {
+    Forced,
+    /// An attempt is already running
+    InFlight,
+    NotFound,
+}
+
+/// How a worker's attempt ended
+#[derive(Debug, Clone)]
+pub enum AttemptEnd<E> {
+    /// Delivered or permanently failed; the entry leaves the queue
+    Done,
+    /// Try again later with the updated entry
+    Again(E),
+}
+
+/// What the worker should do after `end_attempt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AfterAttempt {
+    Requeue,
+    Removed,
+    /// An operator cancelled it during the attempt; count it as cancelled, not failed
+    OperatorCancelled,
+}
+
+/// Operator view of a queue of pending work: retries or scheduled messages
+///
+/// The worker that owns the queue keeps it here: `insert` when an entry is
+/// queued, `begin_attempt`/`end_attempt` around each attempt, and its own
+/// due-time ordering on top. Operators list entries in ID order with keyset
+/// pagination, so a page boundary doesn't shift as entries come and go,
+/// and listing copies at most one page under a read lock, leaving workers
+/// unblocked. Cancel takes effect immediately for an idle entry; for one
+/// in flight the attempt finishes and the worker is told not to requeue
+/// it. Cancels and forces are audited and idempotent: cancelling an ID
+/// twice answers `AlreadyCancelled`, and forcing something already in
+/// flight does nothing.
+pub struct PendingQueue<E: PendingEntry> {
+    entries: RwLock<BTreeMap<String, Slot<E>>>,
+    cancelled: Mutex<LruCache<String, ()>>,
+    /// Woken by `force`; workers wait on it alongside their next due time
+    forced: Notify,
+    audit: AuditLog,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl<E: PendingEntry> PendingQueue<E> {
+    pub fn new(audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        Self {
+            entries: RwLock::new(BTreeMap::new()),
+            cancelled: Mutex::new(LruCache::new(NonZeroUsize::new(CANCELLED_MEMORY).unwrap())),
+            forced: Notify::new(),
+            audit,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn insert(&self, entry: E) {
+        let mut entries = self.entries.write();
+        entries.insert(
+            entry.id().to_string(),
+            Slot {
+                entry,
+                in_flight: false,
+                cancelled: false,
+            },
+        );
+        self.metrics.update_pending_queue_size(E::QUEUE, entries.len());
+    }
+
+    /// Claim an entry for an attempt; `None` if it was cancelled or is already in flight
+    pub fn begin_attempt(&self, id: &str) -> Option<E> {
+        let mut entries = self.entries.write();
+        let slot = entries.get_mut(id)?;
+        if slot.in_flight || slot.cancelled {
+            return None;
+        }
+        slot.in_flight = true;
+        Some(slot.entry.clone())
+    }
+
+    pub fn end_attempt(&self, id: &str, end: AttemptEnd<E>) -> AfterAttempt {
+        let mut entries = self.entries.write();
+        let cancelled = entries.get(id).is_some_and(|slot| slot.cancelled);
+        let after = match end {
+            _ if cancelled => {
+                entries.remove(id);
+                AfterAttempt::OperatorCancelled
+            }
+            AttemptEnd::Done => {
+                entries.remove(id);
+                AfterAttempt::Removed
+            }
+            AttemptEnd::Again(entry) => match entries.get_mut(id) {
+                Some(slot) => {
+                    slot.entry = entry;
+                    slot.in_flight = false;
+                    AfterAttempt::Requeue
+                }
+                None => AfterAttempt::Removed,
+            },
+        };
+        self.metrics.update_pending_queue_size(E::QUEUE, entries.len());
+        after
+    }
+
+    /// Up to `limit` entries with IDs after `after`, optionally filtered
+    pub fn list(&self, filter: Option<&str>, after: Option<&str>, limit: usize) -> PendingPage<E> {
+        let entries = self.entries.read();
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_string()),
+            None => Bound::Unbounded,
+        };
+        let mut page: Vec<E> = entries
+            .range((start, Bound::Unbounded))
+            .filter(|(_, slot)| !slot.cancelled)
+            .map(|(_, slot)| &slot.entry)
+            .filter(|entry| filter.is_none_or(|f| entry.matches(f)))
+            .take(limit + 1)
+            .cloned()
+            .collect();
+        drop(entries);
+
+        let next_cursor = match page.len() > limit {
+            true => {
+                page.truncate(limit);
+                page.last().map(|entry| entry.id().to_string())
+            }
+            false => None,
+        };
+        PendingPage {
+            entries: page,
+            next_cursor,
+        }
+    }
+
+    pub fn cancel(&self, id: &str, actor: &str) -> CancelOutcome {
+        let outcome = {
+            let mut entries = self.entries.write();
+            match entries.get_mut(id) {
+                Some(slot) if slot.cancelled => CancelOutcome::AlreadyCancelled,
+                Some(slot) if slot.in_flight => {
+                    slot.cancelled = true;
+                    CancelOutcome::CancelledInFlight
+                }
+                Some(_) => {
+                    entries.remove(id);
+                    self.metrics.update_pending_queue_size(E::QUEUE, entries.len());
+                    CancelOutcome::Cancelled
+                }
+                None if self.cancelled.lock().contains(id) => CancelOutcome::AlreadyCancelled,
+                None => CancelOutcome::NotFound,
+            }
+        };
+
+        if matches!(outcome, CancelOutcome::Cancelled | CancelOutcome::CancelledInFlight) {
+            self.cancelled.lock().put(id.to_string(), ());
+            info!("Operator {} cancelled {} entry {}", actor, E::QUEUE, id);
+            self.audit.record(AuditEntry::new(
+                actor,
+                format!("{}.cancelled", E::QUEUE),
+                json!({ "id": id, "in_flight": outcome == CancelOutcome::CancelledInFlight }),
+            ));
+            self.metrics.record_pending_queue_action(E::QUEUE, "cancelled");
+        }
+        outcome
+    }
+
+    /// Make an entry due now and wake the workers
+    pub fn force(&self, id: &str, actor: &str) -> ForceOutcome {
+        let outcome = {
+            let mut entries = self.entries.write();
+            match entries.get_mut(id) {
+                Some(slot) if slot.cancelled => ForceOutcome::NotFound,
+                Some(slot) if slot.in_flight => ForceOutcome::InFlight,
+                Some(slot) => {
+                    slot.entry.force(self.clock.now_millis());
+                    ForceOutcome::Forced
+                }
+                None => ForceOutcome::NotFound,
+            }
+        };
+
+        if outcome == ForceOutcome::Forced {
+            self.forced.notify_waiters();
+            self.audit.record(AuditEntry::new(
+                actor,
+                format!("{}.forced", E::QUEUE),
+                json!({ "id": id }),
+            ));
+            self.metrics.record_pending_queue_action(E::QUEUE, "forced");
+        }
+        outcome
+    }
+
+    /// Resolves when an operator forces an entry
+    pub async fn forced(&self) {
+        self.forced.notified().await
+    }
+}
+
+/// Queued entries, including ones mid-attempt, hold their conversation's state
+impl<E: PendingEntry> ConversationRetention for PendingQueue<E> {
+    fn retained(&self, idle: &HashSet<String>) -> HashSet<String> {
+        self.entries
+            .read()
+            .values()
+            .filter(|slot| !slot.cancelled)
+            .map(|slot| slot.entry.conversation_id())
+            .filter(|conversation_id| idle.contains(*conversation_id))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Barrier,
+        },
+        time::Duration,
+    };
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::clock::{Clock, SimClock};
+
+    fn retry(id: &str) -> RetryEntry {
+        RetryEntry {
+            id: id.into(),
+            message_id: format!("msg-{}", id),
+            conversation_id: "dm_alice_bob".into(),
+            recipient: if id.ends_with('7') { "decommissioned-gw".into() } else { "bob".into() },
+            attempts: 1,
+            next_attempt_at: 1_700_000_060_000,
+            last_error: Some("no responders".into()),
+        }
+    }
+
+    fn queue(clock: &Arc<SimClock>) -> RetryQueue {
+        PendingQueue::new(AuditLog::tracing_only(), BrokerMetrics::new().unwrap()).with_clock(clock.clone())
+    }
+
+    fn filled(count: usize) -> RetryQueue {
+        let queue = queue(&Arc::new(SimClock::new()));
+        for index in 0..count {
+            queue.insert(retry(&format!("r-{:04}", index)));
+        }
+        queue
+    }
+
+    fn ids<E: PendingEntry>(page: &PendingPage<E>) -> Vec<&str> {
+        page.entries.iter().map(|entry| entry.id()).collect()
+    }
+
+    #[test]
+    fn listings_page_in_id_order_and_filter() {
+        let queue = filled(7);
+        let first = queue.list(None, None, 3);
+        assert_eq!(ids(&first), ["r-0000", "r-0001", "r-0002"]);
+        let second = queue.list(None, first.next_cursor.as_deref(), 3);
+        assert_eq!(ids(&second), ["r-0003", "r-0004", "r-0005"]);
+        let last = queue.list(None, second.next_cursor.as_deref(), 3);
+        assert_eq!(ids(&last), ["r-0006"]);
+        assert_eq!(last.next_cursor, None);
+
+        // An exactly full last page has no cursor either
+        assert_eq!(queue.list(None, None, 7).next_cursor, None);
+
+        assert!(queue.list(Some("decommissioned"), None, 10).entries.is_empty());
+        queue.insert(retry("r-0007"));
+        assert_eq!(ids(&queue.list(Some("decommissioned"), None, 10)), ["r-0007"]);
+        assert_eq!(queue.list(Some("no responders"), None, 10).entries.len(), 8);
+        assert!(queue.list(Some("nothing like it"), None, 10).entries.is_empty());
+    }
+
+    #[test]
+    fn pages_stay_stable_while_the_queue_churns() {
+        let mut rng = StdRng::seed_from_u64(475);
+        for _ in 0..50 {
+            let queue = filled(100);
+            let mut next_id = 100;
+            // Entries present from the first page to the last must each be listed exactly once
+            let mut survivors: HashSet<String> = (0..100).map(|i| format!("r-{:04}", i)).collect();
+            let mut listed = Vec::new();
+            let mut cursor: Option<String> = None;
+            loop {
+                let page = queue.list(None, cursor.as_deref(), 7);
+                listed.extend(page.entries.iter().map(|entry| entry.id.clone()));
+                for _ in 0..rng.gen_range(0..6) {
+                    let id = format!("r-{:04}", rng.gen_range(0..next_id));
+                    queue.cancel(&id, "ops");
+                    survivors.remove(&id);
+                }
+                for _ in 0..rng.gen_range(0..6) {
+                    queue.insert(retry(&format!("r-{:04}", next_id)));
+                    next_id += 1;
+                }
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+
+            let unique: HashSet<&String> = listed.iter().collect();
+            assert_eq!(unique.len(), listed.len(), "an entry was listed twice");
+            assert!(listed.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", listed);
+            for id in &survivors {
+                assert!(unique.contains(id), "{} was skipped", id);
+            }
+        }
+    }
+
+    #[test]
+    fn cancels_are_idempotent_and_counted_as_operator_cancels() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let queue = filled(3);
+            assert_eq!(queue.cancel("r-0001", "ops"), CancelOutcome::Cancelled);
+            assert_eq!(queue.cancel("r-0001", "ops"), CancelOutcome::AlreadyCancelled);
+            assert_eq!(queue.cancel("r-9999", "ops"), CancelOutcome::NotFound);
+            assert_eq!(ids(&queue.list(None, None, 10)), ["r-0000", "r-0002"]);
+            assert_eq!(queue.begin_attempt("r-0001").map(|e| e.id), None);
+            assert_eq!(queue.force("r-0001", "ops"), ForceOutcome::NotFound);
+
+            let rendered = recorder.handle().render();
+            assert!(
+                rendered.contains(r#"broker_pending_queue_operator_actions_total{queue="retry",action="cancelled"} 1"#),
+                "{}",
+                rendered
+            );
+            assert!(rendered.contains(r#"broker_pending_queue_entries{queue="retry"} 2"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn a_cancel_during_an_attempt_stops_the_requeue() {
+        let queue = filled(2);
+        let entry = queue.begin_attempt("r-0000").unwrap();
+        assert!(queue.begin_attempt("r-0000").is_none(), "one attempt at a time");
+
+        assert_eq!(queue.cancel("r-0000", "ops"), CancelOutcome::CancelledInFlight);
+        assert_eq!(queue.cancel("r-0000", "ops"), CancelOutcome::AlreadyCancelled);
+        assert_eq!(ids(&queue.list(None, None, 10)), ["r-0001"]);
+
+        // The attempt failed and would retry, but the operator got there first
+        let mut again = entry;
+        again.attempts += 1;
+        assert_eq!(queue.end_attempt("r-0000", AttemptEnd::Again(again)), AfterAttempt::OperatorCancelled);
+        assert!(queue.begin_attempt("r-0000").is_none());
+        assert_eq!(queue.cancel("r-0000", "ops"), CancelOutcome::AlreadyCancelled);
+
+        // A cancel that loses to a finished attempt finds nothing
+        queue.begin_attempt("r-0001").unwrap();
+        assert_eq!(queue.end_attempt("r-0001", AttemptEnd::Done), AfterAttempt::Removed);
+        assert_eq!(queue.cancel("r-0001", "ops"), CancelOutcome::NotFound);
+    }
+
+    #[test]
+    fn racing_cancels_and_attempts_agree_on_one_outcome() {
+        for round in 0..200 {
+            let queue = Arc::new(filled(1));
+            let id = "r-0000";
+            let start = Arc::new(Barrier::new(2));
+            let worker = {
+                let (queue, start) = (Arc::clone(&queue), Arc::clone(&start));
+                std::thread::spawn(move || {
+                    start.wait();
+                    let mut ends = Vec::new();
+                    while let Some(mut entry) = queue.begin_attempt(id) {
+                        entry.attempts += 1;
+                        let end = if entry.attempts > 20 { AttemptEnd::Done } else { AttemptEnd::Again(entry) };
+                        ends.push(queue.end_attempt(id, end));
+                    }
+                    ends
+                })
+            };
+            start.wait();
+            let cancelled = queue.cancel(id, "ops");
+            let ends = worker.join().unwrap();
+
+            let operator_cancelled = ends.iter().filter(|end| **end == AfterAttempt::OperatorCancelled).count();
+            match cancelled {
+                // Caught between attempts: the worker never saw it again
+                CancelOutcome::Cancelled => assert_eq!(operator_cancelled, 0, "round {}", round),
+                // Caught mid-attempt: exactly that attempt ended it
+                CancelOutcome::CancelledInFlight => {
+                    assert_eq!(operator_cancelled, 1, "round {}", round);
+                    assert_eq!(ends.last(), Some(&AfterAttempt::OperatorCancelled), "round {}", round);
+                }
+                // Lost to the final attempt
+                CancelOutcome::NotFound => assert_eq!(ends.last(), Some(&AfterAttempt::Removed), "round {}", round),
+                CancelOutcome::AlreadyCancelled => panic!("round {}: first cancel answered AlreadyCancelled", round),
+            }
+            assert!(queue.list(None, None, 10).entries.is_empty(), "round {}", round);
+        }
+    }
+
+    #[tokio::test]
+    async fn forcing_makes_an_idle_entry_due_now_and_wakes_the_workers() {
+        let clock = Arc::new(SimClock::new());
+        clock.advance(Duration::from_secs(5));
+        let queue = Arc::new(queue(&clock));
+        queue.insert(retry("r-0000"));
+        queue.insert(retry("r-0001"));
+
+        let woken = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (queue, woken) = (Arc::clone(&queue), Arc::clone(&woken));
+            tokio::spawn(async move {
+                queue.forced().await;
+                woken.store(true, Ordering::SeqCst);
+            })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(queue.force("r-0000", "ops"), ForceOutcome::Forced);
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        assert!(woken.load(Ordering::SeqCst));
+        assert_eq!(queue.list(None, None, 1).entries[0].next_attempt_at, clock.now_millis());
+
+        queue.begin_attempt("r-0001").unwrap();
+        assert_eq!(queue.force("r-0001", "ops"), ForceOutcome::InFlight);
+        assert_eq!(queue.force("r-9999", "ops"), ForceOutcome::NotFound);
+    }
+}
@@ -0,0 +1,479 @@
+use std::{collections::HashSet, sync::Arc};
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    ingress_admission::SourceClass,
+    message::types::{MessageEnvelope, MessageType, Priority},
+    metrics::BrokerMetrics,
+};
+
+/// Metadata key naming the recipient's tenant, used for cross-tenant rules
+pub const RECIPIENT_TENANT_KEY: &str = "recipient_tenant_id";
+
+/// Metadata key selecting broadcast delivery
+pub const DELIVERY_MODE_KEY: &str = "delivery_mode";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyEffect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetType {
+    User,
+    Group,
+    Broadcast,
+}
+
+impl TargetType {
+    pub fn of(envelope: &MessageEnvelope) -> Self {
+        if envelope.metadata.get(DELIVERY_MODE_KEY).map(String::as_str) == Some("broadcast") {
+            TargetType::Broadcast
+        } else if envelope.is_group_message() {
+            TargetType::Group
+        } else {
+            TargetType::User
+        }
+    }
+}
+
+/// One rule as written in config
+///
+/// Empty match lists match anything. String patterns are exact, or a prefix
+/// when they end in `*`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRuleConfig {
+    pub id: String,
+    pub effect: PolicyEffect,
+    #[serde(default)]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub service_accounts: Vec<String>,
+    #[serde(default)]
+    pub senders: Vec<String>,
+    #[serde(default)]
+    pub tenants: Vec<String>,
+    #[serde(default)]
+    pub kinds: Vec<MessageType>,
+    #[serde(default)]
+    pub priorities: Vec<Priority>,
+    #[serde(default)]
+    pub targets: Vec<TargetType>,
+    /// Match only messages whose recipient tenant differs (true) or matches (false)
+    pub cross_tenant: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    /// Deny messages no rule matched (recommended in production)
+    #[serde(default)]
+    pub default_deny: bool,
+    #[serde(default)]
+    pub rules: Vec<PolicyRuleConfig>,
+}
+
+/// Who handed the message to the broker
+#[derive(Debug, Clone, Default)]
+pub struct IngressSource {
+    /// Gateway the message was consumed from
+    pub gateway_id: String,
+    /// Service account of the publishing credential, if any
+    pub service_account: Option<String>,
+    /// Transport the message arrived on, for ingress admission
+    pub class: SourceClass,
+}
+
+/// Exact or `prefix*` string patterns; no patterns matches anything
+#[derive(Debug, Default)]
+pub(crate) struct StrMatcher {
+    any: bool,
+    exact: HashSet<String>,
+    prefixes: Vec<String>,
+}
+
+impl StrMatcher {
+    pub(crate) fn compile(patterns: &[String]) -> Self {
+        let mut matcher = StrMatcher {
+            any: patterns.is_empty(),
+            ..Default::default()
+        };
+        for pattern in patterns {
+            match pattern.strip_suffix('*') {
+                Some("") => matcher.any = true,
+                Some(prefix) => matcher.prefixes.push(prefix.to_string()),
+                None => {
+                    matcher.exact.insert(pattern.clone());
+                }
+            }
+        }
+        matcher
+    }
+
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        self.any || self.exact.contains(value) || self.prefixes.iter().any(|p| value.starts_with(p.as_str()))
+    }
+
+    pub(crate) fn matches_opt(&self, value: Option<&str>) -> bool {
+        match value {
+            Some(value) => self.matches(value),
+            None => self.any,
+        }
+    }
+}
+
+/// Rule compiled for evaluation: enum fields become bitmasks
+struct CompiledRule {
+    id: Arc<str>,
+    effect: PolicyEffect,
+    kinds: u32,
+    priorities: u8,
+    targets: u8,
+    cross_tenant: Option<bool>,
+    sources: StrMatcher,
+    service_accounts: StrMatcher,
+    senders: StrMatcher,
+    tenants: StrMatcher,
+}
+
+impl CompiledRule {
+    fn compile(rule: &PolicyRuleConfig) -> Self {
+        fn mask<T: Copy>(values: &[T], all: u32, bit: impl Fn(T) -> u32) -> u32 {
+            if values.is_empty() {
+                all
+            } else {
+                values.iter().fold(0, |mask, v| mask | bit(*v))
+            }
+        }
+
+        Self {
+            id: Arc::from(rule.id.as_str()),
+            effect: rule.effect,
+            kinds: mask(&rule.kinds, u32::MAX, |k| 1 << k as u32),
+            priorities: mask(&rule.priorities, u8::MAX as u32, |p| 1 << p as u32) as u8,
+            targets: mask(&rule.targets, u8::MAX as u32, |t| 1 << t as u32) as u8,
+            cross_tenant: rule.cross_tenant,
+            sources: StrMatcher::compile(&rule.sources),
+            service_accounts: StrMatcher::compile(&rule.service_accounts),
+            senders: StrMatcher::compile(&rule.senders),
+            tenants: StrMatcher::compile(&rule.tenants),
+        }
+    }
+
+    fn matches(&self, facts: &Facts<'_>) -> bool {
+        // Cheap bitmask checks first, string matchers last
+        self.kinds & facts.kind != 0
+            && self.priorities & facts.priority != 0
+            && self.targets & facts.target != 0
+            && self.cross_tenant.is_none_or(|cross| cross == facts.cross_tenant)
+            && self.senders.matches(facts.sender)
+            && self.tenants.matches_opt(facts.tenant)
+            && self.sources.matches(facts.source)
+            && self.service_accounts.matches_opt(facts.service_account)
+    }
+}
+
+struct Facts<'a> {
+    kind: u32,
+    priority: u8,
+    target: u8,
+    cross_tenant: bool,
+    sender: &'a str,
+    tenant: Option<&'a str>,
+    source: &'a str,
+    service_account: Option<&'a str>,
+}
+
+struct CompiledPolicy {
+    rules: Vec<CompiledRule>,
+    default_deny: bool,
+}
+
+/// Ordered allow/deny rules evaluated on every ingress message
+///
+/// Rules are compiled once at load time; a reload swaps the whole rule set
+/// atomically so a message is always evaluated against a single version.
+pub struct PolicyEngine {
+    policy: ArcSwap<CompiledPolicy>,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl PolicyEngine {
+    pub fn new(config: &PolicyConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        Self {
+            policy: ArcSwap::from_pointee(compile(config)),
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn reload(&self, config: &PolicyConfig) {
+        self.policy.store(Arc::new(compile(config)));
+        info!(
+            "Policy reloaded: {} rules, default {}",
+            config.rules.len(),
+            if config.default_deny { "deny" } else { "allow" }
+        );
+    }
+
+    pub fn evaluate(&self, source: &IngressSource, envelope: &MessageEnvelope) -> Result<(), PolicyDenied> {
+        let policy = self.policy.load();
+        let facts = Facts {
+            kind: 1 << envelope.message_type as u32,
+            priority: 1 << envelope.priority as u8,
+            target: 1 << TargetType::of(envelope) as u8,
+            cross_tenant: is_cross_tenant(envelope),
+            sender: &envelope.from,
+            tenant: envelope.tenant_id.as_deref(),
+            source: &source.gateway_id,
+            service_account: source.service_account.as_deref(),
+        };
+
+        let matched = policy.rules.iter().find(|rule| rule.matches(&facts));
+        let (rule_id, effect) = match matched {
+            Some(rule) => (&*rule.id, rule.effect),
+            None if policy.default_deny => ("default", PolicyEffect::Deny),
+            None => ("default", PolicyEffect::Allow),
+        };
+
+        self.metrics.record_policy_decision(rule_id, effect == PolicyEffect::Allow);
+        if effect == PolicyEffect::Allow {
+            return Ok(());
+        }
+
+        warn!(
+            "Policy rule {} denied message {} from {} via {}",
+            rule_id, envelope.message_id, envelope.from, source.gateway_id
+        );
+        self.audit.record(AuditEntry::new(
+            source.gateway_id.as_str(),
+            "policy.denied",
+            serde_json::json!({
+                "rule_id": rule_id,
+                "message_id": envelope.message_id,
+                "sender": envelope.from,
+                "tenant_id": envelope.tenant_id,
+                "service_account": source.service_account,
+            }),
+        ));
+
+        Err(PolicyDenied {
+            rule_id: rule_id.to_string(),
+        })
+    }
+}
+
+fn compile(config: &PolicyConfig) -> CompiledPolicy {
+    CompiledPolicy {
+        rules: config.rules.iter().map(CompiledRule::compile).collect(),
+        default_deny: config.default_deny,
+    }
+}
+
+fn is_cross_tenant(envelope: &MessageEnvelope) -> bool {
+    match (envelope.tenant_id.as_deref(), envelope.metadata.get(RECIPIENT_TENANT_KEY)) {
+        (Some(sender), Some(recipient)) => sender != recipient,
+        _ => false,
+    }
+}
+
+/// Sender-visible policy rejection
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+#[error("denied by policy rule {rule_id}")]
+pub struct PolicyDenied {
+    pub rule_id: String,
+}
+
+impl PolicyDenied {
+    pub const CODE: &'static str = "POLICY_DENIED";
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use super::*;
+    use crate::message::types::EncryptedPayload;
+
+    fn rule(id: &str, effect: PolicyEffect) -> PolicyRuleConfig {
+        PolicyRuleConfig {
+            id: id.into(),
+            effect,
+            sources: Vec::new(),
+            service_accounts: Vec::new(),
+            senders: Vec::new(),
+            tenants: Vec::new(),
+            kinds: Vec::new(),
+            priorities: Vec::new(),
+            targets: Vec::new(),
+            cross_tenant: None,
+        }
+    }
+
+    fn engine(default_deny: bool, rules: Vec<PolicyRuleConfig>) -> PolicyEngine {
+        let config = PolicyConfig { default_deny, rules };
+        PolicyEngine::new(&config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+    }
+
+    fn envelope(kind: MessageType, from: &str) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(kind, from.into(), vec!["bob".into()], payload);
+        envelope.tenant_id = Some("acme".into());
+        envelope
+    }
+
+    fn source(gateway_id: &str, service_account: Option<&str>) -> IngressSource {
+        IngressSource {
+            gateway_id: gateway_id.into(),
+            service_account: service_account.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn denied_by(engine: &PolicyEngine, source: &IngressSource, envelope: &MessageEnvelope) -> Option<String> {
+        engine.evaluate(source, envelope).err().map(|denied| denied.rule_id)
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let engine = engine(
+            false,
+            vec![
+                PolicyRuleConfig {
+                    service_accounts: vec!["notifier".into()],
+                    kinds: vec![MessageType::SystemMessage],
+                    ..rule("notifier-system", PolicyEffect::Allow)
+                },
+                PolicyRuleConfig {
+                    kinds: vec![MessageType::SystemMessage],
+                    ..rule("no-system", PolicyEffect::Deny)
+                },
+            ],
+        );
+        let system = envelope(MessageType::SystemMessage, "svc");
+
+        assert_eq!(denied_by(&engine, &source("gw-1", Some("notifier")), &system), None);
+        assert_eq!(denied_by(&engine, &source("gw-1", None), &system).as_deref(), Some("no-system"));
+        assert_eq!(denied_by(&engine, &source("gw-1", None), &envelope(MessageType::TextMessage, "alice")), None);
+    }
+
+    #[test]
+    fn unmatched_messages_follow_the_default() {
+        let text = envelope(MessageType::TextMessage, "alice");
+        assert!(engine(false, Vec::new()).evaluate(&source("gw-1", None), &text).is_ok());
+        assert_eq!(denied_by(&engine(true, Vec::new()), &source("gw-1", None), &text).as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn patterns_match_exactly_or_by_prefix() {
+        let engine = engine(
+            true,
+            vec![PolicyRuleConfig {
+                sources: vec!["gw-eu-*".into(), "gw-us-1".into()],
+                ..rule("known-gateways", PolicyEffect::Allow)
+            }],
+        );
+        let text = envelope(MessageType::TextMessage, "alice");
+        assert!(engine.evaluate(&source("gw-eu-7", None), &text).is_ok());
+        assert!(engine.evaluate(&source("gw-us-1", None), &text).is_ok());
+        assert!(engine.evaluate(&source("gw-us-2", None), &text).is_err());
+    }
+
+    #[test]
+    fn cross_tenant_and_broadcast_targets_are_matched() {
+        let engine = engine(
+            false,
+            vec![
+                PolicyRuleConfig {
+                    cross_tenant: Some(true),
+                    ..rule("no-cross-tenant", PolicyEffect::Deny)
+                },
+                PolicyRuleConfig {
+                    targets: vec![TargetType::Broadcast],
+                    ..rule("no-broadcast", PolicyEffect::Deny)
+                },
+            ],
+        );
+        let mut text = envelope(MessageType::TextMessage, "alice");
+        text.metadata.insert(RECIPIENT_TENANT_KEY.into(), "acme".into());
+        assert!(engine.evaluate(&source("gw-1", None), &text).is_ok());
+
+        text.metadata.insert(RECIPIENT_TENANT_KEY.into(), "globex".into());
+        assert_eq!(denied_by(&engine, &source("gw-1", None), &text).as_deref(), Some("no-cross-tenant"));
+
+        let mut broadcast = envelope(MessageType::TextMessage, "alice");
+        broadcast.metadata.insert(DELIVERY_MODE_KEY.into(), "broadcast".into());
+        assert_eq!(denied_by(&engine, &source("gw-1", None), &broadcast).as_deref(), Some("no-broadcast"));
+    }
+
+    #[test]
+    fn reload_swaps_the_rule_set_atomically() {
+        let allow_all = PolicyConfig {
+            default_deny: true,
+            rules: vec![rule("v1-allow", PolicyEffect::Allow)],
+        };
+        let deny_all = PolicyConfig {
+            default_deny: false,
+            rules: vec![rule("v2-deny", PolicyEffect::Deny)],
+        };
+        let engine = Arc::new(PolicyEngine::new(&allow_all, AuditLog::tracing_only(), BrokerMetrics::new().unwrap()));
+
+        let evaluator = {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                let text = envelope(MessageType::TextMessage, "alice");
+                for _ in 0..10_000 {
+                    // Never a mix of versions, such as v1's default with v2's rules
+                    if let Some(rule_id) = denied_by(&engine, &source("gw-1", None), &text) {
+                        assert_eq!(rule_id, "v2-deny");
+                    }
+                }
+            })
+        };
+        for i in 0..1_000 {
+            engine.reload(if i % 2 == 0 { &deny_all } else { &allow_all });
+        }
+        evaluator.join().unwrap();
+
+        engine.reload(&deny_all);
+        let text = envelope(MessageType::TextMessage, "alice");
+        assert_eq!(denied_by(&engine, &source("gw-1", None), &text).as_deref(), Some("v2-deny"));
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, ignore = "timing is only meaningful in release builds")]
+    fn evaluates_200_rules_in_under_a_microsecond() {
+        let rules = (0..200)
+            .map(|i| PolicyRuleConfig {
+                senders: vec![format!("sender-{}-*", i)],
+                tenants: vec![format!("tenant-{}", i)],
+                kinds: vec![MessageType::SystemMessage],
+                ..rule(&format!("rule-{}", i), PolicyEffect::Deny)
+            })
+            .collect();
+        let engine = engine(false, rules);
+        let source = source("gw-1", None);
+        // Misses every rule, so all 200 are evaluated
+        let text = envelope(MessageType::TextMessage, "alice");
+
+        let iterations = 100_000;
+        let started = Instant::now();
+        for _ in 0..iterations {
+            assert!(engine.evaluate(&source, &text).is_ok());
+        }
+        let per_message = started.elapsed() / iterations;
+        assert!(per_message.as_nanos() < 1_000, "{:?} per evaluation", per_message);
+    }
+}
@@ -0,0 +1,591 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use async_nats::jetstream::kv;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{debug, info};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::RoutingConfig,
+    deadline::Deadline,
+    message::types::{BulkUserSet, PresenceBulkRefresh, PresenceDelta, PresenceStatus, PresenceUpdate},
+    metrics::BrokerMetrics,
+    route_cache::RouteCache,
+    shard::shard_for,
+};
+
+/// Presence entry stored in the presence KV bucket under `presence.{user_id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceRecord {
+    pub gateway_id: String,
+    pub status: PresenceStatus,
+    /// Timestamp in milliseconds
+    pub last_seen: i64,
+}
+
+impl PresenceRecord {
+    /// Not seen for `ttl` as of `now` (milliseconds)
+    pub fn expired(&self, now: i64, ttl: Duration) -> bool {
+        now.saturating_sub(self.last_seen) >= ttl.as_millis() as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkRefreshOutcome {
+    Completed { users: usize },
+    /// A newer bulk refresh from the same gateway took over mid-processing
+    Superseded,
+    /// The caller's deadline passed between chunks; the registry entry is left as it was
+    DeadlineExceeded { written: usize },
+}
+
+#[derive(Default)]
+struct GatewayPresence {
+    users: HashSet<String>,
+    /// Bumped by every bulk refresh; older expansions stop when it changes
+    generation: Arc<AtomicU64>,
+}
+
+/// Presence state: per-user KV records plus a per-gateway user registry
+///
+/// A record not refreshed for `routing.presence_ttl` reads as absent. The
+/// bucket's `max_age` deletes it eventually; lookups don't wait for that.
+pub struct PresenceStore {
+    kv: kv::Store,
+    gateways: DashMap<String, GatewayPresence>,
+    shard_count: usize,
+    bulk_chunk_size: usize,
+    bulk_concurrency: usize,
+    ttl: Duration,
+    routes: Arc<RouteCache>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl PresenceStore {
+    pub fn new(kv: kv::Store, routing: &RoutingConfig, routes: Arc<RouteCache>, metrics: BrokerMetrics) -> Self {
+        Self {
+            kv,
+            gateways: DashMap::new(),
+            shard_count: routing.shard_count,
+            bulk_chunk_size: routing.presence_bulk_chunk_size.max(1),
+            bulk_concurrency: routing.presence_bulk_concurrency.max(1),
+            ttl: routing.presence_ttl,
+            routes,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Apply a single presence heartbeat
+    pub async fn heartbeat(&self, gateway_id: &str, update: &PresenceUpdate) -> Result<(), PresenceError> {
+        let record = PresenceRecord {
+            gateway_id: gateway_id.to_string(),
+            status: update.status,
+            last_seen: update.last_seen,
+        };
+        put_record(&self.kv, &update.user_id, &record).await?;
+        self.metrics.record_presence_kv_writes("individual", 1);
+        if update.status == PresenceStatus::Online {
+            self.routes.user_online(&update.user_id);
+        }
+
+        let mut gateway = self.gateways.entry(gateway_id.to_string()).or_default();
+        match update.status {
+            PresenceStatus::Offline => gateway.users.remove(&update.user_id),
+            _ => gateway.users.insert(update.user_id.clone()),
+        };
+        Ok(())
+    }
+
+    /// Expand a bulk refresh into chunked, bounded-concurrency KV writes
+    ///
+    /// The gateway's registry entry is replaced once at the end. A newer bulk
+    /// refresh from the same gateway, or `deadline` passing, cancels this one
+    /// between chunks.
+    pub async fn bulk_refresh(
+        &self,
+        refresh: PresenceBulkRefresh,
+        deadline: &Deadline,
+    ) -> Result<BulkRefreshOutcome, PresenceError> {
+        let started = self.clock.now_instant();
+        let (generation, token) = {
+            let gateway = self.gateways.entry(refresh.gateway_id.clone()).or_default();
+            let token = Arc::clone(&gateway.generation);
+            (token.fetch_add(1, Ordering::SeqCst) + 1, token)
+        };
+
+        let users = self.expand(&refresh);
+        self.metrics.record_presence_bulk_refresh_size(users.len());
+
+        let record = PresenceRecord {
+            gateway_id: refresh.gateway_id.clone(),
+            status: PresenceStatus::Online,
+            last_seen: refresh.timestamp,
+        };
+        let semaphore = Arc::new(Semaphore::new(self.bulk_concurrency));
+
+        for (index, chunk) in users.chunks(self.bulk_chunk_size).enumerate() {
+            if token.load(Ordering::SeqCst) != generation {
+                debug!("Bulk refresh for {} superseded", refresh.gateway_id);
+                self.metrics.record_presence_bulk_superseded();
+                return Ok(BulkRefreshOutcome::Superseded);
+            }
+            if deadline.expired() {
+                let written = index * self.bulk_chunk_size;
+                debug!("Bulk refresh for {} hit its deadline after {} users", refresh.gateway_id, written);
+                self.metrics.record_deadline_abandoned("presence_bulk_refresh", "chunk");
+                return Ok(BulkRefreshOutcome::DeadlineExceeded { written });
+            }
+
+            let mut writes = JoinSet::new();
+            for user_id in chunk {
+                let permit = Arc::clone(&semaphore)
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore never closed");
+                let kv = self.kv.clone();
+                let user_id = user_id.clone();
+                let record = record.clone();
+                writes.spawn(async move {
+                    let result = put_record(&kv, &user_id, &record).await;
+                    drop(permit);
+                    result
+                });
+            }
+
+            while let Some(result) = writes.join_next().await {
+                result.map_err(|e| PresenceError(e.to_string()))??;
+            }
+            for user_id in chunk {
+                self.routes.user_online(user_id);
+            }
+            self.metrics.record_presence_kv_writes("bulk", chunk.len() as u64);
+        }
+
+        // Single registry update per gateway, unless a newer refresh started
+        let mut gateway = self.gateways.entry(refresh.gateway_id.clone()).or_default();
+        if gateway.generation.load(Ordering::SeqCst) != generation {
+            self.metrics.record_presence_bulk_superseded();
+            return Ok(BulkRefreshOutcome::Superseded);
+        }
+        gateway.users = users.iter().cloned().collect();
+        drop(gateway);
+
+        let elapsed = self.clock.now_instant().saturating_duration_since(started);
+        self.metrics.record_presence_bulk_refresh_duration(elapsed.as_secs_f64());
+        info!("Bulk presence refresh for {}: {} users in {:?}", refresh.gateway_id, users.len(), elapsed);
+        Ok(BulkRefreshOutcome::Completed { users: users.len() })
+    }
+
+    /// Apply incremental changes that follow a bulk refresh
+    pub async fn apply_delta(&self, delta: &PresenceDelta) -> Result<(), PresenceError> {
+        for (users, status) in [
+            (&delta.online, PresenceStatus::Online),
+            (&delta.offline, PresenceStatus::Offline),
+        ] {
+            let record = PresenceRecord {
+                gateway_id: delta.gateway_id.clone(),
+                status,
+                last_seen: delta.timestamp,
+            };
+            for user_id in users {
+                put_record(&self.kv, user_id, &record).await?;
+                if status == PresenceStatus::Online {
+                    self.routes.user_online(user_id);
+                }
+            }
+            self.metrics.record_presence_kv_writes("delta", users.len() as u64);
+        }
+
+        let mut gateway = self.gateways.entry(delta.gateway_id.clone()).or_default();
+        gateway.users.extend(delta.online.iter().cloned());
+        for user_id in &delta.offline {
+            gateway.users.remove(user_id);
+        }
+        Ok(())
+    }
+
+    pub async fn lookup(&self, user_id: &str) -> Result<Option<PresenceRecord>, PresenceError> {
+        let value = self
+            .kv
+            .get(presence_key(user_id))
+            .await
+            .map_err(|e| PresenceError(e.to_string()))?;
+        let Some(bytes) = value else {
+            return Ok(None);
+        };
+        let record: PresenceRecord = serde_json::from_slice(&bytes).map_err(|e| PresenceError(e.to_string()))?;
+        Ok(Some(record).filter(|record| !record.expired(self.clock.now_millis(), self.ttl)))
+    }
+
+    /// Apply a presence record replicated from another region; `None` deletes it
+    pub async fn apply_replicated(&self, user_id: &str, record: Option<&PresenceRecord>) -> Result<(), PresenceError> {
+        match record {
+            Some(record) => {
+                put_record(&self.kv, user_id, record).await?;
+                if record.status == PresenceStatus::Online {
+                    self.routes.user_online(user_id);
+                }
+            }
+            None => {
+                self.kv
+                    .delete(presence_key(user_id))
+                    .await
+                    .map_err(|e| PresenceError(e.to_string()))?;
+            }
+        }
+        self.metrics.record_presence_kv_writes("replicated", 1);
+        Ok(())
+    }
+
+    /// Gateways the registry places at least one user on
+    pub fn registered_gateways(&self) -> Vec<String> {
+        self.gateways
+            .iter()
+            .filter(|gateway| !gateway.users.is_empty())
+            .map(|gateway| gateway.key().clone())
+            .collect()
+    }
+
+    /// Mark every user the registry places on a dead gateway offline
+    ///
+    /// Only records still naming the gateway are touched, with a revision
+    /// check, so a user who reconnected elsewhere meanwhile keeps the new
+    /// record. Routes are dropped so the next delivery resolves afresh. The
+    /// registry entry goes too, cancelling any bulk refresh in progress.
+    pub async fn evict_gateway(&self, gateway_id: &str) -> Result<usize, PresenceError> {
+        let Some((_, gateway)) = self.gateways.remove(gateway_id) else {
+            return Ok(0);
+        };
+        gateway.generation.fetch_add(1, Ordering::SeqCst);
+
+        let now = self.clock.now_millis();
+        let mut evicted = 0;
+        for user_id in &gateway.users {
+            let entry = self
+                .kv
+                .entry(presence_key(user_id))
+                .await
+                .map_err(|e| PresenceError(e.to_string()))?;
+            let Some(entry) = entry.filter(|entry| entry.operation == kv::Operation::Put) else {
+                continue;
+            };
+            let Ok(record) = serde_json::from_slice::<PresenceRecord>(&entry.value) else {
+                continue;
+            };
+            if record.gateway_id != gateway_id || record.status == PresenceStatus::Offline {
+                continue;
+            }
+            let offline = PresenceRecord {
+                status: PresenceStatus::Offline,
+                last_seen: now,
+                ..record
+            };
+            let value = serde_json::to_vec(&offline).map_err(|e| PresenceError(e.to_string()))?;
+            match self.kv.update(presence_key(user_id), value.into(), entry.revision).await {
+                Ok(_) => evicted += 1,
+                Err(e) => debug!("Kept presence for {} on eviction of {}: {}", user_id, gateway_id, e),
+            }
+            self.routes.invalidate(user_id);
+        }
+        self.metrics.record_presence_kv_writes("gateway_eviction", evicted as u64);
+        Ok(evicted)
+    }
+
+    /// Users the registry currently places on the gateway
+    pub fn gateway_user_count(&self, gateway_id: &str) -> usize {
+        self.gateways.get(gateway_id).map_or(0, |g| g.users.len())
+    }
+
+    fn expand(&self, refresh: &PresenceBulkRefresh) -> Vec<String> {
+        match &refresh.users {
+            BulkUserSet::Users(users) => users.clone(),
+            BulkUserSet::ShardRanges(ranges) => {
+                let Some(gateway) = self.gateways.get(&refresh.gateway_id) else {
+                    return Vec::new();
+                };
+                gateway
+                    .users
+                    .iter()
+                    .filter(|user_id| {
+                        let shard = shard_for(user_id, self.shard_count) as u32;
+                        ranges.iter().any(|(start, end)| (*start..=*end).contains(&shard))
+                    })
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+}
+
+fn presence_key(user_id: &str) -> String {
+    format!("presence.{}", user_id)
+}
+
+/// User ID of a presence KV key
+pub fn presence_user(key: &str) -> Option<&str> {
+    key.strip_prefix("presence.")
+}
+
+async fn put_record(kv: &kv::Store, user_id: &str, record: &PresenceRecord) -> Result<(), PresenceError> {
+    let value = serde_json::to_vec(record).map_err(|e| PresenceError(e.to_string()))?;
+    kv.put(presence_key(user_id), value.into())
+        .await
+        .map_err(|e| PresenceError(e.to_string()))?;
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("presence store error: {0}")]
+pub struct PresenceError(pub String);
+
+/// Against a JetStream server at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored presence`
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_nats::jetstream;
+    use async_trait::async_trait;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        route_cache::{RouteLookupError, UserLookup, UserResolver},
+    };
+
+    struct NoUsers;
+
+    #[async_trait]
+    impl UserResolver for NoUsers {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            Ok(UserLookup::NotFound)
+        }
+    }
+
+    async fn store(chunk_size: usize, concurrency: usize) -> PresenceStore {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let client = async_nats::connect(url).await.unwrap();
+        let kv = jetstream::new(client)
+            .create_key_value(kv::Config {
+                bucket: format!("presence-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let mut routing = BrokerConfig::load().unwrap().routing;
+        routing.presence_bulk_chunk_size = chunk_size;
+        routing.presence_bulk_concurrency = concurrency;
+        let metrics = BrokerMetrics::new().unwrap();
+        let routes = Arc::new(RouteCache::new(Arc::new(NoUsers), &routing, metrics.clone()));
+        PresenceStore::new(kv, &routing, routes, metrics).with_clock(Arc::new(SimClock::new()))
+    }
+
+    /// Every write to the bucket, including overwrites
+    async fn writes(store: &PresenceStore) -> u64 {
+        store.kv.status().await.unwrap().info.state.last_sequence
+    }
+
+    fn users(prefix: &str, count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{}-{}", prefix, i)).collect()
+    }
+
+    fn refresh(store: &PresenceStore, set: BulkUserSet) -> PresenceBulkRefresh {
+        PresenceBulkRefresh {
+            gateway_id: "gw-1".into(),
+            users: set,
+            timestamp: store.clock.now_millis(),
+        }
+    }
+
+    fn online(store: &PresenceStore, user_id: &str) -> PresenceUpdate {
+        PresenceUpdate {
+            user_id: user_id.into(),
+            status: PresenceStatus::Online,
+            device_id: "d".into(),
+            last_seen: store.clock.now_millis(),
+            platform: None,
+        }
+    }
+
+    #[test]
+    fn records_expire_once_unseen_for_the_ttl() {
+        let clock = SimClock::new();
+        let ttl = Duration::from_secs(300);
+        let record = PresenceRecord {
+            gateway_id: "gw-1".into(),
+            status: PresenceStatus::Online,
+            last_seen: clock.now_millis(),
+        };
+        assert!(!record.expired(clock.now_millis(), ttl));
+        clock.advance(ttl - Duration::from_millis(1));
+        assert!(!record.expired(clock.now_millis(), ttl));
+        clock.advance(Duration::from_millis(1));
+        assert!(record.expired(clock.now_millis(), ttl));
+        // A gateway clock running ahead doesn't make a record expire early
+        let ahead = PresenceRecord {
+            last_seen: clock.now_millis() + 5_000,
+            ..record
+        };
+        assert!(!ahead.expired(clock.now_millis(), ttl));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn lookups_stop_returning_users_unseen_for_the_ttl() {
+        let clock = Arc::new(SimClock::new());
+        let store = store(100, 8).await.with_clock(clock.clone());
+        let ttl = store.ttl;
+        store.heartbeat("gw-1", &online(&store, "alice")).await.unwrap();
+        store.heartbeat("gw-1", &online(&store, "bob")).await.unwrap();
+
+        clock.advance(ttl - Duration::from_secs(1));
+        assert!(store.lookup("alice").await.unwrap().is_some());
+        store.heartbeat("gw-1", &online(&store, "alice")).await.unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        assert!(store.lookup("bob").await.unwrap().is_none());
+        assert!(store.lookup("alice").await.unwrap().is_some(), "the second heartbeat restarted alice's TTL");
+        // Still in the bucket until its max_age removes it
+        assert!(store.kv.get(presence_key("bob")).await.unwrap().is_some());
+
+        clock.advance(ttl);
+        assert!(store.lookup("alice").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn bulk_refresh_writes_each_user_once_like_heartbeats() {
+        let individual = store(100, 8).await;
+        for user_id in users("user", 500) {
+            individual.heartbeat("gw-1", &online(&individual, &user_id)).await.unwrap();
+        }
+
+        let bulk = store(100, 8).await;
+        let outcome = bulk
+            .bulk_refresh(refresh(&bulk, BulkUserSet::Users(users("user", 500))), &Deadline::none())
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, BulkRefreshOutcome::Completed { users: 500 });
+        assert_eq!(writes(&individual).await, 500);
+        assert_eq!(writes(&bulk).await, writes(&individual).await);
+        assert_eq!(bulk.gateway_user_count("gw-1"), 500);
+        assert_eq!(bulk.lookup("user-7").await.unwrap().unwrap().gateway_id, "gw-1");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn shard_range_refresh_only_rewrites_registered_users_in_range() {
+        let store = store(100, 8).await;
+        store
+            .bulk_refresh(refresh(&store, BulkUserSet::Users(users("user", 200))), &Deadline::none())
+            .await
+            .unwrap();
+        let before = writes(&store).await;
+
+        let in_first_shard = users("user", 200)
+            .iter()
+            .filter(|user_id| shard_for(user_id, store.shard_count) == 0)
+            .count();
+        let outcome = store
+            .bulk_refresh(refresh(&store, BulkUserSet::ShardRanges(vec![(0, 0)])), &Deadline::none())
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, BulkRefreshOutcome::Completed { users: in_first_shard });
+        assert_eq!(writes(&store).await - before, in_first_shard as u64);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_newer_refresh_supersedes_one_in_progress() {
+        let store = store(1, 1).await;
+        let older = refresh(&store, BulkUserSet::Users(users("old", 200)));
+        let newer = refresh(&store, BulkUserSet::Users(users("new", 3)));
+
+        let deadline = Deadline::none();
+        let (older, newer) = tokio::join!(
+            store.bulk_refresh(older, &deadline),
+            store.bulk_refresh(newer, &deadline)
+        );
+
+        assert_eq!(older.unwrap(), BulkRefreshOutcome::Superseded);
+        assert_eq!(newer.unwrap(), BulkRefreshOutcome::Completed { users: 3 });
+        // The older expansion stopped between chunks and never touched the registry
+        assert!(writes(&store).await < 203);
+        assert_eq!(store.gateway_user_count("gw-1"), 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_refresh_past_its_deadline_stops_writing_within_a_chunk() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let store = store(1, 1).await;
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(1));
+
+        let (outcome, ()) = tokio::join!(
+            store.bulk_refresh(refresh(&store, BulkUserSet::Users(users("user", 200))), &deadline),
+            async {
+                while writes(&store).await < 5 {
+                    tokio::task::yield_now().await;
+                }
+                clock.advance(Duration::from_secs(1));
+            }
+        );
+
+        let BulkRefreshOutcome::DeadlineExceeded { written } = outcome.unwrap() else {
+            panic!("refresh outlived its deadline");
+        };
+        assert!((5..200).contains(&written), "{}", written);
+        // The chunk in flight when the deadline passed finished, nothing after it started
+        assert_eq!(writes(&store).await, written as u64);
+        assert_eq!(store.gateway_user_count("gw-1"), 0, "the registry is left as it was");
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains(r#"broker_deadline_abandoned_total{rpc="presence_bulk_refresh",stage="chunk"} 1"#),
+            "{}",
+            rendered
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn deltas_follow_a_bulk_refresh() {
+        let store = store(100, 8).await;
+        store
+            .bulk_refresh(refresh(&store, BulkUserSet::Users(users("user", 10))), &Deadline::none())
+            .await
+            .unwrap();
+        let delta = PresenceDelta {
+            gateway_id: "gw-1".into(),
+            online: vec!["user-10".into()],
+            offline: vec!["user-0".into(), "user-1".into()],
+            timestamp: store.clock.now_millis(),
+        };
+        store.apply_delta(&delta).await.unwrap();
+
+        assert_eq!(store.gateway_user_count("gw-1"), 9);
+        assert_eq!(store.lookup("user-0").await.unwrap().unwrap().status, PresenceStatus::Offline);
+    }
+}
@@ -0,0 +1,386 @@
+use std::{collections::HashMap, sync::Arc};
+use arc_swap::ArcSwap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::{PreviewConfig, PreviewPolicy},
+    config_watch::ConfigWatcher,
+    content_policy::normalize,
+    conversation::ConversationStateStore,
+    message::types::{MessageEnvelope, MessageType},
+};
+
+/// How much of a message a tenant's previews may show
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewMode {
+    /// Leading text of readable text payloads, templates for everything else
+    #[default]
+    Text,
+    /// The same fixed placeholder for every message
+    Placeholder,
+    /// No preview
+    None,
+}
+
+/// What a payload is, for picking a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Text,
+    Image,
+    Video,
+    Audio,
+    File,
+}
+
+impl ContentKind {
+    /// Kind of `content_type`, or of the message type when none is declared
+    pub fn of(envelope: &MessageEnvelope) -> Self {
+        match envelope.payload.content_type.as_deref().map(normalize) {
+            Some(content_type) => match content_type.split('/').next().unwrap_or_default() {
+                "text" => ContentKind::Text,
+                "image" => ContentKind::Image,
+                "video" => ContentKind::Video,
+                "audio" => ContentKind::Audio,
+                _ => ContentKind::File,
+            },
+            None if envelope.message_type == MessageType::MediaMessage => ContentKind::File,
+            None => ContentKind::Text,
+        }
+    }
+
+    fn default_template(&self) -> &'static str {
+        match self {
+            ContentKind::Text => "💬 Message",
+            ContentKind::Image => "📷 Photo",
+            ContentKind::Video => "🎥 Video",
+            ContentKind::Audio => "🎤 Audio",
+            ContentKind::File => "📎 File",
+        }
+    }
+}
+
+/// Preview text for push hand-off and the last-message cache
+///
+/// Both go through `extract`, so a conversation's cached preview and the
+/// push for the same message always agree. A tenant's policy replaces
+/// `preview.default` entirely:
+///
+/// - `text`: the first `max_graphemes` grapheme clusters of a text payload,
+///   cut on cluster boundaries so emoji sequences and combining marks stay
+///   whole. Only unencrypted payloads (no `iv`, `tag` or `key_id`) have text
+///   the broker can read; end-to-end encrypted ones get the kind's template.
+/// - `placeholder`: `placeholder` for every message, whatever its content,
+///   for regulated tenants whose content must not leave the broker.
+/// - `none`: no preview at all.
+///
+/// Non-text content uses the most specific `templates` entry for its
+/// content type (exact, then `type/*`, then `*/*`), falling back to a
+/// built-in label per kind.
+pub struct PreviewExtractor {
+    config: ArcSwap<PreviewConfig>,
+}
+
+impl PreviewExtractor {
+    pub fn new(config: PreviewConfig) -> Self {
+        Self {
+            config: ArcSwap::from_pointee(config),
+        }
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let extractor = Arc::clone(self);
+        watcher.on_reload(move |config| extractor.config.store(Arc::new(config.preview.clone())));
+    }
+
+    pub fn extract(&self, envelope: &MessageEnvelope) -> Option<String> {
+        let config = self.config.load();
+        let policy = envelope
+            .tenant_id
+            .as_deref()
+            .and_then(|tenant| config.tenants.get(tenant))
+            .unwrap_or(&config.default);
+        extract_with(policy, envelope)
+    }
+}
+
+fn extract_with(policy: &PreviewPolicy, envelope: &MessageEnvelope) -> Option<String> {
+    match policy.mode {
+        PreviewMode::None => None,
+        PreviewMode::Placeholder => Some(policy.placeholder.clone()),
+        PreviewMode::Text => {
+            let kind = ContentKind::of(envelope);
+            if kind == ContentKind::Text {
+                if let Some(text) = readable_text(envelope) {
+                    return Some(truncate_graphemes(&text, policy.max_graphemes));
+                }
+            }
+            Some(template(&policy.templates, envelope, kind))
+        }
+    }
+}
+
+fn template(templates: &HashMap<String, String>, envelope: &MessageEnvelope, kind: ContentKind) -> String {
+    let lookup = |pattern: &str| templates.get(pattern).cloned();
+    envelope
+        .payload
+        .content_type
+        .as_deref()
+        .map(normalize)
+        .and_then(|content_type| {
+            let major = content_type.split('/').next().unwrap_or_default().to_string();
+            lookup(&content_type).or_else(|| lookup(&format!("{}/*", major)))
+        })
+        .or_else(|| lookup("*/*"))
+        .unwrap_or_else(|| kind.default_template().to_string())
+}
+
+/// Plaintext of an unencrypted text payload
+fn readable_text(envelope: &MessageEnvelope) -> Option<String> {
+    let payload = &envelope.payload;
+    if payload.iv.is_some() || payload.tag.is_some() || payload.key_id.is_some() {
+        return None;
+    }
+    let bytes = STANDARD.decode(&payload.ciphertext).ok()?;
+    let text = String::from_utf8(bytes).ok()?;
+    // Line breaks and other controls read badly in a notification
+    let text: String = text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// At most `max` grapheme clusters of `text`, ending in `…` when cut
+pub fn truncate_graphemes(text: &str, max: usize) -> String {
+    let max = max.max(1);
+    if text.graphemes(true).nth(max).is_none() {
+        return text.to_string();
+    }
+    // The ellipsis counts towards `max`
+    let end = text
+        .grapheme_indices(true)
+        .nth(max - 1)
+        .map_or(text.len(), |(index, _)| index);
+    format!("{}…", text[..end].trim_end())
+}
+
+/// Last message preview of a conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastMessagePreview {
+    pub message_id: String,
+    pub from: String,
+    pub preview: String,
+    /// Message timestamp in milliseconds
+    pub timestamp: i64,
+    /// Timestamp in milliseconds
+    pub cached_at: i64,
+}
+
+/// Retained last-message preview per conversation, extracted with the same
+/// policy as push hand-off
+pub struct PreviewCache {
+    extractor: Arc<PreviewExtractor>,
+    previews: DashMap<String, LastMessagePreview>,
+    clock: SharedClock,
+}
+
+impl PreviewCache {
+    pub fn new(extractor: Arc<PreviewExtractor>) -> Self {
+        Self {
+            extractor,
+            previews: DashMap::new(),
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keep `envelope`'s preview unless a newer message is already cached
+    pub fn record(&self, envelope: &MessageEnvelope) {
+        let conversation_id = envelope.conversation_id();
+        let Some(preview) = self.extractor.extract(envelope) else {
+            self.previews.remove(&conversation_id);
+            return;
+        };
+        let entry = LastMessagePreview {
+            message_id: envelope.message_id.clone(),
+            from: envelope.from.clone(),
+            preview,
+            timestamp: envelope.timestamp,
+            cached_at: self.clock.now_millis(),
+        };
+        self.previews
+            .entry(conversation_id)
+            .and_modify(|current| {
+                if current.timestamp <= entry.timestamp {
+                    *current = entry.clone();
+                }
+            })
+            .or_insert(entry);
+    }
+
+    pub fn get(&self, conversation_id: &str) -> Option<LastMessagePreview> {
+        self.previews.get(conversation_id).map(|entry| entry.clone())
+    }
+}
+
+impl ConversationStateStore for PreviewCache {
+    fn name(&self) -> &'static str {
+        "preview"
+    }
+
+    fn evict(&self, conversation_id: &str) -> bool {
+        self.previews.remove(conversation_id).is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.previews.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        message::types::EncryptedPayload,
+    };
+
+    fn policy(mode: PreviewMode) -> PreviewPolicy {
+        PreviewPolicy {
+            mode,
+            max_graphemes: 10,
+            placeholder: "New message".into(),
+            templates: HashMap::from([
+                ("image/gif".to_string(), "GIF".to_string()),
+                ("audio/*".to_string(), "🎙️ Voice message".to_string()),
+            ]),
+        }
+    }
+
+    fn extractor() -> PreviewExtractor {
+        let mut config = BrokerConfig::load().unwrap().preview;
+        config.default = policy(PreviewMode::Text);
+        config.tenants = HashMap::from([
+            ("regulated".to_string(), policy(PreviewMode::Placeholder)),
+            ("silent".to_string(), policy(PreviewMode::None)),
+        ]);
+        PreviewExtractor::new(config)
+    }
+
+    fn envelope(text: &str, content_type: Option<&str>) -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::TextMessage,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: STANDARD.encode(text),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: content_type.map(str::to_string),
+            },
+        )
+    }
+
+    fn tenant(mut envelope: MessageEnvelope, tenant: &str) -> MessageEnvelope {
+        envelope.tenant_id = Some(tenant.into());
+        envelope
+    }
+
+    #[test]
+    fn each_content_type_gets_its_text_or_template() {
+        let extractor = extractor();
+        let preview = |content_type: Option<&str>| extractor.extract(&envelope("See you\nat noon", content_type)).unwrap();
+
+        assert_eq!(preview(None), "See you a…");
+        assert_eq!(preview(Some("Text/Plain; charset=utf-8")), "See you a…");
+        assert_eq!(preview(Some("image/jpeg")), "📷 Photo");
+        assert_eq!(preview(Some("image/gif")), "GIF", "an exact template beats the built-in label");
+        assert_eq!(preview(Some("video/mp4")), "🎥 Video");
+        assert_eq!(preview(Some("audio/ogg")), "🎙️ Voice message", "a wildcard template beats the built-in label");
+        assert_eq!(preview(Some("application/pdf")), "📎 File");
+
+        let mut media = envelope("{}", None);
+        media.message_type = MessageType::MediaMessage;
+        assert_eq!(extractor.extract(&media).unwrap(), "📎 File");
+
+        // Text the broker can't read gets the text template, not ciphertext
+        let mut encrypted = envelope("See you at noon", Some("text/plain"));
+        encrypted.payload.iv = Some("iv".into());
+        assert_eq!(extractor.extract(&encrypted).unwrap(), "💬 Message");
+        let mut binary = envelope("", None);
+        binary.payload.ciphertext = STANDARD.encode([0xff, 0xfe, 0x00]);
+        assert_eq!(extractor.extract(&binary).unwrap(), "💬 Message");
+        assert_eq!(extractor.extract(&envelope(" \n\t ", None)).unwrap(), "💬 Message");
+    }
+
+    #[test]
+    fn tenant_policies_replace_the_default() {
+        let extractor = extractor();
+        let text = envelope("Lab results attached", None);
+        let photo = envelope("", Some("image/png"));
+
+        assert_eq!(extractor.extract(&text).unwrap(), "Lab resul…");
+        assert_eq!(extractor.extract(&tenant(text.clone(), "unlisted")).unwrap(), "Lab resul…");
+        // Regulated tenants' content never reaches the push service, whatever its type
+        assert_eq!(extractor.extract(&tenant(text.clone(), "regulated")).unwrap(), "New message");
+        assert_eq!(extractor.extract(&tenant(photo.clone(), "regulated")).unwrap(), "New message");
+        assert_eq!(extractor.extract(&tenant(text, "silent")), None);
+        assert_eq!(extractor.extract(&tenant(photo, "silent")), None);
+    }
+
+    #[test]
+    fn truncation_keeps_grapheme_clusters_whole() {
+        // Nine clusters fit with the ellipsis; none is split in any script
+        let family = "👨‍👩‍👧‍👦";
+        let flag = "🇯🇵";
+        let accented = "e\u{301}";
+        let text = format!("{}{}{}abcdefghij", family, flag, accented);
+        let truncated = truncate_graphemes(&text, 10);
+        assert_eq!(truncated, format!("{}{}{}abcdef…", family, flag, accented));
+        assert_eq!(truncated.graphemes(true).count(), 10);
+
+        assert_eq!(truncate_graphemes("日本語のテキストです。長い", 5), "日本語の…");
+        assert_eq!(truncate_graphemes("한국어 메시지", 4), "한국어…", "trailing spaces go before the ellipsis");
+        // Exactly at the limit nothing is cut
+        assert_eq!(truncate_graphemes(&family.repeat(10), 10), family.repeat(10));
+        assert_eq!(truncate_graphemes(&family.repeat(11), 10), format!("{}…", family.repeat(9)));
+        assert_eq!(truncate_graphemes("ab", 0), "…", "at least the ellipsis");
+    }
+
+    #[test]
+    fn the_cache_agrees_with_hand_off_and_keeps_the_newest_message() {
+        let extractor = Arc::new(extractor());
+        let clock = Arc::new(SimClock::new());
+        let cache = PreviewCache::new(extractor.clone()).with_clock(clock.clone());
+
+        let mut first = envelope("An earlier message about lunch", None);
+        first.timestamp = 1_000;
+        let mut second = envelope("", Some("image/png"));
+        second.timestamp = 2_000;
+        cache.record(&second);
+        clock.advance(Duration::from_secs(5));
+        cache.record(&first);
+
+        let cached = cache.get(&second.conversation_id()).unwrap();
+        assert_eq!(cached.message_id, second.message_id);
+        assert_eq!(Some(cached.preview), extractor.extract(&second));
+        assert_eq!(cached.cached_at, clock.now_millis() - 5_000);
+
+        // A tenant without previews drops the cached one rather than leave it stale
+        let mut silent = tenant(envelope("Hidden", None), "silent");
+        silent.timestamp = 3_000;
+        cache.record(&silent);
+        assert!(cache.get(&silent.conversation_id()).is_none());
+    }
+}
@@ -0,0 +1,194 @@
+use std::{num::NonZeroUsize, time::Instant};
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::PriorityInheritanceConfig,
+    message::types::{MessageEnvelope, Priority},
+    metrics::BrokerMetrics,
+    trace::MessageTrace,
+};
+
+/// Promotes replies to the priority of the message they answer
+///
+/// Fanout records each message's priority; replies carrying `in_reply_to`
+/// are raised to at most the parent's priority, capped per tenant. Replies
+/// are never demoted and cache misses leave the priority unchanged.
+pub struct PriorityInheritance {
+    recent: Mutex<LruCache<String, (Priority, Instant)>>,
+    config: PriorityInheritanceConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl PriorityInheritance {
+    pub fn new(config: PriorityInheritanceConfig, metrics: BrokerMetrics) -> Self {
+        let capacity = NonZeroUsize::new(config.cache_size.max(1)).unwrap();
+        Self {
+            recent: Mutex::new(LruCache::new(capacity)),
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Remember a fanned-out message's priority
+    pub fn record(&self, message_id: &str, priority: Priority) {
+        if !self.config.enabled || priority == Priority::Bulk {
+            return;
+        }
+        self.recent
+            .lock()
+            .put(message_id.to_string(), (priority, self.clock.now_instant()));
+    }
+
+    /// Promote a reply if its parent is known, returning the new priority
+    pub fn apply(&self, envelope: &mut MessageEnvelope, trace: &mut MessageTrace) -> Option<Priority> {
+        if !self.config.enabled {
+            return None;
+        }
+        let parent_id = envelope.in_reply_to.as_deref()?;
+
+        let now = self.clock.now_instant();
+        let parent = {
+            let mut recent = self.recent.lock();
+            match recent.get(parent_id) {
+                Some((priority, at)) if now.saturating_duration_since(*at) < self.config.cache_ttl => Some(*priority),
+                Some(_) => {
+                    recent.pop(parent_id);
+                    None
+                }
+                None => None,
+            }
+        };
+
+        let Some(parent) = parent else {
+            self.metrics.record_priority_inheritance("cache_miss");
+            return None;
+        };
+
+        let cap = envelope
+            .tenant_id
+            .as_ref()
+            .and_then(|tenant| self.config.tenant_caps.get(tenant))
+            .copied()
+            .unwrap_or(self.config.default_cap)
+            .min(Priority::High);
+        let inherited = parent.min(cap);
+
+        if inherited <= envelope.priority {
+            self.metrics.record_priority_inheritance("unchanged");
+            return None;
+        }
+
+        trace.record(
+            "priority_inheritance",
+            format!("promoted {:?} -> {:?} from parent {}", envelope.priority, inherited, parent_id),
+        );
+        envelope.priority = inherited;
+        self.metrics.record_priority_inheritance("promoted");
+        Some(inherited)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    const TTL: Duration = Duration::from_secs(600);
+
+    fn capped_at(default_cap: Priority) -> (PriorityInheritance, Arc<SimClock>) {
+        let config = PriorityInheritanceConfig {
+            enabled: true,
+            cache_size: 100,
+            cache_ttl: TTL,
+            default_cap,
+            tenant_caps: HashMap::from([("capped".to_string(), Priority::Normal)]),
+        };
+        let clock = Arc::new(SimClock::new());
+        let inheritance = PriorityInheritance::new(config, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (inheritance, clock)
+    }
+
+    fn reply(parent_id: &str, priority: Priority) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(MessageType::TextMessage, "alice".into(), vec!["ops".into()], payload);
+        envelope.in_reply_to = Some(parent_id.into());
+        envelope.priority = priority;
+        envelope
+    }
+
+    #[test]
+    fn replies_to_high_priority_parents_are_promoted_and_traced() {
+        let (inheritance, _) = capped_at(Priority::High);
+        inheritance.record("alert-1", Priority::High);
+
+        let mut envelope = reply("alert-1", Priority::Normal);
+        let mut trace = MessageTrace::new(&envelope.message_id);
+        assert_eq!(inheritance.apply(&mut envelope, &mut trace), Some(Priority::High));
+        assert_eq!(envelope.priority, Priority::High);
+        assert_eq!(trace.events.len(), 1);
+        assert_eq!(trace.events[0].stage, "priority_inheritance");
+    }
+
+    #[test]
+    fn unknown_and_expired_parents_pass_through() {
+        let (inheritance, clock) = capped_at(Priority::High);
+        let mut trace = MessageTrace::default();
+
+        let mut envelope = reply("unknown", Priority::Normal);
+        assert_eq!(inheritance.apply(&mut envelope, &mut trace), None);
+        assert_eq!(envelope.priority, Priority::Normal);
+
+        inheritance.record("alert-1", Priority::High);
+        clock.advance(TTL);
+        let mut envelope = reply("alert-1", Priority::Normal);
+        assert_eq!(inheritance.apply(&mut envelope, &mut trace), None);
+        assert_eq!(envelope.priority, Priority::Normal);
+        assert!(trace.events.is_empty());
+    }
+
+    #[test]
+    fn replies_are_never_demoted() {
+        let (inheritance, _) = capped_at(Priority::High);
+        inheritance.record("chat-1", Priority::Normal);
+
+        let mut envelope = reply("chat-1", Priority::High);
+        assert_eq!(inheritance.apply(&mut envelope, &mut MessageTrace::default()), None);
+        assert_eq!(envelope.priority, Priority::High);
+    }
+
+    #[test]
+    fn promotion_stops_at_the_cap() {
+        let (inheritance, _) = capped_at(Priority::High);
+        inheritance.record("alert-1", Priority::High);
+
+        let mut envelope = reply("alert-1", Priority::Bulk);
+        envelope.tenant_id = Some("capped".into());
+        assert_eq!(inheritance.apply(&mut envelope, &mut MessageTrace::default()), Some(Priority::Normal));
+
+        let (inheritance, _) = capped_at(Priority::Normal);
+        inheritance.record("alert-1", Priority::High);
+        let mut envelope = reply("alert-1", Priority::Normal);
+        assert_eq!(inheritance.apply(&mut envelope, &mut MessageTrace::default()), None);
+        assert_eq!(envelope.priority, Priority::Normal);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::info;
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    config::ProfilingConfig,
+    metrics::BrokerMetrics,
+};
+
+/// Clears the in-progress flag when a collection ends, however it ends
+struct Collecting<'a>(&'a AtomicBool);
+
+impl Drop for Collecting<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// On-demand CPU and heap profiles in gzipped pprof protobuf format
+///
+/// CPU profiles sample stacks with pprof-rs for the requested duration,
+/// capped at `profiling.max_seconds`, and need the `profiling` feature.
+/// Heap profiles dump jemalloc's sampled allocation profile and need the
+/// `heap-profiling` feature plus `prof:true` in jemalloc's `malloc_conf`.
+/// Without the feature an endpoint answers `Unsupported`, so builds that
+/// never profile carry none of the machinery. Only one collection of
+/// either kind runs at a time; every one is audited and counted in
+/// `broker_profiles_total{kind,outcome}`.
+pub struct Profiler {
+    config: ProfilingConfig,
+    collecting: AtomicBool,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl Profiler {
+    pub fn new(config: ProfilingConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        Self {
+            config,
+            collecting: AtomicBool::new(false),
+            audit,
+            metrics,
+        }
+    }
+
+    fn begin(&self, kind: &'static str) -> Result<Collecting<'_>, ProfileError> {
+        if !self.config.enabled {
+            return Err(ProfileError::Disabled);
+        }
+        self.collecting
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| {
+                self.metrics.record_profile(kind, "busy");
+                ProfileError::Busy
+            })?;
+        Ok(Collecting(&self.collecting))
+    }
+
+    fn finish(&self, kind: &'static str, actor: &str, details: serde_json::Value, result: &Result<Vec<u8>, ProfileError>) {
+        match result {
+            Ok(profile) => {
+                info!("Collected {} profile ({} bytes) for {}", kind, profile.len(), actor);
+                self.audit.record(AuditEntry::new(actor, format!("profiling.{}", kind), details));
+                self.metrics.record_profile(kind, "collected");
+            }
+            Err(_) => self.metrics.record_profile(kind, "failed"),
+        }
+    }
+
+    /// Sample CPU stacks for `seconds`, capped at `profiling.max_seconds`
+    pub async fn cpu(&self, seconds: u64, actor: &str) -> Result<Vec<u8>, ProfileError> {
+        if seconds == 0 {
+            return Err(ProfileError::InvalidDuration);
+        }
+        let _collecting = self.begin("cpu")?;
+        let duration = Duration::from_secs(seconds.min(self.config.max_seconds));
+        let result = cpu::collect(duration, self.config.frequency).await;
+        self.finish(
+            "cpu",
+            actor,
+            serde_json::json!({ "seconds": duration.as_secs() }),
+            &result,
+        );
+        result
+    }
+
+    /// Sampled live allocations as of now
+    pub async fn heap(&self, actor: &str) -> Result<Vec<u8>, ProfileError> {
+        let _collecting = self.begin("heap")?;
+        let result = heap::collect().await;
+        self.finish("heap", actor, serde_json::json!({}), &result);
+        result
+    }
+}
+
+#[cfg(feature = "profiling")]
+mod cpu {
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use pprof::protos::Message;
+
+    use super::ProfileError;
+
+    /// How often the sampling thread checks whether the request went away
+    const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+    /// Tells the sampling thread to stop when the request future is dropped
+    struct CancelOnDrop(Arc<AtomicBool>);
+
+    impl Drop for CancelOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    pub async fn collect(duration: Duration, frequency: u32) -> Result<Vec<u8>, ProfileError> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel = CancelOnDrop(Arc::clone(&cancelled));
+        // The sampling guard is signal-driven and not Send; keep it on one blocking thread
+        tokio::task::spawn_blocking(move || {
+            let guard = pprof::ProfilerGuardBuilder::default()
+                .frequency(frequency as i32)
+                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+                .build()
+                .map_err(|e| ProfileError::Failed(e.to_string()))?;
+            // A client that disconnects frees the thread and the profiler within a poll
+            let deadline = Instant::now() + duration;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                if cancelled.load(Ordering::Acquire) {
+                    return Err(ProfileError::Cancelled);
+                }
+                std::thread::sleep((deadline - now).min(CANCEL_POLL));
+            }
+            let profile = guard
+                .report()
+                .build()
+                .and_then(|report| report.pprof())
+                .map_err(|e| ProfileError::Failed(e.to_string()))?;
+
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&profile.encode_to_vec())
+                .map_err(|e| ProfileError::Failed(e.to_string()))?;
+            encoder.finish().map_err(|e| ProfileError::Failed(e.to_string()))
+        })
+        .await
+        .map_err(|e| ProfileError::Failed(e.to_string()))?
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+mod cpu {
+    use std::time::Duration;
+
+    use super::ProfileError;
+
+    pub async fn collect(_duration: Duration, _frequency: u32) -> Result<Vec<u8>, ProfileError> {
+        Err(ProfileError::Unsupported("profiling"))
+    }
+}
+
+#[cfg(feature = "heap-profiling")]
+mod heap {
+    use super::ProfileError;
+
+    pub async fn collect() -> Result<Vec<u8>, ProfileError> {
+        let Some(control) = jemalloc_pprof::PROF_CTL.as_ref() else {
+            return Err(ProfileError::HeapInactive);
+        };
+        let mut control = control.lock().await;
+        if !control.activated() {
+            return Err(ProfileError::HeapInactive);
+        }
+        // Already gzipped pprof
+        control.dump_pprof().map_err(|e| ProfileError::Failed(e.to_string()))
+    }
+}
+
+#[cfg(not(feature = "heap-profiling"))]
+mod heap {
+    use super::ProfileError;
+
+    pub async fn collect() -> Result<Vec<u8>, ProfileError> {
+        Err(ProfileError::Unsupported("heap-profiling"))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("profiling is disabled")]
+    Disabled,
+    #[error("built without the {0} feature")]
+    Unsupported(&'static str),
+    #[error("jemalloc heap profiling is not active; set prof:true in malloc_conf")]
+    HeapInactive,
+    #[error("another profile is being collected")]
+    Busy,
+    #[error("profile duration must be at least one second")]
+    InvalidDuration,
+    #[error("profile request went away before the collection finished")]
+    Cancelled,
+    #[error("profile collection failed: {0}")]
+    Failed(String),
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "profiling")]
+    use std::{sync::Arc, time::Instant};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    fn profiler(configure: impl FnOnce(&mut ProfilingConfig)) -> Profiler {
+        let mut config = BrokerConfig::load().unwrap().profiling;
+        config.enabled = true;
+        configure(&mut config);
+        Profiler::new(config, AuditLog::tracing_only(), BrokerMetrics::new().unwrap())
+    }
+
+    #[tokio::test]
+    async fn one_collection_runs_at_a_time() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let profiler = profiler(|_| {});
+
+        let collecting = profiler.begin("cpu").unwrap();
+        assert!(matches!(profiler.cpu(1, "ops").await, Err(ProfileError::Busy)));
+        assert!(matches!(profiler.heap("ops").await, Err(ProfileError::Busy)));
+        drop(collecting);
+        assert!(profiler.begin("heap").is_ok());
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_profiles_total{kind="cpu",outcome="busy"} 1"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_profiles_total{kind="heap",outcome="busy"} 1"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn disabled_profiling_and_empty_durations_are_refused() {
+        let disabled = profiler(|config| config.enabled = false);
+        assert!(matches!(disabled.cpu(1, "ops").await, Err(ProfileError::Disabled)));
+        assert!(matches!(disabled.heap("ops").await, Err(ProfileError::Disabled)));
+        assert!(matches!(profiler(|_| {}).cpu(0, "ops").await, Err(ProfileError::InvalidDuration)));
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    #[tokio::test]
+    async fn builds_without_the_feature_answer_unsupported() {
+        let profiler = profiler(|_| {});
+        assert!(matches!(profiler.cpu(1, "ops").await, Err(ProfileError::Unsupported("profiling"))));
+        // The failed collection released the slot
+        assert!(profiler.begin("cpu").is_ok());
+    }
+
+    #[cfg(not(feature = "heap-profiling"))]
+    #[tokio::test]
+    async fn heap_profiles_need_their_feature() {
+        let profiler = profiler(|_| {});
+        assert!(matches!(profiler.heap("ops").await, Err(ProfileError::Unsupported("heap-profiling"))));
+    }
+
+    /// Keeps one core busy until dropped, so the profile has stacks to sample
+    #[cfg(feature = "profiling")]
+    struct SyntheticLoad(Arc<AtomicBool>, Option<std::thread::JoinHandle<u64>>);
+
+    #[cfg(feature = "profiling")]
+    impl SyntheticLoad {
+        fn start() -> Self {
+            let stop = Arc::new(AtomicBool::new(false));
+            let running = Arc::clone(&stop);
+            let worker = std::thread::spawn(move || {
+                let mut hash = 0u64;
+                while !running.load(Ordering::Relaxed) {
+                    for round in 0..10_000u64 {
+                        hash = hash.wrapping_mul(6364136223846793005).wrapping_add(round);
+                    }
+                    std::hint::black_box(hash);
+                }
+                hash
+            });
+            Self(stop, Some(worker))
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    impl Drop for SyntheticLoad {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+            if let Some(worker) = self.1.take() {
+                let _ = worker.join();
+            }
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[tokio::test]
+    async fn cpu_profiles_parse_as_pprof_under_load() {
+        use std::io::Read;
+        use pprof::protos::Message;
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let profiler = profiler(|config| config.frequency = 250);
+        let load = SyntheticLoad::start();
+        let gzipped = profiler.cpu(1, "ops").await.unwrap();
+        drop(load);
+
+        let mut encoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzipped[..]).read_to_end(&mut encoded).unwrap();
+        let profile = pprof::protos::Profile::decode(&encoded[..]).unwrap();
+        assert!(!profile.sample.is_empty(), "no samples in {} bytes", encoded.len());
+        let name = |index: i64| profile.string_table[index as usize].as_str();
+        let sample_types: Vec<_> = profile.sample_type.iter().map(|t| (name(t.ty), name(t.unit))).collect();
+        assert!(sample_types.contains(&("cpu", "nanoseconds")), "{:?}", sample_types);
+        assert!(profile.string_table.iter().any(|name| name.contains("SyntheticLoad")));
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_profiles_total{kind="cpu",outcome="collected"} 1"#), "{}", rendered);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[tokio::test]
+    async fn requested_durations_are_capped() {
+        let profiler = profiler(|config| config.max_seconds = 1);
+        let started = Instant::now();
+        profiler.cpu(3600, "ops").await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+
+    #[cfg(feature = "profiling")]
+    #[tokio::test]
+    async fn a_dropped_request_frees_the_profiler_within_a_poll() {
+        let profiler = profiler(|_| {});
+        let started = Instant::now();
+        assert!(tokio::time::timeout(Duration::from_millis(300), profiler.cpu(60, "ops")).await.is_err());
+
+        // The abandoned sampler stops and releases pprof's single profiler slot
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        profiler.cpu(1, "ops").await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    }
+}
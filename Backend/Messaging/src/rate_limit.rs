@@ -0,0 +1,1166 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use arc_swap::ArcSwapOption;
+use axum::{
+    http::HeaderValue,
+    response::{IntoResponseParts, ResponseParts},
+};
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::{
+    abuse::{AbuseScores, AbuseSignal},
+    background_quota::{BackgroundQuota, WorkerClass},
+    clock::{SharedClock, SystemClock},
+    config::{QuotaFeedbackConfig, RateLimits},
+    message::types::{MessageEnvelope, Priority},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+    user_events::{RecentUserEvents, UserEventKind},
+};
+
+pub const RATE_LIMIT_LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub const RATE_LIMIT_REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub const RATE_LIMIT_RESET_HEADER: &str = "x-ratelimit-reset";
+
+/// Metadata on a released deferred message: milliseconds it waited for quota
+pub const DEFERRED_METADATA: &str = "rate_limit_deferred_ms";
+
+/// A High priority message held until its sender has quota again
+struct Deferred {
+    envelope: MessageEnvelope,
+    deferred_at: Instant,
+}
+
+/// Token bucket for one user, with its earned burst credit
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    /// Current earn-back window and messages sent in it
+    window_start: Instant,
+    window_sent: u32,
+    quiet_windows: u32,
+    credit: f64,
+    /// High priority messages waiting for tokens, oldest first
+    deferred: VecDeque<Deferred>,
+}
+
+impl Bucket {
+    fn new(now: Instant, capacity: f64) -> Self {
+        Self {
+            tokens: capacity,
+            last_refill: now,
+            window_start: now,
+            window_sent: 0,
+            quiet_windows: 0,
+            credit: 0.0,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant, capacity: f64, per_second: f64) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.last_refill = now;
+    }
+
+    /// Close finished windows, earning credit for quiet streaks; returns credit earned
+    fn roll_windows(&mut self, now: Instant, earn_back: &EarnBack) -> f64 {
+        let elapsed = now.duration_since(self.window_start).as_secs_f64();
+        let completed = (elapsed / earn_back.window.as_secs_f64()) as u32;
+        if completed == 0 {
+            return 0.0;
+        }
+        self.window_start += earn_back.window * completed;
+
+        // Only the first finished window saw traffic; any after it were idle
+        let busy = self.window_sent as f64 > earn_back.quiet_threshold;
+        if busy {
+            self.quiet_windows = 0;
+        }
+        let before = self.quiet_windows;
+        self.quiet_windows = self.quiet_windows.saturating_add(completed - busy as u32);
+        self.window_sent = 0;
+
+        let earning_windows = self.quiet_windows.saturating_sub(before.max(earn_back.quiet_windows - 1));
+        let earned = (earning_windows as f64 * earn_back.per_window).min(earn_back.max - self.credit);
+        if earned <= 0.0 {
+            return 0.0;
+        }
+        self.credit += earned;
+        earned
+    }
+}
+
+/// A sender's bucket right after a check, for rate-limit feedback
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QuotaStatus {
+    /// Messages per window
+    pub limit: u32,
+    /// Whole messages that can be sent now, burst credit included
+    pub remaining: u32,
+    /// Seconds until the bucket is full again, rounded up
+    pub reset_seconds: u64,
+    /// Until one more message would be admitted, behind any deferred ones
+    #[serde(skip)]
+    pub retry_after: Duration,
+}
+
+impl QuotaStatus {
+    fn of(bucket: &Bucket, capacity: f64, per_second: f64) -> Self {
+        Self {
+            limit: capacity as u32,
+            remaining: (bucket.tokens + bucket.credit).max(0.0) as u32,
+            reset_seconds: ((capacity - bucket.tokens).max(0.0) / per_second).ceil() as u64,
+            // A zero override never refills
+            retry_after: Duration::try_from_secs_f64(
+                (bucket.deferred.len() as f64 + 1.0 - bucket.tokens - bucket.credit).max(0.0) / per_second,
+            )
+            .unwrap_or(Duration::MAX),
+        }
+    }
+
+    /// `(name, value)` pairs for REST headers and gRPC metadata alike
+    pub fn header_values(&self) -> [(&'static str, String); 3] {
+        [
+            (RATE_LIMIT_LIMIT_HEADER, self.limit.to_string()),
+            (RATE_LIMIT_REMAINING_HEADER, self.remaining.to_string()),
+            (RATE_LIMIT_RESET_HEADER, self.reset_seconds.to_string()),
+        ]
+    }
+
+    /// Set the rate-limit entries on a tonic response or status
+    pub fn apply_metadata(&self, metadata: &mut tonic::metadata::MetadataMap) {
+        for (name, value) in self.header_values() {
+            metadata.insert(name, value.parse().expect("digits are valid metadata"));
+        }
+    }
+}
+
+/// Lets REST handlers return `(quota, body)` to add the `X-RateLimit-*` headers
+impl IntoResponseParts for QuotaStatus {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut parts: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        for (name, value) in self.header_values() {
+            parts
+                .headers_mut()
+                .insert(name, HeaderValue::from_str(&value).expect("digits are valid header values"));
+        }
+        Ok(parts)
+    }
+}
+
+/// Burst credit earn-back settings
+struct EarnBack {
+    window: Duration,
+    quiet_threshold: f64,
+    quiet_windows: u32,
+    per_window: f64,
+    max: f64,
+}
+
+/// Tokens taken for a batch that can be handed back if the batch is rejected
+#[derive(Debug, Default)]
+#[must_use = "reservations must be committed or released"]
+pub struct Reservation {
+    /// User, tokens taken, and how many of them came from burst credit
+    taken: Vec<(String, u32, f64)>,
+    /// Each user's bucket right after their tokens were taken
+    quotas: Vec<(String, QuotaStatus)>,
+}
+
+impl Reservation {
+    /// Keep the tokens; returns each user's quota as of the reservation
+    pub fn commit(mut self) -> Vec<(String, QuotaStatus)> {
+        self.taken.clear();
+        std::mem::take(&mut self.quotas)
+    }
+}
+
+/// Outcome of `UserRateLimiter::check_or_defer`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Allowed(QuotaStatus),
+    /// Held in the sender's deferred queue; released through `release_deferred`
+    Deferred(QuotaStatus),
+}
+
+/// Per-user message rate limiter (`limits.user_message_limit` per `limits.user_message_window`)
+///
+/// Users who stay under `limits.burst_credit_quiet_fraction` of their limit
+/// for `limits.burst_credit_quiet_windows` consecutive windows earn burst
+/// credit, which is spent before the bucket and forfeited the first time
+/// the user is actually limited.
+///
+/// High priority messages that would be rejected are instead held, up to
+/// `limits.deferred_queue_size` per user, in a queue kept on the user's
+/// bucket, so it goes when the bucket is evicted and a bucket with queued
+/// messages is never evicted. They are released in order as the bucket
+/// refills, each also taking a publish from the background quota's
+/// `rate_limit_deferred` share, so a flood of deferred releases can't crowd
+/// out live traffic. While a user has messages queued, newer messages can't
+/// take tokens ahead of them: High priority ones join the queue and the rest
+/// are rejected.
+pub struct UserRateLimiter {
+    buckets: DashMap<String, Bucket>,
+    capacity: f64,
+    per_second: f64,
+    earn_back: Option<EarnBack>,
+    deferred_queue_size: usize,
+    /// Per-user fraction of the normal limit, set by automated restrictions
+    overrides: DashMap<String, f64>,
+    /// Told about every hit once attached
+    abuse: ArcSwapOption<AbuseScores>,
+    recent_events: ArcSwapOption<RecentUserEvents>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl UserRateLimiter {
+    pub fn new(limits: &RateLimits, metrics: BrokerMetrics) -> Self {
+        let capacity = limits.user_message_limit as f64;
+        let window = limits.user_message_window.max(Duration::from_secs(1));
+        let earn_back = (limits.burst_credit_max > 0).then(|| EarnBack {
+            window,
+            quiet_threshold: capacity * limits.burst_credit_quiet_fraction,
+            quiet_windows: limits.burst_credit_quiet_windows.max(1),
+            per_window: limits.burst_credit_per_window as f64,
+            max: limits.burst_credit_max as f64,
+        });
+        Self {
+            buckets: DashMap::new(),
+            capacity,
+            per_second: capacity / window.as_secs_f64(),
+            earn_back,
+            deferred_queue_size: limits.deferred_queue_size,
+            overrides: DashMap::new(),
+            abuse: ArcSwapOption::empty(),
+            recent_events: ArcSwapOption::empty(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn attach_abuse(&self, abuse: Arc<AbuseScores>) {
+        self.abuse.store(Some(abuse));
+    }
+
+    pub fn attach_recent_events(&self, events: Arc<RecentUserEvents>) {
+        self.recent_events.store(Some(events));
+    }
+
+    fn record_hit(&self, user_id: &str) {
+        self.metrics.record_rate_limit_hit(user_id);
+        if let Some(abuse) = &*self.abuse.load() {
+            abuse.observe(user_id, AbuseSignal::RateLimited);
+        }
+        if let Some(events) = &*self.recent_events.load() {
+            events.record(user_id, UserEventKind::RateLimited, None, None);
+        }
+    }
+
+    /// Scale the user's limit to `fraction` of normal, or restore it with `None`
+    pub fn set_override(&self, user_id: &str, fraction: Option<f64>) {
+        match fraction {
+            Some(fraction) => {
+                self.overrides.insert(user_id.to_string(), fraction.clamp(0.0, 1.0));
+            }
+            None => {
+                self.overrides.remove(user_id);
+            }
+        }
+    }
+
+    /// Bucket capacity and refill rate for the user, after any override
+    fn limits_for(&self, user_id: &str) -> (f64, f64) {
+        let scale = self.overrides.get(user_id).map_or(1.0, |fraction| *fraction);
+        (self.capacity * scale, self.per_second * scale)
+    }
+
+    /// Take one token for the user
+    pub fn check(&self, user_id: &str) -> Result<QuotaStatus, RateLimited> {
+        let (taken, quota) = self.take(user_id, 1);
+        if taken.is_some() {
+            return Ok(quota);
+        }
+        self.record_hit(user_id);
+        Err(RateLimited {
+            user_id: user_id.to_string(),
+            quota,
+        })
+    }
+
+    /// Take one token for the sender, deferring High priority messages instead of rejecting them
+    pub fn check_or_defer(&self, envelope: &MessageEnvelope) -> Result<Admission, RateLimited> {
+        let user_id = envelope.from.as_str();
+        let now = self.clock.now_instant();
+        let (capacity, per_second) = self.limits_for(user_id);
+        let mut bucket = self
+            .buckets
+            .entry(user_id.to_string())
+            .or_insert_with(|| Bucket::new(now, capacity));
+
+        if bucket.deferred.is_empty() {
+            if let (Some(_), quota) = self.take_from(&mut bucket, now, capacity, per_second, 1) {
+                return Ok(Admission::Allowed(quota));
+            }
+        }
+        let quota = QuotaStatus::of(&bucket, capacity, per_second);
+
+        if envelope.priority == Priority::High && self.deferred_queue_size > 0 {
+            if bucket.deferred.len() < self.deferred_queue_size {
+                bucket.deferred.push_back(Deferred {
+                    envelope: envelope.clone(),
+                    deferred_at: now,
+                });
+                self.metrics.record_rate_limit_deferral("deferred");
+                return Ok(Admission::Deferred(quota));
+            }
+            self.metrics.record_rate_limit_deferral("overflow");
+        }
+        drop(bucket);
+        self.record_hit(user_id);
+        Err(RateLimited {
+            user_id: user_id.to_string(),
+            quota,
+        })
+    }
+
+    /// Release deferred messages whose senders have tokens again, oldest first per sender
+    ///
+    /// Stops once the background quota's `rate_limit_deferred` share is spent.
+    /// Released envelopes carry `DEFERRED_METADATA`.
+    pub fn release_deferred(&self, quota: &BackgroundQuota) -> Vec<MessageEnvelope> {
+        let now = self.clock.now_instant();
+        let mut released = Vec::new();
+        let mut waiting = 0;
+        let mut exhausted = false;
+
+        for mut bucket in self.buckets.iter_mut() {
+            if bucket.deferred.is_empty() {
+                continue;
+            }
+            let (capacity, per_second) = self.limits_for(bucket.key());
+            while !exhausted && !bucket.deferred.is_empty() {
+                bucket.refill(now, capacity, per_second);
+                if bucket.tokens + bucket.credit < 1.0 {
+                    break;
+                }
+                if !quota.try_acquire(WorkerClass::RateLimitDeferred, 1) {
+                    exhausted = true;
+                    break;
+                }
+                if self.take_from(&mut bucket, now, capacity, per_second, 1).0.is_none() {
+                    break;
+                }
+                let Some(Deferred { mut envelope, deferred_at }) = bucket.deferred.pop_front() else {
+                    break;
+                };
+                let waited = now.duration_since(deferred_at);
+                envelope
+                    .metadata
+                    .insert(DEFERRED_METADATA.to_string(), waited.as_millis().to_string());
+                self.metrics.record_rate_limit_deferral("released");
+                self.metrics.record_deferred_release_latency(waited.as_secs_f64());
+                released.push(envelope);
+            }
+            waiting += bucket.deferred.len();
+        }
+
+        quota.report_backlog(WorkerClass::RateLimitDeferred, waiting as u64);
+        self.metrics.update_rate_limit_deferred(waiting);
+        released
+    }
+
+    /// Poll for releasable deferred messages and hand them to `released` for routing
+    pub fn spawn_deferred_release(
+        self: &Arc<Self>,
+        quota: Arc<BackgroundQuota>,
+        poll: Duration,
+        released: mpsc::Sender<MessageEnvelope>,
+    ) -> tokio::task::JoinHandle<()> {
+        let limiter = Arc::clone(self);
+        spawn_traced("rate_limit_deferred_release", TaskContext::new("rate_limit"), async move {
+            loop {
+                limiter.clock.sleep(poll).await;
+                for envelope in limiter.release_deferred(&quota) {
+                    let message_id = envelope.message_id.clone();
+                    if released.send(envelope).await.is_err() {
+                        debug!("Router gone; dropping released deferred message {}", message_id);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Take `count` tokens from each user, all or nothing
+    pub fn reserve(&self, demands: &[(String, u32)]) -> Result<Reservation, RateLimited> {
+        let mut reservation = Reservation::default();
+
+        for (user_id, count) in demands {
+            let (taken, quota) = self.take(user_id, *count);
+            let Some(credit) = taken else {
+                self.release(reservation);
+                self.record_hit(user_id);
+                return Err(RateLimited {
+                    user_id: user_id.clone(),
+                    quota,
+                });
+            };
+            reservation.taken.push((user_id.clone(), *count, credit));
+            reservation.quotas.push((user_id.clone(), quota));
+        }
+
+        Ok(reservation)
+    }
+
+    /// Return reserved tokens to their buckets and credit
+    pub fn release(&self, mut reservation: Reservation) {
+        for (user_id, count, credit) in reservation.taken.drain(..) {
+            let (capacity, _) = self.limits_for(&user_id);
+            if let Some(mut bucket) = self.buckets.get_mut(&user_id) {
+                bucket.tokens = (bucket.tokens + count as f64 - credit).min(capacity);
+                bucket.credit += credit;
+                bucket.window_sent = bucket.window_sent.saturating_sub(count);
+            }
+        }
+    }
+
+    /// Drop buckets that have been idle long enough to be full again, unless messages are deferred on them
+    pub fn evict_idle(&self, idle: Duration) -> usize {
+        let now = self.clock.now_instant();
+        let before = self.buckets.len();
+        self.buckets.retain(|_, bucket| {
+            !bucket.deferred.is_empty() || now.duration_since(bucket.last_refill) < idle
+        });
+        before.saturating_sub(self.buckets.len())
+    }
+
+    /// Take `count` tokens, spending burst credit first; returns the credit
+    /// spent, or `None` if limited, and the bucket state under the same lock
+    fn take(&self, user_id: &str, count: u32) -> (Option<f64>, QuotaStatus) {
+        let now = self.clock.now_instant();
+        let (capacity, per_second) = self.limits_for(user_id);
+
+        let mut bucket = self
+            .buckets
+            .entry(user_id.to_string())
+            .or_insert_with(|| Bucket::new(now, capacity));
+        // Deferred messages have first claim on the tokens
+        if !bucket.deferred.is_empty() {
+            bucket.refill(now, capacity, per_second);
+            return (None, QuotaStatus::of(&bucket, capacity, per_second));
+        }
+        self.take_from(&mut bucket, now, capacity, per_second, count)
+    }
+
+    fn take_from(
+        &self,
+        bucket: &mut Bucket,
+        now: Instant,
+        capacity: f64,
+        per_second: f64,
+        count: u32,
+    ) -> (Option<f64>, QuotaStatus) {
+        bucket.refill(now, capacity, per_second);
+        if let Some(earn_back) = &self.earn_back {
+            if bucket.roll_windows(now, earn_back) > 0.0 {
+                self.metrics.record_burst_credit_level(bucket.credit);
+            }
+        }
+
+        let from_credit = bucket.credit.min(count as f64);
+        let from_bucket = count as f64 - from_credit;
+        if bucket.tokens < from_bucket {
+            if bucket.credit > 0.0 {
+                self.metrics.record_burst_credit_forfeited(bucket.credit);
+            }
+            bucket.credit = 0.0;
+            bucket.quiet_windows = 0;
+            // Keeps the current window from counting as quiet
+            bucket.window_sent = u32::MAX;
+            return (None, QuotaStatus::of(&*bucket, capacity, per_second));
+        }
+
+        bucket.credit -= from_credit;
+        bucket.tokens -= from_bucket;
+        bucket.window_sent = bucket.window_sent.saturating_add(count);
+        if from_credit > 0.0 {
+            self.metrics.record_burst_credit_consumed(from_credit);
+        }
+        (Some(from_credit), QuotaStatus::of(&*bucket, capacity, per_second))
+    }
+}
+
+#[derive(Serialize)]
+struct QuotaFeedbackMessage<'a> {
+    user_id: &'a str,
+    #[serde(flatten)]
+    quota: &'a QuotaStatus,
+}
+
+/// Early warning to NATS-sourced senders running low on quota
+///
+/// After a successful check on NATS ingress, a sender whose remaining
+/// budget has dropped below `quota_feedback.threshold` of the limit gets
+/// one `QuotaStatus` on `{quota_feedback.subject}.{user_id}`, at most once
+/// per rate-limit window, so well-behaved clients can slow down before they
+/// are rejected. gRPC and REST callers get the same numbers in every
+/// response instead.
+pub struct QuotaFeedback {
+    client: async_nats::Client,
+    config: QuotaFeedbackConfig,
+    window: Duration,
+    /// Sender -> when their last feedback was sent
+    sent: DashMap<String, Instant>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl QuotaFeedback {
+    pub fn new(client: async_nats::Client, config: QuotaFeedbackConfig, limits: &RateLimits, metrics: BrokerMetrics) -> Self {
+        Self {
+            client,
+            config,
+            window: limits.user_message_window.max(Duration::from_secs(1)),
+            sent: DashMap::new(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether `quota` warrants feedback now; marks it sent if so
+    pub fn should_send(&self, user_id: &str, quota: &QuotaStatus) -> bool {
+        if !self.config.enabled || quota.remaining as f64 >= quota.limit as f64 * self.config.threshold {
+            return false;
+        }
+        let now = self.clock.now_instant();
+        let mut due = false;
+        self.sent
+            .entry(user_id.to_string())
+            .and_modify(|last| {
+                if now.duration_since(*last) >= self.window {
+                    *last = now;
+                    due = true;
+                }
+            })
+            .or_insert_with(|| {
+                due = true;
+                now
+            });
+        due
+    }
+
+    pub async fn observe(&self, user_id: &str, quota: &QuotaStatus) {
+        if !self.should_send(user_id, quota) {
+            return;
+        }
+        let message = QuotaFeedbackMessage { user_id, quota };
+        let Ok(payload) = serde_json::to_vec(&message) else {
+            return;
+        };
+        match self
+            .client
+            .publish(format!("{}.{}", self.config.subject, user_id), payload.into())
+            .await
+        {
+            Ok(()) => self.metrics.record_quota_feedback_sent(),
+            Err(e) => warn!("Failed to send quota feedback to {}: {}", user_id, e),
+        }
+    }
+
+    /// Forget senders whose last feedback is older than a window
+    pub fn evict_idle(&self) {
+        let now = self.clock.now_instant();
+        self.sent.retain(|_, last| now.duration_since(*last) < self.window);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("rate limit exceeded for {user_id}")]
+pub struct RateLimited {
+    pub user_id: String,
+    /// Bucket state at the rejection, for feedback headers
+    pub quota: QuotaStatus,
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    /// 10 per minute; quiet at 5 or fewer, credit from the third quiet window, 2 per window up to 5
+    fn limits() -> RateLimits {
+        let mut limits = BrokerConfig::load().unwrap().limits;
+        limits.user_message_limit = 10;
+        limits.user_message_window = WINDOW;
+        limits.burst_credit_quiet_fraction = 0.5;
+        limits.burst_credit_quiet_windows = 3;
+        limits.burst_credit_per_window = 2;
+        limits.burst_credit_max = 5;
+        limits.deferred_queue_size = 0;
+        limits
+    }
+
+    fn limiter(limits: &RateLimits) -> (UserRateLimiter, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let limiter = UserRateLimiter::new(limits, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (limiter, clock)
+    }
+
+    /// Close finished windows without sending, then read the bucket
+    fn credit(limiter: &UserRateLimiter) -> f64 {
+        let _ = limiter.take("alice", 0);
+        limiter.buckets.get("alice").unwrap().credit
+    }
+
+    fn tokens(limiter: &UserRateLimiter) -> f64 {
+        limiter.buckets.get("alice").unwrap().tokens
+    }
+
+    fn send(limiter: &UserRateLimiter, count: u32) {
+        for _ in 0..count {
+            limiter.check("alice").unwrap();
+        }
+    }
+
+    /// Send a little in each of `windows` windows
+    fn stay_quiet(limiter: &UserRateLimiter, clock: &SimClock, windows: u32) {
+        for _ in 0..windows {
+            clock.advance(WINDOW);
+            let _ = limiter.take("alice", 0);
+            send(limiter, 2);
+        }
+    }
+
+    /// Earn the full 5 credit with the bucket back at 10 tokens
+    fn earned(limits: &RateLimits) -> (UserRateLimiter, Arc<SimClock>) {
+        let (limiter, clock) = limiter(limits);
+        send(&limiter, 1);
+        clock.advance(WINDOW * 5);
+        assert_eq!(credit(&limiter), 5.0);
+        assert_eq!(tokens(&limiter), 10.0);
+        (limiter, clock)
+    }
+
+    #[test]
+    fn quiet_windows_accumulate_credit_after_the_streak() {
+        let (limiter, clock) = limiter(&limits());
+        send(&limiter, 2);
+
+        stay_quiet(&limiter, &clock, 1);
+        clock.advance(WINDOW);
+        assert_eq!(credit(&limiter), 0.0);
+
+        clock.advance(WINDOW);
+        assert_eq!(credit(&limiter), 2.0);
+        clock.advance(WINDOW);
+        assert_eq!(credit(&limiter), 4.0);
+    }
+
+    #[test]
+    fn credit_stops_at_the_cap() {
+        let (limiter, clock) = limiter(&limits());
+        send(&limiter, 1);
+
+        clock.advance(WINDOW * 3);
+        assert_eq!(credit(&limiter), 2.0);
+        clock.advance(WINDOW * 20);
+        assert_eq!(credit(&limiter), 5.0);
+    }
+
+    #[test]
+    fn a_busy_window_restarts_the_streak() {
+        let (limiter, clock) = limiter(&limits());
+        stay_quiet(&limiter, &clock, 1);
+        send(&limiter, 4);
+
+        // The busy window doesn't count, so two quiet ones after it aren't enough
+        stay_quiet(&limiter, &clock, 2);
+        clock.advance(WINDOW);
+        assert_eq!(credit(&limiter), 0.0);
+        clock.advance(WINDOW);
+        assert_eq!(credit(&limiter), 2.0);
+    }
+
+    #[test]
+    fn credit_is_spent_before_the_bucket() {
+        let (limiter, _) = earned(&limits());
+
+        send(&limiter, 5);
+        assert_eq!(limiter.buckets.get("alice").unwrap().credit, 0.0);
+        assert_eq!(tokens(&limiter), 10.0);
+
+        send(&limiter, 1);
+        assert_eq!(tokens(&limiter), 9.0);
+    }
+
+    #[test]
+    fn credit_extends_a_burst_past_the_limit() {
+        let (limiter, _) = earned(&limits());
+        assert_eq!(limiter.check("alice").unwrap().remaining, 14);
+
+        send(&limiter, 14);
+        assert!(limiter.check("alice").is_err());
+    }
+
+    #[test]
+    fn being_limited_forfeits_all_credit_and_the_streak() {
+        let (limiter, clock) = earned(&limits());
+
+        // A batch larger than credit and tokens together is refused outright
+        assert!(limiter.reserve(&[("alice".into(), 16)]).is_err());
+        assert_eq!(limiter.buckets.get("alice").unwrap().credit, 0.0);
+        assert_eq!(tokens(&limiter), 10.0);
+
+        // The limited window is never quiet, and the streak starts over after it
+        clock.advance(WINDOW * 3);
+        assert_eq!(credit(&limiter), 0.0);
+        clock.advance(WINDOW);
+        assert_eq!(credit(&limiter), 2.0);
+    }
+
+    #[test]
+    fn released_reservations_hand_credit_back() {
+        let (limiter, _) = earned(&limits());
+
+        let reservation = limiter.reserve(&[("alice".into(), 7)]).unwrap();
+        assert_eq!(limiter.buckets.get("alice").unwrap().credit, 0.0);
+        assert_eq!(tokens(&limiter), 8.0);
+
+        limiter.release(reservation);
+        assert_eq!(limiter.buckets.get("alice").unwrap().credit, 5.0);
+        assert_eq!(tokens(&limiter), 10.0);
+    }
+
+    #[test]
+    fn zero_cap_disables_earn_back() {
+        let mut limits = limits();
+        limits.burst_credit_max = 0;
+        let (limiter, clock) = limiter(&limits);
+        send(&limiter, 1);
+
+        clock.advance(WINDOW * 20);
+        assert_eq!(credit(&limiter), 0.0);
+        assert_eq!(limiter.check("alice").unwrap().remaining, 9);
+    }
+
+    /// 10 per 10s: one token a second, so resets are whole seconds
+    fn quota_limits() -> RateLimits {
+        let mut limits = limits();
+        limits.user_message_window = Duration::from_secs(10);
+        limits
+    }
+
+    fn quota(limit: u32, remaining: u32, reset_seconds: u64) -> QuotaStatus {
+        QuotaStatus {
+            limit,
+            remaining,
+            reset_seconds,
+            retry_after: Duration::ZERO,
+        }
+    }
+
+    /// The header fields only; `retry_after` has tests of its own
+    fn headers_only(status: QuotaStatus) -> QuotaStatus {
+        QuotaStatus { retry_after: Duration::ZERO, ..status }
+    }
+
+    #[test]
+    fn quota_tracks_the_bucket_up_to_and_past_the_limit() {
+        let (limiter, clock) = limiter(&quota_limits());
+        for sent in 1..=10 {
+            assert_eq!(headers_only(limiter.check("alice").unwrap()), quota(10, 10 - sent, sent as u64));
+        }
+
+        let limited = limiter.check("alice").unwrap_err();
+        assert_eq!(headers_only(limited.quota), quota(10, 0, 10));
+
+        // Partial tokens don't count as remaining, but do shorten the reset
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(headers_only(limiter.check("alice").unwrap()), quota(10, 1, 9));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(headers_only(limiter.check("alice").unwrap()), quota(10, 9, 1));
+    }
+
+    #[test]
+    fn reservations_report_every_sender() {
+        let (limiter, _) = limiter(&quota_limits());
+        let reservation = limiter.reserve(&[("alice".into(), 3), ("bob".into(), 8)]).unwrap();
+        assert_eq!(
+            reservation
+                .commit()
+                .into_iter()
+                .map(|(user_id, status)| (user_id, headers_only(status)))
+                .collect::<Vec<_>>(),
+            vec![("alice".to_string(), quota(10, 7, 3)), ("bob".to_string(), quota(10, 2, 8))]
+        );
+
+        let limited = limiter.reserve(&[("alice".into(), 1), ("bob".into(), 3)]).unwrap_err();
+        assert_eq!(limited.user_id, "bob");
+        assert_eq!(headers_only(limited.quota), quota(10, 2, 8));
+        // The failed reservation handed alice's token back
+        assert_eq!(headers_only(limiter.check("alice").unwrap()), quota(10, 6, 4));
+    }
+
+    /// `retry_after` to the millisecond, past float rounding
+    fn retry_ms(status: &QuotaStatus) -> u128 {
+        (status.retry_after.as_secs_f64() * 1000.0).round() as u128
+    }
+
+    #[test]
+    fn retry_after_counts_down_to_the_next_token() {
+        let (limiter, clock) = limiter(&quota_limits());
+        send(&limiter, 8);
+        assert_eq!(limiter.check("alice").unwrap().retry_after, Duration::ZERO, "one token left");
+        assert_eq!(retry_ms(&limiter.check("alice").unwrap()), 1000, "the bucket is empty");
+
+        let limited = limiter.check("alice").unwrap_err();
+        assert_eq!(retry_ms(&limited.quota), 1000);
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(retry_ms(&limiter.check("alice").unwrap_err().quota), 600);
+        clock.advance(Duration::from_millis(600));
+        limiter.check("alice").unwrap();
+    }
+
+    #[test]
+    fn retry_after_waits_behind_deferred_messages_and_counts_credit() {
+        let (limiter, _) = limiter(&deferring_limits());
+        send(&limiter, 10);
+        let waits: Vec<u128> = (0..3)
+            .map(|index| match limiter.check_or_defer(&message(&format!("m{}", index), Priority::High)) {
+                Ok(Admission::Deferred(quota)) => retry_ms(&quota),
+                _ => panic!("m{} wasn't deferred", index),
+            })
+            .collect();
+        assert_eq!(waits, [1000, 2000, 3000]);
+        let Err(overflow) = limiter.check_or_defer(&message("m3", Priority::High)) else {
+            panic!("the deferred queue was full");
+        };
+        assert_eq!(retry_ms(&overflow.quota), 4000);
+
+        // Burst credit is spent before anyone waits: 10 tokens and 5 credit at one per 6s
+        let (limiter, _) = earned(&limits());
+        send(&limiter, 13);
+        assert_eq!(limiter.check("alice").unwrap().retry_after, Duration::ZERO, "one credit left");
+        assert_eq!(retry_ms(&limiter.check("alice").unwrap()), 6000);
+        assert_eq!(retry_ms(&limiter.check("alice").unwrap_err().quota), 6000);
+    }
+
+    #[test]
+    fn a_zero_override_never_has_a_retry_time() {
+        let (limiter, _) = limiter(&quota_limits());
+        limiter.set_override("alice", Some(0.0));
+        assert_eq!(limiter.check("alice").unwrap_err().quota.retry_after, Duration::MAX);
+    }
+
+    #[test]
+    fn quota_becomes_rest_headers_and_grpc_metadata() {
+        let status = quota(10, 4, 6);
+
+        let response = axum::response::IntoResponse::into_response((status, "ok"));
+        let headers = response.headers();
+        assert_eq!(headers.get("X-RateLimit-Limit").unwrap(), "10");
+        assert_eq!(headers.get("X-RateLimit-Remaining").unwrap(), "4");
+        assert_eq!(headers.get("X-RateLimit-Reset").unwrap(), "6");
+
+        let mut metadata = tonic::metadata::MetadataMap::new();
+        status.apply_metadata(&mut metadata);
+        assert_eq!(metadata.get(RATE_LIMIT_LIMIT_HEADER).unwrap(), "10");
+        assert_eq!(metadata.get(RATE_LIMIT_REMAINING_HEADER).unwrap(), "4");
+        assert_eq!(metadata.get(RATE_LIMIT_RESET_HEADER).unwrap(), "6");
+    }
+
+    /// 10 per 10s with up to 3 High priority messages deferred
+    fn deferring_limits() -> RateLimits {
+        let mut limits = quota_limits();
+        limits.deferred_queue_size = 3;
+        limits
+    }
+
+    /// Background quota on its own clock, so tests decide when it has publishes to give
+    fn background_quota() -> (BackgroundQuota, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let config = BrokerConfig::load().unwrap().background_quota;
+        let quota = BackgroundQuota::new(config, 1_000, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        clock.advance(Duration::from_secs(1));
+        (quota, clock)
+    }
+
+    fn message(id: &str, priority: Priority) -> MessageEnvelope {
+        let mut envelope = MessageEnvelope::new(
+            MessageType::SystemMessage,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: "aGk=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        );
+        envelope.message_id = id.into();
+        envelope.priority = priority;
+        envelope
+    }
+
+    fn deferred(limiter: &UserRateLimiter, id: &str) -> bool {
+        matches!(limiter.check_or_defer(&message(id, Priority::High)), Ok(Admission::Deferred(_)))
+    }
+
+    /// IDs and waits of the released messages
+    fn released(limiter: &UserRateLimiter, quota: &BackgroundQuota) -> Vec<(String, String)> {
+        limiter
+            .release_deferred(quota)
+            .into_iter()
+            .map(|envelope| (envelope.message_id, envelope.metadata[DEFERRED_METADATA].clone()))
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|(id, waited)| (id.to_string(), waited.to_string())).collect()
+    }
+
+    #[test]
+    fn deferred_messages_are_released_in_order_as_tokens_return() {
+        let (limiter, clock) = limiter(&deferring_limits());
+        let (quota, _) = background_quota();
+        send(&limiter, 10);
+
+        for id in ["h1", "h2", "h3"] {
+            assert!(deferred(&limiter, id), "{}", id);
+        }
+        assert!(limiter.check_or_defer(&message("n1", Priority::Normal)).is_err());
+        assert!(released(&limiter, &quota).is_empty());
+
+        // The queue has first claim on the refilled token
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.check("alice").is_err());
+        assert_eq!(released(&limiter, &quota), pairs(&[("h1", "1000")]));
+
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(released(&limiter, &quota), pairs(&[("h2", "3500"), ("h3", "3500")]));
+        assert!(released(&limiter, &quota).is_empty());
+
+        // Nothing queued: ordinary traffic takes tokens again
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.check_or_defer(&message("n2", Priority::Normal)).is_ok());
+    }
+
+    #[test]
+    fn a_full_queue_rejects_and_counts_the_overflow() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let (limiter, _) = limiter(&deferring_limits());
+            send(&limiter, 10);
+            for id in ["h1", "h2", "h3"] {
+                assert!(deferred(&limiter, id));
+            }
+            let overflow = limiter.check_or_defer(&message("h4", Priority::High)).unwrap_err();
+            assert_eq!(overflow.user_id, "alice");
+            assert!(limiter.check_or_defer(&message("b1", Priority::Bulk)).is_err());
+            assert_eq!(limiter.buckets.get("alice").unwrap().deferred.len(), 3);
+        });
+
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_rate_limit_deferrals_total{outcome="deferred"} 3"#,
+            r#"broker_rate_limit_deferrals_total{outcome="overflow"} 1"#,
+        ] {
+            assert!(rendered.contains(line), "missing {}\n{}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn releases_wait_for_the_background_quota() {
+        let (limiter, clock) = limiter(&deferring_limits());
+        let (quota, quota_clock) = background_quota();
+        send(&limiter, 10);
+        for id in ["h1", "h2", "h3"] {
+            assert!(deferred(&limiter, id));
+        }
+        clock.advance(Duration::from_secs(10));
+
+        // Spend the deferred class's share
+        while quota.try_acquire(WorkerClass::RateLimitDeferred, 1) {}
+        assert!(released(&limiter, &quota).is_empty());
+        assert_eq!(limiter.buckets.get("alice").unwrap().deferred.len(), 3);
+
+        quota_clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            released(&limiter, &quota),
+            pairs(&[("h1", "10000"), ("h2", "10000"), ("h3", "10000")])
+        );
+    }
+
+    #[test]
+    fn burst_credit_is_spent_before_anything_is_deferred() {
+        let mut limits = deferring_limits();
+        limits.user_message_window = WINDOW;
+        let (limiter, clock) = earned(&limits);
+        let (quota, _) = background_quota();
+
+        // Credit stretches the burst to 15 before anything is deferred
+        for index in 0..15 {
+            let admitted = limiter.check_or_defer(&message(&format!("n{}", index), Priority::Normal));
+            assert!(matches!(admitted, Ok(Admission::Allowed(_))), "{}", index);
+        }
+        assert!(deferred(&limiter, "h1"));
+        assert_eq!(limiter.buckets.get("alice").unwrap().credit, 0.0);
+        assert!(deferred(&limiter, "h2"));
+
+        // Tokens come back at 10 a minute; the first goes to h1
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(released(&limiter, &quota), pairs(&[("h1", "6000")]));
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(released(&limiter, &quota), pairs(&[("h2", "12000")]));
+        assert_eq!(limiter.buckets.get("alice").unwrap().credit, 0.0);
+    }
+
+    #[test]
+    fn buckets_with_deferred_messages_are_never_evicted() {
+        let (limiter, clock) = limiter(&deferring_limits());
+        let (quota, _) = background_quota();
+        send(&limiter, 10);
+        assert!(deferred(&limiter, "h1"));
+        limiter.check("bob").unwrap();
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.evict_idle(Duration::from_secs(30)), 1);
+        assert!(limiter.buckets.contains_key("alice"));
+
+        assert_eq!(released(&limiter, &quota).len(), 1);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.evict_idle(Duration::from_secs(30)), 1);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn a_zero_queue_size_rejects_high_priority_too() {
+        let (limiter, _) = limiter(&quota_limits());
+        send(&limiter, 10);
+        assert!(limiter.check_or_defer(&message("h1", Priority::High)).is_err());
+        assert!(limiter.buckets.get("alice").unwrap().deferred.is_empty());
+    }
+
+    fn nats_url() -> String {
+        std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into())
+    }
+
+    fn feedback_config(enabled: bool) -> QuotaFeedbackConfig {
+        let mut config = BrokerConfig::load().unwrap().quota_feedback;
+        config.enabled = enabled;
+        config.threshold = 0.2;
+        config
+    }
+
+    /// Deciding never touches NATS, so the client is never connected
+    async fn feedback(enabled: bool, clock: &Arc<SimClock>) -> QuotaFeedback {
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(nats_url())
+            .await
+            .unwrap();
+        QuotaFeedback::new(client, feedback_config(enabled), &quota_limits(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone())
+    }
+
+    /// Sends `count` messages, returning how many triggered feedback
+    fn feedback_for(limiter: &UserRateLimiter, feedback: &QuotaFeedback, count: u32) -> usize {
+        (0..count)
+            .filter(|_| feedback.should_send("alice", &limiter.check("alice").unwrap()))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn feedback_fires_once_per_window_below_the_threshold() {
+        let (limiter, clock) = limiter(&quota_limits());
+        let feedback = feedback(true, &clock).await;
+
+        // Below 2 of 10 remaining only from the ninth send
+        assert_eq!(feedback_for(&limiter, &feedback, 8), 0);
+        assert_eq!(feedback_for(&limiter, &feedback, 2), 1);
+
+        // Low again in the same window: no repeat
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(feedback_for(&limiter, &feedback, 4), 0);
+
+        // A window after the first warning it fires again, once
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(feedback_for(&limiter, &feedback, 5), 1);
+
+        // Other senders are tracked separately
+        let bob = quota(10, 0, 10);
+        assert!(feedback.should_send("bob", &bob));
+        assert!(!feedback.should_send("bob", &bob));
+
+        clock.advance(Duration::from_secs(10));
+        feedback.evict_idle();
+        assert!(feedback.sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_feedback_never_fires() {
+        let (limiter, clock) = limiter(&quota_limits());
+        let feedback = feedback(false, &clock).await;
+        assert_eq!(feedback_for(&limiter, &feedback, 10), 0);
+    }
+
+    /// Runs against NATS at `NATS_URL` (default `localhost:4222`):
+    /// `cargo test -- --ignored rate_limit`
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn feedback_is_published_to_the_sender() {
+        use tokio_stream::StreamExt;
+
+        let client = async_nats::connect(nats_url()).await.unwrap();
+        let mut config = feedback_config(true);
+        config.subject = format!("quota-test-{}", uuid::Uuid::new_v4().simple());
+        let mut messages = client.subscribe(format!("{}.alice", config.subject)).await.unwrap();
+        client.flush().await.unwrap();
+
+        let (limiter, clock) = limiter(&quota_limits());
+        let feedback = QuotaFeedback::new(client, config, &quota_limits(), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone());
+        for _ in 0..10 {
+            feedback.observe("alice", &limiter.check("alice").unwrap()).await;
+        }
+
+        let message = tokio::time::timeout(Duration::from_secs(2), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&message.payload).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({ "user_id": "alice", "limit": 10, "remaining": 1, "reset_seconds": 9 })
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(200), messages.next()).await.is_err());
+    }
+}
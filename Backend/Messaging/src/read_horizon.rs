@@ -0,0 +1,375 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::{DashMap, SharedValue};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    api::{
+        proto::{DeliveryFrame, ReadHorizon},
+        subscriptions::SubscriptionRegistry,
+    },
+    clock::{SharedClock, SystemClock},
+    message::types::{MessageEnvelope, MessageType},
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Compact multi-device sync event pushed to the user's open streams
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadHorizonChanged {
+    pub user_id: String,
+    pub conversation_id: String,
+    pub sequence: u64,
+}
+
+/// CAS attempts per flush before the horizon is requeued
+const MAX_CAS_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+struct PendingHorizon {
+    sequence: u64,
+}
+
+/// `(user_id, conversation_id)`
+type HorizonKey = (String, String);
+
+/// Last read sequence per user per conversation, backed by NATS KV
+///
+/// Entries live under `readhorizon.{user}.{conversation}` with the
+/// conversation ID base64url-encoded, since DM conversation IDs contain `:`.
+/// Receipts are coalesced per (user, conversation) for `coalesce_window` and
+/// flushed as compare-and-set writes that never move a horizon backwards.
+pub struct ReadHorizonStore {
+    kv: kv::Store,
+    pending: DashMap<HorizonKey, PendingHorizon>,
+    subscriptions: Arc<SubscriptionRegistry>,
+    coalesce_window: Duration,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ReadHorizonStore {
+    pub fn new(
+        kv: kv::Store,
+        subscriptions: Arc<SubscriptionRegistry>,
+        coalesce_window: Duration,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            kv,
+            pending: DashMap::new(),
+            subscriptions,
+            coalesce_window,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Queue a read receipt's sequence; non-receipts are ignored
+    pub fn record_receipt(&self, envelope: &MessageEnvelope) {
+        if envelope.message_type != MessageType::Read {
+            return;
+        }
+        let Some(sequence) = envelope.sequence else {
+            return;
+        };
+        self.record(&envelope.from, &envelope.conversation_id(), sequence);
+    }
+
+    pub fn record(&self, user_id: &str, conversation_id: &str, sequence: u64) {
+        let mut entry = self
+            .pending
+            .entry((user_id.to_string(), conversation_id.to_string()))
+            .or_insert(PendingHorizon { sequence });
+        if sequence > entry.sequence {
+            entry.sequence = sequence;
+        }
+        self.metrics.record_read_horizon_update("queued");
+    }
+
+    /// Queue many `(user_id, conversation_id, sequence)` receipts, taking each
+    /// map shard's lock once
+    pub fn record_batch<'a>(&self, receipts: impl IntoIterator<Item = (&'a str, &'a str, u64)>) {
+        let mut by_shard: HashMap<usize, Vec<(HorizonKey, u64)>> = HashMap::new();
+        for (user_id, conversation_id, sequence) in receipts {
+            let key = (user_id.to_string(), conversation_id.to_string());
+            by_shard
+                .entry(self.pending.determine_map(&key))
+                .or_default()
+                .push((key, sequence));
+        }
+
+        for (shard, receipts) in by_shard {
+            let mut shard = self.pending.shards()[shard].write();
+            for (key, sequence) in receipts {
+                let entry = shard
+                    .entry(key)
+                    .or_insert_with(|| SharedValue::new(PendingHorizon { sequence }))
+                    .get_mut();
+                if sequence > entry.sequence {
+                    entry.sequence = sequence;
+                }
+                self.metrics.record_read_horizon_update("queued");
+            }
+        }
+    }
+
+    /// Horizons for several conversations; pending receipts are included
+    pub async fn get(
+        &self,
+        user_id: &str,
+        conversation_ids: &[String],
+    ) -> Result<HashMap<String, u64>, ReadHorizonError> {
+        let mut horizons = HashMap::with_capacity(conversation_ids.len());
+        for conversation_id in conversation_ids {
+            let stored = self
+                .kv
+                .get(horizon_key(user_id, conversation_id))
+                .await
+                .map_err(|e| ReadHorizonError(e.to_string()))?
+                .and_then(|bytes| parse_sequence(&bytes));
+            let pending = self
+                .pending
+                .get(&(user_id.to_string(), conversation_id.clone()))
+                .map(|p| p.sequence);
+
+            if let Some(sequence) = stored.max(pending) {
+                horizons.insert(conversation_id.clone(), sequence);
+            }
+        }
+        Ok(horizons)
+    }
+
+    pub fn spawn_flusher(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let store = Arc::clone(self);
+        spawn_traced("read_horizon_flusher", TaskContext::new("read_horizon"), async move {
+            loop {
+                store.clock.sleep(store.coalesce_window).await;
+                store.flush().await;
+            }
+        })
+    }
+
+    /// Write every coalesced horizon and notify the user's devices
+    pub async fn flush(&self) {
+        let keys: Vec<HorizonKey> = self.pending.iter().map(|e| e.key().clone()).collect();
+
+        for key in keys {
+            let Some((_, pending)) = self.pending.remove(&key) else {
+                continue;
+            };
+            let (user_id, conversation_id) = key;
+
+            match self.advance(&user_id, &conversation_id, pending.sequence).await {
+                Ok(true) => {
+                    self.metrics.record_read_horizon_update("written");
+                    self.notify(ReadHorizonChanged {
+                        user_id,
+                        conversation_id,
+                        sequence: pending.sequence,
+                    });
+                }
+                Ok(false) => self.metrics.record_read_horizon_update("stale"),
+                Err(e) => {
+                    warn!("Failed to write read horizon for {}: {}", user_id, e);
+                    // Requeue without clobbering a newer receipt
+                    self.record(&user_id, &conversation_id, pending.sequence);
+                }
+            }
+        }
+    }
+
+    /// Compare-and-set the stored horizon; false if it was already at or past `sequence`
+    async fn advance(&self, user_id: &str, conversation_id: &str, sequence: u64) -> Result<bool, ReadHorizonError> {
+        let key = horizon_key(user_id, conversation_id);
+        let value = sequence.to_string();
+
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let entry = self
+                .kv
+                .entry(&key)
+                .await
+                .map_err(|e| ReadHorizonError(e.to_string()))?;
+
+            let written = match entry {
+                Some(entry) if entry.operation == kv::Operation::Put => {
+                    if parse_sequence(&entry.value).is_some_and(|current| current >= sequence) {
+                        return Ok(false);
+                    }
+                    self.kv
+                        .update(&key, value.clone().into(), entry.revision)
+                        .await
+                        .is_ok()
+                }
+                _ => self.kv.create(&key, value.clone().into()).await.is_ok(),
+            };
+
+            if written {
+                return Ok(true);
+            }
+            // Lost a race with another broker; re-read and compare again
+            debug!("Read horizon CAS conflict on {}", key);
+        }
+
+        Err(ReadHorizonError(format!("gave up on {} after {} CAS attempts", key, MAX_CAS_ATTEMPTS)))
+    }
+
+    fn notify(&self, event: ReadHorizonChanged) {
+        let frame = DeliveryFrame {
+            message_id: format!("readhorizon:{}:{}", event.conversation_id, event.sequence),
+            from: event.user_id.clone(),
+            envelope: Vec::new(),
+            timestamp: self.clock.now_millis(),
+            read_horizon: Some(ReadHorizon {
+                conversation_id: event.conversation_id,
+                sequence: event.sequence,
+            }),
+            digest: None,
+            keepalive: None,
+        };
+        self.subscriptions.deliver(&event.user_id, &frame);
+    }
+}
+
+fn horizon_key(user_id: &str, conversation_id: &str) -> String {
+    format!("readhorizon.{}.{}", user_id, URL_SAFE_NO_PAD.encode(conversation_id))
+}
+
+/// `(user_id, conversation_id)` of a read horizon KV key
+pub fn parse_horizon_key(key: &str) -> Option<(String, String)> {
+    let (user_id, conversation) = key.strip_prefix("readhorizon.")?.rsplit_once('.')?;
+    let conversation_id = String::from_utf8(URL_SAFE_NO_PAD.decode(conversation).ok()?).ok()?;
+    Some((user_id.to_string(), conversation_id))
+}
+
+pub fn parse_sequence(bytes: &[u8]) -> Option<u64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("read horizon store error: {0}")]
+pub struct ReadHorizonError(pub String);
+
+/// The store tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored read_horizon`
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{api::subscriptions::FrameStream, config::BrokerConfig};
+
+    const CONVERSATION: &str = "dm:alice:bob";
+
+    #[test]
+    fn keys_round_trip_conversation_ids_with_separators() {
+        let key = horizon_key("alice", CONVERSATION);
+        assert!(!key.contains(':'));
+        assert_eq!(parse_horizon_key(&key), Some(("alice".into(), CONVERSATION.into())));
+        assert_eq!(parse_horizon_key("presence.alice"), None);
+        assert_eq!(parse_sequence(b"42"), Some(42));
+        assert_eq!(parse_sequence(b"-1"), None);
+    }
+
+    async fn store() -> (ReadHorizonStore, Arc<SubscriptionRegistry>) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let kv = jetstream::new(async_nats::connect(url).await.unwrap())
+            .create_key_value(kv::Config {
+                bucket: format!("readhorizon-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let subscriptions = Arc::new(SubscriptionRegistry::new(&BrokerConfig::load().unwrap().api, metrics.clone()));
+        let store = ReadHorizonStore::new(kv, subscriptions.clone(), Duration::from_millis(250), metrics);
+        (store, subscriptions)
+    }
+
+    async fn stored(store: &ReadHorizonStore) -> Option<u64> {
+        let bytes = store.kv.get(horizon_key("alice", CONVERSATION)).await.unwrap()?;
+        parse_sequence(&bytes)
+    }
+
+    /// Every write to the bucket, including overwrites
+    async fn writes(store: &ReadHorizonStore) -> u64 {
+        store.kv.status().await.unwrap().info.state.last_sequence
+    }
+
+    async fn next_horizon(stream: &mut FrameStream) -> Option<ReadHorizon> {
+        let frame = tokio::time::timeout(Duration::from_millis(100), stream.next()).await.ok()??;
+        frame.unwrap().read_horizon
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn out_of_order_receipts_never_move_the_horizon_back() {
+        let (store, _) = store().await;
+
+        store.record_batch([("alice", CONVERSATION, 7), ("alice", CONVERSATION, 12), ("alice", CONVERSATION, 9)]);
+        store.flush().await;
+        assert_eq!(stored(&store).await, Some(12));
+
+        // A late receipt from another device
+        store.record("alice", CONVERSATION, 5);
+        store.flush().await;
+        assert_eq!(stored(&store).await, Some(12));
+
+        store.record("alice", CONVERSATION, 20);
+        store.flush().await;
+        assert_eq!(stored(&store).await, Some(20));
+        assert_eq!(writes(&store).await, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn the_users_other_devices_get_a_sync_event() {
+        let (store, subscriptions) = store().await;
+        let (_, mut phone) = subscriptions.open("alice".into(), "gw-1".into());
+        let (_, mut laptop) = subscriptions.open("alice".into(), "gw-2".into());
+        let (_, mut bob) = subscriptions.open("bob".into(), "gw-1".into());
+
+        store.record("alice", CONVERSATION, 12);
+        store.flush().await;
+
+        let expected = ReadHorizon {
+            conversation_id: CONVERSATION.into(),
+            sequence: 12,
+        };
+        assert_eq!(next_horizon(&mut phone).await, Some(expected.clone()));
+        assert_eq!(next_horizon(&mut laptop).await, Some(expected));
+        assert_eq!(next_horizon(&mut bob).await, None);
+
+        // A stale receipt writes nothing and tells nobody
+        store.record("alice", CONVERSATION, 3);
+        store.flush().await;
+        assert_eq!(next_horizon(&mut phone).await, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn rapid_scrolling_coalesces_into_one_write() {
+        let (store, _) = store().await;
+        for sequence in 1..=100 {
+            store.record("alice", CONVERSATION, sequence);
+        }
+
+        // Pending receipts are visible before the window closes
+        let horizons = store.get("alice", &[CONVERSATION.to_string(), "group:x".into()]).await.unwrap();
+        assert_eq!(horizons, HashMap::from([(CONVERSATION.to_string(), 100)]));
+        assert_eq!(writes(&store).await, 0);
+
+        store.flush().await;
+        assert_eq!(writes(&store).await, 1);
+        assert_eq!(stored(&store).await, Some(100));
+    }
+}
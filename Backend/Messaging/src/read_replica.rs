@@ -0,0 +1,187 @@
+use std::time::{Duration, Instant};
+use async_nats::jetstream;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::{config::NatsConfig, metrics::BrokerMetrics};
+
+/// Read-only operations that may be served by the mirror stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOperation {
+    FetchHistory,
+    GapRepair,
+    DlqStats,
+}
+
+impl ReadOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadOperation::FetchHistory => "fetch_history",
+            ReadOperation::GapRepair => "gap_repair",
+            ReadOperation::DlqStats => "dlq_stats",
+        }
+    }
+}
+
+/// Which stream served a read and how far behind it may be
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadFreshness {
+    pub stream: String,
+    pub from_mirror: bool,
+    /// Sequences the mirror trailed the primary by when last measured
+    pub mirror_lag: u64,
+}
+
+struct LagSample {
+    lag: u64,
+    measured_at: Instant,
+}
+
+/// Directs read-only operations at the mirror stream when it is caught up
+///
+/// Mirror lag is the difference between the primary's and the mirror's last
+/// sequence, sampled at most once per `lag_check_interval`. Reads fall back
+/// to the primary once lag exceeds `mirror_max_lag`.
+pub struct ReadStreamSelector {
+    jetstream: jetstream::Context,
+    primary: String,
+    mirror: Option<String>,
+    max_lag: u64,
+    lag_check_interval: Duration,
+    sample: Mutex<Option<LagSample>>,
+    metrics: BrokerMetrics,
+}
+
+impl ReadStreamSelector {
+    pub fn new(jetstream: jetstream::Context, config: &NatsConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            jetstream,
+            primary: config.stream_name.clone(),
+            mirror: config.read_stream_name.clone(),
+            max_lag: config.mirror_max_lag,
+            lag_check_interval: config.mirror_lag_check_interval,
+            sample: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    pub fn jetstream(&self) -> &jetstream::Context {
+        &self.jetstream
+    }
+
+    /// Pick the stream for a read
+    pub async fn select(&self, operation: ReadOperation) -> ReadFreshness {
+        let lag = match &self.mirror {
+            Some(mirror) => match self.mirror_lag(mirror).await {
+                Ok(lag) => Some(lag),
+                Err(e) => {
+                    warn!("Failed to measure mirror lag for {}: {}", mirror, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let freshness = route(&self.primary, self.mirror.as_deref(), self.max_lag, lag);
+        if let (Some(lag), false) = (lag, freshness.from_mirror) {
+            debug!("Mirror lags {} sequences, reading {} from primary", lag, operation.as_str());
+        }
+        let served_by = if freshness.from_mirror { "mirror" } else { "primary" };
+        self.metrics.record_stream_read(operation.as_str(), served_by);
+        freshness
+    }
+
+    async fn mirror_lag(&self, mirror: &str) -> Result<u64, async_nats::Error> {
+        if let Some(sample) = &*self.sample.lock() {
+            if sample.measured_at.elapsed() < self.lag_check_interval {
+                return Ok(sample.lag);
+            }
+        }
+
+        let primary_last = self
+            .jetstream
+            .get_stream(&self.primary)
+            .await?
+            .info()
+            .await?
+            .state
+            .last_sequence;
+        let mirror_last = self
+            .jetstream
+            .get_stream(mirror)
+            .await?
+            .info()
+            .await?
+            .state
+            .last_sequence;
+
+        let lag = primary_last.saturating_sub(mirror_last);
+        self.metrics.update_mirror_lag(lag);
+        *self.sample.lock() = Some(LagSample {
+            lag,
+            measured_at: Instant::now(),
+        });
+        Ok(lag)
+    }
+}
+
+/// The mirror when its lag is known and within `max_lag`, otherwise the primary
+fn route(primary: &str, mirror: Option<&str>, max_lag: u64, lag: Option<u64>) -> ReadFreshness {
+    match (mirror, lag) {
+        (Some(mirror), Some(lag)) if lag <= max_lag => ReadFreshness {
+            stream: mirror.to_string(),
+            from_mirror: true,
+            mirror_lag: lag,
+        },
+        _ => ReadFreshness {
+            stream: primary.to_string(),
+            from_mirror: false,
+            mirror_lag: lag.unwrap_or(0),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caught_up_mirrors_serve_reads() {
+        let freshness = route("messages", Some("messages-mirror"), 1000, Some(12));
+        assert!(freshness.from_mirror);
+        assert_eq!(freshness.stream, "messages-mirror");
+        assert_eq!(freshness.mirror_lag, 12);
+
+        assert!(route("messages", Some("messages-mirror"), 1000, Some(1000)).from_mirror);
+    }
+
+    #[test]
+    fn lagging_mirrors_fall_back_to_the_primary() {
+        let freshness = route("messages", Some("messages-mirror"), 1000, Some(1001));
+        assert!(!freshness.from_mirror);
+        assert_eq!(freshness.stream, "messages");
+        // The response still tells the caller how stale the mirror was
+        assert_eq!(freshness.mirror_lag, 1001);
+    }
+
+    #[test]
+    fn unmeasured_or_missing_mirrors_read_the_primary() {
+        let unmeasured = route("messages", Some("messages-mirror"), 1000, None);
+        assert!(!unmeasured.from_mirror);
+        assert_eq!(unmeasured.mirror_lag, 0);
+
+        let unconfigured = route("messages", None, 1000, None);
+        assert_eq!(unconfigured.stream, "messages");
+        assert!(!unconfigured.from_mirror);
+    }
+
+    #[test]
+    fn freshness_serializes_for_responses() {
+        let freshness = route("messages", Some("messages-mirror"), 1000, Some(3));
+        assert_eq!(
+            serde_json::to_value(&freshness).unwrap(),
+            serde_json::json!({ "stream": "messages-mirror", "from_mirror": true, "mirror_lag": 3 })
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::sync::Arc;
+use arc_swap::ArcSwapOption;
+use async_nats::HeaderMap;
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, warn};
+
+use crate::{
+    api::proto::{BulkReceiptFrame, ReceiptStatus},
+    config::ReceiptConfig,
+    degradation::DegradationSwitchboard,
+    delivery::DeliveryTracker,
+    e2e_latency::{priority_label, DeliveryTiming, E2eLatency},
+    envelope_guard::EnvelopeGuard,
+    message::types::MessageType,
+    metrics::BrokerMetrics,
+    read_horizon::ReadHorizonStore,
+    subject_match::{subscription_subject, SubjectClass, SubjectRouter},
+    task::{spawn_traced, TaskContext},
+    user_events::{RecentUserEvents, UserEventKind},
+};
+
+/// Header selecting the receipt encoding; absent means one JSON envelope
+pub const RECEIPT_FORMAT_HEADER: &str = "Receipt-Format";
+pub const BULK_FORMAT: &str = "bulk-v1";
+pub const SINGLE_FORMAT: &str = "single";
+
+/// Reply to a gateway's capability request on `receipts.capabilities_subject`
+///
+/// Gateways keep sending single receipts until a broker answers with
+/// `bulk-v1` in `formats`, so mixed-version fleets stay on the legacy path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptCapabilities {
+    pub formats: Vec<String>,
+    /// Largest bulk frame the broker accepts
+    pub max_entries: usize,
+    /// How long gateways should batch before publishing
+    pub flush_window_ms: u64,
+}
+
+/// Applies delivery and read receipts from gateways
+///
+/// Accepts the legacy format (one JSON `MessageEnvelope` per receipt,
+/// acking `in_reply_to`) and `BulkReceiptFrame`s. A bulk frame is applied
+/// as one batch: delivery acks and read horizons each take every touched
+/// map shard's lock once. `receipts.subject` may capture `{gateway_id}`
+/// (`broker.receipts.{gateway_id}`); bulk frames must then name the
+/// gateway they were published under.
+pub struct ReceiptConsumer {
+    tracker: Arc<DeliveryTracker>,
+    horizons: Arc<ReadHorizonStore>,
+    switchboard: Arc<DegradationSwitchboard>,
+    guard: Arc<EnvelopeGuard>,
+    e2e: E2eLatency,
+    subjects: Arc<SubjectRouter>,
+    /// Told about read receipts once attached; delivery acks reach it through the tracker
+    recent_events: ArcSwapOption<RecentUserEvents>,
+    config: ReceiptConfig,
+    metrics: BrokerMetrics,
+}
+
+impl ReceiptConsumer {
+    pub fn new(
+        tracker: Arc<DeliveryTracker>,
+        horizons: Arc<ReadHorizonStore>,
+        switchboard: Arc<DegradationSwitchboard>,
+        guard: Arc<EnvelopeGuard>,
+        subjects: Arc<SubjectRouter>,
+        config: ReceiptConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            tracker,
+            horizons,
+            switchboard,
+            guard,
+            e2e: E2eLatency::new(&config, metrics.clone()),
+            subjects,
+            recent_events: ArcSwapOption::empty(),
+            config,
+            metrics,
+        }
+    }
+
+    pub fn attach_recent_events(&self, events: Arc<RecentUserEvents>) {
+        self.recent_events.store(Some(events));
+    }
+
+    pub fn capabilities(&self) -> ReceiptCapabilities {
+        ReceiptCapabilities {
+            formats: vec![SINGLE_FORMAT.to_string(), BULK_FORMAT.to_string()],
+            max_entries: self.config.max_entries_per_frame,
+            flush_window_ms: self.config.gateway_flush_window_ms,
+        }
+    }
+
+    /// Apply one receipt publish, returning the number of receipts in it
+    pub fn handle(&self, subject: &str, headers: Option<&HeaderMap>, payload: &[u8]) -> Result<usize, ReceiptError> {
+        // Receipts bypass ingress, so the degradation toggle is applied here too
+        if !self.switchboard.receipts_enabled() {
+            self.metrics.record_message_dropped("degraded_receipts");
+            return Ok(0);
+        }
+        let subjects = self.subjects.load();
+        let routed = subjects.classify(subject).filter(|routed| routed.class() == SubjectClass::Receipt);
+        let Some(routed) = routed else {
+            self.metrics.record_subject_unmatched(SubjectClass::Receipt.as_str());
+            return Err(ReceiptError::Unrouted(subject.to_string()));
+        };
+        let gateway_id = routed.capture("gateway_id");
+        self.guard
+            .check_headers(headers)
+            .map_err(|e| ReceiptError::Decode(e.to_string()))?;
+
+        let format = headers
+            .and_then(|headers| headers.get(RECEIPT_FORMAT_HEADER))
+            .map_or(SINGLE_FORMAT, |value| value.as_str());
+
+        match format {
+            BULK_FORMAT => self.apply_bulk(gateway_id, payload),
+            SINGLE_FORMAT => self.apply_single(payload),
+            other => {
+                self.metrics.record_receipt_decode_error("unknown_format");
+                Err(ReceiptError::UnknownFormat(other.to_string()))
+            }
+        }
+    }
+
+    fn apply_single(&self, payload: &[u8]) -> Result<usize, ReceiptError> {
+        let envelope = self.guard.parse(payload).map_err(|e| {
+            self.metrics.record_receipt_decode_error(SINGLE_FORMAT);
+            ReceiptError::Decode(e.to_string())
+        })?;
+        if !envelope.is_receipt() {
+            return Err(ReceiptError::NotAReceipt(envelope.message_id));
+        }
+
+        if let Some(acked) = &envelope.in_reply_to {
+            self.tracker.ack(acked, &envelope.from);
+        }
+        if envelope.message_type == MessageType::Delivered {
+            match DeliveryTiming::from_receipt(&envelope) {
+                Some(timing) => self.e2e.record(&timing),
+                None => self.metrics.record_e2e_latency_skipped("no_gateway"),
+            }
+        }
+        self.horizons.record_receipt(&envelope);
+        if envelope.message_type == MessageType::Read {
+            if let Some(events) = &*self.recent_events.load() {
+                events.record(
+                    &envelope.from,
+                    UserEventKind::Receipt,
+                    Some(&envelope.conversation_id()),
+                    envelope.in_reply_to.as_deref(),
+                );
+            }
+        }
+        self.metrics.record_receipts_per_frame(SINGLE_FORMAT, 1);
+        Ok(1)
+    }
+
+    fn apply_bulk(&self, gateway_id: Option<&str>, payload: &[u8]) -> Result<usize, ReceiptError> {
+        let frame = BulkReceiptFrame::decode(payload).map_err(|e| {
+            self.metrics.record_receipt_decode_error(BULK_FORMAT);
+            ReceiptError::Decode(e.to_string())
+        })?;
+        if let Some(gateway_id) = gateway_id.filter(|&gateway_id| gateway_id != frame.gateway_id) {
+            self.metrics.record_receipt_decode_error("gateway_mismatch");
+            return Err(ReceiptError::GatewayMismatch(frame.gateway_id, gateway_id.to_string()));
+        }
+        if frame.receipts.len() > self.config.max_entries_per_frame {
+            self.metrics.record_receipt_decode_error("oversized");
+            return Err(ReceiptError::Oversized(frame.receipts.len()));
+        }
+
+        self.e2e.observe_frame(&frame.gateway_id, frame.sent_at);
+        for entry in frame.receipts.iter().filter(|entry| entry.status() == ReceiptStatus::Delivered) {
+            self.e2e.record(&DeliveryTiming {
+                gateway_id: &frame.gateway_id,
+                delivered_at: entry.timestamp,
+                ingested_at: entry.ingested_at,
+                path: entry.delivery_path(),
+                priority: priority_label(&entry.priority),
+            });
+        }
+
+        // Read implies delivered, so every entry acks
+        let acked = self.tracker.ack_batch(
+            frame
+                .receipts
+                .iter()
+                .map(|entry| (entry.message_id.as_str(), entry.recipient.as_str())),
+        );
+        self.horizons.record_batch(
+            frame
+                .receipts
+                .iter()
+                .filter(|entry| entry.status() == ReceiptStatus::Read && !entry.conversation_id.is_empty())
+                .map(|entry| (entry.recipient.as_str(), entry.conversation_id.as_str(), entry.sequence)),
+        );
+        if let Some(events) = &*self.recent_events.load() {
+            for entry in frame.receipts.iter().filter(|entry| entry.status() == ReceiptStatus::Read) {
+                events.record(
+                    &entry.recipient,
+                    UserEventKind::Receipt,
+                    Some(entry.conversation_id.as_str()).filter(|id| !id.is_empty()),
+                    Some(&entry.message_id),
+                );
+            }
+        }
+
+        debug!(
+            "Applied {} receipts from {} ({} tracked)",
+            frame.receipts.len(),
+            frame.gateway_id,
+            acked
+        );
+        self.metrics.record_receipts_per_frame(BULK_FORMAT, frame.receipts.len());
+        Ok(frame.receipts.len())
+    }
+
+    /// Consume the receipt subject and answer capability requests
+    pub fn spawn(self: &Arc<Self>, client: async_nats::Client) {
+        let consumer = Arc::clone(self);
+        let nats = client.clone();
+        spawn_traced("receipt_consumer", TaskContext::new("receipts"), async move {
+            let mut messages = match nats
+                .queue_subscribe(subscription_subject(&consumer.config.subject), consumer.config.queue_group.clone())
+                .await
+            {
+                Ok(messages) => messages,
+                Err(e) => {
+                    warn!("Failed to subscribe to receipts: {}", e);
+                    return;
+                }
+            };
+            while let Some(message) = messages.next().await {
+                if let Err(e) = consumer.handle(&message.subject, message.headers.as_ref(), &message.payload) {
+                    warn!("Dropped receipt publish: {}", e);
+                }
+            }
+        });
+
+        let consumer = Arc::clone(self);
+        spawn_traced("receipt_capabilities", TaskContext::new("receipts"), async move {
+            let mut requests = match client.subscribe(consumer.config.capabilities_subject.clone()).await {
+                Ok(requests) => requests,
+                Err(e) => {
+                    warn!("Failed to subscribe to receipt capability requests: {}", e);
+                    return;
+                }
+            };
+            let Ok(reply) = serde_json::to_vec(&consumer.capabilities()) else {
+                return;
+            };
+            while let Some(request) = requests.next().await {
+                let Some(inbox) = request.reply else {
+                    continue;
+                };
+                if let Err(e) = client.publish(inbox, reply.clone().into()).await {
+                    debug!("Failed to answer receipt capability request: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReceiptError {
+    #[error("unknown receipt format: {0}")]
+    UnknownFormat(String),
+    #[error("malformed receipt: {0}")]
+    Decode(String),
+    #[error("{0} is not a receipt")]
+    NotAReceipt(String),
+    #[error("bulk receipt frame with {0} entries exceeds the limit")]
+    Oversized(usize),
+    #[error("receipt published on {0}, which isn't a receipt subject")]
+    Unrouted(String),
+    #[error("bulk receipt frame from {0} published under gateway {1}")]
+    GatewayMismatch(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::proto::{DeliveryPath, ReceiptEntry},
+        message::types::{EncryptedPayload, MessageEnvelope},
+    };
+
+    fn entry(i: usize, status: ReceiptStatus) -> ReceiptEntry {
+        let mut entry = ReceiptEntry {
+            message_id: format!("msg-{}", i),
+            recipient: format!("user-{}", i % 7),
+            timestamp: 1_700_000_000_000 + i as i64,
+            conversation_id: if status == ReceiptStatus::Read { "group_team".into() } else { String::new() },
+            sequence: i as u64,
+            ingested_at: 1_699_999_999_000,
+            priority: "normal".into(),
+            ..Default::default()
+        };
+        entry.set_status(status);
+        entry.set_delivery_path(DeliveryPath::OfflineReplay);
+        entry
+    }
+
+    fn frame(entries: usize) -> BulkReceiptFrame {
+        BulkReceiptFrame {
+            gateway_id: "gw-1".into(),
+            receipts: (0..entries)
+                .map(|i| entry(i, if i % 3 == 0 { ReceiptStatus::Read } else { ReceiptStatus::Delivered }))
+                .collect(),
+            sent_at: 1_700_000_000_500,
+        }
+    }
+
+    #[test]
+    fn bulk_frames_round_trip() {
+        for entries in [0, 1, 500] {
+            let frame = frame(entries);
+            let decoded = BulkReceiptFrame::decode(frame.encode_to_vec().as_slice()).unwrap();
+            assert_eq!(decoded, frame);
+        }
+
+        let decoded = BulkReceiptFrame::decode(frame(3).encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded.receipts[0].status(), ReceiptStatus::Read);
+        assert_eq!(decoded.receipts[1].status(), ReceiptStatus::Delivered);
+        assert_eq!(decoded.receipts[1].delivery_path(), DeliveryPath::OfflineReplay);
+    }
+
+    #[test]
+    fn frames_from_older_gateways_decode_with_defaults() {
+        // Only the fields the first bulk-v1 gateways sent
+        let mut old = ReceiptEntry {
+            message_id: "msg-1".into(),
+            recipient: "bob".into(),
+            timestamp: 1,
+            ..Default::default()
+        };
+        old.set_status(ReceiptStatus::Delivered);
+        let frame = BulkReceiptFrame {
+            gateway_id: "gw-1".into(),
+            receipts: vec![old],
+            sent_at: 0,
+        };
+
+        let decoded = BulkReceiptFrame::decode(frame.encode_to_vec().as_slice()).unwrap();
+        let entry = &decoded.receipts[0];
+        assert_eq!(entry.ingested_at, 0);
+        assert_eq!(entry.delivery_path(), DeliveryPath::Unspecified);
+        assert!(entry.priority.is_empty() && entry.conversation_id.is_empty());
+    }
+
+    #[test]
+    fn truncated_frames_fail_to_decode() {
+        let bytes = frame(10).encode_to_vec();
+        assert!(BulkReceiptFrame::decode(&bytes[..bytes.len() - 3]).is_err());
+        assert!(BulkReceiptFrame::decode(&[0xff, 0xff, 0xff][..]).is_err());
+    }
+
+    #[test]
+    fn a_bulk_frame_is_far_smaller_than_single_receipts() {
+        let frame = frame(100);
+        let singles: usize = frame
+            .receipts
+            .iter()
+            .map(|entry| {
+                let mut receipt = MessageEnvelope::new(
+                    MessageType::Delivered,
+                    entry.recipient.clone(),
+                    vec!["alice".into()],
+                    EncryptedPayload {
+                        ciphertext: String::new(),
+                        iv: None,
+                        tag: None,
+                        key_id: None,
+                        content_type: None,
+                    },
+                );
+                receipt.in_reply_to = Some(entry.message_id.clone());
+                serde_json::to_vec(&receipt).unwrap().len()
+            })
+            .sum();
+        assert!(frame.encode_to_vec().len() * 3 < singles);
+    }
+
+    #[test]
+    fn capabilities_advertise_both_formats() {
+        let capabilities = ReceiptCapabilities {
+            formats: vec![SINGLE_FORMAT.into(), BULK_FORMAT.into()],
+            max_entries: 1000,
+            flush_window_ms: 50,
+        };
+        let json: serde_json::Value = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(json["formats"], serde_json::json!(["single", "bulk-v1"]));
+
+        let parsed: ReceiptCapabilities = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.max_entries, 1000);
+        assert_eq!(parsed.flush_window_ms, 50);
+    }
+}
@@ -0,0 +1,542 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use dashmap::DashMap;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::RecipientTraceConfig,
+    config_watch::ConfigWatcher,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+    shard::shard_for,
+    trace::MessageTrace,
+    user_events::{RecentUserEvents, UserEventKind},
+};
+
+/// Resolution of the per-message sampling hash, matching `sampling.rs`
+const SAMPLE_BUCKETS: usize = 1_000_000;
+
+/// Why a candidate recipient didn't get the message; shared by every filter stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionReason {
+    /// The sender's own copy
+    Sender,
+    /// The recipient blocked the sender
+    Blocked,
+    DoNotDisturb,
+    /// The recipient's clients can't render the message type
+    Capability,
+    ArchivedConversation,
+    /// The route cache holds a negative entry for the recipient
+    UnknownUser,
+    /// No live session and the message isn't queued for offline delivery
+    Offline,
+    BannedMember,
+    /// An invitation event already went out and the user hasn't joined
+    InvitationPending,
+    InvitationExpired,
+    /// A thread-activity marker went out within `threads.marker_window`
+    ThreadMarkerCoalesced,
+}
+
+impl ExclusionReason {
+    pub const ALL: [ExclusionReason; 11] = [
+        ExclusionReason::Sender,
+        ExclusionReason::Blocked,
+        ExclusionReason::DoNotDisturb,
+        ExclusionReason::Capability,
+        ExclusionReason::ArchivedConversation,
+        ExclusionReason::UnknownUser,
+        ExclusionReason::Offline,
+        ExclusionReason::BannedMember,
+        ExclusionReason::InvitationPending,
+        ExclusionReason::InvitationExpired,
+        ExclusionReason::ThreadMarkerCoalesced,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExclusionReason::Sender => "sender",
+            ExclusionReason::Blocked => "blocked",
+            ExclusionReason::DoNotDisturb => "do_not_disturb",
+            ExclusionReason::Capability => "capability",
+            ExclusionReason::ArchivedConversation => "archived_conversation",
+            ExclusionReason::UnknownUser => "unknown_user",
+            ExclusionReason::Offline => "offline",
+            ExclusionReason::BannedMember => "banned_member",
+            ExclusionReason::InvitationPending => "invitation_pending",
+            ExclusionReason::InvitationExpired => "invitation_expired",
+            ExclusionReason::ThreadMarkerCoalesced => "thread_marker_coalesced",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// How a recipient got something other than the message itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Downgrade {
+    /// A payload-free thread-activity marker instead of the reply
+    ThreadMarker,
+    /// A group invitation event instead of the group message
+    Invitation,
+    /// Queued for offline delivery instead of pushed to a live session
+    OfflineQueue,
+}
+
+impl Downgrade {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Downgrade::ThreadMarker => "thread_marker",
+            Downgrade::Invitation => "invitation",
+            Downgrade::OfflineQueue => "offline_queue",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum RecipientDecision {
+    Included,
+    Excluded { reason: ExclusionReason },
+    Downgraded { how: Downgrade },
+}
+
+impl RecipientDecision {
+    fn describe(&self) -> String {
+        match self {
+            RecipientDecision::Included => "included".to_string(),
+            RecipientDecision::Excluded { reason } => format!("excluded ({})", reason.as_str()),
+            RecipientDecision::Downgraded { how } => format!("downgraded ({})", how.as_str()),
+        }
+    }
+}
+
+/// Per-recipient records for one traced message
+struct Recording {
+    watched: bool,
+    cap: usize,
+    records: Vec<(String, RecipientDecision)>,
+    /// Decisions past `cap`, summarized as totals
+    included_beyond: usize,
+    excluded_beyond: [usize; ExclusionReason::ALL.len()],
+    downgraded_beyond: usize,
+}
+
+/// Recipient decisions taken while building one message's fanout
+///
+/// Filter stages report every candidate through `include`, `exclude` and
+/// `downgrade`. Exclusions are always tallied into a fixed array for the
+/// per-reason counters; per-recipient records are kept only when
+/// `RecipientTracer::begin` chose to trace the message, so an untraced
+/// message costs the stages no allocation. With recent user events
+/// attached, exclusions other than the sender's own copy also go into
+/// the recipient's ring.
+pub struct RecipientDecisions {
+    recording: Option<Box<Recording>>,
+    excluded: [u32; ExclusionReason::ALL.len()],
+    recent: Option<Box<RecentSink>>,
+}
+
+/// Where exclusions are reported as `Suppressed` user events
+struct RecentSink {
+    events: Arc<RecentUserEvents>,
+    conversation_id: String,
+    message_id: String,
+}
+
+impl RecipientDecisions {
+    /// Tally only, no per-recipient records
+    pub fn untraced() -> Self {
+        Self {
+            recording: None,
+            excluded: [0; ExclusionReason::ALL.len()],
+            recent: None,
+        }
+    }
+
+    fn traced(cap: usize, watched: bool) -> Self {
+        Self {
+            recording: Some(Box::new(Recording {
+                watched,
+                cap,
+                records: Vec::new(),
+                included_beyond: 0,
+                excluded_beyond: [0; ExclusionReason::ALL.len()],
+                downgraded_beyond: 0,
+            })),
+            excluded: [0; ExclusionReason::ALL.len()],
+            recent: None,
+        }
+    }
+
+    fn with_recent(mut self, events: Arc<RecentUserEvents>, envelope: &MessageEnvelope) -> Self {
+        self.recent = Some(Box::new(RecentSink {
+            events,
+            conversation_id: envelope.conversation_id(),
+            message_id: envelope.message_id.clone(),
+        }));
+        self
+    }
+
+    pub fn is_traced(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn include(&mut self, recipient: &str) {
+        self.decide(recipient, RecipientDecision::Included);
+    }
+
+    pub fn exclude(&mut self, recipient: &str, reason: ExclusionReason) {
+        self.excluded[reason.index()] += 1;
+        if let Some(sink) = self.recent.as_deref().filter(|_| reason != ExclusionReason::Sender) {
+            sink.events.record(
+                recipient,
+                UserEventKind::Suppressed { reason },
+                Some(&sink.conversation_id),
+                Some(&sink.message_id),
+            );
+        }
+        self.decide(recipient, RecipientDecision::Excluded { reason });
+    }
+
+    pub fn downgrade(&mut self, recipient: &str, how: Downgrade) {
+        self.decide(recipient, RecipientDecision::Downgraded { how });
+    }
+
+    fn decide(&mut self, recipient: &str, decision: RecipientDecision) {
+        let Some(recording) = self.recording.as_deref_mut() else {
+            return;
+        };
+        if recording.records.len() < recording.cap {
+            recording.records.push((recipient.to_string(), decision));
+            return;
+        }
+        match decision {
+            RecipientDecision::Included => recording.included_beyond += 1,
+            RecipientDecision::Excluded { reason } => recording.excluded_beyond[reason.index()] += 1,
+            RecipientDecision::Downgraded { .. } => recording.downgraded_beyond += 1,
+        }
+    }
+
+    /// Latest recorded decision for `recipient`, if it was within the cap
+    pub fn decision_for(&self, recipient: &str) -> Option<RecipientDecision> {
+        self.recording
+            .as_ref()?
+            .records
+            .iter()
+            .rev()
+            .find(|(user, _)| user == recipient)
+            .map(|(_, decision)| *decision)
+    }
+}
+
+/// What a routing watch matches
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum RoutingWatch {
+    Message(String),
+    Sender(String),
+    Conversation(String),
+}
+
+/// Records why each fanout candidate was included, excluded or downgraded
+///
+/// Messages matching an active `RoutingWatch`, and a `recipient_trace.sample_rate`
+/// fraction of all messages, get a per-recipient record in their trace
+/// timeline under the `recipients` stage, capped at
+/// `recipient_trace.max_recipients` with the rest summarized by outcome.
+/// The sample hashes the message ID like `EnvelopeSampler`, so with a rate
+/// at or below `sampling.base_rate` every sampled decision also reaches the
+/// debug stream. Traces of watched messages are kept for
+/// `/debug/traces/:message_id`. Exclusions are counted in
+/// `broker_recipient_exclusions_total{reason}` for every message.
+pub struct RecipientTracer {
+    config: ArcSwap<RecipientTraceConfig>,
+    watches: DashMap<RoutingWatch, Instant>,
+    retained: Mutex<LruCache<String, MessageTrace>>,
+    recent_events: ArcSwapOption<RecentUserEvents>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl RecipientTracer {
+    pub fn new(config: RecipientTraceConfig, metrics: BrokerMetrics) -> Self {
+        let retained = NonZeroUsize::new(config.retained_traces.max(1)).unwrap();
+        Self {
+            config: ArcSwap::from_pointee(config),
+            watches: DashMap::new(),
+            retained: Mutex::new(LruCache::new(retained)),
+            recent_events: ArcSwapOption::empty(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn attach_recent_events(&self, events: Arc<RecentUserEvents>) {
+        self.recent_events.store(Some(events));
+    }
+
+    /// Start recording decisions for a message about to fan out
+    pub fn begin(&self, envelope: &MessageEnvelope) -> RecipientDecisions {
+        let decisions = self.decisions_for(envelope);
+        match self.recent_events.load_full() {
+            Some(events) => decisions.with_recent(events, envelope),
+            None => decisions,
+        }
+    }
+
+    fn decisions_for(&self, envelope: &MessageEnvelope) -> RecipientDecisions {
+        let config = self.config.load();
+        if !config.enabled {
+            return RecipientDecisions::untraced();
+        }
+        let watched = self.is_watched(envelope);
+        let threshold = (config.sample_rate.clamp(0.0, 1.0) * SAMPLE_BUCKETS as f64) as usize;
+        if watched || shard_for(&envelope.message_id, SAMPLE_BUCKETS) < threshold {
+            RecipientDecisions::traced(config.max_recipients, watched)
+        } else {
+            RecipientDecisions::untraced()
+        }
+    }
+
+    fn is_watched(&self, envelope: &MessageEnvelope) -> bool {
+        if self.watches.is_empty() {
+            return false;
+        }
+        let now = self.clock.now_instant();
+        let active = |watch: RoutingWatch| self.watches.get(&watch).is_some_and(|until| *until > now);
+        active(RoutingWatch::Message(envelope.message_id.clone()))
+            || active(RoutingWatch::Sender(envelope.from.clone()))
+            || active(RoutingWatch::Conversation(envelope.conversation_id()))
+    }
+
+    /// Count exclusions and move recorded decisions into the trace timeline
+    pub fn finish(&self, decisions: RecipientDecisions, trace: &mut MessageTrace) {
+        for reason in ExclusionReason::ALL {
+            let count = decisions.excluded[reason.index()];
+            if count > 0 {
+                self.metrics.record_recipient_exclusions(reason.as_str(), count as u64);
+            }
+        }
+        let Some(recording) = decisions.recording else {
+            return;
+        };
+
+        for (recipient, decision) in &recording.records {
+            trace.record("recipients", format!("{}: {}", recipient, decision.describe()));
+        }
+        let beyond: usize = recording.included_beyond
+            + recording.excluded_beyond.iter().sum::<usize>()
+            + recording.downgraded_beyond;
+        if beyond > 0 {
+            let excluded: Vec<String> = ExclusionReason::ALL
+                .iter()
+                .filter(|reason| recording.excluded_beyond[reason.index()] > 0)
+                .map(|reason| format!("{}={}", reason.as_str(), recording.excluded_beyond[reason.index()]))
+                .collect();
+            trace.record(
+                "recipients",
+                format!(
+                    "{} more: {} included, {} downgraded, excluded [{}]",
+                    beyond,
+                    recording.included_beyond,
+                    recording.downgraded_beyond,
+                    excluded.join(", ")
+                ),
+            );
+        }
+        self.metrics.record_recipient_trace(if recording.watched { "watched" } else { "sampled" });
+
+        if recording.watched {
+            self.retained.lock().put(trace.message_id.clone(), trace.clone());
+        }
+    }
+
+    /// Trace a watched message was routed with, if still retained
+    pub fn retained(&self, message_id: &str) -> Option<MessageTrace> {
+        self.retained.lock().get(message_id).cloned()
+    }
+
+    /// Trace matching messages until `ttl`, or `recipient_trace.watch_ttl`, passes
+    pub fn add_watch(&self, watch: RoutingWatch, ttl: Option<Duration>) {
+        let ttl = ttl.unwrap_or_else(|| self.config.load().watch_ttl);
+        self.watches.insert(watch, self.clock.now_instant() + ttl);
+        self.prune();
+    }
+
+    pub fn remove_watch(&self, watch: &RoutingWatch) {
+        self.watches.remove(watch);
+    }
+
+    fn prune(&self) {
+        let now = self.clock.now_instant();
+        self.watches.retain(|_, until| *until > now);
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let tracer = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            tracer.config.store(Arc::new(config.recipient_trace.clone()));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    fn tracer(configure: impl FnOnce(&mut RecipientTraceConfig)) -> (RecipientTracer, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().recipient_trace;
+        config.sample_rate = 0.0;
+        configure(&mut config);
+        let clock = Arc::new(SimClock::new());
+        let tracer = RecipientTracer::new(config, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (tracer, clock)
+    }
+
+    fn message(from: &str) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        MessageEnvelope::new(MessageType::TextMessage, from.into(), vec!["bob".into()], payload)
+    }
+
+    fn details(trace: &MessageTrace) -> Vec<&str> {
+        trace
+            .events
+            .iter()
+            .filter(|event| event.stage == "recipients")
+            .map(|event| event.detail.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn unwatched_unsampled_messages_record_nothing_but_still_count() {
+        let (tracer, _) = tracer(|_| {});
+        let envelope = message("alice");
+        let mut decisions = tracer.begin(&envelope);
+        assert!(!decisions.is_traced());
+
+        decisions.include("bob");
+        decisions.exclude("carol", ExclusionReason::Blocked);
+        decisions.exclude("dave", ExclusionReason::Blocked);
+        decisions.downgrade("erin", Downgrade::OfflineQueue);
+        assert_eq!(decisions.decision_for("carol"), None);
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let mut trace = MessageTrace::new(envelope.message_id.clone());
+        metrics::with_local_recorder(&recorder, || tracer.finish(decisions, &mut trace));
+        assert!(trace.events.is_empty());
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_recipient_exclusions_total{reason="blocked"} 2"#), "{}", rendered);
+        assert!(!rendered.contains("broker_recipient_traces_total"), "{}", rendered);
+    }
+
+    #[test]
+    fn every_watch_kind_traces_until_its_ttl_passes() {
+        let (tracer, clock) = tracer(|_| {});
+        let envelope = message("alice");
+        let ttl = Some(Duration::from_secs(60));
+
+        for watch in [
+            RoutingWatch::Message(envelope.message_id.clone()),
+            RoutingWatch::Sender("alice".into()),
+            RoutingWatch::Conversation(envelope.conversation_id()),
+        ] {
+            tracer.add_watch(watch.clone(), ttl);
+            assert!(tracer.begin(&envelope).is_traced(), "{:?}", watch);
+            assert!(!tracer.begin(&message("mallory")).is_traced(), "{:?}", watch);
+            tracer.remove_watch(&watch);
+            assert!(!tracer.begin(&envelope).is_traced(), "{:?}", watch);
+        }
+
+        tracer.add_watch(RoutingWatch::Sender("alice".into()), ttl);
+        clock.advance(Duration::from_secs(59));
+        assert!(tracer.begin(&envelope).is_traced());
+        clock.advance(Duration::from_secs(1));
+        assert!(!tracer.begin(&envelope).is_traced());
+
+        // Expired watches go the next time one is added
+        tracer.add_watch(RoutingWatch::Sender("bob".into()), None);
+        assert_eq!(tracer.watches.len(), 1);
+    }
+
+    #[test]
+    fn disabled_tracing_ignores_watches() {
+        let (tracer, _) = tracer(|config| config.enabled = false);
+        tracer.add_watch(RoutingWatch::Sender("alice".into()), None);
+        assert!(!tracer.begin(&message("alice")).is_traced());
+    }
+
+    #[test]
+    fn sampled_messages_are_traced_but_not_retained() {
+        let (tracer, _) = tracer(|config| config.sample_rate = 1.0);
+        let envelope = message("alice");
+        let mut decisions = tracer.begin(&envelope);
+        assert!(decisions.is_traced());
+        decisions.exclude("bob", ExclusionReason::DoNotDisturb);
+
+        let mut trace = MessageTrace::new(envelope.message_id.clone());
+        tracer.finish(decisions, &mut trace);
+        assert_eq!(details(&trace), ["bob: excluded (do_not_disturb)"]);
+        assert!(tracer.retained(&envelope.message_id).is_none());
+    }
+
+    #[test]
+    fn watched_decisions_past_the_cap_are_summarized_by_outcome() {
+        let (tracer, _) = tracer(|config| config.max_recipients = 2);
+        let envelope = message("alice");
+        tracer.add_watch(RoutingWatch::Message(envelope.message_id.clone()), None);
+
+        let mut decisions = tracer.begin(&envelope);
+        decisions.exclude("alice", ExclusionReason::Sender);
+        decisions.include("bob");
+        decisions.include("carol");
+        decisions.exclude("dave", ExclusionReason::Capability);
+        decisions.exclude("erin", ExclusionReason::Capability);
+        decisions.exclude("frank", ExclusionReason::Offline);
+        decisions.downgrade("grace", Downgrade::OfflineQueue);
+        assert_eq!(decisions.decision_for("bob"), Some(RecipientDecision::Included));
+        assert_eq!(decisions.decision_for("dave"), None);
+
+        let mut trace = MessageTrace::new(envelope.message_id.clone());
+        tracer.finish(decisions, &mut trace);
+        assert_eq!(
+            details(&trace),
+            [
+                "alice: excluded (sender)",
+                "bob: included",
+                "5 more: 1 included, 1 downgraded, excluded [capability=2, offline=1]",
+            ]
+        );
+        assert_eq!(tracer.retained(&envelope.message_id).unwrap().events.len(), 3);
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use async_nats::{jetstream::context::PublishErrorKind as JetStreamPublishErrorKind, RequestErrorKind};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::RetryConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Publish failure categories the classifier distinguishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishErrorKind {
+    /// Nobody subscribed to the subject; the gateway is gone
+    NoResponders,
+    /// Core request timed out
+    Timeout,
+    /// JetStream ack did not arrive in time
+    AckTimeout,
+    StreamNotFound,
+    /// Expected last sequence or message ID mismatch
+    WrongLastSequence,
+    MaxPayloadExceeded,
+    PermissionDenied,
+    /// Connection dropped while publishing
+    Disconnected,
+    Other,
+}
+
+impl PublishErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PublishErrorKind::NoResponders => "no_responders",
+            PublishErrorKind::Timeout => "timeout",
+            PublishErrorKind::AckTimeout => "ack_timeout",
+            PublishErrorKind::StreamNotFound => "stream_not_found",
+            PublishErrorKind::WrongLastSequence => "wrong_last_sequence",
+            PublishErrorKind::MaxPayloadExceeded => "max_payload_exceeded",
+            PublishErrorKind::PermissionDenied => "permission_denied",
+            PublishErrorKind::Disconnected => "disconnected",
+            PublishErrorKind::Other => "other",
+        }
+    }
+
+    pub fn from_request(kind: RequestErrorKind) -> Self {
+        match kind {
+            RequestErrorKind::NoResponders => PublishErrorKind::NoResponders,
+            RequestErrorKind::TimedOut => PublishErrorKind::Timeout,
+            _ => PublishErrorKind::Other,
+        }
+    }
+
+    pub fn from_jetstream(kind: JetStreamPublishErrorKind) -> Self {
+        match kind {
+            JetStreamPublishErrorKind::TimedOut => PublishErrorKind::AckTimeout,
+            JetStreamPublishErrorKind::StreamNotFound => PublishErrorKind::StreamNotFound,
+            JetStreamPublishErrorKind::WrongLastSequence | JetStreamPublishErrorKind::WrongLastMessageId => {
+                PublishErrorKind::WrongLastSequence
+            }
+            JetStreamPublishErrorKind::BrokenPipe => PublishErrorKind::Disconnected,
+            _ => PublishErrorKind::Other,
+        }
+    }
+
+    /// Kind for a server `-ERR` description or an error without a typed kind
+    pub fn from_description(description: &str) -> Self {
+        let description = description.to_lowercase();
+        if description.contains("no responders") {
+            PublishErrorKind::NoResponders
+        } else if description.contains("permissions violation") {
+            PublishErrorKind::PermissionDenied
+        } else if description.contains("maximum payload") || description.contains("max payload") {
+            PublishErrorKind::MaxPayloadExceeded
+        } else if description.contains("timed out") || description.contains("timeout") {
+            PublishErrorKind::Timeout
+        } else if description.contains("disconnected") || description.contains("broken pipe") {
+            PublishErrorKind::Disconnected
+        } else {
+            PublishErrorKind::Other
+        }
+    }
+}
+
+/// What to do with a failed publish
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryAction {
+    RetryNow,
+    RetryBackoff,
+    QueueOffline,
+    DeadLetter,
+    Drop,
+}
+
+impl RetryAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetryAction::RetryNow => "retry_now",
+            RetryAction::RetryBackoff => "retry_backoff",
+            RetryAction::QueueOffline => "queue_offline",
+            RetryAction::DeadLetter => "dead_letter",
+            RetryAction::Drop => "drop",
+        }
+    }
+
+    fn is_retry(&self) -> bool {
+        matches!(self, RetryAction::RetryNow | RetryAction::RetryBackoff)
+    }
+}
+
+/// Built-in mapping; `retry.overrides` replaces individual entries
+pub fn default_action(kind: PublishErrorKind) -> RetryAction {
+    match kind {
+        PublishErrorKind::NoResponders => RetryAction::QueueOffline,
+        PublishErrorKind::Timeout | PublishErrorKind::AckTimeout => RetryAction::RetryNow,
+        PublishErrorKind::Disconnected | PublishErrorKind::StreamNotFound | PublishErrorKind::Other => {
+            RetryAction::RetryBackoff
+        }
+        PublishErrorKind::MaxPayloadExceeded | PublishErrorKind::PermissionDenied => RetryAction::DeadLetter,
+        // Already stored by an earlier attempt
+        PublishErrorKind::WrongLastSequence => RetryAction::Drop,
+    }
+}
+
+struct SubjectFailures {
+    consecutive: u32,
+    last_failure: Instant,
+}
+
+/// Maps publish failures to a retry action, escalating chronically failing subjects
+///
+/// A subject that fails `retry.escalate_after` consecutive times within
+/// `retry.history_window` skips retries and goes straight to the offline
+/// queue. Any successful publish clears the subject's history.
+pub struct RetryClassifier {
+    actions: HashMap<PublishErrorKind, RetryAction>,
+    history: DashMap<String, SubjectFailures>,
+    escalate_after: u32,
+    history_window: Duration,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl RetryClassifier {
+    pub fn new(config: &RetryConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            actions: config.overrides.clone(),
+            history: DashMap::new(),
+            escalate_after: config.escalate_after.max(1),
+            history_window: config.history_window,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn classify(&self, subject: &str, kind: PublishErrorKind) -> RetryAction {
+        let mut action = self.actions.get(&kind).copied().unwrap_or_else(|| default_action(kind));
+
+        let now = self.clock.now_instant();
+        let mut failures = self.history.entry(subject.to_string()).or_insert(SubjectFailures {
+            consecutive: 0,
+            last_failure: now,
+        });
+        if now.duration_since(failures.last_failure) > self.history_window {
+            failures.consecutive = 0;
+        }
+        failures.consecutive += 1;
+        failures.last_failure = now;
+
+        if action.is_retry() && failures.consecutive >= self.escalate_after {
+            action = RetryAction::QueueOffline;
+        }
+        drop(failures);
+
+        self.metrics.record_publish_error_classified(kind.as_str(), action.as_str());
+        action
+    }
+
+    pub fn record_success(&self, subject: &str) {
+        self.history.remove(subject);
+    }
+
+    /// Forget subjects whose last failure is outside the history window
+    pub fn prune(&self) {
+        let now = self.clock.now_instant();
+        self.history
+            .retain(|_, failures| now.duration_since(failures.last_failure) <= self.history_window);
+    }
+
+    pub fn spawn_prune_task(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let classifier = Arc::clone(self);
+        spawn_traced("retry_history_prune", TaskContext::new("retry"), async move {
+            let mut interval = tokio::time::interval(classifier.history_window.max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                classifier.prune();
+            }
+        })
+    }
+}
+
+/// The gateway test runs against NATS at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored retry_classifier`
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const WINDOW: Duration = Duration::from_secs(60);
+    const SUBJECT: &str = "gateway.user.bob";
+
+    fn classifier(overrides: HashMap<PublishErrorKind, RetryAction>) -> (RetryClassifier, Arc<SimClock>) {
+        let mut config = BrokerConfig::load().unwrap().retry;
+        config.escalate_after = 5;
+        config.history_window = WINDOW;
+        config.overrides = overrides;
+        let clock = Arc::new(SimClock::new());
+        let classifier = RetryClassifier::new(&config, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (classifier, clock)
+    }
+
+    #[test]
+    fn client_error_variants_map_to_kinds() {
+        assert_eq!(PublishErrorKind::from_request(RequestErrorKind::NoResponders), PublishErrorKind::NoResponders);
+        assert_eq!(PublishErrorKind::from_request(RequestErrorKind::TimedOut), PublishErrorKind::Timeout);
+        assert_eq!(PublishErrorKind::from_request(RequestErrorKind::Other), PublishErrorKind::Other);
+
+        for (variant, kind) in [
+            (JetStreamPublishErrorKind::TimedOut, PublishErrorKind::AckTimeout),
+            (JetStreamPublishErrorKind::StreamNotFound, PublishErrorKind::StreamNotFound),
+            (JetStreamPublishErrorKind::WrongLastSequence, PublishErrorKind::WrongLastSequence),
+            (JetStreamPublishErrorKind::WrongLastMessageId, PublishErrorKind::WrongLastSequence),
+            (JetStreamPublishErrorKind::BrokenPipe, PublishErrorKind::Disconnected),
+            (JetStreamPublishErrorKind::Other, PublishErrorKind::Other),
+        ] {
+            assert_eq!(PublishErrorKind::from_jetstream(variant), kind);
+        }
+    }
+
+    #[test]
+    fn server_descriptions_map_to_kinds() {
+        for (description, kind) in [
+            ("no responders available for request", PublishErrorKind::NoResponders),
+            ("Permissions Violation for Publish to \"gateway.user.bob\"", PublishErrorKind::PermissionDenied),
+            ("Maximum Payload Violation", PublishErrorKind::MaxPayloadExceeded),
+            ("request timed out", PublishErrorKind::Timeout),
+            ("broken pipe", PublishErrorKind::Disconnected),
+            ("Authorization Violation", PublishErrorKind::Other),
+        ] {
+            assert_eq!(PublishErrorKind::from_description(description), kind, "{}", description);
+        }
+    }
+
+    #[test]
+    fn each_kind_gets_its_default_action() {
+        let (classifier, _) = classifier(HashMap::new());
+        for (kind, action) in [
+            (PublishErrorKind::NoResponders, RetryAction::QueueOffline),
+            (PublishErrorKind::Timeout, RetryAction::RetryNow),
+            (PublishErrorKind::AckTimeout, RetryAction::RetryNow),
+            (PublishErrorKind::StreamNotFound, RetryAction::RetryBackoff),
+            (PublishErrorKind::WrongLastSequence, RetryAction::Drop),
+            (PublishErrorKind::MaxPayloadExceeded, RetryAction::DeadLetter),
+            (PublishErrorKind::PermissionDenied, RetryAction::DeadLetter),
+            (PublishErrorKind::Disconnected, RetryAction::RetryBackoff),
+            (PublishErrorKind::Other, RetryAction::RetryBackoff),
+        ] {
+            // A fresh subject each time so history never escalates
+            assert_eq!(classifier.classify(kind.as_str(), kind), action, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn consecutive_failures_escalate_retries_to_the_offline_queue() {
+        let (classifier, _) = classifier(HashMap::new());
+        for _ in 0..4 {
+            assert_eq!(classifier.classify(SUBJECT, PublishErrorKind::AckTimeout), RetryAction::RetryNow);
+        }
+        assert_eq!(classifier.classify(SUBJECT, PublishErrorKind::AckTimeout), RetryAction::QueueOffline);
+        // Other subjects keep their own history
+        assert_eq!(classifier.classify("gateway.user.carol", PublishErrorKind::AckTimeout), RetryAction::RetryNow);
+
+        classifier.record_success(SUBJECT);
+        assert_eq!(classifier.classify(SUBJECT, PublishErrorKind::AckTimeout), RetryAction::RetryNow);
+    }
+
+    #[test]
+    fn escalation_never_rescues_terminal_actions() {
+        let (classifier, _) = classifier(HashMap::new());
+        for _ in 0..10 {
+            assert_eq!(classifier.classify(SUBJECT, PublishErrorKind::PermissionDenied), RetryAction::DeadLetter);
+        }
+        assert_eq!(classifier.classify(SUBJECT, PublishErrorKind::WrongLastSequence), RetryAction::Drop);
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_not_consecutive() {
+        let (classifier, clock) = classifier(HashMap::new());
+        for _ in 0..4 {
+            classifier.classify(SUBJECT, PublishErrorKind::Timeout);
+            clock.advance(WINDOW / 2);
+        }
+        clock.advance(WINDOW);
+        assert_eq!(classifier.classify(SUBJECT, PublishErrorKind::Timeout), RetryAction::RetryNow);
+
+        clock.advance(WINDOW + Duration::from_secs(1));
+        classifier.prune();
+        assert!(classifier.history.is_empty());
+    }
+
+    #[test]
+    fn config_overrides_replace_single_entries() {
+        let overrides: HashMap<PublishErrorKind, RetryAction> =
+            serde_json::from_str(r#"{"no_responders": "retry_backoff", "permission_denied": "drop"}"#).unwrap();
+        let (classifier, _) = classifier(overrides);
+
+        assert_eq!(classifier.classify("a", PublishErrorKind::NoResponders), RetryAction::RetryBackoff);
+        assert_eq!(classifier.classify("b", PublishErrorKind::PermissionDenied), RetryAction::Drop);
+        assert_eq!(classifier.classify("c", PublishErrorKind::Timeout), RetryAction::RetryNow);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a NATS server"]
+    async fn a_vanished_gateway_is_queued_offline_without_retrying() {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let client = async_nats::connect(url).await.unwrap();
+        let subject = format!("gateway-test.{}.user.bob", Uuid::new_v4().simple());
+        let (classifier, _) = classifier(HashMap::new());
+
+        let mut gateway = client.subscribe(subject.clone()).await.unwrap();
+        let responder = {
+            let client = client.clone();
+            tokio::spawn(async move {
+                while let Some(request) = gateway.next().await {
+                    client.publish(request.reply.unwrap(), "ok".into()).await.unwrap();
+                }
+            })
+        };
+        client.request(subject.clone(), "hi".into()).await.unwrap();
+
+        // The gateway dies and its subscription goes with it
+        responder.abort();
+        let _ = responder.await;
+        client.flush().await.unwrap();
+
+        let mut attempts = 0;
+        let action = loop {
+            attempts += 1;
+            let error = client.request(subject.clone(), "hi".into()).await.unwrap_err();
+            let action = classifier.classify(&subject, PublishErrorKind::from_request(error.kind()));
+            if !action.is_retry() || attempts == 10 {
+                break action;
+            }
+        };
+        assert_eq!(action, RetryAction::QueueOffline);
+        assert_eq!(attempts, 1);
+    }
+}
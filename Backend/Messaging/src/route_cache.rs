@@ -0,0 +1,437 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use async_trait::async_trait;
+use lru::LruCache;
+
+use crate::{
+    cache_shrink::{lru_entry_bytes, Reclaimed, ShrinkableCache},
+    clock::{SharedClock, SystemClock},
+    config::RoutingConfig,
+    lock_metrics::{Mutex, NamedLock},
+    metrics::BrokerMetrics,
+    presence::PresenceRecord,
+};
+
+/// Where a known user can currently be reached
+#[derive(Debug, Clone)]
+pub struct UserRoute {
+    /// Presence record, or `None` for an existing user with no presence
+    pub presence: Option<PresenceRecord>,
+}
+
+#[derive(Debug, Clone)]
+pub enum UserLookup {
+    Found(UserRoute),
+    /// The user conclusively does not exist
+    NotFound,
+}
+
+/// Presence and user directory lookup behind the route cache
+#[async_trait]
+pub trait UserResolver: Send + Sync {
+    async fn resolve(&self, user_id: &str) -> Result<UserLookup, RouteLookupError>;
+}
+
+/// Two-tier route cache: known users, and users known not to exist
+///
+/// Negative entries have their own TTL and size budget so deleted accounts
+/// referenced by old groups short-circuit without crowding out real routes.
+/// A negative entry must never hide a user who just came online: presence
+/// online events purge it after the presence write lands, and bump a
+/// generation so lookups resolved before the purge don't store a negative
+/// result behind it.
+///
+/// Entries stored by `refresh` are marked warmed; their first hit is counted
+/// as `warmed_hit` so pre-warming shows up in the lookup metrics.
+pub struct RouteCache {
+    resolver: Arc<dyn UserResolver>,
+    positive: Mutex<LruCache<String, PositiveEntry>>,
+    negative: Mutex<NegativeTier>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    negative_capacity: NonZeroUsize,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+struct PositiveEntry {
+    route: UserRoute,
+    stored_at: Instant,
+    /// Stored by `refresh` and not yet looked up
+    warmed: bool,
+}
+
+struct NegativeTier {
+    entries: LruCache<String, Instant>,
+    /// Bumped by every purge or invalidation, under the same lock
+    generation: u64,
+}
+
+impl RouteCache {
+    pub fn new(resolver: Arc<dyn UserResolver>, routing: &RoutingConfig, metrics: BrokerMetrics) -> Self {
+        let positive_size = NonZeroUsize::new(routing.cache_size.max(1)).unwrap();
+        let negative_size = NonZeroUsize::new(routing.negative_cache_size.max(1)).unwrap();
+        Self {
+            resolver,
+            positive: Mutex::named("route_cache.positive", LruCache::new(positive_size)),
+            negative: Mutex::named(
+                "route_cache.negative",
+                NegativeTier {
+                    entries: LruCache::new(negative_size),
+                    generation: 0,
+                },
+            ),
+            positive_ttl: routing.route_cache_ttl,
+            negative_ttl: routing.negative_cache_ttl,
+            negative_capacity: negative_size,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Route for a user, or `None` if the user does not exist
+    pub async fn lookup(&self, user_id: &str) -> Result<Option<UserRoute>, RouteLookupError> {
+        if let Some((route, warmed)) = self.positive_hit(user_id) {
+            self.metrics
+                .record_route_cache_lookup(if warmed { "warmed_hit" } else { "positive_hit" });
+            return Ok(Some(route));
+        }
+
+        let generation = {
+            let mut negative = self.negative.lock();
+            match negative.entries.get(user_id) {
+                Some(at) if self.clock.now_instant().duration_since(*at) < self.negative_ttl => {
+                    self.metrics.record_route_cache_lookup("negative_hit");
+                    return Ok(None);
+                }
+                Some(_) => {
+                    negative.entries.pop(user_id);
+                }
+                None => {}
+            }
+            negative.generation
+        };
+        self.metrics.record_route_cache_lookup("miss");
+
+        match self.resolver.resolve(user_id).await? {
+            UserLookup::Found(route) => {
+                // Lifecycle events during the resolve may have made the result stale
+                if self.negative.lock().generation == generation {
+                    self.store_positive(user_id, route.clone(), false);
+                }
+                Ok(Some(route))
+            }
+            UserLookup::NotFound => {
+                let mut negative = self.negative.lock();
+                // A purge during the resolve may mean the user now exists
+                if negative.generation == generation {
+                    negative.entries.put(user_id.to_string(), self.clock.now_instant());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Resolve a user ahead of demand and cache the result as warmed
+    ///
+    /// Only known users are stored; a negative result is left to the next
+    /// real lookup so warming never hides a user behind a negative entry.
+    pub async fn refresh(&self, user_id: &str) -> Result<(), RouteLookupError> {
+        let generation = self.negative.lock().generation;
+        if let UserLookup::Found(route) = self.resolver.resolve(user_id).await? {
+            if self.negative.lock().generation == generation {
+                self.store_positive(user_id, route, true);
+            }
+        }
+        Ok(())
+    }
+
+    /// Presence online event; call after the presence record is written
+    pub fn user_online(&self, user_id: &str) {
+        self.invalidate(user_id);
+    }
+
+    /// Replace a route found stale on delivery with a freshly read one
+    pub fn replace(&self, user_id: &str, route: UserRoute) {
+        self.purge_negative(user_id);
+        self.store_positive(user_id, route, false);
+    }
+
+    /// User lifecycle event (created or deleted); drops both tiers
+    pub fn invalidate(&self, user_id: &str) {
+        self.purge_negative(user_id);
+        self.positive.lock().pop(user_id);
+    }
+
+    fn purge_negative(&self, user_id: &str) {
+        let mut negative = self.negative.lock();
+        negative.entries.pop(user_id);
+        negative.generation += 1;
+    }
+
+    fn store_positive(&self, user_id: &str, route: UserRoute, warmed: bool) {
+        self.positive.lock().put(
+            user_id.to_string(),
+            PositiveEntry {
+                route,
+                stored_at: self.clock.now_instant(),
+                warmed,
+            },
+        );
+    }
+
+    /// Cached route and whether this is the first hit on a warmed entry
+    fn positive_hit(&self, user_id: &str) -> Option<(UserRoute, bool)> {
+        let mut positive = self.positive.lock();
+        match positive.get_mut(user_id) {
+            Some(entry) if self.clock.now_instant().duration_since(entry.stored_at) < self.positive_ttl => {
+                let warmed = std::mem::take(&mut entry.warmed);
+                Some((entry.route.clone(), warmed))
+            }
+            Some(_) => {
+                positive.pop(user_id);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Shrinks the negative tier; forgetting that a user doesn't exist only
+/// costs a resolve
+impl ShrinkableCache for RouteCache {
+    fn cache_name(&self) -> &'static str {
+        "route_cache.negative"
+    }
+
+    fn footprint(&self) -> usize {
+        let negative = self.negative.lock();
+        negative
+            .entries
+            .iter()
+            .map(|(user_id, _)| lru_entry_bytes::<Instant>(user_id))
+            .sum()
+    }
+
+    /// Halve the negative tier, keeping the most recently used half
+    fn shrink(&self) -> Reclaimed {
+        let mut negative = self.negative.lock();
+        let target = (negative.entries.cap().get() / 2).max(1);
+        let mut reclaimed = Reclaimed::default();
+        while negative.entries.len() > target {
+            let Some((user_id, _)) = negative.entries.pop_lru() else {
+                break;
+            };
+            reclaimed.entries += 1;
+            reclaimed.bytes += lru_entry_bytes::<Instant>(&user_id);
+        }
+        negative.entries.resize(NonZeroUsize::new(target).unwrap());
+        reclaimed
+    }
+
+    fn restore(&self) {
+        self.negative.lock().entries.resize(self.negative_capacity);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("route lookup failed: {0}")]
+pub struct RouteLookupError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::{clock::SimClock, config::BrokerConfig};
+
+    const NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+    /// User directory that can hold a resolve open after reading it
+    #[derive(Default)]
+    struct Directory {
+        users: parking_lot::Mutex<HashSet<String>>,
+        resolves: AtomicUsize,
+        stall: AtomicBool,
+        /// Signalled once a stalled resolve has read the directory
+        read: Notify,
+        release: Notify,
+    }
+
+    #[async_trait]
+    impl UserResolver for Directory {
+        async fn resolve(&self, user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            let exists = self.users.lock().contains(user_id);
+            self.resolves.fetch_add(1, Ordering::SeqCst);
+            if self.stall.load(Ordering::SeqCst) {
+                self.read.notify_one();
+                self.release.notified().await;
+            }
+            Ok(if exists {
+                UserLookup::Found(UserRoute { presence: None })
+            } else {
+                UserLookup::NotFound
+            })
+        }
+    }
+
+    impl Directory {
+        fn resolves(&self) -> usize {
+            self.resolves.load(Ordering::SeqCst)
+        }
+    }
+
+    fn cache(negative_cache_size: usize) -> (Arc<RouteCache>, Arc<Directory>, Arc<SimClock>) {
+        let mut routing = BrokerConfig::load().unwrap().routing;
+        routing.route_cache_ttl = Duration::from_secs(30);
+        routing.negative_cache_ttl = NEGATIVE_TTL;
+        routing.negative_cache_size = negative_cache_size;
+        let directory = Arc::new(Directory::default());
+        let clock = Arc::new(SimClock::new());
+        let cache = RouteCache::new(directory.clone(), &routing, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (Arc::new(cache), directory, clock)
+    }
+
+    #[tokio::test]
+    async fn unknown_users_short_circuit_until_the_negative_ttl() {
+        let (cache, directory, clock) = cache(100);
+
+        assert!(cache.lookup("ghost").await.unwrap().is_none());
+        clock.advance(NEGATIVE_TTL - Duration::from_secs(1));
+        assert!(cache.lookup("ghost").await.unwrap().is_none());
+        assert_eq!(directory.resolves(), 1);
+
+        // Expired: the account exists again by the time it's resolved
+        directory.users.lock().insert("ghost".into());
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.lookup("ghost").await.unwrap().is_some());
+        assert!(cache.lookup("ghost").await.unwrap().is_some());
+        assert_eq!(directory.resolves(), 2);
+    }
+
+    #[tokio::test]
+    async fn negative_entries_have_their_own_budget() {
+        let (cache, directory, _) = cache(2);
+        directory.users.lock().insert("alice".into());
+        cache.lookup("alice").await.unwrap();
+
+        for ghost in ["ghost-1", "ghost-2", "ghost-3"] {
+            cache.lookup(ghost).await.unwrap();
+        }
+        assert_eq!(directory.resolves(), 4);
+
+        // The oldest negative entry went; the positive one is untouched
+        cache.lookup("ghost-3").await.unwrap();
+        cache.lookup("alice").await.unwrap();
+        assert_eq!(directory.resolves(), 4);
+        cache.lookup("ghost-1").await.unwrap();
+        assert_eq!(directory.resolves(), 5);
+    }
+
+    #[tokio::test]
+    async fn coming_online_purges_the_negative_entry() {
+        let (cache, directory, _) = cache(100);
+        assert!(cache.lookup("bob").await.unwrap().is_none());
+
+        directory.users.lock().insert("bob".into());
+        cache.user_online("bob");
+        assert!(cache.lookup("bob").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn an_online_event_during_a_resolve_is_never_masked() {
+        let (cache, directory, _) = cache(100);
+        directory.stall.store(true, Ordering::SeqCst);
+
+        // The resolve reads the directory before bob's account and presence land
+        let lookup = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.lookup("bob").await.unwrap() }
+        });
+        directory.read.notified().await;
+        directory.users.lock().insert("bob".into());
+        cache.user_online("bob");
+
+        directory.stall.store(false, Ordering::SeqCst);
+        directory.release.notify_one();
+        assert!(lookup.await.unwrap().is_none());
+
+        // The stale "no such user" was not cached behind the purge
+        assert!(cache.lookup("bob").await.unwrap().is_some());
+        assert_eq!(directory.resolves(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_deletion_during_a_resolve_is_not_cached_over() {
+        let (cache, directory, _) = cache(100);
+        directory.users.lock().insert("carol".into());
+        directory.stall.store(true, Ordering::SeqCst);
+
+        let lookup = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.lookup("carol").await.unwrap() }
+        });
+        directory.read.notified().await;
+        directory.users.lock().remove("carol");
+        cache.invalidate("carol");
+
+        directory.stall.store(false, Ordering::SeqCst);
+        directory.release.notify_one();
+        assert!(lookup.await.unwrap().is_some());
+        assert!(cache.lookup("carol").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_drop_cached_routes() {
+        let (cache, directory, _) = cache(100);
+        directory.users.lock().insert("dave".into());
+        assert!(cache.lookup("dave").await.unwrap().is_some());
+
+        directory.users.lock().remove("dave");
+        cache.invalidate("dave");
+        assert!(cache.lookup("dave").await.unwrap().is_none());
+        assert_eq!(directory.resolves(), 2);
+    }
+
+    #[tokio::test]
+    async fn shrinking_the_negative_tier_keeps_the_most_recent_half() {
+        let (cache, directory, _) = cache(100);
+        let unknown: Vec<String> = (0..100).map(|i| format!("ghost-{}", i)).collect();
+        for user_id in &unknown {
+            cache.lookup(user_id).await.unwrap();
+        }
+        // Looked up again, so the oldest ten are now the hottest
+        for user_id in &unknown[..10] {
+            cache.lookup(user_id).await.unwrap();
+        }
+        assert_eq!(directory.resolves(), 100);
+
+        let before = cache.footprint();
+        let reclaimed = cache.shrink();
+        assert_eq!(reclaimed.entries, 50);
+        assert_eq!(before - cache.footprint(), reclaimed.bytes);
+
+        for user_id in unknown[..10].iter().chain(&unknown[60..]) {
+            cache.lookup(user_id).await.unwrap();
+        }
+        assert_eq!(directory.resolves(), 100, "the kept half still short-circuits");
+        cache.lookup(&unknown[10]).await.unwrap();
+        assert_eq!(directory.resolves(), 101);
+
+        cache.restore();
+        assert_eq!(cache.negative.lock().entries.cap().get(), 100);
+    }
+}
@@ -0,0 +1,505 @@
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use lru::LruCache;
+use parking_lot::Mutex;
+use tracing::debug;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    coalesce::Coalescer,
+    config::{CoalesceConfig, RouteRepairConfig},
+    message::types::PresenceStatus,
+    metrics::BrokerMetrics,
+    presence::{PresenceRecord, PresenceStore},
+    retry_classifier::PublishErrorKind,
+    route_cache::{RouteCache, UserRoute},
+};
+
+/// What a repair did for one recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepairOutcome {
+    /// Redelivered on the user's new route
+    Recovered { gateway_id: String },
+    /// Presence has the user offline, or still on the dead gateway; queue offline
+    WentOffline,
+    /// The user's repair budget for the window is spent
+    RateLimited,
+    /// Presence couldn't be read in time
+    PresenceUnavailable,
+    /// The new route failed too
+    RedeliveryFailed(PublishErrorKind),
+}
+
+impl RepairOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepairOutcome::Recovered { .. } => "recovered",
+            RepairOutcome::WentOffline => "went_offline",
+            RepairOutcome::RateLimited => "rate_limited",
+            RepairOutcome::PresenceUnavailable => "presence_unavailable",
+            RepairOutcome::RedeliveryFailed(_) => "redelivery_failed",
+        }
+    }
+}
+
+struct RepairWindow {
+    started: Instant,
+    attempts: u32,
+}
+
+/// Reactive repair of a cached route that just failed with no responders
+///
+/// The fanout calls `repair` with the stale route and a redelivery closure
+/// instead of handing the recipient straight to the classifier's offline
+/// action. Presence is re-read for the user, bounded by
+/// `route_repair.presence_timeout_ms`; concurrent repairs of the same user,
+/// one per message of a burst, share that read and its result for
+/// `route_repair.dedup_window_ms`. The cached route is then replaced with
+/// the fresh one or evicted, and when the user is on another gateway the
+/// message is redelivered there within the same fanout. Each user gets at
+/// most `route_repair.max_per_user` repairs per `route_repair.window`, so a
+/// presence outage can't turn every failed publish into a presence read;
+/// past that, and whenever the repair can't place the message, the caller
+/// falls back to queueing offline as before.
+pub struct RouteRepair {
+    routes: Arc<RouteCache>,
+    presence: Arc<PresenceStore>,
+    lookups: Coalescer<Option<PresenceRecord>, String>,
+    windows: Mutex<LruCache<String, RepairWindow>>,
+    config: RouteRepairConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl RouteRepair {
+    pub fn new(
+        routes: Arc<RouteCache>,
+        presence: Arc<PresenceStore>,
+        config: RouteRepairConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let dedup = CoalesceConfig {
+            enabled: true,
+            window_ms: config.dedup_window_ms,
+            max_keys: config.tracked_users,
+            max_entry_bytes: usize::MAX,
+        };
+        Self {
+            routes,
+            presence,
+            lookups: Coalescer::new("route_repair", dedup, |_| 0, metrics.clone()),
+            windows: Mutex::new(LruCache::new(NonZeroUsize::new(config.tracked_users.max(1)).unwrap())),
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.lookups = self.lookups.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Repair `user_id`'s route after `stale` failed, and redeliver on the new one
+    pub async fn repair<F, Fut>(&self, user_id: &str, stale: &UserRoute, redeliver: F) -> RepairOutcome
+    where
+        F: FnOnce(PresenceRecord) -> Fut,
+        Fut: Future<Output = Result<(), PublishErrorKind>>,
+    {
+        let outcome = self.run(user_id, stale, redeliver).await;
+        debug!("Route repair for {}: {:?}", user_id, outcome);
+        self.metrics.record_route_repair(outcome.as_str());
+        outcome
+    }
+
+    async fn run<F, Fut>(&self, user_id: &str, stale: &UserRoute, redeliver: F) -> RepairOutcome
+    where
+        F: FnOnce(PresenceRecord) -> Fut,
+        Fut: Future<Output = Result<(), PublishErrorKind>>,
+    {
+        if !self.admit(user_id) {
+            return RepairOutcome::RateLimited;
+        }
+
+        let timeout = Duration::from_millis(self.config.presence_timeout_ms);
+        let lookup = self.lookups.run(user_id.to_string(), || async {
+            tokio::select! {
+                result = self.presence.lookup(user_id) => result.map_err(|e| e.to_string()),
+                _ = self.clock.sleep(timeout) => Err("timed out".to_string()),
+            }
+        });
+        let record = match lookup.await {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("Presence re-check for {} failed: {}", user_id, e);
+                return RepairOutcome::PresenceUnavailable;
+            }
+        };
+
+        let stale_gateway = stale.presence.as_ref().map(|presence| presence.gateway_id.as_str());
+        let record = match record {
+            Some(record) if record.status != PresenceStatus::Offline && Some(record.gateway_id.as_str()) != stale_gateway => record,
+            // Presence hasn't caught up with the dead gateway, or the user really left
+            _ => {
+                self.routes.invalidate(user_id);
+                return RepairOutcome::WentOffline;
+            }
+        };
+
+        self.routes.replace(
+            user_id,
+            UserRoute {
+                presence: Some(record.clone()),
+            },
+        );
+        let gateway_id = record.gateway_id.clone();
+        match redeliver(record).await {
+            Ok(()) => RepairOutcome::Recovered { gateway_id },
+            Err(kind) => RepairOutcome::RedeliveryFailed(kind),
+        }
+    }
+
+    /// Count one repair against the user's window; false once it's spent
+    fn admit(&self, user_id: &str) -> bool {
+        let now = self.clock.now_instant();
+        let mut windows = self.windows.lock();
+        let window = windows.get_or_insert_mut(user_id.to_string(), || RepairWindow {
+            started: now,
+            attempts: 0,
+        });
+        if now.saturating_duration_since(window.started) >= self.config.window {
+            window.started = now;
+            window.attempts = 0;
+        }
+        if window.attempts >= self.config.max_per_user {
+            return false;
+        }
+        window.attempts += 1;
+        true
+    }
+}
+
+/// Against a JetStream server at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored route_repair`
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use async_nats::jetstream::{self, kv};
+    use async_trait::async_trait;
+    use tokio::task::JoinSet;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        message::types::PresenceUpdate,
+        route_cache::{RouteLookupError, UserLookup, UserResolver},
+    };
+
+    struct NoUsers;
+
+    #[async_trait]
+    impl UserResolver for NoUsers {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            Ok(UserLookup::NotFound)
+        }
+    }
+
+    /// Resolves routes from presence, as the broker's resolver does
+    struct Directory {
+        presence: Arc<PresenceStore>,
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl UserResolver for Directory {
+        async fn resolve(&self, user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let presence = self.presence.lookup(user_id).await.unwrap();
+            Ok(UserLookup::Found(UserRoute { presence }))
+        }
+    }
+
+    /// Gateways that answer publishes while up, and what they delivered
+    #[derive(Default)]
+    struct Gateways {
+        up: Mutex<HashSet<String>>,
+        delivered: Mutex<Vec<(String, String)>>,
+    }
+
+    impl Gateways {
+        fn set_up(&self, gateways: &[&str]) {
+            *self.up.lock() = gateways.iter().map(|gateway| gateway.to_string()).collect();
+        }
+
+        fn publish(&self, gateway_id: &str, user_id: &str) -> Result<(), PublishErrorKind> {
+            if !self.up.lock().contains(gateway_id) {
+                return Err(PublishErrorKind::NoResponders);
+            }
+            self.delivered.lock().push((gateway_id.to_string(), user_id.to_string()));
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Delivery {
+        Direct(String),
+        Repaired(RepairOutcome),
+    }
+
+    struct World {
+        /// Presence as written through another broker, whose heartbeats
+        /// don't reach this broker's route cache
+        remote: Arc<PresenceStore>,
+        directory: Arc<Directory>,
+        routes: Arc<RouteCache>,
+        repair: RouteRepair,
+        gateways: Gateways,
+        kv: kv::Store,
+        clock: Arc<SimClock>,
+    }
+
+    impl World {
+        async fn new(configure: impl FnOnce(&mut RouteRepairConfig)) -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let client = async_nats::connect(url).await.unwrap();
+            let kv = jetstream::new(client)
+                .create_key_value(kv::Config {
+                    bucket: format!("repair-test-{}", Uuid::new_v4().simple()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let config = BrokerConfig::load().unwrap();
+            let mut repair_config = config.route_repair.clone();
+            configure(&mut repair_config);
+            let metrics = BrokerMetrics::new().unwrap();
+            let clock = Arc::new(SimClock::new());
+
+            let remote_routes = Arc::new(RouteCache::new(Arc::new(NoUsers), &config.routing, metrics.clone()));
+            let remote = Arc::new(
+                PresenceStore::new(kv.clone(), &config.routing, remote_routes, metrics.clone()).with_clock(clock.clone()),
+            );
+            let directory = Arc::new(Directory {
+                presence: remote.clone(),
+                reads: AtomicUsize::new(0),
+            });
+            let routes = Arc::new(
+                RouteCache::new(directory.clone(), &config.routing, metrics.clone()).with_clock(clock.clone()),
+            );
+            let local = Arc::new(
+                PresenceStore::new(kv.clone(), &config.routing, routes.clone(), metrics.clone()).with_clock(clock.clone()),
+            );
+            let repair = RouteRepair::new(routes.clone(), local, repair_config, metrics).with_clock(clock.clone());
+            Self {
+                remote,
+                directory,
+                routes,
+                repair,
+                gateways: Gateways::default(),
+                kv,
+                clock,
+            }
+        }
+
+        async fn connect(&self, user_id: &str, gateway_id: &str, status: PresenceStatus) {
+            let update = PresenceUpdate {
+                user_id: user_id.into(),
+                status,
+                device_id: "d".into(),
+                last_seen: self.clock.now_millis(),
+                platform: None,
+            };
+            self.remote.heartbeat(gateway_id, &update).await.unwrap();
+        }
+
+        /// One recipient of a fanout: the cached route, then a repair on no responders
+        async fn deliver(&self, user_id: &str) -> Delivery {
+            let route = self.routes.lookup(user_id).await.unwrap().unwrap();
+            let gateway_id = route.presence.as_ref().unwrap().gateway_id.clone();
+            match self.gateways.publish(&gateway_id, user_id) {
+                Ok(()) => Delivery::Direct(gateway_id),
+                Err(_) => {
+                    let redeliver = |record: PresenceRecord| async move { self.gateways.publish(&record.gateway_id, user_id) };
+                    Delivery::Repaired(self.repair.repair(user_id, &route, redeliver).await)
+                }
+            }
+        }
+
+        /// The route cached for the user, without resolving one
+        async fn cached_gateway(&self, user_id: &str) -> Option<String> {
+            let reads = self.directory.reads.load(Ordering::Relaxed);
+            let route = self.routes.lookup(user_id).await.unwrap();
+            (self.directory.reads.load(Ordering::Relaxed) == reads)
+                .then(|| route.and_then(|route| route.presence).map(|presence| presence.gateway_id))
+                .flatten()
+        }
+    }
+
+    fn recovered(gateway_id: &str) -> Delivery {
+        Delivery::Repaired(RepairOutcome::Recovered {
+            gateway_id: gateway_id.into(),
+        })
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_gateway_move_is_repaired_within_the_same_delivery() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new(|_| {}).await;
+        world.gateways.set_up(&["gw-1", "gw-2"]);
+        world.connect("alice", "gw-1", PresenceStatus::Online).await;
+        assert_eq!(world.deliver("alice").await, Delivery::Direct("gw-1".into()));
+
+        // Alice reconnects through gw-2 on another broker and gw-1 goes away
+        world.connect("alice", "gw-2", PresenceStatus::Online).await;
+        world.gateways.set_up(&["gw-2"]);
+        assert_eq!(world.cached_gateway("alice").await.as_deref(), Some("gw-1"));
+
+        assert_eq!(world.deliver("alice").await, recovered("gw-2"));
+        assert_eq!(
+            world.gateways.delivered.lock().as_slice(),
+            [("gw-1".to_string(), "alice".to_string()), ("gw-2".to_string(), "alice".to_string())]
+        );
+        assert_eq!(world.cached_gateway("alice").await.as_deref(), Some("gw-2"));
+        assert_eq!(world.deliver("alice").await, Delivery::Direct("gw-2".into()));
+
+        let rendered = recorder.handle().render();
+        let line = r#"broker_route_repairs_total{outcome="recovered"} 1"#;
+        assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn users_whose_presence_lags_or_who_left_go_offline() {
+        let world = World::new(|_| {}).await;
+        world.gateways.set_up(&["gw-1"]);
+        for user_id in ["bob", "carol", "dave"] {
+            world.connect(user_id, "gw-1", PresenceStatus::Online).await;
+            world.deliver(user_id).await;
+        }
+        world.gateways.set_up(&[]);
+
+        // Bob's presence still names the dead gateway; Carol went offline; Dave's record is gone
+        world.connect("carol", "gw-1", PresenceStatus::Offline).await;
+        world.kv.delete("presence.dave").await.unwrap();
+        for user_id in ["bob", "carol", "dave"] {
+            assert_eq!(world.deliver(user_id).await, Delivery::Repaired(RepairOutcome::WentOffline), "{}", user_id);
+            assert_eq!(world.cached_gateway(user_id).await, None, "{} kept a route", user_id);
+        }
+        assert_eq!(world.gateways.delivered.lock().len(), 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_redelivery_that_fails_too_is_reported() {
+        let world = World::new(|_| {}).await;
+        world.gateways.set_up(&["gw-1"]);
+        world.connect("alice", "gw-1", PresenceStatus::Online).await;
+        world.deliver("alice").await;
+        world.connect("alice", "gw-2", PresenceStatus::Online).await;
+        world.gateways.set_up(&[]);
+
+        let outcome = world.deliver("alice").await;
+        assert_eq!(outcome, Delivery::Repaired(RepairOutcome::RedeliveryFailed(PublishErrorKind::NoResponders)));
+        // The route is still the best known one
+        assert_eq!(world.cached_gateway("alice").await.as_deref(), Some("gw-2"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_burst_of_failures_shares_one_presence_read() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new(|config| config.max_per_user = 100).await;
+        world.gateways.set_up(&["gw-1"]);
+        world.connect("alice", "gw-1", PresenceStatus::Online).await;
+        let stale = world.routes.lookup("alice").await.unwrap().unwrap();
+        world.connect("alice", "gw-2", PresenceStatus::Online).await;
+        world.gateways.set_up(&["gw-2"]);
+
+        // Every message of a burst to the stale route fails and asks for a repair
+        let world = Arc::new(world);
+        let mut repairs = JoinSet::new();
+        for _ in 0..20 {
+            let (world, stale) = (world.clone(), stale.clone());
+            repairs.spawn(async move {
+                let gateways = &world.gateways;
+                let redeliver = |record: PresenceRecord| async move { gateways.publish(&record.gateway_id, "alice") };
+                world.repair.repair("alice", &stale, redeliver).await
+            });
+        }
+        while let Some(outcome) = repairs.join_next().await {
+            assert_eq!(outcome.unwrap(), RepairOutcome::Recovered { gateway_id: "gw-2".into() });
+        }
+        assert_eq!(world.gateways.delivered.lock().len(), 20);
+
+        // The rest joined the read in flight or reused its result
+        let rendered = recorder.handle().render();
+        for line in [
+            r#"broker_coalesced_requests_total{endpoint="route_repair",outcome="computed"} 1"#,
+            r#"broker_route_repairs_total{outcome="recovered"} 20"#,
+        ] {
+            assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn repairs_are_rate_limited_per_user_until_the_window_passes() {
+        let world = World::new(|config| {
+            config.max_per_user = 3;
+            config.window = Duration::from_secs(60);
+        })
+        .await;
+        world.connect("alice", "gw-1", PresenceStatus::Online).await;
+        world.connect("bob", "gw-1", PresenceStatus::Online).await;
+        let stale = |gateway_id: &str| UserRoute {
+            presence: Some(PresenceRecord {
+                gateway_id: gateway_id.into(),
+                status: PresenceStatus::Online,
+                last_seen: 0,
+            }),
+        };
+        let redeliver = |_: PresenceRecord| async { Ok(()) };
+
+        for _ in 0..3 {
+            assert!(matches!(world.repair.repair("alice", &stale("gw-0"), redeliver).await, RepairOutcome::Recovered { .. }));
+        }
+        assert_eq!(world.repair.repair("alice", &stale("gw-0"), redeliver).await, RepairOutcome::RateLimited);
+        assert!(matches!(world.repair.repair("bob", &stale("gw-0"), redeliver).await, RepairOutcome::Recovered { .. }));
+
+        world.clock.advance(Duration::from_secs(59));
+        assert_eq!(world.repair.repair("alice", &stale("gw-0"), redeliver).await, RepairOutcome::RateLimited);
+        world.clock.advance(Duration::from_secs(1));
+        world.connect("alice", "gw-1", PresenceStatus::Online).await;
+        assert!(matches!(world.repair.repair("alice", &stale("gw-0"), redeliver).await, RepairOutcome::Recovered { .. }));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_presence_outage_falls_back_to_offline_delivery() {
+        let world = World::new(|_| {}).await;
+        world.gateways.set_up(&["gw-1"]);
+        world.connect("alice", "gw-1", PresenceStatus::Online).await;
+        world.deliver("alice").await;
+        world.gateways.set_up(&[]);
+
+        let client = async_nats::connect(std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into()))
+            .await
+            .unwrap();
+        jetstream::new(client).delete_key_value(&world.kv.name).await.unwrap();
+        assert_eq!(world.deliver("alice").await, Delivery::Repaired(RepairOutcome::PresenceUnavailable));
+    }
+}
@@ -0,0 +1,621 @@
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+use async_nats::jetstream::kv;
+use chrono::Utc;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    background_quota::{BackgroundQuota, WorkerClass},
+    config::RouteWarmingConfig,
+    lock_metrics::{Mutex, NamedLock},
+    membership::MembershipCache,
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+    route_cache::RouteCache,
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+};
+
+const HOURS_PER_DAY: usize = 24;
+/// Seven days of history plus today, so today never overwrites the day a week ago
+const DAY_ROWS: usize = 8;
+const MS_PER_HOUR: i64 = 3_600_000;
+
+const ACTIVITY_CODEC: VersionedCodec<Vec<ActivityHistogram>> = VersionedCodec::new(schema::ROUTE_ACTIVITY, 1, &[]);
+const HOT_GROUPS_CODEC: VersionedCodec<Vec<String>> = VersionedCodec::new(schema::HOT_GROUPS, 1, &[]);
+
+/// Hourly routing counts of one shard over the last week
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActivityHistogram {
+    /// Day number (days since the epoch, UTC) each row holds
+    days: [i64; DAY_ROWS],
+    counts: [[u32; HOURS_PER_DAY]; DAY_ROWS],
+}
+
+impl ActivityHistogram {
+    pub fn add(&mut self, day: i64, hour: usize, count: u32) {
+        let row = day.rem_euclid(DAY_ROWS as i64) as usize;
+        if self.days[row] != day {
+            self.days[row] = day;
+            self.counts[row] = [0; HOURS_PER_DAY];
+        }
+        self.counts[row][hour] = self.counts[row][hour].saturating_add(count);
+    }
+
+    /// Count recorded for `day` at `hour`, zero if that day has rolled out
+    pub fn get(&self, day: i64, hour: usize) -> u32 {
+        let row = day.rem_euclid(DAY_ROWS as i64) as usize;
+        if self.days[row] == day {
+            self.counts[row][hour]
+        } else {
+            0
+        }
+    }
+
+    /// Same-hour average over the seven days before `day`
+    pub fn predict(&self, day: i64, hour: usize) -> f64 {
+        (1..DAY_ROWS as i64)
+            .map(|back| self.get(day - back, hour) as f64)
+            .sum::<f64>()
+            / (DAY_ROWS - 1) as f64
+    }
+
+    /// Average hourly count over the seven days before `day`
+    pub fn baseline(&self, day: i64) -> f64 {
+        (0..HOURS_PER_DAY).map(|hour| self.predict(day, hour)).sum::<f64>() / HOURS_PER_DAY as f64
+    }
+}
+
+/// Refreshes hot routes shortly before predicted activity spikes
+///
+/// Every routed user and group is counted into its shard's hourly
+/// histogram, persisted to KV under `activity.{broker_id}` along with the
+/// most routed groups under `hotgroups.{broker_id}`. A shard is hot
+/// for an hour when the same-hour average of the previous seven days is at
+/// least `threshold_factor` times its hourly baseline and at least
+/// `min_hourly_events`. `lead_time` before a hot hour starts, the most
+/// frequently routed users and groups of the hot shards are re-resolved
+/// into the route and membership caches. Refreshes are paced by the
+/// `route_warming` background quota class, so they only use capacity live
+/// traffic leaves over.
+pub struct RouteWarmer {
+    config: RouteWarmingConfig,
+    broker_id: String,
+    shard_count: usize,
+    /// Routing events per shard in the current hour, folded in every tick
+    current: Vec<AtomicU32>,
+    histograms: Mutex<Vec<ActivityHistogram>>,
+    users: Mutex<LruCache<String, u32>>,
+    groups: Mutex<LruCache<String, u32>>,
+    routes: Arc<RouteCache>,
+    memberships: Arc<MembershipCache>,
+    quota: Arc<BackgroundQuota>,
+    kv: kv::Store,
+    metrics: BrokerMetrics,
+}
+
+impl RouteWarmer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: RouteWarmingConfig,
+        broker_id: String,
+        shard_count: usize,
+        routes: Arc<RouteCache>,
+        memberships: Arc<MembershipCache>,
+        quota: Arc<BackgroundQuota>,
+        kv: kv::Store,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let shard_count = shard_count.max(1);
+        let tracked = NonZeroUsize::new(config.tracked_keys.max(1)).unwrap();
+        Self {
+            broker_id,
+            shard_count,
+            current: (0..shard_count).map(|_| AtomicU32::new(0)).collect(),
+            histograms: Mutex::named("route_warming.histograms", vec![ActivityHistogram::default(); shard_count]),
+            users: Mutex::named("route_warming.users", LruCache::new(tracked)),
+            groups: Mutex::named("route_warming.groups", LruCache::new(tracked)),
+            routes,
+            memberships,
+            quota,
+            kv,
+            metrics,
+            config,
+        }
+    }
+
+    /// Count a route lookup for a user
+    pub fn observe_user(&self, user_id: &str) {
+        if self.config.enabled {
+            self.observe(&self.users, user_id);
+        }
+    }
+
+    /// Count a membership lookup for a group
+    pub fn observe_group(&self, group_id: &str) {
+        if self.config.enabled {
+            self.observe(&self.groups, group_id);
+        }
+    }
+
+    fn observe(&self, keys: &Mutex<LruCache<String, u32>>, id: &str) {
+        self.current[shard_for(id, self.shard_count)].fetch_add(1, Ordering::Relaxed);
+        let mut keys = keys.lock();
+        match keys.get_mut(id) {
+            Some(count) => *count = count.saturating_add(1),
+            None => {
+                keys.put(id.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let warmer = Arc::clone(self);
+        Some(spawn_traced("route_warmer", TaskContext::new("route_warming"), async move {
+            if let Err(e) = warmer.load().await {
+                warn!("Starting route warming without history: {}", e);
+            }
+
+            let mut ticker = tokio::time::interval(warmer.config.tick_interval);
+            let mut hour = current_hour();
+            let mut warmed_for = None;
+            let mut last_persist = tokio::time::Instant::now();
+            loop {
+                ticker.tick().await;
+                let now = current_hour();
+                // Fold with the hour the events were counted in
+                warmer.fold(hour);
+                if now != hour {
+                    warmer.report_hour(hour);
+                    hour = now;
+                }
+
+                let target = (Utc::now().timestamp_millis() + warmer.config.lead_time.as_millis() as i64)
+                    .div_euclid(MS_PER_HOUR);
+                if target > now && warmed_for != Some(target) {
+                    warmed_for = Some(target);
+                    warmer.warm(target).await;
+                }
+
+                if last_persist.elapsed() >= warmer.config.persist_interval {
+                    last_persist = tokio::time::Instant::now();
+                    if let Err(e) = warmer.persist().await {
+                        warn!("Failed to persist route activity: {}", e);
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Shards predicted to spike during the absolute hour `target`
+    pub fn hot_shards(&self, target: i64) -> HashSet<usize> {
+        let (day, hour) = split_hour(target);
+        self.histograms
+            .lock()
+            .iter()
+            .enumerate()
+            .filter(|(_, histogram)| {
+                let predicted = histogram.predict(day, hour);
+                predicted >= self.config.min_hourly_events as f64
+                    && predicted >= self.config.threshold_factor * histogram.baseline(day)
+            })
+            .map(|(shard, _)| shard)
+            .collect()
+    }
+
+    /// Refresh the most routed users and groups of the shards hot at `target`
+    pub async fn warm(&self, target: i64) {
+        let hot = self.hot_shards(target);
+        if hot.is_empty() {
+            return;
+        }
+        let users = self.top(&self.users, &hot, self.config.top_users);
+        let groups = self.top(&self.groups, &hot, self.config.top_groups);
+        info!(
+            "Warming {} routes and {} groups for {} shards predicted hot",
+            users.len(),
+            groups.len(),
+            hot.len()
+        );
+
+        let mut remaining = (users.len() + groups.len()) as u64;
+        for user_id in &users {
+            self.quota.report_backlog(WorkerClass::RouteWarming, remaining);
+            self.quota.acquire(WorkerClass::RouteWarming, 1).await;
+            let outcome = match self.routes.refresh(user_id).await {
+                Ok(()) => "refreshed",
+                Err(e) => {
+                    debug!("Warming route for {} failed: {}", user_id, e);
+                    "failed"
+                }
+            };
+            self.metrics.record_route_warmed("user", outcome);
+            remaining -= 1;
+        }
+        for group_id in &groups {
+            self.quota.report_backlog(WorkerClass::RouteWarming, remaining);
+            self.quota.acquire(WorkerClass::RouteWarming, 1).await;
+            let outcome = match self.memberships.refresh(group_id).await {
+                Ok(()) => "refreshed",
+                Err(e) => {
+                    debug!("Warming membership of {} failed: {}", group_id, e);
+                    "failed"
+                }
+            };
+            self.metrics.record_route_warmed("group", outcome);
+            remaining -= 1;
+        }
+        self.quota.report_backlog(WorkerClass::RouteWarming, 0);
+    }
+
+    fn top(&self, keys: &Mutex<LruCache<String, u32>>, hot: &HashSet<usize>, limit: usize) -> Vec<String> {
+        self.top_by(keys, |id| hot.contains(&shard_for(id, self.shard_count)), limit)
+    }
+
+    fn top_by(&self, keys: &Mutex<LruCache<String, u32>>, keep: impl Fn(&str) -> bool, limit: usize) -> Vec<String> {
+        let mut candidates: Vec<(String, u32)> = keys
+            .lock()
+            .iter()
+            .filter(|(id, _)| keep(id))
+            .map(|(id, count)| (id.clone(), *count))
+            .collect();
+        candidates.sort_unstable_by_key(|candidate| std::cmp::Reverse(candidate.1));
+        candidates.truncate(limit);
+        candidates.into_iter().map(|(id, _)| id).collect()
+    }
+
+    /// Move the per-shard counters into the histograms under the absolute `hour`
+    fn fold(&self, hour: i64) {
+        let (day, hour_of_day) = split_hour(hour);
+        let mut histograms = self.histograms.lock();
+        for (shard, counter) in self.current.iter().enumerate() {
+            let count = counter.swap(0, Ordering::Relaxed);
+            if count > 0 {
+                histograms[shard].add(day, hour_of_day, count);
+            }
+        }
+    }
+
+    fn report_hour(&self, hour: i64) {
+        let (day, hour_of_day) = split_hour(hour);
+        let histograms = self.histograms.lock();
+        let predicted: f64 = histograms.iter().map(|h| h.predict(day, hour_of_day)).sum();
+        let actual: u64 = histograms.iter().map(|h| h.get(day, hour_of_day) as u64).sum();
+        self.metrics.update_route_activity(predicted, actual as f64);
+    }
+
+    async fn load(&self) -> Result<(), RouteWarmingError> {
+        let Some(value) = self
+            .kv
+            .get(activity_key(&self.broker_id))
+            .await
+            .map_err(|e| RouteWarmingError(e.to_string()))?
+        else {
+            return Ok(());
+        };
+        let mut stored = ACTIVITY_CODEC
+            .decode(&value)
+            .map_err(|e| RouteWarmingError(e.to_string()))?;
+        // A changed shard count makes the old layout meaningless
+        if stored.len() != self.shard_count {
+            return Err(RouteWarmingError(format!(
+                "stored activity has {} shards, expected {}",
+                stored.len(),
+                self.shard_count
+            )));
+        }
+        std::mem::swap(&mut *self.histograms.lock(), &mut stored);
+        Ok(())
+    }
+
+    async fn persist(&self) -> Result<(), RouteWarmingError> {
+        let value = ACTIVITY_CODEC
+            .encode(&self.histograms.lock())
+            .map_err(|e| RouteWarmingError(e.to_string()))?;
+        self.kv
+            .put(activity_key(&self.broker_id), value.into())
+            .await
+            .map_err(|e| RouteWarmingError(e.to_string()))?;
+
+        let groups = self.top_by(&self.groups, |_| true, self.config.persisted_groups);
+        let value = HOT_GROUPS_CODEC
+            .encode(&groups)
+            .map_err(|e| RouteWarmingError(e.to_string()))?;
+        self.kv
+            .put(hot_groups_key(&self.broker_id), value.into())
+            .await
+            .map_err(|e| RouteWarmingError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Most routed groups as of the last persist, hottest first
+    pub async fn persisted_hot_groups(&self) -> Result<Vec<String>, RouteWarmingError> {
+        let Some(value) = self
+            .kv
+            .get(hot_groups_key(&self.broker_id))
+            .await
+            .map_err(|e| RouteWarmingError(e.to_string()))?
+        else {
+            return Ok(Vec::new());
+        };
+        HOT_GROUPS_CODEC
+            .decode(&value)
+            .map_err(|e| RouteWarmingError(e.to_string()))
+    }
+}
+
+/// Hours since the epoch, UTC
+fn current_hour() -> i64 {
+    Utc::now().timestamp_millis().div_euclid(MS_PER_HOUR)
+}
+
+/// Absolute hour into (day number, hour of day)
+fn split_hour(hour: i64) -> (i64, usize) {
+    (
+        hour.div_euclid(HOURS_PER_DAY as i64),
+        hour.rem_euclid(HOURS_PER_DAY as i64) as usize,
+    )
+}
+
+fn activity_key(broker_id: &str) -> String {
+    format!("activity.{}", broker_id)
+}
+
+fn hot_groups_key(broker_id: &str) -> String {
+    format!("hotgroups.{}", broker_id)
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("route warming error: {0}")]
+pub struct RouteWarmingError(pub String);
+
+/// The warming tests need a KV bucket for activity history, from JetStream at
+/// `NATS_URL` (default `localhost:4222`): `cargo test -- --ignored route_warming`
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    use async_nats::jetstream;
+    use async_trait::async_trait;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::BrokerConfig,
+        membership::{MembershipResolver, ResolverError},
+        route_cache::{RouteLookupError, UserLookup, UserResolver, UserRoute},
+    };
+
+    const DAY: i64 = 20_000;
+    const SHARDS: usize = 16;
+    const HOUR: Duration = Duration::from_secs(3600);
+    const LEAD_TIME: Duration = Duration::from_secs(15 * 60);
+
+    #[test]
+    fn frozen_v1_activity_still_loads() {
+        let fixture: &[u8] = include_bytes!("../fixtures/persist/route_activity.v1.bin");
+        for bytes in [fixture, &fixture[5..]] {
+            let shards = ACTIVITY_CODEC.decode(bytes).unwrap();
+            assert_eq!(shards.len(), 2);
+            assert_eq!(shards[0].get(DAY + 1, 9), 10);
+            assert_eq!(shards[0].get(DAY + 7, 23), 4);
+            assert_eq!(shards[0].get(DAY + 1, 10), 0);
+            assert_eq!(shards[1].get(DAY + 1, 9), 0);
+        }
+
+        let fixture: &[u8] = include_bytes!("../fixtures/persist/hot_groups.v1.bin");
+        for bytes in [fixture, &fixture[5..]] {
+            assert_eq!(HOT_GROUPS_CODEC.decode(bytes).unwrap(), vec!["group_team", "group_ops"]);
+        }
+    }
+
+    #[test]
+    fn rows_roll_over_after_a_week() {
+        let mut histogram = ActivityHistogram::default();
+        histogram.add(DAY, 9, 10);
+        histogram.add(DAY, 9, 5);
+        assert_eq!(histogram.get(DAY, 9), 15);
+        assert_eq!(histogram.get(DAY, 10), 0);
+
+        // Same row eight days later: the old day is gone
+        histogram.add(DAY + 8, 9, 1);
+        assert_eq!(histogram.get(DAY, 9), 0);
+        assert_eq!(histogram.get(DAY + 8, 9), 1);
+    }
+
+    #[test]
+    fn prediction_is_the_same_hour_weekly_average() {
+        let mut histogram = ActivityHistogram::default();
+        for back in 1..=7 {
+            histogram.add(DAY - back, 9, 70 * back as u32);
+            histogram.add(DAY - back, 3, 7);
+        }
+        // Today's own count never feeds its prediction
+        histogram.add(DAY, 9, 1_000_000);
+
+        assert!((histogram.predict(DAY, 9) - 280.0).abs() < 1e-9);
+        assert!((histogram.predict(DAY, 3) - 7.0).abs() < 1e-9);
+        assert!((histogram.baseline(DAY) - (280.0 + 7.0) / 24.0).abs() < 1e-9);
+        // Eight days back is outside the window
+        assert_eq!(histogram.predict(DAY + 8, 9), 0.0);
+    }
+
+    #[test]
+    fn hours_split_into_day_and_hour() {
+        assert_eq!(split_hour(DAY * 24 + 9), (DAY, 9));
+        assert_eq!(split_hour(-1), (-1, 23));
+    }
+
+    #[derive(Default)]
+    struct Directory {
+        resolves: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl UserResolver for Directory {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            self.resolves.fetch_add(1, Ordering::SeqCst);
+            Ok(UserLookup::Found(UserRoute { presence: None }))
+        }
+    }
+
+    struct NoGroups;
+
+    #[async_trait]
+    impl MembershipResolver for NoGroups {
+        async fn resolve(&self, _group_id: &str) -> Result<Vec<String>, ResolverError> {
+            Ok(Vec::new())
+        }
+    }
+
+    struct Simulation {
+        warmer: RouteWarmer,
+        routes: Arc<RouteCache>,
+        directory: Arc<Directory>,
+        clock: Arc<SimClock>,
+    }
+
+    async fn bucket() -> kv::Store {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        jetstream::new(async_nats::connect(url).await.unwrap())
+            .create_key_value(kv::Config {
+                bucket: format!("route-warming-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+    }
+
+    fn simulation(kv: kv::Store, enabled: bool) -> Simulation {
+        let config = BrokerConfig::load().unwrap();
+        let mut routing = config.routing.clone();
+        routing.cache_size = 10_000;
+        routing.route_cache_ttl = 2 * HOUR;
+        let metrics = BrokerMetrics::new().unwrap();
+        let clock = Arc::new(SimClock::new());
+        let directory = Arc::new(Directory::default());
+        let routes = Arc::new(RouteCache::new(directory.clone(), &routing, metrics.clone()).with_clock(clock.clone()));
+        let memberships = Arc::new(MembershipCache::new(Arc::new(NoGroups), &routing, &config.limits, metrics.clone()));
+        let quota = Arc::new(BackgroundQuota::new(config.background_quota.clone(), 100_000, metrics.clone()));
+
+        let mut warming = config.route_warming;
+        warming.enabled = enabled;
+        warming.lead_time = LEAD_TIME;
+        warming.threshold_factor = 3.0;
+        warming.min_hourly_events = 10;
+        warming.tracked_keys = 5_000;
+        warming.top_users = 2_000;
+        Simulation {
+            warmer: RouteWarmer::new(warming, "broker-1".into(), SHARDS, routes.clone(), memberships, quota, kv, metrics),
+            routes,
+            directory,
+            clock,
+        }
+    }
+
+    /// Users routed during hour `hour` of any day: a trickle all day plus the 9am spike
+    fn active_users(hour: usize) -> Vec<String> {
+        let trickle = (0..2_000).filter(|i| (i + hour * 37).is_multiple_of(100));
+        let spike = (hour == 9).then_some(0..1_000).into_iter().flatten();
+        trickle.chain(spike).map(|i| format!("user-{}", i)).collect()
+    }
+
+    impl Simulation {
+        /// Route every active user of `hour`, returning the cache misses
+        async fn run_hour(&self, day: i64, hour: usize) -> usize {
+            let before = self.directory.resolves.load(Ordering::SeqCst);
+            for user_id in active_users(hour) {
+                self.warmer.observe_user(&user_id);
+                self.routes.lookup(&user_id).await.unwrap();
+            }
+            self.warmer.fold(day * 24 + hour as i64);
+            self.directory.resolves.load(Ordering::SeqCst) - before
+        }
+
+        /// A week of history, then today up to the spike; returns the spike's misses
+        async fn run(&self, warm: bool) -> usize {
+            for day in DAY - 7..DAY {
+                for hour in 0..HOURS_PER_DAY {
+                    self.run_hour(day, hour).await;
+                    self.clock.advance(HOUR);
+                }
+            }
+            for hour in 0..8 {
+                self.run_hour(DAY, hour).await;
+                self.clock.advance(HOUR);
+            }
+            self.run_hour(DAY, 8).await;
+
+            // The warmer fires `lead_time` before the spike hour starts
+            self.clock.advance(HOUR - LEAD_TIME);
+            if warm {
+                self.warmer.warm(DAY * 24 + 9).await;
+            }
+            self.clock.advance(LEAD_TIME);
+            self.run_hour(DAY, 9).await
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn warming_cuts_the_morning_spike_misses() {
+        let cold = simulation(bucket().await, false).run(false).await;
+
+        let simulation = simulation(bucket().await, true);
+        assert!(simulation.warmer.hot_shards(DAY * 24 + 9).is_empty());
+        let warm = simulation.run(true).await;
+
+        let spike = active_users(9).len();
+        // Without warming every spike route has expired overnight
+        assert!(cold * 10 >= spike * 9, "cold run missed only {} of {}", cold, spike);
+        assert!(warm * 10 <= cold, "warming left {} misses against {} cold", warm, cold);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn only_spiking_shards_are_hot() {
+        let simulation = simulation(bucket().await, true);
+        for day in DAY - 7..DAY {
+            for hour in 0..HOURS_PER_DAY {
+                simulation.run_hour(day, hour).await;
+            }
+        }
+
+        assert_eq!(simulation.warmer.hot_shards(DAY * 24 + 9).len(), SHARDS);
+        assert!(simulation.warmer.hot_shards(DAY * 24 + 3).is_empty());
+        // Disabled warming doesn't even count
+        let disabled = self::simulation(bucket().await, false);
+        disabled.warmer.observe_user("user-1");
+        assert!(disabled.warmer.current.iter().all(|count| count.load(Ordering::Relaxed) == 0));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn history_survives_a_restart() {
+        let kv = bucket().await;
+        let before = simulation(kv.clone(), true);
+        for day in DAY - 7..DAY {
+            before.run_hour(day, 9).await;
+        }
+        before.warmer.observe_group("group_standup");
+        before.warmer.persist().await.unwrap();
+
+        let after = simulation(kv, true);
+        after.warmer.load().await.unwrap();
+        assert_eq!(after.warmer.hot_shards(DAY * 24 + 9), before.warmer.hot_shards(DAY * 24 + 9));
+        assert_eq!(after.warmer.persisted_hot_groups().await.unwrap(), vec!["group_standup"]);
+    }
+}
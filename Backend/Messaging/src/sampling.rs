@@ -0,0 +1,374 @@
+use std::time::Duration;
+use async_nats::jetstream;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    config::SamplingConfig,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+    trace::{MessageTrace, TraceEvent},
+};
+
+/// Resolution of the per-message sampling hash
+const SAMPLE_BUCKETS: usize = 1_000_000;
+
+/// How sampled payload ciphertext is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadRedaction {
+    /// Replace with its SHA-256 hex digest
+    Hash,
+    /// Keep the first `truncate_bytes` bytes
+    Truncate,
+    Keep,
+}
+
+/// Why a message was sampled, in rule precedence order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleReason {
+    Tenant,
+    DeadLettered,
+    SlowProcessing,
+    BaseRate,
+}
+
+/// Processing outcome attached to a sample
+#[derive(Debug, Clone, Default)]
+pub struct SampleContext {
+    pub dead_lettered: bool,
+    pub processing_latency: Duration,
+    /// Routing decisions taken for the message (targets, fanout mode)
+    pub routing: Vec<String>,
+}
+
+/// Record published to the debug sample subject
+#[derive(Debug, Clone, Serialize)]
+pub struct SampleRecord {
+    pub reason: SampleReason,
+    pub envelope: MessageEnvelope,
+    pub routing: Vec<String>,
+    pub dead_lettered: bool,
+    pub processing_latency_ms: f64,
+    pub trace: Vec<TraceEvent>,
+    /// Timestamp in milliseconds
+    pub sampled_at: i64,
+}
+
+/// Samples processed envelopes into a debug stream for offline analysis
+///
+/// The base-rate check hashes the message ID, so every broker makes the
+/// same decision for a message. Records go through a bounded queue and are
+/// dropped rather than blocking the pipeline when the queue is full.
+pub struct EnvelopeSampler {
+    config: SamplingConfig,
+    threshold: usize,
+    queue: mpsc::Sender<SampleRecord>,
+    metrics: BrokerMetrics,
+}
+
+impl EnvelopeSampler {
+    /// Build the sampler and spawn its publisher task
+    pub fn start(config: SamplingConfig, jetstream: jetstream::Context, metrics: BrokerMetrics) -> Self {
+        let subject = config.subject.clone();
+        let (sampler, rx) = Self::new(config, metrics.clone());
+        spawn_traced("sample_publisher", TaskContext::new("sampling"), async move {
+            publish_samples(rx, jetstream, subject, metrics).await;
+        });
+        sampler
+    }
+
+    /// The sampler and the receiving end of its record queue
+    fn new(config: SamplingConfig, metrics: BrokerMetrics) -> (Self, mpsc::Receiver<SampleRecord>) {
+        let (queue, rx) = mpsc::channel(config.queue_size.max(1));
+        let sampler = Self {
+            threshold: (config.base_rate.clamp(0.0, 1.0) * SAMPLE_BUCKETS as f64) as usize,
+            config,
+            queue,
+            metrics,
+        };
+        (sampler, rx)
+    }
+
+    /// Sampling decision; always-sample rules take precedence over the base rate
+    pub fn decide(&self, envelope: &MessageEnvelope, context: &SampleContext) -> Option<SampleReason> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        if envelope
+            .tenant_id
+            .as_ref()
+            .is_some_and(|tenant| self.config.always_tenants.contains(tenant))
+        {
+            return Some(SampleReason::Tenant);
+        }
+        if self.config.always_dead_lettered && context.dead_lettered {
+            return Some(SampleReason::DeadLettered);
+        }
+        if self
+            .config
+            .latency_threshold_ms
+            .is_some_and(|ms| context.processing_latency >= Duration::from_millis(ms))
+        {
+            return Some(SampleReason::SlowProcessing);
+        }
+        if shard_for(&envelope.message_id, SAMPLE_BUCKETS) < self.threshold {
+            return Some(SampleReason::BaseRate);
+        }
+        None
+    }
+
+    /// Sample the message if selected, noting the decision in its trace
+    pub fn offer(&self, envelope: &MessageEnvelope, context: SampleContext, trace: &mut MessageTrace) {
+        let Some(reason) = self.decide(envelope, &context) else {
+            return;
+        };
+        trace.record("sampling", format!("sampled ({:?})", reason));
+
+        let mut envelope = envelope.clone();
+        envelope.payload.ciphertext = self.redact(&envelope.payload.ciphertext);
+
+        let record = SampleRecord {
+            reason,
+            envelope,
+            routing: context.routing,
+            dead_lettered: context.dead_lettered,
+            processing_latency_ms: context.processing_latency.as_secs_f64() * 1000.0,
+            trace: trace.events.clone(),
+            sampled_at: Utc::now().timestamp_millis(),
+        };
+
+        match self.queue.try_send(record) {
+            Ok(()) => self.metrics.record_envelope_sample("queued"),
+            Err(_) => self.metrics.record_envelope_sample("dropped"),
+        }
+    }
+
+    pub fn redact(&self, ciphertext: &str) -> String {
+        match self.config.redaction {
+            PayloadRedaction::Keep => ciphertext.to_string(),
+            PayloadRedaction::Truncate => {
+                let mut end = self.config.truncate_bytes.min(ciphertext.len());
+                while !ciphertext.is_char_boundary(end) {
+                    end -= 1;
+                }
+                ciphertext[..end].to_string()
+            }
+            PayloadRedaction::Hash => {
+                let digest = ring::digest::digest(&ring::digest::SHA256, ciphertext.as_bytes());
+                format!("sha256:{}", hex::encode(digest.as_ref()))
+            }
+        }
+    }
+}
+
+async fn publish_samples(
+    mut rx: mpsc::Receiver<SampleRecord>,
+    jetstream: jetstream::Context,
+    subject: String,
+    metrics: BrokerMetrics,
+) {
+    while let Some(record) = rx.recv().await {
+        let payload = match serde_json::to_vec(&record) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to encode sample record: {}", e);
+                continue;
+            }
+        };
+        match jetstream.publish(subject.clone(), payload.into()).await {
+            Ok(_) => metrics.record_envelope_sample("published"),
+            Err(e) => {
+                warn!("Failed to publish sample record: {}", e);
+                metrics.record_envelope_sample("publish_failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::types::{EncryptedPayload, MessageType};
+
+    fn config() -> SamplingConfig {
+        SamplingConfig {
+            enabled: true,
+            base_rate: 0.001,
+            subject: "broker.debug.samples".into(),
+            always_tenants: vec!["acme".into()],
+            always_dead_lettered: true,
+            latency_threshold_ms: Some(500),
+            redaction: PayloadRedaction::Hash,
+            truncate_bytes: 4,
+            queue_size: 16,
+        }
+    }
+
+    fn sampler(config: SamplingConfig) -> (EnvelopeSampler, mpsc::Receiver<SampleRecord>) {
+        EnvelopeSampler::new(config, BrokerMetrics::new().unwrap())
+    }
+
+    fn envelope(tenant_id: Option<&str>) -> MessageEnvelope {
+        let mut envelope = MessageEnvelope::new(
+            MessageType::TextMessage,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: "aGVsbG8gd29ybGQ=".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        );
+        envelope.tenant_id = tenant_id.map(str::to_string);
+        envelope
+    }
+
+    #[test]
+    fn base_rate_holds_over_a_large_run() {
+        let (sampler, _rx) = sampler(config());
+        let context = SampleContext::default();
+        let runs = 1_000_000;
+        let sampled = (0..runs)
+            .filter(|_| sampler.decide(&envelope(None), &context) == Some(SampleReason::BaseRate))
+            .count();
+
+        // Expected 1000 with a standard deviation of about 32; allow five
+        assert!((840..=1_160).contains(&sampled), "sampled {} of {}", sampled, runs);
+    }
+
+    #[test]
+    fn decisions_are_stable_per_message() {
+        let (sampler, _rx) = sampler(SamplingConfig {
+            base_rate: 0.5,
+            ..config()
+        });
+        let context = SampleContext::default();
+        for _ in 0..100 {
+            let envelope = envelope(None);
+            assert_eq!(sampler.decide(&envelope, &context), sampler.decide(&envelope, &context));
+        }
+    }
+
+    #[test]
+    fn always_sample_rules_take_precedence_in_order() {
+        let (sampler, _rx) = sampler(SamplingConfig {
+            base_rate: 1.0,
+            ..config()
+        });
+        let slow_dead_letter = SampleContext {
+            dead_lettered: true,
+            processing_latency: Duration::from_secs(1),
+            routing: Vec::new(),
+        };
+        let slow = SampleContext {
+            dead_lettered: false,
+            ..slow_dead_letter.clone()
+        };
+
+        assert_eq!(sampler.decide(&envelope(Some("acme")), &slow_dead_letter), Some(SampleReason::Tenant));
+        assert_eq!(sampler.decide(&envelope(Some("globex")), &slow_dead_letter), Some(SampleReason::DeadLettered));
+        assert_eq!(sampler.decide(&envelope(None), &slow), Some(SampleReason::SlowProcessing));
+        assert_eq!(sampler.decide(&envelope(None), &SampleContext::default()), Some(SampleReason::BaseRate));
+    }
+
+    #[test]
+    fn always_sample_rules_apply_at_a_zero_base_rate() {
+        let (sampler, _rx) = sampler(SamplingConfig {
+            base_rate: 0.0,
+            ..config()
+        });
+        let at_threshold = SampleContext {
+            processing_latency: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let under_threshold = SampleContext {
+            processing_latency: Duration::from_millis(499),
+            ..Default::default()
+        };
+
+        assert_eq!(sampler.decide(&envelope(Some("acme")), &SampleContext::default()), Some(SampleReason::Tenant));
+        assert_eq!(sampler.decide(&envelope(None), &at_threshold), Some(SampleReason::SlowProcessing));
+        assert_eq!(sampler.decide(&envelope(None), &under_threshold), None);
+    }
+
+    #[test]
+    fn disabled_samples_nothing() {
+        let (sampler, _rx) = sampler(SamplingConfig {
+            enabled: false,
+            ..config()
+        });
+        assert_eq!(sampler.decide(&envelope(Some("acme")), &SampleContext::default()), None);
+    }
+
+    #[test]
+    fn redaction_modes() {
+        let redact = |redaction, ciphertext| {
+            sampler(SamplingConfig {
+                redaction,
+                ..config()
+            })
+            .0
+            .redact(ciphertext)
+        };
+
+        assert_eq!(redact(PayloadRedaction::Keep, "aGVsbG8="), "aGVsbG8=");
+        assert_eq!(redact(PayloadRedaction::Truncate, "aGVsbG8="), "aGVs");
+        assert_eq!(redact(PayloadRedaction::Truncate, "aG"), "aG");
+        // Never splits a multi-byte character
+        assert_eq!(redact(PayloadRedaction::Truncate, "aGVé"), "aGV");
+        assert_eq!(
+            redact(PayloadRedaction::Hash, "hello"),
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn offered_samples_are_redacted_and_traced() {
+        let (sampler, mut rx) = sampler(config());
+        let envelope = envelope(Some("acme"));
+        let mut trace = MessageTrace::new(envelope.message_id.clone());
+        let context = SampleContext {
+            routing: vec!["user:bob".into()],
+            ..Default::default()
+        };
+
+        sampler.offer(&envelope, context, &mut trace);
+
+        let record = rx.try_recv().unwrap();
+        assert_eq!(record.reason, SampleReason::Tenant);
+        assert!(record.envelope.payload.ciphertext.starts_with("sha256:"));
+        assert_eq!(record.routing, vec!["user:bob".to_string()]);
+        assert_eq!(trace.events.last().unwrap().stage, "sampling");
+        assert_eq!(record.trace.len(), trace.events.len());
+        // The caller's envelope is untouched
+        assert_eq!(envelope.payload.ciphertext, "aGVsbG8gd29ybGQ=");
+    }
+
+    #[test]
+    fn a_full_queue_drops_instead_of_blocking() {
+        let (sampler, mut rx) = sampler(SamplingConfig {
+            queue_size: 2,
+            ..config()
+        });
+        for _ in 0..5 {
+            let envelope = envelope(Some("acme"));
+            let mut trace = MessageTrace::new(envelope.message_id.clone());
+            sampler.offer(&envelope, SampleContext::default(), &mut trace);
+        }
+
+        let mut queued = 0;
+        while rx.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, 2);
+    }
+}
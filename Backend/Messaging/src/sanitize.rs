@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{
+    config::SanitizeConfig,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Appended to values cut at their codepoint limit
+pub const TRUNCATION_MARKER: char = '\u{2026}';
+
+/// What was done to a metadata value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeAction {
+    ControlStripped,
+    BidiNeutralized,
+    Normalized,
+    Truncated,
+}
+
+impl SanitizeAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SanitizeAction::ControlStripped => "control_stripped",
+            SanitizeAction::BidiNeutralized => "bidi_neutralized",
+            SanitizeAction::Normalized => "normalized",
+            SanitizeAction::Truncated => "truncated",
+        }
+    }
+}
+
+/// Cleans user-visible metadata fields at ingress
+///
+/// Only the metadata keys listed in `sanitize.fields` are touched; payload
+/// bodies are end-to-end encrypted and never inspected. Each value is NFC
+/// normalized, stripped of control characters (including the ESC that
+/// starts ANSI sequences, and the Unicode line separators) and of bidi embedding, override and isolate
+/// codepoints, then cut to the field's codepoint limit with a trailing
+/// `…`. The raw value is replaced in place and not kept anywhere.
+pub struct MetadataSanitizer {
+    /// Metadata key to maximum codepoints
+    fields: HashMap<String, usize>,
+    metrics: BrokerMetrics,
+}
+
+impl MetadataSanitizer {
+    pub fn new(config: &SanitizeConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            fields: config
+                .fields
+                .iter()
+                .map(|field| (field.key.clone(), field.max_codepoints.max(1)))
+                .collect(),
+            metrics,
+        }
+    }
+
+    pub fn sanitize(&self, envelope: &mut MessageEnvelope) {
+        for (key, value) in envelope.metadata.iter_mut() {
+            let Some(&max_codepoints) = self.fields.get(key) else {
+                continue;
+            };
+            let (clean, actions) = sanitize_value(value, max_codepoints);
+            if actions.is_empty() {
+                continue;
+            }
+            for action in actions {
+                self.metrics.record_metadata_sanitized(key, action.as_str());
+            }
+            *value = clean;
+        }
+    }
+}
+
+/// Sanitized copy of `value` and the actions applied, in order
+pub fn sanitize_value(value: &str, max_codepoints: usize) -> (String, Vec<SanitizeAction>) {
+    let mut actions = Vec::new();
+
+    let normalized: String = value.nfc().collect();
+    if normalized != value {
+        actions.push(SanitizeAction::Normalized);
+    }
+
+    let mut clean = String::with_capacity(normalized.len());
+    let (mut stripped_control, mut stripped_bidi) = (false, false);
+    for c in normalized.chars() {
+        if is_control(c) {
+            stripped_control = true;
+        } else if is_bidi_control(c) {
+            stripped_bidi = true;
+        } else {
+            clean.push(c);
+        }
+    }
+    if stripped_control {
+        actions.push(SanitizeAction::ControlStripped);
+    }
+    if stripped_bidi {
+        actions.push(SanitizeAction::BidiNeutralized);
+    }
+
+    // Keep `max - 1` codepoints plus the marker; cutting at a char index keeps valid UTF-8
+    if clean.chars().count() > max_codepoints {
+        let keep = clean
+            .char_indices()
+            .nth(max_codepoints - 1)
+            .map_or(0, |(index, _)| index);
+        clean.truncate(keep);
+        clean.push(TRUNCATION_MARKER);
+        actions.push(SanitizeAction::Truncated);
+    }
+
+    (clean, actions)
+}
+
+/// C0/C1 controls plus the line and paragraph separators, which break log lines just the same
+fn is_control(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{2028}' | '\u{2029}')
+}
+
+/// Codepoints that reorder surrounding text: LRM/RLM/ALM, embeddings,
+/// overrides and isolates
+fn is_bidi_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{SanitizedField, SanitizeConfig},
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    /// Strings that have broken terminals, log pipelines or UIs
+    const NASTY: &[&str] = &[
+        "\u{1b}[31mred\u{1b}[0m",
+        "\u{1b}]0;pwned\u{7}",
+        "\u{1b}[2J\u{1b}[H",
+        "admin\u{202E}gpj.exe",
+        "\u{2066}isolated\u{2069}",
+        "\u{200F}\u{061C}marks\u{200E}",
+        "nul\u{0}byte",
+        "line\r\nbreak",
+        "tab\tbed",
+        "del\u{7f}ete",
+        "c1\u{85}\u{9b}31m",
+        "sep\u{2028}ara\u{2029}tor",
+        "e\u{301}\u{301}\u{301}",
+        "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}",
+        "Z\u{351}\u{36b}\u{343}\u{36a}\u{302}a\u{357}\u{30a}l\u{334}g\u{35e}o",
+        "\u{feff}bom",
+        "𝕳𝖊𝖑𝖑𝖔",
+    ];
+
+    fn is_clean(value: &str) -> bool {
+        value.chars().all(|c| !is_control(c) && !is_bidi_control(c))
+    }
+
+    fn sanitizer() -> MetadataSanitizer {
+        MetadataSanitizer::new(&SanitizeConfig::default(), BrokerMetrics::new().unwrap())
+    }
+
+    fn envelope(metadata: &[(&str, &str)]) -> MessageEnvelope {
+        let mut envelope = MessageEnvelope::new(
+            MessageType::TextMessage,
+            "alice".into(),
+            vec!["bob".into()],
+            EncryptedPayload {
+                ciphertext: "\u{1b}[31m\u{202E}".into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        );
+        for (key, value) in metadata {
+            envelope.metadata.insert(key.to_string(), value.to_string());
+        }
+        envelope
+    }
+
+    #[test]
+    fn the_nasty_corpus_comes_out_clean() {
+        for nasty in NASTY {
+            let (clean, _) = sanitize_value(nasty, 64);
+            assert!(is_clean(&clean), "{:?} -> {:?}", nasty, clean);
+            assert_eq!(clean, clean.nfc().collect::<String>(), "{:?}", nasty);
+            // Sanitizing twice changes nothing
+            assert_eq!(sanitize_value(&clean, 64), (clean.clone(), Vec::new()));
+        }
+    }
+
+    #[test]
+    fn logs_and_admin_json_stay_clean() {
+        let sanitizer = sanitizer();
+        for nasty in NASTY {
+            let mut envelope = envelope(&[("display_name", nasty), ("conversation_title", nasty)]);
+            sanitizer.sanitize(&mut envelope);
+
+            let logged = format!("{} from {}", envelope.metadata["display_name"], envelope.from);
+            assert!(is_clean(&logged), "{:?}", logged);
+            let json = serde_json::to_string(&envelope.metadata).unwrap();
+            assert!(!json.contains("\\u00") && !json.contains('\u{1b}'), "{}", json);
+            assert!(is_clean(&json), "{}", json);
+        }
+    }
+
+    #[test]
+    fn actions_are_reported_per_kind() {
+        assert_eq!(sanitize_value("plain", 64), ("plain".into(), Vec::new()));
+        assert_eq!(
+            sanitize_value("\u{1b}[1mhi", 64),
+            ("[1mhi".into(), vec![SanitizeAction::ControlStripped])
+        );
+        assert_eq!(
+            sanitize_value("a\u{202E}b", 64),
+            ("ab".into(), vec![SanitizeAction::BidiNeutralized])
+        );
+        assert_eq!(
+            sanitize_value("e\u{301}", 64),
+            ("\u{e9}".into(), vec![SanitizeAction::Normalized])
+        );
+        assert_eq!(sanitize_value("abcdef", 4), ("abc\u{2026}".into(), vec![SanitizeAction::Truncated]));
+    }
+
+    #[test]
+    fn truncation_keeps_utf8_boundaries() {
+        let long = "ü🦀語e\u{301}".repeat(4_000);
+        for limit in [1, 2, 3, 5, 63, 64, 65, 1000] {
+            let (clean, actions) = sanitize_value(&long, limit);
+            assert!(actions.contains(&SanitizeAction::Truncated));
+            assert_eq!(clean.chars().count(), limit, "{}", limit);
+            assert!(clean.ends_with(TRUNCATION_MARKER));
+            // Round-trips through bytes, so no codepoint was cut in half
+            assert_eq!(String::from_utf8(clean.clone().into_bytes()).unwrap(), clean);
+        }
+
+        let (exact, actions) = sanitize_value("🦀🦀🦀", 3);
+        assert_eq!(exact, "🦀🦀🦀");
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn only_listed_metadata_is_touched() {
+        let config = SanitizeConfig {
+            fields: vec![SanitizedField {
+                key: "display_name".into(),
+                max_codepoints: 0,
+            }],
+        };
+        let sanitizer = MetadataSanitizer::new(&config, BrokerMetrics::new().unwrap());
+        let mut envelope = envelope(&[("display_name", "\u{1b}[31mMallory"), ("trace", "\u{1b}raw")]);
+        sanitizer.sanitize(&mut envelope);
+
+        // A zero limit still keeps room for the marker
+        assert_eq!(envelope.metadata["display_name"], TRUNCATION_MARKER.to_string());
+        assert_eq!(envelope.metadata["trace"], "\u{1b}raw");
+        // Payloads are encrypted and never inspected
+        assert_eq!(envelope.payload.ciphertext, "\u{1b}[31m\u{202E}");
+    }
+}
@@ -0,0 +1,346 @@
+use std::{ops::Range, sync::Arc};
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::DashMap;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    config::RoutingConfig,
+    conversation::ConversationStateStore,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// CAS attempts per block claim
+const MAX_CAS_ATTEMPTS: usize = 5;
+
+#[derive(Default)]
+struct ConversationBlocks {
+    /// Next sequence to hand out
+    next: u64,
+    /// Exclusive end of the block being handed out
+    end: u64,
+    /// Block claimed ahead of exhaustion
+    prefetched: Option<Range<u64>>,
+    prefetching: bool,
+}
+
+/// Per-conversation ordering sequence numbers, durable across failover
+///
+/// Sequences start at 1 and are claimed from a KV high-water mark under
+/// `seq.{base64url(conversation_id)}` in blocks of
+/// `routing.sequence_block_size`; numbers are then handed out from memory.
+/// The next block is claimed in the background once the current one runs
+/// low. A broker taking over a conversation claims past the high-water mark,
+/// so sequences never regress, but the unused rest of the previous owner's
+/// block (and of any block dropped on eviction) is skipped: gaps of up to
+/// one block per failover are expected. Ranges from `allocate` are
+/// contiguous.
+pub struct SequenceAllocator {
+    kv: kv::Store,
+    conversations: DashMap<String, Arc<Mutex<ConversationBlocks>>>,
+    block_size: u64,
+    /// Remaining numbers at which the next block is prefetched
+    prefetch_at: u64,
+    metrics: BrokerMetrics,
+}
+
+impl SequenceAllocator {
+    pub fn new(kv: kv::Store, routing: &RoutingConfig, metrics: BrokerMetrics) -> Self {
+        let block_size = routing.sequence_block_size.max(1);
+        Self {
+            kv,
+            conversations: DashMap::new(),
+            block_size,
+            prefetch_at: (block_size as f64 * routing.sequence_prefetch_fraction) as u64,
+            metrics,
+        }
+    }
+
+    /// Allocate `count` contiguous sequence numbers for the conversation
+    pub async fn allocate(self: &Arc<Self>, conversation_id: &str, count: u64) -> Result<Range<u64>, SequenceError> {
+        let state = self.state(conversation_id);
+        let mut blocks = state.lock().await;
+
+        while blocks.end - blocks.next < count {
+            let block = match blocks.prefetched.take() {
+                Some(block) => block,
+                None => self.claim(conversation_id, self.block_size.max(count)).await?,
+            };
+            if block.start == blocks.end {
+                blocks.end = block.end;
+            } else {
+                // Another broker claimed in between; the rest of our block is a gap
+                let skipped = blocks.end - blocks.next;
+                if skipped > 0 {
+                    self.metrics.record_sequence_gap(skipped);
+                }
+                blocks.next = block.start;
+                blocks.end = block.end;
+            }
+        }
+
+        let range = blocks.next..blocks.next + count;
+        blocks.next += count;
+
+        if blocks.end - blocks.next <= self.prefetch_at && blocks.prefetched.is_none() && !blocks.prefetching {
+            blocks.prefetching = true;
+            self.spawn_prefetch(conversation_id.to_string(), Arc::clone(&state));
+        }
+        Ok(range)
+    }
+
+    pub async fn next(self: &Arc<Self>, conversation_id: &str) -> Result<u64, SequenceError> {
+        Ok(self.allocate(conversation_id, 1).await?.start)
+    }
+
+    /// Claim the conversation's first block ahead of its first allocation
+    ///
+    /// The block is held as the prefetched block, so `current` still reports
+    /// nothing handed out. Returns false when the conversation already has
+    /// state here and nothing was claimed.
+    pub async fn warm(&self, conversation_id: &str) -> Result<bool, SequenceError> {
+        let state = self.state(conversation_id);
+        let mut blocks = state.lock().await;
+        if blocks.end > 0 || blocks.prefetched.is_some() || blocks.prefetching {
+            return Ok(false);
+        }
+        blocks.prefetched = Some(self.claim(conversation_id, self.block_size).await?);
+        Ok(true)
+    }
+
+    pub fn has_state(&self, conversation_id: &str) -> bool {
+        self.conversations.contains_key(conversation_id)
+    }
+
+    /// Last sequence handed out by this broker, if any
+    pub async fn current(&self, conversation_id: &str) -> Option<u64> {
+        let state = self.conversations.get(conversation_id).map(|s| Arc::clone(s.value()))?;
+        let blocks = state.lock().await;
+        (blocks.next > 0).then(|| blocks.next - 1)
+    }
+
+    fn state(&self, conversation_id: &str) -> Arc<Mutex<ConversationBlocks>> {
+        if let Some(state) = self.conversations.get(conversation_id) {
+            return Arc::clone(state.value());
+        }
+        Arc::clone(
+            self.conversations
+                .entry(conversation_id.to_string())
+                .or_default()
+                .value(),
+        )
+    }
+
+    fn spawn_prefetch(self: &Arc<Self>, conversation_id: String, state: Arc<Mutex<ConversationBlocks>>) {
+        let allocator = Arc::clone(self);
+        spawn_traced("sequence_prefetch", TaskContext::new("sequence"), async move {
+            let block = allocator.claim(&conversation_id, allocator.block_size).await;
+            let mut blocks = state.lock().await;
+            blocks.prefetching = false;
+            match block {
+                Ok(block) => blocks.prefetched = Some(block),
+                Err(e) => warn!("Sequence block prefetch for {} failed: {}", conversation_id, e),
+            }
+        });
+    }
+
+    /// Advance the KV high-water mark by `size`, returning the claimed block
+    async fn claim(&self, conversation_id: &str, size: u64) -> Result<Range<u64>, SequenceError> {
+        let key = sequence_key(conversation_id);
+
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let entry = self
+                .kv
+                .entry(&key)
+                .await
+                .map_err(|e| SequenceError(e.to_string()))?;
+
+            let (claimed, written) = match entry {
+                Some(entry) if entry.operation == kv::Operation::Put => {
+                    let high = parse_high_water(&entry.value)
+                        .ok_or_else(|| SequenceError(format!("corrupt high-water mark at {}", key)))?;
+                    let value = (high + size).to_string();
+                    let written = self.kv.update(&key, value.into(), entry.revision).await.is_ok();
+                    (high, written)
+                }
+                _ => (0, self.kv.create(&key, size.to_string().into()).await.is_ok()),
+            };
+
+            if written {
+                self.metrics.record_sequence_block_claimed();
+                return Ok(claimed + 1..claimed + size + 1);
+            }
+            // Another broker claimed a block first; re-read and claim after it
+            debug!("Sequence block CAS conflict on {}", key);
+        }
+
+        Err(SequenceError(format!(
+            "gave up claiming a block on {} after {} CAS attempts",
+            key, MAX_CAS_ATTEMPTS
+        )))
+    }
+}
+
+impl ConversationStateStore for SequenceAllocator {
+    fn name(&self) -> &'static str {
+        "sequence"
+    }
+
+    fn evict(&self, conversation_id: &str) -> bool {
+        // The KV high-water mark stays; the next allocation claims a fresh block
+        self.conversations.remove(conversation_id).is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.conversations.len()
+    }
+}
+
+pub(crate) fn sequence_key(conversation_id: &str) -> String {
+    format!("seq.{}", URL_SAFE_NO_PAD.encode(conversation_id))
+}
+
+fn parse_high_water(value: &[u8]) -> Option<u64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("sequence allocation failed: {0}")]
+pub struct SequenceError(pub String);
+
+/// The failover tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+/// `cargo test -- --ignored sequence`
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    const BLOCK: u64 = 100;
+    const CONVERSATION: &str = "dm:alice:bob";
+
+    #[test]
+    fn high_water_marks_parse_as_decimal() {
+        assert_eq!(parse_high_water(b"4000"), Some(4000));
+        assert_eq!(parse_high_water(b""), None);
+        assert_eq!(parse_high_water(b"-1"), None);
+        assert_eq!(parse_high_water(&[0xff]), None);
+    }
+
+    #[test]
+    fn conversation_ids_stay_a_single_key_token() {
+        let key = sequence_key("dm:alice.smith:bob");
+        assert!(key.starts_with("seq."));
+        assert_eq!(key.matches('.').count(), 1);
+    }
+
+    async fn bucket() -> kv::Store {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        jetstream::new(async_nats::connect(url).await.unwrap())
+            .create_key_value(kv::Config {
+                bucket: format!("sequence-test-{}", Uuid::new_v4().simple()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+    }
+
+    /// A broker process; a new one over the same bucket is the failover owner
+    fn allocator(kv: &kv::Store) -> Arc<SequenceAllocator> {
+        let mut routing = BrokerConfig::load().unwrap().routing;
+        routing.sequence_block_size = BLOCK;
+        routing.sequence_prefetch_fraction = 0.2;
+        Arc::new(SequenceAllocator::new(kv.clone(), &routing, BrokerMetrics::new().unwrap()))
+    }
+
+    async fn take(allocator: &Arc<SequenceAllocator>, count: usize) -> Vec<u64> {
+        let mut sequences = Vec::with_capacity(count);
+        for _ in 0..count {
+            sequences.push(allocator.next(CONVERSATION).await.unwrap());
+        }
+        sequences
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_restart_mid_block_continues_after_the_block() {
+        let kv = bucket().await;
+        let first = allocator(&kv);
+        assert_eq!(take(&first, 50).await, (1..=50).collect::<Vec<_>>());
+        assert_eq!(first.current(CONVERSATION).await, Some(50));
+        drop(first);
+
+        let second = allocator(&kv);
+        assert_eq!(second.current(CONVERSATION).await, None);
+        assert_eq!(second.next(CONVERSATION).await.unwrap(), BLOCK + 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn sequences_never_regress_across_repeated_failovers() {
+        let kv = bucket().await;
+        let mut handed_out = Vec::new();
+        let mut failover_gaps = Vec::new();
+
+        // Crash points before, at and past the prefetch threshold and block end
+        for crash_after in [10, 79, 85, 100, 150, 1] {
+            let owner = allocator(&kv);
+            let sequences = take(&owner, crash_after).await;
+            if let (Some(last), Some(first)) = (handed_out.last(), sequences.first()) {
+                failover_gaps.push(first - last - 1);
+            }
+            handed_out.extend(sequences);
+            drop(owner);
+        }
+
+        assert!(handed_out.windows(2).all(|pair| pair[0] < pair[1]));
+        // The unused rest of the current block, plus a block prefetched just before the crash
+        assert!(failover_gaps.iter().all(|gap| *gap < 2 * BLOCK), "{:?}", failover_gaps);
+        // Within one owner's lifetime there are no gaps at all
+        let gaps: u64 = handed_out.windows(2).map(|pair| pair[1] - pair[0] - 1).sum();
+        assert_eq!(gaps, failover_gaps.iter().sum::<u64>());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn prefetching_keeps_one_owner_gapless() {
+        let kv = bucket().await;
+        let owner = allocator(&kv);
+        let sequences = take(&owner, 5 * BLOCK as usize).await;
+        assert_eq!(sequences, (1..=5 * BLOCK).collect::<Vec<_>>());
+
+        let range = owner.allocate(CONVERSATION, 3 * BLOCK).await.unwrap();
+        assert_eq!(range.end - range.start, 3 * BLOCK);
+        assert!(range.start > 5 * BLOCK);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn an_evicted_conversation_claims_past_the_new_owner() {
+        let kv = bucket().await;
+        let old = allocator(&kv);
+        let new = allocator(&kv);
+        assert_eq!(take(&old, 30).await.last(), Some(&30));
+
+        // Ownership moves: the old owner drops its state, the new one claims the next block
+        assert!(old.evict(CONVERSATION));
+        assert_eq!(new.next(CONVERSATION).await.unwrap(), BLOCK + 1);
+        assert!(new.evict(CONVERSATION));
+        assert_eq!(old.next(CONVERSATION).await.unwrap(), 2 * BLOCK + 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn warming_claims_without_handing_out() {
+        let kv = bucket().await;
+        let owner = allocator(&kv);
+        assert!(owner.warm(CONVERSATION).await.unwrap());
+        assert!(!owner.warm(CONVERSATION).await.unwrap());
+        assert_eq!(owner.current(CONVERSATION).await, None);
+        assert_eq!(owner.next(CONVERSATION).await.unwrap(), 1);
+    }
+}
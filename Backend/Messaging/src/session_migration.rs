@@ -0,0 +1,834 @@
+use std::{sync::Arc, time::Duration};
+use async_nats::{jetstream::kv, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backoff::{retry_with_clock, BackoffPolicy, Jitter, RetryBudget},
+    clock::{SharedClock, SystemClock},
+    config::SessionMigrationConfig,
+    delivery_id::DeliveryStamp,
+    message::types::PresenceStatus,
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+    presence::PresenceStore,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Next step of a session migration; persisted before it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStep {
+    /// Ask the target gateway to prepare a session
+    Prepare,
+    /// Start mirroring deliveries to the target gateway
+    DualDelivery,
+    /// Ask the source gateway to hand the client a reconnect hint
+    HintReconnect,
+    /// Wait for the client to show up on the target gateway
+    AwaitReconnect,
+    /// Client moved: release the source session
+    Complete,
+    /// Client never moved (or a step failed): discard the prepared session
+    Abort,
+}
+
+impl MigrationStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MigrationStep::Prepare => "prepare",
+            MigrationStep::DualDelivery => "dual_delivery",
+            MigrationStep::HintReconnect => "hint_reconnect",
+            MigrationStep::AwaitReconnect => "await_reconnect",
+            MigrationStep::Complete => "complete",
+            MigrationStep::Abort => "abort",
+        }
+    }
+
+    fn mirrors(&self) -> bool {
+        matches!(self, MigrationStep::HintReconnect | MigrationStep::AwaitReconnect)
+    }
+}
+
+const RECORD_CODEC: VersionedCodec<SessionMigrationRecord> =
+    VersionedCodec::new(schema::SESSION_MIGRATION_RECORD, 1, &[]);
+
+/// Migration state stored under `session.{base64url(user_id)}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionMigrationRecord {
+    pub user_id: String,
+    pub from_gateway: String,
+    pub to_gateway: String,
+    pub step: MigrationStep,
+    /// Broker that drives the migration and resumes it after a restart
+    pub driver: String,
+    pub issued_by: String,
+    /// Reconnect deadline in milliseconds, set on entering `AwaitReconnect`
+    pub deadline: Option<i64>,
+    /// Timestamp in milliseconds
+    pub started_at: i64,
+}
+
+/// Request sent to `{gateway_control_prefix}.{gateway_id}`; any reply is an ack
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum GatewaySessionCommand {
+    PrepareSession { user_id: String, from_gateway: String },
+    ReconnectHint { user_id: String, to_gateway: String },
+    ReleaseSession { user_id: String },
+    AbortSession { user_id: String },
+    /// Liveness probe for interest reconciliation; carries no session change
+    Ping,
+}
+
+/// Broker-driven move of a user's sessions from one gateway to another
+///
+/// While the client reconnects, every delivery is mirrored to the target
+/// gateway with the primary delivery's stamp, so the client dedupes the copy
+/// it gets twice. Each step is written to KV before it runs and every step is
+/// idempotent, so a broker restarting mid-migration re-runs the persisted
+/// step and carries on; the reconnect deadline is persisted too.
+pub struct SessionMigrator {
+    broker_id: String,
+    client: async_nats::Client,
+    kv: kv::Store,
+    presence: Arc<PresenceStore>,
+    config: SessionMigrationConfig,
+    /// Retries for gateway commands, bounded by the broker-wide budget
+    command_backoff: BackoffPolicy,
+    budget: Arc<RetryBudget>,
+    command_attempts: metrics::Counter,
+    /// User -> target gateway for migrations in the dual-delivery window
+    mirrors: DashMap<String, String>,
+    /// Wakes the driver waiting on a user's reconnect
+    reconnects: DashMap<String, Arc<Notify>>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl SessionMigrator {
+    pub fn new(
+        broker_id: String,
+        client: async_nats::Client,
+        kv: kv::Store,
+        presence: Arc<PresenceStore>,
+        config: SessionMigrationConfig,
+        budget: Arc<RetryBudget>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let command_backoff = BackoffPolicy::new(Duration::from_millis(100))
+            .max(Duration::from_secs(2))
+            .jitter(Jitter::Decorrelated)
+            .max_retries(config.command_retries);
+        Self {
+            broker_id,
+            client,
+            kv,
+            presence,
+            config,
+            command_backoff,
+            budget,
+            command_attempts: metrics.retry_attempts("gateway_session_command"),
+            mirrors: DashMap::new(),
+            reconnects: DashMap::new(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Persist a new migration and drive it in the background
+    pub async fn start(
+        self: &Arc<Self>,
+        user_id: &str,
+        from_gateway: &str,
+        to_gateway: &str,
+        issued_by: &str,
+    ) -> Result<(), SessionMigrationError> {
+        if from_gateway == to_gateway {
+            return Err(SessionMigrationError::SameGateway);
+        }
+        let record = SessionMigrationRecord {
+            user_id: user_id.to_string(),
+            from_gateway: from_gateway.to_string(),
+            to_gateway: to_gateway.to_string(),
+            step: MigrationStep::Prepare,
+            driver: self.broker_id.clone(),
+            issued_by: issued_by.to_string(),
+            deadline: None,
+            started_at: self.clock.now_millis(),
+        };
+        let value = RECORD_CODEC
+            .encode(&record)
+            .map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+        let revision = match self.kv.create(record_key(user_id), value.into()).await {
+            Ok(revision) => revision,
+            Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => {
+                return Err(SessionMigrationError::InProgress(user_id.to_string()));
+            }
+            Err(e) => return Err(SessionMigrationError::Store(e.to_string())),
+        };
+
+        info!(
+            "Migrating sessions of {} from {} to {} ({})",
+            user_id, from_gateway, to_gateway, issued_by
+        );
+        self.spawn_driver(record, revision);
+        Ok(())
+    }
+
+    /// Pick up migrations this broker was driving before a restart
+    pub async fn resume(self: &Arc<Self>) -> Result<usize, SessionMigrationError> {
+        let mut keys = self
+            .kv
+            .keys()
+            .await
+            .map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+        let mut resumed = 0;
+
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+            let Some((record, revision)) = self.load(&key).await? else {
+                continue;
+            };
+            if record.driver != self.broker_id {
+                continue;
+            }
+            info!(
+                "Resuming session migration of {} at step {}",
+                record.user_id,
+                record.step.as_str()
+            );
+            self.metrics.record_session_migration_resumed(record.step.as_str());
+            self.spawn_driver(record, revision);
+            resumed += 1;
+        }
+        Ok(resumed)
+    }
+
+    /// Presence online event; lets a waiting migration finish without polling
+    pub fn on_presence(&self, user_id: &str) {
+        if let Some(notify) = self.reconnects.get(user_id) {
+            notify.notify_one();
+        }
+    }
+
+    /// Gateway currently receiving mirrored deliveries for the user
+    pub fn mirror_target(&self, user_id: &str) -> Option<String> {
+        self.mirrors.get(user_id).map(|gateway| gateway.clone())
+    }
+
+    /// Copy a delivery to the target gateway while the user is migrating
+    ///
+    /// `stamp` must be the stamp of the primary delivery so the client sees
+    /// the same delivery ID on both paths.
+    pub async fn mirror(&self, user_id: &str, stamp: &DeliveryStamp, payload: Bytes) -> Result<bool, SessionMigrationError> {
+        let Some(gateway) = self.mirror_target(user_id) else {
+            return Ok(false);
+        };
+        let mut headers = HeaderMap::new();
+        stamp.apply(&mut headers);
+        self.client
+            .publish_with_headers(session_subject(&self.config, &gateway, user_id), headers, payload)
+            .await
+            .map_err(|e| SessionMigrationError::Gateway(e.to_string()))?;
+        self.metrics.record_session_migration_mirrored();
+        Ok(true)
+    }
+
+    fn spawn_driver(self: &Arc<Self>, record: SessionMigrationRecord, revision: u64) {
+        let migrator = Arc::clone(self);
+        let context = TaskContext::new("session_migration");
+        spawn_traced("session_migration", context, async move {
+            let user_id = record.user_id.clone();
+            if let Err(e) = migrator.drive(record, revision).await {
+                warn!("Session migration of {} stopped: {}", user_id, e);
+            }
+            migrator.mirrors.remove(&user_id);
+            migrator.reconnects.remove(&user_id);
+        });
+    }
+
+    async fn drive(&self, mut record: SessionMigrationRecord, mut revision: u64) -> Result<(), SessionMigrationError> {
+        if record.step.mirrors() {
+            self.mirrors.insert(record.user_id.clone(), record.to_gateway.clone());
+        }
+
+        loop {
+            let step = record.step;
+            let started = self.clock.now_instant();
+            let result = self.run_step(&record).await;
+            self.metrics.record_session_migration_step(
+                step.as_str(),
+                if result.is_ok() { "ok" } else { "failed" },
+                self.clock.now_instant().saturating_duration_since(started).as_secs_f64(),
+            );
+
+            let next = match result {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(e) if matches!(step, MigrationStep::Complete | MigrationStep::Abort) => {
+                    // The gateway cleans up on its own session timeout; don't wedge the record
+                    warn!(
+                        "Session migration of {} could not {}: {}",
+                        record.user_id,
+                        step.as_str(),
+                        e
+                    );
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        "Session migration of {} failed at {}: {}",
+                        record.user_id,
+                        step.as_str(),
+                        e
+                    );
+                    MigrationStep::Abort
+                }
+            };
+
+            record.step = next;
+            if next == MigrationStep::AwaitReconnect && record.deadline.is_none() {
+                let timeout = self.config.reconnect_timeout.as_millis() as i64;
+                record.deadline = Some(self.clock.now_millis() + timeout);
+            }
+            revision = self.persist(&record, revision).await?;
+        }
+
+        self.mirrors.remove(&record.user_id);
+        self.kv
+            .delete(record_key(&record.user_id))
+            .await
+            .map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Run one step; `None` once the migration is finished
+    async fn run_step(&self, record: &SessionMigrationRecord) -> Result<Option<MigrationStep>, SessionMigrationError> {
+        let user_id = record.user_id.clone();
+        match record.step {
+            MigrationStep::Prepare => {
+                let command = GatewaySessionCommand::PrepareSession {
+                    user_id,
+                    from_gateway: record.from_gateway.clone(),
+                };
+                self.command(&record.to_gateway, &command).await?;
+                Ok(Some(MigrationStep::DualDelivery))
+            }
+            MigrationStep::DualDelivery => {
+                self.mirrors.insert(user_id, record.to_gateway.clone());
+                Ok(Some(MigrationStep::HintReconnect))
+            }
+            MigrationStep::HintReconnect => {
+                let command = GatewaySessionCommand::ReconnectHint {
+                    user_id,
+                    to_gateway: record.to_gateway.clone(),
+                };
+                self.command(&record.from_gateway, &command).await?;
+                Ok(Some(MigrationStep::AwaitReconnect))
+            }
+            MigrationStep::AwaitReconnect => {
+                let deadline = record.deadline.unwrap_or_else(|| self.clock.now_millis());
+                if self.await_reconnect(record, deadline).await {
+                    Ok(Some(MigrationStep::Complete))
+                } else {
+                    self.metrics.record_session_migration("timed_out");
+                    Ok(Some(MigrationStep::Abort))
+                }
+            }
+            MigrationStep::Complete => {
+                // Stop mirroring before the source lets go of the session
+                self.mirrors.remove(&user_id);
+                let command = GatewaySessionCommand::ReleaseSession { user_id };
+                self.command(&record.from_gateway, &command).await?;
+                self.metrics.record_session_migration("completed");
+                Ok(None)
+            }
+            MigrationStep::Abort => {
+                self.mirrors.remove(&user_id);
+                let command = GatewaySessionCommand::AbortSession { user_id };
+                self.command(&record.to_gateway, &command).await?;
+                self.metrics.record_session_migration("aborted");
+                Ok(None)
+            }
+        }
+    }
+
+    /// True once presence places the user on the target gateway before the deadline
+    async fn await_reconnect(&self, record: &SessionMigrationRecord, deadline: i64) -> bool {
+        let notify = self
+            .reconnects
+            .entry(record.user_id.clone())
+            .or_insert_with(|| Arc::new(Notify::new()))
+            .clone();
+        let poll = Duration::from_millis(self.config.presence_poll_ms.max(1));
+
+        loop {
+            match self.presence.lookup(&record.user_id).await {
+                Ok(Some(presence))
+                    if presence.gateway_id == record.to_gateway && presence.status == PresenceStatus::Online =>
+                {
+                    return true;
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Presence lookup for {} failed: {}", record.user_id, e),
+            }
+
+            let remaining = deadline - self.clock.now_millis();
+            if remaining <= 0 {
+                return false;
+            }
+            let wait = poll.min(Duration::from_millis(remaining as u64));
+            tokio::select! {
+                _ = notify.notified() => {}
+                _ = self.clock.sleep(wait) => {}
+            }
+        }
+    }
+
+    async fn command(&self, gateway_id: &str, command: &GatewaySessionCommand) -> Result<(), SessionMigrationError> {
+        let payload: Bytes = serde_json::to_vec(command)
+            .map_err(|e| SessionMigrationError::Gateway(e.to_string()))?
+            .into();
+        let subject = format!("{}.{}", self.config.gateway_control_prefix, gateway_id);
+        let timeout = Duration::from_millis(self.config.request_timeout_ms);
+
+        let clock = self.clock.as_ref();
+        retry_with_clock(clock, &self.command_backoff, Some(&self.budget), &self.command_attempts, |_| {
+            let request = self.client.request(subject.clone(), payload.clone());
+            async move {
+                tokio::select! {
+                    reply = request => reply
+                        .map(|_| ())
+                        .map_err(|e| SessionMigrationError::Gateway(format!("{}: {}", gateway_id, e))),
+                    _ = clock.sleep(timeout) => Err(SessionMigrationError::Gateway(format!("{} did not reply", gateway_id))),
+                }
+            }
+        })
+        .await
+    }
+
+    async fn persist(&self, record: &SessionMigrationRecord, revision: u64) -> Result<u64, SessionMigrationError> {
+        let value = RECORD_CODEC
+            .encode(record)
+            .map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+        self.kv
+            .update(record_key(&record.user_id), value.into(), revision)
+            .await
+            .map_err(|e| SessionMigrationError::Store(e.to_string()))
+    }
+
+    async fn load(&self, key: &str) -> Result<Option<(SessionMigrationRecord, u64)>, SessionMigrationError> {
+        let entry = self
+            .kv
+            .entry(key)
+            .await
+            .map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+
+        match entry {
+            Some(entry) if entry.operation == kv::Operation::Put => {
+                let record = RECORD_CODEC
+                    .decode(&entry.value)
+                    .map_err(|e| SessionMigrationError::Store(e.to_string()))?;
+                Ok(Some((record, entry.revision)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn record_key(user_id: &str) -> String {
+    format!("session.{}", URL_SAFE_NO_PAD.encode(user_id))
+}
+
+/// Per-gateway delivery subject used for mirrored copies
+fn session_subject(config: &SessionMigrationConfig, gateway_id: &str, user_id: &str) -> String {
+    format!("{}.{}.{}", config.gateway_session_prefix, gateway_id, user_id)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionMigrationError {
+    #[error("a session migration for {0} is already in progress")]
+    InProgress(String),
+    #[error("source and target gateway are the same")]
+    SameGateway,
+    #[error("gateway command failed: {0}")]
+    Gateway(String),
+    #[error("session migration store error: {0}")]
+    Store(String),
+}
+
+/// The migration tests run two mock gateways against JetStream at `NATS_URL`
+/// (default `localhost:4222`): `cargo test -- --ignored session_migration`
+#[cfg(test)]
+mod tests {
+    use async_nats::jetstream;
+    use async_trait::async_trait;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        delivery_id::DELIVERY_ID_HEADER,
+        message::types::PresenceUpdate,
+        route_cache::{RouteCache, RouteLookupError, UserLookup, UserResolver},
+    };
+
+    const BROKER: &str = "broker-1";
+
+    #[test]
+    fn frozen_v1_records_still_load() {
+        let fixture: &[u8] = include_bytes!("../fixtures/persist/session_migration_record.v1.bin");
+        for bytes in [fixture, &fixture[5..]] {
+            let record = RECORD_CODEC.decode(bytes).unwrap();
+            assert_eq!(record.user_id, "alice");
+            assert_eq!((record.from_gateway.as_str(), record.to_gateway.as_str()), ("gw-1", "gw-2"));
+            assert_eq!(record.step, MigrationStep::AwaitReconnect);
+            assert_eq!(record.driver, BROKER);
+            assert_eq!(record.issued_by, "ops");
+            assert_eq!(record.deadline, Some(1_700_000_030_000));
+            assert_eq!(record.started_at, 1_700_000_000_000);
+        }
+    }
+
+    struct NoUsers;
+
+    #[async_trait]
+    impl UserResolver for NoUsers {
+        async fn resolve(&self, _user_id: &str) -> Result<UserLookup, RouteLookupError> {
+            Ok(UserLookup::NotFound)
+        }
+    }
+
+    #[test]
+    fn only_the_reconnect_window_mirrors() {
+        assert!(!MigrationStep::Prepare.mirrors());
+        assert!(!MigrationStep::DualDelivery.mirrors());
+        assert!(MigrationStep::HintReconnect.mirrors());
+        assert!(MigrationStep::AwaitReconnect.mirrors());
+        assert!(!MigrationStep::Complete.mirrors());
+        assert!(!MigrationStep::Abort.mirrors());
+    }
+
+    #[test]
+    fn record_keys_stay_a_single_token() {
+        let key = record_key("alice.smith@example.com");
+        assert_eq!(key.matches('.').count(), 1);
+        assert!(key.starts_with("session."));
+        assert_ne!(key, record_key("alice.smith@example.org"));
+    }
+
+    #[test]
+    fn gateway_commands_are_tagged() {
+        let command = GatewaySessionCommand::ReconnectHint {
+            user_id: "alice".into(),
+            to_gateway: "gw-b".into(),
+        };
+        let value = serde_json::to_value(&command).unwrap();
+        assert_eq!(value["command"], "reconnect_hint");
+        assert_eq!(value["to_gateway"], "gw-b");
+    }
+
+    struct Fixture {
+        client: async_nats::Client,
+        kv: kv::Store,
+        presence: Arc<PresenceStore>,
+        config: SessionMigrationConfig,
+        clock: Arc<SimClock>,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let client = async_nats::connect(url).await.unwrap();
+            let context = jetstream::new(client.clone());
+            let id = Uuid::new_v4().simple().to_string();
+            let kv = context
+                .create_key_value(kv::Config {
+                    bucket: format!("migration-test-{}", id),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let presence_kv = context
+                .create_key_value(kv::Config {
+                    bucket: format!("migration-presence-test-{}", id),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            let broker = BrokerConfig::load().unwrap();
+            let metrics = BrokerMetrics::new().unwrap();
+            let routes = Arc::new(RouteCache::new(Arc::new(NoUsers), &broker.routing, metrics.clone()));
+            let presence = Arc::new(PresenceStore::new(presence_kv, &broker.routing, routes, metrics));
+
+            let mut config = broker.session_migration;
+            config.gateway_control_prefix = format!("test.{}.control", id);
+            config.gateway_session_prefix = format!("test.{}.session", id);
+            config.request_timeout_ms = 500;
+            config.command_retries = 1;
+            config.reconnect_timeout = Duration::from_secs(10);
+            config.presence_poll_ms = 50;
+            Self {
+                client,
+                kv,
+                presence,
+                config,
+                clock: Arc::new(SimClock::new()),
+            }
+        }
+
+        /// A broker process; building a second one over the same bucket is a restart
+        fn migrator(&self) -> Arc<SessionMigrator> {
+            let retry = BrokerConfig::load().unwrap().retry;
+            Arc::new(
+                SessionMigrator::new(
+                    BROKER.into(),
+                    self.client.clone(),
+                    self.kv.clone(),
+                    self.presence.clone(),
+                    self.config.clone(),
+                    Arc::new(RetryBudget::new(&retry)),
+                    BrokerMetrics::new().unwrap(),
+                )
+                .with_clock(self.clock.clone()),
+            )
+        }
+
+        /// Mock gateway acking every session command and reporting it to the test
+        async fn gateway(&self, gateway_id: &str) -> mpsc::UnboundedReceiver<GatewaySessionCommand> {
+            let subject = format!("{}.{}", self.config.gateway_control_prefix, gateway_id);
+            let mut requests = self.client.subscribe(subject).await.unwrap();
+            let client = self.client.clone();
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(request) = requests.next().await {
+                    let command: GatewaySessionCommand = serde_json::from_slice(&request.payload).unwrap();
+                    if let Some(reply) = request.reply {
+                        client.publish(reply, Bytes::from_static(b"ok")).await.unwrap();
+                    }
+                    let _ = tx.send(command);
+                }
+            });
+            rx
+        }
+
+        async fn come_online(&self, migrator: &SessionMigrator, gateway_id: &str) {
+            let update = PresenceUpdate {
+                user_id: "alice".into(),
+                status: PresenceStatus::Online,
+                device_id: "phone".into(),
+                last_seen: self.clock.now_millis(),
+                platform: None,
+            };
+            self.presence.heartbeat(gateway_id, &update).await.unwrap();
+            migrator.on_presence("alice");
+        }
+
+        /// Write a record as if a broker had persisted `step` and then died
+        async fn crashed_at(&self, step: MigrationStep, deadline: Option<i64>) {
+            let record = SessionMigrationRecord {
+                user_id: "alice".into(),
+                from_gateway: "gw-a".into(),
+                to_gateway: "gw-b".into(),
+                step,
+                driver: BROKER.into(),
+                issued_by: "ops".into(),
+                deadline,
+                started_at: self.clock.now_millis(),
+            };
+            let value = RECORD_CODEC.encode(&record).unwrap();
+            self.kv.put(record_key("alice"), value.into()).await.unwrap();
+        }
+
+        /// Until the driver sleeps on the clock waiting for a reconnect
+        async fn waiting(&self) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.clock.pending() == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("the driver never waited for the reconnect");
+        }
+
+        async fn finished(&self) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.kv.get(record_key("alice")).await.unwrap().is_some() {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            })
+            .await
+            .expect("migration record was not removed");
+        }
+    }
+
+    async fn next(gateway: &mut mpsc::UnboundedReceiver<GatewaySessionCommand>) -> GatewaySessionCommand {
+        tokio::time::timeout(Duration::from_secs(5), gateway.recv())
+            .await
+            .expect("gateway got no command")
+            .unwrap()
+    }
+
+    async fn mirroring(migrator: &SessionMigrator) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while migrator.mirror_target("alice").is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("deliveries were never mirrored");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn sessions_move_to_the_target_gateway() {
+        let fixture = Fixture::new().await;
+        let mut source = fixture.gateway("gw-a").await;
+        let mut target = fixture.gateway("gw-b").await;
+        let mut mirrored = fixture
+            .client
+            .subscribe(session_subject(&fixture.config, "gw-b", "alice"))
+            .await
+            .unwrap();
+        let migrator = fixture.migrator();
+
+        assert!(matches!(
+            migrator.start("alice", "gw-a", "gw-a", "ops").await,
+            Err(SessionMigrationError::SameGateway)
+        ));
+        migrator.start("alice", "gw-a", "gw-b", "ops").await.unwrap();
+        assert!(matches!(
+            migrator.start("alice", "gw-a", "gw-b", "ops").await,
+            Err(SessionMigrationError::InProgress(_))
+        ));
+
+        assert!(matches!(
+            next(&mut target).await,
+            GatewaySessionCommand::PrepareSession { from_gateway, .. } if from_gateway == "gw-a"
+        ));
+        assert!(matches!(
+            next(&mut source).await,
+            GatewaySessionCommand::ReconnectHint { to_gateway, .. } if to_gateway == "gw-b"
+        ));
+        mirroring(&migrator).await;
+
+        let stamp = DeliveryStamp {
+            delivery_id: "d-1".into(),
+            attempt: 1,
+            checksum: None,
+        };
+        assert!(migrator.mirror("alice", &stamp, Bytes::from_static(b"hi")).await.unwrap());
+        assert!(!migrator.mirror("bob", &stamp, Bytes::from_static(b"hi")).await.unwrap());
+        let copy = tokio::time::timeout(Duration::from_secs(5), mirrored.next()).await.unwrap().unwrap();
+        assert_eq!(copy.headers.unwrap().get(DELIVERY_ID_HEADER).unwrap().as_str(), "d-1");
+
+        fixture.come_online(&migrator, "gw-b").await;
+        assert!(matches!(next(&mut source).await, GatewaySessionCommand::ReleaseSession { .. }));
+        fixture.finished().await;
+        assert_eq!(migrator.mirror_target("alice"), None);
+        assert!(target.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_client_that_never_reconnects_is_rolled_back() {
+        let mut fixture = Fixture::new().await;
+        fixture.config.reconnect_timeout = Duration::from_millis(500);
+        let mut source = fixture.gateway("gw-a").await;
+        let mut target = fixture.gateway("gw-b").await;
+        let migrator = fixture.migrator();
+
+        migrator.start("alice", "gw-a", "gw-b", "ops").await.unwrap();
+        assert!(matches!(next(&mut target).await, GatewaySessionCommand::PrepareSession { .. }));
+        assert!(matches!(next(&mut source).await, GatewaySessionCommand::ReconnectHint { .. }));
+
+        // Still on the source gateway when the deadline passes
+        fixture.come_online(&migrator, "gw-a").await;
+        fixture.waiting().await;
+        fixture.clock.advance(fixture.config.reconnect_timeout);
+        assert!(matches!(next(&mut target).await, GatewaySessionCommand::AbortSession { .. }));
+        fixture.finished().await;
+        assert_eq!(migrator.mirror_target("alice"), None);
+        assert!(source.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_restarted_broker_reruns_the_persisted_step() {
+        let fixture = Fixture::new().await;
+        let mut source = fixture.gateway("gw-a").await;
+        let mut target = fixture.gateway("gw-b").await;
+        fixture.crashed_at(MigrationStep::HintReconnect, None).await;
+
+        let migrator = fixture.migrator();
+        assert_eq!(migrator.resume().await.unwrap(), 1);
+        mirroring(&migrator).await;
+        assert_eq!(migrator.mirror_target("alice").as_deref(), Some("gw-b"));
+        assert!(matches!(next(&mut source).await, GatewaySessionCommand::ReconnectHint { .. }));
+
+        fixture.come_online(&migrator, "gw-b").await;
+        assert!(matches!(next(&mut source).await, GatewaySessionCommand::ReleaseSession { .. }));
+        fixture.finished().await;
+        // Prepare ran before the crash and is not repeated
+        assert!(target.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn the_reconnect_deadline_survives_a_restart() {
+        let fixture = Fixture::new().await;
+        let mut target = fixture.gateway("gw-b").await;
+        let passed = fixture.clock.now_millis() - 1_000;
+        fixture.crashed_at(MigrationStep::AwaitReconnect, Some(passed)).await;
+
+        assert_eq!(fixture.migrator().resume().await.unwrap(), 1);
+        assert!(matches!(next(&mut target).await, GatewaySessionCommand::AbortSession { .. }));
+        fixture.finished().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn migrations_driven_by_another_broker_are_left_alone() {
+        let fixture = Fixture::new().await;
+        let mut record = SessionMigrationRecord {
+            user_id: "alice".into(),
+            from_gateway: "gw-a".into(),
+            to_gateway: "gw-b".into(),
+            step: MigrationStep::Prepare,
+            driver: "broker-2".into(),
+            issued_by: "ops".into(),
+            deadline: None,
+            started_at: fixture.clock.now_millis(),
+        };
+        fixture
+            .kv
+            .put(record_key("alice"), RECORD_CODEC.encode(&record).unwrap().into())
+            .await
+            .unwrap();
+        assert_eq!(fixture.migrator().resume().await.unwrap(), 0);
+
+        record.driver = BROKER.into();
+        fixture
+            .kv
+            .put(record_key("alice"), RECORD_CODEC.encode(&record).unwrap().into())
+            .await
+            .unwrap();
+        let _target = fixture.gateway("gw-b").await;
+        let _source = fixture.gateway("gw-a").await;
+        assert_eq!(fixture.migrator().resume().await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,15 @@
+/// Stable shard assignment for a user or conversation ID
+///
+/// Uses 64-bit FNV-1a so gateways and brokers agree on shard numbers
+/// regardless of build or platform.
+pub fn shard_for(id: &str, shard_count: usize) -> usize {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let hash = id
+        .as_bytes()
+        .iter()
+        .fold(OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(PRIME));
+
+    (hash % shard_count.max(1) as u64) as usize
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    config::ShedExemptionConfig,
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+};
+
+/// Traffic that load shedding and degradation must leave alone
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExemptionSelector {
+    Conversation { conversation_id: String },
+    /// A tenant's conversations whose envelopes carry `label` under
+    /// `shed_exemptions.label_key` in their metadata
+    TenantLabel { tenant_id: String, label: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExemptionError {
+    #[error("shed exemption list is full ({0} entries)")]
+    Full(usize),
+}
+
+#[derive(Default)]
+struct CompiledExemptions {
+    conversations: HashSet<String>,
+    /// Labels by tenant
+    tenant_labels: HashMap<String, HashSet<String>>,
+}
+
+impl CompiledExemptions {
+    fn compile<'a>(selectors: impl Iterator<Item = &'a ExemptionSelector>) -> Self {
+        let mut compiled = Self::default();
+        for selector in selectors {
+            match selector {
+                ExemptionSelector::Conversation { conversation_id } => {
+                    compiled.conversations.insert(conversation_id.clone());
+                }
+                ExemptionSelector::TenantLabel { tenant_id, label } => {
+                    compiled
+                        .tenant_labels
+                        .entry(tenant_id.clone())
+                        .or_default()
+                        .insert(label.clone());
+                }
+            }
+        }
+        compiled
+    }
+
+    fn is_empty(&self) -> bool {
+        self.conversations.is_empty() && self.tenant_labels.is_empty()
+    }
+}
+
+/// Conversations never dropped or deferred by shedding
+///
+/// Static selectors come from `shed_exemptions.selectors`; runtime ones are
+/// added and removed with control commands, audited, and count toward the
+/// same `shed_exemptions.max_entries` cap so the list can't grow into a way
+/// around shedding altogether.
+///
+/// Shedding code calls `exempts` only once it has decided to drop or defer
+/// a message, so unshed traffic never pays for the lookup. The lookup
+/// itself is one `ArcSwap` load and at most two hash set probes. Messages
+/// let through are counted in `broker_shed_exempted_total` by the behavior
+/// that would have shed them, which shows how much capacity the exemptions
+/// take while the broker is shedding.
+pub struct ShedExemptions {
+    compiled: ArcSwap<CompiledExemptions>,
+    static_selectors: Vec<ExemptionSelector>,
+    runtime: Mutex<BTreeSet<ExemptionSelector>>,
+    label_key: String,
+    max_entries: usize,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl ShedExemptions {
+    pub fn new(config: &ShedExemptionConfig, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        let static_selectors: Vec<ExemptionSelector> =
+            config.selectors.iter().take(config.max_entries).cloned().collect();
+        let exemptions = Self {
+            compiled: ArcSwap::from_pointee(CompiledExemptions::compile(static_selectors.iter())),
+            static_selectors,
+            runtime: Mutex::new(BTreeSet::new()),
+            label_key: config.label_key.clone(),
+            max_entries: config.max_entries,
+            audit,
+            metrics,
+        };
+        exemptions.metrics.update_shed_exemptions(exemptions.static_selectors.len());
+        exemptions
+    }
+
+    /// Whether shedding must let `envelope` through; counted under `behavior` when it does
+    pub fn exempts(&self, envelope: &MessageEnvelope, behavior: &'static str) -> bool {
+        let compiled = self.compiled.load();
+        if compiled.is_empty() {
+            return false;
+        }
+        let exempt = compiled.conversations.contains(&envelope.conversation_id())
+            || envelope
+                .tenant_id
+                .as_deref()
+                .and_then(|tenant_id| compiled.tenant_labels.get(tenant_id))
+                .zip(envelope.metadata.get(&self.label_key))
+                .is_some_and(|(labels, label)| labels.contains(label));
+        if exempt {
+            self.metrics.record_shed_exempted(behavior);
+        }
+        exempt
+    }
+
+    pub fn add(&self, selector: ExemptionSelector, reason: &str, actor: &str) -> Result<(), ExemptionError> {
+        {
+            let mut runtime = self.runtime.lock();
+            if self.static_selectors.contains(&selector) || runtime.contains(&selector) {
+                return Ok(());
+            }
+            let total = self.static_selectors.len() + runtime.len();
+            if total >= self.max_entries {
+                return Err(ExemptionError::Full(total));
+            }
+            runtime.insert(selector.clone());
+            self.recompile(&runtime);
+        }
+        info!("Shed exemption {:?} added by {}: {}", selector, actor, reason);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "shed_exemption.added",
+            serde_json::json!({ "selector": selector, "reason": reason }),
+        ));
+        Ok(())
+    }
+
+    /// Remove a runtime exemption; static ones can't be removed at runtime
+    pub fn remove(&self, selector: &ExemptionSelector, actor: &str) -> bool {
+        {
+            let mut runtime = self.runtime.lock();
+            if !runtime.remove(selector) {
+                return false;
+            }
+            self.recompile(&runtime);
+        }
+        self.audit.record(AuditEntry::new(
+            actor,
+            "shed_exemption.removed",
+            serde_json::json!({ "selector": selector }),
+        ));
+        true
+    }
+
+    fn recompile(&self, runtime: &BTreeSet<ExemptionSelector>) {
+        let compiled = CompiledExemptions::compile(self.static_selectors.iter().chain(runtime.iter()));
+        self.compiled.store(Arc::new(compiled));
+        self.metrics.update_shed_exemptions(self.static_selectors.len() + runtime.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        config::{BrokerConfig, DegradationConfig, DegradationLevels},
+        degradation::{DegradationLevel, DegradationSwitchboard},
+        ingress::{shed, IngressRejection},
+        kind_budget::{BudgetAction, KindBudgets, TrafficKind},
+        message::types::{EncryptedPayload, MessageType, Priority},
+    };
+
+    /// Everything between the shedding decision and the exemption list
+    struct Shedding {
+        switchboard: Arc<DegradationSwitchboard>,
+        kind_budgets: KindBudgets,
+        exemptions: ShedExemptions,
+        metrics: BrokerMetrics,
+    }
+
+    impl Shedding {
+        fn new(selectors: Vec<ExemptionSelector>, audit: AuditLog) -> Self {
+            let clock = Arc::new(SimClock::new());
+            let config = BrokerConfig::load().unwrap();
+            let metrics = BrokerMetrics::new().unwrap();
+            let degradation = DegradationConfig {
+                level_ttl: Duration::from_secs(600),
+                auto_trigger_on_shed: false,
+                levels: DegradationLevels::default(),
+            };
+            let switchboard = Arc::new(
+                DegradationSwitchboard::new(&degradation, AuditLog::tracing_only(), metrics.clone()).with_clock(clock.clone()),
+            );
+            let mut budgets = config.kind_budgets;
+            budgets.enabled = true;
+            budgets.min_window_messages = 10;
+            let kind_budgets =
+                KindBudgets::new(budgets, 1000, switchboard.clone(), metrics.clone()).with_clock(clock.clone());
+            let mut exemption_config = config.shed_exemptions;
+            exemption_config.selectors = selectors;
+            exemption_config.max_entries = 3;
+            Self {
+                switchboard,
+                kind_budgets,
+                exemptions: ShedExemptions::new(&exemption_config, audit, metrics.clone()),
+                metrics,
+            }
+        }
+
+        fn shed(&self, envelope: &MessageEnvelope) -> Result<(), IngressRejection> {
+            let kind = self.kind_budgets.observe(envelope);
+            shed(&self.switchboard, &self.kind_budgets, &self.exemptions, &self.metrics, envelope, kind)
+        }
+    }
+
+    fn conversation(conversation_id: &str) -> ExemptionSelector {
+        ExemptionSelector::Conversation {
+            conversation_id: conversation_id.into(),
+        }
+    }
+
+    fn labelled(tenant_id: &str, label: &str) -> ExemptionSelector {
+        ExemptionSelector::TenantLabel {
+            tenant_id: tenant_id.into(),
+            label: label.into(),
+        }
+    }
+
+    fn envelope(message_type: MessageType, from: &str, to: &str, label: Option<&str>) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "c2VjcmV0".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(message_type, from.into(), vec![to.into()], payload);
+        envelope.tenant_id = Some("acme".into());
+        if let Some(label) = label {
+            envelope.metadata.insert("conversation_label".into(), label.into());
+        }
+        envelope
+    }
+
+    #[test]
+    fn exempted_conversations_flow_untouched_at_the_highest_shed_level() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let shedding = Shedding::new(
+                vec![conversation("dm:ceo:cfo"), labelled("acme", "incident-bridge")],
+                AuditLog::tracing_only(),
+            );
+            shedding.switchboard.set_level(DegradationLevel::Emergency, "oncall", "overload", None);
+
+            let kinds = [MessageType::TextMessage, MessageType::Delivered, MessageType::Typing, MessageType::Presence];
+            for message_type in kinds {
+                let mut by_conversation = envelope(message_type, "ceo", "cfo", None);
+                let mut by_label = envelope(message_type, "alice", "bob", Some("incident-bridge"));
+                let mut other_conversation = envelope(message_type, "ceo", "bob", None);
+                let mut other_label = envelope(message_type, "alice", "bob", Some("watercooler"));
+                for envelope in [&mut by_conversation, &mut by_label, &mut other_conversation, &mut other_label] {
+                    envelope.priority = Priority::Bulk;
+                }
+                let mut other_tenant = by_label.clone();
+                other_tenant.tenant_id = Some("initech".into());
+
+                assert!(shedding.shed(&by_conversation).is_ok(), "{:?}", message_type);
+                assert!(shedding.shed(&by_label).is_ok(), "{:?}", message_type);
+                for comparable in [&other_conversation, &other_label, &other_tenant] {
+                    assert!(
+                        matches!(shedding.shed(comparable), Err(IngressRejection::Degraded(_))),
+                        "{:?} to {}",
+                        message_type,
+                        comparable.conversation_id()
+                    );
+                }
+            }
+
+            let rendered = recorder.handle().render();
+            for behavior in ["degraded_bulk", "degraded_receipts", "degraded_typing", "degraded_presence"] {
+                let exempted = format!(r#"broker_shed_exempted_total{{behavior="{}"}} 2"#, behavior);
+                assert!(rendered.contains(&exempted), "{}", rendered);
+            }
+        });
+    }
+
+    #[test]
+    fn exempted_receipts_pass_a_kind_budget_that_defers_the_rest() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let shedding = Shedding::new(vec![conversation("dm:ceo:cfo")], AuditLog::tracing_only());
+            // Conserve keeps receipts but caps them at 10% of what's admitted
+            shedding.switchboard.set_level(DegradationLevel::Conserve, "oncall", "overload", None);
+            for _ in 0..20 {
+                shedding.shed(&envelope(MessageType::TextMessage, "alice", "bob", None)).unwrap();
+            }
+
+            let mut deferred = 0;
+            for _ in 0..10 {
+                match shedding.shed(&envelope(MessageType::Read, "alice", "bob", None)) {
+                    Ok(()) => {}
+                    Err(IngressRejection::OverBudget(over)) => {
+                        assert_eq!((over.kind, over.action), (TrafficKind::Receipt, BudgetAction::Defer));
+                        deferred += 1;
+                    }
+                    Err(other) => panic!("{}", other),
+                }
+                assert!(shedding.shed(&envelope(MessageType::Read, "ceo", "cfo", None)).is_ok());
+            }
+            // Three receipts fit under 10% before the budget closes; one of them was exempt
+            assert_eq!(deferred, 8);
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_shed_exempted_total{behavior="kind_budget"} 9"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn unshed_traffic_never_consults_the_list() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let shedding = Shedding::new(vec![conversation("dm:ceo:cfo")], AuditLog::tracing_only());
+            let mut bulk = envelope(MessageType::TextMessage, "ceo", "cfo", None);
+            bulk.priority = Priority::Bulk;
+            for _ in 0..50 {
+                shedding.shed(&bulk).unwrap();
+                shedding.shed(&envelope(MessageType::Typing, "ceo", "cfo", None)).unwrap();
+            }
+            assert!(!recorder.handle().render().contains("broker_shed_exempted_total"));
+        });
+    }
+
+    #[test]
+    fn runtime_exemptions_are_capped_audited_and_removable() {
+        let audit_path = std::env::temp_dir().join(format!("shed-exemption-audit-{}", uuid::Uuid::new_v4().simple()));
+        let config = BrokerConfig::load().unwrap();
+        let audit = AuditLog::open(Some(&audit_path.to_string_lossy()), &config.audit, BrokerMetrics::new().unwrap()).unwrap();
+        let shedding = Shedding::new(vec![conversation("dm:ceo:cfo")], audit);
+        shedding.switchboard.set_level(DegradationLevel::Emergency, "oncall", "overload", None);
+        let mut typing = envelope(MessageType::Typing, "alice", "bob", None);
+        assert!(shedding.shed(&typing).is_err());
+
+        shedding.exemptions.add(conversation("dm:alice:bob"), "incident bridge", "oncall").unwrap();
+        assert!(shedding.shed(&typing).is_ok(), "takes effect on the next message");
+        // Re-adding an existing selector, static or runtime, is a no-op
+        shedding.exemptions.add(conversation("dm:alice:bob"), "again", "oncall").unwrap();
+        shedding.exemptions.add(conversation("dm:ceo:cfo"), "again", "oncall").unwrap();
+        shedding.exemptions.add(labelled("acme", "exec"), "exec channel", "oncall").unwrap();
+        assert!(matches!(
+            shedding.exemptions.add(conversation("dm:carol:dave"), "one too many", "oncall"),
+            Err(ExemptionError::Full(3))
+        ));
+
+        assert!(!shedding.exemptions.remove(&conversation("dm:ceo:cfo"), "oncall"), "static selectors stay");
+        assert!(shedding.exemptions.remove(&conversation("dm:alice:bob"), "oncall"));
+        assert!(shedding.shed(&typing).is_err());
+        typing.metadata.insert("conversation_label".into(), "exec".into());
+        assert!(shedding.shed(&typing).is_ok());
+        shedding.exemptions.add(conversation("dm:carol:dave"), "room again", "oncall").unwrap();
+
+        let written = std::fs::read_to_string(&audit_path).unwrap();
+        assert_eq!(written.matches("shed_exemption.added").count(), 3, "{}", written);
+        assert_eq!(written.matches("shed_exemption.removed").count(), 1, "{}", written);
+        assert!(written.contains("incident bridge"), "{}", written);
+        let _ = std::fs::remove_file(&audit_path);
+    }
+}
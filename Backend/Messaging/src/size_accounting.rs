@@ -0,0 +1,256 @@
+use async_nats::HeaderMap;
+use tracing::warn;
+
+use crate::{config::SizeAccountingConfig, metrics::BrokerMetrics, shard::shard_for};
+
+/// Resolution of the per-message warning sample hash
+const SAMPLE_BUCKETS: usize = 1_000_000;
+
+/// `NATS/1.0\r\n` status line plus the blank line ending a header block
+const HEADER_FRAMING_BYTES: usize = 12;
+
+/// Points in the pipeline where a message's size is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeStage {
+    /// Ciphertext as received from the client
+    IngressPayload,
+    /// Serialized envelope as accepted at ingress
+    IngressEnvelope,
+    /// Body of the egress publish or frame
+    EgressBody,
+    /// Header bytes on the egress publish
+    EgressHeaders,
+}
+
+impl SizeStage {
+    const COUNT: usize = 4;
+
+    fn index(&self) -> usize {
+        match self {
+            SizeStage::IngressPayload => 0,
+            SizeStage::IngressEnvelope => 1,
+            SizeStage::EgressBody => 2,
+            SizeStage::EgressHeaders => 3,
+        }
+    }
+}
+
+/// Sizes of one message at each stage; fixed array so recording never allocates
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageSizes {
+    bytes: [u32; SizeStage::COUNT],
+}
+
+impl MessageSizes {
+    pub fn record(&mut self, stage: SizeStage, bytes: usize) {
+        self.bytes[stage.index()] = bytes.min(u32::MAX as usize) as u32;
+    }
+
+    pub fn record_headers(&mut self, headers: &HeaderMap) {
+        self.record(SizeStage::EgressHeaders, header_bytes(headers));
+    }
+
+    pub fn get(&self, stage: SizeStage) -> usize {
+        self.bytes[stage.index()] as usize
+    }
+
+    /// Full egress frame size: body plus headers
+    pub fn egress_frame(&self) -> usize {
+        self.get(SizeStage::EgressBody) + self.get(SizeStage::EgressHeaders)
+    }
+
+    /// Egress frame size over ingress payload size; `None` until both are known
+    pub fn inflation(&self) -> Option<f64> {
+        let payload = self.get(SizeStage::IngressPayload);
+        let frame = self.egress_frame();
+        (payload > 0 && frame > 0).then(|| frame as f64 / payload as f64)
+    }
+
+    /// Bytes added by each cause, in `(cause, bytes)` pairs
+    pub fn causes(&self) -> [(&'static str, usize); 3] {
+        let payload = self.get(SizeStage::IngressPayload);
+        let envelope = self.get(SizeStage::IngressEnvelope);
+        [
+            ("envelope_overhead", envelope.saturating_sub(payload)),
+            ("encoding", self.get(SizeStage::EgressBody).saturating_sub(envelope)),
+            ("headers", self.get(SizeStage::EgressHeaders)),
+        ]
+    }
+}
+
+/// Wire size of a NATS header block
+pub fn header_bytes(headers: &HeaderMap) -> usize {
+    let mut bytes = HEADER_FRAMING_BYTES;
+    for (name, values) in headers.iter() {
+        let name: &[u8] = name.as_ref();
+        for value in values {
+            // `Name: value\r\n`
+            bytes += name.len() + value.as_str().len() + 4;
+        }
+    }
+    bytes
+}
+
+/// Exports how much larger messages leave the broker than they arrived
+///
+/// Inflation beyond `size_accounting.warn_factor` is logged for a sample of
+/// messages, chosen by message ID hash so the log volume stays bounded.
+pub struct SizeAccountant {
+    warn_factor: f64,
+    warn_threshold: usize,
+    metrics: BrokerMetrics,
+}
+
+impl SizeAccountant {
+    pub fn new(config: &SizeAccountingConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            warn_factor: config.warn_factor,
+            warn_threshold: (config.warn_sample_rate.clamp(0.0, 1.0) * SAMPLE_BUCKETS as f64) as usize,
+            metrics,
+        }
+    }
+
+    /// Record a message once its egress frame is built
+    pub fn observe(&self, message_id: &str, sizes: &MessageSizes) {
+        let Some(inflation) = sizes.inflation() else {
+            return;
+        };
+        self.metrics.record_size_inflation(inflation);
+        for (cause, bytes) in sizes.causes() {
+            self.metrics.record_size_inflation_bytes(cause, bytes);
+        }
+
+        if inflation > self.warn_factor && shard_for(message_id, SAMPLE_BUCKETS) < self.warn_threshold {
+            let [(_, envelope), (_, encoding), (_, headers)] = sizes.causes();
+            warn!(
+                "Message {} inflated {:.1}x: {} byte payload left as {} byte frame \
+                 (envelope +{}, encoding +{}, headers +{})",
+                message_id,
+                inflation,
+                sizes.get(SizeStage::IngressPayload),
+                sizes.egress_frame(),
+                envelope,
+                encoding,
+                headers
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::BrokerConfig,
+        delivery_id::DeliveryStamp,
+        message::types::{EncryptedPayload, MessageEnvelope, MessageType},
+    };
+
+    /// Bytes a minimal envelope may add around its ciphertext
+    ///
+    /// Raise this only for a field that is worth its bytes on every message.
+    const MINIMAL_ENVELOPE_BUDGET: usize = 240;
+
+    fn minimal_envelope(ciphertext: &str) -> MessageEnvelope {
+        MessageEnvelope::new(
+            MessageType::TextMessage,
+            "a".into(),
+            vec!["b".into()],
+            EncryptedPayload {
+                ciphertext: ciphertext.into(),
+                iv: None,
+                tag: None,
+                key_id: None,
+                content_type: None,
+            },
+        )
+    }
+
+    #[test]
+    fn a_minimal_envelope_stays_within_its_byte_budget() {
+        let envelope = minimal_envelope("aGk=");
+        let overhead = serde_json::to_vec(&envelope).unwrap().len() - envelope.payload.ciphertext.len();
+        assert!(
+            overhead <= MINIMAL_ENVELOPE_BUDGET,
+            "minimal envelope overhead grew to {} bytes (budget {})",
+            overhead,
+            MINIMAL_ENVELOPE_BUDGET
+        );
+    }
+
+    #[test]
+    fn envelope_overhead_does_not_grow_with_the_payload() {
+        let small = minimal_envelope("aGk=");
+        let large = minimal_envelope(&"A".repeat(8192));
+        let overhead = |envelope: &MessageEnvelope| {
+            serde_json::to_vec(envelope).unwrap().len() - envelope.payload.ciphertext.len()
+        };
+        assert_eq!(overhead(&small), overhead(&large));
+    }
+
+    #[test]
+    fn header_bytes_match_the_wire_format() {
+        assert_eq!(header_bytes(&HeaderMap::new()), HEADER_FRAMING_BYTES);
+
+        let mut headers = HeaderMap::new();
+        DeliveryStamp {
+            delivery_id: "d-1".into(),
+            attempt: 2,
+            checksum: None,
+        }
+        .apply(&mut headers);
+        let expected = HEADER_FRAMING_BYTES
+            + "Broker-Delivery-Id: d-1\r\n".len()
+            + "Broker-Delivery-Attempt: 2\r\n".len();
+        assert_eq!(header_bytes(&headers), expected);
+    }
+
+    #[test]
+    fn inflation_needs_both_ends() {
+        let mut sizes = MessageSizes::default();
+        assert_eq!(sizes.inflation(), None);
+        sizes.record(SizeStage::IngressPayload, 8192);
+        assert_eq!(sizes.inflation(), None);
+
+        sizes.record(SizeStage::IngressEnvelope, 8500);
+        sizes.record(SizeStage::EgressBody, 11_000);
+        sizes.record(SizeStage::EgressHeaders, 300);
+        assert_eq!(sizes.egress_frame(), 11_300);
+        assert!((sizes.inflation().unwrap() - 11_300.0 / 8192.0).abs() < 1e-9);
+        assert_eq!(
+            sizes.causes(),
+            [("envelope_overhead", 308), ("encoding", 2500), ("headers", 300)]
+        );
+    }
+
+    #[test]
+    fn shrinking_stages_count_as_no_inflation() {
+        let mut sizes = MessageSizes::default();
+        sizes.record(SizeStage::IngressPayload, 1000);
+        sizes.record(SizeStage::IngressEnvelope, 900);
+        sizes.record(SizeStage::EgressBody, 800);
+        assert_eq!(sizes.causes(), [("envelope_overhead", 0), ("encoding", 0), ("headers", 0)]);
+    }
+
+    #[test]
+    fn sizes_are_a_fixed_array() {
+        let mut sizes = MessageSizes::default();
+        sizes.record(SizeStage::EgressBody, usize::MAX);
+        assert_eq!(sizes.get(SizeStage::EgressBody), u32::MAX as usize);
+        assert_eq!(std::mem::size_of::<MessageSizes>(), SizeStage::COUNT * 4);
+    }
+
+    #[test]
+    fn the_warning_sample_rate_is_clamped() {
+        let mut config = BrokerConfig::load().unwrap().size_accounting;
+        config.warn_sample_rate = 0.01;
+        let accountant = SizeAccountant::new(&config, BrokerMetrics::new().unwrap());
+        assert_eq!(accountant.warn_threshold, SAMPLE_BUCKETS / 100);
+
+        config.warn_sample_rate = 5.0;
+        let accountant = SizeAccountant::new(&config, BrokerMetrics::new().unwrap());
+        assert_eq!(accountant.warn_threshold, SAMPLE_BUCKETS);
+        // Every over-factor message is logged at a rate of 1
+        assert!((0..100).all(|i| shard_for(&format!("msg-{}", i), SAMPLE_BUCKETS) < accountant.warn_threshold));
+    }
+}
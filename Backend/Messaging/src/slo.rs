@@ -0,0 +1,439 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    config::{SloConfig, SloDefinition},
+    config_watch::ConfigWatcher,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Width of one fine bucket; burn rate windows are multiples of it
+const BUCKET: Duration = Duration::from_secs(10);
+/// Width of one budget bucket over the SLO period
+const BUDGET_BUCKET: Duration = Duration::from_secs(3600);
+/// Burn rate windows, named as they are exported
+pub const BURN_WINDOWS: [(&str, Duration); 3] = [
+    ("5m", Duration::from_secs(300)),
+    ("1h", Duration::from_secs(3600)),
+    ("6h", Duration::from_secs(21600)),
+];
+
+/// What an SLO measures, fed from existing `BrokerMetrics` instrumentation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SliIndicator {
+    /// Deliveries published or queued within `threshold_ms`; failed deliveries are bad
+    DeliveryLatency { threshold_ms: u64 },
+    /// Ingress messages not dropped by the broker
+    IngressAcceptance,
+    /// Sender-to-device deliveries within `threshold_ms`, from gateway delivery receipts
+    EndToEndLatency { threshold_ms: u64 },
+}
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    total: u64,
+    bad: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.total += other.total;
+        self.bad += other.bad;
+    }
+}
+
+/// Good/bad event counts of one SLO over rolling windows
+///
+/// Events go into two atomics for the current bucket; `roll` moves them
+/// into a ring of 10s buckets covering the longest burn rate window and a
+/// ring of hourly buckets covering the SLO period.
+struct SloState {
+    definition: SloDefinition,
+    current_total: AtomicU64,
+    current_bad: AtomicU64,
+    rings: Mutex<Rings>,
+}
+
+struct Rings {
+    fine: Vec<Counts>,
+    fine_next: usize,
+    budget: Vec<Counts>,
+    budget_next: usize,
+    /// Fine buckets folded into the open budget bucket
+    budget_fill: usize,
+}
+
+impl SloState {
+    fn new(definition: SloDefinition, period: Duration) -> Self {
+        let fine = (BURN_WINDOWS[2].1.as_secs() / BUCKET.as_secs()) as usize;
+        let budget = (period.as_secs() / BUDGET_BUCKET.as_secs()).max(1) as usize;
+        Self {
+            definition,
+            current_total: AtomicU64::new(0),
+            current_bad: AtomicU64::new(0),
+            rings: Mutex::new(Rings {
+                fine: (0..fine).map(|_| Counts::default()).collect(),
+                fine_next: 0,
+                budget: (0..budget).map(|_| Counts::default()).collect(),
+                budget_next: 0,
+                budget_fill: 0,
+            }),
+        }
+    }
+
+    fn record(&self, bad: bool) {
+        self.current_total.fetch_add(1, Ordering::Relaxed);
+        if bad {
+            self.current_bad.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Close the current bucket; returns burn rates per window and remaining budget
+    fn roll(&self) -> ([f64; 3], f64) {
+        let closed = Counts {
+            total: self.current_total.swap(0, Ordering::Relaxed),
+            bad: self.current_bad.swap(0, Ordering::Relaxed),
+        };
+        let allowed = 1.0 - self.definition.objective;
+
+        let mut rings = self.rings.lock();
+        let rings = &mut *rings;
+        let slot = rings.fine_next;
+        rings.fine[slot] = closed;
+        rings.fine_next = (slot + 1) % rings.fine.len();
+
+        let open = rings.budget_next;
+        if rings.budget_fill == 0 {
+            rings.budget[open] = Counts::default();
+        }
+        rings.budget[open].add(&closed);
+        rings.budget_fill += 1;
+        if rings.budget_fill as u64 * BUCKET.as_secs() >= BUDGET_BUCKET.as_secs() {
+            rings.budget_fill = 0;
+            rings.budget_next = (open + 1) % rings.budget.len();
+        }
+
+        let mut burn = [0.0; 3];
+        for (index, (_, window)) in BURN_WINDOWS.iter().enumerate() {
+            let buckets = (window.as_secs() / BUCKET.as_secs()) as usize;
+            let mut sum = Counts::default();
+            for back in 1..=buckets.min(rings.fine.len()) {
+                sum.add(&rings.fine[(slot + rings.fine.len() + 1 - back) % rings.fine.len()]);
+            }
+            burn[index] = burn_rate(&sum, allowed);
+        }
+
+        let mut period = Counts::default();
+        for counts in &rings.budget {
+            period.add(counts);
+        }
+        (burn, 1.0 - burn_rate(&period, allowed))
+    }
+}
+
+/// Error rate over the allowed error rate; 1.0 spends the budget exactly over the period
+fn burn_rate(counts: &Counts, allowed: f64) -> f64 {
+    if counts.total == 0 || allowed <= 0.0 {
+        return 0.0;
+    }
+    (counts.bad as f64 / counts.total as f64) / allowed
+}
+
+/// In-process SLIs with burn rates ready to alert on
+///
+/// Definitions come from `slo.objectives` and are swapped on config
+/// reload; a definition whose name, indicator and objective are unchanged
+/// keeps its history. Recording an event is two relaxed atomic adds per
+/// matching SLO, with no allocation. Every 10s the tracker exports
+/// `broker_slo_burn_rate{slo,window}` for 5m, 1h and 6h and
+/// `broker_slo_error_budget_remaining_ratio{slo}` over `slo.period`, which
+/// goes negative once the budget is overspent.
+pub struct SloTracker {
+    period: Duration,
+    slos: ArcSwap<Vec<Arc<SloState>>>,
+}
+
+impl SloTracker {
+    pub fn new(config: &SloConfig) -> Self {
+        Self {
+            period: config.period,
+            slos: ArcSwap::from_pointee(
+                config
+                    .objectives
+                    .iter()
+                    .map(|definition| Arc::new(SloState::new(definition.clone(), config.period)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// A delivery was published or queued after `seconds`
+    pub fn observe_delivery(&self, seconds: f64) {
+        for slo in self.slos.load().iter() {
+            if let SliIndicator::DeliveryLatency { threshold_ms } = slo.definition.indicator {
+                slo.record(seconds * 1000.0 > threshold_ms as f64);
+            }
+        }
+    }
+
+    /// A gateway reported delivery `seconds` after ingest, clock-corrected
+    pub fn observe_e2e_delivery(&self, seconds: f64) {
+        for slo in self.slos.load().iter() {
+            if let SliIndicator::EndToEndLatency { threshold_ms } = slo.definition.indicator {
+                slo.record(seconds * 1000.0 > threshold_ms as f64);
+            }
+        }
+    }
+
+    pub fn observe_delivery_failed(&self) {
+        for slo in self.slos.load().iter() {
+            if matches!(slo.definition.indicator, SliIndicator::DeliveryLatency { .. }) {
+                slo.record(true);
+            }
+        }
+    }
+
+    /// An ingress message arrived; `dropped` counts it against availability
+    pub fn observe_ingress(&self, dropped: bool) {
+        for slo in self.slos.load().iter() {
+            if slo.definition.indicator == SliIndicator::IngressAcceptance {
+                if dropped {
+                    // Arrival was already counted; only the outcome is new
+                    slo.current_bad.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    slo.record(false);
+                }
+            }
+        }
+    }
+
+    pub fn reload(&self, config: &SloConfig) {
+        let current = self.slos.load();
+        let next: Vec<Arc<SloState>> = config
+            .objectives
+            .iter()
+            .map(|definition| {
+                current
+                    .iter()
+                    .find(|slo| slo.definition == *definition)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(SloState::new(definition.clone(), self.period)))
+            })
+            .collect();
+        info!("SLO definitions reloaded: {} objectives", next.len());
+        self.slos.store(Arc::new(next));
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let tracker = Arc::clone(self);
+        watcher.on_reload(move |config| tracker.reload(&config.slo));
+    }
+
+    pub fn spawn(self: &Arc<Self>, metrics: BrokerMetrics) -> tokio::task::JoinHandle<()> {
+        let tracker = Arc::clone(self);
+        spawn_traced("slo_tracker", TaskContext::new("slo"), async move {
+            let mut ticker = tokio::time::interval(BUCKET);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                for slo in tracker.slos.load().iter() {
+                    let (burn, remaining) = slo.roll();
+                    for ((window, _), rate) in BURN_WINDOWS.iter().zip(burn) {
+                        metrics.update_slo_burn_rate(&slo.definition.name, window, rate);
+                    }
+                    metrics.update_slo_budget_remaining(&slo.definition.name, remaining);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_BUCKETS: usize = 360;
+
+    fn definition(name: &str, indicator: SliIndicator, objective: f64) -> SloDefinition {
+        SloDefinition {
+            name: name.to_string(),
+            indicator,
+            objective,
+        }
+    }
+
+    fn latency_slo(period: Duration) -> SloState {
+        SloState::new(definition("latency", SliIndicator::DeliveryLatency { threshold_ms: 1000 }, 0.999), period)
+    }
+
+    /// Closes `buckets` buckets of `total` events each, `bad` of them bad
+    fn feed(slo: &SloState, buckets: usize, total: u64, bad: u64) -> ([f64; 3], f64) {
+        let mut last = ([0.0; 3], 0.0);
+        for _ in 0..buckets {
+            for event in 0..total {
+                slo.record(event < bad);
+            }
+            last = slo.roll();
+        }
+        last
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn steady_error_rate_burns_every_window_alike() {
+        let slo = latency_slo(Duration::from_secs(24 * 3600));
+        // 1% bad against a 0.1% allowance burns 10x
+        let (burn, _) = feed(&slo, 6 * HOUR_BUCKETS, 1000, 10);
+        for rate in burn {
+            assert_close(rate, 10.0);
+        }
+
+        let (burn, _) = feed(&slo, 1, 1000, 0);
+        assert_close(burn[0], 10.0 * 29.0 / 30.0);
+    }
+
+    #[test]
+    fn a_short_incident_shows_in_proportion_to_each_window() {
+        let slo = latency_slo(Duration::from_secs(24 * 3600));
+        feed(&slo, 6 * HOUR_BUCKETS, 1000, 0);
+
+        // Five minutes at 10% bad: the 5m window sees all of it, the longer
+        // windows see it diluted by the clean history before it
+        let (burn, _) = feed(&slo, 30, 1000, 100);
+        assert_close(burn[0], 100.0);
+        assert_close(burn[1], 100.0 * 30.0 / 360.0);
+        assert_close(burn[2], 100.0 * 30.0 / 2160.0);
+
+        // The incident rolls out of each window in turn
+        let (burn, _) = feed(&slo, 30, 1000, 0);
+        assert_close(burn[0], 0.0);
+        assert_close(burn[1], 100.0 * 30.0 / 360.0);
+        let (burn, _) = feed(&slo, HOUR_BUCKETS, 1000, 0);
+        assert_close(burn[1], 0.0);
+        assert_close(burn[2], 100.0 * 30.0 / 2160.0);
+    }
+
+    #[test]
+    fn error_budget_covers_the_period_and_can_go_negative() {
+        let slo = latency_slo(Duration::from_secs(2 * 3600));
+
+        // An hour burning at 0.5x leaves half the two-hour budget
+        let (_, remaining) = feed(&slo, HOUR_BUCKETS, 2000, 1);
+        assert_close(remaining, 0.5);
+
+        // An hour burning at 2x overspends it
+        let (_, remaining) = feed(&slo, HOUR_BUCKETS, 2000, 4);
+        assert_close(remaining, -0.25);
+
+        // A clean hour pushes the first hour out of the period
+        let (_, remaining) = feed(&slo, HOUR_BUCKETS, 2000, 0);
+        assert_close(remaining, 0.0);
+    }
+
+    #[test]
+    fn no_traffic_burns_nothing() {
+        let slo = latency_slo(Duration::from_secs(3600));
+        let (burn, remaining) = feed(&slo, 10, 0, 0);
+        assert_eq!(burn, [0.0; 3]);
+        assert_eq!(remaining, 1.0);
+    }
+
+    fn tracker() -> SloTracker {
+        SloTracker::new(&SloConfig {
+            period: Duration::from_secs(3600),
+            objectives: vec![
+                definition("delivery", SliIndicator::DeliveryLatency { threshold_ms: 1000 }, 0.99),
+                definition("ingress", SliIndicator::IngressAcceptance, 0.9995),
+                definition("e2e", SliIndicator::EndToEndLatency { threshold_ms: 2000 }, 0.9),
+            ],
+        })
+    }
+
+    fn burn_5m(tracker: &SloTracker, name: &str) -> f64 {
+        let slos = tracker.slos.load();
+        slos.iter().find(|slo| slo.definition.name == name).unwrap().roll().0[0]
+    }
+
+    #[test]
+    fn observations_feed_only_their_indicator() {
+        let tracker = tracker();
+        for _ in 0..95 {
+            tracker.observe_delivery(0.2);
+        }
+        // Exactly at the threshold is still good
+        tracker.observe_delivery(1.0);
+        tracker.observe_delivery(1.5);
+        tracker.observe_delivery(3.0);
+        tracker.observe_delivery_failed();
+        tracker.observe_delivery_failed();
+
+        for _ in 0..2000 {
+            tracker.observe_ingress(false);
+        }
+        // Drops follow the arrival they belong to
+        tracker.observe_ingress(true);
+        tracker.observe_ingress(true);
+
+        for _ in 0..8 {
+            tracker.observe_e2e_delivery(1.0);
+        }
+        tracker.observe_e2e_delivery(2.5);
+        tracker.observe_e2e_delivery(0.5);
+
+        // 4 of 100 deliveries bad against 1%, 2 of 2000 drops against
+        // 0.05%, 1 of 10 receipts late against 10%
+        assert_close(burn_5m(&tracker, "delivery"), 4.0);
+        assert_close(burn_5m(&tracker, "ingress"), 2.0);
+        assert_close(burn_5m(&tracker, "e2e"), 1.0);
+    }
+
+    #[test]
+    fn reload_keeps_history_of_unchanged_definitions() {
+        let tracker = tracker();
+        let before: Vec<_> = tracker.slos.load().iter().cloned().collect();
+
+        let mut config = SloConfig {
+            period: Duration::from_secs(3600),
+            objectives: before.iter().map(|slo| slo.definition.clone()).collect(),
+        };
+        config.objectives[1].objective = 0.999;
+        config.objectives.remove(2);
+        config
+            .objectives
+            .push(definition("strict", SliIndicator::DeliveryLatency { threshold_ms: 100 }, 0.999));
+        tracker.reload(&config);
+
+        let after = tracker.slos.load();
+        assert_eq!(after.len(), 3);
+        assert!(Arc::ptr_eq(&after[0], &before[0]));
+        assert!(!Arc::ptr_eq(&after[1], &before[1]));
+        assert_eq!(after[1].definition.objective, 0.999);
+        assert_eq!(after[2].definition.name, "strict");
+
+        // New definitions start observing right away
+        tracker.observe_delivery(0.5);
+        assert_close(burn_5m(&tracker, "strict"), 1000.0);
+    }
+
+    #[test]
+    fn indicators_parse_from_config() {
+        let parsed: SliIndicator = serde_json::from_str(r#"{"kind":"delivery_latency","threshold_ms":1000}"#).unwrap();
+        assert_eq!(parsed, SliIndicator::DeliveryLatency { threshold_ms: 1000 });
+        let parsed: SliIndicator = serde_json::from_str(r#"{"kind":"ingress_acceptance"}"#).unwrap();
+        assert_eq!(parsed, SliIndicator::IngressAcceptance);
+    }
+}
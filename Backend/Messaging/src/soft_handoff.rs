@@ -0,0 +1,583 @@
+use std::sync::Arc;
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::SoftHandoffConfig,
+    delivery::{DeliveryTracker, PendingDeadline},
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+    task::{spawn_traced, ShutdownSignal, TaskContext},
+};
+
+const RECORD_CODEC: VersionedCodec<HandoffRecord> = VersionedCodec::new(schema::SOFT_STATE_HANDOFF, 1, &[]);
+
+/// Soft state a draining broker leaves for its successor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandoffRecord {
+    pub broker_id: String,
+    pub slot: String,
+    /// Timestamp in milliseconds
+    pub created_at: i64,
+    #[serde(default)]
+    pub deadlines: Vec<PendingDeadline>,
+}
+
+/// Carries in-memory timers across a drain-restart
+///
+/// On shutdown the broker writes its armed delivery deadlines into one
+/// record under `handoff.{slot}.{broker_id}` in the `soft_handoff.bucket`
+/// KV bucket, whose `max_age` should be `soft_handoff.record_ttl`. On
+/// startup a broker claims every record of its `soft_handoff.slot` (an
+/// empty slot shares one pool across the fleet) by swapping it for an
+/// empty value at its revision, so each record is restored at most once,
+/// then re-arms the deadlines for the time they had left. Records older
+/// than `record_ttl` are purged unread, so stale timers never come back.
+///
+/// A deadline that fires on the old broker while it drains is published
+/// again by the successor, which the hand-off append's dedup absorbs.
+/// Nothing else needs carrying: typing and presence fan out as they
+/// arrive and receipts are applied per publish, so neither holds a
+/// window that a restart could lose.
+pub struct SoftStateHandoff {
+    kv: kv::Store,
+    broker_id: String,
+    tracker: Arc<DeliveryTracker>,
+    config: SoftHandoffConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl SoftStateHandoff {
+    pub fn new(
+        kv: kv::Store,
+        broker_id: String,
+        tracker: Arc<DeliveryTracker>,
+        config: SoftHandoffConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            kv,
+            broker_id,
+            tracker,
+            config,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Write this broker's soft state for a successor; returns how many deadlines it carries
+    pub async fn hand_off(&self) -> Result<usize, HandoffError> {
+        let mut deadlines = self.tracker.pending_deadlines();
+        if deadlines.len() > self.config.max_deadlines {
+            // Soonest first, so the ones cut are furthest from firing
+            warn!(
+                "Handing off {} of {} pending delivery deadlines",
+                self.config.max_deadlines,
+                deadlines.len()
+            );
+            deadlines.truncate(self.config.max_deadlines);
+        }
+        if deadlines.is_empty() {
+            return Ok(0);
+        }
+
+        let count = deadlines.len();
+        let record = HandoffRecord {
+            broker_id: self.broker_id.clone(),
+            slot: self.config.slot.clone(),
+            created_at: self.clock.now_millis(),
+            deadlines,
+        };
+        let value = RECORD_CODEC.encode(&record).map_err(|e| HandoffError::Encode(e.to_string()))?;
+        self.kv
+            .put(record_key(&self.config.slot, &self.broker_id), value.into())
+            .await
+            .map_err(|e| HandoffError::Store(e.to_string()))?;
+        self.metrics.record_soft_handoff("published", count);
+        info!("Handed off {} delivery deadlines", count);
+        Ok(count)
+    }
+
+    /// Claim and restore records left for this broker's slot; returns how many deadlines were re-armed
+    pub async fn claim(&self) -> Result<usize, HandoffError> {
+        let prefix = slot_prefix(&self.config.slot);
+        let mut keys = self.kv.keys().await.map_err(|e| HandoffError::Store(e.to_string()))?;
+        let mut restored = 0;
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(|e| HandoffError::Store(e.to_string()))?;
+            if !key.starts_with(&prefix) {
+                continue;
+            }
+            let entry = self.kv.entry(&key).await.map_err(|e| HandoffError::Store(e.to_string()))?;
+            // Empty values are records another broker already claimed
+            let Some(entry) = entry.filter(|entry| entry.operation == kv::Operation::Put && !entry.value.is_empty())
+            else {
+                continue;
+            };
+            let record = match RECORD_CODEC.decode(&entry.value) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Discarding unreadable soft state handoff {}: {}", key, e);
+                    self.discard(&key).await;
+                    continue;
+                }
+            };
+            if record.broker_id == self.broker_id {
+                continue;
+            }
+            let age_ms = self.clock.now_millis() - record.created_at;
+            if age_ms > self.config.record_ttl.as_millis() as i64 {
+                debug!("Soft state handoff from {} expired {}ms ago", record.broker_id, age_ms);
+                self.metrics.record_soft_handoff("expired", record.deadlines.len());
+                self.discard(&key).await;
+                continue;
+            }
+            if self.kv.update(&key, Bytes::new(), entry.revision).await.is_err() {
+                self.metrics.record_soft_handoff("contended", record.deadlines.len());
+                continue;
+            }
+
+            let count = self.tracker.restore_deadlines(record.deadlines);
+            self.metrics.record_soft_handoff("restored", count);
+            info!("Restored {} delivery deadlines handed off by {}", count, record.broker_id);
+            restored += count;
+            self.discard(&key).await;
+        }
+        Ok(restored)
+    }
+
+    async fn discard(&self, key: &str) {
+        if let Err(e) = self.kv.purge(key).await {
+            debug!("Failed to purge soft state handoff {}: {}", key, e);
+        }
+    }
+
+    /// Claim handed-off state now and hand this broker's off at shutdown
+    pub fn spawn(self: &Arc<Self>, shutdown: ShutdownSignal) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let handoff = Arc::clone(self);
+        Some(spawn_traced("soft_handoff", TaskContext::new("soft_handoff"), async move {
+            if let Err(e) = handoff.claim().await {
+                warn!("Failed to claim handed-off soft state: {}", e);
+            }
+            shutdown.wait().await;
+            if let Err(e) = handoff.hand_off().await {
+                warn!("Failed to hand off soft state: {}", e);
+            }
+        }))
+    }
+}
+
+fn slot_prefix(slot: &str) -> String {
+    // KV key tokens can't be empty; a lone `_` is never a whole base64 encoding
+    match slot {
+        "" => "handoff._.".to_string(),
+        slot => format!("handoff.{}.", URL_SAFE_NO_PAD.encode(slot)),
+    }
+}
+
+fn record_key(slot: &str, broker_id: &str) -> String {
+    format!("{}{}", slot_prefix(slot), URL_SAFE_NO_PAD.encode(broker_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandoffError {
+    #[error("soft state handoff store error: {0}")]
+    Store(String),
+    #[error("failed to encode soft state handoff: {0}")]
+    Encode(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_nats::jetstream;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        delivery::{DeliveryHandOff, DeliveryState},
+        history_cache::HistoryCache,
+        message::types::{EncryptedPayload, MessageEnvelope, MessageType},
+        nats_probe::tests::EmbeddedServer,
+        preview::PreviewExtractor,
+    };
+
+    const DEADLINE: Duration = Duration::from_secs(30);
+
+    /// A broker's delivery tracker with its timer task running, and its handoff
+    struct Broker {
+        tracker: Arc<DeliveryTracker>,
+        handoff: SoftStateHandoff,
+        timers: tokio::task::JoinHandle<()>,
+    }
+
+    impl Broker {
+        fn new(
+            broker_id: &str,
+            jetstream: &jetstream::Context,
+            kv: &kv::Store,
+            fallback_subject: &str,
+            config: &SoftHandoffConfig,
+            clock: &Arc<SimClock>,
+        ) -> Self {
+            let broker = BrokerConfig::load().unwrap();
+            let metrics = BrokerMetrics::new().unwrap();
+            let tracker = Arc::new(
+                DeliveryTracker::new(
+                    jetstream.clone(),
+                    kv.clone(),
+                    fallback_subject.into(),
+                    Arc::new(PreviewExtractor::new(broker.preview)),
+                    Arc::new(HistoryCache::new(&broker.history_cache, metrics.clone())),
+                    &broker.routing,
+                    metrics.clone(),
+                )
+                .with_clock(clock.clone()),
+            );
+            let handoff = SoftStateHandoff::new(kv.clone(), broker_id.into(), tracker.clone(), config.clone(), metrics)
+                .with_clock(clock.clone());
+            Self {
+                timers: tracker.spawn_timer_task(),
+                tracker,
+                handoff,
+            }
+        }
+
+        fn send(&self, recipients: &[&str], deadline: Duration) -> String {
+            let mut envelope = MessageEnvelope::new(
+                MessageType::TextMessage,
+                "alice".into(),
+                recipients.iter().map(|r| r.to_string()).collect(),
+                EncryptedPayload {
+                    ciphertext: "aGk=".into(),
+                    iv: None,
+                    tag: None,
+                    key_id: None,
+                    content_type: None,
+                },
+            );
+            envelope.delivery_deadline_ms = Some(deadline.as_millis() as u64);
+            let recipients: Vec<String> = recipients.iter().map(|r| r.to_string()).collect();
+            self.tracker.track(&envelope, &recipients);
+            envelope.message_id
+        }
+
+        fn state(&self, message_id: &str, recipient: &str) -> Option<DeliveryState> {
+            self.tracker.status(message_id, recipient).map(|status| status.state)
+        }
+
+        /// Wait for the timer task to hand the recipient off
+        async fn handed_off(&self, message_id: &str, recipient: &str) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.state(message_id, recipient) != Some(DeliveryState::HandedOff) {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("{} wasn't handed off", recipient));
+        }
+
+        /// Give the timer task the chance to fire early, if it were going to
+        async fn still_pending(&self, message_id: &str, recipient: &str) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(
+                matches!(self.state(message_id, recipient), Some(DeliveryState::Pending | DeliveryState::Queued)),
+                "{} fired early",
+                recipient
+            );
+        }
+    }
+
+    impl Drop for Broker {
+        fn drop(&mut self) {
+            self.timers.abort();
+        }
+    }
+
+    fn config() -> SoftHandoffConfig {
+        SoftHandoffConfig {
+            enabled: true,
+            bucket: "handoff".into(),
+            slot: String::new(),
+            record_ttl: Duration::from_secs(30),
+            max_deadlines: 100,
+        }
+    }
+
+    /// Brokers against an embedded server, which acks the handoff put and keeps it
+    struct Embedded {
+        server: EmbeddedServer,
+        jetstream: jetstream::Context,
+        kv: kv::Store,
+        clock: Arc<SimClock>,
+    }
+
+    impl Embedded {
+        async fn new() -> Self {
+            let clock = Arc::new(SimClock::new());
+            let server = EmbeddedServer::start(clock.clone()).await;
+            let jetstream = jetstream::new(async_nats::connect(&server.url).await.unwrap());
+            let kv = jetstream.get_key_value("handoff").await.unwrap();
+            Self {
+                server,
+                jetstream,
+                kv,
+                clock,
+            }
+        }
+
+        fn broker(&self, broker_id: &str, config: &SoftHandoffConfig) -> Broker {
+            Broker::new(broker_id, &self.jetstream, &self.kv, "push.fallback", config, &self.clock)
+        }
+
+        /// The record the last `hand_off` wrote, with its key
+        fn record(&self) -> (String, HandoffRecord) {
+            let (subject, value) = self.server.puts.lock().last().cloned().expect("a record was written");
+            (subject, RECORD_CODEC.decode(&value).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_deadline_armed_on_one_broker_fires_on_its_successor_on_time() {
+        let embedded = Embedded::new().await;
+        let first = embedded.broker("broker-a", &config());
+        let message_id = first.send(&["bob", "carol", "dave"], DEADLINE);
+        first.tracker.ack(&message_id, "carol");
+        first.tracker.mark_queued(&message_id, "dave");
+
+        // Drained 10s in; only recipients still waiting on their deadline are carried
+        embedded.clock.advance(Duration::from_secs(10));
+        assert_eq!(first.handoff.hand_off().await.unwrap(), 2);
+        drop(first);
+        let (subject, record) = embedded.record();
+        assert_eq!(subject, format!("$KV.handoff.{}", record_key("", "broker-a")));
+        assert_eq!(record.broker_id, "broker-a");
+        let mut carried: Vec<(&str, DeliveryState)> =
+            record.deadlines.iter().map(|d| (d.recipient.as_str(), d.state)).collect();
+        carried.sort();
+        assert_eq!(carried, [("bob", DeliveryState::Pending), ("dave", DeliveryState::Queued)]);
+
+        // The successor starts 5s later and re-arms for the 15s left
+        embedded.clock.advance(Duration::from_secs(5));
+        let second = embedded.broker("broker-b", &config());
+        assert_eq!(second.tracker.restore_deadlines(record.deadlines), 2);
+        assert_eq!(second.state(&message_id, "dave"), Some(DeliveryState::Queued));
+        assert_eq!(second.state(&message_id, "carol"), None);
+
+        embedded.clock.advance(Duration::from_secs(15) - Duration::from_millis(1));
+        second.still_pending(&message_id, "bob").await;
+        second.still_pending(&message_id, "dave").await;
+        embedded.clock.advance(Duration::from_millis(1));
+        second.handed_off(&message_id, "bob").await;
+        second.handed_off(&message_id, "dave").await;
+    }
+
+    #[tokio::test]
+    async fn overdue_deadlines_fire_at_once_and_tracked_messages_are_not_restored_twice() {
+        let embedded = Embedded::new().await;
+        let first = embedded.broker("broker-a", &config());
+        let message_id = first.send(&["bob"], Duration::from_secs(2));
+        first.handoff.hand_off().await.unwrap();
+        let (_, record) = embedded.record();
+
+        // The deadline passed while the successor was starting
+        embedded.clock.advance(Duration::from_secs(3));
+        let second = embedded.broker("broker-b", &config());
+        assert_eq!(second.tracker.restore_deadlines(record.deadlines.clone()), 1);
+        second.handed_off(&message_id, "bob").await;
+        assert_eq!(second.tracker.restore_deadlines(record.deadlines), 0);
+        assert_eq!(second.state(&message_id, "bob"), Some(DeliveryState::HandedOff));
+    }
+
+    #[tokio::test]
+    async fn records_carry_the_soonest_deadlines_and_idle_brokers_write_none() {
+        let embedded = Embedded::new().await;
+        let config = SoftHandoffConfig {
+            max_deadlines: 2,
+            ..config()
+        };
+        let broker = embedded.broker("broker-a", &config);
+        assert_eq!(broker.handoff.hand_off().await.unwrap(), 0);
+        assert!(embedded.server.puts.lock().is_empty());
+
+        let late = broker.send(&["bob"], Duration::from_secs(60));
+        let soon = broker.send(&["bob"], Duration::from_secs(10));
+        let middle = broker.send(&["bob"], Duration::from_secs(30));
+        assert_eq!(broker.handoff.hand_off().await.unwrap(), 2);
+        let (_, record) = embedded.record();
+        let carried: Vec<&str> = record.deadlines.iter().map(|d| d.message_id.as_str()).collect();
+        assert_eq!(carried, [soon.as_str(), middle.as_str()]);
+        assert_ne!(carried[1], late);
+        assert_eq!(record.created_at, embedded.clock.now_millis());
+        assert_eq!(record.deadlines[0].fires_at, record.created_at + 10_000);
+    }
+
+    #[test]
+    fn slots_get_their_own_key_prefixes() {
+        assert_eq!(slot_prefix(""), "handoff._.");
+        assert_ne!(slot_prefix("0"), slot_prefix("1"));
+        assert!(record_key("0", "broker-a").starts_with(&slot_prefix("0")));
+        assert!(!record_key("", "broker-a").starts_with(&slot_prefix("0")));
+        // Broker IDs with dots stay one key token
+        assert_eq!(record_key("", "broker.a").matches('.').count(), 2);
+    }
+
+    /// Brokers against a JetStream server at `NATS_URL`, with a fresh handoff bucket and fallback stream
+    struct Cluster {
+        jetstream: jetstream::Context,
+        kv: kv::Store,
+        config: SoftHandoffConfig,
+        fallback: String,
+        clock: Arc<SimClock>,
+    }
+
+    impl Cluster {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+            let name = format!("handoff_test_{}", Uuid::new_v4().simple());
+            let kv = jetstream
+                .create_key_value(kv::Config {
+                    bucket: name.clone(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            jetstream
+                .create_stream(jetstream::stream::Config {
+                    name: name.clone(),
+                    subjects: vec![format!("{}.fallback", name)],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            Self {
+                jetstream,
+                kv,
+                config: SoftHandoffConfig {
+                    bucket: name.clone(),
+                    ..config()
+                },
+                fallback: name,
+                clock: Arc::new(SimClock::new()),
+            }
+        }
+
+        fn broker(&self, broker_id: &str) -> Broker {
+            let fallback_subject = format!("{}.fallback", self.fallback);
+            Broker::new(broker_id, &self.jetstream, &self.kv, &fallback_subject, &self.config, &self.clock)
+        }
+
+        async fn handoffs(&self) -> Vec<DeliveryHandOff> {
+            let mut stream = self.jetstream.get_stream(&self.fallback).await.unwrap();
+            let state = stream.info().await.unwrap().state;
+            let mut handoffs = Vec::new();
+            for sequence in state.first_sequence..=state.last_sequence {
+                if let Ok(raw) = stream.get_raw_message(sequence).await {
+                    let message = async_nats::Message::try_from(raw).unwrap();
+                    handoffs.push(serde_json::from_slice(&message.payload).unwrap());
+                }
+            }
+            handoffs
+        }
+    }
+
+    /// Run against a JetStream-enabled server at `NATS_URL`: `cargo test -- --ignored soft_handoff`
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_drain_restart_hands_the_deadline_to_the_successor_once() {
+        let cluster = Cluster::new().await;
+        let first = cluster.broker("broker-a");
+        let message_id = first.send(&["bob"], DEADLINE);
+        cluster.clock.advance(Duration::from_secs(10));
+        assert_eq!(first.handoff.hand_off().await.unwrap(), 1);
+        assert_eq!(first.handoff.claim().await.unwrap(), 0, "a broker never claims its own record");
+        drop(first);
+
+        cluster.clock.advance(Duration::from_secs(5));
+        let (second, third) = (cluster.broker("broker-b"), cluster.broker("broker-c"));
+        assert_eq!(second.handoff.claim().await.unwrap(), 1);
+        assert_eq!(third.handoff.claim().await.unwrap(), 0, "the record was claimed once");
+        assert!(cluster.kv.get(record_key("", "broker-a")).await.unwrap().is_none());
+
+        cluster.clock.advance(Duration::from_secs(15));
+        second.handed_off(&message_id, "bob").await;
+        let handoffs = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let handoffs = cluster.handoffs().await;
+                if !handoffs.is_empty() {
+                    return handoffs;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(handoffs.len(), 1);
+        assert_eq!(handoffs[0].message_id, message_id);
+        assert_eq!(handoffs[0].recipient, "bob");
+        assert_eq!(handoffs[0].reason, "deadline_exceeded");
+        assert!(third.state(&message_id, "bob").is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn stale_and_unreadable_records_are_discarded_unrestored() {
+        let cluster = Cluster::new().await;
+        let first = cluster.broker("broker-a");
+        let message_id = first.send(&["bob"], Duration::from_secs(600));
+        first.handoff.hand_off().await.unwrap();
+        cluster
+            .kv
+            .put(record_key("", "broker-z"), Bytes::from_static(b"not a record"))
+            .await
+            .unwrap();
+        drop(first);
+
+        cluster.clock.advance(cluster.config.record_ttl + Duration::from_secs(1));
+        let second = cluster.broker("broker-b");
+        assert_eq!(second.handoff.claim().await.unwrap(), 0);
+        assert!(second.state(&message_id, "bob").is_none());
+        for broker_id in ["broker-a", "broker-z"] {
+            assert!(cluster.kv.get(record_key("", broker_id)).await.unwrap().is_none(), "{}", broker_id);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn brokers_only_claim_their_own_slot() {
+        let cluster = Cluster::new().await;
+        let in_slot = |broker_id: &str, slot: &str| {
+            let config = SoftHandoffConfig {
+                slot: slot.into(),
+                ..cluster.config.clone()
+            };
+            let fallback_subject = format!("{}.fallback", cluster.fallback);
+            Broker::new(broker_id, &cluster.jetstream, &cluster.kv, &fallback_subject, &config, &cluster.clock)
+        };
+        let first = in_slot("broker-a", "0");
+        first.send(&["bob"], DEADLINE);
+        first.handoff.hand_off().await.unwrap();
+
+        assert_eq!(in_slot("broker-b", "1").handoff.claim().await.unwrap(), 0);
+        assert_eq!(in_slot("broker-c", "").handoff.claim().await.unwrap(), 0);
+        assert_eq!(in_slot("broker-d", "0").handoff.claim().await.unwrap(), 1);
+    }
+}
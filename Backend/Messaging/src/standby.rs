@@ -0,0 +1,397 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    cluster::ClusterView,
+    config::StandbyConfig,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerRole {
+    /// Initialized and warm, but consuming no ingress and owning nothing
+    Standby,
+    Active,
+}
+
+impl BrokerRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BrokerRole::Standby => "standby",
+            BrokerRole::Active => "active",
+        }
+    }
+}
+
+/// Why a standby broker went active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivationTrigger {
+    Command,
+    /// No active peer has been seen for `standby.auto_activate_after`
+    PeerLost,
+}
+
+impl ActivationTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActivationTrigger::Command => "command",
+            ActivationTrigger::PeerLost => "peer_lost",
+        }
+    }
+}
+
+/// Warm standby gate
+///
+/// A standby broker runs preflight, connects, and keeps its read-only
+/// state fresh (cluster view, control topic, KV watches) like any other
+/// broker, but it registers in the cluster as standby so it owns no
+/// partitions, and everything that consumes ingress or serves clients waits
+/// in `wait_active` before starting. Activation flips the role once; the
+/// time from trigger until every waiter is released is the activation
+/// latency, reported against `standby.activation_budget_ms`.
+pub struct StandbyController {
+    role: watch::Sender<BrokerRole>,
+    cluster: Arc<ClusterView>,
+    config: StandbyConfig,
+    audit: AuditLog,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl StandbyController {
+    /// Call before `ClusterView::spawn` so the first heartbeat already says standby
+    pub fn new(config: StandbyConfig, cluster: Arc<ClusterView>, audit: AuditLog, metrics: BrokerMetrics) -> Self {
+        let role = if config.enabled {
+            cluster.set_standby(true);
+            BrokerRole::Standby
+        } else {
+            BrokerRole::Active
+        };
+        metrics.update_broker_standby(role == BrokerRole::Standby);
+
+        Self {
+            role: watch::Sender::new(role),
+            cluster,
+            config,
+            audit,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn broker_id(&self) -> &str {
+        self.cluster.local_id()
+    }
+
+    pub fn role(&self) -> BrokerRole {
+        *self.role.borrow()
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.role() == BrokerRole::Standby
+    }
+
+    /// Resolves once the broker is active; immediately if it never was standby
+    pub async fn wait_active(&self) {
+        let mut role = self.role.subscribe();
+        // The sender lives as long as `self`, so this can't fail while we're borrowed
+        let _ = role.wait_for(|role| *role == BrokerRole::Active).await;
+    }
+
+    /// Switch to full operation; false if the broker already was active
+    pub async fn activate(&self, trigger: ActivationTrigger, actor: &str) -> bool {
+        let started = self.clock.now_instant();
+        if !self.role.send_if_modified(|role| {
+            let was_standby = *role == BrokerRole::Standby;
+            *role = BrokerRole::Active;
+            was_standby
+        }) {
+            return false;
+        }
+
+        // Claim partitions: peers see us as an owner from the next heartbeat on
+        self.cluster.set_standby(false);
+        if let Err(e) = self.cluster.heartbeat().await {
+            warn!("Heartbeat after activation failed, ownership waits for the next tick: {}", e);
+        }
+
+        let elapsed = self.clock.now_instant().saturating_duration_since(started);
+        let budget = Duration::from_millis(self.config.activation_budget_ms);
+        if elapsed > budget {
+            warn!("Standby activation took {:?}, over the {:?} budget", elapsed, budget);
+        }
+        info!("Broker activated from standby ({}) in {:?}", trigger.as_str(), elapsed);
+
+        self.metrics.update_broker_standby(false);
+        self.metrics
+            .record_standby_activation(trigger.as_str(), elapsed.as_secs_f64(), elapsed <= budget);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "broker.activated",
+            serde_json::json!({
+                "broker_id": self.cluster.local_id(),
+                "trigger": trigger.as_str(),
+                "activation_ms": elapsed.as_millis() as u64,
+            }),
+        ));
+        true
+    }
+
+    /// Activate automatically once no active peer has been seen for the threshold
+    pub fn spawn_peer_monitor(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.is_standby() || self.config.auto_activate_after.is_zero() {
+            return None;
+        }
+
+        let controller = Arc::clone(self);
+        Some(spawn_traced("standby_monitor", TaskContext::new("standby"), async move {
+            let threshold = controller.config.auto_activate_after.as_millis() as i64;
+            let poll = Duration::from_millis(controller.config.peer_poll_ms.max(10));
+            // Remembered across ticks because stale peers drop out of `members`
+            let mut last_active: Option<i64> = None;
+            while controller.is_standby() {
+                controller.clock.sleep(poll).await;
+                let newest = controller
+                    .cluster
+                    .members()
+                    .into_iter()
+                    .filter(|peer| !peer.standby)
+                    .map(|peer| peer.last_seen)
+                    .max();
+                last_active = last_active.max(newest);
+                // Never having seen an active peer means "not yet", not "lost"
+                let Some(last_seen) = last_active else {
+                    continue;
+                };
+                if controller.clock.now_millis() - last_seen >= threshold {
+                    controller
+                        .activate(ActivationTrigger::PeerLost, controller.cluster.local_id())
+                        .await;
+                }
+            }
+        }))
+    }
+}
+
+/// The failover tests run two in-process brokers against JetStream at `NATS_URL`
+/// (default `localhost:4222`): `cargo test -- --ignored standby`
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Instant};
+
+    use async_nats::jetstream::{
+        self,
+        consumer::{pull, PullConsumer},
+        kv, stream,
+    };
+    use parking_lot::Mutex;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    const AUTO_ACTIVATE_AFTER: Duration = Duration::from_millis(800);
+    const HEARTBEAT: Duration = Duration::from_millis(100);
+
+    struct Fleet {
+        context: jetstream::Context,
+        members: kv::Store,
+        ingress_subject: String,
+        consumer: PullConsumer,
+    }
+
+    impl Fleet {
+        async fn new() -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let context = jetstream::new(async_nats::connect(url).await.unwrap());
+            let id = Uuid::new_v4().simple();
+            let members = context
+                .create_key_value(kv::Config {
+                    bucket: format!("standby-cluster-test-{}", id),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let ingress_subject = format!("test.{}.ingress", id);
+            let stream = context
+                .create_stream(stream::Config {
+                    name: format!("standby-ingress-test-{}", id),
+                    subjects: vec![ingress_subject.clone()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            // Shared durable consumer, as every broker's ingress worker uses
+            let consumer = stream
+                .create_consumer(pull::Config {
+                    durable_name: Some("ingress".into()),
+                    ack_wait: Duration::from_secs(1),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            Self {
+                context,
+                members,
+                ingress_subject,
+                consumer,
+            }
+        }
+
+        fn cluster(&self, broker_id: &str) -> Arc<ClusterView> {
+            let mut config = BrokerConfig::load().unwrap().cluster;
+            config.heartbeat_interval = HEARTBEAT;
+            Arc::new(ClusterView::new(self.members.clone(), broker_id.into(), &config))
+        }
+
+        fn standby(&self, broker_id: &str, auto_activate_after: Duration) -> Arc<StandbyController> {
+            let mut config = BrokerConfig::load().unwrap().standby;
+            config.enabled = true;
+            config.auto_activate_after = auto_activate_after;
+            config.peer_poll_ms = 50;
+            let cluster = self.cluster(broker_id);
+            let controller = Arc::new(StandbyController::new(
+                config,
+                cluster.clone(),
+                AuditLog::tracing_only(),
+                BrokerMetrics::new().unwrap(),
+            ));
+            cluster.spawn();
+            controller
+        }
+
+
+        async fn publish(&self, id: usize) {
+            self.context
+                .publish(self.ingress_subject.clone(), format!("msg-{}", id).into())
+                .await
+                .unwrap()
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Ingress worker recording every message it acks
+    async fn consume(consumer: PullConsumer, consumed: Arc<Mutex<HashSet<String>>>) {
+        let mut messages = consumer.messages().await.unwrap();
+        while let Some(Ok(message)) = messages.next().await {
+            consumed.lock().insert(String::from_utf8_lossy(&message.payload).into_owned());
+            message.ack().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn standby_takes_over_a_killed_active_broker_without_losing_ingress() {
+        let fleet = Fleet::new().await;
+        let consumed = Arc::new(Mutex::new(HashSet::new()));
+
+        // Active broker: heartbeats and an ingress worker, both killed together
+        let active = fleet.cluster("broker-active");
+        let heartbeats = tokio::spawn({
+            let active = active.clone();
+            async move {
+                loop {
+                    active.heartbeat().await.unwrap();
+                    tokio::time::sleep(HEARTBEAT).await;
+                }
+            }
+        });
+        let active_worker = tokio::spawn(consume(fleet.consumer.clone(), consumed.clone()));
+
+        let standby = fleet.standby("broker-standby", AUTO_ACTIVATE_AFTER);
+        standby.spawn_peer_monitor().unwrap();
+        let standby_worker = tokio::spawn({
+            let (standby, consumer, consumed) = (standby.clone(), fleet.consumer.clone(), consumed.clone());
+            async move {
+                standby.wait_active().await;
+                consume(consumer, consumed).await;
+            }
+        });
+
+        for id in 0..100 {
+            fleet.publish(id).await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(standby.is_standby());
+        assert_eq!(standby.cluster.members().len(), 2);
+        // Standby owns nothing while the active broker is alive
+        assert!((0..100).all(|i| standby.cluster.remote_owner(&format!("group_{}", i)).is_some()));
+
+        heartbeats.abort();
+        active_worker.abort();
+        let killed = Instant::now();
+
+        // Clients keep sending through the gap
+        for id in 100..200 {
+            fleet.publish(id).await;
+        }
+        tokio::time::timeout(AUTO_ACTIVATE_AFTER * 4, standby.wait_active())
+            .await
+            .expect("standby never activated");
+        let gap = killed.elapsed();
+        assert!(gap >= AUTO_ACTIVATE_AFTER - HEARTBEAT, "activated after only {:?}", gap);
+        assert!(gap <= AUTO_ACTIVATE_AFTER + Duration::from_millis(500), "activation took {:?}", gap);
+
+        for id in 200..300 {
+            fleet.publish(id).await;
+        }
+        // Messages in flight on the killed worker come back after the ack wait
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while consumed.lock().len() < 300 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("only {} of 300 ingress messages were consumed", consumed.lock().len()));
+        assert!((0..300).all(|id| consumed.lock().contains(&format!("msg-{}", id))));
+        standby_worker.abort();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn activation_by_command_claims_ownership_once() {
+        let fleet = Fleet::new().await;
+        let standby = fleet.standby("broker-standby", Duration::ZERO);
+        assert!(standby.spawn_peer_monitor().is_none());
+        assert_eq!(standby.role(), BrokerRole::Standby);
+
+        tokio::time::sleep(HEARTBEAT * 3).await;
+        assert!(standby.cluster.members().iter().all(|peer| peer.standby));
+        // A standby-only cluster has no owner at all
+        assert!(standby.cluster.remote_owner("group_team").is_none());
+
+        let started = Instant::now();
+        assert!(standby.activate(ActivationTrigger::Command, "ops").await);
+        assert!(started.elapsed() < Duration::from_millis(BrokerConfig::load().unwrap().standby.activation_budget_ms));
+        tokio::time::timeout(Duration::from_millis(100), standby.wait_active())
+            .await
+            .unwrap();
+        assert!(!standby.activate(ActivationTrigger::Command, "ops").await);
+
+        tokio::time::sleep(HEARTBEAT * 3).await;
+        assert!(standby.cluster.members().iter().all(|peer| !peer.standby));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_standby_without_an_active_peer_waits() {
+        let fleet = Fleet::new().await;
+        let standby = fleet.standby("broker-standby", Duration::from_millis(200));
+        standby.spawn_peer_monitor().unwrap();
+
+        // Never having seen an active peer is not a peer loss
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(standby.is_standby());
+    }
+}
@@ -0,0 +1,552 @@
+use std::{collections::HashMap, sync::Arc};
+use arc_swap::{ArcSwap, Guard};
+use tracing::{info, warn};
+
+use crate::{
+    config::BrokerConfig,
+    config_watch::ConfigWatcher,
+};
+
+/// Most `*`/`{name}` tokens one pattern may have
+pub const MAX_CAPTURES: usize = 4;
+
+/// What a broker-consumed subject is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubjectClass {
+    Receipt,
+    ReceiptCapabilities,
+    Control,
+    ConfigDump,
+    CorruptionReport,
+    DictionaryHoldings,
+    DictionaryRequest,
+}
+
+impl SubjectClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubjectClass::Receipt => "receipt",
+            SubjectClass::ReceiptCapabilities => "receipt_capabilities",
+            SubjectClass::Control => "control",
+            SubjectClass::ConfigDump => "config_dump",
+            SubjectClass::CorruptionReport => "corruption_report",
+            SubjectClass::DictionaryHoldings => "dictionary_holdings",
+            SubjectClass::DictionaryRequest => "dictionary_request",
+        }
+    }
+}
+
+struct Route {
+    class: SubjectClass,
+    pattern: String,
+    /// Name of each wildcard position; `None` for a bare `*`
+    names: Vec<Option<String>>,
+}
+
+#[derive(Default)]
+struct Node {
+    literals: HashMap<Box<str>, usize>,
+    wildcard: Option<usize>,
+    /// Route of a pattern ending in `>` after this node
+    tail: Option<usize>,
+    /// Route of a pattern ending at this node
+    end: Option<usize>,
+}
+
+/// A classified subject; captures borrow from the subject, nothing is copied
+pub struct SubjectMatch<'m, 's> {
+    route: &'m Route,
+    captures: [&'s str; MAX_CAPTURES],
+    tail: Option<&'s str>,
+}
+
+impl<'s> SubjectMatch<'_, 's> {
+    pub fn class(&self) -> SubjectClass {
+        self.route.class
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.route.pattern
+    }
+
+    /// Token matched by `{name}`
+    pub fn capture(&self, name: &str) -> Option<&'s str> {
+        self.route
+            .names
+            .iter()
+            .position(|candidate| candidate.as_deref() == Some(name))
+            .map(|position| self.captures[position])
+    }
+
+    /// Tokens matched by a trailing `>`, dots included
+    pub fn tail(&self) -> Option<&'s str> {
+        self.tail
+    }
+}
+
+/// Token trie over every subject pattern the broker consumes
+///
+/// Patterns are NATS subjects whose tokens may be `*`, `{name}` (a `*`
+/// whose token is captured under `name`) or a final `>`. Matching walks
+/// the trie token by token, preferring a literal over a wildcard over `>`
+/// and backtracking when the preferred branch dead-ends, so the most
+/// specific pattern wins. It doesn't allocate: tokens are split off the
+/// subject in place and captures are slices of it in a fixed-size array.
+pub struct SubjectMatcher {
+    nodes: Vec<Node>,
+    routes: Vec<Route>,
+}
+
+impl SubjectMatcher {
+    pub fn compile<'p>(patterns: impl IntoIterator<Item = (SubjectClass, &'p str)>) -> Result<Self, SubjectMatchError> {
+        let mut matcher = Self {
+            nodes: vec![Node::default()],
+            routes: Vec::new(),
+        };
+        for (class, pattern) in patterns {
+            matcher.insert(class, pattern)?;
+        }
+        Ok(matcher)
+    }
+
+    /// Patterns of every subject the broker's own consumers subscribe to
+    pub fn from_config(config: &BrokerConfig) -> Result<Self, SubjectMatchError> {
+        let control = config.nats.control_topic.as_str();
+        let dump = format!("{}.config.{{broker_id}}", control);
+        Self::compile([
+            (SubjectClass::Receipt, config.receipts.subject.as_str()),
+            (SubjectClass::ReceiptCapabilities, config.receipts.capabilities_subject.as_str()),
+            (SubjectClass::Control, control),
+            (SubjectClass::ConfigDump, dump.as_str()),
+            (SubjectClass::CorruptionReport, config.integrity.report_subject.as_str()),
+            (SubjectClass::DictionaryHoldings, config.compression.holdings_subject.as_str()),
+            (SubjectClass::DictionaryRequest, config.compression.request_subject.as_str()),
+        ])
+    }
+
+    fn insert(&mut self, class: SubjectClass, pattern: &str) -> Result<(), SubjectMatchError> {
+        let invalid = |reason: &str| SubjectMatchError::Invalid(pattern.to_string(), reason.to_string());
+        let mut node = 0;
+        let mut names: Vec<Option<String>> = Vec::new();
+        let mut tail = false;
+        for token in pattern.split('.') {
+            if tail {
+                return Err(invalid("`>` must be the last token"));
+            }
+            node = match token {
+                "" => return Err(invalid("empty token")),
+                ">" => {
+                    tail = true;
+                    node
+                }
+                "*" => {
+                    names.push(None);
+                    self.wildcard_child(node)
+                }
+                _ if token.starts_with('{') && token.ends_with('}') => {
+                    let name = &token[1..token.len() - 1];
+                    if name.is_empty() || names.iter().flatten().any(|existing| existing == name) {
+                        return Err(invalid("capture names must be non-empty and unique"));
+                    }
+                    names.push(Some(name.to_string()));
+                    self.wildcard_child(node)
+                }
+                _ if token.contains(['*', '>', '{', '}']) => return Err(invalid("wildcards must be whole tokens")),
+                _ => self.literal_child(node, token),
+            };
+        }
+        if names.len() > MAX_CAPTURES {
+            return Err(invalid("too many wildcards"));
+        }
+
+        let route = self.routes.len();
+        let slot = match tail {
+            true => &mut self.nodes[node].tail,
+            false => &mut self.nodes[node].end,
+        };
+        if let Some(existing) = *slot {
+            return Err(SubjectMatchError::Conflict(
+                pattern.to_string(),
+                self.routes[existing].pattern.clone(),
+            ));
+        }
+        *slot = Some(route);
+        self.routes.push(Route {
+            class,
+            pattern: pattern.to_string(),
+            names,
+        });
+        Ok(())
+    }
+
+    fn literal_child(&mut self, node: usize, token: &str) -> usize {
+        if let Some(&child) = self.nodes[node].literals.get(token) {
+            return child;
+        }
+        let child = self.push_node();
+        self.nodes[node].literals.insert(token.into(), child);
+        child
+    }
+
+    fn wildcard_child(&mut self, node: usize) -> usize {
+        if let Some(child) = self.nodes[node].wildcard {
+            return child;
+        }
+        let child = self.push_node();
+        self.nodes[node].wildcard = Some(child);
+        child
+    }
+
+    fn push_node(&mut self) -> usize {
+        self.nodes.push(Node::default());
+        self.nodes.len() - 1
+    }
+
+    /// The most specific pattern matching `subject`, if any
+    pub fn classify<'m, 's>(&'m self, subject: &'s str) -> Option<SubjectMatch<'m, 's>> {
+        // Checked up front, since a `>` would otherwise take an empty token into its tail
+        if subject.split('.').any(str::is_empty) {
+            return None;
+        }
+        let mut captures = [""; MAX_CAPTURES];
+        let (route, tail) = self.walk(0, Some(subject), &mut captures, 0)?;
+        Some(SubjectMatch {
+            route: &self.routes[route],
+            captures,
+            tail,
+        })
+    }
+
+    fn walk<'s>(
+        &self,
+        node: usize,
+        rest: Option<&'s str>,
+        captures: &mut [&'s str; MAX_CAPTURES],
+        captured: usize,
+    ) -> Option<(usize, Option<&'s str>)> {
+        let node = &self.nodes[node];
+        let Some(rest) = rest else {
+            return node.end.map(|route| (route, None));
+        };
+        let (token, next) = match rest.split_once('.') {
+            Some((token, next)) => (token, Some(next)),
+            None => (rest, None),
+        };
+
+        if let Some(&child) = node.literals.get(token) {
+            if let Some(found) = self.walk(child, next, captures, captured) {
+                return Some(found);
+            }
+        }
+        if let Some(child) = node.wildcard.filter(|_| captured < MAX_CAPTURES) {
+            captures[captured] = token;
+            if let Some(found) = self.walk(child, next, captures, captured + 1) {
+                return Some(found);
+            }
+        }
+        node.tail.map(|route| (route, Some(rest)))
+    }
+}
+
+/// NATS subscription subject for a pattern: `{name}` tokens become `*`
+pub fn subscription_subject(pattern: &str) -> String {
+    pattern
+        .split('.')
+        .map(|token| match token.starts_with('{') && token.ends_with('}') {
+            true => "*",
+            false => token,
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The live `SubjectMatcher`, recompiled on config reload
+///
+/// A reloaded config whose patterns don't compile keeps the previous
+/// matcher. Subscriptions aren't changed by a reload, so a consumer whose
+/// subject moved still receives the old one until restart and drops what
+/// no longer classifies.
+pub struct SubjectRouter {
+    matcher: ArcSwap<SubjectMatcher>,
+}
+
+impl SubjectRouter {
+    pub fn new(config: &BrokerConfig) -> Result<Self, SubjectMatchError> {
+        Ok(Self {
+            matcher: ArcSwap::from_pointee(SubjectMatcher::from_config(config)?),
+        })
+    }
+
+    pub fn load(&self) -> Guard<Arc<SubjectMatcher>> {
+        self.matcher.load()
+    }
+
+    pub fn reload(&self, config: &BrokerConfig) {
+        match SubjectMatcher::from_config(config) {
+            Ok(matcher) => {
+                info!("Subject matcher reloaded: {} patterns", matcher.routes.len());
+                self.matcher.store(Arc::new(matcher));
+            }
+            Err(e) => warn!("Keeping the previous subject matcher: {}", e),
+        }
+    }
+
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let router = Arc::clone(self);
+        watcher.on_reload(move |config| router.reload(config));
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SubjectMatchError {
+    #[error("invalid subject pattern {0}: {1}")]
+    Invalid(String, String),
+    #[error("subject pattern {0} overlaps {1}")]
+    Conflict(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+
+    fn matcher(patterns: &[(SubjectClass, &str)]) -> SubjectMatcher {
+        SubjectMatcher::compile(patterns.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn the_most_specific_pattern_wins() {
+        let matcher = matcher(&[
+            (SubjectClass::Receipt, "broker.receipts.{gateway_id}"),
+            (SubjectClass::ReceiptCapabilities, "broker.receipts.capabilities"),
+            (SubjectClass::Control, "broker.control.>"),
+            (SubjectClass::ConfigDump, "broker.control.config.{broker_id}"),
+        ]);
+
+        let receipt = matcher.classify("broker.receipts.gw-7").unwrap();
+        assert_eq!(receipt.class(), SubjectClass::Receipt);
+        assert_eq!(receipt.capture("gateway_id"), Some("gw-7"));
+        assert_eq!(receipt.capture("broker_id"), None);
+        assert_eq!(receipt.pattern(), "broker.receipts.{gateway_id}");
+        assert_eq!(matcher.classify("broker.receipts.capabilities").unwrap().class(), SubjectClass::ReceiptCapabilities);
+
+        let dump = matcher.classify("broker.control.config.b-1").unwrap();
+        assert_eq!(dump.class(), SubjectClass::ConfigDump);
+        assert_eq!(dump.capture("broker_id"), Some("b-1"));
+        // One token short of the dump pattern, and one too many: both fall back to `>`
+        for subject in ["broker.control.config", "broker.control.config.b-1.extra"] {
+            let control = matcher.classify(subject).unwrap();
+            assert_eq!(control.class(), SubjectClass::Control);
+            assert_eq!(control.tail(), subject.strip_prefix("broker.control."));
+        }
+
+        for subject in ["broker.control", "broker.receipts", "broker.receipts.gw.7", "broker..receipts", "", "other"] {
+            assert!(matcher.classify(subject).is_none(), "{}", subject);
+        }
+    }
+
+    #[test]
+    fn malformed_and_overlapping_patterns_are_refused() {
+        for pattern in ["a.>.b", "a..b", "a.b*", "a.{}", "a.{x}.{x}", "a.*.*.*.*.*", "a.{x"] {
+            let compiled = SubjectMatcher::compile([(SubjectClass::Control, pattern)]);
+            assert!(matches!(compiled, Err(SubjectMatchError::Invalid(..))), "{}", pattern);
+        }
+        let overlapping = SubjectMatcher::compile([(SubjectClass::Receipt, "a.*"), (SubjectClass::Control, "a.{x}")]);
+        assert!(matches!(overlapping, Err(SubjectMatchError::Conflict(..))));
+        assert!(SubjectMatcher::compile([(SubjectClass::Receipt, "a.*"), (SubjectClass::Control, "a.>")]).is_ok());
+    }
+
+    #[test]
+    fn subscriptions_replace_captures_with_wildcards() {
+        assert_eq!(subscription_subject("broker.receipts.{gateway_id}"), "broker.receipts.*");
+        assert_eq!(subscription_subject("broker.control.>"), "broker.control.>");
+        assert_eq!(subscription_subject("broker.receipts"), "broker.receipts");
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Reference {
+        pattern: String,
+        captures: Vec<(String, String)>,
+        tail: Option<String>,
+    }
+
+    /// Every pattern tried against every subject; ties go to the pattern
+    /// whose tokens, first to last, are literal before wildcard before `>`
+    fn reference(patterns: &[String], subject: &str) -> Option<Reference> {
+        let tokens: Vec<&str> = subject.split('.').collect();
+        if tokens.iter().any(|token| token.is_empty()) {
+            return None;
+        }
+        let mut best: Option<(Vec<u8>, Reference)> = None;
+        'patterns: for pattern in patterns {
+            let mut rank = Vec::new();
+            let mut captures = Vec::new();
+            let mut tail = None;
+            let pattern_tokens: Vec<&str> = pattern.split('.').collect();
+            for (position, &token) in pattern_tokens.iter().enumerate() {
+                if token == ">" {
+                    if position >= tokens.len() {
+                        continue 'patterns;
+                    }
+                    tail = Some(tokens[position..].join("."));
+                    rank.push(2);
+                    break;
+                }
+                let Some(&subject_token) = tokens.get(position) else {
+                    continue 'patterns;
+                };
+                if token == "*" || token.starts_with('{') {
+                    if let Some(name) = token.strip_prefix('{').and_then(|token| token.strip_suffix('}')) {
+                        captures.push((name.to_string(), subject_token.to_string()));
+                    }
+                    rank.push(1);
+                } else if token == subject_token {
+                    rank.push(0);
+                } else {
+                    continue 'patterns;
+                }
+            }
+            if tail.is_none() && pattern_tokens.len() != tokens.len() {
+                continue;
+            }
+            if best.as_ref().is_none_or(|(best, _)| rank < *best) {
+                let found = Reference {
+                    pattern: pattern.clone(),
+                    captures,
+                    tail,
+                };
+                best = Some((rank, found));
+            }
+        }
+        best.map(|(_, found)| found)
+    }
+
+    fn random_pattern(rng: &mut StdRng) -> String {
+        let length = rng.gen_range(1..=4);
+        let mut names = 0;
+        let mut tokens = Vec::new();
+        for position in 0..length {
+            let token = match rng.gen_range(0..10) {
+                0..=4 => ["a", "b", "c"].choose(rng).unwrap().to_string(),
+                5 | 6 => "*".to_string(),
+                7 | 8 => {
+                    names += 1;
+                    format!("{{n{}}}", names)
+                }
+                _ if position == length - 1 => ">".to_string(),
+                _ => "a".to_string(),
+            };
+            tokens.push(token);
+        }
+        tokens.join(".")
+    }
+
+    fn random_subject(rng: &mut StdRng) -> String {
+        let length = rng.gen_range(0..=5);
+        (0..length)
+            .map(|_| match rng.gen_range(0..20) {
+                0 => "",
+                1 => "d",
+                _ => ["a", "b", "c"].choose(rng).unwrap(),
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    #[test]
+    fn classification_agrees_with_a_reference_matcher() {
+        let mut rng = StdRng::seed_from_u64(478);
+        for _ in 0..500 {
+            let mut accepted = Vec::new();
+            let mut matcher = SubjectMatcher::compile([]).unwrap();
+            for _ in 0..rng.gen_range(1..12) {
+                let pattern = random_pattern(&mut rng);
+                if matcher.insert(SubjectClass::Control, &pattern).is_ok() {
+                    accepted.push(pattern);
+                }
+            }
+
+            for _ in 0..50 {
+                let subject = random_subject(&mut rng);
+                let expected = reference(&accepted, &subject);
+                let actual = matcher.classify(&subject).map(|found| Reference {
+                    pattern: found.pattern().to_string(),
+                    captures: found
+                        .route
+                        .names
+                        .iter()
+                        .flatten()
+                        .map(|name| (name.clone(), found.capture(name).unwrap().to_string()))
+                        .collect(),
+                    tail: found.tail().map(str::to_string),
+                });
+                assert_eq!(actual, expected, "{:?} against {:?}", subject, accepted);
+            }
+        }
+    }
+
+    fn receipts_by_gateway() -> BrokerConfig {
+        let mut config = BrokerConfig::load().unwrap();
+        config.receipts.subject = "broker.receipts.{gateway_id}".into();
+        config
+    }
+
+    #[test]
+    fn the_configured_subjects_classify() {
+        let config = receipts_by_gateway();
+        let matcher = SubjectMatcher::from_config(&config).unwrap();
+        let cases = [
+            ("broker.receipts.gw-1", SubjectClass::Receipt),
+            (config.receipts.capabilities_subject.as_str(), SubjectClass::ReceiptCapabilities),
+            (config.nats.control_topic.as_str(), SubjectClass::Control),
+            (config.integrity.report_subject.as_str(), SubjectClass::CorruptionReport),
+            (config.compression.holdings_subject.as_str(), SubjectClass::DictionaryHoldings),
+            (config.compression.request_subject.as_str(), SubjectClass::DictionaryRequest),
+        ];
+        for (subject, class) in cases {
+            assert_eq!(matcher.classify(subject).map(|found| found.class()), Some(class), "{}", subject);
+        }
+        let dump = format!("{}.config.broker-2", config.nats.control_topic);
+        assert_eq!(matcher.classify(&dump).unwrap().capture("broker_id"), Some("broker-2"));
+    }
+
+    #[test]
+    fn reloads_swap_the_matcher_and_keep_it_when_the_new_one_is_bad() {
+        let router = SubjectRouter::new(&BrokerConfig::load().unwrap()).unwrap();
+        let held = router.load();
+        assert!(held.classify("broker.receipts.gw-1").is_none());
+
+        router.reload(&receipts_by_gateway());
+        assert_eq!(router.load().classify("broker.receipts.gw-1").unwrap().capture("gateway_id"), Some("gw-1"));
+        // A matcher loaded before the reload is left whole
+        assert!(held.classify("broker.receipts.gw-1").is_none());
+        assert!(held.classify("broker.receipts").is_some());
+
+        let mut bad = receipts_by_gateway();
+        bad.receipts.subject = "broker.>.receipts".into();
+        router.reload(&bad);
+        assert!(router.load().classify("broker.receipts.gw-1").is_some());
+    }
+
+    /// The receipt path's classify and capture, measured per match. Needs
+    /// the counting allocator: `cargo test --no-default-features --features tls subject_match`
+    #[cfg(not(feature = "jemalloc"))]
+    #[test]
+    fn matching_a_receipt_allocates_nothing() {
+        use crate::envelope_guard::allocations;
+
+        let router = SubjectRouter::new(&receipts_by_gateway()).unwrap();
+        let subjects: Vec<String> = (0..1000).map(|i| format!("broker.receipts.gw-{}", i)).collect();
+        // arc_swap registers each thread on its first load; that's once per thread, not per match
+        drop(router.load());
+        let allocated = allocations::measure(|| {
+            for subject in &subjects {
+                let matcher = router.load();
+                let routed = matcher.classify(subject).filter(|routed| routed.class() == SubjectClass::Receipt);
+                assert!(routed.and_then(|routed| routed.capture("gateway_id")).is_some());
+            }
+        });
+        assert_eq!(allocated, 0, "{} bytes over {} matches", allocated, subjects.len());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
+use dashmap::DashMap;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    metrics::BrokerMetrics,
+};
+
+/// Registry of NATS subjects the broker has created or published to
+///
+/// Drives the `broker_active_topics` gauge with the number of subjects that
+/// saw activity within `active_window`.
+pub struct SubjectRegistry {
+    subjects: DashMap<String, AtomicI64>,
+    active_window: Duration,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl SubjectRegistry {
+    pub fn new(active_window: Duration, metrics: BrokerMetrics) -> Self {
+        Self {
+            subjects: DashMap::new(),
+            active_window,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Record activity on a subject
+    pub fn touch(&self, subject: &str) {
+        let now = self.clock.now_millis();
+
+        // Fast path: no allocation for subjects we already know
+        if let Some(last_activity) = self.subjects.get(subject) {
+            last_activity.store(now, Ordering::Relaxed);
+            return;
+        }
+
+        self.subjects
+            .entry(subject.to_string())
+            .or_insert_with(|| AtomicI64::new(now))
+            .store(now, Ordering::Relaxed);
+    }
+
+    /// Number of subjects active within the window
+    pub fn active_count(&self) -> usize {
+        let cutoff = self.clock.now_millis() - self.active_window.as_millis() as i64;
+        self.subjects
+            .iter()
+            .filter(|entry| entry.value().load(Ordering::Relaxed) >= cutoff)
+            .count()
+    }
+
+    /// Total subjects tracked, active or not
+    pub fn len(&self) -> usize {
+        self.subjects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subjects.is_empty()
+    }
+
+    /// Forget subjects idle for longer than `idle`, returning how many were removed
+    pub fn prune(&self, idle: Duration) -> usize {
+        let cutoff = self.clock.now_millis() - idle.as_millis() as i64;
+        let before = self.subjects.len();
+        self.subjects
+            .retain(|_, last_activity| last_activity.load(Ordering::Relaxed) >= cutoff);
+        before.saturating_sub(self.subjects.len())
+    }
+
+    pub fn refresh_gauge(&self) {
+        self.metrics.update_active_topics(self.active_count() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clock::SimClock;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn registry() -> (SubjectRegistry, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let registry = SubjectRegistry::new(WINDOW, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (registry, clock)
+    }
+
+    #[test]
+    fn counts_only_subjects_active_within_the_window() {
+        let (registry, clock) = registry();
+        for i in 0..1_000 {
+            registry.touch(&format!("history.conv-{}", i));
+        }
+        clock.advance(WINDOW / 2);
+        registry.touch("history.conv-0");
+        assert_eq!(registry.active_count(), 1_000);
+
+        clock.advance(WINDOW / 2 + Duration::from_secs(1));
+        assert_eq!(registry.active_count(), 1);
+        assert_eq!(registry.len(), 1_000);
+    }
+
+    #[test]
+    fn prune_forgets_idle_subjects() {
+        let (registry, clock) = registry();
+        for i in 0..1_000 {
+            registry.touch(&format!("history.conv-{}", i));
+        }
+        clock.advance(Duration::from_secs(10));
+        registry.touch("history.conv-7");
+        clock.advance(Duration::from_secs(10));
+
+        assert_eq!(registry.prune(Duration::from_secs(15)), 999);
+        assert_eq!(registry.len(), 1);
+    }
+}
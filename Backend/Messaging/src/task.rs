@@ -0,0 +1,367 @@
+use std::{future::Future, sync::OnceLock, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+use crate::{
+    backoff::{BackoffPolicy, Jitter},
+    clock::{SharedClock, SystemClock},
+    metrics::BrokerMetrics,
+};
+
+static BROKER_ID: OnceLock<String> = OnceLock::new();
+
+/// Set once at startup so every task span carries the broker ID
+pub fn set_broker_id(broker_id: &str) {
+    let _ = BROKER_ID.set(broker_id.to_string());
+}
+
+/// Correlation fields attached to a background task's span
+#[derive(Debug, Clone)]
+pub struct TaskContext {
+    pub subsystem: &'static str,
+    pub shard: Option<usize>,
+    pub message_id: Option<String>,
+    pub conversation_id: Option<String>,
+}
+
+impl TaskContext {
+    pub fn new(subsystem: &'static str) -> Self {
+        Self {
+            subsystem,
+            shard: None,
+            message_id: None,
+            conversation_id: None,
+        }
+    }
+
+    pub fn with_shard(mut self, shard: usize) -> Self {
+        self.shard = Some(shard);
+        self
+    }
+
+    pub fn with_message(mut self, message_id: impl Into<String>) -> Self {
+        self.message_id = Some(message_id.into());
+        self
+    }
+
+    pub fn with_conversation(mut self, conversation_id: impl Into<String>) -> Self {
+        self.conversation_id = Some(conversation_id.into());
+        self
+    }
+
+    pub fn span(&self, name: &'static str) -> Span {
+        let span = info_span!(
+            "task",
+            task = name,
+            subsystem = self.subsystem,
+            broker_id = BROKER_ID.get().map(String::as_str).unwrap_or(""),
+            shard = field::Empty,
+            message_id = field::Empty,
+            conversation_id = field::Empty,
+        );
+        if let Some(shard) = self.shard {
+            span.record("shard", shard);
+        }
+        if let Some(message_id) = &self.message_id {
+            span.record("message_id", message_id.as_str());
+        }
+        if let Some(conversation_id) = &self.conversation_id {
+            span.record("conversation_id", conversation_id.as_str());
+        }
+        span
+    }
+}
+
+/// `tokio::spawn` with the task running inside its context span
+pub fn spawn_traced<F>(name: &'static str, ctx: TaskContext, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.instrument(ctx.span(name)))
+}
+
+/// Log panics with the ambient span fields; the panicking task's span is
+/// still entered when the hook runs
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        error!(panic = %info, "Task panicked");
+        previous(info);
+    }));
+}
+
+/// What happens when a supervised task panics or returns an error
+#[derive(Debug, Clone, Copy)]
+pub enum RestartPolicy {
+    /// Restart with exponential backoff between attempts
+    Restart {
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    },
+    /// Trigger broker shutdown
+    Escalate,
+}
+
+/// Broker-wide shutdown signal raised by escalating tasks
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    tx: watch::Sender<bool>,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self { tx }
+    }
+
+    pub fn trigger(&self, reason: &str) {
+        error!("Shutdown triggered: {}", reason);
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spawns long-running tasks with a restart policy declared at registration
+#[derive(Clone)]
+pub struct TaskSupervisor {
+    shutdown: ShutdownSignal,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl TaskSupervisor {
+    pub fn new(shutdown: ShutdownSignal, metrics: BrokerMetrics) -> Self {
+        Self {
+            shutdown,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Run `factory()` until shutdown, applying `policy` to panics and errors
+    pub fn spawn<F, Fut>(&self, name: &'static str, ctx: TaskContext, policy: RestartPolicy, factory: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let clock = self.clock.clone();
+        let metrics = self.metrics.clone();
+        let span = ctx.span(name);
+
+        tokio::spawn(
+            async move {
+                let mut backoff = match policy {
+                    RestartPolicy::Restart { initial_backoff, max_backoff } => Some(
+                        BackoffPolicy::new(initial_backoff)
+                            .max(max_backoff)
+                            .jitter(Jitter::Equal)
+                            .iter(),
+                    ),
+                    RestartPolicy::Escalate => None,
+                };
+
+                loop {
+                    let attempt = tokio::spawn(factory().instrument(Span::current()));
+                    let failure = tokio::select! {
+                        result = attempt => match result {
+                            Ok(Ok(())) => {
+                                info!("Task {} finished", name);
+                                return;
+                            }
+                            Ok(Err(e)) => format!("error: {:#}", e),
+                            Err(join) if join.is_panic() => {
+                                metrics.record_task_panic(ctx.subsystem);
+                                "panic".to_string()
+                            }
+                            Err(join) => format!("cancelled: {}", join),
+                        },
+                        _ = shutdown.wait() => return,
+                    };
+
+                    match policy {
+                        RestartPolicy::Restart { max_backoff, .. } => {
+                            let delay = backoff.as_mut().and_then(Iterator::next).unwrap_or(max_backoff);
+                            warn!("Task {} failed ({}), restarting in {:?}", name, failure, delay);
+                            metrics.record_task_restart(ctx.subsystem);
+                            tokio::select! {
+                                _ = clock.sleep(delay) => {}
+                                _ = shutdown.wait() => return,
+                            }
+                        }
+                        RestartPolicy::Escalate => {
+                            shutdown.trigger(&format!("task {} failed: {}", name, failure));
+                            return;
+                        }
+                    }
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        metrics::MetricScope,
+    };
+
+    /// Process-wide recorder; each test reads its own prefixed series
+    fn recorder() -> &'static PrometheusHandle {
+        static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+        HANDLE.get_or_init(|| PrometheusBuilder::new().install_recorder().unwrap())
+    }
+
+    fn supervisor(prefix: &str) -> (TaskSupervisor, ShutdownSignal) {
+        recorder();
+        let metrics = BrokerMetrics::scoped(MetricScope::new(Some(prefix))).unwrap();
+        let shutdown = ShutdownSignal::new();
+        (TaskSupervisor::new(shutdown.clone(), metrics), shutdown)
+    }
+
+    fn counter(name: &str) -> Option<u64> {
+        recorder()
+            .render()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.trim().parse().ok())
+    }
+
+    async fn corrupted() -> anyhow::Result<()> {
+        panic!("consumer state corrupted")
+    }
+
+    async fn always_fails() -> anyhow::Result<()> {
+        anyhow::bail!("always fails")
+    }
+
+    #[tokio::test]
+    async fn a_panicking_worker_restarts_with_backoff() {
+        let (supervisor, shutdown) = supervisor("restart_test");
+        // Backoff sleeps run back to back, so the gaps are exactly the delays
+        let clock = Arc::new(SimClock::new().auto_advance(true));
+        let supervisor = supervisor.with_clock(clock.clone());
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let policy = RestartPolicy::Restart {
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(80),
+        };
+
+        let started = Arc::clone(&attempts);
+        let handle = supervisor.spawn("retry_worker", TaskContext::new("retry"), policy, move || {
+            let (started, clock) = (Arc::clone(&started), clock.clone());
+            async move {
+                let attempt = {
+                    let mut started = started.lock().unwrap();
+                    started.push(clock.now_instant());
+                    started.len()
+                };
+                if attempt <= 3 {
+                    panic!("worker crashed on attempt {}", attempt);
+                }
+                anyhow::Ok(())
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 4);
+        // Equal jitter keeps each delay within [half, full] of 20ms, 40ms, 80ms
+        for (gap, base) in attempts.windows(2).map(|w| w[1] - w[0]).zip([20, 40, 80]) {
+            assert!(gap >= Duration::from_millis(base / 2), "restarted after {:?}", gap);
+            assert!(gap <= Duration::from_millis(base), "restarted after {:?}", gap);
+        }
+        assert!(!shutdown.is_triggered());
+        assert_eq!(counter("restart_test_broker_task_panics_total{subsystem=\"retry\"}"), Some(3));
+        assert_eq!(counter("restart_test_broker_task_restarts_total{subsystem=\"retry\"}"), Some(3));
+    }
+
+    #[tokio::test]
+    async fn errors_restart_without_counting_as_panics() {
+        let (supervisor, _) = supervisor("error_test");
+        let runs = Arc::new(AtomicUsize::new(0));
+        let policy = RestartPolicy::Restart {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+
+        let counted = Arc::clone(&runs);
+        let handle = supervisor.spawn("presence_poller", TaskContext::new("presence"), policy, move || {
+            let runs = counted.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                if runs == 1 {
+                    anyhow::bail!("poll failed");
+                }
+                anyhow::Ok(())
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(counter("error_test_broker_task_panics_total{subsystem=\"presence\"}"), None);
+        assert_eq!(counter("error_test_broker_task_restarts_total{subsystem=\"presence\"}"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn an_escalating_task_triggers_shutdown() {
+        let (supervisor, shutdown) = supervisor("escalate_test");
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counted = Arc::clone(&runs);
+        let handle = supervisor.spawn("consumer", TaskContext::new("routing"), RestartPolicy::Escalate, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            corrupted()
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), shutdown.wait()).await.unwrap();
+        handle.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(counter("escalate_test_broker_task_panics_total{subsystem=\"routing\"}"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_a_restarting_task() {
+        let (supervisor, shutdown) = supervisor("shutdown_test");
+        let policy = RestartPolicy::Restart {
+            initial_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+        };
+        let handle = supervisor.spawn("worker", TaskContext::new("retry"), policy, always_fails);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        shutdown.trigger("test");
+        tokio::time::timeout(Duration::from_secs(5), handle).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,926 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+};
+use async_nats::jetstream::{self, kv, stream};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+use crate::{
+    api::auth::{ApiAuth, Scope},
+    audit::{AuditEntry, AuditLog},
+    clock::{SharedClock, SystemClock},
+    config::{ApiKeyConfig, ContentTypeRule, PreviewPolicy, TenantConfig},
+    config_override::RuntimeOverrides,
+    control::{ControlCommand, ControlMessage},
+    metrics::BrokerMetrics,
+    offline_quarantine::PurgeMode,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Requested shape of a tenant, as posted to `/admin/tenants`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantSpec {
+    /// Lowercase letters, digits, `-` and `_`; used in config paths, stream names and subjects
+    pub tenant_id: String,
+    /// Messages per window for each of the tenant's conversations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_limit: Option<u32>,
+    /// Replaces `content_types.default` for the tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_types: Option<Vec<ContentTypeRule>>,
+    /// Replaces `preview.default` for the tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<PreviewPolicy>,
+    /// Key into `tenants.retention_categories`
+    pub retention_category: String,
+    /// Scopes of the tenant's generated API key
+    pub api_key_scopes: Vec<Scope>,
+}
+
+impl TenantSpec {
+    /// Config layers carrying the spec's overrides
+    fn layers(&self) -> Vec<(String, Value)> {
+        let mut layers = Vec::new();
+        if let Some(limit) = self.conversation_limit {
+            layers.push((format!("conversation_limit.tenants.{}", self.tenant_id), Value::from(limit)));
+        }
+        if let Some(rules) = &self.content_types {
+            let rules = serde_json::to_value(rules).expect("content type rules serialize");
+            layers.push((format!("content_types.tenants.{}", self.tenant_id), rules));
+        }
+        if let Some(policy) = &self.preview {
+            let policy = serde_json::to_value(policy).expect("preview policies serialize");
+            layers.push((format!("preview.tenants.{}", self.tenant_id), policy));
+        }
+        layers
+    }
+
+    fn same_as(&self, other: &TenantSpec) -> bool {
+        serde_json::to_value(self).ok() == serde_json::to_value(other).ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TenantState {
+    Active,
+    /// Soft-deleted: its key is rejected, and it's purged at `purge_at` unless restored
+    Deleted {
+        deleted_at: DateTime<Utc>,
+        purge_at: DateTime<Utc>,
+        deleted_by: String,
+    },
+}
+
+/// A tenant as persisted in the `tenants.bucket` KV bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantRecord {
+    pub spec: TenantSpec,
+    #[serde(flatten)]
+    pub state: TenantState,
+    pub stream: String,
+    pub api_key_id: String,
+    /// Hex SHA-256 of the API key; the key itself is never stored
+    pub api_key_sha256: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: String,
+}
+
+impl TenantRecord {
+    fn api_key(&self) -> ApiKeyConfig {
+        ApiKeyConfig {
+            id: self.api_key_id.clone(),
+            sha256: self.api_key_sha256.clone(),
+            scopes: self.spec.api_key_scopes.clone(),
+            user_id: None,
+            tenant_id: Some(self.spec.tenant_id.clone()),
+            not_after: match &self.state {
+                TenantState::Active => None,
+                TenantState::Deleted { deleted_at, .. } => Some(*deleted_at),
+            },
+        }
+    }
+}
+
+/// Response to a provisioning request
+#[derive(Debug, Clone, Serialize)]
+pub struct Provisioned {
+    pub tenant: TenantRecord,
+    /// The generated key; only present on the request that created the tenant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TenantError {
+    #[error("invalid tenant spec: {0}")]
+    Invalid(String),
+    #[error("tenant {0} already exists with a different spec")]
+    Conflict(String),
+    #[error("tenant {0} is soft-deleted; restore it first")]
+    Deleted(String),
+    #[error("tenant {0} not found")]
+    NotFound(String),
+    #[error("tenant {0} is past its restore grace period")]
+    GraceExpired(String),
+    #[error("tenant store: {0}")]
+    Store(String),
+    #[error("tenant stream: {0}")]
+    Stream(String),
+}
+
+impl TenantError {
+    fn outcome(&self) -> &'static str {
+        match self {
+            TenantError::Invalid(_) => "invalid",
+            TenantError::Conflict(_) | TenantError::Deleted(_) => "conflict",
+            TenantError::NotFound(_) | TenantError::GraceExpired(_) => "not_found",
+            TenantError::Store(_) | TenantError::Stream(_) => "failed",
+        }
+    }
+}
+
+fn store_error(e: impl std::fmt::Display) -> TenantError {
+    TenantError::Store(e.to_string())
+}
+
+fn stream_error(e: impl std::fmt::Display) -> TenantError {
+    TenantError::Stream(e.to_string())
+}
+
+fn owner(tenant_id: &str) -> String {
+    format!("tenant:{}", tenant_id)
+}
+
+/// Self-service tenant onboarding
+///
+/// `provision` validates a `TenantSpec` and then, in order, creates the
+/// tenant's stream with its retention category's limits, registers its
+/// config overrides as managed layers on `RuntimeOverrides`, and writes the
+/// record to KV with a create, so two brokers provisioning the same tenant
+/// can't both win. A failed step undoes the earlier ones; a stream that
+/// already existed is left alone. The API key is generated here and
+/// returned once: only its hash is stored. Provisioning an existing tenant
+/// with the same spec succeeds without a new key.
+///
+/// Deletion follows the offline quarantine's soft/hard semantics. A soft
+/// delete rejects the tenant's key at once but keeps everything else, and
+/// can be undone with `restore` for `tenants.delete_grace`; after that, or
+/// right away for a hard delete, the stream, overrides and record are
+/// removed.
+///
+/// Every change is broadcast as a `TenantChanged` control command, on
+/// which each broker re-reads the record and applies it. Records are also
+/// loaded at startup and re-read every `tenants.sync_interval`, which
+/// covers a missed broadcast and purges soft deletes past their grace.
+pub struct TenantRegistry {
+    kv: kv::Store,
+    jetstream: jetstream::Context,
+    client: async_nats::Client,
+    control_topic: String,
+    config: TenantConfig,
+    overrides: Arc<RuntimeOverrides>,
+    auth: Arc<ApiAuth>,
+    /// Tenants applied on this broker
+    applied: Mutex<HashSet<String>>,
+    rng: SystemRandom,
+    clock: SharedClock,
+    audit: AuditLog,
+    metrics: BrokerMetrics,
+}
+
+impl TenantRegistry {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        kv: kv::Store,
+        jetstream: jetstream::Context,
+        client: async_nats::Client,
+        control_topic: String,
+        config: TenantConfig,
+        overrides: Arc<RuntimeOverrides>,
+        auth: Arc<ApiAuth>,
+        audit: AuditLog,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            kv,
+            jetstream,
+            client,
+            control_topic,
+            config,
+            overrides,
+            auth,
+            applied: Mutex::new(HashSet::new()),
+            rng: SystemRandom::new(),
+            clock: SystemClock::shared(),
+            audit,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn provision(&self, spec: TenantSpec, actor: &str) -> Result<Provisioned, TenantError> {
+        let result = self.try_provision(spec, actor).await;
+        let outcome = match &result {
+            Ok(Provisioned { api_key: Some(_), .. }) => "created",
+            Ok(Provisioned { api_key: None, .. }) => "unchanged",
+            Err(e) => e.outcome(),
+        };
+        self.metrics.record_tenant_operation("provision", outcome);
+        result
+    }
+
+    async fn try_provision(&self, spec: TenantSpec, actor: &str) -> Result<Provisioned, TenantError> {
+        self.validate(&spec)?;
+        if let Some(existing) = self.get(&spec.tenant_id).await? {
+            return self.existing(existing, &spec);
+        }
+
+        let stream = format!("{}_{}", self.config.stream_prefix, spec.tenant_id);
+        let created_stream = self.ensure_stream(&spec, &stream).await?;
+
+        let owner = owner(&spec.tenant_id);
+        if let Err(e) = self.overrides.set_managed(&owner, spec.layers()) {
+            self.rollback_stream(created_stream.then_some(stream.as_str())).await;
+            return Err(TenantError::Invalid(e.to_string()));
+        }
+
+        let api_key = self.generate_key()?;
+        let record = TenantRecord {
+            api_key_id: format!("tenant-{}", spec.tenant_id),
+            api_key_sha256: hex::encode(digest(&SHA256, api_key.as_bytes())),
+            spec,
+            state: TenantState::Active,
+            stream: stream.clone(),
+            created_at: self.clock.now_utc(),
+            created_by: actor.to_string(),
+        };
+        let value = serde_json::to_vec(&record).map_err(store_error)?;
+        if let Err(e) = self.kv.create(&record.spec.tenant_id, value.into()).await {
+            self.overrides.clear_managed(&owner);
+            if e.kind() == kv::CreateErrorKind::AlreadyExists {
+                // Lost a race with another broker; its record and stream stand
+                let winner = self
+                    .get(&record.spec.tenant_id)
+                    .await?
+                    .ok_or_else(|| TenantError::Store(format!("tenant {} vanished", record.spec.tenant_id)))?;
+                return self.existing(winner, &record.spec);
+            }
+            self.rollback_stream(created_stream.then_some(stream.as_str())).await;
+            return Err(store_error(e));
+        }
+
+        self.apply(&record);
+        info!("Tenant {} provisioned by {}", record.spec.tenant_id, actor);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "tenant.provisioned",
+            serde_json::json!({ "tenant": record.spec, "stream": record.stream, "api_key_id": record.api_key_id }),
+        ));
+        self.broadcast(&record.spec.tenant_id, actor).await;
+        Ok(Provisioned {
+            tenant: record,
+            api_key: Some(api_key),
+        })
+    }
+
+    /// Re-provisioning: the same spec is a no-op, anything else a conflict
+    fn existing(&self, existing: TenantRecord, spec: &TenantSpec) -> Result<Provisioned, TenantError> {
+        if matches!(existing.state, TenantState::Deleted { .. }) {
+            return Err(TenantError::Deleted(spec.tenant_id.clone()));
+        }
+        if !existing.spec.same_as(spec) {
+            return Err(TenantError::Conflict(spec.tenant_id.clone()));
+        }
+        self.apply(&existing);
+        Ok(Provisioned {
+            tenant: existing,
+            api_key: None,
+        })
+    }
+
+    fn validate(&self, spec: &TenantSpec) -> Result<(), TenantError> {
+        let id = &spec.tenant_id;
+        if id.is_empty()
+            || id.len() > 64
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        {
+            return Err(TenantError::Invalid(format!(
+                "tenant_id {:?} must be 1 to 64 lowercase letters, digits, '-' or '_'",
+                id
+            )));
+        }
+        if !self.config.retention_categories.contains_key(&spec.retention_category) {
+            return Err(TenantError::Invalid(format!(
+                "unknown retention category {}",
+                spec.retention_category
+            )));
+        }
+        if spec.api_key_scopes.is_empty() {
+            return Err(TenantError::Invalid("api_key_scopes is empty".to_string()));
+        }
+        // Tenant keys are refused outside `TENANT_ROUTES`, all of which need send
+        if spec.api_key_scopes.iter().any(|scope| *scope != Scope::Send) {
+            return Err(TenantError::Invalid("tenant keys can only have the send scope".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Create the tenant's stream; true if this call created it
+    async fn ensure_stream(&self, spec: &TenantSpec, name: &str) -> Result<bool, TenantError> {
+        if self.jetstream.get_stream(name).await.is_ok() {
+            return Ok(false);
+        }
+        let category = &self.config.retention_categories[&spec.retention_category];
+        self.jetstream
+            .create_stream(stream::Config {
+                name: name.to_string(),
+                subjects: vec![format!("{}.{}.>", self.config.subject_prefix, spec.tenant_id)],
+                max_age: category.max_age,
+                max_bytes: category.max_bytes.unwrap_or(-1),
+                ..Default::default()
+            })
+            .await
+            .map_err(stream_error)?;
+        Ok(true)
+    }
+
+    async fn rollback_stream(&self, created: Option<&str>) {
+        let Some(name) = created else {
+            return;
+        };
+        if let Err(e) = self.jetstream.delete_stream(name).await {
+            warn!("Rolling back tenant stream {} failed: {}", name, e);
+        }
+    }
+
+    fn generate_key(&self) -> Result<String, TenantError> {
+        let mut secret = [0u8; 32];
+        self.rng
+            .fill(&mut secret)
+            .map_err(|_| TenantError::Store("system randomness unavailable".to_string()))?;
+        Ok(format!("tk_{}", URL_SAFE_NO_PAD.encode(secret)))
+    }
+
+    /// Soft or hard delete a tenant
+    pub async fn delete(&self, tenant_id: &str, mode: PurgeMode, actor: &str) -> Result<(), TenantError> {
+        let result = self.try_delete(tenant_id, mode, actor).await;
+        let outcome = match &result {
+            Ok(()) => mode.as_str(),
+            Err(e) => e.outcome(),
+        };
+        self.metrics.record_tenant_operation("delete", outcome);
+        result
+    }
+
+    async fn try_delete(&self, tenant_id: &str, mode: PurgeMode, actor: &str) -> Result<(), TenantError> {
+        let (mut record, revision) = self
+            .get_with_revision(tenant_id)
+            .await?
+            .ok_or_else(|| TenantError::NotFound(tenant_id.to_string()))?;
+
+        match mode {
+            PurgeMode::Hard => self.purge(&record).await?,
+            PurgeMode::Soft => {
+                if matches!(record.state, TenantState::Deleted { .. }) {
+                    return Ok(());
+                }
+                let now = self.clock.now_utc();
+                let grace = chrono::Duration::seconds(self.config.delete_grace.as_secs() as i64);
+                record.state = TenantState::Deleted {
+                    deleted_at: now,
+                    purge_at: now + grace,
+                    deleted_by: actor.to_string(),
+                };
+                let value = serde_json::to_vec(&record).map_err(store_error)?;
+                self.kv
+                    .update(tenant_id, value.into(), revision)
+                    .await
+                    .map_err(store_error)?;
+                self.apply(&record);
+            }
+        }
+
+        info!("Tenant {} deleted ({}) by {}", tenant_id, mode.as_str(), actor);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "tenant.deleted",
+            serde_json::json!({ "tenant_id": tenant_id, "mode": mode.as_str() }),
+        ));
+        self.broadcast(tenant_id, actor).await;
+        Ok(())
+    }
+
+    /// Undo a soft delete still within its grace period
+    pub async fn restore(&self, tenant_id: &str, actor: &str) -> Result<TenantRecord, TenantError> {
+        let result = self.try_restore(tenant_id, actor).await;
+        self.metrics.record_tenant_operation(
+            "restore",
+            match &result {
+                Ok(_) => "restored",
+                Err(e) => e.outcome(),
+            },
+        );
+        result
+    }
+
+    async fn try_restore(&self, tenant_id: &str, actor: &str) -> Result<TenantRecord, TenantError> {
+        let (mut record, revision) = self
+            .get_with_revision(tenant_id)
+            .await?
+            .ok_or_else(|| TenantError::NotFound(tenant_id.to_string()))?;
+        match &record.state {
+            TenantState::Active => return Ok(record),
+            TenantState::Deleted { purge_at, .. } if *purge_at <= self.clock.now_utc() => {
+                return Err(TenantError::GraceExpired(tenant_id.to_string()));
+            }
+            TenantState::Deleted { .. } => {}
+        }
+        record.state = TenantState::Active;
+        let value = serde_json::to_vec(&record).map_err(store_error)?;
+        self.kv
+            .update(tenant_id, value.into(), revision)
+            .await
+            .map_err(store_error)?;
+        self.apply(&record);
+
+        info!("Tenant {} restored by {}", tenant_id, actor);
+        self.audit.record(AuditEntry::new(
+            actor,
+            "tenant.restored",
+            serde_json::json!({ "tenant_id": tenant_id }),
+        ));
+        self.broadcast(tenant_id, actor).await;
+        Ok(record)
+    }
+
+    /// Remove the stream, overrides, key and record for good
+    async fn purge(&self, record: &TenantRecord) -> Result<(), TenantError> {
+        if self.jetstream.get_stream(&record.stream).await.is_ok() {
+            self.jetstream
+                .delete_stream(&record.stream)
+                .await
+                .map_err(stream_error)?;
+        }
+        self.kv.purge(&record.spec.tenant_id).await.map_err(store_error)?;
+        self.unapply(&record.spec.tenant_id);
+        Ok(())
+    }
+
+    pub async fn get(&self, tenant_id: &str) -> Result<Option<TenantRecord>, TenantError> {
+        Ok(self.get_with_revision(tenant_id).await?.map(|(record, _)| record))
+    }
+
+    async fn get_with_revision(&self, tenant_id: &str) -> Result<Option<(TenantRecord, u64)>, TenantError> {
+        let Some(entry) = self.kv.entry(tenant_id).await.map_err(store_error)? else {
+            return Ok(None);
+        };
+        if entry.operation != kv::Operation::Put {
+            return Ok(None);
+        }
+        let record = serde_json::from_slice(&entry.value).map_err(store_error)?;
+        Ok(Some((record, entry.revision)))
+    }
+
+    pub async fn list(&self) -> Result<Vec<TenantRecord>, TenantError> {
+        let mut keys = self.kv.keys().await.map_err(store_error)?;
+        let mut records = Vec::new();
+        while let Some(key) = keys.next().await {
+            let key = key.map_err(store_error)?;
+            if let Some(record) = self.get(&key).await? {
+                records.push(record);
+            }
+        }
+        records.sort_by(|a, b| a.spec.tenant_id.cmp(&b.spec.tenant_id));
+        Ok(records)
+    }
+
+    /// `TenantChanged`: re-read one tenant and apply what's stored
+    pub async fn refresh(&self, tenant_id: &str) -> Result<(), TenantError> {
+        match self.get(tenant_id).await? {
+            Some(record) => self.apply(&record),
+            None => self.unapply(tenant_id),
+        }
+        Ok(())
+    }
+
+    /// Apply every stored tenant, drop ones no longer stored, and purge expired soft deletes
+    pub async fn sync(&self) -> Result<usize, TenantError> {
+        let records = self.list().await?;
+        let now = self.clock.now_utc();
+        let mut stored = HashSet::new();
+        for record in &records {
+            match &record.state {
+                TenantState::Deleted { purge_at, .. } if *purge_at <= now => {
+                    info!("Purging tenant {} past its restore grace", record.spec.tenant_id);
+                    if let Err(e) = self.purge(record).await {
+                        warn!("Purging tenant {} failed: {}", record.spec.tenant_id, e);
+                    }
+                }
+                _ => {
+                    self.apply(record);
+                    stored.insert(record.spec.tenant_id.clone());
+                }
+            }
+        }
+        let gone: Vec<String> = self.applied.lock().difference(&stored).cloned().collect();
+        for tenant_id in &gone {
+            self.unapply(tenant_id);
+        }
+        Ok(stored.len())
+    }
+
+    fn apply(&self, record: &TenantRecord) {
+        let tenant_id = &record.spec.tenant_id;
+        if let Err(e) = self.overrides.set_managed(&owner(tenant_id), record.spec.layers()) {
+            warn!("Tenant {} overrides not applied: {}", tenant_id, e);
+        }
+        self.auth.set_tenant_key(tenant_id, Some(record.api_key()));
+        let mut applied = self.applied.lock();
+        applied.insert(tenant_id.clone());
+        self.metrics.update_tenants(applied.len());
+    }
+
+    fn unapply(&self, tenant_id: &str) {
+        self.overrides.clear_managed(&owner(tenant_id));
+        self.auth.set_tenant_key(tenant_id, None);
+        let mut applied = self.applied.lock();
+        applied.remove(tenant_id);
+        self.metrics.update_tenants(applied.len());
+    }
+
+    /// Tell the other brokers; a missed broadcast is caught up by the next sync
+    async fn broadcast(&self, tenant_id: &str, actor: &str) {
+        let message = ControlMessage {
+            command_id: uuid::Uuid::new_v4().to_string(),
+            issued_by: actor.to_string(),
+            timestamp: self.clock.now_millis(),
+            scope: None,
+            command: ControlCommand::TenantChanged {
+                tenant_id: tenant_id.to_string(),
+            },
+        };
+        let payload = serde_json::to_vec(&message).expect("control messages serialize");
+        if let Err(e) = self.client.publish(self.control_topic.clone(), payload.into()).await {
+            warn!("Broadcasting change to tenant {} failed: {}", tenant_id, e);
+        }
+    }
+
+    pub fn spawn_sync(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let registry = Arc::clone(self);
+        spawn_traced("tenant_sync", TaskContext::new("tenant"), async move {
+            loop {
+                if let Err(e) = registry.sync().await {
+                    warn!("Tenant sync failed: {}", e);
+                }
+                registry.clock.sleep(registry.config.sync_interval).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        nats_probe::tests::EmbeddedServer,
+    };
+
+    const SEND: &str = "/broker.v1.Broker/SendTransaction";
+
+    fn spec(tenant_id: &str) -> TenantSpec {
+        TenantSpec {
+            tenant_id: tenant_id.into(),
+            conversation_limit: Some(50),
+            content_types: Some(vec![ContentTypeRule {
+                content_type: "text/plain".into(),
+                max_size: Some(4096),
+            }]),
+            preview: None,
+            retention_category: "short".into(),
+            api_key_scopes: vec![Scope::Send],
+        }
+    }
+
+    /// One broker's view of the tenants, with the pieces it applies them to
+    struct Broker {
+        registry: TenantRegistry,
+        overrides: Arc<RuntimeOverrides>,
+        auth: Arc<ApiAuth>,
+    }
+
+    impl Broker {
+        fn new(client: async_nats::Client, kv: kv::Store, config: TenantConfig, clock: &Arc<SimClock>) -> Self {
+            let broker = BrokerConfig::load().unwrap();
+            let metrics = BrokerMetrics::new().unwrap();
+            let overrides = Arc::new(RuntimeOverrides::new(
+                "broker-1".into(),
+                broker.config_overrides,
+                AuditLog::tracing_only(),
+                metrics.clone(),
+            ));
+            let mut auth_config = broker.api.auth;
+            auth_config.enabled = true;
+            auth_config.keys = Vec::new();
+            auth_config.keys_file = None;
+            let auth = Arc::new(ApiAuth::new(auth_config, AuditLog::tracing_only(), metrics.clone()).unwrap());
+            let registry = TenantRegistry::new(
+                kv,
+                jetstream::new(client.clone()),
+                client,
+                "broker.control".into(),
+                config,
+                overrides.clone(),
+                auth.clone(),
+                AuditLog::tracing_only(),
+                metrics,
+            )
+            .with_clock(clock.clone());
+            Self {
+                registry,
+                overrides,
+                auth,
+            }
+        }
+
+        /// The effective config with this broker's managed layers applied
+        fn config(&self) -> BrokerConfig {
+            BrokerConfig::load_with_overrides(&self.overrides.layers()).unwrap()
+        }
+
+        fn accepts(&self, api_key: &str) -> bool {
+            self.auth.check(SEND, Some(format!("Bearer {}", api_key).as_str())).is_ok()
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_specs_are_refused_before_anything_is_provisioned() {
+        let clock = Arc::new(SimClock::new());
+        let server = EmbeddedServer::start(clock.clone()).await;
+        let client = async_nats::connect(&server.url).await.unwrap();
+        let kv = jetstream::new(client.clone()).get_key_value("tenants").await.unwrap();
+        let broker = Broker::new(client, kv, BrokerConfig::load().unwrap().tenants, &clock);
+
+        let mut invalid = Vec::new();
+        for tenant_id in ["", "Acme", "acme.eu", "acme corp", &"a".repeat(65)] {
+            invalid.push(spec(tenant_id));
+        }
+        invalid.push(TenantSpec {
+            retention_category: "forever".into(),
+            ..spec("acme")
+        });
+        invalid.push(TenantSpec {
+            api_key_scopes: Vec::new(),
+            ..spec("acme")
+        });
+        invalid.push(TenantSpec {
+            api_key_scopes: vec![Scope::Send, Scope::Admin],
+            ..spec("acme")
+        });
+        for spec in invalid {
+            let tenant_id = spec.tenant_id.clone();
+            match broker.registry.provision(spec, "ops").await {
+                Err(TenantError::Invalid(_)) => {}
+                other => panic!("{:?} wasn't refused: {:?}", tenant_id, other.map(|p| p.tenant)),
+            }
+        }
+        assert!(server.published.lock().is_empty(), "no change was broadcast");
+        assert!(broker.overrides.layers().is_empty());
+        assert!(broker.registry.applied.lock().is_empty());
+
+        // The longest allowed id passes validation and only then touches the store
+        let longest = spec(&"a".repeat(64));
+        assert!(matches!(broker.registry.provision(longest, "ops").await, Err(TenantError::Store(_))));
+    }
+
+    #[test]
+    fn specs_become_tenant_layers_and_deleted_tenants_keys_expire() {
+        let mut spec = spec("acme");
+        assert_eq!(
+            spec.layers(),
+            vec![
+                ("conversation_limit.tenants.acme".to_string(), Value::from(50)),
+                (
+                    "content_types.tenants.acme".to_string(),
+                    serde_json::json!([{ "content_type": "text/plain", "max_size": 4096 }])
+                ),
+            ]
+        );
+        let config = BrokerConfig::load_with_overrides(&spec.layers()).unwrap();
+        assert_eq!(config.conversation_limit.tenants["acme"], 50);
+        assert_eq!(config.content_types.tenants["acme"][0].max_size, Some(4096));
+
+        spec.conversation_limit = None;
+        spec.content_types = None;
+        assert!(spec.layers().is_empty());
+        assert!(!spec.same_as(&self::spec("acme")));
+
+        let created_at = SimClock::new().now_utc();
+        let mut record = TenantRecord {
+            spec,
+            state: TenantState::Active,
+            stream: "tenant_acme".into(),
+            api_key_id: "tenant-acme".into(),
+            api_key_sha256: "00".into(),
+            created_at,
+            created_by: "ops".into(),
+        };
+        assert_eq!(record.api_key().not_after, None);
+        assert_eq!(record.api_key().tenant_id.as_deref(), Some("acme"));
+        record.state = TenantState::Deleted {
+            deleted_at: created_at,
+            purge_at: created_at + chrono::Duration::days(7),
+            deleted_by: "ops".into(),
+        };
+        assert_eq!(record.api_key().not_after, Some(created_at));
+
+        let stored = serde_json::to_value(&record).unwrap();
+        assert_eq!(stored["state"], "deleted");
+        assert_eq!(stored["deleted_by"], "ops");
+        let read: TenantRecord = serde_json::from_value(stored).unwrap();
+        assert!(matches!(read.state, TenantState::Deleted { .. }));
+    }
+
+    /// A JetStream server with a fresh tenants bucket and stream prefix
+    struct Cluster {
+        client: async_nats::Client,
+        jetstream: jetstream::Context,
+        kv: kv::Store,
+        config: TenantConfig,
+    }
+
+    impl Cluster {
+        async fn new(max_value_size: i32) -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let client = async_nats::connect(url).await.unwrap();
+            let jetstream = jetstream::new(client.clone());
+            let run = Uuid::new_v4().simple().to_string();
+            let kv = jetstream
+                .create_key_value(kv::Config {
+                    bucket: format!("tenant-test-{}", run),
+                    max_value_size,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let mut config = BrokerConfig::load().unwrap().tenants;
+            config.stream_prefix = format!("tenant_test_{}", run);
+            config.subject_prefix = format!("tenant-test-{}", run);
+            config.delete_grace = Duration::from_secs(3600);
+            Self {
+                client,
+                jetstream,
+                kv,
+                config,
+            }
+        }
+
+        fn broker(&self, clock: &Arc<SimClock>) -> Broker {
+            Broker::new(self.client.clone(), self.kv.clone(), self.config.clone(), clock)
+        }
+
+        fn stream(&self, tenant_id: &str) -> String {
+            format!("{}_{}", self.config.stream_prefix, tenant_id)
+        }
+
+        async fn has_stream(&self, tenant_id: &str) -> bool {
+            self.jetstream.get_stream(self.stream(tenant_id)).await.is_ok()
+        }
+    }
+
+    /// Run against a JetStream-enabled server at `NATS_URL`: `cargo test -- --ignored tenant`
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn reprovisioning_the_same_spec_is_idempotent() {
+        let cluster = Cluster::new(-1).await;
+        let broker = cluster.broker(&Arc::new(SimClock::new()));
+
+        let created = broker.registry.provision(spec("acme"), "ops").await.unwrap();
+        let api_key = created.api_key.clone().unwrap();
+        assert!(api_key.starts_with("tk_"));
+        assert_eq!(created.tenant.api_key_sha256, hex::encode(digest(&SHA256, api_key.as_bytes())));
+        assert!(cluster.has_stream("acme").await);
+        assert!(broker.accepts(&api_key));
+        assert_eq!(broker.config().conversation_limit.tenants["acme"], 50);
+
+        // The same spec again: the same record back, and no second key
+        let again = broker.registry.provision(spec("acme"), "ops").await.unwrap();
+        assert!(again.api_key.is_none());
+        assert_eq!(again.tenant.api_key_sha256, created.tenant.api_key_sha256);
+        assert_eq!(again.tenant.created_at, created.tenant.created_at);
+        assert!(broker.accepts(&api_key));
+        assert_eq!(broker.registry.list().await.unwrap().len(), 1);
+
+        let changed = TenantSpec {
+            conversation_limit: Some(80),
+            ..spec("acme")
+        };
+        assert!(matches!(broker.registry.provision(changed, "ops").await, Err(TenantError::Conflict(_))));
+        assert_eq!(broker.config().conversation_limit.tenants["acme"], 50);
+
+        // Soft-deleted tenants must be restored, not re-provisioned
+        broker.registry.delete("acme", PurgeMode::Soft, "ops").await.unwrap();
+        assert!(!broker.accepts(&api_key));
+        assert!(matches!(broker.registry.provision(spec("acme"), "ops").await, Err(TenantError::Deleted(_))));
+        broker.registry.restore("acme", "ops").await.unwrap();
+        assert!(broker.accepts(&api_key));
+
+        broker.registry.delete("acme", PurgeMode::Hard, "ops").await.unwrap();
+        assert!(!cluster.has_stream("acme").await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_failed_record_write_rolls_back_the_stream_and_overrides() {
+        // Too small for any record, so provisioning fails at its last step
+        let cluster = Cluster::new(64).await;
+        let broker = cluster.broker(&Arc::new(SimClock::new()));
+
+        assert!(matches!(broker.registry.provision(spec("acme"), "ops").await, Err(TenantError::Store(_))));
+        assert!(!cluster.has_stream("acme").await, "the created stream was deleted");
+        assert!(broker.overrides.layers().is_empty());
+        assert!(broker.registry.get("acme").await.unwrap().is_none());
+        assert!(broker.registry.applied.lock().is_empty());
+
+        // A stream that was already there isn't the rollback's to delete
+        cluster
+            .jetstream
+            .create_stream(stream::Config {
+                name: cluster.stream("beta"),
+                subjects: vec![format!("{}.beta.>", cluster.config.subject_prefix)],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(matches!(broker.registry.provision(spec("beta"), "ops").await, Err(TenantError::Store(_))));
+        assert!(cluster.has_stream("beta").await);
+        assert!(broker.overrides.layers().is_empty());
+        cluster.jetstream.delete_stream(cluster.stream("beta")).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_second_broker_picks_up_tenants_without_a_restart() {
+        let cluster = Cluster::new(-1).await;
+        let clock = Arc::new(SimClock::new());
+        let (first, second) = (cluster.broker(&clock), cluster.broker(&clock));
+        let mut control = cluster.client.subscribe("broker.control").await.unwrap();
+        cluster.client.flush().await.unwrap();
+
+        let api_key = first.registry.provision(spec("acme"), "ops").await.unwrap().api_key.unwrap();
+        assert!(!second.accepts(&api_key));
+
+        // The broadcast names the tenant, and the control handler's refresh applies it
+        let changed = |message: async_nats::Message| {
+            match serde_json::from_slice::<ControlMessage>(&message.payload).unwrap().command {
+                ControlCommand::TenantChanged { tenant_id } => tenant_id,
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        let tenant_id = changed(control.next().await.unwrap());
+        second.registry.refresh(&tenant_id).await.unwrap();
+        assert!(second.accepts(&api_key));
+        assert_eq!(second.config().conversation_limit.tenants["acme"], 50);
+
+        // A missed broadcast is caught by the periodic sync; so is a missed soft delete
+        let beta_key = first.registry.provision(spec("beta"), "ops").await.unwrap().api_key.unwrap();
+        first.registry.delete("acme", PurgeMode::Soft, "ops").await.unwrap();
+        assert_eq!(second.registry.sync().await.unwrap(), 2);
+        assert!(second.accepts(&beta_key));
+        assert!(!second.accepts(&api_key));
+
+        // Past the grace the next sync purges the tenant for good
+        clock.advance(cluster.config.delete_grace);
+        assert!(matches!(first.registry.restore("acme", "ops").await, Err(TenantError::GraceExpired(_))));
+        assert_eq!(second.registry.sync().await.unwrap(), 1);
+        assert!(!cluster.has_stream("acme").await);
+        assert!(second.registry.get("acme").await.unwrap().is_none());
+        first.registry.sync().await.unwrap();
+        assert!(!first.config().conversation_limit.tenants.contains_key("acme"));
+        assert_eq!(first.config().conversation_limit.tenants["beta"], 50);
+
+        first.registry.delete("beta", PurgeMode::Hard, "ops").await.unwrap();
+    }
+}
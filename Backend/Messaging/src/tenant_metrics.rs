@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::config::MetricsConfig;
+
+/// Delivery latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Rolled-up counters for one metered tenant
+#[derive(Default)]
+struct TenantRollup {
+    messages_received: AtomicU64,
+    messages_delivered: AtomicU64,
+    bytes_received: AtomicU64,
+    queue_depth: AtomicI64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    /// Sum of observed latencies in microseconds
+    latency_sum_micros: AtomicU64,
+}
+
+/// Per-tenant metrics views for explicitly metered tenants
+///
+/// The tenant set is fixed at construction, so recording is a map lookup
+/// plus atomic increments. Only built when `metrics.metered_tenants` is
+/// non-empty; callers hold an `Option` and skip recording otherwise.
+pub struct TenantMetrics {
+    rollups: HashMap<String, TenantRollup>,
+    tokens: HashMap<String, String>,
+}
+
+impl TenantMetrics {
+    pub fn from_config(config: &MetricsConfig) -> Option<Arc<Self>> {
+        if config.metered_tenants.is_empty() {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            rollups: config
+                .metered_tenants
+                .keys()
+                .map(|tenant| (tenant.clone(), TenantRollup::default()))
+                .collect(),
+            tokens: config.metered_tenants.clone(),
+        }))
+    }
+
+    pub fn record_received(&self, tenant_id: Option<&str>, bytes: usize) {
+        if let Some(rollup) = self.rollup(tenant_id) {
+            rollup.messages_received.fetch_add(1, Ordering::Relaxed);
+            rollup.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_delivered(&self, tenant_id: Option<&str>, latency_seconds: f64) {
+        let Some(rollup) = self.rollup(tenant_id) else {
+            return;
+        };
+        rollup.messages_delivered.fetch_add(1, Ordering::Relaxed);
+        rollup.latency_count.fetch_add(1, Ordering::Relaxed);
+        rollup
+            .latency_sum_micros
+            .fetch_add((latency_seconds * 1_000_000.0) as u64, Ordering::Relaxed);
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| latency_seconds <= *le) {
+            rollup.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn adjust_queue_depth(&self, tenant_id: Option<&str>, delta: i64) {
+        if let Some(rollup) = self.rollup(tenant_id) {
+            rollup.queue_depth.fetch_add(delta, Ordering::Relaxed);
+        }
+    }
+
+    /// Check a bearer token against the tenant's configured token
+    pub fn authorize(&self, tenant_id: &str, token: &str) -> bool {
+        self.tokens
+            .get(tenant_id)
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+    }
+
+    /// Render one tenant's series in Prometheus text format
+    pub fn render(&self, tenant_id: &str) -> Option<String> {
+        let rollup = self.rollups.get(tenant_id)?;
+        let label = format!("tenant=\"{}\"", escape_label(tenant_id));
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "broker_tenant_messages_received_total",
+                "Messages accepted at ingress",
+                rollup.messages_received.load(Ordering::Relaxed),
+            ),
+            (
+                "broker_tenant_messages_delivered_total",
+                "Messages delivered to recipients",
+                rollup.messages_delivered.load(Ordering::Relaxed),
+            ),
+            (
+                "broker_tenant_bytes_received_total",
+                "Payload bytes accepted at ingress",
+                rollup.bytes_received.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{}{{{}}} {}", name, help, name, name, label, value);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP broker_tenant_queue_depth Messages queued for the tenant's users\n# TYPE broker_tenant_queue_depth gauge\nbroker_tenant_queue_depth{{{}}} {}",
+            label,
+            rollup.queue_depth.load(Ordering::Relaxed).max(0)
+        );
+
+        let name = "broker_tenant_delivery_latency_seconds";
+        let _ = writeln!(out, "# HELP {} Delivery latency\n# TYPE {} histogram", name, name);
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&rollup.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, label, le, cumulative);
+        }
+        let count = rollup.latency_count.load(Ordering::Relaxed);
+        let sum = rollup.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, label, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, label, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, label, count);
+
+        Some(out)
+    }
+
+    fn rollup(&self, tenant_id: Option<&str>) -> Option<&TenantRollup> {
+        self.rollups.get(tenant_id?)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrokerConfig;
+
+    fn config(tenants: &[(&str, &str)]) -> MetricsConfig {
+        let mut config = BrokerConfig::load().unwrap().metrics;
+        config.metered_tenants = tenants
+            .iter()
+            .map(|(tenant, token)| (tenant.to_string(), token.to_string()))
+            .collect();
+        config
+    }
+
+    fn metered() -> Arc<TenantMetrics> {
+        TenantMetrics::from_config(&config(&[("acme", "acme-token"), ("globex", "globex-token")])).unwrap()
+    }
+
+    #[test]
+    fn no_metered_tenants_builds_nothing() {
+        assert!(TenantMetrics::from_config(&config(&[])).is_none());
+    }
+
+    #[test]
+    fn tokens_are_scoped_to_their_tenant() {
+        let metrics = metered();
+        assert!(metrics.authorize("acme", "acme-token"));
+        assert!(!metrics.authorize("acme", "globex-token"));
+        assert!(!metrics.authorize("acme", "acme-token-extra"));
+        assert!(!metrics.authorize("acme", ""));
+        assert!(!metrics.authorize("initech", "acme-token"));
+    }
+
+    #[test]
+    fn a_tenant_view_never_contains_another_tenants_series() {
+        let metrics = metered();
+        metrics.record_received(Some("acme"), 100);
+        metrics.record_delivered(Some("acme"), 0.02);
+        metrics.adjust_queue_depth(Some("acme"), 3);
+        for _ in 0..7 {
+            metrics.record_received(Some("globex"), 4_096);
+        }
+
+        let acme = metrics.render("acme").unwrap();
+        assert!(!acme.contains("globex"));
+        assert!(acme.contains("broker_tenant_messages_received_total{tenant=\"acme\"} 1\n"));
+        assert!(acme.contains("broker_tenant_bytes_received_total{tenant=\"acme\"} 100\n"));
+        assert!(acme.contains("broker_tenant_queue_depth{tenant=\"acme\"} 3\n"));
+        assert!(acme.contains("broker_tenant_delivery_latency_seconds_bucket{tenant=\"acme\",le=\"0.025\"} 1\n"));
+        assert!(acme.contains("broker_tenant_delivery_latency_seconds_bucket{tenant=\"acme\",le=\"0.01\"} 0\n"));
+
+        let globex = metrics.render("globex").unwrap();
+        assert!(!globex.contains("acme"));
+        assert!(globex.contains("broker_tenant_messages_received_total{tenant=\"globex\"} 7\n"));
+        assert!(globex.contains("broker_tenant_delivery_latency_seconds_count{tenant=\"globex\"} 0\n"));
+    }
+
+    #[test]
+    fn unmetered_traffic_is_not_rolled_up() {
+        let metrics = metered();
+        metrics.record_received(Some("initech"), 100);
+        metrics.record_received(None, 100);
+        metrics.record_delivered(Some("initech"), 0.01);
+
+        assert!(metrics.render("initech").is_none());
+        assert_eq!(metrics.rollups.len(), 2);
+        assert!(metrics.render("acme").unwrap().contains("broker_tenant_messages_received_total{tenant=\"acme\"} 0\n"));
+    }
+}
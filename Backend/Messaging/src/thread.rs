@@ -0,0 +1,527 @@
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use async_trait::async_trait;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::ThreadConfig,
+    membership::ResolverError,
+    message::types::{EncryptedPayload, MessageEnvelope, MessageType, Priority},
+    metrics::BrokerMetrics,
+    path_override::{PathContext, PathFeature, PathOverrides},
+    recipient_trace::{Downgrade, ExclusionReason, RecipientDecisions},
+};
+
+/// Metadata on a thread-activity marker: thread replies since the last marker
+pub const THREAD_NEW_COUNT_METADATA: &str = "thread_new_count";
+
+/// Who a thread reply fans out to in full
+#[derive(Debug, Clone, Default)]
+pub struct ThreadParticipants {
+    /// Users who posted in or were mentioned into the thread
+    pub participants: Vec<String>,
+    /// Conversation members who opted into notifications for the thread
+    pub subscribers: Vec<String>,
+}
+
+/// Source of truth for thread participation, next to `MembershipResolver`
+#[async_trait]
+pub trait ThreadParticipantResolver: Send + Sync {
+    async fn resolve(&self, conversation_id: &str, thread_id: &str) -> Result<ThreadParticipants, ResolverError>;
+}
+
+/// Key of a thread's own ordering sequence in the `SequenceAllocator`
+///
+/// Nested under the conversation, so a thread sequence never collides with
+/// the conversation's or another conversation's thread.
+pub fn thread_sequence_key(conversation_id: &str, thread_id: &str) -> String {
+    format!("{}/thread/{}", conversation_id, thread_id)
+}
+
+struct CachedThread {
+    recipients: Arc<HashSet<String>>,
+    fetched_at: Instant,
+}
+
+/// Thread participants plus opted-in subscribers, cached per (conversation, thread)
+///
+/// Entries expire after `threads.participant_ttl`; the `InvalidateThread`
+/// control command drops one thread or every thread of a conversation when
+/// participation changes.
+pub struct ThreadParticipantCache {
+    resolver: Arc<dyn ThreadParticipantResolver>,
+    cache: Mutex<LruCache<(String, String), CachedThread>>,
+    ttl: Duration,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ThreadParticipantCache {
+    pub fn new(resolver: Arc<dyn ThreadParticipantResolver>, config: &ThreadConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            resolver,
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(config.cache_size.max(1)).unwrap())),
+            ttl: config.participant_ttl,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub async fn recipients(
+        &self,
+        conversation_id: &str,
+        thread_id: &str,
+    ) -> Result<Arc<HashSet<String>>, ResolverError> {
+        let key = (conversation_id.to_string(), thread_id.to_string());
+        if let Some(cached) = self.cache.lock().get(&key) {
+            if self.clock.now_instant().saturating_duration_since(cached.fetched_at) < self.ttl {
+                self.metrics.record_thread_cache_lookup("hit");
+                return Ok(Arc::clone(&cached.recipients));
+            }
+        }
+        self.metrics.record_thread_cache_lookup("miss");
+
+        let resolved = self.resolver.resolve(conversation_id, thread_id).await?;
+        let recipients: Arc<HashSet<String>> = Arc::new(
+            resolved
+                .participants
+                .into_iter()
+                .chain(resolved.subscribers)
+                .collect(),
+        );
+        self.cache.lock().put(
+            key,
+            CachedThread {
+                recipients: Arc::clone(&recipients),
+                fetched_at: self.clock.now_instant(),
+            },
+        );
+        Ok(recipients)
+    }
+
+    /// Drop one thread, or every cached thread of the conversation when `thread_id` is `None`
+    pub fn invalidate(&self, conversation_id: &str, thread_id: Option<&str>) {
+        let mut cache = self.cache.lock();
+        match thread_id {
+            Some(thread_id) => {
+                cache.pop(&(conversation_id.to_string(), thread_id.to_string()));
+            }
+            None => {
+                let keys: Vec<_> = cache
+                    .iter()
+                    .filter(|((conversation, _), _)| conversation == conversation_id)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in keys {
+                    cache.pop(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Full copies and activity markers for one thread reply
+#[derive(Debug, Clone, Default)]
+pub struct ThreadFanout {
+    /// Members receiving the reply itself
+    pub full: Vec<String>,
+    /// Members receiving a thread-activity marker instead
+    pub markers: Vec<(String, MessageEnvelope)>,
+    /// Members whose marker was coalesced into a later one
+    pub coalesced: usize,
+}
+
+struct MarkerState {
+    last_sent: Option<Instant>,
+    /// Replies since the last marker went out
+    unsent: u32,
+}
+
+/// Splits conversation fanout for thread replies
+///
+/// Messages without `thread_id` fan out unchanged. A thread reply goes in
+/// full to thread participants and opted-in subscribers only; every other
+/// conversation member gets at most one `ThreadActivity` marker per
+/// (member, thread) every `threads.marker_window`, carrying the number of
+/// replies since their last marker so conversation lists can show
+/// "3 new in thread". Markers carry no payload and are sent at bulk priority.
+/// A path override forbidding coalescing sends a marker for every reply.
+pub struct ThreadRouter {
+    participants: Arc<ThreadParticipantCache>,
+    overrides: Arc<PathOverrides>,
+    markers: Mutex<LruCache<(String, String, String), MarkerState>>,
+    marker_window: Duration,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ThreadRouter {
+    pub fn new(
+        participants: Arc<ThreadParticipantCache>,
+        overrides: Arc<PathOverrides>,
+        config: &ThreadConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            participants,
+            overrides,
+            markers: Mutex::new(LruCache::new(
+                NonZeroUsize::new(config.marker_state_size.max(1)).unwrap(),
+            )),
+            marker_window: config.marker_window,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn participants(&self) -> &Arc<ThreadParticipantCache> {
+        &self.participants
+    }
+
+    /// Split `members` of the envelope's conversation into full copies and markers
+    pub async fn fanout(
+        &self,
+        envelope: &MessageEnvelope,
+        members: &[String],
+        decisions: &mut RecipientDecisions,
+    ) -> Result<ThreadFanout, ResolverError> {
+        let Some(thread_id) = envelope.thread_id.as_deref() else {
+            return Ok(ThreadFanout {
+                full: members.to_vec(),
+                ..Default::default()
+            });
+        };
+        let conversation_id = envelope.conversation_id();
+        let recipients = self.participants.recipients(&conversation_id, thread_id).await?;
+        let path = PathContext::of(envelope, &conversation_id, None);
+        let coalesce = self.overrides.decide(&path, PathFeature::Coalescing, true);
+
+        let mut fanout = ThreadFanout::default();
+        let now = self.clock.now_instant();
+        let mut markers = self.markers.lock();
+        for member in members {
+            if recipients.contains(member) || *member == envelope.from {
+                fanout.full.push(member.clone());
+                continue;
+            }
+
+            let key = (member.clone(), conversation_id.clone(), thread_id.to_string());
+            let state = markers.get_or_insert_mut(key, || MarkerState {
+                last_sent: None,
+                unsent: 0,
+            });
+            state.unsent = state.unsent.saturating_add(1);
+            if coalesce && state.last_sent.is_some_and(|at| now.duration_since(at) < self.marker_window) {
+                fanout.coalesced += 1;
+                decisions.exclude(member, ExclusionReason::ThreadMarkerCoalesced);
+                continue;
+            }
+            let marker = thread_marker(envelope, &conversation_id, thread_id, member, state.unsent);
+            state.last_sent = Some(now);
+            state.unsent = 0;
+            fanout.markers.push((member.clone(), marker));
+            decisions.downgrade(member, Downgrade::ThreadMarker);
+        }
+        drop(markers);
+
+        self.metrics.record_thread_fanout("full", fanout.full.len());
+        self.metrics.record_thread_fanout("marker", fanout.markers.len());
+        self.metrics.record_thread_fanout("coalesced", fanout.coalesced);
+        Ok(fanout)
+    }
+}
+
+fn thread_marker(
+    reply: &MessageEnvelope,
+    conversation_id: &str,
+    thread_id: &str,
+    member: &str,
+    new_count: u32,
+) -> MessageEnvelope {
+    let payload = EncryptedPayload {
+        ciphertext: String::new(),
+        iv: None,
+        tag: None,
+        key_id: None,
+        content_type: None,
+    };
+    let mut marker = MessageEnvelope::new(
+        MessageType::ThreadActivity,
+        reply.from.clone(),
+        vec![member.to_string()],
+        payload,
+    );
+    marker.tenant_id = reply.tenant_id.clone();
+    marker.priority = Priority::Bulk;
+    marker.in_reply_to = Some(reply.message_id.clone());
+    marker.thread_id = Some(thread_id.to_string());
+    marker.thread_sequence = reply.thread_sequence;
+    marker.metadata.insert("conversation_id".to_string(), conversation_id.to_string());
+    marker
+        .metadata
+        .insert(THREAD_NEW_COUNT_METADATA.to_string(), new_count.to_string());
+    marker
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::SimClock,
+        config::BrokerConfig,
+        path_override::{OverrideEffect, PathOverrideRule},
+        recipient_trace::{RecipientDecision, RecipientTracer, RoutingWatch},
+    };
+
+    const GROUP: &str = "group-1";
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    /// Alice and Bob posted in every thread; Carol follows it
+    #[derive(Default)]
+    struct FixedThreads {
+        resolves: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ThreadParticipantResolver for FixedThreads {
+        async fn resolve(&self, _conversation_id: &str, _thread_id: &str) -> Result<ThreadParticipants, ResolverError> {
+            self.resolves.fetch_add(1, Ordering::Relaxed);
+            Ok(ThreadParticipants {
+                participants: vec!["alice".into(), "bob".into()],
+                subscribers: vec!["carol".into()],
+            })
+        }
+    }
+
+    fn members() -> Vec<String> {
+        ["alice", "bob", "carol", "dave", "erin"].map(String::from).to_vec()
+    }
+
+    fn reply(from: &str, thread_id: Option<&str>) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        let mut envelope = MessageEnvelope::new(MessageType::GroupMessage, from.into(), vec![GROUP.into()], payload);
+        envelope.thread_id = thread_id.map(String::from);
+        envelope
+    }
+
+    struct Fixture {
+        resolver: Arc<FixedThreads>,
+        overrides: Arc<PathOverrides>,
+        router: ThreadRouter,
+        clock: Arc<SimClock>,
+    }
+
+    fn fixture() -> Fixture {
+        let broker = BrokerConfig::load().unwrap();
+        let mut config = broker.threads;
+        config.marker_window = WINDOW;
+        let metrics = BrokerMetrics::new().unwrap();
+        let resolver = Arc::new(FixedThreads::default());
+        let clock = Arc::new(SimClock::new());
+        let participants = Arc::new(
+            ThreadParticipantCache::new(resolver.clone(), &config, metrics.clone()).with_clock(clock.clone()),
+        );
+        let overrides = Arc::new(PathOverrides::new(
+            broker.path_override,
+            AuditLog::tracing_only(),
+            metrics.clone(),
+        ));
+        let router = ThreadRouter::new(participants, overrides.clone(), &config, metrics).with_clock(clock.clone());
+        Fixture {
+            resolver,
+            overrides,
+            router,
+            clock,
+        }
+    }
+
+    impl Fixture {
+        async fn fanout(&self, envelope: &MessageEnvelope) -> ThreadFanout {
+            let mut decisions = RecipientDecisions::untraced();
+            self.router.fanout(envelope, &members(), &mut decisions).await.unwrap()
+        }
+    }
+
+    fn marked(fanout: &ThreadFanout) -> Vec<(&str, &str)> {
+        fanout
+            .markers
+            .iter()
+            .map(|(member, marker)| (member.as_str(), marker.metadata[THREAD_NEW_COUNT_METADATA].as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn thread_replies_go_in_full_to_participants_only() {
+        let fixture = fixture();
+        let mut envelope = reply("alice", Some("t1"));
+        envelope.thread_sequence = Some(7);
+
+        let fanout = fixture.fanout(&envelope).await;
+
+        assert_eq!(fanout.full, ["alice", "bob", "carol"]);
+        assert_eq!(marked(&fanout), [("dave", "1"), ("erin", "1")]);
+        for (member, marker) in &fanout.markers {
+            assert_eq!(marker.message_type, MessageType::ThreadActivity);
+            assert_eq!(marker.to, std::slice::from_ref(member));
+            assert!(marker.payload.ciphertext.is_empty());
+            assert_eq!(marker.priority, Priority::Bulk);
+            assert_eq!(marker.in_reply_to.as_deref(), Some(envelope.message_id.as_str()));
+            assert_eq!(marker.thread_id.as_deref(), Some("t1"));
+            assert_eq!(marker.thread_sequence, Some(7));
+            assert_eq!(marker.metadata["conversation_id"], GROUP);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_sender_outside_the_thread_still_gets_their_copy() {
+        let fixture = fixture();
+        let fanout = fixture.fanout(&reply("dave", Some("t1"))).await;
+        assert_eq!(fanout.full, ["alice", "bob", "carol", "dave"]);
+        assert_eq!(marked(&fanout), [("erin", "1")]);
+    }
+
+    #[tokio::test]
+    async fn main_conversation_messages_fan_out_unchanged() {
+        let fixture = fixture();
+        let fanout = fixture.fanout(&reply("alice", None)).await;
+        assert_eq!(fanout.full, members());
+        assert!(fanout.markers.is_empty());
+        assert_eq!(fixture.resolver.resolves.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn markers_are_coalesced_to_one_per_window() {
+        let fixture = fixture();
+
+        let first = fixture.fanout(&reply("alice", Some("t1"))).await;
+        assert_eq!(marked(&first), [("dave", "1"), ("erin", "1")]);
+        for _ in 0..3 {
+            fixture.clock.advance(Duration::from_secs(10));
+            let coalesced = fixture.fanout(&reply("bob", Some("t1"))).await;
+            assert!(coalesced.markers.is_empty());
+            assert_eq!(coalesced.coalesced, 2);
+            assert_eq!(coalesced.full, ["alice", "bob", "carol"]);
+        }
+
+        // Another thread has its own window
+        let other = fixture.fanout(&reply("alice", Some("t2"))).await;
+        assert_eq!(marked(&other), [("dave", "1"), ("erin", "1")]);
+
+        // The next marker counts every reply since the last one went out
+        fixture.clock.advance(WINDOW);
+        let next = fixture.fanout(&reply("alice", Some("t1"))).await;
+        assert_eq!(marked(&next), [("dave", "4"), ("erin", "4")]);
+        assert_eq!(next.coalesced, 0);
+    }
+
+    #[tokio::test]
+    async fn an_override_forbidding_coalescing_marks_every_reply() {
+        let fixture = fixture();
+        fixture.overrides.set(
+            PathOverrideRule {
+                id: "no-coalescing".into(),
+                feature: PathFeature::Coalescing,
+                effect: OverrideEffect::Forbid,
+                tenants: Vec::new(),
+                conversations: vec![GROUP.into()],
+                gateways: Vec::new(),
+                kinds: Vec::new(),
+            },
+            None,
+            "ops",
+        );
+
+        for _ in 0..3 {
+            let fanout = fixture.fanout(&reply("alice", Some("t1"))).await;
+            assert_eq!(marked(&fanout), [("dave", "1"), ("erin", "1")]);
+            assert_eq!(fanout.coalesced, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn participants_are_cached_until_invalidated() {
+        let fixture = fixture();
+        let cache = fixture.router.participants();
+
+        cache.recipients(GROUP, "t1").await.unwrap();
+        cache.recipients(GROUP, "t1").await.unwrap();
+        cache.recipients(GROUP, "t2").await.unwrap();
+        cache.recipients("group-2", "t1").await.unwrap();
+        assert_eq!(fixture.resolver.resolves.load(Ordering::Relaxed), 3);
+
+        cache.invalidate(GROUP, Some("t1"));
+        cache.recipients(GROUP, "t1").await.unwrap();
+        cache.recipients(GROUP, "t2").await.unwrap();
+        assert_eq!(fixture.resolver.resolves.load(Ordering::Relaxed), 4);
+
+        // Every thread of the conversation, no other conversation's
+        cache.invalidate(GROUP, None);
+        cache.recipients(GROUP, "t1").await.unwrap();
+        cache.recipients(GROUP, "t2").await.unwrap();
+        cache.recipients("group-2", "t1").await.unwrap();
+        assert_eq!(fixture.resolver.resolves.load(Ordering::Relaxed), 6);
+
+        // And everything once the TTL has run out
+        fixture.clock.advance(BrokerConfig::load().unwrap().threads.participant_ttl);
+        cache.recipients("group-2", "t1").await.unwrap();
+        assert_eq!(fixture.resolver.resolves.load(Ordering::Relaxed), 7);
+    }
+
+    #[test]
+    fn thread_sequences_nest_under_their_conversation() {
+        assert_eq!(thread_sequence_key(GROUP, "t1"), "group-1/thread/t1");
+        assert_ne!(thread_sequence_key(GROUP, "t1"), thread_sequence_key("group-2", "t1"));
+        assert_ne!(thread_sequence_key(GROUP, "t1"), GROUP);
+    }
+
+    #[tokio::test]
+    async fn markers_and_coalesced_markers_are_traced_per_member() {
+        let fixture = fixture();
+        let tracer = RecipientTracer::new(BrokerConfig::load().unwrap().recipient_trace, BrokerMetrics::new().unwrap());
+        tracer.add_watch(RoutingWatch::Conversation(GROUP.into()), None);
+
+        let first = reply("alice", Some("t1"));
+        let mut decisions = tracer.begin(&first);
+        fixture.router.fanout(&first, &members(), &mut decisions).await.unwrap();
+        assert_eq!(decisions.decision_for("dave"), Some(RecipientDecision::Downgraded { how: Downgrade::ThreadMarker }));
+        assert_eq!(decisions.decision_for("carol"), None, "full copies aren't a decision of this stage");
+
+        fixture.clock.advance(Duration::from_secs(10));
+        let second = reply("bob", Some("t1"));
+        let mut decisions = tracer.begin(&second);
+        fixture.router.fanout(&second, &members(), &mut decisions).await.unwrap();
+        for member in ["dave", "erin"] {
+            assert_eq!(
+                decisions.decision_for(member),
+                Some(RecipientDecision::Excluded { reason: ExclusionReason::ThreadMarkerCoalesced }),
+                "{}",
+                member
+            );
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use std::{collections::HashMap, sync::Arc};
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+        ECDSA_P384_SHA384_ASN1_SIGNING,
+    },
+};
+use serde::Serialize;
+use tracing::{error, info, warn};
+use x509_parser::{certificate::X509Certificate, pem::Pem};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::{BrokerConfig, MonitoredCert, TlsMonitorConfig},
+    config_watch::ConfigWatcher,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Result of one pairing or chain check
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", content = "detail", rename_all = "snake_case")]
+pub enum CheckOutcome {
+    Ok,
+    Failed(String),
+    /// Nothing to check against, or a key format that can't be inspected
+    Unchecked,
+}
+
+impl CheckOutcome {
+    fn failed(&self) -> bool {
+        matches!(self, CheckOutcome::Failed(_))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertStatus {
+    pub purpose: String,
+    /// Earliest expiry in the file's chain, seconds since the epoch
+    pub not_after: Option<i64>,
+    /// Why the certificate file couldn't be read or parsed
+    pub invalid: Option<String>,
+    pub key_pair: CheckOutcome,
+    pub chain: CheckOutcome,
+    /// Smallest `tls_monitor.warn_days` threshold the certificate is inside
+    pub warn_threshold: Option<u32>,
+}
+
+/// Expiry, key pairing and chain checks for every configured certificate
+///
+/// Covers the gRPC listener certificate, the NATS client certificate and CA
+/// bundle, and anything listed in `tls_monitor.extra` (admin and webhook
+/// client certificates). Each check exports the earliest `not_after` of the
+/// file's chain as `broker_tls_cert_expiry_timestamp_seconds{purpose}`, sets
+/// `broker_tls_cert_invalid{purpose}` when the file won't parse (with the
+/// expiry gauge at 0, so expiry alerts fire for it too), and
+/// `broker_tls_cert_check_failed{purpose,check}` when the private key
+/// doesn't match the certificate or the chain doesn't verify against the
+/// configured CA, so a bad rotation shows up before the next handshake.
+/// A warning is logged once each time a certificate crosses one of
+/// `tls_monitor.warn_days`, and an error on every check once it has expired.
+/// Checks read and parse files and load keys, so the periodic task runs
+/// them on the blocking pool.
+pub struct TlsMonitor {
+    targets: ArcSwap<Vec<MonitoredCert>>,
+    config: ArcSwap<TlsMonitorConfig>,
+    /// Smallest threshold already warned about, per purpose
+    warned: Mutex<HashMap<String, u32>>,
+    metrics: BrokerMetrics,
+    clock: SharedClock,
+}
+
+impl TlsMonitor {
+    pub fn new(config: &BrokerConfig, metrics: BrokerMetrics) -> Self {
+        Self {
+            targets: ArcSwap::from_pointee(targets(config)),
+            config: ArcSwap::from_pointee(config.tls_monitor.clone()),
+            warned: Mutex::new(HashMap::new()),
+            metrics,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Check every configured certificate once; blocks on file reads
+    pub fn check_all(&self) -> Vec<CertStatus> {
+        let now = self.clock.now_utc().timestamp();
+        self.targets
+            .load()
+            .iter()
+            .map(|target| {
+                let mut status = check(target);
+                self.report(&mut status, now);
+                status
+            })
+            .collect()
+    }
+
+    fn report(&self, status: &mut CertStatus, now: i64) {
+        let purpose = status.purpose.clone();
+        let purpose = purpose.as_str();
+        self.metrics.update_tls_cert_invalid(purpose, status.invalid.is_some());
+        self.metrics.update_tls_cert_check_failed(purpose, "key_pair", status.key_pair.failed());
+        self.metrics.update_tls_cert_check_failed(purpose, "chain", status.chain.failed());
+
+        if let Some(reason) = &status.invalid {
+            error!("TLS certificate for {} is unusable: {}", purpose, reason);
+            self.metrics.update_tls_cert_expiry(purpose, 0);
+            return;
+        }
+        if let CheckOutcome::Failed(reason) = &status.key_pair {
+            error!("TLS key for {} doesn't match its certificate: {}", purpose, reason);
+        }
+        if let CheckOutcome::Failed(reason) = &status.chain {
+            error!("TLS certificate chain for {} doesn't verify: {}", purpose, reason);
+        }
+
+        let Some(not_after) = status.not_after else {
+            return;
+        };
+        self.metrics.update_tls_cert_expiry(purpose, not_after);
+
+        let days_left = (not_after - now) / SECONDS_PER_DAY;
+        if not_after <= now {
+            error!("TLS certificate for {} expired {} days ago", purpose, -days_left);
+            return;
+        }
+        let threshold = self
+            .config
+            .load()
+            .warn_days
+            .iter()
+            .copied()
+            .filter(|days| days_left < *days as i64)
+            .min();
+        status.warn_threshold = threshold;
+        let mut warned = self.warned.lock();
+        match threshold {
+            Some(days) if warned.get(purpose) != Some(&days) => {
+                warn!("TLS certificate for {} expires in {} days (under {})", purpose, days_left, days);
+                warned.insert(purpose.to_string(), days);
+            }
+            Some(_) => {}
+            None => {
+                if warned.remove(purpose).is_some() {
+                    info!("TLS certificate for {} renewed, expires in {} days", purpose, days_left);
+                }
+            }
+        }
+    }
+
+    /// Follow certificate paths and thresholds across reloads
+    pub fn watch(self: &Arc<Self>, watcher: &mut ConfigWatcher) {
+        let monitor = Arc::clone(self);
+        watcher.on_reload(move |config| {
+            monitor.targets.store(Arc::new(targets(config)));
+            monitor.config.store(Arc::new(config.tls_monitor.clone()));
+        });
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let monitor = Arc::clone(self);
+        spawn_traced("tls_monitor", TaskContext::new("tls"), async move {
+            loop {
+                let checker = Arc::clone(&monitor);
+                if let Err(e) = tokio::task::spawn_blocking(move || checker.check_all()).await {
+                    error!("TLS certificate check panicked: {}", e);
+                }
+                monitor.clock.sleep(monitor.config.load().check_interval).await;
+            }
+        })
+    }
+}
+
+/// Certificates named anywhere in the config, plus `tls_monitor.extra`
+fn targets(config: &BrokerConfig) -> Vec<MonitoredCert> {
+    let mut targets = Vec::new();
+    if let Some(cert) = &config.api.grpc_tls_cert {
+        targets.push(MonitoredCert {
+            purpose: "grpc".into(),
+            cert: cert.clone(),
+            key: config.api.grpc_tls_key.clone(),
+            // Peers verify the gRPC listener against the NATS CA, see `forward.rs`
+            ca: config.nats.tls_ca.clone(),
+        });
+    }
+    if let Some(cert) = &config.nats.tls_cert {
+        targets.push(MonitoredCert {
+            purpose: "nats_client".into(),
+            cert: cert.clone(),
+            key: config.nats.tls_key.clone(),
+            ca: config.nats.tls_ca.clone(),
+        });
+    }
+    if let Some(ca) = &config.nats.tls_ca {
+        targets.push(MonitoredCert {
+            purpose: "nats_ca".into(),
+            cert: ca.clone(),
+            key: None,
+            ca: None,
+        });
+    }
+    targets.extend(config.tls_monitor.extra.iter().cloned());
+    targets
+}
+
+fn check(target: &MonitoredCert) -> CertStatus {
+    let mut status = CertStatus {
+        purpose: target.purpose.clone(),
+        not_after: None,
+        invalid: None,
+        key_pair: CheckOutcome::Unchecked,
+        chain: CheckOutcome::Unchecked,
+        warn_threshold: None,
+    };
+
+    let pems = match read_pems(&target.cert) {
+        Ok(pems) => pems,
+        Err(reason) => {
+            status.invalid = Some(reason);
+            return status;
+        }
+    };
+    let chain = match parse_certs(&pems) {
+        Ok(chain) if !chain.is_empty() => chain,
+        Ok(_) => {
+            status.invalid = Some(format!("no certificate in {}", target.cert));
+            return status;
+        }
+        Err(reason) => {
+            status.invalid = Some(reason);
+            return status;
+        }
+    };
+    status.not_after = chain.iter().map(|cert| cert.validity().not_after.timestamp()).min();
+
+    if let Some(key) = &target.key {
+        status.key_pair = check_key_pair(&chain[0], key);
+    }
+    if let Some(ca) = &target.ca {
+        status.chain = check_chain(&chain, ca);
+    }
+    status
+}
+
+fn read_pems(path: &str) -> Result<Vec<Pem>, String> {
+    let raw = std::fs::read(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+    Pem::iter_from_buffer(&raw)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("bad PEM in {}: {}", path, e))
+}
+
+/// Certificates in file order: leaf first, then intermediates
+fn parse_certs(pems: &[Pem]) -> Result<Vec<X509Certificate<'_>>, String> {
+    pems.iter()
+        .filter(|pem| pem.label == "CERTIFICATE")
+        .map(|pem| pem.parse_x509().map_err(|e| format!("bad certificate: {}", e)))
+        .collect()
+}
+
+fn check_key_pair(leaf: &X509Certificate<'_>, key_path: &str) -> CheckOutcome {
+    let pems = match read_pems(key_path) {
+        Ok(pems) => pems,
+        Err(reason) => return CheckOutcome::Failed(reason),
+    };
+    let Some(key) = pems.iter().find(|pem| pem.label.ends_with("PRIVATE KEY")) else {
+        return CheckOutcome::Failed(format!("no private key in {}", key_path));
+    };
+    // SEC1 and encrypted keys can't be loaded to derive their public half
+    let Some(public) = public_key_of(key) else {
+        return CheckOutcome::Unchecked;
+    };
+    if public == leaf.public_key().subject_public_key.data.as_ref() {
+        CheckOutcome::Ok
+    } else {
+        CheckOutcome::Failed(format!("{} holds the key for a different certificate", key_path))
+    }
+}
+
+/// Public key bytes in the form certificates carry them
+fn public_key_of(key: &Pem) -> Option<Vec<u8>> {
+    let der = key.contents.as_slice();
+    match key.label.as_str() {
+        "PRIVATE KEY" => {
+            let rng = SystemRandom::new();
+            if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+                return Some(pair.public_key().as_ref().to_vec());
+            }
+            for alg in [&ECDSA_P256_SHA256_ASN1_SIGNING, &ECDSA_P384_SHA384_ASN1_SIGNING] {
+                if let Ok(pair) = EcdsaKeyPair::from_pkcs8(alg, der, &rng) {
+                    return Some(pair.public_key().as_ref().to_vec());
+                }
+            }
+            RsaKeyPair::from_pkcs8(der).ok().map(|pair| pair.public_key().as_ref().to_vec())
+        }
+        "RSA PRIVATE KEY" => RsaKeyPair::from_der(der).ok().map(|pair| pair.public_key().as_ref().to_vec()),
+        _ => None,
+    }
+}
+
+/// Each certificate must be signed by the next, and the last by a CA in the bundle
+fn check_chain(chain: &[X509Certificate<'_>], ca_path: &str) -> CheckOutcome {
+    let ca_pems = match read_pems(ca_path) {
+        Ok(pems) => pems,
+        Err(reason) => return CheckOutcome::Failed(reason),
+    };
+    let roots = match parse_certs(&ca_pems) {
+        Ok(roots) => roots,
+        Err(reason) => return CheckOutcome::Failed(reason),
+    };
+
+    for pair in chain.windows(2) {
+        let (cert, issuer) = (&pair[0], &pair[1]);
+        if cert.issuer().as_raw() != issuer.subject().as_raw() {
+            return CheckOutcome::Failed(format!("{} is not issued by the next certificate, {}", cert.subject(), issuer.subject()));
+        }
+        if let Err(e) = cert.verify_signature(Some(issuer.public_key())) {
+            return CheckOutcome::Failed(format!("signature on {} doesn't verify: {}", cert.subject(), e));
+        }
+    }
+
+    let last = chain.last().expect("chain is non-empty");
+    let verified = roots
+        .iter()
+        .filter(|root| root.subject().as_raw() == last.issuer().as_raw())
+        .any(|root| last.verify_signature(Some(root.public_key())).is_ok());
+    if verified {
+        CheckOutcome::Ok
+    } else {
+        CheckOutcome::Failed(format!("no CA in {} signed {}", ca_path, last.issuer()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+    use chrono::{Datelike, Utc};
+    use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusRecorder};
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::clock::{Clock, SimClock};
+
+    /// A CA, certificates it issued and one issued by a stranger, in a temp dir
+    struct Fixtures {
+        dir: PathBuf,
+    }
+
+    impl Fixtures {
+        fn generate(valid_days: i64) -> Self {
+            let dir = std::env::temp_dir().join(format!("tls-monitor-{}", Uuid::new_v4().simple()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let ca = |name: &str| {
+                let mut params = CertificateParams::new(Vec::new());
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                params.distinguished_name = DistinguishedName::new();
+                params.distinguished_name.push(DnType::CommonName, name);
+                rcgen::Certificate::from_params(params).unwrap()
+            };
+            let expiry = Utc::now() + chrono::Duration::days(valid_days);
+            let leaf = || {
+                let mut params = CertificateParams::new(vec!["broker.internal".to_string()]);
+                params.not_after = rcgen::date_time_ymd(expiry.year(), expiry.month() as u8, expiry.day() as u8);
+                rcgen::Certificate::from_params(params).unwrap()
+            };
+            let write = |file: &str, contents: String| std::fs::write(dir.join(file), contents).unwrap();
+
+            let cluster_ca = ca("cluster ca");
+            write("ca.pem", cluster_ca.serialize_pem().unwrap());
+            let broker = leaf();
+            write("broker.pem", broker.serialize_pem_with_signer(&cluster_ca).unwrap());
+            write("broker.key", broker.serialize_private_key_pem());
+            write("other.key", leaf().serialize_private_key_pem());
+            write("stranger.pem", leaf().serialize_pem_with_signer(&ca("cluster ca")).unwrap());
+            Self { dir }
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_string_lossy().into_owned()
+        }
+
+        fn target(&self, purpose: &str, cert: &str, key: &str) -> MonitoredCert {
+            MonitoredCert {
+                purpose: purpose.into(),
+                cert: self.path(cert),
+                key: Some(self.path(key)),
+                ca: Some(self.path("ca.pem")),
+            }
+        }
+    }
+
+    impl Drop for Fixtures {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn monitor(targets: Vec<MonitoredCert>, clock: &Arc<SimClock>) -> TlsMonitor {
+        let mut config = BrokerConfig::load().unwrap();
+        config.api.grpc_tls_cert = None;
+        config.nats.tls_cert = None;
+        config.nats.tls_ca = None;
+        config.tls_monitor.extra = targets;
+        TlsMonitor::new(&config, BrokerMetrics::new().unwrap()).with_clock(clock.clone())
+    }
+
+    fn check(monitor: &TlsMonitor, recorder: &PrometheusRecorder) -> Vec<CertStatus> {
+        metrics::with_local_recorder(recorder, || monitor.check_all())
+    }
+
+    fn assert_rendered(recorder: &PrometheusRecorder, lines: &[String]) {
+        let rendered = recorder.handle().render();
+        for line in lines {
+            assert!(rendered.contains(line.as_str()), "missing {} in\n{}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn a_matching_key_and_chain_export_the_expiry() {
+        let fixtures = Fixtures::generate(90);
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let monitor = monitor(vec![fixtures.target("grpc", "broker.pem", "broker.key")], &Arc::new(SimClock::new()));
+
+        let status = &check(&monitor, &recorder)[0];
+        assert_eq!((&status.key_pair, &status.chain), (&CheckOutcome::Ok, &CheckOutcome::Ok));
+        assert_eq!((status.invalid.as_deref(), status.warn_threshold), (None, None));
+        assert_rendered(
+            &recorder,
+            &[
+                format!(r#"broker_tls_cert_expiry_timestamp_seconds{{purpose="grpc"}} {}"#, status.not_after.unwrap()),
+                r#"broker_tls_cert_invalid{purpose="grpc"} 0"#.into(),
+                r#"broker_tls_cert_check_failed{purpose="grpc",check="key_pair"} 0"#.into(),
+                r#"broker_tls_cert_check_failed{purpose="grpc",check="chain"} 0"#.into(),
+            ],
+        );
+    }
+
+    #[test]
+    fn short_lived_certs_cross_each_warning_threshold_as_time_passes() {
+        let fixtures = Fixtures::generate(20);
+        let clock = Arc::new(SimClock::new());
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let monitor = monitor(vec![fixtures.target("nats_client", "broker.pem", "broker.key")], &clock);
+
+        assert_eq!(check(&monitor, &recorder)[0].warn_threshold, Some(30));
+        assert_eq!(monitor.warned.lock().get("nats_client"), Some(&30));
+        clock.advance(Duration::from_secs(8 * SECONDS_PER_DAY as u64));
+        assert_eq!(check(&monitor, &recorder)[0].warn_threshold, Some(14));
+        clock.advance(Duration::from_secs(7 * SECONDS_PER_DAY as u64));
+        assert_eq!(check(&monitor, &recorder)[0].warn_threshold, Some(7));
+        assert_eq!(monitor.warned.lock().get("nats_client"), Some(&7));
+
+        clock.advance(Duration::from_secs(7 * SECONDS_PER_DAY as u64));
+        let expired = &check(&monitor, &recorder)[0];
+        assert!(expired.not_after.unwrap() < clock.now_utc().timestamp());
+        assert_eq!(expired.warn_threshold, None);
+    }
+
+    #[test]
+    fn mismatched_keys_and_foreign_chains_are_reported_per_check() {
+        let fixtures = Fixtures::generate(90);
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let monitor = monitor(
+            vec![
+                fixtures.target("webhook", "broker.pem", "other.key"),
+                fixtures.target("admin", "stranger.pem", "broker.key"),
+            ],
+            &Arc::new(SimClock::new()),
+        );
+
+        let statuses = check(&monitor, &recorder);
+        assert!(matches!(statuses[0].key_pair, CheckOutcome::Failed(_)));
+        assert_eq!(statuses[0].chain, CheckOutcome::Ok);
+        assert!(matches!(statuses[1].chain, CheckOutcome::Failed(_)));
+        assert_rendered(
+            &recorder,
+            &[
+                r#"broker_tls_cert_check_failed{purpose="webhook",check="key_pair"} 1"#.into(),
+                r#"broker_tls_cert_check_failed{purpose="webhook",check="chain"} 0"#.into(),
+                r#"broker_tls_cert_check_failed{purpose="admin",check="chain"} 1"#.into(),
+            ],
+        );
+    }
+
+    #[test]
+    fn a_certificate_that_stops_parsing_zeroes_its_expiry() {
+        let fixtures = Fixtures::generate(90);
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let monitor = monitor(vec![fixtures.target("grpc", "broker.pem", "broker.key")], &Arc::new(SimClock::new()));
+        check(&monitor, &recorder);
+
+        std::fs::write(fixtures.path("broker.pem"), "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydA==\n-----END CERTIFICATE-----\n").unwrap();
+        let status = &check(&monitor, &recorder)[0];
+        assert!(status.invalid.is_some());
+        assert_rendered(
+            &recorder,
+            &[
+                r#"broker_tls_cert_invalid{purpose="grpc"} 1"#.into(),
+                r#"broker_tls_cert_expiry_timestamp_seconds{purpose="grpc"} 0"#.into(),
+            ],
+        );
+
+        std::fs::remove_file(fixtures.path("broker.pem")).unwrap();
+        assert!(check(&monitor, &recorder)[0].invalid.as_deref().unwrap().contains("can't read"));
+    }
+
+    #[tokio::test]
+    async fn the_periodic_check_runs_off_the_runtime_on_the_clock() {
+        let fixtures = Fixtures::generate(20);
+        let clock = Arc::new(SimClock::new());
+        let monitor = Arc::new(monitor(vec![fixtures.target("grpc", "broker.pem", "broker.key")], &clock));
+        let task = monitor.spawn();
+
+        while clock.pending() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(monitor.warned.lock().get("grpc"), Some(&30));
+        task.abort();
+    }
+}
@@ -0,0 +1,36 @@
+use chrono::Utc;
+use serde::Serialize;
+
+/// One decision recorded while processing a message
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceEvent {
+    /// Timestamp in milliseconds
+    pub at: i64,
+    /// Pipeline stage that made the decision
+    pub stage: &'static str,
+    pub detail: String,
+}
+
+/// Decisions made for a single message as it moves through the pipeline
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MessageTrace {
+    pub message_id: String,
+    pub events: Vec<TraceEvent>,
+}
+
+impl MessageTrace {
+    pub fn new(message_id: impl Into<String>) -> Self {
+        Self {
+            message_id: message_id.into(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, stage: &'static str, detail: impl Into<String>) {
+        self.events.push(TraceEvent {
+            at: Utc::now().timestamp_millis(),
+            stage,
+            detail: detail.into(),
+        });
+    }
+}
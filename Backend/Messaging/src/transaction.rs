@@ -0,0 +1,907 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use async_nats::{
+    jetstream::{self, kv},
+    HeaderMap,
+};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    archive::ArchivedConversations,
+    clock::{SharedClock, SystemClock},
+    config::RateLimits,
+    deadline::{Deadline, DeadlineExceeded},
+    idempotent_append::{self, AppendKey, AppendPurpose},
+    message::types::MessageEnvelope,
+    metrics::BrokerMetrics,
+    persist::{schema, VersionedCodec},
+    rate_limit::{QuotaStatus, UserRateLimiter},
+    sequence::SequenceAllocator,
+    task::{spawn_traced, ShutdownSignal, TaskContext},
+    thread::thread_sequence_key,
+};
+
+pub const TRANSACTION_ID_HEADER: &str = "Broker-Transaction-Id";
+pub const TRANSACTION_INDEX_HEADER: &str = "Broker-Transaction-Index";
+pub const TRANSACTION_SIZE_HEADER: &str = "Broker-Transaction-Size";
+
+/// Preparing markers older than this belong to a broker that crashed mid-publish
+const ORPHAN_GRACE: Duration = Duration::from_secs(30);
+
+const MARKER_CODEC: VersionedCodec<TransactionMarker> = VersionedCodec::new(schema::TRANSACTION_MARKER, 1, &[]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MarkerState {
+    /// Messages are being published; consumers must wait
+    Preparing,
+    /// Every message was published; consumers fan out
+    Enqueued,
+    /// Publishing failed part-way; consumers dead-letter every message
+    DeadLettered,
+}
+
+/// Batch marker stored in the checkpoint bucket under `txn.{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TransactionMarker {
+    transaction_id: String,
+    message_ids: Vec<String>,
+    state: MarkerState,
+    created_at: i64,
+}
+
+/// What a consumer should do with a message belonging to a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionDisposition {
+    FanOut,
+    DeadLetter,
+    /// The batch is still being published; redeliver later
+    Wait,
+}
+
+/// Accepted transaction
+#[derive(Debug, Clone)]
+pub struct TransactionReceipt {
+    pub transaction_id: String,
+    pub message_ids: Vec<String>,
+    pub sequences: Vec<u64>,
+    /// Each sender's rate-limit bucket after the batch was reserved
+    pub quotas: Vec<(String, QuotaStatus)>,
+}
+
+/// Per-message rejection detail
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageError {
+    pub index: usize,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// All-or-nothing acceptance of a related batch of messages
+///
+/// Every message is validated and rate-limit tokens for the whole batch are
+/// reserved before anything is published. A checkpoint marker records the
+/// batch so that after a crash it either fans out completely (marker was
+/// `Enqueued`) or is dead-lettered completely (marker was `Preparing`).
+/// Marker transitions are compare-and-swap on the marker's revision, so a
+/// publisher finishing late can't flip a batch that recovery or a consumer
+/// already dead-lettered back to `Enqueued`.
+pub struct TransactionCoordinator {
+    limits: RateLimits,
+    limiter: Arc<UserRateLimiter>,
+    sequences: Arc<SequenceAllocator>,
+    archived: Arc<ArchivedConversations>,
+    jetstream: jetstream::Context,
+    checkpoints: kv::Store,
+    ingress_subject: String,
+    dead_letter_subject: String,
+    unfanned: DashMap<String, usize>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl TransactionCoordinator {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        limits: RateLimits,
+        limiter: Arc<UserRateLimiter>,
+        sequences: Arc<SequenceAllocator>,
+        archived: Arc<ArchivedConversations>,
+        jetstream: jetstream::Context,
+        checkpoints: kv::Store,
+        ingress_subject: String,
+        dead_letter_subject: String,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            limits,
+            limiter,
+            sequences,
+            archived,
+            jetstream,
+            checkpoints,
+            ingress_subject,
+            dead_letter_subject,
+            unfanned: DashMap::new(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Validate, reserve and publish `messages` as one batch
+    ///
+    /// Past `deadline` the batch is abandoned before the next stage, with the
+    /// rate-limit reservation released; once publishing has started the
+    /// marker is dead-lettered as for a publish failure. The final `Enqueued`
+    /// write isn't abandoned, as finishing is cheaper than undoing.
+    pub async fn submit(
+        &self,
+        mut messages: Vec<MessageEnvelope>,
+        deadline: &Deadline,
+    ) -> Result<TransactionReceipt, TransactionError> {
+        if let Err(e) = validate_batch(&messages, &self.limits) {
+            match &e {
+                TransactionError::TooLarge { .. } => self.metrics.record_transaction("too_large"),
+                TransactionError::Rejected(_) => self.metrics.record_transaction("invalid"),
+                _ => {}
+            }
+            return Err(e);
+        }
+
+        let mut errors = Vec::new();
+        for (index, message) in messages.iter().enumerate() {
+            let archived = deadline
+                .run("archive_lookup", self.archived.rejects(message))
+                .await?
+                .map_err(|e| TransactionError::Archive(e.to_string()))?;
+            if archived {
+                errors.push(MessageError {
+                    index,
+                    code: "CONVERSATION_ARCHIVED",
+                    message: format!("conversation {} is archived", message.conversation_id()),
+                });
+            }
+        }
+        if !errors.is_empty() {
+            self.metrics.record_transaction("archived");
+            return Err(TransactionError::Rejected(errors));
+        }
+
+        // Reserve capacity for the whole batch at once
+        let reservation = match self.limiter.reserve(&sender_demands(&messages)) {
+            Ok(reservation) => reservation,
+            Err(limited) => {
+                self.metrics.record_transaction("rate_limited");
+                let errors = messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| m.from == limited.user_id)
+                    .map(|(index, _)| MessageError {
+                        index,
+                        code: "RATE_LIMITED",
+                        message: limited.to_string(),
+                    })
+                    .collect();
+                return Err(TransactionError::RateLimited {
+                    errors,
+                    quota: limited.quota,
+                });
+            }
+        };
+
+        let transaction_id = Uuid::new_v4().to_string();
+        let assigned = match deadline.run("assign_sequences", self.assign_sequences(&mut messages)).await {
+            Ok(assigned) => assigned,
+            Err(e) => {
+                self.limiter.release(reservation);
+                self.metrics.record_transaction("deadline_exceeded");
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = assigned {
+            self.limiter.release(reservation);
+            self.metrics.record_transaction("sequence_failed");
+            return Err(e);
+        }
+        if let Err(e) = deadline.check("checkpoint") {
+            self.limiter.release(reservation);
+            self.metrics.record_transaction("deadline_exceeded");
+            return Err(e.into());
+        }
+
+        let mut marker = TransactionMarker {
+            transaction_id: transaction_id.clone(),
+            message_ids: messages.iter().map(|m| m.message_id.clone()).collect(),
+            state: MarkerState::Preparing,
+            created_at: self.clock.now_millis(),
+        };
+        let revision = match self.create_marker(&marker).await {
+            Ok(revision) => revision,
+            Err(e) => {
+                self.limiter.release(reservation);
+                self.metrics.record_transaction("checkpoint_failed");
+                return Err(e);
+            }
+        };
+
+        let size = messages.len().to_string();
+        for (index, message) in messages.iter().enumerate() {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRANSACTION_ID_HEADER, transaction_id.as_str());
+            headers.insert(TRANSACTION_INDEX_HEADER, index.to_string().as_str());
+            headers.insert(TRANSACTION_SIZE_HEADER, size.as_str());
+
+            let published = match deadline.run("publish", self.publish(headers, message)).await {
+                Ok(published) => published,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = published {
+                warn!("Transaction {} failed at message {}: {}", transaction_id, index, e);
+                marker.state = MarkerState::DeadLettered;
+                if let Err(e) = self.update_marker(&marker, revision).await {
+                    // Left as Preparing; recovery dead-letters it after the grace period
+                    warn!("Failed to mark transaction {} dead-lettered: {}", transaction_id, e);
+                }
+                self.limiter.release(reservation);
+                self.metrics.record_transaction(match e {
+                    TransactionError::Deadline(_) => "deadline_exceeded",
+                    _ => "publish_failed",
+                });
+                return Err(e);
+            }
+        }
+
+        marker.state = MarkerState::Enqueued;
+        if let Err(e) = self.update_marker(&marker, revision).await {
+            // Still Preparing, or already dead-lettered as an orphan; either way it won't fan out
+            warn!("Failed to mark transaction {} enqueued: {}", transaction_id, e);
+            self.limiter.release(reservation);
+            self.metrics.record_transaction("checkpoint_failed");
+            return Err(e);
+        }
+        let quotas = reservation.commit();
+
+        self.unfanned.insert(transaction_id.clone(), messages.len());
+        self.metrics.record_transaction("accepted");
+
+        Ok(TransactionReceipt {
+            transaction_id,
+            message_ids: marker.message_ids,
+            sequences: messages.iter().filter_map(|m| m.sequence).collect(),
+            quotas,
+        })
+    }
+
+    /// Decide what to do with a consumed message carrying a transaction header
+    ///
+    /// A `Preparing` marker past the orphan grace is dead-lettered here, so
+    /// consumers never wait on a batch whose publisher is gone.
+    pub async fn disposition(&self, transaction_id: &str) -> TransactionDisposition {
+        if self.unfanned.contains_key(transaction_id) {
+            return TransactionDisposition::FanOut;
+        }
+
+        match self.read_marker(transaction_id).await {
+            Ok(Some((marker, revision))) => match marker.state {
+                MarkerState::Enqueued => TransactionDisposition::FanOut,
+                MarkerState::DeadLettered => TransactionDisposition::DeadLetter,
+                MarkerState::Preparing if is_orphaned(&marker, self.clock.now_millis()) => {
+                    match self.abandon(marker, revision).await {
+                        Ok(()) => TransactionDisposition::DeadLetter,
+                        // Lost to the publisher or another consumer; the next redelivery reads the winner
+                        Err(_) => TransactionDisposition::Wait,
+                    }
+                }
+                MarkerState::Preparing => TransactionDisposition::Wait,
+            },
+            // Marker already cleaned up after every message fanned out
+            Ok(None) => TransactionDisposition::FanOut,
+            Err(e) => {
+                warn!("Failed to read transaction {} marker: {}", transaction_id, e);
+                TransactionDisposition::Wait
+            }
+        }
+    }
+
+    /// Record that one message of the transaction finished fanout
+    ///
+    /// A batch this broker isn't counting, e.g. one submitted elsewhere, had
+    /// its marker read as `Enqueued` to get here, so the marker goes at once;
+    /// its other messages then fan out on a missing marker.
+    pub async fn mark_fanned(&self, transaction_id: &str) {
+        let done = match self.unfanned.get_mut(transaction_id) {
+            Some(mut remaining) => {
+                *remaining = remaining.saturating_sub(1);
+                *remaining == 0
+            }
+            None => true,
+        };
+
+        if done {
+            self.unfanned.remove(transaction_id);
+            if let Err(e) = self.checkpoints.delete(marker_key(transaction_id)).await {
+                warn!("Failed to delete transaction {} marker: {}", transaction_id, e);
+            }
+        }
+    }
+
+    /// Publish a message of a failed transaction to the dead-letter subject
+    ///
+    /// Dead-lettering the same message twice, e.g. when recovery reruns after
+    /// a crash, leaves one copy.
+    pub async fn dead_letter(
+        &self,
+        transaction_id: &str,
+        message_id: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), TransactionError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRANSACTION_ID_HEADER, transaction_id);
+        let key = AppendKey {
+            purpose: AppendPurpose::DeadLetter,
+            message_id,
+            recipient: "",
+        };
+        let appended = idempotent_append::append(
+            &self.jetstream,
+            key,
+            self.dead_letter_subject.clone(),
+            headers,
+            payload.into(),
+            &self.metrics,
+        )
+        .await
+        .map_err(|e| TransactionError::Publish(e.to_string()))?;
+        if let idempotent_append::Appended::New { .. } = appended {
+            self.metrics.record_message_dropped("transaction_dead_lettered");
+        }
+        Ok(())
+    }
+
+    /// Resolve markers left behind by a crash
+    ///
+    /// `Preparing` markers past the grace period are dead-lettered; `Enqueued`
+    /// markers are tracked so they are cleaned up once fanout completes.
+    /// Returns how many `Preparing` markers are still within their grace.
+    pub async fn recover(&self) -> Result<usize, TransactionError> {
+        let mut keys = self
+            .checkpoints
+            .keys()
+            .await
+            .map_err(|e| TransactionError::Checkpoint(e.to_string()))?;
+
+        let now_ms = self.clock.now_millis();
+        let mut dead_lettered = 0;
+        let mut preparing = 0;
+
+        while let Some(key) = keys.next().await {
+            let Ok(key) = key else { continue };
+            let Some(transaction_id) = key.strip_prefix("txn.") else {
+                continue;
+            };
+            let Ok(Some((marker, revision))) = self.read_marker(transaction_id).await else {
+                continue;
+            };
+
+            match marker.state {
+                MarkerState::Preparing if is_orphaned(&marker, now_ms) => {
+                    if self.abandon(marker, revision).await.is_ok() {
+                        dead_lettered += 1;
+                    }
+                }
+                MarkerState::Preparing => preparing += 1,
+                MarkerState::Enqueued => {
+                    self.unfanned
+                        .entry(marker.transaction_id.clone())
+                        .or_insert(marker.message_ids.len());
+                }
+                MarkerState::DeadLettered => {}
+            }
+        }
+
+        info!(
+            "Transaction recovery: {} pending, {} preparing, {} dead-lettered",
+            self.unfanned.len(),
+            preparing,
+            dead_lettered
+        );
+        Ok(preparing)
+    }
+
+    /// Recover at startup, then again after the grace period while any batch is still preparing
+    ///
+    /// A batch caught mid-publish by the startup pass is left `Preparing`
+    /// until it's past its grace, so recovery reruns until none remain.
+    pub fn spawn(self: &Arc<Self>, shutdown: ShutdownSignal) -> tokio::task::JoinHandle<()> {
+        let coordinator = Arc::clone(self);
+        spawn_traced("transaction_recovery", TaskContext::new("transaction"), async move {
+            loop {
+                match coordinator.recover().await {
+                    Ok(0) => return,
+                    Ok(_) => {}
+                    Err(e) => warn!("Transaction recovery failed: {}", e),
+                }
+                tokio::select! {
+                    _ = coordinator.clock.sleep(ORPHAN_GRACE) => {}
+                    _ = shutdown.wait() => return,
+                }
+            }
+        })
+    }
+
+    /// Dead-letter an orphaned `Preparing` marker unless it changed since it was read
+    async fn abandon(&self, mut marker: TransactionMarker, revision: u64) -> Result<(), TransactionError> {
+        marker.state = MarkerState::DeadLettered;
+        self.update_marker(&marker, revision).await?;
+        self.metrics.record_transaction("orphan_dead_lettered");
+        warn!("Dead-lettered orphaned transaction {}", marker.transaction_id);
+        Ok(())
+    }
+
+    async fn assign_sequences(&self, messages: &mut [MessageEnvelope]) -> Result<(), TransactionError> {
+        let mut per_conversation: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, message) in messages.iter().enumerate() {
+            per_conversation
+                .entry(message.conversation_id())
+                .or_default()
+                .push(index);
+        }
+
+        for (conversation_id, indices) in per_conversation {
+            let range = self
+                .sequences
+                .allocate(&conversation_id, indices.len() as u64)
+                .await
+                .map_err(|e| TransactionError::Sequence(e.to_string()))?;
+            for (index, sequence) in indices.into_iter().zip(range) {
+                messages[index].sequence = Some(sequence);
+            }
+        }
+
+        // Thread replies also take the next number of their thread
+        let mut per_thread: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, message) in messages.iter().enumerate() {
+            if let Some(thread_id) = &message.thread_id {
+                per_thread
+                    .entry(thread_sequence_key(&message.conversation_id(), thread_id))
+                    .or_default()
+                    .push(index);
+            }
+        }
+        for (thread_key, indices) in per_thread {
+            let range = self
+                .sequences
+                .allocate(&thread_key, indices.len() as u64)
+                .await
+                .map_err(|e| TransactionError::Sequence(e.to_string()))?;
+            for (index, sequence) in indices.into_iter().zip(range) {
+                messages[index].thread_sequence = Some(sequence);
+            }
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, headers: HeaderMap, message: &MessageEnvelope) -> Result<(), TransactionError> {
+        let payload = serde_json::to_vec(message).map_err(|e| TransactionError::Publish(e.to_string()))?;
+        self.jetstream
+            .publish_with_headers(self.ingress_subject.clone(), headers, payload.into())
+            .await
+            .map_err(|e| TransactionError::Publish(e.to_string()))?
+            .await
+            .map_err(|e| TransactionError::Publish(e.to_string()))?;
+        self.metrics.record_nats_published(1);
+        Ok(())
+    }
+
+    /// Write a new marker; returns its revision
+    async fn create_marker(&self, marker: &TransactionMarker) -> Result<u64, TransactionError> {
+        let value = MARKER_CODEC
+            .encode(marker)
+            .map_err(|e| TransactionError::Checkpoint(e.to_string()))?;
+        self.checkpoints
+            .create(marker_key(&marker.transaction_id), value.into())
+            .await
+            .map_err(|e| TransactionError::Checkpoint(e.to_string()))
+    }
+
+    /// Replace the marker at `revision`; fails if anyone else wrote it since
+    async fn update_marker(&self, marker: &TransactionMarker, revision: u64) -> Result<u64, TransactionError> {
+        let value = MARKER_CODEC
+            .encode(marker)
+            .map_err(|e| TransactionError::Checkpoint(e.to_string()))?;
+        self.checkpoints
+            .update(marker_key(&marker.transaction_id), value.into(), revision)
+            .await
+            .map_err(|e| TransactionError::Checkpoint(e.to_string()))
+    }
+
+    async fn read_marker(&self, transaction_id: &str) -> Result<Option<(TransactionMarker, u64)>, TransactionError> {
+        let entry = self
+            .checkpoints
+            .entry(marker_key(transaction_id))
+            .await
+            .map_err(|e| TransactionError::Checkpoint(e.to_string()))?;
+
+        match entry {
+            Some(entry) if entry.operation == kv::Operation::Put => MARKER_CODEC
+                .decode(&entry.value)
+                .map(|marker| Some((marker, entry.revision)))
+                .map_err(|e| TransactionError::Checkpoint(e.to_string())),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Check batch size and every message up front, reporting each invalid one
+fn validate_batch(messages: &[MessageEnvelope], limits: &RateLimits) -> Result<(), TransactionError> {
+    if messages.is_empty() {
+        return Err(TransactionError::Empty);
+    }
+    if messages.len() > limits.max_transaction_messages {
+        return Err(TransactionError::TooLarge {
+            max: limits.max_transaction_messages,
+        });
+    }
+
+    let errors: Vec<MessageError> = messages
+        .iter()
+        .enumerate()
+        .filter_map(|(index, message)| {
+            message.validate(limits).err().map(|e| MessageError {
+                index,
+                code: "INVALID_MESSAGE",
+                message: e.to_string(),
+            })
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(TransactionError::Rejected(errors));
+    }
+    Ok(())
+}
+
+/// Messages per sender, in first-appearance order
+fn sender_demands(messages: &[MessageEnvelope]) -> Vec<(String, u32)> {
+    let mut demands: Vec<(String, u32)> = Vec::new();
+    for message in messages {
+        match demands.iter_mut().find(|(sender, _)| *sender == message.from) {
+            Some((_, count)) => *count += 1,
+            None => demands.push((message.from.clone(), 1)),
+        }
+    }
+    demands
+}
+
+fn marker_key(transaction_id: &str) -> String {
+    format!("txn.{}", transaction_id)
+}
+
+/// Whether a `Preparing` marker has outlived any publisher that could still finish it
+fn is_orphaned(marker: &TransactionMarker, now_ms: i64) -> bool {
+    marker.state == MarkerState::Preparing && now_ms.saturating_sub(marker.created_at) > ORPHAN_GRACE.as_millis() as i64
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionError {
+    #[error("transaction is empty")]
+    Empty,
+    #[error("transaction exceeds {max} messages")]
+    TooLarge { max: usize },
+    #[error("transaction rejected")]
+    Rejected(Vec<MessageError>),
+    /// Rejected for a sender's rate limit, with that sender's bucket state
+    #[error("transaction rate limited")]
+    RateLimited { errors: Vec<MessageError>, quota: QuotaStatus },
+    #[error("checkpoint error: {0}")]
+    Checkpoint(String),
+    #[error("sequence error: {0}")]
+    Sequence(String),
+    #[error("archive lookup error: {0}")]
+    Archive(String),
+    #[error("publish error: {0}")]
+    Publish(String),
+    #[error(transparent)]
+    Deadline(#[from] DeadlineExceeded),
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+        message::types::{EncryptedPayload, MessageType},
+    };
+
+    fn limits() -> RateLimits {
+        let mut limits = BrokerConfig::load().unwrap().limits;
+        limits.user_message_limit = 5;
+        limits.burst_credit_max = 0;
+        limits.max_transaction_messages = 10;
+        limits
+    }
+
+    fn message(from: &str, to: &str) -> MessageEnvelope {
+        let payload = EncryptedPayload {
+            ciphertext: "aGk=".into(),
+            iv: None,
+            tag: None,
+            key_id: None,
+            content_type: None,
+        };
+        MessageEnvelope::new(MessageType::TextMessage, from.into(), vec![to.into()], payload)
+    }
+
+    fn limiter(limits: &RateLimits) -> UserRateLimiter {
+        UserRateLimiter::new(limits, BrokerMetrics::new().unwrap()).with_clock(Arc::new(SimClock::new()))
+    }
+
+    fn marker(state: MarkerState, created_at: i64) -> TransactionMarker {
+        TransactionMarker {
+            transaction_id: "txn-1".into(),
+            message_ids: vec!["m1".into(), "m2".into()],
+            state,
+            created_at,
+        }
+    }
+
+    #[test]
+    fn mid_batch_validation_failure_rejects_the_whole_batch() {
+        let batch = vec![message("alice", "bob"), message("alice", "bad recipient"), message("alice", "carol")];
+        let Err(TransactionError::Rejected(errors)) = validate_batch(&batch, &limits()) else {
+            panic!("batch with an invalid message was accepted");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index, 1);
+        assert_eq!(errors[0].code, "INVALID_MESSAGE");
+    }
+
+    #[test]
+    fn batch_size_is_capped() {
+        let batch: Vec<_> = (0..11).map(|_| message("alice", "bob")).collect();
+        assert!(matches!(validate_batch(&batch, &limits()), Err(TransactionError::TooLarge { max: 10 })));
+        assert!(validate_batch(&batch[..10], &limits()).is_ok());
+        assert!(matches!(validate_batch(&[], &limits()), Err(TransactionError::Empty)));
+    }
+
+    #[test]
+    fn demands_are_grouped_per_sender() {
+        let batch = vec![message("alice", "bob"), message("bob", "alice"), message("alice", "carol")];
+        assert_eq!(sender_demands(&batch), vec![("alice".to_string(), 2), ("bob".to_string(), 1)]);
+    }
+
+    #[test]
+    fn a_limited_sender_releases_every_reserved_token() {
+        let limits = limits();
+        let limiter = limiter(&limits);
+
+        let limited = limiter.reserve(&[("alice".into(), 3), ("bob".into(), 6)]).unwrap_err();
+        assert_eq!(limited.user_id, "bob");
+
+        // Alice's three tokens went back when Bob's demand failed
+        let reservation = limiter.reserve(&[("alice".into(), 5), ("bob".into(), 5)]).unwrap();
+        let _ = reservation.commit();
+    }
+
+    #[test]
+    fn released_reservations_restore_capacity() {
+        let limits = limits();
+        let limiter = limiter(&limits);
+
+        let reservation = limiter.reserve(&[("alice".into(), 5)]).unwrap();
+        assert!(limiter.reserve(&[("alice".into(), 1)]).is_err());
+        limiter.release(reservation);
+
+        let reservation = limiter.reserve(&[("alice".into(), 5)]).unwrap();
+        let _ = reservation.commit();
+        assert!(limiter.reserve(&[("alice".into(), 1)]).is_err());
+    }
+
+    #[test]
+    fn only_preparing_markers_past_the_grace_are_orphaned() {
+        let created_at = 1_000_000;
+        let grace = ORPHAN_GRACE.as_millis() as i64;
+
+        // A publisher may still finish within the grace, so consumers wait
+        assert!(!is_orphaned(&marker(MarkerState::Preparing, created_at), created_at + grace));
+        // After it the whole batch is dead-lettered, never partly fanned out
+        assert!(is_orphaned(&marker(MarkerState::Preparing, created_at), created_at + grace + 1));
+        assert!(!is_orphaned(&marker(MarkerState::Enqueued, created_at), created_at + grace * 10));
+        assert!(!is_orphaned(&marker(MarkerState::DeadLettered, created_at), created_at + grace * 10));
+    }
+
+    #[test]
+    fn markers_survive_the_checkpoint_codec() {
+        let encoded = MARKER_CODEC.encode(&marker(MarkerState::Enqueued, 42)).unwrap();
+        let decoded = MARKER_CODEC.decode(&encoded).unwrap();
+        assert_eq!(decoded.state, MarkerState::Enqueued);
+        assert_eq!(decoded.message_ids, vec!["m1", "m2"]);
+        assert_eq!(decoded.created_at, 42);
+        assert_eq!(marker_key("txn-1"), "txn.txn-1");
+    }
+
+    #[test]
+    fn frozen_v1_markers_still_load() {
+        let fixture: &[u8] = include_bytes!("../fixtures/persist/transaction_marker.v1.bin");
+        // Markers written before versioning are the same JSON without the header
+        for bytes in [fixture, &fixture[5..]] {
+            let marker = MARKER_CODEC.decode(bytes).unwrap();
+            assert_eq!(marker.transaction_id, "txn-1");
+            assert_eq!(marker.message_ids, vec!["m1", "m2"]);
+            assert_eq!(marker.state, MarkerState::Enqueued);
+            assert_eq!(marker.created_at, 1_700_000_000_000);
+        }
+    }
+
+    async fn coordinator() -> (TransactionCoordinator, Arc<SimClock>) {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+        let context = jetstream::new(async_nats::connect(url).await.unwrap());
+        let id = Uuid::new_v4().simple();
+        let bucket = |name: &str| kv::Config {
+            bucket: format!("txn-{}-test-{}", name, id),
+            ..Default::default()
+        };
+        let broker = BrokerConfig::load().unwrap();
+        let metrics = BrokerMetrics::new().unwrap();
+        let limits = limits();
+        let clock = Arc::new(SimClock::new());
+        let coordinator = TransactionCoordinator::new(
+            limits.clone(),
+            Arc::new(limiter(&limits)),
+            Arc::new(SequenceAllocator::new(
+                context.create_key_value(bucket("sequences")).await.unwrap(),
+                &broker.routing,
+                metrics.clone(),
+            )),
+            Arc::new(ArchivedConversations::new(
+                context.create_key_value(bucket("archive")).await.unwrap(),
+                &broker.archive,
+                AuditLog::tracing_only(),
+                metrics.clone(),
+            )),
+            context.clone(),
+            context.create_key_value(bucket("checkpoints")).await.unwrap(),
+            format!("test.{}.ingress", id),
+            format!("test.{}.dead", id),
+            metrics,
+        )
+        .with_clock(clock.clone());
+        (coordinator, clock)
+    }
+
+    /// The coordinator tests run against JetStream at `NATS_URL` (default `localhost:4222`):
+    /// `cargo test -- --ignored transaction`
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_batch_past_its_deadline_hands_its_reservation_back() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let (coordinator, _) = coordinator().await;
+        let batch = vec![message("alice", "bob"), message("alice", "bob"), message("alice", "bob")];
+        // Archive state cached, so the lookups answer without a round trip
+        coordinator
+            .archived
+            .set_archived(&batch[0].conversation_id(), false, "ops")
+            .await
+            .unwrap();
+        let clock = Arc::new(SimClock::new());
+        let deadline = Deadline::after_on(clock.clone(), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+
+        let Err(TransactionError::Deadline(exceeded)) = coordinator.submit(batch, &deadline).await else {
+            panic!("batch outlived its deadline");
+        };
+        assert_eq!(exceeded.stage, "assign_sequences");
+        // All five of alice's tokens are back
+        let _ = coordinator.limiter.reserve(&[("alice".into(), 5)]).unwrap().commit();
+        assert!(coordinator.unfanned.is_empty());
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains(r#"broker_transactions_total{outcome="deadline_exceeded"} 1"#),
+            "{}",
+            rendered
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn thread_sequences_nest_inside_the_conversation_sequence() {
+        let (coordinator, _) = coordinator().await;
+        let threaded = |thread_id: Option<&str>| {
+            let mut envelope = message("alice", "bob");
+            envelope.thread_id = thread_id.map(String::from);
+            envelope
+        };
+
+        let mut assigned = Vec::new();
+        for _ in 0..2 {
+            let mut batch = vec![
+                threaded(None),
+                threaded(Some("t1")),
+                threaded(None),
+                threaded(Some("t2")),
+                threaded(Some("t1")),
+                threaded(None),
+            ];
+            coordinator.assign_sequences(&mut batch).await.unwrap();
+            assigned.extend(batch);
+        }
+
+        // One conversation order across main and thread messages, in submission order
+        let sequences: Vec<u64> = assigned.iter().map(|m| m.sequence.unwrap()).collect();
+        assert!(sequences.windows(2).all(|pair| pair[1] == pair[0] + 1), "{:?}", sequences);
+
+        // Each thread counts its own replies without gaps, main messages have none
+        let in_thread = |thread_id: &str| -> Vec<u64> {
+            assigned
+                .iter()
+                .filter(|m| m.thread_id.as_deref() == Some(thread_id))
+                .map(|m| m.thread_sequence.unwrap())
+                .collect()
+        };
+        let t1 = in_thread("t1");
+        assert_eq!(t1, (t1[0]..t1[0] + 4).collect::<Vec<_>>());
+        let t2 = in_thread("t2");
+        assert_eq!(t2, [t2[0], t2[0] + 1]);
+        assert!(assigned.iter().filter(|m| m.thread_id.is_none()).all(|m| m.thread_sequence.is_none()));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_preparing_orphan_is_dead_lettered_once_past_the_grace() {
+        let (coordinator, clock) = coordinator().await;
+        let coordinator = Arc::new(coordinator);
+        // A publisher that crashed between the marker and its last message
+        coordinator
+            .create_marker(&marker(MarkerState::Preparing, clock.now_millis()))
+            .await
+            .unwrap();
+
+        let shutdown = ShutdownSignal::new();
+        let recovery = coordinator.spawn(shutdown.clone());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while clock.pending() == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("recovery never waited out the grace");
+        assert_eq!(coordinator.disposition("txn-1").await, TransactionDisposition::Wait);
+
+        // The rerun after the grace dead-letters the whole batch and ends the task
+        clock.advance(ORPHAN_GRACE + Duration::from_millis(1));
+        tokio::time::timeout(Duration::from_secs(5), recovery).await.unwrap().unwrap();
+        let (marker, _) = coordinator.read_marker("txn-1").await.unwrap().unwrap();
+        assert_eq!(marker.state, MarkerState::DeadLettered);
+        assert_eq!(coordinator.disposition("txn-1").await, TransactionDisposition::DeadLetter);
+        assert!(!shutdown.is_triggered());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn fanning_out_another_brokers_batch_deletes_its_marker() {
+        let (coordinator, clock) = coordinator().await;
+        coordinator
+            .create_marker(&marker(MarkerState::Enqueued, clock.now_millis()))
+            .await
+            .unwrap();
+        assert!(coordinator.unfanned.is_empty());
+
+        assert_eq!(coordinator.disposition("txn-1").await, TransactionDisposition::FanOut);
+        coordinator.mark_fanned("txn-1").await;
+        assert!(coordinator.read_marker("txn-1").await.unwrap().is_none());
+        // The rest of the batch fans out on the missing marker
+        assert_eq!(coordinator.disposition("txn-1").await, TransactionDisposition::FanOut);
+    }
+}
@@ -0,0 +1,566 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::Instant,
+};
+use async_nats::jetstream::kv;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::DashMap;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::UnreadConfig,
+    history::HistoryReader,
+    metrics::BrokerMetrics,
+    read_horizon::ReadHorizonStore,
+};
+
+/// CAS attempts per skip write
+const MAX_CAS_ATTEMPTS: usize = 5;
+
+/// Unread messages of one user in one conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnreadCount {
+    pub count: u64,
+    /// The skip correction lost precision; `count` may be low
+    pub approximate: bool,
+    /// Cut at `unread.max_count`
+    pub clamped: bool,
+}
+
+/// Sequences of a conversation never delivered to a user (redacted,
+/// suppressed for a blocked sender), stored under
+/// `skips.{user}.{base64url(conversation_id)}`
+///
+/// The newest `unread.exact_skip_limit` are kept exactly; older ones are
+/// folded into a count with the highest folded sequence.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SkippedSequences {
+    /// Ascending
+    exact: Vec<u64>,
+    folded: u64,
+    folded_through: u64,
+}
+
+impl SkippedSequences {
+    fn add(&mut self, sequence: u64, limit: usize) {
+        if let Err(index) = self.exact.binary_search(&sequence) {
+            self.exact.insert(index, sequence);
+        }
+        while self.exact.len() > limit.max(1) {
+            let oldest = self.exact.remove(0);
+            self.folded += 1;
+            self.folded_through = self.folded_through.max(oldest);
+        }
+    }
+
+    /// Skipped sequences after `horizon` up to `latest`, and whether that's exact
+    fn after(&self, horizon: u64, latest: u64) -> (u64, bool) {
+        let exact = self
+            .exact
+            .iter()
+            .filter(|&&sequence| sequence > horizon && sequence <= latest)
+            .count() as u64;
+        // Folded ones are all at or below `folded_through`; past it none can be unread
+        if self.folded == 0 || horizon >= self.folded_through {
+            return (exact, true);
+        }
+        (exact + self.folded, false)
+    }
+
+    /// Drop what a horizon at `horizon` has made irrelevant
+    fn prune(&mut self, horizon: u64) -> bool {
+        let before = self.exact.len();
+        self.exact.retain(|&sequence| sequence > horizon);
+        let folded = self.folded > 0 && horizon >= self.folded_through;
+        if folded {
+            self.folded = 0;
+            self.folded_through = 0;
+        }
+        folded || self.exact.len() != before
+    }
+}
+
+struct CachedUnread {
+    latest: u64,
+    horizon: u64,
+    unread: UnreadCount,
+    at: Instant,
+}
+
+/// Broker-side unread counts: latest sequence minus read horizon, less skips
+///
+/// A conversation's latest sequence comes from deliveries this broker has
+/// seen (`observe_delivery`), else from the newest message in its history
+/// subject. Sequences a user never received (`record_skip`) don't count as
+/// unread; beyond `unread.exact_skip_limit` per conversation the oldest are
+/// folded into a count and results that depend on them are flagged
+/// approximate. Results are cached per (user, conversation); an entry is
+/// dropped by a read or skip for that pair and ignored once the horizon or
+/// the conversation's latest sequence moves, with `unread.cache_ttl`
+/// covering deliveries and skips seen only by other brokers. Sequence gaps from allocator
+/// failover (see `SequenceAllocator`) are counted as unread.
+pub struct UnreadCounter {
+    config: UnreadConfig,
+    kv: kv::Store,
+    horizons: Arc<ReadHorizonStore>,
+    history: Arc<HistoryReader>,
+    latest: DashMap<String, u64>,
+    cache: Mutex<LruCache<(String, String), CachedUnread>>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl UnreadCounter {
+    pub fn new(
+        config: UnreadConfig,
+        kv: kv::Store,
+        horizons: Arc<ReadHorizonStore>,
+        history: Arc<HistoryReader>,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(config.cache_size.max(1)).unwrap())),
+            config,
+            kv,
+            horizons,
+            history,
+            latest: DashMap::new(),
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A message with `sequence` was delivered in the conversation
+    pub fn observe_delivery(&self, conversation_id: &str, sequence: u64) {
+        let mut latest = self.latest.entry(conversation_id.to_string()).or_insert(sequence);
+        if sequence > *latest {
+            *latest = sequence;
+        }
+    }
+
+    /// The user's read horizon moved
+    pub fn observe_read(&self, user_id: &str, conversation_id: &str) {
+        self.cache
+            .lock()
+            .pop(&(user_id.to_string(), conversation_id.to_string()));
+    }
+
+    /// `sequence` of the conversation will never reach `user_id`
+    pub async fn record_skip(&self, user_id: &str, conversation_id: &str, sequence: u64) -> Result<(), UnreadError> {
+        self.observe_read(user_id, conversation_id);
+        let key = skip_key(user_id, conversation_id);
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let entry = self
+                .kv
+                .entry(&key)
+                .await
+                .map_err(|e| UnreadError(e.to_string()))?
+                .filter(|entry| entry.operation == kv::Operation::Put);
+            let (mut skips, revision) = match &entry {
+                Some(entry) => (
+                    serde_json::from_slice::<SkippedSequences>(&entry.value).unwrap_or_default(),
+                    Some(entry.revision),
+                ),
+                None => (SkippedSequences::default(), None),
+            };
+            skips.add(sequence, self.config.exact_skip_limit);
+
+            let value = serde_json::to_vec(&skips).map_err(|e| UnreadError(e.to_string()))?;
+            let written = match revision {
+                Some(revision) => self.kv.update(&key, value.into(), revision).await.is_ok(),
+                None => self.kv.create(&key, value.into()).await.is_ok(),
+            };
+            if written {
+                self.metrics.record_unread_skip();
+                return Ok(());
+            }
+        }
+        Err(UnreadError(format!("gave up on {} after {} CAS attempts", key, MAX_CAS_ATTEMPTS)))
+    }
+
+    /// Unread counts for each conversation; ones with no messages count zero
+    pub async fn counts(&self, user_id: &str, conversation_ids: &[String]) -> Result<Vec<(String, UnreadCount)>, UnreadError> {
+        let started = self.clock.now_instant();
+        let horizons = self
+            .horizons
+            .get(user_id, conversation_ids)
+            .await
+            .map_err(|e| UnreadError(e.to_string()))?;
+
+        let mut counts = Vec::with_capacity(conversation_ids.len());
+        for conversation_id in conversation_ids {
+            let horizon = horizons.get(conversation_id).copied().unwrap_or(0);
+            let unread = self.count(user_id, conversation_id, horizon).await?;
+            self.metrics.record_unread_result(match unread {
+                UnreadCount { clamped: true, .. } => "clamped",
+                UnreadCount { approximate: true, .. } => "approximate",
+                _ => "exact",
+            });
+            counts.push((conversation_id.clone(), unread));
+        }
+        self.metrics
+            .record_unread_latency(self.clock.now_instant().saturating_duration_since(started).as_secs_f64());
+        Ok(counts)
+    }
+
+    async fn count(&self, user_id: &str, conversation_id: &str, horizon: u64) -> Result<UnreadCount, UnreadError> {
+        let key = (user_id.to_string(), conversation_id.to_string());
+        let known_latest = self.latest.get(conversation_id).map(|latest| *latest);
+        if let Some(cached) = self.cache.lock().get(&key) {
+            let current = cached.horizon == horizon && known_latest.is_none_or(|latest| latest == cached.latest);
+            if current && self.clock.now_instant().saturating_duration_since(cached.at) < self.config.cache_ttl {
+                self.metrics.record_unread_cache_lookup("hit");
+                return Ok(cached.unread);
+            }
+        }
+        self.metrics.record_unread_cache_lookup("miss");
+
+        let latest = match known_latest {
+            Some(latest) => latest,
+            None => {
+                let latest = self
+                    .history
+                    .latest_sequence(conversation_id)
+                    .await
+                    .map_err(|e| UnreadError(e.to_string()))?
+                    .unwrap_or(0);
+                if latest > 0 {
+                    self.observe_delivery(conversation_id, latest);
+                }
+                latest
+            }
+        };
+
+        let (skipped, exact) = if latest > horizon {
+            self.skipped_after(user_id, conversation_id, horizon, latest).await?
+        } else {
+            (0, true)
+        };
+        let unread = latest.saturating_sub(horizon).saturating_sub(skipped);
+        let count = UnreadCount {
+            count: unread.min(self.config.max_count),
+            approximate: !exact,
+            clamped: unread > self.config.max_count,
+        };
+
+        self.cache.lock().put(
+            key,
+            CachedUnread {
+                latest,
+                horizon,
+                unread: count,
+                at: self.clock.now_instant(),
+            },
+        );
+        Ok(count)
+    }
+
+    async fn skipped_after(&self, user_id: &str, conversation_id: &str, horizon: u64, latest: u64) -> Result<(u64, bool), UnreadError> {
+        let key = skip_key(user_id, conversation_id);
+        let Some(entry) = self
+            .kv
+            .entry(&key)
+            .await
+            .map_err(|e| UnreadError(e.to_string()))?
+            .filter(|entry| entry.operation == kv::Operation::Put)
+        else {
+            return Ok((0, true));
+        };
+        let mut skips: SkippedSequences = serde_json::from_slice(&entry.value).map_err(|e| UnreadError(e.to_string()))?;
+        let skipped = skips.after(horizon, latest);
+
+        // Opportunistic cleanup; a lost race just leaves it for next time
+        if skips.prune(horizon) {
+            let value = serde_json::to_vec(&skips).map_err(|e| UnreadError(e.to_string()))?;
+            if let Err(e) = self.kv.update(&key, value.into(), entry.revision).await {
+                debug!("Skipped sequence cleanup for {} deferred: {}", key, e);
+            }
+        }
+        Ok(skipped)
+    }
+}
+
+fn skip_key(user_id: &str, conversation_id: &str) -> String {
+    format!("skips.{}.{}", user_id, URL_SAFE_NO_PAD.encode(conversation_id))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unread count error: {0}")]
+pub struct UnreadError(pub String);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use async_nats::jetstream::{self, stream};
+    use base64::engine::general_purpose::STANDARD;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{api::subscriptions::SubscriptionRegistry, clock::SimClock, config::BrokerConfig, read_replica::ReadStreamSelector};
+
+    const CONVERSATION: &str = "dm:alice:bob";
+
+    fn skips(sequences: &[u64], limit: usize) -> SkippedSequences {
+        let mut skips = SkippedSequences::default();
+        for &sequence in sequences {
+            skips.add(sequence, limit);
+        }
+        skips
+    }
+
+    #[test]
+    fn skips_stay_exact_up_to_the_limit_then_fold() {
+        let mut skips = skips(&[5, 2, 9, 2], 3);
+        assert_eq!(skips.exact, vec![2, 5, 9]);
+        assert_eq!(skips.after(0, 10), (3, true));
+        assert_eq!(skips.after(5, 10), (1, true));
+        assert_eq!(skips.after(0, 8), (2, true), "skips past the latest sequence don't count");
+
+        skips.add(12, 3);
+        assert_eq!(skips.exact, vec![5, 9, 12]);
+        assert_eq!((skips.folded, skips.folded_through), (1, 2));
+        // Below the fold nothing is known about which side of the horizon it fell
+        assert_eq!(skips.after(1, 12), (4, false));
+        assert_eq!(skips.after(0, 12), (4, false));
+        // At or past the fold, what was folded is already read
+        assert_eq!(skips.after(2, 12), (3, true));
+    }
+
+    #[test]
+    fn pruning_drops_what_the_horizon_has_passed() {
+        let mut skips = skips(&[1, 2, 3, 7, 8], 3);
+        assert_eq!((skips.folded, skips.folded_through), (2, 2));
+
+        assert!(!skips.prune(1), "nothing at or below 1 is kept exactly, and the fold isn't passed");
+        assert!(skips.prune(3));
+        assert_eq!(skips.exact, vec![7, 8]);
+        assert_eq!(skips.folded, 0);
+        assert!(!skips.prune(3));
+        assert_eq!(skips.after(3, 10), (2, true));
+    }
+
+    #[test]
+    fn skip_keys_are_single_tokens() {
+        let key = skip_key("alice", CONVERSATION);
+        assert_eq!(key.split('.').count(), 3, "{}", key);
+        assert!(!key.contains(':'));
+        assert_ne!(key, skip_key("bob", CONVERSATION));
+    }
+
+    struct World {
+        counter: UnreadCounter,
+        horizons: Arc<ReadHorizonStore>,
+        skips: kv::Store,
+        jetstream: jetstream::Context,
+        prefix: String,
+    }
+
+    impl World {
+        async fn new(tune: impl FnOnce(&mut UnreadConfig)) -> Self {
+            let url = std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into());
+            let jetstream = jetstream::new(async_nats::connect(url).await.unwrap());
+            let id = Uuid::new_v4().simple().to_string();
+            let mut config = BrokerConfig::load().unwrap();
+            tune(&mut config.unread);
+            let metrics = BrokerMetrics::new().unwrap();
+
+            let prefix = format!("unreadtest.{}", id);
+            config.nats.stream_name = format!("UNREAD_TEST_{}", id);
+            config.nats.read_stream_name = None;
+            jetstream
+                .create_stream(stream::Config {
+                    name: config.nats.stream_name.clone(),
+                    subjects: vec![format!("{}.>", prefix)],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let selector = ReadStreamSelector::new(jetstream.clone(), &config.nats, metrics.clone());
+            let history = Arc::new(HistoryReader::new(Arc::new(selector), prefix.clone(), 100));
+
+            let bucket = |name: &str| kv::Config {
+                bucket: format!("unread-test-{}-{}", name, id),
+                ..Default::default()
+            };
+            let skips = jetstream.create_key_value(bucket("skips")).await.unwrap();
+            let subscriptions = Arc::new(SubscriptionRegistry::new(&config.api, metrics.clone()));
+            let horizons = Arc::new(ReadHorizonStore::new(
+                jetstream.create_key_value(bucket("horizons")).await.unwrap(),
+                subscriptions,
+                Duration::from_millis(250),
+                metrics.clone(),
+            ));
+            let counter = UnreadCounter::new(config.unread, skips.clone(), horizons.clone(), history, metrics);
+            Self {
+                counter,
+                horizons,
+                skips,
+                jetstream,
+                prefix,
+            }
+        }
+
+        /// Another broker sharing the same buckets
+        fn peer(&self) -> UnreadCounter {
+            UnreadCounter::new(
+                self.counter.config.clone(),
+                self.skips.clone(),
+                self.horizons.clone(),
+                self.counter.history.clone(),
+                BrokerMetrics::new().unwrap(),
+            )
+        }
+
+        async fn count(&self, user_id: &str, conversation_id: &str) -> UnreadCount {
+            let counts = self.counter.counts(user_id, &[conversation_id.to_string()]).await.unwrap();
+            counts[0].1
+        }
+    }
+
+    fn exact(count: u64) -> UnreadCount {
+        UnreadCount {
+            count,
+            approximate: false,
+            clamped: false,
+        }
+    }
+
+    /// Run with `cargo test -- --ignored unread` against a JetStream server
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn redacted_and_suppressed_messages_are_not_unread() {
+        let world = World::new(|_| {}).await;
+        world.counter.observe_delivery(CONVERSATION, 10);
+        world.horizons.record("alice", CONVERSATION, 4);
+
+        // 6 was redacted, 8 came from a sender alice has blocked, 3 was
+        // redacted before she read past it
+        for sequence in [6, 8, 3] {
+            world.counter.record_skip("alice", CONVERSATION, sequence).await.unwrap();
+        }
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(4));
+        assert_eq!(world.count("bob", CONVERSATION).await, exact(10), "skips are per user");
+
+        // Reading past a skip doesn't count it twice
+        world.horizons.record("alice", CONVERSATION, 7);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(2));
+        world.horizons.record("alice", CONVERSATION, 10);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(0));
+        assert_eq!(world.count("alice", "group:empty").await, exact(0));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn counts_clamp_at_the_max_and_turn_approximate_past_the_skip_limit() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let world = World::new(|unread| {
+            unread.max_count = 8;
+            unread.exact_skip_limit = 2;
+        })
+        .await;
+
+        world.counter.observe_delivery("group:busy", 20);
+        world.counter.observe_delivery("group:full", 8);
+        let counts = world
+            .counter
+            .counts("alice", &["group:busy".to_string(), "group:full".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            counts[0].1,
+            UnreadCount {
+                count: 8,
+                approximate: false,
+                clamped: true
+            }
+        );
+        assert_eq!(counts[1].1, exact(8), "reaching the max isn't clamping");
+
+        // Three skips with room for two: 2 is folded
+        world.counter.observe_delivery(CONVERSATION, 10);
+        for sequence in [2, 3, 4] {
+            world.counter.record_skip("alice", CONVERSATION, sequence).await.unwrap();
+        }
+        world.horizons.record("alice", CONVERSATION, 1);
+        assert_eq!(
+            world.count("alice", CONVERSATION).await,
+            UnreadCount {
+                count: 6,
+                approximate: true,
+                clamped: false
+            }
+        );
+        world.horizons.record("alice", CONVERSATION, 2);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(6));
+
+        let rendered = recorder.handle().render();
+        for kind in ["clamped", "approximate"] {
+            assert!(rendered.contains(&format!(r#"broker_unread_results_total{{kind="{}"}} 1"#, kind)), "{}", rendered);
+        }
+        assert!(rendered.contains(r#"broker_unread_results_total{kind="exact"} 2"#), "{}", rendered);
+        assert!(rendered.contains("broker_unread_skips_recorded_total 3"), "{}", rendered);
+        assert!(rendered.contains("broker_unread_compute_seconds_count 3"), "{}", rendered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn cached_counts_follow_deliveries_reads_and_their_ttl() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let clock = Arc::new(SimClock::new());
+        let mut world = World::new(|unread| unread.cache_ttl = Duration::from_secs(30)).await;
+        world.counter = world.peer().with_clock(clock.clone());
+
+        world.counter.observe_delivery(CONVERSATION, 5);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(5));
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(5));
+        world.counter.observe_delivery(CONVERSATION, 7);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(7));
+        world.horizons.record("alice", CONVERSATION, 3);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(4));
+
+        // A skip recorded by another broker isn't seen until the entry ages out
+        world.peer().record_skip("alice", CONVERSATION, 6).await.unwrap();
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(4));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(3));
+
+        // One recorded here drops the entry at once
+        world.counter.record_skip("alice", CONVERSATION, 5).await.unwrap();
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(2));
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_unread_cache_lookups_total{result="hit"} 2"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_unread_cache_lookups_total{result="miss"} 5"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn the_latest_sequence_falls_back_to_history() {
+        let world = World::new(|_| {}).await;
+        let payload = STANDARD.encode(serde_json::to_vec(&serde_json::json!({ "sequence": 12 })).unwrap());
+        world
+            .jetstream
+            .publish(format!("{}.{}", world.prefix, CONVERSATION), payload.into())
+            .await
+            .unwrap()
+            .await
+            .unwrap();
+
+        world.horizons.record("alice", CONVERSATION, 9);
+        assert_eq!(world.count("alice", CONVERSATION).await, exact(3));
+        assert_eq!(world.counter.latest.get(CONVERSATION).map(|latest| *latest), Some(12));
+    }
+}
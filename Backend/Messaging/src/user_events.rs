@@ -0,0 +1,400 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use lru::LruCache;
+use serde::Serialize;
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::UserEventsConfig,
+    lock_metrics::{Mutex, NamedLock},
+    metrics::BrokerMetrics,
+    recipient_trace::ExclusionReason,
+    shard::shard_for,
+    task::{spawn_traced, TaskContext},
+};
+
+/// What the broker did for or to a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UserEventKind {
+    /// A gateway acked delivery of a message to the user
+    Delivered,
+    /// The user was offline and the message went to their offline queue
+    Queued,
+    /// The user was left out of a message's fanout
+    Suppressed { reason: ExclusionReason },
+    /// A publish by the user hit their rate limit
+    RateLimited,
+    /// The user sent a read receipt
+    Receipt,
+}
+
+/// One entry of a user's recent-event ring; metadata only, never payload
+#[derive(Debug, Clone, Serialize)]
+pub struct UserEvent {
+    /// Milliseconds since the epoch
+    pub at: i64,
+    #[serde(flatten)]
+    pub kind: UserEventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    /// With the user, identifies the delivery in `DeliveryTracker`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+/// Fixed-size event ring, overwritten oldest first once full
+struct Ring {
+    events: Vec<UserEvent>,
+    /// Slot the next event goes into
+    next: usize,
+    last_active: Instant,
+}
+
+impl Ring {
+    fn new(size: usize, now: Instant) -> Self {
+        Self {
+            events: Vec::with_capacity(size),
+            next: 0,
+            last_active: now,
+        }
+    }
+
+    fn reset(&mut self, now: Instant) {
+        // Keeps the allocation for the next user claiming the slot
+        self.events.clear();
+        self.next = 0;
+        self.last_active = now;
+    }
+
+    fn push(&mut self, event: UserEvent, size: usize, now: Instant) {
+        if self.events.len() < size {
+            self.events.push(event);
+        } else {
+            self.events[self.next] = event;
+        }
+        self.next = (self.next + 1) % size;
+        self.last_active = now;
+    }
+
+    /// Events newest first
+    fn newest_first(&self) -> impl Iterator<Item = &UserEvent> {
+        let len = self.events.len();
+        // Until the ring first fills, `next == len`
+        (0..len).map(move |i| &self.events[(self.next + len - 1 - i) % len])
+    }
+}
+
+/// Users of one shard, each indexing a ring in the slab
+struct Shard {
+    /// User to slab slot, least recently active first
+    index: LruCache<String, usize>,
+    slab: Vec<Ring>,
+    /// Slots released by the sweeper, reused before the slab grows
+    free: Vec<usize>,
+}
+
+impl Shard {
+    /// Slot for a newly active user, evicting the least recently active one when full
+    fn claim(&mut self, user_id: &str, capacity: usize, ring_size: usize, now: Instant) -> (usize, bool) {
+        let mut evicted = false;
+        let slot = if let Some(slot) = self.free.pop() {
+            self.slab[slot].reset(now);
+            slot
+        } else if self.slab.len() < capacity {
+            self.slab.push(Ring::new(ring_size, now));
+            self.slab.len() - 1
+        } else {
+            let (_, slot) = self.index.pop_lru().expect("full shard has users");
+            self.slab[slot].reset(now);
+            evicted = true;
+            slot
+        };
+        self.index.put(user_id.to_string(), slot);
+        (slot, evicted)
+    }
+}
+
+/// Bounded history of recent broker events per recently active user
+///
+/// Answers "what has the broker done for this user lately" for support
+/// tooling through `/admin/users/:user_id/recent` without tracing being
+/// enabled in advance. Users hash to `user_events.shards` shards, each
+/// holding at most `users_per_shard` rings of `ring_size` events in a slab
+/// whose slots are reused rather than freed, so memory is bounded by
+/// `shards * users_per_shard * ring_size` events however many users pass
+/// through. A shard that is full evicts its least recently active user;
+/// the sweeper releases users idle longer than `active_window`. Events
+/// carry only IDs, a kind and a timestamp.
+pub struct RecentUserEvents {
+    shards: Vec<Mutex<Shard>>,
+    config: UserEventsConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl RecentUserEvents {
+    pub fn new(config: UserEventsConfig, metrics: BrokerMetrics) -> Self {
+        let users_per_shard = config.users_per_shard.max(1);
+        Self {
+            shards: (0..config.shards.max(1))
+                .map(|_| {
+                    Mutex::named(
+                        "user_events.shard",
+                        Shard {
+                            index: LruCache::unbounded(),
+                            slab: Vec::with_capacity(users_per_shard),
+                            free: Vec::new(),
+                        },
+                    )
+                })
+                .collect(),
+            config: UserEventsConfig {
+                ring_size: config.ring_size.max(1),
+                users_per_shard,
+                ..config
+            },
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn record(
+        &self,
+        user_id: &str,
+        kind: UserEventKind,
+        conversation_id: Option<&str>,
+        message_id: Option<&str>,
+    ) {
+        if !self.config.enabled {
+            return;
+        }
+        let event = UserEvent {
+            at: self.clock.now_millis(),
+            kind,
+            conversation_id: conversation_id.map(str::to_string),
+            message_id: message_id.map(str::to_string),
+        };
+        let now = self.clock.now_instant();
+        let mut shard = self.shards[shard_for(user_id, self.shards.len())].lock();
+        let slot = match shard.index.get(user_id) {
+            Some(&slot) => slot,
+            None => {
+                let (slot, evicted) = shard.claim(user_id, self.config.users_per_shard, self.config.ring_size, now);
+                if evicted {
+                    self.metrics.record_user_event_ring_evicted();
+                }
+                slot
+            }
+        };
+        shard.slab[slot].push(event, self.config.ring_size, now);
+    }
+
+    /// Recent events for the user, newest first; empty once idle past `active_window`
+    pub fn recent(&self, user_id: &str) -> Vec<UserEvent> {
+        let shard = self.shards[shard_for(user_id, self.shards.len())].lock();
+        // Peek so support lookups don't keep an idle user's ring alive
+        let Some(&slot) = shard.index.peek(user_id) else {
+            return Vec::new();
+        };
+        let ring = &shard.slab[slot];
+        if self.clock.now_instant().saturating_duration_since(ring.last_active) > self.config.active_window {
+            return Vec::new();
+        }
+        ring.newest_first().cloned().collect()
+    }
+
+    /// Release rings of users idle past `active_window`; returns how many are left
+    pub fn sweep(&self) -> usize {
+        let now = self.clock.now_instant();
+        let mut remaining = 0;
+        for shard in &self.shards {
+            let mut shard = shard.lock();
+            // LRU order is activity order, so idle users are all at the front
+            while let Some((_, &slot)) = shard.index.peek_lru() {
+                if now.saturating_duration_since(shard.slab[slot].last_active) <= self.config.active_window {
+                    break;
+                }
+                shard.index.pop_lru();
+                shard.slab[slot].reset(now);
+                shard.free.push(slot);
+            }
+            remaining += shard.index.len();
+        }
+        self.metrics.update_user_event_rings(remaining);
+        remaining
+    }
+
+    pub fn spawn_sweeper(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let events = Arc::clone(self);
+        let interval = self.config.sweep_interval.max(Duration::from_secs(1));
+        Some(spawn_traced("user_events_sweeper", TaskContext::new("user_events"), async move {
+            loop {
+                events.clock.sleep(interval).await;
+                events.sweep();
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+    use crate::clock::SimClock;
+
+    fn config(ring_size: usize, shards: usize, users_per_shard: usize) -> UserEventsConfig {
+        UserEventsConfig {
+            enabled: true,
+            ring_size,
+            shards,
+            users_per_shard,
+            active_window: Duration::from_secs(900),
+            sweep_interval: Duration::from_secs(30),
+        }
+    }
+
+    fn events(config: UserEventsConfig) -> (RecentUserEvents, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        let events = RecentUserEvents::new(config, BrokerMetrics::new().unwrap()).with_clock(clock.clone());
+        (events, clock)
+    }
+
+    fn delivered(events: &RecentUserEvents, user_id: &str, message_id: &str) {
+        events.record(user_id, UserEventKind::Delivered, Some("dm:alice:bob"), Some(message_id));
+    }
+
+    fn message_ids(events: &RecentUserEvents, user_id: &str) -> Vec<String> {
+        events
+            .recent(user_id)
+            .into_iter()
+            .map(|event| event.message_id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rings_keep_the_newest_events_through_wraparound() {
+        let (events, _) = events(config(3, 1, 4));
+        assert!(events.recent("alice").is_empty());
+
+        delivered(&events, "alice", "m1");
+        delivered(&events, "alice", "m2");
+        assert_eq!(message_ids(&events, "alice"), ["m2", "m1"]);
+
+        // Every position of the write slot, across several laps
+        for sent in 3..=10 {
+            delivered(&events, "alice", &format!("m{}", sent));
+            let expected: Vec<String> = (sent.max(3) - 2..=sent).rev().map(|i| format!("m{}", i)).collect();
+            assert_eq!(message_ids(&events, "alice"), expected, "after m{}", sent);
+        }
+
+        let shard = events.shards[0].lock();
+        let ring = &shard.slab[shard.index.peek("alice").copied().unwrap()];
+        assert_eq!(ring.events.len(), 3);
+        assert!(ring.events.capacity() < 8, "the ring never grew past its size");
+    }
+
+    #[test]
+    fn events_serialize_as_metadata_newest_first() {
+        let (events, clock) = events(config(20, 4, 16));
+        events.record("bob", UserEventKind::RateLimited, None, None);
+        clock.advance(Duration::from_millis(5));
+        events.record(
+            "bob",
+            UserEventKind::Suppressed {
+                reason: ExclusionReason::Blocked,
+            },
+            Some("dm:alice:bob"),
+            Some("m1"),
+        );
+        clock.advance(Duration::from_millis(5));
+        events.record("bob", UserEventKind::Receipt, Some("dm:alice:bob"), Some("m1"));
+
+        let recent = serde_json::to_value(events.recent("bob")).unwrap();
+        let at = recent[2]["at"].as_i64().unwrap();
+        assert_eq!(
+            recent,
+            serde_json::json!([
+                { "at": at + 10, "kind": "receipt", "conversation_id": "dm:alice:bob", "message_id": "m1" },
+                { "at": at + 5, "kind": "suppressed", "reason": "blocked", "conversation_id": "dm:alice:bob", "message_id": "m1" },
+                { "at": at, "kind": "rate_limited" },
+            ])
+        );
+    }
+
+    #[test]
+    fn a_flood_of_distinct_users_stays_within_the_slabs() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let (events, _) = metrics::with_local_recorder(&recorder, || events(config(5, 4, 8)));
+        metrics::with_local_recorder(&recorder, || {
+            for user in 0..10_000 {
+                for message in 0..3 {
+                    delivered(&events, &format!("user-{}", user), &format!("m{}", message));
+                }
+            }
+        });
+
+        let mut users = 0;
+        for shard in &events.shards {
+            let shard = shard.lock();
+            assert_eq!(shard.slab.len(), 8);
+            assert!(shard.slab.iter().all(|ring| ring.events.len() <= 5));
+            users += shard.index.len();
+        }
+        assert_eq!(users, 32);
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains(&format!("broker_user_event_ring_evictions_total {}", 10_000 - 32)),
+            "{}",
+            rendered
+        );
+
+        // The latest users of each shard survive; the earliest were evicted
+        assert_eq!(message_ids(&events, "user-9999"), ["m2", "m1", "m0"]);
+        assert!(events.recent("user-0").is_empty());
+    }
+
+    #[test]
+    fn idle_users_are_hidden_then_swept_and_their_slots_reused() {
+        let (events, clock) = events(config(5, 1, 4));
+        delivered(&events, "alice", "m1");
+        clock.advance(Duration::from_secs(600));
+        delivered(&events, "bob", "m1");
+
+        // Looking alice up doesn't keep their ring alive
+        clock.advance(Duration::from_secs(301));
+        assert!(events.recent("alice").is_empty());
+        assert_eq!(message_ids(&events, "bob"), ["m1"]);
+        assert_eq!(events.sweep(), 1);
+
+        delivered(&events, "carol", "m1");
+        let shard = events.shards[0].lock();
+        assert_eq!(shard.slab.len(), 2, "carol took alice's slot");
+        assert_eq!(shard.slab[shard.index.peek("carol").copied().unwrap()].events.len(), 1);
+        drop(shard);
+
+        clock.advance(Duration::from_secs(901));
+        assert_eq!(events.sweep(), 0);
+    }
+
+    #[test]
+    fn disabled_events_record_nothing() {
+        let mut config = config(5, 1, 4);
+        config.enabled = false;
+        let (events, _) = events(config);
+        delivered(&events, "alice", "m1");
+        assert!(events.recent("alice").is_empty());
+        assert!(Arc::new(events).spawn_sweeper().is_none());
+    }
+}
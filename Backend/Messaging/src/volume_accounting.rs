@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use async_nats::{jetstream, HeaderMap};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::VolumeAccountingConfig,
+    idempotent_append::{self, AppendKey, AppendPurpose, Appended},
+    metrics::BrokerMetrics,
+    shard::shard_for,
+    task::{spawn_traced, ShutdownSignal, TaskContext},
+};
+
+/// Conversation ID under which deliveries past `max_conversations` are counted
+pub const OVERFLOW_CONVERSATION: &str = "_overflow";
+
+#[derive(Debug, Clone, Default)]
+struct Volume {
+    tenant_id: Option<String>,
+    bytes: u64,
+    messages: u64,
+}
+
+/// Delivered volume of one conversation over one flush interval
+///
+/// Downstream sums deltas per conversation after dropping repeated
+/// `delta_id`s; the stream's duplicate window drops most repeats before
+/// they get that far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeDelta {
+    /// `{broker_id}:{started_at}:{interval}:{conversation_id}`, stable across retries
+    pub delta_id: String,
+    pub broker_id: String,
+    pub conversation_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    pub bytes: u64,
+    pub messages: u64,
+    /// Interval bounds, timestamps in milliseconds
+    pub interval_start: i64,
+    pub interval_end: i64,
+}
+
+/// One row of `/admin/volume/top`
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationVolume {
+    pub conversation_id: String,
+    pub tenant_id: Option<String>,
+    pub bytes: u64,
+    pub messages: u64,
+}
+
+/// Durable per-conversation delivered-bytes accounting
+///
+/// Deliveries accumulate in `volume_accounting.shards` maps keyed by
+/// conversation. Every `volume_accounting.flush_interval` the maps are
+/// swapped out and each conversation's total becomes one `VolumeDelta`
+/// appended to `volume_accounting.subject`, so memory only holds the
+/// conversations active in the current interval. At most
+/// `volume_accounting.max_conversations` are tracked per interval; later
+/// conversations are folded into `_overflow`, keeping the total exact.
+///
+/// Each delta's ID is fixed when its interval closes and is also the
+/// append's `Nats-Msg-Id`, so a delta re-sent after a failed or unconfirmed
+/// append is dropped as a duplicate instead of counted twice. Deltas whose
+/// append failed are retried with the same ID on the next flush. The IDs
+/// include the process start time, so a restarted broker never reuses one.
+/// A crash loses at most the current interval's unflushed volume; it never
+/// double-counts. On shutdown the current interval is flushed immediately.
+///
+/// The delivery path records each egress frame's size with
+/// `record_delivered`, next to `SizeAccountant::observe`.
+pub struct VolumeAccounting {
+    broker_id: String,
+    started_at: i64,
+    shards: Vec<Mutex<HashMap<String, Volume>>>,
+    tracked: AtomicU64,
+    interval: AtomicU64,
+    interval_start: Mutex<i64>,
+    /// Closed deltas whose append hasn't been confirmed
+    unconfirmed: Mutex<Vec<VolumeDelta>>,
+    jetstream: jetstream::Context,
+    config: VolumeAccountingConfig,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl VolumeAccounting {
+    pub fn new(
+        broker_id: String,
+        jetstream: jetstream::Context,
+        config: VolumeAccountingConfig,
+        metrics: BrokerMetrics,
+    ) -> Self {
+        let clock = SystemClock::shared();
+        let now = clock.now_millis();
+        Self {
+            broker_id,
+            started_at: now,
+            shards: (0..config.shards.max(1)).map(|_| Mutex::new(HashMap::new())).collect(),
+            tracked: AtomicU64::new(0),
+            interval: AtomicU64::new(0),
+            interval_start: Mutex::new(now),
+            unconfirmed: Mutex::new(Vec::new()),
+            jetstream,
+            config,
+            clock,
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        let now = clock.now_millis();
+        self.started_at = now;
+        *self.interval_start.get_mut() = now;
+        self.clock = clock;
+        self
+    }
+
+    pub fn record_delivered(&self, conversation_id: &str, tenant_id: Option<&str>, bytes: usize) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut shard = self.shards[shard_for(conversation_id, self.shards.len())].lock();
+        let key = if shard.contains_key(conversation_id)
+            || self.tracked.fetch_add(1, Ordering::Relaxed) < self.config.max_conversations as u64
+        {
+            conversation_id
+        } else {
+            self.tracked.fetch_sub(1, Ordering::Relaxed);
+            self.metrics.record_volume_overflow(bytes);
+            OVERFLOW_CONVERSATION
+        };
+        let volume = shard.entry(key.to_string()).or_insert_with(|| Volume {
+            tenant_id: tenant_id.map(str::to_string),
+            ..Default::default()
+        });
+        volume.bytes += bytes as u64;
+        volume.messages += 1;
+    }
+
+    /// Conversations with the most delivered bytes in the current interval
+    pub fn top(&self, limit: usize) -> Vec<ConversationVolume> {
+        let mut volumes: Vec<ConversationVolume> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .iter()
+                    .map(|(conversation_id, volume)| ConversationVolume {
+                        conversation_id: conversation_id.clone(),
+                        tenant_id: volume.tenant_id.clone(),
+                        bytes: volume.bytes,
+                        messages: volume.messages,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        volumes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.conversation_id.cmp(&b.conversation_id)));
+        volumes.truncate(limit);
+        volumes
+    }
+
+    /// Close the current interval into deltas, queued behind any unconfirmed ones
+    fn close_interval(&self) {
+        let now = self.clock.now_millis();
+        let interval_start = std::mem::replace(&mut *self.interval_start.lock(), now);
+        let interval = self.interval.fetch_add(1, Ordering::Relaxed);
+
+        let mut deltas = Vec::new();
+        for shard in &self.shards {
+            let volumes = std::mem::take(&mut *shard.lock());
+            for (conversation_id, volume) in volumes {
+                deltas.push(VolumeDelta {
+                    delta_id: format!("{}:{}:{}:{}", self.broker_id, self.started_at, interval, conversation_id),
+                    broker_id: self.broker_id.clone(),
+                    conversation_id,
+                    tenant_id: volume.tenant_id,
+                    bytes: volume.bytes,
+                    messages: volume.messages,
+                    interval_start,
+                    interval_end: now,
+                });
+            }
+        }
+        // Swapped out above; anything recorded since belongs to the new interval
+        self.tracked.store(0, Ordering::Relaxed);
+        self.unconfirmed.lock().extend(deltas);
+    }
+
+    /// Close the interval and append every unconfirmed delta
+    pub async fn flush(&self) {
+        self.close_interval();
+        let pending = std::mem::take(&mut *self.unconfirmed.lock());
+        if pending.is_empty() {
+            return;
+        }
+
+        let mut failed = Vec::new();
+        for delta in pending {
+            match self.append(&delta).await {
+                Ok(Appended::New { .. }) => self.metrics.record_volume_delta("appended", delta.bytes),
+                Ok(Appended::Duplicate { .. }) => self.metrics.record_volume_delta("duplicate", delta.bytes),
+                Err(e) => {
+                    warn!("Failed to append volume delta {}: {}", delta.delta_id, e);
+                    self.metrics.record_volume_delta("failed", delta.bytes);
+                    failed.push(delta);
+                }
+            }
+        }
+
+        let mut unconfirmed = self.unconfirmed.lock();
+        // Oldest stay first; past the cap the oldest are given up on
+        failed.append(&mut unconfirmed);
+        let excess = failed.len().saturating_sub(self.config.max_unconfirmed);
+        if excess > 0 {
+            warn!("Dropping {} unconfirmed volume deltas past the retry cap", excess);
+            for delta in failed.drain(..excess) {
+                self.metrics.record_volume_delta("dropped", delta.bytes);
+            }
+        }
+        *unconfirmed = failed;
+        self.metrics.update_volume_unconfirmed(unconfirmed.len());
+    }
+
+    async fn append(&self, delta: &VolumeDelta) -> Result<Appended, idempotent_append::AppendError> {
+        let key = AppendKey {
+            purpose: AppendPurpose::Volume,
+            message_id: &delta.delta_id,
+            recipient: "",
+        };
+        let payload = serde_json::to_vec(delta).expect("volume deltas serialize");
+        idempotent_append::append(
+            &self.jetstream,
+            key,
+            self.config.subject.clone(),
+            HeaderMap::new(),
+            payload.into(),
+            &self.metrics,
+        )
+        .await
+    }
+
+    /// Flush every interval, and once more when shutdown is triggered
+    pub fn spawn(self: &Arc<Self>, shutdown: ShutdownSignal) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let accounting = Arc::clone(self);
+        Some(spawn_traced("volume_accounting", TaskContext::new("volume_accounting"), async move {
+            loop {
+                tokio::select! {
+                    _ = accounting.clock.sleep(accounting.config.flush_interval) => accounting.flush().await,
+                    _ = shutdown.wait() => {
+                        info!("Flushing delivered volume before shutdown");
+                        accounting.flush().await;
+                        return;
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Tests that append run against a JetStream-enabled server at `NATS_URL`:
+/// `cargo test -- --ignored volume_accounting`
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+    };
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    fn nats_url() -> String {
+        std::env::var("NATS_URL").unwrap_or_else(|_| "localhost:4222".into())
+    }
+
+    fn config(subject: &str) -> VolumeAccountingConfig {
+        let mut config = BrokerConfig::load().unwrap().volume_accounting;
+        config.enabled = true;
+        config.subject = subject.to_string();
+        config.flush_interval = INTERVAL;
+        config.shards = 4;
+        config.max_conversations = 3;
+        config
+    }
+
+    fn accounting(jetstream: &jetstream::Context, subject: &str, clock: &Arc<SimClock>) -> VolumeAccounting {
+        VolumeAccounting::new("broker-1".into(), jetstream.clone(), config(subject), BrokerMetrics::new().unwrap())
+            .with_clock(clock.clone())
+    }
+
+    /// Accumulating never touches NATS, so the client is never connected
+    async fn offline() -> jetstream::Context {
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(nats_url())
+            .await
+            .unwrap();
+        jetstream::new(client)
+    }
+
+    fn unconfirmed(accounting: &VolumeAccounting) -> Vec<VolumeDelta> {
+        let mut deltas = accounting.unconfirmed.lock().clone();
+        deltas.sort_by(|a, b| a.delta_id.cmp(&b.delta_id));
+        deltas
+    }
+
+    #[tokio::test]
+    async fn only_the_current_intervals_conversations_are_held() {
+        let clock = Arc::new(SimClock::new());
+        let accounting = accounting(&offline().await, "test.volume", &clock);
+        let started = clock.now_millis();
+        for (conversation_id, bytes) in [("dm:a:b", 400), ("group-1", 1_000), ("dm:a:b", 100), ("dm:c:d", 10)] {
+            accounting.record_delivered(conversation_id, Some("tenant-a"), bytes);
+        }
+        // Past `max_conversations` new ones fold into the overflow row, keeping the total
+        accounting.record_delivered("dm:e:f", None, 7);
+        accounting.record_delivered("group-2", None, 8);
+        accounting.record_delivered("group-1", Some("tenant-a"), 1);
+
+        let top = accounting.top(10);
+        let rows: Vec<(&str, u64, u64)> =
+            top.iter().map(|row| (row.conversation_id.as_str(), row.bytes, row.messages)).collect();
+        assert_eq!(
+            rows,
+            [("group-1", 1_001, 2), ("dm:a:b", 500, 2), (OVERFLOW_CONVERSATION, 15, 2), ("dm:c:d", 10, 1)]
+        );
+        assert_eq!(accounting.top(1).len(), 1);
+
+        clock.advance(INTERVAL);
+        accounting.close_interval();
+        assert!(accounting.top(10).is_empty(), "closing the interval frees it");
+        let deltas = unconfirmed(&accounting);
+        assert_eq!(deltas.iter().map(|delta| delta.bytes).sum::<u64>(), 1_526);
+        assert!(deltas
+            .iter()
+            .all(|delta| delta.interval_start == started && delta.interval_end == started + INTERVAL.as_millis() as i64));
+        assert_eq!(deltas.iter().find(|delta| delta.conversation_id == "group-1").unwrap().tenant_id.as_deref(), Some("tenant-a"));
+
+        // The new interval tracks conversations from scratch
+        for conversation_id in ["dm:e:f", "group-2", "group-3"] {
+            accounting.record_delivered(conversation_id, None, 1);
+        }
+        assert!(accounting.top(10).iter().all(|row| row.conversation_id != OVERFLOW_CONVERSATION));
+    }
+
+    #[tokio::test]
+    async fn delta_ids_are_fixed_per_interval_and_never_reused_by_a_restart() {
+        let clock = Arc::new(SimClock::new());
+        let jetstream = offline().await;
+        let first = accounting(&jetstream, "test.volume", &clock);
+        first.record_delivered("dm:a:b", None, 10);
+        first.close_interval();
+        first.record_delivered("dm:a:b", None, 10);
+        first.close_interval();
+        let ids: Vec<String> = unconfirmed(&first).into_iter().map(|delta| delta.delta_id).collect();
+        let prefix = format!("broker-1:{}:", clock.now_millis());
+        assert_eq!(ids, [format!("{}0:dm:a:b", prefix), format!("{}1:dm:a:b", prefix)]);
+
+        clock.advance(Duration::from_secs(1));
+        let restarted = accounting(&jetstream, "test.volume", &clock);
+        restarted.record_delivered("dm:a:b", None, 10);
+        restarted.close_interval();
+        let restarted_id = &unconfirmed(&restarted)[0].delta_id;
+        assert!(!ids.contains(restarted_id), "{}", restarted_id);
+    }
+
+    /// A fresh stream holding `subject`, deduplicating like the broker's
+    async fn stream(jetstream: &jetstream::Context) -> (String, String) {
+        let id = Uuid::new_v4().simple().to_string();
+        let (name, subject) = (format!("VOLUME_TEST_{}", id), format!("test.volume.{}", id));
+        jetstream
+            .create_stream(jetstream::stream::Config {
+                name: name.clone(),
+                subjects: vec![subject.clone()],
+                duplicate_window: Duration::from_secs(600),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        (name, subject)
+    }
+
+    async fn connected() -> jetstream::Context {
+        jetstream::new(async_nats::connect(nats_url()).await.unwrap())
+    }
+
+    /// Every delta in the stream
+    async fn stored(jetstream: &jetstream::Context, name: &str) -> Vec<VolumeDelta> {
+        let mut stream = jetstream.get_stream(name).await.unwrap();
+        let last = stream.info().await.unwrap().state.last_sequence;
+        let mut deltas = Vec::new();
+        for sequence in 1..=last {
+            let raw = stream.get_raw_message(sequence).await.unwrap();
+            let message = async_nats::Message::try_from(raw).unwrap();
+            deltas.push(serde_json::from_slice(&message.payload).unwrap());
+        }
+        deltas
+    }
+
+    /// Bytes per conversation as downstream sums them, repeats dropped
+    fn billed(deltas: &[VolumeDelta]) -> HashMap<String, u64> {
+        let mut seen = std::collections::HashSet::new();
+        let mut billed = HashMap::new();
+        for delta in deltas.iter().filter(|delta| seen.insert(delta.delta_id.clone())) {
+            *billed.entry(delta.conversation_id.clone()).or_default() += delta.bytes;
+        }
+        billed
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_crash_before_the_flush_loses_that_interval_and_nothing_is_counted_twice() {
+        let jetstream = connected().await;
+        let (name, subject) = stream(&jetstream).await;
+        let clock = Arc::new(SimClock::new());
+
+        let crashed = accounting(&jetstream, &subject, &clock);
+        crashed.record_delivered("dm:a:b", None, 100);
+        crashed.record_delivered("group-1", None, 50);
+        clock.advance(INTERVAL);
+        crashed.flush().await;
+        // Accumulated but never flushed when the process dies
+        crashed.record_delivered("dm:a:b", None, 30);
+        drop(crashed);
+
+        clock.advance(Duration::from_secs(5));
+        let restarted = accounting(&jetstream, &subject, &clock);
+        restarted.record_delivered("dm:a:b", None, 20);
+        clock.advance(INTERVAL);
+        restarted.flush().await;
+        // An interval with nothing delivered appends nothing
+        restarted.flush().await;
+
+        let deltas = stored(&jetstream, &name).await;
+        assert_eq!(deltas.len(), 3);
+        assert_eq!(billed(&deltas), HashMap::from([("dm:a:b".to_string(), 120), ("group-1".to_string(), 50)]));
+        // Both processes numbered their first interval 0; the start time keeps them apart
+        let first_intervals: Vec<&VolumeDelta> =
+            deltas.iter().filter(|delta| delta.delta_id.ends_with(":0:dm:a:b")).collect();
+        assert_eq!(first_intervals.len(), 2);
+        assert_ne!(first_intervals[0].delta_id, first_intervals[1].delta_id);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn a_replayed_delta_is_dropped_as_a_duplicate() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let jetstream = connected().await;
+        let (name, subject) = stream(&jetstream).await;
+        let clock = Arc::new(SimClock::new());
+        let accounting = accounting(&jetstream, &subject, &clock);
+        accounting.record_delivered("dm:a:b", None, 100);
+        accounting.flush().await;
+
+        // The append landed but its ack was lost, so the same delta goes out again
+        let delta = stored(&jetstream, &name).await.remove(0);
+        accounting.unconfirmed.lock().push(delta.clone());
+        accounting.record_delivered("dm:a:b", None, 5);
+        accounting.flush().await;
+        assert!(matches!(accounting.append(&delta).await.unwrap(), Appended::Duplicate { sequence: 1 }));
+
+        let deltas = stored(&jetstream, &name).await;
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(billed(&deltas)["dm:a:b"], 105);
+        assert!(accounting.unconfirmed.lock().is_empty());
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains(r#"broker_volume_deltas_total{outcome="appended"} 2"#), "{}", rendered);
+        assert!(rendered.contains(r#"broker_volume_deltas_total{outcome="duplicate"} 1"#), "{}", rendered);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a JetStream server"]
+    async fn failed_appends_retry_under_the_same_id_and_shutdown_flushes() {
+        let jetstream = connected().await;
+        let clock = Arc::new(SimClock::new());
+        let subject = format!("test.volume.{}", Uuid::new_v4().simple());
+        let accounting = Arc::new(accounting(&jetstream, &subject, &clock));
+
+        // No stream holds the subject yet, so the append fails
+        accounting.record_delivered("dm:a:b", None, 100);
+        accounting.flush().await;
+        let failed = unconfirmed(&accounting);
+        assert_eq!(failed.len(), 1);
+
+        let name = format!("VOLUME_TEST_{}", Uuid::new_v4().simple());
+        jetstream
+            .create_stream(jetstream::stream::Config {
+                name: name.clone(),
+                subjects: vec![subject.clone()],
+                duplicate_window: Duration::from_secs(600),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let shutdown = ShutdownSignal::new();
+        let task = accounting.spawn(shutdown.clone()).unwrap();
+        accounting.record_delivered("group-1", None, 40);
+        shutdown.trigger("test");
+        task.await.unwrap();
+
+        let deltas = stored(&jetstream, &name).await;
+        assert_eq!(deltas[0].delta_id, failed[0].delta_id);
+        assert_eq!(billed(&deltas), HashMap::from([("dm:a:b".to_string(), 100), ("group-1".to_string(), 40)]));
+        assert!(accounting.unconfirmed.lock().is_empty());
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::WarmupConfig,
+    membership::MembershipCache,
+    metrics::BrokerMetrics,
+    task::{spawn_traced, TaskContext},
+};
+
+/// Startup fill of the group membership cache
+///
+/// Resolves the configured groups followed by the hottest groups persisted
+/// by the route warmer, at most `warmup.groups_per_second`, so a cold
+/// broker doesn't send its first traffic burst straight to the resolver.
+/// Warm-up ends when every group was tried or after `warmup.max_duration`,
+/// whichever comes first; resolver errors are counted and skipped. With
+/// `warmup.gate_readiness`, `/ready` reports not ready until warm-up has
+/// ended or reached `warmup.readiness_threshold`.
+pub struct CacheWarmup {
+    config: WarmupConfig,
+    memberships: Arc<MembershipCache>,
+    total: AtomicUsize,
+    attempted: AtomicUsize,
+    finished: AtomicBool,
+    metrics: BrokerMetrics,
+}
+
+impl CacheWarmup {
+    pub fn new(config: WarmupConfig, memberships: Arc<MembershipCache>, metrics: BrokerMetrics) -> Self {
+        Self {
+            finished: AtomicBool::new(!config.enabled),
+            config,
+            memberships,
+            total: AtomicUsize::new(0),
+            attempted: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    /// Groups tried so far over groups planned; 1.0 once warm-up has ended
+    pub fn progress(&self) -> f64 {
+        if self.finished.load(Ordering::Acquire) {
+            return 1.0;
+        }
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        self.attempted.load(Ordering::Relaxed) as f64 / total as f64
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Whether the readiness endpoint may report ready
+    pub fn ready(&self) -> bool {
+        !self.config.gate_readiness || self.progress() >= self.config.readiness_threshold
+    }
+
+    /// Warm the configured groups plus `hot_groups`, in that order, in the background
+    pub fn spawn(self: &Arc<Self>, hot_groups: Vec<String>) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let warmup = Arc::clone(self);
+        Some(spawn_traced("cache_warmup", TaskContext::new("warmup"), async move {
+            warmup.run(hot_groups).await;
+        }))
+    }
+
+    pub async fn run(&self, hot_groups: Vec<String>) {
+        let mut groups = self.config.groups.clone();
+        for group_id in hot_groups {
+            if !groups.contains(&group_id) {
+                groups.push(group_id);
+            }
+        }
+        groups.truncate(self.config.top_k);
+        self.total.store(groups.len(), Ordering::Relaxed);
+
+        let started = Instant::now();
+        let deadline = started + self.config.max_duration;
+        let interval = Duration::from_secs_f64(1.0 / self.config.groups_per_second.max(0.001));
+        let mut pacing = tokio::time::interval(interval);
+        // A slow resolve doesn't earn a burst afterwards
+        pacing.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut failed = 0u64;
+        for group_id in &groups {
+            pacing.tick().await;
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            match tokio::time::timeout(remaining, self.memberships.refresh(group_id)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    failed += 1;
+                    debug!("Warm-up resolve of {} failed: {}", group_id, e);
+                }
+                Err(_) => break,
+            }
+            self.attempted.fetch_add(1, Ordering::Relaxed);
+            self.metrics.update_warmup_progress(self.progress());
+        }
+
+        let attempted = self.attempted.load(Ordering::Relaxed);
+        if attempted < groups.len() {
+            warn!(
+                "Warm-up hit its {:?} cap after {} of {} groups; serving anyway",
+                self.config.max_duration,
+                attempted,
+                groups.len()
+            );
+        } else {
+            info!(
+                "Warm-up resolved {} groups ({} failed) in {:?}",
+                attempted,
+                failed,
+                started.elapsed()
+            );
+        }
+        self.metrics.record_warmup_failures(failed);
+        self.finished.store(true, Ordering::Release);
+        self.metrics.update_warmup_progress(1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::{
+        config::BrokerConfig,
+        membership::{MembershipResolver, ResolverError},
+    };
+
+    /// Records every resolve; `bad-` groups fail, `hang-` groups never return
+    /// and `gated-` groups wait for a permit
+    struct RecordingResolver {
+        calls: Mutex<Vec<(String, Instant)>>,
+        gate: Semaphore,
+    }
+
+    impl RecordingResolver {
+        fn new() -> Self {
+            Self {
+                calls: Mutex::new(Vec::new()),
+                gate: Semaphore::new(0),
+            }
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().iter().map(|(group_id, _)| group_id.clone()).collect()
+        }
+    }
+
+    #[async_trait]
+    impl MembershipResolver for RecordingResolver {
+        async fn resolve(&self, group_id: &str) -> Result<Vec<String>, ResolverError> {
+            self.calls.lock().push((group_id.to_string(), Instant::now()));
+            if group_id.starts_with("hang-") {
+                std::future::pending::<()>().await;
+            }
+            if group_id.starts_with("gated-") {
+                self.gate.acquire().await.unwrap().forget();
+            }
+            if group_id.starts_with("bad-") {
+                return Err(ResolverError("resolver unavailable".into()));
+            }
+            Ok(vec!["alice".into(), "bob".into()])
+        }
+    }
+
+    fn config() -> WarmupConfig {
+        let mut config = BrokerConfig::load().unwrap().warmup;
+        config.enabled = true;
+        config.groups = Vec::new();
+        config.top_k = 1000;
+        config.groups_per_second = 1000.0;
+        config.max_duration = Duration::from_secs(10);
+        config.gate_readiness = false;
+        config
+    }
+
+    fn warmup(config: WarmupConfig) -> (Arc<CacheWarmup>, Arc<RecordingResolver>) {
+        let broker = BrokerConfig::load().unwrap();
+        let resolver = Arc::new(RecordingResolver::new());
+        let metrics = BrokerMetrics::new().unwrap();
+        let memberships = Arc::new(MembershipCache::new(
+            resolver.clone(),
+            &broker.routing,
+            &broker.limits,
+            metrics.clone(),
+        ));
+        (Arc::new(CacheWarmup::new(config, memberships, metrics)), resolver)
+    }
+
+    fn groups(prefix: &str, count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{}{}", prefix, i)).collect()
+    }
+
+    #[tokio::test]
+    async fn configured_groups_go_first_and_the_plan_is_capped_at_top_k() {
+        let mut config = config();
+        config.groups = vec!["pinned".into(), "hot-1".into()];
+        config.top_k = 4;
+        let (warmup, resolver) = warmup(config);
+
+        warmup.run(groups("hot-", 5)).await;
+
+        assert_eq!(resolver.calls(), ["pinned", "hot-1", "hot-0", "hot-2"]);
+        assert!(warmup.is_finished());
+        assert_eq!(warmup.progress(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn resolves_are_throttled_to_the_configured_rate() {
+        let mut config = config();
+        config.groups_per_second = 20.0;
+        let (warmup, resolver) = warmup(config);
+
+        let started = Instant::now();
+        warmup.run(groups("group-", 11)).await;
+        let elapsed = started.elapsed();
+
+        // The first resolve is immediate, then one every 50ms
+        assert!(elapsed >= Duration::from_millis(480), "11 groups at 20/s took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "11 groups at 20/s took {:?}", elapsed);
+        let calls = resolver.calls.lock();
+        assert_eq!(calls.len(), 11);
+        for pair in calls.windows(2) {
+            let gap = pair[1].1 - pair[0].1;
+            assert!(gap >= Duration::from_millis(40), "resolves {:?} apart", gap);
+        }
+    }
+
+    #[tokio::test]
+    async fn serving_starts_once_the_duration_cap_passes() {
+        let mut config = config();
+        config.groups_per_second = 10.0;
+        config.max_duration = Duration::from_millis(350);
+        let (warmup, resolver) = warmup(config);
+
+        let started = Instant::now();
+        warmup.run(groups("group-", 100)).await;
+        let elapsed = started.elapsed();
+
+        assert!(elapsed < Duration::from_millis(600), "warm-up ran {:?}", elapsed);
+        assert!(warmup.is_finished());
+        assert_eq!(warmup.progress(), 1.0);
+        let attempted = resolver.calls().len();
+        assert!((3..=5).contains(&attempted), "{} groups tried in 350ms at 10/s", attempted);
+    }
+
+    #[tokio::test]
+    async fn a_hung_resolver_is_cut_off_by_the_cap() {
+        let mut config = config();
+        config.max_duration = Duration::from_millis(200);
+        let (warmup, resolver) = warmup(config);
+
+        let started = Instant::now();
+        warmup.run(vec!["group-0".into(), "hang-1".into(), "group-2".into()]).await;
+
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(warmup.is_finished());
+        assert_eq!(resolver.calls(), ["group-0", "hang-1"]);
+    }
+
+    #[tokio::test]
+    async fn resolver_errors_do_not_stop_warm_up() {
+        let (warmup, resolver) = warmup(config());
+
+        warmup
+            .run(vec!["bad-0".into(), "group-1".into(), "bad-2".into(), "group-3".into()])
+            .await;
+
+        assert_eq!(resolver.calls().len(), 4);
+        assert_eq!(warmup.attempted.load(Ordering::Relaxed), 4);
+        assert!(warmup.is_finished());
+    }
+
+    #[tokio::test]
+    async fn readiness_waits_for_the_threshold_when_gated() {
+        let mut config = config();
+        config.gate_readiness = true;
+        config.readiness_threshold = 0.5;
+        let (warmup, resolver) = warmup(config);
+        let handle = warmup.spawn(groups("gated-", 4)).unwrap();
+
+        let wait_attempted = |count: usize| {
+            let warmup = warmup.clone();
+            async move {
+                while warmup.attempted.load(Ordering::Relaxed) < count {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        assert!(!warmup.ready());
+
+        resolver.gate.add_permits(1);
+        wait_attempted(1).await;
+        assert_eq!(warmup.progress(), 0.25);
+        assert!(!warmup.ready());
+
+        resolver.gate.add_permits(1);
+        wait_attempted(2).await;
+        assert!(warmup.ready());
+        assert!(!warmup.is_finished());
+
+        resolver.gate.add_permits(2);
+        handle.await.unwrap();
+        assert!(warmup.ready());
+    }
+
+    #[tokio::test]
+    async fn ungated_readiness_does_not_wait() {
+        let (warmup, resolver) = warmup(config());
+        let handle = warmup.spawn(groups("gated-", 4)).unwrap();
+
+        assert!(warmup.ready());
+        resolver.gate.add_permits(4);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn disabled_warm_up_is_finished_and_ready() {
+        let mut config = config();
+        config.enabled = false;
+        config.gate_readiness = true;
+        let (warmup, resolver) = warmup(config);
+
+        assert!(warmup.spawn(groups("group-", 3)).is_none());
+        assert!(warmup.is_finished());
+        assert!(warmup.ready());
+        assert!(resolver.calls().is_empty());
+    }
+}
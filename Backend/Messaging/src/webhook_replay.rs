@@ -0,0 +1,470 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{SharedClock, SystemClock},
+    config::{WebhookProviderConfig, WebhookReplayConfig},
+    metrics::BrokerMetrics,
+};
+
+/// Unit of a provider's timestamp header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampUnit {
+    #[default]
+    Seconds,
+    Millis,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReplayRejection {
+    #[error("unknown webhook provider {0}")]
+    UnknownProvider(String),
+    #[error("missing or malformed {0} header")]
+    Malformed(String),
+    #[error("timestamp is {age_ms}ms old, past the freshness window")]
+    Stale { age_ms: i64 },
+    #[error("timestamp is {ahead_ms}ms in the future, past the clock skew tolerance")]
+    Future { ahead_ms: i64 },
+    #[error("request was already accepted")]
+    Replayed,
+    /// Every cached nonce is still inside its window; evicting one would reopen it
+    #[error("replay cache is full")]
+    CacheFull,
+}
+
+impl ReplayRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplayRejection::UnknownProvider(_) => "unknown_provider",
+            ReplayRejection::Malformed(_) => "malformed",
+            ReplayRejection::Stale { .. } => "stale",
+            ReplayRejection::Future { .. } => "future",
+            ReplayRejection::Replayed => "replayed",
+            ReplayRejection::CacheFull => "cache_full",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ReplayRejection::UnknownProvider(_) => StatusCode::NOT_FOUND,
+            ReplayRejection::Malformed(_) => StatusCode::BAD_REQUEST,
+            ReplayRejection::Stale { .. } | ReplayRejection::Future { .. } => StatusCode::UNAUTHORIZED,
+            ReplayRejection::Replayed => StatusCode::CONFLICT,
+            ReplayRejection::CacheFull => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl IntoResponse for ReplayRejection {
+    fn into_response(self) -> Response {
+        (self.status(), format!("{}: {}", self.as_str(), self)).into_response()
+    }
+}
+
+/// Nonces seen for one provider, with the time each one may be forgotten
+#[derive(Default)]
+struct NonceCache {
+    expiries: HashMap<String, i64>,
+    /// `(expires_at, nonce)`, soonest first
+    by_expiry: BTreeSet<(i64, String)>,
+}
+
+impl NonceCache {
+    fn purge(&mut self, now: i64) {
+        while let Some((expires_at, _)) = self.by_expiry.first() {
+            if *expires_at > now {
+                break;
+            }
+            let (_, nonce) = self.by_expiry.pop_first().expect("checked above");
+            self.expiries.remove(&nonce);
+        }
+    }
+}
+
+/// Replay protection for signed webhook requests
+///
+/// A request's provider timestamp must fall within
+/// `[now - freshness_window - clock_skew, now + clock_skew]`: the freshness
+/// window bounds how long a captured request stays usable, and the skew
+/// tolerance separately absorbs the provider's clock running ahead of or
+/// behind ours. Inside that range each request's nonce (its nonce header,
+/// or its signature for providers without one) is accepted once; it stays
+/// cached until its timestamp leaves the range, after which the freshness
+/// check alone rejects it.
+///
+/// The cache holds at most `max_nonces_per_provider` entries per provider.
+/// Only expired nonces are ever evicted: evicting a live one would let its
+/// request be replayed inside the window, so a full cache rejects new
+/// requests instead.
+///
+/// Both checks need only headers. `replay_protection` runs them before the
+/// body is read, so a rejected request costs no body parsing. The guard
+/// doesn't verify the provider's signature itself, so it trusts whatever
+/// timestamp and nonce it is given.
+pub struct ReplayGuard {
+    config: WebhookReplayConfig,
+    caches: HashMap<String, Mutex<NonceCache>>,
+    clock: SharedClock,
+    metrics: BrokerMetrics,
+}
+
+impl ReplayGuard {
+    pub fn new(config: WebhookReplayConfig, metrics: BrokerMetrics) -> Self {
+        let caches = config
+            .providers
+            .keys()
+            .map(|provider| (provider.clone(), Mutex::new(NonceCache::default())))
+            .collect();
+        Self {
+            config,
+            caches,
+            clock: SystemClock::shared(),
+            metrics,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Check and claim a request from `provider`, as of `now` in milliseconds
+    pub fn admit(&self, provider: &str, headers: &HeaderMap, now: i64) -> Result<(), ReplayRejection> {
+        let result = self.check(provider, headers, now);
+        let outcome = match &result {
+            Ok(()) => "accepted",
+            Err(rejection) => rejection.as_str(),
+        };
+        self.metrics.record_webhook_replay_check(provider, outcome);
+        result
+    }
+
+    fn check(&self, provider: &str, headers: &HeaderMap, now: i64) -> Result<(), ReplayRejection> {
+        let Some(provider_config) = self.config.providers.get(provider) else {
+            return Err(ReplayRejection::UnknownProvider(provider.to_string()));
+        };
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let timestamp = timestamp_ms(headers, provider_config)?;
+        let window = self.config.freshness_window.as_millis() as i64;
+        let skew = self.config.clock_skew_ms as i64;
+        // Saturating, so a hostile timestamp reads as far past or far ahead instead of overflowing
+        let age = now.saturating_sub(timestamp);
+        if age > window + skew {
+            return Err(ReplayRejection::Stale { age_ms: age });
+        }
+        let ahead = timestamp.saturating_sub(now);
+        if ahead > skew {
+            return Err(ReplayRejection::Future { ahead_ms: ahead });
+        }
+
+        let nonce_header = provider_config
+            .nonce_header
+            .as_deref()
+            .unwrap_or(&provider_config.signature_header);
+        let nonce = header_str(headers, nonce_header)?;
+        // Fresh until the timestamp leaves the accepted range
+        let expires_at = timestamp + window + skew;
+
+        let mut cache = self.caches[provider].lock();
+        cache.purge(now);
+        if cache.expiries.contains_key(nonce) {
+            return Err(ReplayRejection::Replayed);
+        }
+        if cache.expiries.len() >= self.config.max_nonces_per_provider {
+            return Err(ReplayRejection::CacheFull);
+        }
+        cache.expiries.insert(nonce.to_string(), expires_at);
+        cache.by_expiry.insert((expires_at, nonce.to_string()));
+        let cached = cache.expiries.len();
+        drop(cache);
+        self.metrics.update_webhook_nonces(provider, cached);
+        Ok(())
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, ReplayRejection> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| ReplayRejection::Malformed(name.to_string()))
+}
+
+fn timestamp_ms(headers: &HeaderMap, provider: &WebhookProviderConfig) -> Result<i64, ReplayRejection> {
+    let value: i64 = header_str(headers, &provider.timestamp_header)?
+        .parse()
+        .map_err(|_| ReplayRejection::Malformed(provider.timestamp_header.clone()))?;
+    match provider.timestamp_unit {
+        TimestampUnit::Seconds => value.checked_mul(1000),
+        TimestampUnit::Millis => Some(value),
+    }
+    .ok_or_else(|| ReplayRejection::Malformed(provider.timestamp_header.clone()))
+}
+
+/// State for `replay_protection`: the shared guard and the route's provider
+#[derive(Clone)]
+pub struct ReplayProtection {
+    pub guard: Arc<ReplayGuard>,
+    pub provider: String,
+}
+
+/// Middleware for webhook bridge routes
+pub async fn replay_protection(State(state): State<ReplayProtection>, request: Request, next: Next) -> Response {
+    match state.guard.admit(&state.provider, request.headers(), state.guard.clock.now_millis()) {
+        Ok(()) => next.run(request).await,
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use axum::{body::Body, middleware, routing::post, Router};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        config::BrokerConfig,
+    };
+
+    const WINDOW: Duration = Duration::from_secs(300);
+    const SKEW_MS: i64 = 30_000;
+
+    fn config() -> WebhookReplayConfig {
+        let mut config = BrokerConfig::load().unwrap().webhook_replay;
+        config.enabled = true;
+        config.freshness_window = WINDOW;
+        config.clock_skew_ms = SKEW_MS as u64;
+        config.max_nonces_per_provider = 100;
+        config.providers = HashMap::from([
+            (
+                "stripe".to_string(),
+                WebhookProviderConfig {
+                    timestamp_header: "x-timestamp".into(),
+                    timestamp_unit: TimestampUnit::Millis,
+                    nonce_header: Some("x-nonce".into()),
+                    signature_header: "x-signature".into(),
+                },
+            ),
+            (
+                "slack".to_string(),
+                WebhookProviderConfig {
+                    timestamp_header: "x-slack-request-timestamp".into(),
+                    timestamp_unit: TimestampUnit::Seconds,
+                    nonce_header: None,
+                    signature_header: "x-slack-signature".into(),
+                },
+            ),
+        ]);
+        config
+    }
+
+    fn guard(config: WebhookReplayConfig) -> (ReplayGuard, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new());
+        (ReplayGuard::new(config, BrokerMetrics::new().unwrap()).with_clock(clock.clone()), clock)
+    }
+
+    fn signed(timestamp_ms: i64, nonce: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-timestamp", timestamp_ms.to_string().parse().unwrap());
+        headers.insert("x-nonce", nonce.parse().unwrap());
+        headers.insert("x-signature", "v1=deadbeef".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn a_replay_inside_the_window_is_rejected_as_replayed() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let (guard, clock) = guard(config());
+            let request = signed(clock.now_millis(), "n-1");
+            assert_eq!(guard.admit("stripe", &request, clock.now_millis()), Ok(()));
+
+            clock.advance(WINDOW);
+            assert_eq!(guard.admit("stripe", &request, clock.now_millis()), Err(ReplayRejection::Replayed));
+            // Another nonce with the same timestamp is a different request
+            assert_eq!(guard.admit("stripe", &signed(clock.now_millis() - WINDOW.as_millis() as i64, "n-2"), clock.now_millis()), Ok(()));
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_webhook_replay_checks_total{provider="stripe",outcome="accepted"} 2"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_webhook_replay_checks_total{provider="stripe",outcome="replayed"} 1"#), "{}", rendered);
+            assert!(rendered.contains(r#"broker_webhook_nonces_cached{provider="stripe"} 2"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn a_replay_past_the_window_is_rejected_as_stale_after_its_nonce_expires() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, || {
+            let (guard, clock) = guard(config());
+            let sent = clock.now_millis();
+            let request = signed(sent, "n-1");
+            guard.admit("stripe", &request, clock.now_millis()).unwrap();
+
+            clock.advance(WINDOW + Duration::from_millis(SKEW_MS as u64 + 1));
+            let age_ms = clock.now_millis() - sent;
+            assert_eq!(guard.admit("stripe", &request, clock.now_millis()), Err(ReplayRejection::Stale { age_ms }));
+            // The fresh request that purges it finds the nonce gone, not replayable
+            guard.admit("stripe", &signed(clock.now_millis(), "n-2"), clock.now_millis()).unwrap();
+            assert_eq!(guard.caches["stripe"].lock().expiries.len(), 1);
+
+            let rendered = recorder.handle().render();
+            assert!(rendered.contains(r#"broker_webhook_replay_checks_total{provider="stripe",outcome="stale"} 1"#), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn skew_is_tolerated_on_both_sides_up_to_the_boundary() {
+        let (guard, clock) = guard(config());
+        let now = clock.now_millis();
+        let window = WINDOW.as_millis() as i64;
+
+        // Provider clock ahead of ours
+        assert_eq!(guard.admit("stripe", &signed(now + SKEW_MS, "ahead"), now), Ok(()));
+        assert_eq!(
+            guard.admit("stripe", &signed(now + SKEW_MS + 1, "too-far-ahead"), now),
+            Err(ReplayRejection::Future { ahead_ms: SKEW_MS + 1 })
+        );
+        // Behind: the window plus the skew, and not a millisecond more
+        assert_eq!(guard.admit("stripe", &signed(now - window - SKEW_MS, "behind"), now), Ok(()));
+        assert_eq!(
+            guard.admit("stripe", &signed(now - window - SKEW_MS - 1, "too-far-behind"), now),
+            Err(ReplayRejection::Stale { age_ms: window + SKEW_MS + 1 })
+        );
+
+        // A request at the edge stays cached until its timestamp leaves the range
+        clock.advance(Duration::from_millis(1));
+        let later = clock.now_millis();
+        assert_eq!(guard.admit("stripe", &signed(now + SKEW_MS, "ahead"), later), Err(ReplayRejection::Replayed));
+        assert!(matches!(guard.admit("stripe", &signed(now - window - SKEW_MS, "behind"), later), Err(ReplayRejection::Stale { .. })));
+        assert!(guard.caches["stripe"].lock().expiries.contains_key("ahead"));
+        assert!(!guard.caches["stripe"].lock().expiries.contains_key("behind"));
+    }
+
+    #[test]
+    fn extreme_timestamps_are_rejected_without_overflowing() {
+        let (guard, clock) = guard(config());
+        let now = clock.now_millis();
+        assert_eq!(
+            guard.admit("stripe", &signed(i64::MIN, "min"), now),
+            Err(ReplayRejection::Stale { age_ms: i64::MAX })
+        );
+        assert_eq!(
+            guard.admit("stripe", &signed(i64::MAX, "max"), now),
+            Err(ReplayRejection::Future { ahead_ms: i64::MAX - now })
+        );
+        assert!(guard.caches["stripe"].lock().expiries.is_empty());
+    }
+
+    #[test]
+    fn a_full_cache_only_evicts_expired_nonces() {
+        let mut config = config();
+        config.max_nonces_per_provider = 2;
+        let (guard, clock) = guard(config);
+        let first = clock.now_millis();
+        guard.admit("stripe", &signed(first, "n-1"), first).unwrap();
+        clock.advance(Duration::from_secs(10));
+        let second = clock.now_millis();
+        guard.admit("stripe", &signed(second, "n-2"), second).unwrap();
+
+        assert_eq!(guard.admit("stripe", &signed(second, "n-3"), second), Err(ReplayRejection::CacheFull));
+        assert_eq!(guard.admit("stripe", &signed(first, "n-1"), second), Err(ReplayRejection::Replayed));
+
+        // n-1 expires first and makes room; n-2 is still live and stays put
+        clock.advance(WINDOW + Duration::from_millis(SKEW_MS as u64) - Duration::from_secs(10));
+        let now = clock.now_millis();
+        assert_eq!(guard.admit("stripe", &signed(now, "n-3"), now), Ok(()));
+        assert_eq!(guard.admit("stripe", &signed(second, "n-2"), now), Err(ReplayRejection::Replayed));
+        assert_eq!(guard.admit("stripe", &signed(now, "n-4"), now), Err(ReplayRejection::CacheFull));
+    }
+
+    #[test]
+    fn providers_without_a_nonce_are_deduplicated_by_signature() {
+        let (guard, clock) = guard(config());
+        let now = clock.now_millis();
+        let request = |signature: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-slack-request-timestamp", (now / 1000).to_string().parse().unwrap());
+            headers.insert("x-slack-signature", signature.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(guard.admit("slack", &request("v0=aaa"), now), Ok(()));
+        assert_eq!(guard.admit("slack", &request("v0=aaa"), now), Err(ReplayRejection::Replayed));
+        assert_eq!(guard.admit("slack", &request("v0=bbb"), now), Ok(()));
+        // Caches are per provider
+        assert_eq!(guard.admit("stripe", &signed(now, "v0=aaa"), now), Ok(()));
+
+        assert_eq!(
+            guard.admit("slack", &HeaderMap::new(), now),
+            Err(ReplayRejection::Malformed("x-slack-request-timestamp".into()))
+        );
+        assert_eq!(guard.admit("github", &request("v0=ccc"), now), Err(ReplayRejection::UnknownProvider("github".into())));
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_never_reach_the_body() {
+        let (guard, clock) = guard(config());
+        let state = ReplayProtection {
+            guard: Arc::new(guard),
+            provider: "stripe".into(),
+        };
+        let handled = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .route(
+                "/bridge/stripe",
+                post({
+                    let handled = handled.clone();
+                    move |body: String| async move {
+                        handled.fetch_add(1, Ordering::SeqCst);
+                        body
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(state, replay_protection));
+        let send = |timestamp: i64, nonce: &str| {
+            let request = axum::http::Request::post("/bridge/stripe")
+                .header("x-timestamp", timestamp.to_string())
+                .header("x-nonce", nonce)
+                .header("x-signature", "v1=deadbeef")
+                .body(Body::from("{\"event\":\"charge\"}"))
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let now = clock.now_millis();
+        assert_eq!(send(now, "n-1").await.unwrap().status(), StatusCode::OK);
+        let replayed = send(now, "n-1").await.unwrap();
+        assert_eq!(replayed.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(replayed.into_body(), usize::MAX).await.unwrap();
+        assert!(body.starts_with(b"replayed: "), "{:?}", body);
+
+        clock.advance(WINDOW + Duration::from_millis(SKEW_MS as u64 + 1));
+        let stale = send(now, "n-1").await.unwrap();
+        assert_eq!(stale.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(stale.into_body(), usize::MAX).await.unwrap();
+        assert!(body.starts_with(b"stale: "), "{:?}", body);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+    }
+}